@@ -0,0 +1,197 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+
+//! Python bindings for the splinter client, for scripting workloads and experimenting with
+//! extensions from an interpreter or notebook. Every method blocks until the server responds.
+//!
+//! ```python
+//! import splinter
+//!
+//! client = splinter.Client("client.toml", "eth0", 0, 1)
+//! client.put(1, b"key", b"value")
+//! assert client.get(1, b"key") == b"value"
+//! ```
+
+#![feature(specialization)]
+
+extern crate db;
+extern crate futures;
+#[macro_use]
+extern crate pyo3;
+extern crate splinter_client;
+
+use db::backend::SocketBackend;
+use db::config::ClientConfig;
+use db::harness;
+use db::wireformat::RpcStatus;
+
+use futures::Future;
+
+use pyo3::exceptions;
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyList};
+
+use splinter_client::Error;
+
+// Converts the error of a failed operation into a Python exception. A get or delete on a key
+// that does not exist raises a KeyError, everything else a RuntimeError.
+fn to_pyerr(err: Error) -> PyErr {
+    match err {
+        Error::Status(status) if status == RpcStatus::StatusObjectDoesNotExist as u8 => {
+            PyErr::new::<exceptions::KeyError, _>(err.to_string())
+        }
+
+        _ => PyErr::new::<exceptions::RuntimeError, _>(err.to_string()),
+    }
+}
+
+/// A client of a Splinter server, exchanging packets with it over a raw socket on one of the
+/// machine's network interfaces (which requires root). Operations are issued on behalf of a
+/// single tenant, and block until they complete.
+#[pyclass]
+struct Client {
+    // The client operations are issued through.
+    client: splinter_client::Client<SocketBackend>,
+
+    token: PyToken,
+}
+
+#[pymethods]
+impl Client {
+    /// Creates a client.
+    ///
+    /// # Arguments
+    ///
+    /// * `config`: Path of the client's toml config, which has the client and server addresses.
+    /// * `iface`:  The network interface requests are sent out on (ex: "eth0").
+    /// * `queue`:  An identifier for the client's socket, also used as it's UDP source port.
+    ///             Every client in a process must be given a different one.
+    /// * `tenant`: The tenant operations are issued on behalf of.
+    #[new]
+    fn __new__(
+        obj: &PyRawObject,
+        config: &str,
+        iface: &str,
+        queue: i32,
+        tenant: u32,
+    ) -> PyResult<()> {
+        let config = ClientConfig::load_from(config);
+        if let Err(err) = config.validate() {
+            return Err(PyErr::new::<exceptions::ValueError, _>(err.to_string()));
+        }
+
+        // Packets are allocated from DPDK's pool, even though no NIC is bound to DPDK.
+        harness::init();
+
+        let port = SocketBackend::new(iface, queue)
+            .map_err(|err| PyErr::new::<exceptions::IOError, _>(err.to_string()))?;
+
+        obj.init(|token| Client {
+            client: splinter_client::Client::new(&config, port, tenant),
+            token: token,
+        })
+    }
+
+    /// Looks up a key, and returns it's value. Raises a KeyError if the key does not exist.
+    fn get(&self, table: u64, key: &PyBytes) -> PyResult<PyObject> {
+        let val = self.client
+            .get(table, key.as_bytes())
+            .wait()
+            .map_err(to_pyerr)?;
+
+        Ok(PyBytes::new(self.py(), &val).into())
+    }
+
+    /// Looks up several keys at once, and returns their values in the same order. Every lookup
+    /// is sent out before waiting on any of them.
+    fn get_many(&self, table: u64, keys: &PyList) -> PyResult<PyObject> {
+        let mut pending = Vec::new();
+        for key in keys.iter() {
+            let key: &PyBytes = key.extract()?;
+            pending.push(self.client.get(table, key.as_bytes()));
+        }
+
+        let py = self.py();
+        let vals = PyList::empty(py);
+        for response in pending {
+            let val = response.wait().map_err(to_pyerr)?;
+            vals.append(PyBytes::new(py, &val))?;
+        }
+
+        Ok(vals.into())
+    }
+
+    /// Inserts a key-value pair.
+    fn put(&self, table: u64, key: &PyBytes, val: &PyBytes) -> PyResult<()> {
+        self.client
+            .put(table, key.as_bytes(), val.as_bytes())
+            .wait()
+            .map(|_| ())
+            .map_err(to_pyerr)
+    }
+
+    /// Inserts several key-value pairs at once, for instance to load a dataset. `pairs` is a
+    /// list of (key, value) tuples of bytes. Every insert is sent out before waiting on any of
+    /// them.
+    fn put_many(&self, table: u64, pairs: &PyList) -> PyResult<()> {
+        let mut pending = Vec::new();
+        for pair in pairs.iter() {
+            let (key, val): (&PyBytes, &PyBytes) = pair.extract()?;
+            pending.push(self.client.put(table, key.as_bytes(), val.as_bytes()));
+        }
+
+        for response in pending {
+            response.wait().map_err(to_pyerr)?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes a key. Raises a KeyError if the key does not exist.
+    fn delete(&self, table: u64, key: &PyBytes) -> PyResult<()> {
+        self.client
+            .delete(table, key.as_bytes())
+            .wait()
+            .map(|_| ())
+            .map_err(to_pyerr)
+    }
+
+    /// Invokes an extension installed at the server, and returns whatever it wrote into it's
+    /// response. `args` is passed to the extension as is; Python's struct module is handy for
+    /// packing them.
+    fn invoke(&self, name: &str, args: &PyBytes) -> PyResult<PyObject> {
+        let resp = self.client
+            .invoke(name, args.as_bytes())
+            .wait()
+            .map_err(to_pyerr)?;
+
+        Ok(PyBytes::new(self.py(), &resp).into())
+    }
+
+    /// Returns the number of gets that were served from the client's cache, and the number
+    /// that were sent to the server.
+    fn cache_stats(&self) -> PyResult<(u64, u64)> {
+        Ok(self.client.cache_stats())
+    }
+}
+
+/// The splinter module.
+#[pymodinit]
+fn splinter(_py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_class::<Client>()?;
+
+    Ok(())
+}
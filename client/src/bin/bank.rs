@@ -0,0 +1,257 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! A workload checking that the transfer() extension (see `ext/transfer`) neither loses nor
+//! makes up money, and with it that the compare and swaps it is built on are atomic. Every
+//! balance is summed up, random transfers are issued between the accounts with many of them in
+//! flight at once, and every balance is summed up again. The run fails if the sums differ.
+//!
+//! Accounts are the keys 1 to `n_keys` of a table, laid out like the objects the server
+//! populates tables with for tests: the account's number is written little endian into the
+//! first bytes of the key, and the first eight bytes of the value are it's balance. Running
+//! several instances at once, on different queues, checks transfers racing across clients and
+//! server cores; every instance's sums only match once all of them are done, so it's check is
+//! only meaningful for the last one to finish.
+//!
+//! Data operations are sent over a raw socket on one of the machine's network interfaces, and so
+//! require root.
+
+extern crate db;
+extern crate futures;
+extern crate splinter_client;
+
+use std::collections::VecDeque;
+use std::env;
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use db::backend::SocketBackend;
+use db::config::ClientConfig;
+use db::harness;
+use db::wireformat::RpcStatus;
+
+use futures::Future;
+
+use splinter_client::{Client, Error};
+
+const USAGE: &str = "Usage: splinter-bank [options]
+
+Options:
+    --config <path>     Client config with the server's address (default: client.toml)
+    --iface <name>      Network interface requests are sent on (default: eth0)
+    --queue <id>        Identifier of the socket, also used as it's UDP port (default: 0)
+    --tenant <id>       Tenant requests are issued on behalf of (default: 1)
+    --table <id>        Table holding the accounts (default: 1)
+    --accounts <n>      Number of accounts, numbered from 1 (default: the config's n_keys)
+    --transfers <n>     Number of transfers issued (default: the config's num_reqs)
+    --window <n>        Number of transfers in flight at once (default: 32)
+    --amount <n>        Largest amount moved by a transfer (default: 10)";
+
+/// The name the transfer() extension is invoked by.
+const NAME: &str = "transfer";
+
+/// The statuses transfer() responds with, in the order of their codes starting at 1.
+const STATUSES: [&str; 6] = [
+    "successful",
+    "invalid arguments",
+    "no account",
+    "insufficient balance",
+    "contended",
+    "failed",
+];
+
+/// Options of a run.
+struct Options {
+    // Path of the client config.
+    config: String,
+
+    // The network interface requests are sent out on.
+    iface: String,
+
+    // Identifier for the socket requests are sent out on.
+    queue: i32,
+
+    // The tenant requests are issued on behalf of.
+    tenant: u32,
+
+    // The table holding the accounts, and the number of accounts.
+    table: u64,
+    accounts: Option<u64>,
+
+    // The number of transfers, the number of them in flight at once, and the largest amount one
+    // moves.
+    transfers: Option<u64>,
+    window: usize,
+    amount: u64,
+}
+
+// Prints an error along with the usage, and exits.
+fn usage(msg: &str) -> ! {
+    eprintln!("{}\n\n{}", msg, USAGE);
+    process::exit(2);
+}
+
+// Prints an error and exits.
+fn fail(msg: &str) -> ! {
+    eprintln!("{}", msg);
+    process::exit(1);
+}
+
+// Parses a number passed in on the command line.
+fn number<N: std::str::FromStr>(what: &str, arg: &str) -> N {
+    arg.parse()
+        .unwrap_or_else(|_| usage(&format!("Invalid {} \"{}\"", what, arg)))
+}
+
+// Returns the key an account is stored under.
+fn key(account: u64, key_len: usize) -> Vec<u8> {
+    (0..key_len)
+        .map(|byte| if byte < 8 { (account >> (8 * byte)) as u8 } else { 0 })
+        .collect()
+}
+
+// Returns the arguments to transfer() moving an amount between two accounts.
+fn args(table: u64, key_len: usize, from: u64, to: u64, amount: u64) -> Vec<u8> {
+    let mut args = Vec::with_capacity(34);
+    args.extend((0..8).map(|byte| (table >> (8 * byte)) as u8));
+    args.extend((0..2).map(|byte| (key_len >> (8 * byte)) as u8));
+    for val in [from, to, amount].iter() {
+        args.extend((0..8).map(|byte| (val >> (8 * byte)) as u8));
+    }
+    args
+}
+
+// Sums up the balances of every account, reading them from the server rather than the client's
+// cache. Exits if one of them cannot be read.
+fn total(client: &Client<SocketBackend>, opts: &Options, key_len: usize, accounts: u64) -> u128 {
+    let mut sum = 0;
+    for account in 1..accounts + 1 {
+        let key = key(account, key_len);
+        client.invalidate(opts.table, &key);
+        match client.get(opts.table, &key).wait() {
+            Ok(ref val) if val.len() >= 8 => {
+                sum += val[..8].iter().rev().fold(0u64, |acc, &b| (acc << 8) | b as u64) as u128;
+            }
+            Ok(_) => fail(&format!("Account {} is too short to hold a balance", account)),
+            Err(err) => fail(&format!("Failed to read account {}: {}", account, err)),
+        }
+    }
+    sum
+}
+
+// Returns the next number of a xorshift generator.
+fn next(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+fn main() {
+    let mut opts = Options {
+        config: String::from("client.toml"),
+        iface: String::from("eth0"),
+        queue: 0,
+        tenant: 1,
+        table: 1,
+        accounts: None,
+        transfers: None,
+        window: 32,
+        amount: 10,
+    };
+
+    let mut argv: Vec<String> = env::args().skip(1).collect();
+    while argv.len() > 0 {
+        if argv.len() < 2 {
+            usage(&format!("Missing value for {}", argv[0]));
+        }
+
+        let val = argv.remove(1);
+        match argv.remove(0).as_str() {
+            "--config" => opts.config = val,
+            "--iface" => opts.iface = val,
+            "--queue" => opts.queue = number("queue", &val),
+            "--tenant" => opts.tenant = number("tenant", &val),
+            "--table" => opts.table = number("table", &val),
+            "--accounts" => opts.accounts = Some(number("accounts", &val)),
+            "--transfers" => opts.transfers = Some(number("transfers", &val)),
+            "--window" => opts.window = number("window", &val),
+            "--amount" => opts.amount = number("amount", &val),
+            opt => usage(&format!("Unknown option {}", opt)),
+        }
+    }
+
+    let config = ClientConfig::load_from(&opts.config);
+    if let Err(err) = config.validate() {
+        fail(&format!("{}", err));
+    }
+
+    let accounts = opts.accounts.unwrap_or(config.n_keys as u64);
+    let transfers = opts.transfers.unwrap_or(config.num_reqs as u64);
+    if accounts < 2 || config.key_len < 8 || opts.window == 0 || opts.amount == 0 {
+        usage("Need at least 2 accounts, 8 byte keys, a window and an amount");
+    }
+
+    // Packets are allocated from DPDK's pool, even though no NIC is bound to DPDK.
+    harness::init();
+    let port = SocketBackend::new(&opts.iface, opts.queue)
+        .unwrap_or_else(|err| fail(&format!("Failed to open a socket on {}: {}", opts.iface, err)));
+    let client = Client::new(&config, port, opts.tenant);
+
+    let before = total(&client, &opts, config.key_len, accounts);
+    println!("Total balance of {} accounts before: {}", accounts, before);
+
+    // Transfers are kept `window` in flight, and their responses picked up in the order they were
+    // issued.
+    let mut rand = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.subsec_nanos() as u64 | 1)
+        .unwrap_or(1);
+    let mut counts = [0u64; 6];
+    let mut errors = 0;
+    let mut inflight = VecDeque::with_capacity(opts.window);
+    let mut issued = 0;
+    while issued < transfers || inflight.len() > 0 {
+        while issued < transfers && inflight.len() < opts.window {
+            let from = 1 + next(&mut rand) % accounts;
+            let to = 1 + (from + next(&mut rand) % (accounts - 1)) % accounts;
+            let amount = 1 + next(&mut rand) % opts.amount;
+            let args = args(opts.table, config.key_len, from, to, amount);
+            inflight.push_back(client.invoke(NAME, &args));
+            issued += 1;
+        }
+
+        match inflight.pop_front().map(|resp| resp.wait()) {
+            Some(Ok(ref resp)) if resp.len() == 1 && resp[0] >= 1 && resp[0] <= 6 => {
+                counts[resp[0] as usize - 1] += 1;
+            }
+            Some(Err(Error::Status(s))) if s == RpcStatus::StatusInvalidExtension as u8 => {
+                fail("The transfer() extension is not loaded for the tenant");
+            }
+            _ => errors += 1,
+        }
+    }
+
+    for (status, count) in STATUSES.iter().zip(counts.iter()) {
+        println!("Transfers {}: {}", status, count);
+    }
+    println!("Transfers that failed at the client or server: {}", errors);
+
+    let after = total(&client, &opts, config.key_len, accounts);
+    println!("Total balance of {} accounts after: {}", accounts, after);
+    if after != before {
+        fail(&format!("Balances do not add up: {} before, {} after", before, after));
+    }
+}
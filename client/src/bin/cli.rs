@@ -0,0 +1,798 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+
+//! A command line tool for poking at a running server without writing a Rust program.
+//!
+//! Data operations (get, put, del and invoke) are sent over a raw socket on one of the machine's
+//! network interfaces, and so require root. Administrative operations (install-ext, tables,
+//! export-table, import-table, stats, queues, profile, slow, memory, config, health, members,
+//! replica, migrate, partition, backup, audit and tenant) are sent over TCP to the server's
+//! `install_addr`.
+//!
+//! Keys, values and arguments are taken as is, or as hex if they start with "0x". Values are
+//! printed as is if they are printable, and as hex otherwise.
+
+extern crate db;
+extern crate futures;
+extern crate splinter_client;
+
+use std::env;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::mem::{size_of, transmute};
+use std::net::{Ipv4Addr, Shutdown, TcpStream};
+use std::process;
+
+use db::backend::SocketBackend;
+use db::config::ClientConfig;
+use db::harness;
+use db::table::{ACCESS_INVOKE, ACCESS_READ, ACCESS_WRITE};
+use db::wireformat::{BackupAction, ConfigAction, InstallRequest, MigrateAction, OpCode, PartitionAction,
+                     ReplicaAction, RpcRequestHeader, RpcResponseHeader, RpcStatus,
+                     ProfileAction, Service, SnapshotAction, TenantAction};
+
+use futures::Future;
+
+use splinter_client::{Client, Error};
+
+const USAGE: &str = "Usage: splinter-cli [options] <command> [arguments]
+
+Options:
+    --config <path>    Client config with the server's address (default: client.toml)
+    --iface <name>     Network interface data operations are sent on (default: eth0)
+    --queue <id>       Identifier of the socket, also used as it's UDP port (default: 0)
+    --tenant <id>      Tenant operations are issued on behalf of (default: 1)
+
+Commands:
+    get <table> <key>              Look up a key
+    put <table> <key> <value>      Insert a key-value pair
+    del <table> <key>              Remove a key-value pair
+    invoke <name> [args]           Invoke an installed extension
+    install-ext <name> <path>      Install the extension in a .so file under a name
+    tables                         List the tenant's tables and the number of objects in each
+    export-table <cred> <table> <path>
+                                   Write every object of the table to a snapshot file at path
+                                   under the server's snapshot_dir, replacing it
+    import-table <cred> <table> <path>
+                                   Write every object in the snapshot file at path under the
+                                   server's snapshot_dir to the table, once the whole file
+                                   checks out
+    stats                          Count the tenants, tables and objects at the server, the
+                                   packets handled and dropped by dispatchers and NIC ports, and
+                                   the gets, misses, puts and bytes moved on every table
+    queues                         Print the task running and the tasks waiting on every core
+    profile start [hz]             Start sampling the server's stacks, so many times per second
+                                   of CPU time (default: the server's)
+    profile stop                   Stop sampling, and print the stacks folded for flamegraph.pl
+    slow [since]                   Print the requests that took longer than the server's slow
+                                   request threshold, starting at a sequence number (default: 0)
+    memory                         Print the memory held by the heap, packet pools, tenants and
+                                   tables, and how fragmented each tenant's arena is
+    config get                     Print the config the server runs with
+    config set <cred> <field=value>...
+                                   Change fields of the running config, such as tx_batch=16 or
+                                   log_levels='"db=debug"', with values written as in TOML.
+                                   Takes the operator's credential
+    health                         Check that every core is making progress and that the NIC
+                                   links and memory are fit to serve requests. Exits with 1 if
+                                   the server is not ready
+    members                        Print whether the server hears heartbeats from every other
+                                   member of the cluster, and when it last did
+    replica status                 Print the server's role, how far writes were shipped to and
+                                   applied at it's backup and read replicas, how far behind it
+                                   is as a read replica, or it's state in the Raft group
+    replica promote <cred>         Turn a standby into a primary that accepts writes. The
+                                   credential is the peer_credential or the operator's
+    migrate start <cred> <addr>    Move the tenant to the server whose install_addr is addr,
+                                   which must already have the tenant and share the server's
+                                   peer_credential. Requests keep being served while it's
+                                   tables are copied over. Takes the tenant's credential or the
+                                   operator's
+    migrate status <cred>          Print how far the migration running on the server got
+    partition show                 Print the tenant's routing table: the key every range of
+                                   it's keys starts at, and the server holding it
+    partition split <cred> <key> <ip>
+                                   Split the range key falls in at key, handing the part from
+                                   key onwards to the server taking requests on ip
+    partition merge <cred> <key>   Merge the range starting at key into the one before it. Both
+                                   must be held by the same server
+    partition assign <cred> <key> <ip>
+                                   Hand the range starting at key to the server taking requests
+                                   on ip. Objects in the range are not moved. Changes take the
+                                   tenant's credential or the operator's
+    backup start <cred> [full]     Back up every table to the server's object store, only what
+                                   changed unless full is given or a full backup is due. Takes
+                                   the operator's credential
+    backup status <cred>           Print how the last backup or restore went. Takes the
+                                   tenant's credential or the operator's
+    backup restore <cred> [id]     Restore a backup (default: the last one) into the tenants
+                                   at the server. Takes the operator's credential
+    tenant create <cred> <quota> <memory> [tables...]
+                                   Create the tenant with a credential, the number of bytes an
+                                   invocation may allocate (0 for the default), the number of
+                                   bytes it's objects may take up (0 for no limit) and it's
+                                   tables. The quotas can only tighten those of it's tier
+    tenant suspend|resume|delete <cred>
+                                   Suspend, resume or delete the tenant. Every action takes the
+                                   tenant's credential or the operator's
+    tenant drain <cred>            Wait for the tenant's requests in flight, then unload it's
+                                   extensions and remove it along with it's tables
+    tenant grant <cred> <table> <tenant> <access>
+                                   Share one of the tenant's tables with another tenant. Access
+                                   is any of r (read), w (write) and i (invoke), or - to revoke
+    tenant publish|withdraw <cred> <table>
+                                   Mount one of the tenant's tables read-only into every other
+                                   tenant, or unmount it
+    tenant key <cred> <key>        Replace the key the tenant's values are encrypted with at
+                                   rest with 32 bytes (0x followed by hex), or - to remove it
+    audit <cred> [since]           Print the tenant's audit records, starting at a sequence
+                                   number (default: 0). Tenant 0 holds the operator's records,
+                                   and takes the operator's credential";
+
+/// Options that apply to every command.
+struct Options {
+    // Path of the client config.
+    config: String,
+
+    // The network interface data operations are sent out on.
+    iface: String,
+
+    // Identifier for the socket data operations are sent out on.
+    queue: i32,
+
+    // The tenant operations are issued on behalf of.
+    tenant: u32,
+}
+
+// Prints an error along with the usage, and exits.
+fn usage(msg: &str) -> ! {
+    eprintln!("{}\n\n{}", msg, USAGE);
+    process::exit(2);
+}
+
+// Prints an error and exits.
+fn fail(msg: &str) -> ! {
+    eprintln!("{}", msg);
+    process::exit(1);
+}
+
+// Parses a number passed in on the command line.
+fn number<N: std::str::FromStr>(what: &str, arg: &str) -> N {
+    arg.parse()
+        .unwrap_or_else(|_| usage(&format!("Invalid {} \"{}\"", what, arg)))
+}
+
+// Converts a key, value or argument passed in on the command line into bytes. Arguments starting
+// with "0x" are read as hex.
+fn bytes(arg: &str) -> Vec<u8> {
+    if !arg.starts_with("0x") {
+        return arg.as_bytes().to_vec();
+    }
+
+    let hex = &arg[2..];
+    if hex.len() % 2 != 0 {
+        usage(&format!("Odd number of hex digits in \"{}\"", arg));
+    }
+
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .unwrap_or_else(|| usage(&format!("Invalid hex \"{}\"", arg)))
+        })
+        .collect()
+}
+
+// Formats a value for printing; as is if it is printable, as hex otherwise.
+fn printable(val: &[u8]) -> String {
+    match std::str::from_utf8(val) {
+        Ok(s) if s.chars().all(|c| !c.is_control() || c == '\n' || c == '\t') => s.to_string(),
+
+        _ => {
+            let hex: Vec<String> = val.iter().map(|b| format!("{:02x}", b)).collect();
+            format!("0x{}", hex.concat())
+        }
+    }
+}
+
+// Returns a readable name for the raw value of an RpcStatus.
+fn status_name(status: u8) -> String {
+    let name = match status {
+        s if s == RpcStatus::StatusOk as u8 => "ok",
+        s if s == RpcStatus::StatusTenantDoesNotExist as u8 => "tenant does not exist",
+        s if s == RpcStatus::StatusTableDoesNotExist as u8 => "table does not exist",
+        s if s == RpcStatus::StatusObjectDoesNotExist as u8 => "key does not exist",
+        s if s == RpcStatus::StatusMalformedRequest as u8 => "malformed request",
+        s if s == RpcStatus::StatusInternalError as u8 => "internal error",
+        s if s == RpcStatus::StatusInvalidExtension as u8 => "extension does not exist",
+        s if s == RpcStatus::StatusInvalidOperation as u8 => "invalid operation",
+        s if s == RpcStatus::StatusRateLimited as u8 => "rate limited",
+        s if s == RpcStatus::StatusTenantSuspended as u8 => "tenant is suspended",
+        s if s == RpcStatus::StatusPermissionDenied as u8 => "permission denied",
+        s if s == RpcStatus::StatusMemoryExhausted as u8 => "memory quota exhausted",
+        s if s == RpcStatus::StatusAllocExhausted as u8 => "allocation quota exhausted",
+        s if s == RpcStatus::StatusTenantBusy as u8 => "tenant has requests in flight",
+        s if s == RpcStatus::StatusTooManyExtensions as u8 => "too many extensions installed",
+        s if s == RpcStatus::StatusExtensionsTooLarge as u8 => "extensions too large",
+        s if s == RpcStatus::StatusNotPrimary as u8 => "server is a standby",
+        s if s == RpcStatus::StatusNotLeader as u8 => "server is not the raft leader",
+        s if s == RpcStatus::StatusTenantMoved as u8 => "tenant moved to another server",
+        s if s == RpcStatus::StatusWrongPartition as u8 => "key is held by another server",
+        s if s == RpcStatus::StatusDraining as u8 => "server is shutting down",
+        s if s == RpcStatus::StatusValueTooLarge as u8 => "value too large for a response",
+        s if s == RpcStatus::StatusKeyTooLong as u8 => "key too long",
+        s if s == RpcStatus::StatusDeadlineExceeded as u8 => "deadline exceeded",
+        _ => return format!("status {}", status),
+    };
+
+    name.to_string()
+}
+
+// Formats the error an operation failed with.
+fn describe(err: Error) -> String {
+    match err {
+        Error::Status(status) => format!("Failed: {}", status_name(status)),
+        err => format!("Failed: {}", err),
+    }
+}
+
+// Opens a client for data operations.
+fn client(opts: &Options, config: &ClientConfig) -> Client<SocketBackend> {
+    // Packets are allocated from DPDK's pool, even though no NIC is bound to DPDK.
+    harness::init();
+
+    let port = SocketBackend::new(&opts.iface, opts.queue)
+        .unwrap_or_else(|err| fail(&format!("Failed to open a socket on {}: {}", opts.iface, err)));
+
+    Client::new(config, port, opts.tenant)
+}
+
+// Sends an RPC to the server's install() TCP endpoint, and returns the payload of it's response.
+fn admin(config: &ClientConfig, req: &[u8]) -> Vec<u8> {
+    let connect = |addr: &str| -> std::io::Result<Vec<u8>> {
+        let mut stream = TcpStream::connect(addr)?;
+        stream.write_all(req)?;
+        stream.flush()?;
+        stream.shutdown(Shutdown::Write)?;
+
+        let mut res = Vec::new();
+        stream.read_to_end(&mut res)?;
+        Ok(res)
+    };
+
+    let res = connect(&config.install_addr).unwrap_or_else(|err| {
+        fail(&format!("Failed to reach the server at {}: {}", config.install_addr, err))
+    });
+
+    if res.len() < size_of::<RpcResponseHeader>() {
+        fail("Failed: truncated response");
+    }
+
+    // Some failures come with a description of what went wrong.
+    if res[0] != RpcStatus::StatusOk as u8 {
+        let detail = String::from_utf8_lossy(&res[size_of::<RpcResponseHeader>()..]);
+        fail(format!("Failed: {}\n{}", status_name(res[0]), detail).trim_right());
+    }
+
+    res[size_of::<RpcResponseHeader>()..].to_vec()
+}
+
+// Builds a request for an administrative RPC that carries nothing besides it's header.
+fn admin_request(op: OpCode, tenant: u32) -> Vec<u8> {
+    let hdr = RpcRequestHeader::new(Service::MasterService, op, tenant, 0);
+    let hdr: [u8; size_of::<RpcRequestHeader>()] = unsafe { transmute(hdr) };
+    hdr.to_vec()
+}
+
+// Appends the credential an administrative request carries, as a little endian u64.
+fn push_credential(req: &mut Vec<u8>, arg: &str) {
+    let credential: u64 = number("credential", arg);
+    for byte in 0..8 {
+        req.push((credential >> (8 * byte)) as u8);
+    }
+}
+
+// Builds a tenant() request that carries an action and the tenant's credential, followed by
+// little endian u32s and u64s as the action needs.
+fn tenant_request(
+    tenant: u32,
+    action: TenantAction,
+    credential: u64,
+    args: &[(u64, usize)],
+) -> Vec<u8> {
+    let mut req = admin_request(OpCode::SandstormTenantRpc, tenant);
+    req.push(action as u8);
+    for &(val, len) in [(credential, 8)].iter().chain(args.iter()) {
+        for byte in 0..len {
+            req.push((val >> (8 * byte)) as u8);
+        }
+    }
+    req
+}
+
+// Reads the little endian u64s off an administrative RPC's response.
+fn words(payload: &[u8]) -> Vec<u64> {
+    payload
+        .chunks(8)
+        .filter(|word| word.len() == 8)
+        .map(|word| {
+            word.iter()
+                .rev()
+                .fold(0u64, |acc, byte| (acc << 8) | *byte as u64)
+        })
+        .collect()
+}
+
+// Executes a command.
+fn run(opts: &Options, config: &ClientConfig, cmd: &str, args: &[String]) {
+    let expect = |n: usize| {
+        if args.len() != n {
+            usage(&format!("{} takes {} arguments", cmd, n));
+        }
+    };
+
+    match cmd {
+        "get" => {
+            expect(2);
+            let table = number("table", &args[0]);
+            match client(opts, config).get(table, &bytes(&args[1])).wait() {
+                Ok(val) => println!("{}", printable(&val)),
+                Err(err) => fail(&describe(err)),
+            }
+        }
+
+        "put" => {
+            expect(3);
+            let table = number("table", &args[0]);
+            let client = client(opts, config);
+            if let Err(err) = client.put(table, &bytes(&args[1]), &bytes(&args[2])).wait() {
+                fail(&describe(err));
+            }
+        }
+
+        "del" => {
+            expect(2);
+            let table = number("table", &args[0]);
+            if let Err(err) = client(opts, config).delete(table, &bytes(&args[1])).wait() {
+                fail(&describe(err));
+            }
+        }
+
+        "invoke" => {
+            if args.len() < 1 || args.len() > 2 {
+                usage("invoke takes a name, and optionally arguments");
+            }
+
+            let params = args.get(1).map_or(Vec::new(), |arg| bytes(arg));
+            match client(opts, config).invoke(&args[0], &params).wait() {
+                Ok(resp) => println!("{}", printable(&resp)),
+                Err(err) => fail(&describe(err)),
+            }
+        }
+
+        "install-ext" => {
+            expect(2);
+            let mut extn = Vec::new();
+            File::open(&args[1])
+                .and_then(|mut file| file.read_to_end(&mut extn))
+                .unwrap_or_else(|err| fail(&format!("Failed to read {}: {}", args[1], err)));
+
+            let name = args[0].as_bytes();
+            let hdr = InstallRequest::new(opts.tenant, name.len() as u32, extn.len() as u32, 0);
+            let hdr: [u8; size_of::<InstallRequest>()] = unsafe { transmute(hdr) };
+
+            let mut req = Vec::with_capacity(hdr.len() + name.len() + extn.len());
+            req.extend_from_slice(&hdr);
+            req.extend_from_slice(name);
+            req.append(&mut extn);
+            admin(config, &req);
+        }
+
+        "tables" => {
+            expect(0);
+            let req = admin_request(OpCode::SandstormTablesRpc, opts.tenant);
+            let res = words(&admin(config, &req));
+            println!("{:>20} {:>20}", "table", "objects");
+            for table in res.chunks(2).filter(|table| table.len() == 2) {
+                println!("{:>20} {:>20}", table[0], table[1]);
+            }
+        }
+
+        "export-table" | "import-table" => {
+            expect(3);
+            let mut req = admin_request(OpCode::SandstormSnapshotRpc, opts.tenant);
+            req.push(match cmd {
+                "export-table" => SnapshotAction::Export as u8,
+                _ => SnapshotAction::Import as u8,
+            });
+            push_credential(&mut req, &args[0]);
+
+            let table: u64 = number("table", &args[1]);
+            for byte in 0..8 {
+                req.push((table >> (8 * byte)) as u8);
+            }
+            req.extend_from_slice(args[2].as_bytes());
+
+            print!("{}", String::from_utf8_lossy(&admin(config, &req)));
+        }
+
+        "stats" => {
+            expect(0);
+            let req = admin_request(OpCode::SandstormStatsRpc, opts.tenant);
+            let res = words(&admin(config, &req));
+            if res.len() < 11 || res.len() < 11 + 8 * res[10] as usize {
+                fail("Failed: truncated response");
+            }
+
+            println!("tenants {}\ntables  {}\nobjects {}", res[0], res[1], res[2]);
+            println!(
+                "\nrx packets {}\ntx packets {}\nparse errors {}\ninvalid requests {}\n\
+                 mbufs exhausted {}\nauth failures {}\ndeadlines expired {}",
+                res[3], res[4], res[5], res[6], res[7], res[8], res[9]
+            );
+
+            let ports = res[10] as usize;
+            if ports > 0 {
+                println!(
+                    "\n{:>6} {:>14} {:>14} {:>16} {:>16} {:>10} {:>10} {:>10} {:>10}",
+                    "port", "rx", "tx", "rx bytes", "tx bytes", "missed", "rx errors",
+                    "tx errors", "no mbuf"
+                );
+                for (port, r) in res[11..11 + 8 * ports].chunks(8).enumerate() {
+                    println!(
+                        "{:>6} {:>14} {:>14} {:>16} {:>16} {:>10} {:>10} {:>10} {:>10}",
+                        port, r[0], r[1], r[2], r[3], r[4], r[5], r[6], r[7]
+                    );
+                }
+            }
+
+            let tables = &res[11 + 8 * ports..];
+            let records: Vec<&[u64]> = tables.chunks(7).filter(|r| r.len() == 7).collect();
+            if records.len() > 0 {
+                println!(
+                    "\n{:>10} {:>20} {:>12} {:>12} {:>12} {:>14} {:>14}",
+                    "tenant", "table", "gets", "misses", "puts", "read", "written"
+                );
+                for r in records {
+                    println!(
+                        "{:>10} {:>20} {:>12} {:>12} {:>12} {:>14} {:>14}",
+                        r[0], r[1], r[2], r[3], r[4], r[5], r[6]
+                    );
+                }
+            }
+        }
+
+        "queues" => {
+            expect(0);
+            let req = admin_request(OpCode::SandstormQueuesRpc, opts.tenant);
+            print!("{}", String::from_utf8_lossy(&admin(config, &req)));
+        }
+
+        "profile" => {
+            let mut req = admin_request(OpCode::SandstormProfileRpc, opts.tenant);
+            match args.get(0).map(|action| action.as_str()) {
+                Some("start") if args.len() <= 2 => {
+                    let hz: u32 = args.get(1).map_or(0, |hz| number("rate", hz));
+                    req.push(ProfileAction::Start as u8);
+                    for byte in 0..4 {
+                        req.push((hz >> (8 * byte)) as u8);
+                    }
+                }
+
+                Some("stop") if args.len() == 1 => req.push(ProfileAction::Stop as u8),
+
+                _ => usage("profile takes start and an optional rate, or stop"),
+            }
+
+            print!("{}", String::from_utf8_lossy(&admin(config, &req)));
+        }
+
+        "slow" => {
+            if args.len() > 1 {
+                usage("slow takes an optional sequence number");
+            }
+
+            let mut req = admin_request(OpCode::SandstormSlowRpc, opts.tenant);
+            let since: u64 = args.get(0).map_or(0, |since| number("sequence number", since));
+            for byte in 0..8 {
+                req.push((since >> (8 * byte)) as u8);
+            }
+
+            print!("{}", String::from_utf8_lossy(&admin(config, &req)));
+        }
+
+        "memory" => {
+            expect(0);
+            let req = admin_request(OpCode::SandstormMemoryRpc, opts.tenant);
+            print!("{}", String::from_utf8_lossy(&admin(config, &req)));
+        }
+
+        "config" => {
+            let mut req = admin_request(OpCode::SandstormConfigRpc, opts.tenant);
+            match args.get(0).map(|action| action.as_str()) {
+                Some("get") if args.len() == 1 => req.push(ConfigAction::Get as u8),
+
+                Some("set") if args.len() > 2 => {
+                    req.push(ConfigAction::Set as u8);
+                    push_credential(&mut req, &args[1]);
+                    req.extend_from_slice(args[2..].join("\n").as_bytes());
+                }
+
+                _ => usage("config takes get, or set, a credential and one or more field=value \
+                            changes"),
+            }
+
+            print!("{}", String::from_utf8_lossy(&admin(config, &req)));
+        }
+
+        "health" => {
+            expect(0);
+            let req = admin_request(OpCode::SandstormHealthRpc, opts.tenant);
+            let report = String::from_utf8_lossy(&admin(config, &req)).into_owned();
+            print!("{}", report);
+            if !report.contains("\"ready\":true") {
+                process::exit(1);
+            }
+        }
+
+        "members" => {
+            expect(0);
+            let req = admin_request(OpCode::SandstormMembersRpc, opts.tenant);
+            print!("{}", String::from_utf8_lossy(&admin(config, &req)));
+        }
+
+        "replica" => {
+            let mut req = admin_request(OpCode::SandstormReplicaRpc, opts.tenant);
+            match args.get(0).map(|action| action.as_str()) {
+                Some("status") if args.len() == 1 => req.push(ReplicaAction::Status as u8),
+                Some("promote") if args.len() == 2 => {
+                    req.push(ReplicaAction::Promote as u8);
+                    let credential: u64 = number("credential", &args[1]);
+                    for byte in 0..8 {
+                        req.push((credential >> (8 * byte)) as u8);
+                    }
+                }
+                _ => usage("replica takes status, or promote and a credential"),
+            }
+
+            print!("{}", String::from_utf8_lossy(&admin(config, &req)));
+        }
+
+        "migrate" => {
+            let mut req = admin_request(OpCode::SandstormMigrateRpc, opts.tenant);
+            match args.get(0).map(|action| action.as_str()) {
+                Some("start") if args.len() == 3 => {
+                    req.push(MigrateAction::Start as u8);
+                    push_credential(&mut req, &args[1]);
+                    req.extend_from_slice(args[2].as_bytes());
+                }
+                Some("status") if args.len() == 2 => {
+                    req.push(MigrateAction::Status as u8);
+                    push_credential(&mut req, &args[1]);
+                }
+                _ => usage("migrate takes start, a credential and a server's install_addr, or \
+                            status and a credential"),
+            }
+
+            print!("{}", String::from_utf8_lossy(&admin(config, &req)));
+        }
+
+        "backup" => {
+            let mut req = admin_request(OpCode::SandstormBackupRpc, opts.tenant);
+            match (args.get(0).map(|action| action.as_str()), args.len()) {
+                (Some("start"), 2) | (Some("start"), 3) => {
+                    let full = match args.get(2).map(|arg| arg.as_str()) {
+                        None => 0,
+                        Some("full") => 1,
+                        Some(_) => usage("backup start takes a credential and optionally full"),
+                    };
+                    req.push(BackupAction::Start as u8);
+                    push_credential(&mut req, &args[1]);
+                    req.push(full);
+                }
+                (Some("status"), 2) => {
+                    req.push(BackupAction::Status as u8);
+                    push_credential(&mut req, &args[1]);
+                }
+                (Some("restore"), 2) | (Some("restore"), 3) => {
+                    let id: u64 = args.get(2).map_or(0, |id| number("backup", id));
+                    req.push(BackupAction::Restore as u8);
+                    push_credential(&mut req, &args[1]);
+                    for byte in 0..8 {
+                        req.push((id >> (8 * byte)) as u8);
+                    }
+                }
+                _ => usage("backup takes start, status or restore, a credential, and then full \
+                            or a backup's id if any"),
+            }
+
+            print!("{}", String::from_utf8_lossy(&admin(config, &req)));
+        }
+
+        "partition" => {
+            let mut req = admin_request(OpCode::SandstormPartitionRpc, opts.tenant);
+            let ip = |arg: &String| match arg.parse::<Ipv4Addr>() {
+                Ok(ip) => ip.octets(),
+                Err(_) => usage(&format!("{} is not an IPv4 address", arg)),
+            };
+
+            match args.get(0).map(|action| action.as_str()) {
+                Some("show") if args.len() == 1 => req.push(PartitionAction::Show as u8),
+                Some("split") if args.len() == 4 => {
+                    req.push(PartitionAction::Split as u8);
+                    push_credential(&mut req, &args[1]);
+                    req.extend_from_slice(&ip(&args[3]));
+                    req.extend_from_slice(&bytes(&args[2]));
+                }
+                Some("merge") if args.len() == 3 => {
+                    req.push(PartitionAction::Merge as u8);
+                    push_credential(&mut req, &args[1]);
+                    req.extend_from_slice(&bytes(&args[2]));
+                }
+                Some("assign") if args.len() == 4 => {
+                    req.push(PartitionAction::Assign as u8);
+                    push_credential(&mut req, &args[1]);
+                    req.extend_from_slice(&ip(&args[3]));
+                    req.extend_from_slice(&bytes(&args[2]));
+                }
+                _ => usage("partition takes show, or split, merge or assign and a credential"),
+            }
+
+            print!("{}", String::from_utf8_lossy(&admin(config, &req)));
+        }
+
+        "audit" => {
+            if args.len() < 1 || args.len() > 2 {
+                usage("audit takes a credential and an optional sequence number");
+            }
+
+            let mut req = admin_request(OpCode::SandstormAuditRpc, opts.tenant);
+            let credential: u64 = number("credential", &args[0]);
+            let since: u64 = args.get(1).map_or(0, |since| number("sequence number", since));
+            for word in [credential, since].iter() {
+                for byte in 0..8 {
+                    req.push((word >> (8 * byte)) as u8);
+                }
+            }
+
+            print!("{}", String::from_utf8_lossy(&admin(config, &req)));
+        }
+
+        "tenant" => {
+            if args.len() < 2 {
+                usage("tenant takes an action and a credential");
+            }
+
+            let credential = number("credential", &args[1]);
+            let req = match args[0].as_str() {
+                "create" => {
+                    if args.len() < 4 {
+                        usage("tenant create takes a credential, quota, memory limit and tables");
+                    }
+
+                    let mut words = vec![(number("quota", &args[2]), 8)];
+                    words.push((number("memory limit", &args[3]), 8));
+                    words.push(((args.len() - 4) as u64, 4));
+                    for table in args[4..].iter() {
+                        words.push((number("table", table), 8));
+                    }
+                    tenant_request(opts.tenant, TenantAction::Create, credential, &words)
+                }
+
+                "grant" => {
+                    expect(5);
+                    let mut access = 0u64;
+                    for flag in args[4].chars() {
+                        access |= match flag {
+                            'r' => ACCESS_READ as u64,
+                            'w' => ACCESS_WRITE as u64,
+                            'i' => ACCESS_INVOKE as u64,
+                            '-' => 0,
+                            _ => usage(&format!("Invalid access \"{}\"", args[4])),
+                        };
+                    }
+
+                    let words = vec![
+                        (number("table", &args[2]), 8),
+                        (number::<u64>("tenant", &args[3]), 4),
+                        (access, 1),
+                    ];
+                    tenant_request(opts.tenant, TenantAction::Grant, credential, &words)
+                }
+
+                "publish" | "withdraw" => {
+                    expect(3);
+                    let words = vec![
+                        (number("table", &args[2]), 8),
+                        ((args[0] == "publish") as u64, 1),
+                    ];
+                    tenant_request(opts.tenant, TenantAction::Publish, credential, &words)
+                }
+
+                "key" => {
+                    expect(3);
+                    let mut req = tenant_request(opts.tenant, TenantAction::Key, credential, &[]);
+                    if args[2] != "-" {
+                        let key = bytes(&args[2]);
+                        if key.len() != 32 {
+                            usage("tenant key takes a key of 32 bytes, or - to remove it");
+                        }
+                        req.extend_from_slice(&key);
+                    }
+                    req
+                }
+
+                action => {
+                    expect(2);
+                    let action = match action {
+                        "suspend" => TenantAction::Suspend,
+                        "resume" => TenantAction::Resume,
+                        "delete" => TenantAction::Delete,
+                        "drain" => TenantAction::Drain,
+                        _ => usage(&format!("Unknown tenant action \"{}\"", action)),
+                    };
+                    tenant_request(opts.tenant, action, credential, &[])
+                }
+            };
+            let res = words(&admin(config, &req));
+            if args[0] == "drain" && res.len() >= 3 {
+                println!("extensions {}\ntables     {}\nobjects    {}", res[0], res[1], res[2]);
+            }
+        }
+
+        _ => usage(&format!("Unknown command \"{}\"", cmd)),
+    }
+}
+
+fn main() {
+    let mut opts = Options {
+        config: String::from("client.toml"),
+        iface: String::from("eth0"),
+        queue: 0,
+        tenant: 1,
+    };
+
+    // Options come first, followed by the command and it's arguments.
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    while args.len() > 0 && args[0].starts_with("--") {
+        if args.len() < 2 {
+            usage(&format!("Missing value for {}", args[0]));
+        }
+
+        let val = args.remove(1);
+        match args.remove(0).as_str() {
+            "--config" => opts.config = val,
+            "--iface" => opts.iface = val,
+            "--queue" => opts.queue = number("queue", &val),
+            "--tenant" => opts.tenant = number("tenant", &val),
+            opt => usage(&format!("Unknown option {}", opt)),
+        }
+    }
+
+    if args.len() == 0 {
+        usage("Missing command");
+    }
+
+    let config = ClientConfig::load_from(&opts.config);
+    if let Err(err) = config.validate() {
+        fail(&format!("{}", err));
+    }
+
+    let cmd = args.remove(0);
+    run(&opts, &config, &cmd, &args);
+}
@@ -0,0 +1,263 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+
+use std::collections::{BTreeMap, HashMap};
+
+// Identifies a cached value: the tenant, table, and key it was read for.
+type CacheKey = (u32, u64, Vec<u8>);
+
+// A value read from the server.
+struct Entry {
+    // The value.
+    val: Vec<u8>,
+
+    // The time-stamp in cycles after which the value is no longer served.
+    expires: u64,
+
+    // The position of the entry in the recency order. Larger is more recent.
+    tick: u64,
+}
+
+/// A look-aside cache of values recently read from the server, evicted least recently used
+/// first once it holds `capacity` values. Values expire `ttl` cycles after they were read, which
+/// bounds how stale a value can get when the key is written by someone else.
+///
+/// The cache knows nothing of writes; callers invalidate keys they write to. Every invalidation
+/// bumps an epoch, and a value is only filled in if no invalidation happened since the read was
+/// issued, so that a read racing with a write cannot put back the old value.
+pub struct Cache {
+    // The maximum number of values held.
+    capacity: usize,
+
+    // The time in cycles a value is served for after being filled in.
+    ttl: u64,
+
+    // The values, keyed by what they were read for.
+    entries: HashMap<CacheKey, Entry>,
+
+    // The keys of the entries, ordered from least to most recently used.
+    order: BTreeMap<u64, CacheKey>,
+
+    // The tick assigned to the next entry that is filled in or used.
+    tick: u64,
+
+    // The number of invalidations so far.
+    epoch: u64,
+
+    // The number of lookups that found a value, and that did not.
+    hits: u64,
+    misses: u64,
+}
+
+// Implementation of methods on Cache.
+impl Cache {
+    /// Creates an empty cache.
+    ///
+    /// # Arguments
+    ///
+    /// * `capacity`: The maximum number of values held. Zero disables the cache.
+    /// * `ttl`:      The time in cycles a value is served for after being filled in.
+    pub fn new(capacity: usize, ttl: u64) -> Cache {
+        Cache {
+            capacity: capacity,
+            ttl: ttl,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            epoch: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Returns true if the cache holds any values at all.
+    pub fn enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Returns the number of invalidations so far. Passed back into `fill()`.
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Returns the number of lookups that found a value, and the number that did not.
+    pub fn stats(&self) -> (u64, u64) {
+        (self.hits, self.misses)
+    }
+
+    /// Looks up a value, and marks it as the most recently used one.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant`: The tenant the value was read for.
+    /// * `table`:  The table the value was read from.
+    /// * `key`:    The key the value was read for.
+    /// * `now`:    The current time-stamp in cycles.
+    ///
+    /// # Return
+    ///
+    /// A copy of the value, or None if it is not cached or expired.
+    pub fn get(&mut self, tenant: u32, table: u64, key: &[u8], now: u64) -> Option<Vec<u8>> {
+        if !self.enabled() {
+            return None;
+        }
+
+        let ckey = (tenant, table, key.to_vec());
+        let (expired, tick) = match self.entries.get(&ckey) {
+            Some(entry) => (entry.expires <= now, entry.tick),
+            None => {
+                self.misses += 1;
+                return None;
+            }
+        };
+
+        self.order.remove(&tick);
+        if expired {
+            self.entries.remove(&ckey);
+            self.misses += 1;
+            return None;
+        }
+
+        let tick = self.next_tick();
+        self.order.insert(tick, ckey.clone());
+
+        let entry = self.entries.get_mut(&ckey).unwrap();
+        entry.tick = tick;
+        self.hits += 1;
+        Some(entry.val.clone())
+    }
+
+    /// Fills in a value read from the server, evicting the least recently used value if the
+    /// cache is full.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant`: The tenant the value was read for.
+    /// * `table`:  The table the value was read from.
+    /// * `key`:    The key the value was read for.
+    /// * `val`:    The value.
+    /// * `epoch`:  The value of `epoch()` when the read was issued. The value is not filled in
+    ///             if anything was invalidated since.
+    /// * `now`:    The current time-stamp in cycles.
+    pub fn fill(&mut self, tenant: u32, table: u64, key: &[u8], val: &[u8], epoch: u64, now: u64) {
+        if !self.enabled() || epoch != self.epoch {
+            return;
+        }
+
+        let ckey = (tenant, table, key.to_vec());
+        if let Some(entry) = self.entries.remove(&ckey) {
+            self.order.remove(&entry.tick);
+        }
+
+        while self.entries.len() >= self.capacity {
+            let oldest = match self.order.keys().next() {
+                Some(tick) => *tick,
+                None => break,
+            };
+            let victim = self.order.remove(&oldest).unwrap();
+            self.entries.remove(&victim);
+        }
+
+        let tick = self.next_tick();
+        self.order.insert(tick, ckey.clone());
+        self.entries.insert(
+            ckey,
+            Entry {
+                val: val.to_vec(),
+                expires: now + self.ttl,
+                tick: tick,
+            },
+        );
+    }
+
+    /// Drops the value cached for a key, if any.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant`: The tenant the value was read for.
+    /// * `table`:  The table the value was read from.
+    /// * `key`:    The key the value was read for.
+    pub fn invalidate(&mut self, tenant: u32, table: u64, key: &[u8]) {
+        if !self.enabled() {
+            return;
+        }
+
+        self.epoch += 1;
+        if let Some(entry) = self.entries.remove(&(tenant, table, key.to_vec())) {
+            self.order.remove(&entry.tick);
+        }
+    }
+
+    // Returns the next tick in the recency order.
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Cache;
+
+    #[test]
+    fn lru() {
+        let mut cache = Cache::new(2, 100);
+        cache.fill(1, 1, b"a", b"1", 0, 0);
+        cache.fill(1, 1, b"b", b"2", 0, 0);
+
+        // Using `a` makes `b` the least recently used value.
+        assert_eq!(Some(b"1".to_vec()), cache.get(1, 1, b"a", 10));
+        cache.fill(1, 1, b"c", b"3", 0, 10);
+
+        assert_eq!(None, cache.get(1, 1, b"b", 10));
+        assert_eq!(Some(b"1".to_vec()), cache.get(1, 1, b"a", 10));
+        assert_eq!(Some(b"3".to_vec()), cache.get(1, 1, b"c", 10));
+        assert_eq!(None, cache.get(2, 1, b"a", 10));
+        assert_eq!((3, 2), cache.stats());
+    }
+
+    #[test]
+    fn ttl() {
+        let mut cache = Cache::new(4, 100);
+        cache.fill(1, 1, b"a", b"1", 0, 50);
+        assert_eq!(Some(b"1".to_vec()), cache.get(1, 1, b"a", 149));
+        assert_eq!(None, cache.get(1, 1, b"a", 150));
+    }
+
+    #[test]
+    fn invalidate() {
+        let mut cache = Cache::new(4, 100);
+        cache.fill(1, 1, b"a", b"1", 0, 0);
+
+        // A read issued before the invalidation must not fill in it's value after it.
+        let epoch = cache.epoch();
+        cache.invalidate(1, 1, b"a");
+        assert_eq!(None, cache.get(1, 1, b"a", 0));
+        cache.fill(1, 1, b"a", b"1", epoch, 0);
+        assert_eq!(None, cache.get(1, 1, b"a", 0));
+
+        let epoch = cache.epoch();
+        cache.fill(1, 1, b"a", b"2", epoch, 0);
+        assert_eq!(Some(b"2".to_vec()), cache.get(1, 1, b"a", 0));
+    }
+
+    #[test]
+    fn disabled() {
+        let mut cache = Cache::new(0, 100);
+        cache.fill(1, 1, b"a", b"1", 0, 0);
+        assert_eq!(None, cache.get(1, 1, b"a", 0));
+    }
+}
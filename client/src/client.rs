@@ -0,0 +1,1249 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::net::Ipv4Addr;
+use std::ptr;
+use std::rc::Rc;
+use std::str::FromStr;
+
+use db::backend::NetBackend;
+use db::config::{ClientConfig, PortRangeConfig, ServerEndpointConfig};
+use db::cycles;
+use db::e2d2::headers::*;
+use db::e2d2::interface::*;
+use db::e2d2::common::EmptyMetadata;
+use db::e2d2::native::zcsi::mbuf_free;
+use db::multiop;
+use db::partition;
+use db::rpc;
+use db::wireformat::{MultiOpResponse, Reader, RpcResponseHeader, RpcStatus, MAX_REQUEST_PRIORITY};
+
+use futures::{task, Async, Future, Poll};
+
+use super::cache::Cache;
+use super::error::Error;
+use super::op::{Headers, Op};
+use super::retry::RetryPolicy;
+use super::route::{Router, ServerStats};
+
+/// The time a request is given to complete before it is retried, if none was configured.
+const DEFAULT_TIMEOUT_US: u64 = 1000;
+
+/// The time a cached value is served for, if none was configured.
+const DEFAULT_CACHE_TTL_US: u64 = 10000;
+
+/// The maximum number of responses received from the network in one shot.
+const RX_BURST: usize = 32;
+
+/// The maximum number of payload bytes on a multiop() request built by batching operations.
+/// Keeps requests, and the responses to batched gets on small values, within a single frame.
+const MAX_BATCH_BYTES: usize = 1024;
+
+// Operations waiting to be sent out together on a multiop() request.
+struct Batch {
+    // The RPC identifiers of the operations, in the order they were added.
+    ids: Vec<u64>,
+
+    // The payload of the multiop() request.
+    ops: Vec<u8>,
+
+    // The time-stamp in cycles at which the first operation was added.
+    opened: u64,
+}
+
+// A multiop() request that has been sent out and is waiting on a response.
+struct Multi {
+    // The RPC identifiers of the operations on the request, in order.
+    ids: Vec<u64>,
+
+    // The server the request was sent to.
+    server: usize,
+
+    // The time-stamp in cycles after which the operations on the request are retried or failed.
+    deadline: u64,
+}
+
+// An operation that has not completed yet.
+struct Pending {
+    // The operation, kept around to rebuild the request on a retry.
+    op: Op,
+
+    // The tenant the operation was issued on behalf of.
+    tenant: u32,
+
+    // The server the operation is sent to.
+    server: usize,
+
+    // The policy deciding whether and when the request is retried.
+    policy: Rc<RetryPolicy>,
+
+    // The time-stamp in cycles after which the request is retried or failed, or after which it
+    // is sent out again if it is backing off.
+    deadline: u64,
+
+    // The number of times the request was sent out so far.
+    attempts: u32,
+
+    // True if the request is waiting out a delay before being sent out again, rather than
+    // waiting on a response.
+    backoff: bool,
+
+    // True if the operation is part of a batch, in which case it is retried or failed along
+    // with the multiop() request carrying it rather than on it's own deadline.
+    batched: bool,
+
+    // The cache's epoch when the operation was issued.
+    epoch: u64,
+
+    // The number of milliseconds behind the primary a read replica can answer the operation at,
+    // if it can be answered by one.
+    staleness: Option<u32>,
+
+    // The time-stamp in cycles by which the operation must complete, or zero if it has no
+    // deadline. Every request sent out for it carries the time left.
+    expires: u64,
+
+    // The priority every request sent out for the operation carries within the tenant's share.
+    priority: u8,
+}
+
+// The state of a Client, shared with the futures of operations issued through it.
+struct Inner<T>
+where
+    T: NetBackend,
+{
+    // The network queue requests are sent and responses are received on.
+    port: T,
+
+    // The tenant operations are issued on behalf of unless another one is specified.
+    tenant: u32,
+
+    // The network headers on requests to each server, by index.
+    hdrs: Vec<Headers>,
+
+    // Maps operations to servers, and tracks which servers are responding.
+    router: Router,
+
+    // The number of destination UDP ports a request can be sent to.
+    dst_ports: u16,
+
+    // The ranges of UDP ports reserved for tenants at the server, keyed by tenant.
+    ranges: HashMap<u32, PortRangeConfig>,
+
+    // The RPC identifier assigned to the next operation.
+    next_id: u64,
+
+    // The total number of requests sent so far, including retries.
+    sent: u64,
+
+    // The time in cycles a request is given to complete before being retried.
+    timeout: u64,
+
+    // The state of the generator used to jitter retry delays.
+    rand: u64,
+
+    // The maximum number of operations batched onto one multiop() request. Batching is
+    // disabled if less than 2.
+    batch_ops: usize,
+
+    // The time in cycles an operation waits for others to be batched with.
+    batch_delay: u64,
+
+    // Batches that have not been sent out yet, keyed by tenant and server.
+    batches: HashMap<(u32, usize), Batch>,
+
+    // multiop() requests waiting on responses, keyed by RPC identifier.
+    multis: HashMap<u64, Multi>,
+
+    // Values recently read from the server.
+    cache: Cache,
+
+    // Operations waiting on responses, keyed by RPC identifier.
+    pending: HashMap<u64, Pending>,
+
+    // Results of operations whose futures have not picked them up yet.
+    done: HashMap<u64, Result<Vec<u8>, Error>>,
+}
+
+/// A client of a Splinter server. Operations return futures that resolve once the server
+/// responds. RPC identifiers, timeouts, and retries are taken care of by the client.
+///
+/// A client owns a single network queue, and is meant to be used from the thread that created
+/// it. Futures resolve by driving the client's queue whenever they are polled, so they can be
+/// waited on with `Future::wait()` or combined with other futures without any reactor. A client
+/// can also be driven explicitly by calling `poll()`.
+///
+/// Every operation is retried according to the client's RetryPolicy. `with_policy()` returns
+/// another client on the same queue whose operations are retried according to a different one.
+///
+/// Requests are spread across the servers in the config's `servers`, by consistent hashing on
+/// keys or by a static map of tables to servers. Operations routed to a server that stopped
+/// responding fail with `Error::Unavailable` until the server is tried again. Servers can be
+/// added and removed while the client is running, with `add_server()` and `remove_server()`.
+///
+/// If `cache_entries` is configured, gets are served out of a cache of recently read values
+/// where possible. A put through the client invalidates the key it writes to; writes by other
+/// clients or by extensions are only seen once the cached value expires.
+///
+/// `with_staleness()` returns a client whose gets and invokes can be answered by a read replica
+/// out of data that is behind the primary's by upto a bound. Such a client is meant to be
+/// configured with read replicas as it's servers; a replica that is further behind than the
+/// bound refuses operations with StatusNotPrimary, as it does writes.
+///
+/// `with_deadline()` returns a client whose operations must complete within a budget of time
+/// from being issued, retries included. Every request carries the time left, so that the server
+/// does not start work on it once the client has given up. Operations that run out of time fail
+/// with `Error::DeadlineExceeded`.
+///
+/// `with_priority()` returns a client whose operations run ahead of the tenant's other waiting
+/// requests at a lower priority, so that interactive requests are not stuck behind background
+/// work issued on behalf of the same tenant. Requests of other tenants are not affected.
+pub struct Client<T>
+where
+    T: NetBackend,
+{
+    inner: Rc<RefCell<Inner<T>>>,
+
+    // The policy operations issued through this client are retried according to.
+    policy: Rc<RetryPolicy>,
+
+    // The number of milliseconds behind the primary gets and invokes issued through this client
+    // can be answered at, None if they must be answered by the primary.
+    staleness: Option<u32>,
+
+    // The number of microseconds operations issued through this client have to complete in,
+    // None if they have no deadline.
+    budget_us: Option<u64>,
+
+    // The priority of operations issued through this client within the tenant's share of the
+    // server, zero by default.
+    priority: u8,
+}
+
+// Implementation of methods on Client.
+impl<T> Client<T>
+where
+    T: NetBackend,
+{
+    /// Creates a client.
+    ///
+    /// # Arguments
+    ///
+    /// * `config`: Network related configuration such as the MAC and IP address of the client
+    ///             and server, along with timeouts.
+    /// * `port`:   The network queue requests will be sent and responses received on. The
+    ///             client's UDP source port is the queue's identifier.
+    /// * `tenant`: The tenant operations are issued on behalf of.
+    ///
+    /// # Return
+    ///
+    /// A client that can issue operations to the server in the config.
+    pub fn new(config: &ClientConfig, port: T, tenant: u32) -> Client<T> {
+        let hdrs: Vec<Headers> = config
+            .endpoints()
+            .iter()
+            .map(|server| headers(config, server, port.txq() as u16))
+            .collect();
+
+        let timeout_us = if config.timeout_us > 0 {
+            config.timeout_us
+        } else {
+            DEFAULT_TIMEOUT_US
+        };
+
+        let cache_ttl_us = if config.cache_ttl_us > 0 {
+            config.cache_ttl_us
+        } else {
+            DEFAULT_CACHE_TTL_US
+        };
+
+        let mut ranges = HashMap::new();
+        for range in config.tenant_ports.iter() {
+            for tenant in range.tenants.iter() {
+                ranges.entry(*tenant).or_insert(range.clone());
+            }
+        }
+
+        let inner = Inner {
+            port: port,
+            tenant: tenant,
+            hdrs: hdrs,
+            router: Router::from_config(config),
+            dst_ports: config.server_udp_ports,
+            ranges: ranges,
+            next_id: 1,
+            sent: 0,
+            timeout: timeout_us * cycles::cycles_per_second() / 1000000,
+            rand: cycles::rdtsc() | 1,
+            batch_ops: config.batch_ops,
+            batch_delay: config.batch_delay_us * cycles::cycles_per_second() / 1000000,
+            batches: HashMap::new(),
+            multis: HashMap::new(),
+            cache: Cache::new(
+                config.cache_entries,
+                cache_ttl_us * cycles::cycles_per_second() / 1000000,
+            ),
+            pending: HashMap::new(),
+            done: HashMap::new(),
+        };
+
+        Client {
+            inner: Rc::new(RefCell::new(inner)),
+            policy: Rc::new(RetryPolicy::from_config(config)),
+            staleness: None,
+            budget_us: None,
+            priority: 0,
+        }
+    }
+
+    /// Returns a client on the same network queue that retries operations according to a
+    /// different policy. Operations issued through either client are driven by the other.
+    ///
+    /// # Arguments
+    ///
+    /// * `policy`: The policy operations issued through the returned client are retried by.
+    ///
+    /// # Return
+    ///
+    /// A client sharing this client's queue and pending requests.
+    pub fn with_policy(&self, policy: RetryPolicy) -> Client<T> {
+        Client {
+            inner: Rc::clone(&self.inner),
+            policy: Rc::new(policy),
+            staleness: self.staleness,
+            budget_us: self.budget_us,
+            priority: self.priority,
+        }
+    }
+
+    /// Returns a client on the same network queue whose gets and invokes can be answered by a
+    /// read replica, out of data that is behind the primary's. Operations issued through either
+    /// client are driven by the other.
+    ///
+    /// # Arguments
+    ///
+    /// * `bound_ms`: The number of milliseconds behind the primary the returned client's gets
+    ///               and invokes can be answered at.
+    ///
+    /// # Return
+    ///
+    /// A client sharing this client's queue, pending requests, and retry policy.
+    pub fn with_staleness(&self, bound_ms: u32) -> Client<T> {
+        Client {
+            inner: Rc::clone(&self.inner),
+            policy: Rc::clone(&self.policy),
+            staleness: Some(bound_ms),
+            budget_us: self.budget_us,
+            priority: self.priority,
+        }
+    }
+
+    /// Returns a client on the same network queue whose operations must complete within a
+    /// budget of time from being issued. Operations issued through either client are driven by
+    /// the other.
+    ///
+    /// # Arguments
+    ///
+    /// * `budget_us`: The number of microseconds the returned client's operations have to
+    ///                complete in, retries included.
+    ///
+    /// # Return
+    ///
+    /// A client sharing this client's queue, pending requests, retry policy, and staleness
+    /// bound.
+    pub fn with_deadline(&self, budget_us: u64) -> Client<T> {
+        Client {
+            inner: Rc::clone(&self.inner),
+            policy: Rc::clone(&self.policy),
+            staleness: self.staleness,
+            budget_us: Some(budget_us),
+            priority: self.priority,
+        }
+    }
+
+    /// Returns a client on the same network queue whose operations run ahead of the tenant's
+    /// other requests waiting at the server at a lower priority. Operations issued through
+    /// either client are driven by the other.
+    ///
+    /// # Arguments
+    ///
+    /// * `level`: The priority of the returned client's operations, from zero, the default,
+    ///            upto `MAX_REQUEST_PRIORITY`. Higher levels are clamped to it.
+    ///
+    /// # Return
+    ///
+    /// A client sharing this client's queue, pending requests, retry policy, staleness bound,
+    /// and deadline.
+    pub fn with_priority(&self, level: u8) -> Client<T> {
+        Client {
+            inner: Rc::clone(&self.inner),
+            policy: Rc::clone(&self.policy),
+            staleness: self.staleness,
+            budget_us: self.budget_us,
+            priority: level.min(MAX_REQUEST_PRIORITY),
+        }
+    }
+
+    /// Returns the policy operations issued through this client are retried according to.
+    pub fn policy(&self) -> &RetryPolicy {
+        &self.policy
+    }
+
+    /// Looks up a key.
+    ///
+    /// # Arguments
+    ///
+    /// * `table`: Id of the table from which the key is looked up.
+    /// * `key`:   Byte string of key whose value is to be fetched. Limit 64 KB.
+    ///
+    /// # Return
+    ///
+    /// A future resolving to the key's value.
+    pub fn get(&self, table: u64, key: &[u8]) -> Response<T> {
+        self.issue(Op::Get {
+            table: table,
+            key: key.to_vec(),
+        })
+    }
+
+    /// Inserts a key-value pair.
+    ///
+    /// # Arguments
+    ///
+    /// * `table`: Id of the table into which the key-value pair is to be inserted.
+    /// * `key`:   Byte string of key whose value is to be inserted. Limit 64 KB.
+    /// * `val`:   Byte string of the value to be inserted.
+    ///
+    /// # Return
+    ///
+    /// A future resolving to an empty value once the pair was inserted.
+    pub fn put(&self, table: u64, key: &[u8], val: &[u8]) -> Response<T> {
+        self.issue(Op::Put {
+            table: table,
+            key: key.to_vec(),
+            val: val.to_vec(),
+        })
+    }
+
+    /// Removes a key-value pair.
+    ///
+    /// # Arguments
+    ///
+    /// * `table`: Id of the table from which the key-value pair is to be removed.
+    /// * `key`:   Byte string of key to be removed. Limit 64 KB.
+    ///
+    /// # Return
+    ///
+    /// A future resolving to an empty value once the pair was removed. Fails with
+    /// StatusObjectDoesNotExist if there was no such key.
+    pub fn delete(&self, table: u64, key: &[u8]) -> Response<T> {
+        self.issue(Op::Delete {
+            table: table,
+            key: key.to_vec(),
+        })
+    }
+
+    /// Invokes an extension installed at the server.
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: The name of the extension.
+    /// * `args`: The arguments to the extension.
+    ///
+    /// # Return
+    ///
+    /// A future resolving to whatever the extension wrote into it's response, or to
+    /// `Error::Pushback` if the extension pushed the invocation back to the client, which an
+    /// `Executor` can resume it from.
+    pub fn invoke(&self, name: &str, args: &[u8]) -> Response<T> {
+        let mut payload = Vec::with_capacity(name.len() + args.len());
+        payload.extend_from_slice(name.as_bytes());
+        payload.extend_from_slice(args);
+
+        self.issue(Op::Invoke {
+            name_len: name.len() as u32,
+            payload: payload,
+        })
+    }
+
+    /// Receives responses from the network, and retries or fails operations that timed out.
+    /// Called whenever a future returned by the client is polled, so this only needs to be
+    /// called explicitly if the client is not being driven through it's futures.
+    pub fn poll(&self) {
+        self.inner.borrow_mut().drive();
+    }
+
+    /// Returns the number of operations waiting on responses from the server.
+    pub fn outstanding(&self) -> usize {
+        self.inner.borrow().pending.len()
+    }
+
+    /// Drops the cached value of a key, if any. Puts through the client already do this; this
+    /// is for keys known to have been written some other way, for instance by an extension.
+    ///
+    /// # Arguments
+    ///
+    /// * `table`: Id of the table the key belongs to.
+    /// * `key`:   Byte string of the key.
+    pub fn invalidate(&self, table: u64, key: &[u8]) {
+        let mut inner = self.inner.borrow_mut();
+        let tenant = inner.tenant;
+        inner.cache.invalidate(tenant, table, key);
+    }
+
+    /// Returns whether each server, in the order they are listed in the config and then added,
+    /// can currently be sent requests.
+    pub fn servers_up(&self) -> Vec<bool> {
+        let inner = self.inner.borrow();
+        let now = cycles::rdtsc();
+        (0..inner.router.servers())
+            .map(|server| inner.router.available(server, now))
+            .collect()
+    }
+
+    /// Starts routing requests to another server. Keys move to it only from the arcs of the
+    /// hash ring it takes over, so the rest keep going to the same servers.
+    ///
+    /// # Arguments
+    ///
+    /// * `config`: The config the client was created with, for the client's own addresses.
+    /// * `server`: The server.
+    ///
+    /// # Return
+    ///
+    /// The index of the server, for `remove_server()` and `server_stats()`.
+    pub fn add_server(&self, config: &ClientConfig, server: &ServerEndpointConfig) -> usize {
+        let mut inner = self.inner.borrow_mut();
+        let hdrs = headers(config, server, inner.port.txq() as u16);
+        inner.hdrs.push(hdrs);
+        inner.router.add(server)
+    }
+
+    /// Stops routing requests to a server. It's keys move to the servers after it on the hash
+    /// ring. Responses to requests already sent to it are still picked up, and requests to it
+    /// that are retried are routed afresh.
+    ///
+    /// # Arguments
+    ///
+    /// * `server`: The index of the server, in the order servers were listed in the config and
+    ///             then added.
+    ///
+    /// # Return
+    ///
+    /// False if there is no such server, or if it is the last one left.
+    pub fn remove_server(&self, server: usize) -> bool {
+        self.inner.borrow_mut().router.remove(server)
+    }
+
+    /// Returns the number of operations routed to, responses from, and timeouts on each server,
+    /// along with it's share of the hash ring.
+    pub fn server_stats(&self) -> Vec<ServerStats> {
+        self.inner.borrow().router.stats(cycles::rdtsc())
+    }
+
+    /// Returns the number of gets that were served from the cache, and the number that were
+    /// sent to the server.
+    pub fn cache_stats(&self) -> (u64, u64) {
+        self.inner.borrow().cache.stats()
+    }
+
+    // Issues an operation on behalf of the client's tenant, and returns a future for it's result.
+    fn issue(&self, op: Op) -> Response<T> {
+        let tenant = self.inner.borrow().tenant;
+        let id = self.issue_as(tenant, op);
+
+        Response {
+            inner: Rc::clone(&self.inner),
+            id: id,
+        }
+    }
+
+    /// Assigns an identifier to an operation and sends it out.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant`: The tenant the operation is issued on behalf of.
+    /// * `op`:     The operation.
+    ///
+    /// # Return
+    ///
+    /// The identifier of the operation. It's result can be picked up with `take()`.
+    pub(crate) fn issue_as(&self, tenant: u32, op: Op) -> u64 {
+        let mut inner = self.inner.borrow_mut();
+        let id = inner.next_id;
+        inner.next_id += 1;
+
+        // Gets are served out of the cache where possible, and puts and deletes make sure that
+        // the cache does not serve the key's old value anymore.
+        match op {
+            Op::Get { table, ref key } => {
+                let hit = inner.cache.get(tenant, table, key, cycles::rdtsc());
+                if let Some(val) = hit {
+                    inner.done.insert(id, Ok(val));
+                    return id;
+                }
+            }
+
+            Op::Put { table, ref key, .. } | Op::Delete { table, ref key } => {
+                inner.cache.invalidate(tenant, table, key)
+            }
+
+            Op::Invoke { .. } => {}
+        }
+        let epoch = inner.cache.epoch();
+
+        // Fail right away if the server the operation routes to is down.
+        let server = inner.route(tenant, &op);
+        if !inner.router.available(server, cycles::rdtsc()) {
+            inner.done.insert(id, Err(Error::Unavailable));
+            return id;
+        }
+        inner.router.routed(server);
+
+        let now = cycles::rdtsc();
+        let expires = self.budget_us
+            .map_or(0, |us| now + us * cycles::cycles_per_second() / 1000000);
+
+        // Small gets and puts are held back to be sent out with others on a multiop() request.
+        // Read replicas do not answer multiop()s, so gets that can go to one are not. Neither
+        // are operations with a deadline or priority, which a multiop() would have to share.
+        let batchable = inner.batch_ops > 1
+            && self.staleness.is_none()
+            && expires == 0
+            && self.priority == 0;
+        let batched = match op.batch_len() {
+            Some(len) if batchable && len <= MAX_BATCH_BYTES => {
+                // Send out the batch first if the operation would not fit on it.
+                let fits = inner
+                    .batches
+                    .get(&(tenant, server))
+                    .map_or(true, |batch| batch.ops.len() + len <= MAX_BATCH_BYTES);
+                if !fits {
+                    inner.flush(tenant, server);
+                }
+
+                let now = cycles::rdtsc();
+                let batch = inner.batches.entry((tenant, server)).or_insert_with(|| Batch {
+                    ids: Vec::new(),
+                    ops: Vec::new(),
+                    opened: now,
+                });
+                batch.ids.push(id);
+                op.batch(&mut batch.ops)
+            }
+
+            _ => false,
+        };
+
+        let deadline = if batched {
+            u64::max_value()
+        } else {
+            let sent = inner.send(&op, self.staleness, expires, self.priority, tenant, server, id);
+            if let Err(err) = sent {
+                inner.done.insert(id, Err(err));
+                return id;
+            }
+
+            inner.attempt_deadline(expires)
+        };
+
+        inner.pending.insert(
+            id,
+            Pending {
+                op: op,
+                tenant: tenant,
+                server: server,
+                policy: Rc::clone(&self.policy),
+                deadline: deadline,
+                attempts: 1,
+                backoff: false,
+                batched: batched,
+                epoch: epoch,
+                staleness: self.staleness,
+                expires: expires,
+                priority: self.priority,
+            },
+        );
+
+        // Send the batch out once it is full.
+        if batched {
+            let full = {
+                let batch = &inner.batches[&(tenant, server)];
+                batch.ids.len() >= inner.batch_ops
+            };
+            if full {
+                inner.flush(tenant, server);
+            }
+        }
+
+        return id;
+    }
+
+    /// Picks up the result of an operation issued with `issue_as()`, without driving the client.
+    ///
+    /// # Arguments
+    ///
+    /// * `id`: The identifier of the operation.
+    ///
+    /// # Return
+    ///
+    /// The result, or None if the operation has not completed yet.
+    pub(crate) fn take(&self, id: u64) -> Option<Result<Vec<u8>, Error>> {
+        self.inner.borrow_mut().done.remove(&id)
+    }
+}
+
+// Implementation of methods on Inner.
+impl<T> Inner<T>
+where
+    T: NetBackend,
+{
+    // Builds and sends out the request for an operation. Gets and invokes with a staleness
+    // bound carry it, operations with a deadline carry the time left before it, and every
+    // request carries the operation's priority. A request the network queue did not accept, or
+    // that a packet could not be allocated for, is dropped, and will be retried once it times
+    // out. An operation whose request can never be built, such as one too long for a single
+    // datagram, fails with the status the server would have answered it with.
+    fn send(
+        &mut self,
+        op: &Op,
+        staleness: Option<u32>,
+        expires: u64,
+        priority: u8,
+        tenant: u32,
+        server: usize,
+        id: u64,
+    ) -> Result<(), Error> {
+        let dst = self.dst_port(tenant);
+        let mut request = match op.request(&self.hdrs[server], tenant, id, dst) {
+            Ok(request) => request,
+            Err(err) => {
+                return match err.status() {
+                    RpcStatus::StatusInternalError => Ok(()),
+                    status => Err(Error::Status(status as u8)),
+                };
+            }
+        };
+        if priority > 0 {
+            rpc::set_rpc_priority(&mut request, priority);
+        }
+        let request = match (op, staleness) {
+            (&Op::Get { .. }, Some(bound)) | (&Op::Invoke { .. }, Some(bound)) => {
+                rpc::add_rpc_staleness(request, bound)
+            }
+            _ => request,
+        };
+        let request = match expires {
+            0 => request,
+            _ => {
+                let left = expires.saturating_sub(cycles::rdtsc());
+                let us = left * 1000000 / cycles::cycles_per_second();
+                rpc::add_rpc_deadline(request, us.min(u32::max_value() as u64) as u32)
+            }
+        };
+        self.transmit(request);
+
+        Ok(())
+    }
+
+    // Returns when an attempt at an operation sent out now times out: after the client's
+    // timeout, or at the operation's deadline if that comes first.
+    fn attempt_deadline(&self, expires: u64) -> u64 {
+        let timeout = cycles::rdtsc() + self.timeout;
+        match expires {
+            0 => timeout,
+            _ => timeout.min(expires),
+        }
+    }
+
+    // Returns the server an operation should be sent to. Operations of a tenant that was
+    // migrated go to the server it moved to, and those on keys of a partitioned tenant to the
+    // server holding the key.
+    fn route(&self, tenant: u32, op: &Op) -> usize {
+        if let Some(server) = self.router.relocated(tenant) {
+            return server;
+        }
+
+        match *op {
+            Op::Get { table, ref key }
+            | Op::Put { table, ref key, .. }
+            | Op::Delete { table, ref key } => self.router
+                .partitioned(tenant, key)
+                .unwrap_or_else(|| self.router.route(table, key)),
+
+            Op::Invoke { .. } => self.router.route_tenant(tenant),
+        }
+    }
+
+    // Sends out a batch of operations. A batch with a single operation in it is sent out as a
+    // regular request.
+    fn flush(&mut self, tenant: u32, server: usize) {
+        let batch = match self.batches.remove(&(tenant, server)) {
+            Some(batch) => batch,
+            None => return,
+        };
+
+        let now = cycles::rdtsc();
+        if batch.ids.len() == 1 {
+            let id = batch.ids[0];
+            if let Some(mut pending) = self.pending.remove(&id) {
+                let (staleness, expires) = (pending.staleness, pending.expires);
+                let sent = self.send(
+                    &pending.op,
+                    staleness,
+                    expires,
+                    pending.priority,
+                    tenant,
+                    server,
+                    id,
+                );
+                if let Err(err) = sent {
+                    self.complete(id, &pending, Err(err));
+                    return;
+                }
+
+                pending.batched = false;
+                pending.deadline = now + self.timeout;
+                self.pending.insert(id, pending);
+            }
+            return;
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let dst = self.dst_port(tenant);
+        let request = {
+            let hdrs = &self.hdrs[server];
+            rpc::create_multiop_rpc(
+                &hdrs.mac,
+                &hdrs.ip,
+                &hdrs.udp,
+                tenant,
+                batch.ids.len() as u32,
+                &batch.ops,
+                id,
+                dst,
+            )
+        };
+
+        // A batch that a packet could not be allocated for is retried once it times out.
+        if let Ok(request) = request {
+            self.transmit(request);
+        }
+
+        self.multis.insert(
+            id,
+            Multi {
+                ids: batch.ids,
+                server: server,
+                deadline: now + self.timeout,
+            },
+        );
+    }
+
+    // Hands a request to the network queue, dropping it if the queue did not accept it.
+    fn transmit(&mut self, request: Packet<IpHeader, EmptyMetadata>) {
+        let mut pkts = [unsafe { request.get_mbuf() }];
+        match self.port.tx_burst(&mut pkts) {
+            Ok(1) => {}
+            _ => unsafe { mbuf_free(pkts[0]) },
+        }
+
+        self.sent += 1;
+    }
+
+    // Computes the destination UDP port of the next request from a tenant.
+    fn dst_port(&self, tenant: u32) -> u16 {
+        if let Some(range) = self.ranges.get(&tenant) {
+            return range.port(self.sent);
+        }
+
+        (tenant & 0xffff) as u16 & (self.dst_ports - 1)
+    }
+
+    // Returns the next number from a xorshift generator, used to jitter retry delays.
+    fn next_rand(&mut self) -> u64 {
+        self.rand ^= self.rand << 13;
+        self.rand ^= self.rand >> 7;
+        self.rand ^= self.rand << 17;
+        self.rand
+    }
+
+    // Completes an operation, keeping the cache coherent with it. A get fills in the value it
+    // read, and a put or delete invalidates the key again so that gets that raced with it are not
+    // filled in.
+    fn complete(&mut self, id: u64, pending: &Pending, result: Result<Vec<u8>, Error>) {
+        match (&pending.op, &result) {
+            (&Op::Get { table, ref key }, &Ok(ref val)) => {
+                let now = cycles::rdtsc();
+                self.cache
+                    .fill(pending.tenant, table, key, val, pending.epoch, now);
+            }
+
+            (&Op::Put { table, ref key, .. }, _) | (&Op::Delete { table, ref key }, _) => {
+                self.cache.invalidate(pending.tenant, table, key);
+            }
+
+            _ => {}
+        }
+
+        self.done.insert(id, result);
+    }
+
+    // Decides what to do with an operation that failed an attempt. The operation is either put
+    // back to wait out a delay before it's next attempt, or completed with the error.
+    fn retry_or_fail(&mut self, id: u64, mut pending: Pending, status: Option<u8>, err: Error) {
+        // The operation ran out of time, at the client or at the server. It is not worth
+        // another attempt.
+        let expired = pending.expires != 0 && cycles::rdtsc() >= pending.expires;
+        if expired || status == Some(RpcStatus::StatusDeadlineExceeded as u8) {
+            self.complete(id, &pending, Err(Error::DeadlineExceeded));
+            return;
+        }
+
+        // The tenant was migrated or the key is held elsewhere, the next attempt goes to where the
+        // server said it is.
+        let moved = status == Some(RpcStatus::StatusTenantMoved as u8)
+            || status == Some(RpcStatus::StatusWrongPartition as u8);
+        if moved {
+            pending.server = self.route(pending.tenant, &pending.op);
+        }
+
+        let idempotent = pending.op.idempotent();
+        if !pending
+            .policy
+            .should_retry(pending.attempts, idempotent, status)
+        {
+            self.complete(id, &pending, Err(err));
+            return;
+        }
+
+        let rand = self.next_rand();
+        let delay = pending.policy.delay_us(pending.attempts, rand);
+        pending.backoff = true;
+        pending.deadline = cycles::rdtsc() + delay * cycles::cycles_per_second() / 1000000;
+        self.pending.insert(id, pending);
+    }
+
+    // Completes the operations on a multiop() request whose response has arrived. Operations
+    // the server did not get to are sent out again on their own.
+    fn complete_multi(&mut self, multi: Multi, payload: &[u8]) {
+        let hdr = size_of::<MultiOpResponse>();
+        let status = payload[0];
+        if status != RpcStatus::StatusOk as u8 || payload.len() < hdr {
+            for id in multi.ids {
+                if let Some(mut pending) = self.pending.remove(&id) {
+                    pending.batched = false;
+                    self.retry_or_fail(id, pending, Some(status), Error::Status(status));
+                }
+            }
+            return;
+        }
+
+        let mut results = &payload[hdr..];
+        for id in multi.ids {
+            let result = multiop::parse_result(results);
+            if let Some((_, _, len)) = result {
+                results = &results[len..];
+            }
+
+            let mut pending = match self.pending.remove(&id) {
+                Some(pending) => pending,
+                None => continue,
+            };
+            pending.batched = false;
+
+            match result {
+                Some((status, _, _)) if status != RpcStatus::StatusOk as u8 => {
+                    self.retry_or_fail(id, pending, Some(status), Error::Status(status));
+                }
+
+                Some((_, val, _)) => {
+                    self.complete(id, &pending, Ok(val.to_vec()));
+                }
+
+                // Not executed at the server. Send it out right away on it's own.
+                None => {
+                    pending.backoff = true;
+                    pending.deadline = 0;
+                    self.pending.insert(id, pending);
+                }
+            }
+        }
+    }
+
+    // Learns where a tenant's operations should go from a response saying they were sent to the
+    // wrong server: the tenant was migrated away, and the IP address of the server it moved to
+    // follows the header, or the key is held by another server, and the tenant's routing table
+    // follows the header. If the client does not route to that server, the tenant's operations
+    // fail once out of retries.
+    fn reroute(&mut self, payload: &[u8]) {
+        let hdr = size_of::<RpcResponseHeader>();
+        let tenant = match Reader::at(payload, 2).u32_le() {
+            Some(tenant) => tenant,
+            None => return,
+        };
+
+        match payload[0] {
+            s if s == RpcStatus::StatusTenantMoved as u8 && payload.len() >= hdr + 4 => {
+                let mut ip = [0u8; 4];
+                ip.copy_from_slice(&payload[hdr..hdr + 4]);
+                self.router.relocate(tenant, ip);
+            }
+
+            s if s == RpcStatus::StatusWrongPartition as u8 => {
+                if let Some(map) = partition::decode(&payload[hdr..]) {
+                    self.router.partition(tenant, &map);
+                }
+            }
+
+            _ => {}
+        }
+    }
+
+    // Completes operations whose responses have arrived, and then retries or fails the ones that
+    // timed out.
+    fn drive(&mut self) {
+        // Send out batches that have waited long enough for operations to join them.
+        let now = cycles::rdtsc();
+        let ready: Vec<(u32, usize)> = self.batches
+            .iter()
+            .filter(|&(_, b)| b.opened + self.batch_delay <= now)
+            .map(|(key, _)| *key)
+            .collect();
+
+        for (tenant, server) in ready {
+            self.flush(tenant, server);
+        }
+
+        let mut mbufs = vec![ptr::null_mut(); RX_BURST];
+        let recvd = self.port.rx_burst(&mut mbufs[..]).unwrap_or(0) as usize;
+
+        for mbuf in mbufs.into_iter().take(recvd) {
+            let packet = unsafe {
+                packet_from_mbuf_no_increment(mbuf, 0)
+                    .parse_header::<MacHeader>()
+                    .parse_header::<IpHeader>()
+                    .parse_header::<UdpHeader>()
+            };
+
+            // Responses to requests that already completed, for instance the response to the
+            // first attempt of a retried request, are simply dropped.
+            {
+                let payload = packet.get_payload();
+                if payload.len() >= size_of::<RpcResponseHeader>() {
+                    let id = Reader::at(payload, 6).u64_le().unwrap_or(0);
+                    self.reroute(payload);
+
+                    if let Some(multi) = self.multis.remove(&id) {
+                        self.router.success(multi.server);
+                        self.complete_multi(multi, payload);
+                    }
+
+                    // Ignore responses to requests that are backing off; they were already
+                    // answered with a status saying they should be retried.
+                    let answered = self.pending
+                        .get(&id)
+                        .map_or(false, |p| !p.backoff && !p.batched);
+                    if answered {
+                        let pending = self.pending.remove(&id).unwrap();
+                        self.router.success(pending.server);
+                        match pending.op.response(payload) {
+                            Err(Error::Status(status)) => {
+                                self.retry_or_fail(id, pending, Some(status), Error::Status(status))
+                            }
+
+                            result => {
+                                self.complete(id, &pending, result);
+                            }
+                        }
+                    }
+                }
+            }
+
+            packet.free_packet();
+        }
+
+        // Retry or fail the operations on multiop() requests that timed out.
+        let now = cycles::rdtsc();
+        let expired: Vec<u64> = self.multis
+            .iter()
+            .filter(|&(_, m)| m.deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in expired {
+            let multi = self.multis.remove(&id).unwrap();
+            self.router.failure(multi.server, now);
+            for id in multi.ids {
+                if let Some(mut pending) = self.pending.remove(&id) {
+                    pending.batched = false;
+                    self.retry_or_fail(id, pending, None, Error::Timeout);
+                }
+            }
+        }
+
+        // Send out operations that are done backing off, and retry or fail ones that timed out.
+        let expired: Vec<u64> = self.pending
+            .iter()
+            .filter(|&(_, p)| p.deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in expired {
+            let mut pending = self.pending.remove(&id).unwrap();
+            if !pending.backoff {
+                self.router.failure(pending.server, now);
+                self.retry_or_fail(id, pending, None, Error::Timeout);
+                continue;
+            }
+
+            // The server could have been removed while the operation was backing off.
+            if !self.router.active(pending.server) {
+                pending.server = self.route(pending.tenant, &pending.op);
+            }
+
+            if !self.router.available(pending.server, now) {
+                self.complete(id, &pending, Err(Error::Unavailable));
+                continue;
+            }
+
+            // Operations that ran out of time while backing off are not sent out again.
+            if pending.expires != 0 && now >= pending.expires {
+                self.complete(id, &pending, Err(Error::DeadlineExceeded));
+                continue;
+            }
+
+            let (tenant, server) = (pending.tenant, pending.server);
+            let (staleness, expires) = (pending.staleness, pending.expires);
+            let sent =
+                self.send(&pending.op, staleness, expires, pending.priority, tenant, server, id);
+            if let Err(err) = sent {
+                self.complete(id, &pending, Err(err));
+                continue;
+            }
+
+            pending.attempts += 1;
+            pending.backoff = false;
+            pending.deadline = self.attempt_deadline(pending.expires);
+            self.pending.insert(id, pending);
+        }
+    }
+}
+
+// Builds the network headers on requests from a client to a server.
+fn headers(config: &ClientConfig, server: &ServerEndpointConfig, src_port: u16) -> Headers {
+    let mut udp: UdpHeader = UdpHeader::new();
+    udp.set_src_port(src_port);
+    udp.set_dst_port(0);
+    udp.set_length(8);
+    udp.set_checksum(0);
+
+    let mut ip: IpHeader = IpHeader::new();
+    ip.set_src(u32::from(
+        Ipv4Addr::from_str(&config.ip_address).expect("Failed to create source IP."),
+    ));
+    ip.set_dst(u32::from(
+        Ipv4Addr::from_str(&server.ip_address).expect("Failed to create destination IP."),
+    ));
+    ip.set_ttl(128);
+    ip.set_version(4);
+    ip.set_ihl(5);
+    ip.set_length(20);
+    ip.set_protocol(0x11);
+
+    let mut mac: MacHeader = MacHeader::new();
+    mac.src = config.parse_mac();
+    mac.dst = server.parse_mac();
+    mac.set_etype(0x0800);
+
+    Headers {
+        mac: mac,
+        ip: ip,
+        udp: udp,
+    }
+}
+
+// Implementation of the Clone trait for Client. Clones share the network queue, pending
+// requests, retry policy, staleness bound, deadline, and priority.
+impl<T> Clone for Client<T>
+where
+    T: NetBackend,
+{
+    fn clone(&self) -> Client<T> {
+        Client {
+            inner: Rc::clone(&self.inner),
+            policy: Rc::clone(&self.policy),
+            staleness: self.staleness,
+            budget_us: self.budget_us,
+            priority: self.priority,
+        }
+    }
+}
+
+/// A future for the result of an operation issued through a Client.
+pub struct Response<T>
+where
+    T: NetBackend,
+{
+    // The state of the client the operation was issued through.
+    inner: Rc<RefCell<Inner<T>>>,
+
+    // The RPC identifier of the operation.
+    id: u64,
+}
+
+// Implementation of the Future trait for Response.
+impl<T> Future for Response<T>
+where
+    T: NetBackend,
+{
+    type Item = Vec<u8>;
+    type Error = Error;
+
+    /// Drives the client the operation was issued through, and returns the operation's result
+    /// if it completed. Otherwise, asks to be polled again right away, since responses only
+    /// arrive by polling the network queue.
+    fn poll(&mut self) -> Poll<Vec<u8>, Error> {
+        let mut inner = self.inner.borrow_mut();
+        inner.drive();
+
+        match inner.done.remove(&self.id) {
+            Some(Ok(value)) => Ok(Async::Ready(value)),
+            Some(Err(err)) => Err(err),
+            None => {
+                task::current().notify();
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+// Implementation of the Drop trait for Response. An operation whose future was dropped is
+// abandoned, and it's response dropped when it arrives.
+impl<T> Drop for Response<T>
+where
+    T: NetBackend,
+{
+    fn drop(&mut self) {
+        let mut inner = self.inner.borrow_mut();
+        inner.pending.remove(&self.id);
+        inner.done.remove(&self.id);
+    }
+}
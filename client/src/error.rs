@@ -0,0 +1,94 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use std::error;
+use std::fmt;
+
+use db::wireformat::RpcStatus;
+
+use sandstorm::db::QuotaExceeded;
+
+/// The reasons an operation issued through a Client can fail.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// The server did not respond, even after the request was retried.
+    Timeout,
+
+    /// The server responded with a status other than StatusOk, or the request could not be built
+    /// for a reason the server would have responded with, such as a request too long for a
+    /// single datagram. The status is the raw value of the server's `RpcStatus`.
+    Status(u8),
+
+    /// The Worker the operation was handed to went away before completing it.
+    Shutdown,
+
+    /// The server the operation routes to is marked down after repeated timeouts.
+    Unavailable,
+
+    /// The operation did not complete before it's deadline. The server may or may not have
+    /// executed it.
+    DeadlineExceeded,
+
+    /// The extension an invocation ran pushed it back to the client (StatusPushback). Holds the
+    /// state the extension captured, which an `Executor` resumes the extension with.
+    Pushback(Vec<u8>),
+}
+
+// Implementation of methods on Error.
+impl Error {
+    /// Returns the quota the server refused the operation for, if it was refused for one.
+    pub fn quota(&self) -> Option<QuotaExceeded> {
+        match *self {
+            Error::Status(s) if s == RpcStatus::StatusMemoryExhausted as u8 => {
+                Some(QuotaExceeded::Memory)
+            }
+            Error::Status(s) if s == RpcStatus::StatusAllocExhausted as u8 => {
+                Some(QuotaExceeded::Allocation)
+            }
+            Error::Status(s) if s == RpcStatus::StatusRateLimited as u8 => {
+                Some(QuotaExceeded::Rate)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Timeout => "Request timed out",
+            Error::Status(_) => "Request failed at the server",
+            Error::Shutdown => "Worker shut down",
+            Error::Unavailable => "Server unavailable",
+            Error::DeadlineExceeded => "Deadline exceeded",
+            Error::Pushback(_) => "Invocation pushed back to the client",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Timeout => write!(f, "Request timed out"),
+            Error::Status(status) => write!(f, "Request failed at the server, status {}", status),
+            Error::Shutdown => write!(f, "Worker shut down before completing the request"),
+            Error::Unavailable => write!(f, "Server is marked down after repeated timeouts"),
+            Error::DeadlineExceeded => write!(f, "Request did not complete before it's deadline"),
+            Error::Pushback(ref state) => {
+                write!(f, "Invocation pushed back to the client, {} bytes of state", state.len())
+            }
+        }
+    }
+}
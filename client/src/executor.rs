@@ -0,0 +1,352 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use db::backend::NetBackend;
+use db::bytes::{BufMut, Bytes, BytesMut};
+use db::ext::Extension;
+use db::wireformat::RpcStatus;
+
+use futures::Future;
+
+use sandstorm::buf::{MultiReadBuf, ReadBuf, WriteBuf};
+use sandstorm::continuation::Step;
+use sandstorm::db::DB;
+
+use super::client::Client;
+use super::error::Error;
+use super::wasm::Module;
+
+/// Runs extensions at the client instead of the server.
+///
+/// An extension is loaded from the same .so file that was installed at the server, or from a
+/// WASM build of the same code, and runs against the server's data: every get() it does is
+/// issued to the server as a regular get RPC, every put() as a regular put RPC, and every del()
+/// as a delete. The extension is resumed until it completes, and whatever it wrote into it's
+/// response is returned.
+///
+/// `invoke()` first hands an invocation to the server, and only runs it at the client if the
+/// extension pushed it back (`StatusPushback`). The extension is then resumed with the state it
+/// captured, which it reads through `DB::resumed`, and carries on from where it was pushed back,
+/// so writes it made at the server are not made again. A call to `DB::pushback` at the client
+/// returns false, and the extension carries on itself.
+pub struct Executor<T>
+where
+    T: NetBackend + 'static,
+{
+    // The client data and invocations are requested through.
+    client: Client<T>,
+
+    // The extensions that were loaded, keyed by the name they are invoked with.
+    extensions: HashMap<String, Loaded>,
+}
+
+// An extension loaded at the client.
+enum Loaded {
+    // Loaded from the .so file installed at the server.
+    Native(Extension),
+
+    // Loaded from a WASM build of the extension.
+    Wasm(Module),
+}
+
+// Implementation of methods on Executor.
+impl<T> Executor<T>
+where
+    T: NetBackend + 'static,
+{
+    /// Creates an executor without any extensions loaded.
+    ///
+    /// # Arguments
+    ///
+    /// * `client`: The client extensions request data through. Invocations are issued on
+    ///             behalf of it's tenant.
+    pub fn new(client: Client<T>) -> Executor<T> {
+        Executor {
+            client: client,
+            extensions: HashMap::new(),
+        }
+    }
+
+    /// Loads an extension that can then be run at the client.
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: The name the extension is invoked with, the same as at the server.
+    /// * `path`: The path (absolute or relative) of the .so file containing the extension, or
+    ///           of the .wasm file containing a WASM build of it.
+    ///
+    /// # Return
+    ///
+    /// True if the extension was loaded.
+    pub fn load(&mut self, name: &str, path: &str) -> bool {
+        let ext = if path.ends_with(".wasm") {
+            Module::load(path).map(Loaded::Wasm)
+        } else {
+            Extension::load(path).map(Loaded::Native)
+        };
+
+        match ext {
+            Some(ext) => {
+                self.extensions.insert(String::from(name), ext);
+                true
+            }
+
+            None => false,
+        }
+    }
+
+    /// Invokes an extension at the server, resuming it at the client if it pushes the
+    /// invocation back.
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: The name of the extension.
+    /// * `args`: The arguments to the extension.
+    ///
+    /// # Return
+    ///
+    /// Whatever the extension wrote into it's response, wherever it ran. `Error::Pushback` if
+    /// the invocation was pushed back, and the extension was not loaded at the client.
+    pub fn invoke(&self, name: &str, args: &[u8]) -> Result<Vec<u8>, Error> {
+        match self.client.invoke(name, args).wait() {
+            Err(Error::Pushback(ref state)) if self.extensions.contains_key(name) => {
+                self.resume(name, args, state)
+            }
+
+            result => result,
+        }
+    }
+
+    /// Runs an extension at the client, from the start.
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: The name of the extension.
+    /// * `args`: The arguments to the extension.
+    ///
+    /// # Return
+    ///
+    /// Whatever the extension wrote into it's response. `StatusInvalidExtension` if the
+    /// extension was not loaded, `StatusInternalError` if a WASM extension trapped, or the
+    /// error of the first request the extension issued that failed for a reason other than a
+    /// missing key.
+    pub fn run(&self, name: &str, args: &[u8]) -> Result<Vec<u8>, Error> {
+        self.execute(name, args, None)
+    }
+
+    /// Resumes an invocation the extension pushed back, at the client.
+    ///
+    /// # Arguments
+    ///
+    /// * `name`:  The name of the extension.
+    /// * `args`:  The arguments the extension was invoked with.
+    /// * `state`: The state the extension captured when it pushed the invocation back, as
+    ///            returned in `Error::Pushback`.
+    ///
+    /// # Return
+    ///
+    /// Whatever the extension wrote into it's response after it was resumed. Errors as for
+    /// `run()`.
+    pub fn resume(&self, name: &str, args: &[u8], state: &[u8]) -> Result<Vec<u8>, Error> {
+        self.execute(name, args, Some(state.to_vec()))
+    }
+
+    // Runs an extension at the client until it completes, resumed with a pushed back
+    // invocation's state if there is one.
+    fn execute(&self, name: &str, args: &[u8], state: Option<Vec<u8>>) -> Result<Vec<u8>, Error> {
+        let ext = match self.extensions.get(name) {
+            Some(ext) => ext,
+            None => return Err(Error::Status(RpcStatus::StatusInvalidExtension as u8)),
+        };
+
+        let remote = Rc::new(Remote {
+            client: self.client.clone(),
+            args: args.to_vec(),
+            resumed: state,
+            resp: RefCell::new(Vec::new()),
+            failed: RefCell::new(None),
+        });
+
+        match *ext {
+            // Resume the extension until it completes; yields only matter to the server's
+            // scheduler.
+            Loaded::Native(ref ext) => {
+                let db: Rc<DB> = Rc::clone(&remote) as Rc<DB>;
+                let mut gen = ext.get(db);
+                loop {
+                    match gen.resume() {
+                        Step::Yield(_) => continue,
+                        Step::Done(_) => break,
+                    }
+                }
+            }
+
+            Loaded::Wasm(ref module) => {
+                if !module.run(&*remote) {
+                    remote.fail(Error::Status(RpcStatus::StatusInternalError as u8));
+                }
+            }
+        }
+
+        if let Some(err) = remote.failed.borrow_mut().take() {
+            return Err(err);
+        }
+
+        let resp = remote.resp.borrow().clone();
+        Ok(resp)
+    }
+}
+
+// The database an extension run at the client sees. Every access is a request to the server,
+// waited on before returning to the extension.
+struct Remote<T>
+where
+    T: NetBackend + 'static,
+{
+    // The client requests are issued through.
+    client: Client<T>,
+
+    // The arguments the extension was invoked with, and the state it is resumed with if the
+    // invocation was pushed back.
+    args: Vec<u8>,
+    resumed: Option<Vec<u8>>,
+
+    // Whatever the extension wrote into it's response so far.
+    resp: RefCell<Vec<u8>>,
+
+    // The error of the first request that failed for a reason other than a missing key.
+    failed: RefCell<Option<Error>>,
+}
+
+// Implementation of methods on Remote.
+impl<T> Remote<T>
+where
+    T: NetBackend + 'static,
+{
+    // Records the first error hit by the extension.
+    fn fail(&self, err: Error) {
+        let mut failed = self.failed.borrow_mut();
+        if failed.is_none() {
+            *failed = Some(err);
+        }
+    }
+
+    // Reads a value from the server. None if the key does not exist or the read failed.
+    fn read(&self, table: u64, key: &[u8]) -> Option<Bytes> {
+        match self.client.get(table, key).wait() {
+            Ok(val) => Some(Bytes::from(val)),
+
+            Err(Error::Status(status)) if status == RpcStatus::StatusObjectDoesNotExist as u8 => {
+                None
+            }
+
+            Err(err) => {
+                self.fail(err);
+                None
+            }
+        }
+    }
+}
+
+// The DB trait for Remote. Allocations are laid out as the key's length (two bytes, little
+// endian), followed by the key, followed by whatever the extension writes into them.
+impl<T> DB for Remote<T>
+where
+    T: NetBackend + 'static,
+{
+    fn get(&self, table: u64, key: &[u8]) -> Option<ReadBuf> {
+        self.read(table, key)
+            .map(|val| unsafe { ReadBuf::new(val) })
+    }
+
+    fn multiget(&self, table: u64, key_len: u16, keys: &[u8]) -> Option<MultiReadBuf> {
+        let mut vals = Vec::new();
+        for key in keys.chunks(key_len as usize) {
+            if key.len() != key_len as usize {
+                break;
+            }
+
+            match self.read(table, key) {
+                Some(val) => vals.push(val),
+                None => return None,
+            }
+        }
+
+        unsafe { Some(MultiReadBuf::new(vals)) }
+    }
+
+    fn alloc(&self, table: u64, key: &[u8], val_len: u64) -> Option<WriteBuf> {
+        // The key's length is written in two bytes, as it is on the wire.
+        if key.len() > u16::max_value() as usize {
+            return None;
+        }
+
+        let mut buf = BytesMut::with_capacity(2 + key.len() + val_len as usize);
+        buf.put_u16_le(key.len() as u16);
+        buf.put_slice(key);
+
+        unsafe { Some(WriteBuf::new(table, buf)) }
+    }
+
+    fn put(&self, buf: WriteBuf) -> bool {
+        let (table, buf) = unsafe { buf.freeze() };
+        let key_len = (buf[0] as usize) | ((buf[1] as usize) << 8);
+        let (key, val) = buf[2..].split_at(key_len);
+
+        match self.client.put(table, key, val).wait() {
+            Ok(_) => true,
+
+            Err(err) => {
+                self.fail(err);
+                false
+            }
+        }
+    }
+
+    fn cas(&self, _buf: WriteBuf, _expected: Option<&[u8]>) -> bool {
+        // No request compares and writes an object atomically, so a compare and swap cannot be
+        // run at the client. Fail the extension rather than race with other writers.
+        self.fail(Error::Status(RpcStatus::StatusInvalidOperation as u8));
+        false
+    }
+
+    fn del(&self, table: u64, key: &[u8]) {
+        // Deleting a key that does not exist is not an error, just like at the server.
+        match self.client.delete(table, key).wait() {
+            Err(Error::Status(status)) if status == RpcStatus::StatusObjectDoesNotExist as u8 => {}
+            Err(err) => self.fail(err),
+            Ok(_) => {}
+        }
+    }
+
+    fn args(&self) -> &[u8] {
+        &self.args
+    }
+
+    fn resp(&self, response: &[u8]) {
+        self.resp.borrow_mut().extend_from_slice(response);
+    }
+
+    fn resumed(&self) -> Option<&[u8]> {
+        self.resumed.as_ref().map(|state| &state[..])
+    }
+
+    fn debug_log(&self, _msg: &str) {}
+}
@@ -0,0 +1,282 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! Builds the arguments to the filter() extension (see `ext/filter`), which scans a range of
+//! keys at the server and returns only the objects matching a predicate, and parses what it
+//! returns. For example, the objects among keys 0 to 999 whose u32 at offset 8 is between 10
+//! and 20, or whose byte at offset 0 is 1:
+//!
+//! ```ignore
+//! let pred = Predicate::cmp(8, 4, Cmp::Ge, 10)
+//!     .and(Predicate::cmp(8, 4, Cmp::Le, 20))
+//!     .or(Predicate::cmp(0, 1, Cmp::Eq, 1));
+//! let resp = client.invoke("filter", &args(1, 30, 0, 1000, 0, &pred)).wait()?;
+//! let scan = Scan::parse(30, &resp);
+//! ```
+
+/// The name the filter() extension is invoked by.
+pub const NAME: &str = "filter";
+
+/// The status the extension responds with once it scanned every key.
+pub const SUCCESSFUL: u8 = 0x01;
+
+/// The status the extension responds with if the arguments or the predicate are malformed.
+pub const INVALIDARG: u8 = 0x02;
+
+/// The status the extension responds with once it stopped at the limit of matching objects.
+pub const MORE: u8 = 0x03;
+
+/// The comparisons a predicate can make between a field and a constant.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Cmp {
+    Eq = 0x01,
+    Ne = 0x02,
+    Lt = 0x03,
+    Le = 0x04,
+    Gt = 0x05,
+    Ge = 0x06,
+}
+
+/// A predicate on the value of an object.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Predicate {
+    /// The field, an unsigned integer `width` (1, 2, 4 or 8) bytes long at `offset` into the
+    /// value read little endian, compares to `constant`. False if the value is too short to hold
+    /// the field.
+    Cmp {
+        offset: u16,
+        width: u8,
+        op: Cmp,
+        constant: u64,
+    },
+
+    /// Both predicates hold.
+    And(Box<Predicate>, Box<Predicate>),
+
+    /// Either predicate holds.
+    Or(Box<Predicate>, Box<Predicate>),
+}
+
+// Implementation of methods on Predicate.
+impl Predicate {
+    /// Returns a comparison between a field of the value and a constant.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset`:   The offset of the field into the value.
+    /// * `width`:    The width of the field in bytes: 1, 2, 4 or 8. The extension refuses any
+    ///               other width.
+    /// * `op`:       The comparison.
+    /// * `constant`: The constant the field is compared to.
+    pub fn cmp(offset: u16, width: u8, op: Cmp, constant: u64) -> Predicate {
+        Predicate::Cmp {
+            offset: offset,
+            width: width,
+            op: op,
+            constant: constant,
+        }
+    }
+
+    /// Returns a predicate holding when both this one and another one hold.
+    pub fn and(self, other: Predicate) -> Predicate {
+        Predicate::And(Box::new(self), Box::new(other))
+    }
+
+    /// Returns a predicate holding when either this one or another one holds.
+    pub fn or(self, other: Predicate) -> Predicate {
+        Predicate::Or(Box::new(self), Box::new(other))
+    }
+
+    /// Appends the predicate to a buffer, in the postfix order the extension evaluates it in.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf`: The buffer.
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        match *self {
+            Predicate::Cmp {
+                offset,
+                width,
+                op,
+                constant,
+            } => {
+                buf.extend_from_slice(&[0x01, op as u8, offset as u8, (offset >> 8) as u8, width]);
+                push_le(buf, constant, 8);
+            }
+
+            Predicate::And(ref left, ref right) => {
+                left.encode(buf);
+                right.encode(buf);
+                buf.push(0x02);
+            }
+
+            Predicate::Or(ref left, ref right) => {
+                left.encode(buf);
+                right.encode(buf);
+                buf.push(0x03);
+            }
+        }
+    }
+}
+
+/// Returns the arguments to the filter() extension.
+///
+/// # Arguments
+///
+/// * `table`:   The table scanned.
+/// * `key_len`: The length of it's keys. Every key is it's number written little endian into it's
+///              first eight bytes, with any bytes after those zero, so it must be at least 8.
+/// * `first`:   The number of the first key scanned.
+/// * `count`:   The number of keys scanned.
+/// * `limit`:   The number of matching objects the scan stops at, zero for no limit.
+/// * `pred`:    The predicate objects must match.
+pub fn args(
+    table: u64,
+    key_len: u16,
+    first: u64,
+    count: u32,
+    limit: u32,
+    pred: &Predicate,
+) -> Vec<u8> {
+    let mut args = Vec::new();
+    push_le(&mut args, table, 8);
+    push_le(&mut args, key_len as u64, 2);
+    push_le(&mut args, first, 8);
+    push_le(&mut args, count as u64, 4);
+    push_le(&mut args, limit as u64, 4);
+    pred.encode(&mut args);
+    args
+}
+
+/// The objects a scan found.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Scan {
+    /// True if the scan stopped at it's limit, and should be carried on from `next`.
+    pub more: bool,
+
+    /// The number of the first key not scanned.
+    pub next: u64,
+
+    /// The key and value of every object that matched, in the order of their keys.
+    pub objects: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+// Implementation of methods on Scan.
+impl Scan {
+    /// Parses what the filter() extension responded with.
+    ///
+    /// # Arguments
+    ///
+    /// * `key_len`: The length of the keys scanned.
+    /// * `resp`:    The response written by the extension.
+    ///
+    /// # Return
+    ///
+    /// The objects found, or None if the extension refused the arguments or the response is
+    /// malformed.
+    pub fn parse(key_len: u16, resp: &[u8]) -> Option<Scan> {
+        let key_len = key_len as usize;
+        if resp.len() < 9 || (resp[0] != SUCCESSFUL && resp[0] != MORE) {
+            return None;
+        }
+
+        let mut objects = Vec::new();
+        let mut off = 9;
+        while off < resp.len() {
+            if resp.len() - off < key_len + 4 {
+                return None;
+            }
+            let len = le(&resp[off + key_len..off + key_len + 4]) as usize;
+            let start = off + key_len + 4;
+            if resp.len() - start < len {
+                return None;
+            }
+
+            objects.push((resp[off..off + key_len].to_vec(), resp[start..start + len].to_vec()));
+            off = start + len;
+        }
+
+        Some(Scan {
+            more: resp[0] == MORE,
+            next: le(&resp[1..9]),
+            objects: objects,
+        })
+    }
+}
+
+// Appends the `n` low bytes of a value to a buffer, little endian.
+fn push_le(buf: &mut Vec<u8>, val: u64, n: usize) {
+    for byte in 0..n {
+        buf.push((val >> (8 * byte)) as u8);
+    }
+}
+
+// Reads an unsigned little endian integer of upto eight bytes.
+fn le(buf: &[u8]) -> u64 {
+    buf.iter().rev().fold(0, |acc, &byte| (acc << 8) | byte as u64)
+}
+
+// This module contains unit tests for the filter() extension's arguments and response.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // This unit test verifies that predicates are encoded in postfix order, with comparisons laid
+    // out the way the extension expects them.
+    #[test]
+    fn test_encode() {
+        let mut buf = Vec::new();
+        Predicate::cmp(0x102, 4, Cmp::Ge, 7).encode(&mut buf);
+        assert_eq!(vec![0x01, 0x06, 0x02, 0x01, 4, 7, 0, 0, 0, 0, 0, 0, 0], buf);
+
+        let pred = Predicate::cmp(0, 1, Cmp::Eq, 1)
+            .and(Predicate::cmp(1, 1, Cmp::Lt, 2))
+            .or(Predicate::cmp(2, 1, Cmp::Ne, 3));
+        let mut buf = Vec::new();
+        pred.encode(&mut buf);
+        assert_eq!(3 * 13 + 2, buf.len());
+        assert_eq!(0x02, buf[26]);
+        assert_eq!(0x03, buf[40]);
+        assert_eq!(0x03, buf[14]);
+
+        let args = args(9, 30, 0x100, 1000, 5, &pred);
+        assert_eq!(26 + buf.len(), args.len());
+        assert_eq!(&[9, 0, 0, 0, 0, 0, 0, 0, 30, 0, 0, 1], &args[..12]);
+        assert_eq!(&buf[..], &args[26..]);
+    }
+
+    // This unit test verifies that responses are parsed into the objects they hold, and that
+    // refusals and truncated responses are not.
+    #[test]
+    fn test_parse() {
+        let mut resp = vec![MORE, 5, 0, 0, 0, 0, 0, 0, 0];
+        resp.extend_from_slice(&[1, 2, 3, 0, 0, 0, 7, 8, 9]);
+        resp.extend_from_slice(&[4, 5, 0, 0, 0, 0]);
+
+        let scan = Scan::parse(2, &resp).unwrap();
+        assert!(scan.more);
+        assert_eq!(5, scan.next);
+        assert_eq!(
+            vec![(vec![1, 2], vec![7, 8, 9]), (vec![4, 5], vec![])],
+            scan.objects
+        );
+
+        assert!(Scan::parse(2, &resp[..resp.len() - 1]).is_none());
+        assert!(Scan::parse(2, &resp[..12]).is_none());
+        assert!(Scan::parse(2, &[INVALIDARG]).is_none());
+        assert_eq!(0, Scan::parse(2, &resp[..9]).unwrap().objects.len());
+    }
+}
@@ -0,0 +1,251 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use std::cell::Cell;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use futures::sync::oneshot;
+use futures::{Async, Future, Poll};
+
+use super::error::Error;
+use super::op::Op;
+
+/// An operation handed to a Worker by a Handle, along with where to send it's result.
+pub(crate) struct Command {
+    // The tenant the operation is issued on behalf of.
+    pub tenant: u32,
+
+    // The operation.
+    pub op: Op,
+
+    // The channel the operation's result is sent back on.
+    pub reply: oneshot::Sender<Result<Vec<u8>, Error>>,
+}
+
+/// The receiving end of the channel between a Handle and one Worker. Mailboxes are created
+/// along with a Handle, and then moved to the threads that will construct Workers on them.
+pub struct Mailbox {
+    pub(crate) rx: Receiver<Command>,
+}
+
+/// A handle for issuing operations through a set of Workers, each running on it's own core
+/// with it's own network queue, UDP source port and table of pending requests.
+///
+/// Handles are cheap to clone, and every thread issuing operations should own a clone of it's
+/// own. Operations are handed to Workers over channels, so neither the handle nor the Workers
+/// ever take a lock. Gets and puts on the same key always go to the same Worker, so they complete
+/// in the order they were issued; invokes are spread across Workers round-robin.
+pub struct Handle {
+    // The sending ends of the channels to every Worker, indexed by Worker.
+    txs: Vec<Sender<Command>>,
+
+    // The Worker the next invoke is handed to.
+    next: Cell<usize>,
+}
+
+// Implementation of methods on Handle.
+impl Handle {
+    /// Creates a handle along with the mailboxes of the Workers it will issue operations to.
+    ///
+    /// # Arguments
+    ///
+    /// * `workers`: The number of Workers, usually one per core generating requests.
+    ///
+    /// # Return
+    ///
+    /// The handle, and one Mailbox per Worker.
+    pub fn new(workers: usize) -> (Handle, Vec<Mailbox>) {
+        assert!(workers > 0);
+
+        let mut txs = Vec::with_capacity(workers);
+        let mut mailboxes = Vec::with_capacity(workers);
+        for _ in 0..workers {
+            let (tx, rx) = channel();
+            txs.push(tx);
+            mailboxes.push(Mailbox { rx: rx });
+        }
+
+        let handle = Handle {
+            txs: txs,
+            next: Cell::new(0),
+        };
+
+        (handle, mailboxes)
+    }
+
+    /// Looks up a key.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant`: The tenant the lookup is issued on behalf of.
+    /// * `table`:  Id of the table from which the key is looked up.
+    /// * `key`:    Byte string of key whose value is to be fetched. Limit 64 KB.
+    ///
+    /// # Return
+    ///
+    /// A future resolving to the key's value.
+    pub fn get(&self, tenant: u32, table: u64, key: &[u8]) -> Reply {
+        let worker = self.by_key(table, key);
+        self.issue(
+            worker,
+            tenant,
+            Op::Get {
+                table: table,
+                key: key.to_vec(),
+            },
+        )
+    }
+
+    /// Inserts a key-value pair.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant`: The tenant the insert is issued on behalf of.
+    /// * `table`:  Id of the table into which the key-value pair is to be inserted.
+    /// * `key`:    Byte string of key whose value is to be inserted. Limit 64 KB.
+    /// * `val`:    Byte string of the value to be inserted.
+    ///
+    /// # Return
+    ///
+    /// A future resolving to an empty value once the pair was inserted.
+    pub fn put(&self, tenant: u32, table: u64, key: &[u8], val: &[u8]) -> Reply {
+        let worker = self.by_key(table, key);
+        self.issue(
+            worker,
+            tenant,
+            Op::Put {
+                table: table,
+                key: key.to_vec(),
+                val: val.to_vec(),
+            },
+        )
+    }
+
+    /// Removes a key-value pair.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant`: The tenant the delete is issued on behalf of.
+    /// * `table`:  Id of the table from which the key-value pair is to be removed.
+    /// * `key`:    Byte string of key to be removed. Limit 64 KB.
+    ///
+    /// # Return
+    ///
+    /// A future resolving to an empty value once the pair was removed.
+    pub fn delete(&self, tenant: u32, table: u64, key: &[u8]) -> Reply {
+        let worker = self.by_key(table, key);
+        self.issue(
+            worker,
+            tenant,
+            Op::Delete {
+                table: table,
+                key: key.to_vec(),
+            },
+        )
+    }
+
+    /// Invokes an extension installed at the server.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant`: The tenant the invocation is issued on behalf of.
+    /// * `name`:   The name of the extension.
+    /// * `args`:   The arguments to the extension.
+    ///
+    /// # Return
+    ///
+    /// A future resolving to whatever the extension wrote into it's response.
+    pub fn invoke(&self, tenant: u32, name: &str, args: &[u8]) -> Reply {
+        let mut payload = Vec::with_capacity(name.len() + args.len());
+        payload.extend_from_slice(name.as_bytes());
+        payload.extend_from_slice(args);
+
+        let worker = self.next.get();
+        self.next.set((worker + 1) % self.txs.len());
+
+        self.issue(
+            worker,
+            tenant,
+            Op::Invoke {
+                name_len: name.len() as u32,
+                payload: payload,
+            },
+        )
+    }
+
+    /// Returns the number of Workers operations are spread across.
+    pub fn workers(&self) -> usize {
+        self.txs.len()
+    }
+
+    // Picks the Worker that operations on a key are handed to.
+    fn by_key(&self, table: u64, key: &[u8]) -> usize {
+        let mut hasher = DefaultHasher::new();
+        table.hash(&mut hasher);
+        key.hash(&mut hasher);
+
+        (hasher.finish() % self.txs.len() as u64) as usize
+    }
+
+    // Hands an operation to a Worker, and returns a future for it's result.
+    fn issue(&self, worker: usize, tenant: u32, op: Op) -> Reply {
+        let (tx, rx) = oneshot::channel();
+
+        // If the Worker has gone away the command is dropped along with the sending end of the
+        // oneshot, which fails the future with Error::Shutdown.
+        let _ = self.txs[worker].send(Command {
+            tenant: tenant,
+            op: op,
+            reply: tx,
+        });
+
+        Reply { rx: rx }
+    }
+}
+
+// Implementation of the Clone trait for Handle. Clones start issuing invokes from the same
+// Worker independently of each other.
+impl Clone for Handle {
+    fn clone(&self) -> Handle {
+        Handle {
+            txs: self.txs.clone(),
+            next: Cell::new(self.next.get()),
+        }
+    }
+}
+
+/// A future for the result of an operation issued through a Handle. Unlike the futures returned
+/// by a Client, a Reply does not drive the network; it resolves once the Worker the operation was
+/// handed to completes it.
+pub struct Reply {
+    rx: oneshot::Receiver<Result<Vec<u8>, Error>>,
+}
+
+// Implementation of the Future trait for Reply.
+impl Future for Reply {
+    type Item = Vec<u8>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Vec<u8>, Error> {
+        match self.rx.poll() {
+            Ok(Async::Ready(Ok(value))) => Ok(Async::Ready(value)),
+            Ok(Async::Ready(Err(err))) => Err(err),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(_) => Err(Error::Shutdown),
+        }
+    }
+}
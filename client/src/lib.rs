@@ -0,0 +1,41 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+extern crate db;
+extern crate futures;
+extern crate sandstorm;
+extern crate wasmi;
+
+mod cache;
+mod client;
+mod error;
+mod executor;
+mod handle;
+mod op;
+mod retry;
+mod route;
+mod wasm;
+mod worker;
+
+pub mod filter;
+pub mod project;
+
+pub use self::client::{Client, Response};
+pub use self::error::Error;
+pub use self::executor::Executor;
+pub use self::handle::{Handle, Mailbox, Reply};
+pub use self::retry::RetryPolicy;
+pub use self::route::ServerStats;
+pub use self::worker::Worker;
@@ -0,0 +1,207 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use std::mem::size_of;
+
+use db::e2d2::common::EmptyMetadata;
+use db::e2d2::headers::*;
+use db::e2d2::interface::*;
+use db::error;
+use db::multiop;
+use db::rpc;
+use db::wireformat::{GetResponse, InvokeResponse, MultiOpResponse, OpCode, PutResponse, RpcStatus};
+
+use super::error::Error;
+
+/// An operation issued through a Client. Operations are kept around until they complete, so
+/// that the request can be rebuilt from them if it needs to be retried.
+pub enum Op {
+    Get { table: u64, key: Vec<u8> },
+
+    Put { table: u64, key: Vec<u8>, val: Vec<u8> },
+
+    // The server only executes deletes as part of a multiop(), so a delete that is not batched
+    // is sent out as a multiop() request of one.
+    Delete { table: u64, key: Vec<u8> },
+
+    Invoke { name_len: u32, payload: Vec<u8> },
+}
+
+/// The network headers and addressing that every request from a Client carries.
+pub struct Headers {
+    pub mac: MacHeader,
+    pub ip: IpHeader,
+    pub udp: UdpHeader,
+}
+
+// Implementation of methods on Op.
+impl Op {
+    /// Builds the request for this operation.
+    ///
+    /// # Arguments
+    ///
+    /// * `hdrs`:   The network headers to write into the request.
+    /// * `tenant`: The tenant issuing the operation.
+    /// * `id`:     The RPC identifier to write into the request.
+    /// * `dst`:    The UDP port on the server the request is destined for.
+    ///
+    /// # Return
+    ///
+    /// The request, parsed upto it's IP header, or an error if a packet could not be allocated
+    /// for it or it is too long for a single datagram.
+    pub fn request(
+        &self,
+        hdrs: &Headers,
+        tenant: u32,
+        id: u64,
+        dst: u16,
+    ) -> error::Result<Packet<IpHeader, EmptyMetadata>> {
+        match *self {
+            Op::Get { table, ref key } => rpc::create_get_rpc(
+                &hdrs.mac, &hdrs.ip, &hdrs.udp, tenant, table, key, id, dst,
+            ),
+
+            Op::Put {
+                table,
+                ref key,
+                ref val,
+            } => rpc::create_put_rpc(
+                &hdrs.mac, &hdrs.ip, &hdrs.udp, tenant, table, key, val, id, dst,
+            ),
+
+            Op::Delete { table, ref key } => {
+                let mut ops = Vec::with_capacity(multiop::ENTRY_LEN + key.len());
+                multiop::push_entry(&mut ops, OpCode::SandstormDeleteRpc, table, key, &[]);
+                rpc::create_multiop_rpc(
+                    &hdrs.mac, &hdrs.ip, &hdrs.udp, tenant, 1, &ops, id, dst,
+                )
+            }
+
+            Op::Invoke {
+                name_len,
+                ref payload,
+            } => rpc::create_invoke_rpc(
+                &hdrs.mac, &hdrs.ip, &hdrs.udp, tenant, name_len, payload, id, dst,
+            ),
+        }
+    }
+
+    /// Appends this operation to the payload of a multiop() request, if it can be batched. Only
+    /// gets, puts and deletes can.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf`: The payload of the multiop() request.
+    ///
+    /// # Return
+    ///
+    /// True if the operation was appended.
+    pub fn batch(&self, buf: &mut Vec<u8>) -> bool {
+        match *self {
+            Op::Get { table, ref key } => {
+                multiop::push_entry(buf, OpCode::SandstormGetRpc, table, key, &[]);
+                true
+            }
+
+            Op::Put {
+                table,
+                ref key,
+                ref val,
+            } => {
+                multiop::push_entry(buf, OpCode::SandstormPutRpc, table, key, val);
+                true
+            }
+
+            Op::Delete { table, ref key } => {
+                multiop::push_entry(buf, OpCode::SandstormDeleteRpc, table, key, &[]);
+                true
+            }
+
+            Op::Invoke { .. } => false,
+        }
+    }
+
+    /// Returns the number of bytes this operation takes up on a multiop() request, or None if it
+    /// cannot be batched.
+    pub fn batch_len(&self) -> Option<usize> {
+        match *self {
+            Op::Get { ref key, .. } => Some(multiop::ENTRY_LEN + key.len()),
+            Op::Put {
+                ref key, ref val, ..
+            } => Some(multiop::ENTRY_LEN + key.len() + val.len()),
+            Op::Delete { ref key, .. } => Some(multiop::ENTRY_LEN + key.len()),
+            Op::Invoke { .. } => None,
+        }
+    }
+
+    /// Returns true if executing the operation twice has the same effect as executing it once.
+    /// Extensions can do anything, so invokes are never assumed to be idempotent.
+    pub fn idempotent(&self) -> bool {
+        match *self {
+            Op::Get { .. } => true,
+            Op::Put { .. } => true,
+            Op::Delete { .. } => true,
+            Op::Invoke { .. } => false,
+        }
+    }
+
+    /// Decodes the server's response to this operation.
+    ///
+    /// # Arguments
+    ///
+    /// * `payload`: The UDP payload of the response.
+    ///
+    /// # Return
+    ///
+    /// The value for a get(), nothing for a put() or delete(), and whatever the extension wrote
+    /// for an invoke(). An error if the server did not complete the operation, which for an
+    /// invoke() the extension pushed back to the client is `Error::Pushback`.
+    pub fn response(&self, payload: &[u8]) -> Result<Vec<u8>, Error> {
+        let hdr = match *self {
+            Op::Get { .. } => size_of::<GetResponse>(),
+            Op::Put { .. } => size_of::<PutResponse>(),
+            Op::Delete { .. } => size_of::<MultiOpResponse>(),
+            Op::Invoke { .. } => size_of::<InvokeResponse>(),
+        };
+
+        // An invocation pushed back to the client carries the state it is resumed with.
+        if let Op::Invoke { .. } = *self {
+            if payload.len() >= hdr && payload[0] == RpcStatus::StatusPushback as u8 {
+                return Err(Error::Pushback(payload[hdr..].to_vec()));
+            }
+        }
+
+        // Requests the server refused to execute, such as those from a suspended tenant, are
+        // answered with only a common header, so check the status before the length.
+        if payload.len() > 0 && payload[0] != RpcStatus::StatusOk as u8 {
+            return Err(Error::Status(payload[0]));
+        }
+
+        if payload.len() < hdr {
+            return Err(Error::Status(RpcStatus::StatusMalformedRequest as u8));
+        }
+
+        // A delete's status is the status of the only operation on it's multiop() request.
+        if let Op::Delete { .. } = *self {
+            return match multiop::parse_result(&payload[hdr..]) {
+                Some((status, _, _)) if status == RpcStatus::StatusOk as u8 => Ok(Vec::new()),
+                Some((status, _, _)) => Err(Error::Status(status)),
+                None => Err(Error::Status(RpcStatus::StatusMalformedRequest as u8)),
+            };
+        }
+
+        Ok(payload[hdr..].to_vec())
+    }
+}
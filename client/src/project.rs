@@ -0,0 +1,291 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! Compiles projections to the arguments of the project() extension (see `ext/project`), which
+//! looks up a list of keys at the server and returns a row of computed columns for every object,
+//! and parses the rows it returns. For example, the equivalent of
+//! `SELECT f0, (f1 - f0) / 2 FROM t WHERE key IN (k1, k2)`, where `f0` and `f1` are the u32s at
+//! offsets 0 and 4 into the value:
+//!
+//! ```ignore
+//! let (f0, f1) = (Expr::field(0, 4), Expr::field(4, 4));
+//! let cols = vec![f0.clone(), (f1 - f0) / Expr::constant(2)];
+//! let resp = client.invoke("project", &args(1, 30, &cols, &[k1, k2].concat())).wait()?;
+//! let rows = Row::parse(30, cols.len(), &resp);
+//! ```
+
+use std::ops::{Add, Div, Mul, Rem, Sub};
+
+/// The name the project() extension is invoked by.
+pub const NAME: &str = "project";
+
+/// The status the extension responds with once it looked up every key.
+pub const SUCCESSFUL: u8 = 0x01;
+
+/// The status the extension responds with if the arguments or an expression are malformed.
+pub const INVALIDARG: u8 = 0x02;
+
+/// The largest number of columns a projection can have.
+pub const MAX_COLUMNS: usize = 8;
+
+/// The arithmetic operations an expression can apply.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Op {
+    Add = 0x03,
+    Sub = 0x04,
+    Mul = 0x05,
+    Div = 0x06,
+    Rem = 0x07,
+}
+
+/// An expression computing a column from the value of an object. Expressions are combined with
+/// the `+`, `-`, `*`, `/` and `%` operators; the first three wrap around at the server, and
+/// division or remainder by zero is null, as is anything computed from a null.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    /// The field, an unsigned integer `width` (1, 2, 4 or 8) bytes long at `offset` into the
+    /// value read little endian. Null if the value is too short to hold the field.
+    Field { offset: u16, width: u8 },
+
+    /// A constant.
+    Const(u64),
+
+    /// An operation on the results of two expressions.
+    Arith(Op, Box<Expr>, Box<Expr>),
+}
+
+// Implementation of methods on Expr.
+impl Expr {
+    /// Returns a field of the value.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset`: The offset of the field into the value.
+    /// * `width`:  The width of the field in bytes: 1, 2, 4 or 8. The extension refuses any other
+    ///             width.
+    pub fn field(offset: u16, width: u8) -> Expr {
+        Expr::Field {
+            offset: offset,
+            width: width,
+        }
+    }
+
+    /// Returns a constant.
+    pub fn constant(val: u64) -> Expr {
+        Expr::Const(val)
+    }
+
+    /// Appends the expression to a buffer, in the postfix order the extension evaluates it in.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf`: The buffer.
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        match *self {
+            Expr::Field { offset, width } => {
+                buf.extend_from_slice(&[0x01, offset as u8, (offset >> 8) as u8, width]);
+            }
+
+            Expr::Const(val) => {
+                buf.push(0x02);
+                push_le(buf, val, 8);
+            }
+
+            Expr::Arith(op, ref left, ref right) => {
+                left.encode(buf);
+                right.encode(buf);
+                buf.push(op as u8);
+            }
+        }
+    }
+}
+
+// Implements an arithmetic operator on expressions, building an `Expr::Arith`.
+macro_rules! arith {
+    ($trait:ident, $method:ident, $op:expr) => {
+        impl $trait for Expr {
+            type Output = Expr;
+
+            fn $method(self, other: Expr) -> Expr {
+                Expr::Arith($op, Box::new(self), Box::new(other))
+            }
+        }
+    };
+}
+
+arith!(Add, add, Op::Add);
+arith!(Sub, sub, Op::Sub);
+arith!(Mul, mul, Op::Mul);
+arith!(Div, div, Op::Div);
+arith!(Rem, rem, Op::Rem);
+
+/// Returns the arguments to the project() extension.
+///
+/// # Arguments
+///
+/// * `table`:   The table the objects are looked up in.
+/// * `key_len`: The length of it's keys.
+/// * `columns`: The expressions of the columns, upto `MAX_COLUMNS` of them.
+/// * `keys`:    The keys to look up, one after another, `key_len` bytes each.
+pub fn args(table: u64, key_len: u16, columns: &[Expr], keys: &[u8]) -> Vec<u8> {
+    let mut args = Vec::new();
+    push_le(&mut args, table, 8);
+    push_le(&mut args, key_len as u64, 2);
+    push_le(&mut args, (keys.len() / key_len.max(1) as usize) as u64, 4);
+    args.push(columns.len() as u8);
+
+    for column in columns.iter() {
+        let mut expr = Vec::new();
+        column.encode(&mut expr);
+        push_le(&mut args, expr.len() as u64, 2);
+        args.extend_from_slice(&expr);
+    }
+
+    args.extend_from_slice(keys);
+    args
+}
+
+/// A row the project() extension returned.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Row {
+    /// The key of the object the row was computed from.
+    pub key: Vec<u8>,
+
+    /// The value of every column, None where it is null.
+    pub columns: Vec<Option<u64>>,
+}
+
+// Implementation of methods on Row.
+impl Row {
+    /// Parses what the project() extension responded with.
+    ///
+    /// # Arguments
+    ///
+    /// * `key_len`: The length of the keys looked up.
+    /// * `ncols`:   The number of columns projected.
+    /// * `resp`:    The response written by the extension.
+    ///
+    /// # Return
+    ///
+    /// A row for every key that exists, in the order of the keys, or None if the extension
+    /// refused the arguments or the response is malformed.
+    pub fn parse(key_len: u16, ncols: usize, resp: &[u8]) -> Option<Vec<Row>> {
+        if resp.len() < 5 || resp[0] != SUCCESSFUL || ncols > MAX_COLUMNS {
+            return None;
+        }
+
+        let count = le(&resp[1..5]) as usize;
+        let row_len = key_len as usize + 1 + 8 * ncols;
+        if (resp.len() - 5) as u64 != count as u64 * row_len as u64 {
+            return None;
+        }
+
+        let rows = resp[5..]
+            .chunks(row_len)
+            .map(|row| {
+                let (key, rest) = row.split_at(key_len as usize);
+                let columns = rest[1..]
+                    .chunks(8)
+                    .enumerate()
+                    .map(|(idx, val)| match rest[0] & (1 << idx) {
+                        0 => Some(le(val)),
+                        _ => None,
+                    })
+                    .collect();
+
+                Row {
+                    key: key.to_vec(),
+                    columns: columns,
+                }
+            })
+            .collect();
+
+        Some(rows)
+    }
+}
+
+// Appends the `n` low bytes of a value to a buffer, little endian.
+fn push_le(buf: &mut Vec<u8>, val: u64, n: usize) {
+    for byte in 0..n {
+        buf.push((val >> (8 * byte)) as u8);
+    }
+}
+
+// Reads an unsigned little endian integer of upto eight bytes.
+fn le(buf: &[u8]) -> u64 {
+    buf.iter().rev().fold(0, |acc, &byte| (acc << 8) | byte as u64)
+}
+
+// This module contains unit tests for the project() extension's arguments and response.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // This unit test verifies that expressions are encoded in postfix order, with fields and
+    // constants laid out the way the extension expects them.
+    #[test]
+    fn test_encode() {
+        let mut buf = Vec::new();
+        Expr::field(0x102, 4).encode(&mut buf);
+        assert_eq!(vec![0x01, 0x02, 0x01, 4], buf);
+
+        let expr = (Expr::field(4, 4) - Expr::field(0, 4)) / Expr::constant(2);
+        let mut buf = Vec::new();
+        expr.encode(&mut buf);
+        assert_eq!(4 + 4 + 1 + 9 + 1, buf.len());
+        assert_eq!(0x04, buf[8]);
+        assert_eq!(&[0x02, 2, 0, 0, 0, 0, 0, 0, 0], &buf[9..18]);
+        assert_eq!(0x06, buf[18]);
+
+        let args = args(9, 2, &[Expr::constant(1), expr], &[1, 0, 2, 0, 3, 0]);
+        assert_eq!(&[9, 0, 0, 0, 0, 0, 0, 0, 2, 0, 3, 0, 0, 0, 2, 9, 0, 0x02], &args[..18]);
+        assert_eq!(&[19, 0], &args[26..28]);
+        assert_eq!(&buf[..], &args[28..47]);
+        assert_eq!(&[1, 0, 2, 0, 3, 0], &args[47..]);
+    }
+
+    // This unit test verifies that responses are parsed into rows with their nulls, and that
+    // refusals and truncated responses are not.
+    #[test]
+    fn test_parse() {
+        let mut resp = vec![SUCCESSFUL, 2, 0, 0, 0];
+        resp.extend_from_slice(&[1, 0, 0b10, 7, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        resp.extend_from_slice(&[2, 0, 0, 8, 0, 0, 0, 0, 0, 0, 0, 9, 0, 0, 0, 0, 0, 0, 0]);
+
+        let rows = Row::parse(2, 2, &resp).unwrap();
+        assert_eq!(
+            vec![
+                Row {
+                    key: vec![1, 0],
+                    columns: vec![Some(7), None],
+                },
+                Row {
+                    key: vec![2, 0],
+                    columns: vec![Some(8), Some(9)],
+                },
+            ],
+            rows
+        );
+
+        assert!(Row::parse(2, 2, &resp[..resp.len() - 1]).is_none());
+        assert!(Row::parse(2, 1, &resp).is_none());
+        assert!(Row::parse(2, 2, &[INVALIDARG]).is_none());
+        assert_eq!(
+            Some(vec![]),
+            Row::parse(2, 2, &[SUCCESSFUL, 0, 0, 0, 0])
+        );
+    }
+}
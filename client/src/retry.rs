@@ -0,0 +1,199 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use db::config::ClientConfig;
+use db::wireformat::RpcStatus;
+
+/// The number of times a request is sent out before failing, if no retry count was configured.
+const DEFAULT_MAX_ATTEMPTS: u32 = 4;
+
+/// The factor the delay between retries grows by, if none was configured.
+const DEFAULT_BACKOFF: f64 = 2.0;
+
+/// Decides whether and when an operation that failed is retried.
+///
+/// Requests are retried if no response arrives in time, and if the server responds with a status
+/// saying it did not get to execute the request, such as `StatusRateLimited`. The first retry
+/// waits for `base_us` microseconds, and every following one waits `backoff` times longer than
+/// the one before, upto `max_us`. Every delay is then spread out by upto `jitter` times itself in
+/// either direction, so that clients that lost requests at the same time do not retry in lockstep.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// The maximum number of times a request is sent out, including the first one.
+    pub max_attempts: u32,
+
+    /// The delay in microseconds before the first retry. Zero retries right away.
+    pub base_us: u64,
+
+    /// The factor the delay grows by on every retry.
+    pub backoff: f64,
+
+    /// The maximum delay in microseconds between two attempts. Zero if there is no maximum.
+    pub max_us: u64,
+
+    /// The fraction of the delay it is randomly spread out by, between 0 and 1.
+    pub jitter: f64,
+
+    /// If true, operations that are not idempotent are not retried after a timeout, since the
+    /// server may have executed the first attempt. They are still retried on statuses that
+    /// guarantee the request was not executed.
+    pub idempotent_only: bool,
+
+    /// The raw values of the `RpcStatus`es a request is retried on.
+    pub statuses: Vec<u8>,
+}
+
+// Implementation of methods on RetryPolicy.
+impl RetryPolicy {
+    /// Creates the retry policy described by a client config.
+    ///
+    /// # Arguments
+    ///
+    /// * `config`: The config. `max_retries` and the `retry_*` fields are used.
+    ///
+    /// # Return
+    ///
+    /// The retry policy.
+    pub fn from_config(config: &ClientConfig) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: config
+                .max_retries
+                .map(|retries| retries + 1)
+                .unwrap_or(DEFAULT_MAX_ATTEMPTS),
+            base_us: config.retry_base_us,
+            backoff: if config.retry_backoff > 0.0 {
+                config.retry_backoff
+            } else {
+                DEFAULT_BACKOFF
+            },
+            max_us: config.retry_max_us,
+            jitter: config.retry_jitter.max(0.0).min(1.0),
+            idempotent_only: config.retry_idempotent_only,
+            statuses: vec![
+                RpcStatus::StatusRateLimited as u8,
+                RpcStatus::StatusTenantMoved as u8,
+                RpcStatus::StatusWrongPartition as u8,
+                RpcStatus::StatusDraining as u8,
+            ],
+        }
+    }
+
+    /// Creates a retry policy that never retries.
+    pub fn never() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 1,
+            base_us: 0,
+            backoff: DEFAULT_BACKOFF,
+            max_us: 0,
+            jitter: 0.0,
+            idempotent_only: false,
+            statuses: Vec::new(),
+        }
+    }
+
+    /// Decides whether a request can be sent out again.
+    ///
+    /// # Arguments
+    ///
+    /// * `attempts`:   The number of times the request was sent out so far.
+    /// * `idempotent`: True if executing the operation twice has the same effect as once.
+    /// * `status`:     The status the server responded with, None if it did not respond.
+    ///
+    /// # Return
+    ///
+    /// True if the request should be retried.
+    pub fn should_retry(&self, attempts: u32, idempotent: bool, status: Option<u8>) -> bool {
+        if attempts >= self.max_attempts {
+            return false;
+        }
+
+        match status {
+            Some(status) => self.statuses.contains(&status),
+            None => idempotent || !self.idempotent_only,
+        }
+    }
+
+    /// Computes how long to wait before sending a request out again.
+    ///
+    /// # Arguments
+    ///
+    /// * `attempts`: The number of times the request was sent out so far. At least one.
+    /// * `rand`:     A random number used to jitter the delay.
+    ///
+    /// # Return
+    ///
+    /// The delay in microseconds.
+    pub fn delay_us(&self, attempts: u32, rand: u64) -> u64 {
+        if self.base_us == 0 {
+            return 0;
+        }
+
+        let mut delay = self.base_us as f64 * self.backoff.powi(attempts as i32 - 1);
+        if self.max_us > 0 && delay > self.max_us as f64 {
+            delay = self.max_us as f64;
+        }
+
+        // Spread the delay uniformly over [delay * (1 - jitter), delay * (1 + jitter)].
+        let unit = (rand % 1000001) as f64 / 1000000.0;
+        delay *= 1.0 + self.jitter * (2.0 * unit - 1.0);
+
+        return delay as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RetryPolicy;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 4,
+            base_us: 100,
+            backoff: 2.0,
+            max_us: 300,
+            jitter: 0.0,
+            idempotent_only: true,
+            statuses: vec![0x09],
+        }
+    }
+
+    #[test]
+    fn backoff() {
+        let policy = policy();
+        assert_eq!(100, policy.delay_us(1, 0));
+        assert_eq!(200, policy.delay_us(2, 0));
+        assert_eq!(300, policy.delay_us(3, 0));
+        assert_eq!(300, policy.delay_us(9, 0));
+    }
+
+    #[test]
+    fn jitter() {
+        let mut policy = policy();
+        policy.jitter = 0.5;
+        assert_eq!(50, policy.delay_us(1, 0));
+        assert_eq!(100, policy.delay_us(1, 500000));
+        assert_eq!(150, policy.delay_us(1, 1000000));
+    }
+
+    #[test]
+    fn should_retry() {
+        let policy = policy();
+        assert!(policy.should_retry(1, true, None));
+        assert!(!policy.should_retry(1, false, None));
+        assert!(policy.should_retry(1, false, Some(0x09)));
+        assert!(!policy.should_retry(1, true, Some(0x04)));
+        assert!(!policy.should_retry(4, true, None));
+    }
+}
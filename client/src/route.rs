@@ -0,0 +1,724 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+use db::config::{ClientConfig, ServerEndpointConfig};
+use db::cycles;
+use db::partition::Map;
+
+/// The number of consecutive timeouts after which a server is marked down, if none was
+/// configured.
+const DEFAULT_HEALTH_FAILURES: u32 = 3;
+
+/// The time a server is marked down for before requests are sent to it again, if none was
+/// configured.
+const DEFAULT_HEALTH_RETRY_US: u64 = 100000;
+
+/// The number of points (virtual nodes) every unit of a server's weight is given on the hash
+/// ring, if none was configured. More points spread keys more evenly across servers.
+const DEFAULT_VIRTUAL_NODES: u32 = 64;
+
+// How keys are mapped to servers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Routing {
+    // Every key is mapped to a server through a consistent hash ring.
+    Hash,
+
+    // Keys are mapped to the server listing their table. Keys in tables that are not listed
+    // anywhere are mapped through the hash ring.
+    Static,
+}
+
+// The routing table of a partitioned tenant, with the ranges of it's keys mapped to servers.
+#[derive(Clone, Debug)]
+struct Partitions {
+    // The version of the routing table.
+    version: u64,
+
+    // The key every range starts at, and the server holding it if the client routes to it,
+    // ordered by key.
+    ranges: Vec<(Vec<u8>, Option<usize>)>,
+}
+
+// What is known about the health of a server.
+#[derive(Clone, Debug, Default)]
+struct Health {
+    // The number of requests to the server in a row that timed out.
+    failures: u32,
+
+    // The time-stamp in cycles until which the server is considered down.
+    down_until: u64,
+}
+
+/// Counters kept for every server a client routes to, for observing how load shifts when
+/// servers are added or removed.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ServerStats {
+    /// The number of operations routed to the server.
+    pub routed: u64,
+
+    /// The number of responses received from the server.
+    pub responses: u64,
+
+    /// The number of requests to the server that timed out.
+    pub timeouts: u64,
+
+    /// The fraction of the hash ring the server owns, that is, the share of keys routed to it
+    /// by consistent hashing. 0 once the server was removed.
+    pub share: f64,
+
+    /// True if the server is not marked down.
+    pub up: bool,
+
+    /// False once the server was removed.
+    pub active: bool,
+}
+
+// A server known to the router.
+#[derive(Clone, Debug)]
+struct Server {
+    // The server's share of keys relative to the others, in units of virtual nodes.
+    weight: u32,
+
+    // The tables statically mapped to the server.
+    tables: Vec<u64>,
+
+    // The server's IP address, if it parses.
+    ip: Option<[u8; 4]>,
+
+    // False once the server was removed. Removed servers keep their index, so that requests
+    // already sent to them can still be matched up with their responses.
+    active: bool,
+
+    // The server's health.
+    health: Health,
+
+    // Counters on the server, the share and up fields are filled in on demand.
+    stats: ServerStats,
+}
+
+/// Maps operations to the servers they should be sent to, and keeps track of which servers are
+/// responding.
+///
+/// A server is marked down once `failures` requests to it in a row time out, and operations
+/// routed to it fail right away instead of waiting on timeouts of their own. After `retry`
+/// cycles, requests are sent to it again; the server is marked up again when one of them is
+/// answered, and down again if the next one times out.
+///
+/// Keys are hashed with a fixed function, so every client routes a key to the same server.
+/// Every server is given `weight` virtual nodes on the hash ring, placed by hashing the server's
+/// index, so adding or removing a server only moves the keys on it's arcs of the ring.
+pub struct Router {
+    // How keys are mapped to servers.
+    routing: Routing,
+
+    // The number of points on the hash ring for every unit of a server's weight.
+    vnodes: u32,
+
+    // Points on the hash ring and the servers they belong to, sorted by point.
+    ring: Vec<(u64, usize)>,
+
+    // The server holding each statically mapped table.
+    tables: HashMap<u64, usize>,
+
+    // The server every operation of a tenant goes to, for tenants that were migrated to a
+    // server, whatever table or key the operation is on.
+    tenants: HashMap<u32, usize>,
+
+    // The routing table of every partitioned tenant the client has heard of.
+    partitions: HashMap<u32, Partitions>,
+
+    // Every server that was routed to, by index.
+    servers: Vec<Server>,
+
+    // The number of timeouts in a row after which a server is marked down.
+    failures: u32,
+
+    // The time in cycles a server is marked down for.
+    retry: u64,
+}
+
+// Implementation of methods on Router.
+impl Router {
+    /// Creates the router described by a client config.
+    ///
+    /// # Arguments
+    ///
+    /// * `config`: The config. `servers`, `routing`, `virtual_nodes`, and the `health_*` fields
+    ///             are used.
+    ///
+    /// # Return
+    ///
+    /// The router.
+    pub fn from_config(config: &ClientConfig) -> Router {
+        let failures = if config.health_failures > 0 {
+            config.health_failures
+        } else {
+            DEFAULT_HEALTH_FAILURES
+        };
+
+        let retry_us = if config.health_retry_us > 0 {
+            config.health_retry_us
+        } else {
+            DEFAULT_HEALTH_RETRY_US
+        };
+
+        let vnodes = if config.virtual_nodes > 0 {
+            config.virtual_nodes
+        } else {
+            DEFAULT_VIRTUAL_NODES
+        };
+
+        Router::new(
+            &config.endpoints(),
+            &config.routing,
+            vnodes,
+            failures,
+            retry_us * cycles::cycles_per_second() / 1000000,
+        )
+    }
+
+    /// Creates a router.
+    ///
+    /// # Arguments
+    ///
+    /// * `servers`:  The servers to route across. Must not be empty.
+    /// * `routing`:  "static" to route by the tables listed for each server, otherwise keys are
+    ///               routed by consistent hashing.
+    /// * `vnodes`:   The number of points on the hash ring for every unit of a server's weight.
+    /// * `failures`: The number of timeouts in a row after which a server is marked down.
+    /// * `retry`:    The time in cycles a server is marked down for.
+    ///
+    /// # Return
+    ///
+    /// The router.
+    pub fn new(
+        servers: &[ServerEndpointConfig],
+        routing: &str,
+        vnodes: u32,
+        failures: u32,
+        retry: u64,
+    ) -> Router {
+        let mut router = Router {
+            routing: if routing == "static" {
+                Routing::Static
+            } else {
+                Routing::Hash
+            },
+            vnodes: vnodes.max(1),
+            ring: Vec::new(),
+            tables: HashMap::new(),
+            tenants: HashMap::new(),
+            partitions: HashMap::new(),
+            servers: Vec::new(),
+            failures: failures,
+            retry: retry,
+        };
+
+        for server in servers.iter() {
+            router.servers.push(Server {
+                weight: server.weight.max(1),
+                tables: server.tables.clone(),
+                ip: parse_ip(&server.ip_address),
+                active: true,
+                health: Health::default(),
+                stats: ServerStats::default(),
+            });
+        }
+        router.rebuild();
+
+        router
+    }
+
+    /// Returns the number of servers routed across, including ones that were removed.
+    pub fn servers(&self) -> usize {
+        self.servers.len()
+    }
+
+    /// Adds a server. Only the keys on the arcs of the ring it takes over move to it, along with
+    /// any tables it lists that were not statically mapped to another server.
+    ///
+    /// # Arguments
+    ///
+    /// * `server`: The server.
+    ///
+    /// # Return
+    ///
+    /// The index of the added server.
+    pub fn add(&mut self, server: &ServerEndpointConfig) -> usize {
+        self.servers.push(Server {
+            weight: server.weight.max(1),
+            tables: server.tables.clone(),
+            ip: parse_ip(&server.ip_address),
+            active: true,
+            health: Health::default(),
+            stats: ServerStats::default(),
+        });
+        self.rebuild();
+
+        self.servers.len() - 1
+    }
+
+    /// Removes a server. The keys on it's arcs of the ring move to the servers after them, and
+    /// it's statically mapped tables are routed by hashing.
+    ///
+    /// # Arguments
+    ///
+    /// * `server`: The index of the server.
+    ///
+    /// # Return
+    ///
+    /// False if there is no such server, or if it is the last one left.
+    pub fn remove(&mut self, server: usize) -> bool {
+        let left = self.servers.iter().filter(|s| s.active).count();
+        match self.servers.get(server) {
+            Some(s) if s.active && left > 1 => {}
+            _ => return false,
+        }
+
+        self.servers[server].active = false;
+        self.rebuild();
+
+        true
+    }
+
+    /// Returns whether a server is still routed to.
+    pub fn active(&self, server: usize) -> bool {
+        self.servers[server].active
+    }
+
+    /// Sends every operation of a tenant to the server with an IP address, such as the one a
+    /// server answered with after the tenant was migrated away from it.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant`: The tenant.
+    /// * `ip`:     The server's IPv4 address, in network order.
+    ///
+    /// # Return
+    ///
+    /// False if no active server has the address, in which case the tenant is routed as before.
+    pub fn relocate(&mut self, tenant: u32, ip: [u8; 4]) -> bool {
+        let server = self.servers
+            .iter()
+            .position(|server| server.active && server.ip == Some(ip));
+
+        match server {
+            Some(server) => {
+                self.tenants.insert(tenant, server);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the server a tenant was relocated to, if it was and the server is still active.
+    pub fn relocated(&self, tenant: u32) -> Option<usize> {
+        match self.tenants.get(&tenant) {
+            Some(&server) if self.servers[server].active => Some(server),
+            _ => None,
+        }
+    }
+
+    /// Records that an operation was routed to a server.
+    pub fn routed(&mut self, server: usize) {
+        self.servers[server].stats.routed += 1;
+    }
+
+    /// Returns the counters on every server, by index.
+    ///
+    /// # Arguments
+    ///
+    /// * `now`: The current time-stamp in cycles.
+    pub fn stats(&self, now: u64) -> Vec<ServerStats> {
+        // Every point owns the arc of the ring between the point before it and itself.
+        let mut shares = vec![0f64; self.servers.len()];
+        for (idx, &(point, server)) in self.ring.iter().enumerate() {
+            let prev = if idx > 0 {
+                self.ring[idx - 1].0
+            } else {
+                self.ring[self.ring.len() - 1].0
+            };
+
+            let arc = point.wrapping_sub(prev);
+            shares[server] += arc as f64 / 18446744073709551616.0;
+        }
+
+        // A ring of one point owns all of it, even though it's only arc wraps around to nothing.
+        if self.ring.len() > 0 && shares.iter().all(|share| *share == 0.0) {
+            shares[self.ring[0].1] = 1.0;
+        }
+
+        self.servers
+            .iter()
+            .enumerate()
+            .map(|(idx, server)| ServerStats {
+                share: shares[idx],
+                up: server.health.down_until <= now,
+                active: server.active,
+                ..server.stats.clone()
+            })
+            .collect()
+    }
+
+    /// Routes a partitioned tenant's keys by it's routing table, unless the one already known is
+    /// as recent.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant`: The tenant.
+    /// * `map`:    The routing table, such as the one a server answered with after a key was
+    ///             sent to it that it does not hold.
+    ///
+    /// # Return
+    ///
+    /// False if the table was older than the one already known, and so ignored.
+    pub fn partition(&mut self, tenant: u32, map: &Map) -> bool {
+        if self.partitions.get(&tenant).map_or(false, |p| p.version >= map.version) {
+            return false;
+        }
+
+        let ranges = map.ranges
+            .iter()
+            .map(|range| {
+                let server = self.servers
+                    .iter()
+                    .position(|server| server.active && server.ip == Some(range.ip));
+                (range.start.clone(), server)
+            })
+            .collect();
+
+        let partitions = Partitions {
+            version: map.version,
+            ranges: ranges,
+        };
+        self.partitions.insert(tenant, partitions);
+        true
+    }
+
+    /// Returns the server holding a key of a partitioned tenant, or None if the tenant isn't
+    /// known to be partitioned, or if the server holding the key is not routed to.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant`: The tenant.
+    /// * `key`:    The key.
+    pub fn partitioned(&self, tenant: u32, key: &[u8]) -> Option<usize> {
+        let ranges = match self.partitions.get(&tenant) {
+            Some(partitions) => &partitions.ranges,
+            None => return None,
+        };
+
+        // The range a key falls in is the last one starting at or before it. The first range
+        // starts at the empty key.
+        let idx = match ranges.binary_search_by(|range| range.0.as_slice().cmp(key)) {
+            Ok(idx) => idx,
+            Err(idx) => idx - 1,
+        };
+
+        match ranges[idx].1 {
+            Some(server) if self.servers[server].active => Some(server),
+            _ => None,
+        }
+    }
+
+    /// Returns the server a key should be read from or written to.
+    ///
+    /// # Arguments
+    ///
+    /// * `table`: The table the key belongs to.
+    /// * `key`:   The key.
+    pub fn route(&self, table: u64, key: &[u8]) -> usize {
+        if self.routing == Routing::Static {
+            if let Some(server) = self.tables.get(&table) {
+                return *server;
+            }
+        }
+
+        self.lookup(hash(table, key))
+    }
+
+    /// Returns the server that extensions invoked by a tenant should run on. Invocations carry
+    /// no key, so all of a tenant's invocations go to the same server.
+    pub fn route_tenant(&self, tenant: u32) -> usize {
+        self.lookup(mix(tenant as u64))
+    }
+
+    /// Returns true if requests can be sent to a server: it is not marked down, or it has been
+    /// down long enough to be tried again.
+    ///
+    /// # Arguments
+    ///
+    /// * `server`: The server.
+    /// * `now`:    The current time-stamp in cycles.
+    pub fn available(&self, server: usize, now: u64) -> bool {
+        self.servers[server].health.down_until <= now
+    }
+
+    /// Records that a server answered a request.
+    pub fn success(&mut self, server: usize) {
+        let server = &mut self.servers[server];
+        server.stats.responses += 1;
+        server.health.failures = 0;
+        server.health.down_until = 0;
+    }
+
+    /// Records that a request to a server timed out, marking it down if too many did in a row.
+    ///
+    /// # Arguments
+    ///
+    /// * `server`: The server.
+    /// * `now`:    The current time-stamp in cycles.
+    pub fn failure(&mut self, server: usize, now: u64) {
+        let server = &mut self.servers[server];
+        server.stats.timeouts += 1;
+        server.health.failures += 1;
+        if server.health.failures >= self.failures {
+            server.health.down_until = now + self.retry;
+        }
+    }
+
+    // Places the virtual nodes of every active server on the ring, and maps the tables they
+    // list. A server's points depend only on it's index, so they land in the same place
+    // whichever other servers there are.
+    fn rebuild(&mut self) {
+        self.ring.clear();
+        self.tables.clear();
+        for (idx, server) in self.servers.iter().enumerate() {
+            if !server.active {
+                continue;
+            }
+
+            for point in 0..(server.weight * self.vnodes) {
+                self.ring
+                    .push((mix(((idx as u64) << 32) | point as u64), idx));
+            }
+
+            for table in server.tables.iter() {
+                self.tables.entry(*table).or_insert(idx);
+            }
+        }
+        self.ring.sort();
+    }
+
+    // Returns the server owning the first point on the ring at or after a hash.
+    fn lookup(&self, point: u64) -> usize {
+        let idx = match self.ring.binary_search(&(point, 0)) {
+            Ok(idx) => idx,
+            Err(idx) => idx,
+        };
+
+        self.ring[idx % self.ring.len()].1
+    }
+}
+
+// Parses a server's IPv4 address into network order.
+fn parse_ip(ip: &str) -> Option<[u8; 4]> {
+    Ipv4Addr::from_str(ip).ok().map(|ip| ip.octets())
+}
+
+// Hashes a key and the table it belongs to (FNV-1a, followed by a finalizer so that similar
+// keys land far apart on the ring).
+fn hash(table: u64, key: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for i in 0..8 {
+        h ^= (table >> (i * 8)) & 0xff;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+
+    for byte in key.iter() {
+        h ^= *byte as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+
+    mix(h)
+}
+
+// Scrambles a value (the finalizer of splitmix64).
+fn mix(val: u64) -> u64 {
+    let mut z = val.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Router;
+    use db::config::ServerEndpointConfig;
+    use db::partition::{Map, Range};
+
+    fn servers(n: usize) -> Vec<ServerEndpointConfig> {
+        (0..n)
+            .map(|_| ServerEndpointConfig {
+                weight: 1,
+                ..ServerEndpointConfig::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn hash() {
+        let router = Router::new(&servers(4), "hash", 64, 3, 100);
+
+        let mut counts = [0; 4];
+        for k in 0u32..4000 {
+            let key = format!("key{}", k);
+            let server = router.route(1, key.as_bytes());
+            assert_eq!(server, router.route(1, key.as_bytes()));
+            counts[server] += 1;
+        }
+
+        // Every server should own a reasonable share of the keys.
+        for count in counts.iter() {
+            assert!(*count > 500, "{:?}", counts);
+        }
+
+        // Adding a server only moves keys onto the new server.
+        let bigger = Router::new(&servers(5), "hash", 64, 3, 100);
+        for k in 0u32..4000 {
+            let key = format!("key{}", k);
+            let before = router.route(1, key.as_bytes());
+            let after = bigger.route(1, key.as_bytes());
+            assert!(after == before || after == 4);
+        }
+    }
+
+    #[test]
+    fn static_tables() {
+        let mut servers = servers(2);
+        servers[0].tables = vec![1];
+        servers[1].tables = vec![2, 3];
+
+        let router = Router::new(&servers, "static", 64, 3, 100);
+        assert_eq!(0, router.route(1, b"a"));
+        assert_eq!(1, router.route(2, b"a"));
+        assert_eq!(1, router.route(3, b"b"));
+        assert!(router.route(4, b"a") < 2);
+    }
+
+    #[test]
+    fn health() {
+        let mut router = Router::new(&servers(2), "hash", 64, 2, 100);
+        assert!(router.available(0, 0));
+
+        router.failure(0, 10);
+        assert!(router.available(0, 10));
+        router.failure(0, 10);
+        assert!(!router.available(0, 10));
+        assert!(!router.available(0, 109));
+        assert!(router.available(1, 10));
+
+        // Tried again once the retry interval passed, down again if that times out.
+        assert!(router.available(0, 110));
+        router.failure(0, 110);
+        assert!(!router.available(0, 150));
+
+        router.success(0);
+        assert!(router.available(0, 150));
+
+        let stats = router.stats(150);
+        assert_eq!(3, stats[0].timeouts);
+        assert_eq!(1, stats[0].responses);
+        assert!(stats[0].up);
+    }
+
+    #[test]
+    fn membership() {
+        let mut router = Router::new(&servers(3), "hash", 64, 3, 100);
+        let before: Vec<usize> = (0u32..3000)
+            .map(|k| router.route(1, format!("key{}", k).as_bytes()))
+            .collect();
+
+        // Adding a server at runtime moves keys only onto it, like building the router with it.
+        let added = router.add(&servers(1)[0]);
+        assert_eq!(3, added);
+        let built = Router::new(&servers(4), "hash", 64, 3, 100);
+        for k in 0u32..3000 {
+            let key = format!("key{}", k);
+            let after = router.route(1, key.as_bytes());
+            assert_eq!(built.route(1, key.as_bytes()), after);
+            assert!(after == before[k as usize] || after == 3);
+        }
+
+        // Removing it again moves every key back.
+        assert!(router.remove(3));
+        assert!(!router.active(3));
+        for k in 0u32..3000 {
+            let key = format!("key{}", k);
+            assert_eq!(before[k as usize], router.route(1, key.as_bytes()));
+        }
+
+        // Shares of the ring add upto one, and the last server cannot be removed.
+        let total: f64 = router.stats(0).iter().map(|s| s.share).sum();
+        assert!((total - 1.0).abs() < 1e-9);
+        assert_eq!(0.0, router.stats(0)[3].share);
+        assert!(router.remove(0) && router.remove(1));
+        assert!(!router.remove(2));
+        assert!((router.stats(0)[2].share - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn relocate() {
+        let mut servers = servers(3);
+        for (idx, server) in servers.iter_mut().enumerate() {
+            server.ip_address = format!("10.0.0.{}", idx + 1);
+        }
+
+        let mut router = Router::new(&servers, "hash", 64, 3, 100);
+        assert_eq!(None, router.relocated(7));
+        assert!(!router.relocate(7, [10, 0, 0, 9]));
+        assert!(router.relocate(7, [10, 0, 0, 2]));
+        assert_eq!(Some(1), router.relocated(7));
+        assert_eq!(None, router.relocated(8));
+
+        // Removed servers are not relocated to.
+        assert!(router.remove(1));
+        assert_eq!(None, router.relocated(7));
+        assert!(!router.relocate(7, [10, 0, 0, 2]));
+    }
+
+    #[test]
+    fn partitions() {
+        let mut servers = servers(3);
+        for (idx, server) in servers.iter_mut().enumerate() {
+            server.ip_address = format!("10.0.0.{}", idx + 1);
+        }
+
+        let range = |start: &[u8], last: u8| Range {
+            start: start.to_vec(),
+            ip: [10, 0, 0, last],
+        };
+        let map = Map::new(2, vec![range(b"", 3), range(b"m", 1), range(b"t", 9)]).unwrap();
+
+        let mut router = Router::new(&servers, "hash", 64, 3, 100);
+        assert_eq!(None, router.partitioned(7, b"a"));
+        assert!(router.partition(7, &map));
+        assert_eq!(Some(2), router.partitioned(7, b"a"));
+        assert_eq!(Some(0), router.partitioned(7, b"m"));
+        assert_eq!(Some(0), router.partitioned(7, b"pear"));
+        assert_eq!(None, router.partitioned(7, b"tea"));
+        assert_eq!(None, router.partitioned(8, b"a"));
+
+        // Older tables are ignored.
+        let old = Map::new(1, vec![range(b"", 2)]).unwrap();
+        assert!(!router.partition(7, &old));
+        assert_eq!(Some(2), router.partitioned(7, b"a"));
+    }
+}
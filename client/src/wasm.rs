@@ -0,0 +1,250 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! Runs extensions built for WASM, against the DB trait. The extension imports every call into
+//! the database it makes, as laid out in `sandstorm::wasm`, and is handed keys, values and
+//! responses through it's exported memory. Every invocation gets a fresh instance of the module.
+
+use std::fs::File;
+use std::io::Read;
+
+use wasmi::{self, Externals, FuncInstance, FuncRef, ImportsBuilder, MemoryRef};
+use wasmi::{ModuleImportResolver, ModuleInstance, NopExternals, RuntimeArgs, RuntimeValue};
+use wasmi::{Signature, Trap, TrapKind, ValueType};
+
+use sandstorm::db::DB;
+
+// The index of every import an extension can call.
+const GET: usize = 0;
+const TAKE: usize = 1;
+const PUT: usize = 2;
+const DEL: usize = 3;
+const ARGS_LEN: usize = 4;
+const ARGS: usize = 5;
+const RESUMED_LEN: usize = 6;
+const RESUMED: usize = 7;
+const RESP: usize = 8;
+const DEBUG_LOG: usize = 9;
+
+// The name, index, parameters and return type of every import.
+static IMPORTS: [(&str, usize, &[ValueType], Option<ValueType>); 10] = [
+    (
+        "sandstorm_get",
+        GET,
+        &[ValueType::I64, ValueType::I32, ValueType::I32],
+        Some(ValueType::I32),
+    ),
+    ("sandstorm_take", TAKE, &[ValueType::I32], None),
+    (
+        "sandstorm_put",
+        PUT,
+        &[ValueType::I64, ValueType::I32, ValueType::I32, ValueType::I32, ValueType::I32],
+        Some(ValueType::I32),
+    ),
+    ("sandstorm_del", DEL, &[ValueType::I64, ValueType::I32, ValueType::I32], None),
+    ("sandstorm_args_len", ARGS_LEN, &[], Some(ValueType::I32)),
+    ("sandstorm_args", ARGS, &[ValueType::I32], None),
+    ("sandstorm_resumed_len", RESUMED_LEN, &[], Some(ValueType::I32)),
+    ("sandstorm_resumed", RESUMED, &[ValueType::I32], None),
+    ("sandstorm_resp", RESP, &[ValueType::I32, ValueType::I32], None),
+    ("sandstorm_debug_log", DEBUG_LOG, &[ValueType::I32, ValueType::I32], None),
+];
+
+/// An extension built for WASM, loaded at the client.
+pub struct Module {
+    // The parsed and validated module, instantiated for every invocation.
+    module: wasmi::Module,
+}
+
+// Implementation of methods on Module.
+impl Module {
+    /// Loads an extension built for WASM.
+    ///
+    /// # Arguments
+    ///
+    /// * `path`: The path (absolute or relative) of the .wasm file containing the extension.
+    ///
+    /// # Return
+    ///
+    /// The extension, or None if the file could not be read or is not a valid module.
+    pub fn load(path: &str) -> Option<Module> {
+        let mut buf = Vec::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_end(&mut buf))
+            .ok()?;
+
+        wasmi::Module::from_buffer(buf)
+            .ok()
+            .map(|module| Module { module: module })
+    }
+
+    /// Runs an invocation of the extension to completion.
+    ///
+    /// # Arguments
+    ///
+    /// * `db`: The database the extension reads and writes, and responds through.
+    ///
+    /// # Return
+    ///
+    /// True if the invocation completed. False if the module imports something the executor
+    /// does not provide, does not export `memory` and `run`, or trapped.
+    pub fn run(&self, db: &DB) -> bool {
+        let imports = ImportsBuilder::new().with_resolver("env", &Resolver);
+        let instance = match ModuleInstance::new(&self.module, &imports) {
+            Ok(instance) => match instance.run_start(&mut NopExternals) {
+                Ok(instance) => instance,
+                Err(_) => return false,
+            },
+            Err(_) => return false,
+        };
+
+        let memory = instance
+            .export_by_name("memory")
+            .and_then(|export| export.as_memory().cloned());
+        let mut host = match memory {
+            Some(memory) => Host {
+                db: db,
+                memory: memory,
+                staged: None,
+            },
+            None => return false,
+        };
+
+        instance.invoke_export("run", &[], &mut host).is_ok()
+    }
+}
+
+// Resolves the imports of an extension to the calls into the database.
+struct Resolver;
+
+impl ModuleImportResolver for Resolver {
+    fn resolve_func(&self, name: &str, signature: &Signature) -> Result<FuncRef, wasmi::Error> {
+        let import = IMPORTS.iter().find(|import| import.0 == name);
+        match import {
+            Some(&(_, index, params, ret))
+                if signature.params() == params && signature.return_type() == ret =>
+            {
+                Ok(FuncInstance::alloc_host(Signature::new(params, ret), index))
+            }
+
+            _ => Err(wasmi::Error::Instantiation(format!("Unknown import {}", name))),
+        }
+    }
+}
+
+// The calls into the database made by an instance of an extension.
+struct Host<'a> {
+    // The database the calls are made on.
+    db: &'a DB,
+
+    // The memory exported by the instance.
+    memory: MemoryRef,
+
+    // The value of the key last looked up, until the extension copies it out.
+    staged: Option<Vec<u8>>,
+}
+
+// Implementation of methods on Host.
+impl<'a> Host<'a> {
+    // Copies bytes out of the instance's memory.
+    fn bytes(&self, ptr: u32, len: u32) -> Result<Vec<u8>, Trap> {
+        self.memory
+            .get(ptr, len as usize)
+            .map_err(|_| Trap::new(TrapKind::MemoryAccessOutOfBounds))
+    }
+
+    // Copies bytes into the instance's memory.
+    fn copy(&self, ptr: u32, data: &[u8]) -> Result<(), Trap> {
+        self.memory
+            .set(ptr, data)
+            .map_err(|_| Trap::new(TrapKind::MemoryAccessOutOfBounds))
+    }
+}
+
+impl<'a> Externals for Host<'a> {
+    fn invoke_index(
+        &mut self,
+        index: usize,
+        args: RuntimeArgs,
+    ) -> Result<Option<RuntimeValue>, Trap> {
+        match index {
+            GET => {
+                let key = self.bytes(args.nth_checked(1)?, args.nth_checked(2)?)?;
+                self.staged = self.db
+                    .get(args.nth_checked(0)?, &key)
+                    .map(|val| val.read().to_vec());
+                let len = self.staged.as_ref().map_or(-1, |val| val.len() as i32);
+                Ok(Some(RuntimeValue::I32(len)))
+            }
+
+            TAKE => {
+                let val = self.staged.take().unwrap_or_default();
+                self.copy(args.nth_checked(0)?, &val)?;
+                Ok(None)
+            }
+
+            PUT => {
+                let table: u64 = args.nth_checked(0)?;
+                let key = self.bytes(args.nth_checked(1)?, args.nth_checked(2)?)?;
+                let val = self.bytes(args.nth_checked(3)?, args.nth_checked(4)?)?;
+                let written = match self.db.alloc(table, &key, val.len() as u64) {
+                    Some(mut buf) => {
+                        buf.write_slice(&val);
+                        self.db.put(buf)
+                    }
+                    None => false,
+                };
+                Ok(Some(RuntimeValue::I32(written as i32)))
+            }
+
+            DEL => {
+                let key = self.bytes(args.nth_checked(1)?, args.nth_checked(2)?)?;
+                self.db.del(args.nth_checked(0)?, &key);
+                Ok(None)
+            }
+
+            ARGS_LEN => Ok(Some(RuntimeValue::I32(self.db.args().len() as i32))),
+
+            ARGS => {
+                self.copy(args.nth_checked(0)?, self.db.args())?;
+                Ok(None)
+            }
+
+            RESUMED_LEN => {
+                let len = self.db.resumed().map_or(-1, |state| state.len() as i32);
+                Ok(Some(RuntimeValue::I32(len)))
+            }
+
+            RESUMED => {
+                self.copy(args.nth_checked(0)?, self.db.resumed().unwrap_or(&[]))?;
+                Ok(None)
+            }
+
+            RESP => {
+                let data = self.bytes(args.nth_checked(0)?, args.nth_checked(1)?)?;
+                self.db.resp(&data);
+                Ok(None)
+            }
+
+            DEBUG_LOG => {
+                let msg = self.bytes(args.nth_checked(0)?, args.nth_checked(1)?)?;
+                self.db.debug_log(&String::from_utf8_lossy(&msg));
+                Ok(None)
+            }
+
+            _ => Err(Trap::new(TrapKind::Unreachable)),
+        }
+    }
+}
@@ -0,0 +1,137 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use std::collections::HashMap;
+use std::sync::mpsc::TryRecvError;
+
+use db::backend::NetBackend;
+use db::config::ClientConfig;
+
+use futures::sync::oneshot;
+
+use super::client::Client;
+use super::error::Error;
+use super::handle::Mailbox;
+
+/// The maximum number of operations a Worker takes out of it's mailbox in one step.
+const MAX_COMMANDS: usize = 32;
+
+/// Issues operations handed to it by a Handle over a Client of it's own. A Worker owns a network
+/// queue and the table of requests pending on it, and is meant to be stepped repeatedly by the
+/// thread that created it, typically one pinned to a core.
+pub struct Worker<T>
+where
+    T: NetBackend,
+{
+    // The client operations are issued through.
+    client: Client<T>,
+
+    // The mailbox operations are received on.
+    mailbox: Mailbox,
+
+    // Where to send the result of every operation issued so far, keyed by RPC identifier.
+    replies: HashMap<u64, oneshot::Sender<Result<Vec<u8>, Error>>>,
+
+    // Set once every Handle feeding the mailbox has been dropped.
+    closed: bool,
+}
+
+// Implementation of methods on Worker.
+impl<T> Worker<T>
+where
+    T: NetBackend,
+{
+    /// Creates a worker.
+    ///
+    /// # Arguments
+    ///
+    /// * `config`:  Network related configuration such as the MAC and IP address of the client
+    ///              and server, along with timeouts.
+    /// * `port`:    The network queue this worker sends requests and receives responses on. The
+    ///              worker's UDP source port is the queue's identifier, so every worker on a
+    ///              machine must be given a different queue.
+    /// * `tenant`:  The tenant of the worker's client. Operations from a Handle carry their own.
+    /// * `mailbox`: The mailbox operations are received on.
+    ///
+    /// # Return
+    ///
+    /// A worker that issues operations received on the mailbox.
+    pub fn new(config: &ClientConfig, port: T, tenant: u32, mailbox: Mailbox) -> Worker<T> {
+        Worker {
+            client: Client::new(config, port, tenant),
+            mailbox: mailbox,
+            replies: HashMap::new(),
+            closed: false,
+        }
+    }
+
+    /// Issues newly received operations, drives the network queue, and sends back the results
+    /// of operations that completed.
+    pub fn step(&mut self) {
+        for _ in 0..MAX_COMMANDS {
+            match self.mailbox.rx.try_recv() {
+                Ok(cmd) => {
+                    let id = self.client.issue_as(cmd.tenant, cmd.op);
+                    self.replies.insert(id, cmd.reply);
+                }
+
+                Err(TryRecvError::Empty) => break,
+
+                Err(TryRecvError::Disconnected) => {
+                    self.closed = true;
+                    break;
+                }
+            }
+        }
+
+        if self.replies.is_empty() {
+            return;
+        }
+
+        self.client.poll();
+
+        let client = &self.client;
+        let mut completed = Vec::new();
+        for id in self.replies.keys() {
+            if let Some(result) = client.take(*id) {
+                completed.push((*id, result));
+            }
+        }
+
+        for (id, result) in completed {
+            // The Reply may have been dropped, in which case nobody cares about the result.
+            let _ = self.replies.remove(&id).unwrap().send(result);
+        }
+    }
+
+    /// Steps the worker until every Handle feeding it has been dropped and every operation it
+    /// received has completed.
+    pub fn run(&mut self) {
+        while !self.closed || !self.replies.is_empty() {
+            self.step();
+        }
+    }
+
+    /// Returns the number of operations received by the worker that have not completed yet.
+    pub fn outstanding(&self) -> usize {
+        self.replies.len()
+    }
+
+    /// Returns the client operations are issued through, which can also be used directly from
+    /// the worker's thread.
+    pub fn client(&self) -> &Client<T> {
+        &self.client
+    }
+}
@@ -13,10 +13,70 @@
  * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
  */
 
-use std::mem::size_of;
+use std::collections::HashMap;
+use std::mem::{replace, size_of};
+use std::ptr;
+use std::sync::Arc;
 
 use bytes::{BufMut, Bytes, BytesMut};
 
+use spin::{Mutex, RwLock};
+
+/// The number of bytes an arena reserves from the system at a time. Objects are carved out of
+/// these chunks, so a chunk is returned to the system only once every object in it is dropped.
+const CHUNK_LEN: usize = 1 << 20;
+
+/// Objects larger than this many bytes are allocated on their own instead of out of an arena's
+/// chunk, so that a single large object does not waste the rest of a chunk.
+const MAX_CARVED: usize = CHUNK_LEN / 16;
+
+// The memory a single tenant's objects are allocated out of. Objects of different tenants never
+// share a chunk, so fragmentation caused by one tenant's writes does not hold on to memory on
+// behalf of others.
+struct Arena {
+    // The unused tail of the chunk objects are currently carved out of.
+    chunk: Mutex<BytesMut>,
+
+    // The start address of every chunk reserved by the arena that may still hold objects. A
+    // chunk is only forgotten once a census finds no objects in it, so this over-counts until
+    // the next census.
+    chunks: Mutex<Vec<usize>>,
+}
+
+// Implementation of methods on Arena.
+impl Arena {
+    // Returns an empty arena. Chunks are only reserved once objects are allocated.
+    fn new() -> Arena {
+        Arena {
+            chunk: Mutex::new(BytesMut::new()),
+            chunks: Mutex::new(Vec::new()),
+        }
+    }
+
+    // Returns an empty `BytesMut` with a capacity of exactly `size` bytes, carved out of the
+    // arena's current chunk if it is small enough.
+    fn carve(&self, size: usize) -> BytesMut {
+        if size > MAX_CARVED {
+            return BytesMut::with_capacity(size);
+        }
+
+        let mut chunk = self.chunk.lock();
+        if chunk.capacity() < size {
+            *chunk = BytesMut::with_capacity(CHUNK_LEN);
+
+            // A chunk freed since the last census can be handed back at the same address.
+            let start = chunk.as_ptr() as usize;
+            let mut chunks = self.chunks.lock();
+            if !chunks.contains(&start) {
+                chunks.push(start);
+            }
+        }
+
+        let rest = chunk.split_off(size);
+        replace(&mut *chunk, rest)
+    }
+}
+
 /// This type represents the memory allocator in Sandstorm. The allocator
 /// allocates and initializes objects that can then be inserted into a
 /// particular tenant's hash table. Each allocated object has the following
@@ -26,7 +86,12 @@ use bytes::{BufMut, Bytes, BytesMut};
 ///     | Tenant-ID | Table-ID  | Key-Length |     Key     |       Value       |
 ///     |___________|___________|____________|_____________|___________________|
 ///        4 Bytes     8 Bytes     2 Bytes      Var Length       Var Length
-pub struct Allocator {}
+///
+/// Every tenant's objects are allocated out of an arena of it's own.
+pub struct Allocator {
+    // The arena of every tenant that has allocated objects, created on it's first allocation.
+    arenas: RwLock<HashMap<u32, Arc<Arena>>>,
+}
 
 // Implementation of methods on Allocator.
 impl Allocator {
@@ -36,7 +101,69 @@ impl Allocator {
     /// # Return
     /// An allocator of type `Allocator`.
     pub fn new() -> Allocator {
-        Allocator {}
+        Allocator {
+            arenas: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// This method releases a tenant's arena, once the tenant has been removed from the system.
+    /// The arena's memory is returned to the system as the objects allocated out of it are
+    /// dropped, without visiting the objects.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant`: An identifier for the tenant whose arena should be released.
+    pub fn release(&self, tenant: u32) {
+        self.arenas.write().remove(&tenant);
+    }
+
+    /// This method starts a census of the memory held by every tenant's arena. Every live
+    /// object must then be passed to `Census::count()`, after which `Census::finish()` works out
+    /// how much of the memory reserved by each arena is taken up by objects.
+    ///
+    /// # Return
+    ///
+    /// A census of the chunks reserved at the time of the call.
+    pub fn census(&self) -> Census {
+        let mut arenas = Vec::new();
+        let mut chunks = Vec::new();
+        let mut current = Vec::new();
+        for (tenant, arena) in self.arenas.read().iter() {
+            let tail = arena.chunk.lock();
+            if tail.capacity() > 0 {
+                current.push(tail.as_ptr() as usize);
+            }
+
+            for start in arena.chunks.lock().iter() {
+                chunks.push(Chunk {
+                    start: *start,
+                    arena: arenas.len(),
+                    live: 0,
+                });
+            }
+            arenas.push((*tenant, Arc::clone(arena)));
+        }
+        chunks.sort_by_key(|chunk| chunk.start);
+
+        let mut census = Census {
+            arenas: arenas,
+            chunks: chunks,
+            current: Vec::new(),
+            standalone: HashMap::new(),
+        };
+        let current: Vec<usize> = current.iter().filter_map(|tail| census.find(*tail)).collect();
+        census.current = current;
+        census
+    }
+
+    // Returns the arena a tenant's objects are allocated out of, creating it if required.
+    fn arena(&self, tenant: u32) -> Arc<Arena> {
+        if let Some(arena) = self.arenas.read().get(&tenant) {
+            return Arc::clone(arena);
+        }
+
+        let mut arenas = self.arenas.write();
+        Arc::clone(arenas.entry(tenant).or_insert_with(|| Arc::new(Arena::new())))
     }
 
     /// This method allocates space for an object, and writes metadata and only
@@ -62,10 +189,15 @@ impl Allocator {
     ///
     /// # Return
     /// A `BytesMut` to the underlying allocation. Any writes to this handle
-    /// will be added to the object's value.
+    /// will be added to the object's value. None if the key is longer than
+    /// 64 KB, which the object's metadata cannot describe.
     pub fn raw(&self, tenant: u32, table: u64, key: &[u8], val_len: u64)
                -> Option<BytesMut>
     {
+        if key.len() > u16::max_value() as usize {
+            return None;
+        }
+
         // Allocate space for the object.
         match self.alloc(tenant, table, key.len() as u16, val_len) {
             // The allocation was successfull.
@@ -102,10 +234,15 @@ impl Allocator {
     /// A tupule corresponding to the allocated object. The first member is a
     /// `Bytes` handle over the underlying object's key. The second, is again a
     /// `Bytes` handle to the entire object. Returning both these handles allows
-    /// for easy insertion into the tenant's table.
+    /// for easy insertion into the tenant's table. None if the key is longer
+    /// than 64 KB, which the object's metadata cannot describe.
     pub fn object(&self, tenant: u32, table: u64, key: &[u8], val: &[u8])
                   -> Option<(Bytes, Bytes)>
     {
+        if key.len() > u16::max_value() as usize {
+            return None;
+        }
+
         // Allocate space for the object.
         match self.alloc(tenant, table, key.len() as u16, val.len() as u64) {
             // The allocation was successfull.
@@ -128,6 +265,80 @@ impl Allocator {
         }
     }
 
+    /// This method allocates a full object, like `object()`, at the start of a region with room
+    /// to spare after the object's value. Bytes appended to the value later can then be written
+    /// into the room by `extend()`, instead of copying the object into a larger one.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant`: An identifier for the tenant requesting for the allocation.
+    /// * `table`:  An identifier for the table the allocated object will be
+    ///             added to.
+    /// * `key`:    A slice of bytes representing the key for the object.
+    /// * `parts`:  The slices of bytes making up the value for the object, in
+    ///             order.
+    /// * `room`:   The number of bytes to leave free after the value.
+    ///
+    /// # Return
+    /// A tupule whose first member is a `Bytes` handle to the entire object,
+    /// and whose second member is a `Bytes` handle to the region, `room` bytes
+    /// longer than the object. None if the key is longer than 64 KB, which the
+    /// object's metadata cannot describe.
+    pub fn object_with_room(&self, tenant: u32, table: u64, key: &[u8], parts: &[&[u8]],
+                            room: usize) -> Option<(Bytes, Bytes)>
+    {
+        if key.len() > u16::max_value() as usize {
+            return None;
+        }
+
+        let val_len: usize = parts.iter().map(|part| part.len()).sum();
+        let mut region = match self.alloc(tenant, table, key.len() as u16,
+                                          (val_len + room) as u64)
+        {
+            Some(region) => region,
+            None => return None,
+        };
+
+        region.put_slice(key);
+        for part in parts.iter() {
+            region.put_slice(part);
+        }
+
+        // The room is zeroed, so that the region never exposes what the memory held before.
+        let used = region.len();
+        region.resize(used + room, 0);
+        let region: Bytes = region.freeze();
+        Some((region.slice_to(used), region))
+    }
+
+    /// This method appends bytes to the value of an object allocated by `object_with_room()`,
+    /// writing them into the room left after the object in it's region.
+    ///
+    /// # Arguments
+    ///
+    /// * `region`: The region the object was allocated at the start of.
+    /// * `object`: The entire object, a view of the start of the region.
+    /// * `data`:   The bytes to append to the object's value.
+    ///
+    /// # Return
+    /// A `Bytes` handle to the entire extended object, another view of the
+    /// start of the region.
+    ///
+    /// # Safety
+    /// The bytes written over must not be part of any view of the region. This
+    /// holds if the object is the longest view ever taken of the start of the
+    /// region, which `Table::room()` checks for the object under a key, as
+    /// long as the key's lock is held until the extended object is written
+    /// back to the table.
+    pub unsafe fn extend(&self, region: &Bytes, object: &Bytes, data: &[u8]) -> Bytes {
+        let used = object.len();
+        assert!(object.as_ptr() == region.as_ptr() && used + data.len() <= region.len());
+
+        let room = region.as_ptr().offset(used as isize) as *mut u8;
+        ptr::copy_nonoverlapping(data.as_ptr(), room, data.len());
+        region.slice_to(used + data.len())
+    }
+
     // This is an internal method the performs the actual allocation. The head
     // of each allocated piece of memory consists of metadata identifying the
     // tenant this object belongs to, the data table the object belongs to, and
@@ -151,9 +362,8 @@ impl Allocator {
                     key_len as usize + // To store the key.
                     val_len as usize;  // To store the value.
 
-        // Allocate space for the object.
-        // XXX This could actually allocate more than size bytes.
-        let mut object = BytesMut::with_capacity(size);
+        // Allocate space for the object out of the tenant's arena.
+        let mut object = self.arena(tenant).carve(size);
 
         // Write metadata into the object.
         object.put_u32_le(tenant);
@@ -196,6 +406,18 @@ impl Allocator {
         }
     }
 
+    /// This method returns the number of bytes an object takes up once allocated, which is
+    /// what it is charged to a memory quota for.
+    ///
+    /// # Arguments
+    ///
+    /// * `key_len`: The length of the object's key.
+    /// * `val_len`: The length of the object's value.
+    #[inline]
+    pub fn footprint(&self, key_len: usize, val_len: usize) -> usize {
+        self.meta_size() + key_len + val_len
+    }
+
     // This method returns the amount of metadata on each allocated object.
     #[inline]
     fn meta_size(&self) -> usize {
@@ -206,10 +428,124 @@ impl Allocator {
     }
 }
 
+/// The memory held by one tenant's objects, as worked out by a census.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ArenaUsage {
+    /// The tenant the memory is held on behalf of.
+    pub tenant: u32,
+
+    /// The number of chunks the tenant's objects are carved out of.
+    pub chunks: usize,
+
+    /// The number of bytes reserved from the system, in chunks and in objects too large to be
+    /// carved out of one.
+    pub reserved: usize,
+
+    /// The number of bytes of the reserved memory taken up by live objects.
+    pub live: usize,
+}
+
+// A chunk registered with an arena, along with the bytes counted in it so far.
+struct Chunk {
+    start: usize,
+    arena: usize,
+    live: usize,
+}
+
+/// A count of the live objects in each arena's chunks, started by `Allocator::census()`. The
+/// count is only an estimate if objects are written or dropped while it is taken.
+pub struct Census {
+    // Every arena at the start of the census, along with the tenant it belongs to.
+    arenas: Vec<(u32, Arc<Arena>)>,
+
+    // Every chunk registered at the start of the census, ordered by start address.
+    chunks: Vec<Chunk>,
+
+    // The index into `chunks` of the chunk each arena is currently carving objects out of.
+    current: Vec<usize>,
+
+    // The bytes taken up by objects that were not carved out of a chunk, by tenant.
+    standalone: HashMap<u32, usize>,
+}
+
+// Implementation of methods on Census.
+impl Census {
+    /// Counts a live object towards the census.
+    ///
+    /// # Arguments
+    ///
+    /// * `object`: The entire object, as allocated by the Allocator.
+    pub fn count(&mut self, object: &[u8]) {
+        if let Some(index) = self.find(object.as_ptr() as usize) {
+            self.chunks[index].live += object.len();
+            return;
+        }
+
+        // The object's tenant is the first thing in it's metadata.
+        if object.len() >= size_of::<u32>() {
+            let tenant = object[..4].iter().rev().fold(0u32, |t, b| (t << 8) | *b as u32);
+            *self.standalone.entry(tenant).or_insert(0) += object.len();
+        }
+    }
+
+    /// Completes the census. Chunks found to hold no objects have been returned to the system,
+    /// and are forgotten by their arena.
+    ///
+    /// # Return
+    ///
+    /// The memory held by every tenant's objects, ordered by tenant.
+    pub fn finish(self) -> Vec<ArenaUsage> {
+        let mut usage: HashMap<u32, ArenaUsage> = HashMap::new();
+        for &(tenant, _) in self.arenas.iter() {
+            usage.insert(tenant, ArenaUsage { tenant: tenant, chunks: 0, reserved: 0, live: 0 });
+        }
+
+        for (index, chunk) in self.chunks.iter().enumerate() {
+            let (tenant, ref arena) = self.arenas[chunk.arena];
+            if chunk.live == 0 && !self.current.contains(&index) {
+                arena.chunks.lock().retain(|start| *start != chunk.start);
+                continue;
+            }
+
+            let entry = usage.get_mut(&tenant).expect("Census lost track of an arena.");
+            entry.chunks += 1;
+            entry.reserved += CHUNK_LEN;
+            entry.live += chunk.live;
+        }
+
+        for (tenant, bytes) in self.standalone.iter() {
+            let entry = usage
+                .entry(*tenant)
+                .or_insert(ArenaUsage { tenant: *tenant, chunks: 0, reserved: 0, live: 0 });
+            entry.reserved += *bytes;
+            entry.live += *bytes;
+        }
+
+        let mut usage: Vec<ArenaUsage> = usage.into_iter().map(|(_, entry)| entry).collect();
+        usage.sort_by_key(|entry| entry.tenant);
+        usage
+    }
+
+    // Returns the index of the chunk an address falls within, if it falls within one.
+    fn find(&self, address: usize) -> Option<usize> {
+        let index = match self.chunks.binary_search_by_key(&address, |chunk| chunk.start) {
+            Ok(index) => return Some(index),
+            Err(0) => return None,
+            Err(index) => index - 1,
+        };
+
+        if address < self.chunks[index].start + CHUNK_LEN {
+            Some(index)
+        } else {
+            None
+        }
+    }
+}
+
 // This module contains simple unit tests for Allocator.
 #[cfg(test)]
 mod tests {
-    use super::Allocator;
+    use super::{Allocator, Arena, ArenaUsage, CHUNK_LEN, MAX_CARVED};
     use bytes::{BufMut, BytesMut};
 
     // This unit test verifies the return value of the "meta_size()" method
@@ -310,6 +646,18 @@ mod tests {
         }
     }
 
+    // This unit test verifies that keys too long for an object's metadata are refused, rather
+    // than having their length truncated.
+    #[test]
+    fn test_long_key() {
+        let heap = Allocator::new();
+        let key = vec![1; 1 << 16];
+
+        assert!(heap.raw(0, 1, &key, 8).is_none());
+        assert!(heap.object(0, 1, &key, &[2; 8]).is_none());
+        assert!(heap.object(0, 1, &key[1..], &[2; 8]).is_some());
+    }
+
     // This unit test verifies Allocator's "object()" method. It requests for
     // an allocation through the method, and verifies the size and contents
     // of the returned handle.
@@ -343,4 +691,100 @@ mod tests {
             }
         }
     }
+
+    // This unit test verifies that an object allocated with room to spare is a view of the start
+    // of it's region, that extending it in place leaves earlier views as they were, and that the
+    // room is zeroed.
+    #[test]
+    fn test_extend() {
+        let heap = Allocator::new();
+
+        let (obj, region) = heap
+            .object_with_room(7, 1, &[1, 2], &[&[3; 20], &[4; 20]], 64)
+            .expect("Failed to allocate object.");
+        assert_eq!(56, obj.len());
+        assert_eq!(120, region.len());
+        assert_eq!(obj.as_ptr(), region.as_ptr());
+        assert_eq!(&[0; 64][..], &region[56..]);
+
+        let longer = unsafe { heap.extend(&region, &obj, &[5; 64]) };
+        assert_eq!(120, longer.len());
+        assert_eq!(56, obj.len());
+
+        let (k, v) = heap.resolve(longer).expect("Failed to resolve object.");
+        assert_eq!(&[1, 2][..], &k[..]);
+        assert_eq!(&[3; 20][..], &v[..20]);
+        assert_eq!(&[4; 20][..], &v[20..40]);
+        assert_eq!(&[5; 64][..], &v[40..]);
+        let (_, v) = heap.resolve(obj).expect("Failed to resolve object.");
+        assert_eq!(40, v.len());
+    }
+
+    // This unit test verifies that objects carved out of an arena have exactly the requested
+    // capacity, and do not overlap.
+    #[test]
+    fn test_carve() {
+        let arena = Arena::new();
+
+        let mut a = arena.carve(100);
+        let mut b = arena.carve(100);
+        let c = arena.carve(MAX_CARVED + 1);
+        assert_eq!(100, a.capacity());
+        assert_eq!(100, b.capacity());
+        assert_eq!(MAX_CARVED + 1, c.capacity());
+
+        a.put_slice(&[1; 100]);
+        b.put_slice(&[2; 100]);
+        assert_eq!(&[1; 100][..], &a[..]);
+        assert_eq!(&[2; 100][..], &b[..]);
+    }
+
+    // This unit test verifies that releasing a tenant's arena does not invalidate objects
+    // allocated out of it.
+    #[test]
+    fn test_release() {
+        let heap = Allocator::new();
+
+        let (_, obj) = heap.object(7, 1, &[1, 2], &[3; 64]).expect("Failed to allocate object.");
+        heap.release(7);
+        let (k, v) = heap.resolve(obj).expect("Failed to resolve object.");
+        assert_eq!(&[1, 2][..], &k[..]);
+        assert_eq!(&[3; 64][..], &v[..]);
+    }
+
+    // This unit test verifies that a census charges objects to the arena they were carved out
+    // of, counts large objects on their own, and forgets chunks that no longer hold objects.
+    #[test]
+    fn test_census() {
+        let heap = Allocator::new();
+
+        let (_, a) = heap.object(1, 1, &[1], &[0; 100]).expect("Failed to allocate object.");
+        let (_, b) = heap.object(2, 1, &[2], &[0; MAX_CARVED]).expect("Failed to allocate object.");
+
+        let mut census = heap.census();
+        census.count(&a);
+        census.count(&b);
+        let usage = census.finish();
+        assert_eq!(
+            vec![
+                ArenaUsage { tenant: 1, chunks: 1, reserved: CHUNK_LEN, live: a.len() },
+                ArenaUsage { tenant: 2, chunks: 0, reserved: b.len(), live: b.len() },
+            ],
+            usage
+        );
+
+        // Fill up tenant 1's chunk so that a second one is reserved, and drop every object.
+        // Only the chunk objects are still being carved out of should be left.
+        let len = a.len();
+        drop(a);
+        for _ in 0..(CHUNK_LEN / len + 1) {
+            heap.object(1, 1, &[1], &[0; 100]).expect("Failed to allocate object.");
+        }
+
+        for _ in 0..2 {
+            let usage = heap.census().finish();
+            assert_eq!(1, usage[0].chunks);
+            assert_eq!(0, usage[0].live);
+        }
+    }
 }
@@ -0,0 +1,324 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+
+// Audit logging. Security relevant events -- extension installs, tenants and tables being
+// created, tenants being suspended, resumed and deleted, keys being replaced, tables being
+// shared or published, changes to the running config, and requests that failed to
+// authenticate -- are appended to a log along with the tenant they were carried out on behalf
+// of, or `OPERATOR` for changes the operator made to the whole server. Every record is a line
+// of JSON with a sequence number that never repeats, so that a reader can page through the
+// log. The most recent records are kept in memory to answer the audit() RPC, and every record
+// is also appended to a file, if one is configured.
+
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::{Once, ONCE_INIT};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::common::TenantId;
+
+use spin::Mutex;
+
+/// The number of records kept in memory. Older records can only be found in the log's file.
+const MAX_RECORDS: usize = 1 << 16;
+
+/// The maximum number of records returned by a single query.
+pub const MAX_QUERY: usize = 1024;
+
+/// The tenant events the operator carries out on the whole server, rather than on behalf of a
+/// tenant, are attributed to. No tenant can be created with it.
+pub const OPERATOR: TenantId = 0;
+
+/// A security relevant event.
+pub enum Event<'a> {
+    /// An extension was installed. `ok` is false if it failed to load.
+    Install { name: &'a str, ok: bool },
+
+    /// The tenant was created.
+    CreateTenant,
+
+    /// A table was created for the tenant.
+    CreateTable { table: u64 },
+
+    /// The tenant was suspended.
+    Suspend,
+
+    /// The tenant was resumed.
+    Resume,
+
+    /// The tenant was deleted.
+    DeleteTenant,
+
+    /// The tenant was drained and removed.
+    DrainTenant,
+
+    /// The tenant's key was replaced, or removed if `installed` is false.
+    Key { installed: bool },
+
+    /// The tenant changed the access another tenant has to one of it's tables. An `access` of
+    /// zero revokes access.
+    Grant { table: u64, grantee: TenantId, access: u8 },
+
+    /// The tenant published one of it's tables to every tenant, or withdrew it.
+    Publish { table: u64, published: bool },
+
+    /// A request to carry out `action` on behalf of the tenant did not carry the tenant's
+    /// credential.
+    AuthFailure { action: &'a str },
+
+    /// The operator changed a field of the running config through a config() RPC. Attributed to
+    /// `OPERATOR`. The values are rendered as TOML.
+    Config { field: &'a str, old: &'a str, new: &'a str },
+}
+
+// Implementation of methods on Event.
+impl<'a> Event<'a> {
+    // Returns the fields describing the event as JSON, without the enclosing braces.
+    fn fields(&self) -> String {
+        match *self {
+            Event::Install { name, ok } => {
+                format!("\"event\":\"install\",\"name\":{},\"ok\":{}", quote(name), ok)
+            }
+            Event::CreateTenant => String::from("\"event\":\"create_tenant\""),
+            Event::CreateTable { table } => {
+                format!("\"event\":\"create_table\",\"table\":{}", table)
+            }
+            Event::Suspend => String::from("\"event\":\"suspend\""),
+            Event::Resume => String::from("\"event\":\"resume\""),
+            Event::DeleteTenant => String::from("\"event\":\"delete_tenant\""),
+            Event::DrainTenant => String::from("\"event\":\"drain_tenant\""),
+            Event::Key { installed } => format!("\"event\":\"key\",\"installed\":{}", installed),
+            Event::Grant {
+                table,
+                grantee,
+                access,
+            } => format!(
+                "\"event\":\"grant\",\"table\":{},\"grantee\":{},\"access\":{}",
+                table, grantee, access
+            ),
+            Event::Publish { table, published } => format!(
+                "\"event\":\"publish\",\"table\":{},\"published\":{}",
+                table, published
+            ),
+            Event::AuthFailure { action } => {
+                format!("\"event\":\"auth_failure\",\"action\":{}", quote(action))
+            }
+            Event::Config { field, old, new } => format!(
+                "\"event\":\"config\",\"field\":{},\"old\":{},\"new\":{}",
+                quote(field),
+                quote(old),
+                quote(new)
+            ),
+        }
+    }
+}
+
+// The log. Records are only ever appended to it.
+struct Log {
+    // The sequence number of the next record.
+    next: u64,
+
+    // The most recent records, oldest first, along with the tenant each is attributed to.
+    records: VecDeque<(TenantId, u64, String)>,
+
+    // The file records are appended to, if any.
+    file: Option<File>,
+}
+
+// Implementation of methods on Log.
+impl Log {
+    // Returns an empty log that is not backed by a file.
+    fn new() -> Log {
+        Log {
+            next: 1,
+            records: VecDeque::new(),
+            file: None,
+        }
+    }
+
+    // Appends a record of an event at `ts` seconds since the UNIX epoch to the log.
+    fn append(&mut self, ts: u64, tenant: TenantId, event: &Event) {
+        let seq = self.next;
+        self.next += 1;
+
+        let line = format!(
+            "{{\"seq\":{},\"ts\":{},\"tenant\":{},{}}}",
+            seq,
+            ts,
+            tenant,
+            event.fields()
+        );
+
+        if let Some(ref mut file) = self.file {
+            if let Err(err) = file.write_all(line.as_bytes()).and_then(|_| file.write_all(b"\n")) {
+                warn!("Failed to write audit record: {}", err);
+            }
+        }
+
+        if self.records.len() == MAX_RECORDS {
+            self.records.pop_front();
+        }
+        self.records.push_back((tenant, seq, line));
+    }
+
+    // Returns upto `max` of a tenant's records kept in memory, starting at sequence number
+    // `since`, each followed by a newline.
+    fn query(&self, tenant: TenantId, since: u64, max: usize) -> Vec<u8> {
+        let mut ret = Vec::new();
+        for &(_, _, ref line) in self
+            .records
+            .iter()
+            .filter(|record| record.0 == tenant && record.1 >= since)
+            .take(max)
+        {
+            ret.extend_from_slice(line.as_bytes());
+            ret.push(b'\n');
+        }
+        ret
+    }
+}
+
+/// Initializes LOG exactly once.
+static LOG_INIT: Once = ONCE_INIT;
+
+/// The log shared by all threads. Use `shared()` to access it.
+static mut LOG: *const Mutex<Log> = 0 as *const Mutex<Log>;
+
+// Returns the log shared by all threads, allocating it on first use.
+fn shared() -> &'static Mutex<Log> {
+    unsafe {
+        LOG_INIT.call_once(|| {
+            LOG = Box::into_raw(Box::new(Mutex::new(Log::new())));
+        });
+        &*LOG
+    }
+}
+
+/// Configures the file audit records are appended to. Records already in memory are not
+/// written out to it.
+///
+/// # Arguments
+///
+/// * `path`: The file records are appended to. Empty if records are only kept in memory.
+pub fn configure(path: &str) {
+    let mut log = shared().lock();
+    log.file = None;
+
+    if path.len() > 0 {
+        match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => log.file = Some(file),
+            Err(err) => warn!("Failed to open audit log {}: {}", path, err),
+        }
+    }
+}
+
+/// Appends a record of an event to the audit log. Performs I/O if the log is backed by a file,
+/// so should not be called while processing requests on the data path.
+///
+/// # Arguments
+///
+/// * `tenant`: The tenant the event is attributed to.
+/// * `event`:  The event.
+pub fn record(tenant: TenantId, event: Event) {
+    let ts = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    shared().lock().append(ts, tenant, &event);
+}
+
+/// Looks up a tenant's records in the audit log.
+///
+/// # Arguments
+///
+/// * `tenant`: The tenant whose records should be returned.
+/// * `since`:  The smallest sequence number of records that should be returned.
+///
+/// # Return
+///
+/// The oldest records still kept in memory with a sequence number of at least `since`, upto
+/// `MAX_QUERY` of them, as lines of JSON.
+pub fn query(tenant: TenantId, since: u64) -> Vec<u8> {
+    shared().lock().query(tenant, since, MAX_QUERY)
+}
+
+/// Returns a string as a JSON string literal.
+pub fn quote(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len() + 2);
+    ret.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            c if (c as u32) < 0x20 => ret.push_str(&format!("\\u{:04x}", c as u32)),
+            c => ret.push(c),
+        }
+    }
+    ret.push('"');
+    ret
+}
+
+// This module contains unit tests for audit logging.
+#[cfg(test)]
+mod tests {
+    use super::{quote, Event, Log};
+
+    // This unit test verifies that records are formatted as lines of JSON, and that queries
+    // only return the records of the tenant asked for, starting at the sequence number asked for.
+    #[test]
+    fn test_query() {
+        let mut log = Log::new();
+        log.append(100, 1, &Event::CreateTenant);
+        log.append(101, 2, &Event::AuthFailure { action: "delete" });
+        log.append(102, 1, &Event::CreateTable { table: 7 });
+        log.append(
+            103,
+            1,
+            &Event::Grant {
+                table: 7,
+                grantee: 2,
+                access: 1,
+            },
+        );
+
+        assert_eq!(
+            "{\"seq\":1,\"ts\":100,\"tenant\":1,\"event\":\"create_tenant\"}\n\
+             {\"seq\":3,\"ts\":102,\"tenant\":1,\"event\":\"create_table\",\"table\":7}\n\
+             {\"seq\":4,\"ts\":103,\"tenant\":1,\"event\":\"grant\",\"table\":7,\"grantee\":2,\
+             \"access\":1}\n",
+            String::from_utf8(log.query(1, 0, 10)).unwrap()
+        );
+
+        assert_eq!(
+            "{\"seq\":2,\"ts\":101,\"tenant\":2,\"event\":\"auth_failure\",\
+             \"action\":\"delete\"}\n",
+            String::from_utf8(log.query(2, 0, 10)).unwrap()
+        );
+
+        let later = String::from_utf8(log.query(1, 4, 10)).unwrap();
+        assert!(later.starts_with("{\"seq\":4,"));
+        assert_eq!(1, log.query(1, 0, 1).iter().filter(|b| **b == b'\n').count());
+        assert_eq!(0, log.query(3, 0, 10).len());
+    }
+
+    // This unit test verifies that extension names are escaped.
+    #[test]
+    fn test_quote() {
+        assert_eq!("\"a\\\"b\\\\c\\u000a\"", quote("a\"b\\c\n"));
+    }
+}
@@ -0,0 +1,48 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use super::NetBackend;
+
+use e2d2::allocators::CacheAligned;
+use e2d2::common::Result;
+use e2d2::interface::{PacketRx, PacketTx, PortQueue};
+use e2d2::native::zcsi::MBuf;
+
+// The DPDK backend. Every Netbricks port queue pair is a backend.
+impl NetBackend for CacheAligned<PortQueue> {
+    /// Refer to the `NetBackend` trait for documentation.
+    #[inline]
+    fn rx_burst(&self, pkts: &mut [*mut MBuf]) -> Result<u32> {
+        self.recv(pkts)
+    }
+
+    /// Refer to the `NetBackend` trait for documentation.
+    #[inline]
+    fn tx_burst(&self, pkts: &mut [*mut MBuf]) -> Result<u32> {
+        self.send(pkts)
+    }
+
+    /// Refer to the `NetBackend` trait for documentation.
+    #[inline]
+    fn rxq(&self) -> i32 {
+        PortQueue::rxq(self)
+    }
+
+    /// Refer to the `NetBackend` trait for documentation.
+    #[inline]
+    fn txq(&self) -> i32 {
+        PortQueue::txq(self)
+    }
+}
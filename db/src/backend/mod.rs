@@ -0,0 +1,66 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+mod dpdk;
+mod loopback;
+mod socket;
+mod uring;
+mod xdp;
+
+pub use self::loopback::LoopbackBackend;
+pub use self::socket::SocketBackend;
+pub use self::uring::UringBackend;
+pub use self::xdp::XdpBackend;
+
+use std::fmt::Display;
+
+use e2d2::common::Result;
+use e2d2::native::zcsi::MBuf;
+
+/// The interface through which the database receives and transmits packets. Every dispatcher
+/// owns one receive and transmit queue pair on a backend, and all packets are exchanged in
+/// bursts of Netbricks MBufs carrying complete ethernet frames, irrespective of the backend.
+pub trait NetBackend: Display + Clone + Send + 'static {
+    /// Receives a burst of packets.
+    ///
+    /// # Arguments
+    ///
+    /// * `pkts`: A slice that received packets will be written into from the start. The caller
+    ///           owns every packet that was written.
+    ///
+    /// # Return
+    ///
+    /// The number of packets that were received.
+    fn rx_burst(&self, pkts: &mut [*mut MBuf]) -> Result<u32>;
+
+    /// Transmits a burst of packets.
+    ///
+    /// # Arguments
+    ///
+    /// * `pkts`: The packets to be transmitted. The backend takes ownership of every packet it
+    ///           transmits, starting from the first one. The caller retains ownership of the
+    ///           remaining packets.
+    ///
+    /// # Return
+    ///
+    /// The number of packets that were transmitted.
+    fn tx_burst(&self, pkts: &mut [*mut MBuf]) -> Result<u32>;
+
+    /// Returns the identifier of the receive queue on the backend.
+    fn rxq(&self) -> i32;
+
+    /// Returns the identifier of the transmit queue on the backend.
+    fn txq(&self) -> i32;
+}
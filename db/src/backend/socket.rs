@@ -0,0 +1,234 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use std::ffi::CString;
+use std::fmt;
+use std::io;
+use std::mem::{size_of, zeroed};
+use std::sync::Arc;
+
+use super::NetBackend;
+
+use e2d2::common::{ErrorKind, Result};
+use e2d2::native::zcsi::{mbuf_alloc, mbuf_free, MBuf};
+
+use libc;
+
+/// The largest frame that can be received. Matches the default data room of a Netbricks MBuf.
+const MAX_FRAME_LEN: usize = 2048;
+
+/// Socket option to join a group of packet sockets that incoming packets are spread across.
+/// Not exported by the version of libc in use.
+const PACKET_FANOUT: libc::c_int = 18;
+
+/// Spread packets across a fanout group by flow hash, keeping every flow on one socket.
+const PACKET_FANOUT_HASH: libc::c_int = 0;
+
+/// The ethertype of IPv4 packets.
+const ETH_P_IP: u16 = 0x0800;
+
+// Closes the socket when the last handle to it is dropped.
+pub(super) struct Fd(pub(super) libc::c_int);
+
+impl Drop for Fd {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+/// Opens a raw packet socket on a network interface, and adds it to the interface's fanout
+/// group, so that each socket on the interface receives a disjoint set of flows.
+///
+/// # Arguments
+///
+/// * `iface`: The name of the network interface (ex: "eth0").
+///
+/// # Return
+///
+/// The socket, which is closed when dropped.
+pub(super) fn open_packet_socket(iface: &str) -> io::Result<Fd> {
+    let name = CString::new(iface)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Bad interface name"))?;
+
+    unsafe {
+        let index = libc::if_nametoindex(name.as_ptr());
+        if index == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let fd = libc::socket(
+            libc::AF_PACKET,
+            libc::SOCK_RAW | libc::SOCK_NONBLOCK,
+            ETH_P_IP.to_be() as libc::c_int,
+        );
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = Fd(fd);
+
+        // Bind the socket to the interface.
+        let mut addr: libc::sockaddr_ll = zeroed();
+        addr.sll_family = libc::AF_PACKET as u16;
+        addr.sll_protocol = ETH_P_IP.to_be();
+        addr.sll_ifindex = index as i32;
+        if libc::bind(
+            fd.0,
+            &addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+            size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+        ) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Join the interface's fanout group. The interface index identifies the group.
+        let fanout: libc::c_int = (index as libc::c_int & 0xffff) | (PACKET_FANOUT_HASH << 16);
+        if libc::setsockopt(
+            fd.0,
+            libc::SOL_PACKET,
+            PACKET_FANOUT,
+            &fanout as *const libc::c_int as *const libc::c_void,
+            size_of::<libc::c_int>() as libc::socklen_t,
+        ) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(fd)
+    }
+}
+
+/// A backend that exchanges ethernet frames with the kernel over a raw packet socket. This does
+/// not require a NIC dedicated to DPDK, at the cost of a system call per packet. Every socket on
+/// an interface joins the same fanout group, so that each one receives a disjoint set of flows,
+/// much like the receive queues on a NIC.
+#[derive(Clone)]
+pub struct SocketBackend {
+    // The raw packet socket bound to the interface.
+    fd: Arc<Fd>,
+
+    // The name of the interface the socket is bound to.
+    iface: String,
+
+    // An identifier for the socket. Used as both, the receive and transmit queue.
+    queue: i32,
+}
+
+// Implementation of methods on SocketBackend.
+impl SocketBackend {
+    /// Creates a packet socket on a network interface.
+    ///
+    /// # Arguments
+    ///
+    /// * `iface`: The name of the network interface (ex: "eth0").
+    /// * `queue`: An identifier for the socket, reported as it's receive and transmit queue.
+    ///
+    /// # Return
+    ///
+    /// A backend that can be used to exchange packets on the interface.
+    pub fn new(iface: &str, queue: i32) -> io::Result<SocketBackend> {
+        Ok(SocketBackend {
+            fd: Arc::new(open_packet_socket(iface)?),
+            iface: String::from(iface),
+            queue: queue,
+        })
+    }
+}
+
+// Implementation of the NetBackend trait for SocketBackend.
+impl NetBackend for SocketBackend {
+    /// Refer to the `NetBackend` trait for documentation.
+    fn rx_burst(&self, pkts: &mut [*mut MBuf]) -> Result<u32> {
+        let mut recvd = 0;
+
+        for pkt in pkts.iter_mut() {
+            unsafe {
+                let mbuf = mbuf_alloc();
+                if mbuf.is_null() {
+                    break;
+                }
+
+                let len = libc::recv(
+                    self.fd.0,
+                    (*mbuf).data_address(0) as *mut libc::c_void,
+                    MAX_FRAME_LEN,
+                    libc::MSG_DONTWAIT,
+                );
+
+                // Nothing left to receive, or the receive failed.
+                if len <= 0 {
+                    mbuf_free(mbuf);
+                    break;
+                }
+
+                (*mbuf).add_data_end(len as usize);
+                *pkt = mbuf;
+            }
+
+            recvd += 1;
+        }
+
+        Ok(recvd)
+    }
+
+    /// Refer to the `NetBackend` trait for documentation.
+    fn tx_burst(&self, pkts: &mut [*mut MBuf]) -> Result<u32> {
+        let mut sent = 0;
+
+        for pkt in pkts.iter() {
+            unsafe {
+                let len = libc::send(
+                    self.fd.0,
+                    (**pkt).data_address(0) as *const libc::c_void,
+                    (**pkt).data_len(),
+                    libc::MSG_DONTWAIT,
+                );
+
+                // The caller retains every packet that could not be sent. Only report an error
+                // if nothing was sent, since the caller no longer owns the ones that were.
+                if len < 0 {
+                    match io::Error::last_os_error().kind() {
+                        io::ErrorKind::WouldBlock => break,
+                        _ if sent > 0 => break,
+                        _ => return Err(ErrorKind::CannotSend.into()),
+                    }
+                }
+
+                // The frame was copied into the kernel, release it.
+                mbuf_free(*pkt);
+            }
+
+            sent += 1;
+        }
+
+        Ok(sent)
+    }
+
+    /// Refer to the `NetBackend` trait for documentation.
+    fn rxq(&self) -> i32 {
+        self.queue
+    }
+
+    /// Refer to the `NetBackend` trait for documentation.
+    fn txq(&self) -> i32 {
+        self.queue
+    }
+}
+
+// Implementation of the Display trait for SocketBackend.
+impl fmt::Display for SocketBackend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "socket: iface: {} queue: {}", self.iface, self.queue)
+    }
+}
@@ -0,0 +1,635 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::mem::{size_of, zeroed};
+use std::ptr;
+use std::sync::atomic::{fence, Ordering};
+use std::sync::Arc;
+
+use super::socket::{open_packet_socket, Fd};
+use super::NetBackend;
+
+use e2d2::common::{ErrorKind, Result};
+use e2d2::native::zcsi::{mbuf_alloc, mbuf_free, MBuf};
+
+use libc;
+use spin::Mutex;
+
+// The following are from linux/io_uring.h, and are not exported by the version of libc in use.
+// Provided buffer rings and multishot receives require Linux 6.0 or later.
+const SYS_IO_URING_SETUP: libc::c_long = 425;
+const SYS_IO_URING_ENTER: libc::c_long = 426;
+const SYS_IO_URING_REGISTER: libc::c_long = 427;
+
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x8000000;
+const IORING_OFF_SQES: libc::off_t = 0x10000000;
+
+const IORING_ENTER_GETEVENTS: u32 = 1 << 0;
+
+const IORING_REGISTER_BUFFERS: u32 = 0;
+const IORING_REGISTER_PBUF_RING: u32 = 22;
+
+const IORING_OP_WRITE_FIXED: u8 = 5;
+const IORING_OP_RECV: u8 = 27;
+
+const IOSQE_BUFFER_SELECT: u8 = 1 << 5;
+const IORING_RECV_MULTISHOT: u16 = 1 << 1;
+
+const IORING_CQE_F_BUFFER: u32 = 1 << 0;
+const IORING_CQE_F_MORE: u32 = 1 << 1;
+const IORING_CQE_BUFFER_SHIFT: u32 = 16;
+
+/// The size of every receive and transmit buffer. Large enough for any frame that fits in an
+/// MBuf.
+const BUF_SIZE: usize = 2048;
+
+/// The number of buffers frames are received into. Must be a power of two.
+const NUM_RX_BUFS: usize = 2048;
+
+/// The number of registered buffers frames are transmitted from. Bounds the number of
+/// transmissions in flight.
+const NUM_TX_BUFS: usize = 1024;
+
+/// The number of submission queue entries. The kernel sizes the completion queue at twice this.
+const SQ_ENTRIES: u32 = 1024;
+
+/// The identifier of the provided buffer group that frames are received into.
+const RX_BGID: u16 = 0;
+
+/// Tags the completions of the multishot receive. Transmissions are tagged with the index of
+/// the buffer they were sent from.
+const RECV_TAG: u64 = u64::max_value();
+
+#[repr(C)]
+struct SqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+struct CqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+struct UringParams {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets,
+}
+
+#[repr(C)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    op_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    addr3: u64,
+    pad: u64,
+}
+
+#[repr(C)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+#[repr(C)]
+struct BufReg {
+    ring_addr: u64,
+    ring_entries: u32,
+    bgid: u16,
+    flags: u16,
+    resv: [u64; 3],
+}
+
+#[repr(C)]
+struct Buf {
+    addr: u64,
+    len: u32,
+    bid: u16,
+    resv: u16,
+}
+
+// A memory mapping, unmapped when dropped.
+struct Map {
+    addr: *mut u8,
+    len: usize,
+}
+
+// Implementation of methods on Map.
+impl Map {
+    // Maps `len` bytes of `fd` at offset `off`, or anonymous memory if `fd` is negative.
+    unsafe fn new(fd: libc::c_int, len: usize, off: libc::off_t) -> io::Result<Map> {
+        let flags = if fd < 0 {
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_POPULATE
+        } else {
+            libc::MAP_SHARED | libc::MAP_POPULATE
+        };
+
+        let addr = libc::mmap(
+            ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            flags,
+            fd,
+            off,
+        );
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Map {
+            addr: addr as *mut u8,
+            len: len,
+        })
+    }
+
+    // Returns a pointer to a `T` at byte offset `off` into the mapping.
+    #[inline]
+    unsafe fn at<T>(&self, off: usize) -> *mut T {
+        self.addr.offset(off as isize) as *mut T
+    }
+}
+
+impl Drop for Map {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.addr as *mut libc::c_void, self.len) };
+    }
+}
+
+// Reads an index shared with the kernel.
+#[inline]
+unsafe fn load_acquire<T: Copy>(p: *const T) -> T {
+    let v = ptr::read_volatile(p);
+    fence(Ordering::Acquire);
+    v
+}
+
+// Writes an index shared with the kernel.
+#[inline]
+unsafe fn store_release<T: Copy>(p: *mut T, v: T) {
+    fence(Ordering::Release);
+    ptr::write_volatile(p, v);
+}
+
+// An io_uring instance wrapping a packet socket, along with it's buffers.
+struct Uring {
+    // The packet socket and the ring. Declared before the mappings so that they are closed first.
+    socket: Fd,
+    ring: Fd,
+
+    // The submission queue ring and entries, and the completion queue ring.
+    sq: Map,
+    sqes: Map,
+    cq: Map,
+    params: UringParams,
+
+    // The submission queue tail, and the number of entries yet to be submitted.
+    sq_tail: u32,
+    to_submit: u32,
+
+    // The buffers frames are received into, and the ring they are provided to the kernel on.
+    rx_bufs: Map,
+    buf_ring: Map,
+    buf_tail: u16,
+
+    // The registered buffers frames are transmitted from, and the indices of the free ones.
+    tx_bufs: Map,
+    tx_free: Vec<u16>,
+
+    // Frames that were received, but have not been handed out to the dispatcher yet.
+    received: VecDeque<*mut MBuf>,
+
+    // Whether the multishot receive needs to be (re)submitted.
+    rearm: bool,
+}
+
+// The rings and buffers are only ever accessed while holding the lock around a Uring.
+unsafe impl Send for Uring {}
+
+// Implementation of methods on Uring.
+impl Uring {
+    // Sets up a ring over a packet socket on `iface`.
+    unsafe fn new(iface: &str) -> io::Result<Uring> {
+        let socket = open_packet_socket(iface)?;
+
+        let mut params: UringParams = zeroed();
+        let ring = libc::syscall(
+            SYS_IO_URING_SETUP,
+            SQ_ENTRIES,
+            &mut params as *mut UringParams,
+        );
+        if ring < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let ring = Fd(ring as libc::c_int);
+
+        let sq_len = params.sq_off.array as usize + params.sq_entries as usize * size_of::<u32>();
+        let cq_len = params.cq_off.cqes as usize + params.cq_entries as usize * size_of::<Cqe>();
+        let sq = Map::new(ring.0, sq_len, IORING_OFF_SQ_RING)?;
+        let cq = Map::new(ring.0, cq_len, IORING_OFF_CQ_RING)?;
+        let sqes = Map::new(
+            ring.0,
+            params.sq_entries as usize * size_of::<Sqe>(),
+            IORING_OFF_SQES,
+        )?;
+
+        // Provide the receive buffers to the kernel through a buffer ring.
+        let rx_bufs = Map::new(-1, NUM_RX_BUFS * BUF_SIZE, 0)?;
+        let buf_ring = Map::new(-1, NUM_RX_BUFS * size_of::<Buf>(), 0)?;
+        let reg = BufReg {
+            ring_addr: buf_ring.addr as u64,
+            ring_entries: NUM_RX_BUFS as u32,
+            bgid: RX_BGID,
+            flags: 0,
+            resv: [0; 3],
+        };
+        if libc::syscall(
+            SYS_IO_URING_REGISTER,
+            ring.0,
+            IORING_REGISTER_PBUF_RING,
+            &reg as *const BufReg,
+            1,
+        ) < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Register the transmit buffers, so that the kernel does not have to map them on
+        // every transmission.
+        let tx_bufs = Map::new(-1, NUM_TX_BUFS * BUF_SIZE, 0)?;
+        let iov = libc::iovec {
+            iov_base: tx_bufs.addr as *mut libc::c_void,
+            iov_len: tx_bufs.len,
+        };
+        if libc::syscall(
+            SYS_IO_URING_REGISTER,
+            ring.0,
+            IORING_REGISTER_BUFFERS,
+            &iov as *const libc::iovec,
+            1,
+        ) < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut uring = Uring {
+            socket: socket,
+            ring: ring,
+            sq: sq,
+            sqes: sqes,
+            cq: cq,
+            params: params,
+            sq_tail: 0,
+            to_submit: 0,
+            rx_bufs: rx_bufs,
+            buf_ring: buf_ring,
+            buf_tail: 0,
+            tx_bufs: tx_bufs,
+            tx_free: (0..NUM_TX_BUFS as u16).collect(),
+            received: VecDeque::with_capacity(NUM_RX_BUFS),
+            rearm: true,
+        };
+        uring.sq_tail = *uring.sq.at::<u32>(uring.params.sq_off.tail as usize);
+
+        for bid in 0..NUM_RX_BUFS as u16 {
+            uring.provide(bid);
+        }
+
+        Ok(uring)
+    }
+
+    // Returns a receive buffer to the kernel.
+    #[inline]
+    unsafe fn provide(&mut self, bid: u16) {
+        // The tail overlays the reserved field of the first entry, so it must not be written.
+        let buf = self
+            .buf_ring
+            .at::<Buf>((self.buf_tail as usize & (NUM_RX_BUFS - 1)) * size_of::<Buf>());
+        (*buf).addr = self.rx_bufs.addr as u64 + (bid as usize * BUF_SIZE) as u64;
+        (*buf).len = BUF_SIZE as u32;
+        (*buf).bid = bid;
+
+        self.buf_tail = self.buf_tail.wrapping_add(1);
+        store_release(self.buf_ring.at::<u16>(14), self.buf_tail);
+    }
+
+    // Returns the next free submission queue entry, zeroed, or None if the queue is full.
+    #[inline]
+    unsafe fn next_sqe(&mut self) -> Option<&mut Sqe> {
+        let head = load_acquire(self.sq.at::<u32>(self.params.sq_off.head as usize));
+        if self.sq_tail.wrapping_sub(head) >= self.params.sq_entries {
+            return None;
+        }
+
+        let mask = *self.sq.at::<u32>(self.params.sq_off.ring_mask as usize);
+        let idx = self.sq_tail & mask;
+        *self
+            .sq
+            .at::<u32>(self.params.sq_off.array as usize + idx as usize * size_of::<u32>()) = idx;
+
+        self.sq_tail = self.sq_tail.wrapping_add(1);
+        self.to_submit += 1;
+
+        let sqe = self.sqes.at::<Sqe>(idx as usize * size_of::<Sqe>());
+        ptr::write_bytes(sqe, 0, 1);
+        Some(&mut *sqe)
+    }
+
+    // Submits all queued entries to the kernel with a single system call, reaping completions
+    // along the way if `getevents` is true.
+    #[inline]
+    unsafe fn submit(&mut self, getevents: bool) -> io::Result<()> {
+        if self.to_submit == 0 && !getevents {
+            return Ok(());
+        }
+
+        store_release(
+            self.sq.at::<u32>(self.params.sq_off.tail as usize),
+            self.sq_tail,
+        );
+
+        let flags = if getevents { IORING_ENTER_GETEVENTS } else { 0 };
+        let ret = libc::syscall(
+            SYS_IO_URING_ENTER,
+            self.ring.0,
+            self.to_submit,
+            0,
+            flags,
+            ptr::null::<libc::c_void>(),
+            0,
+        );
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        self.to_submit -= ret as u32;
+        Ok(())
+    }
+
+    // Queues up a multishot receive on the socket, if one is not already active.
+    #[inline]
+    unsafe fn arm(&mut self) {
+        if !self.rearm {
+            return;
+        }
+
+        let fd = self.socket.0;
+        if let Some(sqe) = self.next_sqe() {
+            sqe.opcode = IORING_OP_RECV;
+            sqe.flags = IOSQE_BUFFER_SELECT;
+            sqe.ioprio = IORING_RECV_MULTISHOT;
+            sqe.fd = fd;
+            sqe.buf_index = RX_BGID;
+            sqe.user_data = RECV_TAG;
+        } else {
+            return;
+        }
+
+        self.rearm = false;
+    }
+
+    // Processes all completions, copying received frames into MBufs and releasing the buffers
+    // of completed transmissions.
+    unsafe fn reap(&mut self) {
+        let head_p = self.cq.at::<u32>(self.params.cq_off.head as usize);
+        let tail = load_acquire(self.cq.at::<u32>(self.params.cq_off.tail as usize));
+        let mask = *self.cq.at::<u32>(self.params.cq_off.ring_mask as usize);
+
+        let mut head = *head_p;
+        while head != tail {
+            let cqe = &*self.cq.at::<Cqe>(
+                self.params.cq_off.cqes as usize + (head & mask) as usize * size_of::<Cqe>(),
+            );
+
+            if cqe.user_data == RECV_TAG {
+                if cqe.flags & IORING_CQE_F_BUFFER != 0 {
+                    let bid = (cqe.flags >> IORING_CQE_BUFFER_SHIFT) as u16;
+
+                    // Copy the frame into an MBuf. If none are available, drop the frame.
+                    let mbuf = if cqe.res > 0 { mbuf_alloc() } else { ptr::null_mut() };
+                    if !mbuf.is_null() {
+                        ptr::copy_nonoverlapping(
+                            self.rx_bufs.at::<u8>(bid as usize * BUF_SIZE),
+                            (*mbuf).data_address(0),
+                            cqe.res as usize,
+                        );
+                        (*mbuf).add_data_end(cqe.res as usize);
+                        self.received.push_back(mbuf);
+                    }
+
+                    self.provide(bid);
+                }
+
+                // The kernel terminates a multishot receive when it runs out of buffers, or on
+                // an error. Resubmit it on the next poll.
+                if cqe.flags & IORING_CQE_F_MORE == 0 {
+                    self.rearm = true;
+                }
+            } else {
+                self.tx_free.push(cqe.user_data as u16);
+            }
+
+            head = head.wrapping_add(1);
+        }
+
+        store_release(head_p, head);
+    }
+}
+
+/// A backend that exchanges ethernet frames with the kernel through io_uring, for environments
+/// where AF_XDP is not available. Frames are received through a single multishot receive into a
+/// ring of kernel-selected buffers, and transmitted from registered buffers, so that a burst of
+/// packets costs at most one system call instead of one per packet. Like the socket backend,
+/// every instance is a packet socket in the interface's fanout group, and frames are copied
+/// between the ring's buffers and MBufs.
+#[derive(Clone)]
+pub struct UringBackend {
+    uring: Arc<Mutex<Uring>>,
+
+    // The name of the interface the socket is bound to.
+    iface: String,
+
+    // An identifier for the socket. Used as both, the receive and transmit queue.
+    queue: i32,
+}
+
+// Implementation of methods on UringBackend.
+impl UringBackend {
+    /// Creates an io_uring backed packet socket on a network interface.
+    ///
+    /// # Arguments
+    ///
+    /// * `iface`: The name of the network interface (ex: "eth0").
+    /// * `queue`: An identifier for the socket, reported as it's receive and transmit queue.
+    ///
+    /// # Return
+    ///
+    /// A backend that can be used to exchange packets on the interface.
+    pub fn new(iface: &str, queue: i32) -> io::Result<UringBackend> {
+        let uring = unsafe { Uring::new(iface)? };
+
+        Ok(UringBackend {
+            uring: Arc::new(Mutex::new(uring)),
+            iface: String::from(iface),
+            queue: queue,
+        })
+    }
+}
+
+// Implementation of the NetBackend trait for UringBackend.
+impl NetBackend for UringBackend {
+    /// Refer to the `NetBackend` trait for documentation.
+    fn rx_burst(&self, pkts: &mut [*mut MBuf]) -> Result<u32> {
+        let mut uring = self.uring.lock();
+
+        unsafe {
+            uring.reap();
+
+            // Nothing was pending. Enter the kernel once so that it can post completions for
+            // frames that have arrived since, and resubmit the receive if needed.
+            if uring.received.is_empty() {
+                uring.arm();
+                if uring.submit(true).is_err() {
+                    return Err(ErrorKind::BadQueue.into());
+                }
+                uring.reap();
+            }
+        }
+
+        let mut recvd = 0;
+        for pkt in pkts.iter_mut() {
+            match uring.received.pop_front() {
+                Some(mbuf) => *pkt = mbuf,
+                None => break,
+            }
+
+            recvd += 1;
+        }
+
+        Ok(recvd)
+    }
+
+    /// Refer to the `NetBackend` trait for documentation.
+    fn tx_burst(&self, pkts: &mut [*mut MBuf]) -> Result<u32> {
+        let mut uring = self.uring.lock();
+        let mut sent = 0;
+
+        unsafe {
+            // Reclaim the buffers of completed transmissions before picking new ones.
+            uring.reap();
+
+            let fd = uring.socket.0;
+            for pkt in pkts.iter() {
+                let len = (**pkt).data_len();
+                if len > BUF_SIZE {
+                    break;
+                }
+
+                let idx = match uring.tx_free.pop() {
+                    Some(idx) => idx,
+                    None => break,
+                };
+                let buf = uring.tx_bufs.at::<u8>(idx as usize * BUF_SIZE);
+
+                let queued = match uring.next_sqe() {
+                    Some(sqe) => {
+                        sqe.opcode = IORING_OP_WRITE_FIXED;
+                        sqe.fd = fd;
+                        sqe.addr = buf as u64;
+                        sqe.len = len as u32;
+                        sqe.buf_index = 0;
+                        sqe.user_data = idx as u64;
+                        true
+                    }
+
+                    None => false,
+                };
+
+                if !queued {
+                    uring.tx_free.push(idx);
+                    break;
+                }
+
+                ptr::copy_nonoverlapping((**pkt).data_address(0), buf, len);
+                mbuf_free(*pkt);
+
+                sent += 1;
+            }
+
+            // Submit the whole burst with one system call. The frames were already copied out,
+            // so they are considered sent even if the kernel is unable to accept them right now;
+            // they will be submitted along with the next burst.
+            if uring.submit(false).is_err() && sent == 0 {
+                return Err(ErrorKind::CannotSend.into());
+            }
+        }
+
+        Ok(sent)
+    }
+
+    /// Refer to the `NetBackend` trait for documentation.
+    fn rxq(&self) -> i32 {
+        self.queue
+    }
+
+    /// Refer to the `NetBackend` trait for documentation.
+    fn txq(&self) -> i32 {
+        self.queue
+    }
+}
+
+// Implementation of the Display trait for UringBackend.
+impl fmt::Display for UringBackend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "io_uring: iface: {} queue: {}", self.iface, self.queue)
+    }
+}
@@ -0,0 +1,557 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use std::ffi::CString;
+use std::fmt;
+use std::io;
+use std::mem::{size_of, zeroed};
+use std::ptr;
+use std::sync::atomic::{fence, Ordering};
+use std::sync::Arc;
+
+use super::NetBackend;
+
+use e2d2::common::{ErrorKind, Result};
+use e2d2::native::zcsi::{mbuf_alloc, mbuf_free, MBuf};
+
+use libc;
+use spin::Mutex;
+
+// The following are from linux/if_xdp.h and linux/bpf.h, and are not exported by the version
+// of libc in use. Only the original (4.18) versions of the structures are used; newer kernels
+// accept these based on their size.
+const AF_XDP: libc::c_int = 44;
+const SOL_XDP: libc::c_int = 283;
+const XDP_MMAP_OFFSETS: libc::c_int = 1;
+const XDP_RX_RING: libc::c_int = 2;
+const XDP_TX_RING: libc::c_int = 3;
+const XDP_UMEM_REG: libc::c_int = 4;
+const XDP_UMEM_FILL_RING: libc::c_int = 5;
+const XDP_UMEM_COMPLETION_RING: libc::c_int = 6;
+const XDP_PGOFF_RX_RING: libc::off_t = 0;
+const XDP_PGOFF_TX_RING: libc::off_t = 0x80000000;
+const XDP_UMEM_PGOFF_FILL_RING: libc::off_t = 0x100000000;
+const XDP_UMEM_PGOFF_COMPLETION_RING: libc::off_t = 0x180000000;
+
+const SYS_BPF: libc::c_long = 321;
+const BPF_MAP_UPDATE_ELEM: libc::c_int = 2;
+const BPF_OBJ_GET: libc::c_int = 7;
+
+/// The size of every frame in the umem. Large enough for any frame that fits in an MBuf.
+const FRAME_SIZE: usize = 2048;
+
+/// The number of frames in the umem. Half are used for receives, and half for transmits.
+const NUM_FRAMES: usize = 4096;
+
+/// The number of descriptors on every ring.
+const RING_SIZE: u32 = 2048;
+
+#[repr(C)]
+struct SockaddrXdp {
+    sxdp_family: u16,
+    sxdp_flags: u16,
+    sxdp_ifindex: u32,
+    sxdp_queue_id: u32,
+    sxdp_shared_umem_fd: u32,
+}
+
+#[repr(C)]
+struct XdpRingOffset {
+    producer: u64,
+    consumer: u64,
+    desc: u64,
+}
+
+#[repr(C)]
+struct XdpMmapOffsets {
+    rx: XdpRingOffset,
+    tx: XdpRingOffset,
+    fr: XdpRingOffset,
+    cr: XdpRingOffset,
+}
+
+#[repr(C)]
+struct XdpUmemReg {
+    addr: u64,
+    len: u64,
+    chunk_size: u32,
+    headroom: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct XdpDesc {
+    addr: u64,
+    len: u32,
+    options: u32,
+}
+
+#[repr(C)]
+struct BpfObjGet {
+    pathname: u64,
+    bpf_fd: u32,
+    file_flags: u32,
+}
+
+#[repr(C)]
+struct BpfMapUpdate {
+    map_fd: u32,
+    pad: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+/// A single producer, single consumer ring shared with the kernel.
+struct Ring {
+    // Pointers to the producer and consumer indices inside the mapping.
+    producer: *mut u32,
+    consumer: *mut u32,
+
+    // Pointer to the first entry on the ring.
+    entries: *mut u8,
+
+    // The mapping the ring lives in, along with it's length.
+    map: *mut libc::c_void,
+    map_len: usize,
+}
+
+// Implementation of methods on Ring.
+impl Ring {
+    // Maps a ring of `T` entries at page offset `pgoff` of a socket.
+    unsafe fn map<T>(fd: libc::c_int, off: &XdpRingOffset, pgoff: libc::off_t) -> io::Result<Ring> {
+        let len = off.desc as usize + RING_SIZE as usize * size_of::<T>();
+        let map = libc::mmap(
+            ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED | libc::MAP_POPULATE,
+            fd,
+            pgoff,
+        );
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        let base = map as *mut u8;
+        Ok(Ring {
+            producer: base.offset(off.producer as isize) as *mut u32,
+            consumer: base.offset(off.consumer as isize) as *mut u32,
+            entries: base.offset(off.desc as isize),
+            map: map,
+            map_len: len,
+        })
+    }
+
+    // Returns a pointer to the entry at index `idx`.
+    #[inline]
+    unsafe fn entry<T>(&self, idx: u32) -> *mut T {
+        (self.entries as *mut T).offset((idx & (RING_SIZE - 1)) as isize)
+    }
+
+    // Returns the number of entries available to consume, and the consumer index.
+    #[inline]
+    unsafe fn available(&self) -> (u32, u32) {
+        let prod = ptr::read_volatile(self.producer);
+        fence(Ordering::Acquire);
+        let cons = ptr::read_volatile(self.consumer);
+        (prod.wrapping_sub(cons), cons)
+    }
+
+    // Returns the number of free slots to produce into, and the producer index.
+    #[inline]
+    unsafe fn free(&self) -> (u32, u32) {
+        let cons = ptr::read_volatile(self.consumer);
+        fence(Ordering::Acquire);
+        let prod = ptr::read_volatile(self.producer);
+        (RING_SIZE - prod.wrapping_sub(cons), prod)
+    }
+
+    // Publishes entries up to `prod` to the kernel.
+    #[inline]
+    unsafe fn produce(&self, prod: u32) {
+        fence(Ordering::Release);
+        ptr::write_volatile(self.producer, prod);
+    }
+
+    // Releases entries up to `cons` back to the kernel.
+    #[inline]
+    unsafe fn consume(&self, cons: u32) {
+        fence(Ordering::Release);
+        ptr::write_volatile(self.consumer, cons);
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        // Rings that were never mapped are zeroed.
+        if !self.map.is_null() {
+            unsafe { libc::munmap(self.map, self.map_len) };
+        }
+    }
+}
+
+// An AF_XDP socket along with it's umem and rings.
+struct Xsk {
+    fd: libc::c_int,
+
+    // The umem that all frames are received into and transmitted from.
+    umem: *mut u8,
+
+    rx: Ring,
+    tx: Ring,
+    fill: Ring,
+    completion: Ring,
+
+    // Umem frames that are free to transmit from.
+    tx_frames: Vec<u64>,
+}
+
+// The rings and umem are only ever accessed while holding the lock around an Xsk.
+unsafe impl Send for Xsk {}
+
+impl Drop for Xsk {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+            libc::munmap(self.umem as *mut libc::c_void, NUM_FRAMES * FRAME_SIZE);
+        }
+    }
+}
+
+/// A backend that exchanges packets with a NIC queue over an AF_XDP socket, for deployments
+/// that cannot dedicate the NIC to DPDK. An XDP program redirecting packets to the socket must
+/// already be attached to the interface, and it's XSKMAP must be pinned to the BPF filesystem;
+/// the socket inserts itself into the map at the index of it's queue. Frames are copied between
+/// the umem and MBufs, so the rest of the database sees the same packets as with DPDK.
+#[derive(Clone)]
+pub struct XdpBackend {
+    xsk: Arc<Mutex<Xsk>>,
+
+    // The name of the interface the socket is bound to.
+    iface: String,
+
+    // The NIC queue the socket is bound to. Used as both, the receive and transmit queue.
+    queue: i32,
+}
+
+// Returns the last OS error if `ret` is negative.
+fn check(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+// Sets an integer socket option on an XDP socket.
+unsafe fn set_ring_size(fd: libc::c_int, opt: libc::c_int) -> io::Result<()> {
+    let size = RING_SIZE;
+    check(libc::setsockopt(
+        fd,
+        SOL_XDP,
+        opt,
+        &size as *const u32 as *const libc::c_void,
+        size_of::<u32>() as libc::socklen_t,
+    )).map(|_| ())
+}
+
+// Inserts an XDP socket into the XSKMAP pinned at `path`, at index `queue`.
+unsafe fn insert_xsk(path: &str, queue: u32, fd: libc::c_int) -> io::Result<()> {
+    let path = CString::new(path)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Bad XSKMAP path"))?;
+
+    let mut get: BpfObjGet = zeroed();
+    get.pathname = path.as_ptr() as u64;
+    let map = libc::syscall(SYS_BPF, BPF_OBJ_GET, &get as *const BpfObjGet, size_of::<BpfObjGet>());
+    let map = check(map as libc::c_int)?;
+
+    let value: u32 = fd as u32;
+    let mut update: BpfMapUpdate = zeroed();
+    update.map_fd = map as u32;
+    update.key = &queue as *const u32 as u64;
+    update.value = &value as *const u32 as u64;
+    let ret = libc::syscall(
+        SYS_BPF,
+        BPF_MAP_UPDATE_ELEM,
+        &update as *const BpfMapUpdate,
+        size_of::<BpfMapUpdate>(),
+    );
+    libc::close(map);
+
+    check(ret as libc::c_int).map(|_| ())
+}
+
+// Implementation of methods on XdpBackend.
+impl XdpBackend {
+    /// Creates an AF_XDP socket on a queue of a network interface.
+    ///
+    /// # Arguments
+    ///
+    /// * `iface`: The name of the network interface (ex: "eth0").
+    /// * `queue`: The NIC queue to bind the socket to.
+    /// * `map`:   The path at which the XSKMAP of the interface's XDP program is pinned.
+    ///
+    /// # Return
+    ///
+    /// A backend that can be used to exchange packets on the interface's queue.
+    pub fn new(iface: &str, queue: i32, map: &str) -> io::Result<XdpBackend> {
+        let name = CString::new(iface)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Bad interface name"))?;
+
+        unsafe {
+            let index = libc::if_nametoindex(name.as_ptr());
+            if index == 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            // Allocate the umem. Every frame is FRAME_SIZE bytes long.
+            let umem = libc::mmap(
+                ptr::null_mut(),
+                NUM_FRAMES * FRAME_SIZE,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_POPULATE,
+                -1,
+                0,
+            );
+            if umem == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+
+            let fd = match check(libc::socket(AF_XDP, libc::SOCK_RAW, 0)) {
+                Ok(fd) => fd,
+                Err(err) => {
+                    libc::munmap(umem, NUM_FRAMES * FRAME_SIZE);
+                    return Err(err);
+                }
+            };
+
+            // From here on, the umem and socket are released by Xsk's destructor on an error.
+            let mut xsk = Xsk {
+                fd: fd,
+                umem: umem as *mut u8,
+                rx: zeroed(),
+                tx: zeroed(),
+                fill: zeroed(),
+                completion: zeroed(),
+                tx_frames: Vec::with_capacity(NUM_FRAMES / 2),
+            };
+            XdpBackend::setup(&mut xsk, index, queue as u32)?;
+            insert_xsk(map, queue as u32, fd)?;
+
+            Ok(XdpBackend {
+                xsk: Arc::new(Mutex::new(xsk)),
+                iface: String::from(iface),
+                queue: queue,
+            })
+        }
+    }
+
+    // Registers the umem, maps all rings, binds the socket, and hands half of the umem over to
+    // the kernel to receive into.
+    unsafe fn setup(xsk: &mut Xsk, index: u32, queue: u32) -> io::Result<()> {
+        let reg = XdpUmemReg {
+            addr: xsk.umem as u64,
+            len: (NUM_FRAMES * FRAME_SIZE) as u64,
+            chunk_size: FRAME_SIZE as u32,
+            headroom: 0,
+        };
+        check(libc::setsockopt(
+            xsk.fd,
+            SOL_XDP,
+            XDP_UMEM_REG,
+            &reg as *const XdpUmemReg as *const libc::c_void,
+            size_of::<XdpUmemReg>() as libc::socklen_t,
+        ))?;
+
+        set_ring_size(xsk.fd, XDP_UMEM_FILL_RING)?;
+        set_ring_size(xsk.fd, XDP_UMEM_COMPLETION_RING)?;
+        set_ring_size(xsk.fd, XDP_RX_RING)?;
+        set_ring_size(xsk.fd, XDP_TX_RING)?;
+
+        let mut off: XdpMmapOffsets = zeroed();
+        let mut len = size_of::<XdpMmapOffsets>() as libc::socklen_t;
+        check(libc::getsockopt(
+            xsk.fd,
+            SOL_XDP,
+            XDP_MMAP_OFFSETS,
+            &mut off as *mut XdpMmapOffsets as *mut libc::c_void,
+            &mut len,
+        ))?;
+
+        ptr::write(&mut xsk.rx, Ring::map::<XdpDesc>(xsk.fd, &off.rx, XDP_PGOFF_RX_RING)?);
+        ptr::write(&mut xsk.tx, Ring::map::<XdpDesc>(xsk.fd, &off.tx, XDP_PGOFF_TX_RING)?);
+        ptr::write(&mut xsk.fill, Ring::map::<u64>(xsk.fd, &off.fr, XDP_UMEM_PGOFF_FILL_RING)?);
+        ptr::write(
+            &mut xsk.completion,
+            Ring::map::<u64>(xsk.fd, &off.cr, XDP_UMEM_PGOFF_COMPLETION_RING)?,
+        );
+
+        let addr = SockaddrXdp {
+            sxdp_family: AF_XDP as u16,
+            sxdp_flags: 0,
+            sxdp_ifindex: index,
+            sxdp_queue_id: queue,
+            sxdp_shared_umem_fd: 0,
+        };
+        check(libc::bind(
+            xsk.fd,
+            &addr as *const SockaddrXdp as *const libc::sockaddr,
+            size_of::<SockaddrXdp>() as libc::socklen_t,
+        ))?;
+
+        // The first half of the umem is used for receives, the second half for transmits.
+        let (_, prod) = xsk.fill.free();
+        for i in 0..(NUM_FRAMES / 2) {
+            *xsk.fill.entry::<u64>(prod + i as u32) = (i * FRAME_SIZE) as u64;
+        }
+        xsk.fill.produce(prod + (NUM_FRAMES / 2) as u32);
+
+        for i in (NUM_FRAMES / 2)..NUM_FRAMES {
+            xsk.tx_frames.push((i * FRAME_SIZE) as u64);
+        }
+
+        Ok(())
+    }
+}
+
+// Implementation of the NetBackend trait for XdpBackend.
+impl NetBackend for XdpBackend {
+    /// Refer to the `NetBackend` trait for documentation.
+    fn rx_burst(&self, pkts: &mut [*mut MBuf]) -> Result<u32> {
+        let xsk = self.xsk.lock();
+        let mut recvd = 0;
+
+        unsafe {
+            let (avail, cons) = xsk.rx.available();
+            let (_, fill) = xsk.fill.free();
+
+            let n = ::std::cmp::min(avail as usize, pkts.len()) as u32;
+            for i in 0..n {
+                let desc: XdpDesc = *xsk.rx.entry::<XdpDesc>(cons + i);
+
+                // Copy the frame into an MBuf. If none are available, drop the frame.
+                let mbuf = mbuf_alloc();
+                if !mbuf.is_null() {
+                    ptr::copy_nonoverlapping(
+                        xsk.umem.offset(desc.addr as isize),
+                        (*mbuf).data_address(0),
+                        desc.len as usize,
+                    );
+                    (*mbuf).add_data_end(desc.len as usize);
+                    pkts[recvd as usize] = mbuf;
+                    recvd += 1;
+                }
+
+                // Hand the frame back to the kernel. The fill ring has as many slots as there
+                // are receive frames, so there is always room.
+                *xsk.fill.entry::<u64>(fill + i) = desc.addr;
+            }
+
+            xsk.rx.consume(cons + n);
+            xsk.fill.produce(fill + n);
+        }
+
+        Ok(recvd)
+    }
+
+    /// Refer to the `NetBackend` trait for documentation.
+    fn tx_burst(&self, pkts: &mut [*mut MBuf]) -> Result<u32> {
+        let mut xsk = self.xsk.lock();
+        let mut sent = 0;
+
+        unsafe {
+            // First, reclaim frames whose transmission has completed.
+            let (done, cons) = xsk.completion.available();
+            for i in 0..done {
+                let addr = *xsk.completion.entry::<u64>(cons + i);
+                xsk.tx_frames.push(addr);
+            }
+            xsk.completion.consume(cons + done);
+
+            // Next, copy packets into free frames and post them on the transmit ring.
+            let (free, prod) = xsk.tx.free();
+            for pkt in pkts.iter() {
+                if sent >= free {
+                    break;
+                }
+
+                let len = (**pkt).data_len();
+                if len > FRAME_SIZE {
+                    break;
+                }
+
+                let addr = match xsk.tx_frames.pop() {
+                    Some(addr) => addr,
+                    None => break,
+                };
+
+                ptr::copy_nonoverlapping(
+                    (**pkt).data_address(0),
+                    xsk.umem.offset(addr as isize),
+                    len,
+                );
+                *xsk.tx.entry::<XdpDesc>(prod + sent) = XdpDesc {
+                    addr: addr,
+                    len: len as u32,
+                    options: 0,
+                };
+                mbuf_free(*pkt);
+
+                sent += 1;
+            }
+
+            if sent == 0 {
+                return Ok(0);
+            }
+
+            // Publish the descriptors, and kick the kernel to transmit them.
+            xsk.tx.produce(prod + sent);
+            let ret = libc::sendto(
+                xsk.fd,
+                ptr::null(),
+                0,
+                libc::MSG_DONTWAIT,
+                ptr::null(),
+                0,
+            );
+            if ret < 0 {
+                match io::Error::last_os_error().kind() {
+                    io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => {}
+                    _ => return Err(ErrorKind::CannotSend.into()),
+                }
+            }
+        }
+
+        Ok(sent)
+    }
+
+    /// Refer to the `NetBackend` trait for documentation.
+    fn rxq(&self) -> i32 {
+        self.queue
+    }
+
+    /// Refer to the `NetBackend` trait for documentation.
+    fn txq(&self) -> i32 {
+        self.queue
+    }
+}
+
+// Implementation of the Display trait for XdpBackend.
+impl fmt::Display for XdpBackend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "xdp: iface: {} queue: {}", self.iface, self.queue)
+    }
+}
@@ -0,0 +1,996 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+
+// Backups of every table to S3-compatible object storage (see `s3`). A full backup streams a
+// snapshot of every table each tenant owns to the bucket (see `snapshot`). The backups taken in
+// between full ones only hold the objects written since the last backup (incremental), or since
+// the last full backup (differential), as records of puts and deletes encoded the way
+// replication encodes them (see `replica::encode()`). Rather than every write in the log since
+// the last backup, these hold the last state of every key written, which is all a restore needs.
+// Backups are numbered, and laid out in the bucket as:
+//
+//   <id>/manifest.toml      the kind of backup, the backup it builds on, and it's tables
+//   <id>/<tenant>-<table>   the snapshot, or the records, of one table
+//   latest                  the number of the last complete backup
+//
+// The manifest is written after every table, and "latest" after the manifest, so a backup that
+// fails halfway is never restored from. Restoring a backup applies the full backup it builds on
+// and then every backup up to it, in order, through the path writes take, so that restored
+// objects are shipped to a standby as well. Objects missing from the backup are left alone, a
+// restore that fails partway leaves what it restored until then, and tenants must already exist
+// at the server restored to.
+//
+// Every backup is taken at one consistent cut: the moment it starts. Writes made while it is
+// being written out save the value they overwrite first, once per key, and the backup holds the
+// saved values rather than the newer ones, as well as objects deleted since the cut. So a backup
+// never holds one table of a tenant as of a later moment than another, such as TAO associations
+// pointing at objects that do not exist in the same backup. Saved values are kept in
+// memory until the backup is written out, which takes as much memory as the keys written to
+// while it runs.
+//
+// Values of tenants that have a key are sealed with it, so a tenant must still have the key a
+// backup was taken under when it is restored, and a value that does not open with it fails the
+// restore. The first backup after a server starts is always
+// a full one, since writes made before the start were not tracked.
+
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter};
+use std::mem;
+use std::str::{from_utf8, FromStr};
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use std::sync::{Arc, Once, ONCE_INIT};
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::audit::quote;
+use super::common::{TableId, TenantId};
+use super::crypt::Key;
+use super::master::Master;
+use super::replica::{self, Record};
+use super::s3::Store;
+use super::snapshot;
+use super::toml;
+
+use spin::{Mutex, RwLock};
+
+/// The kinds of backups.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    /// Every object of every table.
+    Full,
+
+    /// The objects written since the last backup.
+    Incremental,
+
+    /// The objects written since the last full backup.
+    Differential,
+}
+
+// Implementation of methods on Kind.
+impl Kind {
+    // Returns the name the kind goes by in manifests and status.
+    fn name(&self) -> &'static str {
+        match *self {
+            Kind::Full => "full",
+            Kind::Incremental => "incremental",
+            Kind::Differential => "differential",
+        }
+    }
+}
+
+/// How backups are taken.
+pub struct Settings {
+    /// The bucket backups are written to.
+    pub store: Store,
+
+    /// The number of seconds between backups, or zero if backups are only taken on request.
+    pub interval_s: u64,
+
+    /// Every how many backups a full one is taken. Zero or one makes every backup a full one.
+    pub full_every: u64,
+
+    /// True if the backups between full ones are differential rather than incremental.
+    pub differential: bool,
+}
+
+// What a backup holds, written to the bucket as TOML.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Manifest {
+    // The backup's number, it's kind, and the number of the backup it builds on, zero for a full
+    // backup.
+    id: u64,
+    kind: String,
+    base: u64,
+
+    // The number of seconds since the epoch when the backup started.
+    time: u64,
+
+    tables: Vec<Entry>,
+}
+
+// A table in a backup.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Entry {
+    tenant: u32,
+    table: u64,
+
+    // The number of objects or records written out.
+    objects: u64,
+
+    // Whether values are sealed with the tenant's key.
+    sealed: bool,
+}
+
+// What was asked of `serve()`.
+enum Request {
+    Backup(bool),
+    Restore(u64),
+}
+
+// The state of the backups taken since the server started.
+struct History {
+    // The numbers of the last backup and the last full backup, zero if none was taken.
+    last: u64,
+    last_full: u64,
+
+    // The number of backups taken since the last full backup.
+    since_full: u64,
+}
+
+// The state of the last backup or restore.
+struct Status {
+    // "backing up", "restoring", "done" or "failed", and why it failed if it did.
+    phase: &'static str,
+    error: String,
+
+    // What is being backed up or restored, and the number of objects written out or restored.
+    id: u64,
+    kind: &'static str,
+    objects: u64,
+}
+
+// Reads the current value of an object, None if there is none.
+type ReadFn = Box<Fn(TenantId, TableId, &[u8]) -> Option<Vec<u8>> + Send + Sync>;
+
+// The cut a backup is taken at, holding the value every key had at the cut, if it was written
+// to since.
+struct Cut {
+    read: ReadFn,
+    before: Mutex<HashMap<(TenantId, TableId, Vec<u8>), Option<Vec<u8>>>>,
+}
+
+// Implementation of methods on Cut.
+impl Cut {
+    // Returns a cut that reads the current values of objects with `read`.
+    fn new(read: ReadFn) -> Cut {
+        Cut {
+            read: read,
+            before: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Saves the value of the key a write is about to overwrite, unless it was saved before.
+    // Must be called before the write is applied. The key's value is read without the lock
+    // held, since the backup holds a lock on the table's bucket while it takes this one.
+    fn save(&self, rec: &Record) {
+        let key = (rec.tenant, rec.table, rec.key.to_vec());
+        if self.before.lock().contains_key(&key) {
+            return;
+        }
+
+        // Another write to the key can only have been applied after it saved the value, so
+        // the first value saved is the one at the cut.
+        let val = (self.read)(rec.tenant, rec.table, rec.key);
+        self.before.lock().entry(key).or_insert(val);
+    }
+
+    // Returns the value a key had at the cut, given it's current value. Must be called after
+    // the current value was read, so that a write applied in between was saved.
+    fn at(
+        &self,
+        tenant: TenantId,
+        table: TableId,
+        key: &[u8],
+        val: Option<Vec<u8>>,
+    ) -> Option<Vec<u8>> {
+        match self.before.lock().get(&(tenant, table, key.to_vec())) {
+            Some(before) => before.clone(),
+            None => val,
+        }
+    }
+
+    // Returns the keys of a table that had a value at the cut and are not in `seen`, because
+    // they were deleted since, along with those values.
+    fn deleted(
+        &self,
+        tenant: TenantId,
+        table: TableId,
+        seen: &HashSet<Vec<u8>>,
+    ) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.before
+            .lock()
+            .iter()
+            .filter(|&(&(t, tt, ref k), _)| t == tenant && tt == table && !seen.contains(k))
+            .filter_map(|(&(_, _, ref k), val)| val.clone().map(|val| (k.clone(), val)))
+            .collect()
+    }
+}
+
+// The state shared by all threads.
+struct Shared {
+    settings: RwLock<Option<Settings>>,
+    cut: RwLock<Option<Cut>>,
+    dirty: Mutex<HashMap<(TenantId, TableId), HashSet<Vec<u8>>>>,
+    requested: Mutex<Option<Request>>,
+    history: Mutex<History>,
+    status: Mutex<Option<Status>>,
+}
+
+/// Set once backups are configured, so that writes are only tracked when they will be backed up.
+static TRACKING: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Initializes SHARED exactly once.
+static INIT: Once = ONCE_INIT;
+
+/// The state shared by all threads. Use `shared()` to access it.
+static mut SHARED: *const Shared = 0 as *const Shared;
+
+// Returns the state shared by all threads, allocating it on first use.
+fn shared() -> &'static Shared {
+    unsafe {
+        INIT.call_once(|| {
+            SHARED = Box::into_raw(Box::new(Shared {
+                settings: RwLock::new(None),
+                cut: RwLock::new(None),
+                dirty: Mutex::new(HashMap::new()),
+                requested: Mutex::new(None),
+                history: Mutex::new(History {
+                    last: 0,
+                    last_full: 0,
+                    since_full: 0,
+                }),
+                status: Mutex::new(None),
+            }));
+        });
+        &*SHARED
+    }
+}
+
+/// Sets up backups. Must be called before the server accepts requests, with `serve()` called on
+/// a thread of it's own.
+///
+/// # Arguments
+///
+/// * `settings`: How backups are taken.
+pub fn configure(settings: Settings) {
+    *shared().settings.write() = Some(settings);
+    TRACKING.store(true, Ordering::Release);
+}
+
+/// Returns true if backups were configured.
+pub fn configured() -> bool {
+    TRACKING.load(Ordering::Acquire)
+}
+
+/// Applies a write, noting that the object was written so that the next backup that is not a
+/// full one holds it. While a backup is being taken, the value the write overwrites is saved
+/// first, so that the backup holds the value at it's cut.
+///
+/// # Arguments
+///
+/// * `rec`:   The record of the write.
+/// * `apply`: Applies the write locally.
+///
+/// # Return
+///
+/// Whatever `apply` returned.
+#[inline]
+pub fn write<T, F: FnOnce() -> T>(rec: &Record, apply: F) -> T {
+    if !TRACKING.load(Ordering::Relaxed) {
+        return apply();
+    }
+
+    // The cut is held until the write is applied, so that a backup's cut never falls between
+    // noting a write and applying it.
+    let cut = shared().cut.read();
+    shared()
+        .dirty
+        .lock()
+        .entry((rec.tenant, rec.table))
+        .or_insert(HashSet::new())
+        .insert(rec.key.to_vec());
+    if let Some(ref cut) = *cut {
+        cut.save(rec);
+    }
+    apply()
+}
+
+/// Asks for a backup to be taken. The backup is taken by `serve()`.
+///
+/// # Arguments
+///
+/// * `full`: True if the backup must be a full one.
+///
+/// # Return
+///
+/// False if backups are not configured, or a backup or restore is already waiting to run.
+pub fn start(full: bool) -> bool {
+    request(Request::Backup(full))
+}
+
+/// Asks for a backup to be restored. The backup is restored by `serve()`.
+///
+/// # Arguments
+///
+/// * `id`: The number of the backup, or zero for the last one.
+///
+/// # Return
+///
+/// False if backups are not configured, or a backup or restore is already waiting to run.
+pub fn restore(id: u64) -> bool {
+    request(Request::Restore(id))
+}
+
+// Hands a request to `serve()`, unless another one is waiting.
+fn request(req: Request) -> bool {
+    if !configured() {
+        return false;
+    }
+
+    let mut requested = shared().requested.lock();
+    if requested.is_some() {
+        return false;
+    }
+    *requested = Some(req);
+    true
+}
+
+/// Takes backups every configured interval, and the backups and restores asked for by `start()`
+/// and `restore()`. Blocks the calling thread.
+///
+/// # Arguments
+///
+/// * `master`: Master, which tables are read from and restored into.
+pub fn serve(master: Arc<Master>) {
+    let mut last = Instant::now();
+    loop {
+        let interval_s = shared().settings.read().as_ref().map_or(0, |s| s.interval_s);
+        let requested = shared().requested.lock().take();
+        let requested = match requested {
+            Some(requested) => requested,
+            None if interval_s > 0 && last.elapsed() >= Duration::from_secs(interval_s) => {
+                Request::Backup(false)
+            }
+            None => {
+                sleep(Duration::from_millis(100));
+                continue;
+            }
+        };
+
+        match requested {
+            Request::Backup(full) => {
+                backup(&master, full);
+                last = Instant::now();
+            }
+            Request::Restore(id) => restore_backup(&master, id),
+        }
+    }
+}
+
+// Returns the kind of the next backup, and the number of the backup it builds on.
+fn next_kind(full: bool, history: &History, settings: &Settings) -> (Kind, u64) {
+    if full || history.last_full == 0 || history.since_full + 1 >= settings.full_every {
+        return (Kind::Full, 0);
+    }
+
+    match settings.differential {
+        true => (Kind::Differential, history.last_full),
+        false => (Kind::Incremental, history.last),
+    }
+}
+
+// Takes a backup, and records how it went.
+fn backup(master: &Arc<Master>, full: bool) {
+    let settings = shared().settings.read();
+    let settings = match *settings {
+        Some(ref settings) => settings,
+        None => return,
+    };
+
+    // Backups carry on from the last one in the bucket, so that their numbers are never reused.
+    let id = match latest(&settings.store) {
+        Ok(latest) => latest + 1,
+        Err(e) => {
+            error!("Failed to read the last backup: {}", e);
+            set_status("failed", 0, "", 0, e.to_string());
+            return;
+        }
+    };
+
+    let (kind, base) = next_kind(full, &shared().history.lock(), settings);
+    set_status("backing up", id, kind.name(), 0, String::new());
+    info!("Taking {} backup {}", kind.name(), id);
+
+    // Writes made from here on go into the next backup, and save the values they overwrite
+    // until this one is written out. The keys written before are put back if the backup fails,
+    // or if the next one builds on the same full backup.
+    let dirty = {
+        let mut cut = shared().cut.write();
+        let reader = Arc::clone(master);
+        *cut = Some(Cut::new(Box::new(move |tenant, table, key| {
+            reader.read_object(tenant, table, key)
+        })));
+        mem::replace(&mut *shared().dirty.lock(), HashMap::new())
+    };
+    let result = {
+        let cut = shared().cut.read();
+        let cut = cut.as_ref().unwrap();
+        take(master, cut, &settings.store, id, kind, base, &dirty)
+    };
+    *shared().cut.write() = None;
+    if result.is_err() || kind == Kind::Differential {
+        let mut current = shared().dirty.lock();
+        for (table, keys) in dirty.into_iter() {
+            current.entry(table).or_insert(HashSet::new()).extend(keys);
+        }
+    }
+
+    match result {
+        Ok(objects) => {
+            let mut history = shared().history.lock();
+            history.last = id;
+            history.since_full += 1;
+            if kind == Kind::Full {
+                history.last_full = id;
+                history.since_full = 0;
+            }
+
+            info!("Took {} backup {} of {} objects", kind.name(), id, objects);
+            set_status("done", id, kind.name(), objects, String::new());
+        }
+
+        Err(e) => {
+            error!("Failed to take backup {}: {}", id, e);
+            set_status("failed", id, kind.name(), 0, e.to_string());
+        }
+    }
+}
+
+// Writes a backup at a cut to the bucket, and returns the number of objects written out.
+fn take(
+    master: &Master,
+    cut: &Cut,
+    store: &Store,
+    id: u64,
+    kind: Kind,
+    base: u64,
+    dirty: &HashMap<(TenantId, TableId), HashSet<Vec<u8>>>,
+) -> io::Result<u64> {
+    let mut manifest = Manifest {
+        id: id,
+        kind: kind.name().to_string(),
+        base: base,
+        time: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+        tables: Vec::new(),
+    };
+
+    // A full backup holds every table, and the others only those written to.
+    let mut tables: Vec<(TenantId, TableId)> = match kind {
+        Kind::Full => master
+            .tenant_ids()
+            .into_iter()
+            .flat_map(|tenant| {
+                let tables = master.owned_tables(tenant).unwrap_or(Vec::new());
+                tables.into_iter().map(move |table| (tenant, table))
+            })
+            .collect(),
+        _ => dirty
+            .keys()
+            .filter(|&&(tenant, _)| master.owned_tables(tenant).is_some())
+            .cloned()
+            .collect(),
+    };
+    tables.sort();
+
+    let mut objects = 0;
+    for &(tenant, table) in tables.iter() {
+        let key = master.tenant_key(tenant);
+        let name = object(id, tenant, table);
+        let count = match kind {
+            Kind::Full => put_snapshot(master, cut, store, &name, id, (tenant, table), &key)?,
+            _ => {
+                let keys = dirty.get(&(tenant, table)).unwrap();
+                put_delta(master, cut, store, &name, (tenant, table), keys, &key)?
+            }
+        };
+
+        manifest.tables.push(Entry {
+            tenant: tenant,
+            table: table,
+            objects: count,
+            sealed: key.is_some(),
+        });
+        objects += count;
+        if let Some(ref mut status) = *shared().status.lock() {
+            status.objects = objects;
+        }
+    }
+
+    let manifest = toml::Value::try_from(&manifest)
+        .and_then(|value| toml::to_string(&value))
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    store.put(&format!("{}/manifest.toml", id), manifest.as_bytes())?;
+    store.put("latest", id.to_string().as_bytes())?;
+
+    Ok(objects)
+}
+
+// Streams a snapshot of a table at a cut to the bucket through a temporary file, and returns the
+// number of objects in it.
+fn put_snapshot(
+    master: &Master,
+    cut: &Cut,
+    store: &Store,
+    name: &str,
+    id: u64,
+    (tenant, table): (TenantId, TableId),
+    key: &Option<Arc<Key>>,
+) -> io::Result<u64> {
+    let path = env::temp_dir().join(format!("splinter-backup-{}-{}-{}", id, tenant, table));
+    let path = path.to_string_lossy().into_owned();
+
+    let result = (|| {
+        let file = File::create(&path)?;
+        let flags = key.as_ref().map_or(0, |_| snapshot::FLAG_SEALED);
+        let mut writer = snapshot::Writer::new(BufWriter::new(file), tenant, table, flags)?;
+
+        let mut failed = None;
+        let mut seen = HashSet::new();
+        {
+            let mut push = |k: &[u8], val: &[u8]| {
+                if failed.is_some() {
+                    return;
+                }
+                failed = match *key {
+                    Some(ref key) => writer.push(k, &key.seal(table, k, val)).err(),
+                    None => writer.push(k, val).err(),
+                };
+            };
+
+            // Objects written since the cut are written out as they were at the cut, and those
+            // created since are left out. Objects deleted since are written out after the rest.
+            master.visit_table(tenant, table, &mut |rec| {
+                seen.insert(rec.key.to_vec());
+                if let Some(val) = cut.at(tenant, table, rec.key, Some(rec.val.to_vec())) {
+                    push(rec.key, &val);
+                }
+            });
+            for (k, val) in cut.deleted(tenant, table, &seen).into_iter() {
+                push(&k, &val);
+            }
+        }
+        if let Some(err) = failed {
+            return Err(err);
+        }
+
+        let count = writer.finish()?;
+        store.put_file(name, &path)?;
+        Ok(count)
+    })();
+
+    let _ = fs::remove_file(&path);
+    result
+}
+
+// Writes a record of the state at a cut of every key of a table written to since the last backup
+// to the bucket, and returns the number of records.
+fn put_delta(
+    master: &Master,
+    cut: &Cut,
+    store: &Store,
+    name: &str,
+    (tenant, table): (TenantId, TableId),
+    keys: &HashSet<Vec<u8>>,
+    key: &Option<Arc<Key>>,
+) -> io::Result<u64> {
+    let mut records = Vec::new();
+    for k in keys.iter() {
+        let val = cut.at(tenant, table, k, master.read_object(tenant, table, k));
+        let (op, val) = match val {
+            Some(val) => (replica::OP_PUT, val),
+            None => (replica::OP_DELETE, Vec::new()),
+        };
+        let val = match *key {
+            Some(ref key) => key.seal(table, k, &val),
+            None => val,
+        };
+
+        let rec = Record {
+            op: op,
+            tenant: tenant,
+            table: table,
+            key: k,
+            val: &val,
+        };
+        replica::encode(&mut records, &rec);
+    }
+
+    store.put(name, &records)?;
+    Ok(keys.len() as u64)
+}
+
+// Restores a backup, and records how it went.
+fn restore_backup(master: &Master, id: u64) {
+    let settings = shared().settings.read();
+    let store = match *settings {
+        Some(ref settings) => &settings.store,
+        None => return,
+    };
+
+    set_status("restoring", id, "", 0, String::new());
+    let result = latest(store).and_then(|latest| {
+        let id = if id == 0 { latest } else { id };
+        if id == 0 {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "there are no backups"));
+        }
+        if let Some(ref mut status) = *shared().status.lock() {
+            status.id = id;
+        }
+
+        let manifests = chain(id, &mut |id| load(store, id))?;
+        let mut objects = 0;
+        for manifest in manifests.iter() {
+            info!("Restoring {} backup {}", manifest.kind, manifest.id);
+            objects += apply(master, store, manifest)?;
+        }
+        Ok((id, objects))
+    });
+
+    match result {
+        Ok((id, objects)) => {
+            info!("Restored backup {}, {} objects", id, objects);
+            set_status("done", id, "", objects, String::new());
+        }
+
+        Err(e) => {
+            error!("Failed to restore backup {}: {}", id, e);
+            set_status("failed", id, "", 0, e.to_string());
+        }
+    }
+}
+
+// Returns the manifests of the backups a backup builds on, from the full backup up to and
+// including it, loading each with `load`.
+fn chain(id: u64, load: &mut FnMut(u64) -> io::Result<Manifest>) -> io::Result<Vec<Manifest>> {
+    let mut chain = Vec::new();
+    let mut id = id;
+    loop {
+        let manifest = load(id)?;
+        if manifest.id != id || (manifest.kind != "full" && manifest.base >= id) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("the manifest of backup {} is inconsistent", id),
+            ));
+        }
+
+        id = manifest.base;
+        let full = manifest.kind == "full";
+        chain.push(manifest);
+        if full {
+            chain.reverse();
+            return Ok(chain);
+        }
+    }
+}
+
+// Applies the tables in a backup, and returns the number of objects applied. Tables of tenants
+// that do not exist here are skipped.
+fn apply(master: &Master, store: &Store, manifest: &Manifest) -> io::Result<u64> {
+    let mut objects = 0;
+    for entry in manifest.tables.iter() {
+        let (tenant, table) = (entry.tenant as TenantId, entry.table as TableId);
+        if master.owned_tables(tenant).is_none() {
+            warn!("Skipped restoring table {} of missing tenant {}", table, tenant);
+            continue;
+        }
+
+        let key = match (entry.sealed, master.tenant_key(tenant)) {
+            (false, _) => None,
+            (true, Some(key)) => Some(key),
+            (true, None) => {
+                warn!("Skipped restoring table {} of tenant {}, it has no key", table, tenant);
+                continue;
+            }
+        };
+        // A value that does not open was not sealed with the tenant's key, or was changed in
+        // the bucket, and fails the restore.
+        let unseal = |k: &[u8], val: Vec<u8>| match key {
+            Some(ref key) => key.open(table, k, &val).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "a value does not open with it's key")
+            }),
+            None => Ok(val),
+        };
+
+        let name = object(manifest.id, tenant, table);
+        let write = |op: u8, k: &[u8], val: &[u8]| {
+            let rec = Record {
+                op: op,
+                tenant: tenant,
+                table: table,
+                key: k,
+                val: val,
+            };
+            master.write_record(&rec);
+        };
+
+        if manifest.kind == "full" {
+            // Snapshots can be as large as the table, so they are streamed through a temporary
+            // file rather than read into memory.
+            let path = env::temp_dir().join(format!(
+                "splinter-restore-{}-{}-{}",
+                manifest.id, tenant, table
+            ));
+            let path = path.to_string_lossy().into_owned();
+
+            let result: io::Result<u64> = (|| {
+                store.get_file(&name, &path)?;
+
+                // Check the snapshot and open every value in full first, so that a corrupt one
+                // changes nothing.
+                let input = BufReader::new(File::open(&path)?);
+                let (mut reader, _) = snapshot::Reader::new(input)?;
+                while let Some((k, val)) = reader.next()? {
+                    unseal(&k, val)?;
+                }
+
+                let input = BufReader::new(File::open(&path)?);
+                let (mut reader, _) = snapshot::Reader::new(input)?;
+                let mut count = 0;
+                while let Some((k, val)) = reader.next()? {
+                    write(replica::OP_PUT, &k, &unseal(&k, val)?);
+                    count += 1;
+                }
+                Ok(count)
+            })();
+
+            let _ = fs::remove_file(&path);
+            objects += result?;
+        } else {
+            let body = store.get(&name)?;
+            let mut records = Vec::new();
+            let mut off = 0;
+            while off < body.len() {
+                match replica::decode(&body[off..]) {
+                    Some((rec, len)) => {
+                        let val = unseal(rec.key, rec.val.to_vec())?;
+                        records.push((rec.op, rec.key.to_vec(), val));
+                        off += len;
+                    }
+                    None => return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated")),
+                }
+            }
+
+            for &(op, ref k, ref val) in records.iter() {
+                write(op, k, val);
+            }
+            objects += records.len() as u64;
+        }
+    }
+
+    Ok(objects)
+}
+
+// Returns the number of the last complete backup in the bucket, zero if there is none.
+fn latest(store: &Store) -> io::Result<u64> {
+    match store.get("latest") {
+        Ok(body) => from_utf8(&body)
+            .ok()
+            .and_then(|body| u64::from_str(body.trim()).ok())
+            .ok_or(io::Error::new(io::ErrorKind::InvalidData, "\"latest\" is malformed")),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+// Reads the manifest of a backup off the bucket.
+fn load(store: &Store, id: u64) -> io::Result<Manifest> {
+    let body = store.get(&format!("{}/manifest.toml", id))?;
+    from_utf8(&body)
+        .ok()
+        .and_then(|body| toml::from_str(body).ok())
+        .ok_or(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("the manifest of backup {} is malformed", id),
+        ))
+}
+
+// Returns the key of the object a table is written to in a backup.
+fn object(id: u64, tenant: TenantId, table: TableId) -> String {
+    format!("{}/{}-{}", id, tenant, table)
+}
+
+// Replaces the state of the last backup or restore.
+fn set_status(phase: &'static str, id: u64, kind: &'static str, objects: u64, error: String) {
+    *shared().status.lock() = Some(Status {
+        phase: phase,
+        error: error,
+        id: id,
+        kind: kind,
+        objects: objects,
+    });
+}
+
+/// Describes the last backup or restore run at this server as a line of JSON.
+pub fn status() -> String {
+    let status = shared().status.lock();
+    let status = match *status {
+        Some(ref status) => status,
+        None => return String::from("{\"phase\":null}\n"),
+    };
+
+    format!(
+        "{{\"phase\":\"{}\",\"id\":{},\"kind\":{},\"objects\":{},\"error\":{}}}\n",
+        status.phase,
+        status.id,
+        quote(status.kind),
+        status.objects,
+        quote(&status.error)
+    )
+}
+
+// This module contains unit tests for backups.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Returns the manifest of a backup of a kind built on another.
+    fn manifest(id: u64, kind: Kind, base: u64) -> Manifest {
+        Manifest {
+            id: id,
+            kind: kind.name().to_string(),
+            base: base,
+            time: 0,
+            tables: vec![Entry {
+                tenant: 1,
+                table: 2,
+                objects: 3,
+                sealed: true,
+            }],
+        }
+    }
+
+    // This unit test verifies that full backups are taken first and then every `full_every`
+    // backups, with the ones between building on the right backup.
+    #[test]
+    fn test_next_kind() {
+        let mut settings = Settings {
+            store: Store::new("http://127.0.0.1:9000/backups", "a", "s", "us-east-1").unwrap(),
+            interval_s: 0,
+            full_every: 3,
+            differential: false,
+        };
+        let mut history = History {
+            last: 0,
+            last_full: 0,
+            since_full: 0,
+        };
+        assert_eq!((Kind::Full, 0), next_kind(false, &history, &settings));
+
+        history.last = 5;
+        history.last_full = 5;
+        assert_eq!((Kind::Incremental, 5), next_kind(false, &history, &settings));
+        assert_eq!((Kind::Full, 0), next_kind(true, &history, &settings));
+
+        history.last = 6;
+        history.since_full = 1;
+        assert_eq!((Kind::Incremental, 6), next_kind(false, &history, &settings));
+        settings.differential = true;
+        assert_eq!((Kind::Differential, 5), next_kind(false, &history, &settings));
+
+        history.since_full = 2;
+        assert_eq!((Kind::Full, 0), next_kind(false, &history, &settings));
+        settings.full_every = 0;
+        history.since_full = 0;
+        assert_eq!((Kind::Full, 0), next_kind(false, &history, &settings));
+    }
+
+    // This unit test verifies that restoring follows a backup back to it's full backup, and
+    // rejects manifests that do not add up.
+    #[test]
+    fn test_chain() {
+        let mut manifests = HashMap::new();
+        manifests.insert(1, manifest(1, Kind::Full, 0));
+        manifests.insert(2, manifest(2, Kind::Incremental, 1));
+        manifests.insert(3, manifest(3, Kind::Incremental, 2));
+        manifests.insert(4, manifest(4, Kind::Differential, 1));
+        manifests.insert(5, manifest(5, Kind::Incremental, 5));
+        manifests.insert(6, manifest(7, Kind::Full, 0));
+
+        let mut load = |id: u64| {
+            manifests
+                .get(&id)
+                .cloned()
+                .ok_or(io::Error::new(io::ErrorKind::NotFound, "no manifest"))
+        };
+        let ids = |chain: Vec<Manifest>| chain.iter().map(|m| m.id).collect::<Vec<u64>>();
+
+        assert_eq!(vec![1, 2, 3], ids(chain(3, &mut load).unwrap()));
+        assert_eq!(vec![1, 4], ids(chain(4, &mut load).unwrap()));
+        assert_eq!(vec![1], ids(chain(1, &mut load).unwrap()));
+        assert!(chain(5, &mut load).is_err());
+        assert!(chain(6, &mut load).is_err());
+        assert_eq!(io::ErrorKind::NotFound, chain(8, &mut load).unwrap_err().kind());
+    }
+
+    // This unit test verifies that a cut holds the value every key had when it was taken: the
+    // first value overwritten is saved, keys created since have none, and keys deleted since
+    // are reported.
+    #[test]
+    fn test_cut() {
+        let table = Arc::new(Mutex::new(HashMap::new()));
+        table.lock().insert(b"a".to_vec(), b"1".to_vec());
+        table.lock().insert(b"b".to_vec(), b"2".to_vec());
+
+        let reader = Arc::clone(&table);
+        let cut = Cut::new(Box::new(move |_, _, key| reader.lock().get(key).cloned()));
+        let write = |op: u8, key: &[u8], val: &[u8]| {
+            let rec = Record {
+                op: op,
+                tenant: 1,
+                table: 2,
+                key: key,
+                val: val,
+            };
+            cut.save(&rec);
+            match op {
+                replica::OP_PUT => table.lock().insert(key.to_vec(), val.to_vec()),
+                _ => table.lock().remove(key),
+            };
+        };
+
+        write(replica::OP_PUT, b"a", b"3");
+        write(replica::OP_PUT, b"a", b"4");
+        write(replica::OP_PUT, b"c", b"5");
+        write(replica::OP_DELETE, b"b", &[]);
+
+        let now = |key: &[u8]| table.lock().get(key).cloned();
+        assert_eq!(Some(b"1".to_vec()), cut.at(1, 2, b"a", now(b"a")));
+        assert_eq!(None, cut.at(1, 2, b"c", now(b"c")));
+        assert_eq!(None, cut.at(1, 3, b"a", None));
+        assert_eq!(Some(b"6".to_vec()), cut.at(1, 2, b"d", Some(b"6".to_vec())));
+
+        let mut seen = HashSet::new();
+        seen.insert(b"a".to_vec());
+        seen.insert(b"c".to_vec());
+        assert_eq!(vec![(b"b".to_vec(), b"2".to_vec())], cut.deleted(1, 2, &seen));
+        assert!(cut.deleted(1, 3, &seen).is_empty());
+    }
+
+    // This unit test verifies that a manifest survives the trip through TOML.
+    #[test]
+    fn test_manifest() {
+        let manifest = manifest(4, Kind::Differential, 1);
+        let written = toml::Value::try_from(&manifest)
+            .and_then(|value| toml::to_string(&value))
+            .unwrap();
+        assert_eq!(manifest, toml::from_str(&written).unwrap());
+    }
+}
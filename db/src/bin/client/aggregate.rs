@@ -17,6 +17,7 @@
 
 extern crate db;
 extern crate rand;
+extern crate sandstorm;
 extern crate zipf;
 
 mod dispatch;
@@ -30,12 +31,15 @@ use db::cycles;
 use db::e2d2::allocators::CacheAligned;
 use db::e2d2::interface::PortQueue;
 use db::e2d2::scheduler::*;
+use db::histogram::{self, Histogram};
 use db::log::*;
 use db::wireformat::*;
 
 use rand::distributions::Sample;
 use rand::{SeedableRng, XorShiftRng};
 
+use sandstorm::simd;
+
 use zipf::ZipfDistribution;
 
 /// This type implements the send half of a client that issues back to back reads to a server and
@@ -100,9 +104,10 @@ impl AggregateSend {
         ord: u32,
     ) -> AggregateSend {
         // Allocate a vector for the invoke() RPC's payload. The payload consists of the name of
-        // the extension, the table id (8 bytes), the key length, the aggregate size, and the order.
+        // the extension, the table id (8 bytes), the aggregate size, the order, the key, and the
+        // number of rounds every record is checksummed with.
         let len = "aggregate".as_bytes().len() + size_of::<u64>() + size_of::<u32>()
-            + size_of::<u32>() + config.key_len;
+            + size_of::<u32>() + config.key_len + size_of::<u32>();
         let mut i_buff = Vec::with_capacity(len);
 
         // Pre-populate the extension name and table id.
@@ -110,7 +115,8 @@ impl AggregateSend {
         i_buff.extend_from_slice(&unsafe { transmute::<u64, [u8; 8]>(1u64.to_le()) });
         i_buff.extend_from_slice(&unsafe { transmute::<u32, [u8; 4]>(num.to_le()) });
         i_buff.extend_from_slice(&unsafe { transmute::<u32, [u8; 4]>(ord.to_le()) });
-        i_buff.resize(len, 0);
+        i_buff.resize(len - size_of::<u32>(), 0);
+        i_buff.extend_from_slice(&unsafe { transmute::<u32, [u8; 4]>(config.aggr_rounds.to_le()) });
 
         // Allocate and init a buffer into which keys will be generated.
         let mut n_buff = Vec::with_capacity(config.key_len);
@@ -222,15 +228,24 @@ struct AggregateRecv {
     /// The total number of responses received so far.
     recvd: u64,
 
-    /// Vector of sampled request latencies. Required to calculate distributions once all responses
-    /// have been received.
-    latencies: Vec<u64>,
+    /// Histogram of request latencies in nanoseconds. Required to calculate distributions once
+    /// all responses have been received.
+    latencies: Histogram,
+
+    /// The file the latency distribution is appended to once all responses have been received,
+    /// and the name of the table it is written under. Nothing is written if the path is empty.
+    report: String,
+    name: String,
 
     /// Number of keys to aggregate across. Required for the native case.
     num: u32,
 
     /// Order of the final polynomial to be computed.
     ord: u32,
+
+    /// The number of rounds every record is checksummed with, zero if only it's first byte is
+    /// aggregated. Required for the native case.
+    rounds: u32,
 }
 
 // Implementation of methods on AggregateRecv.
@@ -252,7 +267,7 @@ impl AggregateRecv {
     ///
     /// # Return
     ///
-    /// A receiver that measures the median latency and throughput of a Sandstorm server.
+    /// A receiver that measures the latency distribution and throughput of a Sandstorm server.
     fn new(
         port: CacheAligned<PortQueue>,
         resps: u64,
@@ -263,6 +278,8 @@ impl AggregateRecv {
         num: u32,
         ord: u32,
     ) -> AggregateRecv {
+        let name = format!("aggregate-rxq{}", port.rxq());
+
         AggregateRecv {
             receiver: dispatch::Receiver::new(port),
             multi_rx: dispatch::Receiver::new(send.clone()),
@@ -271,13 +288,17 @@ impl AggregateRecv {
             responses: resps,
             start: cycles::rdtsc(),
             recvd: 0,
-            latencies: Vec::with_capacity(2 * 1000 * 1000),
+            latencies: Histogram::new(histogram::DEFAULT_PRECISION),
+            report: config.latency_report.clone(),
+            name: name,
             num: num,
             ord: ord,
+            rounds: config.aggr_rounds,
         }
     }
 
-    /// Aggregates the first byte across a list of values.
+    /// Aggregates the first byte, or the checksum if configured with rounds, across a list of
+    /// values.
     ///
     /// # Arguments
     ///
@@ -292,14 +313,17 @@ impl AggregateRecv {
                 break;
             }
 
-            cols.push(row[0]);
+            match self.rounds {
+                0 => cols.push(row[0] as u64),
+                _ => cols.push(simd::checksum(row, self.rounds)),
+            }
         }
 
-        // Aggregate the collected set of bytes.
-        let mut aggr = cols.iter().fold(init, |sum, e| sum + (*e as u64));
+        // Aggregate the collected set of columns.
+        let mut aggr = cols.iter().fold(init, |sum, e| sum.wrapping_add(*e));
 
         for _mul in 1..self.ord {
-            aggr *= aggr;
+            aggr = aggr.wrapping_mul(aggr);
         }
 
         aggr
@@ -309,16 +333,20 @@ impl AggregateRecv {
     fn measurements(&mut self) {
         let stop = cycles::rdtsc();
 
-        self.latencies.sort();
-        let median = self.latencies[self.latencies.len() / 2];
-        let tail = self.latencies[(self.latencies.len() * 99) / 100];
-
         info!(
             "Median(ns): {} Tail(ns): {} Throughput(Kops/s): {}",
-            cycles::to_seconds(median) * 1e9,
-            cycles::to_seconds(tail) * 1e9,
+            self.latencies.percentile(50.0),
+            self.latencies.percentile(99.0),
             self.recvd as f64 / cycles::to_seconds(stop - self.start)
         );
+        info!("Latency(ns) {}", self.latencies.summary());
+
+        if self.report.len() > 0 {
+            let table = self.latencies.report(&self.name, "ns");
+            if let Err(ref err) = histogram::append_report(&self.report, &table) {
+                error!("Failed to write latency report {}: {}", self.report, err);
+            }
+        }
     }
 }
 
@@ -348,10 +376,9 @@ impl Executable for AggregateRecv {
                     self.recvd += 1;
 
                     let p = packet.parse_header::<InvokeResponse>();
-                    if self.recvd & 0xf == 0 {
-                        self.latencies
-                            .push(cycles::rdtsc() - p.get_header().common_header.stamp);
-                    }
+                    self.latencies.record(cycles::to_nanoseconds(
+                        cycles::rdtsc() - p.get_header().common_header.stamp,
+                    ));
                     p.free_packet();
                 }
             }
@@ -365,10 +392,9 @@ impl Executable for AggregateRecv {
 
                     let p = packet.parse_header::<MultiGetResponse>();
                     let _s = self.aggregate(0, p.get_payload());
-                    if self.recvd & 0xf == 0 {
-                        self.latencies
-                            .push(cycles::rdtsc() - p.get_header().common_header.stamp);
-                    }
+                    self.latencies.record(cycles::to_nanoseconds(
+                        cycles::rdtsc() - p.get_header().common_header.stamp,
+                    ));
                     p.free_packet();
                 }
             }
@@ -492,6 +518,20 @@ fn main() {
     let config = config::ClientConfig::load();
     info!("Starting up Sandstorm client with config {:?}", config);
 
+    // Refuse to start on a config that is malformed.
+    if let Err(ref err) = config.validate() {
+        error!("{}", err);
+        std::process::exit(1);
+    }
+
+    // Start out with an empty latency report, receivers append to it once they are done.
+    if config.latency_report.len() > 0 {
+        if let Err(ref err) = histogram::reset_report(&config.latency_report) {
+            error!("Failed to reset latency report {}: {}", config.latency_report, err);
+            std::process::exit(1);
+        }
+    }
+
     // Based on the supplied client configuration, compute the amount of time it will take to send
     // out `num_reqs` requests at a rate of `req_rate` requests per second.
     let exec = config.num_reqs / config.req_rate;
@@ -34,6 +34,7 @@ use db::cycles;
 use db::e2d2::allocators::*;
 use db::e2d2::interface::*;
 use db::e2d2::scheduler::*;
+use db::histogram::{self, Histogram};
 use db::log::*;
 use db::wireformat::*;
 
@@ -291,13 +292,18 @@ where
     // The total number of responses received so far.
     recvd: u64,
 
-    // Vector of sampled request latencies. Required to calculate distributions once all responses
-    // have been received.
-    latencies: Vec<u64>,
+    // Histogram of request latencies in nanoseconds. Required to calculate distributions once all
+    // responses have been received.
+    latencies: Histogram,
 
     // If true, this receiver will make latency measurements.
     master: bool,
 
+    // The file the latency distribution is appended to once all responses have been received,
+    // and the name of the table it is written under. Nothing is written if the path is empty.
+    report: String,
+    name: String,
+
     // Time stamp in cycles at which measurement stopped.
     stop: u64,
 }
@@ -314,19 +320,23 @@ where
     /// * `port` :  Network port on which responses will be polled for.
     /// * `resps`:  The number of responses to wait for before calculating statistics.
     /// * `master`: Boolean indicating if the receiver should make latency measurements.
+    /// * `report`: File the latency distribution is appended to. Nothing is written if empty.
+    /// * `name`:   Name of the table the latency distribution is written under.
     ///
     /// # Return
     ///
-    /// A response receiver that measures the median latency and throughput of a Sandstorm
+    /// A response receiver that measures the latency distribution and throughput of a Sandstorm
     /// server.
-    fn new(port: T, resps: u64, master: bool) -> BadRecv<T> {
+    fn new(port: T, resps: u64, master: bool, report: String, name: String) -> BadRecv<T> {
         BadRecv {
             receiver: dispatch::Receiver::new(port),
             responses: resps,
             start: cycles::rdtsc(),
             recvd: 0,
-            latencies: Vec::with_capacity(resps as usize),
+            latencies: Histogram::new(histogram::DEFAULT_PRECISION),
             master: master,
+            report: report,
+            name: name,
             stop: 0,
         }
     }
@@ -346,24 +356,19 @@ where
 
         // Calculate & print median & tail latency only on the master thread.
         if self.master {
-            self.latencies.sort();
-
-            let m;
-            let t = self.latencies[(self.latencies.len() * 99) / 100];
-            match self.latencies.len() % 2 {
-                0 => {
-                    let n = self.latencies.len();
-                    m = (self.latencies[n / 2] + self.latencies[(n / 2) + 1]) / 2;
-                }
-
-                _ => m = self.latencies[self.latencies.len() / 2],
-            }
-
             println!(
                 ">>> {} {}",
-                cycles::to_seconds(m) * 1e9,
-                cycles::to_seconds(t) * 1e9
+                self.latencies.percentile(50.0),
+                self.latencies.percentile(99.0)
             );
+            println!("BAD Latency(ns) {}", self.latencies.summary());
+
+            if self.report.len() > 0 {
+                let table = self.latencies.report(&self.name, "ns");
+                if let Err(ref err) = histogram::append_report(&self.report, &table) {
+                    error!("Failed to write latency report {}: {}", self.report, err);
+                }
+            }
         }
     }
 }
@@ -392,8 +397,9 @@ where
                     let curr = cycles::rdtsc();
 
                     let p = packet.parse_header::<InvokeResponse>();
-                    self.latencies
-                        .push(curr - p.get_header().common_header.stamp);
+                    self.latencies.record(cycles::to_nanoseconds(
+                        curr - p.get_header().common_header.stamp,
+                    ));
                     p.free_packet();
                 } else {
                     packet.free_packet();
@@ -458,11 +464,17 @@ fn setup_send<S>(
 ///
 /// # Arguments
 ///
+/// * `config`:    Client configuration, the path of the latency report is taken from it.
 /// * `ports`:     Network port on which packets will be sent.
 /// * `scheduler`: Netbricks scheduler to which BadRecv will be added.
 /// * `master`:    If true, the added BadRecv will make latency measurements.
-fn setup_recv<S>(ports: Vec<CacheAligned<PortQueue>>, scheduler: &mut S, _core: i32, master: bool)
-where
+fn setup_recv<S>(
+    config: &config::ClientConfig,
+    ports: Vec<CacheAligned<PortQueue>>,
+    scheduler: &mut S,
+    _core: i32,
+    master: bool,
+) where
     S: Scheduler + Sized,
 {
     if ports.len() != 1 {
@@ -475,6 +487,8 @@ where
         ports[0].clone(),
         34 * 1000 * 1000 as u64,
         master,
+        config.latency_report.clone(),
+        format!("bad-rxq{}", ports[0].rxq()),
     )) {
         Ok(_) => {
             info!(
@@ -496,6 +510,20 @@ fn main() {
     let config = config::ClientConfig::load();
     info!("Starting up Sandstorm client with config {:?}", config);
 
+    // Refuse to start on a config that is malformed.
+    if let Err(ref err) = config.validate() {
+        error!("{}", err);
+        std::process::exit(1);
+    }
+
+    // Start out with an empty latency report, receivers append to it once they are done.
+    if config.latency_report.len() > 0 {
+        if let Err(ref err) = histogram::reset_report(&config.latency_report) {
+            error!("Failed to reset latency report {}: {}", config.latency_report, err);
+            std::process::exit(1);
+        }
+    }
+
     // Based on the supplied client configuration, compute the amount of time it will take to send
     // out `num_reqs` requests at a rate of `req_rate` requests per second.
     let exec = config.num_reqs / config.req_rate;
@@ -533,7 +561,13 @@ fn main() {
                 receive[i],
                 Arc::new(
                     move |_ports, sched: &mut StandaloneScheduler, core: i32, _sibling| {
-                        setup_recv(port.clone(), sched, core, master)
+                        setup_recv(
+                            &config::ClientConfig::load(),
+                            port.clone(),
+                            sched,
+                            core,
+                            master,
+                        )
                     },
                 ),
             ).expect("Failed to initialize receive side.");
@@ -14,17 +14,324 @@
  */
 
 use std::cell::Cell;
+use std::collections::HashMap;
 use std::fmt::Display;
 use std::net::Ipv4Addr;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
 use db::config;
+use db::cycles;
 use db::e2d2::allocators::*;
 use db::e2d2::common::EmptyMetadata;
 use db::e2d2::headers::*;
 use db::e2d2::interface::*;
+use db::error;
 use db::log::*;
+use db::multiop;
 use db::rpc;
+use db::wireformat::OpCode;
+
+/// Fixed point representation of 1.0 for the backoff factor on Congestion.
+const BACKOFF_ONE: usize = 1024;
+
+/// The largest backoff factor, in units of BACKOFF_ONE. Bounds how far the request rate can be cut.
+const BACKOFF_MAX: usize = 1024 * BACKOFF_ONE;
+
+/// The minimum time between two multiplicative decreases in microseconds. Roughly a round trip,
+/// so that a single congestion episode, which marks many responses, only cuts the rate once.
+const DECREASE_INTERVAL_US: u64 = 100;
+
+/// Congestion state shared between a Sender and the Receiver getting it's responses. Responses
+/// marked as congested by the server cut the rate at which the Sender issues requests in half,
+/// and every unmarked response nudges it back up, in the manner of TCP's AIMD.
+#[allow(dead_code)]
+pub struct Congestion {
+    // The factor by which the gap between requests is stretched, in units of BACKOFF_ONE.
+    backoff: AtomicUsize,
+
+    // The time-stamp in cycles of the last multiplicative decrease.
+    last_decrease: AtomicUsize,
+
+    // The minimum time between two multiplicative decreases in cycles.
+    interval: u64,
+}
+
+// Implementation of methods on Congestion.
+impl Congestion {
+    /// Constructs congestion state for a Sender and Receiver pair.
+    ///
+    /// # Return
+    ///
+    /// A handle to the congestion state that can be shared by the Sender and Receiver.
+    #[allow(dead_code)]
+    pub fn new() -> Arc<Congestion> {
+        Arc::new(Congestion {
+            backoff: AtomicUsize::new(BACKOFF_ONE),
+            last_decrease: AtomicUsize::new(0),
+            interval: (cycles::cycles_per_second() / 1000000) * DECREASE_INTERVAL_US,
+        })
+    }
+
+    /// Updates the congestion state on receiving a burst of responses.
+    ///
+    /// # Arguments
+    ///
+    /// * `marked`:   The number of responses in the burst marked as congested.
+    /// * `unmarked`: The number of responses in the burst that were not marked.
+    /// * `now`:      The current time-stamp in cycles.
+    #[allow(dead_code)]
+    pub fn update(&self, marked: usize, unmarked: usize, now: u64) {
+        let backoff = self.backoff.load(Ordering::Relaxed);
+
+        // Multiplicative decrease, at most once every interval.
+        if marked > 0 {
+            let last = self.last_decrease.load(Ordering::Relaxed) as u64;
+            if now - last >= self.interval {
+                let backoff = ::std::cmp::min(backoff * 2, BACKOFF_MAX);
+                self.backoff.store(backoff, Ordering::Relaxed);
+                self.last_decrease.store(now as usize, Ordering::Relaxed);
+            }
+            return;
+        }
+
+        // Additive increase, one unit for every unmarked response.
+        if backoff > BACKOFF_ONE {
+            let backoff = ::std::cmp::max(backoff.saturating_sub(unmarked), BACKOFF_ONE);
+            self.backoff.store(backoff, Ordering::Relaxed);
+        }
+    }
+
+    /// Stretches the gap between two requests by the current backoff factor.
+    ///
+    /// # Arguments
+    ///
+    /// * `gap`: The gap between two requests in cycles at the configured request rate.
+    ///
+    /// # Return
+    ///
+    /// The gap to actually leave between two requests in cycles.
+    #[inline]
+    #[allow(dead_code)]
+    pub fn stretch(&self, gap: u64) -> u64 {
+        let backoff = self.backoff.load(Ordering::Relaxed) as u64;
+        (gap * backoff) / BACKOFF_ONE as u64
+    }
+}
+
+/// The gaps between requests issued by an open-loop generator. Requests are issued on this
+/// schedule whether or not earlier ones were answered, so that a server that cannot keep up
+/// shows up as growing latencies and unanswered requests instead of a slower client.
+#[allow(dead_code)]
+pub struct Arrivals {
+    // The mean gap between two requests in cycles.
+    mean: u64,
+
+    // If true, gaps are drawn from an exponential distribution, making arrivals a Poisson
+    // process. Otherwise, every gap is the mean.
+    poisson: bool,
+
+    // State of the xorshift generator gaps are drawn with.
+    rand: u64,
+}
+
+// Implementation of methods on Arrivals.
+impl Arrivals {
+    /// Constructs an arrival schedule.
+    ///
+    /// # Arguments
+    ///
+    /// * `config`: Client configuration with the request rate and the arrival process.
+    ///
+    /// # Return
+    ///
+    /// A schedule issuing `req_rate` requests per second on average.
+    #[allow(dead_code)]
+    pub fn new(config: &config::ClientConfig) -> Arrivals {
+        Arrivals::with_rate(config, config.req_rate)
+    }
+
+    /// Constructs an arrival schedule with a rate other than the client's.
+    ///
+    /// # Arguments
+    ///
+    /// * `config`: Client configuration with the arrival process.
+    /// * `rate`:   The number of requests to issue per second on average.
+    ///
+    /// # Return
+    ///
+    /// A schedule issuing `rate` requests per second on average.
+    #[allow(dead_code)]
+    pub fn with_rate(config: &config::ClientConfig, rate: usize) -> Arrivals {
+        Arrivals {
+            mean: cycles::cycles_per_second() / rate.max(1) as u64,
+            poisson: config.arrivals == "poisson",
+            rand: cycles::rdtsc() | 1,
+        }
+    }
+
+    /// Returns the gap to leave between the request that was just issued and the next one.
+    #[inline]
+    #[allow(dead_code)]
+    pub fn gap(&mut self) -> u64 {
+        if !self.poisson {
+            return self.mean;
+        }
+
+        self.rand ^= self.rand << 13;
+        self.rand ^= self.rand >> 7;
+        self.rand ^= self.rand << 17;
+
+        // Inverse transform sampling, with a uniform variate in (0, 1].
+        let u = ((self.rand >> 11) + 1) as f64 / (1u64 << 53) as f64;
+        (-u.ln() * self.mean as f64) as u64
+    }
+}
+
+/// Counts of the requests issued by an open-loop generator, shared between a Sender and the
+/// Receiver getting it's responses, so that requests that were never answered are accounted for.
+#[allow(dead_code)]
+pub struct Accounting {
+    // The number of requests handed to the network.
+    issued: AtomicUsize,
+
+    // The number of requests that went out more than a microsecond after they were due, because
+    // the generator could not keep up with the schedule.
+    late: AtomicUsize,
+}
+
+// Implementation of methods on Accounting.
+impl Accounting {
+    /// Constructs the counts for a Sender and Receiver pair.
+    #[allow(dead_code)]
+    pub fn new() -> Arc<Accounting> {
+        Arc::new(Accounting {
+            issued: AtomicUsize::new(0),
+            late: AtomicUsize::new(0),
+        })
+    }
+
+    /// Counts a request that was handed to the network.
+    ///
+    /// # Arguments
+    ///
+    /// * `due`: The time-stamp in cycles the request was scheduled for.
+    /// * `now`: The time-stamp in cycles it was actually issued at.
+    #[inline]
+    #[allow(dead_code)]
+    pub fn issue(&self, due: u64, now: u64) {
+        self.issued.fetch_add(1, Ordering::Relaxed);
+        if now > due && now - due > cycles::cycles_per_second() / 1000000 {
+            self.late.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the number of requests issued, and how many of them went out late.
+    #[allow(dead_code)]
+    pub fn counts(&self) -> (u64, u64) {
+        (
+            self.issued.load(Ordering::Relaxed) as u64,
+            self.late.load(Ordering::Relaxed) as u64,
+        )
+    }
+}
+
+/// The phase of a run a response arrived in.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Phase {
+    /// Before measurement started, while caches and queues reach a steady state.
+    Warmup,
+
+    /// During measurement, along with the number of whole seconds since it started.
+    Measure(u64),
+
+    /// After measurement stopped, while requests are still issued so that the last ones measured
+    /// see the same load as the rest.
+    Cooldown,
+}
+
+/// The warm-up, measurement, and cool-down phases of a run, shared by every Sender and Receiver
+/// on the client. The run starts when the first request is sent out.
+#[allow(dead_code)]
+pub struct Phases {
+    // The time-stamp in cycles the run started at, or 0 if it has not started yet.
+    start: AtomicUsize,
+
+    // The length of the warm-up and measurement phases in cycles. Phases are disabled if the
+    // measurement phase is of length 0.
+    warmup: u64,
+    measure: u64,
+
+    // The length of every phase in seconds.
+    secs: (u64, u64, u64),
+}
+
+// Implementation of methods on Phases.
+#[allow(dead_code)]
+impl Phases {
+    /// Constructs the phases of a run.
+    ///
+    /// # Arguments
+    ///
+    /// * `config`: Client configuration with the length of every phase.
+    pub fn new(config: &config::ClientConfig) -> Arc<Phases> {
+        Arc::new(Phases {
+            start: AtomicUsize::new(0),
+            warmup: config.warmup_secs * cycles::cycles_per_second(),
+            measure: config.measure_secs * cycles::cycles_per_second(),
+            secs: (config.warmup_secs, config.measure_secs, config.cooldown_secs),
+        })
+    }
+
+    /// Returns true if the run is split into phases.
+    pub fn enabled(&self) -> bool {
+        self.measure > 0
+    }
+
+    /// Starts the run, unless it was already started.
+    ///
+    /// # Arguments
+    ///
+    /// * `now`: The current time-stamp in cycles.
+    #[inline]
+    pub fn begin(&self, now: u64) {
+        if self.start.load(Ordering::Relaxed) == 0 {
+            self.start
+                .compare_and_swap(0, now as usize, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the phase the run is in.
+    ///
+    /// # Arguments
+    ///
+    /// * `now`: The current time-stamp in cycles.
+    pub fn phase(&self, now: u64) -> Phase {
+        let start = self.start.load(Ordering::Relaxed) as u64;
+        if start == 0 || now < start + self.warmup {
+            return Phase::Warmup;
+        }
+
+        let elapsed = now - start - self.warmup;
+        if elapsed < self.measure {
+            return Phase::Measure(elapsed / cycles::cycles_per_second());
+        }
+
+        Phase::Cooldown
+    }
+
+    /// Returns the length of the measurement phase in seconds.
+    pub fn measure_secs(&self) -> u64 {
+        self.secs.1
+    }
+
+    /// Returns the length of the whole run in seconds.
+    pub fn total_secs(&self) -> u64 {
+        self.secs.0 + self.secs.1 + self.secs.2
+    }
+}
 
 /// A simple RPC request generator for Sandstorm.
 pub struct Sender {
@@ -46,6 +353,13 @@ pub struct Sender {
 
     // The number of destination UDP ports a packet can be sent to.
     dst_ports: u16,
+
+    // Maps tenants that were assigned a dedicated range of UDP ports at the server to the range.
+    tenant_ports: HashMap<u32, config::PortRangeConfig>,
+
+    // Congestion state that determines how far requests should be spaced out, if congestion
+    // control was enabled.
+    congestion: Option<Arc<Congestion>>,
 }
 
 impl Sender {
@@ -100,6 +414,14 @@ impl Sender {
         mac_header.dst = config.parse_server_mac();
         mac_header.set_etype(0x0800);
 
+        // Lookup the UDP port range for every tenant that was assigned one.
+        let mut tenant_ports = HashMap::new();
+        for range in config.tenant_ports.iter() {
+            for tenant in range.tenants.iter() {
+                tenant_ports.entry(*tenant).or_insert(range.clone());
+            }
+        }
+
         Sender {
             net_port: port.clone(),
             req_udp_header: udp_header,
@@ -107,6 +429,36 @@ impl Sender {
             req_mac_header: mac_header,
             requests_sent: Cell::new(0),
             dst_ports: dst_ports,
+            tenant_ports: tenant_ports,
+            congestion: None,
+        }
+    }
+
+    /// Enables congestion control, spacing requests out according to the supplied state.
+    ///
+    /// # Arguments
+    ///
+    /// * `congestion`: Congestion state shared with the Receiver getting this Sender's responses.
+    #[allow(dead_code)]
+    pub fn set_congestion(&mut self, congestion: Arc<Congestion>) {
+        self.congestion = Some(congestion);
+    }
+
+    /// Computes the gap to leave before the next request.
+    ///
+    /// # Arguments
+    ///
+    /// * `gap`: The gap between two requests in cycles at the configured request rate.
+    ///
+    /// # Return
+    ///
+    /// The gap in cycles, stretched if the server signaled congestion.
+    #[inline]
+    #[allow(dead_code)]
+    pub fn next_gap(&self, gap: u64) -> u64 {
+        match self.congestion {
+            Some(ref congestion) => congestion.stretch(gap),
+            None => gap,
         }
     }
 
@@ -200,6 +552,35 @@ impl Sender {
         self.send_req(request);
     }
 
+    /// Creates and sends out a delete. The server only executes deletes as part of a multiop(),
+    /// so this is a multiop() RPC request carrying a single operation. Network headers are
+    /// populated based on arguments passed into new() above.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant`: Id of the tenant requesting the removal.
+    /// * `table`:  Id of the table from which the key is to be removed.
+    /// * `key`:    Byte string of the key to be removed. Limit 64 KB.
+    /// * `id`:     RPC identifier.
+    #[allow(dead_code)]
+    pub fn send_delete(&self, tenant: u32, table: u64, key: &[u8], id: u64) {
+        let mut ops = Vec::with_capacity(multiop::ENTRY_LEN + key.len());
+        multiop::push_entry(&mut ops, OpCode::SandstormDeleteRpc, table, key, &[]);
+
+        let request = rpc::create_multiop_rpc(
+            &self.req_mac_header,
+            &self.req_ip_header,
+            &self.req_udp_header,
+            tenant,
+            1,
+            &ops,
+            id,
+            self.get_dst_port(tenant),
+        );
+
+        self.send_req(request);
+    }
+
     /// Creates and sends out an invoke() RPC request. Network headers are populated based on
     /// arguments passed into new() above.
     ///
@@ -230,14 +611,28 @@ impl Sender {
     /// Computes the destination UDP port given a tenant identifier.
     #[inline]
     fn get_dst_port(&self, tenant: u32) -> u16 {
+        // If the tenant was assigned a range of ports, then spread requests across it.
+        if let Some(range) = self.tenant_ports.get(&tenant) {
+            return range.port(self.requests_sent.get());
+        }
+
         // The two least significant bytes of the tenant id % the total number of destination
         // ports.
         (tenant & 0xffff) as u16 & (self.dst_ports - 1)
     }
 
-    /// Sends a request/packet parsed upto IP out the network interface.
+    /// Sends a request/packet parsed upto IP out the network interface. A request that could
+    /// not be built is dropped with a warning.
     #[inline]
-    fn send_req(&self, request: Packet<IpHeader, EmptyMetadata>) {
+    fn send_req(&self, request: error::Result<Packet<IpHeader, EmptyMetadata>>) {
+        let request = match request {
+            Ok(request) => request,
+            Err(err) => {
+                warn!("Failed to build request: {}", err);
+                return;
+            }
+        };
+
         // Send the request out the network.
         unsafe {
             let mut pkts = [request.get_mbuf()];
@@ -273,6 +668,10 @@ where
 
     // The total number of responses received.
     responses_recv: Cell<u64>,
+
+    // Congestion state to update with the marks on received responses, if congestion control
+    // was enabled.
+    congestion: Option<Arc<Congestion>>,
 }
 
 // Implementation of methods on Receiver.
@@ -294,9 +693,20 @@ where
             net_port: port.clone(),
             max_rx_packets: 32,
             responses_recv: Cell::new(0),
+            congestion: None,
         }
     }
 
+    /// Enables congestion control, updating the supplied state with the marks on every response.
+    ///
+    /// # Arguments
+    ///
+    /// * `congestion`: Congestion state shared with the Sender whose responses are received here.
+    #[allow(dead_code)]
+    pub fn set_congestion(&mut self, congestion: Arc<Congestion>) {
+        self.congestion = Some(congestion);
+    }
+
     /// Receives responses/packets from the network interface.
     #[inline]
     pub fn recv_res(&self) -> Option<Vec<Packet<UdpHeader, EmptyMetadata>>> {
@@ -344,6 +754,12 @@ where
                 packets.push(packet);
             }
 
+            // Back off or ramp up depending on how many responses the server marked.
+            if let Some(ref congestion) = self.congestion {
+                let marked = packets.iter().filter(|p| rpc::parse_rpc_congested(p)).count();
+                congestion.update(marked, packets.len() - marked, cycles::rdtsc());
+            }
+
             return Some(packets);
         }
     }
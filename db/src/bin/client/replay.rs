@@ -0,0 +1,532 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+
+#![feature(use_extern_macros)]
+
+extern crate db;
+
+mod dispatch;
+mod setup;
+
+use std::fmt::Display;
+use std::mem::transmute;
+use std::sync::Arc;
+
+use db::config;
+use db::cycles;
+use db::e2d2::allocators::*;
+use db::e2d2::interface::*;
+use db::e2d2::scheduler::*;
+use db::histogram::{self, Histogram};
+use db::log::*;
+use db::trace::{Trace, TraceOp};
+
+/// The largest value a put is issued with. Values recorded on the trace that are larger than
+/// this are truncated, so that every request fits into a single frame.
+const MAX_VALUE_LEN: usize = 1024;
+
+/// The largest number of requests a sender issues in one go when it is behind the trace.
+const SEND_BURST: usize = 32;
+
+/// Replays requests recorded on a trace to a Sandstorm server, keeping the gaps between them.
+struct ReplaySend {
+    // The trace being replayed. Shared by every sender on the client.
+    trace: Arc<Trace>,
+
+    // Network stack required to actually send RPC requests out the network.
+    sender: dispatch::Sender,
+
+    // The index of the next request on the trace this sender issues. Senders take turns, so
+    // that every one of them issues every `stride`th request.
+    next: usize,
+    stride: usize,
+
+    // The number of cycles per nanosecond of the trace, after it was sped up.
+    scale: f64,
+
+    // The time stamp in cycles the replay started at, or 0 if it has not started yet.
+    start: u64,
+
+    // The key requests are issued on. Only the first four bytes vary, like on the keys the
+    // server is populated with.
+    key: Vec<u8>,
+
+    // The value puts are issued with. Always all zero bytes.
+    val: Vec<u8>,
+
+    // The number of requests that were issued more than a microsecond after they were due.
+    late: u64,
+
+    // If true, requests are stamped with the time they were due instead of the time they went
+    // out, so that measured latencies include any time spent waiting on the client.
+    correct: bool,
+}
+
+// Implementation of methods on ReplaySend.
+impl ReplaySend {
+    /// Constructs a ReplaySend.
+    ///
+    /// # Arguments
+    ///
+    /// * `config`:    Client configuration with the key length and speedup, as well as network
+    ///                related parameters.
+    /// * `port`:      Network port over which requests will be sent out.
+    /// * `trace`:     The trace to be replayed.
+    /// * `sender`:    The index of this sender among all senders on the client.
+    /// * `senders`:   The number of senders on the client.
+    ///
+    /// # Return
+    ///
+    /// A sender that issues every `senders`th request on the trace, starting at `sender`.
+    fn new(
+        config: &config::ClientConfig,
+        port: CacheAligned<PortQueue>,
+        trace: Arc<Trace>,
+        sender: usize,
+        senders: usize,
+    ) -> ReplaySend {
+        let speedup = if config.trace_speedup > 0.0 {
+            config.trace_speedup
+        } else {
+            1.0
+        };
+
+        ReplaySend {
+            trace: trace,
+            sender: dispatch::Sender::new(config, port, config.server_udp_ports as u16),
+            next: sender,
+            stride: senders,
+            scale: cycles::cycles_per_second() as f64 / 1e9 / speedup,
+            start: 0,
+            key: vec![0; config.key_len.max(4)],
+            val: vec![0; MAX_VALUE_LEN],
+            late: 0,
+            correct: config.correct_omission,
+        }
+    }
+}
+
+// Implementation of the `Drop` trait on ReplaySend.
+impl Drop for ReplaySend {
+    fn drop(&mut self) {
+        println!("Replay Late Requests {}", self.late);
+    }
+}
+
+// The Executable trait allowing ReplaySend to be scheduled by Netbricks.
+impl Executable for ReplaySend {
+    // Called internally by Netbricks.
+    fn execute(&mut self) {
+        let now = cycles::rdtsc();
+        if self.start == 0 {
+            self.start = now;
+        }
+
+        // Issue every request that is due. A sender that fell behind catches up a burst at a
+        // time, so that it's scheduler still gets to run other tasks.
+        let records = self.trace.records();
+        for _ in 0..SEND_BURST {
+            let record = match records.get(self.next) {
+                Some(record) => record,
+                None => return,
+            };
+
+            let due = self.start + (record.time as f64 * self.scale) as u64;
+            if now < due {
+                return;
+            }
+
+            if now - due > cycles::cycles_per_second() / 1000000 {
+                self.late += 1;
+            }
+
+            // A request that went out late is measured from when it was due, if configured to,
+            // so that stalls on the client are not left out of latencies.
+            let stamp = if self.correct { due } else { now };
+
+            let key: [u8; 4] = unsafe { transmute(record.key.to_le()) };
+            self.key[0..4].copy_from_slice(&key);
+
+            match record.op {
+                TraceOp::Get => self.sender.send_get(record.tenant, 1, &self.key, stamp),
+
+                TraceOp::Put => {
+                    let len = (record.size as usize).max(1).min(MAX_VALUE_LEN);
+                    self.sender
+                        .send_put(record.tenant, 1, &self.key, &self.val[..len], stamp)
+                }
+
+                TraceOp::Delete => self.sender.send_delete(record.tenant, 1, &self.key, stamp),
+            }
+
+            self.next += self.stride;
+        }
+    }
+
+    fn dependencies(&mut self) -> Vec<usize> {
+        vec![]
+    }
+}
+
+/// Receives responses to requests replayed by ReplaySend.
+struct ReplayRecv<T>
+where
+    T: PacketTx + PacketRx + Display + Clone + 'static,
+{
+    // The network stack required to receives RPC response packets from a network port.
+    receiver: dispatch::Receiver<T>,
+
+    // The number of responses to wait for before printing out statistics.
+    responses: u64,
+
+    // Time stamp in cycles at which the first response was received.
+    start: u64,
+
+    // The total number of responses received so far.
+    recvd: u64,
+
+    // Histogram of request latencies in nanoseconds.
+    latencies: Histogram,
+
+    // The file the latency distribution is appended to once all responses have been received,
+    // and the name of the table it is written under. Nothing is written if the path is empty.
+    report: String,
+    name: String,
+
+    // Time stamp in cycles at which measurement stopped.
+    stop: u64,
+}
+
+// Implementation of methods on ReplayRecv.
+impl<T> ReplayRecv<T>
+where
+    T: PacketTx + PacketRx + Display + Clone + 'static,
+{
+    /// Constructs a ReplayRecv.
+    ///
+    /// # Arguments
+    ///
+    /// * `port` :  Network port on which responses will be polled for.
+    /// * `resps`:  The number of responses to wait for before calculating statistics.
+    /// * `report`: File the latency distribution is appended to. Nothing is written if empty.
+    /// * `name`:   Name of the table the latency distribution is written under.
+    ///
+    /// # Return
+    ///
+    /// A receiver that measures the latency distribution and throughput of the replay.
+    fn new(port: T, resps: u64, report: String, name: String) -> ReplayRecv<T> {
+        ReplayRecv {
+            receiver: dispatch::Receiver::new(port),
+            responses: resps,
+            start: 0,
+            recvd: 0,
+            latencies: Histogram::new(histogram::DEFAULT_PRECISION),
+            report: report,
+            name: name,
+            stop: 0,
+        }
+    }
+}
+
+// Implementation of the `Drop` trait on ReplayRecv.
+impl<T> Drop for ReplayRecv<T>
+where
+    T: PacketTx + PacketRx + Display + Clone + 'static,
+{
+    fn drop(&mut self) {
+        // Responses that never arrived are not waited on forever; measure upto the last one.
+        if self.stop == 0 {
+            self.stop = cycles::rdtsc();
+        }
+
+        println!(
+            "Replay Throughput {}",
+            self.recvd as f64 / cycles::to_seconds(self.stop - self.start)
+        );
+        println!(
+            ">>> {} {}",
+            self.latencies.percentile(50.0),
+            self.latencies.percentile(99.0)
+        );
+        println!("Replay Latency(ns) {}", self.latencies.summary());
+
+        if self.report.len() > 0 {
+            let table = self.latencies.report(&self.name, "ns");
+            if let Err(ref err) = histogram::append_report(&self.report, &table) {
+                error!("Failed to write latency report {}: {}", self.report, err);
+            }
+        }
+    }
+}
+
+// Executable trait allowing ReplayRecv to be scheduled by Netbricks.
+impl<T> Executable for ReplayRecv<T>
+where
+    T: PacketTx + PacketRx + Display + Clone + 'static,
+{
+    // Called internally by Netbricks.
+    fn execute(&mut self) {
+        // Don't do anything after all responses have been received.
+        if self.responses <= self.recvd {
+            return;
+        }
+
+        if let Some(mut packets) = self.receiver.recv_res() {
+            let curr = cycles::rdtsc();
+            if self.start == 0 {
+                self.start = curr;
+            }
+
+            while let Some(packet) = packets.pop() {
+                self.recvd += 1;
+
+                // Every response header carries the RPC identifier, the time stamp the request
+                // was sent out at, at the same offset.
+                let stamp = {
+                    let p = packet.get_payload();
+                    if p.len() >= 14 {
+                        unsafe { std::ptr::read_unaligned(p[6..].as_ptr() as *const u64) }
+                    } else {
+                        curr
+                    }
+                };
+
+                if curr > stamp {
+                    self.latencies.record(cycles::to_nanoseconds(curr - stamp));
+                }
+                packet.free_packet();
+            }
+        }
+
+        // The moment all response packets have been received, set the value of the
+        // stop timestamp so that throughput can be estimated later.
+        if self.responses <= self.recvd {
+            self.stop = cycles::rdtsc();
+        }
+    }
+
+    fn dependencies(&mut self) -> Vec<usize> {
+        vec![]
+    }
+}
+
+/// Sets up ReplaySend by adding it to a Netbricks scheduler.
+///
+/// # Arguments
+///
+/// * `config`:    Network related configuration such as the MAC and IP address.
+/// * `ports`:     Network port on which packets will be sent.
+/// * `scheduler`: Netbricks scheduler to which ReplaySend will be added.
+/// * `trace`:     The trace to be replayed.
+/// * `sender`:    The index of the added ReplaySend among all senders on the client.
+/// * `senders`:   The number of senders on the client.
+fn setup_send<S>(
+    config: &config::ClientConfig,
+    ports: Vec<CacheAligned<PortQueue>>,
+    scheduler: &mut S,
+    trace: Arc<Trace>,
+    sender: usize,
+    senders: usize,
+) where
+    S: Scheduler + Sized,
+{
+    if ports.len() != 1 {
+        error!("Client should be configured with exactly 1 port!");
+        std::process::exit(1);
+    }
+
+    // Add the sender to a netbricks pipeline.
+    match scheduler.add_task(ReplaySend::new(
+        config,
+        ports[0].clone(),
+        trace,
+        sender,
+        senders,
+    )) {
+        Ok(_) => {
+            info!(
+                "Successfully added ReplaySend with tx queue {}.",
+                ports[0].txq()
+            );
+        }
+
+        Err(ref err) => {
+            error!("Error while adding to Netbricks pipeline {}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Sets up ReplayRecv by adding it to a Netbricks scheduler.
+///
+/// # Arguments
+///
+/// * `config`:    Client configuration, the path of the latency report is taken from it.
+/// * `ports`:     Network port on which packets will be received.
+/// * `scheduler`: Netbricks scheduler to which ReplayRecv will be added.
+/// * `resps`:     The number of responses the added ReplayRecv waits for.
+fn setup_recv<S>(
+    config: &config::ClientConfig,
+    ports: Vec<CacheAligned<PortQueue>>,
+    scheduler: &mut S,
+    resps: u64,
+) where
+    S: Scheduler + Sized,
+{
+    if ports.len() != 1 {
+        error!("Client should be configured with exactly 1 port!");
+        std::process::exit(1);
+    }
+
+    // Add the receiver to a netbricks pipeline.
+    match scheduler.add_task(ReplayRecv::new(
+        ports[0].clone(),
+        resps,
+        config.latency_report.clone(),
+        format!("replay-rxq{}", ports[0].rxq()),
+    )) {
+        Ok(_) => {
+            info!(
+                "Successfully added ReplayRecv with rx queue {}.",
+                ports[0].rxq()
+            );
+        }
+
+        Err(ref err) => {
+            error!("Error while adding to Netbricks pipeline {}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn main() {
+    db::env_logger::init().expect("ERROR: failed to initialize logger!");
+
+    let config = config::ClientConfig::load();
+    info!("Starting up Sandstorm client with config {:?}", config);
+
+    // Refuse to start on a config that is malformed.
+    if let Err(ref err) = config.validate() {
+        error!("{}", err);
+        std::process::exit(1);
+    }
+
+    if config.trace_path.len() == 0 {
+        error!("`trace_path` is empty, set it to the trace to be replayed.");
+        std::process::exit(1);
+    }
+
+    // Read in the trace once; every sender replays a share of it.
+    let trace = match Trace::load(&config.trace_path, config.n_keys as u32, config.num_tenants) {
+        Ok(trace) => Arc::new(trace),
+
+        Err(ref err) => {
+            error!("Failed to load trace {}: {}", config.trace_path, err);
+            std::process::exit(1);
+        }
+    };
+    info!(
+        "Replaying {} requests spanning {} seconds.",
+        trace.records().len(),
+        trace.duration() as f64 / 1e9
+    );
+
+    // Start out with an empty latency report, receivers append to it once they are done.
+    if config.latency_report.len() > 0 {
+        if let Err(ref err) = histogram::reset_report(&config.latency_report) {
+            error!("Failed to reset latency report {}: {}", config.latency_report, err);
+            std::process::exit(1);
+        }
+    }
+
+    // The replay takes as long as the trace, sped up.
+    let speedup = if config.trace_speedup > 0.0 {
+        config.trace_speedup
+    } else {
+        1.0
+    };
+    let exec = trace.duration() as f64 / 1e9 / speedup;
+
+    // Setup Netbricks.
+    let mut net_context = setup::config_and_init_netbricks(&config);
+
+    // Setup the client pipeline.
+    net_context.start_schedulers();
+
+    // The core id's which will run the sender and receiver threads.
+    // XXX The following two arrays heavily depend on the set of cores
+    // configured in setup.rs
+    let senders = [0, 2, 4, 6];
+    let receive = [1, 3, 5, 7];
+    assert!((senders.len() == 4) && (receive.len() == 4));
+
+    // Setup 4 senders, and 4 receivers.
+    for i in 0..4 {
+        // First, retrieve a tx-rx queue pair from Netbricks
+        let port = net_context
+            .rx_queues
+            .get(&senders[i])
+            .expect("Failed to retrieve network port!")
+            .clone();
+
+        // Sender i issues requests i, i + 4, i + 8 and so on.
+        let total = trace.records().len();
+        let resps = ((total + senders.len() - 1 - i) / senders.len()) as u64;
+
+        // Setup the receive side.
+        net_context
+            .add_pipeline_to_core(
+                receive[i],
+                Arc::new(
+                    move |_ports, sched: &mut StandaloneScheduler, _core: i32, _sibling| {
+                        setup_recv(&config::ClientConfig::load(), port.clone(), sched, resps)
+                    },
+                ),
+            ).expect("Failed to initialize receive side.");
+
+        // Setup the send side.
+        let trace = Arc::clone(&trace);
+        net_context
+            .add_pipeline_to_core(
+                senders[i],
+                Arc::new(
+                    move |ports, sched: &mut StandaloneScheduler, _core: i32, _sibling| {
+                        setup_send(
+                            &config::ClientConfig::load(),
+                            ports,
+                            sched,
+                            Arc::clone(&trace),
+                            i,
+                            senders.len(),
+                        )
+                    },
+                ),
+            ).expect("Failed to initialize send side.");
+    }
+
+    // Allow the system to bootup fully.
+    std::thread::sleep(std::time::Duration::from_secs(1));
+
+    // Run the client.
+    net_context.execute();
+
+    // Sleep for an amount of time approximately equal to the replay's duration, and then
+    // shutdown the client.
+    std::thread::sleep(std::time::Duration::from_secs(exec as u64 + 11));
+
+    // Stop the client.
+    net_context.stop();
+}
@@ -296,6 +296,12 @@ fn main() {
     let config = config::ClientConfig::load();
     info!("Starting up Sandstorm client with config {:?}", config);
 
+    // Refuse to start on a config that is malformed.
+    if let Err(ref err) = config.validate() {
+        error!("{}", err);
+        std::process::exit(1);
+    }
+
     // Setup Netbricks.
     let mut net_context = setup::config_and_init_netbricks(&config);
 
@@ -59,6 +59,7 @@ fn get_default_netbricks_config(config: &ClientConfig) -> NetbricksConfiguration
         loopback: net_port_loopback,
         tso: net_port_tcp_tso,
         csum: net_port_csum_offload,
+        rss: false,
     };
 
     // The set of ports used by netbricks.
@@ -30,6 +30,8 @@ use db::cycles;
 use db::e2d2::allocators::CacheAligned;
 use db::e2d2::interface::PortQueue;
 use db::e2d2::scheduler::*;
+use db::graph;
+use db::histogram::{self, Histogram};
 use db::log::*;
 use db::wireformat::*;
 
@@ -38,6 +40,38 @@ use rand::{Rng, SeedableRng, XorShiftRng};
 
 use zipf::ZipfDistribution;
 
+/// The operations issued by a TAO client. The operation a request was for is encoded in the
+/// lowest two bits of it's RPC identifier (which is otherwise a timestamp), so that responses
+/// can be told apart however large an object's association list is.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TaoOp {
+    ObjGet = 0,
+    AssocGet = 1,
+    ObjUpdate = 2,
+    AssocAdd = 3,
+}
+
+/// The bits of an RPC identifier the operation is encoded in.
+const OP_MASK: u64 = 0x3;
+
+// Implementation of methods on TaoOp.
+impl TaoOp {
+    /// Returns an RPC identifier for this operation, based on the current timestamp.
+    fn stamp(&self, curr: u64) -> u64 {
+        (curr & !OP_MASK) | *self as u64
+    }
+
+    /// Returns the operation encoded in an RPC identifier.
+    fn from_stamp(stamp: u64) -> TaoOp {
+        match stamp & OP_MASK {
+            0 => TaoOp::ObjGet,
+            1 => TaoOp::AssocGet,
+            2 => TaoOp::ObjUpdate,
+            _ => TaoOp::AssocAdd,
+        }
+    }
+}
+
 /// This type implements the send half of a TAO client.
 struct TaoSend {
     /// Random number generator required to seed the Zipfian distribution.
@@ -82,8 +116,17 @@ struct TaoSend {
     /// Request buffer for an `assoc_get` invoke operation. Again, helps reduce heap allocations.
     ia_buff: Vec<u8>,
 
-    /// The percentage of operations that are assoc_gets. The rest are obj_gets.
-    assoc_p: usize,
+    /// Request buffer for an `obj_update` invoke operation.
+    iu_buff: Vec<u8>,
+
+    /// Request buffer for an `assoc_add` invoke operation.
+    iaa_buff: Vec<u8>,
+
+    /// Request buffer for a native assoc_add operation, holding the association's key.
+    naa_buff: Vec<u8>,
+
+    /// The percentages of operations that are obj_gets, assoc_gets, obj_updates, and assoc_adds.
+    mix: [usize; 4],
 
     /// If true and native is false, then obj_gets are sent out as native gets.
     combine: bool,
@@ -117,16 +160,42 @@ impl TaoSend {
         io_buff.extend_from_slice(&unsafe { transmute::<u64, [u8; 8]>(1u64.to_le()) });
         io_buff.resize(len, 0);
 
-        // Allocate a vector for the assoc_get invoke() RPC's payload. The payload consists of the
-        // name of the extension, an opcode, the table id (8 bytes) and the key length.
+        // Allocate a vector for the assoc_get invoke() RPC's payload, which reads an object's
+        // whole association list with an assoc_range. The payload consists of the name of the
+        // extension, an opcode, the table id (8 bytes), the object id (8 bytes), a 2 byte
+        // association type, and the position (4 bytes) and number (4 bytes) of the associations.
         let len = "tao".as_bytes().len() + 1 + size_of::<u64>() + 18;
         let mut ia_buff = Vec::with_capacity(len);
 
-        // Pre-populate the extension name, opcode, and table id.
+        // Pre-populate the extension name, opcode, table id, and number of associations.
         ia_buff.extend_from_slice("tao".as_bytes());
-        ia_buff.extend_from_slice(&[4u8]);
+        ia_buff.extend_from_slice(&[7u8]);
         ia_buff.extend_from_slice(&unsafe { transmute::<u64, [u8; 8]>(2u64.to_le()) });
-        ia_buff.resize(len, 0);
+        ia_buff.resize(len - 4, 0);
+        let limit: [u8; 4] = unsafe { transmute(graph::MAX_FANOUT.to_le()) };
+        ia_buff.extend_from_slice(&limit);
+
+        // Allocate a vector for the obj_update invoke() RPC's payload. The payload consists of the
+        // name of the extension, an opcode, the table id, the object id, a 2 byte object type, and
+        // the object's new 32 byte value.
+        let len = "tao".as_bytes().len() + 1 + size_of::<u64>() + 8 + 2 + 32;
+        let mut iu_buff = Vec::with_capacity(len);
+        iu_buff.extend_from_slice("tao".as_bytes());
+        iu_buff.extend_from_slice(&[2u8]);
+        iu_buff.extend_from_slice(&unsafe { transmute::<u64, [u8; 8]>(1u64.to_le()) });
+        iu_buff.resize(len, 0);
+
+        // Allocate a vector for the assoc_add invoke() RPC's payload. The payload consists of the
+        // name of the extension, an opcode, the table id, the object ids of both ends of the
+        // association with a 2 byte association type in between, and 14 bytes of data, which
+        // the extension stores after the association's time, like the 22 byte associations
+        // the server is populated with.
+        let len = "tao".as_bytes().len() + 1 + size_of::<u64>() + 18 + 14;
+        let mut iaa_buff = Vec::with_capacity(len);
+        iaa_buff.extend_from_slice("tao".as_bytes());
+        iaa_buff.extend_from_slice(&[5u8]);
+        iaa_buff.extend_from_slice(&unsafe { transmute::<u64, [u8; 8]>(2u64.to_le()) });
+        iaa_buff.resize(len, 0);
 
         // Allocate and init a buffer into which keys for a native obj_get will be generated.
         let mut no_buff = Vec::with_capacity(8);
@@ -136,9 +205,30 @@ impl TaoSend {
         let mut na_buff = Vec::with_capacity(10);
         na_buff.resize(10, 0);
 
+        // Allocate and init a buffer into which keys for a native assoc_add will be generated.
+        let mut naa_buff = Vec::with_capacity(18);
+        naa_buff.resize(18, 0);
+
+        // The mix of operations comes from the graph's config, or otherwise consists of just
+        // obj_gets and `assocs_p` percent assoc_gets.
+        let tao = graph::resolve(&config.tao).expect("Failed to resolve the TAO graph.");
+        let mix = if tao.obj_get_pct + tao.assoc_get_pct + tao.obj_update_pct + tao.assoc_add_pct
+            > 0
+        {
+            [
+                tao.obj_get_pct,
+                tao.assoc_get_pct,
+                tao.obj_update_pct,
+                tao.assoc_add_pct,
+            ]
+        } else {
+            [100 - config.assocs_p, config.assocs_p, 0, 0]
+        };
+        let objects = graph::Graph::new(&tao, config.n_keys as u32).objects();
+
         TaoSend {
             random: XorShiftRng::from_seed(rand::random::<[u32; 4]>()),
-            k_dist: ZipfDistribution::new(config.n_keys, config.skew)
+            k_dist: ZipfDistribution::new(objects as usize, config.skew)
                 .expect("Failed to init key generator."),
             t_dist: ZipfDistribution::new(config.num_tenants as usize, config.tenant_skew)
                 .expect("Failed to init tenant generator."),
@@ -153,24 +243,43 @@ impl TaoSend {
             na_buff: na_buff,
             io_buff: io_buff,
             ia_buff: ia_buff,
-            assoc_p: config.assocs_p,
+            iu_buff: iu_buff,
+            iaa_buff: iaa_buff,
+            naa_buff: naa_buff,
+            mix: mix,
             combine: config.combined,
         }
     }
 
-    /// Samples distributions for a tenant id key, and opcode.
+    /// Samples a key.
     ///
     /// # Return
-    /// A 3-tupule consisting of a 4 byte tenant id, 4 byte key, and boolean. If the boolean is
-    /// true, the op should be an obj_get.
+    /// The 4 byte key of an object.
     #[inline]
-    fn sample(&mut self) -> (u32, [u8; 4], bool) {
-        let t = self.t_dist.sample(&mut self.random) as u32;
-
+    fn sample_key(&mut self) -> [u8; 4] {
         let k = self.k_dist.sample(&mut self.random) as u32;
-        let k: [u8; 4] = unsafe { transmute(k.to_le()) };
+        unsafe { transmute(k.to_le()) }
+    }
 
-        let o = self.random.gen::<u32>() % 100 >= self.assoc_p as u32;
+    /// Samples distributions for a tenant id key, and opcode.
+    ///
+    /// # Return
+    /// A 3-tupule consisting of a 4 byte tenant id, 4 byte key, and the operation to issue.
+    #[inline]
+    fn sample(&mut self) -> (u32, [u8; 4], TaoOp) {
+        let t = self.t_dist.sample(&mut self.random) as u32;
+        let k = self.sample_key();
+
+        let p = (self.random.gen::<u32>() % 100) as usize;
+        let o = if p < self.mix[0] {
+            TaoOp::ObjGet
+        } else if p < self.mix[0] + self.mix[1] {
+            TaoOp::AssocGet
+        } else if p < self.mix[0] + self.mix[1] + self.mix[2] {
+            TaoOp::ObjUpdate
+        } else {
+            TaoOp::AssocAdd
+        };
 
         (t, k, o)
     }
@@ -183,38 +292,69 @@ impl TaoSend {
     #[inline]
     fn generate(&mut self, curr: u64) {
         let (t, k, o) = self.sample();
+        let stamp = o.stamp(curr);
+
+        // The object a new association points to.
+        let k2 = match o {
+            TaoOp::AssocAdd => self.sample_key(),
+            _ => [0; 4],
+        };
 
         match self.native {
             // Native request.
             true => match o {
-                true => {
+                TaoOp::ObjGet => {
                     self.no_buff[0..size_of::<u32>()].copy_from_slice(&k);
-                    self.sender.send_get(t, 1, &self.no_buff, curr);
+                    self.sender.send_get(t, 1, &self.no_buff, stamp);
                 }
 
-                false => {
+                TaoOp::AssocGet => {
                     self.na_buff[0..size_of::<u32>()].copy_from_slice(&k);
-                    self.sender.send_get(t, 2, &self.na_buff, curr);
+                    self.sender.send_get(t, 2, &self.na_buff, stamp);
+                }
+
+                TaoOp::ObjUpdate => {
+                    self.no_buff[0..size_of::<u32>()].copy_from_slice(&k);
+                    self.sender.send_put(t, 1, &self.no_buff, &[0; 32], stamp);
+                }
+
+                // Only the association itself is written; the object's association list is left
+                // as is, which a native put cannot update atomically anyway.
+                TaoOp::AssocAdd => {
+                    self.naa_buff[0..4].copy_from_slice(&k);
+                    self.naa_buff[10..14].copy_from_slice(&k2);
+                    self.sender.send_put(t, 2, &self.naa_buff, &[0; 22], stamp);
                 }
             },
 
             // Invoke request. Add the key to the pre-populated payload.
             false => match o {
-                true => match self.combine {
+                TaoOp::ObjGet => match self.combine {
                     true => {
                         self.no_buff[0..size_of::<u32>()].copy_from_slice(&k);
-                        self.sender.send_get(t, 1, &self.no_buff, curr);
+                        self.sender.send_get(t, 1, &self.no_buff, stamp);
                     }
 
                     false => {
                         self.io_buff[12..16].copy_from_slice(&k);
-                        self.sender.send_invoke(t, 3, &self.io_buff, curr);
+                        self.sender.send_invoke(t, 3, &self.io_buff, stamp);
                     }
                 },
 
-                false => {
+                TaoOp::AssocGet => {
                     self.ia_buff[12..16].copy_from_slice(&k);
-                    self.sender.send_invoke(t, 3, &self.ia_buff, curr);
+                    self.sender.send_invoke(t, 3, &self.ia_buff, stamp);
+                }
+
+                TaoOp::ObjUpdate => {
+                    self.iu_buff[12..16].copy_from_slice(&k);
+                    self.sender.send_invoke(t, 3, &self.iu_buff, stamp);
+                }
+
+                TaoOp::AssocAdd => {
+                    self.iaa_buff[12..16].copy_from_slice(&k);
+                    self.iaa_buff[22..26].copy_from_slice(&k2);
+                    self.sender.send_invoke(t, 3, &self.iaa_buff, stamp);
                 }
             },
         }
@@ -270,13 +410,22 @@ struct TaoRecv {
     /// The total number of responses received so far.
     recvd: u64,
 
-    /// Vector of sampled request latencies. Required to calculate distributions once all responses
-    /// have been received. This vector is for the obj_get RPC.
-    o_latencies: Vec<u64>,
+    /// Histogram of request latencies in nanoseconds. Required to calculate distributions once
+    /// all responses have been received. This histogram is for the obj_get RPC.
+    o_latencies: Histogram,
+
+    /// Histogram of request latencies in nanoseconds. Required to calculate distributions once
+    /// all responses have been received. This histogram is for the assoc_get RPC.
+    a_latencies: Histogram,
+
+    /// Histogram of request latencies in nanoseconds for the obj_update and assoc_add RPCs.
+    w_latencies: Histogram,
 
-    /// Vector of sampled request latencies. Required to calculate distributions once all responses
-    /// have been received. This vector is for the assoc_get RPC.
-    a_latencies: Vec<u64>,
+    /// The file the latency distributions are appended to once all responses have been received,
+    /// and the prefix of the names of the tables they are written under. Nothing is written if
+    /// the path is empty.
+    report: String,
+    name: String,
 
     /// Pre-allocated vector to hold assoc keys. Required for the native mode.
     assoc_keys: Vec<u8>,
@@ -302,7 +451,7 @@ impl TaoRecv {
     ///
     /// # Return
     ///
-    /// A receiver that measures the median latency and throughput of a Sandstorm server.
+    /// A receiver that measures the latency distribution and throughput of a Sandstorm server.
     fn new(
         port: CacheAligned<PortQueue>,
         resps: u64,
@@ -315,6 +464,8 @@ impl TaoRecv {
         let mut a_keys = Vec::with_capacity(72);
         a_keys.resize(72, 0);
 
+        let name = format!("tao-rxq{}", port.rxq());
+
         TaoRecv {
             receiver: dispatch::Receiver::new(port),
             multi_rx: dispatch::Receiver::new(send.clone()),
@@ -323,14 +474,26 @@ impl TaoRecv {
             responses: resps,
             start: cycles::rdtsc(),
             recvd: 0,
-            o_latencies: Vec::with_capacity(2 * 1000 * 1000),
-            a_latencies: Vec::with_capacity(2 * 1000 * 1000),
+            o_latencies: Histogram::new(histogram::DEFAULT_PRECISION),
+            a_latencies: Histogram::new(histogram::DEFAULT_PRECISION),
+            w_latencies: Histogram::new(histogram::DEFAULT_PRECISION),
+            report: config.latency_report.clone(),
+            name: name,
             assoc_keys: a_keys,
             combine: config.combined,
         }
     }
 
-    fn assoc_keys(&mut self, list: &[u8]) {
+    /// Builds the keys of the associations in an object's association list into `assoc_keys`.
+    /// The object's id is recovered from the first association, which always points to the
+    /// object right after it.
+    ///
+    /// # Return
+    /// The number of associations in the list.
+    fn assoc_keys(&mut self, list: &[u8]) -> u32 {
+        let n = (list.len() / 16).min(graph::MAX_FANOUT as usize);
+        self.assoc_keys.resize(18 * n, 0);
+
         let mut left: u64 = 0;
         for (idx, e) in list[0..8].iter().enumerate() {
             left |= (*e as u64) << (idx << 3);
@@ -342,9 +505,9 @@ impl TaoRecv {
 
         let left: [u8; 8] = unsafe { transmute(left) };
 
-        let mut n = 0;
-        for id in list.chunks(16) {
-            let mut l = n * 18;
+        let mut i = 0;
+        for id in list.chunks(16).take(n) {
+            let mut l = i * 18;
             let mut r = l + 8;
             self.assoc_keys[l..r].copy_from_slice(&left);
 
@@ -352,34 +515,56 @@ impl TaoRecv {
             r = l + 8;
             self.assoc_keys[l..r].copy_from_slice(&id[0..8]);
 
-            n += 1;
+            i += 1;
         }
+
+        n as u32
     }
 
-    /// Prints out the measured latency distribution and throughput.
+    /// Prints out the measured latency distribution and throughput, and appends the
+    /// distributions to the latency report if there is one.
     fn measurements(&mut self) {
         let stop = cycles::rdtsc();
 
-        self.o_latencies.sort();
-        let o_median = self.o_latencies[self.o_latencies.len() / 2];
-        let o_tail = self.o_latencies[(self.o_latencies.len() * 99) / 100];
-        let o_mean = self.o_latencies.iter().sum::<u64>() as f64 / self.o_latencies.len() as f64;
-
-        self.a_latencies.sort();
-        let a_median = self.a_latencies[self.a_latencies.len() / 2];
-        let a_tail = self.a_latencies[(self.a_latencies.len() * 99) / 100];
-        let a_mean = self.a_latencies.iter().sum::<u64>() as f64 / self.a_latencies.len() as f64;
-
         info!(
-            "AMean(ns) {} AMedian(ns): {} ATail(ns) {} OMean(ns) {} OMedian(ns): {} OTail(ns): {} Throughput(Kops/s): {}",
-            cycles::to_seconds(a_mean as u64) * 1e9,
-            cycles::to_seconds(a_median) * 1e9,
-            cycles::to_seconds(a_tail) * 1e9,
-            cycles::to_seconds(o_mean as u64) * 1e9,
-            cycles::to_seconds(o_median) * 1e9,
-            cycles::to_seconds(o_tail) * 1e9,
+            "AMean(ns) {} AMedian(ns): {} ATail(ns) {} OMean(ns) {} OMedian(ns): {} OTail(ns): {} WMean(ns) {} WMedian(ns): {} WTail(ns): {} Throughput(Kops/s): {}",
+            self.a_latencies.mean(),
+            self.a_latencies.percentile(50.0),
+            self.a_latencies.percentile(99.0),
+            self.o_latencies.mean(),
+            self.o_latencies.percentile(50.0),
+            self.o_latencies.percentile(99.0),
+            self.w_latencies.mean(),
+            self.w_latencies.percentile(50.0),
+            self.w_latencies.percentile(99.0),
             self.recvd as f64 / cycles::to_seconds(stop - self.start)
         );
+        info!("ObjGet Latency(ns) {}", self.o_latencies.summary());
+        info!("AssocGet Latency(ns) {}", self.a_latencies.summary());
+        info!("Write Latency(ns) {}", self.w_latencies.summary());
+
+        if self.report.len() > 0 {
+            let mut tables = self.o_latencies
+                .report(&format!("{}-obj_get", self.name), "ns");
+            tables.push_str(&self.a_latencies
+                .report(&format!("{}-assoc_get", self.name), "ns"));
+            tables.push_str(&self.w_latencies
+                .report(&format!("{}-write", self.name), "ns"));
+
+            if let Err(ref err) = histogram::append_report(&self.report, &tables) {
+                error!("Failed to write latency report {}: {}", self.report, err);
+            }
+        }
+    }
+
+    /// Records the latency of a response to an operation other than a native assoc_get.
+    fn record_latency(&mut self, stamp: u64) {
+        let latency = cycles::to_nanoseconds(cycles::rdtsc() - stamp);
+        match TaoOp::from_stamp(stamp) {
+            TaoOp::ObjGet => self.o_latencies.record(latency),
+            TaoOp::AssocGet => self.a_latencies.record(latency),
+            _ => self.w_latencies.record(latency),
+        }
     }
 }
 
@@ -394,58 +579,40 @@ impl Executable for TaoRecv {
         // Check for received packets. If any, then take latency measurements.
         if let Some(mut resps) = self.receiver.recv_res() {
             while let Some(packet) = resps.pop() {
-                if self.native {
+                // Every response header carries the RPC identifier at the same offset, so it can
+                // be read off before knowing which kind of response this is.
+                let stamp = {
+                    let p = packet.get_payload();
+                    unsafe { std::ptr::read_unaligned(p[6..].as_ptr() as *const u64) }
+                };
+
+                // A native assoc_get is followed up by a multiget() of the associations in the
+                // object's list.
+                if self.native && TaoOp::from_stamp(stamp) == TaoOp::AssocGet {
                     let p = packet.parse_header::<GetResponse>();
 
-                    // Response to obj_get.
-                    if p.get_payload().len() < 50 {
+                    let n = self.assoc_keys(p.get_payload());
+                    if n == 0 {
                         self.recvd += 1;
-
-                        if self.recvd & 0xf == 0 {
-                            self.o_latencies
-                                .push(cycles::rdtsc() - p.get_header().common_header.stamp);
-                        }
-
                         p.free_packet();
                         continue;
                     }
 
-                    self.assoc_keys(p.get_payload());
-
                     self.sender.send_multiget(
                         p.get_header().common_header.tenant,
                         2,
                         18,
-                        4,
+                        n,
                         &self.assoc_keys,
-                        p.get_header().common_header.stamp,
+                        stamp,
                     );
                     p.free_packet();
-                } else {
-                    self.recvd += 1;
-
-                    if self.combine && packet.get_payload().len() <= 50 {
-                        let p = packet.parse_header::<GetResponse>();
-                        if self.recvd & 0xf == 0 {
-                            self.o_latencies
-                                .push(cycles::rdtsc() - p.get_header().common_header.stamp);
-                        }
-                        p.free_packet();
-                        continue;
-                    }
-
-                    let p = packet.parse_header::<InvokeResponse>();
-                    if self.recvd & 0xf == 0 {
-                        if p.get_payload().len() < 50 {
-                            self.o_latencies
-                                .push(cycles::rdtsc() - p.get_header().common_header.stamp);
-                        } else {
-                            self.a_latencies
-                                .push(cycles::rdtsc() - p.get_header().common_header.stamp);
-                        }
-                    }
-                    p.free_packet();
+                    continue;
                 }
+
+                self.recvd += 1;
+                self.record_latency(stamp);
+                packet.free_packet();
             }
         }
 
@@ -456,10 +623,9 @@ impl Executable for TaoRecv {
                     self.recvd += 1;
 
                     let p = packet.parse_header::<MultiGetResponse>();
-                    if self.recvd & 0xf == 0 {
-                        self.a_latencies
-                            .push(cycles::rdtsc() - p.get_header().common_header.stamp);
-                    }
+                    self.a_latencies.record(cycles::to_nanoseconds(
+                        cycles::rdtsc() - p.get_header().common_header.stamp,
+                    ));
                     p.free_packet();
                 }
             }
@@ -571,6 +737,26 @@ fn main() {
     let config = config::ClientConfig::load();
     info!("Starting up Sandstorm client with config {:?}", config);
 
+    // Refuse to start on a config that is malformed.
+    if let Err(ref err) = config.validate() {
+        error!("{}", err);
+        std::process::exit(1);
+    }
+
+    // Start out with an empty latency report, receivers append to it once they are done.
+    if config.latency_report.len() > 0 {
+        if let Err(ref err) = histogram::reset_report(&config.latency_report) {
+            error!("Failed to reset latency report {}: {}", config.latency_report, err);
+            std::process::exit(1);
+        }
+    }
+
+    // Refuse to start on a graph model that cannot be resolved.
+    if let Err(ref err) = graph::resolve(&config.tao) {
+        error!("{}", err);
+        std::process::exit(1);
+    }
+
     // Based on the supplied client configuration, compute the amount of time it will take to send
     // out `num_reqs` requests at a rate of `req_rate` requests per second.
     let exec = config.num_reqs / config.req_rate;
@@ -0,0 +1,410 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+
+use std::collections::HashMap;
+use std::mem::{size_of, transmute};
+use std::ptr::read_unaligned;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use db::log::*;
+use db::wireformat::{GetResponse, OpCode, RpcStatus};
+
+/// The number of bytes at the start of a value describing it: the key's identifier (4 bytes),
+/// followed by the version of the value (8 bytes). Every value the server is populated with
+/// starts with the key's identifier followed by zeros, which reads as version 0.
+pub const VALUE_HDR_LEN: usize = 12;
+
+/// The number of failed checks logged in detail by every verifier. The rest are only counted.
+const LOGGED_FAILURES: usize = 16;
+
+// What is known about the values written to a key.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct KeyState {
+    // The highest version written so far.
+    written: u64,
+
+    // The number of puts to the key that were sent out and not acknowledged yet, and the lowest
+    // version among them.
+    inflight: u32,
+    group: u64,
+
+    // The lowest version a read of the key can return. Puts that overlap in time can be applied
+    // by the server in any order, so this only moves up once all of them were acknowledged, to
+    // the lowest version among them.
+    settled: u64,
+}
+
+// Implementation of methods on KeyState.
+impl KeyState {
+    // Records a put of a version that was sent out.
+    fn sent(&mut self, version: u64) {
+        if self.inflight == 0 {
+            self.group = version;
+        }
+        self.inflight += 1;
+        self.written = self.written.max(version);
+        self.group = self.group.min(version);
+    }
+
+    // Records that the server acknowledged one of the puts that were sent out.
+    fn acked(&mut self) {
+        if self.inflight == 0 {
+            return;
+        }
+
+        self.inflight -= 1;
+        if self.inflight == 0 {
+            self.settled = self.settled.max(self.group);
+        }
+    }
+}
+
+// The response a request sent out is expecting.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Expect {
+    // A get of a key, whose value must be at least version `floor`.
+    Get { tenant: u32, key: u32, floor: u64 },
+
+    // A put to a key.
+    Put { tenant: u32, key: u32 },
+}
+
+/// The outcome of checking a response.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    /// The response was not to a request being verified, or did not complete.
+    Skipped,
+
+    /// The response was as expected.
+    Verified,
+
+    /// A read returned an older value than one that was written and acknowledged before the read
+    /// was sent out, or no value at all.
+    Lost,
+
+    /// A read returned a value that was never written to the key.
+    Corrupt,
+}
+
+/// Tracks the values a client wrote to every key, and checks every read against them. Values
+/// written in verification mode describe themselves (see `fill()`), so that a read can tell
+/// which write it observed and whether the value arrived intact.
+///
+/// The state of keys is shared by every Sender and Receiver pair on the client, while requests
+/// are matched up with their responses by RPC identifier on every pair; `pair()` returns a
+/// verifier for another pair.
+#[allow(dead_code)]
+pub struct Verifier {
+    // The state of every key written so far, keyed by tenant and key identifier.
+    keys: Arc<Mutex<HashMap<(u32, u32), KeyState>>>,
+
+    // The most recent version handed out to a put.
+    version: Arc<AtomicUsize>,
+
+    // Requests waiting on responses, keyed by RPC identifier.
+    outstanding: Mutex<HashMap<u64, Expect>>,
+
+    // The number of responses that were verified, that revealed lost updates, and that revealed
+    // corrupt values.
+    verified: AtomicUsize,
+    lost: AtomicUsize,
+    corrupt: AtomicUsize,
+}
+
+// Implementation of methods on Verifier.
+#[allow(dead_code)]
+impl Verifier {
+    /// Returns a verifier with no keys written yet.
+    pub fn new() -> Arc<Verifier> {
+        Arc::new(Verifier {
+            keys: Arc::new(Mutex::new(HashMap::new())),
+            version: Arc::new(AtomicUsize::new(0)),
+            outstanding: Mutex::new(HashMap::new()),
+            verified: AtomicUsize::new(0),
+            lost: AtomicUsize::new(0),
+            corrupt: AtomicUsize::new(0),
+        })
+    }
+
+    /// Returns a verifier for another Sender and Receiver pair, sharing the state of keys with
+    /// this one.
+    pub fn pair(&self) -> Arc<Verifier> {
+        Arc::new(Verifier {
+            keys: Arc::clone(&self.keys),
+            version: Arc::clone(&self.version),
+            outstanding: Mutex::new(HashMap::new()),
+            verified: AtomicUsize::new(0),
+            lost: AtomicUsize::new(0),
+            corrupt: AtomicUsize::new(0),
+        })
+    }
+
+    /// Records a get that is being sent out.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant`: The tenant issuing the get.
+    /// * `key`:    The key, whose first four bytes are it's identifier.
+    /// * `id`:     The RPC identifier on the request. Must be unique among outstanding requests.
+    pub fn get(&self, tenant: u32, key: &[u8], id: u64) {
+        let key = key_id(key);
+        let floor = self.keys
+            .lock()
+            .unwrap()
+            .get(&(tenant, key))
+            .map_or(0, |state| state.settled);
+
+        self.outstanding
+            .lock()
+            .unwrap()
+            .insert(id, Expect::Get {
+                tenant: tenant,
+                key: key,
+                floor: floor,
+            });
+    }
+
+    /// Records a put that is being sent out, and fills in the value it writes.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant`: The tenant issuing the put.
+    /// * `key`:    The key, whose first four bytes are it's identifier.
+    /// * `val`:    The value, at least VALUE_HDR_LEN bytes long. Overwritten with a new version.
+    /// * `id`:     The RPC identifier on the request. Must be unique among outstanding requests.
+    pub fn put(&self, tenant: u32, key: &[u8], val: &mut [u8], id: u64) {
+        let key = key_id(key);
+        let version = self.version.fetch_add(1, Ordering::Relaxed) as u64 + 1;
+        fill(val, key, version);
+
+        self.keys
+            .lock()
+            .unwrap()
+            .entry((tenant, key))
+            .or_insert_with(KeyState::default)
+            .sent(version);
+
+        self.outstanding
+            .lock()
+            .unwrap()
+            .insert(id, Expect::Put {
+                tenant: tenant,
+                key: key,
+            });
+    }
+
+    /// Checks a response against the request it answers.
+    ///
+    /// # Arguments
+    ///
+    /// * `payload`: The UDP payload of the response.
+    ///
+    /// # Return
+    ///
+    /// The outcome of the check, which is also counted.
+    pub fn check(&self, payload: &[u8]) -> Outcome {
+        // Every response header carries the status, opcode, and RPC identifier at the same
+        // offsets.
+        if payload.len() < 14 {
+            return Outcome::Skipped;
+        }
+        let status = payload[0];
+        let id = unsafe { read_unaligned(payload[6..].as_ptr() as *const u64) };
+
+        let expect = match self.outstanding.lock().unwrap().remove(&id) {
+            Some(expect) => expect,
+            None => return Outcome::Skipped,
+        };
+
+        let outcome = match expect {
+            Expect::Put { tenant, key } => {
+                if status == RpcStatus::StatusOk as u8 {
+                    if let Some(state) = self.keys.lock().unwrap().get_mut(&(tenant, key)) {
+                        state.acked();
+                    }
+                }
+                return Outcome::Skipped;
+            }
+
+            Expect::Get { tenant, key, floor } => {
+                let written = self.keys
+                    .lock()
+                    .unwrap()
+                    .get(&(tenant, key))
+                    .map_or(0, |state| state.written);
+
+                if payload[1] != OpCode::SandstormGetRpc as u8 {
+                    Outcome::Skipped
+                } else if status == RpcStatus::StatusObjectDoesNotExist as u8 {
+                    if floor > 0 {
+                        Outcome::Lost
+                    } else {
+                        Outcome::Skipped
+                    }
+                } else if status != RpcStatus::StatusOk as u8 {
+                    Outcome::Skipped
+                } else {
+                    let val = &payload[size_of::<GetResponse>().min(payload.len())..];
+                    judge(val, key, floor, written)
+                }
+            }
+        };
+
+        let counter = match outcome {
+            Outcome::Verified => &self.verified,
+            Outcome::Lost => &self.lost,
+            Outcome::Corrupt => &self.corrupt,
+            Outcome::Skipped => return outcome,
+        };
+
+        let failures = self.lost.load(Ordering::Relaxed) + self.corrupt.load(Ordering::Relaxed);
+        if outcome != Outcome::Verified && failures < LOGGED_FAILURES {
+            error!("Verification of key {} failed: {:?}", expect_key(&expect), outcome);
+        }
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        outcome
+    }
+
+    /// Returns the number of reads that were verified, that revealed lost updates, and that
+    /// revealed corrupt values.
+    pub fn counts(&self) -> (u64, u64, u64) {
+        (
+            self.verified.load(Ordering::Relaxed) as u64,
+            self.lost.load(Ordering::Relaxed) as u64,
+            self.corrupt.load(Ordering::Relaxed) as u64,
+        )
+    }
+}
+
+/// Writes a self describing value: the key's identifier, the version, and filler derived from
+/// both. Version 0 has all zero filler, matching the values the server is populated with.
+///
+/// # Arguments
+///
+/// * `val`:     The value, at least VALUE_HDR_LEN bytes long.
+/// * `key`:     The identifier of the key the value is written to.
+/// * `version`: The version of the value.
+pub fn fill(val: &mut [u8], key: u32, version: u64) {
+    let k: [u8; 4] = unsafe { transmute(key.to_le()) };
+    let v: [u8; 8] = unsafe { transmute(version.to_le()) };
+    val[0..4].copy_from_slice(&k);
+    val[4..VALUE_HDR_LEN].copy_from_slice(&v);
+
+    let seed = filler(key, version);
+    for (i, byte) in val[VALUE_HDR_LEN..].iter_mut().enumerate() {
+        *byte = (seed >> ((i % 8) * 8)) as u8;
+    }
+}
+
+// Decides what a value read from a key reveals, given the lowest version the read could have
+// returned and the highest version ever written.
+fn judge(val: &[u8], key: u32, floor: u64, written: u64) -> Outcome {
+    if val.len() < VALUE_HDR_LEN {
+        return Outcome::Corrupt;
+    }
+
+    let k = u32::from_le(unsafe { read_unaligned(val.as_ptr() as *const u32) });
+    let version = u64::from_le(unsafe { read_unaligned(val[4..].as_ptr() as *const u64) });
+    if k != key || version > written {
+        return Outcome::Corrupt;
+    }
+
+    let seed = filler(key, version);
+    for (i, byte) in val[VALUE_HDR_LEN..].iter().enumerate() {
+        if *byte != (seed >> ((i % 8) * 8)) as u8 {
+            return Outcome::Corrupt;
+        }
+    }
+
+    if version < floor {
+        return Outcome::Lost;
+    }
+
+    Outcome::Verified
+}
+
+// Returns the filler of a version of a key's value. Zero for version 0.
+fn filler(key: u32, version: u64) -> u64 {
+    if version == 0 {
+        return 0;
+    }
+
+    let mut x = ((key as u64) << 32) ^ version;
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+// Returns the identifier of a key, the first four bytes in little endian.
+fn key_id(key: &[u8]) -> u32 {
+    let mut k = [0u8; 4];
+    let n = key.len().min(4);
+    k[..n].copy_from_slice(&key[..n]);
+    u32::from_le(unsafe { transmute(k) })
+}
+
+// Returns the key identifier a request was for.
+fn expect_key(expect: &Expect) -> u32 {
+    match *expect {
+        Expect::Get { key, .. } | Expect::Put { key, .. } => key,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fill, judge, KeyState, Outcome, VALUE_HDR_LEN};
+
+    #[test]
+    fn values() {
+        let mut val = vec![0u8; 32];
+        fill(&mut val, 7, 3);
+        assert_eq!(Outcome::Verified, judge(&val, 7, 3, 3));
+        assert_eq!(Outcome::Lost, judge(&val, 7, 4, 4));
+        assert_eq!(Outcome::Corrupt, judge(&val, 8, 0, 3));
+        assert_eq!(Outcome::Corrupt, judge(&val, 7, 0, 2));
+        assert_eq!(Outcome::Corrupt, judge(&val[..VALUE_HDR_LEN - 1], 7, 0, 3));
+
+        val[20] ^= 1;
+        assert_eq!(Outcome::Corrupt, judge(&val, 7, 0, 3));
+
+        // The values the server is populated with read as version 0.
+        let mut val = vec![0u8; 100];
+        val[0] = 9;
+        assert_eq!(Outcome::Verified, judge(&val, 9, 0, 0));
+    }
+
+    #[test]
+    fn settle() {
+        let mut state = KeyState::default();
+        state.sent(5);
+        state.sent(6);
+        state.acked();
+
+        // Either put could still be applied last.
+        assert_eq!(0, state.settled);
+        state.acked();
+        assert_eq!(5, state.settled);
+        assert_eq!(6, state.written);
+
+        // An acknowledgement for a put lost track of does nothing.
+        state.acked();
+        state.sent(9);
+        state.acked();
+        assert_eq!(9, state.settled);
+    }
+}
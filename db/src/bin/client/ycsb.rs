@@ -22,8 +22,10 @@ extern crate zipf;
 
 mod dispatch;
 mod setup;
+mod verify;
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
 use std::fmt::Display;
 use std::mem;
 use std::mem::transmute;
@@ -34,6 +36,7 @@ use db::cycles;
 use db::e2d2::allocators::*;
 use db::e2d2::interface::*;
 use db::e2d2::scheduler::*;
+use db::histogram::{self, Histogram};
 use db::log::*;
 use db::rpc::*;
 use db::wireformat::*;
@@ -42,13 +45,141 @@ use rand::distributions::Sample;
 use rand::{Rng, SeedableRng, XorShiftRng};
 use zipf::ZipfDistribution;
 
-// YCSB A, B, and C benchmark.
+// The distribution keys of YCSB operations are drawn from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum KeyDist {
+    // Popular keys are spread across the key space.
+    Zipfian,
+
+    // Every key is equally likely.
+    Uniform,
+
+    // Recently inserted keys are the most popular.
+    Latest,
+}
+
+// The mix of operations in a YCSB workload. Every field other than `dist` is a percentage, and
+// together they add upto 100.
+#[derive(Clone, Debug, PartialEq)]
+struct Mix {
+    // Reads of a single key.
+    read: usize,
+
+    // Overwrites of an existing key.
+    update: usize,
+
+    // Writes of a new key.
+    insert: usize,
+
+    // Reads of a range of consecutive keys.
+    scan: usize,
+
+    // Reads of a key followed by a write to it.
+    rmw: usize,
+
+    // The distribution keys are drawn from.
+    dist: KeyDist,
+}
+
+// Implementation of methods on Mix.
+impl Mix {
+    // Creates a mix of reads and updates.
+    fn read_update(put_pct: usize) -> Mix {
+        Mix {
+            read: 100 - put_pct,
+            update: put_pct,
+            insert: 0,
+            scan: 0,
+            rmw: 0,
+            dist: KeyDist::Zipfian,
+        }
+    }
+
+    // Creates the mix described by a client config. `ycsb_workload` selects one of the core YCSB
+    // workloads A to F; if it is empty, the mix consists of reads and `put_pct` updates. A
+    // distribution in `key_dist` overrides the workload's.
+    fn from_config(config: &config::ClientConfig) -> Mix {
+        Mix::new(&config.ycsb_workload, &config.key_dist, config.put_pct)
+    }
+
+    // Creates the mix of an emulated tenant. The client's workload and distribution are used
+    // where the tenant does not set it's own; a tenant that only sets `put_pct` issues reads and
+    // updates.
+    fn from_tenant(config: &config::ClientConfig, tenant: &config::EmulatedTenantConfig) -> Mix {
+        let workload = if tenant.ycsb_workload.len() > 0 || tenant.put_pct > 0 {
+            &tenant.ycsb_workload
+        } else {
+            &config.ycsb_workload
+        };
+
+        let dist = if tenant.key_dist.len() > 0 {
+            &tenant.key_dist
+        } else {
+            &config.key_dist
+        };
+
+        Mix::new(workload, dist, tenant.put_pct)
+    }
+
+    // Creates the mix of one of the core YCSB workloads A to F, or of reads and `put_pct`
+    // updates if `workload` is empty, with keys drawn from `dist` if it is not empty.
+    fn new(workload: &str, dist: &str, put_pct: usize) -> Mix {
+        let mut mix = Mix::read_update(put_pct);
+        match workload {
+            // Update heavy.
+            "A" => mix = Mix::read_update(50),
+
+            // Read mostly.
+            "B" => mix = Mix::read_update(5),
+
+            // Read only.
+            "C" => mix = Mix::read_update(0),
+
+            // Read latest.
+            "D" => {
+                mix = Mix::read_update(0);
+                mix.read = 95;
+                mix.insert = 5;
+                mix.dist = KeyDist::Latest;
+            }
+
+            // Short ranges.
+            "E" => {
+                mix = Mix::read_update(0);
+                mix.read = 0;
+                mix.scan = 95;
+                mix.insert = 5;
+            }
+
+            // Read-modify-write.
+            "F" => {
+                mix = Mix::read_update(0);
+                mix.read = 50;
+                mix.rmw = 50;
+            }
+
+            _ => {}
+        }
+
+        match dist {
+            "zipfian" => mix.dist = KeyDist::Zipfian,
+            "uniform" => mix.dist = KeyDist::Uniform,
+            "latest" => mix.dist = KeyDist::Latest,
+            _ => {}
+        }
+
+        mix
+    }
+}
+
+// YCSB benchmark, covering the core workloads A to F.
 // The benchmark is created and parameterized with `new()`. Many threads
 // share the same benchmark instance. Each thread can call `abc()` which
 // runs the benchmark until another thread calls `stop()`. Each thread
 // then returns their runtime and the number of gets and puts they have done.
 // This benchmark doesn't care about how get/put are implemented; it takes
 // function pointers to get/put on `new()` and just calls those as it runs.
+// Workloads D, E and F also insert, scan, and read-modify-write; `op()` runs those.
 //
 // The tests below give an example of how to use it and how to aggregate the results.
 pub struct Ycsb {
@@ -58,6 +189,30 @@ pub struct Ycsb {
     tenant_rng: Box<ZipfDistribution>,
     key_buf: Vec<u8>,
     value_buf: Vec<u8>,
+
+    // The mix of operations `op()` draws from.
+    mix: Mix,
+
+    // The number of keys the table was populated with.
+    n_keys: u32,
+
+    // The number of keys this instance has inserted so far.
+    inserted: u32,
+
+    // This instance's index among the instances inserting keys, and the number of them. Inserted
+    // keys are interleaved across instances so that they never collide.
+    writer: u32,
+    writers: u32,
+
+    // The maximum number of keys a scan reads.
+    scan_max: u32,
+
+    // The keys read by the last scan, back to back.
+    scan_buf: Vec<u8>,
+
+    // The tenant every operation is issued on behalf of, or 0 to draw tenants from
+    // `tenant_rng`.
+    tenant: u32,
 }
 
 impl Ycsb {
@@ -102,9 +257,138 @@ impl Ycsb {
             ),
             key_buf: key_buf,
             value_buf: value_buf,
+            mix: Mix::read_update(put_pct),
+            n_keys: n_keys as u32,
+            inserted: 0,
+            writer: 0,
+            writers: 1,
+            scan_max: 100,
+            scan_buf: Vec::new(),
+            tenant: 0,
+        }
+    }
+
+    // Issues every operation from `op()` on behalf of a single tenant.
+    //
+    // # Arguments
+    //  - tenant: The tenant, or 0 to go back to drawing tenants at random.
+    fn set_tenant(&mut self, tenant: u32) {
+        self.tenant = tenant;
+    }
+
+    // Sets the mix of operations issued by `op()`.
+    //
+    // # Arguments
+    //  - mix: The mix of operations.
+    //  - writer: This instance's index among all instances inserting into the same table.
+    //  - writers: The number of instances inserting into the same table.
+    //  - scan_max: The maximum number of keys read by a scan.
+    fn set_mix(&mut self, mix: Mix, writer: u32, writers: u32, scan_max: usize) {
+        self.mix = mix;
+        self.writer = writer;
+        self.writers = writers;
+        self.scan_max = if scan_max > 0 { scan_max as u32 } else { 100 };
+    }
+
+    // Returns the largest key that has been written so far, assuming that every instance inserts
+    // at about the same rate.
+    fn newest(&self) -> u32 {
+        self.n_keys + self.inserted * self.writers
+    }
+
+    // Samples an existing key from the mix's distribution.
+    fn sample_key(&mut self) -> u32 {
+        match self.mix.dist {
+            KeyDist::Zipfian => self.key_rng.sample(&mut self.rng) as u32,
+
+            KeyDist::Uniform => self.rng.gen_range(1, self.newest() + 1),
+
+            // The most popular key is the newest one, the next most popular the one before
+            // that, and so on.
+            KeyDist::Latest => {
+                let offset = self.key_rng.sample(&mut self.rng) as u32 - 1;
+                let newest = self.newest();
+                if offset >= newest {
+                    1
+                } else {
+                    newest - offset
+                }
+            }
         }
     }
 
+    // Writes a key's identifier into the key buffer as a little endian byte array.
+    fn set_key(&mut self, k: u32) {
+        let k: [u8; 4] = unsafe { transmute(k.to_le()) };
+        self.key_buf[0..mem::size_of::<u32>()].copy_from_slice(&k);
+    }
+
+    // Runs one operation drawn from the mix set by `set_mix()`.
+    //
+    // # Arguments
+    //  - get: A function that fetches the data stored under a bytestring key of `self.key_len` bytes.
+    //  - put: A function that stores a bytestring value of `self.value_len` bytes under a
+    //         bytestring key of `self.key_len` bytes. Used for updates and inserts.
+    //  - scan: A function that fetches the data stored under a number of keys, each
+    //          `self.key_len` bytes long and laid out back to back.
+    // # Return
+    //  Whatever the last function called returned. A read-modify-write calls `get` and then `put`
+    //  on the same key without waiting for the read, since values are never actually modified.
+    pub fn op<G, P, S, R>(&mut self, mut get: G, mut put: P, mut scan: S) -> R
+    where
+        G: FnMut(u32, &[u8]) -> R,
+        P: FnMut(u32, &[u8], &[u8]) -> R,
+        S: FnMut(u32, &[u8], u32) -> R,
+    {
+        let p = (self.rng.gen::<u32>() % 100) as usize;
+
+        // Sample a tenant, unless this instance emulates a single one.
+        let t = if self.tenant > 0 {
+            self.tenant
+        } else {
+            self.tenant_rng.sample(&mut self.rng) as u32
+        };
+
+        if p < self.mix.insert {
+            let k = self.n_keys + 1 + self.writer + self.writers * self.inserted;
+            self.inserted += 1;
+            self.set_key(k);
+            return put(t, self.key_buf.as_slice(), self.value_buf.as_slice());
+        }
+
+        let k = self.sample_key();
+
+        if p < self.mix.insert + self.mix.scan {
+            // Scan upto `scan_max` keys starting at the sampled one, without running past the
+            // newest key.
+            let newest = self.newest();
+            let n = (1 + self.rng.gen::<u32>() % self.scan_max).min(newest - k + 1);
+
+            let k_len = self.key_buf.len();
+            self.scan_buf.resize(k_len * n as usize, 0);
+            for i in 0..n {
+                self.set_key(k + i);
+                let off = i as usize * k_len;
+                self.scan_buf[off..off + k_len].copy_from_slice(&self.key_buf);
+            }
+
+            return scan(t, self.scan_buf.as_slice(), n);
+        }
+
+        self.set_key(k);
+
+        if p < self.mix.insert + self.mix.scan + self.mix.update {
+            return put(t, self.key_buf.as_slice(), self.value_buf.as_slice());
+        }
+
+        if p < self.mix.insert + self.mix.scan + self.mix.update + self.mix.rmw {
+            get(t, self.key_buf.as_slice());
+            return put(t, self.key_buf.as_slice(), self.value_buf.as_slice());
+        }
+
+        get(t, self.key_buf.as_slice())
+    }
+
     // Run YCSB A, B, or C (depending on `new()` parameters).
     // The calling thread will not return until `done()` is called on this `Ycsb` instance.
     //
@@ -138,29 +422,52 @@ impl Ycsb {
     }
 }
 
-/// Sends out YCSB based RPC requests to a Sandstorm server.
-struct YcsbSend {
-    // The actual YCSB workload. Required to generate keys and values for get() and put() requests.
-    workload: RefCell<Ycsb>,
+/// The largest number of requests a generator issues in one go when it is behind schedule.
+const SEND_BURST: usize = 32;
 
-    // Network stack required to actually send RPC requests out the network.
-    sender: dispatch::Sender,
+/// A stream of requests issued on it's own schedule. A generator has one stream for every tenant
+/// it emulates, or a single one issuing requests on behalf of tenants drawn at random.
+struct Stream {
+    // The actual YCSB workload. Required to generate keys and values for get() and put() requests.
+    workload: Ycsb,
 
-    // Total number of requests to be sent out.
+    // Total number of requests to be sent out on this stream.
     requests: u64,
 
     // Number of requests that have been sent out so far.
     sent: u64,
 
-    // The inverse of the rate at which requests are to be generated. Basically, the time interval
-    // between two request generations in cycles.
-    rate_inv: u64,
-
-    // The time stamp at which the workload started generating requests in cycles.
-    start: u64,
+    // The schedule requests are issued on, independent of when responses arrive.
+    arrivals: dispatch::Arrivals,
 
-    // The time stamp at which the next request must be issued in cycles.
+    // The time stamp at which the next request must be issued in cycles, or 0 if no request
+    // has been issued yet.
     next: u64,
+}
+
+/// Sends out YCSB based RPC requests to a Sandstorm server.
+struct YcsbSend {
+    // The streams requests are issued on.
+    streams: Vec<Stream>,
+
+    // Network stack required to actually send RPC requests out the network.
+    sender: dispatch::Sender,
+
+    // Counts of issued requests, shared with the receiver of this generator's responses.
+    accounting: Arc<dispatch::Accounting>,
+
+    // If supplied, gets and puts are recorded so that the receiver can check the values read,
+    // along with the last RPC identifier handed out and a buffer values are written into.
+    verifier: Option<Arc<verify::Verifier>>,
+    last: u64,
+    vbuf: Vec<u8>,
+
+    // The phases of the run, started by the first request any sender issues.
+    phases: Arc<dispatch::Phases>,
+
+    // If true, requests are stamped with the time they were due instead of the time they went
+    // out, so that measured latencies include any time spent waiting on the client.
+    correct: bool,
 
     // If true, RPC requests corresponding to native get() and put() operations are sent out. If
     // false, invoke() based RPC requests are sent out.
@@ -184,8 +491,19 @@ impl YcsbSend {
     /// * `config`:    Client configuration with YCSB related (key and value length etc.) as well as
     ///                Network related (Server and Client MAC address etc.) parameters.
     /// * `port`:      Network port over which requests will be sent out.
-    /// * `reqs`:      The number of requests to be issued to the server.
+    /// * `reqs`:      The number of requests to be issued to the server. Split across emulated
+    ///                tenants in proportion to their request rates. Unlimited if the run was
+    ///                split into phases, which then decide when it ends.
     /// * `dst_ports`: The total number of UDP ports the server is listening on.
+    /// * `congestion`: Congestion state shared with the receiver of this generator's responses.
+    ///                 Only used if congestion control was enabled.
+    /// * `accounting`: Counts of issued requests, shared with the receiver of this generator's
+    ///                 responses.
+    /// * `verifier`:  If supplied, records requests so that responses can be verified.
+    /// * `phases`:    The phases of the run, shared with every other sender and receiver.
+    /// * `writer`:    The index of this generator among all generators on the client.
+    /// * `writers`:   The number of generators on the client. Required to keep the keys inserted
+    ///                by different generators apart.
     ///
     /// # Return
     ///
@@ -195,6 +513,12 @@ impl YcsbSend {
         port: CacheAligned<PortQueue>,
         reqs: u64,
         dst_ports: u16,
+        congestion: Arc<dispatch::Congestion>,
+        accounting: Arc<dispatch::Accounting>,
+        verifier: Option<Arc<verify::Verifier>>,
+        phases: Arc<dispatch::Phases>,
+        writer: u32,
+        writers: u32,
     ) -> YcsbSend {
         // The payload on an invoke() based get request consists of the extensions name ("get"),
         // the table id to perform the lookup on, and the key to lookup.
@@ -220,8 +544,20 @@ impl YcsbSend {
         });
         payload_put.resize(payload_len, 0);
 
-        YcsbSend {
-            workload: RefCell::new(Ycsb::new(
+        let mut sender = dispatch::Sender::new(config, port, dst_ports);
+        if config.congestion_control {
+            sender.set_congestion(congestion);
+        }
+
+        let reqs = if phases.enabled() {
+            u64::max_value()
+        } else {
+            reqs
+        };
+
+        let mut streams = Vec::new();
+        if config.emulate.len() == 0 {
+            let mut workload = Ycsb::new(
                 config.key_len,
                 config.value_len,
                 config.n_keys,
@@ -229,13 +565,69 @@ impl YcsbSend {
                 config.skew,
                 config.num_tenants,
                 config.tenant_skew,
-            )),
-            sender: dispatch::Sender::new(config, port, dst_ports),
-            requests: reqs,
-            sent: 0,
-            rate_inv: cycles::cycles_per_second() / config.req_rate as u64,
-            start: cycles::rdtsc(),
-            next: 0,
+            );
+            workload.set_mix(Mix::from_config(config), writer, writers, config.scan_max);
+
+            streams.push(Stream {
+                workload: workload,
+                requests: reqs,
+                sent: 0,
+                arrivals: dispatch::Arrivals::new(config),
+                next: 0,
+            });
+        }
+
+        // Every emulated tenant gets a share of the requests matching it's share of the rate,
+        // so that all of them keep issuing requests for about as long.
+        let rate: usize = config.emulate.iter().map(|tenant| tenant.req_rate).sum();
+        for tenant in config.emulate.iter() {
+            let n_keys = if tenant.n_keys > 0 {
+                tenant.n_keys
+            } else {
+                config.n_keys
+            };
+            let skew = if tenant.skew > 0.0 {
+                tenant.skew
+            } else {
+                config.skew
+            };
+
+            let mut workload = Ycsb::new(
+                config.key_len,
+                config.value_len,
+                n_keys,
+                tenant.put_pct,
+                skew,
+                1,
+                config.tenant_skew,
+            );
+            workload.set_mix(Mix::from_tenant(config, tenant), writer, writers, config.scan_max);
+            workload.set_tenant(tenant.id);
+
+            let requests = if reqs == u64::max_value() {
+                reqs
+            } else {
+                reqs * tenant.req_rate as u64 / rate as u64
+            };
+
+            streams.push(Stream {
+                workload: workload,
+                requests: requests,
+                sent: 0,
+                arrivals: dispatch::Arrivals::with_rate(config, tenant.req_rate),
+                next: 0,
+            });
+        }
+
+        YcsbSend {
+            streams: streams,
+            sender: sender,
+            accounting: accounting,
+            verifier: verifier,
+            last: 0,
+            vbuf: Vec::with_capacity(config.value_len),
+            phases: phases,
+            correct: config.correct_omission,
             native: !config.use_invoke,
             payload_get: RefCell::new(payload_get),
             payload_put: RefCell::new(payload_put),
@@ -243,58 +635,131 @@ impl YcsbSend {
     }
 }
 
+/// Sends out a scan over consecutive keys as a multiget() request.
+///
+/// # Arguments
+///
+/// * `sender`: Network stack the request is sent out on.
+/// * `tenant`: Id of the tenant issuing the scan.
+/// * `keys`:   The keys to be read, laid out back to back.
+/// * `n`:      The number of keys to be read.
+/// * `id`:     RPC identifier.
+fn scan(sender: &dispatch::Sender, tenant: u32, keys: &[u8], n: u32, id: u64) {
+    let k_len = keys.len() / n as usize;
+    sender.send_multiget(tenant, 1, k_len as u16, n, keys, id);
+}
+
 // The Executable trait allowing YcsbSend to be scheduled by Netbricks.
 impl Executable for YcsbSend {
     // Called internally by Netbricks.
     fn execute(&mut self) {
-        // Return if there are no more requests to generate.
-        if self.requests <= self.sent {
-            return;
-        }
-
         // Get the current time stamp so that we can determine if it is time to issue the next RPC.
         let curr = cycles::rdtsc();
+        self.phases.begin(curr);
+
+        let sender = &self.sender;
+        let accounting = &self.accounting;
+        let native = self.native;
+        let correct = self.correct;
+        let mut p_get = self.payload_get.borrow_mut();
+        let mut p_put = self.payload_put.borrow_mut();
+
+        // Requests being verified are matched up with their responses by RPC identifier, so
+        // requests that would carry the same stamp are stamped a cycle apart.
+        let verifier = &self.verifier;
+        let vbuf = &mut self.vbuf;
+        let last = Cell::new(self.last);
+        let next_id = |stamp: u64| {
+            if verifier.is_none() {
+                return stamp;
+            }
 
-        // If it is either time to send out a request, or if a request has never been sent out,
-        // then, do so.
-        if curr >= self.next || self.next == 0 {
-            if self.native == true {
-                // Configured to issue native RPCs, issue a regular get()/put() operation.
-                self.workload.borrow_mut().abc(
-                    |tenant, key| self.sender.send_get(tenant, 1, key, curr),
-                    |tenant, key, val| self.sender.send_put(tenant, 1, key, val, curr),
-                );
-            } else {
-                // Configured to issue invoke() RPCs.
-                let mut p_get = self.payload_get.borrow_mut();
-                let mut p_put = self.payload_put.borrow_mut();
-
-                // XXX Heavily dependent on how `Ycsb` creates a key. Only the first four
-                // bytes of the key matter, the rest are zero. The value is always zero.
-                self.workload.borrow_mut().abc(
-                    |tenant, key| {
-                        // First 11 bytes on the payload were already pre-populated with the
-                        // extension name (3 bytes), and the table id (8 bytes). Just write in the
-                        // first 4 bytes of the key.
-                        p_get[11..15].copy_from_slice(&key[0..4]);
-                        self.sender.send_invoke(tenant, 3, &p_get, curr)
-                    },
-                    |tenant, key, _val| {
-                        // First 13 bytes on the payload were already pre-populated with the
-                        // extension name (3 bytes), the table id (8 bytes), and the key length (2
-                        // bytes). Just write in the first 4 bytes of the key. The value is anyway
-                        // always zero.
-                        p_put[13..17].copy_from_slice(&key[0..4]);
-                        self.sender.send_invoke(tenant, 3, &p_put, curr)
-                    },
-                );
+            let id = stamp.max(last.get() + 1);
+            last.set(id);
+            id
+        };
+
+        for stream in self.streams.iter_mut() {
+            // The schedule starts with the first request.
+            if stream.next == 0 {
+                stream.next = curr;
             }
 
-            // Update the time stamp at which the next request should be generated, assuming that
-            // the first request was sent out at self.start.
-            self.sent += 1;
-            self.next = self.start + self.sent * self.rate_inv;
+            // Issue every request that is due. A stream that fell behind the schedule catches
+            // up a burst at a time instead of skipping requests, so that other streams and the
+            // scheduler's other tasks still get to run.
+            for _ in 0..SEND_BURST {
+                // Move on if there are no more requests to generate, or if the next one is not
+                // due.
+                if stream.requests <= stream.sent || curr < stream.next {
+                    break;
+                }
+
+                // A request that went out late because the client stalled is measured from when
+                // it was due, if configured to, so that the stall is not left out of latencies.
+                let stamp = if correct { stream.next } else { curr };
+
+                if native == true {
+                    // Configured to issue native RPCs, issue a regular get()/put() operation.
+                    stream.workload.op(
+                        |tenant, key| {
+                            let id = next_id(stamp);
+                            if let Some(ref verifier) = *verifier {
+                                verifier.get(tenant, key, id);
+                            }
+                            sender.send_get(tenant, 1, key, id)
+                        },
+                        |tenant, key, val| {
+                            let id = next_id(stamp);
+                            match *verifier {
+                                // Write a new version of the key instead of the workload's value.
+                                Some(ref verifier) => {
+                                    vbuf.clear();
+                                    vbuf.extend_from_slice(val);
+                                    verifier.put(tenant, key, &mut vbuf[..], id);
+                                    sender.send_put(tenant, 1, key, &vbuf[..], id)
+                                }
+
+                                None => sender.send_put(tenant, 1, key, val, id),
+                            }
+                        },
+                        |tenant, keys, n| scan(sender, tenant, keys, n, next_id(stamp)),
+                    );
+                } else {
+                    // Configured to issue invoke() RPCs.
+                    // XXX Heavily dependent on how `Ycsb` creates a key. Only the first four
+                    // bytes of the key matter, the rest are zero. The value is always zero.
+                    // There is no scan extension, so scans are always issued as native
+                    // multiget() requests.
+                    stream.workload.op(
+                        |tenant, key| {
+                            // First 11 bytes on the payload were already pre-populated with the
+                            // extension name (3 bytes), and the table id (8 bytes). Just write
+                            // in the first 4 bytes of the key.
+                            p_get[11..15].copy_from_slice(&key[0..4]);
+                            sender.send_invoke(tenant, 3, &p_get, stamp)
+                        },
+                        |tenant, key, _val| {
+                            // First 13 bytes on the payload were already pre-populated with the
+                            // extension name (3 bytes), the table id (8 bytes), and the key
+                            // length (2 bytes). Just write in the first 4 bytes of the key. The
+                            // value is anyway always zero.
+                            p_put[13..17].copy_from_slice(&key[0..4]);
+                            sender.send_invoke(tenant, 3, &p_put, stamp)
+                        },
+                        |tenant, keys, n| scan(sender, tenant, keys, n, stamp),
+                    );
+                }
+
+                // Update the time stamp at which the next request should be issued. The gap is
+                // stretched if the server signaled congestion.
+                accounting.issue(stream.next, curr);
+                stream.sent += 1;
+                stream.next += sender.next_gap(stream.arrivals.gap());
+            }
         }
+
+        self.last = last.get();
     }
 
     fn dependencies(&mut self) -> Vec<usize> {
@@ -302,6 +767,15 @@ impl Executable for YcsbSend {
     }
 }
 
+/// The responses received for a tenant emulated by the client.
+struct TenantStats {
+    // The number of responses received.
+    recvd: u64,
+
+    // Histogram of request latencies in nanoseconds, only filled in by the master receiver.
+    latencies: Histogram,
+}
+
 /// Receives responses to YCSB requests sent out by YcsbSend.
 struct YcsbRecv<T>
 where
@@ -320,18 +794,50 @@ where
     // The total number of responses received so far.
     recvd: u64,
 
-    // Vector of sampled request latencies. Required to calculate distributions once all responses
-    // have been received.
-    latencies: Vec<u64>,
+    // Histogram of request latencies in nanoseconds. Required to calculate distributions once all
+    // responses have been received.
+    latencies: Histogram,
 
     // If true, this receiver will make latency measurements.
     master: bool,
 
+    // The file the latency distribution is appended to once all responses have been received,
+    // and the name of the table it is written under. Nothing is written if the path is empty.
+    report: String,
+    name: String,
+
     // If true, then responses will be considered to correspond to native gets and puts.
     native: bool,
 
     // Time stamp in cycles at which measurement stopped.
     stop: u64,
+
+    // Counts of the requests issued by the generator whose responses are received here, and the
+    // number of responses that arrived after measurement stopped. Used to account for requests
+    // that were never answered.
+    accounting: Arc<dispatch::Accounting>,
+    extra: u64,
+
+    // If true, the client emulates tenants, and responses are also counted by tenant.
+    emulated: bool,
+    tenants: HashMap<u32, TenantStats>,
+
+    // If supplied, every response is checked against the request it answers.
+    verifier: Option<Arc<verify::Verifier>>,
+
+    // The phases of the run. If they are enabled, only the `measured` responses received during
+    // the measurement phase count towards throughput and latency.
+    phases: Arc<dispatch::Phases>,
+    measured: u64,
+
+    // The file the timeseries of the measurement phase is appended to, the second of it being
+    // sampled, the responses and latencies received during that second, and the lines for the
+    // seconds sampled so far.
+    timeseries: String,
+    second: u64,
+    second_recvd: u64,
+    second_latencies: Histogram,
+    series: String,
 }
 
 // Implementation of methods on YcsbRecv.
@@ -347,23 +853,92 @@ where
     /// * `resps`:  The number of responses to wait for before calculating statistics.
     /// * `master`: Boolean indicating if the receiver should make latency measurements.
     /// * `native`: If true, responses will be considered to correspond to native gets and puts.
+    /// * `congestion`: If supplied, congestion state updated with the marks on every response.
+    /// * `accounting`: Counts of the requests issued by the generator whose responses are
+    ///                 received here.
+    /// * `report`: File the latency distribution is appended to. Nothing is written if empty.
+    /// * `name`:   Name of the table the latency distribution is written under.
+    /// * `emulated`: If true, throughput and latency are also measured for every tenant.
+    /// * `verifier`: If supplied, checks every response against the request it answers.
+    /// * `phases`: The phases of the run, shared with every sender.
+    /// * `timeseries`: File the timeseries of the measurement phase is appended to. Nothing is
+    ///                 written if empty.
     ///
     /// # Return
     ///
-    /// A YCSB response receiver that measures the median latency and throughput of a Sandstorm
-    /// server.
-    fn new(port: T, resps: u64, master: bool, native: bool) -> YcsbRecv<T> {
+    /// A YCSB response receiver that measures the latency distribution and throughput of a
+    /// Sandstorm server.
+    fn new(
+        port: T,
+        resps: u64,
+        master: bool,
+        native: bool,
+        congestion: Option<Arc<dispatch::Congestion>>,
+        accounting: Arc<dispatch::Accounting>,
+        report: String,
+        name: String,
+        emulated: bool,
+        verifier: Option<Arc<verify::Verifier>>,
+        phases: Arc<dispatch::Phases>,
+        timeseries: String,
+    ) -> YcsbRecv<T> {
+        let mut receiver = dispatch::Receiver::new(port);
+        if let Some(congestion) = congestion {
+            receiver.set_congestion(congestion);
+        }
+
         YcsbRecv {
-            receiver: dispatch::Receiver::new(port),
+            receiver: receiver,
             responses: resps,
             start: cycles::rdtsc(),
             recvd: 0,
-            latencies: Vec::with_capacity(resps as usize),
+            latencies: Histogram::new(histogram::DEFAULT_PRECISION),
             master: master,
+            report: report,
+            name: name,
             native: native,
             stop: 0,
+            accounting: accounting,
+            extra: 0,
+            emulated: emulated,
+            tenants: HashMap::new(),
+            verifier: verifier,
+            phases: phases,
+            measured: 0,
+            timeseries: timeseries,
+            second: 0,
+            second_recvd: 0,
+            second_latencies: Histogram::new(histogram::DEFAULT_PRECISION),
+            series: String::new(),
+        }
+    }
+
+    /// Moves the timeseries on to a second of the measurement phase, closing the line of the
+    /// second sampled so far, along with lines for any seconds in between that saw nothing.
+    ///
+    /// # Arguments
+    ///
+    /// * `second`: The number of whole seconds since measurement started.
+    fn sample(&mut self, second: u64) {
+        while self.second < second {
+            self.close_second();
+            self.second += 1;
         }
     }
+
+    /// Adds the line for the second being sampled to the timeseries, and starts a new one.
+    fn close_second(&mut self) {
+        self.series.push_str(&format!(
+            "{},{},{},{},{}\n",
+            self.name,
+            self.second,
+            self.second_recvd,
+            self.second_latencies.percentile(50.0),
+            self.second_latencies.percentile(99.0)
+        ));
+        self.second_recvd = 0;
+        self.second_latencies = Histogram::new(histogram::DEFAULT_PRECISION);
+    }
 }
 
 // Implementation of the `Drop` trait on YcsbRecv.
@@ -372,32 +947,95 @@ where
     T: PacketTx + PacketRx + Display + Clone + 'static,
 {
     fn drop(&mut self) {
+        // If some requests were never answered, measure upto the time the client stopped.
+        if self.stop == 0 {
+            self.stop = cycles::rdtsc();
+        }
+
+        // If the run was split into phases, throughput is measured over the measurement phase.
+        let (recvd, secs) = if self.phases.enabled() {
+            (self.measured, self.phases.measure_secs() as f64)
+        } else {
+            (self.recvd, cycles::to_seconds(self.stop - self.start))
+        };
+
         // Calculate & print the throughput for all client threads.
+        println!("YCSB Throughput {}", recvd as f64 / secs);
+
+        // Every request issued on schedule that did not get a response was dropped somewhere,
+        // either by the network or by an overloaded server.
+        let (issued, late) = self.accounting.counts();
+        let answered = self.recvd + self.extra;
         println!(
-            "YCSB Throughput {}",
-            self.recvd as f64 / cycles::to_seconds(self.stop - self.start)
+            "YCSB Issued {} Answered {} Dropped {} Late {}",
+            issued,
+            answered,
+            issued.saturating_sub(answered),
+            late
         );
 
+        if let Some(ref verifier) = self.verifier {
+            let (verified, lost, corrupt) = verifier.counts();
+            println!(
+                "YCSB Verified {} Lost {} Corrupt {}",
+                verified, lost, corrupt
+            );
+        }
+
         // Calculate & print median & tail latency only on the master thread.
         if self.master {
-            self.latencies.sort();
-
-            let m;
-            let t = self.latencies[(self.latencies.len() * 99) / 100];
-            match self.latencies.len() % 2 {
-                0 => {
-                    let n = self.latencies.len();
-                    m = (self.latencies[n / 2] + self.latencies[(n / 2) + 1]) / 2;
+            println!(
+                ">>> {} {}",
+                self.latencies.percentile(50.0),
+                self.latencies.percentile(99.0)
+            );
+            println!("YCSB Latency(ns) {}", self.latencies.summary());
+
+            if self.report.len() > 0 {
+                let table = self.latencies.report(&self.name, "ns");
+                if let Err(ref err) = histogram::append_report(&self.report, &table) {
+                    error!("Failed to write latency report {}: {}", self.report, err);
                 }
+            }
+        }
 
-                _ => m = self.latencies[self.latencies.len() / 2],
+        // Break throughput and latency down by emulated tenant, in the order of their ids.
+        let mut tenants: Vec<(&u32, &TenantStats)> = self.tenants.iter().collect();
+        tenants.sort_by_key(|&(tenant, _)| *tenant);
+        for (tenant, stats) in tenants {
+            println!(
+                "YCSB Tenant {} Throughput {}",
+                tenant,
+                stats.recvd as f64 / secs
+            );
+
+            if !self.master {
+                continue;
             }
 
             println!(
-                ">>> {} {}",
-                cycles::to_seconds(m) * 1e9,
-                cycles::to_seconds(t) * 1e9
+                "YCSB Tenant {} Latency(ns) {}",
+                tenant,
+                stats.latencies.summary()
             );
+            if self.report.len() > 0 {
+                let name = format!("{}-tenant{}", self.name, tenant);
+                let table = stats.latencies.report(&name, "ns");
+                if let Err(ref err) = histogram::append_report(&self.report, &table) {
+                    error!("Failed to write latency report {}: {}", self.report, err);
+                }
+            }
+        }
+
+        // Close the last second of the timeseries, unless measurement never started.
+        if self.timeseries.len() > 0 && self.measured > 0 {
+            if self.second_recvd > 0 {
+                self.close_second();
+            }
+
+            if let Err(ref err) = histogram::append_report(&self.timeseries, &self.series) {
+                error!("Failed to write timeseries {}: {}", self.timeseries, err);
+            }
         }
     }
 }
@@ -409,29 +1047,89 @@ where
 {
     // Called internally by Netbricks.
     fn execute(&mut self) {
-        // Don't do anything after all responses have been received.
+        // Stop measuring after all responses have been received, but keep counting the ones
+        // that still arrive so that they are not accounted as dropped.
         if self.responses <= self.recvd {
+            if let Some(mut packets) = self.receiver.recv_res() {
+                self.extra += packets.len() as u64;
+                while let Some(packet) = packets.pop() {
+                    packet.free_packet();
+                }
+            }
             return;
         }
 
         // Try to receive packets from the network port.
         // If there are packets, sample the latency of the server.
         if let Some(mut packets) = self.receiver.recv_res() {
+            let phase = self.phases.phase(cycles::rdtsc());
+            if let dispatch::Phase::Measure(second) = phase {
+                self.sample(second);
+            }
+
             while let Some(packet) = packets.pop() {
                 self.recvd += 1;
 
-                // Measure latency on the master client after the first 2 million requests.
+                // Measure the responses received during the measurement phase, or, if the run
+                // was not split into phases, every one after the first 2 million.
+                let measuring = if self.phases.enabled() {
+                    match phase {
+                        dispatch::Phase::Measure(_) => true,
+                        _ => false,
+                    }
+                } else {
+                    self.recvd > 2 * 1000 * 1000
+                };
+                if measuring && self.phases.enabled() {
+                    self.measured += 1;
+                    self.second_recvd += 1;
+                }
+
+                // Every response header carries the tenant the request was issued for, at the
+                // same offset.
+                let tenant = if self.emulated {
+                    let p = packet.get_payload();
+                    if p.len() >= 6 {
+                        Some(unsafe { std::ptr::read_unaligned(p[2..].as_ptr() as *const u32) })
+                    } else {
+                        None
+                    }
+                } else {
+                    None
+                };
+
+                if let Some(ref verifier) = self.verifier {
+                    verifier.check(packet.get_payload());
+                }
+
+                // Throughput of tenants is counted over the same responses as the client's.
+                let tenant = if measuring || !self.phases.enabled() {
+                    tenant
+                } else {
+                    None
+                };
+                if let Some(tenant) = tenant {
+                    self.tenants
+                        .entry(tenant)
+                        .or_insert_with(|| TenantStats {
+                            recvd: 0,
+                            latencies: Histogram::new(histogram::DEFAULT_PRECISION),
+                        })
+                        .recvd += 1;
+                }
+
+                // Measure latency on the master client.
                 // The start timestamp is present on the RPC response header.
-                if self.recvd > 2 * 1000 * 1000 && self.master {
+                if measuring && self.master {
                     let curr = cycles::rdtsc();
 
-                    match self.native {
+                    let stamp = match self.native {
                         // The response corresponds to an invoke() RPC.
                         false => {
                             let p = packet.parse_header::<InvokeResponse>();
-                            self.latencies
-                                .push(curr - p.get_header().common_header.stamp);
+                            let stamp = p.get_header().common_header.stamp;
                             p.free_packet();
+                            Some(stamp)
                         }
 
                         // The response corresponds to a get() or put() RPC.
@@ -439,20 +1137,43 @@ where
                         true => match parse_rpc_opcode(&packet) {
                             OpCode::SandstormGetRpc => {
                                 let p = packet.parse_header::<GetResponse>();
-                                self.latencies
-                                    .push(curr - p.get_header().common_header.stamp);
+                                let stamp = p.get_header().common_header.stamp;
                                 p.free_packet();
+                                Some(stamp)
                             }
 
                             OpCode::SandstormPutRpc => {
                                 let p = packet.parse_header::<PutResponse>();
-                                self.latencies
-                                    .push(curr - p.get_header().common_header.stamp);
+                                let stamp = p.get_header().common_header.stamp;
                                 p.free_packet();
+                                Some(stamp)
                             }
 
-                            _ => packet.free_packet(),
+                            OpCode::SandstormMultiGetRpc => {
+                                let p = packet.parse_header::<MultiGetResponse>();
+                                let stamp = p.get_header().common_header.stamp;
+                                p.free_packet();
+                                Some(stamp)
+                            }
+
+                            _ => {
+                                packet.free_packet();
+                                None
+                            }
                         },
+                    };
+
+                    if let Some(stamp) = stamp {
+                        let latency = cycles::to_nanoseconds(curr - stamp);
+                        self.latencies.record(latency);
+                        if self.phases.enabled() {
+                            self.second_latencies.record(latency);
+                        }
+                        if let Some(tenant) = tenant {
+                            if let Some(stats) = self.tenants.get_mut(&tenant) {
+                                stats.latencies.record(latency);
+                            }
+                        }
                     }
                 } else {
                     packet.free_packet();
@@ -479,11 +1200,23 @@ where
 /// * `config`:    Network related configuration such as the MAC and IP address.
 /// * `ports`:     Network port on which packets will be sent.
 /// * `scheduler`: Netbricks scheduler to which YcsbSend will be added.
+/// * `congestion`: Congestion state shared with the receiver of the sender's responses.
+/// * `accounting`: Counts of issued requests shared with the receiver of the sender's responses.
+/// * `verifier`:  If supplied, records requests for the receiver of the sender's responses.
+/// * `phases`:    The phases of the run, shared with every sender and receiver.
+/// * `writer`:    The index of the added YcsbSend among all senders on the client.
+/// * `writers`:   The number of senders on the client.
 fn setup_send<S>(
     config: &config::ClientConfig,
     ports: Vec<CacheAligned<PortQueue>>,
     scheduler: &mut S,
     _core: i32,
+    congestion: Arc<dispatch::Congestion>,
+    accounting: Arc<dispatch::Accounting>,
+    verifier: Option<Arc<verify::Verifier>>,
+    phases: Arc<dispatch::Phases>,
+    writer: u32,
+    writers: u32,
 ) where
     S: Scheduler + Sized,
 {
@@ -498,6 +1231,12 @@ fn setup_send<S>(
         ports[0].clone(),
         config.num_reqs as u64,
         config.server_udp_ports as u16,
+        congestion,
+        accounting,
+        verifier,
+        phases,
+        writer,
+        writers,
     )) {
         Ok(_) => {
             info!(
@@ -517,17 +1256,27 @@ fn setup_send<S>(
 ///
 /// # Arguments
 ///
+/// * `config`:    Client configuration, the path of the latency report is taken from it.
 /// * `ports`:     Network port on which packets will be sent.
 /// * `scheduler`: Netbricks scheduler to which YcsbRecv will be added.
 /// * `master`:    If true, the added YcsbRecv will make latency measurements.
 /// * `native`:    If true, the added YcsbRecv will assume that responses correspond to gets
 ///                and puts.
+/// * `congestion`: If supplied, congestion state the added YcsbRecv will update.
+/// * `accounting`: Counts of the requests issued by the sender whose responses are received.
+/// * `verifier`:  If supplied, the added YcsbRecv will check responses with it.
+/// * `phases`:    The phases of the run, shared with every sender and receiver.
 fn setup_recv<S>(
+    config: &config::ClientConfig,
     ports: Vec<CacheAligned<PortQueue>>,
     scheduler: &mut S,
     _core: i32,
     master: bool,
     native: bool,
+    congestion: Option<Arc<dispatch::Congestion>>,
+    accounting: Arc<dispatch::Accounting>,
+    verifier: Option<Arc<verify::Verifier>>,
+    phases: Arc<dispatch::Phases>,
 ) where
     S: Scheduler + Sized,
 {
@@ -536,12 +1285,28 @@ fn setup_recv<S>(
         std::process::exit(1);
     }
 
+    // Receivers wait for a fixed number of responses, unless the phases decide when the run
+    // ends.
+    let responses = if phases.enabled() {
+        u64::max_value()
+    } else {
+        34 * 1000 * 1000 as u64
+    };
+
     // Add the receiver to a netbricks pipeline.
     match scheduler.add_task(YcsbRecv::new(
         ports[0].clone(),
-        34 * 1000 * 1000 as u64,
+        responses,
         master,
         native,
+        congestion,
+        accounting,
+        config.latency_report.clone(),
+        format!("ycsb-rxq{}", ports[0].rxq()),
+        config.emulate.len() > 0,
+        verifier,
+        phases,
+        config.timeseries.clone(),
     )) {
         Ok(_) => {
             info!(
@@ -563,9 +1328,40 @@ fn main() {
     let config = config::ClientConfig::load();
     info!("Starting up Sandstorm client with config {:?}", config);
 
+    // Refuse to start on a config that is malformed.
+    if let Err(ref err) = config.validate() {
+        error!("{}", err);
+        std::process::exit(1);
+    }
+
+    // Start out with an empty latency report, receivers append to it once they are done.
+    if config.latency_report.len() > 0 {
+        if let Err(ref err) = histogram::reset_report(&config.latency_report) {
+            error!("Failed to reset latency report {}: {}", config.latency_report, err);
+            std::process::exit(1);
+        }
+    }
+
+    // Likewise for the timeseries, which starts out with just it's column names.
+    if config.timeseries.len() > 0 {
+        let header = "thread,second,responses,p50_ns,p99_ns\n";
+        if let Err(ref err) = histogram::reset_report(&config.timeseries)
+            .and_then(|_| histogram::append_report(&config.timeseries, header))
+        {
+            error!("Failed to reset timeseries {}: {}", config.timeseries, err);
+            std::process::exit(1);
+        }
+    }
+
     // Based on the supplied client configuration, compute the amount of time it will take to send
-    // out `num_reqs` requests at a rate of `req_rate` requests per second.
-    let exec = config.num_reqs / config.req_rate;
+    // out `num_reqs` requests at a rate of `req_rate` requests per second, or at the combined
+    // rate of the emulated tenants.
+    let rate: usize = config.emulate.iter().map(|tenant| tenant.req_rate).sum();
+    let exec = if rate > 0 {
+        config.num_reqs / rate
+    } else {
+        config.num_reqs / config.req_rate
+    };
 
     // Setup Netbricks.
     let mut net_context = setup::config_and_init_netbricks(&config);
@@ -580,6 +1376,14 @@ fn main() {
     let receive = [1, 3, 5, 7];
     assert!((senders.len() == 4) && (receive.len() == 4));
 
+    // What every pair writes to keys is tracked in one place, so that reads can be checked
+    // against writes from any of them.
+    let tracker = verify::Verifier::new();
+
+    // Every sender and receiver agrees on when each phase of the run starts.
+    let phases = dispatch::Phases::new(&config);
+    let run_secs = phases.total_secs();
+
     // Setup 4 senders, and 4 receivers.
     for i in 0..4 {
         // First, retrieve a tx-rx queue pair from Netbricks
@@ -596,13 +1400,48 @@ fn main() {
 
         let native = !config.use_invoke;
 
+        // The sender and receiver share congestion state, so that marks on responses slow the
+        // sender down.
+        let congestion = dispatch::Congestion::new();
+        let recv_congestion = if config.congestion_control {
+            Some(Arc::clone(&congestion))
+        } else {
+            None
+        };
+
+        // The sender and receiver also share counts of issued requests, so that requests that
+        // were never answered can be accounted for.
+        let accounting = dispatch::Accounting::new();
+        let recv_accounting = Arc::clone(&accounting);
+
+        // The sender records the requests it sends out for the receiver to check responses to.
+        let verifier = if config.verify {
+            Some(tracker.pair())
+        } else {
+            None
+        };
+        let recv_verifier = verifier.clone();
+        let send_phases = Arc::clone(&phases);
+        let recv_phases = Arc::clone(&phases);
+
         // Setup the receive side.
         net_context
             .add_pipeline_to_core(
                 receive[i],
                 Arc::new(
                     move |_ports, sched: &mut StandaloneScheduler, core: i32, _sibling| {
-                        setup_recv(port.clone(), sched, core, master, native)
+                        setup_recv(
+                            &config::ClientConfig::load(),
+                            port.clone(),
+                            sched,
+                            core,
+                            master,
+                            native,
+                            recv_congestion.clone(),
+                            Arc::clone(&recv_accounting),
+                            recv_verifier.clone(),
+                            Arc::clone(&recv_phases),
+                        )
                     },
                 ),
             ).expect("Failed to initialize receive side.");
@@ -613,7 +1452,18 @@ fn main() {
                 senders[i],
                 Arc::new(
                     move |ports, sched: &mut StandaloneScheduler, core: i32, _sibling| {
-                        setup_send(&config::ClientConfig::load(), ports, sched, core)
+                        setup_send(
+                            &config::ClientConfig::load(),
+                            ports,
+                            sched,
+                            core,
+                            Arc::clone(&congestion),
+                            Arc::clone(&accounting),
+                            verifier.clone(),
+                            Arc::clone(&send_phases),
+                            i as u32,
+                            senders.len() as u32,
+                        )
                     },
                 ),
             ).expect("Failed to initialize send side.");
@@ -625,9 +1475,13 @@ fn main() {
     // Run the client.
     net_context.execute();
 
-    // Sleep for an amount of time approximately equal to the estimated execution time, and then
-    // shutdown the client.
-    std::thread::sleep(std::time::Duration::from_secs(exec as u64 + 11));
+    // Sleep for an amount of time approximately equal to the estimated execution time, or for the
+    // phases of the run, and then shutdown the client.
+    if run_secs > 0 {
+        std::thread::sleep(std::time::Duration::from_secs(run_secs + 1));
+    } else {
+        std::thread::sleep(std::time::Duration::from_secs(exec as u64 + 11));
+    }
 
     // Stop the client.
     net_context.stop();
@@ -652,7 +1506,7 @@ mod test {
         for _ in 0..n_threads {
             let done = done.clone();
             threads.push(thread::spawn(move || {
-                let mut b = super::Ycsb::new(10, 100, 1000000, 5, 0.99);
+                let mut b = super::Ycsb::new(10, 100, 1000000, 5, 0.99, 1, 0.1);
                 let mut n_gets = 0u64;
                 let mut n_puts = 0u64;
                 let start = Instant::now();
@@ -701,6 +1555,63 @@ mod test {
         k
     }
 
+    #[test]
+    fn ycsb_op_mix() {
+        let mut b = super::Ycsb::new(4, 100, 1000, 0, 0.99, 1, 0.1);
+        let mut mix = super::Mix::read_update(0);
+        mix.read = 0;
+        mix.scan = 50;
+        mix.insert = 50;
+        b.set_mix(mix, 1, 4, 10);
+
+        let mut scans = 0;
+        let mut inserts = 0;
+        for _ in 0..10000 {
+            b.op(
+                |_t, _key| assert!(false),
+                |_t, key, _value| {
+                    // Keys inserted by this writer are interleaved with those of the other three.
+                    let k = convert_key(key);
+                    assert!(k > 1000);
+                    assert_eq!(1, (k - 1001) % 4);
+                    inserts += 1;
+                },
+                |_t, keys, n| {
+                    assert!(n >= 1 && n <= 10);
+                    assert_eq!(4 * n as usize, keys.len());
+                    scans += 1;
+                },
+            );
+        }
+
+        assert!(scans > 0 && inserts > 0);
+    }
+
+    #[test]
+    fn ycsb_tenant_mix() {
+        let mut config = db::config::ClientConfig::default();
+        config.ycsb_workload = String::from("A");
+        config.key_dist = String::from("uniform");
+
+        // A tenant that sets nothing inherits the client's workload and distribution.
+        let mut tenant = db::config::EmulatedTenantConfig::default();
+        let mix = super::Mix::from_tenant(&config, &tenant);
+        assert_eq!(50, mix.update);
+        assert_eq!(super::KeyDist::Uniform, mix.dist);
+
+        // Setting just `put_pct` gives reads and updates, with the tenant's own distribution.
+        tenant.put_pct = 10;
+        tenant.key_dist = String::from("latest");
+        let mix = super::Mix::from_tenant(&config, &tenant);
+        assert_eq!((90, 10), (mix.read, mix.update));
+        assert_eq!(super::KeyDist::Latest, mix.dist);
+
+        // A workload of it's own overrides `put_pct`.
+        tenant.ycsb_workload = String::from("E");
+        let mix = super::Mix::from_tenant(&config, &tenant);
+        assert_eq!((95, 5, 0), (mix.scan, mix.insert, mix.update));
+    }
+
     #[test]
     fn ycsb_abc_histogram() {
         let hist = Arc::new(Mutex::new(HashMap::new()));
@@ -714,7 +1625,7 @@ mod test {
             let hist = hist.clone();
             let done = done.clone();
             threads.push(thread::spawn(move || {
-                let mut b = super::Ycsb::new(4, 100, n_keys, 5, 0.99);
+                let mut b = super::Ycsb::new(4, 100, n_keys, 5, 0.99, 1, 0.1);
                 let mut n_gets = 0u64;
                 let mut n_puts = 0u64;
                 let start = Instant::now();
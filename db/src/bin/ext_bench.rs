@@ -13,20 +13,18 @@
  * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
  */
 
-#![feature(generator_trait)]
-
 extern crate db;
 extern crate time;
 extern crate sandstorm;
 
 use std::rc::Rc;
-use std::ops::{Generator, GeneratorState};
 
 use db::cycles::*;
 use db::ext::ExtensionManager;
 
 use time::{Duration, PreciseTime};
 
+use sandstorm::continuation::{Continuation, Step};
 use sandstorm::db::DB;
 use sandstorm::null::NullDB;
 
@@ -64,7 +62,7 @@ fn main() {
         let mut ext = ext_manager.get(0, &p)
                                     .unwrap()
                                     .get(Rc::clone(&db) as Rc<DB>);
-        unsafe { ext.resume() };
+        ext.resume();
     }
 
     db.assert_messages(expected.as_slice());
@@ -89,7 +87,7 @@ fn main() {
             load.push(r - l);
 
             let l = rdtsc();
-            unsafe { ext.resume() };
+            ext.resume();
             let r = rdtsc();
             enter.push(r - l);
         }
@@ -105,7 +103,7 @@ fn main() {
 
     let mut ext = ext_manager.get(0, "test").unwrap().get(Rc::clone(&db) as Rc<DB>);
 
-    unsafe { while ext.resume() != GeneratorState::Complete(0) {} };
+    while ext.resume() != Step::Done(0) {}
 
     load.sort();
     enter.sort();
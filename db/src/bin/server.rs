@@ -20,6 +20,11 @@ extern crate libc;
 extern crate nix;
 extern crate spin;
 
+use std::collections::HashMap;
+use std::env;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
 use std::sync::Arc;
 use std::thread::{sleep, spawn};
 use std::time::Duration;
@@ -37,9 +42,33 @@ use db::e2d2::scheduler::*;
 use db::config;
 use db::cycles::*;
 use db::dispatch::Dispatch;
+use db::drain;
+use db::graph;
+use db::logger;
 use db::install::Installer;
+use db::backend::{SocketBackend, UringBackend, XdpBackend};
 use db::master::Master;
+use db::audit;
+use db::backup;
+use db::meter;
+use db::metrics;
+use db::numa;
+use db::tap;
+use db::zcopy;
 use db::sched::RoundRobin;
+use db::span;
+use db::slow;
+use db::geo;
+use db::member;
+use db::readrep;
+use db::replica;
+use db::migrate;
+use db::raft;
+use db::partition;
+use db::s3::Store;
+use db::shm;
+use db::wal;
+use db::runtime;
 use db::task::TaskPriority;
 
 use spin::RwLock;
@@ -55,8 +84,14 @@ const SCAN_INTERVAL_MS: u64 = 10;
 /// milliseconds.
 const MALICIOUS_LIMIT_MS: f64 = 1f64;
 
-/// The identifier of the core that all misbehaving schedulers will be migrated to.
-const GHETTO: u64 = 20;
+/// Set by SIGHUP, asking the watchdog to reload the config.
+static RELOAD: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Set by SIGTERM, asking the watchdog to drain the server and exit.
+static DRAIN: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// The cores the schedulers run on if none were configured.
+const DEFAULT_CORES: [i32; 8] = [10, 11, 12, 13, 14, 15, 16, 17];
 
 /// A simple wrapper around the scheduler, allowing it to be added to a Netbricks pipeline.
 struct Server {
@@ -114,20 +149,84 @@ fn setup_server<S>(
     // Get identifier of the thread this scheduler will run on.
     let tid = unsafe { zcsi::get_thread_id() };
 
-    // Create a dispatcher for the server if needed.
-    let sched = Arc::new(RoundRobin::new(tid, core));
-    let dispatch = Dispatch::new(
-        config,
-        ports[0].clone(),
-        sibling.clone(),
-        Arc::clone(master),
-        Arc::clone(&sched),
-        ports[0].rxq(),
-    );
-    sched.enqueue(Box::new(dispatch));
+    // Create a dispatcher for the server on the configured network backend.
+    let sched = Arc::new(RoundRobin::new(tid, core, &config.groups));
+    match config.backend.as_str() {
+        // Every core gets it's own packet socket, and hence there is nobody to steal from.
+        "socket" => {
+            let socket = SocketBackend::new(&config.net_iface, ports[0].rxq()).unwrap_or_else(
+                |err| {
+                    error!("Failed to open socket on {}: {}", config.net_iface, err);
+                    std::process::exit(1);
+                },
+            );
+
+            let dispatch = Dispatch::new(
+                config,
+                socket.clone(),
+                socket,
+                Arc::clone(master),
+                Arc::clone(&sched),
+                ports[0].rxq(),
+            );
+            sched.enqueue(Box::new(dispatch));
+        }
+
+        // Like "socket", but packets are exchanged in bursts through an io_uring.
+        "uring" => {
+            let uring = UringBackend::new(&config.net_iface, ports[0].rxq()).unwrap_or_else(
+                |err| {
+                    error!("Failed to set up io_uring on {}: {}", config.net_iface, err);
+                    std::process::exit(1);
+                },
+            );
+
+            let dispatch = Dispatch::new(
+                config,
+                uring.clone(),
+                uring,
+                Arc::clone(master),
+                Arc::clone(&sched),
+                ports[0].rxq(),
+            );
+            sched.enqueue(Box::new(dispatch));
+        }
+
+        // Every core gets it's own XDP socket bound to the NIC queue of the same index.
+        "xdp" => {
+            let xsk = XdpBackend::new(&config.net_iface, ports[0].rxq(), &config.xsk_map)
+                .unwrap_or_else(|err| {
+                    error!("Failed to open XDP socket on {}: {}", config.net_iface, err);
+                    std::process::exit(1);
+                });
+
+            let dispatch = Dispatch::new(
+                config,
+                xsk.clone(),
+                xsk,
+                Arc::clone(master),
+                Arc::clone(&sched),
+                ports[0].rxq(),
+            );
+            sched.enqueue(Box::new(dispatch));
+        }
+
+        _ => {
+            let dispatch = Dispatch::new(
+                config,
+                ports[0].clone(),
+                sibling.clone(),
+                Arc::clone(master),
+                Arc::clone(&sched),
+                ports[0].rxq(),
+            );
+            sched.enqueue(Box::new(dispatch));
+        }
+    }
 
     // Add the scheduler to the passed in `handles` vector.
     handles.write().push(Arc::clone(&sched));
+    master.add_scheduler(Arc::clone(&sched));
 
     // Add the server to a netbricks pipeline.
     match scheduler.add_task(Server::new(sched)) {
@@ -147,6 +246,9 @@ fn setup_server<S>(
     }
 }
 
+/// The physical ports served by the server, along with the cores that serve each of them.
+type NicCores = Vec<(config::NicPortConfig, Vec<i32>)>;
+
 /// Returns a struct of type NetbricksConfiguration which can be used to
 /// initialize Netbricks with a default set of parameters.
 ///
@@ -158,40 +260,57 @@ fn setup_server<S>(
 /// receive descriptors, and 256 transmit descriptors will be made available to
 /// Netbricks. Loopback, hardware transmit segementation offload, and hardware
 /// checksum offload will be disabled on this port.
-fn get_default_netbricks_config(config: &config::ServerConfig) -> NetbricksConfiguration {
+///
+/// If several physical ports were configured, every one of them is made available to Netbricks
+/// with one queue pair for each of the cores in `nic_cores` that serve it.
+fn get_default_netbricks_config(
+    config: &config::ServerConfig,
+    nic_cores: &NicCores,
+) -> NetbricksConfiguration {
     // General arguments supplied to netbricks.
     let net_config_name = String::from("server");
     let dpdk_secondary: bool = false;
-    let net_primary_core: i32 = 19;
-    let net_cores: Vec<i32> = vec![10, 11, 12, 13, 14, 15, 16, 17];
+    let net_primary_core: i32 = config.primary_core();
+    let net_cores: Vec<i32> = nic_cores
+        .iter()
+        .flat_map(|&(_, ref cores)| cores.iter().cloned())
+        .collect();
     let net_strict_cores: bool = true;
     let net_pool_size: u32 = 8192 - 1;
     let net_cache_size: u32 = 128;
     let net_dpdk_args: Option<String> = None;
 
-    // Port configuration. Required to configure the physical network interface.
-    let net_port_name = config.nic_pci.clone();
-    let net_port_rx_queues: Vec<i32> = net_cores.clone();
-    let net_port_tx_queues: Vec<i32> = net_cores.clone();
-    let net_port_rxd: i32 = 256;
-    let net_port_txd: i32 = 256;
+    // Port configuration. Required to configure the physical network interface. Backends that
+    // don't use DPDK still need Netbricks for it's packet buffers and schedulers, so they get a
+    // null device instead of the NIC. RSS avoids installing flow rules on the null device.
+    let dpdk = config.uses_dpdk();
+    let net_port_rxd: i32 = descriptors(config.rx_descriptors);
+    let net_port_txd: i32 = descriptors(config.tx_descriptors);
     let net_port_loopback: bool = false;
-    let net_port_tcp_tso: bool = false;
+    // Zero-copy responses span multiple segments, which are only enabled along with TSO.
+    let net_port_tcp_tso: bool = config.zero_copy;
     let net_port_csum_offload: bool = false;
-
-    let net_port_config = PortConfiguration {
-        name: net_port_name,
-        rx_queues: net_port_rx_queues,
-        tx_queues: net_port_tx_queues,
-        rxd: net_port_rxd,
-        txd: net_port_txd,
-        loopback: net_port_loopback,
-        tso: net_port_tcp_tso,
-        csum: net_port_csum_offload,
-    };
+    let net_port_rss: bool = config.rss || !dpdk;
 
     // The set of ports used by netbricks.
-    let net_ports: Vec<PortConfiguration> = vec![net_port_config];
+    let net_ports: Vec<PortConfiguration> = nic_cores
+        .iter()
+        .map(|&(ref nic, ref cores)| PortConfiguration {
+            name: if dpdk {
+                nic.pci.clone()
+            } else {
+                String::from("dpdk:net_null0")
+            },
+            rx_queues: cores.clone(),
+            tx_queues: cores.clone(),
+            rxd: net_port_rxd,
+            txd: net_port_txd,
+            loopback: net_port_loopback,
+            tso: net_port_tcp_tso,
+            csum: net_port_csum_offload,
+            rss: net_port_rss,
+        })
+        .collect();
 
     NetbricksConfiguration {
         name: net_config_name,
@@ -206,13 +325,214 @@ fn get_default_netbricks_config(config: &config::ServerConfig) -> NetbricksConfi
     }
 }
 
+/// Hands the routing tables of the tenants in `partitions` to the partition module, along with
+/// the IP addresses of the ports the server serves, which identify the ranges held here. The
+/// config was validated, so every tenant's ranges make up a valid table.
+fn configure_partitions(config: &config::ServerConfig) {
+    let local: Vec<[u8; 4]> = config
+        .nics()
+        .iter()
+        .filter_map(|nic| Ipv4Addr::from_str(&nic.ip_address).ok())
+        .map(|ip| ip.octets())
+        .collect();
+
+    let mut ranges: HashMap<u32, Vec<partition::Range>> = HashMap::new();
+    for range in config.partitions.iter() {
+        let ip = Ipv4Addr::from_str(&range.ip_address).unwrap();
+        ranges.entry(range.tenant).or_insert(Vec::new()).push(partition::Range {
+            start: range.start.as_bytes().to_vec(),
+            ip: ip.octets(),
+        });
+    }
+
+    let maps: Vec<(u32, partition::Map)> = ranges
+        .into_iter()
+        .filter_map(|(tenant, ranges)| partition::Map::new(1, ranges).map(|map| (tenant, map)))
+        .collect();
+    partition::configure(&local, maps);
+}
+
+/// Hands the bucket in `object_store_url` and how backups are taken to the backup module, if the
+/// config names a bucket. The config was validated, so the URL is well formed.
+fn configure_backups(config: &config::ServerConfig) {
+    if config.object_store_url.len() == 0 {
+        return;
+    }
+
+    let region = match config.object_store_region.len() {
+        0 => "us-east-1",
+        _ => &config.object_store_region,
+    };
+    let store = Store::new(
+        &config.object_store_url,
+        &config.object_store_access_key,
+        &config.object_store_secret_key,
+        region,
+    ).unwrap();
+
+    backup::configure(backup::Settings {
+        store: store,
+        interval_s: config.backup_interval_s,
+        full_every: config.backup_full_every,
+        differential: config.backup_differential,
+    });
+}
+
+/// Hands where heartbeats are exchanged, and how often, to the membership module, if the config
+/// has a `member_addr`.
+fn configure_members(config: &config::ServerConfig) {
+    if config.member_addr.len() == 0 {
+        return;
+    }
+
+    member::configure(member::Settings {
+        addr: config.member_addr.clone(),
+        members: config.members.clone(),
+        install_addr: config.install_addr.clone(),
+        backup_addr: config.backup_addr.clone(),
+        heartbeat_ms: match config.member_heartbeat_ms {
+            0 => member::DEFAULT_HEARTBEAT_MS,
+            ms => ms,
+        },
+        timeout_ms: match config.member_timeout_ms {
+            0 => member::DEFAULT_TIMEOUT_MS,
+            ms => ms,
+        },
+        failover: config.failover,
+    });
+}
+
+/// Hands the site's identifier, the lag bound, the tenants shipped to remote sites and the
+/// servers writes are taken from to the geo-replication module, if the config has `geo_tenants`
+/// or `geo_peers`.
+fn configure_geo(config: &config::ServerConfig) {
+    if config.geo_tenants.len() == 0 && config.geo_peers.len() == 0 {
+        return;
+    }
+
+    geo::configure(geo::Settings {
+        site: config.geo_site,
+        max_lag_ms: match config.geo_max_lag_ms {
+            0 => geo::DEFAULT_MAX_LAG_MS,
+            ms => ms,
+        },
+        tenants: config
+            .geo_tenants
+            .iter()
+            .map(|geo| (geo.tenant, geo.remote.clone()))
+            .collect(),
+        peers: config
+            .geo_peers
+            .iter()
+            .filter_map(|peer| IpAddr::from_str(peer).ok())
+            .collect(),
+    });
+}
+
+/// Returns the number of descriptors to configure on every queue, given the number in the config.
+fn descriptors(configured: usize) -> i32 {
+    if configured > 0 {
+        configured as i32
+    } else {
+        config::DEFAULT_DESCRIPTORS as i32
+    }
+}
+
+/// Returns the physical ports that the server should serve, along with the cores whose
+/// schedulers serve each of them. Cores listed in the config are used as is. Otherwise, if
+/// `numa_bind` is set, cores are picked from every NIC's NUMA node, and the default set of cores
+/// is used if this is not possible. Cores are split into contiguous slices, one per port. A
+/// warning is logged if the cores serving a port are not all on the same NUMA node as it's NIC.
+/// In the case of more ports than cores, it causes the program to exit.
+fn get_nic_cores(config: &config::ServerConfig) -> NicCores {
+    let mut nics = config.nics();
+
+    // Backends that don't use DPDK are bound to a single interface.
+    if !config.uses_dpdk() && nics.len() > 1 {
+        warn!("Only DPDK can serve several ports, serving just {}.", nics[0].pci);
+        nics.truncate(1);
+    }
+
+    let slices: Vec<Vec<i32>> = if config.cores.len() > 0 {
+        numa::partition(&config.cores, nics.len())
+    } else if config.numa_bind {
+        get_numa_cores(config, &nics).unwrap_or_else(|| {
+            warn!("Could not bind to NIC's NUMA node, falling back to default cores.");
+            numa::partition(&DEFAULT_CORES, nics.len())
+        })
+    } else {
+        numa::partition(&DEFAULT_CORES, nics.len())
+    };
+
+    let nic_cores: NicCores = nics.into_iter().zip(slices.into_iter()).collect();
+    for &(ref nic, ref cores) in nic_cores.iter() {
+        if cores.len() == 0 {
+            error!("No cores left to serve port {}, configure more cores.", nic.pci);
+            std::process::exit(1);
+        }
+
+        numa::validate(&nic.pci, cores);
+        info!("Serving port {} on cores {:?}.", nic.pci, cores);
+    }
+
+    return nic_cores;
+}
+
+/// Picks cores for every NIC from it's NUMA node, never handing out a core twice. Every NIC gets
+/// an equal share of the default number of cores.
+///
+/// # Return
+///
+/// The cores for every NIC, or None if some NIC's cores could not be determined.
+fn get_numa_cores(
+    config: &config::ServerConfig,
+    nics: &[config::NicPortConfig],
+) -> Option<Vec<Vec<i32>>> {
+    let share = std::cmp::max(DEFAULT_CORES.len() / nics.len(), 1);
+    let mut slices: Vec<Vec<i32>> = Vec::with_capacity(nics.len());
+
+    for nic in nics.iter() {
+        let cores: Vec<i32> = numa::nic_node(&nic.pci)
+            .and_then(|node| numa::node_cores(node))?
+            .into_iter()
+            .filter(|c| *c != config.primary_core() && *c != config.ghetto_core())
+            .filter(|c| slices.iter().all(|slice| !slice.contains(c)))
+            .take(share)
+            .collect();
+
+        if cores.len() == 0 {
+            return None;
+        }
+
+        slices.push(cores);
+    }
+
+    Some(slices)
+}
+
+/// Returns the config that the dispatcher on `core` should be created with. This is the server's
+/// config, with the address of the physical port that the core serves.
+fn get_core_config(
+    config: &config::ServerConfig,
+    nic_cores: &NicCores,
+    core: i32,
+) -> config::ServerConfig {
+    match nic_cores.iter().find(|&&(_, ref cores)| cores.contains(&core)) {
+        Some(&(ref nic, _)) => config.for_nic(nic),
+        None => config.clone(),
+    }
+}
+
 /// This function configures and initializes Netbricks. In the case of a
 /// failure, it causes the program to exit.
 ///
 /// Returns a Netbricks context which can be used to setup and start the
 /// server/client.
-fn config_and_init_netbricks(config: &config::ServerConfig) -> NetbricksContext {
-    let net_config: NetbricksConfiguration = get_default_netbricks_config(config);
+fn config_and_init_netbricks(
+    config: &config::ServerConfig,
+    nic_cores: &NicCores,
+) -> NetbricksContext {
+    let net_config: NetbricksConfiguration = get_default_netbricks_config(config, nic_cores);
 
     // Initialize Netbricks and return a handle.
     match initialize_system(&net_config) {
@@ -228,6 +548,105 @@ fn config_and_init_netbricks(config: &config::ServerConfig) -> NetbricksContext
     }
 }
 
+/// Logs the rate at which every port in a Netbricks context received and transmitted packets,
+/// along with the number of packets the NIC dropped, since the last call.
+///
+/// # Arguments
+///
+/// * `net_context`: A Netbricks context whose ports should be logged.
+/// * `last`:        The counters read from every port on the last call. Updated in place.
+/// * `secs`:        The number of seconds since the last call.
+fn log_port_stats(net_context: &NetbricksContext, last: &mut HashMap<String, NicStats>, secs: f64) {
+    for (name, port) in net_context.ports.iter() {
+        let stats = match port.nic_stats() {
+            Ok(stats) => stats,
+            Err(ref err) => {
+                warn!("Failed to read stats for port {}: {}", name, err);
+                continue;
+            }
+        };
+
+        if let Some(prev) = last.get(name) {
+            info!(
+                "Port {}: rx {:.3} Mpps {:.3} Gbps, tx {:.3} Mpps {:.3} Gbps, missed {}, \
+                 rx errors {}, tx errors {}, no mbufs {}",
+                name,
+                (stats.ipackets - prev.ipackets) as f64 / secs / 1e6,
+                (stats.ibytes - prev.ibytes) as f64 * 8f64 / secs / 1e9,
+                (stats.opackets - prev.opackets) as f64 / secs / 1e6,
+                (stats.obytes - prev.obytes) as f64 * 8f64 / secs / 1e9,
+                stats.imissed - prev.imissed,
+                stats.ierrors - prev.ierrors,
+                stats.oerrors - prev.oerrors,
+                stats.rx_nombuf - prev.rx_nombuf,
+            );
+        }
+
+        last.insert(name.clone(), stats);
+    }
+}
+
+/// This function sets the MTU on every port in a Netbricks context, if one was configured. In the
+/// case of a failure, it causes the program to exit.
+///
+/// # Arguments
+///
+/// * `config`:      The server's config, consisting of the MTU.
+/// * `net_context`: A Netbricks context whose ports should be configured.
+fn set_port_mtu(config: &config::ServerConfig, net_context: &NetbricksContext) {
+    if config.mtu == 0 || !config.uses_dpdk() {
+        return;
+    }
+
+    for (name, port) in net_context.ports.iter() {
+        if let Err(ref err) = port.set_mtu(config.mtu) {
+            error!("Error while setting MTU on port {}: {}", name, err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// This function installs NIC flow rules for every range of UDP ports that was reserved for a
+/// set of tenants in the config. In the case of a failure, it causes the program to exit.
+///
+/// # Arguments
+///
+/// * `config`:      The server's config, consisting of the reserved UDP port ranges.
+/// * `net_context`: A Netbricks context whose ports should steer the port ranges.
+fn steer_tenant_ports(config: &config::ServerConfig, net_context: &NetbricksContext) {
+    if config.tenant_ports.len() == 0 {
+        return;
+    }
+
+    if config.rss {
+        warn!("Ignoring tenant port ranges, they cannot be steered when RSS is enabled.");
+        return;
+    }
+
+    if !config.exclusive_rx {
+        warn!("Tenant port ranges are not isolated unless exclusive_rx is set.");
+    }
+
+    for port in net_context.ports.values() {
+        for range in config.tenant_ports.iter() {
+            for offset in 0..range.count {
+                let queue = range.queue(offset, port.rxqs());
+                if let Err(ref err) = port.add_udp_flow_rule(range.base + offset, queue) {
+                    error!("Error while steering tenants {:?}: {}", range.tenants, err);
+                    std::process::exit(1);
+                }
+            }
+
+            info!(
+                "Steering UDP ports {} to {} for tenants {:?}.",
+                range.base,
+                range.base + range.count - 1,
+                range.tenants
+            );
+        }
+    }
+}
+
 /// Custom signal handler for stack overflows.
 extern "C" fn handle_sigsegv(
     _signum: i32,
@@ -241,6 +660,132 @@ extern "C" fn handle_sigsegv(
     loop {}
 }
 
+/// Signal handler that turns the packet capture tap on or off.
+extern "C" fn handle_sigusr2(_signum: i32) {
+    tap::toggle();
+}
+
+/// Signal handler that asks for the config to be reloaded.
+extern "C" fn handle_sighup(_signum: i32) {
+    RELOAD.store(true, Ordering::Relaxed);
+}
+
+/// Signal handler that asks for the server to be drained before it exits.
+extern "C" fn handle_sigterm(_signum: i32) {
+    DRAIN.store(true, Ordering::Relaxed);
+}
+
+/// Reloads the config from server.toml, applying fields that can change while the server is
+/// running to `current`. A config that fails validation is rejected as a whole.
+///
+/// # Arguments
+///
+/// * `current`: The config the server is running with. Updated in place.
+fn reload_config(current: &mut config::ServerConfig) {
+    let fresh = config::ServerConfig::load();
+    if let Err(ref err) = fresh.validate() {
+        error!("Ignoring reloaded config. {}", err);
+        return;
+    }
+
+    apply_config(current, &fresh);
+    info!("Reloaded config {:?}", current);
+}
+
+/// Applies the fields of a validated config that can change while the server is running to
+/// `current`, and publishes the result. The new values take effect immediately for the tap,
+/// metering, port stats, logging and the slow request log, and on every dispatcher's next poll.
+///
+/// # Arguments
+///
+/// * `current`: The config the server is running with. Updated in place.
+/// * `fresh`:   The config to take the new values from.
+fn apply_config(current: &mut config::ServerConfig, fresh: &config::ServerConfig) {
+    let tap = (current.tap_rate, current.tap_path.clone(), current.tap_ring);
+    let meter = (current.meter_interval_s, current.meter_path.clone(), current.meter_addr.clone());
+    let levels = current.log_levels.clone();
+    let ignored = current.reload(fresh);
+    if ignored.len() > 0 {
+        warn!("Changes to {:?} require a restart, ignoring them.", ignored);
+    }
+
+    if tap != (current.tap_rate, current.tap_path.clone(), current.tap_ring) {
+        if tap::enabled() {
+            warn!("Cannot reconfigure the tap while it is capturing, turn it off first.");
+        } else {
+            tap::configure(current.tap_rate, &current.tap_path, current.tap_ring);
+        }
+    }
+
+    if meter != (current.meter_interval_s, current.meter_path.clone(), current.meter_addr.clone()) {
+        meter::configure(current.meter_interval_s, &current.meter_path, &current.meter_addr);
+    }
+
+    slow::configure(current.slow_request_us);
+
+    // Levels emptied out on a reload go back to those RUST_LOG asked for at startup.
+    if levels != current.log_levels {
+        let spec = if current.log_levels.len() > 0 {
+            current.log_levels.clone()
+        } else {
+            env::var("RUST_LOG").unwrap_or_default()
+        };
+        if let Err(problem) = logger::configure(&spec) {
+            error!("Ignoring `log_levels`: {}", problem);
+        }
+    }
+
+    runtime::publish(current);
+}
+
+/// Drains the server and exits. New requests are refused from here on, and tasks already taken
+/// on are given upto `drain_timeout_ms` to complete and have their responses sent. The log,
+/// tables to be preserved, metering records, spans and captured frames are then written out.
+///
+/// # Arguments
+///
+/// * `current`: The config the server is running with.
+/// * `master`:  The service whose tasks are waited for.
+/// * `handles`: The schedulers whose responses are waited for.
+fn drain_and_exit(
+    current: &config::ServerConfig,
+    master: &Arc<Master>,
+    handles: &Arc<RwLock<Vec<Arc<RoundRobin>>>>,
+) -> ! {
+    drain::begin();
+    info!("Draining the server before it exits");
+
+    let left = drain::wait(current.drain_timeout_ms, || {
+        let responses: usize = handles.read().iter().map(|s| s.num_responses()).sum();
+        master.in_flight() + responses
+    });
+    if left > 0 {
+        warn!("Timed out draining the server, {} tasks and responses are lost", left);
+    }
+
+    let lsn = match wal::sync() {
+        Ok(lsn) => Some(lsn),
+        Err(err) => {
+            error!("Failed to sync the log while draining: {}", err);
+            None
+        }
+    };
+
+    // Tables that are behind the log cannot be picked up from, so none are preserved unless
+    // the log was synced.
+    if let (Some(lsn), true) = (lsn, current.shm_path.len() > 0) {
+        if let Err(err) = shm::save(master, &current.shm_path, lsn) {
+            error!("Failed to preserve tables in {}: {}", current.shm_path, err);
+        }
+    }
+    meter::flush(|| master.storage());
+    span::poll();
+    tap::poll();
+
+    info!("Drained the server, exiting");
+    std::process::exit(0);
+}
+
 fn main() {
     // First off, install a signal handler to catch stack overflows. On catching a
     // SIGSEGV, we allocate a new stack to the thread to prevent a segmentation fault
@@ -256,13 +801,111 @@ fn main() {
             .expect("Failed to install custom handler for stack overflow.");
     }
 
-    // Basic setup and initialization.
-    db::env_logger::init().expect("ERROR: failed to initialize logger!");
+    // Catch SIGUSR2 to turn the packet capture tap on and off at runtime.
+    let tap_action = signal::SigAction::new(
+        signal::SigHandler::Handler(handle_sigusr2),
+        signal::SaFlags::SA_RESTART,
+        signal::SigSet::empty(),
+    );
+
+    unsafe {
+        let _ret = signal::sigaction(signal::SIGUSR2, &tap_action)
+            .expect("Failed to install handler for the packet capture tap.");
+    }
+
+    // Catch SIGHUP to reload the parts of the config that can change at runtime.
+    let reload_action = signal::SigAction::new(
+        signal::SigHandler::Handler(handle_sighup),
+        signal::SaFlags::SA_RESTART,
+        signal::SigSet::empty(),
+    );
+
+    unsafe {
+        let _ret = signal::sigaction(signal::SIGHUP, &reload_action)
+            .expect("Failed to install handler for config reloads.");
+    }
+
+    // Catch SIGTERM to drain the server instead of dropping the requests it has taken on.
+    let drain_action = signal::SigAction::new(
+        signal::SigHandler::Handler(handle_sigterm),
+        signal::SaFlags::SA_RESTART,
+        signal::SigSet::empty(),
+    );
+
+    unsafe {
+        let _ret = signal::sigaction(signal::SIGTERM, &drain_action)
+            .expect("Failed to install handler for draining.");
+    }
+
+    // Basic setup and initialization. Messages logged while the config is loaded go by RUST_LOG,
+    // the levels in the config take over once it is.
+    logger::init(&env::var("RUST_LOG").unwrap_or_default());
 
     let config = config::ServerConfig::load();
+    if config.log_levels.len() > 0 {
+        if let Err(problem) = logger::configure(&config.log_levels) {
+            error!("Ignoring `log_levels`: {}", problem);
+        }
+    }
     info!("Starting up Sandstorm server with config {:?}", config);
 
-    let master = Arc::new(Master::new());
+    // Refuse to start on a config that is malformed or contradicts itself.
+    if let Err(ref err) = config.validate() {
+        error!("{}", err);
+        std::process::exit(1);
+    }
+
+    let mut master = Master::new();
+    master.limit_extensions(config.max_extensions, config.max_extension_bytes);
+    master.limit_keys(config.max_key_len);
+    master.set_admin_credential(config.admin_credential);
+    for tier in config.tiers.iter() {
+        for tenant in tier.tenants.iter() {
+            master.set_quotas(*tenant, tier.alloc_quota, tier.mem_limit);
+        }
+    }
+    let master = Arc::new(master);
+
+    tap::configure(config.tap_rate, &config.tap_path, config.tap_ring);
+    meter::configure(config.meter_interval_s, &config.meter_path, &config.meter_addr);
+    audit::configure(&config.audit_path);
+    span::configure(&config.span_path);
+    slow::configure(config.slow_request_us);
+    replica::set_credential(config.peer_credential);
+    if config.standby {
+        replica::set_standby();
+    }
+    if config.backup_addr.len() > 0 {
+        replica::enable();
+    }
+    if config.read_replica {
+        readrep::set_replica();
+    }
+    readrep::attach(&config.read_replicas);
+    let mut raft_disk = None;
+    if config.raft_peers.len() > 0 {
+        let marked: Vec<(u32, u64)> = config
+            .raft_tables
+            .iter()
+            .map(|table| (table.tenant, table.table))
+            .collect();
+        let n = config.raft_peers.len();
+        match raft::configure(config.raft_id, n, &marked, &config.raft_dir) {
+            Ok(disk) => raft_disk = Some(disk),
+            Err(err) => {
+                error!("Failed to restore the Raft log from `raft_dir`: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+    configure_partitions(&config);
+    configure_backups(&config);
+    configure_members(&config);
+    configure_geo(&config);
+    if config.metrics_addr.len() > 0 {
+        meter::keep_totals();
+        metrics::enable();
+    }
 
     // Create tenants with data and extensions.
     match config.workload.as_str() {
@@ -282,8 +925,11 @@ fn main() {
                 "Populating TAO data, {} tenants, {} records/tenant",
                 config.num_tenants, config.num_records
             );
+            // The config was validated on startup, so the graph's spec is known to be good.
+            let tao = graph::resolve(&config.tao).expect("Failed to resolve the TAO graph.");
+            let graph = graph::Graph::new(&tao, config.num_records);
             for tenant in 1..(config.num_tenants + 1) {
-                master.fill_tao(tenant, config.num_records);
+                master.fill_tao(tenant, &graph);
                 master.load_test(tenant);
             }
         }
@@ -306,8 +952,64 @@ fn main() {
         }
     }
 
-    // Setup Netbricks.
-    let mut net_context: NetbricksContext = config_and_init_netbricks(&config);
+    // Recover the tables of the tenants just created, before any request can write to them.
+    // Tables preserved by the server this one replaces are picked up first, and the last
+    // checkpoint and the log after that.
+    if config.wal_dir.len() > 0 {
+        wal::configure(wal::Settings {
+            dir: config.wal_dir.clone(),
+            sync_ms: config.wal_sync_ms,
+            checkpoint_s: config.checkpoint_interval_s,
+            skip_corrupt: config.wal_skip_corrupt,
+        });
+    }
+
+    let mut preserved = None;
+    if config.shm_path.len() > 0 {
+        match shm::attach(&master, &config.shm_path, wal::resumes) {
+            Ok(lsn) => preserved = lsn,
+            Err(e) => {
+                error!("Failed to load preserved tables off {}: {}", config.shm_path, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if config.wal_dir.len() > 0 {
+        if let Err(e) = wal::recover(&master, preserved) {
+            error!("Failed to recover from {}: {}", config.wal_dir, e);
+            std::process::exit(1);
+        }
+    }
+
+    // If enabled, allow responses to reference values in table memory. The NIC reads these
+    // values by physical address, so make sure that memory is never paged out.
+    if config.zero_copy && !config.uses_dpdk() {
+        warn!("Zero-copy responses require the DPDK backend. Disabling them.");
+    } else if config.zero_copy {
+        if unsafe { libc::mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE) } != 0 {
+            error!("Failed to lock memory, required for zero-copy responses.");
+            std::process::exit(1);
+        }
+
+        zcopy::enable();
+    }
+
+    // Setup Netbricks, with cores partitioned among the physical ports.
+    let nic_cores = get_nic_cores(&config);
+    let mut net_context: NetbricksContext = config_and_init_netbricks(&config, &nic_cores);
+    set_port_mtu(&config, &net_context);
+    steer_tenant_ports(&config, &net_context);
+
+    // Hand the ports to Master, so that stats() RPCs can report the NIC's counters and health()
+    // RPCs their links.
+    let mut ports: Vec<(String, Arc<PmdPort>)> = net_context
+        .ports
+        .iter()
+        .map(|(name, port)| (name.clone(), Arc::clone(port)))
+        .collect();
+    ports.sort_by(|a, b| a.0.cmp(&b.0));
+    master.set_ports(ports);
 
     // A handle to every scheduler for pre-emption.
     let handles = Arc::new(RwLock::new(Vec::with_capacity(8)));
@@ -320,11 +1022,44 @@ fn main() {
     // Copy out the network address that install() RPCs will be received on.
     let install_addr = config.install_addr.clone();
 
-    // Setup the server pipeline.
+    // Copy out the address metrics are served on, if they are.
+    let metrics_addr = config.metrics_addr.clone();
+
+    // Copy out the address of the backup writes are shipped to, if there is one.
+    let backup_addr = config.backup_addr.clone();
+    let backup_timeout_ms = match config.backup_timeout_ms {
+        0 => replica::DEFAULT_TIMEOUT_MS,
+        timeout => timeout,
+    };
+
+    // Copy out the number of read replicas writes are fed to, and of tenants shipped to remote
+    // sites.
+    let read_replicas = config.read_replicas.len();
+    let geo_tenants = config.geo_tenants.len();
+
+    // Copy out the core misbehaving schedulers are migrated to.
+    let ghetto = config.ghetto_core() as u64;
+
+    // Keep a copy of the config around. Reloads are applied to it, and schedulers that replace
+    // misbehaving ones are set up with it.
+    let mut current = config.clone();
+    runtime::publish(&current);
+
+    // Setup the server pipeline. Every core's dispatcher is configured with the address of the
+    // physical port it serves.
+    let cnic_cores = nic_cores.clone();
     net_context.start_schedulers();
     net_context.add_pipeline_to_run(Arc::new(
         move |ports, scheduler: &mut StandaloneScheduler, core: i32, sibling| {
-            setup_server(&config, ports, sibling, scheduler, core, &cmaster, &chandle)
+            setup_server(
+                &get_core_config(&config, &cnic_cores, core),
+                ports,
+                sibling,
+                scheduler,
+                core,
+                &cmaster,
+                &chandle,
+            )
         },
     ));
 
@@ -333,25 +1068,197 @@ fn main() {
     let _install = spawn(move || {
         // Pin to the ghetto core.
         let tid = unsafe { zcsi::get_thread_id() };
-        unsafe { zcsi::set_affinity(tid, GHETTO) };
+        unsafe { zcsi::set_affinity(tid, ghetto) };
 
         // Run the installer.
         let mut installer = Installer::new(imaster, install_addr);
         installer.execute();
     });
 
+    // Create a thread to ship writes to the backup.
+    if backup_addr.len() > 0 {
+        let _replica = spawn(move || {
+            // Pin to the ghetto core.
+            let tid = unsafe { zcsi::get_thread_id() };
+            unsafe { zcsi::set_affinity(tid, ghetto) };
+
+            replica::ship(&backup_addr, backup_timeout_ms);
+        });
+    }
+
+    // Create a thread per read replica to feed writes to it.
+    for idx in 0..read_replicas {
+        let _readrep = spawn(move || {
+            // Pin to the ghetto core.
+            let tid = unsafe { zcsi::get_thread_id() };
+            unsafe { zcsi::set_affinity(tid, ghetto) };
+
+            readrep::ship(idx);
+        });
+    }
+
+    // Create a thread per geo-replicated tenant to ship it's writes to the remote site.
+    for idx in 0..geo_tenants {
+        let _geo = spawn(move || {
+            // Pin to the ghetto core.
+            let tid = unsafe { zcsi::get_thread_id() };
+            unsafe { zcsi::set_affinity(tid, ghetto) };
+
+            geo::ship(idx);
+        });
+    }
+
+    // Create a thread to exchange heartbeats with the other members of the cluster.
+    if member::enabled() {
+        let _member = spawn(move || {
+            // Pin to the ghetto core.
+            let tid = unsafe { zcsi::get_thread_id() };
+            unsafe { zcsi::set_affinity(tid, ghetto) };
+
+            member::serve();
+        });
+    }
+
+    // Create a thread to copy tenants migrating to another server.
+    let gmaster = Arc::clone(&master);
+    let _migrate = spawn(move || {
+        // Pin to the ghetto core.
+        let tid = unsafe { zcsi::get_thread_id() };
+        unsafe { zcsi::set_affinity(tid, ghetto) };
+
+        migrate::serve(gmaster);
+    });
+
+    // Create a thread to write out the log and take checkpoints.
+    if wal::enabled() {
+        let wmaster = Arc::clone(&master);
+        let _wal = spawn(move || {
+            // Pin to the ghetto core.
+            let tid = unsafe { zcsi::get_thread_id() };
+            unsafe { zcsi::set_affinity(tid, ghetto) };
+
+            wal::serve(wmaster);
+        });
+    }
+
+    // Create a thread to take backups to object storage, and restore them.
+    if backup::configured() {
+        let bmaster = Arc::clone(&master);
+        let _backup = spawn(move || {
+            // Pin to the ghetto core.
+            let tid = unsafe { zcsi::get_thread_id() };
+            unsafe { zcsi::set_affinity(tid, ghetto) };
+
+            backup::serve(bmaster);
+        });
+    }
+
+    // Create a thread to serve metrics scrapes.
+    if metrics_addr.len() > 0 {
+        let mmaster = Arc::clone(&master);
+        let mhandle = Arc::clone(&handles);
+        let _metrics = spawn(move || {
+            // Pin to the ghetto core.
+            let tid = unsafe { zcsi::get_thread_id() };
+            unsafe { zcsi::set_affinity(tid, ghetto) };
+
+            metrics::serve(
+                &metrics_addr,
+                || {
+                    let queues: Vec<metrics::Queues> = mhandle
+                        .read()
+                        .iter()
+                        .map(|sched| metrics::Queues {
+                            core: sched.core(),
+                            waiting: sched.num_waiting(),
+                            responses: sched.num_responses(),
+                        })
+                        .collect();
+                    metrics::render(
+                        &meter::totals(),
+                        &mmaster.storage(),
+                        &mmaster.extension_stats(),
+                        &queues,
+                        &metrics::service_times(),
+                        cycles_per_second(),
+                    )
+                },
+                || mmaster.health_report(),
+            );
+        });
+    }
+
     // Run the server, and give it some time to bootup.
     net_context.execute();
     sleep(Duration::from_millis(1000));
 
+    // Drive this server's node of the Raft group from the first scheduler. The config was
+    // validated on startup, so every peer is known to be a socket address.
+    if let Some(disk) = raft_disk {
+        let peers: Vec<SocketAddr> = current
+            .raft_peers
+            .iter()
+            .map(|peer| SocketAddr::from_str(peer).expect("Malformed Raft peer."))
+            .collect();
+        let secret = &current.raft_secret;
+        match raft::Driver::new(peers, current.raft_id, secret, disk, Arc::clone(&master)) {
+            Ok(driver) => handles.read()[0].enqueue(Box::new(driver)),
+            Err(err) => {
+                error!("Failed to bind to `raft_peers[{}]`: {}", current.raft_id, err);
+                std::process::exit(1);
+            }
+        }
+    }
+
     // Convert to cycles.
     let limit = (MALICIOUS_LIMIT_MS / 1000f64) * (cycles_per_second() as f64);
 
+    // The counters last read from every port, and when they were read.
+    let mut port_stats = HashMap::new();
+    let mut port_stats_ts = rdtsc();
+    if current.port_stats_s > 0 {
+        log_port_stats(&net_context, &mut port_stats, 1f64);
+    }
+
     // Check for misbehaving tasks here.
     loop {
         // Scan schedulers every few milliseconds.
         sleep(Duration::from_millis(SCAN_INTERVAL_MS));
 
+        // Reload the config if asked to.
+        if RELOAD.swap(false, Ordering::Relaxed) {
+            reload_config(&mut current);
+        }
+
+        // Drain the server and exit if asked to.
+        if DRAIN.swap(false, Ordering::Relaxed) {
+            drain_and_exit(&current, &master, &handles);
+        }
+
+        // Apply changes made through config() RPCs.
+        if let Some(tuned) = runtime::take() {
+            apply_config(&mut current, &tuned);
+        }
+
+        // Write out any frames captured by the tap since the last scan.
+        tap::poll();
+
+        // Write out the spans of traced requests whose responses were sent since the last scan.
+        span::poll();
+
+        // Emit metering records if they are due.
+        meter::poll(|| master.storage());
+
+        // Log per-port packet rates if enough time has passed since they were last logged.
+        if current.port_stats_s > 0 {
+            let now = rdtsc();
+            if now - port_stats_ts >= current.port_stats_s * cycles_per_second() {
+                let secs = (now - port_stats_ts) as f64 / cycles_per_second() as f64;
+                log_port_stats(&net_context, &mut port_stats, secs);
+                port_stats_ts = now;
+            }
+        }
+
         for sched in handles.write().iter_mut() {
             // Get the current time stamp to compare scheduler time stamps against.
             let current = rdtsc();
@@ -391,20 +1298,22 @@ fn main() {
 
             // Set the compromised flag on the scheduler and then migrate it. Stop the scheduler.
             sched.compromised();
-            unsafe { zcsi::set_affinity(tid, GHETTO) };
+            unsafe { zcsi::set_affinity(tid, ghetto) };
             net_context.stop_core(core);
 
             // Create and setup a new scheduler on the core.
             let temp = Arc::new(RwLock::new(Vec::with_capacity(1)));
             let cmaster = Arc::clone(&master);
             let ctemp = Arc::clone(&temp);
+            let cnic_cores = nic_cores.clone();
+            let cconfig = current.clone();
             net_context.start_scheduler(core);
             let _res = net_context.add_pipeline_to_core(
                 core,
                 Arc::new(
                     move |ports, scheduler: &mut StandaloneScheduler, core: i32, sibling| {
                         setup_server(
-                            &config::ServerConfig::load(),
+                            &get_core_config(&cconfig, &cnic_cores, core),
                             ports,
                             sibling,
                             scheduler,
@@ -0,0 +1,450 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+// Coalescing of requests that span more than one frame. A request longer than the path's MTU
+// arrives as IPv4 fragments (RFC 791): the first carries the UDP header and the start of the
+// request, and the rest only carry the IP header and the next stretch of bytes. The dispatcher
+// runs every received frame by a `Coalescer` before parsing any header on it; frames that are not
+// fragments pass through untouched, and the fragments of a datagram are held until all of them
+// arrived, at which point they are merged back into one frame that the rest of the receive path
+// handles like any other. Reassembly so runs on the dispatcher, off the workers' time.
+//
+// Fragments are only read off their bytes with a `wireformat::Reader`, like `frame` does, and
+// are held by copy so that their mbufs can be freed right away. A datagram whose fragments
+// overlap, that would be longer than IPv4 allows, or that does not complete within the timeout
+// is dropped in full, and only so many datagrams are held at once, so that a stream of stray
+// fragments cannot pin down more than a bounded amount of memory.
+
+use std::collections::HashMap;
+
+use super::common::PACKET_ETYPE;
+use super::frame::{IP_HEADER_LEN, MAC_HEADER_LEN};
+use super::wireformat::Reader;
+
+/// The number of milliseconds the fragments of a datagram may take to arrive, from the first to
+/// the last.
+pub const TIMEOUT_MS: u64 = 500;
+
+/// The most datagrams held while their fragments arrive.
+const MAX_PENDING: usize = 64;
+
+/// The longest an IPv4 datagram can be, header included.
+const MAX_DATAGRAM: usize = 65535;
+
+/// The bit of the flags and fragment offset field set on every fragment but the last.
+const MORE_FRAGMENTS: u16 = 0x2000;
+
+/// The bits of the flags and fragment offset field holding the offset, in units of 8 bytes.
+const OFFSET_MASK: u16 = 0x1fff;
+
+/// What became of a fragment pushed into a `Coalescer`.
+#[derive(Debug, PartialEq)]
+pub enum Coalesced {
+    /// The fragment is held until the rest of it's datagram arrives.
+    Pending,
+
+    /// The fragment completed it's datagram, which is returned as a single frame, from it's
+    /// Ethernet header on.
+    Whole(Vec<u8>),
+
+    /// The fragment was malformed, or could not be held. It's datagram is dropped.
+    Refused,
+}
+
+// Identifies a datagram: the source and destination addresses, the protocol, and the
+// identification field.
+type Id = (u32, u32, u8, u16);
+
+// The fields of a fragment that reassembly needs.
+struct Fragment<'a> {
+    // The datagram the fragment belongs to.
+    id: Id,
+
+    // The IP header on the fragment, options included.
+    header: &'a [u8],
+
+    // The bytes the fragment carries, and where in the datagram's payload they go.
+    payload: &'a [u8],
+    offset: usize,
+
+    // Whether this is the last fragment of the datagram.
+    last: bool,
+}
+
+// A datagram whose fragments are still arriving.
+struct Partial {
+    // The cycle counter when the first fragment arrived.
+    started: u64,
+
+    // The Ethernet and IP headers of the first fragment, once it arrived.
+    mac: Vec<u8>,
+    header: Vec<u8>,
+
+    // The payload received so far, and the ranges of it that were filled in.
+    payload: Vec<u8>,
+    filled: Vec<(usize, usize)>,
+
+    // The length of the payload, once the last fragment arrived.
+    length: Option<usize>,
+}
+
+/// Holds the fragments of datagrams until they can be merged back together. Every dispatcher
+/// has one of it's own.
+pub struct Coalescer {
+    // The datagrams whose fragments are still arriving.
+    pending: HashMap<Id, Partial>,
+
+    // The number of cycles a datagram may take to arrive in full.
+    timeout: u64,
+}
+
+/// Determines whether a frame is an IPv4 fragment.
+///
+/// # Arguments
+///
+/// * `frame`: The frame, from it's Ethernet header on.
+///
+/// # Return
+///
+/// True if the frame carries an IPv4 packet that is part of a longer datagram. Such frames must
+/// be coalesced before their UDP header, if any, is parsed.
+pub fn is_fragment(frame: &[u8]) -> bool {
+    let mut reader = Reader::at(frame, 12);
+    if reader.u16_be() != Some(PACKET_ETYPE) || reader.u8().map_or(true, |b| b >> 4 != 4) {
+        return false;
+    }
+
+    let mut reader = Reader::at(frame, MAC_HEADER_LEN + 6);
+    match reader.u16_be() {
+        Some(field) => field & (MORE_FRAGMENTS | OFFSET_MASK) != 0,
+        None => false,
+    }
+}
+
+// Implementation of methods on Coalescer.
+impl Coalescer {
+    /// Returns a coalescer that holds no fragments.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout`: The number of cycles the fragments of a datagram may take to arrive, from the
+    ///              first to the last.
+    pub fn new(timeout: u64) -> Coalescer {
+        Coalescer {
+            pending: HashMap::new(),
+            timeout: timeout,
+        }
+    }
+
+    /// Holds a fragment, and merges it's datagram back together if it was the last one missing.
+    ///
+    /// # Arguments
+    ///
+    /// * `frame`: The fragment, from it's Ethernet header on. Only frames for which
+    ///            `is_fragment()` is true should be pushed.
+    /// * `now`:   The current value of the cycle counter.
+    ///
+    /// # Return
+    ///
+    /// What became of the fragment. A datagram merged back together carries the first
+    /// fragment's Ethernet and IP headers, with the IP header's length, fragment offset, flags
+    /// and checksum rewritten for the whole datagram.
+    pub fn push(&mut self, frame: &[u8], now: u64) -> Coalesced {
+        let fragment = match parse(frame) {
+            Some(fragment) => fragment,
+            None => return Coalesced::Refused,
+        };
+
+        if !self.pending.contains_key(&fragment.id) && self.pending.len() >= MAX_PENDING {
+            return Coalesced::Refused;
+        }
+
+        let done = {
+            let partial = self.pending.entry(fragment.id).or_insert_with(|| Partial {
+                started: now,
+                mac: Vec::new(),
+                header: Vec::new(),
+                payload: Vec::new(),
+                filled: Vec::new(),
+                length: None,
+            });
+            match partial.add(&frame[..MAC_HEADER_LEN], &fragment) {
+                Some(done) => done,
+                None => {
+                    self.pending.remove(&fragment.id);
+                    return Coalesced::Refused;
+                }
+            }
+        };
+        if !done {
+            return Coalesced::Pending;
+        }
+
+        match self.pending.remove(&fragment.id) {
+            Some(partial) => Coalesced::Whole(partial.merge()),
+            None => Coalesced::Refused,
+        }
+    }
+
+    /// Drops every datagram that did not arrive in full within the timeout.
+    ///
+    /// # Arguments
+    ///
+    /// * `now`: The current value of the cycle counter.
+    ///
+    /// # Return
+    ///
+    /// The number of datagrams dropped.
+    pub fn expire(&mut self, now: u64) -> usize {
+        let before = self.pending.len();
+        let timeout = self.timeout;
+        self.pending.retain(|_, partial| now.wrapping_sub(partial.started) < timeout);
+        before - self.pending.len()
+    }
+}
+
+// Implementation of methods on Partial.
+impl Partial {
+    // Adds a fragment to the datagram. Returns whether the datagram is now complete, or None if
+    // the fragment overlaps one added before, or disagrees with them on where the datagram ends.
+    fn add(&mut self, mac: &[u8], fragment: &Fragment) -> Option<bool> {
+        let start = fragment.offset;
+        let end = start + fragment.payload.len();
+        if end + fragment.header.len() > MAX_DATAGRAM {
+            return None;
+        }
+
+        // Every fragment but the last carries a multiple of 8 bytes, and none run past the end.
+        if fragment.last {
+            if self.length.is_some() || self.filled.iter().any(|&(_, e)| e > end) {
+                return None;
+            }
+            self.length = Some(end);
+        } else if fragment.payload.len() == 0 || fragment.payload.len() % 8 != 0
+            || self.length.map_or(false, |length| end > length)
+        {
+            return None;
+        }
+
+        if self.filled.iter().any(|&(s, e)| start < e && s < end) {
+            return None;
+        }
+
+        if start == 0 {
+            self.mac = mac.to_vec();
+            self.header = fragment.header.to_vec();
+        }
+        if self.payload.len() < end {
+            self.payload.resize(end, 0);
+        }
+        self.payload[start..end].copy_from_slice(fragment.payload);
+        self.filled.push((start, end));
+
+        let received: usize = self.filled.iter().map(|&(s, e)| e - s).sum();
+        Some(self.length == Some(received))
+    }
+
+    // Merges a complete datagram back into a single frame.
+    fn merge(self) -> Vec<u8> {
+        let mut frame = self.mac;
+        let base = frame.len();
+        let length = self.header.len() + self.payload.len();
+        frame.extend_from_slice(&self.header);
+        frame.extend_from_slice(&self.payload);
+
+        {
+            let header = &mut frame[base..base + self.header.len()];
+            header[2] = (length >> 8) as u8;
+            header[3] = length as u8;
+            header[6] = 0;
+            header[7] = 0;
+            header[10] = 0;
+            header[11] = 0;
+            let sum = checksum(header);
+            header[10] = (sum >> 8) as u8;
+            header[11] = sum as u8;
+        }
+
+        frame
+    }
+}
+
+// Reads the fields of a fragment off a frame, or returns None if the frame is too short for the
+// lengths on it's IP header.
+fn parse(frame: &[u8]) -> Option<Fragment> {
+    let packet = frame.get(MAC_HEADER_LEN..)?;
+    let mut reader = Reader::new(packet);
+    let hlen = (reader.u8()? & 0x0f) as usize * 4;
+    reader.skip(1)?;
+    let length = reader.u16_be()? as usize;
+    let ident = reader.u16_be()?;
+    let field = reader.u16_be()?;
+    reader.skip(1)?;
+    let protocol = reader.u8()?;
+    reader.skip(2)?;
+    let src = reader.u32_be()?;
+    let dst = reader.u32_be()?;
+
+    if hlen < IP_HEADER_LEN || length < hlen || length > packet.len() {
+        return None;
+    }
+
+    Some(Fragment {
+        id: (src, dst, protocol, ident),
+        header: &packet[..hlen],
+        payload: &packet[hlen..length],
+        offset: (field & OFFSET_MASK) as usize * 8,
+        last: field & MORE_FRAGMENTS == 0,
+    })
+}
+
+// Computes the checksum of an IPv4 header (RFC 1071), with it's checksum field zeroed.
+fn checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+    for pair in header.chunks(2) {
+        let word = (pair[0] as u32) << 8 | pair.get(1).map_or(0, |&b| b as u32);
+        sum += word;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+// This module contains unit tests for coalescing fragmented requests.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Builds the fragments of a UDP datagram carrying `payload` bytes, each carrying at most
+    // `mtu` bytes of it past the IP header.
+    fn fragments(ident: u16, payload: &[u8], mtu: usize) -> Vec<Vec<u8>> {
+        let mut datagram = vec![0u8; 8];
+        datagram[4] = ((8 + payload.len()) >> 8) as u8;
+        datagram[5] = (8 + payload.len()) as u8;
+        datagram.extend_from_slice(payload);
+
+        let step = mtu / 8 * 8;
+        let mut frames = Vec::new();
+        let mut off = 0;
+        while off < datagram.len() {
+            let end = if off + step < datagram.len() { off + step } else { datagram.len() };
+            let mut frame = vec![0u8; MAC_HEADER_LEN + IP_HEADER_LEN];
+            frame[12] = 0x08;
+            let length = IP_HEADER_LEN + end - off;
+            let field = (off / 8) as u16 | if end < datagram.len() { MORE_FRAGMENTS } else { 0 };
+            frame[14] = 0x45;
+            frame[16] = (length >> 8) as u8;
+            frame[17] = length as u8;
+            frame[18] = (ident >> 8) as u8;
+            frame[19] = ident as u8;
+            frame[20] = (field >> 8) as u8;
+            frame[21] = field as u8;
+            frame[22] = 64;
+            frame[23] = 17;
+            frame[26..30].copy_from_slice(&[0x0a, 0x00, 0x00, 0x02]);
+            frame[30..34].copy_from_slice(&[0x0a, 0x00, 0x00, 0x01]);
+            frame.extend_from_slice(&datagram[off..end]);
+            frames.push(frame);
+            off = end;
+        }
+        frames
+    }
+
+    // This unit test verifies that fragments are told apart from whole packets.
+    #[test]
+    fn test_is_fragment() {
+        let frames = fragments(1, &[7u8; 100], 48);
+        assert!(frames.iter().all(|frame| is_fragment(frame)));
+
+        let whole = fragments(1, &[7u8; 100], 1500);
+        assert_eq!(1, whole.len());
+        assert!(!is_fragment(&whole[0]));
+
+        let mut arp = frames[0].clone();
+        arp[13] = 0x06;
+        assert!(!is_fragment(&arp));
+        assert!(!is_fragment(&frames[0][..MAC_HEADER_LEN + 4]));
+    }
+
+    // This unit test verifies that fragments are merged back into the datagram they were cut
+    // from, whatever order they arrive in, and that datagrams are told apart by their id.
+    #[test]
+    fn test_coalesce() {
+        let payload: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let whole = fragments(9, &payload, 4000).remove(0);
+        let mut frames = fragments(9, &payload, 256);
+        let other = fragments(10, &payload, 256);
+        assert_eq!(4, frames.len());
+        frames.swap(0, 2);
+
+        let mut coalescer = Coalescer::new(100);
+        assert_eq!(Coalesced::Pending, coalescer.push(&other[0], 0));
+        for frame in frames[..3].iter() {
+            assert_eq!(Coalesced::Pending, coalescer.push(frame, 0));
+        }
+        assert_eq!(2, coalescer.pending.len());
+
+        let merged = match coalescer.push(&frames[3], 0) {
+            Coalesced::Whole(merged) => merged,
+            outcome => panic!("{:?}", outcome),
+        };
+        assert_eq!(1, coalescer.pending.len());
+
+        // The merged frame is the unfragmented one, except for the checksum it did not have.
+        assert_eq!(whole.len(), merged.len());
+        assert_eq!(whole[..24], merged[..24]);
+        assert_eq!(whole[26..], merged[26..]);
+        assert_eq!(0, checksum(&merged[MAC_HEADER_LEN..MAC_HEADER_LEN + IP_HEADER_LEN]));
+    }
+
+    // This unit test verifies that a datagram is dropped if it's fragments overlap, disagree on
+    // where it ends, or do not arrive within the timeout, and that only so many are held.
+    #[test]
+    fn test_refuse() {
+        let frames = fragments(9, &[7u8; 1000], 256);
+
+        let mut coalescer = Coalescer::new(100);
+        assert_eq!(Coalesced::Pending, coalescer.push(&frames[0], 0));
+        assert_eq!(Coalesced::Refused, coalescer.push(&frames[0], 0));
+        assert_eq!(0, coalescer.pending.len());
+
+        let mut longer = frames[3].clone();
+        longer.extend_from_slice(&[0u8; 8]);
+        let length = longer.len() - MAC_HEADER_LEN;
+        longer[16] = (length >> 8) as u8;
+        longer[17] = length as u8;
+        assert_eq!(Coalesced::Pending, coalescer.push(&frames[3], 0));
+        assert_eq!(Coalesced::Refused, coalescer.push(&longer, 0));
+
+        let mut odd = frames[1].clone();
+        odd.pop();
+        odd[17] -= 1;
+        assert_eq!(Coalesced::Refused, coalescer.push(&odd, 0));
+        assert_eq!(Coalesced::Refused, coalescer.push(&frames[1][..40], 0));
+
+        assert_eq!(Coalesced::Pending, coalescer.push(&frames[0], 10));
+        assert_eq!(0, coalescer.expire(109));
+        assert_eq!(1, coalescer.expire(110));
+        assert_eq!(0, coalescer.pending.len());
+
+        for ident in 0..MAX_PENDING {
+            let frames = fragments(ident as u16, &[7u8; 100], 48);
+            assert_eq!(Coalesced::Pending, coalescer.push(&frames[0], 0));
+        }
+        let frames = fragments(MAX_PENDING as u16, &[7u8; 100], 48);
+        assert_eq!(Coalesced::Refused, coalescer.push(&frames[0], 0));
+    }
+}
@@ -13,13 +13,21 @@
  * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
  */
 
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fmt;
 use std::fs::File;
 use std::io::Read;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::str::FromStr;
 
 use super::e2d2::headers::*;
+use super::graph;
+use super::logger;
+use super::member;
+use super::s3::Store;
 use super::toml;
+use super::wireformat::MAX_KEY_LEN;
 
 #[derive(Debug, Clone)]
 pub struct ParseError;
@@ -51,6 +59,99 @@ pub fn parse_mac(mac: &str) -> Result<MacAddress, ParseError> {
     }
 }
 
+/// The core the parent server thread runs on if none was configured.
+pub const DEFAULT_PRIMARY_CORE: i32 = 19;
+
+/// The core misbehaving schedulers are migrated to if none was configured.
+pub const DEFAULT_GHETTO_CORE: i32 = 20;
+
+/// The number of receive and transmit descriptors on every queue if none were configured.
+pub const DEFAULT_DESCRIPTORS: usize = 256;
+
+/// The maximum number of receive queues, and hence cores, on a single port.
+pub const MAX_PORT_QUEUES: usize = 16;
+
+/// The fields of a server's config that the config() RPC can change while the server runs: the
+/// rate limits, log levels, transmit batching, overload, port stats and slow request settings.
+pub const TUNABLE: [&str; 9] = [
+    "rate_limit",
+    "rate_burst",
+    "rate_limit_mark",
+    "log_levels",
+    "tx_batch",
+    "tx_flush_us",
+    "overload_tasks",
+    "port_stats_s",
+    "slow_request_us",
+];
+
+/// The fields of a server's config that hold secrets. The config() RPC renders them as
+/// "<redacted>" when they are set.
+pub const SECRETS: [&str; 4] = [
+    "object_store_secret_key",
+    "raft_secret",
+    "admin_credential",
+    "peer_credential",
+];
+
+/// The smallest number of bytes in a secret servers share to authenticate to each other.
+pub const MIN_SECRET_LEN: usize = 16;
+
+/// A change to a field of a running server's config, with the old and new values rendered as
+/// TOML.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub field: String,
+    pub old: String,
+    pub new: String,
+}
+
+/// Every problem found while validating a config. Each problem names the offending field and
+/// what needs to change for the config to be accepted.
+#[derive(Debug, Clone)]
+pub struct ConfigError {
+    pub problems: Vec<String>,
+}
+
+impl Error for ConfigError {
+    fn description(&self) -> &str {
+        "Invalid configuration."
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid configuration, {} problem(s):", self.problems.len())?;
+        for problem in self.problems.iter() {
+            write!(f, "\n  - {}", problem)?;
+        }
+
+        Ok(())
+    }
+}
+
+// Records a problem if `value` of the field `name` is not a MAC address.
+fn check_mac(problems: &mut Vec<String>, name: &str, value: &str) {
+    if parse_mac(value).is_err() {
+        problems.push(format!(
+            "`{}` = \"{}\" is not a MAC address, expected six colon-separated hex bytes such as \
+             \"3c:fd:fe:04:a1:e0\".",
+            name, value
+        ));
+    }
+}
+
+// Records a problem if `value` of the field `name` is not an IPv4 address.
+fn check_ip(problems: &mut Vec<String>, name: &str, value: &str) {
+    if Ipv4Addr::from_str(value).is_err() {
+        problems.push(format!(
+            "`{}` = \"{}\" is not an IPv4 address, expected a dotted quad such as \
+             \"192.168.0.2\".",
+            name, value
+        ));
+    }
+}
+
 /// Load a config from `filename` otherwise return a default structure.
 fn load_config(filename: &str) -> ServerConfig {
     let mut contents = String::new();
@@ -85,7 +186,7 @@ fn load_config_cl(filename: &str) -> ClientConfig {
 /// Normally this config is recovered from a server.toml file (an example of which is in
 /// server.toml-example). If this file is malformed or missing, the server will typically
 /// crash when it cannot determine a MAC address to bind to.
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct ServerConfig {
     mac_address: String,
     pub ip_address: String,
@@ -97,13 +198,1332 @@ pub struct ServerConfig {
     pub install_addr: String,
     pub workload: String,
     pub num_records: u32,
+
+    #[serde(default)]
+    pub backend: String,
+    #[serde(default)]
+    pub net_iface: String,
+    #[serde(default)]
+    pub xsk_map: String,
+
+    #[serde(default)]
+    pub rss: bool,
+    #[serde(default)]
+    pub exclusive_rx: bool,
+
+    #[serde(default)]
+    pub zero_copy: bool,
+
+    #[serde(default)]
+    pub tx_batch: usize,
+    #[serde(default)]
+    pub tx_flush_us: u64,
+
+    #[serde(default)]
+    pub cores: Vec<i32>,
+    #[serde(default)]
+    pub numa_bind: bool,
+
+    #[serde(default)]
+    pub nic_ports: Vec<NicPortConfig>,
+    #[serde(default)]
+    pub port_stats_s: u64,
+
+    #[serde(default)]
+    primary_core: Option<i32>,
+    #[serde(default)]
+    ghetto_core: Option<i32>,
+    #[serde(default)]
+    pub rx_descriptors: usize,
+    #[serde(default)]
+    pub tx_descriptors: usize,
+    #[serde(default)]
+    pub mtu: u16,
+
+    #[serde(default)]
+    pub tenant_ports: Vec<PortRangeConfig>,
+
+    #[serde(default)]
+    pub overload_tasks: usize,
+
+    #[serde(default)]
+    pub rate_limit: u64,
+    #[serde(default)]
+    pub rate_burst: u64,
+    #[serde(default)]
+    pub rate_limit_mark: bool,
+    #[serde(default)]
+    pub tenant_limits: Vec<TenantLimitConfig>,
+
+    #[serde(default)]
+    pub tap_rate: usize,
+    #[serde(default)]
+    pub tap_path: String,
+    #[serde(default)]
+    pub tap_ring: usize,
+
+    #[serde(default)]
+    pub meter_interval_s: u64,
+    #[serde(default)]
+    pub meter_path: String,
+    #[serde(default)]
+    pub meter_addr: String,
+
+    #[serde(default)]
+    pub audit_path: String,
+
+    #[serde(default)]
+    pub metrics_addr: String,
+
+    #[serde(default)]
+    pub span_path: String,
+
+    #[serde(default)]
+    pub log_levels: String,
+
+    #[serde(default)]
+    pub slow_request_us: u64,
+
+    #[serde(default)]
+    pub backup_addr: String,
+    #[serde(default)]
+    pub backup_timeout_ms: u64,
+    #[serde(default)]
+    pub standby: bool,
+    #[serde(default)]
+    pub peer_credential: u64,
+
+    #[serde(default)]
+    pub read_replicas: Vec<String>,
+    #[serde(default)]
+    pub read_replica: bool,
+
+    #[serde(default)]
+    pub member_addr: String,
+    #[serde(default)]
+    pub members: Vec<String>,
+    #[serde(default)]
+    pub member_heartbeat_ms: u64,
+    #[serde(default)]
+    pub member_timeout_ms: u64,
+    #[serde(default)]
+    pub failover: bool,
+
+    #[serde(default)]
+    pub geo_site: u32,
+    #[serde(default)]
+    pub geo_max_lag_ms: u64,
+    #[serde(default)]
+    pub geo_tenants: Vec<GeoTenantConfig>,
+    #[serde(default)]
+    pub geo_peers: Vec<String>,
+
+    #[serde(default)]
+    pub raft_peers: Vec<String>,
+    #[serde(default)]
+    pub raft_id: usize,
+    #[serde(default)]
+    pub raft_tables: Vec<RaftTableConfig>,
+    #[serde(default)]
+    pub raft_dir: String,
+    #[serde(default)]
+    pub raft_secret: String,
+
+    #[serde(default)]
+    pub partitions: Vec<PartitionConfig>,
+
+    #[serde(default)]
+    pub wal_dir: String,
+    #[serde(default)]
+    pub wal_sync_ms: u64,
+    #[serde(default)]
+    pub checkpoint_interval_s: u64,
+    #[serde(default)]
+    pub wal_skip_corrupt: bool,
+
+    #[serde(default)]
+    pub snapshot_dir: String,
+
+    #[serde(default)]
+    pub object_store_url: String,
+    #[serde(default)]
+    pub object_store_access_key: String,
+    #[serde(default)]
+    pub object_store_secret_key: String,
+    #[serde(default)]
+    pub object_store_region: String,
+    #[serde(default)]
+    pub backup_interval_s: u64,
+    #[serde(default)]
+    pub backup_full_every: u64,
+    #[serde(default)]
+    pub backup_differential: bool,
+
+    #[serde(default)]
+    pub max_extensions: usize,
+    #[serde(default)]
+    pub max_extension_bytes: u64,
+
+    #[serde(default)]
+    pub max_key_len: usize,
+    #[serde(default)]
+    pub admin_credential: u64,
+
+    #[serde(default)]
+    pub mem_capacity: usize,
+
+    #[serde(default)]
+    pub drain_timeout_ms: u64,
+    #[serde(default)]
+    pub shm_path: String,
+
+    #[serde(default)]
+    pub groups: Vec<GroupConfig>,
+
+    #[serde(default)]
+    pub tiers: Vec<TierConfig>,
+
+    #[serde(default)]
+    pub tao: TaoConfig,
+}
+
+/// Configuration for one of several physical ports served by the server. Every port is identified
+/// by it's PCI address (or any other DPDK device name, such as that of a bonded device), and has
+/// it's own MAC and IP address. Fields left empty default to the top level `mac_address` and
+/// `ip_address` in the server's config.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct NicPortConfig {
+    pub pci: String,
+    #[serde(default)]
+    pub mac_address: String,
+    #[serde(default)]
+    pub ip_address: String,
+}
+
+/// Configuration for one of several servers a client spreads it's requests across. Every server
+/// has it's own MAC and IP address. When routing statically, `tables` lists the tables held by
+/// the server. When routing by consistent hashing, `weight` is the server's share of the keys
+/// relative to the other servers, 1 if left out.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct ServerEndpointConfig {
+    pub mac_address: String,
+    pub ip_address: String,
+    #[serde(default)]
+    pub tables: Vec<u64>,
+    #[serde(default)]
+    pub weight: u32,
+}
+
+impl ServerEndpointConfig {
+    /// Parse `mac_address` into NetBrick's format or panic if malformed.
+    pub fn parse_mac(&self) -> MacAddress {
+        parse_mac(&self.mac_address).expect("Malformed mac_address field in servers config.")
+    }
+}
+
+/// Configuration for a scheduling group. Tenants in a group are together guaranteed `share`
+/// percent of the cycles on every core, and this share is then divided equally among them.
+/// Tenants that do not belong to any group are placed into a default group that receives
+/// whatever share is left over.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct GroupConfig {
+    pub name: String,
+    pub share: u32,
+    pub tenants: Vec<u32>,
+}
+
+/// Configuration for the rate at which requests from a set of tenants are admitted across all
+/// cores, which is at most `ops_rate` requests and `bytes_rate` request bytes per second and
+/// tenant, after an initial burst of `ops_burst` requests and `bytes_burst` bytes (one second's
+/// worth if zero). A rate of zero leaves that dimension unlimited. A config with no `tenants`
+/// applies to every tenant that is not listed in another.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct TenantLimitConfig {
+    #[serde(default)]
+    pub tenants: Vec<u32>,
+    #[serde(default)]
+    pub ops_rate: u64,
+    #[serde(default)]
+    pub bytes_rate: u64,
+    #[serde(default)]
+    pub ops_burst: u64,
+    #[serde(default)]
+    pub bytes_burst: u64,
+}
+
+/// A table replicated by the Raft group the server is part of, identified by the tenant that owns
+/// it and it's identifier.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct RaftTableConfig {
+    pub tenant: u32,
+    pub table: u64,
+}
+
+/// A tenant whose writes are shipped to the server in a remote site whose install() TCP endpoint
+/// is at `remote`.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct GeoTenantConfig {
+    pub tenant: u32,
+    pub remote: String,
+}
+
+/// A range of a partitioned tenant's keys, starting at `start` and held by the server taking
+/// requests on `ip_address`. The range ends right before the next range of the tenant starts.
+/// Every partitioned tenant has one range starting at the empty key.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct PartitionConfig {
+    pub tenant: u32,
+    #[serde(default)]
+    pub start: String,
+    pub ip_address: String,
+}
+
+/// Configuration for a named service tier, such as "gold", "silver" or "batch", bundling the
+/// settings every one of `tenants` receives. Each tier turns into a scheduling group with a
+/// `share` percent of every core, a `tenant_limits` entry with the rates and bursts, a
+/// `tenant_ports` range of `port_count` ports starting at `port_base` steered to `queues`, and
+/// the memory quota (`mem_limit` bytes of objects, and `alloc_quota` bytes allocated by every
+/// invocation) of the tier's tenants. A setting left at zero is not applied.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct TierConfig {
+    pub name: String,
+    pub tenants: Vec<u32>,
+    #[serde(default)]
+    pub share: u32,
+    #[serde(default)]
+    pub ops_rate: u64,
+    #[serde(default)]
+    pub bytes_rate: u64,
+    #[serde(default)]
+    pub ops_burst: u64,
+    #[serde(default)]
+    pub bytes_burst: u64,
+    #[serde(default)]
+    pub mem_limit: usize,
+    #[serde(default)]
+    pub alloc_quota: usize,
+    #[serde(default)]
+    pub port_base: u16,
+    #[serde(default)]
+    pub port_count: u16,
+    #[serde(default)]
+    pub queues: Vec<i32>,
+}
+
+/// Configuration for the social graph of the TAO workload. The graph has `objects` objects (the
+/// server's `num_records` or the client's `n_keys` if zero), each with a list of associations
+/// to it's neighbours whose length is drawn from the `fanout` distribution: "constant" (every
+/// object has `fanout_min` associations), "uniform" (between `fanout_min` and `fanout_max`), or
+/// "zipfian" (between the two, with short lists the most common and `fanout_skew` the skew).
+/// The percentages pick the mix of obj_get, assoc_get, obj_update and assoc_add requests issued
+/// by the client; if they are all zero, the client's `assocs_p` decides the mix of reads. If
+/// `spec` is the path to a LinkBench style properties file, any parameters in it override those
+/// in this config.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct TaoConfig {
+    #[serde(default)]
+    pub spec: String,
+    #[serde(default)]
+    pub objects: u32,
+    #[serde(default)]
+    pub fanout: String,
+    #[serde(default)]
+    pub fanout_min: u32,
+    #[serde(default)]
+    pub fanout_max: u32,
+    #[serde(default)]
+    pub fanout_skew: f64,
+    #[serde(default)]
+    pub obj_get_pct: usize,
+    #[serde(default)]
+    pub assoc_get_pct: usize,
+    #[serde(default)]
+    pub obj_update_pct: usize,
+    #[serde(default)]
+    pub assoc_add_pct: usize,
+}
+
+/// Configuration for one of several tenants a single YCSB client emulates. Every emulated tenant
+/// issues requests on it's own schedule at `req_rate` requests per second on every sender, with
+/// it's own mix of operations over it's own keys. An empty `key_dist`, and a zero `n_keys` or
+/// `skew`, fall back to the client's. So does an empty `ycsb_workload`, unless `put_pct` is set,
+/// in which case the tenant issues reads and `put_pct` percent updates.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct EmulatedTenantConfig {
+    pub id: u32,
+    pub req_rate: usize,
+    #[serde(default)]
+    pub ycsb_workload: String,
+    #[serde(default)]
+    pub key_dist: String,
+    #[serde(default)]
+    pub put_pct: usize,
+    #[serde(default)]
+    pub n_keys: usize,
+    #[serde(default)]
+    pub skew: f64,
+}
+
+/// Configuration for a range of UDP destination ports reserved for a set of tenants. Requests
+/// from these tenants are sent to ports `base` through `base + count - 1`, and the server's NIC
+/// steers port `base + i` to receive queue `queues[i % queues.len()]` (or to queue `i` modulo the
+/// number of queues if `queues` is empty). A flood of requests on one range then cannot fill up
+/// queues serving other tenants.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct PortRangeConfig {
+    pub tenants: Vec<u32>,
+    pub base: u16,
+    pub count: u16,
+    #[serde(default)]
+    pub queues: Vec<i32>,
+}
+
+impl PortRangeConfig {
+    /// Returns the UDP port that the `seq`th request from a tenant in this range should be
+    /// sent to. Requests are spread round robin across the range.
+    pub fn port(&self, seq: u64) -> u16 {
+        if self.count == 0 {
+            return self.base;
+        }
+
+        self.base + (seq % self.count as u64) as u16
+    }
+
+    /// Returns the receive queue that UDP port `base + offset` should be steered to.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset`: The offset of the UDP port inside this range.
+    /// * `rxqs`:   The total number of receive queues on the NIC.
+    pub fn queue(&self, offset: u16, rxqs: i32) -> i32 {
+        if self.queues.len() > 0 {
+            self.queues[offset as usize % self.queues.len()]
+        } else {
+            offset as i32 % rxqs
+        }
+    }
 }
 
 impl ServerConfig {
     /// Load server config from server.toml file in the current directory or otherwise return a
     /// default structure.
     pub fn load() -> ServerConfig {
-        load_config("server.toml")
+        let mut config = load_config("server.toml");
+        config.apply_tiers();
+        config
+    }
+
+    /// Turns every one of `tiers` into the scheduling group, tenant limits and port range it
+    /// stands for, appending them to `groups`, `tenant_limits` and `tenant_ports`, so that the
+    /// rest of the server only ever deals with those. `validate()` then catches a tenant placed
+    /// by both a tier and an explicit entry.
+    pub fn apply_tiers(&mut self) {
+        for tier in self.tiers.iter() {
+            if tier.share > 0 {
+                self.groups.push(GroupConfig {
+                    name: tier.name.clone(),
+                    share: tier.share,
+                    tenants: tier.tenants.clone(),
+                });
+            }
+
+            if tier.ops_rate > 0 || tier.bytes_rate > 0 {
+                self.tenant_limits.push(TenantLimitConfig {
+                    tenants: tier.tenants.clone(),
+                    ops_rate: tier.ops_rate,
+                    bytes_rate: tier.bytes_rate,
+                    ops_burst: tier.ops_burst,
+                    bytes_burst: tier.bytes_burst,
+                });
+            }
+
+            if tier.port_count > 0 {
+                self.tenant_ports.push(PortRangeConfig {
+                    tenants: tier.tenants.clone(),
+                    base: tier.port_base,
+                    count: tier.port_count,
+                    queues: tier.queues.clone(),
+                });
+            }
+        }
+    }
+
+    /// Renders the config as TOML, including fields that were left at their defaults. Fields in
+    /// `SECRETS` that are set are rendered as "<redacted>".
+    pub fn to_toml(&self) -> String {
+        let mut value = match toml::Value::try_from(self) {
+            Ok(value) => value,
+            Err(_) => return String::new(),
+        };
+
+        if let toml::Value::Table(ref mut fields) = value {
+            for &name in SECRETS.iter() {
+                let set = match fields.get(name) {
+                    Some(&toml::Value::String(ref secret)) => secret.len() > 0,
+                    Some(&toml::Value::Integer(secret)) => secret != 0,
+                    _ => false,
+                };
+                if set {
+                    let redacted = toml::Value::String(String::from("<redacted>"));
+                    fields.insert(String::from(name), redacted);
+                }
+            }
+        }
+
+        toml::to_string(&value).unwrap_or_default()
+    }
+
+    /// Changes fields in `TUNABLE`. The config is left as is if any of the changes is rejected,
+    /// or if the config they result in does not validate.
+    ///
+    /// # Arguments
+    ///
+    /// * `changes`: TOML assigning new values to the fields, such as "tx_batch = 16".
+    ///
+    /// # Return
+    ///
+    /// Every field whose value changed. Otherwise, an error listing every problem found.
+    pub fn tune(&mut self, changes: &str) -> Result<Vec<Change>, ConfigError> {
+        let fail = |problem: String| ConfigError { problems: vec![problem] };
+
+        let changes: toml::value::Table = toml::from_str(changes)
+            .map_err(|e| fail(format!("The changes are not valid TOML: {}.", e)))?;
+        let mut fields = match toml::Value::try_from(&*self) {
+            Ok(toml::Value::Table(fields)) => fields,
+            _ => return Err(fail(String::from("The running config cannot be rendered."))),
+        };
+
+        let mut problems = Vec::new();
+        let mut changed = Vec::new();
+        for (field, value) in changes.into_iter() {
+            if !TUNABLE.contains(&field.as_str()) {
+                problems.push(format!("`{}` cannot be changed while the server runs.", field));
+                continue;
+            }
+
+            let old = fields.get(&field).map_or(String::new(), |old| old.to_string());
+            let new = value.to_string();
+            if old != new {
+                changed.push(Change {
+                    field: field.clone(),
+                    old: old,
+                    new: new,
+                });
+            }
+            fields.insert(field, value);
+        }
+
+        if problems.len() > 0 {
+            return Err(ConfigError { problems: problems });
+        }
+
+        let tuned: ServerConfig = toml::Value::Table(fields)
+            .try_into()
+            .map_err(|e| fail(format!("{}.", e)))?;
+        tuned.validate()?;
+
+        *self = tuned;
+        Ok(changed)
+    }
+
+    /// Checks the config for malformed addresses and settings that contradict each other, so
+    /// that the server can refuse to start instead of failing later on.
+    ///
+    /// # Return
+    ///
+    /// Ok if the config is usable. Otherwise, an error listing every problem found.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems: Vec<String> = Vec::new();
+
+        // Addresses the server sends packets from and to.
+        check_mac(&mut problems, "mac_address", &self.mac_address);
+        check_ip(&mut problems, "ip_address", &self.ip_address);
+        check_mac(&mut problems, "client_mac", &self.client_mac);
+        check_ip(&mut problems, "client_ip", &self.client_ip);
+        if SocketAddr::from_str(&self.install_addr).is_err() {
+            problems.push(format!(
+                "`install_addr` = \"{}\" is not a socket address, expected an IP address and \
+                 TCP port such as \"127.0.0.1:7700\".",
+                self.install_addr
+            ));
+        }
+
+        // The backend and the interface it is bound to.
+        match self.backend.as_str() {
+            "" | "dpdk" => {
+                for nic in self.nics().iter() {
+                    if nic.pci.len() == 0 {
+                        problems.push(String::from(
+                            "`nic_pci` is empty, set it to the PCI address of the NIC DPDK \
+                             should use.",
+                        ));
+                    }
+                }
+            }
+
+            "socket" | "uring" | "xdp" => {
+                if self.net_iface.len() == 0 {
+                    problems.push(format!(
+                        "`net_iface` is empty, but the \"{}\" backend needs the name of a \
+                         network interface.",
+                        self.backend
+                    ));
+                }
+
+                if self.backend == "xdp" && self.xsk_map.len() == 0 {
+                    problems.push(String::from(
+                        "`xsk_map` is empty, but the \"xdp\" backend needs the path of a pinned \
+                         XSKMAP.",
+                    ));
+                }
+            }
+
+            _ => {
+                problems.push(format!(
+                    "`backend` = \"{}\" is unknown, expected one of \"dpdk\", \"socket\", \
+                     \"uring\", or \"xdp\".",
+                    self.backend
+                ));
+            }
+        }
+
+        // Every extra physical port needs addresses of it's own.
+        let mut pcis = HashSet::new();
+        for (idx, nic) in self.nics().iter().enumerate() {
+            check_mac(&mut problems, &format!("nic_ports[{}].mac_address", idx), &nic.mac_address);
+            check_ip(&mut problems, &format!("nic_ports[{}].ip_address", idx), &nic.ip_address);
+            if !pcis.insert(nic.pci.clone()) {
+                problems.push(format!(
+                    "`nic_ports[{}].pci` = \"{}\" is listed more than once, every port can \
+                     only be served once.",
+                    idx, nic.pci
+                ));
+            }
+        }
+
+        // The core map. Cores run one scheduler each, and cannot be shared with the primary
+        // thread or the core misbehaving schedulers are migrated to.
+        let mut cores = HashSet::new();
+        for core in self.cores.iter() {
+            if *core < 0 {
+                problems.push(format!("`cores` contains {}, cores cannot be negative.", core));
+            }
+
+            if !cores.insert(*core) {
+                problems.push(format!(
+                    "`cores` contains {} more than once, every core runs a single scheduler.",
+                    core
+                ));
+            }
+
+            if *core == self.primary_core() || *core == self.ghetto_core() {
+                problems.push(format!(
+                    "`cores` contains {}, which is reserved as the primary or ghetto core. \
+                     Remove it or change `primary_core` and `ghetto_core`.",
+                    core
+                ));
+            }
+        }
+
+        if self.primary_core() == self.ghetto_core() {
+            problems.push(format!(
+                "`primary_core` and `ghetto_core` are both {}, they must differ.",
+                self.primary_core()
+            ));
+        }
+
+        let ports = if self.uses_dpdk() { self.nics().len() } else { 1 };
+        let per_port = (self.cores.len() + ports - 1) / ports;
+        if per_port > MAX_PORT_QUEUES {
+            problems.push(format!(
+                "{} cores on each of {} port(s) exceeds the limit of {} receive queues per port. \
+                 Configure fewer cores or more ports.",
+                per_port, ports, MAX_PORT_QUEUES
+            ));
+        }
+
+        if self.cores.len() > 0 && self.cores.len() < ports {
+            problems.push(format!(
+                "{} cores cannot serve {} ports, configure at least one core per port.",
+                self.cores.len(),
+                ports
+            ));
+        }
+
+        // Queue sizes and frame sizes.
+        for &(name, descs) in [
+            ("rx_descriptors", self.rx_descriptors),
+            ("tx_descriptors", self.tx_descriptors),
+        ].iter()
+        {
+            if descs != 0 && (!descs.is_power_of_two() || descs < 64 || descs > 4096) {
+                problems.push(format!(
+                    "`{}` = {} is not a power of two between 64 and 4096.",
+                    name, descs
+                ));
+            }
+        }
+
+        if self.mtu != 0 && (self.mtu < 68 || self.mtu > 9000) {
+            problems.push(format!(
+                "`mtu` = {} is out of range, expected a value between 68 and 9000.",
+                self.mtu
+            ));
+        }
+
+        // Tenant port ranges are steered by flow director to queues that must exist, and must
+        // not collide with each other or with the ports steered to every core's queue.
+        if !self.rss {
+            let queues = if self.cores.len() > 0 {
+                per_port as i32
+            } else {
+                MAX_PORT_QUEUES as i32
+            };
+
+            let mut ranges: Vec<(u32, u32)> = Vec::new();
+            for (idx, range) in self.tenant_ports.iter().enumerate() {
+                if range.count == 0 {
+                    problems.push(format!(
+                        "`tenant_ports[{}].count` is 0, a range needs at least one UDP port.",
+                        idx
+                    ));
+                    continue;
+                }
+
+                let end = range.base as u32 + range.count as u32;
+                if end > 65536 {
+                    problems.push(format!(
+                        "`tenant_ports[{}]` runs past UDP port 65535, reduce `base` or `count`.",
+                        idx
+                    ));
+                    continue;
+                }
+
+                if self.cores.len() > 0 && (range.base as usize) < per_port {
+                    problems.push(format!(
+                        "`tenant_ports[{}]` starts at UDP port {}, overlapping ports 0 to {} that \
+                         are steered to every core. Move `base` to {} or higher.",
+                        idx,
+                        range.base,
+                        per_port - 1,
+                        per_port
+                    ));
+                }
+
+                for queue in range.queues.iter() {
+                    if *queue < 0 || *queue >= queues {
+                        problems.push(format!(
+                            "`tenant_ports[{}].queues` contains {}, but there are only {} \
+                             receive queues per port.",
+                            idx, queue, queues
+                        ));
+                    }
+                }
+
+                for &(base, count) in ranges.iter() {
+                    if (range.base as u32) < base + count && base < end {
+                        problems.push(format!(
+                            "`tenant_ports[{}]` overlaps UDP ports {} to {} of an earlier range.",
+                            idx,
+                            base,
+                            base + count - 1
+                        ));
+                    }
+                }
+
+                ranges.push((range.base as u32, range.count as u32));
+            }
+        }
+
+        // Scheduling groups must fit within a core's cycles, and tenants can only be in one.
+        let share: u32 = self.groups.iter().map(|group| group.share).sum();
+        if share > 100 {
+            problems.push(format!(
+                "`groups` are guaranteed {} percent of every core in total, reduce their \
+                 shares to add up to 100 at most.",
+                share
+            ));
+        }
+
+        let mut grouped = HashSet::new();
+        for group in self.groups.iter() {
+            for tenant in group.tenants.iter() {
+                if !grouped.insert(*tenant) {
+                    problems.push(format!(
+                        "Tenant {} is in more than one of `groups`, including \"{}\".",
+                        tenant, group.name
+                    ));
+                }
+            }
+        }
+
+        // Metering records need somewhere to go.
+        if self.meter_interval_s > 0 && self.meter_path.len() == 0 && self.meter_addr.len() == 0 {
+            problems.push(String::from(
+                "`meter_interval_s` is set, but neither `meter_path` nor `meter_addr` say where \
+                 metering records should go.",
+            ));
+        }
+
+        if self.meter_addr.len() > 0 && SocketAddr::from_str(&self.meter_addr).is_err() {
+            problems.push(format!(
+                "`meter_addr` = \"{}\" is not a socket address, expected an IP address and UDP \
+                 port such as \"127.0.0.1:7800\".",
+                self.meter_addr
+            ));
+        }
+
+        if self.metrics_addr.len() > 0 && SocketAddr::from_str(&self.metrics_addr).is_err() {
+            problems.push(format!(
+                "`metrics_addr` = \"{}\" is not a socket address, expected an IP address and TCP \
+                 port such as \"0.0.0.0:9100\".",
+                self.metrics_addr
+            ));
+        }
+
+        if self.backup_addr.len() > 0 && SocketAddr::from_str(&self.backup_addr).is_err() {
+            problems.push(format!(
+                "`backup_addr` = \"{}\" is not a socket address, expected the backup's \
+                 `install_addr` such as \"192.168.0.3:7700\".",
+                self.backup_addr
+            ));
+        }
+
+        if self.standby && self.backup_addr.len() > 0 {
+            problems.push(String::from(
+                "A `standby` cannot have a `backup_addr`, only the primary it backs up ships \
+                 writes.",
+            ));
+        }
+
+        if (self.standby || self.backup_addr.len() > 0) && self.peer_credential == 0 {
+            problems.push(String::from(
+                "`peer_credential` is not set, a primary and it's `standby` need one they share \
+                 to authenticate the writes shipped and the promotion.",
+            ));
+        }
+
+        for (idx, addr) in self.read_replicas.iter().enumerate() {
+            if SocketAddr::from_str(addr).is_err() {
+                problems.push(format!(
+                    "`read_replicas[{}]` = \"{}\" is not a socket address, expected the read \
+                     replica's `install_addr` such as \"192.168.0.4:7700\".",
+                    idx, addr
+                ));
+            }
+        }
+
+        let feeds = self.standby || self.backup_addr.len() > 0 || self.read_replicas.len() > 0;
+        if self.read_replica && feeds {
+            problems.push(String::from(
+                "A `read_replica` cannot be a `standby` or have a `backup_addr` or \
+                 `read_replicas`, it only applies the writes it's primary feeds it.",
+            ));
+        }
+
+        let fed = self.read_replica || self.read_replicas.len() > 0;
+        if fed && !(self.standby || self.backup_addr.len() > 0) && self.peer_credential == 0 {
+            problems.push(String::from(
+                "`peer_credential` is not set, a primary and it's `read_replicas` need one they \
+                 share to authenticate the writes fed.",
+            ));
+        }
+
+        // A Raft group has three servers, each listed by the address it exchanges messages on.
+        if self.raft_peers.len() > 0 {
+            if self.raft_peers.len() != 3 {
+                problems.push(format!(
+                    "`raft_peers` lists {} servers, a Raft group needs exactly 3.",
+                    self.raft_peers.len()
+                ));
+            }
+
+            for (idx, peer) in self.raft_peers.iter().enumerate() {
+                if SocketAddr::from_str(peer).is_err() {
+                    problems.push(format!(
+                        "`raft_peers[{}]` = \"{}\" is not a socket address, expected an IP \
+                         address and UDP port such as \"192.168.0.3:7900\".",
+                        idx, peer
+                    ));
+                }
+            }
+
+            if self.raft_id >= self.raft_peers.len() {
+                problems.push(format!(
+                    "`raft_id` = {} is not the index of any of `raft_peers`.",
+                    self.raft_id
+                ));
+            }
+
+            if self.raft_tables.len() == 0 {
+                problems.push(String::from(
+                    "`raft_peers` is set, but no `raft_tables` are marked to be replicated.",
+                ));
+            }
+
+            if self.raft_dir.len() == 0 {
+                problems.push(String::from(
+                    "`raft_peers` is set, but there is no `raft_dir` to keep the Raft log in.",
+                ));
+            }
+
+            if self.raft_secret.len() < MIN_SECRET_LEN {
+                problems.push(format!(
+                    "`raft_secret` is {} bytes long, it needs at least {} bytes shared by every \
+                     server in `raft_peers`.",
+                    self.raft_secret.len(),
+                    MIN_SECRET_LEN
+                ));
+            }
+
+            let replicated = self.standby || self.backup_addr.len() > 0;
+            let read = self.read_replica || self.read_replicas.len() > 0;
+            if replicated || read {
+                problems.push(String::from(
+                    "A server in a Raft group cannot also have a `backup_addr` or \
+                     `read_replicas`, or be a `standby` or `read_replica`.",
+                ));
+            }
+        } else if self.raft_tables.len() > 0 {
+            problems.push(String::from(
+                "`raft_tables` are marked, but there are no `raft_peers` to replicate them to.",
+            ));
+        }
+
+        // Every member exchanges heartbeats on an address of it's own.
+        if self.member_addr.len() > 0 && SocketAddr::from_str(&self.member_addr).is_err() {
+            problems.push(format!(
+                "`member_addr` = \"{}\" is not a socket address, expected an IP address and UDP \
+                 port such as \"192.168.0.2:7800\".",
+                self.member_addr
+            ));
+        }
+
+        for (idx, addr) in self.members.iter().enumerate() {
+            if SocketAddr::from_str(addr).is_err() {
+                problems.push(format!(
+                    "`members[{}]` = \"{}\" is not a socket address, expected another member's \
+                     `member_addr` such as \"192.168.0.3:7800\".",
+                    idx, addr
+                ));
+            }
+        }
+
+        if self.members.len() > 0 && self.member_addr.len() == 0 {
+            problems.push(String::from(
+                "`members` are listed, but there is no `member_addr` to exchange heartbeats on.",
+            ));
+        }
+
+        let heartbeat_ms = match self.member_heartbeat_ms {
+            0 => member::DEFAULT_HEARTBEAT_MS,
+            ms => ms,
+        };
+        let timeout_ms = match self.member_timeout_ms {
+            0 => member::DEFAULT_TIMEOUT_MS,
+            ms => ms,
+        };
+        if timeout_ms <= heartbeat_ms {
+            problems.push(format!(
+                "`member_timeout_ms` = {} must be longer than `member_heartbeat_ms` = {}, or every \
+                 member would be declared dead between heartbeats.",
+                timeout_ms, heartbeat_ms
+            ));
+        }
+
+        if self.failover && !(self.standby && self.members.len() > 0) {
+            problems.push(String::from(
+                "`failover` is only for a `standby` with `members` to detect it's primary's \
+                 failure through.",
+            ));
+        }
+
+        // Every geo-replicated tenant is shipped to a single remote site, and only by a primary.
+        for (idx, geo) in self.geo_tenants.iter().enumerate() {
+            if SocketAddr::from_str(&geo.remote).is_err() {
+                problems.push(format!(
+                    "`geo_tenants[{}]` has `remote` = \"{}\", which is not a socket address, \
+                     expected the remote server's `install_addr` such as \"10.1.0.2:7700\".",
+                    idx, geo.remote
+                ));
+            }
+
+            if self.geo_tenants[..idx].iter().any(|other| other.tenant == geo.tenant) {
+                problems.push(format!(
+                    "`geo_tenants[{}]` ships tenant {}, which an earlier entry already ships.",
+                    idx, geo.tenant
+                ));
+            }
+        }
+
+        if self.geo_tenants.len() > 0 && (self.standby || self.read_replica) {
+            problems.push(String::from(
+                "A `standby` or `read_replica` cannot have `geo_tenants`, only a primary ships \
+                 writes to a remote site.",
+            ));
+        }
+
+        // Writes from a remote site are only taken from the servers listed, with the credential.
+        for (idx, peer) in self.geo_peers.iter().enumerate() {
+            check_ip(&mut problems, &format!("geo_peers[{}]", idx), peer);
+        }
+
+        if self.geo_peers.len() > 0 && (self.standby || self.read_replica) {
+            problems.push(String::from(
+                "A `standby` or `read_replica` cannot have `geo_peers`, only a primary takes \
+                 writes from a remote site.",
+            ));
+        }
+
+        let geo = self.geo_tenants.len() > 0 || self.geo_peers.len() > 0;
+        if geo && self.peer_credential == 0 {
+            problems.push(String::from(
+                "`peer_credential` is not set, sites need one they share to authenticate the \
+                 writes shipped between them.",
+            ));
+        }
+
+        // The ranges of every partitioned tenant cover all of it's keys, once each.
+        let mut starts: HashMap<u32, Vec<&str>> = HashMap::new();
+        for (idx, range) in self.partitions.iter().enumerate() {
+            check_ip(&mut problems, &format!("partitions[{}].ip_address", idx), &range.ip_address);
+            let tenant = starts.entry(range.tenant).or_insert(Vec::new());
+            if tenant.contains(&range.start.as_str()) {
+                problems.push(format!(
+                    "`partitions[{}]` starts at \"{}\", like another range of tenant {}.",
+                    idx, range.start, range.tenant
+                ));
+            }
+            tenant.push(&range.start);
+        }
+        let mut uncovered: Vec<&u32> = starts
+            .iter()
+            .filter(|&(_, starts)| !starts.contains(&""))
+            .map(|(tenant, _)| tenant)
+            .collect();
+        uncovered.sort();
+        for tenant in uncovered {
+            problems.push(format!(
+                "No range of tenant {} in `partitions` starts at the empty key.",
+                tenant
+            ));
+        }
+
+        // Syncing and checkpointing only apply to a log.
+        if self.wal_dir.len() == 0 && (self.wal_sync_ms > 0 || self.checkpoint_interval_s > 0) {
+            problems.push(String::from(
+                "`wal_sync_ms` or `checkpoint_interval_s` is set, but there is no `wal_dir` to \
+                 log writes to.",
+            ));
+        }
+
+        // Backups go to a bucket reached over HTTPS or HTTP, with requests signed by a key pair.
+        if self.object_store_url.len() > 0 {
+            if let Err(problem) = Store::new(&self.object_store_url, "", "", "") {
+                problems.push(format!(
+                    "`object_store_url` = {}, expected a URL such as \
+                     \"https://s3.example.com/backups\".",
+                    problem
+                ));
+            }
+
+            if self.object_store_access_key.len() == 0 || self.object_store_secret_key.len() == 0 {
+                problems.push(String::from(
+                    "`object_store_url` is set, but `object_store_access_key` or \
+                     `object_store_secret_key` is missing.",
+                ));
+            }
+        } else if self.backup_interval_s > 0 {
+            problems.push(String::from(
+                "`backup_interval_s` is set, but there is no `object_store_url` to back up to.",
+            ));
+        }
+
+        // Every tenant is limited by at most one of `tenant_limits`.
+        let mut limited = HashSet::new();
+        let mut defaults = 0;
+        for (idx, limit) in self.tenant_limits.iter().enumerate() {
+            if limit.ops_rate == 0 && limit.bytes_rate == 0 {
+                problems.push(format!(
+                    "`tenant_limits[{}]` has neither an `ops_rate` nor a `bytes_rate`, and so \
+                     would not limit anything.",
+                    idx
+                ));
+            }
+
+            if limit.tenants.len() == 0 {
+                defaults += 1;
+            }
+
+            for tenant in limit.tenants.iter() {
+                if !limited.insert(*tenant) {
+                    problems.push(format!(
+                        "Tenant {} is in more than one of `tenant_limits`, including \
+                         `tenant_limits[{}]`.",
+                        tenant, idx
+                    ));
+                }
+            }
+        }
+
+        if defaults > 1 {
+            problems.push(format!(
+                "{} of `tenant_limits` have no `tenants`, only one can apply to every other \
+                 tenant.",
+                defaults
+            ));
+        }
+
+        // Every tier has a name of it's own and at least one tenant, and no tenant is in two.
+        let mut names = HashSet::new();
+        let mut tiered = HashSet::new();
+        for (idx, tier) in self.tiers.iter().enumerate() {
+            if tier.name.len() == 0 || !names.insert(tier.name.as_str()) {
+                problems.push(format!(
+                    "`tiers[{}]` needs a `name` that no other tier has, found \"{}\".",
+                    idx, tier.name
+                ));
+            }
+
+            if tier.tenants.len() == 0 {
+                problems.push(format!("Tier \"{}\" has no `tenants`.", tier.name));
+            }
+
+            for tenant in tier.tenants.iter() {
+                if !tiered.insert(*tenant) {
+                    problems.push(format!(
+                        "Tenant {} is in more than one tier, including \"{}\".",
+                        tenant, tier.name
+                    ));
+                }
+            }
+
+            if tier.mem_limit > 0 && tier.alloc_quota > tier.mem_limit {
+                problems.push(format!(
+                    "Tier \"{}\" lets an invocation allocate {} bytes, more than the {} bytes \
+                     of `mem_limit` it's tenants' objects can take up.",
+                    tier.name, tier.alloc_quota, tier.mem_limit
+                ));
+            }
+        }
+
+        // The memory quotas tiers hand out fit within what the server is provisioned for.
+        let promised = self.tiers.iter().fold(0usize, |sum, tier| {
+            sum.saturating_add(tier.mem_limit.saturating_mul(tier.tenants.len()))
+        });
+        if self.mem_capacity > 0 && promised > self.mem_capacity {
+            problems.push(format!(
+                "Tiers promise their tenants {} bytes of objects, more than the {} bytes of \
+                 `mem_capacity`. Lower a tier's `mem_limit`, or raise `mem_capacity`.",
+                promised, self.mem_capacity
+            ));
+        }
+
+        // Objects describe the length of their key in two bytes, as requests do.
+        if self.max_key_len > MAX_KEY_LEN {
+            problems.push(format!(
+                "`max_key_len` = {} is longer than the {} bytes a key can be.",
+                self.max_key_len, MAX_KEY_LEN
+            ));
+        }
+
+        if let Err(problem) = logger::Filter::parse(&self.log_levels) {
+            problems.push(format!("`log_levels` = \"{}\": {}.", self.log_levels, problem));
+        }
+
+        if self.workload == "TAO" {
+            if let Err(problem) = graph::resolve(&self.tao) {
+                problems.push(problem);
+            }
+        }
+
+        if problems.len() > 0 {
+            return Err(ConfigError { problems: problems });
+        }
+
+        return Ok(());
+    }
+
+    /// Applies the fields of a freshly loaded config that can change while the server is running.
+    /// These are the tap, metering, port stats, rate and tenant limiting, overload, transmit
+    /// batching, logging, slow request and drain timeout settings.
+    /// Changes to every other field require a restart, and are ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `fresh`: A freshly loaded and validated config.
+    ///
+    /// # Return
+    ///
+    /// The names of fields that differ in `fresh` but were ignored since they require a restart.
+    pub fn reload(&mut self, fresh: &ServerConfig) -> Vec<&'static str> {
+        let mut ignored = Vec::new();
+
+        macro_rules! ignore {
+            ($old:ident, $new:ident, $out:ident; $($field:ident),*) => {
+                $(
+                    if $old.$field != $new.$field {
+                        $out.push(stringify!($field));
+                    }
+                )*
+            };
+        }
+
+        ignore!(
+            self, fresh, ignored;
+            mac_address,
+            ip_address,
+            udp_port,
+            nic_pci,
+            client_mac,
+            client_ip,
+            num_tenants,
+            install_addr,
+            workload,
+            num_records,
+            backend,
+            net_iface,
+            xsk_map,
+            rss,
+            exclusive_rx,
+            zero_copy,
+            cores,
+            numa_bind,
+            nic_ports,
+            primary_core,
+            ghetto_core,
+            rx_descriptors,
+            tx_descriptors,
+            mtu,
+            tenant_ports,
+            audit_path,
+            metrics_addr,
+            span_path,
+            backup_addr,
+            backup_timeout_ms,
+            standby,
+            peer_credential,
+            read_replicas,
+            read_replica,
+            member_addr,
+            members,
+            member_heartbeat_ms,
+            member_timeout_ms,
+            failover,
+            geo_site,
+            geo_max_lag_ms,
+            geo_tenants,
+            geo_peers,
+            raft_peers,
+            raft_id,
+            raft_tables,
+            raft_dir,
+            raft_secret,
+            partitions,
+            wal_dir,
+            wal_sync_ms,
+            checkpoint_interval_s,
+            wal_skip_corrupt,
+            snapshot_dir,
+            object_store_url,
+            object_store_access_key,
+            object_store_secret_key,
+            object_store_region,
+            backup_interval_s,
+            backup_full_every,
+            backup_differential,
+            max_extensions,
+            max_extension_bytes,
+            max_key_len,
+            admin_credential,
+            mem_capacity,
+            shm_path,
+            groups,
+            tao
+        );
+
+        // Only the rates of a tier can change while the server runs, and they were applied to
+        // `tenant_limits` above. Changes to anything else show up as ignored changes to the
+        // groups and port ranges tiers turn into, or are picked up by tenants created later.
+        self.tiers = fresh.tiers.clone();
+
+        self.tx_batch = fresh.tx_batch;
+        self.tx_flush_us = fresh.tx_flush_us;
+        self.port_stats_s = fresh.port_stats_s;
+        self.overload_tasks = fresh.overload_tasks;
+        self.rate_limit = fresh.rate_limit;
+        self.rate_burst = fresh.rate_burst;
+        self.rate_limit_mark = fresh.rate_limit_mark;
+        self.tenant_limits = fresh.tenant_limits.clone();
+        self.tap_rate = fresh.tap_rate;
+        self.tap_path = fresh.tap_path.clone();
+        self.tap_ring = fresh.tap_ring;
+        self.meter_interval_s = fresh.meter_interval_s;
+        self.meter_path = fresh.meter_path.clone();
+        self.meter_addr = fresh.meter_addr.clone();
+        self.log_levels = fresh.log_levels.clone();
+        self.slow_request_us = fresh.slow_request_us;
+        self.drain_timeout_ms = fresh.drain_timeout_ms;
+
+        return ignored;
+    }
+
+    /// Returns the core the parent server thread runs on.
+    pub fn primary_core(&self) -> i32 {
+        self.primary_core.unwrap_or(DEFAULT_PRIMARY_CORE)
+    }
+
+    /// Returns the core misbehaving schedulers are migrated to.
+    pub fn ghetto_core(&self) -> i32 {
+        self.ghetto_core.unwrap_or(DEFAULT_GHETTO_CORE)
+    }
+
+    /// Returns true if the server exchanges packets with the NIC through DPDK. This is the case
+    /// unless some other `backend` was configured.
+    pub fn uses_dpdk(&self) -> bool {
+        match self.backend.as_str() {
+            "" | "dpdk" => true,
+            _ => false,
+        }
+    }
+
+    /// Returns the physical ports the server should serve. If no `nic_ports` were configured, this
+    /// is the single port described by `nic_pci`, `mac_address`, and `ip_address`.
+    pub fn nics(&self) -> Vec<NicPortConfig> {
+        if self.nic_ports.len() == 0 {
+            return vec![NicPortConfig {
+                pci: self.nic_pci.clone(),
+                mac_address: self.mac_address.clone(),
+                ip_address: self.ip_address.clone(),
+            }];
+        }
+
+        self.nic_ports
+            .iter()
+            .map(|nic| NicPortConfig {
+                pci: nic.pci.clone(),
+                mac_address: if nic.mac_address.len() > 0 {
+                    nic.mac_address.clone()
+                } else {
+                    self.mac_address.clone()
+                },
+                ip_address: if nic.ip_address.len() > 0 {
+                    nic.ip_address.clone()
+                } else {
+                    self.ip_address.clone()
+                },
+            })
+            .collect()
+    }
+
+    /// Returns a copy of this config for a dispatcher serving the physical port `nic`. The
+    /// copy's `nic_pci`, `mac_address`, and `ip_address` are those of the port.
+    pub fn for_nic(&self, nic: &NicPortConfig) -> ServerConfig {
+        let mut config = self.clone();
+        config.nic_pci = nic.pci.clone();
+        config.mac_address = nic.mac_address.clone();
+        config.ip_address = nic.ip_address.clone();
+        return config;
     }
 
     /// Parse `mac_address` into NetBrick's format or panic if malformed.
@@ -151,6 +1571,8 @@ pub struct ClientConfig {
 
     pub num_aggr: u32,
     pub order: u32,
+    #[serde(default)]
+    pub aggr_rounds: u32,
 
     pub combined: bool,
     pub assocs_p: usize,
@@ -159,6 +1581,86 @@ pub struct ClientConfig {
     pub yield_f: u8,
 
     pub bad_ptm: usize,
+
+    #[serde(default)]
+    pub tenant_ports: Vec<PortRangeConfig>,
+
+    #[serde(default)]
+    pub congestion_control: bool,
+
+    #[serde(default)]
+    pub arrivals: String,
+    #[serde(default)]
+    pub correct_omission: bool,
+
+    #[serde(default)]
+    pub timeout_us: u64,
+    #[serde(default)]
+    pub max_retries: Option<u32>,
+    #[serde(default)]
+    pub retry_base_us: u64,
+    #[serde(default)]
+    pub retry_backoff: f64,
+    #[serde(default)]
+    pub retry_max_us: u64,
+    #[serde(default)]
+    pub retry_jitter: f64,
+    #[serde(default)]
+    pub retry_idempotent_only: bool,
+
+    #[serde(default)]
+    pub batch_ops: usize,
+    #[serde(default)]
+    pub batch_delay_us: u64,
+
+    #[serde(default)]
+    pub cache_entries: usize,
+    #[serde(default)]
+    pub cache_ttl_us: u64,
+
+    #[serde(default)]
+    pub routing: String,
+    #[serde(default)]
+    pub virtual_nodes: u32,
+    #[serde(default)]
+    pub health_failures: u32,
+    #[serde(default)]
+    pub health_retry_us: u64,
+
+    #[serde(default)]
+    pub ycsb_workload: String,
+    #[serde(default)]
+    pub key_dist: String,
+    #[serde(default)]
+    pub scan_max: usize,
+    #[serde(default)]
+    pub verify: bool,
+
+    #[serde(default)]
+    pub trace_path: String,
+    #[serde(default)]
+    pub trace_speedup: f64,
+
+    #[serde(default)]
+    pub tao: TaoConfig,
+
+    #[serde(default)]
+    pub servers: Vec<ServerEndpointConfig>,
+
+    #[serde(default)]
+    pub emulate: Vec<EmulatedTenantConfig>,
+
+    #[serde(default)]
+    pub latency_report: String,
+
+    #[serde(default)]
+    pub warmup_secs: u64,
+    #[serde(default)]
+    pub measure_secs: u64,
+    #[serde(default)]
+    pub cooldown_secs: u64,
+    #[serde(default)]
+    pub timeseries: String,
 }
 
 impl ClientConfig {
@@ -168,6 +1670,165 @@ impl ClientConfig {
         load_config_cl("client.toml")
     }
 
+    /// Load client config from a file other than client.toml, or otherwise return a default
+    /// structure.
+    pub fn load_from(filename: &str) -> ClientConfig {
+        load_config_cl(filename)
+    }
+
+    /// Checks the config for malformed addresses, so that the client can refuse to start
+    /// instead of failing later on.
+    ///
+    /// # Return
+    ///
+    /// Ok if the config is usable. Otherwise, an error listing every problem found.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems: Vec<String> = Vec::new();
+
+        check_mac(&mut problems, "mac_address", &self.mac_address);
+        check_ip(&mut problems, "ip_address", &self.ip_address);
+        if self.servers.len() == 0 {
+            check_mac(&mut problems, "server_mac_address", &self.server_mac_address);
+            check_ip(&mut problems, "server_ip_address", &self.server_ip_address);
+        }
+
+        let mut tables = HashSet::new();
+        for server in self.servers.iter() {
+            check_mac(&mut problems, "servers.mac_address", &server.mac_address);
+            check_ip(&mut problems, "servers.ip_address", &server.ip_address);
+            for table in server.tables.iter() {
+                if !tables.insert(*table) {
+                    problems.push(format!(
+                        "Table {} is listed under more than one of `servers`.",
+                        table
+                    ));
+                }
+            }
+        }
+
+        match self.routing.as_str() {
+            "" | "hash" | "static" => {}
+            r => problems.push(format!(
+                "`routing` is {:?}, it must be hash or static.",
+                r
+            )),
+        }
+        if self.nic_pci.len() == 0 {
+            problems.push(String::from(
+                "`nic_pci` is empty, set it to the PCI address of the NIC DPDK should use.",
+            ));
+        }
+
+        if self.server_udp_ports == 0 {
+            problems.push(String::from(
+                "`server_udp_ports` is 0, set it to the number of cores on the server.",
+            ));
+        }
+
+        match self.ycsb_workload.as_str() {
+            "" | "A" | "B" | "C" | "D" | "E" | "F" => {}
+            w => problems.push(format!(
+                "`ycsb_workload` is {:?}, it must be one of A to F, or left out.",
+                w
+            )),
+        }
+
+        match self.arrivals.as_str() {
+            "" | "fixed" | "poisson" => {}
+            a => problems.push(format!(
+                "`arrivals` is {:?}, it must be fixed or poisson.",
+                a
+            )),
+        }
+
+        match self.key_dist.as_str() {
+            "" | "zipfian" | "uniform" | "latest" => {}
+            d => problems.push(format!(
+                "`key_dist` is {:?}, it must be zipfian, uniform, or latest.",
+                d
+            )),
+        }
+
+        if self.retry_jitter < 0.0 || self.retry_jitter > 1.0 {
+            problems.push(format!(
+                "`retry_jitter` is {}, it must be between 0 and 1.",
+                self.retry_jitter
+            ));
+        }
+
+        let mut emulated = HashSet::new();
+        for tenant in self.emulate.iter() {
+            if tenant.id == 0 || !emulated.insert(tenant.id) {
+                problems.push(format!(
+                    "Tenant {} under `emulate` is 0 or listed more than once.",
+                    tenant.id
+                ));
+            }
+
+            if tenant.req_rate == 0 {
+                problems.push(format!(
+                    "`req_rate` of emulated tenant {} is 0, it must be positive.",
+                    tenant.id
+                ));
+            }
+
+            match tenant.ycsb_workload.as_str() {
+                "" | "A" | "B" | "C" | "D" | "E" | "F" => {}
+                w => problems.push(format!(
+                    "`ycsb_workload` of emulated tenant {} is {:?}, it must be one of A to F.",
+                    tenant.id, w
+                )),
+            }
+
+            match tenant.key_dist.as_str() {
+                "" | "zipfian" | "uniform" | "latest" => {}
+                d => problems.push(format!(
+                    "`key_dist` of emulated tenant {} is {:?}, it must be zipfian, uniform, or \
+                     latest.",
+                    tenant.id, d
+                )),
+            }
+        }
+
+        if self.verify && self.use_invoke {
+            problems.push(String::from(
+                "`verify` is set along with `use_invoke`, only native requests can be verified.",
+            ));
+        }
+
+        if self.verify && self.value_len < 12 {
+            problems.push(format!(
+                "`value_len` is {}, it must be at least 12 for `verify`.",
+                self.value_len
+            ));
+        }
+
+        if self.measure_secs == 0 && (self.warmup_secs > 0 || self.cooldown_secs > 0) {
+            problems.push(String::from(
+                "`warmup_secs` or `cooldown_secs` is set but `measure_secs` is 0.",
+            ));
+        }
+
+        if self.measure_secs == 0 && self.timeseries.len() > 0 {
+            problems.push(String::from(
+                "`timeseries` is set but `measure_secs` is 0, there is no window to sample.",
+            ));
+        }
+
+        if self.trace_speedup < 0.0 {
+            problems.push(format!(
+                "`trace_speedup` is {}, it must be positive, or 0 to replay at the recorded pace.",
+                self.trace_speedup
+            ));
+        }
+
+        if problems.len() > 0 {
+            return Err(ConfigError { problems: problems });
+        }
+
+        return Ok(());
+    }
+
     /// Parse `mac_address` into NetBrick's format or panic if malformed.
     /// Linear time, so ideally we'd store this in ClientConfig, but TOML parsing makes that tricky.
     pub fn parse_mac(&self) -> MacAddress {
@@ -181,11 +1842,29 @@ impl ClientConfig {
         parse_mac(&self.server_mac_address)
             .expect("Missing or malformed server_mac_address field in client config.")
     }
+
+    /// Returns the servers requests are spread across: the ones listed under `servers`, or the
+    /// single server at `server_mac_address` and `server_ip_address` if none are.
+    pub fn endpoints(&self) -> Vec<ServerEndpointConfig> {
+        if self.servers.len() > 0 {
+            return self.servers.clone();
+        }
+
+        vec![ServerEndpointConfig {
+            mac_address: self.server_mac_address.clone(),
+            ip_address: self.server_ip_address.clone(),
+            tables: vec![],
+            weight: 1,
+        }]
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::parse_mac;
+    use super::{
+        parse_mac, Change, GeoTenantConfig, PartitionConfig, PortRangeConfig, RaftTableConfig,
+        ServerConfig, TenantLimitConfig, TierConfig,
+    };
 
     #[test]
     fn empty_str() {
@@ -230,4 +1909,494 @@ mod tests {
         }
     }
 
+    #[test]
+    fn port_range() {
+        let range = PortRangeConfig {
+            tenants: vec![1],
+            base: 1024,
+            count: 4,
+            queues: vec![2, 3],
+        };
+
+        assert_eq!(1024, range.port(0));
+        assert_eq!(1027, range.port(3));
+        assert_eq!(1024, range.port(4));
+
+        assert_eq!(2, range.queue(0, 8));
+        assert_eq!(3, range.queue(1, 8));
+        assert_eq!(2, range.queue(2, 8));
+
+        let range = PortRangeConfig {
+            queues: vec![],
+            ..range
+        };
+        assert_eq!(1, range.queue(9, 8));
+    }
+
+    fn valid_config() -> ServerConfig {
+        ServerConfig {
+            mac_address: String::from("3c:fd:fe:04:a1:e0"),
+            ip_address: String::from("192.168.0.2"),
+            nic_pci: String::from("0000:04:00.1"),
+            client_mac: String::from("3c:fd:fe:04:a1:f0"),
+            client_ip: String::from("192.168.0.1"),
+            install_addr: String::from("127.0.0.1:7700"),
+            cores: vec![10, 11, 12, 13],
+            ..ServerConfig::default()
+        }
+    }
+
+    #[test]
+    fn validate_ok() {
+        assert!(valid_config().validate().is_ok());
+    }
+
+    #[test]
+    fn validate_problems() {
+        let config = ServerConfig {
+            ip_address: String::from("192.168.0"),
+            cores: vec![10, 10, 19],
+            tenant_ports: vec![
+                PortRangeConfig {
+                    tenants: vec![1],
+                    base: 2,
+                    count: 4,
+                    queues: vec![],
+                },
+                PortRangeConfig {
+                    tenants: vec![2],
+                    base: 4,
+                    count: 4,
+                    queues: vec![7],
+                },
+            ],
+            ..valid_config()
+        };
+
+        let problems = config.validate().unwrap_err().problems;
+        assert_eq!(6, problems.len());
+        assert!(problems[0].starts_with("`ip_address`"));
+    }
+
+    #[test]
+    fn validate_backup() {
+        let config = ServerConfig {
+            backup_addr: String::from("192.168.0.3:7700"),
+            peer_credential: 0x5eed,
+            ..valid_config()
+        };
+        assert!(config.validate().is_ok());
+
+        let config = ServerConfig {
+            backup_addr: String::from("192.168.0.3"),
+            standby: true,
+            ..valid_config()
+        };
+        let problems = config.validate().unwrap_err().problems;
+        assert_eq!(3, problems.len());
+        assert!(problems[0].starts_with("`backup_addr`"));
+        assert!(problems[2].starts_with("`peer_credential`"));
+    }
+
+    #[test]
+    fn validate_read_replicas() {
+        let config = ServerConfig {
+            read_replicas: vec![
+                String::from("192.168.0.4:7700"),
+                String::from("192.168.0.5:7700"),
+            ],
+            peer_credential: 0x5eed,
+            ..valid_config()
+        };
+        assert!(config.validate().is_ok());
+
+        let config = ServerConfig {
+            read_replicas: vec![String::from("192.168.0.4")],
+            read_replica: true,
+            ..valid_config()
+        };
+        let problems = config.validate().unwrap_err().problems;
+        assert_eq!(3, problems.len());
+        assert!(problems[0].starts_with("`read_replicas[0]`"));
+        assert!(problems[1].starts_with("A `read_replica`"));
+        assert!(problems[2].starts_with("`peer_credential`"));
+    }
+
+    #[test]
+    fn validate_members() {
+        let config = ServerConfig {
+            standby: true,
+            peer_credential: 0x5eed,
+            member_addr: String::from("192.168.0.2:7800"),
+            members: vec![String::from("192.168.0.3:7800")],
+            failover: true,
+            ..valid_config()
+        };
+        assert!(config.validate().is_ok());
+
+        let config = ServerConfig {
+            members: vec![String::from("192.168.0.3")],
+            member_heartbeat_ms: 1000,
+            failover: true,
+            ..valid_config()
+        };
+        let problems = config.validate().unwrap_err().problems;
+        assert_eq!(4, problems.len());
+        assert!(problems[0].starts_with("`members[0]`"));
+        assert!(problems[1].starts_with("`members` are listed"));
+        assert!(problems[2].starts_with("`member_timeout_ms`"));
+        assert!(problems[3].starts_with("`failover`"));
+    }
+
+    #[test]
+    fn validate_geo_tenants() {
+        let config = ServerConfig {
+            geo_site: 1,
+            geo_tenants: vec![GeoTenantConfig {
+                tenant: 1,
+                remote: String::from("10.1.0.2:7700"),
+            }],
+            geo_peers: vec![String::from("10.1.0.2")],
+            peer_credential: 0x5eed,
+            ..valid_config()
+        };
+        assert!(config.validate().is_ok());
+
+        let config = ServerConfig {
+            geo_peers: vec![String::from("10.1.0.2:7700")],
+            ..valid_config()
+        };
+        let problems = config.validate().unwrap_err().problems;
+        assert_eq!(2, problems.len());
+        assert!(problems[0].starts_with("`geo_peers[0]`"));
+        assert!(problems[1].starts_with("`peer_credential`"));
+
+        let config = ServerConfig {
+            standby: true,
+            peer_credential: 0x5eed,
+            geo_tenants: vec![
+                GeoTenantConfig {
+                    tenant: 1,
+                    remote: String::from("10.1.0.2"),
+                },
+                GeoTenantConfig {
+                    tenant: 1,
+                    remote: String::from("10.1.0.3:7700"),
+                },
+            ],
+            ..valid_config()
+        };
+        let problems = config.validate().unwrap_err().problems;
+        assert_eq!(3, problems.len());
+        assert!(problems[0].starts_with("`geo_tenants[0]`"));
+        assert!(problems[1].starts_with("`geo_tenants[1]`"));
+        assert!(problems[2].starts_with("A `standby`"));
+    }
+
+    #[test]
+    fn validate_raft() {
+        let config = ServerConfig {
+            raft_peers: vec![
+                String::from("192.168.0.1:7900"),
+                String::from("192.168.0.2:7900"),
+                String::from("192.168.0.3:7900"),
+            ],
+            raft_id: 1,
+            raft_tables: vec![RaftTableConfig {
+                tenant: 1,
+                table: 1,
+            }],
+            raft_dir: String::from("/var/lib/splinter"),
+            raft_secret: String::from("a secret shared by the group"),
+            ..valid_config()
+        };
+        assert!(config.validate().is_ok());
+
+        let config = ServerConfig {
+            raft_peers: vec![String::from("192.168.0.1:7900"), String::from("192.168.0.2")],
+            raft_id: 2,
+            raft_tables: vec![],
+            raft_secret: String::from("short"),
+            ..valid_config()
+        };
+        let problems = config.validate().unwrap_err().problems;
+        assert_eq!(6, problems.len());
+        assert!(problems[0].starts_with("`raft_peers` lists 2"));
+        assert!(problems[1].starts_with("`raft_peers[1]`"));
+        assert!(problems[2].starts_with("`raft_id`"));
+        assert!(problems[4].starts_with("`raft_peers` is set, but there is no `raft_dir`"));
+        assert!(problems[5].starts_with("`raft_secret` is 5 bytes"));
+
+        let config = ServerConfig {
+            raft_tables: vec![RaftTableConfig {
+                tenant: 1,
+                table: 1,
+            }],
+            ..valid_config()
+        };
+        let problems = config.validate().unwrap_err().problems;
+        assert_eq!(1, problems.len());
+        assert!(problems[0].starts_with("`raft_tables`"));
+    }
+
+    #[test]
+    fn validate_partitions() {
+        let range = |tenant: u32, start: &str, ip: &str| PartitionConfig {
+            tenant: tenant,
+            start: String::from(start),
+            ip_address: String::from(ip),
+        };
+
+        let config = ServerConfig {
+            partitions: vec![range(1, "", "192.168.0.1"), range(1, "m", "192.168.0.2")],
+            ..valid_config()
+        };
+        assert!(config.validate().is_ok());
+
+        let config = ServerConfig {
+            partitions: vec![
+                range(1, "", "192.168.0.1"),
+                range(1, "", "192.168.0.2"),
+                range(2, "m", "192.168.0"),
+            ],
+            ..valid_config()
+        };
+        let problems = config.validate().unwrap_err().problems;
+        assert_eq!(3, problems.len());
+        assert!(problems[0].starts_with("`partitions[1]`"));
+        assert!(problems[1].starts_with("`partitions[2].ip_address`"));
+        assert!(problems[2].starts_with("No range of tenant 2"));
+    }
+
+    #[test]
+    fn validate_wal() {
+        let config = ServerConfig {
+            wal_dir: String::from("/var/lib/splinter"),
+            checkpoint_interval_s: 60,
+            ..valid_config()
+        };
+        assert!(config.validate().is_ok());
+
+        let config = ServerConfig {
+            wal_sync_ms: 5,
+            ..valid_config()
+        };
+        let problems = config.validate().unwrap_err().problems;
+        assert_eq!(1, problems.len());
+        assert!(problems[0].starts_with("`wal_sync_ms`"));
+    }
+
+    #[test]
+    fn validate_object_store() {
+        let config = ServerConfig {
+            object_store_url: String::from("http://192.168.0.9:9000/backups"),
+            object_store_access_key: String::from("access"),
+            object_store_secret_key: String::from("secret"),
+            backup_interval_s: 3600,
+            ..valid_config()
+        };
+        assert!(config.validate().is_ok());
+
+        let config = ServerConfig {
+            object_store_url: String::from("https://s3.example.com/backups"),
+            object_store_access_key: String::from("access"),
+            object_store_secret_key: String::from("secret"),
+            ..valid_config()
+        };
+        assert!(config.validate().is_ok());
+
+        // Certificates can only be checked against DNS names.
+        let config = ServerConfig {
+            object_store_url: String::from("https://192.168.0.9:9000/backups"),
+            object_store_access_key: String::from("access"),
+            ..valid_config()
+        };
+        let problems = config.validate().unwrap_err().problems;
+        assert_eq!(2, problems.len());
+        assert!(problems[0].starts_with("`object_store_url` = \"https://"));
+        assert!(problems[1].contains("`object_store_secret_key`"));
+
+        let config = ServerConfig {
+            backup_interval_s: 3600,
+            ..valid_config()
+        };
+        let problems = config.validate().unwrap_err().problems;
+        assert_eq!(1, problems.len());
+        assert!(problems[0].starts_with("`backup_interval_s`"));
+    }
+
+    #[test]
+    fn validate_tenant_limits() {
+        let limit = TenantLimitConfig {
+            ops_rate: 1000,
+            ..Default::default()
+        };
+        let config = ServerConfig {
+            tenant_limits: vec![
+                TenantLimitConfig {
+                    tenants: vec![1, 2],
+                    ..limit.clone()
+                },
+                TenantLimitConfig {
+                    tenants: vec![2],
+                    ops_rate: 0,
+                    ..limit.clone()
+                },
+                limit.clone(),
+                limit.clone(),
+            ],
+            ..valid_config()
+        };
+
+        let problems = config.validate().unwrap_err().problems;
+        assert_eq!(3, problems.len());
+        assert!(problems[0].starts_with("`tenant_limits[1]` has neither"));
+        assert!(problems[1].starts_with("Tenant 2"));
+        assert!(problems[2].starts_with("2 of `tenant_limits`"));
+    }
+
+    #[test]
+    fn tune() {
+        let mut config = valid_config();
+        let changes = config.tune("tx_batch = 16\nrate_limit_mark = false").unwrap();
+        assert_eq!(
+            vec![Change {
+                field: String::from("tx_batch"),
+                old: String::from("0"),
+                new: String::from("16"),
+            }],
+            changes
+        );
+        assert_eq!(16, config.tx_batch);
+
+        // Nothing is changed if any one change is rejected.
+        let problems = config.tune("tx_batch = 8\nmtu = 9000").unwrap_err().problems;
+        assert_eq!(1, problems.len());
+        assert!(problems[0].starts_with("`mtu`"));
+        assert!(config.tune("tx_batch = \"lots\"").is_err());
+        assert!(config.tune("log_levels = \"db=loud\"").is_err());
+        assert_eq!(16, config.tx_batch);
+    }
+
+    #[test]
+    fn to_toml_redacts() {
+        let config = ServerConfig {
+            raft_secret: String::from("a secret shared by the group"),
+            ..valid_config()
+        };
+        let rendered = config.to_toml();
+        assert!(rendered.contains("raft_secret = \"<redacted>\""));
+        assert!(!rendered.contains("a secret shared by the group"));
+        assert!(rendered.contains("object_store_secret_key = \"\""));
+    }
+
+    #[test]
+    fn apply_tiers() {
+        let mut config = ServerConfig {
+            tiers: vec![
+                TierConfig {
+                    name: String::from("gold"),
+                    tenants: vec![1, 2],
+                    share: 60,
+                    ops_rate: 1000,
+                    port_base: 2000,
+                    port_count: 4,
+                    ..Default::default()
+                },
+                TierConfig {
+                    name: String::from("batch"),
+                    tenants: vec![3],
+                    mem_limit: 1 << 20,
+                    ..Default::default()
+                },
+            ],
+            ..valid_config()
+        };
+        config.apply_tiers();
+        assert!(config.validate().is_ok());
+
+        assert_eq!(1, config.groups.len());
+        assert_eq!("gold", config.groups[0].name);
+        assert_eq!(vec![1, 2], config.groups[0].tenants);
+        assert_eq!(1, config.tenant_limits.len());
+        assert_eq!(1000, config.tenant_limits[0].ops_rate);
+        assert_eq!(1, config.tenant_ports.len());
+        assert_eq!(2000, config.tenant_ports[0].base);
+    }
+
+    #[test]
+    fn validate_tiers() {
+        let tier = TierConfig {
+            name: String::from("gold"),
+            tenants: vec![1],
+            ..Default::default()
+        };
+        let config = ServerConfig {
+            tiers: vec![
+                tier.clone(),
+                TierConfig {
+                    tenants: vec![],
+                    ..tier.clone()
+                },
+            ],
+            ..valid_config()
+        };
+
+        let problems = config.validate().unwrap_err().problems;
+        assert_eq!(2, problems.len());
+        assert!(problems[0].starts_with("`tiers[1]` needs a `name`"));
+        assert!(problems[1].starts_with("Tier \"gold\" has no `tenants`"));
+
+        let config = ServerConfig {
+            tiers: vec![
+                tier.clone(),
+                TierConfig {
+                    name: String::from("silver"),
+                    ..tier.clone()
+                },
+            ],
+            ..valid_config()
+        };
+
+        let problems = config.validate().unwrap_err().problems;
+        assert_eq!(1, problems.len());
+        assert!(problems[0].starts_with("Tenant 1 is in more than one tier"));
+    }
+
+    #[test]
+    fn validate_tier_memory() {
+        let tier = TierConfig {
+            name: String::from("gold"),
+            tenants: vec![1, 2],
+            mem_limit: 1 << 20,
+            ..Default::default()
+        };
+        let mut config = ServerConfig {
+            tiers: vec![tier.clone()],
+            mem_capacity: 2 << 20,
+            ..valid_config()
+        };
+        assert!(config.validate().is_ok());
+
+        config.mem_capacity = (2 << 20) - 1;
+        config.tiers[0].alloc_quota = (1 << 20) + 1;
+        let problems = config.validate().unwrap_err().problems;
+        assert_eq!(2, problems.len());
+        assert!(problems[0].starts_with("Tier \"gold\" lets an invocation allocate"));
+        assert!(problems[1].starts_with("Tiers promise their tenants 2097152 bytes"));
+    }
+
+    #[test]
+    fn reload() {
+        let mut config = valid_config();
+        let fresh = ServerConfig {
+            rate_limit: 1000,
+            cores: vec![10, 11],
+            ..valid_config()
+        };
+
+        assert_eq!(vec!["cores"], config.reload(&fresh));
+        assert_eq!(1000, config.rate_limit);
+        assert_eq!(vec![10, 11, 12, 13], config.cores);
+    }
 }
@@ -14,22 +14,25 @@
  */
 
 use std::cell::Cell;
-use std::ops::{Generator, GeneratorState};
 use std::panic::*;
 use std::rc::Rc;
 use std::sync::Arc;
 
-use super::common::PACKET_UDP_LEN;
+use super::common::{TenantId, PACKET_UDP_LEN};
 use super::context::Context;
 use super::cycles;
+use super::error::{SchedulerError, SplinterError};
 use super::ext::Extension;
+use super::replica;
+use super::span;
 use super::task::TaskState::*;
-use super::task::{Task, TaskPriority, TaskState};
+use super::task::{self, Task, TaskPriority, TaskState};
 
 use e2d2::common::EmptyMetadata;
 use e2d2::headers::UdpHeader;
 use e2d2::interface::Packet;
 
+use sandstorm::continuation::{self, Continuation, Step};
 use sandstorm::db::DB;
 
 /// A container for untrusted code that can be scheduled by the database.
@@ -46,6 +49,39 @@ pub struct Container {
     // determine when the task should be run next, and for accounting purposes.
     time: u64,
 
+    // The tenant that invoked the extension. Required by the scheduler for accounting.
+    tenant: TenantId,
+
+    // The span of the request that invoked the extension, or zero if it is not being traced.
+    span: u64,
+
+    // The deadline in cycles of the request that invoked the extension, or zero if it has none.
+    deadline: u64,
+
+    // The priority the request that invoked the extension asked for within the tenant's share.
+    level: u8,
+
+    // The name the extension was invoked under, and the time-stamps in cycles at which the task
+    // was created and at which it first ran.
+    name: String,
+    created: u64,
+    scheduled: u64,
+
+    // The time in cycles the extension spent in calls into the database. Known once it completes.
+    db_time: u64,
+
+    // The number of times the extension yielded, and whether it panicked.
+    yields: u64,
+    aborted: bool,
+
+    // The sequence number of the extension's last write, if the extension is done running but
+    // the backup has yet to acknowledge it. Zero otherwise.
+    replicating: u64,
+
+    // Set if the extension is done running, but the Raft group has yet to commit it's last
+    // write to a Raft-replicated table.
+    committing: bool,
+
     // An execution context for the task that implements the DB trait. Required
     // for the task to interact with the database.
     db: Cell<Option<Rc<Context>>>,
@@ -55,9 +91,9 @@ pub struct Container {
     // is executing in the system.
     ext: Arc<Extension>,
 
-    // The actual generator/coroutine containing the extension's code to be
+    // The actual continuation containing the extension's code to be
     // executed inside the database.
-    gen: Box<Generator<Yield = u64, Return = u64>>,
+    gen: Box<Continuation>,
 }
 
 // Implementation of methods on Container.
@@ -73,23 +109,38 @@ impl Container {
     ///              extension to interact with the database.
     /// * `ext`:     A handle to the extension that will be run inside this
     ///              container.
+    /// * `name`:    The name the extension was invoked under.
     ///
     /// # Return
     ///
     /// A container that when scheduled, runs the extension.
-    pub fn new(prio: TaskPriority, context: Rc<Context>, ext: Arc<Extension>) -> Container {
-        // The generator is initialized to a dummy. The first call to run() will
-        // retrieve the actual generator from the extension.
+    pub fn new(
+        prio: TaskPriority,
+        context: Rc<Context>,
+        ext: Arc<Extension>,
+        name: String,
+    ) -> Container {
+        // The continuation is initialized to a dummy. The first call to run() will
+        // retrieve the actual continuation from the extension.
         Container {
             state: INITIALIZED,
             priority: prio,
             time: 0,
+            tenant: context.tenant(),
+            span: span::current(),
+            deadline: context.deadline(),
+            level: task::current_level(),
+            name: name,
+            created: cycles::rdtsc(),
+            scheduled: 0,
+            db_time: 0,
+            yields: 0,
+            aborted: false,
+            replicating: 0,
+            committing: false,
             db: Cell::new(Some(context)),
             ext: ext,
-            gen: Box::new(|| {
-                yield 0;
-                return 0;
-            }),
+            gen: continuation::from_fn(|| Step::Done(0)),
         }
     }
 }
@@ -99,39 +150,64 @@ impl Task for Container {
     /// Refer to the Task trait for Documentation.
     fn run(&mut self) -> (TaskState, u64) {
         let start = cycles::rdtsc();
+        let pending = self.state == INITIALIZED || self.state == YIELDED;
 
-        // If the task has never run before, retrieve the generator for the
+        // If the task has never run before, retrieve the continuation for the
         // extension first.
         if self.state == INITIALIZED {
+            self.scheduled = start;
             let context = self.db.replace(None).unwrap();
             self.gen = self.ext.get(Rc::clone(&context) as Rc<DB>);
             self.db.set(Some(context));
         }
 
-        // Resume the task if need be. The task needs to be run/resumed only
-        // if it is in the INITIALIZED or YIELDED state. Nothing needs to be
-        // done if it has already completed, or was aborted.
-        if self.state == INITIALIZED || self.state == YIELDED {
+        // An extension that is done running only waits for the backup to acknowledge it's
+        // writes, or the Raft group to commit them, and is not resumed again.
+        if self.replicating > 0 || self.committing {
+            let context = self.db.replace(None).unwrap();
+            let committed = context.committed();
+            self.db.set(Some(context));
+
+            if replica::acked(self.replicating) && committed {
+                self.replicating = 0;
+                self.committing = false;
+                self.state = COMPLETED;
+            }
+        } else if self.state == INITIALIZED || self.state == YIELDED {
             self.state = RUNNING;
 
-            // As of 04/02/2018, calling resume() on a generator requires an unsafe block.
-            unsafe {
-                // Catch any panics thrown from within the extension.
-                let res = catch_unwind(AssertUnwindSafe(|| match self.gen.resume() {
-                    GeneratorState::Yielded(_) => {
-                        self.state = YIELDED;
-                    }
-
-                    GeneratorState::Complete(_) => {
-                        self.state = COMPLETED;
-                    }
-                }));
-
-                // If there was a panic thrown, then mark the container as COMPLETED so that it
-                // does not get run again.
-                if let Err(_) = res {
+            // Catch any panics thrown from within the extension.
+            let res = catch_unwind(AssertUnwindSafe(|| match self.gen.resume() {
+                Step::Yield(_) => {
+                    self.state = YIELDED;
+                    self.yields += 1;
+                }
+
+                Step::Done(_) => {
                     self.state = COMPLETED;
                 }
+            }));
+
+            // If there was a panic thrown, then mark the container as COMPLETED so that it
+            // does not get run again.
+            if let Err(_) = res {
+                self.state = COMPLETED;
+                self.aborted = true;
+            }
+
+            // Writes made before the extension completed, or panicked, are replicated all the
+            // same. Keep the response back until the backup or the Raft group has them.
+            if self.state == COMPLETED {
+                let context = self.db.replace(None).unwrap();
+                let seq = context.replicated();
+                let committed = context.committed();
+                self.db.set(Some(context));
+
+                if !replica::acked(seq) || !committed {
+                    self.replicating = seq;
+                    self.committing = !committed;
+                    self.state = YIELDED;
+                }
             }
         }
 
@@ -141,6 +217,18 @@ impl Task for Container {
         // Update the total execution time of the task.
         self.time += exec;
 
+        // Charge the extension for the invocation once it is over.
+        if pending && self.state == COMPLETED {
+            let context = self.db.replace(None).unwrap();
+            self.db_time = context.db_cycles();
+            let pushed = context.pushed_back();
+            self.db.set(Some(context));
+            self.ext.charge(self.time, self.db_time, self.yields, self.aborted);
+            if pushed {
+                self.ext.pushed_back();
+            }
+        }
+
         // Return the state and the amount of time the task executed for.
         return (self.state, exec);
     }
@@ -160,6 +248,61 @@ impl Task for Container {
         self.priority.clone()
     }
 
+    /// Refer to the Task trait for Documentation.
+    fn tenant(&self) -> Option<TenantId> {
+        Some(self.tenant)
+    }
+
+    /// Refer to the Task trait for Documentation.
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Refer to the Task trait for Documentation.
+    fn created(&self) -> u64 {
+        self.created
+    }
+
+    /// Refer to the Task trait for Documentation.
+    fn scheduled(&self) -> u64 {
+        self.scheduled
+    }
+
+    /// Refer to the Task trait for Documentation.
+    fn db_time(&self) -> u64 {
+        self.db_time
+    }
+
+    /// Refer to the Task trait for Documentation.
+    fn span(&self) -> u64 {
+        self.span
+    }
+
+    /// Refer to the Task trait for Documentation.
+    fn deadline(&self) -> u64 {
+        self.deadline
+    }
+
+    /// Refer to the Task trait for Documentation.
+    fn level(&self) -> u8 {
+        self.level
+    }
+
+    /// Refer to the Task trait for Documentation.
+    fn expire(&mut self) -> bool {
+        // Once the extension has started, it's writes have to be seen through.
+        if self.state != INITIALIZED {
+            return false;
+        }
+
+        let context = self.db.replace(None).unwrap();
+        context.expire();
+        self.db.set(Some(context));
+
+        self.state = COMPLETED;
+        true
+    }
+
     /// Refer to the Task trait for Documentation.
     unsafe fn tear(
         &mut self,
@@ -167,12 +310,9 @@ impl Task for Container {
         Packet<UdpHeader, EmptyMetadata>,
         Packet<UdpHeader, EmptyMetadata>,
     )> {
-        // First, drop the generator. Doing so ensures that self.db is the
+        // First, drop the continuation. Doing so ensures that self.db is the
         // only reference to the extension's execution context.
-        self.gen = Box::new(|| {
-            yield 0;
-            return 0;
-        });
+        self.gen = continuation::from_fn(|| Step::Done(0));
 
         // Next, unwrap the execution context, and, retrieve and return the
         // request and response packets.
@@ -180,15 +320,17 @@ impl Task for Container {
         match Rc::try_unwrap(context) {
             Ok(db) => {
                 let (req, res) = db.commit();
-
-                let req = req.deparse_header(PACKET_UDP_LEN as usize);
                 let res = res.deparse_header(PACKET_UDP_LEN as usize);
 
                 return Some((req, res));
             }
 
+            // Something held on to the context past the end of the extension. Report it, and drop
+            // the request, rather than the whole core.
             Err(_) => {
-                panic!("Failed to unwrap context!");
+                let err = SplinterError::from(SchedulerError::ContextInUse);
+                error!("{}", err.context(format!("tearing down `{}`", self.name)));
+                return None;
             }
         }
     }
@@ -18,18 +18,28 @@ use std::str;
 use std::sync::Arc;
 
 use super::alloc::Allocator;
+use super::common::TenantId;
+use super::cycles;
+use super::deadline;
+use super::raft;
+use super::readrep;
+use super::replica;
+use super::span;
+use super::table::{ACCESS_INVOKE, ACCESS_READ, ACCESS_WRITE};
 use super::tenant::Tenant;
-use super::wireformat::{InvokeRequest, InvokeResponse};
+use super::wireformat::{InvokeResponse, RpcStatus};
 
-use sandstorm::buf::{MultiReadBuf, ReadBuf, WriteBuf};
-use sandstorm::db::DB;
+use sandstorm::buf::{MultiGetIter, MultiReadBuf, ReadBuf, WriteBuf};
+use sandstorm::db::{QuotaExceeded, DB};
 
 use e2d2::common::EmptyMetadata;
+use e2d2::headers::UdpHeader;
 use e2d2::interface::Packet;
 
 /// The maximum number of bytes that can be allocated by an instance of an
-/// extension on the table heap.
-const MAX_ALLOC: usize = 10240;
+/// extension on the table heap, unless the invoking tenant was created with
+/// a quota of it's own.
+pub const MAX_ALLOC: usize = 10240;
 
 /// This type is passed into the init method of every extension. The methods
 /// on this type form the interface allowing extensions to read and write
@@ -39,8 +49,13 @@ pub struct Context {
     // The packet/buffer consisting of the RPC request header and payload
     // that invoked the extension. This is required to potentially pass in
     // arguments to an extension. For example, a get() extension might require
-    // a key and table identifier to be passed in.
-    request: Packet<InvokeRequest, EmptyMetadata>,
+    // a key and table identifier to be passed in. The payload starts at the
+    // request's header.
+    request: Packet<UdpHeader, EmptyMetadata>,
+
+    // The offset inside the request packet/buffer's payload at which the
+    // extension's name begins, right after the request's header.
+    name_offset: usize,
 
     // The offset inside the request packet/buffer's payload at which the
     // arguments to the extension begin.
@@ -67,6 +82,61 @@ pub struct Context {
     // The total number of bytes allocated by the extension so far
     // (on the table heap).
     allocs: Cell<usize>,
+
+    // The quota that most recently refused an allocation by the extension, if any.
+    exceeded: Cell<Option<QuotaExceeded>>,
+
+    // Whether a response written by the extension did not fit in the response packet. Nothing
+    // more is written to the response once one has not.
+    truncated: Cell<bool>,
+
+    // Whether the extension pushed the invocation back to the tenant. The response holds only
+    // the state the extension captured, and nothing more is written to it.
+    pushed: Cell<bool>,
+
+    // The total number of cycles the extension spent in calls into the database.
+    db_cycles: Cell<u64>,
+
+    // The sequence number of the last write the extension made that is being shipped to a
+    // backup, or zero if there is none. The response needs to wait for the backup to
+    // acknowledge it.
+    replicated: Cell<u64>,
+
+    // The index and term of the last write the extension made to a Raft-replicated table, if
+    // any, and whether one of it's writes to such a table was refused or lost because the server
+    // is not the leader. The response needs to wait for the group to commit the write, and fails
+    // with StatusNotLeader if one was refused or lost.
+    logged: Cell<Option<(u64, u64)>>,
+    not_leader: Cell<bool>,
+
+    // The deadline in cycles of the invoke() request, or zero if it has none, and whether the
+    // extension was never run because the deadline passed first.
+    deadline: u64,
+    expired: Cell<bool>,
+}
+
+// Adds the cycles between it's creation and it's drop to a counter. Created at the top of every
+// call into the database, so that the call's time is counted however it returns.
+struct Timer<'a> {
+    counter: &'a Cell<u64>,
+    start: u64,
+}
+
+// Implementation of methods on Timer.
+impl<'a> Timer<'a> {
+    fn new(counter: &'a Cell<u64>) -> Timer<'a> {
+        Timer {
+            counter: counter,
+            start: cycles::rdtsc(),
+        }
+    }
+}
+
+impl<'a> Drop for Timer<'a> {
+    fn drop(&mut self) {
+        let elapsed = cycles::rdtsc() - self.start;
+        self.counter.set(self.counter.get() + elapsed);
+    }
 }
 
 // Methods on Context.
@@ -76,7 +146,9 @@ impl Context {
     /// # Arguments
     ///
     /// * `req`:      The invoke() RPC request packet/buffer consisting of the
-    ///               header and payload.
+    ///               header and payload, parsed upto it's UDP header.
+    /// * `name_off`: The offset into the payload of `req` at which the
+    ///               extension's name begins.
     /// * `args_off`: The offset into the payload of `req` at which the
     ///               extension's arguments begin.
     /// * `args_len`: The length of the extension's arguments that were written
@@ -90,7 +162,8 @@ impl Context {
     /// # Result
     /// A context that can be used to invoke an extension.
     pub fn new(
-        req: Packet<InvokeRequest, EmptyMetadata>,
+        req: Packet<UdpHeader, EmptyMetadata>,
+        name_off: usize,
         args_off: usize,
         args_len: usize,
         res: Packet<InvokeResponse, EmptyMetadata>,
@@ -99,18 +172,98 @@ impl Context {
     ) -> Context {
         Context {
             request: req,
+            name_offset: name_off,
             args_offset: args_off,
             args_length: args_len,
             response: RefCell::new(res),
             tenant: tenant,
             heap: alloc,
             allocs: Cell::new(0),
+            exceeded: Cell::new(None),
+            truncated: Cell::new(false),
+            pushed: Cell::new(false),
+            db_cycles: Cell::new(0),
+            replicated: Cell::new(0),
+            logged: Cell::new(None),
+            not_leader: Cell::new(false),
+            deadline: deadline::current(),
+            expired: Cell::new(false),
+        }
+    }
+
+    /// Returns the total number of cycles the extension spent in calls into the database.
+    pub fn db_cycles(&self) -> u64 {
+        self.db_cycles.get()
+    }
+
+    /// Returns true if the extension pushed the invocation back to the tenant.
+    pub fn pushed_back(&self) -> bool {
+        self.pushed.get()
+    }
+
+    /// Returns the sequence number of the last write the extension made that is being shipped
+    /// to a backup, or zero if there is none.
+    pub fn replicated(&self) -> u64 {
+        self.replicated.get()
+    }
+
+    /// Returns true once the Raft group committed or lost the last write the extension made to
+    /// a Raft-replicated table, and true if it made none.
+    pub fn committed(&self) -> bool {
+        match self.logged.get() {
+            None => true,
+            Some((index, term)) => match raft::outcome(index, term) {
+                raft::Outcome::Pending => false,
+                raft::Outcome::Committed => true,
+                raft::Outcome::Lost => {
+                    self.not_leader.set(true);
+                    true
+                }
+            },
+        }
+    }
+
+    // Appends a write to a Raft-replicated table to the log, noting that the response needs to
+    // wait for it. Returns false if the server is not the leader.
+    fn log(&self, rec: &replica::Record) -> bool {
+        match raft::propose(rec) {
+            Some(entry) => {
+                self.logged.set(Some(entry));
+                true
+            }
+            None => {
+                self.not_leader.set(true);
+                false
+            }
         }
     }
 
+    /// Returns the identifier of the tenant that invoked the extension.
+    pub fn tenant(&self) -> TenantId {
+        self.tenant.id()
+    }
+
+    /// Returns the deadline in cycles of the invoke() request, or zero if it has none.
+    pub fn deadline(&self) -> u64 {
+        self.deadline
+    }
+
+    /// Notes that the extension will not be run, because the deadline of the invoke() request
+    /// passed before it got to. The response's status says so.
+    pub fn expire(&self) {
+        self.expired.set(true);
+    }
+
     /// This method commits any changes made by an extension to the database.
     /// It consumes the context, and returns the request and response
-    /// packets/buffers to the caller.
+    /// packets/buffers to the caller. If an allocation by the extension was
+    /// refused for a quota, the response's status identifies the quota. If the
+    /// extension pushed the invocation back, the status is StatusPushback. If a
+    /// response written by the extension did not fit in the response packet,
+    /// the status is StatusValueTooLarge. If a write to a Raft-replicated
+    /// table was refused or lost, the status is StatusNotLeader. If the
+    /// extension was never run because it's deadline passed, the status is
+    /// StatusDeadlineExceeded.
     ///
     /// # Return
     /// A tupule whose first member is the request packet/buffer for the
@@ -119,10 +272,38 @@ impl Context {
     pub unsafe fn commit(
         self,
     ) -> (
-        Packet<InvokeRequest, EmptyMetadata>,
+        Packet<UdpHeader, EmptyMetadata>,
         Packet<InvokeResponse, EmptyMetadata>,
     ) {
-        return (self.request, self.response.into_inner());
+        let mut response = self.response.into_inner();
+        match self.exceeded.get() {
+            Some(QuotaExceeded::Memory) => {
+                response.get_mut_header().common_header.status =
+                    RpcStatus::StatusMemoryExhausted;
+            }
+            Some(QuotaExceeded::Allocation) => {
+                response.get_mut_header().common_header.status = RpcStatus::StatusAllocExhausted;
+            }
+            Some(QuotaExceeded::Rate) | None => {}
+        }
+
+        if self.pushed.get() {
+            response.get_mut_header().common_header.status = RpcStatus::StatusPushback;
+        }
+
+        if self.truncated.get() {
+            response.get_mut_header().common_header.status = RpcStatus::StatusValueTooLarge;
+        }
+
+        if self.not_leader.get() {
+            response.get_mut_header().common_header.status = RpcStatus::StatusNotLeader;
+        }
+
+        if self.expired.get() {
+            response.get_mut_header().common_header.status = RpcStatus::StatusDeadlineExceeded;
+        }
+
+        return (self.request, response);
     }
 }
 
@@ -130,9 +311,19 @@ impl Context {
 impl DB for Context {
     /// Lookup the `DB` trait for documentation on this method.
     fn get(&self, table_id: u64, key: &[u8]) -> Option<ReadBuf> {
+        span::event("db.get");
+        let _timer = Timer::new(&self.db_cycles);
+
         // Lookup the database for the key value pair. If it exists, then update
         // the read set and return the value.
-        self.tenant.get_table(table_id)
+        self.tenant.table(table_id, ACCESS_INVOKE | ACCESS_READ)
+                    // Raft-replicated tables are only read at the leader.
+                    .and_then(| table | {
+                        match raft::serves(table.owner(), table_id) {
+                            true => Some(table),
+                            false => None,
+                        }
+                    })
                     .and_then(| table | { table.get(key) })
                     // The object exists in the database. Get a handle to it's
                     // key and value.
@@ -143,9 +334,17 @@ impl DB for Context {
 
     /// Lookup the `DB` trait for documentation on this method.
     fn multiget(&self, table_id: u64, key_len: u16, keys: &[u8]) -> Option<MultiReadBuf> {
+        span::event("db.multiget");
+        let _timer = Timer::new(&self.db_cycles);
+
         // Lookup the database for each key in the supplied list of keys. If all exist,
         // return a MultiReadBuf to the extension.
-        if let Some(table) = self.tenant.get_table(table_id) {
+        if let Some(table) = self.tenant.table(table_id, ACCESS_INVOKE | ACCESS_READ) {
+            // Raft-replicated tables are only read at the leader.
+            if !raft::serves(table.owner(), table_id) {
+                return None;
+            }
+
             let mut objs = Vec::new();
 
             // Iterate through the list of keys. Lookup each one of them at the database.
@@ -175,33 +374,116 @@ impl DB for Context {
         return None;
     }
 
+    /// Lookup the `DB` trait for documentation on this method.
+    fn multiget_iter(&self, table_id: u64, key_len: u16, keys: &[u8]) -> Option<MultiGetIter> {
+        span::event("db.multiget");
+        let _timer = Timer::new(&self.db_cycles);
+
+        if key_len == 0 || keys.len() % key_len as usize != 0 {
+            return None;
+        }
+
+        // Lookup the database for each key in the supplied list of keys. Keys that do not exist
+        // are yielded as such, rather than failing the lookup.
+        let table = self.tenant.table(table_id, ACCESS_INVOKE | ACCESS_READ)?;
+
+        // Raft-replicated tables are only read at the leader.
+        if !raft::serves(table.owner(), table_id) {
+            return None;
+        }
+
+        let vals = keys
+            .chunks(key_len as usize)
+            .map(|key| {
+                table
+                    .get(key)
+                    .and_then(|obj| self.heap.resolve(obj))
+                    .map(|(_k, v)| unsafe { ReadBuf::new(v) })
+            })
+            .collect();
+        Some(MultiGetIter::new(vals))
+    }
+
     /// Lookup the `DB` trait for documentation on this method.
     fn alloc(&self, table_id: u64, key: &[u8], val_len: u64) -> Option<WriteBuf> {
+        span::event("db.alloc");
+        let _timer = Timer::new(&self.db_cycles);
+
         // If the extension has exceeded it's quota, do not allow any more allocs.
-        if self.allocs.get() >= MAX_ALLOC {
+        let quota = match self.tenant.alloc_quota() {
+            0 => MAX_ALLOC,
+            quota => quota,
+        };
+        if self.allocs.get() >= quota {
+            self.exceeded.set(Some(QuotaExceeded::Allocation));
             return None;
         }
 
-        // Check if the tenant owns a table with the requested identifier.
-        // If it does, perform and return an allocation.
+        // Check if the tenant owns, or can write to, a table with the requested
+        // identifier. If it does, perform and return an allocation.
         self.tenant
-            .get_table(table_id)
-            .and_then(|_table| self.heap.raw(self.tenant.id(), table_id, key, val_len))
+            .table(table_id, ACCESS_INVOKE | ACCESS_WRITE)
+            .and_then(|table| {
+                match table.admits(self.heap.footprint(key.len(), val_len as usize)) {
+                    true => self.heap.raw(self.tenant.id(), table_id, key, val_len),
+                    false => {
+                        self.exceeded.set(Some(QuotaExceeded::Memory));
+                        None
+                    }
+                }
+            })
             .and_then(|buf| {
                 self.allocs.set(self.allocs.get() + buf.len());
                 unsafe { Some(WriteBuf::new(table_id, buf)) }
             })
     }
 
+    /// Lookup the `DB` trait for documentation on this method.
+    fn quota_exceeded(&self) -> Option<QuotaExceeded> {
+        self.exceeded.get()
+    }
+
+    /// Lookup the `DB` trait for documentation on this method.
+    fn budget_us(&self) -> Option<u64> {
+        deadline::remaining_us(self.deadline)
+    }
+
     /// Lookup the `DB` trait for documentation on this method.
     fn put(&self, buf: WriteBuf) -> bool {
+        span::event("db.put");
+        let _timer = Timer::new(&self.db_cycles);
+
         // Convert the passed in Writebuf to read only.
         let (table_id, buf) = unsafe { buf.freeze() };
 
+        // A read replica only applies the writes it is fed by it's primary.
+        if readrep::replica() {
+            return false;
+        }
+
         // If the table exists, write to the database.
-        if let Some(table) = self.tenant.get_table(table_id) {
-            return self.heap.resolve(buf.clone()).map_or(false, |(k, _v)| {
-                table.put(k, buf);
+        if let Some(table) = self.tenant.table(table_id, ACCESS_INVOKE | ACCESS_WRITE) {
+            return self.heap.resolve(buf.clone()).map_or(false, |(k, v)| {
+                let rec = replica::Record {
+                    op: replica::OP_PUT,
+                    tenant: table.owner(),
+                    table: table_id,
+                    key: &k,
+                    val: &v,
+                };
+
+                // Writes to Raft-replicated tables go through the log, and only show up in the
+                // table once the group commits them.
+                if raft::governs(table.owner(), table_id) {
+                    return self.log(&rec);
+                }
+
+                // The put takes the key's lock, so that it cannot land in between the read and
+                // write of a compare and swap on the key.
+                let seq = table.exclusive(&k, || {
+                    replica::write(&rec, || table.put(k.clone(), buf))
+                });
+                self.replicated.set(self.replicated.get().max(seq));
                 true
             });
         }
@@ -209,11 +491,156 @@ impl DB for Context {
         return false;
     }
 
+    /// Lookup the `DB` trait for documentation on this method.
+    fn cas(&self, buf: WriteBuf, expected: Option<&[u8]>) -> bool {
+        span::event("db.cas");
+        let _timer = Timer::new(&self.db_cycles);
+
+        let (table_id, buf) = unsafe { buf.freeze() };
+
+        if readrep::replica() {
+            return false;
+        }
+
+        if let Some(table) = self.tenant.table(table_id, ACCESS_INVOKE | ACCESS_WRITE) {
+            // Writes to Raft-replicated tables only show up once the group commits them, so the
+            // comparison would not be against what the write replaces.
+            if raft::governs(table.owner(), table_id) {
+                return false;
+            }
+
+            return self.heap.resolve(buf.clone()).map_or(false, |(k, v)| {
+                table.exclusive(&k, || {
+                    let current = table.get(&k).and_then(|obj| self.heap.resolve(obj));
+                    if current.as_ref().map(|&(_, ref val)| &val[..]) != expected {
+                        return false;
+                    }
+
+                    let rec = replica::Record {
+                        op: replica::OP_PUT,
+                        tenant: table.owner(),
+                        table: table_id,
+                        key: &k,
+                        val: &v,
+                    };
+                    let seq = replica::write(&rec, || table.put(k.clone(), buf));
+                    self.replicated.set(self.replicated.get().max(seq));
+                    true
+                })
+            });
+        }
+
+        return false;
+    }
+
+    /// Lookup the `DB` trait for documentation on this method. Objects are appended to in
+    /// place, in the room left after their value when they were allocated. An object without
+    /// enough room left is copied into one with as much room again as it's value, so a value
+    /// built up by many small appends is only copied a logarithmic number of times.
+    fn append(&self, table_id: u64, key: &[u8], data: &[u8]) -> bool {
+        span::event("db.append");
+        let _timer = Timer::new(&self.db_cycles);
+
+        if readrep::replica() {
+            return false;
+        }
+
+        let quota = match self.tenant.alloc_quota() {
+            0 => MAX_ALLOC,
+            quota => quota,
+        };
+        if self.allocs.get() >= quota {
+            self.exceeded.set(Some(QuotaExceeded::Allocation));
+            return false;
+        }
+
+        let table = match self.tenant.table(table_id, ACCESS_INVOKE | ACCESS_WRITE) {
+            Some(table) => table,
+            None => return false,
+        };
+
+        // Like compare and swaps, appends need to read what the write replaces.
+        if raft::governs(table.owner(), table_id) {
+            return false;
+        }
+
+        table.exclusive(key, || {
+            let current = table.get(key);
+            let room = current.as_ref().and_then(|obj| table.room(key, obj, data.len()));
+
+            let (object, region, allocated) = match (current, room) {
+                // The object has room left after it's value, which is already charged to the
+                // table's quota.
+                (Some(current), Some(region)) => {
+                    let object = unsafe { self.heap.extend(&region, &current, data) };
+                    (object, region, data.len())
+                }
+
+                (current, _) => {
+                    let val = current.and_then(|obj| self.heap.resolve(obj)).map(|(_k, v)| v);
+                    let len = val.as_ref().map_or(0, |val| val.len()) + data.len();
+                    let size = self.heap.footprint(key.len(), len);
+                    if !table.admits(size) {
+                        self.exceeded.set(Some(QuotaExceeded::Memory));
+                        return false;
+                    }
+
+                    // Room is only left if the quota admits it, as it is charged for upfront.
+                    let room = match table.admits(size + len) {
+                        true => len,
+                        false => 0,
+                    };
+                    let parts = [val.as_ref().map_or(&[][..], |val| &val[..]), data];
+                    let tenant = self.tenant.id();
+                    match self.heap.object_with_room(tenant, table_id, key, &parts, room) {
+                        Some((object, region)) => (object, region, size),
+                        None => return false,
+                    }
+                }
+            };
+            self.allocs.set(self.allocs.get() + allocated);
+
+            // The whole value is shipped to backups, so that they do not need to track room.
+            self.heap.resolve(object.clone()).map_or(false, |(k, v)| {
+                let rec = replica::Record {
+                    op: replica::OP_PUT,
+                    tenant: table.owner(),
+                    table: table_id,
+                    key: &k,
+                    val: &v,
+                };
+                let seq = replica::write(&rec, || table.put_in(k.clone(), object, region));
+                self.replicated.set(self.replicated.get().max(seq));
+                true
+            })
+        })
+    }
+
     /// Lookup the `DB` trait for documentation on this method.
     fn del(&self, table_id: u64, key: &[u8]) {
+        span::event("db.del");
+        let _timer = Timer::new(&self.db_cycles);
+
+        if readrep::replica() {
+            return;
+        }
+
         // Delete the key-value pair from the database
-        if let Some(table) = self.tenant.get_table(table_id) {
-            table.delete(key);
+        if let Some(table) = self.tenant.table(table_id, ACCESS_INVOKE | ACCESS_WRITE) {
+            let rec = replica::Record {
+                op: replica::OP_DELETE,
+                tenant: table.owner(),
+                table: table_id,
+                key: key,
+                val: &[],
+            };
+            if raft::governs(table.owner(), table_id) {
+                self.log(&rec);
+                return;
+            }
+
+            let seq = table.exclusive(key, || replica::write(&rec, || table.delete(key)));
+            self.replicated.set(self.replicated.get().max(seq));
         }
     }
 
@@ -231,13 +658,40 @@ impl DB for Context {
 
     /// Lookup the `DB` trait for documentation on this method.
     fn resp(&self, data: &[u8]) {
-        // Write the passed in data to the response packet/buffer.
-        self.response
-            .borrow_mut()
-            .add_to_payload_tail(data.len(), data)
-            .unwrap();
+        // Write the passed in data to the response packet/buffer. If it does not fit, the
+        // response fails with StatusValueTooLarge when the context is committed.
+        if self.truncated.get() || self.pushed.get() || data.len() == 0 {
+            return;
+        }
+
+        let mut response = self.response.borrow_mut();
+        if response.add_to_payload_tail(data.len(), data).is_err() {
+            self.truncated.set(true);
+        }
+    }
+
+    /// Lookup the `DB` trait for documentation on this method.
+    fn pushback(&self, state: &[u8]) -> bool {
+        if self.pushed.get() {
+            return true;
+        }
+        self.pushed.set(true);
+
+        // The state replaces whatever the extension wrote into it's response so far. If it does
+        // not fit, the response fails with StatusValueTooLarge when the context is committed.
+        let mut response = self.response.borrow_mut();
+        let len = response.get_payload().len();
+        let fits = response.remove_from_payload_tail(len).is_ok()
+            && (state.len() == 0 || response.add_to_payload_tail(state.len(), state).is_ok());
+        self.truncated.set(!fits);
+        true
     }
 
     /// Lookup the `DB` trait for documentation on this method.
-    fn debug_log(&self, _msg: &str) {}
+    fn debug_log(&self, msg: &str) {
+        // The extension's name sits on the request's payload, right before it's arguments.
+        let payload = self.request.get_payload();
+        let name = str::from_utf8(&payload[self.name_offset..self.args_offset]).unwrap_or("?");
+        debug!(target: "ext", "tenant {} extension {}: {}", self.tenant.id(), name, msg);
+    }
 }
@@ -0,0 +1,106 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+
+// Counters on the packet path, for the stats() RPC. Dispatchers count the packets they receive
+// and send, the packets they drop because their headers did not parse, the requests they could
+// not hand to a service, and the times the packet pool had no mbuf left for a response. Master
+// counts administrative RPCs that presented the wrong credential, and Master and the schedulers
+// count requests refused because their deadline passed. Counters are bumped once per burst where
+// possible, so that cores do not fight over the cache line on every packet.
+
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+/// The things counted, in the order they are reported in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Counter {
+    /// Packets received by dispatchers.
+    RxPackets = 0,
+
+    /// Packets sent by dispatchers.
+    TxPackets = 1,
+
+    /// Packets dropped because their MAC, IP or UDP header was invalid, and fragmented requests
+    /// dropped because their fragments could not be merged back together.
+    ParseErrors = 2,
+
+    /// Requests for an unknown service or operation, or that a service refused to dispatch.
+    InvalidRequests = 3,
+
+    /// Requests dropped because no mbuf could be allocated for their response.
+    MbufExhausted = 4,
+
+    /// Administrative RPCs refused because of a wrong credential, and messages from other
+    /// servers dropped because they did not authenticate.
+    AuthFailures = 5,
+
+    /// Requests refused without being executed because their deadline had passed.
+    DeadlinesExpired = 6,
+}
+
+/// The number of counters.
+pub const N_COUNTERS: usize = 7;
+
+static COUNTERS: [AtomicUsize; N_COUNTERS] = [
+    ATOMIC_USIZE_INIT,
+    ATOMIC_USIZE_INIT,
+    ATOMIC_USIZE_INIT,
+    ATOMIC_USIZE_INIT,
+    ATOMIC_USIZE_INIT,
+    ATOMIC_USIZE_INIT,
+    ATOMIC_USIZE_INIT,
+];
+
+/// Adds to a counter.
+///
+/// # Arguments
+///
+/// * `counter`: The counter.
+/// * `n`:       The amount to add to it.
+#[inline]
+pub fn add(counter: Counter, n: usize) {
+    if n > 0 {
+        COUNTERS[counter as usize].fetch_add(n, Ordering::Relaxed);
+    }
+}
+
+/// Reads every counter.
+///
+/// # Return
+///
+/// The value of every counter, in the order of `Counter`.
+pub fn snapshot() -> [u64; N_COUNTERS] {
+    let mut values = [0u64; N_COUNTERS];
+    for (value, counter) in values.iter_mut().zip(COUNTERS.iter()) {
+        *value = counter.load(Ordering::Relaxed) as u64;
+    }
+    values
+}
+
+// This module contains unit tests for the packet path counters.
+#[cfg(test)]
+mod tests {
+    use super::{add, snapshot, Counter};
+
+    // This unit test verifies that adding to a counter shows up in a snapshot. Other tests can
+    // bump counters concurrently, so only a lower bound is checked.
+    #[test]
+    fn test_add() {
+        let index = Counter::MbufExhausted as usize;
+        let before = snapshot();
+        add(Counter::MbufExhausted, 3);
+        assert!(snapshot()[index] >= before[index] + 3);
+    }
+}
@@ -0,0 +1,228 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+
+// Encryption of object values at rest. Every tenant can be handed a key of it's own through the
+// tenant() RPC, and anything that writes a tenant's values out of memory -- checkpoints, logs or
+// spilled tables -- seals them with that key, so that a copy of the disk does not reveal any
+// tenant's data without the keys, which are only ever held in memory.
+//
+// Values are sealed with ChaCha20-Poly1305 (RFC 8439) from `ring`. It needs no hardware support,
+// which makes it cheap to run on the cores processing requests, and it authenticates what it
+// encrypts: a value that was tampered with on disk, or moved under another key or table, fails
+// to open instead of turning into garbage. A key must never seal two values under the same
+// nonce, so every sealed value carries a nonce of it's own, made up of a prefix drawn at random
+// when the server starts and a counter that starts at the time the server started, in
+// nanoseconds. Neither depends on where the value is written, so LSNs or backup numbers reused
+// after a restart cannot repeat a nonce. A sealed value is laid out as:
+//
+//   nonce (NONCE_LEN) | ciphertext (as long as the value) | tag (TAG_LEN)
+
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::sync::{Once, ONCE_INIT};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ring::aead::{self, OpeningKey, SealingKey, CHACHA20_POLY1305};
+use ring::rand::{SecureRandom, SystemRandom};
+
+/// The number of bytes in a key.
+pub const KEY_LEN: usize = 32;
+
+/// The number of bytes in a nonce.
+pub const NONCE_LEN: usize = 12;
+
+/// The number of bytes in the tag authenticating a sealed value.
+pub const TAG_LEN: usize = 16;
+
+/// The number of bytes sealing adds to a value.
+pub const SEALED_OVERHEAD: usize = NONCE_LEN + TAG_LEN;
+
+/// Draws PREFIX and starts COUNTER exactly once.
+static INIT: Once = ONCE_INIT;
+
+/// The first bytes of every nonce drawn since the server started. Use `nonce()` to draw one.
+static mut PREFIX: [u8; 4] = [0u8; 4];
+
+/// The rest of the next nonce.
+static COUNTER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// A tenant's key.
+pub struct Key {
+    sealing: SealingKey,
+    opening: OpeningKey,
+}
+
+// Implementation of methods on Key.
+impl Key {
+    /// Returns a key.
+    ///
+    /// # Arguments
+    ///
+    /// * `key`: The bytes of the key.
+    pub fn new(key: &[u8; KEY_LEN]) -> Key {
+        // Keys are only refused if they are not as long as the algorithm's, which KEY_LEN is.
+        Key {
+            sealing: SealingKey::new(&CHACHA20_POLY1305, key).expect("Key of the wrong length"),
+            opening: OpeningKey::new(&CHACHA20_POLY1305, key).expect("Key of the wrong length"),
+        }
+    }
+
+    /// Returns a copy of a value, sealed under a fresh nonce.
+    ///
+    /// # Arguments
+    ///
+    /// * `table`:   The table the value belongs to.
+    /// * `obj_key`: The key of the object the value belongs to.
+    /// * `value`:   The value.
+    ///
+    /// # Return
+    ///
+    /// The sealed value, SEALED_OVERHEAD bytes longer than the value. It only opens for the same
+    /// table and object key.
+    pub fn seal(&self, table: u64, obj_key: &[u8], value: &[u8]) -> Vec<u8> {
+        let nonce = nonce();
+        let mut sealed = Vec::with_capacity(SEALED_OVERHEAD + value.len());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(value);
+        sealed.extend_from_slice(&[0u8; TAG_LEN]);
+
+        // Sealing is only refused if there is no room for the tag, which was made above. A
+        // refusal would leave the value in the clear, so it is not let through.
+        let ad = associated(table, obj_key);
+        aead::seal_in_place(&self.sealing, &nonce, &ad, &mut sealed[NONCE_LEN..], TAG_LEN)
+            .expect("No room for the tag");
+        sealed
+    }
+
+    /// Opens a value sealed by `seal()`.
+    ///
+    /// # Arguments
+    ///
+    /// * `table`:   The table the value belongs to.
+    /// * `obj_key`: The key of the object the value belongs to.
+    /// * `sealed`:  The sealed value.
+    ///
+    /// # Return
+    ///
+    /// The value, or None if it was not sealed with this key for the table and object key, or
+    /// was changed since.
+    pub fn open(&self, table: u64, obj_key: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < SEALED_OVERHEAD {
+            return None;
+        }
+
+        let (nonce, rest) = sealed.split_at(NONCE_LEN);
+        let mut value = rest.to_vec();
+        let ad = associated(table, obj_key);
+        let len = match aead::open_in_place(&self.opening, nonce, &ad, 0, &mut value) {
+            Ok(opened) => opened.len(),
+            Err(_) => return None,
+        };
+
+        value.truncate(len);
+        Some(value)
+    }
+}
+
+// Returns the data a value is sealed along with: the table it belongs to and the key of it's
+// object.
+fn associated(table: u64, obj_key: &[u8]) -> Vec<u8> {
+    let mut ad = Vec::with_capacity(8 + obj_key.len());
+    for byte in 0..8 {
+        ad.push((table >> (8 * byte)) as u8);
+    }
+    ad.extend_from_slice(obj_key);
+    ad
+}
+
+// Draws the next nonce. Nonces are never drawn twice while the server runs, as the counter only
+// grows. Across restarts, the counter starts past every value it reached before unless the clock
+// went back, and the prefix differs with high probability even if it did.
+fn nonce() -> [u8; NONCE_LEN] {
+    unsafe {
+        INIT.call_once(|| {
+            // Without randomness, the counter alone still keeps nonces apart across restarts.
+            let _ = SystemRandom::new().fill(&mut PREFIX);
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|now| now.as_secs() * 1000000000 + now.subsec_nanos() as u64)
+                .unwrap_or(0);
+            COUNTER.store(now as usize, Ordering::Relaxed);
+        });
+    }
+
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed) as u64;
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[..4].copy_from_slice(unsafe { &PREFIX });
+    for byte in 0..8 {
+        nonce[4 + byte] = (count >> (8 * byte)) as u8;
+    }
+    nonce
+}
+
+// This module contains unit tests for encryption at rest.
+#[cfg(test)]
+mod tests {
+    use super::{nonce, Key, KEY_LEN, NONCE_LEN, SEALED_OVERHEAD};
+
+    // This unit test verifies that a sealed value opens back up to the value, and that it does
+    // not carry the value in the clear.
+    #[test]
+    fn test_seal_open() {
+        let key = Key::new(&[7u8; KEY_LEN]);
+        let value: &[u8] = b"Ladies and Gentlemen of the class of '99";
+
+        let sealed = key.seal(3, b"key", value);
+        assert_eq!(value.len() + SEALED_OVERHEAD, sealed.len());
+        assert!(&sealed[NONCE_LEN..NONCE_LEN + value.len()] != value);
+        assert_eq!(Some(value.to_vec()), key.open(3, b"key", &sealed));
+
+        let empty = key.seal(3, b"key", &[]);
+        assert_eq!(Some(Vec::new()), key.open(3, b"key", &empty));
+    }
+
+    // This unit test verifies that a sealed value does not open if any byte of it was changed,
+    // if it was cut short, under another key, or for another table or object key.
+    #[test]
+    fn test_tamper() {
+        let key = Key::new(&[7u8; KEY_LEN]);
+        let sealed = key.seal(3, b"key", b"value");
+
+        for idx in 0..sealed.len() {
+            let mut tampered = sealed.clone();
+            tampered[idx] ^= 0x01;
+            assert_eq!(None, key.open(3, b"key", &tampered));
+        }
+        for len in 0..sealed.len() {
+            assert_eq!(None, key.open(3, b"key", &sealed[..len]));
+        }
+
+        assert_eq!(None, Key::new(&[8u8; KEY_LEN]).open(3, b"key", &sealed));
+        assert_eq!(None, key.open(4, b"key", &sealed));
+        assert_eq!(None, key.open(3, b"kex", &sealed));
+    }
+
+    // This unit test verifies that every value is sealed under a nonce of it's own, so that
+    // sealing the same value twice does not give away that it is the same.
+    #[test]
+    fn test_nonce() {
+        let (first, second) = (nonce(), nonce());
+        assert!(first != second);
+        assert_eq!(first[..4], second[..4]);
+
+        let key = Key::new(&[7u8; KEY_LEN]);
+        assert!(key.seal(3, b"key", b"value") != key.seal(3, b"key", b"value"));
+    }
+}
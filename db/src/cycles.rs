@@ -65,18 +65,23 @@ pub fn cycles_per_second() -> u64 {
 /// Return a 64-bit timestamp using the rdtsc instruction.
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 pub fn rdtsc() -> u64 {
-    unsafe {
-        let lo: u32;
-        let hi: u32;
-        asm!("rdtsc" : "={eax}"(lo), "={edx}"(hi) : : : "volatile");
-        (((hi as u64) << 32) | lo as u64)
-    }
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::_rdtsc;
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::_rdtsc;
+
+    unsafe { _rdtsc() }
 }
 
 pub fn to_seconds(cycles: u64) -> f64 {
     cycles as f64 / cycles_per_second() as f64
 }
 
+/// Converts a number of cycles into nanoseconds, rounding down.
+pub fn to_nanoseconds(cycles: u64) -> u64 {
+    (to_seconds(cycles) * 1e9) as u64
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
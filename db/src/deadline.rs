@@ -0,0 +1,190 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+// Request deadlines, so that an overloaded server does not spend it's time on requests whose
+// clients have already given up on them. A client sets REQUEST_FLAG_DEADLINE on a request, and
+// appends the number of microseconds it will wait for the response. Clocks at the client and
+// server need not agree: the dispatcher turns the budget into a deadline in cycles when the
+// request arrives, and hands it to the task created for the request, much like a span.
+//
+// Master refuses a request whose budget has already run out with StatusDeadlineExceeded. The
+// scheduler does the same for an invoke whose deadline passed while it's task waited to run for
+// the first time, instead of starting the extension. Native operations take about as long to
+// run as to refuse, and are run regardless. Once a task has started, it runs to completion; an
+// extension can check how much of the budget is left with `DB::budget_us()`, and cut it's work
+// short. Requests refused either way are counted under `Counter::DeadlinesExpired`.
+
+use std::cell::Cell;
+use std::mem::size_of;
+
+use super::cycles;
+use super::wireformat::{RpcRequestHeader, DEADLINE_BUDGET_LEN, REQUEST_FLAG_DEADLINE};
+
+use e2d2::common::EmptyMetadata;
+use e2d2::headers::UdpHeader;
+use e2d2::interface::Packet;
+
+thread_local! {
+    // The deadline of the request whose task is being created or run on this thread, or zero if
+    // there is none.
+    static CURRENT: Cell<u64> = Cell::new(0);
+}
+
+/// Reads the budget off an RPC request.
+///
+/// # Arguments
+///
+/// * `payload`: The request, from it's RpcRequestHeader on, without any trace context.
+///
+/// # Return
+///
+/// The number of microseconds the client will wait for a response, if the request carries a
+/// deadline.
+pub fn parse(payload: &[u8]) -> Option<u32> {
+    if payload.len() < size_of::<RpcRequestHeader>() + DEADLINE_BUDGET_LEN {
+        return None;
+    }
+
+    if payload[0] & REQUEST_FLAG_DEADLINE == 0 {
+        return None;
+    }
+
+    let off = payload.len() - DEADLINE_BUDGET_LEN;
+    let budget = payload[off..]
+        .iter()
+        .rev()
+        .fold(0u32, |acc, &byte| (acc << 8) | byte as u32);
+    Some(budget)
+}
+
+/// Strips the budget off an RPC request, so that the service it is dispatched to sees it as it
+/// would have without one, and turns it into a deadline. Requests that are spread over a chain
+/// of buffers cannot carry a budget; the flag is cleared on them, and nothing else is touched.
+///
+/// # Arguments
+///
+/// * `request`: The request, parsed upto it's UDP header, with any trace context stripped.
+///
+/// # Return
+///
+/// The time-stamp in cycles by which the request must be answered, or zero if it carried no
+/// budget.
+pub fn strip(request: &mut Packet<UdpHeader, EmptyMetadata>) -> u64 {
+    let flagged = match request.get_payload().get(0) {
+        Some(service) => service & REQUEST_FLAG_DEADLINE != 0,
+        None => false,
+    };
+    if !flagged {
+        return 0;
+    }
+
+    let budget = if request.chained_len() == 0 {
+        parse(request.get_payload())
+    } else {
+        None
+    };
+
+    request.get_mut_payload()[0] &= !REQUEST_FLAG_DEADLINE;
+    match budget {
+        Some(budget) => {
+            request.trim_payload_size(DEADLINE_BUDGET_LEN);
+            cycles::rdtsc() + budget as u64 * cycles::cycles_per_second() / 1000000
+        }
+
+        None => 0,
+    }
+}
+
+/// Sets the deadline of the request whose task is about to be created on this thread. Tasks
+/// pick this up when created.
+///
+/// # Arguments
+///
+/// * `deadline`: The deadline in cycles, or zero if the request carried none.
+#[inline]
+pub fn enter(deadline: u64) {
+    CURRENT.with(|current| current.set(deadline));
+}
+
+/// Returns the deadline of the request whose task is being created on this thread, or zero if
+/// there is none.
+#[inline]
+pub fn current() -> u64 {
+    CURRENT.with(|current| current.get())
+}
+
+/// Returns true if a deadline has passed.
+///
+/// # Arguments
+///
+/// * `deadline`: The deadline in cycles. A deadline of zero never passes.
+#[inline]
+pub fn expired(deadline: u64) -> bool {
+    deadline != 0 && cycles::rdtsc() >= deadline
+}
+
+/// Returns the number of microseconds left before a deadline.
+///
+/// # Arguments
+///
+/// * `deadline`: The deadline in cycles.
+///
+/// # Return
+///
+/// The number of microseconds left, zero once the deadline has passed, or None if the deadline
+/// is zero.
+pub fn remaining_us(deadline: u64) -> Option<u64> {
+    if deadline == 0 {
+        return None;
+    }
+
+    let left = deadline.saturating_sub(cycles::rdtsc());
+    Some(cycles::to_nanoseconds(left) / 1000)
+}
+
+// This module contains unit tests for request deadlines.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // This unit test verifies that the budget is only parsed off requests flagged as carrying
+    // one.
+    #[test]
+    fn test_parse() {
+        let mut payload = vec![0u8; size_of::<RpcRequestHeader>()];
+        payload[0] = 0x01;
+        payload.extend_from_slice(&[0x40, 0x42, 0x0f, 0x00]);
+        assert_eq!(None, parse(&payload));
+
+        payload[0] |= REQUEST_FLAG_DEADLINE;
+        assert_eq!(Some(1000000), parse(&payload));
+        assert_eq!(None, parse(&payload[..size_of::<RpcRequestHeader>() + 3]));
+    }
+
+    // This unit test verifies that a deadline of zero never passes and has no budget left,
+    // and that one in the past has none left.
+    #[test]
+    fn test_expired() {
+        assert!(!expired(0));
+        assert_eq!(None, remaining_us(0));
+
+        assert!(expired(1));
+        assert_eq!(Some(0), remaining_us(1));
+
+        let later = cycles::rdtsc() + cycles::cycles_per_second();
+        assert!(!expired(later));
+        assert!(remaining_us(later).unwrap() > 0);
+    }
+}
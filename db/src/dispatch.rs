@@ -13,21 +13,33 @@
  * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
  */
 
-use std::fmt::Display;
+use std::mem::size_of;
 use std::net::Ipv4Addr;
 use std::option::Option;
+use std::slice;
 use std::str::FromStr;
 use std::sync::Arc;
 
+use super::backend::NetBackend;
+use super::coalesce::{self, Coalesced, Coalescer};
 use super::common;
 use super::config;
+use super::counters::{self, Counter};
 use super::cycles;
+use super::deadline;
+use super::frame;
 use super::master::Master;
+use super::meter;
+use super::ratelimit::{self, RateLimiter, TenantLimiter};
 use super::rpc::*;
+use super::runtime;
 use super::sched::RoundRobin;
 use super::service::Service;
-use super::task::{Task, TaskPriority, TaskState};
+use super::span;
+use super::tap;
+use super::task::{self, Task, TaskPriority, TaskState};
 use super::wireformat;
+use super::zcopy;
 
 use super::e2d2::common::EmptyMetadata;
 use super::e2d2::headers::*;
@@ -39,7 +51,7 @@ use super::e2d2::interface::*;
 /// port.
 pub struct Dispatch<T>
 where
-    T: PacketRx + PacketTx + Display + Clone + 'static,
+    T: NetBackend,
 {
     /// A ref counted pointer to a master service. The master service
     /// implements the primary interface to the database.
@@ -56,6 +68,11 @@ where
     /// The receive queue over which this dispatcher steals RPC requests from.
     sibling_port: T,
 
+    /// Indicates whether the dispatcher should steal RPC requests from the sibling's receive
+    /// queue when there aren't any on it's own. False if every core must exclusively process
+    /// requests received on it's own queue.
+    steal: bool,
+
     /// The IP address of the server. This is required to ensure that the
     /// server does not process packets that were destined to a different
     /// machine.
@@ -65,6 +82,10 @@ where
     /// network interface in a single burst.
     max_rx_packets: u8,
 
+    /// Holds the fragments of requests that span more than one frame, until they can be merged
+    /// back together.
+    coalescer: Coalescer,
+
     /// The UDP header that will be appended to every response packet (cached
     /// here to avoid wasting time creating a new one for every response
     /// packet).
@@ -78,6 +99,41 @@ where
     /// here to avoid creating a new one for every response packet).
     resp_mac_header: MacHeader,
 
+    /// The number of pending response packets at which they are flushed out the
+    /// network port. Values of 0 and 1 flush responses as soon as they are available.
+    tx_batch: usize,
+
+    /// The maximum amount of time in cycles for which a response packet can be held
+    /// back while waiting for a batch to fill up.
+    tx_flush: u64,
+
+    /// The time-stamp in cycles at which the dispatcher first noticed pending response
+    /// packets that have not been flushed yet. Zero if there are no such packets.
+    tx_wait_start: u64,
+
+    /// The number of tasks waiting to run on the scheduler at which responses are marked as
+    /// congested. Zero if responses should never be marked.
+    overload_tasks: usize,
+
+    /// Limits the rate at which requests are admitted from every tenant and source IP address.
+    /// None if rate limiting is disabled.
+    limiter: Option<RateLimiter>,
+
+    /// If true, requests over the rate limit are answered with a StatusRateLimited response
+    /// instead of being silently dropped.
+    limit_mark: bool,
+
+    /// Limits the rate of requests and request bytes admitted from every tenant with a limit
+    /// configured, shared with every other dispatcher. None if no tenant is limited.
+    tenant_limiter: Option<Arc<TenantLimiter>>,
+
+    /// The rate, burst and tenant limits the limiters above were created with. Limiters are
+    /// only recreated when these change, since that forgets every source's credit.
+    limits: (u64, u64, Vec<config::TenantLimitConfig>),
+
+    /// The generation of the running config the settings above were taken from.
+    generation: usize,
+
     /// The number of response packets that were sent out by the dispatcher in
     /// the last measurement interval.
     responses_sent: u64,
@@ -104,7 +160,7 @@ where
 
 impl<T> Dispatch<T>
 where
-    T: PacketRx + PacketTx + Display + Clone + 'static,
+    T: NetBackend,
 {
     /// This function creates and returns a requests-dispatcher which can be
     /// added to a Netbricks scheduler.
@@ -181,11 +237,26 @@ where
             scheduler: sched,
             network_port: net_port.clone(),
             sibling_port: sib_port.clone(),
+            steal: !config.exclusive_rx,
             network_ip_addr: ip_src_addr,
             max_rx_packets: rx_batch_size,
+            coalescer: Coalescer::new(cycles::cycles_per_second() / 1000 * coalesce::TIMEOUT_MS),
             resp_udp_header: udp_header,
             resp_ip_header: ip_header,
             resp_mac_header: mac_header,
+            tx_batch: config.tx_batch,
+            tx_flush: (cycles::cycles_per_second() / 1000000) * config.tx_flush_us,
+            tx_wait_start: 0,
+            overload_tasks: config.overload_tasks,
+            limiter: if config.rate_limit > 0 {
+                Some(RateLimiter::new(config.rate_limit, config.rate_burst))
+            } else {
+                None
+            },
+            limit_mark: config.rate_limit_mark,
+            tenant_limiter: ratelimit::tenant_limiter(&config.tenant_limits),
+            limits: (config.rate_limit, config.rate_burst, config.tenant_limits.clone()),
+            generation: runtime::generation(),
             responses_sent: 0,
             measurement_start: cycles::rdtsc(),
             measurement_stop: 0,
@@ -215,13 +286,14 @@ where
             mbuf_vector.set_len(self.max_rx_packets as usize);
 
             // Try to receive packets from the network port.
-            match self.network_port.recv(&mut mbuf_vector[..]) {
+            match self.network_port.rx_burst(&mut mbuf_vector[..]) {
                 // The receive call returned successfully.
                 Ok(num_received) => {
                     if num_received == 0 {
                         // No packets were available for receive.
                         return None;
                     }
+                    counters::add(Counter::RxPackets, num_received as usize);
 
                     // Allocate a vector for the received packets.
                     let mut recvd_packets = Vec::<Packet<NullHeader, EmptyMetadata>>::with_capacity(
@@ -238,6 +310,7 @@ where
                     // bumped up here. Hence, the call to
                     // packet_from_mbuf_no_increment().
                     for mbuf in mbuf_vector.iter_mut() {
+                        tap::capture(tap::Direction::Rx, *mbuf);
                         recvd_packets.push(packet_from_mbuf_no_increment(*mbuf, 0));
                     }
 
@@ -253,6 +326,67 @@ where
         }
     }
 
+    /// This function picks up the transmit batching, overload and rate limiting settings of the
+    /// config the server runs with, once it changes.
+    fn retune(&mut self) {
+        let generation = runtime::generation();
+        if generation == self.generation {
+            return;
+        }
+        self.generation = generation;
+
+        let config = runtime::current();
+        self.tx_batch = config.tx_batch;
+        self.tx_flush = (cycles::cycles_per_second() / 1000000) * config.tx_flush_us;
+        self.overload_tasks = config.overload_tasks;
+        self.limit_mark = config.rate_limit_mark;
+
+        let limits = (config.rate_limit, config.rate_burst, config.tenant_limits.clone());
+        if limits != self.limits {
+            self.limiter = if config.rate_limit > 0 {
+                Some(RateLimiter::new(config.rate_limit, config.rate_burst))
+            } else {
+                None
+            };
+            self.tenant_limiter = ratelimit::tenant_limiter(&config.tenant_limits);
+            self.limits = limits;
+        }
+    }
+
+    /// This function decides whether pending response packets should be flushed out the network
+    /// port. Responses are flushed once `tx_batch` of them are pending, or when the oldest of them
+    /// has been pending for longer than `tx_flush` cycles.
+    ///
+    /// # Return
+    ///
+    /// True if pending responses should be sent out right away.
+    fn should_flush(&mut self) -> bool {
+        let pending = self.scheduler.num_responses();
+        if pending == 0 {
+            self.tx_wait_start = 0;
+            return false;
+        }
+
+        if pending >= self.tx_batch {
+            self.tx_wait_start = 0;
+            return true;
+        }
+
+        // The batch hasn't filled up yet. Start the flush timer if this is the first time these
+        // responses were seen, and flush if they have been waiting for too long.
+        let now = cycles::rdtsc();
+        if self.tx_wait_start == 0 {
+            self.tx_wait_start = now;
+        }
+
+        if now - self.tx_wait_start >= self.tx_flush {
+            self.tx_wait_start = 0;
+            return true;
+        }
+
+        return false;
+    }
+
     /// This function attempts to steal a batch of packets from the
     /// dispatcher's network port.
     ///
@@ -272,13 +406,14 @@ where
             mbuf_vector.set_len(self.max_rx_packets as usize);
 
             // Try to receive packets from the sibling.
-            match self.sibling_port.recv(&mut mbuf_vector[..]) {
+            match self.sibling_port.rx_burst(&mut mbuf_vector[..]) {
                 // The receive call returned successfully.
                 Ok(num_received) => {
                     if num_received == 0 {
                         // No packets were available for receive.
                         return None;
                     }
+                    counters::add(Counter::RxPackets, num_received as usize);
 
                     // Allocate a vector for the received packets.
                     let mut recvd_packets = Vec::<Packet<NullHeader, EmptyMetadata>>::with_capacity(
@@ -295,6 +430,7 @@ where
                     // bumped up here. Hence, the call to
                     // packet_from_mbuf_no_increment().
                     for mbuf in mbuf_vector.iter_mut() {
+                        tap::capture(tap::Direction::Rx, *mbuf);
                         recvd_packets.push(packet_from_mbuf_no_increment(*mbuf, 0));
                     }
 
@@ -317,6 +453,13 @@ where
     ///
     /// * `packets`: A vector of packets to be sent out the network, parsed upto their UDP headers.
     fn try_send_packets(&mut self, mut packets: Vec<Packet<IpHeader, EmptyMetadata>>) {
+        // If work is piling up on the scheduler, tell clients to back off.
+        if self.overload_tasks > 0 && self.scheduler.num_waiting() >= self.overload_tasks {
+            for packet in packets.iter_mut() {
+                mark_rpc_congested(packet);
+            }
+        }
+
         // This unsafe block is required to extract the underlying Mbuf's from
         // the passed in batch of packets, and send them out the network port.
         unsafe {
@@ -325,17 +468,20 @@ where
 
             // Extract Mbuf's from the batch of packets.
             while let Some(packet) = packets.pop() {
+                span::sent(packet.get_payload().as_ptr() as usize);
+                tap::capture(tap::Direction::Tx, packet.get_mbuf());
                 mbufs.push(packet.get_mbuf());
             }
 
             // Send out the above MBuf's.
-            match self.network_port.send(&mut mbufs) {
+            match self.network_port.tx_burst(&mut mbufs) {
                 Ok(sent) => {
                     if sent < num_packets as u32 {
                         warn!("Was able to send only {} of {} packets.", sent, num_packets);
                     }
 
                     self.responses_sent += mbufs.len() as u64;
+                    counters::add(Counter::TxPackets, sent as usize);
                 }
 
                 Err(ref err) => {
@@ -375,6 +521,75 @@ where
         }
     }
 
+    /// This method coalesces the fragments of requests that span more than one frame. Frames
+    /// that are not IPv4 fragments are passed through untouched. Fragments are held by the
+    /// dispatcher's coalescer and their packets freed, and once every fragment of a request
+    /// arrived, they are merged back into a single packet that is passed on in their place.
+    ///
+    /// Fragments stolen from the sibling's receive queue are held apart from the dispatcher's
+    /// own, so a request whose fragments are split across the two is dropped once it times out.
+    /// A request is also dropped if it's fragments are malformed or overlap, if it does not fit
+    /// into a single packet once merged, or if too many requests are being merged at once.
+    ///
+    /// # Arguments
+    ///
+    /// * `packets`: A vector of packets that were received from DPDK and
+    ///              wrapped up in Netbrick's Packet<NullHeader, EmptyMetadata>
+    ///              type.
+    ///
+    /// # Return
+    ///
+    /// A vector of packets, none of which are fragments.
+    fn coalesce(
+        &mut self,
+        mut packets: Vec<Packet<NullHeader, EmptyMetadata>>,
+    ) -> Vec<Packet<NullHeader, EmptyMetadata>> {
+        // This vector will hold the set of packets that are not fragments, and merged requests.
+        let mut whole_packets = Vec::with_capacity(self.max_rx_packets as usize);
+        // This vector will hold the set of fragments, which are freed once they were copied.
+        let mut ignore_packets = Vec::with_capacity(self.max_rx_packets as usize);
+
+        let now = cycles::rdtsc();
+        let mut dropped = self.coalescer.expire(now);
+        while let Some(packet) = packets.pop() {
+            if !coalesce::is_fragment(packet.get_payload()) {
+                whole_packets.push(packet);
+                continue;
+            }
+
+            // Fragments are only ever short, so one that spans more than a single buffer is not
+            // worth holding.
+            let coalesced = match packet.chained_len() {
+                0 => self.coalescer.push(packet.get_payload(), now),
+                _ => Coalesced::Refused,
+            };
+            ignore_packets.push(packet);
+
+            match coalesced {
+                Coalesced::Pending => {}
+
+                Coalesced::Whole(frame) => match new_packet() {
+                    Some(mut merged) => match merged.add_to_payload_tail(frame.len(), &frame) {
+                        Ok(()) => whole_packets.push(merged),
+                        Err(_) => {
+                            dropped += 1;
+                            merged.free_packet();
+                        }
+                    },
+                    None => counters::add(Counter::MbufExhausted, 1),
+                },
+
+                Coalesced::Refused => dropped += 1,
+            }
+        }
+
+        // Drop the fragments, and count the requests that could not be merged.
+        counters::add(Counter::ParseErrors, dropped);
+        self.free_packets(ignore_packets);
+
+        return whole_packets;
+    }
+
     /// This method parses the MAC headers on a vector of input packets.
     ///
     /// This method takes in a vector of packets that were received from
@@ -382,8 +597,8 @@ where
     /// headers on the underlying MBufs, effectively rewrapping the packets
     /// into a new type (Packet<MacHeader, EmptyMetadata>).
     ///
-    /// Any packets with an unexpected ethertype on the parsed header are
-    /// dropped by this method.
+    /// Any packets too short to hold a MAC header, or with an unexpected
+    /// ethertype on it are dropped by this method.
     ///
     /// # Arguments
     ///
@@ -395,7 +610,6 @@ where
     ///
     /// A vector of valid packets with their MAC headers parsed. The packets are of type
     /// `Packet<MacHeader, EmptyMetadata>`.
-    #[allow(unused_assignments)]
     fn parse_mac_headers(
         &self,
         mut packets: Vec<Packet<NullHeader, EmptyMetadata>>,
@@ -405,22 +619,12 @@ where
         // This vector will hold the set of invalid parsed packets.
         let mut ignore_packets = Vec::with_capacity(self.max_rx_packets as usize);
 
-        // Parse the MacHeader on each packet, and check if it is valid.
+        // Check the MAC header on each packet off it's bytes, and parse it if it is valid. The
+        // ethertype on it must match what the server expects.
         while let Some(packet) = packets.pop() {
-            let mut valid: bool = true;
-            let packet = packet.parse_header::<MacHeader>();
-
-            // The following block borrows the MAC header from the parsed
-            // packet, and checks if the ethertype on it matches what the
-            // server expects.
-            {
-                let mac_header: &MacHeader = packet.get_header();
-                valid = common::PACKET_ETYPE.eq(&mac_header.etype());
-            }
-
-            match valid {
+            match frame::check_mac(packet.get_payload()) {
                 true => {
-                    parsed_packets.push(packet);
+                    parsed_packets.push(packet.parse_header::<MacHeader>());
                 }
 
                 false => {
@@ -430,6 +634,7 @@ where
         }
 
         // Drop any invalid packets.
+        counters::add(Counter::ParseErrors, ignore_packets.len());
         self.free_packets(ignore_packets);
 
         return parsed_packets;
@@ -443,7 +648,8 @@ where
     ///     - It is not an IPv4 packet,
     ///     - The TTL field on it is 0,
     ///     - It's destination IP address does not match that of the server,
-    ///     - It's IP header and payload are not long enough.
+    ///     - It's IP header and payload are not long enough, or run past the
+    ///       end of the frame.
     ///
     /// # Arguments
     ///
@@ -454,7 +660,6 @@ where
     ///
     /// A vector of packets with their IP headers parsed, and wrapped up in Netbrick's
     /// `Packet<MacHeader, EmptyMetadata>` type.
-    #[allow(unused_assignments)]
     fn parse_ip_headers(
         &self,
         mut packets: Vec<Packet<MacHeader, EmptyMetadata>>,
@@ -464,29 +669,18 @@ where
         // This vector will hold the set of invalid parsed packets.
         let mut ignore_packets = Vec::with_capacity(self.max_rx_packets as usize);
 
-        // Parse the IpHeader on each packet, and check if it is valid.
+        // Check the IP header on each packet off it's bytes, and parse it if it is valid (see
+        // `frame::check_ip()`).
         while let Some(packet) = packets.pop() {
-            let mut valid: bool = true;
-            let packet = packet.parse_header::<IpHeader>();
-
-            // The following block borrows the Ip header from the parsed
-            // packet, and checks if it is valid. A packet is considered
-            // valid if:
-            //      - It is an IPv4 packet,
-            //      - It's TTL (time to live) is greater than zero,
-            //      - It is not long enough,
-            //      - It's destination Ip address matches that of the server.
-            {
-                const MIN_LENGTH_IP: u16 = common::PACKET_IP_LEN + 2;
-                let ip_header: &IpHeader = packet.get_header();
-                valid = (ip_header.version() == 4) && (ip_header.ttl() > 0)
-                    && (ip_header.length() >= MIN_LENGTH_IP)
-                    && (ip_header.dst() == self.network_ip_addr);
-            }
+            let valid = frame::check_ip(
+                packet.get_payload(),
+                packet.chained_len(),
+                self.network_ip_addr,
+            );
 
             match valid {
                 true => {
-                    parsed_packets.push(packet);
+                    parsed_packets.push(packet.parse_header::<IpHeader>());
                 }
 
                 false => {
@@ -496,18 +690,144 @@ where
         }
 
         // Drop any invalid packets.
+        counters::add(Counter::ParseErrors, ignore_packets.len());
         self.free_packets(ignore_packets);
 
         return parsed_packets;
     }
 
+    /// This method rate limits a vector of packets that have had their IP headers parsed. Every
+    /// packet is charged to the tenant on it's RPC header, and to the pair of that tenant and it's
+    /// source IP address. Packets over their tenant's limits are answered with a
+    /// StatusRateLimited response. Packets over their source's limit are dropped, or answered the
+    /// same way if the dispatcher was configured to mark them. Either way, no work is done on
+    /// their behalf.
+    ///
+    /// # Arguments
+    ///
+    /// * `packets`: A vector of packets with their IP headers parsed.
+    ///
+    /// # Return
+    ///
+    /// A vector of packets that are within their tenant's and source's rate limits.
+    fn rate_limit(
+        &mut self,
+        mut packets: Vec<Packet<IpHeader, EmptyMetadata>>,
+    ) -> Vec<Packet<IpHeader, EmptyMetadata>> {
+        if self.limiter.is_none() && self.tenant_limiter.is_none() {
+            return packets;
+        }
+
+        let now = cycles::rdtsc();
+        let mut admitted = Vec::with_capacity(packets.len());
+        let mut throttled = Vec::new();
+        let mut excess = Vec::new();
+
+        {
+            let limiter = &mut self.limiter;
+            let tenants = &self.tenant_limiter;
+            while let Some(packet) = packets.pop() {
+                // The tenant is read straight off the payload, since the UDP header has not
+                // been parsed yet. Packets too short to carry one are left for the parsers
+                // to drop.
+                let src = packet.get_header().src();
+                let len = packet.get_payload().len() as u64;
+                let tenant = match parse_rpc_tenant(packet.get_payload(), size_of::<UdpHeader>()) {
+                    Some(tenant) => tenant,
+                    None => {
+                        admitted.push(packet);
+                        continue;
+                    }
+                };
+
+                if let Some(ref tenants) = *tenants {
+                    if !tenants.admit(tenant, len, now) {
+                        throttled.push(packet);
+                        continue;
+                    }
+                }
+
+                if let Some(ref mut limiter) = *limiter {
+                    if !limiter.admit(tenant, src, now) {
+                        excess.push(packet);
+                        continue;
+                    }
+                }
+
+                admitted.push(packet);
+            }
+        }
+
+        if self.limit_mark {
+            throttled.append(&mut excess);
+        } else {
+            self.free_packets(excess);
+        }
+
+        if throttled.len() == 0 {
+            return admitted;
+        }
+
+        // Answer every throttled request with a response consisting of only a common header.
+        // These responses go out along with the next batch.
+        let mut responses = Vec::with_capacity(throttled.len());
+        while let Some(request) = throttled.pop() {
+            let response = create_packet(
+                &self.resp_mac_header,
+                &self.resp_ip_header,
+                &self.resp_udp_header,
+            );
+            let mut response = match response {
+                Ok(response) => response,
+                Err(_) => {
+                    counters::add(Counter::MbufExhausted, 1);
+                    request.free_packet();
+                    continue;
+                }
+            };
+
+            let request = request.parse_header::<UdpHeader>();
+            response
+                .get_mut_header()
+                .set_dst_port(request.get_header().src_port());
+
+            let mut header = wireformat::RpcResponseHeader::new(
+                parse_rpc_stamp(&request),
+                parse_rpc_opcode(&request),
+                parse_rpc_tenant(request.get_payload(), 0).unwrap_or(0),
+            );
+            header.status = wireformat::RpcStatus::StatusRateLimited;
+
+            let header = unsafe {
+                slice::from_raw_parts(
+                    &header as *const wireformat::RpcResponseHeader as *const u8,
+                    size_of::<wireformat::RpcResponseHeader>(),
+                )
+            };
+            if response.add_to_payload_tail(header.len(), header).is_err() {
+                response.free_packet();
+            } else {
+                match fixup_header_length_fields(response) {
+                    Ok(response) => responses.push(response),
+                    Err(response) => response.free_packet(),
+                }
+            }
+
+            request.free_packet();
+        }
+        self.scheduler.append_resps(&mut responses);
+
+        return admitted;
+    }
+
     /// This function parses the UDP headers on a vector of packets that have
     /// had their IP headers parsed. A vector of valid packets with their UDP
     /// headers parsed is returned.
     ///
     /// A packet is dropped by this method if:
     ///     - It's destination UDP port does not match that of the server,
-    ///     - It's UDP header plus payload is not long enough.
+    ///     - It's UDP header plus payload is not long enough, or runs past the
+    ///       end of the frame.
     ///
     /// # Arguments
     ///
@@ -517,7 +837,6 @@ where
     ///
     /// A vector of packets with their UDP headers parsed. These packets are wrapped in Netbrick's
     /// `Packet<UdpHeader, EmptyMetadata>` type.
-    #[allow(unused_assignments)]
     fn parse_udp_headers(
         &self,
         mut packets: Vec<Packet<IpHeader, EmptyMetadata>>,
@@ -527,23 +846,11 @@ where
         // This vector will hold the set of invalid parsed packets.
         let mut ignore_packets = Vec::with_capacity(self.max_rx_packets as usize);
 
-        // Parse the UdpHeader on each packet, and check if it is valid.
+        // Check the UDP header on each packet off it's bytes, and parse it if it is valid.
         while let Some(packet) = packets.pop() {
-            let mut valid: bool = true;
-            let packet = packet.parse_header::<UdpHeader>();
-
-            // This block borrows the UDP header from the parsed packet, and
-            // checks if it is valid. A packet is considered valid if:
-            //      - It is not long enough,
-            {
-                const MIN_LENGTH_UDP: u16 = common::PACKET_UDP_LEN + 2;
-                let udp_header: &UdpHeader = packet.get_header();
-                valid = udp_header.length() >= MIN_LENGTH_UDP;
-            }
-
-            match valid {
+            match frame::check_udp(packet.get_payload(), packet.chained_len()) {
                 true => {
-                    parsed_packets.push(packet);
+                    parsed_packets.push(packet.parse_header::<UdpHeader>());
                 }
 
                 false => {
@@ -553,6 +860,7 @@ where
         }
 
         // Drop any invalid packets.
+        counters::add(Counter::ParseErrors, ignore_packets.len());
         self.free_packets(ignore_packets);
 
         return parsed_packets;
@@ -572,16 +880,30 @@ where
         // operation.
         let mut ignore_packets = Vec::with_capacity(self.max_rx_packets as usize);
 
-        while let Some(request) = requests.pop() {
+        while let Some(mut request) = requests.pop() {
+            // Strip any trace context, budget and priority off the request, so that the service
+            // never sees them. The budget is turned into a deadline as soon as the request is
+            // picked up.
+            let traced = span::strip(&mut request);
+            let deadline = deadline::strip(&mut request);
+            let level = strip_rpc_priority(&mut request);
+
             // Allocate a packet for the response upfront, and add in MAC, IP, and UDP headers.
-            let mut response = new_packet()
-                .expect("ERROR: Failed to allocate packet for response!")
-                .push_header(&self.resp_mac_header)
-                .expect("ERROR: Failed to add response MAC header")
-                .push_header(&self.resp_ip_header)
-                .expect("ERROR: Failed to add response IP header")
-                .push_header(&self.resp_udp_header)
-                .expect("ERROR: Failed to add response UDP header");
+            // If the packet pool has run dry, the request is dropped and left to the client to
+            // retry.
+            let response = create_packet(
+                &self.resp_mac_header,
+                &self.resp_ip_header,
+                &self.resp_udp_header,
+            );
+            let mut response = match response {
+                Ok(response) => response,
+                Err(_) => {
+                    counters::add(Counter::MbufExhausted, 1);
+                    ignore_packets.push(request);
+                    continue;
+                }
+            };
 
             // Set the destination port on the response UDP header.
             response
@@ -591,12 +913,41 @@ where
             if parse_rpc_service(&request) == wireformat::Service::MasterService {
                 // The request is for Master, get it's opcode, and call into Master.
                 let opcode = parse_rpc_opcode(&request);
-                match self.master_service.dispatch(opcode, request, response) {
+                if meter::enabled() {
+                    if let Some(tenant) = parse_rpc_tenant(request.get_payload(), 0) {
+                        let len = size_of::<UdpHeader>() + request.get_payload().len();
+                        let invoke = opcode == wireformat::OpCode::SandstormInvokeRpc;
+                        meter::request(tenant, len, invoke);
+                    }
+                }
+
+                // Open a span for a traced request. The task created for it picks the span up.
+                let span = match traced {
+                    Some(ref context) => {
+                        let tenant = parse_rpc_tenant(request.get_payload(), 0).unwrap_or(0);
+                        let opcode = request.get_payload()[1];
+                        span::begin(context, tenant, parse_rpc_stamp(&request), opcode)
+                    }
+                    None => 0,
+                };
+
+                span::enter(span);
+                deadline::enter(deadline);
+                task::enter_level(level);
+                let dispatched = self.master_service.dispatch(opcode, request, response);
+                task::enter_level(0);
+                deadline::enter(0);
+                span::enter(0);
+
+                match dispatched {
                     Ok(task) => {
                         self.scheduler.enqueue(task);
                     }
 
                     Err((req, res)) => {
+                        span::dropped(span);
+                        counters::add(Counter::InvalidRequests, 1);
+
                         // Master returned an error. The allocated request and response packets
                         // need to be freed up.
                         ignore_packets.push(req);
@@ -606,6 +957,7 @@ where
             } else {
                 // The request is not for Master. The allocated request and response packets need
                 // to be freed up.
+                counters::add(Counter::InvalidRequests, 1);
                 ignore_packets.push(request);
                 ignore_packets.push(response);
             }
@@ -620,27 +972,37 @@ where
     /// the network port.
     #[inline]
     fn poll(&mut self) {
-        // First, send any pending response packets out.
-        let responses = self.scheduler.responses();
-        if responses.len() > 0 {
+        self.retune();
+
+        // First, send any pending response packets out, and release any values that zero-copy
+        // responses sent out earlier were referencing.
+        if self.should_flush() {
+            let responses = self.scheduler.responses();
             self.try_send_packets(responses);
         }
+        zcopy::reclaim();
 
-        // Next, try to receive packets from the network.
+        // Next, try to receive packets from the network. Requests that span more than one frame
+        // are merged back together before any headers are parsed, so that reassembly never runs
+        // on a worker's time.
         if let Some(packets) = self.try_receive_packets() {
             // Perform basic network processing on the received packets.
+            let packets = self.coalesce(packets);
             let mut packets = self.parse_mac_headers(packets);
             let mut packets = self.parse_ip_headers(packets);
+            let mut packets = self.rate_limit(packets);
             let mut packets = self.parse_udp_headers(packets);
 
             // Dispatch these packets to the appropriate service.
             self.dispatch_requests(packets);
-        } else {
+        } else if self.steal {
             // There were no packets at the receive queue. Try to steal some from the sibling.
             if let Some(stolen) = self.try_steal_packets() {
                 // Perform basic network processing on the stolen packets.
+                let stolen = self.coalesce(stolen);
                 let mut stolen = self.parse_mac_headers(stolen);
                 let mut stolen = self.parse_ip_headers(stolen);
+                let mut stolen = self.rate_limit(stolen);
                 let mut stolen = self.parse_udp_headers(stolen);
 
                 // Dispatch these packets to the appropriate service.
@@ -654,7 +1016,7 @@ where
 // database.
 impl<T> Task for Dispatch<T>
 where
-    T: PacketRx + PacketTx + Display + Clone + 'static,
+    T: NetBackend,
 {
     /// Refer to the `Task` trait for Documentation.
     fn run(&mut self) -> (TaskState, u64) {
@@ -688,6 +1050,55 @@ where
         self.priority.clone()
     }
 
+    /// Refer to the `Task` trait for Documentation.
+    fn tenant(&self) -> Option<common::TenantId> {
+        // The Dispatch task does not run on behalf of any tenant.
+        None
+    }
+
+    /// Refer to the `Task` trait for Documentation.
+    fn name(&self) -> &str {
+        "dispatch"
+    }
+
+    /// Refer to the `Task` trait for Documentation.
+    fn created(&self) -> u64 {
+        // The Dispatch task lives as long as the scheduler, so it's age says nothing.
+        0
+    }
+
+    /// Refer to the `Task` trait for Documentation.
+    fn scheduled(&self) -> u64 {
+        0
+    }
+
+    /// Refer to the `Task` trait for Documentation.
+    fn db_time(&self) -> u64 {
+        0
+    }
+
+    /// Refer to the `Task` trait for Documentation.
+    fn span(&self) -> u64 {
+        // The Dispatch task does not service any one request.
+        0
+    }
+
+    /// Refer to the `Task` trait for Documentation.
+    fn deadline(&self) -> u64 {
+        // The Dispatch task does not service any one request.
+        0
+    }
+
+    /// Refer to the `Task` trait for Documentation.
+    fn level(&self) -> u8 {
+        0
+    }
+
+    /// Refer to the `Task` trait for Documentation.
+    fn expire(&mut self) -> bool {
+        false
+    }
+
     /// Refer to the `Task` trait for Documentation.
     unsafe fn tear(
         &mut self,
@@ -0,0 +1,98 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+// Draining the server before it shuts down, so that a rolling restart does not show up as a
+// burst of timeouts at clients. A SIGTERM starts the drain. From then on, dispatch refuses new
+// requests with StatusDraining, which clients retry, and the server reports itself as not
+// ready. The watchdog waits for tasks already taken on to complete, and for their responses to
+// be sent, upto `drain_timeout_ms`. It then writes out the log, so that no acknowledged write is
+// lost, along with any metering records and spans, and exits.
+
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// The number of milliseconds a drain waits for work in flight, if none was configured.
+pub const DEFAULT_TIMEOUT_MS: u64 = 5000;
+
+/// The number of milliseconds between checks for work in flight.
+const POLL_MS: u64 = 1;
+
+/// Set once the server starts draining. Never cleared; a drained server exits.
+static DRAINING: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Starts draining the server.
+///
+/// # Return
+///
+/// True if this call started the drain, false if it had already been started.
+pub fn begin() -> bool {
+    !DRAINING.swap(true, Ordering::AcqRel)
+}
+
+/// Returns true if the server is draining, and must not take on new requests.
+#[inline]
+pub fn draining() -> bool {
+    DRAINING.load(Ordering::Relaxed)
+}
+
+/// Waits for the work in flight to run out.
+///
+/// # Arguments
+///
+/// * `timeout_ms`: The number of milliseconds to wait for, `DEFAULT_TIMEOUT_MS` if zero.
+/// * `in_flight`:  Called to count the tasks and responses still in flight.
+///
+/// # Return
+///
+/// The amount of work still in flight once the wait is over, zero unless it timed out.
+pub fn wait<F: Fn() -> usize>(timeout_ms: u64, in_flight: F) -> usize {
+    let timeout = match timeout_ms {
+        0 => Duration::from_millis(DEFAULT_TIMEOUT_MS),
+        ms => Duration::from_millis(ms),
+    };
+
+    let start = Instant::now();
+    loop {
+        let left = in_flight();
+        if left == 0 || start.elapsed() >= timeout {
+            return left;
+        }
+
+        sleep(Duration::from_millis(POLL_MS));
+    }
+}
+
+// This module contains unit tests for draining.
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+
+    // This unit test verifies that a wait returns as soon as nothing is in flight, and that it
+    // gives up with what is left once it times out.
+    #[test]
+    fn test_wait() {
+        let polls = Cell::new(3);
+        let left = wait(1000, || {
+            polls.set(polls.get() - 1);
+            polls.get()
+        });
+        assert_eq!((0, 0), (left, polls.get()));
+
+        assert_eq!(7, wait(5, || 7));
+    }
+}
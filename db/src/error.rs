@@ -0,0 +1,379 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! Errors raised by the server's subsystems. A `SplinterError` names the subsystem a failure
+//! happened in and what went wrong, can be wrapped in context describing what the server was
+//! doing at the time, and maps onto the status of the RPC that ran into it. Operational
+//! failures, such as a disk that cannot be written to or a client that went away, are reported
+//! through it and logged, rather than bringing the server down.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::result;
+
+use super::common::{TableId, TenantId};
+use super::wireformat::RpcStatus;
+
+/// A failure while parsing a request, or handing it to a service.
+#[derive(Debug)]
+pub enum DispatchError {
+    /// The request was malformed; the string says how.
+    Malformed(&'static str),
+
+    /// The request was for an unknown service or operation.
+    InvalidOperation(u8),
+}
+
+/// A failure while reading or writing tables.
+#[derive(Debug)]
+pub enum StorageError {
+    /// The tenant does not exist on this server.
+    TenantDoesNotExist(TenantId),
+
+    /// The table does not exist for the tenant.
+    TableDoesNotExist(TableId),
+
+    /// The object does not exist in the table.
+    ObjectDoesNotExist,
+
+    /// Writing the object would take the owner of the table over it's memory quota.
+    MemoryExhausted,
+
+    /// The object could not be allocated on the heap.
+    AllocFailed,
+
+    /// Persistent storage could not be read or written.
+    Io(io::Error),
+}
+
+/// A failure while running tasks.
+#[derive(Debug)]
+pub enum SchedulerError {
+    /// A task's execution context was still referenced once the task completed, so the
+    /// request and response packets could not be taken back from it.
+    ContextInUse,
+
+    /// Tasks were still running on behalf of the tenant.
+    TenantBusy(TenantId),
+
+    /// The server is draining before it shuts down, and takes on no new tasks.
+    Draining,
+}
+
+/// A failure while installing, loading or invoking an extension.
+#[derive(Debug)]
+pub enum ExtensionError {
+    /// The name of the extension is not valid UTF-8.
+    InvalidName,
+
+    /// The tenant has no extension by the name.
+    NotFound(String),
+
+    /// The extension could not be loaded from the file at the path.
+    LoadFailed(String),
+
+    /// The extension could not be saved to a file.
+    Io(io::Error),
+}
+
+/// A failure on the network.
+#[derive(Debug)]
+pub enum NetError {
+    /// An address could not be parsed.
+    InvalidAddress(String),
+
+    /// No packet could be allocated.
+    PacketAlloc,
+
+    /// A packet's buffer had no room left for a header or payload, named by the error.
+    NoRoom(&'static str),
+
+    /// A datagram would carry more bytes of payload, given by the error, than it's length
+    /// fields can describe.
+    DatagramTooLong(usize),
+
+    /// A socket could not be read or written.
+    Io(io::Error),
+}
+
+/// An error in one of the server's subsystems.
+#[derive(Debug)]
+pub enum SplinterError {
+    Dispatch(DispatchError),
+    Storage(StorageError),
+    Scheduler(SchedulerError),
+    Extension(ExtensionError),
+    Net(NetError),
+
+    /// An error, along with what the server was doing when it ran into it.
+    Context(String, Box<SplinterError>),
+}
+
+/// The result of an operation that can fail with a `SplinterError`.
+pub type Result<T> = result::Result<T, SplinterError>;
+
+// Implementation of methods on SplinterError.
+impl SplinterError {
+    /// Wraps this error in a description of what the server was doing when it ran into it.
+    ///
+    /// # Arguments
+    ///
+    /// * `context`: What the server was doing, such as "saving extension `tao`".
+    ///
+    /// # Return
+    ///
+    /// The error, with the context prepended to it's message.
+    pub fn context<C: Into<String>>(self, context: C) -> SplinterError {
+        SplinterError::Context(context.into(), Box::new(self))
+    }
+
+    /// Returns the error underneath any context it was wrapped in.
+    pub fn root(&self) -> &SplinterError {
+        match *self {
+            SplinterError::Context(_, ref inner) => inner.root(),
+            ref err => err,
+        }
+    }
+
+    /// Returns the status of an RPC that failed with this error.
+    pub fn status(&self) -> RpcStatus {
+        match *self.root() {
+            SplinterError::Dispatch(DispatchError::Malformed(_)) => {
+                RpcStatus::StatusMalformedRequest
+            }
+            SplinterError::Dispatch(DispatchError::InvalidOperation(_)) => {
+                RpcStatus::StatusInvalidOperation
+            }
+
+            SplinterError::Storage(StorageError::TenantDoesNotExist(_)) => {
+                RpcStatus::StatusTenantDoesNotExist
+            }
+            SplinterError::Storage(StorageError::TableDoesNotExist(_)) => {
+                RpcStatus::StatusTableDoesNotExist
+            }
+            SplinterError::Storage(StorageError::ObjectDoesNotExist) => {
+                RpcStatus::StatusObjectDoesNotExist
+            }
+            SplinterError::Storage(StorageError::MemoryExhausted) => {
+                RpcStatus::StatusMemoryExhausted
+            }
+
+            SplinterError::Scheduler(SchedulerError::TenantBusy(_)) => {
+                RpcStatus::StatusTenantBusy
+            }
+            SplinterError::Scheduler(SchedulerError::Draining) => RpcStatus::StatusDraining,
+
+            SplinterError::Net(NetError::DatagramTooLong(_)) => RpcStatus::StatusValueTooLarge,
+
+            SplinterError::Extension(ExtensionError::InvalidName) => {
+                RpcStatus::StatusMalformedRequest
+            }
+            SplinterError::Extension(ExtensionError::NotFound(_))
+            | SplinterError::Extension(ExtensionError::LoadFailed(_)) => {
+                RpcStatus::StatusInvalidExtension
+            }
+
+            // Everything else is the server's fault rather than the client's.
+            _ => RpcStatus::StatusInternalError,
+        }
+    }
+}
+
+impl fmt::Display for SplinterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SplinterError::Dispatch(DispatchError::Malformed(how)) => {
+                write!(f, "Malformed request: {}.", how)
+            }
+            SplinterError::Dispatch(DispatchError::InvalidOperation(op)) => {
+                write!(f, "Invalid operation {}.", op)
+            }
+
+            SplinterError::Storage(StorageError::TenantDoesNotExist(tenant)) => {
+                write!(f, "Tenant {} does not exist.", tenant)
+            }
+            SplinterError::Storage(StorageError::TableDoesNotExist(table)) => {
+                write!(f, "Table {} does not exist.", table)
+            }
+            SplinterError::Storage(StorageError::ObjectDoesNotExist) => {
+                write!(f, "Object does not exist.")
+            }
+            SplinterError::Storage(StorageError::MemoryExhausted) => {
+                write!(f, "Memory quota exhausted.")
+            }
+            SplinterError::Storage(StorageError::AllocFailed) => {
+                write!(f, "Failed to allocate object.")
+            }
+            SplinterError::Storage(StorageError::Io(ref err)) => {
+                write!(f, "Storage I/O failed: {}.", err)
+            }
+
+            SplinterError::Scheduler(SchedulerError::ContextInUse) => {
+                write!(f, "Execution context still in use by a completed task.")
+            }
+            SplinterError::Scheduler(SchedulerError::TenantBusy(tenant)) => {
+                write!(f, "Tasks still running on behalf of tenant {}.", tenant)
+            }
+            SplinterError::Scheduler(SchedulerError::Draining) => {
+                write!(f, "Server is draining before it shuts down.")
+            }
+
+            SplinterError::Extension(ExtensionError::InvalidName) => {
+                write!(f, "Extension name is not valid UTF-8.")
+            }
+            SplinterError::Extension(ExtensionError::NotFound(ref name)) => {
+                write!(f, "Extension {} does not exist.", name)
+            }
+            SplinterError::Extension(ExtensionError::LoadFailed(ref path)) => {
+                write!(f, "Failed to load extension from {}.", path)
+            }
+            SplinterError::Extension(ExtensionError::Io(ref err)) => {
+                write!(f, "Failed to save extension: {}.", err)
+            }
+
+            SplinterError::Net(NetError::InvalidAddress(ref addr)) => {
+                write!(f, "Invalid address {}.", addr)
+            }
+            SplinterError::Net(NetError::PacketAlloc) => write!(f, "Failed to allocate packet."),
+            SplinterError::Net(NetError::NoRoom(what)) => {
+                write!(f, "No room in packet for {}.", what)
+            }
+            SplinterError::Net(NetError::DatagramTooLong(len)) => {
+                write!(f, "Datagram too long ({} bytes of payload).", len)
+            }
+            SplinterError::Net(NetError::Io(ref err)) => write!(f, "Network I/O failed: {}.", err),
+
+            SplinterError::Context(ref context, ref inner) => {
+                write!(f, "While {}: {}", context, inner)
+            }
+        }
+    }
+}
+
+impl Error for SplinterError {
+    fn description(&self) -> &str {
+        match *self.root() {
+            SplinterError::Dispatch(_) => "Failed to dispatch request.",
+            SplinterError::Storage(_) => "Storage failure.",
+            SplinterError::Scheduler(_) => "Scheduler failure.",
+            SplinterError::Extension(_) => "Extension failure.",
+            SplinterError::Net(_) => "Network failure.",
+            SplinterError::Context(..) => unreachable!(),
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            SplinterError::Context(_, ref inner) => Some(&**inner),
+            SplinterError::Storage(StorageError::Io(ref err))
+            | SplinterError::Extension(ExtensionError::Io(ref err))
+            | SplinterError::Net(NetError::Io(ref err)) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<DispatchError> for SplinterError {
+    fn from(err: DispatchError) -> SplinterError {
+        SplinterError::Dispatch(err)
+    }
+}
+
+impl From<StorageError> for SplinterError {
+    fn from(err: StorageError) -> SplinterError {
+        SplinterError::Storage(err)
+    }
+}
+
+impl From<SchedulerError> for SplinterError {
+    fn from(err: SchedulerError) -> SplinterError {
+        SplinterError::Scheduler(err)
+    }
+}
+
+impl From<ExtensionError> for SplinterError {
+    fn from(err: ExtensionError) -> SplinterError {
+        SplinterError::Extension(err)
+    }
+}
+
+impl From<NetError> for SplinterError {
+    fn from(err: NetError) -> SplinterError {
+        SplinterError::Net(err)
+    }
+}
+
+/// Adds context to the error of a failed result.
+pub trait ResultExt<T> {
+    /// Wraps the error, if any, in a description of what the server was doing.
+    ///
+    /// # Arguments
+    ///
+    /// * `context`: A closure returning what the server was doing. Only called on an error.
+    ///
+    /// # Return
+    ///
+    /// The result, with context added to it's error.
+    fn context<C: Into<String>, F: FnOnce() -> C>(self, context: F) -> Result<T>;
+}
+
+impl<T, E: Into<SplinterError>> ResultExt<T> for result::Result<T, E> {
+    fn context<C: Into<String>, F: FnOnce() -> C>(self, context: F) -> Result<T> {
+        self.map_err(|err| err.into().context(context()))
+    }
+}
+
+// This module contains unit tests for SplinterError.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // This unit test verifies that errors map onto the status of the RPC that ran into them,
+    // through any context they were wrapped in.
+    #[test]
+    fn test_status() {
+        let err = SplinterError::from(StorageError::TableDoesNotExist(7));
+        assert!(err.status() == RpcStatus::StatusTableDoesNotExist);
+
+        let err = err.context("looking up a key").context("serving get()");
+        assert!(err.status() == RpcStatus::StatusTableDoesNotExist);
+
+        let err = SplinterError::from(ExtensionError::Io(io::Error::from(io::ErrorKind::Other)));
+        assert!(err.status() == RpcStatus::StatusInternalError);
+
+        let err = SplinterError::from(NetError::DatagramTooLong(70000)).context("building get()");
+        assert!(err.status() == RpcStatus::StatusValueTooLarge);
+        let err = SplinterError::from(NetError::NoRoom("key"));
+        assert!(err.status() == RpcStatus::StatusInternalError);
+    }
+
+    // This unit test verifies that context is kept, outermost first, in the error's message, and
+    // that the error underneath it stays reachable.
+    #[test]
+    fn test_context() {
+        let res: result::Result<(), _> = Err(ExtensionError::NotFound(String::from("tao")));
+        let err = res.context(|| "invoking `tao`").unwrap_err();
+
+        assert_eq!("While invoking `tao`: Extension tao does not exist.", err.to_string());
+        assert!(err.cause().is_some());
+        match *err.root() {
+            SplinterError::Extension(ExtensionError::NotFound(ref name)) => {
+                assert_eq!("tao", name)
+            }
+            _ => panic!("Context hid the error underneath it"),
+        }
+    }
+}
@@ -13,14 +13,18 @@
  * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
  */
 
+use std::fs;
 use std::rc::Rc;
 use std::sync::Arc;
-use std::ops::Generator;
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "generators")]
+use std::ops::{Generator, GeneratorState};
 use std::collections::HashMap;
 
 use super::common::TenantId;
 
 use spin::RwLock;
+use sandstorm::continuation::{Continuation, Step};
 use sandstorm::db::DB;
 use libloading::Library;
 use libloading::os::unix::Symbol;
@@ -28,9 +32,46 @@ use libloading::os::unix::Symbol;
 // Number of buckets in the `extensions` hashmap in Extension Manager.
 const EXT_BUCKETS: usize = 32;
 
-// The type signature of the function that will be searched for inside an so.
+// The type signature of the function that will be searched for inside an so. Only servers built
+// with the `generators` feature, which needs a nightly compiler, can call into it.
+#[cfg(feature = "generators")]
 type Proc = unsafe extern "C" fn(Rc<DB>) -> Box<Generator<Yield = u64, Return = u64>>;
 
+// The type signature of the function searched for inside an so built on stable Rust, that does
+// not contain the above.
+type StableProc = unsafe extern "C" fn(Rc<DB>) -> Box<Continuation>;
+
+// The entry point of an extension, depending on whether it was built on generators or on
+// continuations.
+enum Procedure {
+    #[cfg(feature = "generators")]
+    Generator(Symbol<Proc>),
+    Continuation(Symbol<StableProc>),
+}
+
+/// Counters on an extension, accumulated over every invocation of it that ran to completion or
+/// was aborted.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ExtensionStats {
+    /// The number of invocations.
+    pub invocations: u64,
+
+    /// The number of cycles invocations ran for outside of calls into the database.
+    pub compute_cycles: u64,
+
+    /// The number of cycles invocations spent in calls into the database.
+    pub db_cycles: u64,
+
+    /// The number of times invocations yielded to the scheduler.
+    pub yields: u64,
+
+    /// The number of invocations that panicked, and were aborted.
+    pub aborts: u64,
+
+    /// The number of invocations the extension pushed back to the client, to be run there.
+    pub pushbacks: u64,
+}
+
 /// This type represents an extension that has been successfully loaded into
 /// the database. As long as this type is not dropped, the extension will exist
 /// inside the database's address space, and can be called into.
@@ -44,14 +85,23 @@ pub struct Extension {
 
     // The actual symbol inside the dynamically loaded library that will be
     // used by the database during an "invoke".
-    procedure: Symbol<Proc>,
+    procedure: Procedure,
+
+    // The size of the .so file the extension was loaded from in bytes.
+    size: u64,
+
+    // Counters behind `stats()`, in the order of the fields on ExtensionStats.
+    counters: [AtomicUsize; 6],
 }
 
 // Implementation of methods on Extension.
 impl Extension {
     /// This function loads an .so file containing a symbol called "init" into
     /// the database. It returns a handle that can be used to retrieve a
-    /// generator from the loaded so.
+    /// continuation from the loaded so. An .so file built on stable Rust
+    /// contains a symbol called "init_stable" instead, returning the
+    /// continuation itself. The "init" symbol is only looked up by servers
+    /// built with the `generators` feature.
     ///
     /// # Safety
     ///
@@ -68,25 +118,35 @@ impl Extension {
     /// # Return
     ///
     /// An `Extension` if the .so file was found, and contains a symbol called
-    /// "init". This handle can then be used to call into the so.
+    /// "init" or "init_stable". This handle can then be used to call into the
+    /// so.
     pub fn load(name: &str) -> Option<Extension> {
         // First, try to dynamically load the .so file into the database.
         if let Ok(lib) = Library::new(name) {
             // If the load was successfull, try to find a function called
-            // "init" inside the .so file.
-            let mut procedure = None;
-            unsafe {
-                if let Ok(ext) = lib.get::<Proc>(b"init") {
-                    // If the "init" function was found, then unwrap it.
-                    procedure = Some(ext.into_raw());
-                }
-            }
+            // "init" or "init_stable" inside the .so file.
+            let procedure = unsafe {
+                generator(&lib).or_else(|| {
+                    lib.get::<StableProc>(b"init_stable")
+                        .ok()
+                        .map(|ext| Procedure::Continuation(ext.into_raw()))
+                })
+            };
 
             // If the init function was unwrapped, return an extension.
             if let Some(procedure) = procedure {
                 return Some(Extension {
                     library: lib,
                     procedure: procedure,
+                    size: fs::metadata(name).map(|meta| meta.len()).unwrap_or(0),
+                    counters: [
+                        AtomicUsize::new(0),
+                        AtomicUsize::new(0),
+                        AtomicUsize::new(0),
+                        AtomicUsize::new(0),
+                        AtomicUsize::new(0),
+                        AtomicUsize::new(0),
+                    ],
                 });
             }
         }
@@ -96,19 +156,101 @@ impl Extension {
     }
 
     /// This function calls into a previously loaded extension, and returns a
-    /// generator that can be scheduled by the database.
+    /// continuation that can be scheduled by the database.
     ///
     /// # Arguments
     ///
     /// * `db`: A ref-counted type that implements the `DB` interface that the
-    ///         returned generator will be initialized with.
+    ///         returned continuation will be initialized with.
     ///
     /// # Return
     ///
-    /// A generator that can be scheduled by the database.
-    pub fn get(&self, db: Rc<DB>) -> Box<Generator<Yield = u64, Return = u64>> {
-        // Call into the procedure, and return the generator.
-        unsafe { (self.procedure)(db) }
+    /// A continuation that can be scheduled by the database.
+    pub fn get(&self, db: Rc<DB>) -> Box<Continuation> {
+        // Call into the procedure, and return the continuation.
+        match self.procedure {
+            #[cfg(feature = "generators")]
+            Procedure::Generator(ref init) => Box::new(Resumer { gen: unsafe { init(db) } }),
+            Procedure::Continuation(ref init) => unsafe { init(db) },
+        }
+    }
+
+    /// Charges an invocation of this extension that ran to completion or was aborted. Called
+    /// once per invocation, so that counters shared across cores are touched only that often.
+    ///
+    /// # Arguments
+    ///
+    /// * `cycles`:    The total number of cycles the invocation ran for.
+    /// * `db_cycles`: The number of those cycles spent in calls into the database.
+    /// * `yields`:    The number of times the invocation yielded.
+    /// * `aborted`:   True if the invocation panicked.
+    pub fn charge(&self, cycles: u64, db_cycles: u64, yields: u64, aborted: bool) {
+        self.counters[0].fetch_add(1, Ordering::Relaxed);
+        self.counters[1].fetch_add(cycles.saturating_sub(db_cycles) as usize, Ordering::Relaxed);
+        self.counters[2].fetch_add(db_cycles as usize, Ordering::Relaxed);
+        self.counters[3].fetch_add(yields as usize, Ordering::Relaxed);
+        if aborted {
+            self.counters[4].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Charges an invocation in which this extension pushed itself back to the client.
+    pub fn pushed_back(&self) {
+        self.counters[5].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the counters on this extension. An extension that was shared between tenants is
+    /// loaded once, so it's counters cover invocations by all of them.
+    pub fn stats(&self) -> ExtensionStats {
+        let read = |idx: usize| self.counters[idx].load(Ordering::Relaxed) as u64;
+        ExtensionStats {
+            invocations: read(0),
+            compute_cycles: read(1),
+            db_cycles: read(2),
+            yields: read(3),
+            aborts: read(4),
+            pushbacks: read(5),
+        }
+    }
+}
+
+/// Looks up the "init" symbol of an extension built on generators.
+///
+/// # Arguments
+///
+/// * `lib`: The dynamically loaded library of the extension.
+///
+/// # Return
+///
+/// The entry point of the extension, or None if the library does not contain the symbol.
+#[cfg(feature = "generators")]
+unsafe fn generator(lib: &Library) -> Option<Procedure> {
+    lib.get::<Proc>(b"init").ok().map(|ext| Procedure::Generator(ext.into_raw()))
+}
+
+/// Servers built without the `generators` feature only load extensions built on continuations.
+#[cfg(not(feature = "generators"))]
+unsafe fn generator(_lib: &Library) -> Option<Procedure> {
+    None
+}
+
+/// Wraps a generator returned by an extension's "init" up in a continuation that resumes it, so
+/// that the database schedules extensions built on generators the same way as ones built on
+/// continuations.
+#[cfg(feature = "generators")]
+struct Resumer {
+    // The generator resumed whenever the continuation is.
+    gen: Box<Generator<Yield = u64, Return = u64>>,
+}
+
+#[cfg(feature = "generators")]
+impl Continuation for Resumer {
+    fn resume(&mut self) -> Step {
+        // As of 04/02/2018, calling resume() on a generator requires an unsafe block.
+        match unsafe { self.gen.resume() } {
+            GeneratorState::Yielded(val) => Step::Yield(val),
+            GeneratorState::Complete(val) => Step::Done(val),
+        }
     }
 }
 
@@ -218,6 +360,62 @@ impl ExtensionManager {
             .and_then(|ext| Some(Arc::clone(&ext)))
     }
 
+    /// This method returns the number of extensions a tenant installed or was shared, and the
+    /// total size of the .so files they were loaded from.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant`: The tenant.
+    /// * `except`: The name of an extension to leave out, because it is about to be replaced.
+    ///
+    /// # Return
+    ///
+    /// A tupule of the number of extensions and their total size in bytes.
+    pub fn usage(&self, tenant: TenantId, except: &str) -> (usize, u64) {
+        let bucket = (tenant & 0xff) as usize & (EXT_BUCKETS - 1);
+        self.extensions[bucket]
+            .read()
+            .iter()
+            .filter(|&(&(owner, ref name), _)| owner == tenant && name != except)
+            .fold((0, 0), |(count, size), (_, ext)| (count + 1, size + ext.size))
+    }
+
+    /// This method returns the counters on every extension in the database.
+    ///
+    /// # Return
+    ///
+    /// The tenant, name and counters of every extension, ordered by tenant and then name.
+    pub fn stats(&self) -> Vec<(TenantId, String, ExtensionStats)> {
+        let mut stats = Vec::new();
+        for bucket in self.extensions.iter() {
+            for (&(tenant, ref name), ext) in bucket.read().iter() {
+                stats.push((tenant, name.clone(), ext.stats()));
+            }
+        }
+
+        stats.sort_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)));
+        stats
+    }
+
+    /// Unloads every extension a tenant installed or was shared. An extension is unloaded from
+    /// the database's address space once invocations already running it have completed, and
+    /// once every other tenant it was shared with has unloaded it.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant`: The tenant whose extensions must be unloaded.
+    ///
+    /// # Return
+    ///
+    /// The number of extensions unloaded.
+    pub fn unload(&self, tenant: TenantId) -> usize {
+        let bucket = (tenant & 0xff) as usize & (EXT_BUCKETS - 1);
+        let mut map = self.extensions[bucket].write();
+        let before = map.len();
+        map.retain(|&(owner, _), _| owner != tenant);
+        before - map.len()
+    }
+
     /// Shares a previously loaded extension with another tenant.
     ///
     /// # Arguments
@@ -248,8 +446,8 @@ impl ExtensionManager {
 #[cfg(test)]
 mod tests {
     use std::rc::Rc;
-    use std::ops::GeneratorState;
 
+    use sandstorm::continuation::Step;
     use sandstorm::null::NullDB;
     use super::{Extension, ExtensionManager};
 
@@ -257,13 +455,34 @@ mod tests {
     // that both operations were successfull.
     #[test]
     fn test_ext_load() {
-        // Load and retrieve a generator from a test extension.
+        // Load and retrieve a continuation from a test extension.
         let ext = Extension::load("../ext/test/target/release/libtest.so").unwrap();
         let mut gen = ext.get(Rc::new(NullDB::new()));
 
         // Assert that the test extension has one yield statement.
-        unsafe { assert_eq!(GeneratorState::Yielded(0), gen.resume()) };
-        unsafe { assert_eq!(GeneratorState::Complete(0), gen.resume()) };
+        assert_eq!(Step::Yield(0), gen.resume());
+        assert_eq!(Step::Done(0), gen.resume());
+    }
+
+    // This function tests that a generator is run as a continuation that
+    // yields and completes when the generator does.
+    #[test]
+    #[cfg(feature = "generators")]
+    fn test_resumer() {
+        use sandstorm::continuation::Continuation;
+        use super::Resumer;
+
+        let mut resumer = Resumer {
+            gen: Box::new(|| {
+                yield 1;
+                yield 2;
+                return 7;
+            }),
+        };
+
+        assert_eq!(Step::Yield(1), resumer.resume());
+        assert_eq!(Step::Yield(2), resumer.resume());
+        assert_eq!(Step::Done(7), resumer.resume());
     }
 
     // This function tests that an extension without the "init" symbol cannot
@@ -289,6 +508,53 @@ mod tests {
         assert!(man.load("../ext/test/target/release/libtest.so", 0, "test"));
     }
 
+    // This function tests that unloading a tenant's extensions leaves those of
+    // other tenants, and those it shared, loaded.
+    #[test]
+    fn test_man_unload() {
+        let man = ExtensionManager::new();
+        assert!(man.load("../ext/test/target/release/libtest.so", 0, "test"));
+        assert!(man.load("../ext/test/target/release/libtest.so", 32, "test"));
+        assert!(man.share(0, 1, "test"));
+
+        assert_eq!(1, man.usage(0, "").0);
+        assert_eq!(0, man.usage(0, "test").0);
+        assert!(man.usage(0, "").1 > 0);
+
+        assert_eq!(1, man.unload(0));
+        assert!(man.get(0, "test").is_none());
+        assert!(man.get(32, "test").is_some());
+        assert!(man.get(1, "test").is_some());
+        assert_eq!(0, man.unload(0));
+    }
+
+    // This function tests that charges to an extension show up on it's counters, and on those
+    // of every tenant it was shared with.
+    #[test]
+    fn test_man_stats() {
+        let man = ExtensionManager::new();
+        assert!(man.load("../ext/test/target/release/libtest.so", 0, "test"));
+        assert!(man.share(0, 1, "test"));
+
+        let ext = man.get(0, "test").unwrap();
+        ext.charge(100, 30, 2, false);
+        ext.charge(50, 0, 0, true);
+        ext.pushed_back();
+
+        let stats = man.stats();
+        assert_eq!(2, stats.len());
+        assert_eq!((0, String::from("test")), (stats[0].0, stats[0].1.clone()));
+        assert_eq!(1, stats[1].0);
+        for &(_, _, stats) in stats.iter() {
+            assert_eq!(2, stats.invocations);
+            assert_eq!(120, stats.compute_cycles);
+            assert_eq!(30, stats.db_cycles);
+            assert_eq!(2, stats.yields);
+            assert_eq!(1, stats.aborts);
+            assert_eq!(1, stats.pushbacks);
+        }
+    }
+
     // This function tests that the extension manager cannot load an extension
     // without the "init" symbol.
     #[test]
@@ -313,13 +579,13 @@ mod tests {
         let man = ExtensionManager::new();
         assert!(man.load("../ext/test/target/release/libtest.so", 0, "test"));
 
-        // Retrieve the extension, and the generator.
+        // Retrieve the extension, and the continuation.
         let ext = man.get(0, "test").unwrap();
         let mut gen = ext.get(Rc::new(NullDB::new()));
 
         // Assert that the test extension has one yield statement.
-        unsafe { assert_eq!(GeneratorState::Yielded(0), gen.resume()) };
-        unsafe { assert_eq!(GeneratorState::Complete(0), gen.resume()) };
+        assert_eq!(Step::Yield(0), gen.resume());
+        assert_eq!(Step::Done(0), gen.resume());
     }
 
     // This function tests that a non-existent extension cannot be retrieved
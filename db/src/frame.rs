@@ -0,0 +1,235 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+// Checks on the Ethernet, IPv4 and UDP headers of received frames. The dispatcher runs each
+// check on the bytes in front of a header before parsing the packet upto it, so a frame is only
+// ever cast to a header it is long enough to hold, and the fields the checks need are read off
+// the bytes with a `wireformat::Reader` rather than through the cast. A runt or truncated frame
+// is dropped as a parse error instead of tripping an assertion in the packet framework.
+
+use super::common::{PACKET_ETYPE, PACKET_IP_LEN, PACKET_UDP_LEN};
+use super::wireformat::Reader;
+
+/// The length of an Ethernet header: two addresses and the ethertype.
+pub const MAC_HEADER_LEN: usize = 14;
+
+/// The length of an IPv4 header without any options.
+pub const IP_HEADER_LEN: usize = 20;
+
+/// The length of a UDP header.
+pub const UDP_HEADER_LEN: usize = 8;
+
+/// Checks the Ethernet header at the start of a frame.
+///
+/// # Arguments
+///
+/// * `frame`: The frame, from it's Ethernet header on.
+///
+/// # Return
+///
+/// True if the frame is long enough to hold the header, and carries an IPv4 packet.
+pub fn check_mac(frame: &[u8]) -> bool {
+    let mut reader = Reader::new(frame);
+    if reader.skip(12).is_none() {
+        return false;
+    }
+
+    reader.u16_be() == Some(PACKET_ETYPE)
+}
+
+/// Checks the IPv4 header at the start of a packet. A packet passes if:
+///     - It is an IPv4 packet, with a header at least as long as one without options,
+///     - It's TTL (time to live) is greater than zero,
+///     - It is long enough to carry a UDP header and an RPC header's service and opcode, and
+///       it's total length does not run past the end of the frame,
+///     - It's destination IP address matches that of the server.
+///
+/// # Arguments
+///
+/// * `packet`:  The packet, from it's IP header on, upto the end of the first buffer.
+/// * `chained`: The number of bytes of the frame in buffers chained to the first.
+/// * `dst`:     The IP address of the server, in host byte order.
+///
+/// # Return
+///
+/// True if the packet passes.
+pub fn check_ip(packet: &[u8], chained: usize, dst: u32) -> bool {
+    let mut reader = Reader::new(packet);
+    let (version, hlen) = match reader.u8() {
+        Some(b) => (b >> 4, (b & 0x0f) as usize * 4),
+        None => return false,
+    };
+    if version != 4 || hlen < IP_HEADER_LEN || packet.len() < hlen {
+        return false;
+    }
+
+    // The type of service, then the total length of the packet.
+    let _ = reader.skip(1);
+    let length = match reader.u16_be() {
+        Some(length) => length,
+        None => return false,
+    };
+    if length < PACKET_IP_LEN + 2 || length as usize > packet.len() + chained {
+        return false;
+    }
+
+    // The identification, flags and fragment offset, then the TTL.
+    let _ = reader.skip(4);
+    if reader.u8().map_or(true, |ttl| ttl == 0) {
+        return false;
+    }
+
+    // The protocol and checksum, then the source and destination addresses.
+    let _ = reader.skip(7);
+    reader.u32_be() == Some(dst)
+}
+
+/// Checks the UDP header at the start of a datagram.
+///
+/// # Arguments
+///
+/// * `datagram`: The datagram, from it's UDP header on, upto the end of the first buffer.
+/// * `chained`:  The number of bytes of the frame in buffers chained to the first.
+///
+/// # Return
+///
+/// True if the datagram is long enough to carry an RPC header's service and opcode, and it's
+/// length does not run past the end of the frame.
+pub fn check_udp(datagram: &[u8], chained: usize) -> bool {
+    let mut reader = Reader::at(datagram, 4);
+    match reader.u16_be() {
+        Some(length) => {
+            length >= PACKET_UDP_LEN + 2 && length as usize <= datagram.len() + chained
+        }
+        None => false,
+    }
+}
+
+// This module contains unit tests for the checks on received frames.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The address of the server in the frames built below.
+    const DST: u32 = 0x0a000001;
+
+    // Builds a frame carrying a UDP datagram with `payload` bytes of RPC in it, to the server.
+    fn frame(payload: usize) -> Vec<u8> {
+        let mut frame = vec![0u8; MAC_HEADER_LEN + IP_HEADER_LEN + UDP_HEADER_LEN + payload];
+        frame[12] = 0x08;
+
+        let ip = (IP_HEADER_LEN + UDP_HEADER_LEN + payload) as u16;
+        frame[14] = 0x45;
+        frame[16] = (ip >> 8) as u8;
+        frame[17] = ip as u8;
+        frame[22] = 64;
+        frame[23] = 17;
+        frame[30..34].copy_from_slice(&[0x0a, 0x00, 0x00, 0x01]);
+
+        let udp = (UDP_HEADER_LEN + payload) as u16;
+        frame[38] = (udp >> 8) as u8;
+        frame[39] = udp as u8;
+        frame
+    }
+
+    // Runs every check on a frame the way the dispatcher does, moving on to the next header
+    // only if the previous one passed, and slicing at the IP header's length.
+    fn check(frame: &[u8]) -> bool {
+        if !check_mac(frame) {
+            return false;
+        }
+
+        let packet = &frame[MAC_HEADER_LEN..];
+        if !check_ip(packet, 0, DST) {
+            return false;
+        }
+
+        let hlen = (packet[0] & 0x0f) as usize * 4;
+        check_udp(&packet[hlen..], 0)
+    }
+
+    // This unit test verifies that a well formed frame passes every check, and that frames
+    // with a bad field or that were truncated do not.
+    #[test]
+    fn test_checks() {
+        let good = frame(2);
+        assert!(check(&good));
+        assert!(check(&frame(64)));
+        assert!(!check(&frame(1)));
+
+        for &(off, val) in [(12, 0x86), (14, 0x65), (14, 0x44), (22, 0), (33, 2)].iter() {
+            let mut bad = good.clone();
+            bad[off] = val;
+            assert!(!check(&bad));
+        }
+
+        for len in 0..good.len() {
+            assert!(!check(&good[..len]));
+        }
+
+        // Ethernet pads short frames out, past the lengths on the IP and UDP headers.
+        let mut padded = good.clone();
+        padded.extend_from_slice(&[0u8; 16]);
+        assert!(check(&padded));
+
+        // The rest of a jumbo frame can be in buffers chained to the first.
+        let jumbo = frame(4000);
+        let first = &jumbo[MAC_HEADER_LEN..2048];
+        assert!(!check_ip(first, 0, DST));
+        assert!(check_ip(first, jumbo.len() - 2048, DST));
+        assert!(check_udp(&first[IP_HEADER_LEN..], jumbo.len() - 2048));
+    }
+
+    // This unit test fuzzes the checks with random frames, and frames that differ from a well
+    // formed one in a few random bytes, checking that they never panic or read out of bounds,
+    // and that every frame that passes is long enough for the headers it claims to carry.
+    #[test]
+    fn test_fuzz() {
+        let mut state = 0x2545f4914f6cdd1du64;
+        let mut rand = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        let good = frame(16);
+        for _ in 0..100000 {
+            let len = rand() as usize % (good.len() + 8);
+            let mut buf: Vec<u8> = if rand() % 2 == 0 {
+                (0..len).map(|_| rand() as u8).collect()
+            } else {
+                let mut buf = good.clone();
+                buf.resize(len, 0);
+                buf
+            };
+
+            for _ in 0..rand() % 4 {
+                if buf.len() > 0 {
+                    let idx = rand() as usize % buf.len();
+                    buf[idx] = rand() as u8;
+                }
+            }
+
+            if check(&buf) {
+                let packet = &buf[MAC_HEADER_LEN..];
+                let ip = ((packet[2] as usize) << 8) | packet[3] as usize;
+                let hlen = (packet[0] & 0x0f) as usize * 4;
+                assert!(ip <= packet.len());
+                assert!(hlen + UDP_HEADER_LEN + 2 <= packet.len());
+            }
+        }
+    }
+}
@@ -0,0 +1,605 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+
+// Asynchronous geo-replication. The writes made to the tables of tenants marked in the config
+// are shipped to a server in a remote cluster, a site, in batches by a thread per tenant, without
+// ever holding a write up. Every write is stamped with a version: the time in nanoseconds at
+// which it was made, and the identifier of the site it was made at to break ties. Both sites
+// remember the version of the last write to every key of a geo-replicated tenant, and apply a
+// shipped write only if it's version is newer than that of the key's last write; the last writer
+// wins, so sites that both take writes converge whichever order the writes reach them in. A
+// shipped batch that is resent is harmless, since none of it's writes are newer the second time.
+//
+// Lag is bounded. Once the oldest write that has not reached the remote site is older than the
+// bound, writes from the tenant are refused with StatusRateLimited until the remote catches up,
+// which clients retry after backing off. So a disaster that takes out a site loses no more than
+// the bound's worth of writes, and the queue of writes waiting to be shipped cannot grow without
+// bound while the remote is unreachable.
+//
+// Versions are kept in memory, a key and a version per key ever written, and are lost on a
+// restart; the first write to a key after one is always applied. Writes applied from another site
+// are logged, backed up and shipped to this server's standby and read replicas like any other,
+// but are not shipped on to other sites. They are only taken from the servers of other sites
+// listed in the config, and must carry the credential servers share (see `replica::send()`).
+
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use std::sync::{Once, ONCE_INIT};
+use std::thread::sleep;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::audit::quote;
+use super::common::{TableId, TenantId};
+use super::replica::{self, Record};
+use super::wireformat::ReplicaAction;
+
+use spin::{Mutex, RwLock};
+
+/// The number of milliseconds a write can take to reach the remote site when `geo_max_lag_ms`
+/// is zero.
+pub const DEFAULT_MAX_LAG_MS: u64 = 5000;
+
+/// The number of bytes ahead of the record in an entry: the time of the write in nanoseconds
+/// since the UNIX epoch (u64) and the site it was made at (u32), little endian.
+pub const ENTRY_HDR_LEN: usize = 12;
+
+/// The number of bytes of entries a batch is cut off at. A batch always has at least one entry.
+const MAX_BATCH_BYTES: usize = 1 << 20;
+
+/// Settings for geo-replication, taken from the server's config.
+pub struct Settings {
+    /// The identifier of this site. Every site needs a different one.
+    pub site: u32,
+
+    /// The number of milliseconds a write can take to reach the remote site before the tenant's
+    /// writes are refused.
+    pub max_lag_ms: u64,
+
+    /// The tenants whose writes are shipped, and the address of the install() TCP endpoint of
+    /// the server at the remote site each one is shipped to.
+    pub tenants: Vec<(TenantId, String)>,
+
+    /// The IP addresses of the servers at remote sites that writes are taken from.
+    pub peers: Vec<IpAddr>,
+}
+
+/// The version of a write: the time in nanoseconds since the UNIX epoch at which it was made,
+/// and the site it was made at. Versions compare by time, and then by site.
+pub type Version = (u64, u32);
+
+/// Appends an entry, a record along with the version of the write, to a buffer.
+///
+/// # Arguments
+///
+/// * `buf`:     The buffer.
+/// * `version`: The version of the write.
+/// * `rec`:     The record of the write.
+pub fn encode(buf: &mut Vec<u8>, version: Version, rec: &Record) {
+    for &(val, len) in [(version.0, 8), (version.1 as u64, 4)].iter() {
+        for byte in 0..len {
+            buf.push((val >> (8 * byte)) as u8);
+        }
+    }
+    replica::encode(buf, rec);
+}
+
+/// Parses the entry at the start of a buffer.
+///
+/// # Arguments
+///
+/// * `buf`: The buffer.
+///
+/// # Return
+///
+/// The version and record of the write, along with the number of bytes the entry took up. None
+/// if the buffer is too short.
+pub fn decode(buf: &[u8]) -> Option<(Version, Record, usize)> {
+    if buf.len() < ENTRY_HDR_LEN {
+        return None;
+    }
+
+    let le = |off: usize, len: usize| {
+        buf[off..off + len]
+            .iter()
+            .rev()
+            .fold(0u64, |acc, &byte| (acc << 8) | byte as u64)
+    };
+    let version = (le(0, 8), le(8, 4) as u32);
+    replica::decode(&buf[ENTRY_HDR_LEN..]).map(|(rec, len)| (version, rec, ENTRY_HDR_LEN + len))
+}
+
+// The writes of a tenant waiting to reach the remote site.
+struct Stream {
+    // The tenant, and the address of the remote server's install() TCP endpoint.
+    tenant: TenantId,
+    remote: String,
+
+    // The sequence number of the first entry in `pending`, and the entries themselves. Entries
+    // stay in `pending` until the remote acknowledges them.
+    first: u64,
+    pending: Vec<u8>,
+    count: usize,
+}
+
+// Implementation of methods on Stream.
+impl Stream {
+    // Returns an empty stream of a tenant's writes to a remote server.
+    fn new(tenant: TenantId, remote: &str) -> Stream {
+        Stream {
+            tenant: tenant,
+            remote: remote.to_string(),
+            first: 1,
+            pending: Vec::new(),
+            count: 0,
+        }
+    }
+
+    // Returns a copy of the entries at the head of the stream upto `MAX_BATCH_BYTES`, along with
+    // the sequence number of the first of them and their number. None if there are none.
+    fn peek(&self) -> Option<(u64, usize, Vec<u8>)> {
+        if self.count == 0 {
+            return None;
+        }
+
+        // Cut at the last entry boundary below the limit, keeping at least one entry.
+        let mut len = 0;
+        let mut count = 0;
+        while count < self.count {
+            let next = match decode(&self.pending[len..]) {
+                Some((_, _, next)) => next,
+                None => break,
+            };
+            if count > 0 && len + next > MAX_BATCH_BYTES {
+                break;
+            }
+            len += next;
+            count += 1;
+        }
+
+        Some((self.first, count, self.pending[..len].to_vec()))
+    }
+
+    // Drops the entries at the head of the stream once the remote acknowledged them.
+    fn ack(&mut self, count: usize, len: usize) {
+        self.pending = self.pending.split_off(len);
+        self.first += count as u64;
+        self.count -= count;
+    }
+
+    // Returns the number of nanoseconds the entry at the head of the stream has been waiting to
+    // reach the remote for, zero if there is none.
+    fn lag(&self, now: u64) -> u64 {
+        match decode(&self.pending) {
+            Some(((time, _), _, _)) if self.count > 0 => now.saturating_sub(time),
+            _ => 0,
+        }
+    }
+}
+
+// The versions of writes, and the streams they are shipped on.
+struct Geo {
+    // The identifier of this site, and the time of the last version stamped here.
+    site: u32,
+    last: u64,
+
+    // The version of the last write to every key of a tenant in `tracked`.
+    versions: HashMap<(TenantId, TableId, Vec<u8>), Version>,
+
+    // The tenants whose writes are versioned: those shipped from here, and those shipped here.
+    tracked: HashSet<TenantId>,
+
+    // The streams of writes to remote sites, one per tenant shipped from here.
+    streams: Vec<Stream>,
+
+    // The servers at remote sites that writes are taken from.
+    peers: Vec<IpAddr>,
+}
+
+// Implementation of methods on Geo.
+impl Geo {
+    // Returns the state of a site that ships no tenants, and has seen no writes.
+    fn new(site: u32) -> Geo {
+        Geo {
+            site: site,
+            last: 0,
+            versions: HashMap::new(),
+            tracked: HashSet::new(),
+            streams: Vec::new(),
+            peers: Vec::new(),
+        }
+    }
+
+    // Returns the version of a write made here now. Versions stamped here only ever increase,
+    // even if the clock steps back.
+    fn stamp(&mut self, now: u64) -> Version {
+        self.last = now.max(self.last + 1);
+        (self.last, self.site)
+    }
+
+    // Notes the version of a write, if it is newer than that of the last write to the key.
+    // Returns false if it is not, and the write should not be applied.
+    fn admit(&mut self, version: Version, rec: &Record) -> bool {
+        let key = (rec.tenant, rec.table, rec.key.to_vec());
+        match self.versions.get(&key) {
+            Some(last) if *last >= version => return false,
+            _ => {}
+        }
+
+        self.versions.insert(key, version);
+        true
+    }
+}
+
+/// Set if writes are versioned, because tenants are shipped from or to this site.
+static ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// The number of milliseconds a write can take to reach the remote site.
+static mut MAX_LAG_MS: u64 = DEFAULT_MAX_LAG_MS;
+
+/// Initializes GEO and LAGGING exactly once.
+static GEO_INIT: Once = ONCE_INIT;
+
+/// The state shared by all threads. Use `shared()` to access it.
+static mut GEO: *const Mutex<Geo> = 0 as *const Mutex<Geo>;
+
+/// The tenants whose writes are refused until the remote site catches up. Use `lagging()` to
+/// access them.
+static mut LAGGING: *const RwLock<Vec<TenantId>> = 0 as *const RwLock<Vec<TenantId>>;
+
+thread_local! {
+    // Set while this thread applies a write shipped from another site, which must keep it's
+    // version and must not be shipped back.
+    static APPLYING: Cell<bool> = Cell::new(false);
+}
+
+// Returns the state shared by all threads, allocating it on first use.
+fn shared() -> &'static Mutex<Geo> {
+    init();
+    unsafe { &*GEO }
+}
+
+// Returns the tenants whose writes are refused until the remote site catches up.
+fn laggards() -> &'static RwLock<Vec<TenantId>> {
+    init();
+    unsafe { &*LAGGING }
+}
+
+// Allocates GEO and LAGGING, once.
+fn init() {
+    GEO_INIT.call_once(|| unsafe {
+        GEO = Box::into_raw(Box::new(Mutex::new(Geo::new(0))));
+        LAGGING = Box::into_raw(Box::new(RwLock::new(Vec::new())));
+    });
+}
+
+// Returns the time in nanoseconds since the UNIX epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() * 1000000000 + d.subsec_nanos() as u64)
+        .unwrap_or(0)
+}
+
+/// Sets up geo-replication. Must be called before the server accepts requests, with `ship()`
+/// called for every tenant in the settings on a thread of it's own.
+///
+/// # Arguments
+///
+/// * `settings`: This site's identifier, the lag bound, the tenants shipped from here, and the
+///               servers writes are taken from.
+pub fn configure(settings: Settings) {
+    unsafe {
+        MAX_LAG_MS = settings.max_lag_ms;
+    }
+
+    let mut geo = shared().lock();
+    geo.site = settings.site;
+    for &(tenant, ref remote) in settings.tenants.iter() {
+        geo.tracked.insert(tenant);
+        geo.streams.push(Stream::new(tenant, remote));
+    }
+    geo.peers = settings.peers;
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Returns true if writes shipped from a server at another site should be taken.
+///
+/// # Arguments
+///
+/// * `addr`: The IP address the writes came from.
+pub fn peer(addr: IpAddr) -> bool {
+    ENABLED.load(Ordering::Relaxed) && shared().lock().peers.contains(&addr)
+}
+
+/// Versions a write to the tables of a geo-replicated tenant and applies it, appending it to the
+/// tenant's stream if it is shipped from here. Writes to other tenants are simply applied.
+/// Writes are applied under a lock, so that they reach the remote in the order they were made.
+///
+/// # Arguments
+///
+/// * `rec`:   The record of the write.
+/// * `apply`: Applies the write locally.
+///
+/// # Return
+///
+/// Whatever `apply` returned.
+pub fn write<T, F: FnOnce() -> T>(rec: &Record, apply: F) -> T {
+    if !ENABLED.load(Ordering::Relaxed) || APPLYING.with(|applying| applying.get()) {
+        return apply();
+    }
+
+    let mut geo = shared().lock();
+    if !geo.tracked.contains(&rec.tenant) {
+        drop(geo);
+        return apply();
+    }
+
+    let version = geo.stamp(now());
+    geo.admit(version, rec);
+    let ret = apply();
+    if let Some(stream) = geo.streams.iter_mut().find(|s| s.tenant == rec.tenant) {
+        encode(&mut stream.pending, version, rec);
+        stream.count += 1;
+    }
+    ret
+}
+
+/// Applies writes shipped from another site that are newer than the last write to their key,
+/// and drops the rest.
+///
+/// # Arguments
+///
+/// * `entries`: The versions and records of the writes, in the order they were made.
+/// * `apply`:   Applies a write locally, as if a client made it here.
+///
+/// # Return
+///
+/// The number of writes applied.
+pub fn apply<F: FnMut(&Record)>(entries: &[(Version, Record)], mut apply: F) -> usize {
+    ENABLED.store(true, Ordering::Relaxed);
+
+    let mut geo = shared().lock();
+    let mut applied = 0;
+    for &(version, ref rec) in entries.iter() {
+        geo.tracked.insert(rec.tenant);
+        if !geo.admit(version, rec) {
+            continue;
+        }
+
+        APPLYING.with(|applying| applying.set(true));
+        apply(rec);
+        APPLYING.with(|applying| applying.set(false));
+        applied += 1;
+    }
+    applied
+}
+
+/// Returns true if writes from a tenant should be refused, because the oldest of them that has
+/// not reached the remote site is older than the lag bound.
+///
+/// # Arguments
+///
+/// * `tenant`: The tenant.
+#[inline]
+pub fn lagging(tenant: TenantId) -> bool {
+    ENABLED.load(Ordering::Relaxed) && laggards().read().contains(&tenant)
+}
+
+/// Ships a tenant's writes to the remote site, forever, refusing the tenant's writes while the
+/// oldest of them that has not reached the remote is older than the lag bound. A batch the
+/// remote does not acknowledge is resent until it does. Blocks the calling thread.
+///
+/// # Arguments
+///
+/// * `stream`: The index of the tenant in the settings handed to `configure()`.
+pub fn ship(stream: usize) {
+    let epoch = now();
+    let (tenant, remote) = match shared().lock().streams.get(stream) {
+        Some(s) => (s.tenant, s.remote.clone()),
+        None => return,
+    };
+    info!("Geo-replicating tenant {} to {}", tenant, remote);
+
+    let bound = unsafe { MAX_LAG_MS } * 1000000;
+    let mut failing = false;
+    loop {
+        let (batch, lag) = {
+            let geo = shared().lock();
+            (geo.streams[stream].peek(), geo.streams[stream].lag(now()))
+        };
+        set_lagging(tenant, lag > bound);
+
+        let (first, count, entries) = match batch {
+            Some(batch) => batch,
+            None => {
+                sleep(Duration::from_millis(1));
+                continue;
+            }
+        };
+
+        let action = [ReplicaAction::Geo as u8];
+        match replica::send(&remote, &action, epoch, first, count, &entries) {
+            Ok(()) => {
+                if failing {
+                    info!("Remote site at {} is acknowledging writes again", remote);
+                    failing = false;
+                }
+                shared().lock().streams[stream].ack(count, entries.len());
+            }
+
+            Err(e) => {
+                if !failing {
+                    warn!("Remote site at {} did not acknowledge writes: {}", remote, e);
+                    failing = true;
+                }
+                sleep(Duration::from_millis(10));
+            }
+        }
+    }
+}
+
+// Starts or stops refusing writes from a tenant.
+fn set_lagging(tenant: TenantId, lagging: bool) {
+    if lagging == laggards().read().contains(&tenant) {
+        return;
+    }
+
+    let mut laggards = laggards().write();
+    if lagging {
+        warn!("Tenant {} fell behind at the remote site, refusing it's writes", tenant);
+        laggards.push(tenant);
+    } else {
+        info!("Tenant {} caught up at the remote site, accepting it's writes", tenant);
+        laggards.retain(|t| *t != tenant);
+    }
+}
+
+/// Describes geo-replication at this site as a line of JSON.
+pub fn status() -> String {
+    let geo = shared().lock();
+    let now = now();
+    let streams: Vec<String> = geo
+        .streams
+        .iter()
+        .map(|stream| {
+            format!(
+                "{{\"tenant\":{},\"remote\":{},\"queued\":{},\"lag_ms\":{},\"lagging\":{}}}",
+                stream.tenant,
+                quote(&stream.remote),
+                stream.count,
+                stream.lag(now) / 1000000,
+                lagging(stream.tenant)
+            )
+        })
+        .collect();
+
+    format!(
+        "{{\"site\":{},\"keys\":{},\"geo\":[{}]}}\n",
+        geo.site,
+        geo.versions.len(),
+        streams.join(",")
+    )
+}
+
+// This module contains unit tests for geo-replication.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::replica::{OP_DELETE, OP_PUT};
+
+    // This unit test verifies that entries survive a round trip through a buffer, and that a
+    // truncated one is not parsed.
+    #[test]
+    fn test_encode() {
+        let rec = Record {
+            op: OP_PUT,
+            tenant: 7,
+            table: 1,
+            key: b"key",
+            val: b"value",
+        };
+
+        let mut buf = Vec::new();
+        encode(&mut buf, (1 << 40, 3), &rec);
+        assert_eq!(ENTRY_HDR_LEN + replica::RECORD_HDR_LEN + 8, buf.len());
+
+        let (version, parsed, len) = decode(&buf).unwrap();
+        assert_eq!((1 << 40, 3), version);
+        assert_eq!(rec, parsed);
+        assert_eq!(buf.len(), len);
+        assert!(decode(&buf[..len - 1]).is_none());
+    }
+
+    // This unit test verifies that the last writer wins: a write is only admitted if it is newer
+    // than the last write to the key, with ties in time broken by site.
+    #[test]
+    fn test_admit() {
+        let mut geo = Geo::new(1);
+        let rec = Record {
+            op: OP_PUT,
+            tenant: 7,
+            table: 1,
+            key: b"k",
+            val: b"v",
+        };
+
+        let local = geo.stamp(100);
+        assert_eq!((100, 1), local);
+        assert!(geo.admit(local, &rec));
+
+        assert!(!geo.admit((99, 2), &rec));
+        assert!(!geo.admit((100, 0), &rec));
+        assert!(!geo.admit(local, &rec));
+        assert!(geo.admit((100, 2), &rec));
+
+        // Deletes are versioned like puts, and other keys are independent.
+        let del = Record {
+            op: OP_DELETE,
+            key: b"k",
+            val: &[],
+            ..rec
+        };
+        assert!(geo.admit((101, 1), &del));
+        let other = Record { key: b"o", ..rec };
+        assert!(geo.admit((1, 2), &other));
+
+        // Versions stamped here keep increasing if the clock steps back.
+        assert_eq!((101, 1), geo.stamp(50));
+    }
+
+    // This unit test verifies that a stream hands out batches until they are acknowledged, and
+    // measures it's lag from the oldest write not acknowledged.
+    #[test]
+    fn test_stream() {
+        let mut stream = Stream::new(7, "192.168.1.2:7700");
+        assert!(stream.peek().is_none());
+        assert_eq!(0, stream.lag(1000));
+
+        let val = vec![0u8; MAX_BATCH_BYTES / 2];
+        for time in 1..4 {
+            let rec = Record {
+                op: OP_PUT,
+                tenant: 7,
+                table: 1,
+                key: b"k",
+                val: &val,
+            };
+            encode(&mut stream.pending, (time * 100, 1), &rec);
+            stream.count += 1;
+        }
+
+        let (first, count, batch) = stream.peek().unwrap();
+        assert_eq!((1, 1), (first, count));
+        assert_eq!(100, stream.lag(200));
+
+        // Nothing is dropped until acknowledged.
+        assert_eq!(Some((1, 1, batch.clone())), stream.peek());
+        stream.ack(count, batch.len());
+        assert_eq!(800, stream.lag(1000));
+
+        let (first, count, batch) = stream.peek().unwrap();
+        assert_eq!((2, 1), (first, count));
+        stream.ack(count, batch.len());
+        let (first, count, batch) = stream.peek().unwrap();
+        assert_eq!((3, 1), (first, count));
+        stream.ack(count, batch.len());
+        assert!(stream.peek().is_none());
+        assert_eq!(0, stream.lag(1000));
+    }
+}
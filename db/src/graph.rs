@@ -0,0 +1,340 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use std::fs::File;
+use std::io::Read;
+
+use super::config::TaoConfig;
+
+/// The largest number of associations an object can have. Keeps the multiget() of an object's
+/// associations, and the response to it, within a single frame.
+pub const MAX_FANOUT: u32 = 64;
+
+/// The number of associations every object has if the config does not say otherwise.
+const DEFAULT_FANOUT: u32 = 4;
+
+/// The largest number of associations an object can have under a uniform or zipfian fan-out,
+/// if the config does not say otherwise.
+const DEFAULT_MAX_FANOUT: u32 = 16;
+
+/// The social graph of the TAO workload. Association lists are derived from an object's
+/// identifier, so the server can populate the graph and clients can reason about it without
+/// exchanging anything but the config.
+///
+/// The first association of object `i` is always to object `i + 1`, and the rest to the objects
+/// after it. Clients rely on this to recover the identifier of an object from it's list.
+pub struct Graph {
+    // The number of objects in the graph, identified 1 through `objects`.
+    objects: u32,
+
+    // The smallest number of associations an object can have.
+    min: u32,
+
+    // The cumulative probability of an object having `min + i` associations, indexed by `i`.
+    cdf: Vec<f64>,
+}
+
+// Implementation of methods on Graph.
+impl Graph {
+    /// Creates a graph.
+    ///
+    /// # Arguments
+    ///
+    /// * `tao`:     A config returned by `resolve()`.
+    /// * `objects`: The number of objects, if the config does not specify one.
+    ///
+    /// # Return
+    ///
+    /// The graph described by the config.
+    pub fn new(tao: &TaoConfig, objects: u32) -> Graph {
+        let n = tao.fanout_max - tao.fanout_min + 1;
+        let cdf = match tao.fanout.as_str() {
+            "uniform" => (1..(n + 1)).map(|i| i as f64 / n as f64).collect(),
+
+            "zipfian" => {
+                let weights: Vec<f64> = (1..(n + 1))
+                    .map(|rank| 1.0 / (rank as f64).powf(tao.fanout_skew))
+                    .collect();
+                let total: f64 = weights.iter().sum();
+
+                let mut sum = 0.0;
+                weights
+                    .iter()
+                    .map(|w| {
+                        sum += w / total;
+                        sum
+                    })
+                    .collect()
+            }
+
+            _ => vec![1.0],
+        };
+
+        Graph {
+            objects: if tao.objects > 0 { tao.objects } else { objects },
+            min: tao.fanout_min,
+            cdf: cdf,
+        }
+    }
+
+    /// Returns the number of objects in the graph.
+    pub fn objects(&self) -> u32 {
+        self.objects
+    }
+
+    /// Returns the number of associations an object has.
+    ///
+    /// # Arguments
+    ///
+    /// * `id`: The identifier of the object.
+    pub fn fanout(&self, id: u32) -> u32 {
+        // Map the identifier to a point in [0, 1), and invert the distribution at it.
+        let u = (mix(id as u64) >> 11) as f64 / (1u64 << 53) as f64;
+        let i = self.cdf.iter().position(|p| u < *p).unwrap_or(self.cdf.len() - 1);
+
+        self.min + i as u32
+    }
+
+    /// Returns the objects an object is associated with, wrapping around past the last object.
+    ///
+    /// # Arguments
+    ///
+    /// * `id`: The identifier of the object.
+    pub fn neighbours(&self, id: u32) -> Vec<u32> {
+        (1..(self.fanout(id) + 1))
+            .map(|a| (id + a - 1) % self.objects + 1)
+            .collect()
+    }
+}
+
+// Scrambles a value (the finalizer of splitmix64), so that neighbouring identifiers end up with
+// unrelated fan-outs.
+fn mix(val: u64) -> u64 {
+    let mut z = val.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// Applies the parameters in a LinkBench style properties file to a config. The properties
+/// understood are `maxid1` and `startid1` (the number of objects), `nlinks_func`,
+/// `nlinks_default`, `nlinks_max` and `nlinks_shape` (the fan-out distribution), and the request
+/// mix in `getnode`, `updatenode`, `getlink`, `getlinklist`, `addlink` and `updatelink`, which is
+/// scaled upto 100 percent. Everything else is ignored.
+///
+/// # Arguments
+///
+/// * `text`: The contents of the properties file.
+/// * `tao`:  The config to apply the parameters to.
+pub fn parse_linkbench(text: &str, tao: &mut TaoConfig) {
+    let mut start = 1;
+    let mut max = None;
+    let mut mix = [0.0f64; 4];
+
+    for line in text.lines() {
+        let line = line.trim();
+        if line.starts_with('#') || line.starts_with('!') {
+            continue;
+        }
+
+        let mut kv = line.splitn(2, |c| c == '=' || c == ':');
+        let (key, val) = match (kv.next(), kv.next()) {
+            (Some(key), Some(val)) => (key.trim(), val.trim()),
+            _ => continue,
+        };
+
+        let num = val.parse::<f64>().ok();
+        match (key, num) {
+            ("nlinks_func", _) => {
+                tao.fanout = if val.contains("Zipf") {
+                    String::from("zipfian")
+                } else if val.contains("Uniform") {
+                    String::from("uniform")
+                } else {
+                    String::from("constant")
+                };
+            }
+
+            ("maxid1", Some(n)) => max = Some(n as u32),
+            ("startid1", Some(n)) => start = n as u32,
+            ("nlinks_default", Some(n)) => tao.fanout_min = n as u32,
+            ("nlinks_max", Some(n)) => tao.fanout_max = n as u32,
+            ("nlinks_shape", Some(n)) => tao.fanout_skew = n,
+            ("getnode", Some(n)) => mix[0] += n,
+            ("getlink", Some(n)) | ("getlinklist", Some(n)) => mix[1] += n,
+            ("updatenode", Some(n)) => mix[2] += n,
+            ("addlink", Some(n)) | ("updatelink", Some(n)) => mix[3] += n,
+            _ => {}
+        }
+    }
+
+    if let Some(max) = max {
+        tao.objects = if max > start { max - start } else { 0 };
+    }
+
+    let total: f64 = mix.iter().sum();
+    if total > 0.0 {
+        tao.obj_get_pct = (mix[0] * 100.0 / total) as usize;
+        tao.obj_update_pct = (mix[2] * 100.0 / total) as usize;
+        tao.assoc_add_pct = (mix[3] * 100.0 / total) as usize;
+        tao.assoc_get_pct = 100 - tao.obj_get_pct - tao.obj_update_pct - tao.assoc_add_pct;
+    }
+}
+
+/// Loads the spec named by a config, fills in defaults, and checks that the result describes a
+/// graph that can be built.
+///
+/// # Arguments
+///
+/// * `tao`: The config.
+///
+/// # Return
+///
+/// The complete config, or a description of what is wrong with it.
+pub fn resolve(tao: &TaoConfig) -> Result<TaoConfig, String> {
+    let mut tao = tao.clone();
+
+    if tao.spec.len() > 0 {
+        let mut text = String::new();
+        File::open(&tao.spec)
+            .and_then(|mut file| file.read_to_string(&mut text))
+            .map_err(|e| format!("Failed to read the TAO spec `{}`: {}", tao.spec, e))?;
+        parse_linkbench(&text, &mut tao);
+    }
+
+    if tao.fanout.len() == 0 {
+        tao.fanout = String::from("constant");
+    }
+
+    match tao.fanout.as_str() {
+        "constant" => {
+            if tao.fanout_min == 0 {
+                tao.fanout_min = DEFAULT_FANOUT;
+            }
+            tao.fanout_max = tao.fanout_min;
+        }
+
+        "uniform" | "zipfian" => {
+            if tao.fanout_min == 0 {
+                tao.fanout_min = 1;
+            }
+            if tao.fanout_max == 0 {
+                tao.fanout_max = DEFAULT_MAX_FANOUT.max(tao.fanout_min);
+            }
+            if tao.fanout_skew <= 0.0 {
+                tao.fanout_skew = 1.0;
+            }
+        }
+
+        f => {
+            return Err(format!(
+                "`tao.fanout` is {:?}, it must be constant, uniform, or zipfian.",
+                f
+            ))
+        }
+    }
+
+    if tao.fanout_min > tao.fanout_max || tao.fanout_max > MAX_FANOUT {
+        return Err(format!(
+            "`tao.fanout_min` and `tao.fanout_max` are {} and {}, expected 1 <= min <= max <= {}.",
+            tao.fanout_min, tao.fanout_max, MAX_FANOUT
+        ));
+    }
+
+    let pct = tao.obj_get_pct + tao.assoc_get_pct + tao.obj_update_pct + tao.assoc_add_pct;
+    if pct != 0 && pct != 100 {
+        return Err(format!(
+            "The `tao` request percentages add upto {}, expected 100.",
+            pct
+        ));
+    }
+
+    return Ok(tao);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::TaoConfig;
+
+    #[test]
+    fn constant() {
+        let tao = resolve(&TaoConfig::default()).unwrap();
+        let graph = Graph::new(&tao, 100);
+
+        assert_eq!(100, graph.objects());
+        assert_eq!(4, graph.fanout(7));
+        assert_eq!(vec![8, 9, 10, 11], graph.neighbours(7));
+        assert_eq!(vec![99, 100, 1, 2], graph.neighbours(98));
+    }
+
+    #[test]
+    fn zipfian() {
+        let mut tao = TaoConfig::default();
+        tao.fanout = String::from("zipfian");
+        tao.fanout_min = 2;
+        tao.fanout_max = 10;
+        let graph = Graph::new(&resolve(&tao).unwrap(), 10000);
+
+        let mut counts = vec![0; 11];
+        for id in 1..10001 {
+            let f = graph.fanout(id);
+            assert!(f >= 2 && f <= 10);
+            counts[f as usize] += 1;
+        }
+
+        assert!(counts[2] > counts[3] && counts[3] > counts[10]);
+    }
+
+    #[test]
+    fn linkbench() {
+        let mut tao = TaoConfig::default();
+        parse_linkbench(
+            "# A LinkBench workload.\n\
+             startid1 = 1\n\
+             maxid1 = 1000001\n\
+             nlinks_func = com.facebook.LinkBench.distributions.ZipfDistribution\n\
+             nlinks_default = 1\n\
+             nlinks_max = 32\n\
+             getnode = 12.9\n\
+             updatenode = 7.4\n\
+             getlinklist = 50.7\n\
+             addlink = 9\n\
+             deletelink = 3\n",
+            &mut tao,
+        );
+
+        assert_eq!(1000000, tao.objects);
+        assert_eq!("zipfian", tao.fanout);
+        assert_eq!((1, 32), (tao.fanout_min, tao.fanout_max));
+        assert_eq!(
+            100,
+            tao.obj_get_pct + tao.assoc_get_pct + tao.obj_update_pct + tao.assoc_add_pct
+        );
+        assert!(resolve(&tao).is_ok());
+    }
+
+    #[test]
+    fn invalid() {
+        let mut tao = TaoConfig::default();
+        tao.fanout = String::from("uniform");
+        tao.fanout_max = MAX_FANOUT + 1;
+        assert!(resolve(&tao).is_err());
+
+        let mut tao = TaoConfig::default();
+        tao.obj_get_pct = 50;
+        assert!(resolve(&tao).is_err());
+    }
+}
@@ -0,0 +1,259 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use std::collections::HashMap;
+
+use super::common::TenantId;
+use super::config::GroupConfig;
+use super::cycles;
+
+/// The interval in milliseconds after which the virtual clocks of all groups and tenants on a
+/// core are reset. Without this, a group that was idle for a while would accumulate credit and
+/// monopolize the core once it became active again.
+const EPOCH_MS: u64 = 10;
+
+/// Shares are expressed as a percentage of a core.
+const TOTAL_SHARE: u64 = 100;
+
+/// The index of the default group inside `Groups`. Tenants that were not configured to be part
+/// of any group are placed here.
+const DEFAULT_GROUP: usize = 0;
+
+/// A single scheduling group on a core.
+struct Group {
+    // The percentage of the core that this group is entitled to.
+    share: u64,
+
+    // The number of tenants in the group. The group's share is divided equally among them.
+    members: u64,
+
+    // The virtual time of the group. Advances by the cycles consumed by the group's tasks,
+    // scaled inversely by the group's share.
+    vtime: u64,
+}
+
+/// Per-core accounting state for hierarchical scheduling groups. The scheduler uses this to pick
+/// the next task to run; the group with the smallest virtual time runs first, and within a group,
+/// the tenant with the smallest virtual time runs first.
+pub struct Groups {
+    // All groups on the core. The first one is always the default group.
+    groups: Vec<Group>,
+
+    // Maps a tenant to the index of it's group and the tenant's own virtual time.
+    tenants: HashMap<TenantId, (usize, u64)>,
+
+    // The length of an accounting epoch in cycles.
+    epoch: u64,
+
+    // The time-stamp in cycles at which the current accounting epoch began.
+    epoch_start: u64,
+
+    // True if no groups were configured. If so, the scheduler falls back to round robin.
+    disabled: bool,
+}
+
+// Implementation of methods on Groups.
+impl Groups {
+    /// Creates accounting state for a set of scheduling groups.
+    ///
+    /// # Arguments
+    ///
+    /// * `config`: The list of groups the server was configured with. If a tenant is listed
+    ///             under multiple groups, only the first one is honoured.
+    ///
+    /// # Return
+    ///
+    /// Scheduling groups that can be used by a scheduler to order tasks.
+    pub fn new(config: &[GroupConfig]) -> Groups {
+        let total: u64 = config.iter().map(|g| g.share as u64).sum();
+        if total > TOTAL_SHARE {
+            warn!(
+                "Scheduling groups were allocated {}% of each core. Shares will be scaled down.",
+                total
+            );
+        }
+
+        // The default group receives whatever is left over, but never less than 1%.
+        let default = if total >= TOTAL_SHARE { 1 } else { TOTAL_SHARE - total };
+
+        let mut groups = Vec::with_capacity(config.len() + 1);
+        groups.push(Group {
+            share: default,
+            members: 0,
+            vtime: 0,
+        });
+
+        let mut tenants = HashMap::new();
+        for g in config.iter() {
+            let idx = groups.len();
+            let mut members = 0;
+            for tenant in g.tenants.iter() {
+                if tenants.contains_key(tenant) {
+                    warn!("Tenant {} is in more than one group, ignoring {}.", tenant, g.name);
+                    continue;
+                }
+
+                tenants.insert(*tenant, (idx, 0));
+                members += 1;
+            }
+
+            groups.push(Group {
+                share: if g.share == 0 { 1 } else { g.share as u64 },
+                members: members,
+                vtime: 0,
+            });
+        }
+
+        Groups {
+            groups: groups,
+            tenants: tenants,
+            epoch: (cycles::cycles_per_second() / 1000) * EPOCH_MS,
+            epoch_start: cycles::rdtsc(),
+            disabled: config.len() == 0,
+        }
+    }
+
+    /// Returns true if no groups were configured.
+    #[inline]
+    pub fn disabled(&self) -> bool {
+        self.disabled
+    }
+
+    /// Returns the scheduling key for a tenant. Tasks with a smaller key should run first.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant`: The tenant whose key should be returned.
+    ///
+    /// # Return
+    ///
+    /// A tuple consisting of the virtual time of the tenant's group, and the virtual time of the
+    /// tenant within the group.
+    pub fn key(&mut self, tenant: TenantId) -> (u64, u64) {
+        let (group, vtime) = self.lookup(tenant);
+        (self.groups[group].vtime, vtime)
+    }
+
+    /// Charges a tenant and it's group for cycles consumed on the core.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant`: The tenant whose task ran on the core.
+    /// * `cycles`: The number of cycles the task ran for.
+    pub fn charge(&mut self, tenant: TenantId, cycles: u64) {
+        // Start a new epoch if required, forgetting all history.
+        let now = cycles::rdtsc();
+        if now - self.epoch_start > self.epoch {
+            self.reset();
+            self.epoch_start = now;
+        }
+
+        let (group, _) = self.lookup(tenant);
+        let members = self.groups[group].members;
+
+        // A group's virtual time advances slower the larger it's share, and a tenant's virtual
+        // time advances faster the more members there are in it's group.
+        self.groups[group].vtime += (cycles * TOTAL_SHARE) / self.groups[group].share;
+        if let Some(entry) = self.tenants.get_mut(&tenant) {
+            entry.1 += cycles * members;
+        }
+    }
+
+    /// Resets the virtual time of all groups and tenants to zero.
+    fn reset(&mut self) {
+        for group in self.groups.iter_mut() {
+            group.vtime = 0;
+        }
+
+        for (_, entry) in self.tenants.iter_mut() {
+            entry.1 = 0;
+        }
+    }
+
+    // Returns the group index and virtual time of a tenant. Tenants seen for the first time that
+    // weren't configured to be in any group are added to the default group.
+    fn lookup(&mut self, tenant: TenantId) -> (usize, u64) {
+        if let Some(entry) = self.tenants.get(&tenant) {
+            return *entry;
+        }
+
+        self.groups[DEFAULT_GROUP].members += 1;
+        self.tenants.insert(tenant, (DEFAULT_GROUP, 0));
+        (DEFAULT_GROUP, 0)
+    }
+}
+
+// This module contains simple unit tests for Groups.
+#[cfg(test)]
+mod tests {
+    use super::Groups;
+    use config::GroupConfig;
+
+    // Returns two groups, one with 60% of the core and three tenants, and another with 30% and
+    // one tenant.
+    fn groups() -> Groups {
+        Groups::new(&[
+            GroupConfig {
+                name: String::from("a"),
+                share: 60,
+                tenants: vec![1, 2, 3],
+            },
+            GroupConfig {
+                name: String::from("b"),
+                share: 30,
+                tenants: vec![4],
+            },
+        ])
+    }
+
+    // This unit test verifies that scheduling is disabled when no groups are configured.
+    #[test]
+    fn test_disabled() {
+        assert!(Groups::new(&[]).disabled());
+        assert!(!groups().disabled());
+    }
+
+    // This unit test verifies that a group's virtual time advances inversely to it's share.
+    #[test]
+    fn test_charge_group() {
+        let mut groups = groups();
+
+        groups.charge(1, 600);
+        groups.charge(4, 300);
+
+        assert_eq!(1000, groups.key(1).0);
+        assert_eq!(1000, groups.key(4).0);
+    }
+
+    // This unit test verifies that a group's share is divided equally among it's members.
+    #[test]
+    fn test_charge_member() {
+        let mut groups = groups();
+
+        groups.charge(1, 100);
+        assert_eq!((166, 300), groups.key(1));
+        assert_eq!((166, 0), groups.key(2));
+    }
+
+    // This unit test verifies that unknown tenants are placed in the default group.
+    #[test]
+    fn test_default_group() {
+        let mut groups = groups();
+
+        groups.charge(100, 10);
+        assert_eq!((100, 10), groups.key(100));
+        assert_eq!((0, 0), groups.key(1));
+    }
+}
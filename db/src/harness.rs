@@ -0,0 +1,713 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use std::mem::size_of;
+use std::net::Ipv4Addr;
+use std::ptr::{self, read_unaligned};
+use std::str::FromStr;
+use std::sync::{Arc, Once, ONCE_INIT};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use super::backend::{LoopbackBackend, NetBackend};
+use super::common;
+use super::config::ServerConfig;
+use super::counters::{self, Counter};
+use super::dispatch::Dispatch;
+use super::master::Master;
+use super::rpc;
+use super::sched::RoundRobin;
+use super::toml;
+use super::wireformat::{GetResponse, InvokeResponse, PutResponse, RpcResponseHeader, RpcStatus};
+
+use super::e2d2::common::EmptyMetadata;
+use super::e2d2::config::NetbricksConfiguration;
+use super::e2d2::headers::*;
+use super::e2d2::interface::dpdk::init_system;
+use super::e2d2::interface::*;
+use super::e2d2::native::zcsi::mbuf_free;
+
+/// The config a harness's server runs with unless another one is supplied. The addresses only
+/// need to be consistent with each other, since packets never leave the process.
+const LOOPBACK_CONFIG: &str = r#"
+mac_address = "02:00:00:00:00:02"
+ip_address = "10.0.0.2"
+udp_port = 0
+nic_pci = "loopback"
+client_mac = "02:00:00:00:00:01"
+client_ip = "10.0.0.1"
+num_tenants = 1
+install_addr = "127.0.0.1:7700"
+workload = ""
+num_records = 0
+"#;
+
+/// The longest a harness waits for the response to a request.
+const RESPONSE_TIMEOUT_MS: u64 = 1000;
+
+/// The maximum number of responses received from the loopback in one shot.
+const RX_BURST: usize = 32;
+
+// Initializes DPDK once per process. It is needed for it's packet pool, not for any NIC.
+static INIT: Once = ONCE_INIT;
+
+/// Initializes DPDK's packet pool, without binding to any NIC. Called by `Harness::new()`, so
+/// only required before allocating packets without a harness. Hugepages must be available.
+pub fn init() {
+    INIT.call_once(|| {
+        let config = NetbricksConfiguration {
+            name: String::from("harness"),
+            primary_core: 0,
+            cores: vec![0],
+            pool_size: 8192 - 1,
+            cache_size: 128,
+            ..NetbricksConfiguration::default()
+        };
+
+        init_system(&config);
+    });
+}
+
+/// Runs a server inside the current process, connected to the caller over a loopback instead of
+/// a NIC. Requests sent through the harness travel as complete frames through the same dispatch,
+/// scheduling, and extension code as they would on a real server, allowing the server to be
+/// tested end to end without any network. The server runs on a thread of it's own, which is
+/// stopped when the harness is dropped.
+pub struct Harness {
+    // The server's master service, holding tenants, their tables, and extensions.
+    master: Arc<Master>,
+
+    // The scheduler running the server's dispatcher.
+    sched: Arc<RoundRobin>,
+
+    // The thread `sched` runs on.
+    server: Option<JoinHandle<()>>,
+
+    // The client's end of the loopback. The server's dispatcher owns the other end.
+    client: LoopbackBackend,
+
+    // The UDP, IP, and MAC headers on every request sent through the harness.
+    req_udp_header: UdpHeader,
+    req_ip_header: IpHeader,
+    req_mac_header: MacHeader,
+}
+
+// Implementation of methods on Harness.
+impl Harness {
+    /// Creates a harness with a server running a default config, and no tenants.
+    ///
+    /// # Return
+    ///
+    /// A harness whose server is ready to receive requests.
+    pub fn new() -> Harness {
+        let config: ServerConfig =
+            toml::from_str(LOOPBACK_CONFIG).expect("Failed to parse loopback config.");
+        Harness::with_config(&config)
+    }
+
+    /// Creates a harness with a server running the supplied config, and no tenants. Settings
+    /// that concern the NIC or the network backend are ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `config`: The config to create the server's dispatcher with.
+    ///
+    /// # Return
+    ///
+    /// A harness whose server is ready to receive requests.
+    pub fn with_config(config: &ServerConfig) -> Harness {
+        init();
+
+        let master = Arc::new(Master::new());
+        let (client, server) = LoopbackBackend::pair(0);
+
+        // The dispatcher is it's own sibling, there is nobody else to steal from.
+        let sched = Arc::new(RoundRobin::new(0, 0, &config.groups));
+        let dispatch = Dispatch::new(
+            config,
+            server.clone(),
+            server,
+            Arc::clone(&master),
+            Arc::clone(&sched),
+            0,
+        );
+        sched.enqueue(Box::new(dispatch));
+
+        let csched = Arc::clone(&sched);
+        let handle = thread::spawn(move || csched.poll());
+
+        // Headers on requests, mirroring those on responses from the server.
+        let mut udp_header: UdpHeader = UdpHeader::new();
+        udp_header.set_src_port(common::CLIENT_UDP_PORT);
+        udp_header.set_dst_port(0);
+        udp_header.set_length(8);
+        udp_header.set_checksum(0);
+
+        let mut ip_header: IpHeader = IpHeader::new();
+        ip_header.set_src(u32::from(
+            Ipv4Addr::from_str(&config.client_ip).expect("Failed to create client IP address."),
+        ));
+        ip_header.set_dst(u32::from(
+            Ipv4Addr::from_str(&config.ip_address).expect("Failed to create server IP address."),
+        ));
+        ip_header.set_ttl(128);
+        ip_header.set_version(4);
+        ip_header.set_ihl(5);
+        ip_header.set_length(20);
+        ip_header.set_protocol(0x11);
+
+        let mut mac_header: MacHeader = MacHeader::new();
+        mac_header.src = config.parse_client_mac();
+        mac_header.dst = config.parse_mac();
+        mac_header.set_etype(0x0800);
+
+        Harness {
+            master: master,
+            sched: sched,
+            server: Some(handle),
+            client: client,
+            req_udp_header: udp_header,
+            req_ip_header: ip_header,
+            req_mac_header: mac_header,
+        }
+    }
+
+    /// Returns the server's master service. Tenants, tables, and extensions can be added to it at
+    /// any point, and are visible to the very next request.
+    pub fn master(&self) -> &Arc<Master> {
+        &self.master
+    }
+
+    /// Issues a get() RPC and waits for it's response.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant`: Id of the tenant requesting the item.
+    /// * `table`:  Id of the table from which the key is looked up.
+    /// * `key`:    Byte string of key whose value is to be fetched. Limit 64 KB.
+    /// * `id`:     RPC identifier.
+    ///
+    /// # Return
+    ///
+    /// The response, or None if the request could not be built or the server did not respond
+    /// in time.
+    pub fn get(
+        &self,
+        tenant: u32,
+        table: u64,
+        key: &[u8],
+        id: u64,
+    ) -> Option<Packet<UdpHeader, EmptyMetadata>> {
+        let request = rpc::create_get_rpc(
+            &self.req_mac_header,
+            &self.req_ip_header,
+            &self.req_udp_header,
+            tenant,
+            table,
+            key,
+            id,
+            0,
+        );
+
+        request.ok().and_then(|request| self.call(request, id))
+    }
+
+    /// Issues a put() RPC and waits for it's response.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant`: Id of the tenant requesting the insertion.
+    /// * `table`:  Id of the table into which the key-value pair is to be inserted.
+    /// * `key`:    Byte string of key whose value is to be inserted. Limit 64 KB.
+    /// * `val`:    Byte string of the value to be inserted.
+    /// * `id`:     RPC identifier.
+    ///
+    /// # Return
+    ///
+    /// The response, or None if the request could not be built or the server did not respond
+    /// in time.
+    pub fn put(
+        &self,
+        tenant: u32,
+        table: u64,
+        key: &[u8],
+        val: &[u8],
+        id: u64,
+    ) -> Option<Packet<UdpHeader, EmptyMetadata>> {
+        let request = rpc::create_put_rpc(
+            &self.req_mac_header,
+            &self.req_ip_header,
+            &self.req_udp_header,
+            tenant,
+            table,
+            key,
+            val,
+            id,
+            0,
+        );
+
+        request.ok().and_then(|request| self.call(request, id))
+    }
+
+    /// Issues an invoke() RPC and waits for it's response.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant`:   Id of the tenant requesting the invocation.
+    /// * `name_len`: The number of bytes at the head of the payload corresponding to the
+    ///               extensions name.
+    /// * `payload`:  The RPC payload to be written into the packet. Must contain the name of the
+    ///               extension followed by it's arguments.
+    /// * `id`:       RPC identifier.
+    ///
+    /// # Return
+    ///
+    /// The response, or None if the request could not be built or the server did not respond
+    /// in time.
+    pub fn invoke(
+        &self,
+        tenant: u32,
+        name_len: u32,
+        payload: &[u8],
+        id: u64,
+    ) -> Option<Packet<UdpHeader, EmptyMetadata>> {
+        let request = rpc::create_invoke_rpc(
+            &self.req_mac_header,
+            &self.req_ip_header,
+            &self.req_udp_header,
+            tenant,
+            name_len,
+            payload,
+            id,
+            0,
+        );
+
+        request.ok().and_then(|request| self.call(request, id))
+    }
+
+    /// Sends a request to the server and waits for the response carrying the same identifier.
+    /// Responses to other requests that arrive in the meantime are freed.
+    ///
+    /// # Arguments
+    ///
+    /// * `request`: The request, parsed upto it's IP header, as created by the `rpc` module.
+    /// * `id`:      The identifier on the request.
+    ///
+    /// # Return
+    ///
+    /// The response, or None if the server did not respond within RESPONSE_TIMEOUT_MS.
+    pub fn call(
+        &self,
+        request: Packet<IpHeader, EmptyMetadata>,
+        id: u64,
+    ) -> Option<Packet<UdpHeader, EmptyMetadata>> {
+        let mut pkts = [unsafe { request.get_mbuf() }];
+        match self.client.tx_burst(&mut pkts) {
+            Ok(1) => {}
+            _ => {
+                unsafe { mbuf_free(pkts[0]) };
+                return None;
+            }
+        }
+
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(RESPONSE_TIMEOUT_MS) {
+            let mut response = None;
+            for packet in self.recv() {
+                if response.is_none() && parse_stamp(&packet) == id {
+                    response = Some(packet);
+                } else {
+                    packet.free_packet();
+                }
+            }
+
+            if response.is_some() {
+                return response;
+            }
+
+            thread::yield_now();
+        }
+
+        None
+    }
+
+    /// Receives every response waiting on the loopback.
+    ///
+    /// # Return
+    ///
+    /// The responses, parsed upto their UDP headers. The caller must free them.
+    pub fn recv(&self) -> Vec<Packet<UdpHeader, EmptyMetadata>> {
+        let mut mbufs = vec![ptr::null_mut(); RX_BURST];
+        let recvd = self.client.rx_burst(&mut mbufs[..]).unwrap_or(0) as usize;
+
+        mbufs
+            .into_iter()
+            .take(recvd)
+            .map(|mbuf| unsafe {
+                packet_from_mbuf_no_increment(mbuf, 0)
+                    .parse_header::<MacHeader>()
+                    .parse_header::<IpHeader>()
+                    .parse_header::<UdpHeader>()
+            })
+            .collect()
+    }
+}
+
+/// A request made by a step of a scenario.
+#[derive(Clone, Debug)]
+pub enum Request {
+    /// A get() of a key in a tenant's table.
+    Get { tenant: u32, table: u64, key: Vec<u8> },
+
+    /// A put() of a key and value into a tenant's table.
+    Put {
+        tenant: u32,
+        table: u64,
+        key: Vec<u8>,
+        val: Vec<u8>,
+    },
+
+    /// An invoke() of a tenant's extension with arguments.
+    Invoke {
+        tenant: u32,
+        name: String,
+        args: Vec<u8>,
+    },
+}
+
+/// A request in a scenario, and what the response to it must carry.
+#[derive(Clone, Debug)]
+pub struct Step {
+    /// The request.
+    pub request: Request,
+
+    /// The status the response must carry.
+    pub status: RpcStatus,
+
+    /// The bytes the response must carry after it's header, if they are checked at all.
+    pub payload: Option<Vec<u8>>,
+}
+
+/// A script of requests run against a harness one after the other, with assertions on every
+/// response, and on how much the packet path counters grow over the whole run. Scenarios are
+/// built up a step at a time:
+///
+/// ```ignore
+/// let scenario = Scenario::new("put then get")
+///     .put(100, 100, &[1; 30], &[2; 100], RpcStatus::StatusOk)
+///     .get(100, 100, &[1; 30], RpcStatus::StatusOk)
+///     .returns(&[2; 100])
+///     .counts(Counter::RxPackets, 2);
+/// harness.run(&scenario).unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct Scenario {
+    /// The name the scenario is reported under when it fails.
+    pub name: String,
+
+    /// The steps, in the order they are run in.
+    pub steps: Vec<Step>,
+
+    /// The least every counter must grow by over the run. Other tests in the process can bump
+    /// the counters too, so only lower bounds can be checked.
+    pub counters: Vec<(Counter, u64)>,
+}
+
+// Implementation of methods on Scenario.
+impl Scenario {
+    /// Creates a scenario without any steps.
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: The name the scenario is reported under when it fails.
+    pub fn new(name: &str) -> Scenario {
+        Scenario {
+            name: String::from(name),
+            steps: Vec::new(),
+            counters: Vec::new(),
+        }
+    }
+
+    /// Appends a step with a request, whose response must carry a status.
+    pub fn step(mut self, request: Request, status: RpcStatus) -> Scenario {
+        self.steps.push(Step {
+            request: request,
+            status: status,
+            payload: None,
+        });
+        self
+    }
+
+    /// Appends a get(), whose response must carry a status.
+    pub fn get(self, tenant: u32, table: u64, key: &[u8], status: RpcStatus) -> Scenario {
+        let request = Request::Get {
+            tenant: tenant,
+            table: table,
+            key: key.to_vec(),
+        };
+        self.step(request, status)
+    }
+
+    /// Appends a put(), whose response must carry a status.
+    pub fn put(self, tenant: u32, table: u64, key: &[u8], val: &[u8], status: RpcStatus)
+               -> Scenario
+    {
+        let request = Request::Put {
+            tenant: tenant,
+            table: table,
+            key: key.to_vec(),
+            val: val.to_vec(),
+        };
+        self.step(request, status)
+    }
+
+    /// Appends an invoke(), whose response must carry a status.
+    pub fn invoke(self, tenant: u32, name: &str, args: &[u8], status: RpcStatus) -> Scenario {
+        let request = Request::Invoke {
+            tenant: tenant,
+            name: String::from(name),
+            args: args.to_vec(),
+        };
+        self.step(request, status)
+    }
+
+    /// Requires the response to the last step to carry exactly these bytes after it's header:
+    /// the value for a get(), and what the extension responded with for an invoke().
+    pub fn returns(mut self, payload: &[u8]) -> Scenario {
+        if let Some(step) = self.steps.last_mut() {
+            step.payload = Some(payload.to_vec());
+        }
+        self
+    }
+
+    /// Requires a counter to grow by at least `n` over the run.
+    pub fn counts(mut self, counter: Counter, n: u64) -> Scenario {
+        self.counters.push((counter, n));
+        self
+    }
+}
+
+// Implementation of methods on Harness that run scenarios.
+impl Harness {
+    /// Loads one of the extensions under `ext` for a tenant, which must have been built.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant`: The tenant to load the extension for.
+    /// * `name`:   The name of the extension.
+    ///
+    /// # Return
+    ///
+    /// True if the extension was loaded. False otherwise.
+    pub fn load(&self, tenant: u32, name: &str) -> bool {
+        self.master.load_extension(tenant, name)
+    }
+
+    /// Runs the steps of a scenario in order, and then checks the counters.
+    ///
+    /// # Arguments
+    ///
+    /// * `scenario`: The scenario.
+    ///
+    /// # Return
+    ///
+    /// An error describing the first assertion that failed, naming the scenario and step.
+    pub fn run(&self, scenario: &Scenario) -> Result<(), String> {
+        let before = counters::snapshot();
+
+        for (idx, step) in scenario.steps.iter().enumerate() {
+            let fail = |what: String| format!("{}, step {}: {}", scenario.name, idx + 1, what);
+            let id = idx as u64 + 1;
+            let (response, hdr_len) = match step.request {
+                Request::Get {
+                    tenant,
+                    table,
+                    ref key,
+                } => (self.get(tenant, table, key, id), size_of::<GetResponse>()),
+
+                Request::Put {
+                    tenant,
+                    table,
+                    ref key,
+                    ref val,
+                } => (self.put(tenant, table, key, val, id), size_of::<PutResponse>()),
+
+                Request::Invoke {
+                    tenant,
+                    ref name,
+                    ref args,
+                } => {
+                    let mut payload = name.as_bytes().to_vec();
+                    payload.extend_from_slice(args);
+                    let response = self.invoke(tenant, name.len() as u32, &payload, id);
+                    (response, size_of::<InvokeResponse>())
+                }
+            };
+
+            let response = response.ok_or_else(|| fail(format!("no response to {:?}", step)))?;
+            let status = parse_status(&response);
+            let payload = response.get_payload().get(hdr_len..).unwrap_or(&[]).to_vec();
+            response.free_packet();
+
+            if status != Some(step.status as u8) {
+                return Err(fail(format!("expected {:?}, got status {:?}", step.status, status)));
+            }
+
+            if let Some(ref expected) = step.payload {
+                if *expected != payload {
+                    return Err(fail(format!("expected {:?}, got {:?}", expected, payload)));
+                }
+            }
+        }
+
+        let after = counters::snapshot();
+        for &(counter, n) in scenario.counters.iter() {
+            let grew = after[counter as usize] - before[counter as usize];
+            if grew < n {
+                return Err(format!(
+                    "{}: expected {:?} to grow by {}, it grew by {}",
+                    scenario.name, counter, n, grew
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Implementation of the Drop trait for Harness, stopping the server's thread.
+impl Drop for Harness {
+    fn drop(&mut self) {
+        self.sched.compromised();
+        if let Some(handle) = self.server.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Returns the status code on an RPC response, to be compared against `RpcStatus`.
+///
+/// # Arguments
+///
+/// * `response`: A response parsed upto it's UDP header.
+///
+/// # Return
+///
+/// The status, or None if the response is too short to carry one.
+pub fn parse_status(response: &Packet<UdpHeader, EmptyMetadata>) -> Option<u8> {
+    response.get_payload().get(0).cloned()
+}
+
+// Returns the identifier on an RPC response, or zero if it is too short to carry one.
+fn parse_stamp(response: &Packet<UdpHeader, EmptyMetadata>) -> u64 {
+    let payload = response.get_payload();
+    if payload.len() < size_of::<RpcResponseHeader>() {
+        return 0;
+    }
+
+    unsafe { read_unaligned(payload[6..].as_ptr() as *const u64) }
+}
+
+// This module contains end to end tests of the server over a loopback. They need hugepages for
+// DPDK's packet pool, and are hence ignored by default. Run them with `cargo test -- --ignored`.
+#[cfg(test)]
+mod tests {
+    use super::{parse_status, Harness, Scenario};
+
+    use std::mem::size_of;
+
+    use super::super::counters::Counter;
+    use super::super::wireformat::{GetResponse, RpcStatus};
+
+    // This test verifies that a value written by a put() is returned by a get().
+    #[test]
+    #[ignore]
+    fn test_put_get() {
+        let harness = Harness::new();
+        harness.master().fill_test(100, 100, 0);
+
+        let res = harness
+            .put(100, 100, &[1; 30], &[2; 100], 1)
+            .expect("No response to put().");
+        assert_eq!(Some(RpcStatus::StatusOk as u8), parse_status(&res));
+        res.free_packet();
+
+        let res = harness.get(100, 100, &[1; 30], 2).expect("No response to get().");
+        assert_eq!(Some(RpcStatus::StatusOk as u8), parse_status(&res));
+        assert_eq!(&[2; 100][..], &res.get_payload()[size_of::<GetResponse>()..]);
+        res.free_packet();
+    }
+
+    // This test verifies that requests from unknown tenants are answered with an error.
+    #[test]
+    #[ignore]
+    fn test_unknown_tenant() {
+        let harness = Harness::new();
+
+        let res = harness.get(7, 1, &[1; 30], 1).expect("No response to get().");
+        assert_eq!(
+            Some(RpcStatus::StatusTenantDoesNotExist as u8),
+            parse_status(&res)
+        );
+        res.free_packet();
+    }
+
+    // This test verifies that a scenario runs it's steps in order, and fails on the first
+    // response that does not carry what it should.
+    #[test]
+    #[ignore]
+    fn test_scenario() {
+        let harness = Harness::new();
+        harness.master().fill_test(100, 100, 0);
+
+        let scenario = Scenario::new("put then get")
+            .put(100, 100, &[1; 30], &[2; 100], RpcStatus::StatusOk)
+            .get(100, 100, &[1; 30], RpcStatus::StatusOk)
+            .returns(&[2; 100])
+            .get(7, 100, &[1; 30], RpcStatus::StatusTenantDoesNotExist)
+            .counts(Counter::RxPackets, 3)
+            .counts(Counter::TxPackets, 3);
+        assert_eq!(Ok(()), harness.run(&scenario));
+
+        let scenario = Scenario::new("wrong value")
+            .get(100, 100, &[1; 30], RpcStatus::StatusOk)
+            .returns(&[3; 100]);
+        assert!(harness.run(&scenario).unwrap_err().starts_with("wrong value, step 1:"));
+    }
+
+    // This test verifies that an extension loaded into the harness is invoked end to end, and
+    // that keys it does not find are reported as such. The getall() extension must be built.
+    #[test]
+    #[ignore]
+    fn test_extension() {
+        let harness = Harness::new();
+        harness.master().fill_test(100, 100, 0);
+        assert!(harness.load(100, "getall"));
+
+        let mut args = vec![100, 0, 0, 0, 0, 0, 0, 0, 30, 0];
+        args.extend_from_slice(&[1; 30]);
+        args.extend_from_slice(&[2; 30]);
+
+        let mut expected = vec![0x01, 4, 0, 0, 0, 9, 9, 9, 9];
+        expected.extend_from_slice(&[255, 255, 255, 255]);
+
+        let scenario = Scenario::new("getall")
+            .put(100, 100, &[1; 30], &[9; 4], RpcStatus::StatusOk)
+            .invoke(100, "getall", &args, RpcStatus::StatusOk)
+            .returns(&expected)
+            .invoke(100, "missing", &[], RpcStatus::StatusInvalidExtension);
+        assert_eq!(Ok(()), harness.run(&scenario));
+    }
+}
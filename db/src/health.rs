@@ -0,0 +1,295 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+
+// Health and readiness probes, for the health() RPC and the /healthz and /readyz paths on the
+// metrics endpoint. A server is live if every core's scheduler made a scheduling decision
+// recently; a scheduler that has not is wedged, which the process still being up does not tell
+// an orchestrator. A live server is ready if, on top of that, every NIC port's link is up and
+// neither DPDK's packet pools nor the machine's memory are close to running out, and, on a
+// primary, writes are still being replicated to it's backup.
+
+use std::fs::File;
+use std::io::Read;
+
+use super::audit::quote;
+
+use e2d2::interface::dpdk::MempoolStats;
+use e2d2::interface::LinkStatus;
+
+/// The number of milliseconds a scheduler can go without making a scheduling decision before
+/// it's core is considered wedged. Far longer than the watchdog lets a task run, so that a core
+/// is only reported once the watchdog has failed to get it going again.
+pub const WEDGED_MS: u64 = 100;
+
+/// The smallest percentage of a packet pool's mbufs that must be free for the server to be
+/// ready.
+pub const MIN_FREE_MBUFS_PCT: u64 = 5;
+
+/// The smallest number of bytes of memory the machine must have available for the server to be
+/// ready.
+pub const MIN_AVAILABLE_BYTES: u64 = 64 << 20;
+
+/// The liveness of a core's scheduler.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CoreHealth {
+    pub core: i32,
+
+    /// The number of microseconds since the scheduler last made a scheduling decision.
+    pub idle_us: u64,
+
+    /// False if the scheduler is wedged.
+    pub live: bool,
+}
+
+/// The outcome of a health check.
+pub struct Report {
+    /// Set if every core is live.
+    pub live: bool,
+
+    /// Set if the server is live, and none of the problems below were found.
+    pub ready: bool,
+
+    pub cores: Vec<CoreHealth>,
+    pub links: Vec<(String, LinkStatus)>,
+    pub pools: Vec<(i32, MempoolStats)>,
+
+    /// The bytes of memory the machine has available for new allocations, if known.
+    pub mem_available: Option<u64>,
+
+    /// A description of everything found to be wrong.
+    pub problems: Vec<String>,
+}
+
+/// Checks the health of the server.
+///
+/// # Arguments
+///
+/// * `cores`:         Every scheduler's core, along with the cycles since it last made a
+///                    scheduling decision.
+/// * `cps`:           The number of cycles in a second.
+/// * `links`:         The name and link state of every NIC port.
+/// * `pools`:         The counts of every packet pool.
+/// * `mem_available`: The bytes of memory the machine has available, if known.
+/// * `broken`:        True if writes stopped being shipped to a backup that went away.
+///
+/// # Return
+///
+/// Whether the server is live and ready, along with what was checked and found to be wrong.
+pub fn check(
+    cores: &[(i32, u64)],
+    cps: u64,
+    links: Vec<(String, LinkStatus)>,
+    pools: Vec<(i32, MempoolStats)>,
+    mem_available: Option<u64>,
+    broken: bool,
+) -> Report {
+    let mut problems = Vec::new();
+
+    let cps = cps.max(1);
+    let cores: Vec<CoreHealth> = cores
+        .iter()
+        .map(|&(core, idle)| CoreHealth {
+            core: core,
+            idle_us: idle * 1000000 / cps,
+            live: idle * 1000 / cps < WEDGED_MS,
+        })
+        .collect();
+
+    for core in cores.iter().filter(|core| !core.live) {
+        problems.push(format!("core {} is wedged", core.core));
+    }
+    if cores.len() == 0 {
+        problems.push(String::from("no cores are running"));
+    }
+    let live = problems.len() == 0;
+
+    for &(ref name, ref link) in links.iter().filter(|&&(_, ref link)| link.up == 0) {
+        problems.push(format!("the link of port {} is down", name));
+    }
+
+    for &(pool, ref p) in pools.iter() {
+        if p.available * 100 < p.size * MIN_FREE_MBUFS_PCT {
+            problems.push(format!(
+                "packet pool {} has {} of {} mbufs free",
+                pool, p.available, p.size
+            ));
+        }
+    }
+
+    if let Some(bytes) = mem_available {
+        if bytes < MIN_AVAILABLE_BYTES {
+            problems.push(format!("only {} bytes of memory are available", bytes));
+        }
+    }
+
+    if broken {
+        problems.push(String::from("writes are no longer replicated to the backup"));
+    }
+
+    Report {
+        live: live,
+        ready: problems.len() == 0,
+        cores: cores,
+        links: links,
+        pools: pools,
+        mem_available: mem_available,
+        problems: problems,
+    }
+}
+
+// Implementation of methods on Report.
+impl Report {
+    /// Renders the report as a single line of JSON.
+    pub fn render(&self) -> String {
+        let cores: Vec<String> = self
+            .cores
+            .iter()
+            .map(|c| {
+                format!(
+                    "{{\"core\":{},\"idle_us\":{},\"live\":{}}}",
+                    c.core, c.idle_us, c.live
+                )
+            })
+            .collect();
+
+        let links: Vec<String> = self
+            .links
+            .iter()
+            .map(|&(ref name, ref l)| {
+                format!(
+                    "{{\"port\":{},\"up\":{},\"speed_mbps\":{},\"full_duplex\":{}}}",
+                    quote(name),
+                    l.up != 0,
+                    l.speed_mbps,
+                    l.full_duplex != 0
+                )
+            })
+            .collect();
+
+        let pools: Vec<String> = self
+            .pools
+            .iter()
+            .map(|&(pool, ref p)| {
+                format!("{{\"pool\":{},\"available\":{},\"size\":{}}}", pool, p.available, p.size)
+            })
+            .collect();
+
+        let problems: Vec<String> = self.problems.iter().map(|p| quote(p)).collect();
+
+        format!(
+            "{{\"live\":{},\"ready\":{},\"cores\":[{}],\"links\":[{}],\"pools\":[{}],\
+             \"mem_available_bytes\":{},\"problems\":[{}]}}\n",
+            self.live,
+            self.ready,
+            cores.join(","),
+            links.join(","),
+            pools.join(","),
+            self.mem_available.map_or(String::from("null"), |b| b.to_string()),
+            problems.join(",")
+        )
+    }
+}
+
+/// Reads the bytes of memory the machine has available for new allocations without swapping,
+/// as estimated by the kernel.
+pub fn mem_available() -> Option<u64> {
+    let mut meminfo = String::new();
+    File::open("/proc/meminfo")
+        .and_then(|mut file| file.read_to_string(&mut meminfo))
+        .ok()?;
+
+    // The line reads "MemAvailable:   123456 kB".
+    meminfo
+        .lines()
+        .find(|line| line.starts_with("MemAvailable:"))
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|kb| kb.parse::<u64>().ok())
+        .map(|kb| kb * 1024)
+}
+
+// This module contains unit tests for health checks.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A link that is up.
+    fn up() -> LinkStatus {
+        LinkStatus {
+            speed_mbps: 10000,
+            up: 1,
+            full_duplex: 1,
+        }
+    }
+
+    // This unit test verifies that a healthy server is live and ready, and renders as such.
+    #[test]
+    fn test_healthy() {
+        let pool = MempoolStats {
+            size: 100,
+            available: 50,
+            in_use: 50,
+            mbuf_bytes: 2048,
+        };
+        let report = check(
+            &[(1, 2000)],
+            1000000000,
+            vec![(String::from("eth0"), up())],
+            vec![(0, pool)],
+            Some(1 << 30),
+            false,
+        );
+
+        assert!(report.live);
+        assert!(report.ready);
+        assert_eq!(
+            "{\"live\":true,\"ready\":true,\"cores\":[{\"core\":1,\"idle_us\":2,\"live\":true}],\
+             \"links\":[{\"port\":\"eth0\",\"up\":true,\"speed_mbps\":10000,\"full_duplex\":true}],\
+             \"pools\":[{\"pool\":0,\"available\":50,\"size\":100}],\
+             \"mem_available_bytes\":1073741824,\"problems\":[]}\n",
+            report.render()
+        );
+    }
+
+    // This unit test verifies that a wedged core makes the server neither live nor ready, and
+    // that a down link, a nearly empty pool, low memory or a lost backup only make it not ready.
+    #[test]
+    fn test_problems() {
+        let report = check(&[(1, 0), (2, 1000000000)], 1000000000, vec![], vec![], None, false);
+        assert!(!report.live);
+        assert!(!report.ready);
+        assert_eq!(vec![String::from("core 2 is wedged")], report.problems);
+
+        let mut down = up();
+        down.up = 0;
+        let pool = MempoolStats {
+            size: 100,
+            available: 1,
+            in_use: 99,
+            mbuf_bytes: 2048,
+        };
+        let report = check(
+            &[(1, 0)],
+            1000000000,
+            vec![(String::from("eth0"), down)],
+            vec![(0, pool)],
+            Some(1 << 20),
+            true,
+        );
+        assert!(report.live);
+        assert!(!report.ready);
+        assert_eq!(4, report.problems.len());
+    }
+}
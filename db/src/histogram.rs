@@ -0,0 +1,338 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+
+/// The number of bits of precision kept on every value by default. Values are recorded to
+/// within 1 part in 128, that is, less than 1% error.
+pub const DEFAULT_PRECISION: u32 = 7;
+
+/// The percentiles reported by `summary()` and `report()`.
+pub const PERCENTILES: [f64; 4] = [50.0, 90.0, 99.0, 99.9];
+
+/// A high dynamic range histogram of latencies. Unlike a vector of samples, it takes up the same
+/// amount of memory however many values are recorded, and can record every request instead of
+/// a sample of them.
+///
+/// Values below `2^precision` are counted exactly. Above that, every power of two is split into
+/// `2^precision` equally sized buckets, so that a value is always recorded to within one part in
+/// `2^precision` of itself, across the whole range of a u64.
+#[derive(Clone, Debug)]
+pub struct Histogram {
+    // The number of bits of precision kept on every value.
+    precision: u32,
+
+    // The number of values recorded in each bucket.
+    counts: Vec<u64>,
+
+    // The number of values recorded, their sum, and the smallest and largest of them.
+    total: u64,
+    sum: u64,
+    min: u64,
+    max: u64,
+}
+
+// Implementation of methods on Histogram.
+impl Histogram {
+    /// Creates an empty histogram.
+    ///
+    /// # Arguments
+    ///
+    /// * `precision`: The number of bits of precision kept on every value, between 1 and 16.
+    pub fn new(precision: u32) -> Histogram {
+        let precision = precision.max(1).min(16);
+        let buckets = (65 - precision as usize) << precision;
+
+        Histogram {
+            precision: precision,
+            counts: vec![0; buckets],
+            total: 0,
+            sum: 0,
+            min: u64::max_value(),
+            max: 0,
+        }
+    }
+
+    /// Records a value.
+    pub fn record(&mut self, value: u64) {
+        self.record_n(value, 1);
+    }
+
+    /// Records a value several times over.
+    ///
+    /// # Arguments
+    ///
+    /// * `value`: The value.
+    /// * `n`:     The number of times it is recorded.
+    pub fn record_n(&mut self, value: u64, n: u64) {
+        if n == 0 {
+            return;
+        }
+
+        let idx = self.index(value);
+        self.counts[idx] += n;
+        self.total += n;
+        self.sum = self.sum.wrapping_add(value.wrapping_mul(n));
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    /// Adds every value recorded on another histogram into this one. Both must have been created
+    /// with the same precision.
+    pub fn merge(&mut self, other: &Histogram) {
+        assert_eq!(self.precision, other.precision);
+
+        for (count, other) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *count += *other;
+        }
+
+        self.total += other.total;
+        self.sum = self.sum.wrapping_add(other.sum);
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    /// Returns the number of values recorded.
+    pub fn count(&self) -> u64 {
+        self.total
+    }
+
+    /// Returns the smallest value recorded, or 0 if there are none.
+    pub fn min(&self) -> u64 {
+        if self.total == 0 {
+            0
+        } else {
+            self.min
+        }
+    }
+
+    /// Returns the largest value recorded, or 0 if there are none.
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    /// Returns the mean of the values recorded, or 0 if there are none.
+    pub fn mean(&self) -> f64 {
+        if self.total == 0 {
+            return 0.0;
+        }
+
+        self.sum as f64 / self.total as f64
+    }
+
+    /// Returns the value below which a percentage of the recorded values fall.
+    ///
+    /// # Arguments
+    ///
+    /// * `pct`: The percentage, between 0 and 100.
+    ///
+    /// # Return
+    ///
+    /// The largest value that is recorded in the same bucket as the value at the percentile, or
+    /// 0 if no values were recorded.
+    pub fn percentile(&self, pct: f64) -> u64 {
+        if self.total == 0 {
+            return 0;
+        }
+
+        let rank = ((pct.max(0.0).min(100.0) / 100.0) * self.total as f64).ceil() as u64;
+        let rank = rank.max(1);
+
+        let mut seen = 0;
+        for (idx, count) in self.counts.iter().enumerate() {
+            seen += *count;
+            if seen >= rank {
+                let (_, hi) = self.range(idx);
+                return hi.min(self.max);
+            }
+        }
+
+        self.max
+    }
+
+    /// Returns every bucket that has values recorded in it.
+    ///
+    /// # Return
+    ///
+    /// The smallest and largest value counted in each bucket, along with the number of values
+    /// recorded in it, in increasing order.
+    pub fn buckets(&self) -> Vec<(u64, u64, u64)> {
+        self.counts
+            .iter()
+            .enumerate()
+            .filter(|&(_, count)| *count > 0)
+            .map(|(idx, count)| {
+                let (lo, hi) = self.range(idx);
+                (lo, hi, *count)
+            })
+            .collect()
+    }
+
+    /// Returns a one line summary of the histogram, meant for logs.
+    pub fn summary(&self) -> String {
+        let mut line = format!("Count {} Mean {:.0}", self.count(), self.mean());
+        for pct in PERCENTILES.iter() {
+            line.push_str(&format!(" P{} {}", pct, self.percentile(*pct)));
+        }
+        line.push_str(&format!(" Max {}", self.max()));
+
+        line
+    }
+
+    /// Renders the histogram as a TOML table, listing the percentiles in `PERCENTILES` along
+    /// with the full distribution.
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: The name of the table. Must be a valid bare TOML key.
+    /// * `unit`: The unit of the values. Appended to the names of the fields.
+    ///
+    /// # Return
+    ///
+    /// The table.
+    pub fn report(&self, name: &str, unit: &str) -> String {
+        let mut table = format!("[{}]\n", name);
+        table.push_str(&format!("count = {}\n", self.count()));
+        table.push_str(&format!("mean_{} = {:.1}\n", unit, self.mean()));
+        table.push_str(&format!("min_{} = {}\n", unit, self.min()));
+        table.push_str(&format!("max_{} = {}\n", unit, self.max()));
+        for pct in PERCENTILES.iter() {
+            let key = format!("{}", pct).replace(".", "");
+            table.push_str(&format!(
+                "p{}_{} = {}\n",
+                key,
+                unit,
+                self.percentile(*pct)
+            ));
+        }
+
+        // Every bucket as [smallest value, largest value, count].
+        let buckets: Vec<String> = self.buckets()
+            .iter()
+            .map(|&(lo, hi, count)| format!("[{}, {}, {}]", lo, hi, count))
+            .collect();
+        table.push_str(&format!("buckets = [{}]\n\n", buckets.join(", ")));
+
+        table
+    }
+
+    // Returns the bucket a value is counted in.
+    fn index(&self, value: u64) -> usize {
+        let sub = 1u64 << self.precision;
+        if value < sub {
+            return value as usize;
+        }
+
+        let exp = 63 - value.leading_zeros();
+        let shift = exp - self.precision;
+        ((shift as u64) << self.precision) as usize + (value >> shift) as usize
+    }
+
+    // Returns the smallest and largest value counted in a bucket.
+    fn range(&self, idx: usize) -> (u64, u64) {
+        let sub = 1usize << self.precision;
+        if idx < sub {
+            return (idx as u64, idx as u64);
+        }
+
+        let shift = (idx >> self.precision) - 1;
+        let top = (idx - (shift << self.precision)) as u64;
+        let lo = top << shift;
+        (lo, lo + ((1u64 << shift) - 1))
+    }
+}
+
+/// Truncates a report file, so that a run starts with an empty one.
+pub fn reset_report(path: &str) -> io::Result<()> {
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .map(|_| ())
+}
+
+/// Appends a table rendered by `Histogram::report()` to a report file. The table is written out
+/// in one go, so that several threads can append to the same file.
+pub fn append_report(path: &str, table: &str) -> io::Result<()> {
+    let mut file = OpenOptions::new().append(true).create(true).open(path)?;
+    file.write_all(table.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Histogram;
+
+    #[test]
+    fn buckets() {
+        let hist = Histogram::new(3);
+        for value in 0..100000u64 {
+            let (lo, hi) = hist.range(hist.index(value));
+            assert!(lo <= value && value <= hi, "{} in [{}, {}]", value, lo, hi);
+
+            // Within one part in 2^precision.
+            assert!((hi - lo) * 8 <= value.max(1));
+        }
+
+        let (lo, hi) = hist.range(hist.index(u64::max_value()));
+        assert_eq!(15 << 60, lo);
+        assert_eq!(u64::max_value(), hi);
+    }
+
+    #[test]
+    fn percentiles() {
+        let mut hist = Histogram::new(7);
+        for value in 1..10001u64 {
+            hist.record(value);
+        }
+
+        assert_eq!(10000, hist.count());
+        assert_eq!(1, hist.min());
+        assert_eq!(10000, hist.max());
+        assert_eq!(5000.5, hist.mean());
+
+        for &(pct, expected) in [(50.0, 5000), (90.0, 9000), (99.0, 9900), (99.9, 9990)].iter() {
+            let value = hist.percentile(pct) as f64;
+            assert!((value - expected as f64).abs() / expected as f64 <= 0.01);
+        }
+        assert_eq!(10000, hist.percentile(100.0));
+    }
+
+    #[test]
+    fn merge() {
+        let mut a = Histogram::new(7);
+        let mut b = Histogram::new(7);
+        a.record_n(10, 3);
+        b.record(1000);
+
+        a.merge(&b);
+        assert_eq!(4, a.count());
+        assert_eq!(10, a.min());
+        assert_eq!(1000, a.max());
+        assert_eq!(10, a.percentile(75.0));
+        assert_eq!(3, a.buckets()[0].2);
+    }
+
+    #[test]
+    fn empty() {
+        let hist = Histogram::new(7);
+        assert_eq!(0, hist.percentile(99.0));
+        assert_eq!(0, hist.min());
+        assert_eq!(0.0, hist.mean());
+        assert!(hist.report("empty", "ns").contains("buckets = []"));
+    }
+}
@@ -17,10 +17,14 @@ use std::io::{Read, Write};
 use std::net::{Shutdown, TcpListener};
 use std::sync::Arc;
 
+use super::error::{NetError, ResultExt, SplinterError};
 use super::master::Master;
+use super::wireformat::OpCode;
 
 /// This type is responsible for servicing the install() RPC in Sandstorm. It listens for incoming
-/// RPCs on a TCP socket, and hands them off the Master.
+/// RPCs on a TCP socket, and hands them off the Master. The administrative tables(), stats(),
+/// tenant(), audit(), queues(), profile(), slow(), memory(), config(), health(), replica(),
+/// migrate(), partition(), snapshot() and backup() RPCs are received on the same socket.
 pub struct Installer {
     /// Master service that the install() RPC is handed off to.
     master: Arc<Master>,
@@ -49,7 +53,15 @@ impl Installer {
     pub fn execute(&mut self) {
         // Listen for incoming RPCs.
         for stream in self.listener.incoming() {
-            let mut stream = stream.expect("Installer failed to unwrap incoming TCP stream.");
+            let mut stream = match stream {
+                Ok(stream) => stream,
+
+                Err(err) => {
+                    let err = SplinterError::from(NetError::Io(err));
+                    warn!("{}", err.context("accepting an installer connection"));
+                    continue;
+                }
+            };
 
             let mut req: Vec<u8> = vec![];
             // Read from a connection.
@@ -58,15 +70,44 @@ impl Installer {
                     continue;
                 }
 
-                // Handoff to Master.
-                // TODO: Check Service and OpCode in RPC header.
+                // Handoff to Master based on the opcode, the second byte of the RPC header.
+                // TODO: Check Service in RPC header.
                 req.truncate(num);
-                let res = self.master.install(req);
+                let opcode = if req.len() > 1 { req[1] } else { 0 };
+                let res = match opcode {
+                    op if op == OpCode::SandstormTablesRpc as u8 => self.master.tables(req),
+                    op if op == OpCode::SandstormStatsRpc as u8 => self.master.stats(req),
+                    op if op == OpCode::SandstormTenantRpc as u8 => self.master.tenant(req),
+                    op if op == OpCode::SandstormAuditRpc as u8 => self.master.audit(req),
+                    op if op == OpCode::SandstormQueuesRpc as u8 => self.master.queues(req),
+                    op if op == OpCode::SandstormProfileRpc as u8 => self.master.profile(req),
+                    op if op == OpCode::SandstormSlowRpc as u8 => self.master.slow(req),
+                    op if op == OpCode::SandstormMemoryRpc as u8 => self.master.memory(req),
+                    op if op == OpCode::SandstormConfigRpc as u8 => self.master.config(req),
+                    op if op == OpCode::SandstormHealthRpc as u8 => self.master.health(req),
+                    op if op == OpCode::SandstormReplicaRpc as u8 => {
+                        let src = stream.peer_addr().ok().map(|addr| addr.ip());
+                        self.master.replica(req, src)
+                    }
+                    op if op == OpCode::SandstormMigrateRpc as u8 => self.master.migrate(req),
+                    op if op == OpCode::SandstormPartitionRpc as u8 => self.master.partition(req),
+                    op if op == OpCode::SandstormSnapshotRpc as u8 => self.master.snapshot(req),
+                    op if op == OpCode::SandstormBackupRpc as u8 => self.master.backup(req),
+                    op if op == OpCode::SandstormMembersRpc as u8 => self.master.members(req),
+                    _ => self.master.install(req),
+                };
 
-                // Return a response to the client.
-                stream.write_all(&res).unwrap();
-                stream.flush().unwrap();
-                stream.shutdown(Shutdown::Both).unwrap();
+                // Return a response to the client. A client that went away only loses it's
+                // response.
+                let sent = stream
+                    .write_all(&res)
+                    .and_then(|_| stream.flush())
+                    .and_then(|_| stream.shutdown(Shutdown::Both))
+                    .map_err(NetError::Io)
+                    .context(|| format!("responding to installer RPC {}", opcode));
+                if let Err(err) = sent {
+                    warn!("{}", err);
+                }
             }
         }
     }
@@ -13,9 +13,12 @@
  * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
  */
 
-#![feature(generators, generator_trait, asm)]
+#![cfg_attr(feature = "generators", feature(generators, generator_trait))]
 
+extern crate libc;
 extern crate libloading;
+extern crate ring;
+extern crate rustls;
 extern crate sandstorm;
 extern crate serde;
 #[macro_use]
@@ -23,6 +26,8 @@ extern crate serde_derive;
 extern crate spin;
 extern crate toml;
 extern crate time;
+extern crate webpki;
+extern crate webpki_roots;
 
 pub extern crate bytes;
 pub extern crate e2d2;
@@ -33,20 +38,60 @@ pub extern crate log;
 mod alloc;
 mod common;
 mod container;
+mod crypt;
 mod context;
+mod group;
+mod ratelimit;
 mod service;
 mod tenant;
 mod native;
 
 // Public modules for binaries.
+pub mod backend;
 pub mod rpc;
+pub mod multiop;
 pub mod cycles;
 pub mod config;
 pub mod dispatch;
 pub mod ext;
 pub mod table;
 pub mod wireformat;
+pub mod frame;
+pub mod coalesce;
 pub mod master;
 pub mod sched;
 pub mod task;
 pub mod install;
+pub mod numa;
+pub mod graph;
+pub mod histogram;
+pub mod trace;
+pub mod tap;
+pub mod meter;
+pub mod metrics;
+pub mod counters;
+pub mod runtime;
+pub mod audit;
+pub mod logger;
+pub mod span;
+pub mod slow;
+pub mod profile;
+pub mod memory;
+pub mod health;
+pub mod replica;
+pub mod readrep;
+pub mod geo;
+pub mod raft;
+pub mod member;
+pub mod migrate;
+pub mod partition;
+pub mod snapshot;
+pub mod s3;
+pub mod backup;
+pub mod wal;
+pub mod zcopy;
+pub mod harness;
+pub mod error;
+pub mod drain;
+pub mod shm;
+pub mod deadline;
@@ -0,0 +1,240 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+
+// The server's logger. Messages logged through the `log` facade are filtered by the target they
+// were logged under, which is the path of the module they were logged from unless the message
+// says otherwise, and written to stderr. Levels are set with directives of the same form as
+// RUST_LOG: `info,db::dispatch=warn,db::sched=debug` logs everything at info or above, except for
+// dispatch which only logs warnings and errors, and the scheduler which logs debug messages too.
+// Levels can be replaced while the server runs, so that one subsystem can be looked into without
+// a rebuild or a restart. Extensions' debug_log() messages are logged under the "ext" target.
+
+use std::io::{stderr, Write};
+use std::str::FromStr;
+use std::sync::{Once, ONCE_INIT};
+
+use log::{self, Log, LogLevelFilter, LogMetadata, LogRecord, MaxLogLevelFilter};
+
+use spin::RwLock;
+
+/// The level of targets no directive matches, when no directive sets a default of it's own.
+const DEFAULT_LEVEL: LogLevelFilter = LogLevelFilter::Error;
+
+/// The levels messages are logged at, by target.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Filter {
+    // The level of targets no directive matches.
+    default: LogLevelFilter,
+
+    // Target prefixes and their levels, longest prefix first.
+    directives: Vec<(String, LogLevelFilter)>,
+}
+
+// Implementation of methods on Filter.
+impl Filter {
+    /// Parses a comma separated list of directives. A directive is a level, which becomes the
+    /// level of targets no other directive matches, a module path, which logs every message under
+    /// it, or a module path and a level separated by `=`.
+    ///
+    /// # Arguments
+    ///
+    /// * `spec`: The directives. Empty if only errors should be logged.
+    ///
+    /// # Return
+    ///
+    /// The filter, or a description of the first directive that could not be parsed.
+    pub fn parse(spec: &str) -> Result<Filter, String> {
+        let mut filter = Filter {
+            default: DEFAULT_LEVEL,
+            directives: Vec::new(),
+        };
+
+        for directive in spec.split(',').map(|d| d.trim()).filter(|d| d.len() > 0) {
+            let mut parts = directive.splitn(2, '=');
+            let name = parts.next().unwrap_or("").trim();
+            match parts.next().map(|level| level.trim()) {
+                Some(level) => {
+                    let level = LogLevelFilter::from_str(level)
+                        .map_err(|_| format!("\"{}\" has an unknown level", directive))?;
+                    if name.len() == 0 {
+                        return Err(format!("\"{}\" has no module", directive));
+                    }
+                    filter.directives.push((String::from(name), level));
+                }
+
+                None => match LogLevelFilter::from_str(name) {
+                    Ok(level) => filter.default = level,
+                    Err(_) => filter
+                        .directives
+                        .push((String::from(name), LogLevelFilter::Trace)),
+                },
+            }
+        }
+
+        // Longer prefixes are more specific, and of two directives for the same module the later
+        // one wins. The sort is stable, so the later one stays ahead once reversed.
+        filter.directives.reverse();
+        filter
+            .directives
+            .sort_by(|a, b| b.0.len().cmp(&a.0.len()));
+
+        Ok(filter)
+    }
+
+    /// Returns the level messages logged under a target are let through at.
+    pub fn level(&self, target: &str) -> LogLevelFilter {
+        for &(ref name, level) in self.directives.iter() {
+            let matches = target.starts_with(name.as_str())
+                && (target.len() == name.len() || target[name.len()..].starts_with("::"));
+            if matches {
+                return level;
+            }
+        }
+
+        self.default
+    }
+
+    /// Returns the most verbose level any target is let through at.
+    pub fn max(&self) -> LogLevelFilter {
+        self.directives
+            .iter()
+            .map(|&(_, level)| level)
+            .fold(self.default, |max, level| max.max(level))
+    }
+}
+
+// The logger handed to the `log` facade. Shares it's filter with `configure()`.
+struct Logger {
+    filter: &'static RwLock<Filter>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        metadata.level() <= self.filter.read().level(metadata.target())
+    }
+
+    fn log(&self, record: &LogRecord) {
+        if self.enabled(record.metadata()) {
+            let _ = writeln!(
+                stderr(),
+                "{}:{}: {}",
+                record.level(),
+                record.target(),
+                record.args()
+            );
+        }
+    }
+}
+
+/// Initializes FILTER exactly once.
+static INIT: Once = ONCE_INIT;
+
+/// The filter shared by the logger and `configure()`, and the handle on the facade's maximum
+/// level. Set by `init()`.
+static mut FILTER: *const RwLock<Filter> = 0 as *const RwLock<Filter>;
+static mut MAX: *const MaxLogLevelFilter = 0 as *const MaxLogLevelFilter;
+
+/// Installs the logger. Only the first call has any effect.
+///
+/// # Arguments
+///
+/// * `spec`: The directives to log with, in the form `Filter::parse()` takes. Directives that
+///           cannot be parsed are reported, and the logger falls back to logging errors only.
+pub fn init(spec: &str) {
+    let (filter, problem) = match Filter::parse(spec) {
+        Ok(filter) => (filter, None),
+        Err(problem) => (Filter::parse("").unwrap(), Some(problem)),
+    };
+
+    INIT.call_once(|| unsafe {
+        let max = filter.max();
+        FILTER = Box::into_raw(Box::new(RwLock::new(filter)));
+        let _ = log::set_logger(|handle| {
+            handle.set(max);
+            MAX = Box::into_raw(Box::new(handle));
+            Box::new(Logger { filter: &*FILTER })
+        });
+    });
+
+    if let Some(problem) = problem {
+        error!("Ignoring log levels \"{}\": {}", spec, problem);
+    }
+}
+
+/// Replaces the levels the logger was installed with.
+///
+/// # Arguments
+///
+/// * `spec`: The directives to log with, in the form `Filter::parse()` takes.
+///
+/// # Return
+///
+/// A description of the first directive that could not be parsed, if any. The levels are left as
+/// they were in that case.
+pub fn configure(spec: &str) -> Result<(), String> {
+    let filter = Filter::parse(spec)?;
+    unsafe {
+        if FILTER.is_null() || MAX.is_null() {
+            return Err(String::from("the logger was not installed"));
+        }
+
+        // Raising the facade's maximum level before the filter, and lowering it after, keeps
+        // messages that both let through from being dropped while the two disagree.
+        let max = filter.max();
+        if max > log::max_log_level() {
+            (*MAX).set(max);
+        }
+        *(*FILTER).write() = filter;
+        (*MAX).set(max);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Tests that each target is logged at the level of the longest directive matching it, and
+    // that directives only match whole module names.
+    #[test]
+    fn test_level() {
+        let filter = Filter::parse("info, db::dispatch=warn,db::sched=debug,db::sched::x").unwrap();
+        assert_eq!(LogLevelFilter::Info, filter.level("db"));
+        assert_eq!(LogLevelFilter::Info, filter.level("db::master"));
+        assert_eq!(LogLevelFilter::Warn, filter.level("db::dispatch"));
+        assert_eq!(LogLevelFilter::Info, filter.level("db::dispatcher"));
+        assert_eq!(LogLevelFilter::Debug, filter.level("db::sched::y"));
+        assert_eq!(LogLevelFilter::Trace, filter.level("db::sched::x"));
+        assert_eq!(LogLevelFilter::Trace, filter.max());
+    }
+
+    // Tests that an empty spec only logs errors, that later directives for a module win, and that
+    // malformed directives are refused.
+    #[test]
+    fn test_parse() {
+        let filter = Filter::parse("").unwrap();
+        assert_eq!(LogLevelFilter::Error, filter.level("db::master"));
+        assert_eq!(LogLevelFilter::Error, filter.max());
+
+        let filter = Filter::parse("ext=debug,ext=off").unwrap();
+        assert_eq!(LogLevelFilter::Off, filter.level("ext"));
+        assert_eq!(LogLevelFilter::Error, filter.level("db"));
+
+        assert!(Filter::parse("db=loud").is_err());
+        assert!(Filter::parse("=info").is_err());
+    }
+}
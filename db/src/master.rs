@@ -14,33 +14,69 @@
  */
 
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::Write;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
 use std::mem::{size_of, transmute};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::ptr::write_volatile;
 use std::rc::Rc;
-use std::str::from_utf8;
+use std::str::{from_utf8, FromStr};
 use std::sync::Arc;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
 
 use super::alloc::Allocator;
+use super::audit::{self, Event};
+use super::backup;
 use super::common::{TableId, TenantId, PACKET_UDP_LEN};
+use super::cycles;
+use super::deadline;
+use super::drain;
+use super::error::{ExtensionError, ResultExt, SchedulerError, SplinterError};
 use super::container::Container;
-use super::context::Context;
+use super::context::{Context, MAX_ALLOC};
+use super::counters::{self, Counter};
+use super::crypt::{Key, KEY_LEN};
 use super::ext::*;
-use super::native::Native;
+use super::geo;
+use super::graph::Graph;
+use super::health;
+use super::member;
+use super::memory::{self, Report, TableMemory};
+use super::migrate;
+use super::multiop;
+use super::native::{self, Native, NativeOperation, Packets, Step};
+use super::partition;
+use super::profile;
+use super::raft;
+use super::readrep;
+use super::replica;
+use super::runtime;
+use super::sched::RoundRobin;
+use super::slow;
+use super::snapshot;
+use super::rpc::{parse_rpc_stamp, parse_rpc_tenant, push_rpc_header, MAX_DATAGRAM_PAYLOAD};
 use super::service::Service;
+use super::table::{Table, ACCESS_READ, ACCESS_WRITE};
 use super::task::{Task, TaskPriority};
 use super::tenant::Tenant;
+use super::wal;
 use super::wireformat::*;
+use super::zcopy;
 
 use e2d2::common::EmptyMetadata;
 use e2d2::headers::UdpHeader;
-use e2d2::interface::Packet;
+use e2d2::interface::{Packet, PmdPort};
 
 use spin::RwLock;
 
 // The number of buckets in the `tenants` hashtable inside of Master.
 const TENANT_BUCKETS: usize = 32;
 
+// The number of milliseconds draining a tenant waits for tasks in flight on it's behalf.
+const DRAIN_TIMEOUT_MS: u64 = 5000;
+
 /// The primary service in Sandstorm. Master is responsible managing tenants, extensions, and
 /// the database. It implements the Service trait, allowing it to generate schedulable tasks
 /// for data and extension related RPC requests.
@@ -56,6 +92,30 @@ pub struct Master {
 
     // Manager of the table heap. Required to allow writes to the database.
     heap: Arc<Allocator>,
+
+    // The number of extensions a tenant can install, and the total size of their .so files in
+    // bytes. Zero if there is no cap.
+    max_extensions: usize,
+    max_extension_bytes: u64,
+
+    // The longest key in bytes a request can carry. MAX_KEY_LEN if there is no cap besides the
+    // length fields.
+    max_key_len: usize,
+
+    // The allocation quota and memory limit of tenants placed in a service tier, applied when
+    // the tenant is created.
+    quotas: HashMap<TenantId, (usize, usize)>,
+
+    // The operator's credential, which administrative RPCs can carry to act on any tenant. Zero
+    // if there is none.
+    admin_credential: u64,
+
+    // The scheduler running on every core. Required to answer queues() RPCs.
+    schedulers: RwLock<Vec<Arc<RoundRobin>>>,
+
+    // The NIC ports the server receives requests on, along with their names, ordered by name.
+    // Required to report their counters on stats() RPCs, and their links on health() RPCs.
+    ports: RwLock<Vec<(String, Arc<PmdPort>)>>,
 }
 
 // Implementation of methods on Master.
@@ -104,9 +164,96 @@ impl Master {
             ],
             extensions: ExtensionManager::new(),
             heap: Arc::new(Allocator::new()),
+            max_extensions: 0,
+            max_extension_bytes: 0,
+            max_key_len: MAX_KEY_LEN,
+            quotas: HashMap::new(),
+            admin_credential: 0,
+            schedulers: RwLock::new(Vec::new()),
+            ports: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// Registers the NIC ports the server receives requests on, so that stats() RPCs can report
+    /// their counters, and health() RPCs their links.
+    ///
+    /// # Arguments
+    ///
+    /// * `ports`: The ports along with their names, ordered by name.
+    pub fn set_ports(&self, ports: Vec<(String, Arc<PmdPort>)>) {
+        *self.ports.write() = ports;
+    }
+
+    /// Registers the scheduler running on a core, replacing any scheduler registered for the
+    /// same core before, so that queues() RPCs can snapshot it.
+    ///
+    /// # Arguments
+    ///
+    /// * `sched`: The scheduler.
+    pub fn add_scheduler(&self, sched: Arc<RoundRobin>) {
+        let mut schedulers = self.schedulers.write();
+        schedulers.retain(|other| other.core() != sched.core());
+        schedulers.push(sched);
+        schedulers.sort_by_key(|sched| sched.core());
+    }
+
+    /// Sets the quotas a tenant receives once it is created, either at startup or through the
+    /// tenant() RPC. Quotas passed in on the RPC can only tighten them.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant`:      The tenant.
+    /// * `alloc_quota`: The number of bytes an invocation can allocate, zero for the default.
+    /// * `mem_limit`:   The number of bytes the tenant's objects can take up, zero for no limit.
+    pub fn set_quotas(&mut self, tenant: TenantId, alloc_quota: usize, mem_limit: usize) {
+        self.quotas.insert(tenant, (alloc_quota, mem_limit));
+    }
+
+    /// Sets the operator's credential. Administrative RPCs carrying it can act on any tenant,
+    /// including those created at startup, which no other credential can act on.
+    ///
+    /// # Arguments
+    ///
+    /// * `credential`: The credential, zero if there is none.
+    pub fn set_admin_credential(&mut self, credential: u64) {
+        self.admin_credential = credential;
+    }
+
+    // Returns a tenant created at startup, with the quotas it was configured with.
+    fn new_tenant(&self, tenant_id: TenantId) -> Tenant {
+        match self.quotas.get(&tenant_id) {
+            Some(&(alloc_quota, mem_limit)) => {
+                Tenant::with_quota(tenant_id, 0, alloc_quota, mem_limit)
+            }
+            None => Tenant::new(tenant_id),
         }
     }
 
+    /// Caps the extensions every tenant can install. Installing an extension under a name the
+    /// tenant already installed one under replaces it, and only the new one counts.
+    ///
+    /// # Arguments
+    ///
+    /// * `count`: The number of extensions a tenant can install, zero for no cap.
+    /// * `bytes`: The total size of the .so files of a tenant's extensions, zero for no cap.
+    pub fn limit_extensions(&mut self, count: usize, bytes: u64) {
+        self.max_extensions = count;
+        self.max_extension_bytes = bytes;
+    }
+
+    /// Caps the length of the keys requests can carry. Gets, puts, multigets and multiops with
+    /// a longer key are refused with StatusKeyTooLong.
+    ///
+    /// # Arguments
+    ///
+    /// * `len`: The longest key in bytes, zero for no cap besides the 16 bit length fields.
+    pub fn limit_keys(&mut self, len: usize) {
+        self.max_key_len = match len {
+            0 => MAX_KEY_LEN,
+            len => len.min(MAX_KEY_LEN),
+        };
+    }
+
     /// Adds a tenant and a table full of objects.
     ///
     /// # Arguments
@@ -118,7 +265,7 @@ impl Master {
     /// * `num`:       The number of objects to be added to the data table.
     pub fn fill_test(&self, tenant_id: TenantId, table_id: TableId, num: u32) {
         // Create a tenant containing the table.
-        let tenant = Tenant::new(tenant_id);
+        let tenant = self.new_tenant(tenant_id);
         tenant.create_table(table_id);
 
         let table = tenant
@@ -151,11 +298,13 @@ impl Master {
     ///
     /// * `tenant_id`: Identifier of the tenant to be added. Any existing tenant with the same
     ///                identifier will be overwritten.
-    /// * `num`:       The number of objects to be added to the data table.
-    pub fn fill_tao(&self, tenant_id: TenantId, num: u32) {
+    /// * `graph`:     The graph of objects and associations to be added to the tables.
+    pub fn fill_tao(&self, tenant_id: TenantId, graph: &Graph) {
+        let num = graph.objects();
+
         // Create a tenant containing two tables, one for objects, and one for
         // associations.
-        let tenant = Tenant::new(tenant_id);
+        let tenant = self.new_tenant(tenant_id);
         tenant.create_table(1); // Holds tao objects.
         tenant.create_table(2); // Holds tao assocs.
 
@@ -188,8 +337,8 @@ impl Master {
         // Assocs have a 22 byte value (all zeros).
         let val = vec![0; 22];
 
-        // Populate the assoc table. Each object gets four assocs to it's
-        // neighbours.
+        // Populate the assoc table. Each object gets as many assocs to it's
+        // neighbours as the graph's fan-out says.
         for i in 1..(num + 1) {
             let temp: [u8; 4] = unsafe { transmute(i.to_le()) };
             &key[0..4].copy_from_slice(&temp);
@@ -197,8 +346,8 @@ impl Master {
             // Assoc list for this particular object.
             let mut list: Vec<u8> = Vec::new();
 
-            for a in 1u32..5u32 {
-                let temp: [u8; 4] = unsafe { transmute(((i + a) % num).to_le()) };
+            for n in graph.neighbours(i) {
+                let temp: [u8; 4] = unsafe { transmute(n.to_le()) };
                 &key[10..14].copy_from_slice(&temp);
                 list.extend_from_slice(&temp);
                 list.extend_from_slice(&[0; 12]);
@@ -233,7 +382,7 @@ impl Master {
     pub fn fill_aggregate(&self, tenant_id: TenantId, table_id: TableId, num: u32) {
         // One table for the tenant. Both, objects and indirection lists will be
         // stored in here.
-        let tenant = Tenant::new(tenant_id);
+        let tenant = self.new_tenant(tenant_id);
         tenant.create_table(table_id);
 
         let table = tenant
@@ -291,7 +440,9 @@ impl Master {
         self.insert_tenant(tenant);
     }
 
-    /// Loads the get(), put(), tao(), and bad() extensions.
+    /// Loads the get(), put(), tao(), bad(), long(), aggregate(), agg(), bfs(),
+    /// filter(), topk(), vector(), index_put(), index_lookup(), transfer(), project(), score(),
+    /// downsample() and getall() extensions.
     ///
     /// # Arguments
     ///
@@ -332,6 +483,92 @@ impl Master {
         if self.extensions.load(name, tenant, "aggregate") == false {
             panic!("Failed to load aggregate() extension.");
         }
+
+        // Load the agg() extension.
+        let name = "../ext/agg/target/release/libagg.so";
+        if self.extensions.load(name, tenant, "agg") == false {
+            panic!("Failed to load agg() extension.");
+        }
+
+        // Load the bfs() extension.
+        let name = "../ext/bfs/target/release/libbfs.so";
+        if self.extensions.load(name, tenant, "bfs") == false {
+            panic!("Failed to load bfs() extension.");
+        }
+
+        // Load the filter() extension.
+        let name = "../ext/filter/target/release/libfilter.so";
+        if self.extensions.load(name, tenant, "filter") == false {
+            panic!("Failed to load filter() extension.");
+        }
+
+        // Load the topk() extension.
+        let name = "../ext/topk/target/release/libtopk.so";
+        if self.extensions.load(name, tenant, "topk") == false {
+            panic!("Failed to load topk() extension.");
+        }
+
+        // Load the vector() extension.
+        let name = "../ext/vector/target/release/libvector.so";
+        if self.extensions.load(name, tenant, "vector") == false {
+            panic!("Failed to load vector() extension.");
+        }
+
+        // Load the index_put() and index_lookup() extensions.
+        let name = "../ext/index_put/target/release/libindex_put.so";
+        if self.extensions.load(name, tenant, "index_put") == false {
+            panic!("Failed to load index_put() extension.");
+        }
+
+        let name = "../ext/index_lookup/target/release/libindex_lookup.so";
+        if self.extensions.load(name, tenant, "index_lookup") == false {
+            panic!("Failed to load index_lookup() extension.");
+        }
+
+        // Load the transfer() extension.
+        let name = "../ext/transfer/target/release/libtransfer.so";
+        if self.extensions.load(name, tenant, "transfer") == false {
+            panic!("Failed to load transfer() extension.");
+        }
+
+        // Load the project() extension.
+        let name = "../ext/project/target/release/libproject.so";
+        if self.extensions.load(name, tenant, "project") == false {
+            panic!("Failed to load project() extension.");
+        }
+
+        // Load the score() extension.
+        let name = "../ext/score/target/release/libscore.so";
+        if self.extensions.load(name, tenant, "score") == false {
+            panic!("Failed to load score() extension.");
+        }
+
+        // Load the downsample() extension.
+        let name = "../ext/downsample/target/release/libdownsample.so";
+        if self.extensions.load(name, tenant, "downsample") == false {
+            panic!("Failed to load downsample() extension.");
+        }
+
+        // Load the getall() extension, built on continuations rather than generators.
+        let name = "../ext/getall/target/release/libgetall.so";
+        if self.extensions.load(name, tenant, "getall") == false {
+            panic!("Failed to load getall() extension.");
+        }
+    }
+
+    /// Loads a single one of the extensions under `ext`, as built by it's Makefile.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant`: Identifier of the tenant to load the extension for.
+    /// * `name`:   The name of the extension, which is also the name of it's directory.
+    ///
+    /// # Return
+    ///
+    /// True if the extension was loaded. False otherwise.
+    pub fn load_extension(&self, tenant: TenantId, name: &str) -> bool {
+        let path = format!("../ext/{}/target/release/lib{}.so", name, name);
+        self.extensions.load(&path, tenant, name)
     }
 
     /// Loads the get(), put(), and tao() extensions once, and shares them across multiple tenants.
@@ -397,6 +634,22 @@ impl Master {
         map.insert(tenant.id(), Arc::new(tenant));
     }
 
+    /// This method removes a tenant from Master. Tasks already holding a handle to the tenant
+    /// keep it's tables alive until they complete.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant_id`: The identifier for the tenant to be removed.
+    ///
+    /// # Return
+    ///
+    /// The removed tenant if it existed.
+    fn remove_tenant(&self, tenant_id: TenantId) -> Option<Arc<Tenant>> {
+        let bucket = (tenant_id & 0xff) as usize & (TENANT_BUCKETS - 1);
+        let mut map = self.tenants[bucket].write();
+        map.remove(&tenant_id)
+    }
+
     /// Handles the Get() RPC request.
     ///
     /// A hash table lookup is performed on a supplied tenant id, table id, and key. If successfull,
@@ -413,7 +666,6 @@ impl Master {
     ///
     /// A Native task that can be scheduled by the database. In the case of an error, the passed
     /// in request and response packets are returned with the response status appropriately set.
-    #[allow(unreachable_code)]
     #[allow(unused_assignments)]
     fn get(
         &self,
@@ -426,46 +678,41 @@ impl Master {
             Packet<UdpHeader, EmptyMetadata>,
         ),
     > {
-        // First, parse the request packet.
-        let req = req.parse_header::<GetRequest>();
-
-        // Read fields off the request header.
-        let mut tenant_id: TenantId = 0;
-        let mut table_id: TableId = 0;
-        let mut key_length = 0;
-        let mut rpc_stamp = 0;
-
-        {
-            let hdr = req.get_header();
-            tenant_id = hdr.common_header.tenant as TenantId;
-            table_id = hdr.table_id as TableId;
-            key_length = hdr.key_length;
-            rpc_stamp = hdr.common_header.stamp;
-        }
+        // First, read fields off the request header. The key follows it.
+        let hdr = KeyedRequest::read(req.get_payload());
+        let hdr = match hdr {
+            Some(hdr) => hdr,
+            None => {
+                let status = RpcStatus::StatusMalformedRequest;
+                return self.refuse(OpCode::SandstormGetRpc, status, &[], req, res);
+            }
+        };
+        let tenant_id = hdr.tenant as TenantId;
+        let table_id = hdr.table_id as TableId;
+        let key_offset = hdr.header_length;
+        let key_length = hdr.key_length;
+        let rpc_stamp = hdr.stamp;
 
         // Next, add a header to the response packet.
-        let mut res = res.push_header(&GetResponse::new(
-            rpc_stamp,
-            OpCode::SandstormGetRpc,
-            tenant_id,
-        )).expect("Failed to setup GetResponse");
+        let hdr = GetResponse::new(rpc_stamp, OpCode::SandstormGetRpc, tenant_id);
+        let mut res = match push_rpc_header(res, &hdr) {
+            Ok(res) => res,
+            Err(res) => return Err((req, res)),
+        };
 
         // If the payload size is less than the key length, return an error.
-        if req.get_payload().len() < key_length as usize {
+        if req.get_payload().len() < key_offset + key_length {
             res.get_mut_header().common_header.status = RpcStatus::StatusMalformedRequest;
-            return Err((
-                req.deparse_header(PACKET_UDP_LEN as usize),
-                res.deparse_header(PACKET_UDP_LEN as usize),
-            ));
+            return Err((req, res.deparse_header(PACKET_UDP_LEN as usize)));
         }
 
         // Lookup the tenant, and get a handle to the allocator. Required to avoid capturing a
-        // reference to Master in the generator below.
+        // reference to Master in the operation below.
         let tenant = self.get_tenant(tenant_id);
         let alloc = self.heap.clone();
 
-        // Create a generator for this request.
-        let gen = Box::new(move || {
+        // Create a native operation for this request.
+        let op = native::once(move || {
             let mut status: RpcStatus = RpcStatus::StatusTenantDoesNotExist;
 
             let outcome =
@@ -475,12 +722,28 @@ impl Master {
                                 status = RpcStatus::StatusTableDoesNotExist;
                                 tenant.get_table(table_id)
                             })
-                // If the table exists, lookup the provided key, and update
+                // If the table exists, check if the tenant may read it.
+                .and_then(| table | {
+                                status = RpcStatus::StatusPermissionDenied;
+                                match table.permits(tenant_id, ACCESS_READ) {
+                                    true => Some(table),
+                                    false => None,
+                                }
+                            })
+                // Raft-replicated tables are only read at the leader.
+                .and_then(| table | {
+                                status = RpcStatus::StatusNotLeader;
+                                match raft::serves(table.owner(), table_id) {
+                                    true => Some(table),
+                                    false => None,
+                                }
+                            })
+                // If the table can be read, lookup the provided key, and update
                 // the status of the rpc.
                 .and_then(| table | {
                                 status = RpcStatus::StatusObjectDoesNotExist;
-                                let (key, _) = req.get_payload().split_at(key_length as usize);
-                                table.get(key)
+                                let key = &req.get_payload()[key_offset..];
+                                table.get(&key[..key_length])
                             })
                 // If the lookup succeeded, obtain the value, and update the
                 // status of the rpc.
@@ -491,7 +754,15 @@ impl Master {
                 // If the value was obtained, then write to the response packet
                 // and update the status of the rpc.
                 .and_then(| (_k, value) | {
+                                status = RpcStatus::StatusValueTooLarge;
+                                if size_of::<GetResponse>() + value.len() > MAX_DATAGRAM_PAYLOAD {
+                                    return None;
+                                }
+
                                 status = RpcStatus::StatusInternalError;
+                                if zcopy::append(&mut res, &value) {
+                                    return Some(());
+                                }
                                 res.add_to_payload_tail(value.len(), &value[..]).ok()
                             })
                 // If the value was written to the response payload,
@@ -505,7 +776,7 @@ impl Master {
                 // The RPC completed successfully. Update the response header with
                 // the status and value length.
                 Some(()) => {
-                    let val_len = res.get_payload().len() as u32;
+                    let val_len = (res.get_payload().len() + res.chained_len()) as u32;
 
                     let hdr: &mut GetResponse = res.get_mut_header();
                     hdr.value_length = val_len;
@@ -518,19 +789,15 @@ impl Master {
                 }
             }
 
-            // Deparse request and response packets down to UDP, and return from the generator.
+            // Deparse request and response packets down to UDP, and return from the operation.
             return Some((
-                req.deparse_header(PACKET_UDP_LEN as usize),
+                req,
                 res.deparse_header(PACKET_UDP_LEN as usize),
             ));
-
-            // XXX: This yield is required to get the compiler to compile this closure into a
-            // generator. It is unreachable and benign.
-            yield 0;
         });
 
         // Return a native task.
-        return Ok(Box::new(Native::new(TaskPriority::REQUEST, gen)));
+        return Ok(Box::new(Native::new(TaskPriority::REQUEST, tenant_id, op)));
     }
 
     /// Handles the put() RPC request.
@@ -547,7 +814,6 @@ impl Master {
     ///
     /// A Native task that can be scheduled by the database. In the case of an error, the passed
     /// in request and response packets are returned with the response status appropriately set.
-    #[allow(unreachable_code)]
     #[allow(unused_assignments)]
     fn put(
         &self,
@@ -560,93 +826,124 @@ impl Master {
             Packet<UdpHeader, EmptyMetadata>,
         ),
     > {
-        // First, parse the request packet.
-        let req = req.parse_header::<PutRequest>();
-
-        // Read fields off the request header.
-        let mut tenant_id: TenantId = 0;
-        let mut table_id: TableId = 0;
-        let mut key_length = 0;
-        let mut rpc_stamp = 0;
-
-        {
-            let hdr = req.get_header();
-            tenant_id = hdr.common_header.tenant as TenantId;
-            table_id = hdr.table_id as TableId;
-            key_length = hdr.key_length;
-            rpc_stamp = hdr.common_header.stamp;
-        }
+        // First, read fields off the request header. The key and value follow it.
+        let hdr = KeyedRequest::read(req.get_payload());
+        let hdr = match hdr {
+            Some(hdr) => hdr,
+            None => {
+                let status = RpcStatus::StatusMalformedRequest;
+                return self.refuse(OpCode::SandstormPutRpc, status, &[], req, res);
+            }
+        };
+        let tenant_id = hdr.tenant as TenantId;
+        let table_id = hdr.table_id as TableId;
+        let key_offset = hdr.header_length;
+        let key_length = hdr.key_length;
+        let rpc_stamp = hdr.stamp;
 
         // Next, write a header into the response packet.
-        let mut res = res.push_header(&PutResponse::new(
-            rpc_stamp,
-            OpCode::SandstormPutRpc,
-            tenant_id,
-        )).expect("Failed to push PutResponse");
+        let hdr = PutResponse::new(rpc_stamp, OpCode::SandstormPutRpc, tenant_id);
+        let mut res = match push_rpc_header(res, &hdr) {
+            Ok(res) => res,
+            Err(res) => return Err((req, res)),
+        };
 
         // If the payload size is less than the key length, return an error.
-        if req.get_payload().len() < key_length as usize {
+        if req.get_payload().len() < key_offset + key_length {
             res.get_mut_header().common_header.status = RpcStatus::StatusMalformedRequest;
-            return Err((
-                req.deparse_header(PACKET_UDP_LEN as usize),
-                res.deparse_header(PACKET_UDP_LEN as usize),
-            ));
+            return Err((req, res.deparse_header(PACKET_UDP_LEN as usize)));
         }
 
         // Lookup the tenant, and get a handle to the allocator. Required to avoid capturing a
-        // reference to Master in the generator below.
+        // reference to Master in the operation below.
         let tenant = self.get_tenant(tenant_id);
         let alloc = self.heap.clone();
 
-        // Create a generator for this request.
-        let gen = Box::new(move || {
+        // Create a native operation for this request.
+        let op = native::then(move || {
             let mut status: RpcStatus = RpcStatus::StatusTenantDoesNotExist;
+            let mut seq = 0;
+            let mut entry = None;
 
             // If the tenant exists, check if it has a table with the given id,
             // and update the status of the rpc.
-            let outcome = tenant.and_then(|tenant| {
-                status = RpcStatus::StatusTableDoesNotExist;
-                tenant.get_table(table_id)
-            });
+            let outcome = tenant
+                .and_then(|tenant| {
+                    status = RpcStatus::StatusTableDoesNotExist;
+                    tenant.get_table(table_id)
+                })
+                .and_then(|table| {
+                    status = RpcStatus::StatusPermissionDenied;
+                    match table.permits(tenant_id, ACCESS_WRITE) {
+                        true => Some(table),
+                        false => None,
+                    }
+                });
 
             // If the table exists, update the status of the rpc, and allocate an
             // object.
             if let Some(table) = outcome {
                 // Get a reference to the key and value.
                 status = RpcStatus::StatusMalformedRequest;
-                let (key, val) = req.get_payload().split_at(key_length as usize);
-
-                // If there is a value, then write it in.
-                if val.len() > 0 {
+                let (key, val) = req.get_payload()[key_offset..].split_at(key_length);
+
+                // If there is a value that fits within the table's quota, then write it in.
+                if val.len() > 0 && !table.admits(alloc.footprint(key.len(), val.len())) {
+                    status = RpcStatus::StatusMemoryExhausted;
+                } else if val.len() > 0 && raft::governs(table.owner(), table_id) {
+                    // Writes to Raft-replicated tables go through the log, and are applied once
+                    // the group commits them.
+                    let rec = replica::Record {
+                        op: replica::OP_PUT,
+                        tenant: table.owner(),
+                        table: table_id,
+                        key: key,
+                        val: val,
+                    };
+                    entry = raft::propose(&rec);
+                    status = match entry {
+                        Some(_) => RpcStatus::StatusOk,
+                        None => RpcStatus::StatusNotLeader,
+                    };
+                } else if val.len() > 0 {
                     status = RpcStatus::StatusInternalError;
-                    let _result = alloc.object(tenant_id, table_id, key, val)
-                                    // If the allocation succeeds, update the
-                                    // status of the rpc, and insert the object
-                                    // into the table.
-                                    .and_then(| (key, obj) | {
-                                        status = RpcStatus::StatusOk;
-                                        table.put(key, obj);
-                                        Some(())
-                                    });
+
+                    // If the allocation succeeds, update the status of the rpc, and insert the
+                    // object into the table.
+                    if let Some((k, obj)) = alloc.object(tenant_id, table_id, key, val) {
+                        status = RpcStatus::StatusOk;
+                        let rec = replica::Record {
+                            op: replica::OP_PUT,
+                            tenant: table.owner(),
+                            table: table_id,
+                            key: key,
+                            val: val,
+                        };
+                        seq = table.exclusive(key, || replica::write(&rec, || table.put(k, obj)));
+                    }
                 }
             }
 
-            // Update the response header.
-            res.get_mut_header().common_header.status = status;
+            // Hold the response back until the backup has the object, or until the group commits
+            // it, if the table is replicated with Raft.
+            replicated(seq, entry, move |lost| {
+                if lost {
+                    status = RpcStatus::StatusNotLeader;
+                }
 
-            // Deparse request and response packets to UDP, and return from the generator.
-            return Some((
-                req.deparse_header(PACKET_UDP_LEN as usize),
-                res.deparse_header(PACKET_UDP_LEN as usize),
-            ));
+                // Update the response header.
+                res.get_mut_header().common_header.status = status;
 
-            // XXX: This yield is required to get the compiler to compile this closure into a
-            // generator. It is unreachable and benign.
-            yield 0;
+                // Deparse request and response packets to UDP, and return from the operation.
+                Some((
+                    req,
+                    res.deparse_header(PACKET_UDP_LEN as usize),
+                ))
+            })
         });
 
         // Create and return a native task.
-        return Ok(Box::new(Native::new(TaskPriority::REQUEST, gen)));
+        return Ok(Box::new(Native::new(TaskPriority::REQUEST, tenant_id, op)));
     }
 
     /// Handles the multiget() RPC request.
@@ -663,7 +960,6 @@ impl Master {
     ///
     /// A Native task that can be scheduled by the database. In the case of an error, the passed
     /// in request and response packets are returned with the response status appropriately set.
-    #[allow(unreachable_code)]
     #[allow(unused_assignments)]
     fn multiget(
         &self,
@@ -676,49 +972,43 @@ impl Master {
             Packet<UdpHeader, EmptyMetadata>,
         ),
     > {
-        // First, parse the request packet.
-        let req = req.parse_header::<MultiGetRequest>();
-
-        // Read fields off the request header.
-        let mut tenant_id: TenantId = 0;
-        let mut table_id: TableId = 0;
-        let mut key_length = 0;
-        let mut num_keys = 0;
-        let mut rpc_stamp = 0;
-
-        {
-            let hdr = req.get_header();
-            tenant_id = hdr.common_header.tenant as TenantId;
-            table_id = hdr.table_id as TableId;
-            key_length = hdr.key_len;
-            num_keys = hdr.num_keys;
-            rpc_stamp = hdr.common_header.stamp;
-        }
+        // First, read fields off the request header. The keys follow it.
+        let hdr = KeyedRequest::read(req.get_payload());
+        let hdr = match hdr {
+            Some(hdr) => hdr,
+            None => {
+                let status = RpcStatus::StatusMalformedRequest;
+                return self.refuse(OpCode::SandstormMultiGetRpc, status, &[], req, res);
+            }
+        };
+        let tenant_id = hdr.tenant as TenantId;
+        let table_id = hdr.table_id as TableId;
+        let key_offset = hdr.header_length;
+        let key_length = hdr.key_length;
+        let num_keys = hdr.num_keys;
+        let rpc_stamp = hdr.stamp;
 
         // Next, add a header to the response packet.
-        let mut res = res.push_header(&MultiGetResponse::new(
-            rpc_stamp,
-            OpCode::SandstormMultiGetRpc,
-            tenant_id,
-            0,
-        )).expect("Failed to setup MultiGetResponse");
-
-        // If the payload size is less than the key length, return an error.
-        if req.get_payload().len() < ((key_length as u32) * num_keys) as usize {
+        let hdr = MultiGetResponse::new(rpc_stamp, OpCode::SandstormMultiGetRpc, tenant_id, 0);
+        let mut res = match push_rpc_header(res, &hdr) {
+            Ok(res) => res,
+            Err(res) => return Err((req, res)),
+        };
+
+        // If the payload size is less than the length of the keys, return an error.
+        let keys_length = (key_length as u64) * (num_keys as u64);
+        if key_length == 0 || ((req.get_payload().len() - key_offset) as u64) < keys_length {
             res.get_mut_header().common_header.status = RpcStatus::StatusMalformedRequest;
-            return Err((
-                req.deparse_header(PACKET_UDP_LEN as usize),
-                res.deparse_header(PACKET_UDP_LEN as usize),
-            ));
+            return Err((req, res.deparse_header(PACKET_UDP_LEN as usize)));
         }
 
         // Lookup the tenant, and get a handle to the allocator. Required to avoid capturing a
-        // reference to Master in the generator below.
+        // reference to Master in the operation below.
         let tenant = self.get_tenant(tenant_id);
         let alloc = self.heap.clone();
 
-        // Create a generator for this request.
-        let gen = Box::new(move || {
+        // Create a native operation for this request.
+        let op = native::once(move || {
             let mut n_recs: u32 = 0;
             let mut status: RpcStatus = RpcStatus::StatusTenantDoesNotExist;
 
@@ -728,20 +1018,36 @@ impl Master {
                 tenant.and_then(| tenant | {
                                 status = RpcStatus::StatusTableDoesNotExist;
                                 tenant.get_table(table_id)
+                            })
+                // If the table exists, check if the tenant may read it.
+                .and_then(| table | {
+                                status = RpcStatus::StatusPermissionDenied;
+                                match table.permits(tenant_id, ACCESS_READ) {
+                                    true => Some(table),
+                                    false => None,
+                                }
+                            })
+                // Raft-replicated tables are only read at the leader.
+                .and_then(| table | {
+                                status = RpcStatus::StatusNotLeader;
+                                match raft::serves(table.owner(), table_id) {
+                                    true => Some(table),
+                                    false => None,
+                                }
                             });
 
-            // If the table exists, then lookup the keys in the database.
+            // If the table can be read, then lookup the keys in the database.
             if let Some(table) = outcome {
                 status = RpcStatus::StatusObjectDoesNotExist;
 
                 // Iterate across keys in the request payload. There are `num_keys` keys, each
                 // of length `key_length`.
                 let mut n = 0;
-                for key in req.get_payload().chunks(key_length as usize) {
+                for key in req.get_payload()[key_offset..].chunks(key_length) {
                     n += 1;
                     // Corner case: We've either already seen `num_keys` keys or the current key
                     // is not `key_length` bytes long.
-                    if n > num_keys || key.len() != key_length as usize {
+                    if n > num_keys || key.len() != key_length {
                         break;
                     }
 
@@ -776,19 +1082,257 @@ impl Master {
                 res.get_mut_header().num_records = n_recs;
             }
 
-            // Deparse request and response packets to UDP, and return from the generator.
+            // Deparse request and response packets to UDP, and return from the operation.
             return Some((
-                req.deparse_header(PACKET_UDP_LEN as usize),
+                req,
                 res.deparse_header(PACKET_UDP_LEN as usize),
             ));
+        });
+
+        // Create and return a native task.
+        return Ok(Box::new(Native::new(TaskPriority::REQUEST, tenant_id, op)));
+    }
+
+    /// Handles the multiop() RPC request.
+    ///
+    /// If issued by a valid tenant, executes a sequence of gets, puts and deletes in order, and
+    /// returns the status and value of every one of them. Operations are executed until one's
+    /// result does not fit into the response; the ones after it are not executed, and the client
+    /// is expected to issue them again.
+    ///
+    /// # Arguments
+    ///
+    /// * `req`: The RPC request packet sent by the client, parsed upto it's UDP header.
+    /// * `res`: The RPC response packet, with pre-allocated headers upto UDP.
+    ///
+    /// # Return
+    ///
+    /// A Native task that can be scheduled by the database. In the case of an error, the passed
+    /// in request and response packets are returned with the response status appropriately set.
+    #[allow(unused_assignments)]
+    fn multiop(
+        &self,
+        req: Packet<UdpHeader, EmptyMetadata>,
+        res: Packet<UdpHeader, EmptyMetadata>,
+    ) -> Result<
+        Box<Task>,
+        (
+            Packet<UdpHeader, EmptyMetadata>,
+            Packet<UdpHeader, EmptyMetadata>,
+        ),
+    > {
+        // First, read fields off the request header. The operations follow it.
+        let (hdr, ops_offset) = {
+            let mut reader = Reader::new(req.get_payload());
+            let hdr = read_request_header(&mut reader).and_then(|(tenant, stamp)| {
+                reader.u32_le().map(|num_ops| (tenant, stamp, num_ops))
+            });
+            (hdr, reader.offset())
+        };
+        let (tenant_id, rpc_stamp, num_ops) = match hdr {
+            Some((tenant, stamp, num_ops)) => (tenant as TenantId, stamp, num_ops),
+            None => {
+                let status = RpcStatus::StatusMalformedRequest;
+                return self.refuse(OpCode::SandstormMultiOpRpc, status, &[], req, res);
+            }
+        };
+
+        // Next, add a header to the response packet.
+        let mut res = match push_rpc_header(res, &MultiOpResponse::new(rpc_stamp, tenant_id, 0)) {
+            Ok(res) => res,
+            Err(res) => return Err((req, res)),
+        };
+
+        // Lookup the tenant, and get a handle to the allocator. Required to avoid capturing a
+        // reference to Master in the operation below.
+        let tenant = match self.get_tenant(tenant_id) {
+            Some(tenant) => tenant,
+
+            None => {
+                res.get_mut_header().common_header.status = RpcStatus::StatusTenantDoesNotExist;
+                return Err((
+                    req,
+                    res.deparse_header(PACKET_UDP_LEN as usize),
+                ));
+            }
+        };
+        let alloc = self.heap.clone();
+
+        // Create a native operation for this request.
+        let op = native::then(move || {
+            let mut n_results: u32 = 0;
+            let mut status = RpcStatus::StatusOk;
+            let mut seq = 0;
+            let mut entry = None;
+
+            {
+                let mut ops = &req.get_payload()[ops_offset..];
+                while n_results < num_ops {
+                    // A truncated operation means the whole request is malformed.
+                    let (op, len) = match multiop::parse_entry(ops) {
+                        Some(parsed) => parsed,
+
+                        None => {
+                            status = RpcStatus::StatusMalformedRequest;
+                            break;
+                        }
+                    };
+                    ops = &ops[len..];
+
+                    // Reserve space for the result before executing the operation, so that an
+                    // operation is never executed without the client hearing about it.
+                    let offset = res.get_payload().len();
+                    let hdr = multiop::result_header(RpcStatus::StatusOk as u8, 0);
+                    if res.add_to_payload_tail(hdr.len(), &hdr).is_err() {
+                        break;
+                    }
+
+                    // Gets need read access to the table, puts and deletes write access.
+                    let access = match op.opcode == OpCode::SandstormGetRpc as u8 {
+                        true => ACCESS_READ,
+                        false => ACCESS_WRITE,
+                    };
+                    let mut op_status = RpcStatus::StatusTableDoesNotExist;
+                    let table = tenant.get_table(op.table).and_then(|table| {
+                        op_status = RpcStatus::StatusPermissionDenied;
+                        match table.permits(tenant_id, access) {
+                            true => Some(table),
+                            false => None,
+                        }
+                    });
+
+                    // Raft-replicated tables are only read and written at the leader, and writes
+                    // to them go through the log instead of straight to the table.
+                    let table = table.and_then(|table| {
+                        op_status = RpcStatus::StatusNotLeader;
+                        match raft::serves(table.owner(), op.table) {
+                            true => Some(table),
+                            false => None,
+                        }
+                    });
+                    let logged = table
+                        .as_ref()
+                        .map_or(false, |table| raft::governs(table.owner(), op.table));
+
+                    if op.opcode == OpCode::SandstormGetRpc as u8 {
+                        if let Some(table) = table {
+                            op_status = RpcStatus::StatusObjectDoesNotExist;
+                            let value = table
+                                .get(op.key)
+                                .and_then(|object| alloc.resolve(object))
+                                .map(|(_k, value)| value);
+
+                            if let Some(value) = value {
+                                // If the value does not fit, take back the reserved space and
+                                // stop; the get had no side effects.
+                                if res.add_to_payload_tail(value.len(), &value[..]).is_err() {
+                                    let _ = res.remove_from_payload_tail(hdr.len());
+                                    break;
+                                }
+
+                                op_status = RpcStatus::StatusOk;
+                                let prefix =
+                                    multiop::result_header(op_status.clone() as u8, value.len());
+                                res.get_mut_payload()[offset..offset + hdr.len()]
+                                    .copy_from_slice(&prefix);
+                            }
+                        }
+                    } else if op.opcode == OpCode::SandstormPutRpc as u8 {
+                        if let Some(table) = table {
+                            op_status = RpcStatus::StatusMalformedRequest;
+                            let size = alloc.footprint(op.key.len(), op.val.len());
+                            if op.val.len() > 0 && !table.admits(size) {
+                                op_status = RpcStatus::StatusMemoryExhausted;
+                            } else if op.val.len() > 0 && logged {
+                                let rec = replica::Record {
+                                    op: replica::OP_PUT,
+                                    tenant: table.owner(),
+                                    table: op.table,
+                                    key: op.key,
+                                    val: op.val,
+                                };
+                                op_status = RpcStatus::StatusNotLeader;
+                                if let Some(appended) = raft::propose(&rec) {
+                                    op_status = RpcStatus::StatusOk;
+                                    entry = Some(appended);
+                                }
+                            } else if op.val.len() > 0 {
+                                op_status = RpcStatus::StatusInternalError;
+                                if let Some((key, obj)) =
+                                    alloc.object(tenant_id, op.table, op.key, op.val)
+                                {
+                                    op_status = RpcStatus::StatusOk;
+                                    let rec = replica::Record {
+                                        op: replica::OP_PUT,
+                                        tenant: table.owner(),
+                                        table: op.table,
+                                        key: op.key,
+                                        val: op.val,
+                                    };
+                                    seq = table.exclusive(op.key, || {
+                                        replica::write(&rec, || table.put(key, obj))
+                                    });
+                                }
+                            }
+                        }
+                    } else if op.opcode == OpCode::SandstormDeleteRpc as u8 {
+                        if let Some(table) = table {
+                            op_status = RpcStatus::StatusMalformedRequest;
+                            if op.key.len() > 0 {
+                                op_status = RpcStatus::StatusObjectDoesNotExist;
+                                if table.get(op.key).is_some() {
+                                    op_status = RpcStatus::StatusOk;
+                                    let rec = replica::Record {
+                                        op: replica::OP_DELETE,
+                                        tenant: table.owner(),
+                                        table: op.table,
+                                        key: op.key,
+                                        val: &[],
+                                    };
+                                    if !logged {
+                                        seq = table.exclusive(op.key, || {
+                                            replica::write(&rec, || table.delete(op.key))
+                                        });
+                                    } else if let Some(appended) = raft::propose(&rec) {
+                                        entry = Some(appended);
+                                    } else {
+                                        op_status = RpcStatus::StatusNotLeader;
+                                    }
+                                }
+                            }
+                        }
+                    } else {
+                        op_status = RpcStatus::StatusInvalidOperation;
+                    }
+
+                    res.get_mut_payload()[offset] = op_status as u8;
+                    n_results += 1;
+                }
+            }
+
+            // Hold the response back until the backup has every write. Records are acknowledged
+            // in order, so waiting on the last is enough. Likewise for writes to Raft-replicated
+            // tables, which are committed in order. If the last was lost, earlier ones may have
+            // been too, so the whole response is failed.
+            replicated(seq, entry, move |lost| {
+                if lost {
+                    status = RpcStatus::StatusNotLeader;
+                }
+
+                // Update the response header.
+                res.get_mut_header().common_header.status = status;
+                res.get_mut_header().num_results = n_results;
 
-            // XXX: This yield is required to get the compiler to compile this closure into a
-            // generator. It is unreachable and benign.
-            yield 0;
+                // Deparse request and response packets to UDP, and return from the operation.
+                Some((
+                    req,
+                    res.deparse_header(PACKET_UDP_LEN as usize),
+                ))
+            })
         });
 
         // Create and return a native task.
-        return Ok(Box::new(Native::new(TaskPriority::REQUEST, gen)));
+        return Ok(Box::new(Native::new(TaskPriority::REQUEST, tenant_id, op)));
     }
 
     /// Handles the invoke RPC request.
@@ -816,44 +1360,52 @@ impl Master {
             Packet<UdpHeader, EmptyMetadata>,
         ),
     > {
-        // First, parse the request packet.
-        let req = req.parse_header::<InvokeRequest>();
-
-        // Read fields of the request header.
-        let mut tenant_id: TenantId = 0;
-        let mut name_length: usize = 0;
-        let mut args_length: usize = 0;
-        let mut rpc_stamp = 0;
-
-        {
-            let hdr = req.get_header();
-            tenant_id = hdr.common_header.tenant as TenantId;
-            name_length = hdr.name_length as usize;
-            args_length = hdr.args_length as usize;
-            rpc_stamp = hdr.common_header.stamp;
-        }
+        // First, read fields off the request header. The extension's name and arguments follow
+        // it.
+        let hdr = NamedRequest::read(req.get_payload());
+        let hdr = match hdr {
+            Some(hdr) => hdr,
+            None => {
+                let status = RpcStatus::StatusMalformedRequest;
+                return self.refuse(OpCode::SandstormInvokeRpc, status, &[], req, res);
+            }
+        };
+        let tenant_id = hdr.tenant as TenantId;
+        let name_offset = hdr.header_length;
+        let name_length = hdr.name_length;
+        let args_length = hdr.body_length;
+        let rpc_stamp = hdr.stamp;
 
         // Next, add a header to the response packet.
-        let mut res = res.push_header(&InvokeResponse::new(
-            rpc_stamp,
-            OpCode::SandstormInvokeRpc,
-            tenant_id,
-        )).expect("Failed to push InvokeResponse");
+        let hdr = InvokeResponse::new(rpc_stamp, OpCode::SandstormInvokeRpc, tenant_id);
+        let mut res = match push_rpc_header(res, &hdr) {
+            Ok(res) => res,
+            Err(res) => return Err((req, res)),
+        };
 
         // If the payload size is less than the sum of the name and args
         // length, return an error.
-        if req.get_payload().len() < name_length + args_length {
+        let args_offset = name_offset + name_length;
+        if req.get_payload().len() < args_offset + args_length {
             res.get_mut_header().common_header.status = RpcStatus::StatusMalformedRequest;
-            return Err((
-                req.deparse_header(PACKET_UDP_LEN as usize),
-                res.deparse_header(PACKET_UDP_LEN as usize),
-            ));
+            return Err((req, res.deparse_header(PACKET_UDP_LEN as usize)));
         }
 
         // Read the extension's name from the request payload.
         let mut name = Vec::new();
-        name.extend_from_slice(req.get_payload().split_at(name_length).0);
-        let name: String = String::from_utf8(name).expect("ERROR: Failed to get ext name.");
+        name.extend_from_slice(&req.get_payload()[name_offset..args_offset]);
+        let name: String = match String::from_utf8(name) {
+            Ok(name) => name,
+
+            Err(_) => {
+                let err = SplinterError::from(ExtensionError::InvalidName);
+                res.get_mut_header().common_header.status = err.status();
+                return Err((
+                    req,
+                    res.deparse_header(PACKET_UDP_LEN as usize),
+                ));
+            }
+        };
 
         let mut status = RpcStatus::StatusTenantDoesNotExist;
 
@@ -865,14 +1417,16 @@ impl Master {
             if let Some(ext) = self.extensions.get(tenant_id, &name) {
                 let db = Rc::new(Context::new(
                     req,
-                    name_length,
+                    name_offset,
+                    args_offset,
                     args_length,
                     res,
                     tenant,
                     Arc::clone(&self.heap),
                 ));
 
-                return Ok(Box::new(Container::new(TaskPriority::REQUEST, db, ext)));
+                let task = Container::new(TaskPriority::REQUEST, db, ext, name);
+                return Ok(Box::new(task));
             }
         }
 
@@ -880,7 +1434,7 @@ impl Master {
         res.get_mut_header().common_header.status = status;
 
         return Err((
-            req.deparse_header(PACKET_UDP_LEN as usize),
+            req,
             res.deparse_header(PACKET_UDP_LEN as usize),
         ));
     }
@@ -897,27 +1451,27 @@ impl Master {
     ///
     /// A response buffer that can be sent back to the tenant.
     pub fn install(&self, buf: Vec<u8>) -> Vec<u8> {
-        // First off, parse the RPC header.
-        let hdr = buf.as_ptr() as *const InstallRequest;
-
-        let tenant: TenantId;
-        let name_l: usize;
-        let extn_l: usize;
-        let tstamp: u64;
-
-        unsafe {
-            tenant = (*hdr).common_header.tenant as TenantId;
-            name_l = (*hdr).name_length as usize;
-            extn_l = (*hdr).extn_length as usize;
-            tstamp = (*hdr).common_header.stamp;
-        }
+        // First off, read fields off the RPC header. The extension's name and the extension
+        // follow it.
+        let hdr = match NamedRequest::read(&buf) {
+            Some(hdr) => hdr,
+            None => {
+                let (tenant, stamp) = Master::admin_header(&buf).unwrap_or((0, 0));
+                let (op, status) = (OpCode::SandstormInstallRpc, RpcStatus::StatusMalformedRequest);
+                return Master::admin_response(stamp, op, tenant, status, &[]);
+            }
+        };
+        let tenant = hdr.tenant as TenantId;
+        let name_l = hdr.name_length;
+        let extn_l = hdr.body_length;
+        let tstamp = hdr.stamp;
 
         // Create a response for the tenant.
         let mut res = InstallResponse::new(tstamp, OpCode::SandstormInstallRpc, tenant as u32);
         res.common_header.status = RpcStatus::StatusTenantDoesNotExist;
 
         // Check if the tenant provided lengths match the actual request length.
-        if buf.len() != size_of::<InstallRequest>() + name_l + extn_l {
+        if buf.len() != hdr.header_length + name_l + extn_l {
             res.common_header.status = RpcStatus::StatusMalformedRequest;
             let res: [u8; size_of::<InstallResponse>()] = unsafe { transmute(res) };
             let mut ret: Vec<u8> = Vec::new();
@@ -929,25 +1483,40 @@ impl Master {
         if let Some(_) = self.get_tenant(tenant) {
             res.common_header.status = RpcStatus::StatusInternalError;
 
-            let (_, payload) = buf.split_at(size_of::<InstallRequest>());
+            let (_, payload) = buf.split_at(hdr.header_length);
             let (name, payload) = payload.split_at(name_l);
             let (extn, _) = payload.split_at(extn_l);
 
             if let Ok(name) = from_utf8(name) {
+                if let Some(status) = self.exceeds_extension_caps(tenant, name, extn_l as u64) {
+                    res.common_header.status = status;
+                    audit::record(tenant, Event::Install { name: name, ok: false });
+                    let res: [u8; size_of::<InstallResponse>()] = unsafe { transmute(res) };
+                    return res.to_vec();
+                }
+
                 let mut path = String::new();
                 path.push_str("/tmp/");
                 path.push_str(name);
                 path.push_str(".so");
 
-                // No need for error handling here. If a file could not be created or
-                // written to, then it might be better to just crash the server and recover.
-                let mut file = File::create(path.clone()).unwrap();
-                let _ = file.write_all(extn).unwrap();
-                let _ = file.sync_all().unwrap();
-
-                if self.extensions.load(&path, tenant, name) {
+                // A file that could not be created or written to fails the install, instead of
+                // the server.
+                let saved = save_extension(&path, extn)
+                    .context(|| format!("installing extension `{}` for tenant {}", name, tenant));
+                let ok = match saved {
+                    Ok(()) => self.extensions.load(&path, tenant, name),
+
+                    Err(err) => {
+                        warn!("{}", err);
+                        res.common_header.status = err.status();
+                        false
+                    }
+                };
+                if ok {
                     res.common_header.status = RpcStatus::StatusOk;
                 }
+                audit::record(tenant, Event::Install { name: name, ok: ok });
             }
         }
 
@@ -956,35 +1525,2141 @@ impl Master {
         ret.extend_from_slice(&res);
         return ret;
     }
-}
 
-/// Implementation of the Service trait for Master, allowing it to service RPC requests.
-impl Service for Master {
-    /// Lookup the Service trait for documentation.
-    fn dispatch(
-        &self,
-        op: OpCode,
-        req: Packet<UdpHeader, EmptyMetadata>,
-        res: Packet<UdpHeader, EmptyMetadata>,
-    ) -> Result<
-        Box<Task>,
-        (
-            Packet<UdpHeader, EmptyMetadata>,
-            Packet<UdpHeader, EmptyMetadata>,
-        ),
-    > {
-        // Based on the opcode, call the relevant RPC handler.
-        match op {
-            OpCode::SandstormGetRpc => {
-                return self.get(req, res);
+    /// Handles the tables() RPC request, which lists the tables belonging to a tenant.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf`: A buffer consisting of the RPC header.
+    ///
+    /// # Return
+    ///
+    /// A response header, followed by the identifier and number of objects of each table as
+    /// little endian u64s, ordered by table identifier.
+    pub fn tables(&self, buf: Vec<u8>) -> Vec<u8> {
+        let op = OpCode::SandstormTablesRpc;
+        let (tenant, stamp) = match Master::admin_header(&buf) {
+            Some(parsed) => parsed,
+            None => return Master::admin_response(0, op, 0, RpcStatus::StatusMalformedRequest, &[]),
+        };
+
+        let tables = match self.get_tenant(tenant) {
+            Some(tenant) => tenant.tables(),
+            None => {
+                let status = RpcStatus::StatusTenantDoesNotExist;
+                return Master::admin_response(stamp, op, tenant, status, &[]);
             }
+        };
+
+        let mut payload = Vec::new();
+        for (id, table) in tables {
+            let id: [u8; 8] = unsafe { transmute((id as u64).to_le()) };
+            let len: [u8; 8] = unsafe { transmute((table.len() as u64).to_le()) };
+            payload.extend_from_slice(&id);
+            payload.extend_from_slice(&len);
+        }
 
-            OpCode::SandstormPutRpc => {
-                return self.put(req, res);
+        Master::admin_response(stamp, op, tenant, RpcStatus::StatusOk, &payload)
+    }
+
+    /// Handles the stats() RPC request, which counts the tenants, tables and objects at the
+    /// server, the packets handled by dispatchers and by every NIC port, and the operations
+    /// performed on every table. The counts are not a consistent snapshot if the server is being
+    /// written to.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf`: A buffer consisting of the RPC header.
+    ///
+    /// # Return
+    ///
+    /// A response header, followed by the number of tenants, tables and objects, then by every
+    /// packet path counter in the order of `Counter`, then by the number of NIC ports and the
+    /// packets received and sent, bytes received and sent, packets missed, receive and send
+    /// errors, and receives that found no mbuf of every port ordered by name, and then by the
+    /// owner, identifier, gets, misses, puts, bytes read and bytes written of every table ordered
+    /// by owner and identifier, all as little endian u64s. A port whose counters could not be
+    /// read reports zeroes.
+    pub fn stats(&self, buf: Vec<u8>) -> Vec<u8> {
+        let op = OpCode::SandstormStatsRpc;
+        let (tenant, stamp) = match Master::admin_header(&buf) {
+            Some(parsed) => parsed,
+            None => return Master::admin_response(0, op, 0, RpcStatus::StatusMalformedRequest, &[]),
+        };
+
+        let mut counts = [0u64; 3];
+        let mut ops = Vec::new();
+        for bucket in self.tenants.iter() {
+            let map = bucket.read();
+            for tenant in map.values() {
+                counts[0] += 1;
+                for (id, table) in tenant.tables() {
+                    counts[1] += 1;
+                    counts[2] += table.len() as u64;
+
+                    // Tables mounted from another tenant are reported under their owner.
+                    if table.owner() == tenant.id() {
+                        let o = table.ops();
+                        let record = [
+                            tenant.id() as u64,
+                            id,
+                            o.gets,
+                            o.misses,
+                            o.puts,
+                            o.read_bytes,
+                            o.written_bytes,
+                        ];
+                        ops.push(record);
+                    }
+                }
             }
+        }
+        ops.sort_by_key(|record| (record[0], record[1]));
+
+        let ports = self.ports.read().clone();
+        let mut nics = vec![ports.len() as u64];
+        for &(_, ref port) in ports.iter() {
+            let s = port.nic_stats().unwrap_or_default();
+            let record = [
+                s.ipackets, s.opackets, s.ibytes, s.obytes, s.imissed, s.ierrors, s.oerrors,
+                s.rx_nombuf,
+            ];
+            nics.extend_from_slice(&record);
+        }
 
-            OpCode::SandstormMultiGetRpc => {
-                return self.multiget(req, res);
+        let mut payload = Vec::new();
+        let dispatch = counters::snapshot();
+        for count in counts
+            .iter()
+            .chain(dispatch.iter())
+            .chain(nics.iter())
+            .chain(ops.iter().flat_map(|record| record.iter()))
+        {
+            let count: [u8; 8] = unsafe { transmute(count.to_le()) };
+            payload.extend_from_slice(&count);
+        }
+
+        Master::admin_response(stamp, op, tenant, RpcStatus::StatusOk, &payload)
+    }
+
+    /// Handles the tenant() RPC request, which creates, suspends, resumes or deletes the tenant
+    /// on it's header, shares one of it's tables with another tenant or publishes it to every
+    /// tenant, or replaces the key it's values are encrypted with at rest. Creating a tenant that
+    /// already exists, or with a zero credential, fails with StatusInvalidOperation. Every other
+    /// action must carry the credential the tenant was created with or the operator's, and fails
+    /// with StatusPermissionDenied otherwise. Tenants created at startup have no credential of
+    /// their own, so only the operator can act on them. A tenant the operator suspended can only
+    /// be resumed by the operator.
+    ///
+    /// Deleting a tenant frees it's tables once requests already being executed on it's behalf
+    /// complete. Extensions it installed stay loaded. Draining a tenant instead waits for those
+    /// requests, unloads it's extensions, and only then responds.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf`: A buffer consisting of the RPC header followed by a `TenantAction` and it's
+    ///          arguments.
+    ///
+    /// # Return
+    ///
+    /// A response header with the outcome of the action.
+    pub fn tenant(&self, buf: Vec<u8>) -> Vec<u8> {
+        let op = OpCode::SandstormTenantRpc;
+        let (tenant, stamp) = match Master::admin_header(&buf) {
+            Some(parsed) => parsed,
+            None => return Master::admin_response(0, op, 0, RpcStatus::StatusMalformedRequest, &[]),
+        };
+
+        let args = &buf[size_of::<RpcRequestHeader>()..];
+
+        // Draining is the only action with a response that carries more than a status.
+        if let (Some(&action), Some(credential)) = (args.get(0), Master::le(args, 1, 8)) {
+            if action == TenantAction::Drain as u8 {
+                let (status, counts) = self.drain_tenant(tenant, credential);
+                let mut payload = Vec::new();
+                for count in counts.iter() {
+                    let count: [u8; 8] = unsafe { transmute(count.to_le()) };
+                    payload.extend_from_slice(&count);
+                }
+                return Master::admin_response(stamp, op, tenant, status, &payload);
+            }
+        }
+
+        let status = match (args.get(0), Master::le(args, 1, 8)) {
+            (Some(&action), Some(credential)) if action == TenantAction::Create as u8 => {
+                self.create_tenant(tenant, credential, &args[9..])
+            }
+
+            (Some(&action), Some(credential)) if action == TenantAction::Suspend as u8 => {
+                self.get_tenant(tenant).map_or(RpcStatus::StatusTenantDoesNotExist, |t| {
+                    if !self.authenticate(&t, credential, "suspend") {
+                        return RpcStatus::StatusPermissionDenied;
+                    }
+                    match self.admin(credential) {
+                        true => t.set_held(true),
+                        false => t.set_suspended(true),
+                    }
+                    audit::record(tenant, Event::Suspend);
+                    RpcStatus::StatusOk
+                })
+            }
+
+            (Some(&action), Some(credential)) if action == TenantAction::Resume as u8 => {
+                self.get_tenant(tenant).map_or(RpcStatus::StatusTenantDoesNotExist, |t| {
+                    if !self.authenticate(&t, credential, "resume") {
+                        return RpcStatus::StatusPermissionDenied;
+                    }
+
+                    // Only the operator can undo a suspension of it's own.
+                    match self.admin(credential) {
+                        true => t.set_held(false),
+                        false if t.held() => {
+                            audit::record(tenant, Event::AuthFailure { action: "resume" });
+                            counters::add(Counter::AuthFailures, 1);
+                            return RpcStatus::StatusPermissionDenied;
+                        }
+                        false => {}
+                    }
+                    t.set_suspended(false);
+                    audit::record(tenant, Event::Resume);
+                    RpcStatus::StatusOk
+                })
+            }
+
+            (Some(&action), Some(credential)) if action == TenantAction::Delete as u8 => {
+                match self.get_tenant(tenant) {
+                    Some(ref t) if !self.authenticate(t, credential, "delete") => {
+                        RpcStatus::StatusPermissionDenied
+                    }
+                    Some(t) => {
+                        self.teardown(&t);
+                        audit::record(tenant, Event::DeleteTenant);
+                        info!("Deleted tenant {}", tenant);
+                        RpcStatus::StatusOk
+                    }
+                    None => RpcStatus::StatusTenantDoesNotExist,
+                }
+            }
+
+            (Some(&action), Some(credential)) if action == TenantAction::Grant as u8 => {
+                match self.get_tenant(tenant) {
+                    Some(ref t) if !self.authenticate(t, credential, "grant") => {
+                        RpcStatus::StatusPermissionDenied
+                    }
+                    Some(t) => self.grant_table(&t, &args[9..]),
+                    None => RpcStatus::StatusTenantDoesNotExist,
+                }
+            }
+
+            (Some(&action), Some(credential)) if action == TenantAction::Publish as u8 => {
+                match self.get_tenant(tenant) {
+                    Some(ref t) if !self.authenticate(t, credential, "publish") => {
+                        RpcStatus::StatusPermissionDenied
+                    }
+                    Some(t) => self.publish_table(&t, &args[9..]),
+                    None => RpcStatus::StatusTenantDoesNotExist,
+                }
+            }
+
+            (Some(&action), Some(credential)) if action == TenantAction::Key as u8 => {
+                match self.get_tenant(tenant) {
+                    Some(ref t) if !self.authenticate(t, credential, "key") => {
+                        RpcStatus::StatusPermissionDenied
+                    }
+                    Some(t) => self.set_key(&t, &args[9..]),
+                    None => RpcStatus::StatusTenantDoesNotExist,
+                }
+            }
+
+            (Some(_), Some(_)) => RpcStatus::StatusInvalidOperation,
+
+            _ => RpcStatus::StatusMalformedRequest,
+        };
+
+        Master::admin_response(stamp, op, tenant, status, &[])
+    }
+
+    /// Handles the audit() RPC request, which fetches the records attributed to the tenant on it's
+    /// header from the audit log. The request must carry the tenant's credential, and fails with
+    /// StatusPermissionDenied otherwise. Records of a tenant that was deleted can only be found in
+    /// the log's file. The operator's own records are fetched with `audit::OPERATOR` on the
+    /// header and the operator's credential.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf`: A buffer consisting of the RPC header followed by the tenant's credential and the
+    ///          smallest sequence number of records to be fetched, as little endian u64s.
+    ///
+    /// # Return
+    ///
+    /// A response header, followed by upto `audit::MAX_QUERY` records as lines of JSON, oldest
+    /// first.
+    pub fn audit(&self, buf: Vec<u8>) -> Vec<u8> {
+        let op = OpCode::SandstormAuditRpc;
+        let (tenant, stamp) = match Master::admin_header(&buf) {
+            Some(parsed) => parsed,
+            None => return Master::admin_response(0, op, 0, RpcStatus::StatusMalformedRequest, &[]),
+        };
+
+        let args = &buf[size_of::<RpcRequestHeader>()..];
+        let (credential, since) = match (Master::le(args, 0, 8), Master::le(args, 8, 8)) {
+            (Some(credential), Some(since)) if args.len() == 16 => (credential, since),
+            _ => {
+                let status = RpcStatus::StatusMalformedRequest;
+                return Master::admin_response(stamp, op, tenant, status, &[]);
+            }
+        };
+
+        // The operator's own records are only for the operator.
+        let status = match self.get_tenant(tenant) {
+            Some(ref t) if !self.authenticate(t, credential, "audit") => {
+                RpcStatus::StatusPermissionDenied
+            }
+            Some(_) => RpcStatus::StatusOk,
+            None if tenant == audit::OPERATOR && self.admin(credential) => RpcStatus::StatusOk,
+            None if tenant == audit::OPERATOR => RpcStatus::StatusPermissionDenied,
+            None => RpcStatus::StatusTenantDoesNotExist,
+        };
+
+        if status != RpcStatus::StatusOk {
+            return Master::admin_response(stamp, op, tenant, status, &[]);
+        }
+
+        Master::admin_response(stamp, op, tenant, status, &audit::query(tenant, since))
+    }
+
+    /// Handles the queues() RPC request, which takes a snapshot of the tasks on every scheduler:
+    /// the one running right now, if it belongs to a tenant, and every one waiting to run.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf`: A buffer consisting of the RPC header.
+    ///
+    /// # Return
+    ///
+    /// A response header, followed by one line of JSON per task, ordered by core and then by
+    /// position on the run-queue.
+    pub fn queues(&self, buf: Vec<u8>) -> Vec<u8> {
+        let op = OpCode::SandstormQueuesRpc;
+        let (tenant, stamp) = match Master::admin_header(&buf) {
+            Some(parsed) => parsed,
+            None => return Master::admin_response(0, op, 0, RpcStatus::StatusMalformedRequest, &[]),
+        };
+
+        let schedulers: Vec<Arc<RoundRobin>> = self.schedulers.read().clone();
+        let cps = cycles::cycles_per_second().max(1);
+
+        let mut payload = String::new();
+        for sched in schedulers.iter() {
+            for task in sched.snapshot() {
+                let owner = task.tenant.map_or(String::from("null"), |t| t.to_string());
+                payload.push_str(&format!(
+                    "{{\"core\":{},\"tenant\":{},\"name\":{},\"state\":\"{}\",\"age_us\":{}}}\n",
+                    sched.core(),
+                    owner,
+                    audit::quote(&task.name),
+                    task.state.name(),
+                    task.age * 1000000 / cps
+                ));
+            }
+        }
+
+        Master::admin_response(stamp, op, tenant, RpcStatus::StatusOk, payload.as_bytes())
+    }
+
+    /// Handles the slow() RPC request, which fetches the most recent requests that took longer
+    /// than the slow request threshold, from every tenant.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf`: A buffer consisting of the RPC header followed by the smallest sequence number of
+    ///          records to be fetched, as a little endian u64.
+    ///
+    /// # Return
+    ///
+    /// A response header, followed by upto `slow::MAX_QUERY` records as lines of JSON, oldest
+    /// first.
+    pub fn slow(&self, buf: Vec<u8>) -> Vec<u8> {
+        let op = OpCode::SandstormSlowRpc;
+        let (tenant, stamp) = match Master::admin_header(&buf) {
+            Some(parsed) => parsed,
+            None => return Master::admin_response(0, op, 0, RpcStatus::StatusMalformedRequest, &[]),
+        };
+
+        let args = &buf[size_of::<RpcRequestHeader>()..];
+        match Master::le(args, 0, 8) {
+            Some(since) if args.len() == 8 => {
+                let records = slow::query(since);
+                Master::admin_response(stamp, op, tenant, RpcStatus::StatusOk, &records)
+            }
+            _ => Master::admin_response(stamp, op, tenant, RpcStatus::StatusMalformedRequest, &[]),
+        }
+    }
+
+    /// Handles the memory() RPC request, which reports on the memory held by the heap, DPDK's
+    /// packet pools, every tenant's arena and every table. Working out how much of each arena
+    /// is taken up by live objects visits every object, so the request takes as long as a scan
+    /// of the database. The report is not a consistent snapshot if the server is being written
+    /// to.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf`: A buffer consisting of the RPC header.
+    ///
+    /// # Return
+    ///
+    /// A response header, followed by one line of JSON for the heap, then for every packet pool,
+    /// every tenant and every table, ordered by tenant and identifier.
+    pub fn memory(&self, buf: Vec<u8>) -> Vec<u8> {
+        let op = OpCode::SandstormMemoryRpc;
+        let (tenant, stamp) = match Master::admin_header(&buf) {
+            Some(parsed) => parsed,
+            None => return Master::admin_response(0, op, 0, RpcStatus::StatusMalformedRequest, &[]),
+        };
+
+        let mut census = self.heap.census();
+        let mut quotas = Vec::new();
+        let mut tables = Vec::new();
+        for bucket in self.tenants.iter() {
+            let map = bucket.read();
+            for tenant in map.values() {
+                let quota = tenant.quota();
+                quotas.push((tenant.id(), quota.used(), quota.limit()));
+
+                // Tables mounted from another tenant are counted under their owner.
+                for (id, table) in tenant.tables() {
+                    if table.owner() != tenant.id() {
+                        continue;
+                    }
+
+                    table.visit(&mut |object| census.count(object));
+                    tables.push(TableMemory {
+                        tenant: tenant.id(),
+                        table: id,
+                        objects: table.len(),
+                        bytes: table.bytes(),
+                        index: table.capacity() * memory::INDEX_ENTRY_LEN,
+                    });
+                }
+            }
+        }
+        quotas.sort_by_key(|&(id, _, _)| id);
+        tables.sort_by_key(|t| (t.tenant, t.table));
+
+        let report = Report {
+            heap: memory::heap(),
+            pools: memory::pools(),
+            tenants: memory::tenants(&census.finish(), &quotas),
+            tables: tables,
+        };
+
+        Master::admin_response(stamp, op, tenant, RpcStatus::StatusOk, report.render().as_bytes())
+    }
+
+    /// Handles the config() RPC request, which reads the config the server runs with, or
+    /// changes fields of it that can change at runtime. Changes take the operator's credential,
+    /// are recorded to the audit log as operator events, and take effect within a few
+    /// milliseconds. Changes that are rejected fail with StatusMalformedRequest, and change
+    /// nothing.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf`: A buffer consisting of the RPC header followed by a `ConfigAction` and it's
+    ///          arguments.
+    ///
+    /// # Return
+    ///
+    /// A response header, followed by the config as TOML, or by a line describing every field
+    /// that was changed or every problem with the changes.
+    pub fn config(&self, buf: Vec<u8>) -> Vec<u8> {
+        let op = OpCode::SandstormConfigRpc;
+        let (tenant, stamp) = match Master::admin_header(&buf) {
+            Some(parsed) => parsed,
+            None => return Master::admin_response(0, op, 0, RpcStatus::StatusMalformedRequest, &[]),
+        };
+
+        let args = &buf[size_of::<RpcRequestHeader>()..];
+        let changes = from_utf8(args.get(9..).unwrap_or(&[]));
+        match (args.get(0), Master::le(args, 1, 8), changes) {
+            (Some(&action), _, _) if action == ConfigAction::Get as u8 && args.len() == 1 => {
+                let config = runtime::current().to_toml();
+                Master::admin_response(stamp, op, tenant, RpcStatus::StatusOk, config.as_bytes())
+            }
+
+            (Some(&action), Some(credential), Ok(changes)) if action == ConfigAction::Set as u8 => {
+                if !self.operator(tenant, credential, "config") {
+                    let status = RpcStatus::StatusPermissionDenied;
+                    return Master::admin_response(stamp, op, tenant, status, &[]);
+                }
+
+                match runtime::tune(changes) {
+                    Ok(changed) => {
+                        let mut lines = String::new();
+                        for c in changed.iter() {
+                            let event = Event::Config {
+                                field: &c.field,
+                                old: &c.old,
+                                new: &c.new,
+                            };
+                            audit::record(audit::OPERATOR, event);
+                            info!("The operator changed `{}` from {} to {}", c.field, c.old, c.new);
+                            lines.push_str(&format!("{}: {} -> {}\n", c.field, c.old, c.new));
+                        }
+                        let status = RpcStatus::StatusOk;
+                        Master::admin_response(stamp, op, tenant, status, lines.as_bytes())
+                    }
+
+                    Err(err) => {
+                        let status = RpcStatus::StatusMalformedRequest;
+                        let problems = err.problems.join("\n") + "\n";
+                        Master::admin_response(stamp, op, tenant, status, problems.as_bytes())
+                    }
+                }
+            }
+
+            _ => Master::admin_response(stamp, op, tenant, RpcStatus::StatusMalformedRequest, &[]),
+        }
+    }
+
+    /// Checks whether every core is making progress, and whether the NICs and memory are fit to
+    /// serve requests. Cheap enough to be polled every second. A draining server is not ready.
+    pub fn health_report(&self) -> health::Report {
+        let now = cycles::rdtsc();
+        let cores: Vec<(i32, u64)> = self
+            .schedulers
+            .read()
+            .iter()
+            .map(|sched| (sched.core(), now.saturating_sub(sched.latest())))
+            .collect();
+
+        // A port whose link cannot be read is reported as down.
+        let links = self
+            .ports
+            .read()
+            .iter()
+            .map(|&(ref name, ref port)| (name.clone(), port.link().unwrap_or_default()))
+            .collect();
+
+        let mut report = health::check(
+            &cores,
+            cycles::cycles_per_second(),
+            links,
+            memory::pools(),
+            health::mem_available(),
+            replica::broken(),
+        );
+        if drain::draining() {
+            report.ready = false;
+            report.problems.push(String::from("the server is draining before it shuts down"));
+        }
+
+        report
+    }
+
+    /// Returns the number of tasks in flight on behalf of tenants. Every such task holds a
+    /// reference to it's tenant, so the count is that of references beyond the tenant map's,
+    /// and the one taken here. Required to drain the server.
+    pub fn in_flight(&self) -> usize {
+        self.all_tenants()
+            .iter()
+            .map(|tenant| Arc::strong_count(tenant).saturating_sub(2))
+            .sum()
+    }
+
+    /// Handles the health() RPC request, which checks whether every core is making progress,
+    /// and whether the NICs and memory are fit to serve requests. A server that is not ready
+    /// still answers with StatusOk; the verdict is in the payload.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf`: A buffer consisting of the RPC header.
+    ///
+    /// # Return
+    ///
+    /// A response header, followed by the report as a line of JSON.
+    pub fn health(&self, buf: Vec<u8>) -> Vec<u8> {
+        let op = OpCode::SandstormHealthRpc;
+        let (tenant, stamp) = match Master::admin_header(&buf) {
+            Some(parsed) => parsed,
+            None => return Master::admin_response(0, op, 0, RpcStatus::StatusMalformedRequest, &[]),
+        };
+
+        let report = self.health_report().render();
+        Master::admin_response(stamp, op, tenant, RpcStatus::StatusOk, report.as_bytes())
+    }
+
+    /// Handles the members() RPC request, which reports what the server knows about every other
+    /// member of the cluster.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf`: A buffer consisting of the RPC header.
+    ///
+    /// # Return
+    ///
+    /// A response header, followed by the report as a line of JSON.
+    pub fn members(&self, buf: Vec<u8>) -> Vec<u8> {
+        let op = OpCode::SandstormMembersRpc;
+        let (tenant, stamp) = match Master::admin_header(&buf) {
+            Some(parsed) => parsed,
+            None => return Master::admin_response(0, op, 0, RpcStatus::StatusMalformedRequest, &[]),
+        };
+
+        let report = member::status();
+        Master::admin_response(stamp, op, tenant, RpcStatus::StatusOk, report.as_bytes())
+    }
+
+    /// Handles the replica() RPC request, which reports on replication, promotes a standby to a
+    /// primary, or applies writes shipped to a standby by it's primary, or to a primary from
+    /// another site. Records in a batch that were applied before are skipped, so that a primary
+    /// can resend a batch it did not hear back about. Every action but a status request carries
+    /// a credential, which must be the `peer_credential` servers share or the operator's. Writes
+    /// from another site are only taken from the servers in `geo_peers`.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf`: A buffer consisting of the RPC header followed by a `ReplicaAction` and it's
+    ///          arguments.
+    /// * `src`: The IP address the request came from, if it is known.
+    ///
+    /// # Return
+    ///
+    /// A response header, followed by a line of JSON for a status request. Promoting a server
+    /// that is not a standby, or shipping records to one, fails with StatusInvalidOperation, and
+    /// a request with the wrong credential with StatusPermissionDenied.
+    pub fn replica(&self, buf: Vec<u8>, src: Option<IpAddr>) -> Vec<u8> {
+        let op = OpCode::SandstormReplicaRpc;
+        let (tenant, stamp) = match Master::admin_header(&buf) {
+            Some(parsed) => parsed,
+            None => return Master::admin_response(0, op, 0, RpcStatus::StatusMalformedRequest, &[]),
+        };
+
+        let args = &buf[size_of::<RpcRequestHeader>()..];
+        if args.len() == 1 && args[0] == ReplicaAction::Status as u8 {
+            let report = replica::status(&runtime::current().backup_addr)
+                + &readrep::status()
+                + &geo::status()
+                + &raft::status();
+            let status = RpcStatus::StatusOk;
+            return Master::admin_response(stamp, op, tenant, status, report.as_bytes());
+        }
+
+        // Every other action is followed by the credential, and then by it's arguments.
+        let credential = match Master::le(args, 1, 8) {
+            Some(credential) => credential,
+            None => {
+                let status = RpcStatus::StatusMalformedRequest;
+                return Master::admin_response(stamp, op, tenant, status, &[]);
+            }
+        };
+        let rest = &args[9..];
+
+        let status = match args[0] {
+            action if action == ReplicaAction::Promote as u8 && rest.len() == 0 => {
+                if !self.peer(tenant, credential, "promote") {
+                    RpcStatus::StatusPermissionDenied
+                } else if replica::promote() {
+                    warn!("Promoted to primary, writes are now accepted");
+                    RpcStatus::StatusOk
+                } else {
+                    RpcStatus::StatusInvalidOperation
+                }
+            }
+
+            action if action == ReplicaAction::Apply as u8 => {
+                if !self.peer(tenant, credential, "apply") {
+                    RpcStatus::StatusPermissionDenied
+                } else if replica::standby() {
+                    self.apply_records(rest, 0)
+                } else {
+                    RpcStatus::StatusInvalidOperation
+                }
+            }
+
+            action if action == ReplicaAction::Feed as u8 && rest.len() > 0 => {
+                if !self.peer(tenant, credential, "feed") {
+                    RpcStatus::StatusPermissionDenied
+                } else if readrep::replica() {
+                    let status = self.apply_records(rest, 1);
+                    if status == RpcStatus::StatusOk && rest[0] != 0 {
+                        readrep::caught_up();
+                    }
+                    status
+                } else {
+                    RpcStatus::StatusInvalidOperation
+                }
+            }
+
+            action if action == ReplicaAction::Geo as u8 => {
+                let known = src.map_or(false, geo::peer);
+                if !known {
+                    audit::record(tenant, Event::AuthFailure { action: "geo" });
+                    counters::add(Counter::AuthFailures, 1);
+                    RpcStatus::StatusPermissionDenied
+                } else if !self.peer(tenant, credential, "geo") {
+                    RpcStatus::StatusPermissionDenied
+                } else if replica::standby() || readrep::replica() {
+                    RpcStatus::StatusInvalidOperation
+                } else {
+                    self.apply_geo(rest)
+                }
+            }
+
+            _ => RpcStatus::StatusMalformedRequest,
+        };
+
+        Master::admin_response(stamp, op, tenant, status, &[])
+    }
+
+    /// Handles the migrate() RPC request, which starts migrating a tenant to another server or
+    /// reports on the migration, or, on the server a tenant moves to, checks that it has the
+    /// tenant and applies records of the tenant's data. Every action carries a credential:
+    /// starting a migration or reporting on it takes the tenant's or the operator's, and the
+    /// requests servers send each other while migrating take the `peer_credential` servers share
+    /// or the operator's.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf`: A buffer consisting of the RPC header followed by a `MigrateAction`, the
+    ///          credential and the action's arguments.
+    ///
+    /// # Return
+    ///
+    /// A response header, followed by a line of JSON for a status request, or the server's IP
+    /// address for a prepare request. Starting a migration while another runs, of a tenant that
+    /// already moved, of one with Raft-replicated tables, or to a server declared dead fails with
+    /// StatusInvalidOperation, and a request with the wrong credential with
+    /// StatusPermissionDenied.
+    pub fn migrate(&self, buf: Vec<u8>) -> Vec<u8> {
+        let op = OpCode::SandstormMigrateRpc;
+        let (tenant, stamp) = match Master::admin_header(&buf) {
+            Some(parsed) => parsed,
+            None => return Master::admin_response(0, op, 0, RpcStatus::StatusMalformedRequest, &[]),
+        };
+
+        let args = &buf[size_of::<RpcRequestHeader>()..];
+        let credential = match Master::le(args, 1, 8) {
+            Some(credential) => credential,
+            None => {
+                let status = RpcStatus::StatusMalformedRequest;
+                return Master::admin_response(stamp, op, tenant, status, &[]);
+            }
+        };
+        let rest = &args[9..];
+
+        let status = match args[0] {
+            action if action == MigrateAction::Start as u8 => {
+                let dest = from_utf8(rest).ok().map(|dest| dest.trim().to_string());
+                let tables = self.owned_tables(tenant);
+                match (dest, tables, self.get_tenant(tenant)) {
+                    (Some(ref dest), _, _) if SocketAddr::from_str(dest).is_err() => {
+                        RpcStatus::StatusMalformedRequest
+                    }
+                    (_, None, _) | (_, _, None) => RpcStatus::StatusTenantDoesNotExist,
+                    (_, _, Some(ref t)) if !self.authenticate(t, credential, "migrate") => {
+                        RpcStatus::StatusPermissionDenied
+                    }
+                    (Some(ref dest), Some(ref tables), Some(_)) => {
+                        let raft = tables.iter().any(|table| raft::governs(tenant, *table));
+                        match !raft && migrate::start(tenant, dest) {
+                            true => RpcStatus::StatusOk,
+                            false => RpcStatus::StatusInvalidOperation,
+                        }
+                    }
+                    (None, _, _) => RpcStatus::StatusMalformedRequest,
+                }
+            }
+
+            action if action == MigrateAction::Status as u8 && rest.len() == 0 => {
+                let permitted = match self.get_tenant(tenant) {
+                    Some(ref t) => self.authenticate(t, credential, "migrate status"),
+                    None => self.admin(credential),
+                };
+                if !permitted {
+                    let status = RpcStatus::StatusPermissionDenied;
+                    return Master::admin_response(stamp, op, tenant, status, &[]);
+                }
+
+                let report = migrate::status();
+                let status = RpcStatus::StatusOk;
+                return Master::admin_response(stamp, op, tenant, status, report.as_bytes());
+            }
+
+            action if action == MigrateAction::Prepare as u8 && rest.len() == 0 => {
+                if !self.peer(tenant, credential, "prepare") {
+                    RpcStatus::StatusPermissionDenied
+                } else if self.get_tenant(tenant).is_none() {
+                    RpcStatus::StatusTenantDoesNotExist
+                } else {
+                    migrate::arrive(tenant);
+                    let ip = runtime::current().ip_address.clone();
+                    let status = RpcStatus::StatusOk;
+                    return Master::admin_response(stamp, op, tenant, status, ip.as_bytes());
+                }
+            }
+
+            action if action == MigrateAction::Ingest as u8 => {
+                match self.peer(tenant, credential, "ingest") {
+                    true => self.ingest(tenant, rest),
+                    false => RpcStatus::StatusPermissionDenied,
+                }
+            }
+
+            _ => RpcStatus::StatusMalformedRequest,
+        };
+
+        Master::admin_response(stamp, op, tenant, status, &[])
+    }
+
+    /// Handles the partition() RPC request, which reads a tenant's routing table, or splits,
+    /// merges or reassigns it's ranges. Changes take the tenant's credential or the operator's.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf`: A buffer consisting of the RPC header followed by a `PartitionAction` and it's
+    ///          arguments.
+    ///
+    /// # Return
+    ///
+    /// A response header, followed by a line of JSON for a request to read the table. Changes
+    /// that cannot be made, such as merging ranges held by different servers, fail with
+    /// StatusInvalidOperation.
+    pub fn partition(&self, buf: Vec<u8>) -> Vec<u8> {
+        let op = OpCode::SandstormPartitionRpc;
+        let (tenant, stamp) = match Master::admin_header(&buf) {
+            Some(parsed) => parsed,
+            None => return Master::admin_response(0, op, 0, RpcStatus::StatusMalformedRequest, &[]),
+        };
+
+        let args = &buf[size_of::<RpcRequestHeader>()..];
+        if args.len() == 1 && args[0] == PartitionAction::Show as u8 {
+            let report = partition::show(tenant);
+            let status = RpcStatus::StatusOk;
+            return Master::admin_response(stamp, op, tenant, status, report.as_bytes());
+        }
+
+        // Only the routing tables of tenants at this server can be changed, and only by the
+        // tenant or the operator.
+        let credential = match Master::le(args, 1, 8) {
+            Some(credential) => credential,
+            None => {
+                let status = RpcStatus::StatusMalformedRequest;
+                return Master::admin_response(stamp, op, tenant, status, &[]);
+            }
+        };
+        let status = match self.get_tenant(tenant) {
+            Some(ref t) if !self.authenticate(t, credential, "partition") => {
+                RpcStatus::StatusPermissionDenied
+            }
+            Some(_) => RpcStatus::StatusOk,
+            None => RpcStatus::StatusTenantDoesNotExist,
+        };
+        if status != RpcStatus::StatusOk {
+            return Master::admin_response(stamp, op, tenant, status, &[]);
+        }
+
+        // Splits and reassignments carry a server's address ahead of the key.
+        let rest = &args[9..];
+        let mut ip = [0u8; 4];
+        if rest.len() >= 4 {
+            ip.copy_from_slice(&rest[0..4]);
+        }
+
+        let changed = match args[0] {
+            action if action == PartitionAction::Split as u8 && rest.len() > 4 => {
+                partition::change(tenant, |map| map.split(&rest[4..], ip))
+            }
+
+            action if action == PartitionAction::Merge as u8 && rest.len() > 0 => {
+                partition::change(tenant, |map| map.merge(rest))
+            }
+
+            action if action == PartitionAction::Assign as u8 && rest.len() >= 4 => {
+                partition::change(tenant, |map| map.assign(&rest[4..], ip))
+            }
+
+            _ => {
+                let status = RpcStatus::StatusMalformedRequest;
+                return Master::admin_response(stamp, op, tenant, status, &[]);
+            }
+        };
+
+        let status = match changed {
+            true => RpcStatus::StatusOk,
+            false => RpcStatus::StatusInvalidOperation,
+        };
+        Master::admin_response(stamp, op, tenant, status, &[])
+    }
+
+    /// Handles the snapshot() RPC request, which exports one of a tenant's tables to a snapshot
+    /// file under the server's `snapshot_dir`, or imports a snapshot file from there into one.
+    /// Takes the tenant's credential or the operator's.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf`: A buffer consisting of the RPC header followed by a `SnapshotAction`, the
+    ///          credential, the table's identifier and the file's path.
+    ///
+    /// # Return
+    ///
+    /// A response header, followed by a line of JSON with the number of objects exported or
+    /// imported, or by a description of what went wrong.
+    pub fn snapshot(&self, buf: Vec<u8>) -> Vec<u8> {
+        let op = OpCode::SandstormSnapshotRpc;
+        let (tenant, stamp) = match Master::admin_header(&buf) {
+            Some(parsed) => parsed,
+            None => return Master::admin_response(0, op, 0, RpcStatus::StatusMalformedRequest, &[]),
+        };
+
+        let args = &buf[size_of::<RpcRequestHeader>()..];
+        let credential = Master::le(args, 1, 8);
+        let path = from_utf8(args.get(17..).unwrap_or(&[]));
+        let (credential, table, path) = match (credential, Master::le(args, 9, 8), path) {
+            (Some(credential), Some(table), Ok(path)) if path.len() > 0 => {
+                (credential, table as TableId, path)
+            }
+            _ => {
+                let status = RpcStatus::StatusMalformedRequest;
+                return Master::admin_response(stamp, op, tenant, status, &[]);
+            }
+        };
+
+        let status = match self.get_tenant(tenant) {
+            Some(ref t) if !self.authenticate(t, credential, "snapshot") => {
+                RpcStatus::StatusPermissionDenied
+            }
+            Some(_) => RpcStatus::StatusOk,
+            None => RpcStatus::StatusTenantDoesNotExist,
+        };
+        if status != RpcStatus::StatusOk {
+            return Master::admin_response(stamp, op, tenant, status, &[]);
+        }
+
+        // Tenants only get to name files under the snapshot directory.
+        let path = match snapshot::resolve(&runtime::current().snapshot_dir, path) {
+            Some(path) => path,
+            None => {
+                let status = RpcStatus::StatusPermissionDenied;
+                let detail = "Snapshot paths must be relative to the server's snapshot_dir, \
+                              without \"..\"";
+                return Master::admin_response(stamp, op, tenant, status, detail.as_bytes());
+            }
+        };
+
+        let result = match args[0] {
+            action if action == SnapshotAction::Export as u8 => {
+                self.export_table(tenant, table, &path)
+            }
+            action if action == SnapshotAction::Import as u8 => {
+                self.import_table(tenant, table, &path)
+            }
+            _ => Err((RpcStatus::StatusMalformedRequest, String::new())),
+        };
+
+        match result {
+            Ok(count) => {
+                let report = format!("{{\"table\":{},\"objects\":{}}}\n", table, count);
+                Master::admin_response(stamp, op, tenant, RpcStatus::StatusOk, report.as_bytes())
+            }
+            Err((status, detail)) => {
+                Master::admin_response(stamp, op, tenant, status, detail.as_bytes())
+            }
+        }
+    }
+
+    // Writes every object of a table a tenant owns to a snapshot file. The snapshot is written
+    // next to the file and renamed over it once complete, so that a failed export leaves any
+    // earlier snapshot in place. Objects written while the export runs may or may not make it
+    // into the snapshot. If the tenant has a key, every value is sealed with it, so that values
+    // are not written out in the clear. Returns the number of objects exported.
+    fn export_table(
+        &self,
+        tenant: TenantId,
+        table: TableId,
+        path: &Path,
+    ) -> Result<u64, (RpcStatus, String)> {
+        match self.owned_tables(tenant) {
+            None => return Err((RpcStatus::StatusTenantDoesNotExist, String::new())),
+            Some(ref tables) if !tables.contains(&table) => {
+                return Err((RpcStatus::StatusTableDoesNotExist, String::new()))
+            }
+            Some(_) => {}
+        }
+
+        let internal = |err: ::std::io::Error| {
+            let detail = format!("Failed to export to {}: {}", path.display(), err);
+            (RpcStatus::StatusInternalError, detail)
+        };
+
+        let key = self.tenant_key(tenant);
+        let flags = key.as_ref().map_or(0, |_| snapshot::FLAG_SEALED);
+        let partial = format!("{}.partial", path.display());
+        let file = File::create(&partial).map_err(&internal)?;
+        let mut writer = snapshot::Writer::new(BufWriter::new(file), tenant, table, flags)
+            .map_err(&internal)?;
+
+        let mut failed = None;
+        self.visit_table(tenant, table, &mut |rec| {
+            if failed.is_some() {
+                return;
+            }
+            failed = match key {
+                Some(ref key) => writer.push(rec.key, &key.seal(table, rec.key, rec.val)).err(),
+                None => writer.push(rec.key, rec.val).err(),
+            };
+        });
+        if let Some(err) = failed {
+            let _ = fs::remove_file(&partial);
+            return Err(internal(err));
+        }
+
+        let count = writer.finish().map_err(&internal)?;
+        fs::rename(&partial, path).map_err(&internal)?;
+        info!(
+            "Exported {} objects of table {} of tenant {} to {}",
+            count, table, tenant, path.display()
+        );
+
+        Ok(count)
+    }
+
+    // Writes every object in a snapshot file to a table of a tenant, creating the table if it
+    // does not exist. The whole file is read and checked before any object is written, so that
+    // a corrupt snapshot changes nothing. Sealed values are opened with the tenant's key, under
+    // the table the snapshot was exported from. The objects are written like puts, and so are
+    // shipped to a backup. Returns the number of objects imported.
+    fn import_table(
+        &self,
+        tenant: TenantId,
+        table: TableId,
+        path: &Path,
+    ) -> Result<u64, (RpcStatus, String)> {
+        match self.get_tenant(tenant) {
+            None => return Err((RpcStatus::StatusTenantDoesNotExist, String::new())),
+            Some(ref t) if t.get_table(table).map_or(false, |t| t.owner() != tenant) => {
+                return Err((RpcStatus::StatusPermissionDenied, String::new()))
+            }
+            Some(_) => {}
+        }
+
+        // Standbys only take writes shipped by their primary, and Raft-replicated tables only
+        // take writes through the Raft log.
+        if replica::standby() {
+            return Err((RpcStatus::StatusNotPrimary, String::new()));
+        }
+        if raft::governs(tenant, table) {
+            let detail = String::from("Raft-replicated tables cannot be imported into");
+            return Err((RpcStatus::StatusInvalidOperation, detail));
+        }
+
+        let invalid = |err: ::std::io::Error| {
+            let detail = format!("Failed to import {}: {}", path.display(), err);
+            (RpcStatus::StatusInvalidOperation, detail)
+        };
+        let open = || -> ::std::io::Result<(snapshot::Reader<BufReader<File>>, snapshot::Header)> {
+            let file = File::open(path)?;
+            snapshot::Reader::new(BufReader::new(file))
+        };
+
+        let (_, header) = open().map_err(&invalid)?;
+        let sealed = match (header.flags & snapshot::FLAG_SEALED != 0, self.tenant_key(tenant)) {
+            (false, _) => None,
+            (true, Some(key)) => Some(key),
+            (true, None) => {
+                let detail = String::from("The snapshot is sealed, and the tenant has no key");
+                return Err((RpcStatus::StatusInvalidOperation, detail));
+            }
+        };
+        let unseal = |key: &[u8], val: Vec<u8>| match sealed {
+            Some(ref sealed) => sealed.open(header.table, key, &val).ok_or_else(|| {
+                let detail = format!("Failed to import {}: a value did not open", path.display());
+                (RpcStatus::StatusInvalidOperation, detail)
+            }),
+            None => Ok(val),
+        };
+
+        // Check the snapshot and open every value in full first.
+        let (mut reader, _) = open().map_err(&invalid)?;
+        while let Some((key, val)) = reader.next().map_err(&invalid)? {
+            unseal(&key, val)?;
+        }
+
+        let (mut reader, _) = open().map_err(&invalid)?;
+        let mut count = 0;
+        while let Some((key, val)) = reader.next().map_err(&invalid)? {
+            let val = unseal(&key, val)?;
+            let rec = replica::Record {
+                op: replica::OP_PUT,
+                tenant: tenant,
+                table: table,
+                key: &key,
+                val: &val,
+            };
+            self.write_record(&rec);
+            count += 1;
+        }
+        info!(
+            "Imported {} objects into table {} of tenant {} from {}",
+            count, table, tenant, path.display()
+        );
+
+        Ok(count)
+    }
+
+    /// Handles the backup() RPC request, which takes a backup of every table to object storage,
+    /// reports on backups, or restores one. Backups and restores run on a thread of their own
+    /// (see `backup::serve()`); requests only start them. Since they cover every tenant, starting
+    /// one takes the operator's credential. A report takes the header tenant's or the operator's.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf`: A buffer consisting of the RPC header followed by a `BackupAction`, the
+    ///          credential, and the action's arguments.
+    ///
+    /// # Return
+    ///
+    /// A response header, followed by a line of JSON for a status request. Starting a backup or
+    /// restore while another waits to run, or at a server without object storage, fails with
+    /// StatusInvalidOperation.
+    pub fn backup(&self, buf: Vec<u8>) -> Vec<u8> {
+        let op = OpCode::SandstormBackupRpc;
+        let (tenant, stamp) = match Master::admin_header(&buf) {
+            Some(parsed) => parsed,
+            None => return Master::admin_response(0, op, 0, RpcStatus::StatusMalformedRequest, &[]),
+        };
+
+        let args = &buf[size_of::<RpcRequestHeader>()..];
+        let credential = match Master::le(args, 1, 8) {
+            Some(credential) => credential,
+            None => {
+                let status = RpcStatus::StatusMalformedRequest;
+                return Master::admin_response(stamp, op, tenant, status, &[]);
+            }
+        };
+        let rest = &args[9..];
+
+        let status = match args[0] {
+            action if action == BackupAction::Start as u8 && rest.len() == 1 => {
+                if !self.operator(tenant, credential, "backup") {
+                    RpcStatus::StatusPermissionDenied
+                } else if backup::start(rest[0] != 0) {
+                    RpcStatus::StatusOk
+                } else {
+                    RpcStatus::StatusInvalidOperation
+                }
+            }
+
+            action if action == BackupAction::Status as u8 && rest.len() == 0 => {
+                let permitted = match self.get_tenant(tenant) {
+                    Some(ref t) => self.authenticate(t, credential, "backup status"),
+                    None => self.admin(credential),
+                };
+                if !permitted {
+                    let status = RpcStatus::StatusPermissionDenied;
+                    return Master::admin_response(stamp, op, tenant, status, &[]);
+                }
+
+                let report = backup::status();
+                let status = RpcStatus::StatusOk;
+                return Master::admin_response(stamp, op, tenant, status, report.as_bytes());
+            }
+
+            // Standbys only take writes shipped by their primary.
+            action if action == BackupAction::Restore as u8 && rest.len() == 8 => {
+                if !self.operator(tenant, credential, "restore") {
+                    RpcStatus::StatusPermissionDenied
+                } else {
+                    match (replica::standby(), Master::le(rest, 0, 8)) {
+                        (true, _) => RpcStatus::StatusNotPrimary,
+                        (false, Some(id)) if backup::restore(id) => RpcStatus::StatusOk,
+                        (false, _) => RpcStatus::StatusInvalidOperation,
+                    }
+                }
+            }
+
+            _ => RpcStatus::StatusMalformedRequest,
+        };
+
+        Master::admin_response(stamp, op, tenant, status, &[])
+    }
+
+    /// Returns the identifiers of the tables a tenant owns, excluding those shared with it, or
+    /// None if there is no such tenant.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant`: The tenant.
+    pub fn owned_tables(&self, tenant: TenantId) -> Option<Vec<TableId>> {
+        self.get_tenant(tenant)
+            .map(|t| t.tables().into_iter().map(|(id, _)| id).collect())
+    }
+
+    /// Suspends or resumes a tenant, if it exists.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant`:    The tenant.
+    /// * `suspended`: True if the tenant should be suspended, false if it should be resumed.
+    pub fn suspend_tenant(&self, tenant: TenantId, suspended: bool) {
+        if let Some(t) = self.get_tenant(tenant) {
+            t.set_suspended(suspended);
+        }
+    }
+
+    /// Returns the identifier of every tenant at the server.
+    pub fn tenant_ids(&self) -> Vec<TenantId> {
+        let mut ids = Vec::new();
+        for bucket in self.tenants.iter() {
+            ids.extend(bucket.read().keys().cloned());
+        }
+
+        ids
+    }
+
+    /// Returns the key a tenant's values are sealed with when written out, if it has one.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant`: The tenant.
+    pub fn tenant_key(&self, tenant: TenantId) -> Option<Arc<Key>> {
+        self.get_tenant(tenant).and_then(|t| t.key())
+    }
+
+    /// Returns a copy of the value of an object in a table a tenant owns, or None if there is
+    /// no such object.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant`: The tenant.
+    /// * `table`:  The identifier of the table.
+    /// * `key`:    The object's key.
+    pub fn read_object(&self, tenant: TenantId, table: TableId, key: &[u8]) -> Option<Vec<u8>> {
+        match self.get_tenant(tenant).and_then(|t| t.get_table(table)) {
+            Some(ref t) if t.owner() == tenant && key.len() > 0 => t
+                .get(key)
+                .and_then(|object| self.heap.resolve(object))
+                .map(|(_, val)| val.to_vec()),
+            _ => None,
+        }
+    }
+
+    /// Calls a closure on a record of a put of every object in a table a tenant owns. Every
+    /// bucket of the table is locked in turn, so this should not be called on a thread that is
+    /// processing requests.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant`: The tenant.
+    /// * `table`:  The identifier of the table.
+    /// * `f`:      The closure.
+    pub fn visit_table(&self, tenant: TenantId, table: TableId, f: &mut FnMut(&replica::Record)) {
+        let table_ref = match self.get_tenant(tenant).and_then(|t| t.get_table(table)) {
+            Some(ref t) if t.owner() == tenant => Arc::clone(t),
+            _ => return,
+        };
+
+        table_ref.visit(&mut |object| {
+            if let Some((key, val)) = self.heap.resolve(object.clone()) {
+                let rec = replica::Record {
+                    op: replica::OP_PUT,
+                    tenant: tenant,
+                    table: table,
+                    key: &key,
+                    val: &val,
+                };
+                f(&rec);
+            }
+        });
+    }
+
+    /// Waits for the tasks in flight on a tenant's behalf to finish. Dispatch must already have
+    /// stopped handing out tasks for the tenant.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant`:  The tenant.
+    /// * `timeout`: How long to wait for.
+    ///
+    /// # Return
+    ///
+    /// False if tasks were still in flight once the timeout passed.
+    pub fn quiesce(&self, tenant: TenantId, timeout: Duration) -> bool {
+        let t = match self.get_tenant(tenant) {
+            Some(t) => t,
+            None => return true,
+        };
+
+        // Like when draining a tenant, every task holds a reference to it.
+        let start = Instant::now();
+        while Arc::strong_count(&t) > 2 {
+            if start.elapsed() > timeout {
+                return false;
+            }
+            sleep(Duration::from_millis(1));
+        }
+
+        true
+    }
+
+    /// Handles the profile() RPC request, which starts or stops the sampling profiler. Only one
+    /// profile can be taken at a time, so starting the profiler while it runs, or stopping it
+    /// while it doesn't, fails with StatusInvalidOperation.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf`: A buffer consisting of the RPC header followed by a `ProfileAction` and it's
+    ///          arguments.
+    ///
+    /// # Return
+    ///
+    /// A response header, followed by the folded stacks sampled if the profiler was stopped.
+    pub fn profile(&self, buf: Vec<u8>) -> Vec<u8> {
+        let op = OpCode::SandstormProfileRpc;
+        let (tenant, stamp) = match Master::admin_header(&buf) {
+            Some(parsed) => parsed,
+            None => return Master::admin_response(0, op, 0, RpcStatus::StatusMalformedRequest, &[]),
+        };
+
+        let args = &buf[size_of::<RpcRequestHeader>()..];
+        let start = ProfileAction::Start as u8;
+        let stop = ProfileAction::Stop as u8;
+        match (args.get(0), Master::le(args, 1, 4)) {
+            (Some(&action), Some(hz)) if action == start && args.len() == 5 => {
+                let status = if profile::start(hz as u32) {
+                    RpcStatus::StatusOk
+                } else {
+                    RpcStatus::StatusInvalidOperation
+                };
+                Master::admin_response(stamp, op, tenant, status, &[])
+            }
+
+            (Some(&action), None) if action == stop && args.len() == 1 => {
+                match profile::stop() {
+                    Some(folded) => {
+                        let status = RpcStatus::StatusOk;
+                        Master::admin_response(stamp, op, tenant, status, folded.as_bytes())
+                    }
+                    None => {
+                        let status = RpcStatus::StatusInvalidOperation;
+                        Master::admin_response(stamp, op, tenant, status, &[])
+                    }
+                }
+            }
+
+            _ => Master::admin_response(stamp, op, tenant, RpcStatus::StatusMalformedRequest, &[]),
+        }
+    }
+
+    // Drains a tenant for a tenant() RPC, returning the number of extensions unloaded, tables
+    // freed and objects freed. The tenant is suspended first, so that dispatch stops handing
+    // out tasks for it. Every task executing on the tenant's behalf holds a reference to it, so
+    // once the only references left are those held here and by the tenant map, nothing is in
+    // flight any more.
+    fn drain_tenant(&self, tenant: TenantId, credential: u64) -> (RpcStatus, [u64; 3]) {
+        let t = match self.get_tenant(tenant) {
+            Some(ref t) if !self.authenticate(t, credential, "drain") => {
+                return (RpcStatus::StatusPermissionDenied, [0; 3])
+            }
+            Some(t) => t,
+            None => return (RpcStatus::StatusTenantDoesNotExist, [0; 3]),
+        };
+
+        t.set_suspended(true);
+        let start = Instant::now();
+        while Arc::strong_count(&t) > 2 {
+            if start.elapsed() > Duration::from_millis(DRAIN_TIMEOUT_MS) {
+                warn!("Tenant {} still has tasks in flight, it stays suspended", tenant);
+                return (RpcStatus::StatusTenantBusy, [0; 3]);
+            }
+            sleep(Duration::from_millis(1));
+        }
+
+        let extensions = self.extensions.unload(tenant) as u64;
+        let tables = t.tables();
+        let objects: u64 = tables.iter().map(|&(_, ref table)| table.len() as u64).sum();
+        let tables = tables.len() as u64;
+        self.teardown(&t);
+        audit::record(tenant, Event::DrainTenant);
+
+        info!(
+            "Drained tenant {}: {} extensions, {} tables, {} objects",
+            tenant, extensions, tables, objects
+        );
+        (RpcStatus::StatusOk, [extensions, tables, objects])
+    }
+
+    // Removes a tenant, unmounting it's tables from every tenant they were shared with and
+    // releasing it's arena. The tables are freed once the last reference to them is dropped.
+    fn teardown(&self, t: &Arc<Tenant>) {
+        let tenant = t.id();
+        for (table_id, table) in t.tables() {
+            if table.published() {
+                for other in self.all_tenants() {
+                    other.unmount(table_id, tenant);
+                }
+            }
+
+            for grantee in table.grantees() {
+                if let Some(grantee) = self.get_tenant(grantee) {
+                    grantee.unmount(table_id, tenant);
+                }
+            }
+        }
+
+        self.remove_tenant(tenant);
+        self.heap.release(tenant);
+    }
+
+    // Creates a tenant off the arguments to a tenant() RPC that follow the credential: the
+    // allocation quota, the memory limit, the number of tables and the identifier of each table.
+    fn create_tenant(&self, tenant: TenantId, credential: u64, args: &[u8]) -> RpcStatus {
+        let quota = Master::le(args, 0, 8);
+        let limit = Master::le(args, 8, 8);
+        let count = Master::le(args, 16, 4);
+        let (quota, limit, count) = match (quota, limit, count) {
+            (Some(quota), Some(limit), Some(count)) if args.len() == 20 + 8 * count as usize => {
+                (quota as usize, limit as usize, count as usize)
+            }
+            _ => return RpcStatus::StatusMalformedRequest,
+        };
+
+        // A tenant without a credential could not be acted on by anyone but the operator.
+        if tenant == 0 || credential == 0 {
+            return RpcStatus::StatusInvalidOperation;
+        }
+
+        // Look up published tables before taking the lock below, which the lookup would need.
+        let published = self.published_tables();
+
+        // Hold the bucket's lock across the check and the insert, so that two RPCs racing to
+        // create the same tenant cannot both succeed.
+        let bucket = (tenant & 0xff) as usize & (TENANT_BUCKETS - 1);
+        let mut map = self.tenants[bucket].write();
+        if map.contains_key(&tenant) {
+            return RpcStatus::StatusInvalidOperation;
+        }
+
+        // The tenant gets the quotas of it's service tier, or the server's defaults if it is in
+        // none. The client picks the quotas on the RPC, so they can only tighten these.
+        let (alloc_quota, mem_limit) = self.quotas.get(&tenant).cloned().unwrap_or((0, 0));
+        let entitled = if alloc_quota == 0 { MAX_ALLOC } else { alloc_quota };
+        let quota = match quota {
+            quota if quota > 0 && quota < entitled => quota,
+            _ => alloc_quota,
+        };
+        let limit = match limit {
+            limit if limit > 0 && (mem_limit == 0 || limit < mem_limit) => limit,
+            _ => mem_limit,
+        };
+
+        let created = Tenant::with_quota(tenant, credential, quota, limit);
+        audit::record(tenant, Event::CreateTenant);
+        for idx in 0..count {
+            if let Some(table) = Master::le(args, 20 + 8 * idx, 8) {
+                created.create_table(table);
+                audit::record(tenant, Event::CreateTable { table: table });
+            }
+        }
+        for (table_id, table) in published {
+            created.mount(table_id, table);
+        }
+        map.insert(tenant, Arc::new(created));
+
+        info!("Created tenant {} with {} tables", tenant, count);
+        RpcStatus::StatusOk
+    }
+
+    // Grants another tenant access to one of `owner`'s tables off the arguments to a tenant() RPC
+    // that follow the credential: the table, the other tenant and the access granted.
+    fn grant_table(&self, owner: &Arc<Tenant>, args: &[u8]) -> RpcStatus {
+        let (table_id, grantee, access) =
+            match (Master::le(args, 0, 8), Master::le(args, 8, 4), Master::le(args, 12, 1)) {
+                (Some(t), Some(g), Some(a)) if args.len() == 13 => (t, g as TenantId, a as u8),
+                _ => return RpcStatus::StatusMalformedRequest,
+            };
+
+        // Only tables the tenant owns can be shared, not those shared with it.
+        let table = match owner.get_table(table_id) {
+            Some(ref table) if table.owner() != owner.id() => {
+                return RpcStatus::StatusPermissionDenied
+            }
+            Some(table) => table,
+            None => return RpcStatus::StatusTableDoesNotExist,
+        };
+
+        let grantee = match self.get_tenant(grantee) {
+            Some(ref grantee) if grantee.id() == owner.id() => {
+                return RpcStatus::StatusInvalidOperation
+            }
+            Some(grantee) => grantee,
+            None => return RpcStatus::StatusTenantDoesNotExist,
+        };
+
+        if access == 0 {
+            table.grant(grantee.id(), 0);
+            grantee.unmount(table_id, owner.id());
+        } else {
+            if !grantee.mount(table_id, Arc::clone(&table)) {
+                return RpcStatus::StatusInvalidOperation;
+            }
+            table.grant(grantee.id(), access);
+        }
+
+        audit::record(
+            owner.id(),
+            Event::Grant {
+                table: table_id,
+                grantee: grantee.id(),
+                access: access,
+            },
+        );
+
+        info!(
+            "Tenant {} granted tenant {} access {:#x} on table {}",
+            owner.id(),
+            grantee.id(),
+            access,
+            table_id
+        );
+        RpcStatus::StatusOk
+    }
+
+    // Returns the status to fail an install() RPC with if installing an extension of `size` bytes
+    // under `name` would take a tenant over it's caps, or None if it would not.
+    fn exceeds_extension_caps(&self, tenant: TenantId, name: &str, size: u64) -> Option<RpcStatus> {
+        let (count, bytes) = self.extensions.usage(tenant, name);
+        if self.max_extensions > 0 && count + 1 > self.max_extensions {
+            warn!("Tenant {} cannot install more than {} extensions", tenant, self.max_extensions);
+            return Some(RpcStatus::StatusTooManyExtensions);
+        }
+
+        if self.max_extension_bytes > 0 && bytes + size > self.max_extension_bytes {
+            warn!(
+                "Tenant {} cannot install {} bytes of extensions on top of {} bytes, the cap is {}",
+                tenant, size, bytes, self.max_extension_bytes
+            );
+            return Some(RpcStatus::StatusExtensionsTooLarge);
+        }
+
+        None
+    }
+
+    // Replaces a tenant's key off the arguments to a tenant() RPC that follow the credential:
+    // either the key, or nothing if values should no longer be encrypted. The log is handed the
+    // key too, and applies any writes recovery held back for it.
+    fn set_key(&self, tenant: &Arc<Tenant>, args: &[u8]) -> RpcStatus {
+        let key = match args.len() {
+            0 => None,
+            KEY_LEN => {
+                let mut key = [0u8; KEY_LEN];
+                key.copy_from_slice(args);
+                let ret = Key::new(&key);
+                for byte in key.iter_mut() {
+                    unsafe { write_volatile(byte, 0) };
+                }
+                Some(ret)
+            }
+            _ => return RpcStatus::StatusMalformedRequest,
+        };
+
+        let installed = key.is_some();
+        tenant.set_key(key);
+        wal::set_key(self, tenant.id(), tenant.key());
+        raft::set_key(tenant.id(), tenant.key());
+        audit::record(tenant.id(), Event::Key { installed: installed });
+        info!("Replaced the key of tenant {}", tenant.id());
+        RpcStatus::StatusOk
+    }
+
+    // Returns true if a credential on an administrative RPC is the operator's.
+    fn admin(&self, credential: u64) -> bool {
+        self.admin_credential != 0 && credential == self.admin_credential
+    }
+
+    // Checks the credential on an administrative RPC carrying out `action` on a tenant's behalf,
+    // which must be the tenant's own or the operator's, recording a failure to the audit log.
+    fn authenticate(&self, tenant: &Arc<Tenant>, credential: u64, action: &str) -> bool {
+        if tenant.authenticate(credential) || self.admin(credential) {
+            return true;
+        }
+
+        audit::record(tenant.id(), Event::AuthFailure { action: action });
+        counters::add(Counter::AuthFailures, 1);
+        false
+    }
+
+    // Checks the credential on an administrative RPC carrying out `action` on every tenant's
+    // behalf, which must be the operator's, recording a failure to the audit log against the
+    // header's `tenant`.
+    fn operator(&self, tenant: TenantId, credential: u64, action: &str) -> bool {
+        if self.admin(credential) {
+            return true;
+        }
+
+        audit::record(tenant, Event::AuthFailure { action: action });
+        counters::add(Counter::AuthFailures, 1);
+        false
+    }
+
+    // Checks the credential on a replica() RPC carrying out `action`, which must be the one
+    // servers share or the operator's, recording a failure to the audit log against `tenant`.
+    fn peer(&self, tenant: TenantId, credential: u64, action: &str) -> bool {
+        let shared = replica::credential();
+        if (shared != 0 && credential == shared) || self.admin(credential) {
+            return true;
+        }
+
+        audit::record(tenant, Event::AuthFailure { action: action });
+        counters::add(Counter::AuthFailures, 1);
+        false
+    }
+
+    // Publishes or withdraws one of `owner`'s tables off the arguments to a tenant() RPC that
+    // follow the credential: the table, and whether it is published.
+    fn publish_table(&self, owner: &Arc<Tenant>, args: &[u8]) -> RpcStatus {
+        let (table_id, published) = match (Master::le(args, 0, 8), Master::le(args, 8, 1)) {
+            (Some(t), Some(p)) if args.len() == 9 && p <= 1 => (t, p == 1),
+            _ => return RpcStatus::StatusMalformedRequest,
+        };
+
+        let table = match owner.get_table(table_id) {
+            Some(ref table) if table.owner() != owner.id() => {
+                return RpcStatus::StatusPermissionDenied
+            }
+            Some(table) => table,
+            None => return RpcStatus::StatusTableDoesNotExist,
+        };
+
+        // Mount first, so that the table is readable wherever it is mounted. Tenants the table
+        // was granted to explicitly keep it mounted once it is withdrawn.
+        let grantees = table.grantees();
+        let mut shadowed = 0;
+        for other in self.all_tenants() {
+            if other.id() == owner.id() {
+                continue;
+            }
+
+            if published {
+                if !other.mount(table_id, Arc::clone(&table)) {
+                    shadowed += 1;
+                }
+            } else if !grantees.contains(&other.id()) {
+                other.unmount(table_id, owner.id());
+            }
+        }
+        table.publish(published);
+
+        audit::record(
+            owner.id(),
+            Event::Publish {
+                table: table_id,
+                published: published,
+            },
+        );
+        if published {
+            info!(
+                "Tenant {} published table {}, {} tenants have a table of their own under it",
+                owner.id(),
+                table_id,
+                shadowed
+            );
+        } else {
+            info!("Tenant {} withdrew table {}", owner.id(), table_id);
+        }
+        RpcStatus::StatusOk
+    }
+
+    // Returns every tenant in the system. No lock is held once this returns.
+    fn all_tenants(&self) -> Vec<Arc<Tenant>> {
+        let mut tenants = Vec::new();
+        for bucket in self.tenants.iter() {
+            tenants.extend(bucket.read().values().cloned());
+        }
+        tenants
+    }
+
+    // Returns every published table along with it's identifier.
+    fn published_tables(&self) -> Vec<(TableId, Arc<Table>)> {
+        let mut published = Vec::new();
+        for tenant in self.all_tenants() {
+            for (table_id, table) in tenant.tables() {
+                if table.published() {
+                    published.push((table_id, table));
+                }
+            }
+        }
+        published
+    }
+
+    // Reads a little endian integer of `len` bytes at `off` in a buffer, or returns None if the
+    // buffer is too short.
+    fn le(buf: &[u8], off: usize, len: usize) -> Option<u64> {
+        if buf.len() < off + len {
+            return None;
+        }
+
+        let val = buf[off..off + len]
+            .iter()
+            .rev()
+            .fold(0u64, |acc, byte| (acc << 8) | *byte as u64);
+        Some(val)
+    }
+
+    // Applies records of a tenant's data sent by the server it is migrating from, following the
+    // `MigrateAction` and the credential on a migrate() request. Every record is parsed before
+    // any is applied, so that a malformed batch changes nothing, and every one must be a write of
+    // the tenant's.
+    fn ingest(&self, tenant: TenantId, args: &[u8]) -> RpcStatus {
+        if self.get_tenant(tenant).is_none() {
+            return RpcStatus::StatusTenantDoesNotExist;
+        }
+
+        let mut records = Vec::new();
+        let mut off = 0;
+        while off < args.len() {
+            match replica::decode(&args[off..]) {
+                Some((ref rec, _)) if rec.tenant != tenant => {
+                    return RpcStatus::StatusMalformedRequest
+                }
+                Some((rec, len)) => {
+                    records.push(rec);
+                    off += len;
+                }
+                None => return RpcStatus::StatusMalformedRequest,
+            }
+        }
+
+        for rec in records.iter() {
+            self.apply_record(rec);
+        }
+
+        RpcStatus::StatusOk
+    }
+
+    // Applies a batch of records shipped to a standby or fed to a read replica, following the
+    // `ReplicaAction`, the credential and the `off` bytes of arguments ahead of the epoch on a
+    // replica() request. Every record is parsed before any is applied, so that a malformed batch
+    // changes nothing.
+    fn apply_records(&self, args: &[u8], off: usize) -> RpcStatus {
+        let (epoch, first, count) = match (
+            Master::le(args, off, 8),
+            Master::le(args, off + 8, 8),
+            Master::le(args, off + 16, 4),
+        ) {
+            (Some(epoch), Some(first), Some(count)) => (epoch, first, count as usize),
+            _ => return RpcStatus::StatusMalformedRequest,
+        };
+
+        let mut records = Vec::with_capacity(count);
+        let mut off = off + 20;
+        while off < args.len() {
+            match replica::decode(&args[off..]) {
+                Some((rec, len)) => {
+                    records.push(rec);
+                    off += len;
+                }
+                None => return RpcStatus::StatusMalformedRequest,
+            }
+        }
+        if records.len() != count {
+            return RpcStatus::StatusMalformedRequest;
+        }
+
+        let skip = replica::admit(epoch, first, count);
+        for rec in records.iter().skip(skip) {
+            self.apply_record(rec);
+        }
+
+        RpcStatus::StatusOk
+    }
+
+    // Applies a batch of writes shipped from another site to geo-replicated tenants, following
+    // the `ReplicaAction` and the credential on a replica() request. Writes are applied as if a
+    // client made them, but only if they are newer than the last write to their key (see
+    // `geo::apply()`). Every entry is parsed before any is applied, so that a malformed batch
+    // changes nothing.
+    fn apply_geo(&self, args: &[u8]) -> RpcStatus {
+        let count = match Master::le(args, 16, 4) {
+            Some(count) => count as usize,
+            None => return RpcStatus::StatusMalformedRequest,
+        };
+
+        let mut entries = Vec::with_capacity(count);
+        let mut off = 20;
+        while off < args.len() {
+            match geo::decode(&args[off..]) {
+                Some((version, rec, len)) => {
+                    entries.push((version, rec));
+                    off += len;
+                }
+                None => return RpcStatus::StatusMalformedRequest,
+            }
+        }
+        if entries.len() != count {
+            return RpcStatus::StatusMalformedRequest;
+        }
+
+        geo::apply(&entries, |rec| self.write_record(rec));
+        RpcStatus::StatusOk
+    }
+
+    /// Applies a write shipped to a standby, or committed by a Raft group, logging it and noting
+    /// it for the next backup. Tables are created as they are first written to, but tenants are
+    /// not; writes to a missing tenant are dropped.
+    ///
+    /// # Arguments
+    ///
+    /// * `rec`: The record of the write.
+    pub fn apply_record(&self, rec: &replica::Record) {
+        backup::write(rec, || wal::write(rec, || self.replay_record(rec)));
+    }
+
+    /// Applies a write as if a client made it: the write is logged, noted for the next backup,
+    /// and shipped to a standby. Tables are created as they are first written to.
+    ///
+    /// # Arguments
+    ///
+    /// * `rec`: The record of the write.
+    pub fn write_record(&self, rec: &replica::Record) {
+        replica::write(rec, || self.replay_record(rec));
+    }
+
+    /// Applies a write to the local table only, without logging, shipping or otherwise noting
+    /// it. Used to replay the log at recovery; anything else should go through `apply_record()`
+    /// or `write_record()`.
+    ///
+    /// # Arguments
+    ///
+    /// * `rec`: The record of the write.
+    pub fn replay_record(&self, rec: &replica::Record) {
+        let tenant = match self.get_tenant(rec.tenant) {
+            Some(tenant) => tenant,
+            None => {
+                warn!(
+                    "Dropped a replicated write to table {} of missing tenant {}",
+                    rec.table, rec.tenant
+                );
+                return;
+            }
+        };
+
+        // Tables cannot be written to with an empty key.
+        if rec.key.len() == 0 {
+            return;
+        }
+
+        if tenant.get_table(rec.table).map_or(true, |table| table.owner() != rec.tenant) {
+            tenant.create_table(rec.table);
+        }
+        let table = match tenant.get_table(rec.table) {
+            Some(table) => table,
+            None => return,
+        };
+
+        match rec.op {
+            replica::OP_PUT => match self.heap.object(rec.tenant, rec.table, rec.key, rec.val) {
+                Some((key, obj)) => table.put(key, obj),
+                None => warn!(
+                    "Failed to allocate a replicated write to table {} of tenant {}",
+                    rec.table, rec.tenant
+                ),
+            },
+
+            replica::OP_DELETE => table.delete(rec.key),
+
+            op => warn!("Dropped a replicated write with unknown operation {}", op),
+        }
+    }
+
+    // Answers a request without executing it, with a response consisting of a common header
+    // carrying a status, such as for a request from a suspended tenant, followed by a payload.
+    fn refuse(
+        &self,
+        op: OpCode,
+        status: RpcStatus,
+        payload: &[u8],
+        req: Packet<UdpHeader, EmptyMetadata>,
+        mut res: Packet<UdpHeader, EmptyMetadata>,
+    ) -> Result<
+        Box<Task>,
+        (
+            Packet<UdpHeader, EmptyMetadata>,
+            Packet<UdpHeader, EmptyMetadata>,
+        ),
+    > {
+        let tenant = parse_rpc_tenant(req.get_payload(), 0).unwrap_or(0);
+        let mut hdr = RpcResponseHeader::new(parse_rpc_stamp(&req), op, tenant);
+        hdr.status = status;
+
+        let hdr: [u8; size_of::<RpcResponseHeader>()] = unsafe { transmute(hdr) };
+        if res.add_to_payload_tail(hdr.len(), &hdr).is_err()
+            || res.add_to_payload_tail(payload.len(), payload).is_err()
+        {
+            return Err((req, res));
+        }
+
+        let op = native::once(move || Some((req, res)));
+
+        return Ok(Box::new(Native::new(TaskPriority::REQUEST, tenant as TenantId, op)));
+    }
+
+    /// Counts the objects and bytes stored by every tenant, in the tables it owns. Required to
+    /// meter tenants. Every bucket of every table is locked in turn, so this should not be called
+    /// on a thread that is processing requests.
+    ///
+    /// # Return
+    ///
+    /// The identifier of every tenant, along with the number of objects and bytes it stores.
+    pub fn storage(&self) -> Vec<(TenantId, u64, u64)> {
+        let mut storage = Vec::new();
+        for bucket in self.tenants.iter() {
+            let tenants: Vec<Arc<Tenant>> = bucket.read().values().cloned().collect();
+            for tenant in tenants {
+                let (mut objects, mut bytes) = (0, 0);
+                for (_, table) in tenant.tables() {
+                    objects += table.len() as u64;
+                    bytes += table.bytes() as u64;
+                }
+                storage.push((tenant.id(), objects, bytes));
+            }
+        }
+
+        return storage;
+    }
+
+    /// Returns the counters on every extension in the database. Required to export metrics.
+    ///
+    /// # Return
+    ///
+    /// The tenant, name and counters of every extension, ordered by tenant and then name.
+    pub fn extension_stats(&self) -> Vec<(TenantId, String, ExtensionStats)> {
+        self.extensions.stats()
+    }
+
+    // Reads the tenant and stamp off an RPC header received on the install() TCP endpoint, or
+    // returns None if the buffer is too short to hold one.
+    fn admin_header(buf: &[u8]) -> Option<(TenantId, u64)> {
+        let hdr = read_request_header(&mut Reader::new(buf));
+        hdr.map(|(tenant, stamp)| (tenant as TenantId, stamp))
+    }
+
+    // Builds the response to an RPC received on the install() TCP endpoint.
+    fn admin_response(
+        stamp: u64,
+        op: OpCode,
+        tenant: TenantId,
+        status: RpcStatus,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let mut hdr = RpcResponseHeader::new(stamp, op, tenant as u32);
+        hdr.status = status;
+
+        let hdr: [u8; size_of::<RpcResponseHeader>()] = unsafe { transmute(hdr) };
+        let mut ret: Vec<u8> = Vec::new();
+        ret.extend_from_slice(&hdr);
+        ret.extend_from_slice(payload);
+        return ret;
+    }
+}
+
+// Writes an extension to the file at `path`, and syncs it to disk.
+fn save_extension(path: &str, extn: &[u8]) -> Result<(), ExtensionError> {
+    File::create(path)
+        .and_then(|mut file| file.write_all(extn).and_then(|_| file.sync_all()))
+        .map_err(ExtensionError::Io)
+}
+
+// Returns a native operation that holds a response back until the backup has acknowledged the
+// write with sequence number `seq`, and until the Raft group has committed or lost `entry`, if
+// there is one. `respond` then finishes the response, and is told whether the entry was lost.
+fn replicated<F>(seq: u64, entry: Option<(u64, u64)>, respond: F) -> NativeOperation
+where
+    F: FnOnce(bool) -> Packets + 'static,
+{
+    let mut respond = Some(respond);
+    Box::new(move || {
+        if !replica::acked(seq) {
+            return Step::Yield;
+        }
+
+        let lost = match entry.map(|(index, term)| raft::outcome(index, term)) {
+            Some(raft::Outcome::Pending) => return Step::Yield,
+            Some(raft::Outcome::Lost) => true,
+            _ => false,
+        };
+
+        Step::Done(respond.take().and_then(|respond| respond(lost)))
+    })
+}
+
+// Returns true if a get(), put(), multiget() or multiop() request carries a key longer than
+// `max` bytes.
+fn oversized(op: &OpCode, payload: &[u8], max: usize) -> bool {
+    match *op {
+        OpCode::SandstormGetRpc | OpCode::SandstormPutRpc | OpCode::SandstormMultiGetRpc => {
+            KeyedRequest::read(payload).map_or(false, |hdr| hdr.key_length > max)
+        }
+
+        OpCode::SandstormMultiOpRpc if payload.len() >= size_of::<MultiOpRequest>() => {
+            let mut ops = &payload[size_of::<MultiOpRequest>()..];
+            while let Some((entry, len)) = multiop::parse_entry(ops) {
+                if entry.key.len() > max {
+                    return true;
+                }
+                ops = &ops[len..];
+            }
+            false
+        }
+
+        _ => false,
+    }
+}
+
+// Returns true if a get(), put(), multiget() or multiop() request reads or writes a key of a
+// partitioned tenant that another server holds.
+fn misrouted(op: &OpCode, tenant: TenantId, payload: &[u8]) -> bool {
+    match *op {
+        OpCode::SandstormGetRpc | OpCode::SandstormPutRpc => match KeyedRequest::read(payload) {
+            Some(hdr) => {
+                let key = &payload[hdr.header_length..];
+                let len = hdr.key_length.min(key.len());
+                partition::foreign(tenant, &key[..len])
+            }
+            None => false,
+        },
+
+        OpCode::SandstormMultiGetRpc => match KeyedRequest::read(payload) {
+            Some(hdr) => {
+                let keys = &payload[hdr.header_length..];
+                let len = hdr.key_length;
+                len > 0 && keys
+                    .chunks(len)
+                    .take(hdr.num_keys as usize)
+                    .any(|key| partition::foreign(tenant, key))
+            }
+            None => false,
+        },
+
+        OpCode::SandstormMultiOpRpc if payload.len() >= size_of::<MultiOpRequest>() => {
+            let mut ops = &payload[size_of::<MultiOpRequest>()..];
+            while let Some((entry, len)) = multiop::parse_entry(ops) {
+                if partition::foreign(tenant, entry.key) {
+                    return true;
+                }
+                ops = &ops[len..];
+            }
+            false
+        }
+
+        _ => false,
+    }
+}
+
+/// Implementation of the Service trait for Master, allowing it to service RPC requests.
+impl Service for Master {
+    /// Lookup the Service trait for documentation.
+    fn dispatch(
+        &self,
+        op: OpCode,
+        mut req: Packet<UdpHeader, EmptyMetadata>,
+        res: Packet<UdpHeader, EmptyMetadata>,
+    ) -> Result<
+        Box<Task>,
+        (
+            Packet<UdpHeader, EmptyMetadata>,
+            Packet<UdpHeader, EmptyMetadata>,
+        ),
+    > {
+        // A draining server takes on no new requests. Clients retry them once it is back, or
+        // against another server.
+        if drain::draining() {
+            let status = SplinterError::from(SchedulerError::Draining).status();
+            return self.refuse(op, status, &[], req, res);
+        }
+
+        // A request whose budget ran out before it got here is not started. It's client has
+        // already given up on it.
+        if deadline::expired(deadline::current()) {
+            counters::add(Counter::DeadlinesExpired, 1);
+            return self.refuse(op, RpcStatus::StatusDeadlineExceeded, &[], req, res);
+        }
+
+        // Strip any staleness bound off the request, so that handlers never see it.
+        let bound = readrep::strip(&mut req);
+
+        // Requests on keys longer than the server takes are refused before any work is done.
+        if self.max_key_len < MAX_KEY_LEN && oversized(&op, req.get_payload(), self.max_key_len) {
+            return self.refuse(op, RpcStatus::StatusKeyTooLong, &[], req, res);
+        }
+
+        // Requests from a tenant that was migrated away are answered with where it went.
+        let tenant = parse_rpc_tenant(req.get_payload(), 0);
+        if let Some(ip) = tenant.and_then(|tenant| migrate::moved(tenant as TenantId)) {
+            return self.refuse(op, RpcStatus::StatusTenantMoved, &ip, req, res);
+        }
+
+        // Requests on keys of a partitioned tenant held by another server are answered with the
+        // tenant's routing table.
+        if let Some(tenant) = tenant.map(|tenant| tenant as TenantId) {
+            if misrouted(&op, tenant, req.get_payload()) {
+                let map = partition::encoded(tenant).unwrap_or(Vec::new());
+                return self.refuse(op, RpcStatus::StatusWrongPartition, &map, req, res);
+            }
+        }
+
+        // Requests from a suspended tenant are answered without being executed.
+        let suspended = tenant
+            .and_then(|tenant| self.get_tenant(tenant as TenantId))
+            .map_or(false, |tenant| tenant.suspended());
+        if suspended {
+            return self.refuse(op, RpcStatus::StatusTenantSuspended, &[], req, res);
+        }
+
+        // A read replica only answers reads that tolerate how far behind the primary it is. It
+        // refuses everything else with how far behind that is, so that the client can go to the
+        // primary instead.
+        if readrep::replica() {
+            let reads = op == OpCode::SandstormGetRpc
+                || op == OpCode::SandstormMultiGetRpc
+                || op == OpCode::SandstormInvokeRpc;
+            if !reads || !bound.map_or(false, readrep::fresh) {
+                let ms = readrep::staleness_ms().unwrap_or(u32::max_value() as u64);
+                let ms = ms.min(u32::max_value() as u64) as u32;
+                let ms: [u8; 4] = unsafe { transmute(ms.to_le()) };
+                return self.refuse(op, RpcStatus::StatusNotPrimary, &ms, req, res);
+            }
+        }
+
+        // A standby only answers reads, until it is promoted.
+        let writes = op == OpCode::SandstormPutRpc
+            || op == OpCode::SandstormMultiOpRpc
+            || op == OpCode::SandstormInvokeRpc;
+        if writes && replica::standby() {
+            return self.refuse(op, RpcStatus::StatusNotPrimary, &[], req, res);
+        }
+
+        // Writes from a geo-replicated tenant are refused while the remote site lags too far
+        // behind, until it catches up. Clients back off and retry them.
+        if writes && tenant.map_or(false, |tenant| geo::lagging(tenant as TenantId)) {
+            return self.refuse(op, RpcStatus::StatusRateLimited, &[], req, res);
+        }
+
+        // Based on the opcode, call the relevant RPC handler.
+        match op {
+            OpCode::SandstormGetRpc => {
+                return self.get(req, res);
+            }
+
+            OpCode::SandstormPutRpc => {
+                return self.put(req, res);
+            }
+
+            OpCode::SandstormMultiGetRpc => {
+                return self.multiget(req, res);
+            }
+
+            OpCode::SandstormMultiOpRpc => {
+                return self.multiop(req, res);
             }
 
             OpCode::SandstormInvokeRpc => {
@@ -0,0 +1,547 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+
+// Cluster membership and failure detection. Every server lists the others in it's config by the
+// UDP address they exchange heartbeats on, and sends each of them a heartbeat every so often. A
+// member that has not been heard from for the timeout is declared dead, and alive again once it's
+// heartbeats resume. Heartbeats carry the sender's install() address and the address of the
+// backup it ships writes to, which is what the rest of the server knows other servers by:
+//
+//  - A primary gives up on a backup declared dead right away, instead of waiting out the backup
+//    timeout (see `replica::ship()`).
+//  - Migrations to a server declared dead are refused, and one that is running fails right away
+//    (see `migrate::start()`).
+//  - A standby with failover enabled promotes itself once the primary shipping writes to it is
+//    declared dead. A heartbeat timeout cannot tell a dead primary from one that is cut off from
+//    the standby, so failover is only safe on a network that does not partition the two.
+//  - `status()` reports the state of every member, for clients and operators to route around
+//    dead servers with.
+//
+// The list of members is static; heartbeats from anyone not on it are ignored. A single thread
+// sends and receives heartbeats on a blocking UDP socket.
+
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::str::{from_utf8, FromStr};
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use std::sync::{Once, ONCE_INIT};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::audit::quote;
+use super::replica;
+
+use spin::RwLock;
+
+/// The number of milliseconds between heartbeats when `member_heartbeat_ms` is zero.
+pub const DEFAULT_HEARTBEAT_MS: u64 = 100;
+
+/// The number of milliseconds a member can go unheard from when `member_timeout_ms` is zero.
+pub const DEFAULT_TIMEOUT_MS: u64 = 1000;
+
+/// The bytes every heartbeat starts with.
+const MAGIC: &[u8; 4] = b"SPHB";
+
+/// The largest heartbeat received.
+const MAX_DATAGRAM: usize = 1024;
+
+/// Settings for membership, taken from the server's config.
+pub struct Settings {
+    /// The address heartbeats are received on, and sent from.
+    pub addr: String,
+
+    /// The addresses every other member receives heartbeats on.
+    pub members: Vec<String>,
+
+    /// The addresses of this server's install() TCP endpoint, and of the backup it ships writes
+    /// to, if any. Both are carried on every heartbeat.
+    pub install_addr: String,
+    pub backup_addr: String,
+
+    /// The number of milliseconds between heartbeats.
+    pub heartbeat_ms: u64,
+
+    /// The number of milliseconds a member can go unheard from before it is declared dead.
+    pub timeout_ms: u64,
+
+    /// Set if a standby promotes itself when it's primary is declared dead.
+    pub failover: bool,
+}
+
+/// What a server knows about a member.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum State {
+    /// Never heard from.
+    Unknown,
+
+    /// Heard from within the timeout.
+    Alive,
+
+    /// Not heard from for the timeout.
+    Dead,
+}
+
+/// A heartbeat, as sent to every member.
+#[derive(Debug, PartialEq)]
+pub struct Heartbeat<'a> {
+    /// The time in nanoseconds since the UNIX epoch at which the sender started. Lets a member
+    /// tell a restarted server from the one before it.
+    pub incarnation: u64,
+
+    /// The address of the sender's install() TCP endpoint.
+    pub install_addr: &'a str,
+
+    /// The address of the install() TCP endpoint of the backup the sender ships writes to. Empty
+    /// if it has none.
+    pub backup_addr: &'a str,
+}
+
+/// Appends a heartbeat to a buffer: the magic bytes, the incarnation (u64), and the length (u16)
+/// and bytes of each address, little endian.
+///
+/// # Arguments
+///
+/// * `buf`: The buffer.
+/// * `hb`:  The heartbeat.
+pub fn encode(buf: &mut Vec<u8>, hb: &Heartbeat) {
+    buf.extend_from_slice(MAGIC);
+    for byte in 0..8 {
+        buf.push((hb.incarnation >> (8 * byte)) as u8);
+    }
+    for addr in [hb.install_addr, hb.backup_addr].iter() {
+        buf.push(addr.len() as u8);
+        buf.push((addr.len() >> 8) as u8);
+        buf.extend_from_slice(addr.as_bytes());
+    }
+}
+
+/// Parses a heartbeat.
+///
+/// # Arguments
+///
+/// * `buf`: The datagram the heartbeat came in.
+///
+/// # Return
+///
+/// The heartbeat, or None if the datagram is not a well formed one.
+pub fn decode(buf: &[u8]) -> Option<Heartbeat> {
+    if buf.len() < 12 || &buf[..4] != MAGIC {
+        return None;
+    }
+
+    let incarnation = buf[4..12]
+        .iter()
+        .rev()
+        .fold(0u64, |acc, &byte| (acc << 8) | byte as u64);
+
+    let mut off = 12;
+    let mut addrs = Vec::with_capacity(2);
+    for _ in 0..2 {
+        if buf.len() < off + 2 {
+            return None;
+        }
+        let len = buf[off] as usize | (buf[off + 1] as usize) << 8;
+        match buf.get(off + 2..off + 2 + len).and_then(|addr| from_utf8(addr).ok()) {
+            Some(addr) => addrs.push(addr),
+            None => return None,
+        }
+        off += 2 + len;
+    }
+    if off != buf.len() {
+        return None;
+    }
+
+    Some(Heartbeat {
+        incarnation: incarnation,
+        install_addr: addrs[0],
+        backup_addr: addrs[1],
+    })
+}
+
+// A member, as known from it's heartbeats.
+struct Member {
+    // The address the member receives heartbeats on, and sends them from.
+    addr: SocketAddr,
+
+    // What the member's last heartbeat said.
+    incarnation: u64,
+    install_addr: String,
+    backup_addr: String,
+
+    // When the member was last heard from, and what is known about it.
+    heard: Option<Instant>,
+    state: State,
+}
+
+// Every member, and how long they can go unheard from.
+struct Members {
+    members: Vec<Member>,
+    timeout: Duration,
+}
+
+// Implementation of methods on Members.
+impl Members {
+    // Returns the members at a list of addresses, none of which have been heard from.
+    fn new(addrs: &[SocketAddr], timeout: Duration) -> Members {
+        let members = addrs
+            .iter()
+            .map(|addr| Member {
+                addr: *addr,
+                incarnation: 0,
+                install_addr: String::new(),
+                backup_addr: String::new(),
+                heard: None,
+                state: State::Unknown,
+            })
+            .collect();
+
+        Members {
+            members: members,
+            timeout: timeout,
+        }
+    }
+
+    // Notes a heartbeat from an address. Returns the index of the member that sent it and the
+    // state it was in before, or None if the sender is not a member.
+    fn heard(&mut self, from: SocketAddr, hb: &Heartbeat, now: Instant) -> Option<(usize, State)> {
+        let idx = match self.members.iter().position(|member| member.addr == from) {
+            Some(idx) => idx,
+            None => return None,
+        };
+        let member = &mut self.members[idx];
+        let was = member.state;
+
+        if member.incarnation != hb.incarnation && was == State::Alive {
+            info!("Member at {} restarted", from);
+        }
+        member.incarnation = hb.incarnation;
+        member.install_addr = hb.install_addr.to_string();
+        member.backup_addr = hb.backup_addr.to_string();
+        member.heard = Some(now);
+        member.state = State::Alive;
+        Some((idx, was))
+    }
+
+    // Declares members that have gone unheard from for the timeout dead. Returns the indexes of
+    // those that were alive until now.
+    fn expire(&mut self, now: Instant) -> Vec<usize> {
+        let timeout = self.timeout;
+        let mut died = Vec::new();
+        for (idx, member) in self.members.iter_mut().enumerate() {
+            let expired = member
+                .heard
+                .map_or(false, |heard| now.duration_since(heard) >= timeout);
+            if expired && member.state == State::Alive {
+                member.state = State::Dead;
+                died.push(idx);
+            }
+        }
+        died
+    }
+}
+
+/// Set if the server exchanges heartbeats with other members.
+static ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Initializes MEMBERSHIP exactly once.
+static MEMBERSHIP_INIT: Once = ONCE_INIT;
+
+/// The settings and members shared by all threads. Use `shared()` to access them.
+static mut MEMBERSHIP: *const RwLock<Option<(Settings, Members)>> =
+    0 as *const RwLock<Option<(Settings, Members)>>;
+
+// Returns the settings and members shared by all threads, allocating them on first use.
+fn shared() -> &'static RwLock<Option<(Settings, Members)>> {
+    unsafe {
+        MEMBERSHIP_INIT.call_once(|| {
+            MEMBERSHIP = Box::into_raw(Box::new(RwLock::new(None)));
+        });
+        &*MEMBERSHIP
+    }
+}
+
+/// Sets up membership. Must be called before `serve()`, and before any of the other servers
+/// are relied on being declared dead.
+///
+/// # Arguments
+///
+/// * `settings`: Where heartbeats are exchanged, and how often.
+pub fn configure(settings: Settings) {
+    let addrs: Vec<SocketAddr> = settings
+        .members
+        .iter()
+        .filter_map(|addr| SocketAddr::from_str(addr).ok())
+        .collect();
+    let members = Members::new(&addrs, Duration::from_millis(settings.timeout_ms));
+
+    *shared().write() = Some((settings, members));
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Returns true if the server exchanges heartbeats with other members.
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Returns what is known about the member whose install() TCP endpoint is at an address.
+/// Servers that are not members are always Unknown.
+///
+/// # Arguments
+///
+/// * `install_addr`: The address of the member's install() TCP endpoint.
+pub fn state(install_addr: &str) -> State {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return State::Unknown;
+    }
+
+    let membership = shared().read();
+    membership
+        .as_ref()
+        .and_then(|&(_, ref members)| {
+            members
+                .members
+                .iter()
+                .find(|member| member.install_addr == install_addr)
+                .map(|member| member.state)
+        })
+        .unwrap_or(State::Unknown)
+}
+
+/// Returns true if the member whose install() TCP endpoint is at an address is declared dead.
+///
+/// # Arguments
+///
+/// * `install_addr`: The address of the member's install() TCP endpoint.
+#[inline]
+pub fn down(install_addr: &str) -> bool {
+    state(install_addr) == State::Dead
+}
+
+/// Sends heartbeats to every member, and receives theirs, declaring members dead and alive as
+/// they stop and resume. Blocks the calling thread.
+pub fn serve() {
+    let (addr, heartbeat, beat) = match *shared().read() {
+        Some((ref settings, _)) => {
+            let incarnation = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs() * 1000000000 + d.subsec_nanos() as u64)
+                .unwrap_or(1);
+            let hb = Heartbeat {
+                incarnation: incarnation,
+                install_addr: &settings.install_addr,
+                backup_addr: &settings.backup_addr,
+            };
+            let mut beat = Vec::new();
+            encode(&mut beat, &hb);
+            (
+                settings.addr.clone(),
+                Duration::from_millis(settings.heartbeat_ms),
+                beat,
+            )
+        }
+        None => return,
+    };
+
+    let socket = match UdpSocket::bind(&addr) {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!("Failed to bind heartbeats to {}: {}. Membership is disabled.", addr, e);
+            ENABLED.store(false, Ordering::Relaxed);
+            return;
+        }
+    };
+    info!("Exchanging heartbeats on {}", addr);
+
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    let mut next = Instant::now();
+    loop {
+        let now = Instant::now();
+        if now >= next {
+            send(&socket, &beat);
+            expire(now);
+            next = now + heartbeat;
+        }
+
+        let wait = next.duration_since(now).max(Duration::from_millis(1));
+        let _ = socket.set_read_timeout(Some(wait));
+        match socket.recv_from(&mut buf) {
+            Ok((len, from)) => {
+                if let Some(hb) = decode(&buf[..len]) {
+                    heard(from, &hb);
+                }
+            }
+
+            Err(ref e) if would_block(e) => {}
+
+            Err(e) => warn!("Failed to receive heartbeats: {}", e),
+        }
+    }
+}
+
+// Returns true if a receive failed only because nothing arrived within the timeout.
+fn would_block(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+}
+
+// Sends a heartbeat to every member. A member it cannot be sent to is simply not heard from.
+fn send(socket: &UdpSocket, beat: &[u8]) {
+    let addrs: Vec<SocketAddr> = match *shared().read() {
+        Some((_, ref members)) => members.members.iter().map(|member| member.addr).collect(),
+        None => return,
+    };
+
+    for addr in addrs.iter() {
+        let _ = socket.send_to(beat, addr);
+    }
+}
+
+// Notes a heartbeat from an address.
+fn heard(from: SocketAddr, hb: &Heartbeat) {
+    let mut membership = shared().write();
+    if let Some((_, ref mut members)) = *membership {
+        match members.heard(from, hb, Instant::now()) {
+            Some((_, State::Unknown)) => info!("Member at {} is alive", from),
+            Some((_, State::Dead)) => warn!("Member at {} is alive again", from),
+            _ => {}
+        }
+    }
+}
+
+// Declares members that have gone unheard from for the timeout dead, and fails over to this
+// server if one of them was the primary shipping writes to it.
+fn expire(now: Instant) {
+    let mut promote = false;
+    {
+        let mut membership = shared().write();
+        let (settings, members) = match *membership {
+            Some((ref settings, ref mut members)) => (settings, members),
+            None => return,
+        };
+
+        for idx in members.expire(now) {
+            let member = &members.members[idx];
+            warn!(
+                "Member at {} (install_addr {}) is dead",
+                member.addr, member.install_addr
+            );
+
+            let primary = member.backup_addr.len() > 0
+                && member.backup_addr == settings.install_addr;
+            promote |= primary && settings.failover;
+        }
+    }
+
+    if promote && replica::promote() {
+        warn!("The primary is dead, promoted to primary. Writes are now accepted");
+    }
+}
+
+/// Describes what this server knows about every member as a line of JSON.
+pub fn status() -> String {
+    let membership = shared().read();
+    let members = match *membership {
+        Some((_, ref members)) => members,
+        None => return String::from("{\"members\":null}\n"),
+    };
+
+    let now = Instant::now();
+    let members: Vec<String> = members
+        .members
+        .iter()
+        .map(|member| {
+            let state = match member.state {
+                State::Unknown => "unknown",
+                State::Alive => "alive",
+                State::Dead => "dead",
+            };
+            let heard = match member.heard {
+                Some(heard) => {
+                    let ago = now.duration_since(heard);
+                    (ago.as_secs() * 1000 + ago.subsec_nanos() as u64 / 1000000).to_string()
+                }
+                None => String::from("null"),
+            };
+            format!(
+                "{{\"addr\":\"{}\",\"install_addr\":{},\"state\":\"{}\",\"heard_ms_ago\":{},\
+                 \"incarnation\":{}}}",
+                member.addr,
+                quote(&member.install_addr),
+                state,
+                heard,
+                member.incarnation
+            )
+        })
+        .collect();
+
+    format!("{{\"members\":[{}]}}\n", members.join(","))
+}
+
+// This module contains unit tests for membership.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // This unit test verifies that heartbeats survive a round trip through a buffer, and that
+    // truncated or padded ones are not parsed.
+    #[test]
+    fn test_encode() {
+        let hb = Heartbeat {
+            incarnation: 1 << 40,
+            install_addr: "192.168.0.2:7700",
+            backup_addr: "",
+        };
+
+        let mut buf = Vec::new();
+        encode(&mut buf, &hb);
+        assert_eq!(12 + 2 + 16 + 2, buf.len());
+        assert_eq!(Some(hb), decode(&buf));
+
+        assert!(decode(&buf[..buf.len() - 1]).is_none());
+        buf.push(0);
+        assert!(decode(&buf).is_none());
+        assert!(decode(b"SPHX00000000\0\0\0\0").is_none());
+    }
+
+    // This unit test verifies that members are declared dead once they go unheard from for the
+    // timeout, and alive again once heard from, and that strangers are ignored.
+    #[test]
+    fn test_expire() {
+        let a = SocketAddr::from_str("192.168.0.3:7800").unwrap();
+        let b = SocketAddr::from_str("192.168.0.4:7800").unwrap();
+        let mut members = Members::new(&[a, b], Duration::from_millis(100));
+        let hb = Heartbeat {
+            incarnation: 1,
+            install_addr: "192.168.0.3:7700",
+            backup_addr: "",
+        };
+
+        let start = Instant::now();
+        assert_eq!(Some((0, State::Unknown)), members.heard(a, &hb, start));
+        let stranger = SocketAddr::from_str("192.168.0.5:7800").unwrap();
+        assert_eq!(None, members.heard(stranger, &hb, start));
+
+        // Members never heard from are not declared dead.
+        assert!(members.expire(start + Duration::from_millis(50)).is_empty());
+        assert_eq!(vec![0], members.expire(start + Duration::from_millis(100)));
+        assert!(members.expire(start + Duration::from_millis(200)).is_empty());
+        assert_eq!(State::Dead, members.members[0].state);
+        assert_eq!(State::Unknown, members.members[1].state);
+
+        let later = start + Duration::from_millis(300);
+        assert_eq!(Some((0, State::Dead)), members.heard(a, &hb, later));
+        assert_eq!(State::Alive, members.members[0].state);
+        assert_eq!("192.168.0.3:7700", members.members[0].install_addr);
+    }
+}
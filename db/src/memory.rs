@@ -0,0 +1,315 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+
+// Memory introspection, for the memory() RPC. Reports on where the server's memory went: the
+// process heap as seen by malloc, DPDK's packet pools, the chunks each tenant's arena has
+// reserved for it's objects, and the objects and index of every table. Arenas are charged for
+// whole chunks, so the fraction of a tenant's reserved memory not taken up by live objects is an
+// estimate of how fragmented it's arena is.
+
+use std::fs::File;
+use std::io::Read;
+use std::mem::size_of;
+
+use super::alloc::ArenaUsage;
+use super::common::{TableId, TenantId};
+
+use bytes::Bytes;
+use e2d2::interface::dpdk::{mempool_stats, MempoolStats};
+use libc;
+
+/// The largest packet pool index probed for. Pools are created per NUMA socket, or per core if
+/// DPDK was built that way.
+const MAX_POOLS: i32 = 128;
+
+/// The bytes a table's index takes up per object it can hold: the hash, the key and the value.
+pub const INDEX_ENTRY_LEN: usize = size_of::<u64>() + 2 * size_of::<Bytes>();
+
+// The statistics glibc returns from mallinfo(). The counts are ints, and wrap past 4 GB.
+#[repr(C)]
+#[derive(Default)]
+struct Mallinfo {
+    arena: libc::c_int,
+    ordblks: libc::c_int,
+    smblks: libc::c_int,
+    hblks: libc::c_int,
+    hblkhd: libc::c_int,
+    usmblks: libc::c_int,
+    fsmblks: libc::c_int,
+    uordblks: libc::c_int,
+    fordblks: libc::c_int,
+    keepcost: libc::c_int,
+}
+
+extern "C" {
+    fn mallinfo() -> Mallinfo;
+}
+
+/// The process heap, in bytes.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Heap {
+    /// The memory malloc reserved from the system outside of mmap()ed blocks, and how much of
+    /// it is in use and free.
+    pub arena: u64,
+    pub in_use: u64,
+    pub free: u64,
+
+    /// The memory in blocks large enough that malloc mmap()ed them on their own.
+    pub mmapped: u64,
+
+    /// The resident set size of the process, including memory DPDK reserved from hugepages
+    /// that has been touched.
+    pub rss: u64,
+}
+
+/// The memory held on behalf of a tenant, in bytes.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TenantMemory {
+    pub tenant: TenantId,
+
+    /// The chunks reserved by the tenant's arena, and the memory reserved in all, along with
+    /// how much of it is taken up by live objects.
+    pub chunks: usize,
+    pub reserved: usize,
+    pub live: usize,
+
+    /// The memory charged to the tenant's quota, and it's limit, zero if there is none.
+    pub quota_used: usize,
+    pub quota_limit: usize,
+}
+
+/// The memory held by a table, in bytes.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TableMemory {
+    pub tenant: TenantId,
+    pub table: TableId,
+
+    /// The number of objects in the table, and the bytes they take up.
+    pub objects: usize,
+    pub bytes: usize,
+
+    /// An estimate of the bytes taken up by the table's index.
+    pub index: usize,
+}
+
+/// Everything the memory() RPC reports.
+pub struct Report {
+    pub heap: Heap,
+    pub pools: Vec<(i32, MempoolStats)>,
+    pub tenants: Vec<TenantMemory>,
+    pub tables: Vec<TableMemory>,
+}
+
+// Implementation of methods on Report.
+impl Report {
+    /// Renders a report as one line of JSON per heap, packet pool, tenant and table, in that
+    /// order.
+    pub fn render(&self) -> String {
+        let h = &self.heap;
+        let mut out = format!(
+            "{{\"kind\":\"heap\",\"arena_bytes\":{},\"in_use_bytes\":{},\"free_bytes\":{},\
+             \"mmap_bytes\":{},\"rss_bytes\":{},\"fragmentation\":{:.3}}}\n",
+            h.arena,
+            h.in_use,
+            h.free,
+            h.mmapped,
+            h.rss,
+            ratio(h.free, h.arena)
+        );
+
+        for &(pool, ref p) in self.pools.iter() {
+            out.push_str(&format!(
+                "{{\"kind\":\"mempool\",\"pool\":{},\"mbufs\":{},\"available\":{},\
+                 \"in_use\":{},\"mbuf_bytes\":{},\"bytes\":{}}}\n",
+                pool,
+                p.size,
+                p.available,
+                p.in_use,
+                p.mbuf_bytes,
+                p.size * p.mbuf_bytes
+            ));
+        }
+
+        for t in self.tenants.iter() {
+            out.push_str(&format!(
+                "{{\"kind\":\"tenant\",\"tenant\":{},\"chunks\":{},\"reserved_bytes\":{},\
+                 \"live_bytes\":{},\"quota_used_bytes\":{},\"quota_limit_bytes\":{},\
+                 \"fragmentation\":{:.3}}}\n",
+                t.tenant,
+                t.chunks,
+                t.reserved,
+                t.live,
+                t.quota_used,
+                t.quota_limit,
+                ratio((t.reserved - t.live.min(t.reserved)) as u64, t.reserved as u64)
+            ));
+        }
+
+        for t in self.tables.iter() {
+            out.push_str(&format!(
+                "{{\"kind\":\"table\",\"tenant\":{},\"table\":{},\"objects\":{},\"bytes\":{},\
+                 \"index_bytes\":{}}}\n",
+                t.tenant, t.table, t.objects, t.bytes, t.index
+            ));
+        }
+
+        out
+    }
+}
+
+/// Merges the memory reserved by each tenant's arena with it's quota. Tenants that have
+/// nothing allocated have no arena, and arenas outlive the tenants they were reserved for
+/// until their objects are dropped, so either can be missing.
+///
+/// # Arguments
+///
+/// * `arenas`: The outcome of a census, ordered by tenant.
+/// * `quotas`: The identifier of each tenant, along with the bytes charged to it's quota and
+///             it's limit.
+///
+/// # Return
+///
+/// The memory held on behalf of every tenant, ordered by tenant.
+pub fn tenants(arenas: &[ArenaUsage], quotas: &[(TenantId, usize, usize)]) -> Vec<TenantMemory> {
+    let mut tenants: Vec<TenantMemory> = arenas
+        .iter()
+        .map(|a| TenantMemory {
+            tenant: a.tenant,
+            chunks: a.chunks,
+            reserved: a.reserved,
+            live: a.live,
+            quota_used: 0,
+            quota_limit: 0,
+        })
+        .collect();
+
+    for &(tenant, used, limit) in quotas.iter() {
+        let index = match tenants.binary_search_by_key(&tenant, |t| t.tenant) {
+            Ok(index) => index,
+            Err(index) => {
+                let mut entry = TenantMemory::default();
+                entry.tenant = tenant;
+                tenants.insert(index, entry);
+                index
+            }
+        };
+        tenants[index].quota_used = used;
+        tenants[index].quota_limit = limit;
+    }
+
+    tenants
+}
+
+/// Reads the statistics of the process heap.
+pub fn heap() -> Heap {
+    let info = unsafe { mallinfo() };
+    let mut heap = Heap {
+        arena: info.arena as u32 as u64,
+        in_use: info.uordblks as u32 as u64,
+        free: info.fordblks as u32 as u64,
+        mmapped: info.hblkhd as u32 as u64,
+        rss: 0,
+    };
+
+    // The second field of statm is the resident set size, in pages.
+    let mut statm = String::new();
+    if let Ok(mut file) = File::open("/proc/self/statm") {
+        let _ = file.read_to_string(&mut statm);
+    }
+    let pages = statm.split_whitespace().nth(1).and_then(|rss| rss.parse::<u64>().ok());
+    let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if let Some(pages) = pages {
+        heap.rss = pages * page.max(0) as u64;
+    }
+
+    heap
+}
+
+/// Reads the counts of every packet pool DPDK created.
+pub fn pools() -> Vec<(i32, MempoolStats)> {
+    (0..MAX_POOLS)
+        .filter_map(|pool| mempool_stats(pool).map(|stats| (pool, stats)))
+        .collect()
+}
+
+// Returns a fraction, or zero if the denominator is.
+fn ratio(num: u64, den: u64) -> f64 {
+    if den == 0 {
+        0.0
+    } else {
+        num as f64 / den as f64
+    }
+}
+
+// This module contains unit tests for memory reports.
+#[cfg(test)]
+mod tests {
+    use super::super::alloc::ArenaUsage;
+    use super::*;
+
+    // This unit test verifies that arenas and quotas are merged by tenant, keeping tenants
+    // that only have one of the two.
+    #[test]
+    fn test_tenants() {
+        let arenas = vec![
+            ArenaUsage { tenant: 1, chunks: 2, reserved: 200, live: 150 },
+            ArenaUsage { tenant: 3, chunks: 1, reserved: 100, live: 10 },
+        ];
+        let quotas = vec![(1, 150, 1000), (2, 0, 500)];
+
+        let tenants = tenants(&arenas, &quotas);
+        let ids: Vec<TenantId> = tenants.iter().map(|t| t.tenant).collect();
+        assert_eq!(vec![1, 2, 3], ids);
+        assert_eq!((2, 200, 150, 150, 1000), {
+            let t = &tenants[0];
+            (t.chunks, t.reserved, t.live, t.quota_used, t.quota_limit)
+        });
+        assert_eq!(500, tenants[1].quota_limit);
+        assert_eq!(0, tenants[2].quota_limit);
+    }
+
+    // This unit test verifies the lines a report is rendered into.
+    #[test]
+    fn test_render() {
+        let report = Report {
+            heap: Heap { arena: 1000, in_use: 750, free: 250, mmapped: 64, rss: 4096 },
+            pools: vec![(
+                0,
+                MempoolStats { size: 8, available: 6, in_use: 2, mbuf_bytes: 2048 },
+            )],
+            tenants: vec![TenantMemory {
+                tenant: 1,
+                chunks: 1,
+                reserved: 400,
+                live: 100,
+                quota_used: 100,
+                quota_limit: 0,
+            }],
+            tables: vec![TableMemory { tenant: 1, table: 7, objects: 2, bytes: 100, index: 96 }],
+        };
+
+        let expected = "\
+{\"kind\":\"heap\",\"arena_bytes\":1000,\"in_use_bytes\":750,\"free_bytes\":250,\
+\"mmap_bytes\":64,\"rss_bytes\":4096,\"fragmentation\":0.250}
+{\"kind\":\"mempool\",\"pool\":0,\"mbufs\":8,\"available\":6,\"in_use\":2,\
+\"mbuf_bytes\":2048,\"bytes\":16384}
+{\"kind\":\"tenant\",\"tenant\":1,\"chunks\":1,\"reserved_bytes\":400,\"live_bytes\":100,\
+\"quota_used_bytes\":100,\"quota_limit_bytes\":0,\"fragmentation\":0.750}
+{\"kind\":\"table\",\"tenant\":1,\"table\":7,\"objects\":2,\"bytes\":100,\"index_bytes\":96}
+";
+        assert_eq!(expected, report.render());
+    }
+}
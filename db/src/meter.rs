@@ -0,0 +1,405 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+// Resource metering for billing. Dispatchers and schedulers charge every tenant for the requests
+// they receive, the bytes on those requests and their responses, the extensions they invoke, and
+// the cycles their tasks run for. Charges accumulate on the thread making them, and are folded
+// into a shared table every few milliseconds so that the hot path never takes a lock. A single
+// thread periodically drains the shared table, adds the bytes each tenant stores, and emits one
+// record per tenant as a line of JSON, appended to a file or sent as a UDP datagram. The same
+// charges can also be totalled up since startup, for the metrics exporter.
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::UdpSocket;
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use std::sync::{Once, ONCE_INIT};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::common::TenantId;
+use super::cycles;
+
+use spin::Mutex;
+
+/// The number of microseconds charges can sit on a thread before being folded into the shared
+/// table.
+const FLUSH_US: u64 = 10000;
+
+/// The resources consumed by a tenant since it's last record.
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct Usage {
+    /// The number of requests received.
+    pub requests: u64,
+
+    /// The number of extensions invoked.
+    pub invocations: u64,
+
+    /// The number of cycles the tenant's tasks ran for.
+    pub cycles: u64,
+
+    /// The number of bytes on the tenant's requests, from the UDP header on.
+    pub rx_bytes: u64,
+
+    /// The number of bytes on responses to the tenant, from the UDP header on.
+    pub tx_bytes: u64,
+}
+
+// Implementation of methods on Usage.
+impl Usage {
+    // Adds the resources on another usage to this one.
+    fn add(&mut self, other: &Usage) {
+        self.requests += other.requests;
+        self.invocations += other.invocations;
+        self.cycles += other.cycles;
+        self.rx_bytes += other.rx_bytes;
+        self.tx_bytes += other.tx_bytes;
+    }
+}
+
+// Where records end up.
+struct Sink {
+    // The number of cycles between two records for the same tenant. Zero if metering is off.
+    interval: u64,
+
+    // The time-stamp in cycles at which records were last emitted.
+    last: u64,
+
+    // The file records are appended to, if any.
+    file: Option<File>,
+
+    // The socket records are sent out on, and the address they are sent to, if any.
+    socket: Option<(UdpSocket, String)>,
+
+    // Usage folded in from every thread since records were last emitted.
+    usage: HashMap<TenantId, Usage>,
+
+    // Usage folded in from every thread since totals started being kept, if they are.
+    totals: HashMap<TenantId, Usage>,
+}
+
+/// Set when tenants are being metered, or when totals are being kept.
+static ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Set when records are being emitted.
+static METERING: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Set when the usage of every tenant since startup is being kept, see `totals()`.
+static TOTALS: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Initializes SINK exactly once.
+static SINK_INIT: Once = ONCE_INIT;
+
+/// The sink shared by all threads. Use `sink()` to access it.
+static mut SINK: *const Mutex<Sink> = 0 as *const Mutex<Sink>;
+
+thread_local! {
+    // Usage charged on this thread since it was last folded into the shared table.
+    static LOCAL: RefCell<HashMap<TenantId, Usage>> = RefCell::new(HashMap::new());
+
+    // The time-stamp in cycles at which this thread last folded it's usage into the shared table.
+    static FLUSHED: Cell<u64> = Cell::new(0);
+}
+
+// Returns the sink shared by all threads, allocating it on first use.
+fn sink() -> &'static Mutex<Sink> {
+    unsafe {
+        SINK_INIT.call_once(|| {
+            let sink = Sink {
+                interval: 0,
+                last: 0,
+                file: None,
+                socket: None,
+                usage: HashMap::new(),
+                totals: HashMap::new(),
+            };
+            SINK = Box::into_raw(Box::new(Mutex::new(sink)));
+        });
+
+        &*SINK
+    }
+}
+
+/// Configures metering. Usage charged before metering was turned on is discarded.
+///
+/// # Arguments
+///
+/// * `interval_s`: The number of seconds between two records for the same tenant. Zero turns
+///                 metering off.
+/// * `path`:       The file records are appended to. Empty if records are not written to a file.
+/// * `addr`:       The address (IPv4:Port) records are sent to as UDP datagrams. Empty if records
+///                 are not sent over the network.
+pub fn configure(interval_s: u64, path: &str, addr: &str) {
+    let mut sink = sink().lock();
+    sink.interval = interval_s * cycles::cycles_per_second();
+    sink.last = cycles::rdtsc();
+    sink.usage.clear();
+    sink.file = None;
+    sink.socket = None;
+
+    if interval_s > 0 && path.len() > 0 {
+        match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => sink.file = Some(file),
+            Err(err) => warn!("Failed to open metering file {}: {}", path, err),
+        }
+    }
+
+    if interval_s > 0 && addr.len() > 0 {
+        match UdpSocket::bind("0.0.0.0:0") {
+            Ok(socket) => sink.socket = Some((socket, String::from(addr))),
+            Err(err) => warn!("Failed to open a socket for metering records: {}", err),
+        }
+    }
+
+    METERING.store(interval_s > 0, Ordering::Relaxed);
+    ENABLED.store(interval_s > 0 || TOTALS.load(Ordering::Relaxed), Ordering::Relaxed);
+}
+
+/// Starts keeping the usage of every tenant since this call, on top of any metering. Used to
+/// export cumulative counters.
+pub fn keep_totals() {
+    TOTALS.store(true, Ordering::Relaxed);
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Returns the usage of every tenant since `keep_totals()` was called. Charges made within the
+/// last few milliseconds may not be included yet.
+pub fn totals() -> Vec<(TenantId, Usage)> {
+    let sink = sink().lock();
+    let mut totals: Vec<(TenantId, Usage)> = sink.totals.iter().map(|(t, u)| (*t, *u)).collect();
+    totals.sort_by_key(|total| total.0);
+    totals
+}
+
+/// Returns true if tenants are being charged, either for metering or for totals.
+#[inline]
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Charges a tenant for a request it sent.
+///
+/// # Arguments
+///
+/// * `tenant`: The tenant that sent the request.
+/// * `bytes`:  The number of bytes on the request, from the UDP header on.
+/// * `invoke`: True if the request invokes an extension.
+#[inline]
+pub fn request(tenant: TenantId, bytes: usize, invoke: bool) {
+    if !enabled() {
+        return;
+    }
+
+    charge(tenant, |usage| {
+        usage.requests += 1;
+        usage.rx_bytes += bytes as u64;
+        if invoke {
+            usage.invocations += 1;
+        }
+    });
+}
+
+/// Charges a tenant for the cycles one of it's tasks ran for, and the response it produced.
+///
+/// # Arguments
+///
+/// * `tenant`: The tenant the task ran on behalf of.
+/// * `cycles`: The number of cycles the task ran for.
+/// * `bytes`:  The number of bytes on the response, from the UDP header on. Zero if the task has
+///             not completed yet.
+#[inline]
+pub fn task(tenant: TenantId, cycles: u64, bytes: usize) {
+    if !enabled() {
+        return;
+    }
+
+    charge(tenant, |usage| {
+        usage.cycles += cycles;
+        usage.tx_bytes += bytes as u64;
+    });
+}
+
+// Charges a tenant on this thread, folding this thread's charges into the shared table if they
+// have not been in a while.
+fn charge<F: FnOnce(&mut Usage)>(tenant: TenantId, f: F) {
+    LOCAL.with(|local| {
+        let mut local = local.borrow_mut();
+        f(local.entry(tenant).or_insert(Usage::default()));
+
+        let now = cycles::rdtsc();
+        let every = cycles::cycles_per_second() / 1000000 * FLUSH_US;
+        let flush = FLUSHED.with(|flushed| {
+            let due = now.saturating_sub(flushed.get()) >= every;
+            if due {
+                flushed.set(now);
+            }
+            due
+        });
+
+        if flush {
+            let mut sink = sink().lock();
+            let (metering, totals) = (sink.interval > 0, TOTALS.load(Ordering::Relaxed));
+            for (tenant, usage) in local.drain() {
+                if metering {
+                    sink.usage.entry(tenant).or_insert(Usage::default()).add(&usage);
+                }
+                if totals {
+                    sink.totals.entry(tenant).or_insert(Usage::default()).add(&usage);
+                }
+            }
+        }
+    });
+}
+
+/// Emits a record for every tenant if enough time has passed since records were last emitted.
+/// Should be called periodically on a thread that is not processing requests, because it
+/// performs I/O.
+///
+/// # Arguments
+///
+/// * `storage`: Called to look up the number of objects and bytes every tenant stores, only if
+///              records are due.
+pub fn poll<F: FnOnce() -> Vec<(TenantId, u64, u64)>>(storage: F) {
+    emit(storage, false);
+}
+
+/// Emits a record for every tenant covering the usage since records were last emitted, even if
+/// the interval is not over yet. Required to shut down without losing that usage.
+///
+/// # Arguments
+///
+/// * `storage`: Called to look up the number of objects and bytes every tenant stores.
+pub fn flush<F: FnOnce() -> Vec<(TenantId, u64, u64)>>(storage: F) {
+    emit(storage, true);
+}
+
+// Emits records, if they are due or `force` is set.
+fn emit<F: FnOnce() -> Vec<(TenantId, u64, u64)>>(storage: F, force: bool) {
+    if !METERING.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let now = cycles::rdtsc();
+    let (usage, secs) = {
+        let mut sink = sink().lock();
+        let due = now.saturating_sub(sink.last) >= sink.interval;
+        if sink.interval == 0 || !(due || force) {
+            return;
+        }
+
+        let secs = (now - sink.last) as f64 / cycles::cycles_per_second() as f64;
+        sink.last = now;
+        (::std::mem::replace(&mut sink.usage, HashMap::new()), secs)
+    };
+
+    let ts = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    // Every tenant storing data gets a record, even if it was idle over the interval.
+    let mut records: Vec<(TenantId, Usage, u64, u64)> = storage()
+        .into_iter()
+        .map(|(tenant, objects, bytes)| {
+            let used = usage.get(&tenant).cloned().unwrap_or_default();
+            (tenant, used, objects, bytes)
+        })
+        .collect();
+    for (tenant, used) in usage.iter() {
+        if !records.iter().any(|record| record.0 == *tenant) {
+            records.push((*tenant, *used, 0, 0));
+        }
+    }
+    records.sort_by_key(|record| record.0);
+
+    let mut sink = sink().lock();
+    for &(tenant, ref used, objects, bytes) in records.iter() {
+        let line = record(ts, secs, tenant, used, objects, bytes);
+
+        if let Some(ref mut file) = sink.file {
+            if let Err(err) = file.write_all(line.as_bytes()).and_then(|_| file.write_all(b"\n")) {
+                warn!("Failed to write metering record: {}", err);
+            }
+        }
+
+        if let Some((ref socket, ref addr)) = sink.socket {
+            if let Err(err) = socket.send_to(line.as_bytes(), addr.as_str()) {
+                warn!("Failed to send metering record to {}: {}", addr, err);
+            }
+        }
+    }
+}
+
+/// Formats a tenant's usage over an interval as a line of JSON.
+///
+/// # Arguments
+///
+/// * `ts`:      The time at the end of the interval in seconds since the UNIX epoch.
+/// * `secs`:    The length of the interval in seconds.
+/// * `tenant`:  The tenant.
+/// * `used`:    The resources the tenant consumed over the interval.
+/// * `objects`: The number of objects the tenant stores at the end of the interval.
+/// * `bytes`:   The number of bytes the tenant's objects take up at the end of the interval.
+///
+/// # Return
+///
+/// The record, without a trailing newline.
+fn record(ts: u64, secs: f64, tenant: TenantId, used: &Usage, objects: u64, bytes: u64) -> String {
+    format!(
+        "{{\"ts\":{},\"interval_s\":{:.3},\"tenant\":{},\"requests\":{},\"invocations\":{},\
+         \"cycles\":{},\"rx_bytes\":{},\"tx_bytes\":{},\"objects\":{},\"bytes_stored\":{}}}",
+        ts,
+        secs,
+        tenant,
+        used.requests,
+        used.invocations,
+        used.cycles,
+        used.rx_bytes,
+        used.tx_bytes,
+        objects,
+        bytes
+    )
+}
+
+// This module contains unit tests for metering.
+#[cfg(test)]
+mod tests {
+    use super::{record, Usage};
+
+    // This unit test verifies that records are formatted as a single line of JSON.
+    #[test]
+    fn test_record() {
+        let mut used = Usage {
+            requests: 3,
+            cycles: 1000,
+            rx_bytes: 90,
+            tx_bytes: 40,
+            ..Default::default()
+        };
+        used.add(&Usage {
+            invocations: 1,
+            requests: 1,
+            ..Default::default()
+        });
+
+        assert_eq!(
+            "{\"ts\":1500000000,\"interval_s\":10.000,\"tenant\":7,\"requests\":4,\
+             \"invocations\":1,\"cycles\":1000,\"rx_bytes\":90,\"tx_bytes\":40,\"objects\":2,\
+             \"bytes_stored\":128}",
+            record(1500000000, 10.0, 7, &used, 2, 128)
+        );
+    }
+}
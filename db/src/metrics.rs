@@ -0,0 +1,419 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+
+// Server metrics in the Prometheus text exposition format. Schedulers record how long every task
+// they complete ran for into a histogram local to their thread, which is folded into a shared one
+// every few milliseconds. A single thread serves the metrics over plain HTTP on a TCP socket;
+// every scrape renders the shared histogram along with the per tenant totals kept by the meter,
+// the objects and bytes each tenant stores, the counters on every extension, and the depth of
+// every scheduler's queues. The /healthz and /readyz paths instead answer with a health check,
+// with a status of 503 if the server is not live or ready respectively.
+
+use std::cell::{Cell, RefCell};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use std::sync::{Once, ONCE_INIT};
+
+use super::common::TenantId;
+use super::cycles;
+use super::ext::ExtensionStats;
+use super::health::Report;
+use super::histogram::Histogram;
+use super::meter::Usage;
+
+use spin::Mutex;
+
+/// The number of bits of precision kept on task service times.
+const PRECISION: u32 = 4;
+
+/// The number of microseconds service times can sit on a thread before being folded into the
+/// shared histogram.
+const FLUSH_US: u64 = 10000;
+
+/// The upper bounds in microseconds of the buckets service times are exported in.
+const BOUNDS_US: [u64; 12] = [1, 2, 5, 10, 20, 50, 100, 200, 500, 1000, 5000, 10000];
+
+/// Set when metrics are being collected.
+static ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Initializes SHARED exactly once.
+static SHARED_INIT: Once = ONCE_INIT;
+
+/// The histogram shared by all threads. Use `shared()` to access it.
+static mut SHARED: *const Mutex<Histogram> = 0 as *const Mutex<Histogram>;
+
+thread_local! {
+    // Service times recorded on this thread since they were last folded into the shared one.
+    static LOCAL: RefCell<Histogram> = RefCell::new(Histogram::new(PRECISION));
+
+    // The time-stamp in cycles at which this thread last folded it's histogram into the shared
+    // one.
+    static FLUSHED: Cell<u64> = Cell::new(0);
+}
+
+// Returns the histogram shared by all threads, allocating it on first use.
+fn shared() -> &'static Mutex<Histogram> {
+    unsafe {
+        SHARED_INIT.call_once(|| {
+            SHARED = Box::into_raw(Box::new(Mutex::new(Histogram::new(PRECISION))));
+        });
+
+        &*SHARED
+    }
+}
+
+/// The state of a scheduler's queues at the time of a scrape.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Queues {
+    /// The core the scheduler runs on.
+    pub core: i32,
+
+    /// The number of tasks waiting to run.
+    pub waiting: usize,
+
+    /// The number of responses waiting to be sent out.
+    pub responses: usize,
+}
+
+/// Turns on the collection of task service times.
+pub fn enable() {
+    shared();
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Returns true if task service times are being collected.
+#[inline]
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Records the service time of a task that completed on this thread.
+///
+/// # Arguments
+///
+/// * `cycles`: The number of cycles the task ran for over all of it's invocations.
+pub fn task(cycles: u64) {
+    if !enabled() {
+        return;
+    }
+
+    LOCAL.with(|local| local.borrow_mut().record(cycles));
+
+    let now = cycles::rdtsc();
+    let every = cycles::cycles_per_second() / 1000000 * FLUSH_US;
+    let flush = FLUSHED.with(|flushed| {
+        let due = now.saturating_sub(flushed.get()) >= every;
+        if due {
+            flushed.set(now);
+        }
+        due
+    });
+    if !flush {
+        return;
+    }
+
+    LOCAL.with(|local| {
+        let mut local = local.borrow_mut();
+        shared().lock().merge(&local);
+        *local = Histogram::new(PRECISION);
+    });
+}
+
+/// Returns a copy of the service times folded in from every thread so far.
+pub fn service_times() -> Histogram {
+    shared().lock().clone()
+}
+
+// Escapes a label value, as the text format requires of backslashes, quotes and new lines.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// Appends the help and type lines for a metric.
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind));
+}
+
+/// Renders metrics in the Prometheus text exposition format.
+///
+/// # Arguments
+///
+/// * `totals`:  The usage of every tenant since startup.
+/// * `storage`: The number of objects and bytes every tenant stores.
+/// * `exts`:    The tenant, name and counters of every extension.
+/// * `queues`:  The state of every scheduler's queues.
+/// * `hist`:    The service times of completed tasks, in cycles.
+/// * `cps`:     The number of cycles in a second.
+///
+/// # Return
+///
+/// The body of a response to a scrape.
+pub fn render(
+    totals: &[(TenantId, Usage)],
+    storage: &[(TenantId, u64, u64)],
+    exts: &[(TenantId, String, ExtensionStats)],
+    queues: &[Queues],
+    hist: &Histogram,
+    cps: u64,
+) -> String {
+    let mut out = String::new();
+
+    let counters: [(&str, &str, fn(&Usage) -> u64); 5] = [
+        ("splinter_requests_total", "Requests received.", |u| u.requests),
+        ("splinter_invocations_total", "Extensions invoked.", |u| u.invocations),
+        ("splinter_cycles_total", "Cycles the tenant's tasks ran for.", |u| u.cycles),
+        ("splinter_rx_bytes_total", "Bytes on requests, from the UDP header on.", |u| u.rx_bytes),
+        ("splinter_tx_bytes_total", "Bytes on responses, from the UDP header on.", |u| u.tx_bytes),
+    ];
+    for &(name, help, value) in counters.iter() {
+        header(&mut out, name, "counter", help);
+        for &(tenant, ref usage) in totals.iter() {
+            out.push_str(&format!("{}{{tenant=\"{}\"}} {}\n", name, tenant, value(usage)));
+        }
+    }
+
+    header(&mut out, "splinter_objects", "gauge", "Objects stored by the tenant.");
+    for &(tenant, objects, _) in storage.iter() {
+        out.push_str(&format!("splinter_objects{{tenant=\"{}\"}} {}\n", tenant, objects));
+    }
+
+    header(&mut out, "splinter_stored_bytes", "gauge", "Bytes stored by the tenant.");
+    for &(tenant, _, bytes) in storage.iter() {
+        out.push_str(&format!("splinter_stored_bytes{{tenant=\"{}\"}} {}\n", tenant, bytes));
+    }
+
+    let counters: [(&str, &str, fn(&ExtensionStats) -> u64); 6] = [
+        ("splinter_extension_invocations_total", "Invocations run.", |s| s.invocations),
+        ("splinter_extension_compute_cycles_total", "Cycles outside DB calls.", |s| {
+            s.compute_cycles
+        }),
+        ("splinter_extension_db_cycles_total", "Cycles in DB calls.", |s| s.db_cycles),
+        ("splinter_extension_yields_total", "Yields to the scheduler.", |s| s.yields),
+        ("splinter_extension_aborts_total", "Invocations that panicked.", |s| s.aborts),
+        ("splinter_extension_pushbacks_total", "Invocations pushed back.", |s| s.pushbacks),
+    ];
+    for &(name, help, value) in counters.iter() {
+        header(&mut out, name, "counter", help);
+        for &(tenant, ref ext, ref stats) in exts.iter() {
+            out.push_str(&format!(
+                "{}{{tenant=\"{}\",extension=\"{}\"}} {}\n",
+                name,
+                tenant,
+                escape(ext),
+                value(stats)
+            ));
+        }
+    }
+
+    header(&mut out, "splinter_tasks_waiting", "gauge", "Tasks waiting to run.");
+    for q in queues.iter() {
+        out.push_str(&format!("splinter_tasks_waiting{{core=\"{}\"}} {}\n", q.core, q.waiting));
+    }
+
+    header(&mut out, "splinter_responses_pending", "gauge", "Responses waiting to be sent.");
+    for q in queues.iter() {
+        out.push_str(&format!(
+            "splinter_responses_pending{{core=\"{}\"}} {}\n",
+            q.core, q.responses
+        ));
+    }
+
+    // Buckets are cumulative. A bucket of the histogram is counted under a bound only if every
+    // value it could hold is at or below that bound.
+    let name = "splinter_task_seconds";
+    header(&mut out, name, "histogram", "Service time of completed tasks.");
+    let buckets = hist.buckets();
+    for bound in BOUNDS_US.iter() {
+        let limit = (*bound as f64 / 1e6) * cps as f64;
+        let count: u64 = buckets
+            .iter()
+            .filter(|&&(_, hi, _)| hi as f64 <= limit)
+            .map(|&(_, _, count)| count)
+            .sum();
+        out.push_str(&format!(
+            "{}_bucket{{le=\"{}\"}} {}\n",
+            name,
+            *bound as f64 / 1e6,
+            count
+        ));
+    }
+    out.push_str(&format!("{}_bucket{{le=\"+Inf\"}} {}\n", name, hist.count()));
+
+    let sum = if cps > 0 {
+        hist.mean() * hist.count() as f64 / cps as f64
+    } else {
+        0.0
+    };
+    out.push_str(&format!("{}_sum {}\n", name, sum));
+    out.push_str(&format!("{}_count {}\n", name, hist.count()));
+
+    out
+}
+
+// Returns the path on the request line of an HTTP request, or an empty string if there isn't
+// one.
+fn path(req: &[u8]) -> &str {
+    let line = req.split(|&b| b == b'\r' || b == b'\n').next().unwrap_or(&[]);
+    line.split(|&b| b == b' ')
+        .nth(1)
+        .and_then(|path| ::std::str::from_utf8(path).ok())
+        .unwrap_or("")
+}
+
+// Reads the request off a connection and answers it. The request itself is not looked at beyond
+// the path on the first read; the health check paths get the health report, and every other
+// path gets whatever `body` renders.
+fn respond<F: Fn() -> String, H: Fn() -> Report>(stream: &mut TcpStream, body: &F, health: &H) {
+    let mut buf = [0u8; 1024];
+    let num = match stream.read(&mut buf) {
+        Ok(num) => num,
+        Err(_) => return,
+    };
+
+    let (ok, kind, body) = match path(&buf[..num]) {
+        "/healthz" => {
+            let report = health();
+            (report.live, "application/json", report.render())
+        }
+        "/readyz" => {
+            let report = health();
+            (report.ready, "application/json", report.render())
+        }
+        _ => (true, "text/plain; version=0.0.4", body()),
+    };
+
+    let status = if ok {
+        "200 OK"
+    } else {
+        "503 Service Unavailable"
+    };
+    let res = format!(
+        "HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
+        status,
+        kind,
+        body.len(),
+        body
+    );
+    if let Err(e) = stream.write_all(res.as_bytes()) {
+        warn!("Failed to answer metrics scrape: {}", e);
+    }
+}
+
+/// Serves metrics over HTTP until the listener fails. Blocks the calling thread.
+///
+/// # Arguments
+///
+/// * `addr`:   The address to listen for scrapes on.
+/// * `body`:   Renders the body of the response to a scrape.
+/// * `health`: Checks the health of the server, for the /healthz and /readyz paths.
+pub fn serve<F: Fn() -> String, H: Fn() -> Report>(addr: &str, body: F, health: H) {
+    let listener = match TcpListener::bind(addr) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to listen for metrics scrapes on {}: {}", addr, e);
+            return;
+        }
+    };
+
+    info!("Serving metrics on {}", addr);
+    for stream in listener.incoming() {
+        match stream {
+            Ok(mut stream) => respond(&mut stream, &body, &health),
+            Err(e) => warn!("Failed to accept metrics scrape: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Tests that every tenant, scheduler and bucket shows up in the rendered metrics.
+    #[test]
+    fn test_render() {
+        let usage = Usage {
+            requests: 3,
+            invocations: 1,
+            cycles: 900,
+            rx_bytes: 120,
+            tx_bytes: 80,
+        };
+        let queues = Queues {
+            core: 2,
+            waiting: 5,
+            responses: 1,
+        };
+
+        // At 1e6 cycles a second, a cycle is a microsecond.
+        let mut hist = Histogram::new(PRECISION);
+        hist.record(1);
+        hist.record(40);
+        hist.record(20000);
+
+        let stats = ExtensionStats {
+            invocations: 4,
+            compute_cycles: 300,
+            db_cycles: 100,
+            yields: 6,
+            aborts: 1,
+            pushbacks: 2,
+        };
+        let exts = [(7, String::from("a\"b"), stats)];
+
+        let out = render(&[(7, usage)], &[(7, 10, 400)], &exts, &[queues], &hist, 1000000);
+        assert!(out.contains("# TYPE splinter_requests_total counter\n"));
+        assert!(out.contains("splinter_requests_total{tenant=\"7\"} 3\n"));
+        assert!(out.contains("splinter_tx_bytes_total{tenant=\"7\"} 80\n"));
+        assert!(out.contains("splinter_objects{tenant=\"7\"} 10\n"));
+        assert!(out.contains("splinter_stored_bytes{tenant=\"7\"} 400\n"));
+        assert!(out.contains(
+            "splinter_extension_db_cycles_total{tenant=\"7\",extension=\"a\\\"b\"} 100\n"
+        ));
+        assert!(out.contains(
+            "splinter_extension_pushbacks_total{tenant=\"7\",extension=\"a\\\"b\"} 2\n"
+        ));
+        assert!(out.contains("splinter_tasks_waiting{core=\"2\"} 5\n"));
+        assert!(out.contains("splinter_responses_pending{core=\"2\"} 1\n"));
+        assert!(out.contains("splinter_task_seconds_bucket{le=\"0.000001\"} 1\n"));
+        assert!(out.contains("splinter_task_seconds_bucket{le=\"0.00005\"} 2\n"));
+        assert!(out.contains("splinter_task_seconds_bucket{le=\"0.01\"} 2\n"));
+        assert!(out.contains("splinter_task_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(out.contains("splinter_task_seconds_count 3\n"));
+    }
+
+    // Tests that an empty server still renders every metric's header, and no samples.
+    #[test]
+    fn test_render_empty() {
+        let out = render(&[], &[], &[], &[], &Histogram::new(PRECISION), 1000000);
+        assert!(out.contains("# TYPE splinter_task_seconds histogram\n"));
+        assert!(out.contains("splinter_task_seconds_bucket{le=\"+Inf\"} 0\n"));
+        assert!(!out.contains("tenant=\""));
+        assert!(!out.contains("core=\""));
+    }
+
+    // Tests that the path is picked off the request line, and that garbage has none.
+    #[test]
+    fn test_path() {
+        assert_eq!("/readyz", path(b"GET /readyz HTTP/1.1\r\nHost: splinter\r\n\r\n"));
+        assert_eq!("/metrics", path(b"GET /metrics HTTP/1.0\r\n"));
+        assert_eq!("", path(b"GET"));
+        assert_eq!("", path(b""));
+    }
+}
@@ -0,0 +1,550 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+
+// Live migration of a tenant to another server. The objects in the tables the tenant owns are
+// copied to the destination in the background while the tenant keeps being served here, and the
+// writes made to those tables in the meantime are captured and shipped after the copy. Once few
+// enough are left, ownership switches: requests from the tenant are refused with
+// StatusTenantMoved, carrying the destination's IP address so that clients route the tenant there
+// from then on, and the writes of tasks that were already in flight are shipped last.
+//
+// Migration only moves data. The destination must already have the tenant, along with it's
+// credential and extensions, and tables shared with the tenant by others stay where they are.
+// Records are shipped to the destination's install() TCP endpoint encoded the way replication
+// encodes them (see `replica::encode()`), and applied there through the path a standby applies
+// shipped writes through. One migration runs at a time, on a thread of it's own.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::mem::{size_of, transmute};
+use std::net::{Ipv4Addr, Shutdown, TcpStream};
+use std::str::{from_utf8, FromStr};
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use std::sync::{Arc, Once, ONCE_INIT};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use super::audit::quote;
+use super::common::{TableId, TenantId};
+use super::master::Master;
+use super::member;
+use super::replica::{self, Record};
+use super::wireformat::{MigrateAction, OpCode, RpcRequestHeader, RpcResponseHeader, RpcStatus,
+                        Service};
+
+use spin::{Mutex, RwLock};
+
+/// The number of milliseconds a request to the destination is retried for before the migration
+/// is given up on.
+const TIMEOUT_MS: u64 = 1000;
+
+/// The number of milliseconds the switch waits for tasks in flight on the tenant's behalf.
+const QUIESCE_MS: u64 = 5000;
+
+/// The number of bytes of records a batch is cut off at. A batch always has at least one record.
+const MAX_BATCH_BYTES: usize = 1 << 20;
+
+/// The number of captured writes below which ownership switches. Until then, captured writes
+/// are shipped in rounds while the tenant is still served here.
+const SWITCH_RECORDS: usize = 1024;
+
+/// The number of rounds of captured writes shipped before ownership switches regardless, so that
+/// a tenant writing faster than it's writes are shipped still moves.
+const MAX_ROUNDS: usize = 16;
+
+/// How far a migration got.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Phase {
+    /// The objects in the tenant's tables are being copied.
+    Copying,
+
+    /// The writes made during the copy are being shipped.
+    Draining,
+
+    /// Requests from the tenant are refused, and the last writes are being shipped.
+    Switching,
+
+    /// The tenant is served by the destination.
+    Done,
+
+    /// The migration was given up on, and the tenant is still served here.
+    Failed,
+}
+
+// The state of the last migration started.
+struct Migration {
+    // The tenant migrated, and the destination's install() TCP endpoint.
+    tenant: TenantId,
+    dest: String,
+
+    // How far the migration got, and why it was given up on if it was.
+    phase: Phase,
+    error: String,
+
+    // The number of objects copied, writes captured, and captured writes shipped.
+    copied: u64,
+    captured: u64,
+    shipped: u64,
+
+    // Captured writes waiting to be shipped, and how many there are.
+    pending: Vec<u8>,
+    count: usize,
+}
+
+// Implementation of methods on Migration.
+impl Migration {
+    // Returns a migration of a tenant that has yet to start copying.
+    fn new(tenant: TenantId, dest: &str) -> Migration {
+        Migration {
+            tenant: tenant,
+            dest: dest.to_string(),
+            phase: Phase::Copying,
+            error: String::new(),
+            copied: 0,
+            captured: 0,
+            shipped: 0,
+            pending: Vec::new(),
+            count: 0,
+        }
+    }
+
+    // Notes a write if it is to a table the migrated tenant owns.
+    fn capture(&mut self, rec: &Record) {
+        if rec.tenant == self.tenant {
+            replica::encode(&mut self.pending, rec);
+            self.count += 1;
+            self.captured += 1;
+        }
+    }
+
+    // Takes the captured writes upto `MAX_BATCH_BYTES`, along with how many there are. None if
+    // there are none.
+    fn take(&mut self) -> Option<(usize, Vec<u8>)> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let (count, len) = cut(&self.pending, self.count);
+        let rest = self.pending.split_off(len);
+        let batch = ::std::mem::replace(&mut self.pending, rest);
+        self.count -= count;
+        Some((count, batch))
+    }
+}
+
+// Returns the number of records at the start of a buffer of `count` that fit in a batch, and the
+// number of bytes they take up. At least one record is taken.
+fn cut(buf: &[u8], count: usize) -> (usize, usize) {
+    let mut len = 0;
+    let mut taken = 0;
+    while taken < count {
+        let next = match replica::decode(&buf[len..]) {
+            Some((_, next)) => next,
+            None => break,
+        };
+        if taken > 0 && len + next > MAX_BATCH_BYTES {
+            break;
+        }
+        len += next;
+        taken += 1;
+    }
+
+    (taken, len)
+}
+
+// State shared by the threads making writes, the migration thread, and dispatch.
+struct Shared {
+    // Set while writes are being captured. Writes hold the read lock while they apply, so that
+    // once the write lock is taken to set it, every write applied after the copy starts is
+    // captured.
+    capturing: RwLock<bool>,
+
+    // The last migration started, if any, and one waiting to be run.
+    migration: Mutex<Option<Migration>>,
+    requested: Mutex<Option<(TenantId, String)>>,
+
+    // The IPv4 address of the server every tenant migrated away from this one moved to.
+    moved: RwLock<HashMap<TenantId, [u8; 4]>>,
+}
+
+/// Set once any tenant moved away, so that dispatch only looks tenants up once one has.
+static MOVED: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Initializes SHARED exactly once.
+static INIT: Once = ONCE_INIT;
+
+/// The state shared by all threads. Use `shared()` to access it.
+static mut SHARED: *const Shared = 0 as *const Shared;
+
+// Returns the state shared by all threads, allocating it on first use.
+fn shared() -> &'static Shared {
+    unsafe {
+        INIT.call_once(|| {
+            SHARED = Box::into_raw(Box::new(Shared {
+                capturing: RwLock::new(false),
+                migration: Mutex::new(None),
+                requested: Mutex::new(None),
+                moved: RwLock::new(HashMap::new()),
+            }));
+        });
+        &*SHARED
+    }
+}
+
+/// Applies a write, capturing it if it is to a table owned by a tenant being migrated.
+///
+/// # Arguments
+///
+/// * `rec`:   The record of the write.
+/// * `apply`: Applies the write to the local table.
+pub fn write<F: FnOnce()>(rec: &Record, apply: F) {
+    let capturing = shared().capturing.read();
+    if !*capturing {
+        apply();
+        return;
+    }
+
+    // Writes are captured in the order they are applied in, so that the destination ends up
+    // with the last.
+    let mut migration = shared().migration.lock();
+    apply();
+    if let Some(ref mut migration) = *migration {
+        migration.capture(rec);
+    }
+}
+
+/// Returns the IPv4 address of the server a tenant moved to, if it was migrated away.
+///
+/// # Arguments
+///
+/// * `tenant`: The tenant.
+#[inline]
+pub fn moved(tenant: TenantId) -> Option<[u8; 4]> {
+    if !MOVED.load(Ordering::Acquire) {
+        return None;
+    }
+
+    shared().moved.read().get(&tenant).cloned()
+}
+
+/// Notes that a tenant is moving to this server, so that it is served here again if it was
+/// migrated away before.
+pub fn arrive(tenant: TenantId) {
+    shared().moved.write().remove(&tenant);
+}
+
+/// Asks for a tenant to be migrated. The migration is run by `serve()`.
+///
+/// # Arguments
+///
+/// * `tenant`: The tenant.
+/// * `dest`:   The address of the destination's install() TCP endpoint.
+///
+/// # Return
+///
+/// False if a migration is already running or waiting to, if the tenant already moved, or if
+/// the destination is declared dead by membership (see `member`).
+pub fn start(tenant: TenantId, dest: &str) -> bool {
+    let running = shared().migration.lock().as_ref().map_or(false, |m| {
+        m.phase != Phase::Done && m.phase != Phase::Failed
+    });
+    if running || moved(tenant).is_some() || member::down(dest) {
+        return false;
+    }
+
+    let mut requested = shared().requested.lock();
+    if requested.is_some() {
+        return false;
+    }
+    *requested = Some((tenant, dest.to_string()));
+    true
+}
+
+/// Runs the migrations asked for by `start()`. Blocks the calling thread.
+///
+/// # Arguments
+///
+/// * `master`: Master, which the tenant's tables are read from.
+pub fn serve(master: Arc<Master>) {
+    loop {
+        let requested = shared().requested.lock().take();
+        let (tenant, dest) = match requested {
+            Some(requested) => requested,
+            None => {
+                sleep(Duration::from_millis(10));
+                continue;
+            }
+        };
+
+        *shared().migration.lock() = Some(Migration::new(tenant, &dest));
+        info!("Migrating tenant {} to {}", tenant, dest);
+
+        match run(&master, tenant, &dest) {
+            Ok(()) => {
+                info!("Migrated tenant {} to {}", tenant, dest);
+                set_phase(Phase::Done, String::new());
+            }
+
+            Err(e) => {
+                error!("Failed to migrate tenant {} to {}: {}", tenant, dest, e);
+                shared().moved.write().remove(&tenant);
+                set_phase(Phase::Failed, e.to_string());
+            }
+        }
+
+        // Nothing more is shipped, so stop capturing and drop what is waiting to be.
+        *shared().capturing.write() = false;
+        if let Some(ref mut migration) = *shared().migration.lock() {
+            migration.pending.clear();
+            migration.count = 0;
+        }
+    }
+}
+
+// Moves on to a phase of the migration running.
+fn set_phase(phase: Phase, error: String) {
+    if let Some(ref mut migration) = *shared().migration.lock() {
+        migration.phase = phase;
+        migration.error = error;
+    }
+}
+
+// Runs a migration of a tenant to a destination.
+fn run(master: &Master, tenant: TenantId, dest: &str) -> io::Result<()> {
+    // The destination needs to have the tenant, and says where clients should send it's requests.
+    let ip = call(dest, tenant, MigrateAction::Prepare, &[])?;
+    let ip = from_utf8(&ip)
+        .ok()
+        .and_then(|ip| Ipv4Addr::from_str(ip).ok())
+        .ok_or(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "destination sent a malformed IP address",
+        ))?;
+
+    // Writes are captured from before the copy starts, and applied at the destination after it,
+    // in order, so the destination ends up with the last value of every key whichever version
+    // the copy picked up.
+    *shared().capturing.write() = true;
+
+    let tables: Vec<TableId> = master.owned_tables(tenant).ok_or(io::Error::new(
+        io::ErrorKind::NotFound,
+        "tenant does not exist",
+    ))?;
+    for table in tables.iter() {
+        let mut copy = Vec::new();
+        let mut count = 0;
+        master.visit_table(tenant, *table, &mut |rec| {
+            replica::encode(&mut copy, rec);
+            count += 1;
+        });
+
+        let mut off = 0;
+        while count > 0 {
+            let (taken, len) = cut(&copy[off..], count);
+            call(dest, tenant, MigrateAction::Ingest, &copy[off..off + len])?;
+            off += len;
+            count -= taken;
+            if let Some(ref mut migration) = *shared().migration.lock() {
+                migration.copied += taken as u64;
+            }
+        }
+    }
+
+    // Ship captured writes while the tenant is still served here, until few are left.
+    set_phase(Phase::Draining, String::new());
+    for _ in 0..MAX_ROUNDS {
+        if !ship(dest, tenant, SWITCH_RECORDS)? {
+            break;
+        }
+    }
+
+    // Switch ownership. Tasks in flight finish here, and their writes are shipped last.
+    set_phase(Phase::Switching, String::new());
+    shared().moved.write().insert(tenant, ip.octets());
+    MOVED.store(true, Ordering::Release);
+    if !master.quiesce(tenant, Duration::from_millis(QUIESCE_MS)) {
+        return Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "tenant still has tasks in flight",
+        ));
+    }
+    while ship(dest, tenant, 0)? {}
+
+    Ok(())
+}
+
+// Ships batches of captured writes until no more than `left` are waiting. Returns false if no
+// more than `left` were waiting to begin with.
+fn ship(dest: &str, tenant: TenantId, left: usize) -> io::Result<bool> {
+    let batch = {
+        let mut migration = shared().migration.lock();
+        match *migration {
+            Some(ref mut migration) if migration.count > left => migration.take(),
+            _ => None,
+        }
+    };
+
+    match batch {
+        Some((count, records)) => {
+            call(dest, tenant, MigrateAction::Ingest, &records)?;
+            if let Some(ref mut migration) = *shared().migration.lock() {
+                migration.shipped += count as u64;
+            }
+            Ok(true)
+        }
+
+        None => Ok(false),
+    }
+}
+
+// Sends a migrate() request to the destination, retrying it for upto `TIMEOUT_MS` or until the
+// destination is declared dead. Ingesting a batch twice has the same effect as once, so a batch
+// the destination might have applied without acknowledging is simply resent. Returns the
+// response's payload.
+fn call(dest: &str, tenant: TenantId, action: MigrateAction, args: &[u8]) -> io::Result<Vec<u8>> {
+    let start = Instant::now();
+    loop {
+        match send(dest, tenant, action, args) {
+            Ok(payload) => return Ok(payload),
+            Err(e) => {
+                let refused = e.kind() == io::ErrorKind::PermissionDenied;
+                let expired = start.elapsed() > Duration::from_millis(TIMEOUT_MS);
+                if refused || expired || member::down(dest) {
+                    return Err(e);
+                }
+                sleep(Duration::from_millis(1));
+            }
+        }
+    }
+}
+
+// Sends a migrate() request to the destination once, and waits for it's response. The request
+// carries the credential servers share, which the destination checks before taking the tenant's
+// data. A status other than StatusOk fails with PermissionDenied, since resending the request
+// will not help.
+fn send(dest: &str, tenant: TenantId, action: MigrateAction, args: &[u8]) -> io::Result<Vec<u8>> {
+    let hdr = RpcRequestHeader::new(Service::MasterService, OpCode::SandstormMigrateRpc, tenant, 0);
+    let hdr: [u8; size_of::<RpcRequestHeader>()] = unsafe { transmute(hdr) };
+
+    let credential = replica::credential();
+    let mut req = Vec::with_capacity(hdr.len() + 9 + args.len());
+    req.extend_from_slice(&hdr);
+    req.push(action as u8);
+    for byte in 0..8 {
+        req.push((credential >> (8 * byte)) as u8);
+    }
+    req.extend_from_slice(args);
+
+    let mut stream = TcpStream::connect(dest)?;
+    stream.write_all(&req)?;
+    stream.flush()?;
+    stream.shutdown(Shutdown::Write)?;
+
+    let mut res = Vec::new();
+    stream.read_to_end(&mut res)?;
+    if res.len() < size_of::<RpcResponseHeader>() {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated response"));
+    }
+    if res[0] != RpcStatus::StatusOk as u8 {
+        let msg = format!("refused with status {}", res[0]);
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, msg));
+    }
+
+    Ok(res.split_off(size_of::<RpcResponseHeader>()))
+}
+
+/// Describes the last migration started at this server as a line of JSON.
+pub fn status() -> String {
+    let migration = shared().migration.lock();
+    let migration = match *migration {
+        Some(ref migration) => migration,
+        None => return String::from("{\"phase\":null}\n"),
+    };
+
+    let phase = match migration.phase {
+        Phase::Copying => "copying",
+        Phase::Draining => "draining",
+        Phase::Switching => "switching",
+        Phase::Done => "done",
+        Phase::Failed => "failed",
+    };
+    format!(
+        "{{\"phase\":\"{}\",\"tenant\":{},\"dest\":{},\"copied\":{},\"captured\":{},\
+         \"shipped\":{},\"error\":{}}}\n",
+        phase,
+        migration.tenant,
+        quote(&migration.dest),
+        migration.copied,
+        migration.captured,
+        migration.shipped,
+        quote(&migration.error)
+    )
+}
+
+// This module contains unit tests for migration.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // This unit test verifies that only writes to the migrated tenant's tables are captured, and
+    // that they are taken in order and cut at the size limit.
+    #[test]
+    fn test_capture() {
+        let mut migration = Migration::new(7, "127.0.0.1:7700");
+        assert!(migration.take().is_none());
+
+        let val = vec![0u8; MAX_BATCH_BYTES / 2];
+        for tenant in [7, 8, 7, 7].iter() {
+            let rec = Record {
+                op: replica::OP_PUT,
+                tenant: *tenant,
+                table: 1,
+                key: b"k",
+                val: &val,
+            };
+            migration.capture(&rec);
+        }
+        assert_eq!(3, migration.captured);
+
+        let (count, batch) = migration.take().unwrap();
+        assert_eq!(1, count);
+        assert_eq!(replica::RECORD_HDR_LEN + 1 + val.len(), batch.len());
+        assert_eq!(7, replica::decode(&batch).unwrap().0.tenant);
+
+        assert_eq!(1, migration.take().unwrap().0);
+        assert_eq!(1, migration.take().unwrap().0);
+        assert!(migration.take().is_none());
+    }
+
+    // This unit test verifies that small records are batched together.
+    #[test]
+    fn test_cut() {
+        let mut buf = Vec::new();
+        for _ in 0..3 {
+            let rec = Record {
+                op: replica::OP_DELETE,
+                tenant: 1,
+                table: 2,
+                key: b"key",
+                val: &[],
+            };
+            replica::encode(&mut buf, &rec);
+        }
+
+        assert_eq!((3, buf.len()), cut(&buf, 3));
+        assert_eq!((2, 2 * (replica::RECORD_HDR_LEN + 3)), cut(&buf, 2));
+    }
+}
@@ -0,0 +1,190 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use super::wireformat::OpCode;
+
+/// The number of bytes every operation on a multiop() request is prefixed with: an opcode byte,
+/// an 8 byte table id, a 2 byte key length and a 4 byte value length.
+pub const ENTRY_LEN: usize = 15;
+
+/// The number of bytes every result on a multiop() response is prefixed with: a status byte
+/// and a 4 byte value length.
+pub const RESULT_LEN: usize = 5;
+
+/// An operation on a multiop() request.
+pub struct Entry<'a> {
+    /// The raw opcode of the operation. Only gets, puts and deletes are supported.
+    pub opcode: u8,
+
+    /// The table the operation is on.
+    pub table: u64,
+
+    /// The key the operation is on.
+    pub key: &'a [u8],
+
+    /// The value to be written by a put. Empty for a get or delete.
+    pub val: &'a [u8],
+}
+
+// Writes the lower `n` bytes of a value into a buffer, least significant byte first.
+fn push_le(buf: &mut Vec<u8>, val: u64, n: usize) {
+    for i in 0..n {
+        buf.push((val >> (8 * i)) as u8);
+    }
+}
+
+// Reads `n` bytes off a buffer into a value, least significant byte first.
+fn read_le(buf: &[u8], n: usize) -> u64 {
+    let mut val = 0;
+    for i in 0..n {
+        val |= (buf[i] as u64) << (8 * i);
+    }
+
+    return val;
+}
+
+/// Appends an operation to the payload of a multiop() request.
+///
+/// # Arguments
+///
+/// * `buf`:    The payload.
+/// * `opcode`: The operation, a SandstormGetRpc, SandstormPutRpc or SandstormDeleteRpc.
+/// * `table`:  The table the operation is on.
+/// * `key`:    The key the operation is on. Limit 64 KB.
+/// * `val`:    The value for a put. Empty for a get or delete.
+pub fn push_entry(buf: &mut Vec<u8>, opcode: OpCode, table: u64, key: &[u8], val: &[u8]) {
+    buf.push(opcode as u8);
+    push_le(buf, table, 8);
+    push_le(buf, key.len() as u64, 2);
+    push_le(buf, val.len() as u64, 4);
+    buf.extend_from_slice(key);
+    buf.extend_from_slice(val);
+}
+
+/// Reads the operation at the head of a multiop() request's payload.
+///
+/// # Arguments
+///
+/// * `buf`: The part of the payload that has not been read yet.
+///
+/// # Return
+///
+/// The operation and the number of bytes it took up, or None if the payload was truncated.
+pub fn parse_entry(buf: &[u8]) -> Option<(Entry, usize)> {
+    if buf.len() < ENTRY_LEN {
+        return None;
+    }
+
+    let k_len = read_le(&buf[9..], 2) as usize;
+    let v_len = read_le(&buf[11..], 4) as usize;
+    let len = ENTRY_LEN + k_len + v_len;
+    if buf.len() < len {
+        return None;
+    }
+
+    let entry = Entry {
+        opcode: buf[0],
+        table: read_le(&buf[1..], 8),
+        key: &buf[ENTRY_LEN..ENTRY_LEN + k_len],
+        val: &buf[ENTRY_LEN + k_len..len],
+    };
+
+    return Some((entry, len));
+}
+
+/// Builds the prefix of a result on a multiop() response. The result's value, if any, follows
+/// right after.
+///
+/// # Arguments
+///
+/// * `status`: The raw RpcStatus the operation completed with.
+/// * `v_len`:  The length of the value that follows.
+///
+/// # Return
+///
+/// The prefix.
+pub fn result_header(status: u8, v_len: usize) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(RESULT_LEN);
+    buf.push(status);
+    push_le(&mut buf, v_len as u64, 4);
+    return buf;
+}
+
+/// Reads the result at the head of a multiop() response's payload.
+///
+/// # Arguments
+///
+/// * `buf`: The part of the payload that has not been read yet.
+///
+/// # Return
+///
+/// The raw RpcStatus and value of the result along with the number of bytes it took up, or None
+/// if the payload was truncated.
+pub fn parse_result(buf: &[u8]) -> Option<(u8, &[u8], usize)> {
+    if buf.len() < RESULT_LEN {
+        return None;
+    }
+
+    let len = RESULT_LEN + read_le(&buf[1..], 4) as usize;
+    if buf.len() < len {
+        return None;
+    }
+
+    return Some((buf[0], &buf[RESULT_LEN..len], len));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wireformat::OpCode;
+
+    #[test]
+    fn entries() {
+        let mut buf = Vec::new();
+        push_entry(&mut buf, OpCode::SandstormGetRpc, 1, &[7; 30], &[]);
+        push_entry(&mut buf, OpCode::SandstormPutRpc, 0x0102030405, &[8; 3], &[9; 300]);
+        assert_eq!(2 * ENTRY_LEN + 333, buf.len());
+
+        let (get, len) = parse_entry(&buf).unwrap();
+        assert_eq!(OpCode::SandstormGetRpc as u8, get.opcode);
+        assert_eq!(1, get.table);
+        assert_eq!(&[7; 30][..], get.key);
+        assert_eq!(0, get.val.len());
+
+        let (put, rest) = parse_entry(&buf[len..]).unwrap();
+        assert_eq!(OpCode::SandstormPutRpc as u8, put.opcode);
+        assert_eq!(0x0102030405, put.table);
+        assert_eq!(&[8; 3][..], put.key);
+        assert_eq!(&[9; 300][..], put.val);
+        assert_eq!(buf.len(), len + rest);
+
+        assert!(parse_entry(&buf[..len - 1]).is_none());
+    }
+
+    #[test]
+    fn results() {
+        let mut buf = result_header(0x01, 4);
+        buf.extend_from_slice(&[1, 2, 3, 4]);
+        buf.extend_from_slice(&result_header(0x04, 0));
+
+        let (status, val, len) = parse_result(&buf).unwrap();
+        assert_eq!((0x01, &[1, 2, 3, 4][..], RESULT_LEN + 4), (status, val, len));
+
+        let (status, val, _) = parse_result(&buf[len..]).unwrap();
+        assert_eq!((0x04, 0), (status, val.len()));
+
+        assert!(parse_result(&buf[..3]).is_none());
+    }
+}
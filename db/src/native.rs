@@ -14,29 +14,81 @@
  */
 
 use std::cell::Cell;
-use std::ops::{Generator, GeneratorState};
 
+use super::common::TenantId;
 use super::cycles;
+use super::deadline;
+use super::span;
 use super::task::TaskState::*;
-use super::task::{Task, TaskPriority, TaskState};
+use super::task::{self, Task, TaskPriority, TaskState};
 
 use e2d2::interface::Packet;
 use e2d2::headers::UdpHeader;
 use e2d2::common::EmptyMetadata;
 
-// The expected type signature on a generator for a native operation (ex: get()). The return
-// value is an optional tuple consisting of a request and response packet parsed/deparsed upto
-// their UDP headers. This is to allow for operations that might not require a response packet
-// such as garbage collection, logging etc. to be run as generators too.
-type NativeGenerator = Box<
-    Generator<
-        Yield = u64,
-        Return = Option<(
-            Packet<UdpHeader, EmptyMetadata>,
-            Packet<UdpHeader, EmptyMetadata>,
-        )>,
-    >,
->;
+/// What a native operation returns once it completes: an optional tuple consisting of a request
+/// and response packet parsed/deparsed upto their UDP headers. This is to allow for operations
+/// that might not require a response packet such as garbage collection, logging etc. to be run
+/// as native tasks too.
+pub type Packets = Option<(
+    Packet<UdpHeader, EmptyMetadata>,
+    Packet<UdpHeader, EmptyMetadata>,
+)>;
+
+/// What a native operation did when it was resumed.
+pub enum Step {
+    /// The operation yielded, and has to be resumed again to make progress.
+    Yield,
+
+    /// The operation is complete, and must not be resumed again.
+    Done(Packets),
+}
+
+/// The expected type signature on a native operation (ex: get()). The operation is a state
+/// machine, keeping whatever has to survive a yield in what the closure captures, so that the
+/// server does not depend on generators, and builds on stable Rust.
+pub type NativeOperation = Box<FnMut() -> Step>;
+
+/// Returns a native operation that runs a closure on it's first resume, and completes with what
+/// the closure returned. Suits operations that never yield.
+///
+/// # Arguments
+///
+/// * `op`: The closure implementing the operation.
+///
+/// # Return
+///
+/// The operation, ready to be handed to `Native::new()`.
+pub fn once<F: FnOnce() -> Packets + 'static>(op: F) -> NativeOperation {
+    let mut op = Some(op);
+    Box::new(move || Step::Done(op.take().and_then(|op| op())))
+}
+
+/// Returns a native operation that runs a closure on it's first resume, and from then on runs the
+/// operation the closure returned. Suits operations that do their work up front, and then yield
+/// until it takes effect, such as until a write is replicated.
+///
+/// # Arguments
+///
+/// * `first`: The closure doing the work, and returning the operation to continue with.
+///
+/// # Return
+///
+/// The operation, ready to be handed to `Native::new()`.
+pub fn then<F: FnOnce() -> NativeOperation + 'static>(first: F) -> NativeOperation {
+    let mut first = Some(first);
+    let mut rest: Option<NativeOperation> = None;
+    Box::new(move || {
+        if let Some(first) = first.take() {
+            rest = Some(first());
+        }
+
+        match rest {
+            Some(ref mut rest) => rest(),
+            None => Step::Done(None),
+        }
+    })
+}
 
 /// A task corresponding to a native operation (like get() and put() requests).
 pub struct Native {
@@ -50,16 +102,27 @@ pub struct Native {
     // The priority of the task. Required to determine when the task must be allowed to run next.
     priority: TaskPriority,
 
-    // The underlying generator for the task. Running the task effectively runs this generator.
-    gen: NativeGenerator,
+    // The tenant that issued the request this task is servicing.
+    tenant: TenantId,
+
+    // The span of the request this task is servicing, or zero if it is not being traced.
+    span: u64,
+
+    // The deadline in cycles of the request this task is servicing, or zero if it has none.
+    deadline: u64,
+
+    // The priority the request this task is servicing asked for within the tenant's share.
+    level: u8,
 
-    // The result (if any) returned by the generator once it completes execution.
-    res: Cell<
-        Option<(
-            Packet<UdpHeader, EmptyMetadata>,
-            Packet<UdpHeader, EmptyMetadata>,
-        )>,
-    >,
+    // The time-stamp in cycles at which the task was created, and at which it first ran.
+    created: u64,
+    scheduled: u64,
+
+    // The underlying operation for the task. Running the task effectively resumes this operation.
+    op: NativeOperation,
+
+    // The result (if any) returned by the operation once it completes execution.
+    res: Cell<Packets>,
 }
 
 // Implementation of methods on Native.
@@ -68,20 +131,27 @@ impl Native {
     ///
     /// # Arguments:
     ///
-    /// * `prio`:      The priority of the created task. Required by the scheduler.
-    /// * `generator`: The generator for the task. Will be executed when the task is running.
+    /// * `prio`:   The priority of the created task. Required by the scheduler.
+    /// * `tenant`: The tenant that issued the request. Required by the scheduler.
+    /// * `op`:     The operation for the task. Will be resumed when the task is running.
     ///
     /// # Return:
     ///
     /// A Task containing a native operation that can be handed off to, and run by the scheduler.
-    pub fn new(prio: TaskPriority, generator: NativeGenerator) -> Native {
+    pub fn new(prio: TaskPriority, tenant: TenantId, op: NativeOperation) -> Native {
         // The res field is initialized to None. It will be populated when the task has completed
         // execution.
         Native {
             state: INITIALIZED,
             time: 0,
             priority: prio,
-            gen: generator,
+            tenant: tenant,
+            span: span::current(),
+            deadline: deadline::current(),
+            level: task::current_level(),
+            created: cycles::rdtsc(),
+            scheduled: 0,
+            op: op,
             res: Cell::new(None),
         }
     }
@@ -92,22 +162,22 @@ impl Task for Native {
     /// Refer to the Task trait for documentation.
     fn run(&mut self) -> (TaskState, u64) {
         let start = cycles::rdtsc();
+        if self.state == INITIALIZED {
+            self.scheduled = start;
+        }
 
-        // Run the generator if need be.
+        // Run the operation if need be.
         if self.state == INITIALIZED || self.state == YIELDED {
             self.state = RUNNING;
 
-            // As of 04/02/2018, calling resume() on a generator requires an unsafe block.
-            unsafe {
-                match self.gen.resume() {
-                    GeneratorState::Yielded(_) => {
-                        self.state = YIELDED;
-                    }
-
-                    GeneratorState::Complete(pkts) => {
-                        self.res.set(pkts);
-                        self.state = COMPLETED;
-                    }
+            match (self.op)() {
+                Step::Yield => {
+                    self.state = YIELDED;
+                }
+
+                Step::Done(pkts) => {
+                    self.res.set(pkts);
+                    self.state = COMPLETED;
                 }
             }
         }
@@ -136,6 +206,54 @@ impl Task for Native {
         self.priority.clone()
     }
 
+    /// Refer to the Task trait for documentation.
+    fn tenant(&self) -> Option<TenantId> {
+        Some(self.tenant)
+    }
+
+    /// Refer to the Task trait for documentation.
+    fn name(&self) -> &str {
+        "native"
+    }
+
+    /// Refer to the Task trait for documentation.
+    fn created(&self) -> u64 {
+        self.created
+    }
+
+    /// Refer to the Task trait for documentation.
+    fn scheduled(&self) -> u64 {
+        self.scheduled
+    }
+
+    /// Refer to the Task trait for documentation.
+    fn db_time(&self) -> u64 {
+        // Native operations do nothing besides working on the database.
+        self.time
+    }
+
+    /// Refer to the Task trait for documentation.
+    fn span(&self) -> u64 {
+        self.span
+    }
+
+    /// Refer to the Task trait for documentation.
+    fn deadline(&self) -> u64 {
+        self.deadline
+    }
+
+    /// Refer to the Task trait for documentation.
+    fn level(&self) -> u8 {
+        self.level
+    }
+
+    /// Refer to the Task trait for documentation.
+    fn expire(&mut self) -> bool {
+        // The request and response are held by the operation, which takes about as long to run
+        // as a response saying the deadline passed would take to build.
+        false
+    }
+
     /// Refer to the Task trait for documentation.
     unsafe fn tear(
         &mut self,
@@ -146,3 +264,44 @@ impl Task for Native {
         self.res.replace(None)
     }
 }
+
+// This module contains unit tests for native operations.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // This unit test verifies that an operation built with once() completes on it's first
+    // resume.
+    #[test]
+    fn test_once() {
+        let mut op = once(|| None);
+        match op() {
+            Step::Done(None) => (),
+            _ => panic!("once() did not complete on it's first resume"),
+        }
+    }
+
+    // This unit test verifies that an operation built with then() runs it's first closure once,
+    // and then resumes the operation it returned until that completes.
+    #[test]
+    fn test_then() {
+        let mut op = then(|| {
+            let mut waits = 2;
+            Box::new(move || match waits {
+                0 => Step::Done(None),
+                _ => {
+                    waits -= 1;
+                    Step::Yield
+                }
+            })
+        });
+
+        let yielded = |step: Step| match step {
+            Step::Yield => true,
+            Step::Done(_) => false,
+        };
+        assert!(yielded(op()));
+        assert!(yielded(op()));
+        assert!(!yielded(op()));
+    }
+}
@@ -0,0 +1,203 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use std::fs::{self, File};
+use std::io::Read;
+
+/// Reads a file from sysfs into a string with surrounding whitespace trimmed.
+fn read_sysfs(path: &str) -> Option<String> {
+    let mut contents = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .ok()
+        .map(|_| String::from(contents.trim()))
+}
+
+/// Parses a cpu list of the form found in sysfs (ex: "0-3,8,10-11").
+///
+/// # Arguments
+///
+/// * `list`: The cpu list to be parsed.
+///
+/// # Return
+///
+/// The set of cores in the list, or None if the list was malformed.
+fn parse_cpulist(list: &str) -> Option<Vec<i32>> {
+    let mut cores = Vec::new();
+
+    for range in list.split(',').filter(|r| r.len() > 0) {
+        let bounds: Vec<&str> = range.splitn(2, '-').collect();
+        let lo = bounds[0].parse::<i32>().ok()?;
+        let hi = match bounds.get(1) {
+            Some(hi) => hi.parse::<i32>().ok()?,
+            None => lo,
+        };
+
+        if hi < lo {
+            return None;
+        }
+
+        cores.extend(lo..(hi + 1));
+    }
+
+    return Some(cores);
+}
+
+/// Returns the NUMA node a NIC is attached to.
+///
+/// # Arguments
+///
+/// * `pci`: The PCI address of the NIC (ex: "0000:04:00.1").
+///
+/// # Return
+///
+/// The NUMA node of the NIC, or None if it could not be determined (ex: the machine has only
+/// one node).
+pub fn nic_node(pci: &str) -> Option<i32> {
+    read_sysfs(&format!("/sys/bus/pci/devices/{}/numa_node", pci))
+        .and_then(|node| node.parse::<i32>().ok())
+        .and_then(|node| if node < 0 { None } else { Some(node) })
+}
+
+/// Returns the NUMA node a core belongs to.
+///
+/// # Arguments
+///
+/// * `core`: The identifier of the core.
+///
+/// # Return
+///
+/// The NUMA node of the core, or None if it could not be determined.
+pub fn core_node(core: i32) -> Option<i32> {
+    let entries = fs::read_dir(format!("/sys/devices/system/cpu/cpu{}", core)).ok()?;
+
+    for entry in entries.filter_map(|e| e.ok()) {
+        let name = entry.file_name().into_string().unwrap_or_default();
+        if name.starts_with("node") {
+            if let Ok(node) = name[4..].parse::<i32>() {
+                return Some(node);
+            }
+        }
+    }
+
+    return None;
+}
+
+/// Returns all cores on a NUMA node.
+///
+/// # Arguments
+///
+/// * `node`: The NUMA node whose cores should be returned.
+///
+/// # Return
+///
+/// The cores on the node in ascending order, or None if they could not be determined.
+pub fn node_cores(node: i32) -> Option<Vec<i32>> {
+    read_sysfs(&format!("/sys/devices/system/node/node{}/cpulist", node))
+        .and_then(|list| parse_cpulist(&list))
+}
+
+/// Checks whether a set of cores resides on the same NUMA node as a NIC, warning about every
+/// core that does not. Packets received on such cores are DMA'ed across sockets.
+///
+/// # Arguments
+///
+/// * `pci`:   The PCI address of the NIC.
+/// * `cores`: The cores that will be receiving and transmitting packets on the NIC.
+///
+/// # Return
+///
+/// True if all cores are on the NIC's NUMA node, or the topology could not be determined.
+pub fn validate(pci: &str, cores: &[i32]) -> bool {
+    let node = match nic_node(pci) {
+        Some(node) => node,
+        None => {
+            info!("Could not determine NUMA node of NIC {}.", pci);
+            return true;
+        }
+    };
+
+    let mut valid = true;
+    for core in cores.iter() {
+        match core_node(*core) {
+            Some(n) if n != node => {
+                warn!(
+                    "Core {} is on NUMA node {}, but NIC {} is on node {}.",
+                    core, n, pci, node
+                );
+                valid = false;
+            }
+
+            _ => {}
+        }
+    }
+
+    if !valid {
+        warn!("Cores straddle NUMA nodes. Expect higher tail latency from cross-socket DMA.");
+    }
+
+    return valid;
+}
+
+/// Splits a set of cores into contiguous slices, one for each of several NICs. Slices differ in
+/// length by at most one core, with the earlier slices receiving any cores left over.
+///
+/// # Arguments
+///
+/// * `cores`: The cores to be split.
+/// * `parts`: The number of slices to split the cores into.
+///
+/// # Return
+///
+/// `parts` slices of cores. Some slices are empty if there are fewer cores than slices.
+pub fn partition(cores: &[i32], parts: usize) -> Vec<Vec<i32>> {
+    let mut slices = Vec::with_capacity(parts);
+    let mut start = 0;
+    for part in 0..parts {
+        let len = cores.len() / parts + if part < cores.len() % parts { 1 } else { 0 };
+        slices.push(cores[start..start + len].to_vec());
+        start += len;
+    }
+
+    return slices;
+}
+
+// This module contains simple unit tests for the cpu list parser and partitioning.
+#[cfg(test)]
+mod tests {
+    use super::{parse_cpulist, partition};
+
+    // This unit test verifies that ranges and single cores are parsed.
+    #[test]
+    fn test_parse_cpulist() {
+        assert_eq!(Some(vec![0, 1, 2, 3, 8, 10, 11]), parse_cpulist("0-3,8,10-11"));
+        assert_eq!(Some(vec![]), parse_cpulist(""));
+    }
+
+    // This unit test verifies that malformed lists are rejected.
+    #[test]
+    fn test_parse_cpulist_malformed() {
+        assert_eq!(None, parse_cpulist("3-1"));
+        assert_eq!(None, parse_cpulist("a-b"));
+    }
+
+    // This unit test verifies that cores are split into contiguous slices of near equal length.
+    #[test]
+    fn test_partition() {
+        assert_eq!(vec![vec![1, 2, 3], vec![4, 5]], partition(&[1, 2, 3, 4, 5], 2));
+        assert_eq!(vec![vec![1, 2, 3, 4]], partition(&[1, 2, 3, 4], 1));
+        assert_eq!(vec![vec![1], vec![]], partition(&[1], 2));
+    }
+}
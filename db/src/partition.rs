@@ -0,0 +1,431 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+
+// Range partitioning of a tenant's key space across servers. A partitioned tenant's keys are
+// split into ranges by the keys they start at, and every range is held by one server, named by
+// the IP address it takes requests on. The ranges cover keys in every table of the tenant, so a
+// tenant whose data does not fit on one machine is spread across several without the
+// application sharding it.
+//
+// Every server keeps a copy of the routing table of each partitioned tenant, versioned so that
+// newer tables replace older ones. Gets and puts of keys held by another server are refused with
+// StatusWrongPartition, carrying the tenant's routing table so that clients route by it from then
+// on; this is how tables are distributed to clients, and how they learn of splits and merges.
+//
+// Splits, merges and reassignments only change the routing table, they do not move objects. A
+// range should only be assigned to another server once it is empty, or once it's objects were
+// copied there. Extensions are invoked on a single server per tenant, and only see the ranges
+// held there.
+
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use std::sync::{Once, ONCE_INIT};
+
+use super::audit::quote;
+use super::common::TenantId;
+
+use spin::RwLock;
+
+/// The number of bytes every range on an encoded routing table is prefixed with: the IPv4
+/// address of the server holding it, and the length of the key it starts at.
+pub const RANGE_HDR_LEN: usize = 6;
+
+/// A range of a tenant's keys.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Range {
+    /// The first key in the range. The range ends right before the key the next range starts at.
+    pub start: Vec<u8>,
+
+    /// The IPv4 address of the server holding the range, in network order.
+    pub ip: [u8; 4],
+}
+
+/// The routing table of a partitioned tenant.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Map {
+    /// Bumped on every change to the ranges.
+    pub version: u64,
+
+    /// The ranges, ordered by the key they start at. The first one starts at the empty key, so
+    /// every key falls in exactly one range.
+    pub ranges: Vec<Range>,
+}
+
+// Implementation of methods on Map.
+impl Map {
+    /// Creates a routing table.
+    ///
+    /// # Arguments
+    ///
+    /// * `version`: The version of the table.
+    /// * `ranges`:  The ranges, in any order.
+    ///
+    /// # Return
+    ///
+    /// The table, or None if no range starts at the empty key, or if two start at the same key.
+    pub fn new(version: u64, mut ranges: Vec<Range>) -> Option<Map> {
+        ranges.sort_by(|a, b| a.start.cmp(&b.start));
+        let first = ranges.get(0).map_or(false, |range| range.start.len() == 0);
+        let unique = ranges.windows(2).all(|pair| pair[0].start < pair[1].start);
+        if !first || !unique {
+            return None;
+        }
+
+        Some(Map {
+            version: version,
+            ranges: ranges,
+        })
+    }
+
+    /// Returns the IPv4 address of the server holding a key.
+    pub fn owner(&self, key: &[u8]) -> [u8; 4] {
+        self.ranges[self.find(key)].ip
+    }
+
+    /// Splits the range a key falls in at the key, with the part from the key onwards held by a
+    /// server.
+    ///
+    /// # Arguments
+    ///
+    /// * `key`: The key the new range starts at.
+    /// * `ip`:  The IPv4 address of the server holding the new range.
+    ///
+    /// # Return
+    ///
+    /// False if a range already starts at the key.
+    pub fn split(&mut self, key: &[u8], ip: [u8; 4]) -> bool {
+        let idx = self.find(key);
+        if self.ranges[idx].start == key {
+            return false;
+        }
+
+        let range = Range {
+            start: key.to_vec(),
+            ip: ip,
+        };
+        self.ranges.insert(idx + 1, range);
+        self.version += 1;
+        true
+    }
+
+    /// Merges the range starting at a key into the one before it.
+    ///
+    /// # Return
+    ///
+    /// False if no range starts at the key, if it is the first range, or if the two are held by
+    /// different servers, since merging them would leave the merged range's objects on two.
+    pub fn merge(&mut self, key: &[u8]) -> bool {
+        let idx = self.find(key);
+        if idx == 0 || self.ranges[idx].start != key {
+            return false;
+        }
+        if self.ranges[idx - 1].ip != self.ranges[idx].ip {
+            return false;
+        }
+
+        self.ranges.remove(idx);
+        self.version += 1;
+        true
+    }
+
+    /// Hands the range starting at a key to a server.
+    ///
+    /// # Return
+    ///
+    /// False if no range starts at the key.
+    pub fn assign(&mut self, key: &[u8], ip: [u8; 4]) -> bool {
+        let idx = self.find(key);
+        if self.ranges[idx].start != key {
+            return false;
+        }
+
+        self.ranges[idx].ip = ip;
+        self.version += 1;
+        true
+    }
+
+    /// Appends the table to a buffer: the version (u64) and number of ranges (u32), little
+    /// endian, followed by every range's IPv4 address, the length of the key it starts at (u16,
+    /// little endian), and the key.
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        for byte in 0..8 {
+            buf.push((self.version >> (8 * byte)) as u8);
+        }
+        for byte in 0..4 {
+            buf.push((self.ranges.len() >> (8 * byte)) as u8);
+        }
+
+        for range in self.ranges.iter() {
+            buf.extend_from_slice(&range.ip);
+            buf.push(range.start.len() as u8);
+            buf.push((range.start.len() >> 8) as u8);
+            buf.extend_from_slice(&range.start);
+        }
+    }
+
+    // Returns the index of the range a key falls in, the last one starting at or before it.
+    fn find(&self, key: &[u8]) -> usize {
+        match self.ranges.binary_search_by(|range| range.start.as_slice().cmp(key)) {
+            Ok(idx) => idx,
+            Err(idx) => idx - 1,
+        }
+    }
+}
+
+/// Reads a routing table encoded by `Map::encode()`.
+///
+/// # Return
+///
+/// The table, or None if the buffer is truncated or the ranges are not a valid table.
+pub fn decode(buf: &[u8]) -> Option<Map> {
+    if buf.len() < 12 {
+        return None;
+    }
+
+    let mut version = 0u64;
+    for byte in 0..8 {
+        version |= (buf[byte] as u64) << (8 * byte);
+    }
+    let mut count = 0usize;
+    for byte in 0..4 {
+        count |= (buf[8 + byte] as usize) << (8 * byte);
+    }
+
+    let mut ranges = Vec::new();
+    let mut off = 12;
+    for _ in 0..count {
+        if buf.len() < off + RANGE_HDR_LEN {
+            return None;
+        }
+
+        let mut ip = [0u8; 4];
+        ip.copy_from_slice(&buf[off..off + 4]);
+        let len = buf[off + 4] as usize | (buf[off + 5] as usize) << 8;
+        off += RANGE_HDR_LEN;
+        if buf.len() < off + len {
+            return None;
+        }
+
+        ranges.push(Range {
+            start: buf[off..off + len].to_vec(),
+            ip: ip,
+        });
+        off += len;
+    }
+
+    Map::new(version, ranges)
+}
+
+// The routing tables known to this server, and the addresses it takes requests on.
+struct Shared {
+    maps: RwLock<HashMap<TenantId, Map>>,
+    local: RwLock<Vec<[u8; 4]>>,
+}
+
+/// Set once any tenant is partitioned, so that dispatch only looks keys up once one is.
+static PARTITIONED: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Initializes SHARED exactly once.
+static INIT: Once = ONCE_INIT;
+
+/// The state shared by all threads. Use `shared()` to access it.
+static mut SHARED: *const Shared = 0 as *const Shared;
+
+// Returns the state shared by all threads, allocating it on first use.
+fn shared() -> &'static Shared {
+    unsafe {
+        INIT.call_once(|| {
+            SHARED = Box::into_raw(Box::new(Shared {
+                maps: RwLock::new(HashMap::new()),
+                local: RwLock::new(Vec::new()),
+            }));
+        });
+        &*SHARED
+    }
+}
+
+/// Sets up partitioning at server start.
+///
+/// # Arguments
+///
+/// * `local`: The IPv4 addresses the server takes requests on.
+/// * `maps`:  The routing table of every partitioned tenant.
+pub fn configure(local: &[[u8; 4]], maps: Vec<(TenantId, Map)>) {
+    *shared().local.write() = local.to_vec();
+
+    let mut tables = shared().maps.write();
+    for (tenant, map) in maps.into_iter() {
+        tables.insert(tenant, map);
+    }
+    if tables.len() > 0 {
+        PARTITIONED.store(true, Ordering::Release);
+    }
+}
+
+/// Returns true if a key of a tenant is held by another server.
+///
+/// # Arguments
+///
+/// * `tenant`: The tenant.
+/// * `key`:    The key.
+#[inline]
+pub fn foreign(tenant: TenantId, key: &[u8]) -> bool {
+    if !PARTITIONED.load(Ordering::Acquire) {
+        return false;
+    }
+
+    match shared().maps.read().get(&tenant) {
+        Some(map) => !shared().local.read().contains(&map.owner(key)),
+        None => false,
+    }
+}
+
+/// Returns a tenant's routing table encoded by `Map::encode()`, or None if it isn't partitioned.
+pub fn encoded(tenant: TenantId) -> Option<Vec<u8>> {
+    shared().maps.read().get(&tenant).map(|map| {
+        let mut buf = Vec::new();
+        map.encode(&mut buf);
+        buf
+    })
+}
+
+/// Changes a tenant's routing table. A tenant that isn't partitioned yet starts out with a
+/// single range held by this server.
+///
+/// # Arguments
+///
+/// * `tenant`: The tenant.
+/// * `change`: Makes the change, returning false if it could not be made.
+///
+/// # Return
+///
+/// False if the change could not be made, in which case the table is left as it was.
+pub fn change<F: FnOnce(&mut Map) -> bool>(tenant: TenantId, change: F) -> bool {
+    let local = match shared().local.read().get(0) {
+        Some(ip) => *ip,
+        None => return false,
+    };
+
+    let mut maps = shared().maps.write();
+    let mut map = maps.get(&tenant).cloned().unwrap_or(Map {
+        version: 0,
+        ranges: vec![Range {
+            start: Vec::new(),
+            ip: local,
+        }],
+    });
+    if !change(&mut map) {
+        return false;
+    }
+
+    maps.insert(tenant, map);
+    PARTITIONED.store(true, Ordering::Release);
+    true
+}
+
+/// Describes a tenant's routing table as a line of JSON, with every range's first key quoted
+/// as UTF-8 with invalid bytes replaced.
+pub fn show(tenant: TenantId) -> String {
+    let maps = shared().maps.read();
+    let map = match maps.get(&tenant) {
+        Some(map) => map,
+        None => return String::from("{\"version\":null,\"ranges\":[]}\n"),
+    };
+
+    let local = shared().local.read();
+    let ranges: Vec<String> = map.ranges
+        .iter()
+        .map(|range| {
+            format!(
+                "{{\"start\":{},\"server\":\"{}\",\"local\":{}}}",
+                quote(&String::from_utf8_lossy(&range.start)),
+                Ipv4Addr::from(range.ip),
+                local.contains(&range.ip)
+            )
+        })
+        .collect();
+
+    format!(
+        "{{\"version\":{},\"ranges\":[{}]}}\n",
+        map.version,
+        ranges.join(",")
+    )
+}
+
+// This module contains unit tests for routing tables.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(last: u8) -> [u8; 4] {
+        [10, 0, 0, last]
+    }
+
+    fn range(start: &[u8], last: u8) -> Range {
+        Range {
+            start: start.to_vec(),
+            ip: ip(last),
+        }
+    }
+
+    // This unit test verifies that keys are routed to the range they fall in, and that tables
+    // without a range at the empty key, or with two at the same key, are rejected.
+    #[test]
+    fn test_owner() {
+        let map = Map::new(1, vec![range(b"m", 2), range(b"", 1), range(b"t", 3)]).unwrap();
+        assert_eq!([10, 0, 0, 1], map.owner(b""));
+        assert_eq!([10, 0, 0, 1], map.owner(b"apple"));
+        assert_eq!([10, 0, 0, 2], map.owner(b"m"));
+        assert_eq!([10, 0, 0, 2], map.owner(b"sz"));
+        assert_eq!([10, 0, 0, 3], map.owner(b"zebra"));
+
+        assert!(Map::new(1, vec![range(b"a", 1)]).is_none());
+        assert!(Map::new(1, vec![range(b"", 1), range(b"a", 1), range(b"a", 2)]).is_none());
+    }
+
+    // This unit test verifies that splits, merges and reassignments bump the version, and that
+    // ranges held by different servers are not merged.
+    #[test]
+    fn test_change() {
+        let mut map = Map::new(1, vec![range(b"", 1)]).unwrap();
+        assert!(map.split(b"m", ip(1)));
+        assert!(!map.split(b"m", ip(2)));
+        assert!(map.assign(b"m", ip(2)));
+        assert_eq!(3, map.version);
+        assert_eq!([10, 0, 0, 2], map.owner(b"q"));
+
+        assert!(!map.merge(b"m"));
+        assert!(!map.merge(b""));
+        assert!(!map.assign(b"n", ip(1)));
+        assert!(map.assign(b"m", ip(1)) && map.merge(b"m"));
+        assert_eq!(vec![range(b"", 1)], map.ranges);
+        assert_eq!(5, map.version);
+    }
+
+    // This unit test verifies that a table survives being encoded and decoded, and that a
+    // truncated one is rejected.
+    #[test]
+    fn test_encode() {
+        let map = Map::new(7, vec![range(b"", 1), range(b"key", 2)]).unwrap();
+        let mut buf = Vec::new();
+        map.encode(&mut buf);
+        assert_eq!(12 + 2 * RANGE_HDR_LEN + 3, buf.len());
+
+        assert_eq!(Some(map), decode(&buf));
+        assert!(decode(&buf[..buf.len() - 1]).is_none());
+    }
+}
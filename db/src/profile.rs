@@ -0,0 +1,796 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+
+// A sampling profiler. While it runs, a SIGPROF timer interrupts whichever thread is on a CPU
+// once every so many microseconds of CPU time consumed by the server, and the signal handler
+// records that thread's stack into a buffer allocated once up front. A scheduler tags it's thread
+// with the tenant and extension of the task it is running, so that time spent inside
+// extensions can be told apart even though they are separate objects. When the profiler is
+// stopped, the stacks are symbolized from the symbol tables of the objects they point into and
+// folded into one line per distinct stack, which flamegraph.pl and inferno take as is.
+//
+// Nothing done inside the signal handler allocates or takes a lock. glibc's backtrace() is
+// called once when the profiler starts, so that it is already loaded when the handler needs it.
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::Read;
+use std::mem;
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{compiler_fence, AtomicBool, AtomicUsize, Ordering};
+use std::sync::atomic::{ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+use std::sync::{Once, ONCE_INIT};
+
+use super::common::TenantId;
+
+use libc;
+
+/// The number of samples taken per second of CPU time when a rate is not asked for. Slightly
+/// off a round number, so that sampling does not run in lockstep with periodic work.
+pub const DEFAULT_HZ: u32 = 99;
+
+/// The largest number of samples that can be taken per second of CPU time.
+pub const MAX_HZ: u32 = 1000;
+
+/// The deepest stack that is recorded. Frames beyond this, closest to the root, are cut off.
+const MAX_DEPTH: usize = 64;
+
+/// The largest number of samples that are kept between starting and stopping the profiler.
+/// Samples taken once this many were are dropped.
+const MAX_SAMPLES: usize = 32768;
+
+/// The number of slots threads are tagged in, by thread identifier.
+const MAX_LABELS: usize = 256;
+
+/// The number of bytes of an extension's name that a tag carries.
+const LABEL_LEN: usize = 32;
+
+/// The frames at the top of every recorded stack that belong to the signal handler and the
+/// kernel's signal trampoline, rather than to the thread that was interrupted.
+const SKIP: usize = 2;
+
+// The interval timer that counts down CPU time consumed by the process.
+const ITIMER_PROF: libc::c_int = 2;
+
+// ELF constants needed to read symbol tables.
+const SHT_SYMTAB: u64 = 2;
+const SHT_DYNSYM: u64 = 11;
+const STT_FUNC: u64 = 2;
+const PT_LOAD: u64 = 1;
+
+#[repr(C)]
+struct Itimerval {
+    it_interval: libc::timeval,
+    it_value: libc::timeval,
+}
+
+extern "C" {
+    fn backtrace(buffer: *mut *mut libc::c_void, size: libc::c_int) -> libc::c_int;
+
+    fn setitimer(which: libc::c_int, new: *const Itimerval, old: *mut Itimerval) -> libc::c_int;
+}
+
+// A stack recorded by the signal handler.
+struct Sample {
+    // The number of frames recorded. Written last, so a sample is only complete once this is
+    // non-zero.
+    depth: AtomicUsize,
+
+    // The thread that was interrupted.
+    tid: usize,
+
+    // The tenant of the task the thread was running plus one, or zero if it was not running one,
+    // and the first `len` bytes of the name of the task.
+    tenant: usize,
+    len: usize,
+    name: [u8; LABEL_LEN],
+
+    // Return addresses, innermost first.
+    frames: [usize; MAX_DEPTH],
+}
+
+// The tag of a thread, written only by the thread itself.
+struct Label {
+    // The thread the tag belongs to, or zero while it is written to or unused.
+    tid: AtomicUsize,
+
+    // The same as on a Sample.
+    tenant: usize,
+    len: usize,
+    name: [u8; LABEL_LEN],
+}
+
+/// A stack after symbolization, ready to be folded.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stack {
+    /// The name of the thread the stack was sampled on.
+    pub thread: String,
+
+    /// The tenant and the name of the task the thread was running, if it was running one.
+    pub task: Option<(TenantId, String)>,
+
+    /// The name of each function on the stack, innermost first.
+    pub frames: Vec<String>,
+}
+
+/// Set while the profiler is running.
+static ACTIVE: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// The index of the next sample to take.
+static NEXT: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The number of samples dropped because the buffer was full.
+static DROPPED: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Guards against the profiler being started or stopped by two threads at once.
+static BUSY: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Allocates the buffers and installs the signal handler exactly once.
+static INIT: Once = ONCE_INIT;
+
+/// MAX_SAMPLES samples followed by MAX_LABELS tags. Allocated on first use and never freed, as
+/// a signal handler could be writing to it at any time.
+static mut SAMPLES: *mut Sample = 0 as *mut Sample;
+static mut LABELS: *mut Label = 0 as *mut Label;
+
+thread_local! {
+    // The identifier of this thread, or zero if it has not been looked up yet.
+    static TID: Cell<usize> = Cell::new(0);
+}
+
+// Returns the identifier the kernel knows the calling thread by. Safe to call from a signal
+// handler.
+fn gettid() -> usize {
+    unsafe { libc::syscall(libc::SYS_gettid) as usize }
+}
+
+// Allocates the buffers, and installs the signal handler.
+fn init() {
+    INIT.call_once(|| unsafe {
+        let mut samples: Vec<Sample> = Vec::with_capacity(MAX_SAMPLES);
+        for _ in 0..MAX_SAMPLES {
+            samples.push(mem::zeroed());
+        }
+        SAMPLES = Box::into_raw(samples.into_boxed_slice()) as *mut Sample;
+
+        let mut labels: Vec<Label> = Vec::with_capacity(MAX_LABELS);
+        for _ in 0..MAX_LABELS {
+            labels.push(mem::zeroed());
+        }
+        LABELS = Box::into_raw(labels.into_boxed_slice()) as *mut Label;
+
+        // Loads the unwinder backtrace() depends on, which is not safe within the handler.
+        let mut frames = [ptr::null_mut(); 4];
+        backtrace(frames.as_mut_ptr(), frames.len() as libc::c_int);
+
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = on_sigprof as usize;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(libc::SIGPROF, &action, ptr::null_mut()) != 0 {
+            warn!("Failed to install the profiler's signal handler");
+        }
+    });
+}
+
+// Records the stack of the interrupted thread.
+extern "C" fn on_sigprof(_signal: libc::c_int) {
+    if !ACTIVE.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let index = NEXT.fetch_add(1, Ordering::Relaxed);
+    if index >= MAX_SAMPLES {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+
+    unsafe {
+        let errno = *libc::__errno_location();
+
+        let tid = gettid();
+        let sample = &mut *SAMPLES.add(index);
+        let label = &*LABELS.add(tid % MAX_LABELS);
+
+        sample.tid = tid;
+        sample.tenant = 0;
+        sample.len = 0;
+        if label.tid.load(Ordering::Relaxed) == tid {
+            sample.tenant = label.tenant;
+            sample.len = label.len;
+            sample.name = label.name;
+        }
+
+        let frames = sample.frames.as_mut_ptr() as *mut *mut libc::c_void;
+        let depth = backtrace(frames, MAX_DEPTH as libc::c_int);
+        sample.depth.store(depth.max(1) as usize, Ordering::Release);
+
+        *libc::__errno_location() = errno;
+    }
+}
+
+// Arms the timer to fire every so many microseconds of CPU time, or disarms it if zero.
+fn arm(interval_us: u32) -> bool {
+    let interval = libc::timeval {
+        tv_sec: (interval_us / 1000000) as libc::time_t,
+        tv_usec: (interval_us % 1000000) as libc::suseconds_t,
+    };
+    let timer = Itimerval {
+        it_interval: interval,
+        it_value: interval,
+    };
+
+    unsafe { setitimer(ITIMER_PROF, &timer, ptr::null_mut()) == 0 }
+}
+
+/// Returns true if the profiler is running. Lets schedulers skip tagging their threads when it
+/// is not.
+#[inline]
+pub fn active() -> bool {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+/// Starts the profiler.
+///
+/// # Arguments
+///
+/// * `hz`: The number of samples to take per second of CPU time consumed by the server. Zero
+///         for `DEFAULT_HZ`, capped at `MAX_HZ`.
+///
+/// # Return
+///
+/// False if the profiler was already running, or the timer could not be armed.
+pub fn start(hz: u32) -> bool {
+    if BUSY.swap(true, Ordering::Acquire) {
+        return false;
+    }
+
+    let started = !active() && {
+        init();
+
+        // Samples left over from the last run are cleared, so that they are not mistaken for
+        // ones taken during this one.
+        unsafe {
+            let taken = NEXT.load(Ordering::Relaxed).min(MAX_SAMPLES);
+            for index in 0..taken {
+                (*SAMPLES.add(index)).depth.store(0, Ordering::Relaxed);
+            }
+        }
+        NEXT.store(0, Ordering::Relaxed);
+        DROPPED.store(0, Ordering::Relaxed);
+
+        let hz = if hz == 0 { DEFAULT_HZ } else { hz.min(MAX_HZ) };
+        ACTIVE.store(true, Ordering::SeqCst);
+        if arm(1000000 / hz) {
+            info!("Started the profiler at {} samples per second", hz);
+            true
+        } else {
+            ACTIVE.store(false, Ordering::SeqCst);
+            warn!("Failed to arm the profiler's timer");
+            false
+        }
+    };
+
+    BUSY.store(false, Ordering::Release);
+    started
+}
+
+/// Stops the profiler.
+///
+/// # Return
+///
+/// The samples taken since the profiler was started, folded into one line per distinct stack,
+/// or None if the profiler was not running.
+pub fn stop() -> Option<String> {
+    if BUSY.swap(true, Ordering::Acquire) {
+        return None;
+    }
+
+    let folded = if active() {
+        arm(0);
+        ACTIVE.store(false, Ordering::SeqCst);
+
+        let dropped = DROPPED.load(Ordering::Relaxed);
+        if dropped > 0 {
+            warn!("The profiler dropped {} samples, it's buffer was full", dropped);
+        }
+
+        Some(fold(&symbolize()))
+    } else {
+        None
+    };
+
+    BUSY.store(false, Ordering::Release);
+    folded
+}
+
+/// Tags the calling thread with the task it is about to run, so that samples taken while it
+/// runs are attributed to it. Only needs to be called while the profiler is running.
+///
+/// # Arguments
+///
+/// * `tenant`: The tenant the task runs on behalf of, if any.
+/// * `name`:   The name of the extension the task runs, or a description of it's work.
+pub fn enter(tenant: Option<TenantId>, name: &str) {
+    let tid = TID.with(|tid| {
+        if tid.get() == 0 {
+            tid.set(gettid());
+        }
+        tid.get()
+    });
+
+    unsafe {
+        if LABELS.is_null() {
+            return;
+        }
+
+        // The handler only reads a tag on the thread that owns it, so it only has to be kept
+        // from seeing one that is half written.
+        let label = &mut *LABELS.add(tid % MAX_LABELS);
+        label.tid.store(0, Ordering::Relaxed);
+        compiler_fence(Ordering::SeqCst);
+
+        let len = name.len().min(LABEL_LEN);
+        label.tenant = tenant.map_or(0, |tenant| tenant as usize + 1);
+        label.len = len;
+        label.name[..len].copy_from_slice(&name.as_bytes()[..len]);
+
+        compiler_fence(Ordering::SeqCst);
+        label.tid.store(tid, Ordering::Relaxed);
+    }
+}
+
+/// Removes the tag put on the calling thread by `enter()`.
+pub fn leave() {
+    let tid = TID.with(|tid| tid.get());
+    unsafe {
+        if tid != 0 && !LABELS.is_null() {
+            let label = &*LABELS.add(tid % MAX_LABELS);
+            if label.tid.load(Ordering::Relaxed) == tid {
+                label.tid.store(0, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+// Symbolizes every complete sample taken since the profiler was last started.
+fn symbolize() -> Vec<Stack> {
+    let mut resolver = Resolver::new();
+    let mut threads: HashMap<usize, String> = HashMap::new();
+    let mut stacks = Vec::new();
+
+    let taken = NEXT.load(Ordering::Relaxed).min(MAX_SAMPLES);
+    for index in 0..taken {
+        let sample = unsafe { &*SAMPLES.add(index) };
+        let depth = sample.depth.load(Ordering::Acquire);
+        if depth <= SKIP {
+            continue;
+        }
+
+        let thread = threads
+            .entry(sample.tid)
+            .or_insert_with(|| thread_name(sample.tid))
+            .clone();
+
+        let task = if sample.tenant > 0 {
+            let name = String::from_utf8_lossy(&sample.name[..sample.len]).into_owned();
+            Some(((sample.tenant - 1) as TenantId, name))
+        } else {
+            None
+        };
+
+        // Every frame but the interrupted one holds a return address, which points just past
+        // the call it returns from.
+        let frames = sample.frames[SKIP..depth]
+            .iter()
+            .enumerate()
+            .map(|(i, &addr)| resolver.resolve(if i == 0 { addr } else { addr - 1 }))
+            .collect();
+
+        stacks.push(Stack {
+            thread: thread,
+            task: task,
+            frames: frames,
+        });
+    }
+
+    stacks
+}
+
+// Returns the name of a thread of this process, or the thread's identifier if it has none.
+fn thread_name(tid: usize) -> String {
+    let mut name = String::new();
+    let path = format!("/proc/self/task/{}/comm", tid);
+    match File::open(&path).and_then(|mut file| file.read_to_string(&mut name)) {
+        Ok(_) if name.trim().len() > 0 => String::from(name.trim()),
+        _ => format!("thread-{}", tid),
+    }
+}
+
+/// Folds stacks into the format flamegraph.pl and inferno take: one line per distinct stack,
+/// with the frames from the root down separated by semicolons, followed by the number of times
+/// the stack was sampled. The thread a stack was sampled on forms it's root, followed by the
+/// tenant and task it was running, if any.
+///
+/// # Arguments
+///
+/// * `stacks`: The stacks to be folded.
+///
+/// # Return
+///
+/// The folded stacks, sorted.
+pub fn fold(stacks: &[Stack]) -> String {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for stack in stacks.iter() {
+        let mut line = frame(&stack.thread);
+        if let Some((tenant, ref name)) = stack.task {
+            line.push_str(&format!(";tenant {};{}", tenant, frame(name)));
+        }
+        for name in stack.frames.iter().rev() {
+            line.push(';');
+            line.push_str(&frame(name));
+        }
+        *counts.entry(line).or_insert(0) += 1;
+    }
+
+    let mut lines: Vec<(String, usize)> = counts.into_iter().collect();
+    lines.sort();
+
+    let mut folded = String::new();
+    for (line, count) in lines {
+        folded.push_str(&format!("{} {}\n", line, count));
+    }
+    folded
+}
+
+// Makes a name safe to use as a frame of a folded stack, which cannot contain semicolons or
+// line breaks.
+fn frame(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            ';' => ':',
+            '\n' | '\r' => ' ',
+            c => c,
+        })
+        .collect()
+}
+
+// Resolves addresses to the names of the functions they fall in.
+struct Resolver {
+    // The address the server's executable is mapped at.
+    exe: usize,
+
+    // The functions in every object seen so far, by the address it is mapped at.
+    objects: HashMap<usize, Option<Symbols>>,
+
+    // Names already resolved, by address.
+    names: HashMap<usize, String>,
+}
+
+// Implementation of methods on Resolver.
+impl Resolver {
+    // Returns a resolver with nothing resolved yet.
+    fn new() -> Resolver {
+        let mut info: libc::Dl_info = unsafe { mem::zeroed() };
+        let exe = fold as usize as *const libc::c_void;
+        let exe = if unsafe { libc::dladdr(exe, &mut info) } != 0 {
+            info.dli_fbase as usize
+        } else {
+            0
+        };
+
+        Resolver {
+            exe: exe,
+            objects: HashMap::new(),
+            names: HashMap::new(),
+        }
+    }
+
+    // Returns the name of the function an address falls in. Functions that cannot be found on
+    // the symbol table of their object are named after the object.
+    fn resolve(&mut self, addr: usize) -> String {
+        if let Some(name) = self.names.get(&addr) {
+            return name.clone();
+        }
+
+        let mut info: libc::Dl_info = unsafe { mem::zeroed() };
+        let name = if unsafe { libc::dladdr(addr as *const libc::c_void, &mut info) } == 0 {
+            String::from("[unknown]")
+        } else {
+            let base = info.dli_fbase as usize;
+            let path = if base == self.exe || info.dli_fname.is_null() {
+                String::from("/proc/self/exe")
+            } else {
+                unsafe { CStr::from_ptr(info.dli_fname) }
+                    .to_string_lossy()
+                    .into_owned()
+            };
+
+            let symbols = self
+                .objects
+                .entry(base)
+                .or_insert_with(|| Symbols::load(&path));
+            let found = symbols
+                .as_ref()
+                .and_then(|symbols| symbols.lookup((addr - base) as u64));
+
+            match found {
+                Some(name) => demangle(name),
+                None if !info.dli_sname.is_null() => {
+                    demangle(&unsafe { CStr::from_ptr(info.dli_sname) }.to_string_lossy())
+                }
+                None if base == self.exe => String::from("[server]"),
+                None => {
+                    let file = Path::new(&path).file_name().map(|f| f.to_string_lossy());
+                    format!("[{}]", file.unwrap_or_default())
+                }
+            }
+        };
+
+        self.names.insert(addr, name.clone());
+        name
+    }
+}
+
+// The functions on the symbol table of an ELF object.
+struct Symbols {
+    // The address, relative to the start of the object's mapping, that the object's symbol
+    // values are relative to.
+    bias: u64,
+
+    // The start, end and name of every function, ordered by start.
+    funcs: Vec<(u64, u64, String)>,
+}
+
+// Implementation of methods on Symbols.
+impl Symbols {
+    // Reads the functions off an object's symbol table, falling back to it's dynamic symbol
+    // table if it was stripped. Returns None if the object could not be read or parsed.
+    fn load(path: &str) -> Option<Symbols> {
+        let mut elf = Vec::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_end(&mut elf))
+            .ok()?;
+        Symbols::parse(&elf)
+    }
+
+    // Parses the functions out of the contents of a 64 bit little endian ELF object.
+    fn parse(elf: &[u8]) -> Option<Symbols> {
+        if elf.len() < 64 || &elf[0..4] != b"\x7fELF" || elf[4] != 2 || elf[5] != 1 {
+            return None;
+        }
+
+        // Symbol values are virtual addresses, which the first loadable segment maps to the start
+        // of the file.
+        let (phoff, phsize, phnum) = (le(elf, 0x20, 8)?, le(elf, 0x36, 2)?, le(elf, 0x38, 2)?);
+        let mut bias = 0;
+        for i in 0..phnum {
+            let ph = (phoff + i * phsize) as usize;
+            if le(elf, ph, 4)? == PT_LOAD {
+                bias = le(elf, ph + 0x10, 8)?.wrapping_sub(le(elf, ph + 0x08, 8)?);
+                break;
+            }
+        }
+
+        let (shoff, shsize, shnum) = (le(elf, 0x28, 8)?, le(elf, 0x3a, 2)?, le(elf, 0x3c, 2)?);
+        let section = |i: u64| (shoff + i * shsize) as usize;
+        let find = |kind: u64| (0..shnum).find(|&i| le(elf, section(i) + 4, 4) == Some(kind));
+        let symtab = section(find(SHT_SYMTAB).or_else(|| find(SHT_DYNSYM))?);
+        let strtab = section(le(elf, symtab + 0x28, 4)?);
+
+        let (symoff, symlen) = (le(elf, symtab + 0x18, 8)?, le(elf, symtab + 0x20, 8)?);
+        let (stroff, strlen) = (le(elf, strtab + 0x18, 8)?, le(elf, strtab + 0x20, 8)?);
+        let strings = elf.get(stroff as usize..(stroff + strlen) as usize)?;
+
+        let mut funcs = Vec::new();
+        for sym in (symoff..symoff + symlen).step_by(24) {
+            let sym = sym as usize;
+            let info = le(elf, sym + 4, 1)?;
+            let (value, size) = (le(elf, sym + 8, 8)?, le(elf, sym + 16, 8)?);
+            if info & 0xf != STT_FUNC || value == 0 {
+                continue;
+            }
+
+            let name = &strings[(le(elf, sym, 4)? as usize).min(strings.len())..];
+            let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+            funcs.push((value, value + size.max(1), String::from_utf8_lossy(name).into_owned()));
+        }
+        funcs.sort();
+
+        Some(Symbols {
+            bias: bias,
+            funcs: funcs,
+        })
+    }
+
+    // Returns the name of the function an offset into the object's mapping falls in.
+    fn lookup(&self, offset: u64) -> Option<&str> {
+        let value = offset.wrapping_add(self.bias);
+        let i = match self.funcs.binary_search_by(|f| f.0.cmp(&value)) {
+            Ok(i) => i,
+            Err(0) => return None,
+            Err(i) => i - 1,
+        };
+
+        let (_, end, ref name) = self.funcs[i];
+        if value < end {
+            Some(name)
+        } else {
+            None
+        }
+    }
+}
+
+// Reads a little endian integer of upto 8 bytes at an offset into a buffer.
+fn le(buf: &[u8], off: usize, len: usize) -> Option<u64> {
+    buf.get(off..off + len)
+        .map(|bytes| bytes.iter().rev().fold(0u64, |acc, &b| (acc << 8) | b as u64))
+}
+
+/// Demangles a Rust symbol in the legacy mangling scheme, dropping the hash at it's end. Other
+/// symbols are returned as is.
+///
+/// # Arguments
+///
+/// * `symbol`: The mangled symbol.
+///
+/// # Return
+///
+/// The path of the function, such as `db::sched::RoundRobin::poll`.
+pub fn demangle(symbol: &str) -> String {
+    let mangled = if symbol.starts_with("_ZN") {
+        &symbol[3..]
+    } else if symbol.starts_with("__ZN") {
+        &symbol[4..]
+    } else {
+        return String::from(symbol);
+    };
+
+    let mut parts: Vec<String> = Vec::new();
+    let mut rest = mangled;
+    while !rest.starts_with('E') {
+        let digits = rest.chars().take_while(|c| c.is_ascii_digit()).count();
+        let len: usize = match rest[..digits].parse() {
+            Ok(len) if digits > 0 && rest.len() >= digits + len => len,
+            _ => return String::from(symbol),
+        };
+
+        let part = &rest[digits..digits + len];
+        rest = &rest[digits + len..];
+        parts.push(unescape(part));
+    }
+
+    // The last part is a hash of the function's crate and signature, which only gets in the
+    // way of reading a profile.
+    let hashed = parts.last().map_or(false, |last| {
+        last.len() == 17 && last.starts_with('h') && last[1..].chars().all(|c| c.is_digit(16))
+    });
+    if hashed {
+        parts.pop();
+    }
+
+    parts.join("::")
+}
+
+// Replaces the escape sequences on a part of a legacy Rust symbol with the characters they
+// stand for.
+fn unescape(part: &str) -> String {
+    let part = if part.starts_with("_$") { &part[1..] } else { part };
+
+    let mut out = String::new();
+    let mut rest = part;
+    while !rest.is_empty() {
+        if rest.starts_with("..") {
+            out.push_str("::");
+            rest = &rest[2..];
+            continue;
+        }
+
+        if rest.starts_with('$') {
+            if let Some(end) = rest[1..].find('$') {
+                let escape = &rest[1..end + 1];
+                let c = match escape {
+                    "SP" => Some('@'),
+                    "BP" => Some('*'),
+                    "RF" => Some('&'),
+                    "LT" => Some('<'),
+                    "GT" => Some('>'),
+                    "LP" => Some('('),
+                    "RP" => Some(')'),
+                    "C" => Some(','),
+                    _ if escape.starts_with('u') => u32::from_str_radix(&escape[1..], 16)
+                        .ok()
+                        .and_then(::std::char::from_u32),
+                    _ => None,
+                };
+
+                if let Some(c) = c {
+                    out.push(c);
+                    rest = &rest[end + 2..];
+                    continue;
+                }
+            }
+        }
+
+        let c = rest.chars().next().unwrap();
+        out.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Tests that legacy Rust symbols are demangled without their hash, and that other symbols
+    // are left alone.
+    #[test]
+    fn test_demangle() {
+        assert_eq!(
+            "db::sched::RoundRobin::poll",
+            demangle("_ZN2db5sched10RoundRobin4poll17h0123456789abcdefE")
+        );
+        let symbol = concat!(
+            "_ZN53_$LT$db..native..Native$u20$as$u20$db..task..Task$GT$",
+            "3run17hfedcba9876543210E"
+        );
+        assert_eq!("<db::native::Native as db::task::Task>::run", demangle(symbol));
+        assert_eq!("rte_eth_rx_burst", demangle("rte_eth_rx_burst"));
+        assert_eq!("_ZN3foo", demangle("_ZN3foo"));
+    }
+
+    // Tests that identical stacks are counted once, that frames run from the root down, and that
+    // the task a thread was running sits between the thread and it's frames.
+    #[test]
+    fn test_fold() {
+        let stack = |task: Option<(TenantId, &str)>, frames: &[&str]| Stack {
+            thread: String::from("sched-1"),
+            task: task.map(|(tenant, name)| (tenant, String::from(name))),
+            frames: frames.iter().map(|f| String::from(*f)).collect(),
+        };
+
+        let stacks = vec![
+            stack(None, &["poll", "main"]),
+            stack(Some((3, "get")), &["init", "poll", "main"]),
+            stack(None, &["poll", "main"]),
+            stack(None, &["a;b", "main"]),
+        ];
+
+        assert_eq!(
+            "sched-1;main;a:b 1\n\
+             sched-1;main;poll 2\n\
+             sched-1;tenant 3;get;main;poll;init 1\n",
+            fold(&stacks)
+        );
+        assert_eq!("", fold(&[]));
+    }
+
+    // Tests that addresses in the test's own executable resolve to the functions they fall in.
+    #[test]
+    fn test_resolve() {
+        let mut resolver = Resolver::new();
+        assert_eq!("db::profile::demangle", resolver.resolve(demangle as usize));
+        assert_eq!("db::profile::demangle", resolver.resolve(demangle as usize + 1));
+        assert_eq!("db::profile::fold", resolver.resolve(fold as usize));
+
+        assert!(Symbols::parse(b"not an elf at all, but long enough to be one").is_none());
+    }
+}
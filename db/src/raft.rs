@@ -0,0 +1,2061 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+
+// Raft-replicated tables. Tables marked in the config are replicated across three servers with
+// Raft, for tenants that need writes to survive the loss of any one server. A single Raft group
+// covers every marked table. It's log carries the same records primary-backup replication ships
+// (see `replica::encode()`), and committed entries are applied through the same path a standby
+// applies shipped writes through.
+//
+// Writes to marked tables are only accepted by the leader, which appends them to it's log and
+// holds the request's task back until the entry is committed and applied. Reads of marked tables
+// are only served by the leader, straight from it's tables and without a round of heartbeats, so
+// a leader cut off from the other two can serve stale reads until it notices, which takes upto an
+// election timeout.
+//
+// The Raft state machine is driven by a task on one of the schedulers, which exchanges messages
+// with the other servers over a non-blocking UDP socket and ticks the election and heartbeat
+// timers, instead of by a thread of it's own. Every datagram carries an HMAC-SHA256 tag over the
+// node it is destined for and the message, keyed by a secret the three servers share, and is
+// dropped unless it came from the address of the node it claims to be from and the tag checks
+// out. Raft copes with messages that are duplicated or delayed, so one replayed by someone who
+// captured it changes nothing.
+//
+// A node's term, the vote it cast in that term, and it's log are written to a file in `raft_dir`
+// (see `Disk`) and synced before the node answers a vote or an append, or counts an entry it
+// appended as a leader towards a majority. A server that restarts comes back with them, so it
+// never votes twice in a term nor forgets an entry it acknowledged, and only catches up on the
+// entries it missed while it was down. Entries are applied to the tables again from the start of
+// the log once they are known to be committed. The group survives any one server failing or
+// restarting at a time, but not two at once.
+//
+// Values written by a tenant with a key are sealed with it when they are appended to the log,
+// and so are never in the clear in the file or in a datagram. They are opened as entries are
+// applied, which means every server in the group must be handed the tenant's key. An entry of a
+// tenant whose key this server does not know yet, such as after a restart, waits along with every
+// committed entry after it until the key is handed to it.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, UdpSocket};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use std::sync::{Arc, Once, ONCE_INIT};
+
+use super::common::{TableId, TenantId};
+use super::counters::{self, Counter};
+use super::crypt::Key;
+use super::cycles;
+use super::master::Master;
+use super::replica;
+use super::snapshot::Fnv;
+use super::task::{Task, TaskPriority, TaskState};
+
+use e2d2::common::EmptyMetadata;
+use e2d2::headers::UdpHeader;
+use e2d2::interface::Packet;
+
+use ring::digest::SHA256;
+use ring::hmac::{self, SigningKey};
+use spin::{Mutex, RwLock};
+
+/// The number of milliseconds between ticks of the election and heartbeat timers.
+pub const TICK_MS: u64 = 10;
+
+/// The smallest number of ticks a follower waits to hear from a leader before it campaigns.
+/// Every node waits a random number of ticks upto twice this, so that elections rarely split.
+const ELECTION_TICKS: u64 = 15;
+
+/// The number of ticks between heartbeats from a leader.
+const HEARTBEAT_TICKS: u64 = 5;
+
+/// The number of bytes of entries an append is cut off at, so that it fits in a datagram. An
+/// append always carries at least one entry if there is one to send.
+const MAX_APPEND_BYTES: usize = 60000;
+
+/// The largest datagram the driver receives.
+const MAX_DATAGRAM: usize = 65536;
+
+/// The number of datagrams the driver receives every time it runs.
+const MAX_RECV: usize = 64;
+
+/// The number of bytes in the tag that ends every datagram.
+pub const TAG_LEN: usize = 32;
+
+/// The number of bytes in the header of a record in a node's file: the kind of record (u8), the
+/// length of the rest of the record (u32) and the FNV-1a hash (u64) of both and of the rest,
+/// little endian.
+const RECORD_HDR_LEN: usize = 13;
+
+/// A record of a node's term and vote: the term (u64), and the node voted for (u8, 0xff if none).
+const RECORD_VOTE: u8 = 1;
+
+/// A record of entries: the index of the first of them (u64), their count (u32), and every
+/// entry's term (u64), length (u32) and data. The log is cut before the first entry, so a record
+/// with no entries only cuts it.
+const RECORD_LOG: u8 = 2;
+
+/// Set in the operation of the record in an entry if it's value is sealed with the key of the
+/// tenant owning it's table (see `crypt::Key::seal()`).
+pub const OP_SEALED: u8 = 0x80;
+
+/// An entry in the log.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    /// The term of the leader that appended the entry.
+    pub term: u64,
+
+    /// A record as encoded by `replica::encode()`, with OP_SEALED set in it's operation if it's
+    /// value is sealed. Empty for the entry a leader appends when it is elected, which lets it
+    /// commit the entries of earlier terms.
+    pub data: Vec<u8>,
+}
+
+/// A message exchanged between nodes.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+    /// A candidate asking for a vote.
+    Vote {
+        term: u64,
+        last_index: u64,
+        last_term: u64,
+    },
+
+    /// The answer to a `Vote`.
+    VoteReply { term: u64, granted: bool },
+
+    /// A leader appending entries after `prev_index`, or only heartbeating if there are none.
+    Append {
+        term: u64,
+        prev_index: u64,
+        prev_term: u64,
+        commit: u64,
+        entries: Vec<Entry>,
+    },
+
+    /// The answer to an `Append`. `last` is the index of the last entry the follower now
+    /// matches the leader on if it succeeded, and the index the leader should retry after if
+    /// it didn't.
+    AppendReply { term: u64, success: bool, last: u64 },
+}
+
+// Implementation of methods on Message.
+impl Message {
+    // Returns the term of the node that sent the message.
+    fn term(&self) -> u64 {
+        match *self {
+            Message::Vote { term, .. } => term,
+            Message::VoteReply { term, .. } => term,
+            Message::Append { term, .. } => term,
+            Message::AppendReply { term, .. } => term,
+        }
+    }
+}
+
+/// The roles a node can be in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// What became of an entry a leader appended.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    /// The entry is neither committed nor lost yet.
+    Pending,
+
+    /// The entry is committed, and was applied on this node.
+    Committed,
+
+    /// The entry was replaced by that of a later leader, and will never be committed.
+    Lost,
+}
+
+/// A node of a Raft group.
+pub struct Node {
+    // The identifier of this node, and the number of nodes in the group. Nodes are identified by
+    // their index into the list of every node's address.
+    id: usize,
+    n: usize,
+
+    // The latest term this node has seen, the node it voted for in that term, it's role, and the
+    // leader of the term if it has heard from one.
+    term: u64,
+    voted_for: Option<usize>,
+    role: Role,
+    leader: Option<usize>,
+
+    // The log. The entry at index `i` is `log[i - 1]`; indexes start at 1.
+    log: Vec<Entry>,
+
+    // The term and vote last written to disk, the index of the first entry that was appended or
+    // replaced since entries were last written, and the number of entries on disk.
+    saved_term: u64,
+    saved_vote: Option<usize>,
+    unsaved: u64,
+    durable: u64,
+
+    // The index of the last committed entry, of the last entry handed out to be applied, and of
+    // the last entry applied to the tables.
+    commit: u64,
+    taken: u64,
+    applied: u64,
+
+    // On a candidate, the nodes that voted for it.
+    votes: Vec<bool>,
+
+    // On a leader, the index of the next entry to send every node, and of the last entry every
+    // node is known to match the leader on.
+    next: Vec<u64>,
+    matched: Vec<u64>,
+
+    // The ticks since the timer was last reset, and the ticks after which it fires.
+    elapsed: u64,
+    timeout: u64,
+
+    // The state of the random number generator election timeouts are drawn from.
+    seed: u64,
+
+    // Set if entries were appended that were not sent out yet.
+    dirty: bool,
+
+    // Messages waiting to be sent, along with the node they are destined for.
+    outbox: Vec<(usize, Message)>,
+}
+
+/// A node's term, vote and log, as kept on disk.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Saved {
+    /// The latest term the node had seen.
+    pub term: u64,
+
+    /// The node it voted for in that term, if any.
+    pub voted_for: Option<usize>,
+
+    /// The log.
+    pub log: Vec<Entry>,
+}
+
+/// What changed on a node since it was last written to disk. Returned by `Node::unsaved()`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Unsaved {
+    /// The node's term and vote, if either changed.
+    pub vote: Option<(u64, Option<usize>)>,
+
+    /// The index the entries below start at. The log on disk is cut before it.
+    pub first: u64,
+
+    /// The entries appended or replaced, which may be none if the log was only cut.
+    pub entries: Vec<Entry>,
+}
+
+// Implementation of methods on Node.
+impl Node {
+    /// Returns a follower with an empty log.
+    ///
+    /// # Arguments
+    ///
+    /// * `id`:   The identifier of the node.
+    /// * `n`:    The number of nodes in the group.
+    /// * `seed`: Seeds the election timeouts, and should differ among nodes.
+    pub fn new(id: usize, n: usize, seed: u64) -> Node {
+        Node::restore(id, n, seed, Saved::default())
+    }
+
+    /// Returns a follower that picks up from the term, vote and log it had before a restart.
+    ///
+    /// # Arguments
+    ///
+    /// * `id`:    The identifier of the node.
+    /// * `n`:     The number of nodes in the group.
+    /// * `seed`:  Seeds the election timeouts, and should differ among nodes.
+    /// * `saved`: What the node wrote to disk before it went down.
+    pub fn restore(id: usize, n: usize, seed: u64, saved: Saved) -> Node {
+        let durable = saved.log.len() as u64;
+        let mut node = Node {
+            id: id,
+            n: n,
+            term: saved.term,
+            voted_for: saved.voted_for,
+            role: Role::Follower,
+            leader: None,
+            log: saved.log,
+            saved_term: saved.term,
+            saved_vote: saved.voted_for,
+            unsaved: durable + 1,
+            durable: durable,
+            commit: 0,
+            taken: 0,
+            applied: 0,
+            votes: vec![false; n],
+            next: vec![1; n],
+            matched: vec![0; n],
+            elapsed: 0,
+            timeout: 0,
+            seed: seed | 1,
+            dirty: false,
+            outbox: Vec::new(),
+        };
+        node.reset_timer();
+        node
+    }
+
+    /// Returns the role of the node.
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// Returns the latest term the node has seen.
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    /// Returns the leader of the latest term, if the node heard from one.
+    pub fn leader(&self) -> Option<usize> {
+        self.leader
+    }
+
+    /// Returns the index of the last committed entry.
+    pub fn commit(&self) -> u64 {
+        self.commit
+    }
+
+    /// Returns the index of the last entry in the log.
+    pub fn last_index(&self) -> u64 {
+        self.log.len() as u64
+    }
+
+    // Draws the next number off a xorshift generator.
+    fn rand(&mut self) -> u64 {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+        self.seed
+    }
+
+    // Restarts the timer. A follower or candidate draws a fresh election timeout.
+    fn reset_timer(&mut self) {
+        self.elapsed = 0;
+        self.timeout = match self.role {
+            Role::Leader => HEARTBEAT_TICKS,
+            _ => ELECTION_TICKS + self.rand() % ELECTION_TICKS,
+        };
+    }
+
+    // Returns the term of the entry at an index, 0 for index 0, or None if there is no entry.
+    fn term_at(&self, index: u64) -> Option<u64> {
+        match index {
+            0 => Some(0),
+            i => self.log.get(i as usize - 1).map(|entry| entry.term),
+        }
+    }
+
+    // Returns the index and term of the last entry in the log.
+    fn last(&self) -> (u64, u64) {
+        let index = self.last_index();
+        (index, self.term_at(index).unwrap_or(0))
+    }
+
+    // Appends an entry to the log, to be written to disk.
+    fn push(&mut self, entry: Entry) {
+        self.log.push(entry);
+        self.unsaved = self.unsaved.min(self.last_index());
+    }
+
+    // Cuts the log down to it's first `len` entries. The entries after them stay on disk until
+    // the change is written.
+    fn truncate(&mut self, len: u64) {
+        self.log.truncate(len as usize);
+        self.unsaved = self.unsaved.min(len + 1);
+    }
+
+    // Returns the index of the last entry that is on disk along with every entry before it.
+    fn stable(&self) -> u64 {
+        self.durable.min(self.unsaved - 1)
+    }
+
+    // Moves to a later term as a follower.
+    fn observe(&mut self, term: u64) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.leader = None;
+            if self.role != Role::Follower {
+                self.role = Role::Follower;
+                self.reset_timer();
+            }
+        }
+    }
+
+    /// Advances the timers by a tick. A follower or candidate that heard nothing from a leader
+    /// for it's election timeout campaigns, and a leader heartbeats.
+    pub fn tick(&mut self) {
+        self.elapsed += 1;
+        if self.elapsed < self.timeout {
+            return;
+        }
+
+        match self.role {
+            Role::Leader => {
+                self.reset_timer();
+                self.broadcast();
+            }
+            _ => self.campaign(),
+        }
+    }
+
+    // Starts an election for the next term.
+    fn campaign(&mut self) {
+        self.term += 1;
+        self.role = Role::Candidate;
+        self.leader = None;
+        self.voted_for = Some(self.id);
+        self.votes = vec![false; self.n];
+        self.votes[self.id] = true;
+        self.reset_timer();
+
+        let (last_index, last_term) = self.last();
+        let id = self.id;
+        for peer in (0..self.n).filter(|&peer| peer != id) {
+            let vote = Message::Vote {
+                term: self.term,
+                last_index: last_index,
+                last_term: last_term,
+            };
+            self.outbox.push((peer, vote));
+        }
+        self.tally();
+    }
+
+    // Becomes the leader if a majority voted for this node.
+    fn tally(&mut self) {
+        let votes = self.votes.iter().filter(|&&vote| vote).count();
+        if self.role == Role::Candidate && votes > self.n / 2 {
+            self.role = Role::Leader;
+            self.leader = Some(self.id);
+            self.next = vec![self.last_index() + 1; self.n];
+            self.matched = vec![0; self.n];
+
+            // Entries of earlier terms can only be committed along with one of this term. The
+            // leader only counts towards a majority for entries it has on disk.
+            let term = self.term;
+            self.push(Entry {
+                term: term,
+                data: Vec::new(),
+            });
+            self.matched[self.id] = self.stable();
+            self.advance();
+
+            self.reset_timer();
+            self.broadcast();
+        }
+    }
+
+    // Sends every other node the entries it is missing, or a heartbeat if it misses none.
+    fn broadcast(&mut self) {
+        let id = self.id;
+        for peer in (0..self.n).filter(|&peer| peer != id) {
+            self.send_append(peer);
+        }
+        self.dirty = false;
+    }
+
+    // Sends a node the entries from the next one it is expected to need, and assumes it gets
+    // them, so that appends can be pipelined. A node that did not is sent them again once it
+    // answers a later append with a failure.
+    fn send_append(&mut self, peer: usize) {
+        let prev_index = self.next[peer] - 1;
+        let prev_term = self.term_at(prev_index).unwrap_or(0);
+
+        let mut entries = Vec::new();
+        let mut bytes = 0;
+        for entry in self.log[prev_index as usize..].iter() {
+            if entries.len() > 0 && bytes + entry.data.len() > MAX_APPEND_BYTES {
+                break;
+            }
+            bytes += entry.data.len();
+            entries.push(entry.clone());
+        }
+
+        self.next[peer] = prev_index + entries.len() as u64 + 1;
+        let append = Message::Append {
+            term: self.term,
+            prev_index: prev_index,
+            prev_term: prev_term,
+            commit: self.commit,
+            entries: entries,
+        };
+        self.outbox.push((peer, append));
+    }
+
+    // Commits the last entry of this term that a majority of nodes has.
+    fn advance(&mut self) {
+        let mut index = self.last_index();
+        while index > self.commit && self.term_at(index) == Some(self.term) {
+            let count = self.matched.iter().filter(|&&m| m >= index).count();
+            if count > self.n / 2 {
+                self.commit = index;
+                return;
+            }
+            index -= 1;
+        }
+    }
+
+    /// Handles a message from another node.
+    ///
+    /// # Arguments
+    ///
+    /// * `from`: The node the message came from.
+    /// * `msg`:  The message.
+    pub fn step(&mut self, from: usize, msg: Message) {
+        if from >= self.n || from == self.id {
+            return;
+        }
+        self.observe(msg.term());
+
+        match msg {
+            Message::Vote {
+                term,
+                last_index,
+                last_term,
+            } => {
+                let up_to_date = (last_term, last_index) >= (self.last().1, self.last().0);
+                let free = self.voted_for.map_or(true, |voted| voted == from);
+                let granted = term == self.term && up_to_date && free;
+                if granted {
+                    self.voted_for = Some(from);
+                    self.reset_timer();
+                }
+
+                let reply = Message::VoteReply {
+                    term: self.term,
+                    granted: granted,
+                };
+                self.outbox.push((from, reply));
+            }
+
+            Message::VoteReply { term, granted } => {
+                if self.role == Role::Candidate && term == self.term && granted {
+                    self.votes[from] = true;
+                    self.tally();
+                }
+            }
+
+            Message::Append {
+                term,
+                prev_index,
+                prev_term,
+                commit,
+                entries,
+            } => {
+                let reply = self.append(from, term, prev_index, prev_term, commit, entries);
+                self.outbox.push((from, reply));
+            }
+
+            Message::AppendReply {
+                term,
+                success,
+                last,
+            } => {
+                if self.role != Role::Leader || term != self.term {
+                    return;
+                }
+
+                if success {
+                    self.matched[from] = self.matched[from].max(last);
+                    self.next[from] = self.next[from].max(last + 1);
+                    self.advance();
+                } else {
+                    self.next[from] = (last + 1).max(1).min(self.next[from]);
+                    self.send_append(from);
+                }
+            }
+        }
+    }
+
+    // Handles an append from a leader, returning the reply to it.
+    fn append(
+        &mut self,
+        from: usize,
+        term: u64,
+        prev_index: u64,
+        prev_term: u64,
+        commit: u64,
+        entries: Vec<Entry>,
+    ) -> Message {
+        let fail = |term: u64, last: u64| Message::AppendReply {
+            term: term,
+            success: false,
+            last: last,
+        };
+
+        if term < self.term {
+            return fail(self.term, self.last_index());
+        }
+
+        // A candidate that hears from the leader of it's term gives up.
+        self.role = Role::Follower;
+        self.leader = Some(from);
+        self.reset_timer();
+
+        // The leader retries after the last entry this node has, or before the mismatch.
+        if self.term_at(prev_index) != Some(prev_term) {
+            let last = self.last_index().min(prev_index.saturating_sub(1));
+            return fail(self.term, last);
+        }
+
+        // Entries that conflict with the leader's are dropped along with everything after them.
+        // Committed entries never conflict.
+        let mut index = prev_index;
+        for entry in entries.into_iter() {
+            index += 1;
+            match self.term_at(index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => {
+                    self.truncate(index - 1);
+                    self.push(entry);
+                }
+                None => self.push(entry),
+            }
+        }
+
+        if commit > self.commit {
+            self.commit = commit.min(index);
+        }
+
+        Message::AppendReply {
+            term: self.term,
+            success: true,
+            last: index,
+        }
+    }
+
+    /// Appends an entry to the log, if this node is the leader. The entry goes out the next
+    /// time `flush()` is called.
+    ///
+    /// # Arguments
+    ///
+    /// * `data`: The record to be replicated.
+    ///
+    /// # Return
+    ///
+    /// The index and term of the entry, to be passed to `outcome()`. None if this node is not
+    /// the leader.
+    pub fn propose(&mut self, data: Vec<u8>) -> Option<(u64, u64)> {
+        if self.role != Role::Leader {
+            return None;
+        }
+
+        let term = self.term;
+        self.push(Entry {
+            term: term,
+            data: data,
+        });
+        self.dirty = true;
+        Some((self.last_index(), self.term))
+    }
+
+    /// Sends out the entries appended since the last time this was called.
+    pub fn flush(&mut self) {
+        if self.dirty && self.role == Role::Leader {
+            self.broadcast();
+        }
+    }
+
+    /// Returns what became of an entry appended by `propose()`.
+    ///
+    /// # Arguments
+    ///
+    /// * `index`: The index of the entry.
+    /// * `term`:  The term of the entry.
+    pub fn outcome(&self, index: u64, term: u64) -> Outcome {
+        match self.term_at(index) {
+            Some(t) if t == term && self.applied >= index => Outcome::Committed,
+            Some(t) if t == term => Outcome::Pending,
+            Some(_) => Outcome::Lost,
+            None if self.term > term => Outcome::Lost,
+            None => Outcome::Pending,
+        }
+    }
+
+    /// Takes the messages waiting to be sent.
+    pub fn outbox(&mut self) -> Vec<(usize, Message)> {
+        ::std::mem::replace(&mut self.outbox, Vec::new())
+    }
+
+    /// Takes the committed entries that were not handed out to be applied yet. Once they are
+    /// applied, `applied()` must be called with the index of the last of them.
+    ///
+    /// # Return
+    ///
+    /// The index of the first entry, and the entries.
+    pub fn committed(&mut self) -> (u64, Vec<Entry>) {
+        let first = self.taken + 1;
+        let entries = self.log[self.taken as usize..self.commit as usize].to_vec();
+        self.taken = self.commit;
+        (first, entries)
+    }
+
+    /// Notes that every entry upto an index was applied to the tables.
+    pub fn applied(&mut self, index: u64) {
+        self.applied = self.applied.max(index);
+    }
+
+    /// Returns what changed since the node was last written to disk, or None if nothing did.
+    /// Messages the node has to send must not go out before the changes were written and
+    /// `persisted()` was called with them.
+    pub fn unsaved(&self) -> Option<Unsaved> {
+        let vote = match (self.term, self.voted_for) {
+            saved if saved == (self.saved_term, self.saved_vote) => None,
+            current => Some(current),
+        };
+
+        // Entries past the end of the log that are still on disk need cutting.
+        let dirty = self.unsaved <= self.last_index() || self.durable > self.last_index();
+        if vote.is_none() && !dirty {
+            return None;
+        }
+
+        let first = self.unsaved;
+        Some(Unsaved {
+            vote: vote,
+            first: first,
+            entries: self.log[first as usize - 1..].to_vec(),
+        })
+    }
+
+    /// Notes that changes returned by `unsaved()` were written to disk. A leader counts the
+    /// entries towards a majority from here on.
+    ///
+    /// # Arguments
+    ///
+    /// * `saved`: The changes.
+    pub fn persisted(&mut self, saved: &Unsaved) {
+        if let Some((term, voted_for)) = saved.vote {
+            self.saved_term = term;
+            self.saved_vote = voted_for;
+        }
+
+        // Only entries can have been appended since the changes were taken.
+        let last = saved.first + saved.entries.len() as u64 - 1;
+        self.unsaved = last + 1;
+        self.durable = last;
+
+        if self.role == Role::Leader {
+            self.matched[self.id] = self.stable();
+            self.advance();
+        }
+    }
+}
+
+/// Appends a message to a buffer, as a datagram from a node. The datagram starts with the kind
+/// of message (u8), the node it is from (u8) and the sender's term (u64); the rest of the fields
+/// follow in order, little endian, with an append's entries as a count (u32) followed by every
+/// entry's term (u64), length (u32) and data.
+///
+/// # Arguments
+///
+/// * `buf`:  The buffer.
+/// * `from`: The node sending the message.
+/// * `msg`:  The message.
+pub fn encode(buf: &mut Vec<u8>, from: usize, msg: &Message) {
+    fn put(buf: &mut Vec<u8>, val: u64, len: usize) {
+        for byte in 0..len {
+            buf.push((val >> (8 * byte)) as u8);
+        }
+    }
+
+    match *msg {
+        Message::Vote {
+            term,
+            last_index,
+            last_term,
+        } => {
+            buf.extend_from_slice(&[1, from as u8]);
+            put(buf, term, 8);
+            put(buf, last_index, 8);
+            put(buf, last_term, 8);
+        }
+
+        Message::VoteReply { term, granted } => {
+            buf.extend_from_slice(&[2, from as u8]);
+            put(buf, term, 8);
+            buf.push(granted as u8);
+        }
+
+        Message::Append {
+            term,
+            prev_index,
+            prev_term,
+            commit,
+            ref entries,
+        } => {
+            buf.extend_from_slice(&[3, from as u8]);
+            put(buf, term, 8);
+            put(buf, prev_index, 8);
+            put(buf, prev_term, 8);
+            put(buf, commit, 8);
+            put(buf, entries.len() as u64, 4);
+            for entry in entries.iter() {
+                put(buf, entry.term, 8);
+                put(buf, entry.data.len() as u64, 4);
+                buf.extend_from_slice(&entry.data);
+            }
+        }
+
+        Message::AppendReply {
+            term,
+            success,
+            last,
+        } => {
+            buf.extend_from_slice(&[4, from as u8]);
+            put(buf, term, 8);
+            buf.push(success as u8);
+            put(buf, last, 8);
+        }
+    }
+}
+
+// Reads little endian integers off a datagram, failing once it runs out.
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+// Implementation of methods on Reader.
+impl<'a> Reader<'a> {
+    // Reads `len` bytes.
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.buf.len() < len {
+            return None;
+        }
+        let (head, tail) = self.buf.split_at(len);
+        self.buf = tail;
+        Some(head)
+    }
+
+    // Reads a `len` byte integer.
+    fn int(&mut self, len: usize) -> Option<u64> {
+        self.bytes(len)
+            .map(|b| b.iter().rev().fold(0u64, |acc, &byte| (acc << 8) | byte as u64))
+    }
+}
+
+/// Parses a datagram written by `encode()`.
+///
+/// # Arguments
+///
+/// * `buf`: The datagram.
+///
+/// # Return
+///
+/// The node the message is from, and the message. None if the datagram is malformed.
+pub fn decode(buf: &[u8]) -> Option<(usize, Message)> {
+    let mut r = Reader { buf: buf };
+    let kind = r.int(1)?;
+    let from = r.int(1)? as usize;
+    let term = r.int(8)?;
+
+    let msg = match kind {
+        1 => Message::Vote {
+            term: term,
+            last_index: r.int(8)?,
+            last_term: r.int(8)?,
+        },
+
+        2 => Message::VoteReply {
+            term: term,
+            granted: r.int(1)? != 0,
+        },
+
+        3 => {
+            let prev_index = r.int(8)?;
+            let prev_term = r.int(8)?;
+            let commit = r.int(8)?;
+            let count = r.int(4)?;
+
+            let mut entries = Vec::new();
+            for _ in 0..count {
+                let term = r.int(8)?;
+                let len = r.int(4)? as usize;
+                entries.push(Entry {
+                    term: term,
+                    data: r.bytes(len)?.to_vec(),
+                });
+            }
+
+            Message::Append {
+                term: term,
+                prev_index: prev_index,
+                prev_term: prev_term,
+                commit: commit,
+                entries: entries,
+            }
+        }
+
+        4 => Message::AppendReply {
+            term: term,
+            success: r.int(1)? != 0,
+            last: r.int(8)?,
+        },
+
+        _ => return None,
+    };
+
+    match r.buf.len() {
+        0 => Some((from, msg)),
+        _ => None,
+    }
+}
+
+/// Appends changes to a node to a buffer, as records of it's file. The changes to the vote come
+/// before those to the log.
+///
+/// # Arguments
+///
+/// * `buf`:     The buffer.
+/// * `changes`: The changes.
+pub fn encode_unsaved(buf: &mut Vec<u8>, changes: &Unsaved) {
+    fn put(buf: &mut Vec<u8>, val: u64, len: usize) {
+        for byte in 0..len {
+            buf.push((val >> (8 * byte)) as u8);
+        }
+    }
+
+    fn record(buf: &mut Vec<u8>, kind: u8, body: &[u8]) {
+        let mut hdr = vec![kind];
+        put(&mut hdr, body.len() as u64, 4);
+        let mut hash = Fnv::new();
+        hash.update(&hdr);
+        hash.update(body);
+        buf.extend_from_slice(&hdr);
+        put(buf, hash.0, 8);
+        buf.extend_from_slice(body);
+    }
+
+    if let Some((term, voted_for)) = changes.vote {
+        let mut body = Vec::with_capacity(9);
+        put(&mut body, term, 8);
+        body.push(voted_for.map_or(0xff, |node| node as u8));
+        record(buf, RECORD_VOTE, &body);
+    }
+
+    let mut body = Vec::new();
+    put(&mut body, changes.first, 8);
+    put(&mut body, changes.entries.len() as u64, 4);
+    for entry in changes.entries.iter() {
+        put(&mut body, entry.term, 8);
+        put(&mut body, entry.data.len() as u64, 4);
+        body.extend_from_slice(&entry.data);
+    }
+    record(buf, RECORD_LOG, &body);
+}
+
+/// Replays the records of a node's file, in order.
+///
+/// # Arguments
+///
+/// * `buf`: The contents of the file.
+///
+/// # Return
+///
+/// What the records leave the node with, and the number of bytes of whole records they take up.
+/// A record that is cut short or does not match it's hash ends the replay; it was torn by a
+/// crash while it was being written, and the node never acted on it.
+pub fn replay(buf: &[u8]) -> (Saved, usize) {
+    let mut saved = Saved::default();
+    let mut off = 0;
+    while buf.len() - off >= RECORD_HDR_LEN {
+        let mut r = Reader { buf: &buf[off..] };
+        let (kind, len) = match (r.int(1), r.int(4)) {
+            (Some(kind), Some(len)) => (kind as u8, len as usize),
+            _ => break,
+        };
+        let hash = r.int(8).unwrap_or(0);
+        let body = match r.bytes(len) {
+            Some(body) => body,
+            None => break,
+        };
+
+        let mut check = Fnv::new();
+        check.update(&buf[off..off + 5]);
+        check.update(body);
+        if check.0 != hash {
+            break;
+        }
+
+        let mut r = Reader { buf: body };
+        let applied = match kind {
+            RECORD_VOTE => match (r.int(8), r.int(1)) {
+                (Some(term), Some(node)) => {
+                    saved.term = term;
+                    saved.voted_for = if node == 0xff { None } else { Some(node as usize) };
+                    true
+                }
+                _ => false,
+            },
+
+            RECORD_LOG => {
+                let mut replace = || -> Option<()> {
+                    let first = r.int(8)?;
+                    let count = r.int(4)?;
+                    if first == 0 || first - 1 > saved.log.len() as u64 {
+                        return None;
+                    }
+
+                    saved.log.truncate(first as usize - 1);
+                    for _ in 0..count {
+                        let term = r.int(8)?;
+                        let len = r.int(4)? as usize;
+                        saved.log.push(Entry {
+                            term: term,
+                            data: r.bytes(len)?.to_vec(),
+                        });
+                    }
+                    Some(())
+                };
+                replace().is_some()
+            }
+
+            _ => false,
+        };
+
+        // A record that matches it's hash but cannot be made sense of was written by something
+        // else, and the file cannot be trusted past it.
+        if !applied {
+            warn!("Stopped replaying the Raft log at a record of unknown kind {}", kind);
+            break;
+        }
+        off += RECORD_HDR_LEN + len;
+    }
+
+    (saved, off)
+}
+
+/// The file a node's term, vote and log are kept in, `raft-<id>.log` in `raft_dir`. Changes are
+/// appended to it as records (see `encode_unsaved()`) and synced, and the file is rewritten with
+/// a record of each every time the server starts, which drops the entries that were replaced.
+pub struct Disk {
+    // The file, opened for appending.
+    file: File,
+
+    // The number of bytes of whole records in it. A write that fails is cut back to this.
+    len: u64,
+}
+
+// Implementation of methods on Disk.
+impl Disk {
+    /// Opens a node's file, creating it and the directory it is in if they do not exist.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir`: The directory the file is kept in.
+    /// * `id`:  The identifier of the node.
+    ///
+    /// # Return
+    ///
+    /// The file, and what the node had written to it before the server went down.
+    pub fn open(dir: &str, id: usize) -> io::Result<(Disk, Saved)> {
+        fs::create_dir_all(dir)?;
+        let path = Path::new(dir).join(format!("raft-{}.log", id));
+
+        let mut buf = Vec::new();
+        match File::open(&path) {
+            Ok(mut file) => {
+                file.read_to_end(&mut buf)?;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        let (saved, len) = replay(&buf);
+        if len < buf.len() {
+            warn!("Dropped {} bytes torn off the end of {:?}", buf.len() - len, path);
+        }
+
+        // Write the state out afresh, and only then replace the old file with it.
+        let mut fresh = Vec::new();
+        let changes = Unsaved {
+            vote: Some((saved.term, saved.voted_for)),
+            first: 1,
+            entries: saved.log.clone(),
+        };
+        encode_unsaved(&mut fresh, &changes);
+
+        let tmp = Path::new(dir).join(format!("raft-{}.log.tmp", id));
+        {
+            let mut file = File::create(&tmp)?;
+            file.write_all(&fresh)?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, &path)?;
+        File::open(dir)?.sync_all()?;
+
+        let file = OpenOptions::new().append(true).open(&path)?;
+        info!(
+            "Restored Raft term {} and {} entries from {:?}",
+            saved.term,
+            saved.log.len(),
+            path
+        );
+        Ok((
+            Disk {
+                file: file,
+                len: fresh.len() as u64,
+            },
+            saved,
+        ))
+    }
+
+    /// Appends changes to the file and syncs it.
+    ///
+    /// # Arguments
+    ///
+    /// * `changes`: The changes.
+    pub fn write(&mut self, changes: &Unsaved) -> io::Result<()> {
+        let mut buf = Vec::new();
+        encode_unsaved(&mut buf, changes);
+
+        // A file cut short by a failed write would end in a torn record, so it is cut back to
+        // the last whole one.
+        let result = self.file.write_all(&buf).and_then(|_| self.file.sync_data());
+        match result {
+            Ok(()) => {
+                self.len += buf.len() as u64;
+                Ok(())
+            }
+            Err(e) => {
+                let _ = self.file.set_len(self.len);
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Set once the server is part of a Raft group.
+static ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Initializes NODE and TABLES exactly once.
+static INIT: Once = ONCE_INIT;
+
+/// The node shared by all threads. Use `node()` to access it.
+static mut NODE: *const Mutex<Node> = 0 as *const Mutex<Node>;
+
+/// The tenant and identifier of every table the group replicates. Use `tables()` to access it.
+static mut TABLES: *const RwLock<HashSet<(TenantId, TableId)>> =
+    0 as *const RwLock<HashSet<(TenantId, TableId)>>;
+
+/// Initializes KEYS exactly once.
+static KEYS_INIT: Once = ONCE_INIT;
+
+/// The keys of tenants that have one, which values are sealed with. Use `keys()` to access it.
+static mut KEYS: *const RwLock<HashMap<TenantId, Arc<Key>>> =
+    0 as *const RwLock<HashMap<TenantId, Arc<Key>>>;
+
+// Allocates NODE and TABLES, unless they already are. The node picks up from what it saved.
+fn init(id: usize, n: usize, saved: Saved) {
+    unsafe {
+        INIT.call_once(|| {
+            let seed = cycles::rdtsc() ^ ((id as u64 + 1) << 32);
+            let node = Node::restore(id, n, seed, saved);
+            NODE = Box::into_raw(Box::new(Mutex::new(node)));
+            TABLES = Box::into_raw(Box::new(RwLock::new(HashSet::new())));
+        });
+    }
+}
+
+// Returns the node shared by all threads. Only called once the server is part of a group.
+fn node() -> &'static Mutex<Node> {
+    unsafe { &*NODE }
+}
+
+// Returns the tables the group replicates. Only called once the server is part of a group.
+fn tables() -> &'static RwLock<HashSet<(TenantId, TableId)>> {
+    unsafe { &*TABLES }
+}
+
+// Returns the keys of tenants that have one, allocating the map on first use.
+fn keys() -> &'static RwLock<HashMap<TenantId, Arc<Key>>> {
+    unsafe {
+        KEYS_INIT.call_once(|| {
+            KEYS = Box::into_raw(Box::new(RwLock::new(HashMap::new())));
+        });
+        &*KEYS
+    }
+}
+
+/// Notes a tenant's key, so that it's values are sealed with it when appended to the log, and
+/// it's entries can be applied.
+///
+/// # Arguments
+///
+/// * `tenant`: The tenant.
+/// * `key`:    The tenant's key, or None if it no longer has one.
+pub fn set_key(tenant: TenantId, key: Option<Arc<Key>>) {
+    match key {
+        Some(key) => keys().write().insert(tenant, key),
+        None => keys().write().remove(&tenant),
+    };
+}
+
+/// Makes the server a node of a Raft group replicating a set of tables, picking up from the
+/// term, vote and log the node kept on disk. Must be called before the server accepts requests.
+///
+/// # Arguments
+///
+/// * `id`:     The identifier of this server in the group.
+/// * `n`:      The number of servers in the group.
+/// * `marked`: The tenant and identifier of every table to replicate.
+/// * `dir`:    The directory the node's file is kept in.
+///
+/// # Return
+///
+/// The node's file, to be handed to the `Driver`, or an error if it could not be read or
+/// rewritten, in which case the server must not join the group.
+pub fn configure(
+    id: usize,
+    n: usize,
+    marked: &[(TenantId, TableId)],
+    dir: &str,
+) -> io::Result<Disk> {
+    let (disk, saved) = Disk::open(dir, id)?;
+    init(id, n, saved);
+    tables().write().extend(marked.iter().cloned());
+    ENABLED.store(true, Ordering::Release);
+    Ok(disk)
+}
+
+/// Returns true if a table is replicated by the Raft group.
+///
+/// # Arguments
+///
+/// * `tenant`: The owner of the table.
+/// * `table`:  The identifier of the table.
+#[inline]
+pub fn governs(tenant: TenantId, table: TableId) -> bool {
+    ENABLED.load(Ordering::Acquire) && tables().read().contains(&(tenant, table))
+}
+
+/// Returns true if this server is the leader of the group.
+pub fn leader() -> bool {
+    node().lock().role() == Role::Leader
+}
+
+/// Returns true if a table can be read and written at this server, which it can unless the
+/// group replicates it and this server is not the leader.
+///
+/// # Arguments
+///
+/// * `tenant`: The owner of the table.
+/// * `table`:  The identifier of the table.
+#[inline]
+pub fn serves(tenant: TenantId, table: TableId) -> bool {
+    !governs(tenant, table) || leader()
+}
+
+/// Appends a write to the log, if this server is the leader.
+///
+/// # Arguments
+///
+/// * `rec`: The record of the write.
+///
+/// # Return
+///
+/// The index and term of the entry to pass to `outcome()`, or None if this server is not the
+/// leader.
+pub fn propose(rec: &replica::Record) -> Option<(u64, u64)> {
+    let key = keys().read().get(&rec.tenant).cloned();
+    let data = entry_data(rec, key.as_ref().map(|key| &**key));
+    node().lock().propose(data)
+}
+
+// Encodes a record as the data of an entry, sealing it's value if there is a key to seal it with.
+fn entry_data(rec: &replica::Record, key: Option<&Key>) -> Vec<u8> {
+    let mut data = Vec::with_capacity(replica::RECORD_HDR_LEN + rec.key.len() + rec.val.len());
+    match key {
+        Some(key) => {
+            let val = key.seal(rec.table, rec.key, rec.val);
+            let sealed = replica::Record {
+                op: rec.op | OP_SEALED,
+                tenant: rec.tenant,
+                table: rec.table,
+                key: rec.key,
+                val: &val,
+            };
+            replica::encode(&mut data, &sealed);
+        }
+        None => replica::encode(&mut data, rec),
+    }
+
+    data
+}
+
+/// Returns what became of a write appended by `propose()`.
+pub fn outcome(index: u64, term: u64) -> Outcome {
+    node().lock().outcome(index, term)
+}
+
+/// Describes the state of the group at this server as a line of JSON, or returns an empty
+/// string if the server is not part of one.
+pub fn status() -> String {
+    if !ENABLED.load(Ordering::Acquire) {
+        return String::new();
+    }
+
+    let node = node().lock();
+    let role = match node.role() {
+        Role::Follower => "follower",
+        Role::Candidate => "candidate",
+        Role::Leader => "leader",
+    };
+    format!(
+        "{{\"raft_role\":\"{}\",\"term\":{},\"leader\":{},\"last_index\":{},\"commit\":{},\
+         \"applied\":{}}}\n",
+        role,
+        node.term(),
+        node.leader().map_or(String::from("null"), |l| l.to_string()),
+        node.last_index(),
+        node.commit(),
+        node.applied
+    )
+}
+
+/// Appends the tag authenticating a datagram to it.
+///
+/// # Arguments
+///
+/// * `key`:      The key shared by the nodes of the group.
+/// * `to`:       The node the datagram is destined for.
+/// * `datagram`: The message, as encoded by `encode()`.
+pub fn seal(key: &SigningKey, to: usize, datagram: &mut Vec<u8>) {
+    let mut ctx = hmac::SigningContext::with_key(key);
+    ctx.update(&[to as u8]);
+    ctx.update(datagram);
+    let tag = ctx.sign();
+    datagram.extend_from_slice(tag.as_ref());
+}
+
+/// Checks the tag on a datagram.
+///
+/// # Arguments
+///
+/// * `key`:      The key shared by the nodes of the group.
+/// * `to`:       The node that received the datagram.
+/// * `datagram`: The datagram, as sealed by `seal()`.
+///
+/// # Return
+///
+/// The message without the tag, or None if the tag does not match it.
+pub fn unseal<'a>(key: &SigningKey, to: usize, datagram: &'a [u8]) -> Option<&'a [u8]> {
+    if datagram.len() < TAG_LEN {
+        return None;
+    }
+
+    let (msg, tag) = datagram.split_at(datagram.len() - TAG_LEN);
+    let mut signed = Vec::with_capacity(msg.len() + 1);
+    signed.push(to as u8);
+    signed.extend_from_slice(msg);
+    hmac::verify_with_own_key(key, &signed, tag).ok().map(|_| msg)
+}
+
+/// Returns the key datagrams are authenticated with, derived from the secret the nodes share.
+///
+/// # Arguments
+///
+/// * `secret`: The secret, `raft_secret` in the config.
+pub fn key(secret: &str) -> SigningKey {
+    SigningKey::new(&SHA256, secret.as_bytes())
+}
+
+/// The task driving this server's node of the group. It lives as long as the scheduler it runs
+/// on, and every time it runs, it handles the messages received from the other nodes, ticks the
+/// timers, writes the node's changes to disk, sends out what the node has to say, and applies
+/// committed entries.
+pub struct Driver {
+    // The socket messages are exchanged on, and the address of every node.
+    socket: UdpSocket,
+    addrs: Vec<SocketAddr>,
+
+    // The identifier of this node.
+    id: usize,
+
+    // The key datagrams are authenticated with.
+    key: SigningKey,
+
+    // The file the node's term, vote and log are kept in.
+    disk: Disk,
+
+    // The buffer datagrams are received into.
+    buf: Vec<u8>,
+
+    // Master, which committed entries are applied through.
+    master: Arc<Master>,
+
+    // Committed entries that were not applied yet, along with their index, since the first of
+    // them waits on the key of it's tenant.
+    waiting: Vec<(u64, Entry)>,
+
+    // The number of cycles in a tick, and the time-stamp of the last tick.
+    tick: u64,
+    ticked: u64,
+
+    // The state of the task, and the total cycles it ran for.
+    state: TaskState,
+    time: u64,
+}
+
+// Implementation of methods on Driver.
+impl Driver {
+    /// Binds to this node's address.
+    ///
+    /// # Arguments
+    ///
+    /// * `addrs`:  The address of every node, ordered by identifier.
+    /// * `id`:     The identifier of this node.
+    /// * `secret`: The secret the nodes share, which datagrams are authenticated with.
+    /// * `disk`:   The node's file, as returned by `configure()`.
+    /// * `master`: Master, which committed entries are applied through.
+    ///
+    /// # Return
+    ///
+    /// A task to enqueue on a scheduler, or an error if the socket could not be set up.
+    pub fn new(
+        addrs: Vec<SocketAddr>,
+        id: usize,
+        secret: &str,
+        disk: Disk,
+        master: Arc<Master>,
+    ) -> io::Result<Driver> {
+        let socket = UdpSocket::bind(addrs[id])?;
+        socket.set_nonblocking(true)?;
+
+        Ok(Driver {
+            socket: socket,
+            addrs: addrs,
+            id: id,
+            key: key(secret),
+            disk: disk,
+            buf: vec![0u8; MAX_DATAGRAM],
+            master: master,
+            waiting: Vec::new(),
+            tick: cycles::cycles_per_second() * TICK_MS / 1000,
+            ticked: cycles::rdtsc(),
+            state: TaskState::INITIALIZED,
+            time: 0,
+        })
+    }
+
+    // Receives a datagram, returning the message in it. None if there was nothing to receive,
+    // and Some(None) if the datagram was not from a node of the group or was malformed.
+    fn recv(&mut self) -> Option<Option<(usize, Message)>> {
+        let (len, src) = match self.socket.recv_from(&mut self.buf) {
+            Ok(received) => received,
+            Err(_) => return None,
+        };
+
+        let msg = unseal(&self.key, self.id, &self.buf[..len]).and_then(decode);
+        match msg {
+            Some((from, msg)) if self.addrs.get(from) == Some(&src) => Some(Some((from, msg))),
+            _ => {
+                debug!("Dropped a Raft datagram from {} that did not authenticate", src);
+                counters::add(Counter::AuthFailures, 1);
+                Some(None)
+            }
+        }
+    }
+
+    // Runs the node for a round.
+    fn poll(&mut self) {
+        let mut received = Vec::new();
+        for _ in 0..MAX_RECV {
+            match self.recv() {
+                Some(Some(msg)) => received.push(msg),
+                Some(None) => continue,
+                None => break,
+            }
+        }
+
+        let now = cycles::rdtsc();
+        let (mut outbox, unsaved) = {
+            let mut node = node().lock();
+            for (from, msg) in received.into_iter() {
+                node.step(from, msg);
+            }
+
+            if now.saturating_sub(self.ticked) >= self.tick {
+                self.ticked = now;
+                node.tick();
+            }
+
+            node.flush();
+            (node.outbox(), node.unsaved())
+        };
+
+        // Nothing the node says goes out before what it says it is written to disk. If it could
+        // not be written, the messages are dropped, which Raft copes with, and the changes are
+        // written the next time around.
+        if let Some(ref changes) = unsaved {
+            match self.disk.write(changes) {
+                Ok(()) => node().lock().persisted(changes),
+                Err(e) => {
+                    error!("Failed to write the Raft log: {}", e);
+                    outbox.clear();
+                }
+            }
+        }
+        let (first, entries) = node().lock().committed();
+
+        for (to, msg) in outbox.iter() {
+            let mut datagram = Vec::new();
+            encode(&mut datagram, self.id, msg);
+            seal(&self.key, *to, &mut datagram);
+            // Raft copes with lost messages, so a full socket buffer only slows it down.
+            let _ = self.socket.send_to(&datagram, self.addrs[*to]);
+        }
+
+        // Entries are applied in order, so one waiting on a key holds back those after it.
+        for (i, entry) in entries.into_iter().enumerate() {
+            self.waiting.push((first + i as u64, entry));
+        }
+        let mut done = 0;
+        for &(_, ref entry) in self.waiting.iter() {
+            if entry.data.len() > 0 && !self.apply(&entry.data) {
+                break;
+            }
+            done += 1;
+        }
+        if done > 0 {
+            let last = self.waiting[done - 1].0;
+            self.waiting.drain(..done);
+            node().lock().applied(last);
+        }
+    }
+
+    // Applies the record in an entry, opening it's value if it is sealed. Returns false if the
+    // value is sealed and the key of it's tenant is not known yet.
+    fn apply(&self, data: &[u8]) -> bool {
+        let rec = match replica::decode(data) {
+            Some((rec, _)) => rec,
+            None => {
+                warn!("Skipped a malformed entry in the Raft log");
+                return true;
+            }
+        };
+        if rec.op & OP_SEALED == 0 {
+            self.master.apply_record(&rec);
+            return true;
+        }
+
+        let key = match keys().read().get(&rec.tenant).cloned() {
+            Some(key) => key,
+            None => return false,
+        };
+        match key.open(rec.table, rec.key, rec.val) {
+            Some(val) => self.master.apply_record(&replica::Record {
+                op: rec.op & !OP_SEALED,
+                tenant: rec.tenant,
+                table: rec.table,
+                key: rec.key,
+                val: &val,
+            }),
+            None => warn!(
+                "Skipped an entry in the Raft log of tenant {} that did not open with it's key",
+                rec.tenant
+            ),
+        }
+        true
+    }
+}
+
+// Implementation of the Task trait for Driver.
+impl Task for Driver {
+    /// Refer to the `Task` trait for Documentation.
+    fn run(&mut self) -> (TaskState, u64) {
+        let start = cycles::rdtsc();
+
+        self.state = TaskState::RUNNING;
+        self.poll();
+        self.state = TaskState::YIELDED;
+
+        let exec = cycles::rdtsc() - start;
+        self.time += exec;
+        (self.state, exec)
+    }
+
+    /// Refer to the `Task` trait for Documentation.
+    fn state(&self) -> TaskState {
+        self.state
+    }
+
+    /// Refer to the `Task` trait for Documentation.
+    fn time(&self) -> u64 {
+        self.time
+    }
+
+    /// Refer to the `Task` trait for Documentation.
+    fn priority(&self) -> TaskPriority {
+        // Not DISPATCH, so that the driver moves along with requests when a misbehaving
+        // scheduler is replaced, instead of being dropped along with it's dispatcher.
+        TaskPriority::REQUEST
+    }
+
+    /// Refer to the `Task` trait for Documentation.
+    fn tenant(&self) -> Option<TenantId> {
+        None
+    }
+
+    /// Refer to the `Task` trait for Documentation.
+    fn name(&self) -> &str {
+        "raft"
+    }
+
+    /// Refer to the `Task` trait for Documentation.
+    fn created(&self) -> u64 {
+        0
+    }
+
+    /// Refer to the `Task` trait for Documentation.
+    fn scheduled(&self) -> u64 {
+        0
+    }
+
+    /// Refer to the `Task` trait for Documentation.
+    fn db_time(&self) -> u64 {
+        0
+    }
+
+    /// Refer to the `Task` trait for Documentation.
+    fn span(&self) -> u64 {
+        0
+    }
+
+    /// Refer to the `Task` trait for Documentation.
+    fn deadline(&self) -> u64 {
+        0
+    }
+
+    /// Refer to the `Task` trait for Documentation.
+    fn level(&self) -> u8 {
+        0
+    }
+
+    /// Refer to the `Task` trait for Documentation.
+    fn expire(&mut self) -> bool {
+        false
+    }
+
+    /// Refer to the `Task` trait for Documentation.
+    unsafe fn tear(
+        &mut self,
+    ) -> Option<(
+        Packet<UdpHeader, EmptyMetadata>,
+        Packet<UdpHeader, EmptyMetadata>,
+    )> {
+        None
+    }
+}
+
+// This module contains unit tests for Raft.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writes what changed on a node to a buffer standing in for it's file, the way the driver
+    // does before sending the node's messages.
+    fn save(node: &mut Node, file: &mut Vec<u8>) {
+        if let Some(changes) = node.unsaved() {
+            encode_unsaved(file, &changes);
+            node.persisted(&changes);
+        }
+    }
+
+    // Delivers every message waiting to be sent, until there are none left. Messages to or from
+    // a node in `down` are dropped.
+    fn deliver(nodes: &mut Vec<Node>, down: &[usize]) {
+        loop {
+            let mut msgs = Vec::new();
+            for (from, node) in nodes.iter_mut().enumerate() {
+                save(node, &mut Vec::new());
+                for (to, msg) in node.outbox().into_iter() {
+                    msgs.push((from, to, msg));
+                }
+            }
+            if msgs.len() == 0 {
+                return;
+            }
+
+            for (from, to, msg) in msgs.into_iter() {
+                if !down.contains(&from) && !down.contains(&to) {
+                    nodes[to].step(from, msg);
+                }
+            }
+        }
+    }
+
+    // Ticks a node until it campaigns, and delivers the messages that follow.
+    fn elect(nodes: &mut Vec<Node>, id: usize, down: &[usize]) {
+        let term = nodes[id].term();
+        while nodes[id].term() == term {
+            nodes[id].tick();
+        }
+        deliver(nodes, down);
+    }
+
+    // Applies every committed entry on a node, returning their data.
+    fn apply(node: &mut Node) -> Vec<Vec<u8>> {
+        let (first, entries) = node.committed();
+        if entries.len() > 0 {
+            node.applied(first + entries.len() as u64 - 1);
+        }
+        entries.into_iter().map(|entry| entry.data).collect()
+    }
+
+    // This unit test verifies that messages survive a round trip through a datagram, and that a
+    // truncated or padded one is not parsed.
+    #[test]
+    fn test_encode() {
+        let msgs = vec![
+            Message::Vote {
+                term: 3,
+                last_index: 10,
+                last_term: 2,
+            },
+            Message::VoteReply {
+                term: 3,
+                granted: true,
+            },
+            Message::Append {
+                term: 1 << 40,
+                prev_index: 5,
+                prev_term: 1,
+                commit: 4,
+                entries: vec![
+                    Entry {
+                        term: 1,
+                        data: vec![],
+                    },
+                    Entry {
+                        term: 2,
+                        data: b"record".to_vec(),
+                    },
+                ],
+            },
+            Message::AppendReply {
+                term: 7,
+                success: false,
+                last: 9,
+            },
+        ];
+
+        for msg in msgs.into_iter() {
+            let mut buf = Vec::new();
+            encode(&mut buf, 2, &msg);
+            assert_eq!(Some((2, msg.clone())), decode(&buf));
+            assert!(decode(&buf[..buf.len() - 1]).is_none());
+
+            buf.push(0);
+            assert!(decode(&buf).is_none());
+        }
+    }
+
+    // This unit test verifies that a node is elected, that entries it appends are committed once
+    // a majority has them, and that they are applied in order on every node.
+    #[test]
+    fn test_replicate() {
+        let mut nodes: Vec<Node> = (0..3).map(|id| Node::new(id, 3, id as u64 + 1)).collect();
+        assert!(nodes[0].propose(b"early".to_vec()).is_none());
+
+        elect(&mut nodes, 0, &[]);
+        assert_eq!(Role::Leader, nodes[0].role());
+        assert_eq!(Some(0), nodes[1].leader());
+        assert_eq!(Some(0), nodes[2].leader());
+
+        let (index, term) = nodes[0].propose(b"first".to_vec()).unwrap();
+        nodes[0].propose(b"second".to_vec()).unwrap();
+        assert_eq!(Outcome::Pending, nodes[0].outcome(index, term));
+
+        nodes[0].flush();
+        deliver(&mut nodes, &[]);
+        assert_eq!(3, nodes[0].commit());
+
+        // The empty entry appended on election comes first.
+        let expected = vec![vec![], b"first".to_vec(), b"second".to_vec()];
+        assert_eq!(expected, apply(&mut nodes[0]));
+        assert_eq!(Outcome::Committed, nodes[0].outcome(index, term));
+
+        // Followers learn of the commit on the next heartbeat.
+        for _ in 0..HEARTBEAT_TICKS {
+            nodes[0].tick();
+        }
+        deliver(&mut nodes, &[]);
+        assert_eq!(expected, apply(&mut nodes[1]));
+        assert_eq!(expected, apply(&mut nodes[2]));
+    }
+
+    // This unit test verifies that a leader cut off from the others cannot commit, and that the
+    // entry it appended is lost once a new leader overwrites it.
+    #[test]
+    fn test_lost() {
+        let mut nodes: Vec<Node> = (0..3).map(|id| Node::new(id, 3, id as u64 + 1)).collect();
+        elect(&mut nodes, 0, &[]);
+
+        let (index, term) = nodes[0].propose(b"stranded".to_vec()).unwrap();
+        nodes[0].flush();
+        deliver(&mut nodes, &[0]);
+        assert_eq!(Outcome::Pending, nodes[0].outcome(index, term));
+
+        elect(&mut nodes, 1, &[0]);
+        assert_eq!(Role::Leader, nodes[1].role());
+        nodes[1].propose(b"winner".to_vec()).unwrap();
+        nodes[1].flush();
+        deliver(&mut nodes, &[0]);
+
+        // Once the old leader hears from the new one, it steps down and drops it's entry.
+        for _ in 0..HEARTBEAT_TICKS {
+            nodes[1].tick();
+        }
+        deliver(&mut nodes, &[]);
+        assert_eq!(Role::Follower, nodes[0].role());
+        assert_eq!(Outcome::Lost, nodes[0].outcome(index, term));
+        assert_eq!(nodes[1].last_index(), nodes[0].last_index());
+    }
+
+    // This unit test verifies that a node does not vote for a candidate whose log is behind
+    // it's own, nor twice in the same term.
+    #[test]
+    fn test_vote() {
+        let mut node = Node::new(0, 3, 1);
+        node.step(
+            1,
+            Message::Append {
+                term: 2,
+                prev_index: 0,
+                prev_term: 0,
+                commit: 0,
+                entries: vec![Entry {
+                    term: 2,
+                    data: vec![],
+                }],
+            },
+        );
+        node.outbox();
+
+        let stale = Message::Vote {
+            term: 3,
+            last_index: 5,
+            last_term: 1,
+        };
+        node.step(2, stale);
+        assert_eq!(
+            vec![(
+                2,
+                Message::VoteReply {
+                    term: 3,
+                    granted: false,
+                }
+            )],
+            node.outbox()
+        );
+
+        let current = Message::Vote {
+            term: 3,
+            last_index: 1,
+            last_term: 2,
+        };
+        node.step(1, current.clone());
+        node.step(2, current);
+        let replies: Vec<bool> = node.outbox()
+            .into_iter()
+            .map(|(_, msg)| match msg {
+                Message::VoteReply { granted, .. } => granted,
+                _ => panic!("Expected a VoteReply"),
+            })
+            .collect();
+        assert_eq!(vec![true, false], replies);
+    }
+
+    // This unit test verifies that a node restored from it's file after a restart has the term,
+    // vote and log it had, and does not vote a second time in the term it voted in.
+    #[test]
+    fn test_restart() {
+        let mut file = Vec::new();
+        let mut node = Node::new(0, 3, 1);
+        let entries = vec![
+            Entry {
+                term: 1,
+                data: b"a".to_vec(),
+            },
+            Entry {
+                term: 1,
+                data: b"b".to_vec(),
+            },
+        ];
+        node.step(
+            1,
+            Message::Append {
+                term: 1,
+                prev_index: 0,
+                prev_term: 0,
+                commit: 0,
+                entries: entries.clone(),
+            },
+        );
+        save(&mut node, &mut file);
+
+        // A leader of a later term replaces the second entry.
+        let replaced = Entry {
+            term: 2,
+            data: b"c".to_vec(),
+        };
+        node.step(
+            2,
+            Message::Append {
+                term: 2,
+                prev_index: 1,
+                prev_term: 1,
+                commit: 0,
+                entries: vec![replaced.clone()],
+            },
+        );
+        node.step(
+            1,
+            Message::Vote {
+                term: 3,
+                last_index: 2,
+                last_term: 2,
+            },
+        );
+        save(&mut node, &mut file);
+        assert!(node.unsaved().is_none());
+        node.outbox();
+
+        let (saved, len) = replay(&file);
+        assert_eq!(file.len(), len);
+        assert_eq!(3, saved.term);
+        assert_eq!(Some(1), saved.voted_for);
+        assert_eq!(vec![entries[0].clone(), replaced], saved.log);
+
+        let mut node = Node::restore(0, 3, 1, saved);
+        assert!(node.unsaved().is_none());
+        node.step(
+            2,
+            Message::Vote {
+                term: 3,
+                last_index: 2,
+                last_term: 2,
+            },
+        );
+        assert_eq!(
+            vec![(
+                2,
+                Message::VoteReply {
+                    term: 3,
+                    granted: false,
+                }
+            )],
+            node.outbox()
+        );
+    }
+
+    // This unit test verifies that replaying a node's file stops at a record torn by a crash, or
+    // one that does not match it's hash, keeping everything before it.
+    #[test]
+    fn test_replay() {
+        let mut file = Vec::new();
+        let first = Unsaved {
+            vote: Some((4, None)),
+            first: 1,
+            entries: vec![Entry {
+                term: 4,
+                data: b"kept".to_vec(),
+            }],
+        };
+        encode_unsaved(&mut file, &first);
+        let whole = file.len();
+
+        let second = Unsaved {
+            vote: None,
+            first: 2,
+            entries: vec![Entry {
+                term: 4,
+                data: b"torn".to_vec(),
+            }],
+        };
+        encode_unsaved(&mut file, &second);
+
+        for cut in whole..file.len() {
+            let (saved, len) = replay(&file[..cut]);
+            assert_eq!(whole, len);
+            assert_eq!(first.entries, saved.log);
+            assert_eq!(4, saved.term);
+        }
+
+        let last = file.len() - 1;
+        file[last] ^= 0xff;
+        assert_eq!(whole, replay(&file).1);
+    }
+
+    // This unit test verifies that a leader only counts an entry it appended towards a majority
+    // once the entry is on it's disk.
+    #[test]
+    fn test_durable() {
+        let mut nodes: Vec<Node> = (0..3).map(|id| Node::new(id, 3, id as u64 + 1)).collect();
+        elect(&mut nodes, 0, &[]);
+
+        let (index, _) = nodes[0].propose(b"entry".to_vec()).unwrap();
+        nodes[0].flush();
+        for (to, msg) in nodes[0].outbox().into_iter() {
+            if to == 1 {
+                nodes[1].step(0, msg);
+            }
+        }
+        save(&mut nodes[1], &mut Vec::new());
+        for (_, msg) in nodes[1].outbox().into_iter() {
+            nodes[0].step(1, msg);
+        }
+
+        // One of the two copies of the entry is not on disk yet.
+        assert!(nodes[0].commit() < index);
+        save(&mut nodes[0], &mut Vec::new());
+        assert_eq!(index, nodes[0].commit());
+    }
+
+    // This unit test verifies that the values of entries are sealed when there is a key, and
+    // open back up with it.
+    #[test]
+    fn test_entry_data() {
+        let rec = replica::Record {
+            op: replica::OP_PUT,
+            tenant: 3,
+            table: 9,
+            key: b"apple",
+            val: b"red",
+        };
+        let mut plain = Vec::new();
+        replica::encode(&mut plain, &rec);
+        assert_eq!(plain, entry_data(&rec, None));
+
+        let key = Key::new(&[7u8; 32]);
+        let data = entry_data(&rec, Some(&key));
+        let (sealed, _) = replica::decode(&data).unwrap();
+        assert_eq!(replica::OP_PUT | OP_SEALED, sealed.op);
+        assert_eq!((3, 9, &b"apple"[..]), (sealed.tenant, sealed.table, sealed.key));
+        assert!(sealed.val != b"red");
+        assert_eq!(Some(b"red".to_vec()), key.open(9, b"apple", sealed.val));
+    }
+}
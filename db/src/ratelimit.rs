@@ -0,0 +1,478 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use std::cmp;
+use std::collections::HashMap;
+use std::sync::{Arc, Once, ONCE_INIT};
+
+use super::config::TenantLimitConfig;
+use super::cycles;
+
+use spin::{Mutex, RwLock};
+
+/// The maximum number of sources tracked at once. Once reached, sources whose buckets have
+/// refilled completely are forgotten, since they would be admitted anyway.
+const MAX_SOURCES: usize = 65536;
+
+/// Initializes SHARED exactly once.
+static INIT: Once = ONCE_INIT;
+
+/// The tenant limiter shared by every dispatcher. Use `tenant_limiter()` to access it.
+static mut SHARED: *const Mutex<Option<Arc<TenantLimiter>>> =
+    0 as *const Mutex<Option<Arc<TenantLimiter>>>;
+
+// A token bucket. How fast it refills, how much it holds and what is taken out of it are up to
+// the `Limit` it is charged under.
+struct Bucket {
+    // The credit available on the bucket.
+    credit: u64,
+
+    // The time-stamp in cycles at which the bucket was last refilled.
+    last: u64,
+}
+
+// A limit on the rate of some unit (requests or bytes) charged to a bucket. Credit is measured in
+// units of `rate` per cycle, so that a cycle of elapsed time refills `rate` credit, and a single
+// unit costs a second's worth of cycles. Refilling a bucket takes no division this way, and all
+// arithmetic saturates, so that no rate or burst can overflow it.
+struct Limit {
+    // The number of units admitted per second.
+    rate: u64,
+
+    // The credit a single unit costs.
+    cost: u64,
+
+    // The maximum credit a bucket can accumulate. Determines the largest burst admitted after
+    // the bucket was left idle.
+    depth: u64,
+}
+
+// Implementation of methods on Limit.
+impl Limit {
+    // Creates a limit admitting `rate` units per second after a burst of `burst` units (`rate`
+    // if zero), with time measured in units of `cps` ticks per second.
+    fn new(rate: u64, burst: u64, cps: u64) -> Limit {
+        let burst = if burst == 0 { rate } else { burst };
+        Limit {
+            rate: rate,
+            cost: cps,
+            depth: cps.saturating_mul(burst),
+        }
+    }
+
+    // Creates a limit as above, or returns None if `rate` is zero and the unit is not limited.
+    fn optional(rate: u64, burst: u64, cps: u64) -> Option<Limit> {
+        match rate {
+            0 => None,
+            rate => Some(Limit::new(rate, burst, cps)),
+        }
+    }
+
+    // Returns a bucket for the limit, full at `now`.
+    fn bucket(&self, now: u64) -> Bucket {
+        Bucket {
+            credit: self.depth,
+            last: now,
+        }
+    }
+
+    // Refills a bucket with the time that elapsed since it was last touched, and returns true if
+    // it holds enough credit for `units`.
+    fn refill(&self, bucket: &mut Bucket, units: u64, now: u64) -> bool {
+        let elapsed = now.saturating_sub(bucket.last).saturating_mul(self.rate);
+        bucket.credit = cmp::min(self.depth, bucket.credit.saturating_add(elapsed));
+        bucket.last = now;
+        bucket.credit >= units.saturating_mul(self.cost)
+    }
+
+    // Takes the credit for `units` out of a bucket that `refill()` found enough credit on.
+    fn charge(&self, bucket: &mut Bucket, units: u64) {
+        bucket.credit = bucket.credit.saturating_sub(units.saturating_mul(self.cost));
+    }
+
+    // Returns true if a bucket would have refilled completely by `now`.
+    fn full(&self, bucket: &Bucket, now: u64) -> bool {
+        let elapsed = now.saturating_sub(bucket.last).saturating_mul(self.rate);
+        bucket.credit.saturating_add(elapsed) >= self.depth
+    }
+}
+
+/// Rate limits requests from every (tenant, source IP address) pair with a token bucket.
+/// Every dispatcher has it's own limiter, so limits apply per receive queue.
+pub struct RateLimiter {
+    // The limit every source is held to.
+    limit: Limit,
+
+    // Buckets for every source seen recently, keyed by tenant and source IP address.
+    buckets: HashMap<(u32, u32), Bucket>,
+}
+
+// Implementation of methods on RateLimiter.
+impl RateLimiter {
+    /// Creates a rate limiter.
+    ///
+    /// # Arguments
+    ///
+    /// * `rate`:  The number of requests per second admitted from every source.
+    /// * `burst`: The number of requests admitted back to back from a source that was idle.
+    ///
+    /// # Return
+    ///
+    /// A rate limiter that admits `rate` requests per second from each source.
+    pub fn new(rate: u64, burst: u64) -> RateLimiter {
+        RateLimiter::with_cps(rate, burst, cycles::cycles_per_second())
+    }
+
+    // Creates a rate limiter, with time measured in units of `cps` ticks per second.
+    fn with_cps(rate: u64, burst: u64, cps: u64) -> RateLimiter {
+        RateLimiter {
+            limit: Limit::new(cmp::max(rate, 1), cmp::max(burst, 1), cps),
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Decides whether a request should be admitted, charging it's source if so.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant`: The tenant the request was issued by.
+    /// * `src`:    The source IP address on the request.
+    /// * `now`:    The current time-stamp in cycles.
+    ///
+    /// # Return
+    ///
+    /// True if the request is within it's source's rate limit. False if it should be dropped.
+    pub fn admit(&mut self, tenant: u32, src: u32, now: u64) -> bool {
+        if self.buckets.len() >= MAX_SOURCES && !self.buckets.contains_key(&(tenant, src)) {
+            self.evict(now);
+        }
+
+        let limit = &self.limit;
+        let bucket = self.buckets
+            .entry((tenant, src))
+            .or_insert_with(|| limit.bucket(now));
+
+        if !limit.refill(bucket, 1, now) {
+            return false;
+        }
+
+        limit.charge(bucket, 1);
+        return true;
+    }
+
+    // Forgets every source whose bucket would have refilled completely by `now`.
+    fn evict(&mut self, now: u64) {
+        let limit = &self.limit;
+        self.buckets.retain(|_, bucket| !limit.full(bucket, now));
+    }
+}
+
+// The buckets charged for a tenant's traffic.
+struct TenantBuckets {
+    // The bucket charged a unit for every request.
+    ops: Bucket,
+
+    // The bucket charged a unit for every byte on a request.
+    bytes: Bucket,
+}
+
+/// Limits the number of requests and request bytes admitted per second from every tenant with a
+/// pair of token buckets, configured per tenant. A single limiter is shared by every dispatcher
+/// (see `tenant_limiter()`), so a tenant is held to it's limits across all receive queues rather
+/// than on each of them.
+pub struct TenantLimiter {
+    // The limits the limiter was created with.
+    configs: Vec<TenantLimitConfig>,
+
+    // The request and byte limits configured, in the order of the config.
+    limits: Vec<(Option<Limit>, Option<Limit>)>,
+
+    // The index of the limits applying to each tenant listed in the config.
+    tenants: HashMap<u32, usize>,
+
+    // The index of the limits applying to every tenant not listed in the config, if any.
+    default: Option<usize>,
+
+    // Buckets for every tenant seen recently. Dispatchers only take the write lock to add a
+    // tenant, and otherwise contend only on the buckets of the tenants they admit requests for.
+    buckets: RwLock<HashMap<u32, Mutex<TenantBuckets>>>,
+}
+
+/// Returns the tenant limiter shared by every dispatcher, replacing it if it was created with
+/// other limits.
+///
+/// # Arguments
+///
+/// * `configs`: The limits on each set of tenants.
+///
+/// # Return
+///
+/// The limiter, or None if `configs` is empty and no tenant is limited.
+pub fn tenant_limiter(configs: &[TenantLimitConfig]) -> Option<Arc<TenantLimiter>> {
+    let shared = unsafe {
+        INIT.call_once(|| {
+            SHARED = Box::into_raw(Box::new(Mutex::new(None)));
+        });
+        &*SHARED
+    };
+
+    let mut shared = shared.lock();
+    let current = match *shared {
+        Some(ref limiter) if limiter.configs[..] == configs[..] => return Some(limiter.clone()),
+        _ => TenantLimiter::new(configs).map(Arc::new),
+    };
+    *shared = current.clone();
+    current
+}
+
+// Implementation of methods on TenantLimiter.
+impl TenantLimiter {
+    /// Creates a tenant limiter. Dispatchers should share the one `tenant_limiter()` returns
+    /// instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `configs`: The limits on each set of tenants.
+    ///
+    /// # Return
+    ///
+    /// A limiter, or None if `configs` is empty and no tenant is limited.
+    pub fn new(configs: &[TenantLimitConfig]) -> Option<TenantLimiter> {
+        TenantLimiter::with_cps(configs, cycles::cycles_per_second())
+    }
+
+    // Creates a tenant limiter, with time measured in units of `cps` ticks per second.
+    fn with_cps(configs: &[TenantLimitConfig], cps: u64) -> Option<TenantLimiter> {
+        if configs.len() == 0 {
+            return None;
+        }
+
+        let mut limiter = TenantLimiter {
+            configs: configs.to_vec(),
+            limits: Vec::with_capacity(configs.len()),
+            tenants: HashMap::new(),
+            default: None,
+            buckets: RwLock::new(HashMap::new()),
+        };
+
+        for (idx, config) in configs.iter().enumerate() {
+            limiter.limits.push((
+                Limit::optional(config.ops_rate, config.ops_burst, cps),
+                Limit::optional(config.bytes_rate, config.bytes_burst, cps),
+            ));
+
+            if config.tenants.len() == 0 {
+                limiter.default = Some(idx);
+            }
+
+            for tenant in config.tenants.iter() {
+                limiter.tenants.insert(*tenant, idx);
+            }
+        }
+
+        Some(limiter)
+    }
+
+    /// Decides whether a request should be admitted, charging it's tenant if so. A request is
+    /// admitted only if it fits within both the tenant's request and byte limits.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant`: The tenant the request was issued by.
+    /// * `len`:    The number of bytes on the request.
+    /// * `now`:    The current time-stamp in cycles.
+    ///
+    /// # Return
+    ///
+    /// True if the request is within it's tenant's limits. False if it should be throttled.
+    pub fn admit(&self, tenant: u32, len: u64, now: u64) -> bool {
+        let limit = match self.tenants.get(&tenant).cloned().or(self.default) {
+            Some(limit) => limit,
+            None => return true,
+        };
+        let (ref ops, ref bytes) = self.limits[limit];
+
+        {
+            let buckets = self.buckets.read();
+            if let Some(tenant_buckets) = buckets.get(&tenant) {
+                return TenantLimiter::charge(ops, bytes, &mut tenant_buckets.lock(), len, now);
+            }
+        }
+
+        let mut buckets = self.buckets.write();
+        if buckets.len() >= MAX_SOURCES && !buckets.contains_key(&tenant) {
+            buckets.clear();
+        }
+
+        let tenant_buckets = buckets.entry(tenant).or_insert_with(|| {
+            Mutex::new(TenantBuckets {
+                ops: ops.as_ref().map_or(Bucket { credit: 0, last: now }, |l| l.bucket(now)),
+                bytes: bytes.as_ref().map_or(Bucket { credit: 0, last: now }, |l| l.bucket(now)),
+            })
+        });
+        let mut tenant_buckets = tenant_buckets.lock();
+        TenantLimiter::charge(ops, bytes, &mut tenant_buckets, len, now)
+    }
+
+    // Charges a request of `len` bytes to a tenant's buckets, if both have enough credit for it.
+    fn charge(
+        ops: &Option<Limit>,
+        bytes: &Option<Limit>,
+        buckets: &mut TenantBuckets,
+        len: u64,
+        now: u64,
+    ) -> bool {
+        let ops_ok = ops.as_ref().map_or(true, |ops| ops.refill(&mut buckets.ops, 1, now));
+        let bytes_ok = bytes
+            .as_ref()
+            .map_or(true, |bytes| bytes.refill(&mut buckets.bytes, len, now));
+        if !ops_ok || !bytes_ok {
+            return false;
+        }
+
+        if let Some(ref ops) = *ops {
+            ops.charge(&mut buckets.ops, 1);
+        }
+
+        if let Some(ref bytes) = *bytes {
+            bytes.charge(&mut buckets.bytes, len);
+        }
+
+        return true;
+    }
+}
+
+// This module contains unit tests for RateLimiter.
+#[cfg(test)]
+mod tests {
+    use super::{tenant_limiter, Limit, RateLimiter, TenantLimiter};
+    use config::TenantLimitConfig;
+
+    // This unit test verifies that a burst is admitted, and then requests are dropped until the
+    // bucket refills.
+    #[test]
+    fn test_admit_burst() {
+        let mut limiter = RateLimiter::with_cps(1, 4, 100);
+
+        for _ in 0..4 {
+            assert!(limiter.admit(1, 10, 1000));
+        }
+        assert!(!limiter.admit(1, 10, 1000));
+        assert!(!limiter.admit(1, 10, 1099));
+        assert!(limiter.admit(1, 10, 1100));
+    }
+
+    // This unit test verifies that sources are limited independently of each other.
+    #[test]
+    fn test_admit_sources() {
+        let mut limiter = RateLimiter::with_cps(1, 1, 100);
+
+        assert!(limiter.admit(1, 10, 0));
+        assert!(!limiter.admit(1, 10, 0));
+        assert!(limiter.admit(2, 10, 0));
+        assert!(limiter.admit(1, 11, 0));
+    }
+
+    // This unit test verifies that tenants are throttled on requests and bytes independently,
+    // and that unlisted tenants fall back to the default limits.
+    #[test]
+    fn test_tenant_limits() {
+        let configs = vec![
+            TenantLimitConfig {
+                tenants: vec![1],
+                ops_rate: 2,
+                ..Default::default()
+            },
+            TenantLimitConfig {
+                tenants: vec![2],
+                bytes_rate: 100,
+                ..Default::default()
+            },
+        ];
+        let limiter = TenantLimiter::with_cps(&configs, 1000).unwrap();
+
+        // Tenant 1 gets a burst of two requests, and then one every 500 ticks.
+        assert!(limiter.admit(1, 1000, 0));
+        assert!(limiter.admit(1, 1000, 0));
+        assert!(!limiter.admit(1, 1, 0));
+        assert!(!limiter.admit(1, 1, 499));
+        assert!(limiter.admit(1, 1, 500));
+
+        // Tenant 2 gets a burst of 100 bytes, and then a byte every 10 ticks.
+        assert!(limiter.admit(2, 60, 0));
+        assert!(!limiter.admit(2, 60, 0));
+        assert!(limiter.admit(2, 40, 0));
+        assert!(!limiter.admit(2, 10, 99));
+        assert!(limiter.admit(2, 10, 100));
+
+        // Tenant 3 is not limited at all.
+        for _ in 0..10 {
+            assert!(limiter.admit(3, 1000, 0));
+        }
+
+        let configs = vec![TenantLimitConfig {
+            ops_rate: 1,
+            ..Default::default()
+        }];
+        let limiter = TenantLimiter::with_cps(&configs, 1000).unwrap();
+        assert!(limiter.admit(3, 0, 0));
+        assert!(!limiter.admit(3, 0, 0));
+        assert!(limiter.admit(4, 0, 0));
+        assert!(TenantLimiter::new(&[]).is_none());
+    }
+
+    // This unit test verifies that limits with rates and bursts too large to multiply out
+    // saturate rather than overflow.
+    #[test]
+    fn test_saturate() {
+        let limit = Limit::new(u64::max_value(), u64::max_value(), 3000000000);
+        assert_eq!(u64::max_value(), limit.depth);
+
+        let mut bucket = limit.bucket(0);
+        assert!(limit.refill(&mut bucket, u64::max_value(), 0));
+        limit.charge(&mut bucket, u64::max_value());
+        assert_eq!(0, bucket.credit);
+        assert!(!limit.refill(&mut bucket, 1, 0));
+        assert!(limit.refill(&mut bucket, 1, 1));
+
+        let mut limiter = RateLimiter::with_cps(1, u64::max_value(), 3000000000);
+        for _ in 0..4 {
+            assert!(limiter.admit(1, 10, 0));
+        }
+    }
+
+    // This unit test verifies that every dispatcher shares a tenant's buckets, and that the
+    // shared limiter is replaced once the limits change.
+    #[test]
+    fn test_shared() {
+        let configs = vec![TenantLimitConfig {
+            tenants: vec![1],
+            ops_rate: 1,
+            ops_burst: 1,
+            ..Default::default()
+        }];
+        let first = tenant_limiter(&configs).unwrap();
+        let second = tenant_limiter(&configs).unwrap();
+        assert!(first.admit(1, 0, 0));
+        assert!(!second.admit(1, 0, 0));
+
+        let mut configs = configs;
+        configs[0].ops_burst = 2;
+        let third = tenant_limiter(&configs).unwrap();
+        assert!(third.admit(1, 0, 0));
+        assert!(third.admit(1, 0, 0));
+        assert!(!third.admit(1, 0, 0));
+    }
+}
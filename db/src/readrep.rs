@@ -0,0 +1,485 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+// Asynchronous read replicas. A primary feeds every write it makes to any number of read
+// replicas, which apply them in the order the primary made them and answer the gets, multigets
+// and invokes that clients flag as tolerating stale data. Unlike a backup, a read replica never
+// holds up a write: each one has a queue of records of it's own, shipped in batches by a thread
+// of it's own, and one that falls too far behind is cut off rather than left to grow it's queue
+// without bound. A cut off replica has to be restarted along with a fresh copy of the data.
+//
+// A client flags a request as stale-tolerant by setting REQUEST_FLAG_STALE_OK on it, and
+// appending the number of milliseconds behind the primary it is willing to read at. A read
+// replica knows how far behind it is because the primary says so whenever a batch leaves the
+// replica's queue empty, and sends an empty batch when there has been nothing to ship for a
+// while. The time since the last such batch arrived is the replica's staleness, give or take
+// the time the batch spent on the network. Requests that tolerate less than that, writes, and
+// requests that are not flagged at all are refused with StatusNotPrimary and the replica's
+// staleness, so that the client can take them to the primary instead.
+
+use std::mem::{replace, size_of};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+use std::sync::{Once, ONCE_INIT};
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::audit::quote;
+use super::cycles;
+use super::replica::{self, Record, RECORD_HDR_LEN};
+use super::wireformat::{ReplicaAction, RpcRequestHeader, REQUEST_FLAG_STALE_OK,
+                        STALENESS_BOUND_LEN};
+
+use e2d2::common::EmptyMetadata;
+use e2d2::headers::UdpHeader;
+use e2d2::interface::Packet;
+use spin::Mutex;
+
+/// The number of milliseconds a primary lets go by without feeding a read replica anything
+/// before it sends an empty batch, so that the replica knows it is caught up.
+pub const HEARTBEAT_MS: u64 = 10;
+
+/// The number of bytes of records a batch is cut off at. A batch always has at least one record.
+const MAX_BATCH_BYTES: usize = 1 << 20;
+
+/// The number of bytes of records a read replica's queue can grow to before it is cut off.
+const MAX_QUEUE_BYTES: usize = 64 << 20;
+
+// The records waiting to be fed to a read replica.
+struct Feed {
+    // The address of the replica's install() TCP endpoint.
+    addr: String,
+
+    // The sequence number of the first record in `pending`, and the records themselves. The
+    // next record appended gets sequence number `first + count`.
+    first: u64,
+    pending: Vec<u8>,
+    count: usize,
+
+    // The sequence number of the last record the replica acknowledged.
+    acked: u64,
+
+    // Set once the queue outgrew `MAX_QUEUE_BYTES`. Nothing more is fed to the replica.
+    cut: bool,
+}
+
+// Implementation of methods on Feed.
+impl Feed {
+    // Returns an empty feed to the replica at an address.
+    fn new(addr: &str) -> Feed {
+        Feed {
+            addr: addr.to_string(),
+            first: 1,
+            pending: Vec::new(),
+            count: 0,
+            acked: 0,
+            cut: false,
+        }
+    }
+
+    // Appends a record, cutting the replica off instead if the queue would outgrow it's limit.
+    fn append(&mut self, rec: &Record) {
+        if self.cut {
+            return;
+        }
+
+        let len = RECORD_HDR_LEN + rec.key.len() + rec.val.len();
+        if self.pending.len() + len > MAX_QUEUE_BYTES {
+            self.cut = true;
+            self.pending = Vec::new();
+            self.count = 0;
+            return;
+        }
+
+        replica::encode(&mut self.pending, rec);
+        self.count += 1;
+    }
+
+    // Takes the records appended upto `MAX_BATCH_BYTES`, along with the sequence number of the
+    // first of them, and whether the queue is empty after. The batch is empty if the queue was.
+    fn take(&mut self) -> (u64, usize, Vec<u8>, bool) {
+        // Cut at the last record boundary below the limit, keeping at least one record.
+        let mut len = 0;
+        let mut count = 0;
+        while count < self.count {
+            let next = match replica::decode(&self.pending[len..]) {
+                Some((_, next)) => next,
+                None => break,
+            };
+            if count > 0 && len + next > MAX_BATCH_BYTES {
+                break;
+            }
+            len += next;
+            count += 1;
+        }
+
+        let rest = self.pending.split_off(len);
+        let batch = replace(&mut self.pending, rest);
+        let first = self.first;
+        self.first += count as u64;
+        self.count -= count;
+        (first, count, batch, self.count == 0)
+    }
+}
+
+/// Set if writes are being fed to read replicas.
+static FEEDING: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Set if the server is a read replica.
+static REPLICA: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// On a read replica, the time-stamp in cycles at which the last batch that left the primary
+/// with nothing more to feed it arrived. Zero if none has yet.
+static FRESH: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The epoch of the primary's feeds, the time in nanoseconds since the UNIX epoch at which
+/// they were attached. Lets a replica tell a restarted primary from the one before it.
+static EPOCH: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Initializes FEEDS exactly once.
+static FEEDS_INIT: Once = ONCE_INIT;
+
+/// The feeds to every read replica, shared by all threads. Use `shared()` to access them.
+static mut FEEDS: *const Mutex<Vec<Feed>> = 0 as *const Mutex<Vec<Feed>>;
+
+// Returns the feeds shared by all threads, allocating them on first use.
+fn shared() -> &'static Mutex<Vec<Feed>> {
+    unsafe {
+        FEEDS_INIT.call_once(|| {
+            FEEDS = Box::into_raw(Box::new(Mutex::new(Vec::new())));
+        });
+        &*FEEDS
+    }
+}
+
+/// Starts feeding writes to read replicas. Must be called before the server accepts requests,
+/// with `ship()` called for every replica on a thread of it's own.
+///
+/// # Arguments
+///
+/// * `addrs`: The addresses of the replicas' install() TCP endpoints.
+pub fn attach(addrs: &[String]) {
+    let epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() * 1000000000 + d.subsec_nanos() as u64)
+        .unwrap_or(1);
+    EPOCH.store(epoch as usize, Ordering::Relaxed);
+
+    *shared().lock() = addrs.iter().map(|addr| Feed::new(addr)).collect();
+    FEEDING.store(addrs.len() > 0, Ordering::Relaxed);
+}
+
+/// Applies a write locally and appends a record of it to the queue of every read replica, so
+/// that replicas see writes in the order they were applied.
+///
+/// # Arguments
+///
+/// * `rec`:   The record of the write.
+/// * `apply`: Applies the write to the local table.
+pub fn write<F: FnOnce()>(rec: &Record, apply: F) {
+    if !FEEDING.load(Ordering::Relaxed) {
+        apply();
+        return;
+    }
+
+    let mut feeds = shared().lock();
+    apply();
+    for feed in feeds.iter_mut() {
+        feed.append(rec);
+    }
+}
+
+/// Ships the queue of a read replica to it until the replica is cut off. A batch the replica
+/// does not acknowledge is resent until it does; the replica skips the records in it that it
+/// already applied. Blocks the calling thread.
+///
+/// # Arguments
+///
+/// * `replica`: The index of the replica in the addresses handed to `attach()`.
+pub fn ship(replica: usize) {
+    let epoch = EPOCH.load(Ordering::Relaxed) as u64;
+    let addr = match shared().lock().get(replica) {
+        Some(feed) => feed.addr.clone(),
+        None => return,
+    };
+    info!("Feeding writes to the read replica at {}", addr);
+
+    let heartbeat = Duration::from_millis(HEARTBEAT_MS);
+    let mut last = Instant::now();
+    loop {
+        let batch = {
+            let mut feeds = shared().lock();
+            let feed = &mut feeds[replica];
+            if feed.cut {
+                break;
+            }
+            match feed.count > 0 || last.elapsed() >= heartbeat {
+                true => Some(feed.take()),
+                false => None,
+            }
+        };
+        let (first, count, records, drained) = match batch {
+            Some(batch) => batch,
+            None => {
+                sleep(Duration::from_micros(100));
+                continue;
+            }
+        };
+
+        let action = [ReplicaAction::Feed as u8, drained as u8];
+        let mut failing = false;
+        loop {
+            match replica::send(&addr, &action, epoch, first, count, &records) {
+                Ok(()) => {
+                    if failing {
+                        info!("Read replica at {} is acknowledging writes again", addr);
+                    }
+                    let mut feeds = shared().lock();
+                    feeds[replica].acked = first + count as u64 - 1;
+                    break;
+                }
+
+                Err(e) => {
+                    if !failing {
+                        warn!("Read replica at {} did not acknowledge writes: {}", addr, e);
+                        failing = true;
+                    }
+                    if shared().lock()[replica].cut {
+                        break;
+                    }
+                    sleep(Duration::from_millis(1));
+                }
+            }
+        }
+        last = Instant::now();
+    }
+
+    error!(
+        "Read replica at {} fell more than {} bytes behind. Writes are no longer fed to it.",
+        addr, MAX_QUEUE_BYTES
+    );
+}
+
+/// Marks the server as a read replica, accepting records from a primary and answering only
+/// stale-tolerant reads.
+pub fn set_replica() {
+    REPLICA.store(true, Ordering::Relaxed);
+}
+
+/// Returns true if the server is a read replica.
+#[inline]
+pub fn replica() -> bool {
+    REPLICA.load(Ordering::Relaxed)
+}
+
+/// Notes that the primary has nothing more to feed this read replica, as of now.
+pub fn caught_up() {
+    FRESH.store(cycles::rdtsc() as usize, Ordering::Relaxed);
+}
+
+/// Returns the number of milliseconds this read replica is behind the primary by, or None if
+/// it has never caught up.
+pub fn staleness_ms() -> Option<u64> {
+    match FRESH.load(Ordering::Relaxed) as u64 {
+        0 => None,
+        fresh => {
+            let elapsed = cycles::rdtsc().saturating_sub(fresh);
+            Some(elapsed * 1000 / cycles::cycles_per_second())
+        }
+    }
+}
+
+/// Returns true if this read replica can answer a request that tolerates data some number of
+/// milliseconds behind the primary's.
+///
+/// # Arguments
+///
+/// * `bound_ms`: The number of milliseconds behind the request tolerates.
+pub fn fresh(bound_ms: u32) -> bool {
+    staleness_ms().map_or(false, |ms| ms <= bound_ms as u64)
+}
+
+/// Parses the staleness bound off an RPC request.
+///
+/// # Arguments
+///
+/// * `payload`: The request, from it's RpcRequestHeader on, without any trace context.
+///
+/// # Return
+///
+/// The number of milliseconds behind the primary the request tolerates, if it is flagged as
+/// tolerating stale data.
+pub fn parse(payload: &[u8]) -> Option<u32> {
+    if payload.len() < size_of::<RpcRequestHeader>() + STALENESS_BOUND_LEN {
+        return None;
+    }
+
+    if payload[0] & REQUEST_FLAG_STALE_OK == 0 {
+        return None;
+    }
+
+    let off = payload.len() - STALENESS_BOUND_LEN;
+    let bound = payload[off..]
+        .iter()
+        .rev()
+        .fold(0u32, |acc, &byte| (acc << 8) | byte as u32);
+    Some(bound)
+}
+
+/// Strips the staleness bound off an RPC request, so that the handler it is dispatched to sees
+/// it as it would have without one. Requests that are spread over a chain of buffers cannot
+/// carry a bound; the flag is cleared on them, and nothing else is touched.
+///
+/// # Arguments
+///
+/// * `request`: The request, parsed upto it's UDP header, with any trace context stripped.
+///
+/// # Return
+///
+/// The number of milliseconds behind the primary the request tolerates, if it carried a bound.
+pub fn strip(request: &mut Packet<UdpHeader, EmptyMetadata>) -> Option<u32> {
+    let flagged = match request.get_payload().get(0) {
+        Some(service) => service & REQUEST_FLAG_STALE_OK != 0,
+        None => false,
+    };
+    if !flagged {
+        return None;
+    }
+
+    let bound = if request.chained_len() == 0 {
+        parse(request.get_payload())
+    } else {
+        None
+    };
+
+    request.get_mut_payload()[0] &= !REQUEST_FLAG_STALE_OK;
+    if bound.is_some() {
+        request.trim_payload_size(STALENESS_BOUND_LEN);
+    }
+
+    bound
+}
+
+/// Describes read replication at this server as a line of JSON: how far behind the primary it
+/// is if it is a read replica, and the state of the feed to each of it's read replicas if it is
+/// a primary.
+pub fn status() -> String {
+    let staleness = match staleness_ms() {
+        Some(ms) => ms.to_string(),
+        None => String::from("null"),
+    };
+
+    let feeds: Vec<String> = shared()
+        .lock()
+        .iter()
+        .map(|feed| {
+            format!(
+                "{{\"addr\":{},\"queued\":{},\"acked\":{},\"cut\":{}}}",
+                quote(&feed.addr),
+                feed.count,
+                feed.acked,
+                feed.cut
+            )
+        })
+        .collect();
+
+    format!(
+        "{{\"read_replica\":{},\"staleness_ms\":{},\"read_replicas\":[{}]}}\n",
+        replica(),
+        staleness,
+        feeds.join(",")
+    )
+}
+
+// This module contains unit tests for read replicas.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::replica::OP_PUT;
+
+    // This unit test verifies that a feed hands out batches in order, says when it has been
+    // drained, and hands out empty batches while there is nothing to ship.
+    #[test]
+    fn test_take() {
+        let mut feed = Feed::new("192.168.0.4:7700");
+        let (first, count, batch, drained) = feed.take();
+        assert_eq!((1, 0, 0, true), (first, count, batch.len(), drained));
+
+        let val = vec![0u8; MAX_BATCH_BYTES / 2];
+        for _ in 0..3 {
+            let rec = Record {
+                op: OP_PUT,
+                tenant: 1,
+                table: 1,
+                key: b"k",
+                val: &val,
+            };
+            feed.append(&rec);
+        }
+
+        let (first, count, _, drained) = feed.take();
+        assert_eq!((1, 1, false), (first, count, drained));
+        let (first, count, _, drained) = feed.take();
+        assert_eq!((2, 1, false), (first, count, drained));
+        let (first, count, _, drained) = feed.take();
+        assert_eq!((3, 1, true), (first, count, drained));
+
+        let (first, count, _, drained) = feed.take();
+        assert_eq!((4, 0, true), (first, count, drained));
+    }
+
+    // This unit test verifies that a replica whose queue outgrows it's limit is cut off, and
+    // nothing more is queued for it.
+    #[test]
+    fn test_cut() {
+        let mut feed = Feed::new("192.168.0.4:7700");
+        let val = vec![0u8; MAX_QUEUE_BYTES / 4];
+        let rec = Record {
+            op: OP_PUT,
+            tenant: 1,
+            table: 1,
+            key: b"k",
+            val: &val,
+        };
+
+        for _ in 0..3 {
+            feed.append(&rec);
+        }
+        assert!(!feed.cut);
+        assert_eq!(3, feed.count);
+
+        feed.append(&rec);
+        assert!(feed.cut);
+        assert_eq!(0, feed.count);
+        assert!(feed.pending.is_empty());
+
+        feed.append(&rec);
+        assert_eq!(0, feed.count);
+    }
+
+    // This unit test verifies that the staleness bound is only parsed off requests flagged as
+    // carrying one.
+    #[test]
+    fn test_parse() {
+        let mut payload = vec![0u8; size_of::<RpcRequestHeader>()];
+        payload[0] = 0x01;
+        payload.extend_from_slice(&[0xe8, 0x03, 0x00, 0x00]);
+        assert_eq!(None, parse(&payload));
+
+        payload[0] |= REQUEST_FLAG_STALE_OK;
+        assert_eq!(Some(1000), parse(&payload));
+        assert_eq!(None, parse(&payload[..size_of::<RpcRequestHeader>() + 3]));
+    }
+}
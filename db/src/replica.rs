@@ -0,0 +1,614 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+
+// Synchronous primary-backup replication. Every put and delete a primary executes, whether it
+// came in on a request or from an extension, is appended to a log as a record carrying the tenant,
+// table, key and value it wrote. A single thread ships the log in batches to the backup's
+// install() TCP endpoint, and the task that made a write is held back until the backup
+// acknowledges it, so that every write a client heard back about is on both servers.
+//
+// Writes are applied locally under the log's lock, so that the backup applies them in the order
+// the primary did. A backup that does not acknowledge a batch within the timeout gets the
+// replication on the primary declared broken: waiting tasks are let go, the primary carries on
+// alone, and health checks report it as not ready until it is restarted with a fresh backup.
+//
+// A server started as a standby applies the records it is shipped, answers reads, and refuses
+// writes until it is promoted. Promotion turns it into a primary without a backup of it's own.
+
+use std::io::{self, Read, Write};
+use std::mem::{size_of, transmute};
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+use std::sync::{Once, ONCE_INIT};
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::audit::quote;
+use super::backup;
+use super::common::{TableId, TenantId};
+use super::geo;
+use super::member;
+use super::migrate;
+use super::readrep;
+use super::wal;
+use super::wireformat::{OpCode, ReplicaAction, RpcRequestHeader, RpcResponseHeader, RpcStatus,
+                        Service};
+
+use spin::Mutex;
+
+/// A record of a put.
+pub const OP_PUT: u8 = 0x01;
+
+/// A record of a delete. It's value is empty.
+pub const OP_DELETE: u8 = 0x02;
+
+/// The number of bytes in the header of a record: the operation (u8), tenant (u32), table (u64),
+/// key length (u16) and value length (u32), little endian. The key and value follow.
+pub const RECORD_HDR_LEN: usize = 19;
+
+/// The number of milliseconds a batch can go unacknowledged for when `backup_timeout_ms` is zero.
+pub const DEFAULT_TIMEOUT_MS: u64 = 1000;
+
+/// The number of bytes of records a batch is cut off at. A batch always has at least one record.
+const MAX_BATCH_BYTES: usize = 1 << 20;
+
+/// A write, as shipped to the backup.
+#[derive(Debug, PartialEq)]
+pub struct Record<'a> {
+    /// `OP_PUT` or `OP_DELETE`.
+    pub op: u8,
+
+    /// The tenant owning the table written to, and the table's identifier.
+    pub tenant: TenantId,
+    pub table: TableId,
+
+    pub key: &'a [u8],
+    pub val: &'a [u8],
+}
+
+/// Appends a record to a buffer.
+///
+/// # Arguments
+///
+/// * `buf`: The buffer.
+/// * `rec`: The record.
+pub fn encode(buf: &mut Vec<u8>, rec: &Record) {
+    let words = [
+        (rec.tenant as u64, 4),
+        (rec.table, 8),
+        (rec.key.len() as u64, 2),
+        (rec.val.len() as u64, 4),
+    ];
+
+    buf.push(rec.op);
+    for &(val, len) in words.iter() {
+        for byte in 0..len {
+            buf.push((val >> (8 * byte)) as u8);
+        }
+    }
+    buf.extend_from_slice(rec.key);
+    buf.extend_from_slice(rec.val);
+}
+
+/// Parses the record at the start of a buffer.
+///
+/// # Arguments
+///
+/// * `buf`: The buffer.
+///
+/// # Return
+///
+/// The record, along with the number of bytes it took up. None if the buffer is too short.
+pub fn decode(buf: &[u8]) -> Option<(Record, usize)> {
+    if buf.len() < RECORD_HDR_LEN {
+        return None;
+    }
+
+    let le = |off: usize, len: usize| {
+        buf[off..off + len]
+            .iter()
+            .rev()
+            .fold(0u64, |acc, &byte| (acc << 8) | byte as u64)
+    };
+    let key_len = le(13, 2) as usize;
+    let val_len = le(15, 4) as usize;
+    let len = RECORD_HDR_LEN + key_len + val_len;
+    if buf.len() < len {
+        return None;
+    }
+
+    let key = &buf[RECORD_HDR_LEN..RECORD_HDR_LEN + key_len];
+    let rec = Record {
+        op: buf[0],
+        tenant: le(1, 4) as TenantId,
+        table: le(5, 8) as TableId,
+        key: key,
+        val: &buf[RECORD_HDR_LEN + key_len..len],
+    };
+    Some((rec, len))
+}
+
+// The records waiting to be shipped, and what the backup applied from the primary shipping to
+// it.
+struct Log {
+    // The sequence number of the next record appended. Sequence numbers start at 1.
+    next: u64,
+
+    // The sequence number of the first record in `pending`, and the records themselves.
+    first: u64,
+    pending: Vec<u8>,
+    count: usize,
+
+    // On a standby, the epoch of the primary the records applied came from, and the sequence
+    // number of the last one.
+    applied_epoch: u64,
+    applied: u64,
+}
+
+// Implementation of methods on Log.
+impl Log {
+    // Returns an empty log.
+    fn new() -> Log {
+        Log {
+            next: 1,
+            first: 1,
+            pending: Vec::new(),
+            count: 0,
+            applied_epoch: 0,
+            applied: 0,
+        }
+    }
+
+    // Appends a record, returning it's sequence number.
+    fn append(&mut self, rec: &Record) -> u64 {
+        let seq = self.next;
+        self.next += 1;
+        if self.count == 0 {
+            self.first = seq;
+        }
+
+        encode(&mut self.pending, rec);
+        self.count += 1;
+        seq
+    }
+
+    // Takes the records appended upto `MAX_BATCH_BYTES`, along with the sequence number of the
+    // first of them. None if there are none.
+    fn take(&mut self) -> Option<(u64, usize, Vec<u8>)> {
+        if self.count == 0 {
+            return None;
+        }
+
+        // Cut at the last record boundary below the limit, keeping at least one record.
+        let mut len = 0;
+        let mut count = 0;
+        while count < self.count {
+            let next = match decode(&self.pending[len..]) {
+                Some((_, next)) => next,
+                None => break,
+            };
+            if count > 0 && len + next > MAX_BATCH_BYTES {
+                break;
+            }
+            len += next;
+            count += 1;
+        }
+
+        let rest = self.pending.split_off(len);
+        let batch = ::std::mem::replace(&mut self.pending, rest);
+        let first = self.first;
+        self.first += count as u64;
+        self.count -= count;
+        Some((first, count, batch))
+    }
+
+    // Returns the number of records at the start of a batch of `count` starting at `first` that
+    // were already applied, and notes that the rest are about to be. A new epoch means a new
+    // primary, whose records are all applied.
+    fn admit(&mut self, epoch: u64, first: u64, count: usize) -> usize {
+        if count == 0 {
+            return 0;
+        }
+
+        if epoch != self.applied_epoch {
+            self.applied_epoch = epoch;
+            self.applied = 0;
+        }
+
+        let skip = (self.applied + 1).saturating_sub(first).min(count as u64) as usize;
+        self.applied = self.applied.max(first + count as u64 - 1);
+        skip
+    }
+}
+
+/// Set if writes are being shipped to a backup.
+static ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Set if the backup stopped acknowledging writes, and they no longer are shipped.
+static BROKEN: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Set if the server is a standby, refusing writes until promoted.
+static STANDBY: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// The sequence number of the last record the backup acknowledged.
+static ACKED: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The epoch of this server's log, the time in nanoseconds since the UNIX epoch at which
+/// shipping started. Lets a backup tell a restarted primary from the one before it.
+static EPOCH: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The credential servers present to each other on replica() RPCs, zero if there is none.
+static CREDENTIAL: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Initializes LOG exactly once.
+static LOG_INIT: Once = ONCE_INIT;
+
+/// The log shared by all threads. Use `shared()` to access it.
+static mut LOG: *const Mutex<Log> = 0 as *const Mutex<Log>;
+
+// Returns the log shared by all threads, allocating it on first use.
+fn shared() -> &'static Mutex<Log> {
+    unsafe {
+        LOG_INIT.call_once(|| {
+            LOG = Box::into_raw(Box::new(Mutex::new(Log::new())));
+        });
+        &*LOG
+    }
+}
+
+/// Sets the credential this server presents on the records it ships, and expects on the records
+/// shipped to it.
+pub fn set_credential(credential: u64) {
+    CREDENTIAL.store(credential as usize, Ordering::Relaxed);
+}
+
+/// Returns the credential servers present to each other on replica() RPCs, zero if there is
+/// none.
+pub fn credential() -> u64 {
+    CREDENTIAL.load(Ordering::Relaxed) as u64
+}
+
+/// Marks the server as a standby, refusing writes and accepting records until promoted.
+pub fn set_standby() {
+    STANDBY.store(true, Ordering::Relaxed);
+}
+
+/// Promotes a standby to a primary, which stops accepting records and starts accepting writes.
+///
+/// # Return
+///
+/// False if the server was not a standby.
+pub fn promote() -> bool {
+    STANDBY.swap(false, Ordering::Relaxed)
+}
+
+/// Returns true if the server is a standby, and should refuse writes.
+#[inline]
+pub fn standby() -> bool {
+    STANDBY.load(Ordering::Relaxed)
+}
+
+/// Returns true if replication to the backup broke down, and writes are no longer shipped.
+pub fn broken() -> bool {
+    BROKEN.load(Ordering::Relaxed)
+}
+
+/// Applies a write locally and, if writes are being shipped to a backup, appends a record of it
+/// to the log. Writes to the tables of a tenant being migrated are captured along the way (see
+/// `migrate::write()`), every write is fed to read replicas (see `readrep::write()`), appended
+/// to the write-ahead log (see `wal::write()`), noted for the next backup (see
+/// `backup::write()`), and versioned and shipped to a remote site if it's tenant is
+/// geo-replicated (see `geo::write()`).
+///
+/// # Arguments
+///
+/// * `rec`:   The record of the write.
+/// * `apply`: Applies the write to the local table.
+///
+/// # Return
+///
+/// The sequence number to wait on with `acked()` before acknowledging the write, or zero if it
+/// need not be waited on.
+pub fn write<F: FnOnce()>(rec: &Record, apply: F) -> u64 {
+    geo::write(rec, || {
+        backup::write(rec, || {
+            if !ENABLED.load(Ordering::Relaxed) || BROKEN.load(Ordering::Relaxed) {
+                migrate::write(rec, || readrep::write(rec, || wal::write(rec, apply)));
+                return 0;
+            }
+
+            let mut log = shared().lock();
+            migrate::write(rec, || readrep::write(rec, || wal::write(rec, apply)));
+            log.append(rec)
+        })
+    })
+}
+
+/// Returns true once the backup acknowledged the record with a sequence number, or if
+/// replication broke down.
+///
+/// # Arguments
+///
+/// * `seq`: The sequence number returned by `write()`.
+#[inline]
+pub fn acked(seq: u64) -> bool {
+    seq == 0 || seq <= ACKED.load(Ordering::Acquire) as u64 || BROKEN.load(Ordering::Relaxed)
+}
+
+/// Starts appending writes to the log. Must be called before the server accepts requests, with
+/// `ship()` called on a thread of it's own, or tasks making writes wait forever.
+pub fn enable() {
+    let epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() * 1000000000 + d.subsec_nanos() as u64)
+        .unwrap_or(1);
+    EPOCH.store(epoch as usize, Ordering::Relaxed);
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Ships the log to the backup until the backup fails to acknowledge a batch within the
+/// timeout, or is declared dead by membership (see `member`). Blocks the calling thread.
+///
+/// # Arguments
+///
+/// * `addr`:       The address of the backup's install() TCP endpoint.
+/// * `timeout_ms`: The number of milliseconds a batch can go unacknowledged for.
+pub fn ship(addr: &str, timeout_ms: u64) {
+    let epoch = EPOCH.load(Ordering::Relaxed) as u64;
+    info!("Replicating writes to the backup at {}", addr);
+
+    let timeout = Duration::from_millis(timeout_ms);
+    loop {
+        let batch = shared().lock().take();
+        let (first, count, records) = match batch {
+            Some(batch) => batch,
+            None => {
+                sleep(Duration::from_micros(10));
+                continue;
+            }
+        };
+
+        // A batch the backup might have applied without acknowledging is resent as is; the
+        // backup skips the records in it that it already applied.
+        let start = Instant::now();
+        loop {
+            let action = [ReplicaAction::Apply as u8];
+            match send(addr, &action, epoch, first, count, &records) {
+                Ok(()) => {
+                    let last = first + count as u64 - 1;
+                    ACKED.store(last as usize, Ordering::Release);
+                    break;
+                }
+
+                Err(e) => {
+                    if start.elapsed() > timeout || member::down(addr) {
+                        error!(
+                            "Backup at {} did not acknowledge records {} to {}: {}. Writes are \
+                             no longer replicated.",
+                            addr,
+                            first,
+                            first + count as u64 - 1,
+                            e
+                        );
+                        BROKEN.store(true, Ordering::Relaxed);
+
+                        // Nothing more is shipped, so drop what is waiting to be.
+                        let mut log = shared().lock();
+                        log.pending.clear();
+                        log.count = 0;
+                        return;
+                    }
+
+                    sleep(Duration::from_millis(1));
+                }
+            }
+        }
+    }
+}
+
+/// Sends a batch of records to a server's install() TCP endpoint, and waits for it to
+/// acknowledge them.
+///
+/// # Arguments
+///
+/// * `addr`:    The address of the server's install() TCP endpoint.
+/// * `action`:  The `ReplicaAction` to send the batch on, followed by whatever arguments it
+///              takes ahead of the epoch. This server's credential (see `credential()`) is
+///              sent between the two.
+/// * `epoch`:   The epoch of the log the records come from.
+/// * `first`:   The sequence number of the first record.
+/// * `count`:   The number of records.
+/// * `records`: The records, as encoded by `encode()`.
+///
+/// # Return
+///
+/// An error if the server could not be reached, or did not apply the records.
+pub fn send(
+    addr: &str,
+    action: &[u8],
+    epoch: u64,
+    first: u64,
+    count: usize,
+    records: &[u8],
+) -> io::Result<()> {
+    let hdr = RpcRequestHeader::new(Service::MasterService, OpCode::SandstormReplicaRpc, 0, first);
+    let hdr: [u8; size_of::<RpcRequestHeader>()] = unsafe { transmute(hdr) };
+
+    let mut req = Vec::with_capacity(hdr.len() + action.len() + 28 + records.len());
+    req.extend_from_slice(&hdr);
+    req.extend_from_slice(&action[..1]);
+    for byte in 0..8 {
+        req.push((credential() >> (8 * byte)) as u8);
+    }
+    req.extend_from_slice(&action[1..]);
+    for &(val, len) in [(epoch, 8), (first, 8), (count as u64, 4)].iter() {
+        for byte in 0..len {
+            req.push((val >> (8 * byte)) as u8);
+        }
+    }
+    req.extend_from_slice(records);
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(&req)?;
+    stream.flush()?;
+    stream.shutdown(Shutdown::Write)?;
+
+    let mut res = Vec::new();
+    stream.read_to_end(&mut res)?;
+    if res.len() < size_of::<RpcResponseHeader>() {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated response"));
+    }
+    if res[0] != RpcStatus::StatusOk as u8 {
+        let msg = format!("refused with status {}", res[0]);
+        return Err(io::Error::new(io::ErrorKind::Other, msg));
+    }
+
+    Ok(())
+}
+
+/// Works out which records of a batch shipped by a primary a standby should apply.
+///
+/// # Arguments
+///
+/// * `epoch`: The epoch of the primary's log.
+/// * `first`: The sequence number of the first record in the batch.
+/// * `count`: The number of records in the batch.
+///
+/// # Return
+///
+/// The number of records at the start of the batch that were already applied, and should be
+/// skipped.
+pub fn admit(epoch: u64, first: u64, count: usize) -> usize {
+    shared().lock().admit(epoch, first, count)
+}
+
+/// Describes the state of replication at this server as a line of JSON.
+///
+/// # Arguments
+///
+/// * `backup`: The address of the backup, empty if there is none.
+pub fn status(backup: &str) -> String {
+    let (next, applied_epoch, applied) = {
+        let log = shared().lock();
+        (log.next, log.applied_epoch, log.applied)
+    };
+
+    let role = if standby() { "standby" } else { "primary" };
+    let backup = if backup.len() > 0 {
+        quote(backup)
+    } else {
+        String::from("null")
+    };
+    format!(
+        "{{\"role\":\"{}\",\"backup\":{},\"broken\":{},\"epoch\":{},\"appended\":{},\
+         \"acked\":{},\"applied_epoch\":{},\"applied\":{}}}\n",
+        role,
+        backup,
+        broken(),
+        EPOCH.load(Ordering::Relaxed),
+        next - 1,
+        ACKED.load(Ordering::Relaxed),
+        applied_epoch,
+        applied
+    )
+}
+
+// This module contains unit tests for replication.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // This unit test verifies that records survive a round trip through a buffer, and that a
+    // truncated one is not parsed.
+    #[test]
+    fn test_encode() {
+        let put = Record {
+            op: OP_PUT,
+            tenant: 7,
+            table: 1 << 40,
+            key: b"key",
+            val: b"value",
+        };
+        let del = Record {
+            op: OP_DELETE,
+            tenant: 8,
+            table: 2,
+            key: b"k",
+            val: &[],
+        };
+
+        let mut buf = Vec::new();
+        encode(&mut buf, &put);
+        encode(&mut buf, &del);
+        assert_eq!(2 * RECORD_HDR_LEN + 9, buf.len());
+
+        let (first, len) = decode(&buf).unwrap();
+        assert_eq!(put, first);
+        let (second, rest) = decode(&buf[len..]).unwrap();
+        assert_eq!(del, second);
+        assert_eq!(buf.len(), len + rest);
+
+        assert!(decode(&buf[..len - 1]).is_none());
+        assert!(decode(&buf[..RECORD_HDR_LEN - 1]).is_none());
+    }
+
+    // This unit test verifies that batches are taken in order and cut at the size limit.
+    #[test]
+    fn test_take() {
+        let mut log = Log::new();
+        assert!(log.take().is_none());
+
+        let val = vec![0u8; MAX_BATCH_BYTES / 2];
+        for _ in 0..3 {
+            let rec = Record {
+                op: OP_PUT,
+                tenant: 1,
+                table: 1,
+                key: b"k",
+                val: &val,
+            };
+            log.append(&rec);
+        }
+
+        let (first, count, batch) = log.take().unwrap();
+        assert_eq!((1, 1), (first, count));
+        assert_eq!(RECORD_HDR_LEN + 1 + val.len(), batch.len());
+
+        let (first, count, _) = log.take().unwrap();
+        assert_eq!((2, 1), (first, count));
+        let (first, count, _) = log.take().unwrap();
+        assert_eq!((3, 1), (first, count));
+        assert!(log.take().is_none());
+    }
+
+    // This unit test verifies that a standby skips records it already applied, unless they come
+    // from a new primary.
+    #[test]
+    fn test_admit() {
+        let mut log = Log::new();
+        assert_eq!(0, log.admit(5, 1, 4));
+        assert_eq!(4, log.applied);
+
+        // A resent batch overlapping what was applied.
+        assert_eq!(2, log.admit(5, 3, 4));
+        assert_eq!(6, log.applied);
+        assert_eq!(3, log.admit(5, 1, 3));
+
+        // A restarted primary.
+        assert_eq!(0, log.admit(9, 1, 2));
+        assert_eq!(2, log.applied);
+    }
+}
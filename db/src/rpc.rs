@@ -14,13 +14,20 @@
  */
 
 use std::mem::{size_of, transmute};
+use std::slice;
 
+use super::error::{self, DispatchError, NetError};
 use super::wireformat::*;
 
 use e2d2::common::EmptyMetadata;
-use e2d2::headers::{IpHeader, MacHeader, UdpHeader};
+use e2d2::headers::{EndOffset, IpHeader, MacHeader, UdpHeader};
 use e2d2::interface::*;
 
+/// The largest payload a UDP datagram carries, bounded by the 16 bit length fields on the UDP
+/// and IP headers. Requests and responses are a single datagram each, so this bounds the size
+/// of keys and values along with their RPC headers.
+pub const MAX_DATAGRAM_PAYLOAD: usize = 0xffff - 20 - 8;
+
 /// This function looks into a packet corresponding to an RPC request, and
 /// reads it's service (assumed to be the first byte after the end of the
 /// UDP header).
@@ -35,8 +42,12 @@ use e2d2::interface::*;
 /// If valid, the service the request should be dispatched to. If invalid, a
 /// code corresponding to an invalid service (InvalidService).
 pub fn parse_rpc_service(request: &Packet<UdpHeader, EmptyMetadata>) -> Service {
-    // Read the service off the first byte on the payload.
-    let service: u8 = request.get_payload()[0];
+    // Read the service off the first byte on the payload. A request tolerating stale data is
+    // dispatched like any other; the service strips the flag (see `readrep::strip()`).
+    let service: u8 = match request.get_payload().get(0) {
+        Some(service) => service & !REQUEST_FLAG_STALE_OK,
+        None => return Service::InvalidService,
+    };
     match service.lt(&(Service::InvalidService as u8)) {
         true => unsafe {
             let service: Service = transmute(service);
@@ -64,7 +75,10 @@ pub fn parse_rpc_service(request: &Packet<UdpHeader, EmptyMetadata>) -> Service
 /// to an invalid operation (InvalidOperation) will be returned.
 pub fn parse_rpc_opcode(request: &Packet<UdpHeader, EmptyMetadata>) -> OpCode {
     // Read the opcode off the second byte on the payload.
-    let opcode: u8 = request.get_payload()[1];
+    let opcode: u8 = match request.get_payload().get(1) {
+        Some(&opcode) => opcode,
+        None => return OpCode::InvalidOperation,
+    };
     match opcode.lt(&(OpCode::InvalidOperation as u8)) {
         true => unsafe {
             let opcode: OpCode = transmute(opcode);
@@ -77,11 +91,306 @@ pub fn parse_rpc_opcode(request: &Packet<UdpHeader, EmptyMetadata>) -> OpCode {
     }
 }
 
-/// Allocate a packet with MAC, IP, and UDP headers for an RPC request.
+/// This function reads the tenant off an RPC request's header (assumed to be the four bytes
+/// after the service and opcode). Unlike the functions above, it works on a raw payload so that
+/// the tenant can be read before the UDP header has been parsed.
+///
+/// # Arguments
+///
+/// * `payload`: The payload of a packet carrying an RPC request.
+/// * `offset`:  The offset into `payload` at which the RPC header starts.
+///
+/// # Return
+///
+/// The tenant on the RPC request, or None if the payload is too short to carry one.
+pub fn parse_rpc_tenant(payload: &[u8], offset: usize) -> Option<u32> {
+    Reader::at(payload, offset.checked_add(2)?).u32_le()
+}
+
+/// This function looks into a packet corresponding to an RPC request, and reads it's identifier
+/// (assumed to be the eight bytes after the tenant).
+///
+/// # Arguments
+///
+/// * `request`: A reference to a packet corresponding to an RPC request.
+///              The packet should have been parsed upto it's UDP header.
+///
+/// # Return
+///
+/// The identifier on the RPC request, or zero if the request is too short to carry one.
+pub fn parse_rpc_stamp(request: &Packet<UdpHeader, EmptyMetadata>) -> u64 {
+    Reader::at(request.get_payload(), 6).u64_le().unwrap_or(0)
+}
+
+/// This function reads the key a get() or put() request is for off it's payload.
+///
+/// # Arguments
+///
+/// * `payload`: The payload of a request, from it's RpcRequestHeader on.
+///
+/// # Return
+///
+/// The key, or None if the request is neither a get() nor a put(), or is too short to carry the
+/// key it says it does.
+pub fn parse_rpc_key(payload: &[u8]) -> Option<&[u8]> {
+    if payload.get(1) == Some(&(OpCode::SandstormMultiGetRpc as u8)) {
+        return None;
+    }
+
+    let hdr = KeyedRequest::read(payload)?;
+    Reader::at(payload, hdr.header_length).bytes(hdr.key_length)
+}
+
+/// This function looks into a packet corresponding to an RPC response, and checks whether the
+/// server marked it as having been sent while the server was congested.
+///
+/// # Arguments
+///
+/// * `response`: A reference to a packet corresponding to an RPC response.
+///               The packet should have been parsed upto it's UDP header.
+///
+/// # Return
+///
+/// True if the response carries the RESPONSE_FLAG_CONGESTED flag.
+pub fn parse_rpc_congested(response: &Packet<UdpHeader, EmptyMetadata>) -> bool {
+    let offset = size_of::<RpcResponseHeader>() - 1;
+    match response.get_payload().get(offset) {
+        Some(flags) => flags & RESPONSE_FLAG_CONGESTED != 0,
+        None => false,
+    }
+}
+
+/// Sets the RESPONSE_FLAG_CONGESTED flag on an RPC response.
+///
+/// # Arguments
+///
+/// * `response`: A packet corresponding to an RPC response. The packet should have been
+///               parsed upto it's IP header.
+pub fn mark_rpc_congested(response: &mut Packet<IpHeader, EmptyMetadata>) {
+    let offset = size_of::<UdpHeader>() + size_of::<RpcResponseHeader>() - 1;
+    if let Some(flags) = response.get_mut_payload().get_mut(offset) {
+        *flags |= RESPONSE_FLAG_CONGESTED;
+    }
+}
+
+/// Sets the priority of an RPC request within it's tenant's share of the server.
+///
+/// # Arguments
+///
+/// * `request`:  A packet corresponding to an RPC request, parsed upto it's IP header.
+/// * `priority`: The priority, upto MAX_REQUEST_PRIORITY. Higher values are clamped to it.
+pub fn set_rpc_priority(request: &mut Packet<IpHeader, EmptyMetadata>, priority: u8) {
+    let priority = priority.min(MAX_REQUEST_PRIORITY) << REQUEST_PRIORITY_SHIFT;
+    if let Some(service) = request.get_mut_payload().get_mut(size_of::<UdpHeader>()) {
+        *service = (*service & !REQUEST_PRIORITY_MASK) | priority;
+    }
+}
+
+/// Clears the priority off an RPC request, so that the service it is dispatched to sees it as
+/// it would have without one.
+///
+/// # Arguments
+///
+/// * `request`: A packet corresponding to an RPC request, parsed upto it's UDP header.
+///
+/// # Return
+///
+/// The priority the request carried, zero if it carried none.
+pub fn strip_rpc_priority(request: &mut Packet<UdpHeader, EmptyMetadata>) -> u8 {
+    match request.get_mut_payload().get_mut(0) {
+        Some(service) => {
+            let priority = (*service & REQUEST_PRIORITY_MASK) >> REQUEST_PRIORITY_SHIFT;
+            *service &= !REQUEST_PRIORITY_MASK;
+            priority
+        }
+
+        None => 0,
+    }
+}
+
+/// Asks the server to trace an RPC request, by appending a trace context to it and setting
+/// REQUEST_FLAG_TRACED on it's header.
+///
+/// # Arguments
+///
+/// * `request`: A packet corresponding to an RPC request, parsed upto it's IP header. It must
+///              not be spread over a chain of buffers.
+/// * `trace`:   The identifier of the trace the request belongs to.
+/// * `parent`:  The identifier of the span the request is being issued under.
+///
+/// # Return
+///
+/// The request parsed upto it's IP header, or the request as it was handed in if there was no
+/// room in it's buffer or datagram for the trace context.
+pub fn add_rpc_trace(
+    request: Packet<IpHeader, EmptyMetadata>,
+    trace: &[u8; 16],
+    parent: &[u8; 8],
+) -> Packet<IpHeader, EmptyMetadata> {
+    let mut context = [0u8; TRACE_CONTEXT_LEN];
+    context[..16].copy_from_slice(trace);
+    context[16..].copy_from_slice(parent);
+
+    let mut request = request.parse_header::<UdpHeader>();
+    let len = request.get_payload().len();
+    if request.chained_len() > 0 || len == 0 || len + TRACE_CONTEXT_LEN > MAX_DATAGRAM_PAYLOAD {
+        return request.deparse_header(size_of::<IpHeader>());
+    }
+
+    if request.add_to_payload_tail(TRACE_CONTEXT_LEN, &context).is_err() {
+        return request.deparse_header(size_of::<IpHeader>());
+    }
+
+    request.get_mut_payload()[0] |= REQUEST_FLAG_TRACED;
+    refit_request(request)
+}
+
+/// Lets a read replica answer an RPC request out of data that is behind the primary's, by
+/// appending a staleness bound to it and setting REQUEST_FLAG_STALE_OK on it's header. Must be
+/// called before `add_rpc_deadline()` and `add_rpc_trace()`, if the request also carries a
+/// deadline or is traced.
+///
+/// # Arguments
+///
+/// * `request`:  A packet corresponding to a get, multiget or invoke request, parsed upto it's
+///               IP header. It must not be spread over a chain of buffers.
+/// * `bound_ms`: The number of milliseconds behind the primary the request can be answered at.
+///
+/// # Return
+///
+/// The request parsed upto it's IP header, or the request as it was handed in if there was no
+/// room in it's buffer or datagram for the bound.
+pub fn add_rpc_staleness(
+    request: Packet<IpHeader, EmptyMetadata>,
+    bound_ms: u32,
+) -> Packet<IpHeader, EmptyMetadata> {
+    let bound: [u8; STALENESS_BOUND_LEN] = unsafe { transmute(bound_ms.to_le()) };
+
+    let mut request = request.parse_header::<UdpHeader>();
+    let len = request.get_payload().len();
+    if request.chained_len() > 0 || len == 0 || len + STALENESS_BOUND_LEN > MAX_DATAGRAM_PAYLOAD {
+        return request.deparse_header(size_of::<IpHeader>());
+    }
+
+    if request.add_to_payload_tail(STALENESS_BOUND_LEN, &bound).is_err() {
+        return request.deparse_header(size_of::<IpHeader>());
+    }
+
+    request.get_mut_payload()[0] |= REQUEST_FLAG_STALE_OK;
+    refit_request(request)
+}
+
+/// Tells the server how long the client will wait for a response to an RPC request, by
+/// appending the budget to it and setting REQUEST_FLAG_DEADLINE on it's header. The server does
+/// not start work on the request once the budget has run out. Must be called after
+/// `add_rpc_staleness()` and before `add_rpc_trace()`, if the request also carries a staleness
+/// bound or is traced.
+///
+/// # Arguments
+///
+/// * `request`:   A packet corresponding to an RPC request, parsed upto it's IP header. It must
+///                not be spread over a chain of buffers.
+/// * `budget_us`: The number of microseconds left before the client gives up on the request.
+///
+/// # Return
 ///
-/// # Panic
+/// The request parsed upto it's IP header, or the request as it was handed in if there was no
+/// room in it's buffer or datagram for the budget.
+pub fn add_rpc_deadline(
+    request: Packet<IpHeader, EmptyMetadata>,
+    budget_us: u32,
+) -> Packet<IpHeader, EmptyMetadata> {
+    let budget: [u8; DEADLINE_BUDGET_LEN] = unsafe { transmute(budget_us.to_le()) };
+
+    let mut request = request.parse_header::<UdpHeader>();
+    let len = request.get_payload().len();
+    if request.chained_len() > 0 || len == 0 || len + DEADLINE_BUDGET_LEN > MAX_DATAGRAM_PAYLOAD {
+        return request.deparse_header(size_of::<IpHeader>());
+    }
+
+    if request.add_to_payload_tail(DEADLINE_BUDGET_LEN, &budget).is_err() {
+        return request.deparse_header(size_of::<IpHeader>());
+    }
+
+    request.get_mut_payload()[0] |= REQUEST_FLAG_DEADLINE;
+    refit_request(request)
+}
+
+/// Allocates a packet with MAC, IP, and UDP headers on it. The headers are written into the
+/// packet's buffer before it is parsed upto them, so that the packet is freed instead of leaked
+/// if there is no room for them.
+///
+/// # Arguments
+///
+/// * `mac`: Reference to the MAC header to be added to the packet.
+/// * `ip` : Reference to the IP header to be added to the packet.
+/// * `udp`: Reference to the UDP header to be added to the packet.
+///
+/// # Return
+///
+/// A packet parsed upto it's UDP header, with the supplied network headers written into it, or
+/// an error if a packet could not be allocated or had no room for them.
+pub fn create_packet(
+    mac: &MacHeader,
+    ip: &IpHeader,
+    udp: &UdpHeader,
+) -> error::Result<Packet<UdpHeader, EmptyMetadata>> {
+    let packet = new_packet().ok_or(NetError::PacketAlloc)?;
+    let packet = write_payload(packet, &[as_bytes(mac), as_bytes(ip), as_bytes(udp)], "headers")?;
+
+    Ok(packet
+        .parse_header::<MacHeader>()
+        .parse_header::<IpHeader>()
+        .parse_header::<UdpHeader>())
+}
+
+/// Writes an RPC header onto a packet parsed upto it's UDP header. The header is written into
+/// the packet's buffer before the packet is parsed upto it, so that the packet is handed back
+/// instead of leaked if there is no room for it.
+///
+/// # Arguments
+///
+/// * `packet`: A packet parsed upto it's UDP header, with nothing after it.
+/// * `header`: The RPC header to be written into the packet.
+///
+/// # Return
 ///
-/// Panics if allocation or header manipulation fails at any point.
+/// The packet parsed upto the RPC header, or the packet as it was handed in if there was no
+/// room in it's buffer for the header.
+pub fn push_rpc_header<T: EndOffset<PreviousHeader = UdpHeader>>(
+    mut packet: Packet<UdpHeader, EmptyMetadata>,
+    header: &T,
+) -> Result<Packet<T, EmptyMetadata>, Packet<UdpHeader, EmptyMetadata>> {
+    if packet.add_to_payload_tail(size_of::<T>(), as_bytes(header)).is_err() {
+        return Err(packet);
+    }
+
+    Ok(packet.parse_header::<T>())
+}
+
+// Appends each of `parts` to the payload of a packet, freeing the packet if there is no room in
+// it's buffer for them. `what` names the parts on the error.
+fn write_payload<T: EndOffset>(
+    mut packet: Packet<T, EmptyMetadata>,
+    parts: &[&[u8]],
+    what: &'static str,
+) -> error::Result<Packet<T, EmptyMetadata>> {
+    for part in parts.iter().filter(|part| part.len() > 0) {
+        if packet.add_to_payload_tail(part.len(), part).is_err() {
+            packet.free_packet();
+            return Err(NetError::NoRoom(what).into());
+        }
+    }
+
+    Ok(packet)
+}
+
+// Returns the bytes of a header, as they are laid out on the wire.
+fn as_bytes<T>(header: &T) -> &[u8] {
+    unsafe { slice::from_raw_parts(header as *const T as *const u8, size_of::<T>()) }
+}
+
+/// Allocate a packet with MAC, IP, and UDP headers for an RPC request.
 ///
 /// # Arguments
 ///
@@ -92,27 +401,21 @@ pub fn parse_rpc_opcode(request: &Packet<UdpHeader, EmptyMetadata>) -> OpCode {
 ///
 /// # Return
 ///
-/// A packet with the supplied network headers written into it.
+/// A packet with the supplied network headers written into it, or an error if one could not
+/// be allocated.
 #[inline]
 fn create_request(
     mac: &MacHeader,
     ip: &IpHeader,
     udp: &UdpHeader,
     dst: u16,
-) -> Packet<UdpHeader, EmptyMetadata> {
-    let mut packet = new_packet()
-        .expect("Failed to allocate packet for request!")
-        .push_header(mac)
-        .expect("Failed to push MAC header into request!")
-        .push_header(ip)
-        .expect("Failed to push IP header into request!")
-        .push_header(udp)
-        .expect("Failed to push UDP header into request!");
+) -> error::Result<Packet<UdpHeader, EmptyMetadata>> {
+    let mut packet = create_packet(mac, ip, udp)?;
 
     // Write the destination port into the UDP header.
     packet.get_mut_header().set_dst_port(dst);
 
-    return packet;
+    return Ok(packet);
 }
 
 /// Sets the length fields on the UDP and IP headers of a packet.
@@ -124,12 +427,19 @@ fn create_request(
 ///
 /// # Return
 ///
-/// A packet parsed upto it's IP headers with said fields set.
+/// A packet parsed upto it's IP headers with said fields set. If the packet carries more than
+/// `MAX_DATAGRAM_PAYLOAD` bytes of payload, which the length fields cannot describe, the packet
+/// is handed back untouched as an error instead.
 pub fn fixup_header_length_fields(
     mut request: Packet<UdpHeader, EmptyMetadata>,
-) -> Packet<IpHeader, EmptyMetadata> {
-    // Set fields on the UDP header.
-    let udp_len = (size_of::<UdpHeader>() + request.get_payload().len()) as u16;
+) -> Result<Packet<IpHeader, EmptyMetadata>, Packet<UdpHeader, EmptyMetadata>> {
+    // Set fields on the UDP header. A longer payload would silently wrap the length fields.
+    let payload = request.get_payload().len() + request.chained_len();
+    if payload > MAX_DATAGRAM_PAYLOAD {
+        return Err(request);
+    }
+
+    let udp_len = (size_of::<UdpHeader>() + payload) as u16;
     request.get_mut_header().set_length(udp_len);
 
     // Set fields on the IP header.
@@ -138,15 +448,88 @@ pub fn fixup_header_length_fields(
         .get_mut_header()
         .set_length(size_of::<IpHeader>() as u16 + udp_len);
 
-    return request;
+    return Ok(request);
 }
 
-/// Allocate and populate a packet that requests a server "get" operation.
+/// Cuts a response that is too long for a single datagram down to it's RPC header, with the
+/// status set to StatusValueTooLarge, so that the client hears why instead of waiting on a
+/// response that can never be sent.
+///
+/// # Arguments
 ///
-/// # Panic
+/// * `response`: A packet corresponding to an RPC response, parsed upto it's UDP header, that
+///               `fixup_header_length_fields()` refused. It is freed.
 ///
-/// May panic if there is a problem allocating the packet or constructing
-/// headers.
+/// # Return
+///
+/// A new response carrying the network headers and RPC header of the old one, parsed upto it's
+/// IP header with it's length fields set, or None if a packet could not be allocated for it.
+pub fn truncate_rpc_response(
+    response: Packet<UdpHeader, EmptyMetadata>,
+) -> Option<Packet<IpHeader, EmptyMetadata>> {
+    let frame = response
+        .deparse_header(size_of::<IpHeader>())
+        .deparse_header(size_of::<MacHeader>());
+
+    // Copy the MAC header, followed by the IP, UDP and RPC headers on the old response.
+    let truncated = new_packet().and_then(|packet| packet.push_header(frame.get_header()));
+    let mut truncated = match truncated {
+        Some(truncated) => truncated,
+        None => {
+            frame.free_packet();
+            return None;
+        }
+    };
+
+    let len = size_of::<IpHeader>() + size_of::<UdpHeader>() + size_of::<RpcResponseHeader>();
+    let copied = match frame.get_payload().get(..len) {
+        Some(headers) => truncated.add_to_payload_tail(len, headers).is_ok(),
+        None => false,
+    };
+    frame.free_packet();
+    if !copied {
+        truncated.free_packet();
+        return None;
+    }
+
+    // The status is the first field on the RPC header.
+    let mut truncated = truncated.parse_header::<IpHeader>().parse_header::<UdpHeader>();
+    truncated.get_mut_payload()[0] = RpcStatus::StatusValueTooLarge as u8;
+    match fixup_header_length_fields(truncated) {
+        Ok(truncated) => Some(truncated),
+        Err(truncated) => {
+            truncated.free_packet();
+            None
+        }
+    }
+}
+
+// Sets the length fields on a request built by one of the functions below, freeing the
+// request if it is too long for a single datagram.
+fn finish_request(
+    request: Packet<UdpHeader, EmptyMetadata>,
+) -> error::Result<Packet<IpHeader, EmptyMetadata>> {
+    match fixup_header_length_fields(request) {
+        Ok(request) => Ok(request),
+        Err(request) => {
+            let len = request.get_payload().len() + request.chained_len();
+            request.free_packet();
+            Err(NetError::DatagramTooLong(len).into())
+        }
+    }
+}
+
+// Sets the length fields on a request that `add_rpc_trace()`, `add_rpc_staleness()` or
+// `add_rpc_deadline()` appended to. They only append to a request that still fits in a single
+// datagram afterwards, so the fixup cannot fail.
+fn refit_request(request: Packet<UdpHeader, EmptyMetadata>) -> Packet<IpHeader, EmptyMetadata> {
+    match fixup_header_length_fields(request) {
+        Ok(request) => request,
+        Err(request) => request.deparse_header(size_of::<IpHeader>()),
+    }
+}
+
+/// Allocate and populate a packet that requests a server "get" operation.
 ///
 /// # Arguments
 ///
@@ -155,13 +538,14 @@ pub fn fixup_header_length_fields(
 /// * `udp`:      Reference to the UDP header to be added to the request.
 /// * `tenant`:   Id of the tenant requesting the item.
 /// * `table_id`: Id of the table from which the key is looked up.
-/// * `key`:      Byte string of key whose value is to be fetched. Limit 64 KB.
+/// * `key`:      Byte string of key whose value is to be fetched. Limit MAX_KEY_LEN.
 /// * `id`:       RPC identifier.
 /// * `dst`:      The UDP port on the server the RPC is destined for.
 ///
 /// # Return
 ///
-/// Packet populated with the request parameters.
+/// Packet populated with the request parameters, or an error if one could not be allocated or
+/// the request is too long for a single datagram.
 #[inline]
 pub fn create_get_rpc(
     mac: &MacHeader,
@@ -172,32 +556,23 @@ pub fn create_get_rpc(
     key: &[u8],
     id: u64,
     dst: u16,
-) -> Packet<IpHeader, EmptyMetadata> {
-    // Key length cannot be more than 16 bits. Required to construct the RPC header.
-    if key.len() > u16::max_value() as usize {
-        panic!("Key too long ({} bytes).", key.len());
+) -> error::Result<Packet<IpHeader, EmptyMetadata>> {
+    // A key too long to have it's length written in 16 bits could never fit in a datagram.
+    if key.len() > MAX_KEY_LEN {
+        return Err(NetError::DatagramTooLong(size_of::<GetRequest>() + key.len()).into());
     }
 
     // Allocate a packet, write the header and payload into it, and set fields on it's UDP and IP
     // header.
-    let mut request = create_request(mac, ip, udp, dst)
-        .push_header(&GetRequest::new(tenant, table_id, key.len() as u16, id))
-        .expect("Failed to push RPC header into request!");
+    let request = create_request(mac, ip, udp, dst)?;
+    let hdr = GetRequest::new(tenant, table_id, key.len() as u16, id);
+    let request = write_payload(request, &[as_bytes(&hdr), key], "get() request")?;
 
-    request
-        .add_to_payload_tail(key.len(), &key)
-        .expect("Failed to write key into get() request!");
-
-    fixup_header_length_fields(request.deparse_header(size_of::<UdpHeader>()))
+    finish_request(request)
 }
 
 /// Allocate and populate a packet that requests a server "put" operation.
 ///
-/// # Panic
-///
-/// May panic if there is a problem allocating the packet or constructing
-/// headers.
-///
 /// # Arguments
 ///
 /// * `mac`:      Reference to the MAC header to be added to the request.
@@ -205,14 +580,15 @@ pub fn create_get_rpc(
 /// * `udp`:      Reference to the UDP header to be added to the request.
 /// * `tenant`:   Id of the tenant requesting the insertion.
 /// * `table_id`: Id of the table into which the key-value pair is to be inserted.
-/// * `key`:      Byte string of key whose value is to be inserted. Limit 64 KB.
+/// * `key`:      Byte string of key whose value is to be inserted. Limit MAX_KEY_LEN.
 /// * `val`:      Byte string of the value to be inserted.
 /// * `id`:       RPC identifier.
 /// * `dst`:      The UDP port on the server the RPC is destined for.
 ///
 /// # Return
 ///
-/// Packet populated with the request parameters.
+/// Packet populated with the request parameters, or an error if one could not be allocated or
+/// the request is too long for a single datagram.
 #[inline]
 pub fn create_put_rpc(
     mac: &MacHeader,
@@ -224,27 +600,20 @@ pub fn create_put_rpc(
     val: &[u8],
     id: u64,
     dst: u16,
-) -> Packet<IpHeader, EmptyMetadata> {
-    // Key length cannot be more than 16 bits. Required to construct the RPC header.
-    if key.len() > u16::max_value() as usize {
-        panic!("Key too long ({} bytes).", key.len());
+) -> error::Result<Packet<IpHeader, EmptyMetadata>> {
+    // A key too long to have it's length written in 16 bits could never fit in a datagram.
+    if key.len() > MAX_KEY_LEN {
+        let len = size_of::<PutRequest>() + key.len() + val.len();
+        return Err(NetError::DatagramTooLong(len).into());
     }
 
     // Allocate a packet, write the header and payload into it, and set fields on it's UDP and IP
     // header.
-    let mut request = create_request(mac, ip, udp, dst)
-        .push_header(&PutRequest::new(tenant, table_id, key.len() as u16, id))
-        .expect("Failed to push RPC header into request!");
-
-    let mut payload = Vec::with_capacity(key.len() + val.len());
-    payload.extend_from_slice(key);
-    payload.extend_from_slice(val);
+    let request = create_request(mac, ip, udp, dst)?;
+    let hdr = PutRequest::new(tenant, table_id, key.len() as u16, id);
+    let request = write_payload(request, &[as_bytes(&hdr), key, val], "put() request")?;
 
-    request
-        .add_to_payload_tail(payload.len(), &payload)
-        .expect("Failed to write key into put() request!");
-
-    fixup_header_length_fields(request.deparse_header(size_of::<UdpHeader>()))
+    finish_request(request)
 }
 
 /// Allocate and populate a packet that requests a server "multiget" operation.
@@ -265,7 +634,8 @@ pub fn create_put_rpc(
 ///
 /// # Return
 ///
-/// Packet populated with the request parameters.
+/// Packet populated with the request parameters, or an error if one could not be allocated or
+/// the request is too long for a single datagram.
 #[inline]
 pub fn create_multiget_rpc(
     mac: &MacHeader,
@@ -278,26 +648,54 @@ pub fn create_multiget_rpc(
     keys: &[u8],
     id: u64,
     dst: u16,
-) -> Packet<IpHeader, EmptyMetadata> {
+) -> error::Result<Packet<IpHeader, EmptyMetadata>> {
     // Allocate a packet, write the header and payload into it, and set fields on it's UDP and IP
     // header.
-    let mut request = create_request(mac, ip, udp, dst)
-        .push_header(&MultiGetRequest::new(tenant, table_id, key_len, num_keys, id))
-        .expect("Failed to push RPC header into request!");
+    let hdr = MultiGetRequest::new(tenant, table_id, key_len, num_keys, id);
+    let request = create_request(mac, ip, udp, dst)?;
+    let request = write_payload(request, &[as_bytes(&hdr), keys], "multiget() request")?;
 
-    request
-        .add_to_payload_tail(keys.len(), &keys)
-        .expect("Failed to write key into multiget() request!");
-
-    fixup_header_length_fields(request.deparse_header(size_of::<UdpHeader>()))
+    finish_request(request)
 }
 
-/// Allocate and populate a packet that requests a server "invoke" operation.
+/// Allocate and populate a packet that requests a server "multiop" operation.
 ///
-/// # Panic
+/// # Arguments
+///
+/// * `mac`:     Reference to the MAC header to be added to the request.
+/// * `ip` :     Reference to the IP header to be added to the request.
+/// * `udp`:     Reference to the UDP header to be added to the request.
+/// * `tenant`:  Id of the tenant issuing the operations.
+/// * `num_ops`: The number of operations in `ops`.
+/// * `ops`:     The operations, each encoded by `multiop::push_entry()`.
+/// * `id`:      RPC identifier.
+/// * `dst`:     The UDP port on the server the RPC is destined for.
 ///
-/// May panic if there is a problem allocating the packet or constructing
-/// headers.
+/// # Return
+///
+/// Packet populated with the request parameters, or an error if one could not be allocated or
+/// the request is too long for a single datagram.
+#[inline]
+pub fn create_multiop_rpc(
+    mac: &MacHeader,
+    ip: &IpHeader,
+    udp: &UdpHeader,
+    tenant: u32,
+    num_ops: u32,
+    ops: &[u8],
+    id: u64,
+    dst: u16,
+) -> error::Result<Packet<IpHeader, EmptyMetadata>> {
+    // Allocate a packet, write the header and payload into it, and set fields on it's UDP and IP
+    // header.
+    let hdr = MultiOpRequest::new(tenant, num_ops, id);
+    let request = create_request(mac, ip, udp, dst)?;
+    let request = write_payload(request, &[as_bytes(&hdr), ops], "multiop() request")?;
+
+    finish_request(request)
+}
+
+/// Allocate and populate a packet that requests a server "invoke" operation.
 ///
 /// # Arguments
 ///
@@ -313,7 +711,8 @@ pub fn create_multiget_rpc(
 ///
 /// # Return
 ///
-/// Packet populated with the request parameters.
+/// Packet populated with the request parameters, or an error if one could not be allocated or
+/// the request is too long for a single datagram.
 #[inline]
 pub fn create_invoke_rpc(
     mac: &MacHeader,
@@ -324,30 +723,23 @@ pub fn create_invoke_rpc(
     payload: &[u8],
     id: u64,
     dst: u16,
-) -> Packet<IpHeader, EmptyMetadata> {
-    // The Arguments to the procedure cannot be more that 4 GB long.
-    if payload.len() - name_len as usize > u32::max_value() as usize {
-        panic!(
-            "Args too long ({} bytes).",
-            payload.len() - name_len as usize
-        );
+) -> error::Result<Packet<IpHeader, EmptyMetadata>> {
+    // The name must be at the head of the payload, and the arguments after it cannot be more
+    // than 4 GB long, which is well past what fits in a datagram anyway.
+    let args_len = match payload.len().checked_sub(name_len as usize) {
+        Some(args_len) => args_len,
+        None => return Err(DispatchError::Malformed("name longer than payload").into()),
+    };
+    if args_len > u32::max_value() as usize {
+        return Err(NetError::DatagramTooLong(payload.len()).into());
     }
 
     // Allocate a packet, write the header and payload into it, and set fields on it's UDP and IP
     // header. Since the payload contains both, the name and arguments in it, args_len can be
     // calculated as payload length - name_len.
-    let mut request = create_request(mac, ip, udp, dst)
-        .push_header(&InvokeRequest::new(
-            tenant,
-            name_len,
-            (payload.len() - name_len as usize) as u32,
-            id,
-        ))
-        .expect("Failed to push RPC header into request!");
-
-    request
-        .add_to_payload_tail(payload.len(), &payload)
-        .expect("Failed to write args into invoke() request!");
+    let hdr = InvokeRequest::new(tenant, name_len, args_len as u32, id);
+    let request = create_request(mac, ip, udp, dst)?;
+    let request = write_payload(request, &[as_bytes(&hdr), payload], "invoke() request")?;
 
-    fixup_header_length_fields(request.deparse_header(size_of::<UdpHeader>()))
+    finish_request(request)
 }
@@ -0,0 +1,111 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+
+// The config the server is running with. The watchdog thread publishes it at startup and after
+// every change, so that the config() RPC can read it, and so that dispatchers can notice a new
+// generation with one load and pick up the settings they cache. Changes made through the RPC
+// are validated right away, and then left for the watchdog to apply the same way it applies a
+// reload of server.toml.
+
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::sync::{Arc, Once, ONCE_INIT};
+
+use super::config::{Change, ConfigError, ServerConfig};
+
+use spin::Mutex;
+
+// The published config, and changes to it the watchdog has not applied yet.
+struct Shared {
+    current: Arc<ServerConfig>,
+    pending: Option<ServerConfig>,
+}
+
+/// Bumped every time a config is published.
+static GENERATION: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Guards the one time allocation of `SHARED`.
+static SHARED_INIT: Once = ONCE_INIT;
+
+/// The config shared by all threads. Use `shared()` to access it.
+static mut SHARED: *const Mutex<Shared> = 0 as *const Mutex<Shared>;
+
+// Returns the config shared by all threads, allocating a default one on first use.
+fn shared() -> &'static Mutex<Shared> {
+    unsafe {
+        SHARED_INIT.call_once(|| {
+            SHARED = Box::into_raw(Box::new(Mutex::new(Shared {
+                current: Arc::new(ServerConfig::default()),
+                pending: None,
+            })));
+        });
+        &*SHARED
+    }
+}
+
+/// Publishes the config the server is now running with.
+///
+/// # Arguments
+///
+/// * `config`: The config.
+pub fn publish(config: &ServerConfig) {
+    shared().lock().current = Arc::new(config.clone());
+    GENERATION.fetch_add(1, Ordering::Release);
+}
+
+/// Returns the config the server is running with. Changes that have not been applied yet are
+/// not part of it.
+pub fn current() -> Arc<ServerConfig> {
+    Arc::clone(&shared().lock().current)
+}
+
+/// Returns the number of times a config was published. Cheap enough to check on every poll.
+#[inline]
+pub fn generation() -> usize {
+    GENERATION.load(Ordering::Acquire)
+}
+
+/// Changes tunable fields of the config, on top of any changes not applied yet. The changes
+/// take effect once the watchdog applies them.
+///
+/// # Arguments
+///
+/// * `changes`: TOML assigning new values to fields in `config::TUNABLE`.
+///
+/// # Return
+///
+/// Every field whose value changed, or every problem found with the changes.
+pub fn tune(changes: &str) -> Result<Vec<Change>, ConfigError> {
+    let mut shared = shared().lock();
+    let mut config = match shared.pending {
+        Some(ref pending) => pending.clone(),
+        None => (*shared.current).clone(),
+    };
+
+    let changed = config.tune(changes)?;
+    if changed.len() > 0 {
+        shared.pending = Some(config);
+    }
+    Ok(changed)
+}
+
+/// Takes the changes made through `tune()` since the last call.
+///
+/// # Return
+///
+/// The config to apply, if there were any changes.
+pub fn take() -> Option<ServerConfig> {
+    shared().lock().pending.take()
+}
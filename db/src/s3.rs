@@ -0,0 +1,586 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+
+// A minimal client for S3-compatible object storage, enough to put and get whole objects. Requests
+// are made over HTTP/1.1 with path-style URLs ("https://host:port/bucket/key"), and are signed
+// with AWS Signature Version 4. The store is reached over TLS (`rustls`) when the URL starts with
+// https://, checking it's certificate against the Mozilla roots in `webpki-roots`; plain http://
+// is still accepted for stores on a trusted network, such as a MinIO or Ceph gateway next to the
+// servers. Values written out are sealed with their tenant's key if it has one (see `crypt`).
+//
+// Every request signs the SHA-256 of it's payload, so the store refuses a body that was changed
+// or cut short on the way. Files are hashed in one pass over them and streamed in a second, and
+// responses are streamed to where they are needed as they arrive, whether their body is delimited
+// by it's Content-Length, chunked, or runs to the end of the connection. SHA-256 and HMAC come
+// from `ring`.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Once, ONCE_INIT};
+use std::time::Duration;
+
+use ring::digest::{self, SHA256};
+use ring::hmac::{self, SigningKey};
+use rustls::{self, ClientConfig, ClientSession};
+use time;
+use webpki::DNSNameRef;
+use webpki_roots::TLS_SERVER_ROOTS;
+
+/// The number of seconds a request to the store may stall for before it fails.
+const TIMEOUT_S: u64 = 30;
+
+/// The longest line accepted in the head of a response, or in front of a chunk of it's body.
+const MAX_LINE: u64 = 8192;
+
+/// The most lines accepted in the head of a response, or in the trailer of a chunked body.
+const MAX_LINES: usize = 128;
+
+/// The most bytes of the body of a failed response that are put into it's error.
+const MAX_ERROR: u64 = 4096;
+
+/// Initializes CONFIG exactly once.
+static INIT: Once = ONCE_INIT;
+
+/// The configuration TLS sessions are started with. Use `config()` to access it.
+static mut CONFIG: *const Arc<ClientConfig> = 0 as *const Arc<ClientConfig>;
+
+// Returns the configuration TLS sessions are started with, trusting the Mozilla roots, and
+// builds it on first use.
+fn config() -> Arc<ClientConfig> {
+    unsafe {
+        INIT.call_once(|| {
+            let mut config = ClientConfig::new();
+            config.root_store.add_server_trust_anchors(&TLS_SERVER_ROOTS);
+            CONFIG = Box::into_raw(Box::new(Arc::new(config)));
+        });
+        (*CONFIG).clone()
+    }
+}
+
+/// An S3-compatible bucket, or a prefix within one.
+#[derive(Clone, Debug)]
+pub struct Store {
+    // The host and port requests are sent to.
+    host: String,
+
+    // The name of the host, which it's certificate must be issued to if `tls` is set.
+    name: String,
+
+    // Whether requests are sent over TLS.
+    tls: bool,
+
+    // The path every key is appended to: the bucket, and the prefix within it, if any.
+    path: String,
+
+    // The credentials requests are signed with, and the region of the bucket.
+    access_key: String,
+    secret_key: String,
+    region: String,
+}
+
+// Implementation of methods on Store.
+impl Store {
+    /// Returns a handle to a bucket.
+    ///
+    /// # Arguments
+    ///
+    /// * `url`:        The bucket, as "https://host:port/bucket" or "http://host:port/bucket",
+    ///                 optionally followed by a prefix every key is placed under. Over https://,
+    ///                 the host must be named by DNS rather than by it's IP address.
+    /// * `access_key`: The access key requests are signed with.
+    /// * `secret_key`: The secret key requests are signed with.
+    /// * `region`:     The region of the bucket, such as "us-east-1".
+    ///
+    /// # Return
+    ///
+    /// The handle, or a description of what is wrong with the URL.
+    pub fn new(
+        url: &str,
+        access_key: &str,
+        secret_key: &str,
+        region: &str,
+    ) -> Result<Store, String> {
+        let (tls, port, rest) = if url.starts_with("https://") {
+            (true, 443, &url[8..])
+        } else if url.starts_with("http://") {
+            (false, 80, &url[7..])
+        } else {
+            return Err(format!("\"{}\" does not start with https:// or http://", url));
+        };
+
+        let (host, path) = match rest.find('/') {
+            Some(idx) => (&rest[..idx], rest[idx..].trim_right_matches('/')),
+            None => (rest, ""),
+        };
+        let name = match host.rfind(':') {
+            Some(idx) => &host[..idx],
+            None => host,
+        };
+        if name.len() == 0 || path.len() < 2 {
+            return Err(format!("\"{}\" does not name a host and a bucket", url));
+        }
+
+        // Certificates are only checked against DNS names.
+        if tls && DNSNameRef::try_from_ascii_str(name).is_err() {
+            return Err(format!("\"{}\" does not name it's host by DNS, which TLS needs", url));
+        }
+
+        Ok(Store {
+            host: if host.contains(':') {
+                host.to_string()
+            } else {
+                format!("{}:{}", host, port)
+            },
+            name: name.to_string(),
+            tls: tls,
+            path: path.to_string(),
+            access_key: access_key.to_string(),
+            secret_key: secret_key.to_string(),
+            region: region.to_string(),
+        })
+    }
+
+    /// Writes an object, replacing it if it exists.
+    ///
+    /// # Arguments
+    ///
+    /// * `key`:  The object's key, under the store's prefix.
+    /// * `body`: The object's contents.
+    pub fn put(&self, key: &str, body: &[u8]) -> io::Result<()> {
+        let hash = hex(digest::digest(&SHA256, body).as_ref());
+        self.request("PUT", key, &hash, body.len() as u64, &mut &body[..], &mut io::sink())
+    }
+
+    /// Writes an object with the contents of a file, streaming it from disk.
+    ///
+    /// # Arguments
+    ///
+    /// * `key`:  The object's key, under the store's prefix.
+    /// * `path`: The file. It must not change until the object is written.
+    pub fn put_file(&self, key: &str, path: &str) -> io::Result<()> {
+        let mut file = File::open(path)?;
+        let len = file.metadata()?.len();
+        let hash = hash(&mut file)?;
+        file.seek(SeekFrom::Start(0))?;
+        self.request("PUT", key, &hash, len, &mut file, &mut io::sink())
+    }
+
+    /// Reads an object into memory. Use `get_file()` for objects that may be large.
+    ///
+    /// # Arguments
+    ///
+    /// * `key`: The object's key, under the store's prefix.
+    ///
+    /// # Return
+    ///
+    /// The object's contents, or an error of kind NotFound if there is no such object.
+    pub fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        let mut body = Vec::new();
+        let hash = hex(digest::digest(&SHA256, &[]).as_ref());
+        self.request("GET", key, &hash, 0, &mut io::empty(), &mut body)?;
+        Ok(body)
+    }
+
+    /// Reads an object into a file, streaming it to disk.
+    ///
+    /// # Arguments
+    ///
+    /// * `key`:  The object's key, under the store's prefix.
+    /// * `path`: The file, which is created or truncated. It is left behind, possibly holding
+    ///           part of the object, if the read fails.
+    ///
+    /// # Return
+    ///
+    /// An error of kind NotFound if there is no such object.
+    pub fn get_file(&self, key: &str, path: &str) -> io::Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let hash = hex(digest::digest(&SHA256, &[]).as_ref());
+        self.request("GET", key, &hash, 0, &mut io::empty(), &mut file)?;
+        file.flush()
+    }
+
+    // Sends a signed request with a body of `len` bytes read off `body`, which hash to `hash`,
+    // and writes the body of the response to `out` if it's status is a success.
+    fn request(
+        &self,
+        method: &str,
+        key: &str,
+        hash: &str,
+        len: u64,
+        body: &mut Read,
+        out: &mut Write,
+    ) -> io::Result<()> {
+        let now = time::now_utc();
+        let stamp = time::strftime("%Y%m%dT%H%M%SZ", &now).unwrap();
+        let uri = format!("{}/{}", self.path, encode(key));
+        let auth = self.authorization(method, &uri, &stamp, hash);
+        let head = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nx-amz-date: {}\r\nx-amz-content-sha256: {}\r\n\
+             Authorization: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            method, uri, self.host, stamp, hash, auth, len
+        );
+
+        let mut stream = TcpStream::connect(&self.host)?;
+        stream.set_read_timeout(Some(Duration::from_secs(TIMEOUT_S)))?;
+        stream.set_write_timeout(Some(Duration::from_secs(TIMEOUT_S)))?;
+
+        let status = if self.tls {
+            // The name was checked when the store was created.
+            let name = DNSNameRef::try_from_ascii_str(&self.name).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, "host is not a DNS name")
+            })?;
+            let mut session = ClientSession::new(&config(), name);
+            let tls = rustls::Stream::new(&mut session, &mut stream);
+            exchange(tls, &head, len, body, out)?
+        } else {
+            exchange(&mut stream, &head, len, body, out)?
+        };
+
+        match status {
+            Ok(()) => Ok(()),
+            Err((404, _)) => {
+                Err(io::Error::new(io::ErrorKind::NotFound, format!("{} not found", key)))
+            }
+            Err((status, msg)) => {
+                let msg = format!(
+                    "{} {} failed with status {}: {}",
+                    method,
+                    key,
+                    status,
+                    String::from_utf8_lossy(&msg)
+                );
+                Err(io::Error::new(io::ErrorKind::Other, msg))
+            }
+        }
+    }
+
+    // Returns the Authorization header of a request with a payload hashing to `hash`, following
+    // Signature Version 4.
+    fn authorization(&self, method: &str, uri: &str, stamp: &str, hash: &str) -> String {
+        let date = &stamp[..8];
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let signed = "host;x-amz-content-sha256;x-amz-date";
+        let canonical = format!(
+            "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method, uri, self.host, hash, stamp, signed, hash
+        );
+        let to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            stamp,
+            scope,
+            hex(digest::digest(&SHA256, canonical.as_bytes()).as_ref())
+        );
+
+        let key = signing_key(&self.secret_key, date, &self.region, "s3");
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key,
+            scope,
+            signed,
+            hex(&hmac_sha256(&key, to_sign.as_bytes()))
+        )
+    }
+}
+
+// Sends the head of a request and `len` bytes of it's body over a connection, and reads the
+// response. The body of the response is written to `out` if it's status is a success, and the
+// status and the start of the body are returned otherwise.
+fn exchange<S: Read + Write>(
+    mut stream: S,
+    head: &str,
+    len: u64,
+    body: &mut Read,
+    out: &mut Write,
+) -> io::Result<Result<(), (u32, Vec<u8>)>> {
+    stream.write_all(head.as_bytes())?;
+    let sent = io::copy(&mut body.take(len), &mut stream)?;
+    if sent != len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "body was cut short"));
+    }
+    stream.flush()?;
+
+    let mut reader = BufReader::new(stream);
+    let head = read_head(&mut reader)?;
+    if head.status >= 200 && head.status < 300 {
+        copy_body(&mut reader, &head, out)?;
+        return Ok(Ok(()));
+    }
+
+    // The body only describes the failure, so whatever of it can be read is enough.
+    let mut msg = Vec::new();
+    let _ = copy_body(&mut reader, &head, &mut msg);
+    msg.truncate(MAX_ERROR as usize);
+    Ok(Err((head.status, msg)))
+}
+
+// The parts of the head of a response that matter to reading it.
+#[derive(Debug, PartialEq)]
+struct Head {
+    // The status code.
+    status: u32,
+
+    // The length of the body, if the response gave it.
+    length: Option<u64>,
+
+    // Whether the body is chunked. A chunked body's length is ignored.
+    chunked: bool,
+}
+
+// Reads the status line and headers of a response, upto and including the empty line after them.
+fn read_head<R: BufRead>(reader: &mut R) -> io::Result<Head> {
+    let status = read_line(reader)?
+        .split(' ')
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| malformed("no status"))?;
+
+    let mut head = Head {
+        status: status,
+        length: None,
+        chunked: false,
+    };
+    for _ in 0..MAX_LINES {
+        let line = read_line(reader)?;
+        if line.len() == 0 {
+            return Ok(head);
+        }
+
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next().unwrap_or("").trim().to_lowercase();
+        let value = parts.next().unwrap_or("").trim().to_lowercase();
+        if name == "content-length" {
+            head.length = Some(value.parse().map_err(|_| malformed("bad Content-Length"))?);
+        } else if name == "transfer-encoding" {
+            head.chunked = value.split(',').any(|coding| coding.trim() == "chunked");
+        }
+    }
+
+    Err(malformed("too many headers"))
+}
+
+// Copies the body of a response to `out`, and returns the number of bytes in it.
+fn copy_body<R: BufRead>(reader: &mut R, head: &Head, out: &mut Write) -> io::Result<u64> {
+    if head.chunked {
+        return copy_chunked(reader, out);
+    }
+
+    match head.length {
+        Some(len) => {
+            if io::copy(&mut (&mut *reader).take(len), out)? != len {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated response"));
+            }
+            Ok(len)
+        }
+        None => io::copy(reader, out),
+    }
+}
+
+// Copies a chunked body (RFC 7230, section 4.1) to `out`, and returns the number of bytes in it.
+// Chunk extensions and trailers are read past and ignored.
+fn copy_chunked<R: BufRead>(reader: &mut R, out: &mut Write) -> io::Result<u64> {
+    let mut total = 0;
+    loop {
+        let line = read_line(reader)?;
+        let size = line.split(';').next().unwrap_or("").trim();
+        let size = u64::from_str_radix(size, 16).map_err(|_| malformed("bad chunk size"))?;
+
+        if size == 0 {
+            for _ in 0..MAX_LINES {
+                if read_line(reader)?.len() == 0 {
+                    return Ok(total);
+                }
+            }
+            return Err(malformed("too many trailers"));
+        }
+
+        if io::copy(&mut (&mut *reader).take(size), out)? != size {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated chunk"));
+        }
+        if read_line(reader)?.len() != 0 {
+            return Err(malformed("chunk longer than it's size"));
+        }
+        total += size;
+    }
+}
+
+// Reads a line ending in CRLF, and returns it without the line ending. Lines longer than
+// MAX_LINE are refused, rather than buffered for as long as the store keeps sending.
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<String> {
+    let mut line = Vec::new();
+    (&mut *reader).take(MAX_LINE).read_until(b'\n', &mut line)?;
+    if !line.ends_with(b"\n") {
+        return Err(match line.len() as u64 {
+            MAX_LINE => malformed("line too long"),
+            _ => io::Error::new(io::ErrorKind::UnexpectedEof, "truncated response"),
+        });
+    }
+
+    let end = line.len() - if line.ends_with(b"\r\n") { 2 } else { 1 };
+    Ok(String::from_utf8_lossy(&line[..end]).into_owned())
+}
+
+// Returns an error for a response that does not follow HTTP/1.1.
+fn malformed(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("malformed HTTP response: {}", what))
+}
+
+// Hashes what is left of a stream with SHA-256, and returns the hash in hex.
+fn hash(input: &mut Read) -> io::Result<String> {
+    let mut ctx = digest::Context::new(&SHA256);
+    let mut buf = [0u8; 8192];
+    loop {
+        match input.read(&mut buf) {
+            Ok(0) => return Ok(hex(ctx.finish().as_ref())),
+            Ok(len) => ctx.update(&buf[..len]),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Percent-encodes a key for a URI, leaving slashes and the characters S3 leaves unreserved.
+fn encode(key: &str) -> String {
+    let mut encoded = String::new();
+    for byte in key.bytes() {
+        if (byte as char).is_ascii_alphanumeric() || b"-_.~/".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+// Derives the key requests are signed with on a date, for a region and service.
+fn signing_key(secret: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let key = hmac_sha256(format!("AWS4{}", secret).as_bytes(), date.as_bytes());
+    let key = hmac_sha256(&key, region.as_bytes());
+    let key = hmac_sha256(&key, service.as_bytes());
+    hmac_sha256(&key, b"aws4_request")
+}
+
+// Computes the HMAC-SHA256 of a message under a key.
+fn hmac_sha256(key: &[u8], msg: &[u8]) -> Vec<u8> {
+    hmac::sign(&SigningKey::new(&SHA256, key), msg).as_ref().to_vec()
+}
+
+// Renders bytes as lower case hex.
+fn hex(buf: &[u8]) -> String {
+    buf.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// This module contains unit tests for the object store client.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // This unit test verifies that signing keys are derived as in the Signature Version 4
+    // documentation's example, and that payloads hash the same whether streamed or not.
+    #[test]
+    fn test_signing() {
+        let key = signing_key(
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "20120215",
+            "us-east-1",
+            "iam",
+        );
+        assert_eq!(
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d",
+            hex(&key)
+        );
+
+        assert_eq!(
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            hash(&mut io::empty()).unwrap()
+        );
+        let body = vec![7u8; 100000];
+        assert_eq!(hex(digest::digest(&SHA256, &body).as_ref()), hash(&mut &body[..]).unwrap());
+    }
+
+    // This unit test verifies that bucket URLs are parsed.
+    #[test]
+    fn test_parse() {
+        let store = Store::new("http://10.0.0.5:9000/backups/east/", "a", "s", "r").unwrap();
+        assert_eq!("10.0.0.5:9000", store.host);
+        assert_eq!("/backups/east", store.path);
+        assert!(!store.tls);
+        assert_eq!("minio:80", Store::new("http://minio/b", "a", "s", "r").unwrap().host);
+        assert!(Store::new("http://minio/", "a", "s", "r").is_err());
+        assert!(Store::new("ftp://minio/b", "a", "s", "r").is_err());
+
+        let store = Store::new("https://s3.example.com/b", "a", "s", "r").unwrap();
+        assert_eq!("s3.example.com:443", store.host);
+        assert_eq!("s3.example.com", store.name);
+        assert!(store.tls);
+        let store = Store::new("https://minio.local:9000/b", "a", "s", "r").unwrap();
+        assert_eq!("minio.local", store.name);
+        assert!(Store::new("https://10.0.0.5:9000/b", "a", "s", "r").is_err());
+
+        assert_eq!("a%20b/c%2B", encode("a b/c+"));
+    }
+
+    // This unit test verifies that the heads of responses are parsed, and that bodies are read
+    // upto their Content-Length, to the end of the response, or chunk by chunk.
+    #[test]
+    fn test_response() {
+        let res = b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\nETag: x\r\n\r\nabcdef";
+        let mut reader = &res[..];
+        let head = read_head(&mut reader).unwrap();
+        assert_eq!(
+            Head {
+                status: 200,
+                length: Some(3),
+                chunked: false,
+            },
+            head
+        );
+        let mut body = Vec::new();
+        assert_eq!(3, copy_body(&mut reader, &head, &mut body).unwrap());
+        assert_eq!(b"abc".to_vec(), body);
+
+        let mut reader = &b"HTTP/1.1 200 OK\r\nContent-Length: 9\r\n\r\nabc"[..];
+        let head = read_head(&mut reader).unwrap();
+        assert!(copy_body(&mut reader, &head, &mut Vec::new()).is_err());
+
+        let mut reader = &b"HTTP/1.1 404 Not Found\r\n\r\nmissing"[..];
+        let head = read_head(&mut reader).unwrap();
+        assert_eq!((404, None), (head.status, head.length));
+        let mut body = Vec::new();
+        copy_body(&mut reader, &head, &mut body).unwrap();
+        assert_eq!(b"missing".to_vec(), body);
+
+        let res = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nContent-Length: 1\r\n\r\n\
+                    4;ext=1\r\nWiki\r\n5\r\npedia\r\nE\r\n in\r\n\r\nchunks.\r\n0\r\n\
+                    X-Trailer: y\r\n\r\n";
+        let mut reader = &res[..];
+        let head = read_head(&mut reader).unwrap();
+        assert!(head.chunked);
+        let mut body = Vec::new();
+        assert_eq!(23, copy_body(&mut reader, &head, &mut body).unwrap());
+        assert_eq!(b"Wikipedia in\r\n\r\nchunks.".to_vec(), body);
+        assert_eq!(0, reader.len());
+
+        for bad in [&b"4\r\nWik"[..], b"4\r\nWikipedia\r\n0\r\n\r\n", b"zz\r\n", b"0\r\n"].iter() {
+            assert!(copy_chunked(&mut &bad[..], &mut Vec::new()).is_err());
+        }
+
+        assert!(read_head(&mut &b"garbage"[..]).is_err());
+        assert!(read_head(&mut &b"HTTP/1.1 200 OK\r\nContent-Length: x\r\n\r\n"[..]).is_err());
+        let long = vec![b'a'; MAX_LINE as usize + 10];
+        assert!(read_line(&mut &long[..]).is_err());
+    }
+}
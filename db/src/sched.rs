@@ -14,19 +14,47 @@
  */
 
 use std::collections::VecDeque;
+use std::mem::size_of;
 use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicUsize, Ordering};
 
+use super::common::TenantId;
+use super::config::GroupConfig;
+use super::counters::{self, Counter};
 use super::cycles;
+use super::deadline;
+use super::group::Groups;
+use super::meter;
+use super::metrics;
+use super::profile;
 use super::rpc;
-use super::task::Task;
+use super::slow;
+use super::span;
 use super::task::TaskState::*;
+use super::task::{Task, TaskState};
+use super::wireformat::MAX_REQUEST_PRIORITY;
 
 use e2d2::common::EmptyMetadata;
-use e2d2::headers::IpHeader;
+use e2d2::headers::{IpHeader, UdpHeader};
 use e2d2::interface::Packet;
 
 use spin::RwLock;
 
+/// What a task on a scheduler looked like at the time of a snapshot.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TaskSnapshot {
+    /// The tenant the task runs on behalf of, if any.
+    pub tenant: Option<TenantId>,
+
+    /// The name of the extension the task runs, or a description of it's work.
+    pub name: String,
+
+    /// The state the task was in.
+    pub state: TaskState,
+
+    /// The number of cycles since the task was created, or zero if it is not known.
+    pub age: u64,
+}
+
 /// A simple round robin scheduler for Tasks in Sandstorm.
 pub struct RoundRobin {
     // The time-stamp at which the scheduler last ran. Required to identify whether there is an
@@ -43,6 +71,10 @@ pub struct RoundRobin {
     // Identifier of the core this scheduler is running on. Required for pre-emption.
     core: AtomicIsize,
 
+    // The tenant of the task being run right now plus one, zero if no task is running or the
+    // task belongs to no tenant. Required to snapshot the scheduler.
+    running: AtomicUsize,
+
     // Run-queue of tasks waiting to execute. Tasks on this queue have either yielded, or have been
     // recently enqueued and never run before.
     waiting: RwLock<VecDeque<Box<Task>>>,
@@ -50,6 +82,10 @@ pub struct RoundRobin {
     // Response packets returned by completed tasks. Will be picked up and sent out the network by
     // the Dispatch task.
     responses: RwLock<Vec<Packet<IpHeader, EmptyMetadata>>>,
+
+    // Accounting state for scheduling groups. Required to decide which tenant's task should run
+    // next when the scheduler was configured with groups.
+    groups: RwLock<Groups>,
 }
 
 // Implementation of methods on RoundRobin.
@@ -61,14 +97,18 @@ impl RoundRobin {
     ///
     /// * `thread`: Identifier of the thread this scheduler will run on.
     /// * `core`:   Identifier of the core this scheduler will run on.
-    pub fn new(thread: u64, core: i32) -> RoundRobin {
+    /// * `groups`: Scheduling groups tenants belong to. If empty, tasks are run in round robin
+    ///             order irrespective of the tenant they belong to.
+    pub fn new(thread: u64, core: i32, groups: &[GroupConfig]) -> RoundRobin {
         RoundRobin {
             latest: AtomicUsize::new(cycles::rdtsc() as usize),
             compromised: AtomicBool::new(false),
             thread: AtomicUsize::new(thread as usize),
             core: AtomicIsize::new(core as isize),
+            running: AtomicUsize::new(0),
             waiting: RwLock::new(VecDeque::new()),
             responses: RwLock::new(Vec::new()),
+            groups: RwLock::new(Groups::new(groups)),
         }
     }
 
@@ -107,6 +147,47 @@ impl RoundRobin {
         return tasks.drain(..).collect();
     }
 
+    /// Returns the number of tasks waiting to run on the scheduler.
+    #[inline]
+    pub fn num_waiting(&self) -> usize {
+        self.waiting.read().len()
+    }
+
+    /// Takes a snapshot of the scheduler: the task it is running right now, if it belongs to a
+    /// tenant, followed by every task waiting to run, in the order they are queued in. Locks the
+    /// run-queue while it is copied, so should only be called to diagnose the scheduler.
+    ///
+    /// # Return
+    ///
+    /// A snapshot of every task. The age of the running task is the number of cycles it has been
+    /// running for since it was last scheduled, and it's name is not known.
+    pub fn snapshot(&self) -> Vec<TaskSnapshot> {
+        let now = cycles::rdtsc();
+        let mut tasks = Vec::new();
+
+        let running = self.running.load(Ordering::Relaxed);
+        if running > 0 {
+            tasks.push(TaskSnapshot {
+                tenant: Some((running - 1) as TenantId),
+                name: String::new(),
+                state: RUNNING,
+                age: now.saturating_sub(self.latest()),
+            });
+        }
+
+        for task in self.waiting.read().iter() {
+            let created = task.created();
+            tasks.push(TaskSnapshot {
+                tenant: task.tenant(),
+                name: String::from(task.name()),
+                state: task.state(),
+                age: if created > 0 { now.saturating_sub(created) } else { 0 },
+            });
+        }
+
+        tasks
+    }
+
     /// Returns a list of pending response packets.
     ///
     /// # Return
@@ -120,6 +201,12 @@ impl RoundRobin {
         return responses.drain(..).collect();
     }
 
+    /// Returns the number of pending response packets.
+    #[inline]
+    pub fn num_responses(&self) -> usize {
+        self.responses.read().len()
+    }
+
     /// Appends a list of responses to the scheduler.
     ///
     /// # Arguments
@@ -153,6 +240,71 @@ impl RoundRobin {
         self.core.load(Ordering::Relaxed) as i32
     }
 
+    /// Picks the next task to run from the waiting queue.
+    ///
+    /// If scheduling groups were not configured, this is the task at the head of the queue. If
+    /// they were, then the queue is scanned upto the first task that does not belong to any tenant
+    /// (ex: Dispatch), and the task whose group and tenant have consumed the least amount of
+    /// their share of the core is picked. Tasks that don't belong to a tenant always run in
+    /// round robin order.
+    ///
+    /// Either way, the tenant's share is then handed to the earliest of it's tasks upto the first
+    /// task that does not belong to any tenant at the highest priority it's requests asked for, so
+    /// that a tenant's interactive requests can overtake it's own background traffic. Requests
+    /// from other tenants are never overtaken this way.
+    ///
+    /// # Return
+    ///
+    /// The task to be run next, if there is one.
+    fn next(&self) -> Option<Box<Task>> {
+        let mut waiting = self.waiting.write();
+        let mut groups = self.groups.write();
+
+        let mut pick = 0;
+        if !groups.disabled() {
+            let mut best = None;
+            for (idx, task) in waiting.iter().enumerate() {
+                match task.tenant() {
+                    Some(tenant) => {
+                        let key = groups.key(tenant);
+                        if best.map_or(true, |b| key < b) {
+                            best = Some(key);
+                            pick = idx;
+                        }
+                    }
+
+                    None => break,
+                }
+            }
+        }
+
+        let (tenant, mut level) = match waiting.get(pick) {
+            Some(task) => (task.tenant(), task.level()),
+            None => return None,
+        };
+
+        if let Some(tenant) = tenant {
+            for (idx, task) in waiting.iter().enumerate().skip(pick + 1) {
+                if level >= MAX_REQUEST_PRIORITY {
+                    break;
+                }
+
+                match task.tenant() {
+                    Some(other) if other == tenant && task.level() > level => {
+                        level = task.level();
+                        pick = idx;
+                    }
+
+                    Some(_) => {}
+
+                    None => break,
+                }
+            }
+        }
+
+        waiting.remove(pick)
+    }
+
     /// Picks up a task from the waiting queue, and runs it until it either yields or completes.
     pub fn poll(&self) {
         loop {
@@ -167,19 +319,100 @@ impl RoundRobin {
 
             // If there are tasks to run, then pick one from the head of the queue, and run it until it
             // either completes or yields back.
-            let task = self.waiting.write().pop_front();
+            let task = self.next();
 
             if let Some(mut task) = task {
-                if task.run().0 == COMPLETED {
+                // Mark when a traced request's task first gets to run, and let calls it makes into
+                // the database be marked while it runs.
+                let span = task.span();
+                if span != 0 {
+                    if task.state() == INITIALIZED {
+                        span::mark(span, "scheduled");
+                    }
+                    span::enter(span);
+                }
+
+                let profiling = profile::active();
+                if profiling {
+                    profile::enter(task.tenant(), task.name());
+                }
+
+                // A task whose request's deadline passed while it waited to run for the first
+                // time is completed without being run, if it can be. It's client has given up.
+                let expired = task.state() == INITIALIZED
+                    && deadline::expired(task.deadline())
+                    && task.expire();
+
+                let running = tenant_plus_one(task.tenant());
+                self.running.store(running, Ordering::Relaxed);
+                let (state, exec) = match expired {
+                    true => {
+                        counters::add(Counter::DeadlinesExpired, 1);
+                        span::mark(span, "expired");
+                        (COMPLETED, 0)
+                    }
+
+                    false => task.run(),
+                };
+                self.running.store(0, Ordering::Relaxed);
+
+                if profiling {
+                    profile::leave();
+                }
+
+                if span != 0 {
+                    span::enter(0);
+                }
+
+                // Charge the tenant for the time the task ran for.
+                let tenant = task.tenant();
+                if let Some(tenant) = tenant {
+                    let mut groups = self.groups.write();
+                    if !groups.disabled() {
+                        groups.charge(tenant, exec);
+                    }
+                }
+
+                if state == COMPLETED {
+                    if metrics::enabled() {
+                        metrics::task(task.time());
+                    }
+
                     // The task finished execution, check for request and response packets. If they
                     // exist, then free the request packet, and enqueue the response packet.
                     if let Some((req, res)) = unsafe { task.tear() } {
+                        if let (true, Some(tenant)) = (meter::enabled(), tenant) {
+                            let len = res.get_payload().len() + res.chained_len();
+                            meter::task(tenant, exec, size_of::<UdpHeader>() + len);
+                        }
+
+                        let threshold = slow::threshold();
+                        if threshold > 0 {
+                            self.check_slow(&*task, &req, threshold);
+                        }
+
                         req.free_packet();
-                        self.responses
-                            .write()
-                            .push(rpc::fixup_header_length_fields(res));
+
+                        // A response too long for a datagram is cut down to it's header, with a
+                        // status that tells the client why.
+                        let res = match rpc::fixup_header_length_fields(res) {
+                            Ok(res) => Some(res),
+                            Err(res) => rpc::truncate_rpc_response(res),
+                        };
+                        match res {
+                            Some(res) => {
+                                span::complete(span, res.get_payload().as_ptr() as usize);
+                                self.responses.write().push(res);
+                            }
+
+                            None => counters::add(Counter::MbufExhausted, 1),
+                        }
                     }
                 } else {
+                    if let Some(tenant) = tenant {
+                        meter::task(tenant, exec, 0);
+                    }
+
                     // The task did not complete execution. Add it back to the waiting list so that it
                     // gets to run again.
                     self.waiting.write().push_back(task);
@@ -187,6 +420,35 @@ impl RoundRobin {
             }
         }
     }
+
+    // Records a completed task's request in the slow request log if the task completed more than
+    // `threshold` cycles after it was created.
+    fn check_slow(&self, task: &Task, request: &Packet<UdpHeader, EmptyMetadata>, threshold: u64) {
+        let created = task.created();
+        let total = cycles::rdtsc().saturating_sub(created);
+        if created == 0 || total <= threshold {
+            return;
+        }
+
+        let payload = request.get_payload();
+        slow::record(&slow::SlowRequest {
+            tenant: task.tenant().unwrap_or(0),
+            opcode: payload.get(1).cloned().unwrap_or(0),
+            key: rpc::parse_rpc_key(payload).map(slow::key_hash),
+            name: String::from(task.name()),
+            core: self.core(),
+            total: total,
+            queued: task.scheduled().saturating_sub(created),
+            ran: task.time(),
+            db: task.db_time(),
+        });
+    }
+}
+
+// Encodes the tenant of a task for the `running` field of RoundRobin.
+#[inline]
+fn tenant_plus_one(tenant: Option<TenantId>) -> usize {
+    tenant.map_or(0, |tenant| tenant as usize + 1)
 }
 
 // RoundRobin uses atomics and RwLocks. Hence, it is thread-safe. Need to explicitly mark it as
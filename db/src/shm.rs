@@ -0,0 +1,445 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+// Preserving tables across a restart of the server process, so that a binary upgrade does not
+// mean reloading every table from clients or from the log. As a server drains (see `drain`), it
+// writes every table to a named segment at `shm_path`, which should be on a tmpfs such as
+// /dev/shm or on a hugetlbfs mount, and so outlives the process but not the machine. The server
+// it is restarted as maps the segment in and loads the tables off it at memory speed, before
+// taking on any requests, and then removes it. Objects live in the server's heap, so they are
+// copied out of the segment rather than used in place.
+//
+// Format version 1, with every integer little endian:
+//
+//   header:  magic "SPLSHM\0\0" (8 bytes), format version (u32), the number of tables (u32),
+//            the LSN of the last logged write the tables reflect (u64, zero without a log),
+//            the number of bytes of tables (u64)
+//   tables:  a snapshot of every table (see `snapshot`), one after the other
+//
+// The segment is mapped in whole pages of `PAGE_LEN` bytes, which suits hugetlbfs, and the
+// bytes past the tables are zero. It is written to a path of it's own and renamed to `shm_path`
+// once complete, so a server that dies while draining leaves no segment behind. Tenants are not
+// preserved, only the tables of tenants that exist when the segment is loaded are. Tenants that
+// have a key keep their data sealed wherever it outlives the process, so no segment is written
+// while any does.
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::slice;
+use std::time::Instant;
+
+use super::master::Master;
+use super::replica::{self, Record};
+use super::snapshot;
+
+use libc;
+
+/// The bytes every segment starts with.
+pub const MAGIC: [u8; 8] = *b"SPLSHM\0\0";
+
+/// The newest version of the format, written by `save()`.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// The number of bytes in a segment's header.
+pub const HEADER_LEN: usize = 32;
+
+/// The size segments are rounded up to, that of a huge page.
+pub const PAGE_LEN: usize = 2 << 20;
+
+/// The number of bytes of a snapshot besides it's objects: the header and trailer.
+const SNAPSHOT_OVERHEAD: usize = snapshot::HEADER_LEN + 18;
+
+// A memory mapping of a segment, unmapped when dropped.
+struct Map {
+    addr: *mut u8,
+    len: usize,
+}
+
+// Implementation of methods on Map.
+impl Map {
+    // Maps the first `len` bytes of a file, writable if `write` is set.
+    unsafe fn new(file: &File, len: usize, write: bool) -> io::Result<Map> {
+        let prot = match write {
+            true => libc::PROT_READ | libc::PROT_WRITE,
+            false => libc::PROT_READ,
+        };
+
+        let addr = libc::mmap(
+            ptr::null_mut(),
+            len,
+            prot,
+            libc::MAP_SHARED | libc::MAP_POPULATE,
+            file.as_raw_fd(),
+            0,
+        );
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Map {
+            addr: addr as *mut u8,
+            len: len,
+        })
+    }
+}
+
+impl Drop for Map {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.addr as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+/// What a segment's header says about it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Header {
+    /// The number of tables in the segment.
+    pub tables: u32,
+
+    /// The LSN of the last logged write the tables reflect, zero if writes were not logged.
+    pub lsn: u64,
+
+    /// The number of bytes of tables following the header.
+    pub len: u64,
+}
+
+// Implementation of methods on Header.
+impl Header {
+    /// Encodes the header, as described at the top of this file.
+    pub fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut buf = [0u8; HEADER_LEN];
+        buf[..8].copy_from_slice(&MAGIC);
+        put_le(&mut buf[8..], FORMAT_VERSION as u64, 4);
+        put_le(&mut buf[12..], self.tables as u64, 4);
+        put_le(&mut buf[16..], self.lsn, 8);
+        put_le(&mut buf[24..], self.len, 8);
+        buf
+    }
+
+    /// Decodes the header at the start of a segment.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf`: The segment.
+    ///
+    /// # Return
+    ///
+    /// The header. An error of kind InvalidData if the buffer does not hold a segment, holds one
+    /// of a newer version, or is too short for the tables the header claims.
+    pub fn decode(buf: &[u8]) -> io::Result<Header> {
+        if buf.len() < HEADER_LEN || buf[..8] != MAGIC {
+            return Err(invalid("not a segment of preserved tables"));
+        }
+
+        let version = read_le(&buf[8..], 4) as u32;
+        if version == 0 || version > FORMAT_VERSION {
+            let msg = format!("segment is of format version {}", version);
+            return Err(invalid(&msg));
+        }
+
+        let header = Header {
+            tables: read_le(&buf[12..], 4) as u32,
+            lsn: read_le(&buf[16..], 8),
+            len: read_le(&buf[24..], 8),
+        };
+        if header.len > (buf.len() - HEADER_LEN) as u64 {
+            return Err(invalid("segment is truncated"));
+        }
+
+        Ok(header)
+    }
+}
+
+/// Writes every table to a segment at `path`, replacing any segment already there. Must only
+/// be called once the server has stopped taking on writes.
+///
+/// # Arguments
+///
+/// * `master`: Master, which tables are written from.
+/// * `path`:   The path of the segment.
+/// * `lsn`:    The LSN of the last logged write, zero if writes are not logged.
+///
+/// # Return
+///
+/// The number of objects written. An error if any tenant has a key, or if the segment could
+/// not be written, in which case there is no segment at `path`.
+pub fn save(master: &Master, path: &str, lsn: u64) -> io::Result<u64> {
+    let start = Instant::now();
+    let _ = fs::remove_file(path);
+
+    let mut tables = Vec::new();
+    for tenant in master.tenant_ids() {
+        if master.tenant_key(tenant).is_some() {
+            let msg = format!("tenant {} has a key, it's tables are not preserved", tenant);
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
+        }
+
+        for table in master.owned_tables(tenant).unwrap_or(Vec::new()) {
+            tables.push((tenant, table));
+        }
+    }
+
+    // Size the segment up front; a table that grew since would fail to fit.
+    let mut len = HEADER_LEN;
+    for &(tenant, table) in tables.iter() {
+        len += SNAPSHOT_OVERHEAD;
+        master.visit_table(tenant, table, &mut |rec| {
+            len += snapshot::OBJECT_HDR_LEN + rec.key.len() + rec.val.len();
+        });
+    }
+    let len = (len + PAGE_LEN - 1) / PAGE_LEN * PAGE_LEN;
+
+    let partial = format!("{}.partial", path);
+    let objects = write(master, &partial, len, &tables, lsn);
+    let objects = objects.and_then(|objects| fs::rename(&partial, path).map(|_| objects));
+    if objects.is_err() {
+        let _ = fs::remove_file(&partial);
+    }
+
+    let objects = objects?;
+    info!(
+        "Preserved {} tables, {} objects and {} bytes in {} in {:?}",
+        tables.len(),
+        objects,
+        len,
+        path,
+        start.elapsed()
+    );
+    Ok(objects)
+}
+
+// Writes tables to a new segment of `len` bytes at `path`, and returns the number of objects
+// written.
+fn write(
+    master: &Master,
+    path: &str,
+    len: usize,
+    tables: &[(u32, u64)],
+    lsn: u64,
+) -> io::Result<u64> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    file.set_len(len as u64)?;
+
+    let map = unsafe { Map::new(&file, len, true)? };
+    let seg = unsafe { slice::from_raw_parts_mut(map.addr, map.len) };
+
+    let (hdr, body) = seg.split_at_mut(HEADER_LEN);
+    let total = body.len();
+    let mut out = body;
+    let mut objects = 0;
+    for &(tenant, table) in tables.iter() {
+        let mut writer = snapshot::Writer::new(&mut out, tenant, table, 0)?;
+        let mut failed = None;
+        master.visit_table(tenant, table, &mut |rec| {
+            if failed.is_none() {
+                failed = writer.push(rec.key, rec.val).err();
+            }
+        });
+        if let Some(e) = failed {
+            return Err(e);
+        }
+
+        objects += writer.finish()?;
+    }
+
+    let header = Header {
+        tables: tables.len() as u32,
+        lsn: lsn,
+        len: (total - out.len()) as u64,
+    };
+    hdr.copy_from_slice(&header.encode());
+    Ok(objects)
+}
+
+/// Loads the tables preserved in the segment at `path`, if there is one, and removes it.
+///
+/// # Arguments
+///
+/// * `master`:  Master, which tables are loaded into. Must not have taken on any requests.
+/// * `path`:    The path of the segment.
+/// * `resumes`: Called with the LSN of the last logged write the tables reflect. Returns false
+///              if the log has moved past it, in which case the segment is discarded unloaded.
+///
+/// # Return
+///
+/// The LSN, or None if no tables were loaded. An error if the segment is corrupt, in which case
+/// some tables may have been loaded, and the segment is set aside with ".corrupt" appended to
+/// it's path.
+pub fn attach<F: FnOnce(u64) -> bool>(
+    master: &Master,
+    path: &str,
+    resumes: F,
+) -> io::Result<Option<u64>> {
+    let start = Instant::now();
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let len = file.metadata()?.len() as usize;
+    let loaded = match len {
+        0 => Err(invalid("segment is empty")),
+        _ => unsafe { Map::new(&file, len, false) }.and_then(|map| {
+            let seg = unsafe { slice::from_raw_parts(map.addr, map.len) };
+            let header = Header::decode(seg)?;
+            if !resumes(header.lsn) {
+                warn!("Discarding {}, the log has moved past the writes it reflects", path);
+                return Ok(None);
+            }
+
+            let body = &seg[HEADER_LEN..HEADER_LEN + header.len as usize];
+            let objects = load(body, header.tables, &mut |rec| master.replay_record(rec))?;
+            info!(
+                "Loaded {} tables, {} objects, up to LSN {} off {} in {:?}",
+                header.tables,
+                objects,
+                header.lsn,
+                path,
+                start.elapsed()
+            );
+            Ok(Some(header.lsn))
+        }),
+    };
+
+    // The tables now live in the heap, and a crash from here on must not bring back what the
+    // segment holds.
+    match loaded {
+        Ok(lsn) => fs::remove_file(path).map(|_| lsn),
+        Err(e) => {
+            fs::rename(path, format!("{}.corrupt", path))?;
+            Err(e)
+        }
+    }
+}
+
+/// Loads the tables in the body of a segment.
+///
+/// # Arguments
+///
+/// * `body`:   The snapshots of the tables, one after the other.
+/// * `tables`: The number of tables.
+/// * `apply`:  Called with a put of every object.
+///
+/// # Return
+///
+/// The number of objects loaded. An error of kind InvalidData or UnexpectedEof if any snapshot
+/// is corrupt or truncated, or if there is more to the body than the tables.
+pub fn load(body: &[u8], tables: u32, apply: &mut FnMut(&Record)) -> io::Result<u64> {
+    let mut rest = body;
+    let mut objects = 0;
+    for _ in 0..tables {
+        let (mut reader, header) = snapshot::Reader::new(&mut rest)?;
+        while let Some((key, val)) = reader.next()? {
+            apply(&Record {
+                op: replica::OP_PUT,
+                tenant: header.tenant,
+                table: header.table,
+                key: &key,
+                val: &val,
+            });
+            objects += 1;
+        }
+    }
+
+    if rest.len() > 0 {
+        return Err(invalid("segment holds more than it's tables"));
+    }
+
+    Ok(objects)
+}
+
+// Returns an error of kind InvalidData.
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+// Writes the lower `n` bytes of a value into a buffer, least significant byte first.
+fn put_le(buf: &mut [u8], val: u64, n: usize) {
+    for i in 0..n {
+        buf[i] = (val >> (8 * i)) as u8;
+    }
+}
+
+// Reads `n` bytes off a buffer into a value, least significant byte first.
+fn read_le(buf: &[u8], n: usize) -> u64 {
+    let mut val = 0;
+    for i in 0..n {
+        val |= (buf[i] as u64) << (8 * i);
+    }
+
+    return val;
+}
+
+// This module contains unit tests for preserving tables in shared memory.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // This unit test verifies that headers decode as encoded, and that anything else is
+    // rejected.
+    #[test]
+    fn test_header() {
+        let header = Header {
+            tables: 3,
+            lsn: 42,
+            len: 8,
+        };
+        let mut seg = header.encode().to_vec();
+        seg.extend_from_slice(&[0; 8]);
+        assert_eq!(header, Header::decode(&seg).unwrap());
+
+        assert!(Header::decode(&seg[..HEADER_LEN + 7]).is_err());
+        seg[8] = 2;
+        assert!(Header::decode(&seg).is_err());
+        seg[0] = b'X';
+        assert!(Header::decode(&seg).is_err());
+    }
+
+    // This unit test verifies that the tables in a body are loaded in order, and that a body
+    // with anything but the tables is rejected.
+    #[test]
+    fn test_load() {
+        let mut body = Vec::new();
+        for &(tenant, table) in [(1, 10), (2, 20)].iter() {
+            let mut writer = snapshot::Writer::new(&mut body, tenant, table, 0).unwrap();
+            writer.push(&[tenant as u8], &[7, 8]).unwrap();
+            writer.push(&[tenant as u8, 0], &[]).unwrap();
+            writer.finish().unwrap();
+        }
+
+        let mut seen = Vec::new();
+        let objects = load(&body, 2, &mut |rec| {
+            seen.push((rec.tenant, rec.table, rec.key.to_vec(), rec.val.to_vec()));
+        });
+        assert_eq!(4, objects.unwrap());
+        assert_eq!((1, 10, vec![1], vec![7, 8]), seen[0]);
+        assert_eq!((2, 20, vec![2, 0], vec![]), seen[3]);
+
+        assert!(load(&body, 1, &mut |_| {}).is_err());
+        assert!(load(&body, 3, &mut |_| {}).is_err());
+        assert!(load(&body[..body.len() - 1], 2, &mut |_| {}).is_err());
+    }
+}
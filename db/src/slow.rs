@@ -0,0 +1,263 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+
+// Slow request logging. A request whose task takes longer than a configurable threshold from
+// the time it was dispatched to the time it completed is recorded along with where that time
+// went: waiting to run for the first time, running, and waiting on the run-queue after yielding,
+// with the part of the time it ran that was spent in calls into the database broken out. The
+// most recent records are kept in a bounded ring to answer the slow() RPC. Requests that finish
+// in time only pay for a comparison.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::hash::Hasher;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::sync::{Once, ONCE_INIT};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::audit::quote;
+use super::common::TenantId;
+use super::cycles;
+use super::span::op_name;
+
+use spin::Mutex;
+
+/// The number of records kept. Older records are dropped.
+const MAX_RECORDS: usize = 1024;
+
+/// The maximum number of records returned by a single query.
+pub const MAX_QUERY: usize = 256;
+
+/// A request that took longer than the threshold. Times are in cycles.
+pub struct SlowRequest {
+    /// The tenant that sent the request, and the operation it asked for.
+    pub tenant: TenantId,
+    pub opcode: u8,
+
+    /// A hash of the key on the request, for get() and put() requests, so that requests for the
+    /// same hot key can be picked out without the key itself being recorded.
+    pub key: Option<u64>,
+
+    /// The name of the extension the request invoked, or a description of what the task did.
+    pub name: String,
+
+    /// The core the request's task ran on.
+    pub core: i32,
+
+    /// The time from the request being dispatched to it's task completing.
+    pub total: u64,
+
+    /// The time from the request being dispatched to it's task running for the first time.
+    pub queued: u64,
+
+    /// The time the task ran for, and the part of it that was spent in calls into the database.
+    pub ran: u64,
+    pub db: u64,
+}
+
+// The ring of the most recent records.
+struct Ring {
+    // The sequence number of the next record.
+    next: u64,
+
+    // The most recent records, oldest first, along with their sequence numbers.
+    records: VecDeque<(u64, String)>,
+}
+
+// Implementation of methods on Ring.
+impl Ring {
+    // Returns an empty ring.
+    fn new() -> Ring {
+        Ring {
+            next: 1,
+            records: VecDeque::new(),
+        }
+    }
+
+    // Appends a record of a request completed at `ts` milliseconds since the UNIX epoch, with
+    // times converted at `cps` cycles per second.
+    fn append(&mut self, ts: u64, cps: u64, req: &SlowRequest) {
+        let seq = self.next;
+        self.next += 1;
+
+        let us = |cycles: u64| cycles * 1000000 / cps.max(1);
+        let waited = req.total.saturating_sub(req.queued + req.ran);
+        let key = req.key.map_or(String::from("null"), |key| format!("\"{:016x}\"", key));
+        let line = format!(
+            "{{\"seq\":{},\"ts_ms\":{},\"core\":{},\"tenant\":{},\"op\":\"{}\",\"key_hash\":{},\
+             \"name\":{},\"total_us\":{},\"queued_us\":{},\"run_us\":{},\"db_us\":{},\
+             \"yielded_us\":{}}}",
+            seq,
+            ts,
+            req.core,
+            req.tenant,
+            op_name(req.opcode),
+            key,
+            quote(&req.name),
+            us(req.total),
+            us(req.queued),
+            us(req.ran),
+            us(req.db),
+            us(waited)
+        );
+
+        if self.records.len() == MAX_RECORDS {
+            self.records.pop_front();
+        }
+        self.records.push_back((seq, line));
+    }
+
+    // Returns upto `max` records starting at sequence number `since`, each followed by a newline.
+    fn query(&self, since: u64, max: usize) -> Vec<u8> {
+        let mut ret = Vec::new();
+        for &(_, ref line) in self
+            .records
+            .iter()
+            .filter(|record| record.0 >= since)
+            .take(max)
+        {
+            ret.extend_from_slice(line.as_bytes());
+            ret.push(b'\n');
+        }
+        ret
+    }
+}
+
+/// The threshold in cycles above which requests are recorded, zero if none are.
+static THRESHOLD: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Initializes RING exactly once.
+static RING_INIT: Once = ONCE_INIT;
+
+/// The ring shared by all threads. Use `shared()` to access it.
+static mut RING: *const Mutex<Ring> = 0 as *const Mutex<Ring>;
+
+// Returns the ring shared by all threads, allocating it on first use.
+fn shared() -> &'static Mutex<Ring> {
+    unsafe {
+        RING_INIT.call_once(|| {
+            RING = Box::into_raw(Box::new(Mutex::new(Ring::new())));
+        });
+        &*RING
+    }
+}
+
+/// Configures the threshold above which requests are recorded.
+///
+/// # Arguments
+///
+/// * `threshold_us`: The time in microseconds from a request being dispatched to it's task
+///                   completing above which it is recorded. Zero if no request should be.
+pub fn configure(threshold_us: u64) {
+    let threshold = threshold_us * (cycles::cycles_per_second() / 1000000);
+    THRESHOLD.store(threshold as usize, Ordering::Relaxed);
+}
+
+/// Returns the threshold in cycles above which requests are recorded, or zero if none are.
+#[inline]
+pub fn threshold() -> u64 {
+    THRESHOLD.load(Ordering::Relaxed) as u64
+}
+
+/// Returns a hash of a key, as recorded on slow requests.
+pub fn key_hash(key: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(key);
+    hasher.finish()
+}
+
+/// Records a request that took longer than the threshold.
+///
+/// # Arguments
+///
+/// * `req`: The request.
+pub fn record(req: &SlowRequest) {
+    let ts = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() * 1000 + d.subsec_nanos() as u64 / 1000000)
+        .unwrap_or(0);
+
+    shared().lock().append(ts, cycles::cycles_per_second(), req);
+}
+
+/// Looks up the slow requests recorded.
+///
+/// # Arguments
+///
+/// * `since`: The smallest sequence number of records that should be returned.
+///
+/// # Return
+///
+/// The oldest records still kept with a sequence number of at least `since`, upto `MAX_QUERY`
+/// of them, as lines of JSON.
+pub fn query(since: u64) -> Vec<u8> {
+    shared().lock().query(since, MAX_QUERY)
+}
+
+// This module contains unit tests for slow request logging.
+#[cfg(test)]
+mod tests {
+    use super::{key_hash, Ring, SlowRequest, MAX_RECORDS};
+    use wireformat::OpCode;
+
+    // Returns a slow get() of a key that spent 1000 cycles queued, ran for 400 and yielded for
+    // the rest.
+    fn request(tenant: u32) -> SlowRequest {
+        SlowRequest {
+            tenant: tenant,
+            opcode: OpCode::SandstormGetRpc as u8,
+            key: Some(key_hash(b"key")),
+            name: String::from("native"),
+            core: 3,
+            total: 2000,
+            queued: 1000,
+            ran: 400,
+            db: 300,
+        }
+    }
+
+    // This unit test verifies that records are formatted as lines of JSON with the time a request
+    // took broken down into stages, and that queries start at the sequence number asked for.
+    #[test]
+    fn test_query() {
+        let mut ring = Ring::new();
+        ring.append(100, 1000000, &request(1));
+        ring.append(101, 1000000, &request(2));
+
+        let expected = format!(
+            "{{\"seq\":2,\"ts_ms\":101,\"core\":3,\"tenant\":2,\"op\":\"get\",\
+             \"key_hash\":\"{:016x}\",\"name\":\"native\",\"total_us\":2000,\"queued_us\":1000,\
+             \"run_us\":400,\"db_us\":300,\"yielded_us\":600}}\n",
+            key_hash(b"key")
+        );
+        assert_eq!(expected.as_bytes(), &ring.query(2, 10)[..]);
+        assert_eq!(2, ring.query(0, 10).iter().filter(|&&b| b == b'\n').count());
+        assert_eq!(1, ring.query(0, 1).iter().filter(|&&b| b == b'\n').count());
+        assert!(ring.query(3, 10).is_empty());
+    }
+
+    // This unit test verifies that only the most recent records are kept.
+    #[test]
+    fn test_bounded() {
+        let mut ring = Ring::new();
+        for tenant in 0..(MAX_RECORDS + 10) {
+            ring.append(0, 1000000, &request(tenant as u32));
+        }
+
+        assert_eq!(MAX_RECORDS, ring.records.len());
+        assert_eq!(11, ring.records.front().unwrap().0);
+    }
+}
@@ -0,0 +1,415 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+
+// Portable snapshots of a table. A snapshot file holds every object of one table, and is the
+// format tables are exported to and imported from, so that data can be bulk loaded from offline
+// pipelines and moved between clusters. Pipelines written in Rust can use `Writer` to produce
+// snapshots; the format is simple enough to write from anything else.
+//
+// Format version 1, with every integer little endian:
+//
+//   header:  magic "SPLSNAP\0" (8 bytes), format version (u32), flags (u32, see FLAG_SEALED),
+//            tenant (u32), table (u64)
+//   objects: key length (u16, non-zero), value length (u32), key, value; repeated
+//   trailer: zero (u16), the number of objects (u64), and the FNV-1a hash (u64) of every byte
+//            of the objects, from the first key length upto the trailer
+//
+// The tenant and table in the header are those the snapshot was exported from: a snapshot can be
+// imported into any table, but sealed values only open under the table they were exported from.
+// Readers reject versions newer than the one they know, flags they do not know, and snapshots
+// that are truncated or fail the hash.
+
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+/// The bytes every snapshot starts with.
+pub const MAGIC: [u8; 8] = *b"SPLSNAP\0";
+
+/// The newest version of the format, written by `Writer`.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Set in a snapshot's flags if every value in it is sealed with the key of the tenant it was
+/// exported from (see `crypt::Key::seal()`), under the table in it's header.
+pub const FLAG_SEALED: u32 = 0x1;
+
+/// The number of bytes in a snapshot's header.
+pub const HEADER_LEN: usize = 28;
+
+/// The number of bytes every object in a snapshot is prefixed with: the length of it's key and
+/// the length of it's value.
+pub const OBJECT_HDR_LEN: usize = 6;
+
+/// What a snapshot's header says about it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Header {
+    /// The version of the format the snapshot is in.
+    pub version: u32,
+
+    /// The snapshot's flags.
+    pub flags: u32,
+
+    /// The tenant owning the table the snapshot was taken of.
+    pub tenant: u32,
+
+    /// The table the snapshot was taken of.
+    pub table: u64,
+}
+
+/// Hashes bytes with 64 bit FNV-1a, a piece at a time. The hash is the tuple's field.
+pub struct Fnv(pub u64);
+
+// Implementation of methods on Fnv.
+impl Fnv {
+    /// Returns the hash of no bytes.
+    pub fn new() -> Fnv {
+        Fnv(0xcbf29ce484222325)
+    }
+
+    /// Adds bytes to the hash.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf`: The bytes.
+    pub fn update(&mut self, buf: &[u8]) {
+        for byte in buf.iter() {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+/// Writes a snapshot to a stream, an object at a time.
+pub struct Writer<W: Write> {
+    // The stream written to.
+    out: W,
+
+    // The number of objects written, and the hash of their bytes.
+    count: u64,
+    hash: Fnv,
+}
+
+// Implementation of methods on Writer.
+impl<W: Write> Writer<W> {
+    /// Starts a snapshot by writing it's header.
+    ///
+    /// # Arguments
+    ///
+    /// * `out`:    The stream to write to. Writes are not buffered, so it should be.
+    /// * `tenant`: The tenant owning the table the snapshot is of.
+    /// * `table`:  The table the snapshot is of.
+    /// * `flags`:  The snapshot's flags. FLAG_SEALED if the values pushed are sealed.
+    pub fn new(mut out: W, tenant: u32, table: u64, flags: u32) -> io::Result<Writer<W>> {
+        let mut hdr = Vec::with_capacity(HEADER_LEN);
+        hdr.extend_from_slice(&MAGIC);
+        push_le(&mut hdr, FORMAT_VERSION as u64, 4);
+        push_le(&mut hdr, flags as u64, 4);
+        push_le(&mut hdr, tenant as u64, 4);
+        push_le(&mut hdr, table, 8);
+        out.write_all(&hdr)?;
+
+        Ok(Writer {
+            out: out,
+            count: 0,
+            hash: Fnv::new(),
+        })
+    }
+
+    /// Appends an object to the snapshot.
+    ///
+    /// # Arguments
+    ///
+    /// * `key`: The object's key. Must not be empty, and is limited to 64 KB.
+    /// * `val`: The object's value.
+    pub fn push(&mut self, key: &[u8], val: &[u8]) -> io::Result<()> {
+        if key.len() == 0 || key.len() > 0xffff || val.len() > 0xffffffff {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "key is empty or too long, or value is too long",
+            ));
+        }
+
+        let mut hdr = Vec::with_capacity(OBJECT_HDR_LEN);
+        push_le(&mut hdr, key.len() as u64, 2);
+        push_le(&mut hdr, val.len() as u64, 4);
+        for piece in [&hdr[..], key, val].iter() {
+            self.hash.update(piece);
+            self.out.write_all(piece)?;
+        }
+
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Ends the snapshot by writing it's trailer, and flushes the stream.
+    ///
+    /// # Return
+    ///
+    /// The number of objects in the snapshot.
+    pub fn finish(mut self) -> io::Result<u64> {
+        let mut trailer = Vec::with_capacity(18);
+        push_le(&mut trailer, 0, 2);
+        push_le(&mut trailer, self.count, 8);
+        push_le(&mut trailer, self.hash.0, 8);
+        self.out.write_all(&trailer)?;
+        self.out.flush()?;
+
+        Ok(self.count)
+    }
+}
+
+/// Reads a snapshot from a stream, an object at a time.
+pub struct Reader<R: Read> {
+    // The stream read from.
+    input: R,
+
+    // The number of objects read, and the hash of their bytes.
+    count: u64,
+    hash: Fnv,
+
+    // Set once the trailer was read and checked.
+    done: bool,
+}
+
+// Implementation of methods on Reader.
+impl<R: Read> Reader<R> {
+    /// Starts reading a snapshot by reading and checking it's header.
+    ///
+    /// # Arguments
+    ///
+    /// * `input`: The stream to read from. Reads are not buffered, so it should be.
+    ///
+    /// # Return
+    ///
+    /// The reader and the snapshot's header. An error of kind InvalidData if the stream does not
+    /// hold a snapshot, or holds one of a newer version or with flags this reader does not know.
+    pub fn new(mut input: R) -> io::Result<(Reader<R>, Header)> {
+        let mut hdr = [0u8; HEADER_LEN];
+        input.read_exact(&mut hdr)?;
+        if hdr[..8] != MAGIC {
+            return Err(invalid("not a snapshot"));
+        }
+
+        let header = Header {
+            version: read_le(&hdr[8..], 4) as u32,
+            flags: read_le(&hdr[12..], 4) as u32,
+            tenant: read_le(&hdr[16..], 4) as u32,
+            table: read_le(&hdr[20..], 8),
+        };
+        if header.version == 0 || header.version > FORMAT_VERSION {
+            let msg = format!("snapshot is of format version {}", header.version);
+            return Err(invalid(&msg));
+        }
+        if header.flags & !FLAG_SEALED != 0 {
+            let msg = format!("snapshot has unknown flags {:#x}", header.flags);
+            return Err(invalid(&msg));
+        }
+
+        let reader = Reader {
+            input: input,
+            count: 0,
+            hash: Fnv::new(),
+            done: false,
+        };
+        Ok((reader, header))
+    }
+
+    /// Reads the next object off the snapshot.
+    ///
+    /// # Return
+    ///
+    /// The object's key and value, or None once the trailer was read. An error of kind
+    /// InvalidData if the trailer does not match the objects read, and of kind UnexpectedEof if
+    /// the snapshot is truncated.
+    pub fn next(&mut self) -> io::Result<Option<(Vec<u8>, Vec<u8>)>> {
+        if self.done {
+            return Ok(None);
+        }
+
+        let mut len = [0u8; 2];
+        self.input.read_exact(&mut len)?;
+        let k_len = read_le(&len, 2) as usize;
+
+        // A zero key length starts the trailer.
+        if k_len == 0 {
+            let mut trailer = [0u8; 16];
+            self.input.read_exact(&mut trailer)?;
+            if read_le(&trailer, 8) != self.count || read_le(&trailer[8..], 8) != self.hash.0 {
+                return Err(invalid("snapshot does not match it's trailer"));
+            }
+
+            self.done = true;
+            return Ok(None);
+        }
+
+        let mut v_len = [0u8; 4];
+        self.input.read_exact(&mut v_len)?;
+        let mut key = vec![0u8; k_len];
+        self.input.read_exact(&mut key)?;
+
+        // The value is read without allocating upfront, so that a corrupt length fails on the
+        // end of the stream rather than on a huge allocation.
+        let want = read_le(&v_len, 4);
+        let mut val = Vec::new();
+        (&mut self.input).take(want).read_to_end(&mut val)?;
+        if (val.len() as u64) < want {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "snapshot is truncated"));
+        }
+
+        for piece in [&len[..], &v_len[..], &key, &val].iter() {
+            self.hash.update(piece);
+        }
+        self.count += 1;
+
+        Ok(Some((key, val)))
+    }
+}
+
+/// Resolves the path of a snapshot file named by a tenant against the directory snapshots are
+/// kept in, so that exports and imports cannot touch files outside of it.
+///
+/// # Arguments
+///
+/// * `dir`:  The directory snapshots are kept in, `snapshot_dir` in the server's config.
+/// * `path`: The path named by the tenant.
+///
+/// # Return
+///
+/// The path under `dir`, or None if `dir` is empty, or if `path` is empty, absolute, or has a
+/// ".." in it.
+pub fn resolve(dir: &str, path: &str) -> Option<PathBuf> {
+    if dir.len() == 0 || path.len() == 0 {
+        return None;
+    }
+
+    let path = Path::new(path);
+    let confined = path.components().all(|part| match part {
+        Component::Normal(_) | Component::CurDir => true,
+        _ => false,
+    });
+    if !confined {
+        return None;
+    }
+
+    Some(Path::new(dir).join(path))
+}
+
+// Returns an error of kind InvalidData.
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+// Writes the lower `n` bytes of a value into a buffer, least significant byte first.
+fn push_le(buf: &mut Vec<u8>, val: u64, n: usize) {
+    for i in 0..n {
+        buf.push((val >> (8 * i)) as u8);
+    }
+}
+
+// Reads `n` bytes off a buffer into a value, least significant byte first.
+fn read_le(buf: &[u8], n: usize) -> u64 {
+    let mut val = 0;
+    for i in 0..n {
+        val |= (buf[i] as u64) << (8 * i);
+    }
+
+    return val;
+}
+
+// This module contains unit tests for snapshots.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writes a snapshot of a few objects into a buffer.
+    fn snapshot() -> Vec<u8> {
+        let mut buf = Vec::new();
+        {
+            let mut writer = Writer::new(&mut buf, 3, 9, FLAG_SEALED).unwrap();
+            writer.push(b"apple", b"red").unwrap();
+            writer.push(b"kiwi", b"").unwrap();
+            assert!(writer.push(b"", b"nothing").is_err());
+            assert_eq!(2, writer.finish().unwrap());
+        }
+        buf
+    }
+
+    // This unit test verifies that a snapshot reads back as written.
+    #[test]
+    fn test_roundtrip() {
+        let buf = snapshot();
+        assert_eq!(HEADER_LEN + 2 * OBJECT_HDR_LEN + 12 + 18, buf.len());
+
+        let (mut reader, header) = Reader::new(&buf[..]).unwrap();
+        assert_eq!(
+            Header {
+                version: FORMAT_VERSION,
+                flags: FLAG_SEALED,
+                tenant: 3,
+                table: 9,
+            },
+            header
+        );
+        assert_eq!(Some((b"apple".to_vec(), b"red".to_vec())), reader.next().unwrap());
+        assert_eq!(Some((b"kiwi".to_vec(), Vec::new())), reader.next().unwrap());
+        assert_eq!(None, reader.next().unwrap());
+        assert_eq!(None, reader.next().unwrap());
+    }
+
+    // This unit test verifies that snapshots that are corrupt, truncated, of a newer version or
+    // with unknown flags are rejected.
+    #[test]
+    fn test_reject() {
+        let read_all = |buf: &[u8]| -> io::Result<u64> {
+            let (mut reader, _) = Reader::new(buf)?;
+            let mut count = 0;
+            while let Some(_) = reader.next()? {
+                count += 1;
+            }
+            Ok(count)
+        };
+        assert_eq!(2, read_all(&snapshot()).unwrap());
+
+        let mut corrupt = snapshot();
+        corrupt[HEADER_LEN + OBJECT_HDR_LEN] ^= 1;
+        assert_eq!(io::ErrorKind::InvalidData, read_all(&corrupt).unwrap_err().kind());
+
+        let truncated = snapshot();
+        let truncated = &truncated[..truncated.len() - 1];
+        assert_eq!(io::ErrorKind::UnexpectedEof, read_all(truncated).unwrap_err().kind());
+
+        let mut newer = snapshot();
+        newer[8] = FORMAT_VERSION as u8 + 1;
+        assert_eq!(io::ErrorKind::InvalidData, read_all(&newer).unwrap_err().kind());
+
+        let mut flagged = snapshot();
+        flagged[12] |= 0x2;
+        assert_eq!(io::ErrorKind::InvalidData, read_all(&flagged).unwrap_err().kind());
+        assert!(read_all(b"SPLSNAP").is_err());
+    }
+
+    // This unit test verifies that snapshot paths are confined to the snapshot directory.
+    #[test]
+    fn test_resolve() {
+        let dir = "/var/lib/snapshots";
+        assert_eq!(Some(PathBuf::from("/var/lib/snapshots/a.snap")), resolve(dir, "a.snap"));
+        assert_eq!(Some(PathBuf::from("/var/lib/snapshots/t/a")), resolve(dir, "./t/a"));
+        assert_eq!(None, resolve("", "a.snap"));
+        assert_eq!(None, resolve(dir, ""));
+        assert_eq!(None, resolve(dir, "/etc/passwd"));
+        assert_eq!(None, resolve(dir, "../a.snap"));
+        assert_eq!(None, resolve(dir, "t/../../a.snap"));
+    }
+}
@@ -0,0 +1,626 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+
+// Per request tracing. A client that wants a request traced sets REQUEST_FLAG_TRACED on it's
+// service byte and appends a trace context: the identifier of the trace and of the client's span
+// the request was issued under. The dispatcher strips the context off before the request reaches
+// a service and opens a span for it. The scheduler marks when the request's task first gets to
+// run, the task's calls into the database are marked while it runs, and the span is closed when
+// the response goes out on the wire. Closed spans are queued up and periodically written out by
+// a single thread as lines of OTLP/JSON, which an OpenTelemetry collector can pick up from the
+// file. Only requests that carry a trace context pay for any of this.
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::mem::size_of;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+use std::sync::{Once, ONCE_INIT};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::common::TenantId;
+use super::cycles;
+use super::wireformat::{OpCode, RpcRequestHeader, REQUEST_FLAG_TRACED, TRACE_CONTEXT_LEN};
+
+use e2d2::common::EmptyMetadata;
+use e2d2::headers::UdpHeader;
+use e2d2::interface::Packet;
+
+use spin::Mutex;
+
+/// The largest number of spans that can be open at once. Requests that arrive while this many
+/// are open are not traced.
+const MAX_OPEN: usize = 4096;
+
+/// The number of seconds a span can stay open for. Spans of requests whose responses never made
+/// it out, such as those dropped by a misbehaving scheduler, are closed as dropped after this.
+const STALE_S: u64 = 10;
+
+/// The largest number of closed spans that can be waiting to be written out. Spans closed while
+/// this many are waiting are dropped.
+const MAX_CLOSED: usize = 65536;
+
+/// The trace context carried at the end of a traced request.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TraceContext {
+    /// The identifier of the trace the request belongs to.
+    pub trace: [u8; 16],
+
+    /// The identifier of the span the request was issued under at the client.
+    pub parent: [u8; 8],
+}
+
+// A span covering a request from the time it was dispatched to the time it's response was sent.
+struct Span {
+    // The trace context the request carried.
+    context: TraceContext,
+
+    // The identifier of this span.
+    id: u64,
+
+    // The tenant that sent the request, it's identifier and it's opcode.
+    tenant: TenantId,
+    stamp: u64,
+    opcode: u8,
+
+    // Time-stamps in cycles at which the request was dispatched, and at which it's response was
+    // sent, or it was dropped.
+    start: u64,
+    end: u64,
+
+    // Points of interest on the way, with the time-stamp in cycles at which each was reached.
+    events: Vec<(&'static str, u64)>,
+
+    // True if the request was dropped without a response.
+    dropped: bool,
+}
+
+// The spans shared by all threads.
+struct Spans {
+    // Spans that are open, by identifier.
+    open: HashMap<u64, Span>,
+
+    // The spans of responses waiting to be sent, by the address of the response's payload.
+    sending: HashMap<usize, u64>,
+
+    // Spans that were closed and are waiting to be written out.
+    closed: Vec<Span>,
+
+    // The identifier handed to the last span opened.
+    last: u64,
+
+    // The wall clock time in nanoseconds and the time-stamp in cycles at which tracing was
+    // configured. Used to convert time-stamps into wall clock time.
+    base: (u64, u64),
+
+    // The file spans are written out to.
+    file: Option<File>,
+}
+
+/// Set when spans are being written out.
+static ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// The number of spans that are open. Lets the dispatcher skip looking up responses when no
+/// request is being traced.
+static OPEN: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Initializes SPANS exactly once.
+static SPANS_INIT: Once = ONCE_INIT;
+
+/// The spans shared by all threads. Use `spans()` to access them.
+static mut SPANS: *const Mutex<Spans> = 0 as *const Mutex<Spans>;
+
+thread_local! {
+    // The span of the request whose task is running on this thread, or zero if there is none.
+    static CURRENT: Cell<u64> = Cell::new(0);
+}
+
+// Returns the spans shared by all threads, allocating them on first use.
+fn spans() -> &'static Mutex<Spans> {
+    unsafe {
+        SPANS_INIT.call_once(|| {
+            let spans = Spans {
+                open: HashMap::new(),
+                sending: HashMap::new(),
+                closed: Vec::new(),
+                last: 0,
+                base: (0, 0),
+                file: None,
+            };
+            SPANS = Box::into_raw(Box::new(Mutex::new(spans)));
+        });
+
+        &*SPANS
+    }
+}
+
+/// Configures tracing.
+///
+/// # Arguments
+///
+/// * `path`: The file closed spans are appended to. Empty turns tracing off; requests carrying
+///           a trace context are then executed like any other.
+pub fn configure(path: &str) {
+    let mut spans = spans().lock();
+    spans.file = None;
+
+    if path.len() > 0 {
+        match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => spans.file = Some(file),
+            Err(err) => warn!("Failed to open span file {}: {}", path, err),
+        }
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() * 1000000000 + d.subsec_nanos() as u64)
+        .unwrap_or(0);
+    spans.base = (now, cycles::rdtsc());
+
+    // Span identifiers start off at the wall clock time so that they differ across restarts.
+    spans.last = now;
+
+    ENABLED.store(spans.file.is_some(), Ordering::Relaxed);
+}
+
+/// Returns true if requests carrying a trace context are being traced.
+#[inline]
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Reads the trace context off an RPC request.
+///
+/// # Arguments
+///
+/// * `payload`: The request, from it's RpcRequestHeader on.
+///
+/// # Return
+///
+/// The trace context, if the request carries one.
+pub fn parse(payload: &[u8]) -> Option<TraceContext> {
+    if payload.len() < size_of::<RpcRequestHeader>() + TRACE_CONTEXT_LEN {
+        return None;
+    }
+
+    if payload[0] & REQUEST_FLAG_TRACED == 0 {
+        return None;
+    }
+
+    let off = payload.len() - TRACE_CONTEXT_LEN;
+    let mut context = TraceContext {
+        trace: [0; 16],
+        parent: [0; 8],
+    };
+    context.trace.copy_from_slice(&payload[off..off + 16]);
+    context.parent.copy_from_slice(&payload[off + 16..off + 24]);
+
+    Some(context)
+}
+
+/// Strips the trace context off an RPC request, so that the service it is dispatched to sees it
+/// as it would have without one. Requests that are spread over a chain of buffers cannot carry a
+/// trace context; the flag is cleared on them, and nothing else is touched.
+///
+/// # Arguments
+///
+/// * `request`: The request, parsed upto it's UDP header.
+///
+/// # Return
+///
+/// The trace context, if the request carried one.
+pub fn strip(request: &mut Packet<UdpHeader, EmptyMetadata>) -> Option<TraceContext> {
+    let flagged = match request.get_payload().get(0) {
+        Some(service) => service & REQUEST_FLAG_TRACED != 0,
+        None => false,
+    };
+    if !flagged {
+        return None;
+    }
+
+    let context = if request.chained_len() == 0 {
+        parse(request.get_payload())
+    } else {
+        None
+    };
+
+    request.get_mut_payload()[0] &= !REQUEST_FLAG_TRACED;
+    if context.is_some() {
+        request.trim_payload_size(TRACE_CONTEXT_LEN);
+    }
+
+    context
+}
+
+/// Opens a span for a request that carried a trace context.
+///
+/// # Arguments
+///
+/// * `context`: The trace context on the request.
+/// * `tenant`:  The tenant that sent the request.
+/// * `stamp`:   The identifier on the request.
+/// * `opcode`:  The opcode on the request.
+///
+/// # Return
+///
+/// The identifier of the span, or zero if tracing is off or too many spans are open.
+pub fn begin(context: &TraceContext, tenant: TenantId, stamp: u64, opcode: u8) -> u64 {
+    if !enabled() || OPEN.load(Ordering::Relaxed) >= MAX_OPEN {
+        return 0;
+    }
+
+    let mut spans = spans().lock();
+
+    // Identifiers are handed out in a sequence, mixed so that they look random to a collector.
+    // Zero means no span, and is skipped.
+    spans.last = spans.last.wrapping_add(0x9e3779b97f4a7c15);
+    let mut id = spans.last;
+    id = (id ^ (id >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    id = (id ^ (id >> 27)).wrapping_mul(0x94d049bb133111eb);
+    id = id ^ (id >> 31);
+    if id == 0 {
+        id = 1;
+    }
+
+    let span = Span {
+        context: *context,
+        id: id,
+        tenant: tenant,
+        stamp: stamp,
+        opcode: opcode,
+        start: cycles::rdtsc(),
+        end: 0,
+        events: Vec::new(),
+        dropped: false,
+    };
+    spans.open.insert(id, span);
+    OPEN.fetch_add(1, Ordering::Relaxed);
+
+    id
+}
+
+/// Sets the span of the request whose task is about to run on this thread. Tasks pick this up
+/// when created, and the scheduler sets it to their span while they run.
+///
+/// # Arguments
+///
+/// * `span`: The span, or zero if the task is not being traced.
+#[inline]
+pub fn enter(span: u64) {
+    CURRENT.with(|current| current.set(span));
+}
+
+/// Returns the span of the request whose task is running on this thread, or zero if there is
+/// none.
+#[inline]
+pub fn current() -> u64 {
+    CURRENT.with(|current| current.get())
+}
+
+/// Marks that a span reached a point of interest.
+///
+/// # Arguments
+///
+/// * `span`: The span. Nothing is marked if it is zero.
+/// * `name`: The name of the point, which becomes the name of an event on the span.
+pub fn mark(span: u64, name: &'static str) {
+    if span == 0 {
+        return;
+    }
+
+    let now = cycles::rdtsc();
+    if let Some(span) = spans().lock().open.get_mut(&span) {
+        span.events.push((name, now));
+    }
+}
+
+/// Marks that the task running on this thread reached a point of interest, such as a call into
+/// the database. Costs a thread local lookup if the task's request is not being traced.
+#[inline]
+pub fn event(name: &'static str) {
+    let span = current();
+    if span != 0 {
+        mark(span, name);
+    }
+}
+
+/// Marks that a span's task completed, and remembers the response so that the span can be
+/// closed once the response is sent.
+///
+/// # Arguments
+///
+/// * `span`:     The span.
+/// * `response`: The address of the payload of the response, from the IP header on.
+pub fn complete(span: u64, response: usize) {
+    if span == 0 {
+        return;
+    }
+
+    let now = cycles::rdtsc();
+    let mut spans = spans().lock();
+    let found = match spans.open.get_mut(&span) {
+        Some(span) => {
+            span.events.push(("completed", now));
+            true
+        }
+        None => false,
+    };
+
+    if found {
+        spans.sending.insert(response, span);
+    }
+}
+
+// Closes an open span, queueing it up to be written out.
+fn close(spans: &mut Spans, span: u64, dropped: bool) {
+    if let Some(mut span) = spans.open.remove(&span) {
+        OPEN.fetch_sub(1, Ordering::Relaxed);
+        span.end = cycles::rdtsc();
+        span.dropped = dropped;
+        if spans.closed.len() < MAX_CLOSED {
+            spans.closed.push(span);
+        }
+    }
+}
+
+/// Closes the span of a response that is being sent, if it has one.
+///
+/// # Arguments
+///
+/// * `response`: The address of the payload of the response, from the IP header on.
+#[inline]
+pub fn sent(response: usize) {
+    if OPEN.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+
+    let mut spans = spans().lock();
+    if let Some(span) = spans.sending.remove(&response) {
+        close(&mut spans, span, false);
+    }
+}
+
+/// Closes a span whose request was dropped without a response.
+///
+/// # Arguments
+///
+/// * `span`: The span. Nothing is closed if it is zero.
+pub fn dropped(span: u64) {
+    if span == 0 {
+        return;
+    }
+
+    close(&mut spans().lock(), span, true);
+}
+
+/// Writes out every span closed since the last call. Performs I/O, so should be called
+/// periodically from a thread off the data path.
+pub fn poll() {
+    if !enabled() {
+        return;
+    }
+
+    let (closed, base) = {
+        let mut spans = spans().lock();
+
+        let stale = cycles::rdtsc().saturating_sub(STALE_S * cycles::cycles_per_second());
+        let ids: Vec<u64> = spans
+            .open
+            .values()
+            .filter(|span| span.start < stale)
+            .map(|span| span.id)
+            .collect();
+        for id in ids {
+            close(&mut spans, id, true);
+        }
+
+        let gone: Vec<usize> = spans
+            .sending
+            .iter()
+            .filter(|&(_, id)| !spans.open.contains_key(id))
+            .map(|(response, _)| *response)
+            .collect();
+        for response in gone {
+            spans.sending.remove(&response);
+        }
+
+        let closed: Vec<Span> = spans.closed.drain(..).collect();
+        (closed, spans.base)
+    };
+
+    if closed.len() == 0 {
+        return;
+    }
+
+    let to_ns = |stamp: u64| base.0 + cycles::to_nanoseconds(stamp.saturating_sub(base.1));
+    let mut out = String::new();
+    for span in closed.iter() {
+        out.push_str(&render(span, &to_ns));
+        out.push('\n');
+    }
+
+    let mut spans = spans().lock();
+    if let Some(ref mut file) = spans.file {
+        if let Err(err) = file.write_all(out.as_bytes()) {
+            warn!("Failed to write spans: {}", err);
+        }
+    }
+}
+
+/// Returns the name of an operation as it appears on spans and slow request records.
+pub fn op_name(opcode: u8) -> &'static str {
+    match opcode {
+        op if op == OpCode::SandstormGetRpc as u8 => "get",
+        op if op == OpCode::SandstormPutRpc as u8 => "put",
+        op if op == OpCode::SandstormInvokeRpc as u8 => "invoke",
+        op if op == OpCode::SandstormMultiGetRpc as u8 => "multiget",
+        op if op == OpCode::SandstormMultiOpRpc as u8 => "multiop",
+        _ => "unknown",
+    }
+}
+
+// Renders bytes as lower case hex.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Renders a u64 as the hex of it's big endian bytes, the way span identifiers appear on a trace
+// context.
+fn hex_id(id: u64) -> String {
+    format!("{:016x}", id)
+}
+
+// Renders a closed span as an OTLP/JSON export request holding only that span.
+fn render(span: &Span, to_ns: &Fn(u64) -> u64) -> String {
+    let mut events = Vec::with_capacity(span.events.len() + 2);
+    events.push(format!(
+        "{{\"timeUnixNano\":\"{}\",\"name\":\"dispatched\"}}",
+        to_ns(span.start)
+    ));
+    for &(name, stamp) in span.events.iter() {
+        events.push(format!(
+            "{{\"timeUnixNano\":\"{}\",\"name\":\"{}\"}}",
+            to_ns(stamp),
+            name
+        ));
+    }
+    if !span.dropped {
+        events.push(format!(
+            "{{\"timeUnixNano\":\"{}\",\"name\":\"sent\"}}",
+            to_ns(span.end)
+        ));
+    }
+
+    // A status code of 2 is an error, 0 is unset.
+    let status = if span.dropped {
+        "{\"code\":2,\"message\":\"dropped\"}"
+    } else {
+        "{\"code\":0}"
+    };
+
+    format!(
+        "{{\"resourceSpans\":[{{\"resource\":{{\"attributes\":[{{\"key\":\"service.name\",\
+         \"value\":{{\"stringValue\":\"splinter\"}}}}]}},\"scopeSpans\":[{{\"scope\":\
+         {{\"name\":\"splinter\"}},\"spans\":[{{\"traceId\":\"{}\",\"spanId\":\"{}\",\
+         \"parentSpanId\":\"{}\",\"name\":\"splinter.{}\",\"kind\":2,\
+         \"startTimeUnixNano\":\"{}\",\"endTimeUnixNano\":\"{}\",\"attributes\":[\
+         {{\"key\":\"splinter.tenant\",\"value\":{{\"intValue\":\"{}\"}}}},\
+         {{\"key\":\"splinter.stamp\",\"value\":{{\"intValue\":\"{}\"}}}}],\
+         \"events\":[{}],\"status\":{}}}]}}]}}]}}",
+        hex(&span.context.trace),
+        hex_id(span.id),
+        hex(&span.context.parent),
+        op_name(span.opcode),
+        to_ns(span.start),
+        to_ns(span.end),
+        span.tenant,
+        span.stamp,
+        events.join(","),
+        status
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Returns a traced get() request on table 1 from tenant 7, with a key of three bytes.
+    fn request() -> Vec<u8> {
+        let mut payload = vec![0x01 | REQUEST_FLAG_TRACED, OpCode::SandstormGetRpc as u8];
+        payload.extend_from_slice(&[7, 0, 0, 0]);
+        payload.extend_from_slice(&[42, 0, 0, 0, 0, 0, 0, 0]);
+        payload.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0, 3, 0, b'k', b'e', b'y']);
+        for i in 0..TRACE_CONTEXT_LEN {
+            payload.push(i as u8 + 1);
+        }
+        payload
+    }
+
+    // Tests that the trace context is read off the end of a flagged request.
+    #[test]
+    fn test_parse() {
+        let payload = request();
+        let context = parse(&payload).unwrap();
+        assert_eq!(1, context.trace[0]);
+        assert_eq!(16, context.trace[15]);
+        assert_eq!([17, 18, 19, 20, 21, 22, 23, 24], context.parent);
+    }
+
+    // Tests that requests without the flag, or too short to carry a context, carry none.
+    #[test]
+    fn test_parse_none() {
+        let mut payload = request();
+        payload[0] = 0x01;
+        assert_eq!(None, parse(&payload));
+
+        let mut payload = request();
+        payload.truncate(size_of::<RpcRequestHeader>() + TRACE_CONTEXT_LEN - 1);
+        assert_eq!(None, parse(&payload));
+    }
+
+    // Tests that a rendered span carries the ids on it's trace context, and every event in
+    // order.
+    #[test]
+    fn test_render() {
+        let span = Span {
+            context: parse(&request()).unwrap(),
+            id: 0xab,
+            tenant: 7,
+            stamp: 42,
+            opcode: OpCode::SandstormGetRpc as u8,
+            start: 100,
+            end: 400,
+            events: vec![("scheduled", 200), ("db.get", 300)],
+            dropped: false,
+        };
+
+        let out = render(&span, &|stamp| stamp * 10);
+        assert!(out.contains("\"traceId\":\"0102030405060708090a0b0c0d0e0f10\""));
+        assert!(out.contains("\"spanId\":\"00000000000000ab\""));
+        assert!(out.contains("\"parentSpanId\":\"1112131415161718\""));
+        assert!(out.contains("\"name\":\"splinter.get\""));
+        assert!(out.contains("\"startTimeUnixNano\":\"1000\",\"endTimeUnixNano\":\"4000\""));
+
+        let dispatched = out.find("\"dispatched\"").unwrap();
+        let scheduled = out.find("\"scheduled\"").unwrap();
+        let get = out.find("\"db.get\"").unwrap();
+        let sent = out.find("\"sent\"").unwrap();
+        assert!(dispatched < scheduled && scheduled < get && get < sent);
+        assert!(out.contains("\"status\":{\"code\":0}"));
+    }
+
+    // Tests that a dropped span is marked as an error, and has no send event.
+    #[test]
+    fn test_render_dropped() {
+        let span = Span {
+            context: parse(&request()).unwrap(),
+            id: 1,
+            tenant: 7,
+            stamp: 42,
+            opcode: 0xff,
+            start: 100,
+            end: 400,
+            events: Vec::new(),
+            dropped: true,
+        };
+
+        let out = render(&span, &|stamp| stamp);
+        assert!(out.contains("\"name\":\"splinter.unknown\""));
+        assert!(!out.contains("\"sent\""));
+        assert!(out.contains("\"code\":2"));
+    }
+}
@@ -14,10 +14,24 @@
  */
 
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
 
-use spin::{RwLock};
+use spin::{Mutex, RwLock};
 use bytes::{Bytes};
 
+use super::common::TenantId;
+
+/// Grants a tenant other than a table's owner native get()s and multiget()s on the table.
+pub const ACCESS_READ: u8 = 0x01;
+
+/// Grants a tenant other than a table's owner native put()s and deletes on the table.
+pub const ACCESS_WRITE: u8 = 0x02;
+
+/// Grants a tenant other than a table's owner's extensions access to the table through the DB
+/// trait, upto the reads and writes granted alongside.
+pub const ACCESS_INVOKE: u8 = 0x04;
+
 // The number of buckets in the hash table. Must be a power of two.
 // If you want to change this number, then you will also have to modify
 // the implementation of the Default trait below.
@@ -29,6 +43,122 @@ use bytes::{Bytes};
 //    128 buckets: 18.5 Million ops/s (read-only), 12.3 Million ops/s (50-50)
 const N_BUCKETS : usize = 128;
 
+// Returns the bucket a key falls into. The whole key is hashed (FNV-1a), so that keys sharing
+// a prefix, such as composite or URL-style keys, are spread over every bucket instead of piling
+// up in the one their first byte picks. Empty keys fall into the first bucket.
+#[inline]
+fn bucket(key: &[u8]) -> usize {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for byte in key.iter() {
+        h ^= *byte as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+
+    (h ^ (h >> 32)) as usize & (N_BUCKETS - 1)
+}
+
+// Returns the number of bytes charged for an object in a table: the region it was allocated at
+// the start of if it was written by `Table::put_in()`, or the object itself otherwise.
+#[inline]
+fn charged(object: &Bytes, room: Option<Room>) -> usize {
+    room.map_or(object.len(), |room| room.region.len())
+}
+
+// The number of slots a table's operation counters are spread over. Threads are handed slots
+// round robin, so that schedulers on different cores do not contend on the same cache line.
+const N_SLOTS: usize = 16;
+
+/// Counts of the operations performed on a table since it was created.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TableOps {
+    /// The number of lookups of a key, including those that missed.
+    pub gets: u64,
+
+    /// The number of lookups of a key that was not in the table.
+    pub misses: u64,
+
+    /// The number of objects written.
+    pub puts: u64,
+
+    /// The number of bytes in objects returned by lookups.
+    pub read_bytes: u64,
+
+    /// The number of bytes in objects written.
+    pub written_bytes: u64,
+}
+
+// A slot of operation counters, updated by the threads handed it. Aligned to a cache line so
+// that slots updated by different threads never share one.
+#[repr(align(64))]
+#[derive(Default)]
+struct Slot {
+    gets: AtomicUsize,
+    misses: AtomicUsize,
+    puts: AtomicUsize,
+    read_bytes: AtomicUsize,
+    written_bytes: AtomicUsize,
+}
+
+// The slot handed to the next thread that touches a table.
+static NEXT_SLOT: AtomicUsize = ATOMIC_USIZE_INIT;
+
+thread_local! {
+    // The slot of operation counters this thread updates on every table.
+    static SLOT: usize = NEXT_SLOT.fetch_add(1, Ordering::Relaxed) % N_SLOTS;
+}
+
+/// A limit on the number of bytes the objects in a set of tables can take up, shared by every
+/// table owned by a tenant. Objects count towards the quota of the table they are written to,
+/// regardless of which tenant wrote them.
+pub struct Quota {
+    // The number of bytes the objects can take up. Zero if there is no limit.
+    limit: usize,
+
+    // The number of bytes the objects currently take up.
+    used: AtomicUsize,
+}
+
+// Implementation of methods on Quota.
+impl Quota {
+    /// This function returns a quota with nothing charged to it.
+    ///
+    /// # Arguments
+    ///
+    /// * `limit`: The number of bytes objects can take up, zero for no limit.
+    pub fn new(limit: usize) -> Quota {
+        Quota {
+            limit: limit,
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    /// This function returns the number of bytes charged to the quota.
+    #[inline]
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// This function returns the number of bytes objects can take up, zero if there is no limit.
+    #[inline]
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// This function checks whether an object fits within the quota. Concurrent writers can
+    /// together overshoot the limit by upto one object each.
+    ///
+    /// # Arguments
+    ///
+    /// * `size`: The number of bytes the object takes up.
+    ///
+    /// # Return
+    ///
+    /// True if the object fits.
+    pub fn admits(&self, size: usize) -> bool {
+        self.limit == 0 || self.used() + size <= self.limit
+    }
+}
+
 /// This struct represents a single table in Sandstorm. A table is indexed using
 /// an unordered map, which hashes an object's key to it's value. Tables can be
 /// safely accessed concurrently from multiple threads.
@@ -49,6 +179,43 @@ pub struct Table {
     //        object, without worrying about concurrent updates. An object will
     //        be dropped only when this ref-count goes to zero.
     maps: [RwLock<HashMap<Bytes, Bytes>>; N_BUCKETS],
+
+    // The tenant that created the table, and is allowed to do anything with it.
+    owner: TenantId,
+
+    // The access granted to tenants other than the owner, as a combination of the ACCESS_*
+    // bits. Tenants not on the list have no access.
+    acl: RwLock<HashMap<TenantId, u8>>,
+
+    // Set if the owner published the table, granting every tenant read access to it.
+    published: AtomicBool,
+
+    // The number of bytes taken up by the objects in the table. Maintained on every put and
+    // delete, along with the quota, so that neither requires visiting every object.
+    stored: AtomicUsize,
+
+    // The memory quota the table's objects are charged to.
+    quota: Arc<Quota>,
+
+    // Counts of the operations on the table, spread over N_SLOTS slots. Summed up by `ops()`.
+    slots: Vec<Slot>,
+
+    // Serializes writes to keys, one lock per bucket. Taken by `exclusive()`.
+    cas: Vec<Mutex<()>>,
+
+    // The region every object written by `put_in()` was allocated at the start of, by key, one
+    // map per bucket. An entry is dropped as soon as it's object is overwritten or deleted.
+    rooms: Vec<Mutex<HashMap<Bytes, Room>>>,
+}
+
+// A region of memory an object was allocated at the start of, leaving room after it's value that
+// appends can be written into in place.
+struct Room {
+    // The whole region, charged to the table in place of the object.
+    region: Bytes,
+
+    // The number of bytes at the start of the region taken up by the object in the table.
+    used: usize,
 }
 
 // Implementation of the Default trait for Table.
@@ -122,13 +289,117 @@ impl Default for Table {
                    RwLock::new(HashMap::new()), RwLock::new(HashMap::new()),
                    RwLock::new(HashMap::new()), RwLock::new(HashMap::new()),
                    RwLock::new(HashMap::new()), RwLock::new(HashMap::new()),
-                ]
+                ],
+            owner: 0,
+            acl: RwLock::new(HashMap::new()),
+            published: AtomicBool::new(false),
+            stored: AtomicUsize::new(0),
+            quota: Arc::new(Quota::new(0)),
+            slots: (0..N_SLOTS).map(|_| Slot::default()).collect(),
+            cas: (0..N_BUCKETS).map(|_| Mutex::new(())).collect(),
+            rooms: (0..N_BUCKETS).map(|_| Mutex::new(HashMap::new())).collect(),
         }
     }
 }
 
 // Implementation of Table
 impl Table {
+    /// This function returns an empty table owned by a tenant.
+    ///
+    /// # Arguments
+    ///
+    /// * `owner`: The tenant creating the table.
+    /// * `quota`: The memory quota shared by the tenant's tables.
+    pub fn owned_by(owner: TenantId, quota: Arc<Quota>) -> Table {
+        let mut table = Table::default();
+        table.owner = owner;
+        table.quota = quota;
+        return table;
+    }
+
+    /// This function checks whether an object can be written to the table without going over
+    /// it's owner's memory quota.
+    ///
+    /// # Arguments
+    ///
+    /// * `size`: The number of bytes the object takes up, as returned by `get()`.
+    ///
+    /// # Return
+    ///
+    /// True if the object fits within the quota.
+    #[inline]
+    pub fn admits(&self, size: usize) -> bool {
+        self.quota.admits(size)
+    }
+
+    /// This function returns the tenant that owns the table.
+    #[inline]
+    pub fn owner(&self) -> TenantId {
+        self.owner
+    }
+
+    /// This function checks whether a tenant may access the table in some way.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant`: The tenant accessing the table.
+    /// * `access`: The ACCESS_* bits the access needs.
+    ///
+    /// # Return
+    ///
+    /// True if the tenant owns the table, or was granted every one of the bits, either directly
+    /// or by the table being published.
+    pub fn permits(&self, tenant: TenantId, access: u8) -> bool {
+        if tenant == self.owner {
+            return true;
+        }
+
+        let public = match self.published() {
+            true => ACCESS_READ | ACCESS_INVOKE,
+            false => 0,
+        };
+
+        let granted = self.acl.read().get(&tenant).cloned().unwrap_or(0) | public;
+        granted & access == access
+    }
+
+    /// This function publishes the table, granting every tenant read access to it through native
+    /// get()s and multiget()s and through extensions, or withdraws it. Only the owner can ever
+    /// write to a published table, unless a tenant was granted write access of it's own.
+    ///
+    /// # Arguments
+    ///
+    /// * `published`: True if the table should be published, false if it should be withdrawn.
+    pub fn publish(&self, published: bool) {
+        self.published.store(published, Ordering::Relaxed);
+    }
+
+    /// This function returns true if the table is published.
+    #[inline]
+    pub fn published(&self) -> bool {
+        self.published.load(Ordering::Relaxed)
+    }
+
+    /// This function replaces the access granted to a tenant other than the owner.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant`: The tenant being granted access.
+    /// * `access`: The ACCESS_* bits granted. Zero revokes all access.
+    pub fn grant(&self, tenant: TenantId, access: u8) {
+        let mut acl = self.acl.write();
+        if access == 0 {
+            acl.remove(&tenant);
+        } else {
+            acl.insert(tenant, access);
+        }
+    }
+
+    /// This function returns every tenant that was granted access to the table.
+    pub fn grantees(&self) -> Vec<TenantId> {
+        self.acl.read().keys().cloned().collect()
+    }
+
     /// This function reads an object from a table.
     ///
     /// # Arguments
@@ -143,11 +414,20 @@ impl Table {
     /// If the object does not exist in the Table, this method returns None.
     pub fn get(&self, key: &[u8]) -> Option<Bytes> {
         // First, identify the bucket the key falls into.
-        let bucket: usize = key[0] as usize & (N_BUCKETS - 1);
+        let bucket: usize = bucket(key);
         let map = self.maps[bucket].read();
 
-        // Perform the lookup, and return.
-        return map.get(key).and_then(| value | { Some(value.clone()) });
+        // Perform the lookup, count it, and return.
+        let object = map.get(key).and_then(| value | { Some(value.clone()) });
+
+        let slot = self.slot();
+        slot.gets.fetch_add(1, Ordering::Relaxed);
+        match object {
+            Some(ref object) => slot.read_bytes.fetch_add(object.len(), Ordering::Relaxed),
+            None => slot.misses.fetch_add(1, Ordering::Relaxed),
+        };
+
+        return object;
     }
 
     /// This function writes an object into a table.
@@ -158,19 +438,101 @@ impl Table {
     /// * `object`: A Bytes wrapping the entire object to be written to
     ///             the table.
     pub fn put(&self, key: Bytes, object: Bytes) {
+        self.insert(key, object, None)
+    }
+
+    /// This function writes an object allocated at the start of a larger region into a table, so
+    /// that bytes appended to it's value later can be written into the rest of the region in
+    /// place (see `room()`). The whole region is charged to the table and it's quota.
+    ///
+    /// # Arguments
+    ///
+    /// * `key`:    A Bytes wrapping the key for the object.
+    /// * `object`: A Bytes wrapping the entire object, a view of the start of `region`.
+    /// * `region`: The region the object was allocated at the start of.
+    pub fn put_in(&self, key: Bytes, object: Bytes, region: Bytes) {
+        self.insert(key, object, Some(region))
+    }
+
+    // Writes an object into the table, along with the region it was allocated at the start of
+    // if there is one, and charges the object or the region to the table and it's quota.
+    fn insert(&self, key: Bytes, object: Bytes, region: Option<Bytes>) {
         // First, identify the bucket the key falls into.
-        let bucket: usize = key.slice(0, 1)[0] as usize & (N_BUCKETS - 1);
+        let bucket: usize = bucket(&key);
         let mut map = self.maps[bucket].write();
+        let mut rooms = self.rooms[bucket].lock();
 
         // Next, remove the key from the hash map if it already exists.
         if map.contains_key(&key) {
-            let _val = map.remove(&key);
+            if let Some(val) = map.remove(&key) {
+                let room = rooms.remove(&key);
+                self.uncharge(charged(&val, room));
+            }
         }
 
+        let size = match region {
+            Some(region) => {
+                let size = region.len();
+                rooms.insert(key.clone(), Room { region: region, used: object.len() });
+                size
+            }
+            None => object.len(),
+        };
+
         // Perform the insert.
+        let slot = self.slot();
+        slot.puts.fetch_add(1, Ordering::Relaxed);
+        slot.written_bytes.fetch_add(object.len(), Ordering::Relaxed);
+        self.stored.fetch_add(size, Ordering::Relaxed);
+        self.quota.used.fetch_add(size, Ordering::Relaxed);
         let _obj = map.insert(key, object);
     }
 
+    /// This function returns the region an object was allocated at the start of by `put_in()`,
+    /// if the object is still the one under it's key and the rest of the region has room for
+    /// bytes appended to it's value. The key's lock must be held from the lookup of the object
+    /// until the extended object is written back (see `exclusive()`).
+    ///
+    /// # Arguments
+    ///
+    /// * `key`:    The key of the object.
+    /// * `object`: The entire object, as returned by `get()`.
+    /// * `len`:    The number of bytes to be appended to the object's value.
+    ///
+    /// # Return
+    ///
+    /// The region, if the bytes fit in the room left in it.
+    pub fn room(&self, key: &[u8], object: &Bytes, len: usize) -> Option<Bytes> {
+        let rooms = self.rooms[bucket(key)].lock();
+        rooms.get(key).and_then(|room| {
+            // Objects small enough to be copied out of the region by `Bytes` are not views of
+            // it, and are never extended in place.
+            let view = room.region.as_ptr() == object.as_ptr() && room.used == object.len();
+            match view && object.len() + len <= room.region.len() {
+                true => Some(room.region.clone()),
+                false => None,
+            }
+        })
+    }
+
+    /// This function runs a closure with a key's compare and swap lock held, so that it can read
+    /// the object under the key and write it back without another write to the key landing in
+    /// between. Puts and deletes from clients and extensions take the lock around their write.
+    ///
+    /// # Arguments
+    ///
+    /// * `key`: The key.
+    /// * `f`:   The closure. It must not take the lock of another key.
+    ///
+    /// # Return
+    ///
+    /// Whatever the closure returned.
+    pub fn exclusive<T, F: FnOnce() -> T>(&self, key: &[u8], f: F) -> T {
+        let bucket: usize = bucket(key);
+        let _lock = self.cas[bucket].lock();
+        f()
+    }
+
     /// This function deletes an object from a table.
     ///
     /// # Arguments
@@ -178,12 +540,88 @@ impl Table {
     /// * `key`: The key of the object to be deleted, passed in as a slice of bytes.
     pub fn delete(&self, key: &[u8]) {
         // First, identify the bucket the key falls into.
-        let bucket: usize = key[0] as usize & (N_BUCKETS - 1);
+        let bucket: usize = bucket(key);
         let mut map = self.maps[bucket].write();
 
         // Next, remove the key from the hash map if it already exists.
         if map.contains_key(key) {
-            let _val = map.remove(key);
+            if let Some(val) = map.remove(key) {
+                let room = self.rooms[bucket].lock().remove(key);
+                self.uncharge(charged(&val, room));
+            }
+        }
+    }
+
+    // Returns the slot of operation counters the calling thread updates.
+    #[inline]
+    fn slot(&self) -> &Slot {
+        &self.slots[SLOT.with(|slot| *slot)]
+    }
+
+    /// This function counts the operations performed on a table since it was created, summing
+    /// up the counts kept by every thread. Cheap enough to call from a stats request.
+    ///
+    /// # Return
+    ///
+    /// The counts of operations on the table.
+    pub fn ops(&self) -> TableOps {
+        let mut ops = TableOps::default();
+        for slot in self.slots.iter() {
+            ops.gets += slot.gets.load(Ordering::Relaxed) as u64;
+            ops.misses += slot.misses.load(Ordering::Relaxed) as u64;
+            ops.puts += slot.puts.load(Ordering::Relaxed) as u64;
+            ops.read_bytes += slot.read_bytes.load(Ordering::Relaxed) as u64;
+            ops.written_bytes += slot.written_bytes.load(Ordering::Relaxed) as u64;
+        }
+
+        ops
+    }
+
+    // Takes an object that was removed from the table off the table's and quota's counts.
+    fn uncharge(&self, size: usize) {
+        self.stored.fetch_sub(size, Ordering::Relaxed);
+        self.quota.used.fetch_sub(size, Ordering::Relaxed);
+    }
+
+    /// This function counts the objects in a table. Every bucket is locked in turn, so the count
+    /// is not a consistent snapshot if the table is being written to.
+    ///
+    /// # Return
+    ///
+    /// The number of objects in the table.
+    pub fn len(&self) -> usize {
+        self.maps.iter().map(|map| map.read().len()).sum()
+    }
+
+    /// This function returns the number of bytes taken up by the objects in a table, as
+    /// returned by `get()`, along with the room left after objects written by `put_in()`.
+    ///
+    /// # Return
+    ///
+    /// The number of bytes taken up by the table's objects.
+    #[inline]
+    pub fn bytes(&self) -> usize {
+        self.stored.load(Ordering::Relaxed)
+    }
+
+    /// This function returns the number of objects the table's buckets can hold without
+    /// growing, which is what the memory taken up by the table's index is proportional to.
+    pub fn capacity(&self) -> usize {
+        self.maps.iter().map(|map| map.read().capacity()).sum()
+    }
+
+    /// This function calls a closure on every object in a table. Every bucket is read locked in
+    /// turn while the closure is called on it's objects, so the closure must not write to the
+    /// table.
+    ///
+    /// # Arguments
+    ///
+    /// * `f`: The closure, called with the entire object.
+    pub fn visit(&self, f: &mut FnMut(&Bytes)) {
+        for map in self.maps.iter() {
+            for object in map.read().values() {
+                f(object);
+            }
         }
     }
 }
@@ -193,7 +631,9 @@ impl Table {
 // test basic functionality like reference counting etc.
 #[cfg(test)]
 mod tests {
-    use super::Table;
+    use super::{bucket, Quota, Table, ACCESS_INVOKE, ACCESS_READ, ACCESS_WRITE, N_BUCKETS};
+    use std::sync::Arc;
+    use std::thread;
     use bytes::{BufMut, Bytes, BytesMut};
 
     // This unit test inserts a key-value pair into a table, performs a read
@@ -241,6 +681,30 @@ mod tests {
         assert_eq!(None, table.get(&[0; 30]));
     }
 
+    // This test verifies that len() and bytes() count objects across
+    // buckets, and that deleted objects are no longer counted.
+    #[test]
+    fn test_len() {
+        let table = Table::default();
+        assert_eq!(0, table.len());
+
+        for i in 0..4u8 {
+            let mut obj: BytesMut = BytesMut::with_capacity(60);
+            obj.put_slice(&[i; 30]);
+            obj.put_slice(&[1; 30]);
+            let mut obj: Bytes = obj.freeze();
+
+            let key: Bytes = obj.split_to(30);
+            table.put(key, obj);
+        }
+        assert_eq!(4, table.len());
+        assert_eq!(120, table.bytes());
+
+        table.delete(&[2; 30]);
+        assert_eq!(3, table.len());
+        assert_eq!(90, table.bytes());
+    }
+
     // This test populates a table with one object and performs a read on
     // the object. It then performs an update on this object and checks
     // if the previously read value is still accessible.
@@ -312,4 +776,208 @@ mod tests {
         // Assert that the key was deleted.
         assert_eq!(None, table.get(key));
     }
+
+    // This unit test verifies that read-modify-writes on a key made with it's compare and swap
+    // lock held do not lose updates to each other.
+    #[test]
+    fn test_exclusive() {
+        let table = Arc::new(Table::default());
+        let key: &'static [u8] = &[3; 30];
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let table = Arc::clone(&table);
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        table.exclusive(key, || {
+                            let count = table.get(key).map_or(0, |val| {
+                                val.iter().rev().fold(0u32, |acc, &b| (acc << 8) | b as u32)
+                            }) + 1;
+
+                            let mut obj = BytesMut::with_capacity(34);
+                            obj.put_slice(key);
+                            obj.put_u32_le(count);
+                            let mut obj = obj.freeze();
+                            let key_ref = obj.split_to(30);
+                            table.put(key_ref, obj);
+                        });
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().expect("Failed to join thread.");
+        }
+
+        assert_eq!(Some(Bytes::from(vec![0xa0, 0x0f, 0, 0])), table.get(key));
+    }
+
+    // This unit test verifies that only the owner and tenants granted every requested bit can
+    // access a table, and that grants can be revoked.
+    #[test]
+    fn test_permits() {
+        let table = Table::owned_by(1, Arc::new(Quota::new(0)));
+        assert!(table.permits(1, ACCESS_READ | ACCESS_WRITE | ACCESS_INVOKE));
+        assert!(!table.permits(2, ACCESS_READ));
+
+        table.grant(2, ACCESS_READ | ACCESS_INVOKE);
+        assert!(table.permits(2, ACCESS_READ));
+        assert!(table.permits(2, ACCESS_READ | ACCESS_INVOKE));
+        assert!(!table.permits(2, ACCESS_WRITE));
+        assert!(!table.permits(3, ACCESS_READ));
+        assert_eq!(vec![2], table.grantees());
+
+        table.grant(2, 0);
+        assert!(!table.permits(2, ACCESS_READ));
+        assert_eq!(0, table.grantees().len());
+    }
+
+    // This unit test verifies that a published table can be read, but not written, by every
+    // tenant, and that withdrawing it leaves only the grants.
+    #[test]
+    fn test_publish() {
+        let table = Table::owned_by(1, Arc::new(Quota::new(0)));
+        table.grant(2, ACCESS_WRITE);
+        table.publish(true);
+        assert!(table.published());
+        assert!(table.permits(3, ACCESS_READ | ACCESS_INVOKE));
+        assert!(!table.permits(3, ACCESS_WRITE));
+        assert!(table.permits(2, ACCESS_READ | ACCESS_WRITE));
+
+        table.publish(false);
+        assert!(!table.permits(3, ACCESS_READ));
+        assert!(table.permits(2, ACCESS_WRITE));
+        assert!(!table.permits(2, ACCESS_READ));
+    }
+
+    // This unit test verifies that tables sharing a quota are limited together, and that
+    // overwritten and deleted objects are no longer charged.
+    #[test]
+    fn test_quota() {
+        let quota = Arc::new(Quota::new(100));
+        let a = Table::owned_by(1, Arc::clone(&quota));
+        let b = Table::owned_by(1, Arc::clone(&quota));
+
+        let object = |key: u8, len: usize| -> (Bytes, Bytes) {
+            let mut obj: BytesMut = BytesMut::with_capacity(len);
+            obj.put_slice(&vec![key; len]);
+            let obj: Bytes = obj.freeze();
+            (obj.slice(0, 1), obj)
+        };
+
+        let (k, o) = object(1, 60);
+        a.put(k, o);
+        assert!(b.admits(40));
+        assert!(!b.admits(41));
+
+        let (k, o) = object(1, 30);
+        a.put(k, o);
+        assert_eq!(30, quota.used());
+
+        let (k, o) = object(2, 70);
+        b.put(k, o);
+        assert!(!a.admits(1));
+
+        b.delete(&[2]);
+        assert_eq!(30, quota.used());
+        assert!(Quota::new(0).admits(1 << 40));
+    }
+
+    // This unit test verifies that an object written along with a region is charged the whole
+    // region, that only the object under the key is offered the room left in it, and that the
+    // region stops being charged once the object is overwritten or deleted.
+    #[test]
+    fn test_room() {
+        let quota = Arc::new(Quota::new(0));
+        let table = Table::owned_by(1, Arc::clone(&quota));
+
+        let mut region: BytesMut = BytesMut::with_capacity(100);
+        region.put_slice(&[5; 40]);
+        region.resize(100, 0);
+        let region: Bytes = region.freeze();
+        let object = region.slice_to(40);
+        table.put_in(object.slice(0, 1), object.clone(), region.clone());
+        assert_eq!(100, table.bytes());
+        assert_eq!(100, quota.used());
+        assert_eq!(Some(40), table.get(&[5]).map(|obj| obj.len()));
+
+        assert_eq!(Some(region.clone()), table.room(&[5], &object, 60));
+        assert_eq!(None, table.room(&[5], &object, 61));
+        assert_eq!(None, table.room(&[5], &region.slice_to(39), 1));
+        assert_eq!(None, table.room(&[6], &object, 1));
+
+        let longer = region.slice_to(70);
+        table.put_in(longer.slice(0, 1), longer.clone(), region.clone());
+        assert_eq!(100, quota.used());
+        assert_eq!(None, table.room(&[5], &object, 1));
+        assert_eq!(Some(region.clone()), table.room(&[5], &longer, 30));
+
+        let (k, o) = (longer.slice(0, 1), Bytes::from(vec![5; 50]));
+        table.put(k, o.clone());
+        assert_eq!(50, quota.used());
+        assert_eq!(None, table.room(&[5], &o, 1));
+
+        table.put_in(object.slice(0, 1), object.clone(), region.clone());
+        table.delete(&[5]);
+        assert_eq!(0, table.bytes());
+        assert_eq!(0, quota.used());
+        assert_eq!(None, table.room(&[5], &object, 1));
+    }
+
+    // This unit test verifies that keys sharing a long prefix are spread over the buckets, and
+    // that long and empty keys can be written and read back.
+    #[test]
+    fn test_long_keys() {
+        let mut used = [false; N_BUCKETS];
+        for i in 0..1024 {
+            let key = format!("https://example.com/tenants/7/users/{}/profile", i);
+            used[bucket(key.as_bytes())] = true;
+        }
+        assert!(used.iter().filter(|&&used| used).count() > N_BUCKETS * 3 / 4);
+
+        let table = Table::default();
+        for len in [0, 1, 300, 4096].iter() {
+            let key = vec![7u8; *len];
+            let mut obj: BytesMut = BytesMut::with_capacity(len + 10);
+            obj.put_slice(&key);
+            obj.put_slice(&[1; 10]);
+            let mut obj: Bytes = obj.freeze();
+
+            let key_ref: Bytes = obj.split_to(*len);
+            table.put(key_ref, obj);
+            assert_eq!(Some(10), table.get(&key).map(|obj| obj.len()));
+        }
+        assert_eq!(4, table.len());
+
+        table.delete(&[]);
+        assert_eq!(None, table.get(&[]));
+    }
+
+    // This unit test checks that lookups, misses and writes are counted, along with the bytes
+    // they move, including those made from other threads.
+    #[test]
+    fn test_ops() {
+        let table = Arc::new(Table::default());
+
+        let mut obj: BytesMut = BytesMut::with_capacity(10);
+        obj.put_slice(&[3; 10]);
+        let obj: Bytes = obj.freeze();
+        table.put(obj.slice(0, 1), obj);
+
+        assert!(table.get(&[3]).is_some());
+        assert!(table.get(&[4]).is_none());
+
+        let other = Arc::clone(&table);
+        thread::spawn(move || {
+            assert!(other.get(&[3]).is_some());
+        }).join()
+            .unwrap();
+
+        let ops = table.ops();
+        assert_eq!(3, ops.gets);
+        assert_eq!(1, ops.misses);
+        assert_eq!(1, ops.puts);
+        assert_eq!(20, ops.read_bytes);
+        assert_eq!(10, ops.written_bytes);
+    }
 }
@@ -0,0 +1,401 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+// A packet capture tap for debugging the protocol between clients and servers. When turned on,
+// dispatchers copy a sampled fraction of the frames they receive and transmit into a shared
+// queue. A single thread periodically drains this queue into a pcapng file, annotating every
+// frame with the tenant and request identifier on it's RPC header. Alternatively, the queue can
+// hold the most recent frames in memory, and be written out only when the tap is turned off.
+
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+use std::sync::{Once, ONCE_INIT};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use e2d2::native::zcsi::MBuf;
+
+use spin::Mutex;
+
+/// The maximum number of bytes captured from every frame.
+const SNAP_LEN: usize = 256;
+
+/// The maximum number of frames queued up between two calls to `poll()` when streaming.
+const STREAM_DEPTH: usize = 65536;
+
+/// The direction a captured frame was travelling in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    Rx,
+    Tx,
+}
+
+// A frame copied out by the tap, along with it's annotations.
+struct Record {
+    dir: Direction,
+    micros: u64,
+    orig_len: usize,
+    frame: Vec<u8>,
+}
+
+// Where captured frames end up.
+struct Sink {
+    // Frames captured but not written out yet.
+    records: VecDeque<Record>,
+
+    // The maximum number of frames held in `records`. The oldest frame is dropped when full.
+    depth: usize,
+
+    // True if frames are held in memory until the tap is turned off, instead of being streamed.
+    ring: bool,
+
+    // The path of the capture file, and the file itself if it has been opened.
+    path: String,
+    file: Option<BufWriter<File>>,
+}
+
+/// Set when frames are being captured.
+static ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Set when the tap should be turned on or off on the next call to `poll()`.
+static TOGGLE: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// One in every these many frames is captured on every thread.
+static RATE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Initializes SINK exactly once.
+static SINK_INIT: Once = ONCE_INIT;
+
+/// The sink shared by all dispatchers. Use `sink()` to access it.
+static mut SINK: *const Mutex<Sink> = 0 as *const Mutex<Sink>;
+
+thread_local! {
+    // The number of frames seen by this thread since the last one it captured.
+    static SEEN: Cell<usize> = Cell::new(0);
+}
+
+// Returns the sink shared by all dispatchers, allocating it on first use.
+fn sink() -> &'static Mutex<Sink> {
+    unsafe {
+        SINK_INIT.call_once(|| {
+            let sink = Sink {
+                records: VecDeque::new(),
+                depth: STREAM_DEPTH,
+                ring: false,
+                path: String::new(),
+                file: None,
+            };
+            SINK = Box::into_raw(Box::new(Mutex::new(sink)));
+        });
+
+        &*SINK
+    }
+}
+
+/// Configures the tap. The tap starts out turned off.
+///
+/// # Arguments
+///
+/// * `rate`: One in every `rate` frames is captured on every dispatcher.
+/// * `path`: The pcapng file captured frames are written to.
+/// * `ring`: If non-zero, the number of most recent frames held in memory while the tap is on.
+///           These are written out when the tap is turned off. If zero, frames are streamed to
+///           the file as they are captured.
+pub fn configure(rate: usize, path: &str, ring: usize) {
+    let mut sink = sink().lock();
+    sink.records = VecDeque::new();
+    sink.depth = if ring > 0 { ring } else { STREAM_DEPTH };
+    sink.ring = ring > 0;
+    sink.path = String::from(path);
+    sink.file = None;
+
+    RATE.store(if rate > 0 { rate } else { 1 }, Ordering::Relaxed);
+}
+
+/// Requests that the tap be turned on if it is off and vice-versa. Safe to call from a signal
+/// handler; the request takes effect on the next call to `poll()`.
+pub fn toggle() {
+    TOGGLE.store(true, Ordering::Relaxed);
+}
+
+/// Returns true if the tap is capturing frames.
+#[inline]
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Possibly captures a frame, if the tap is turned on.
+///
+/// # Arguments
+///
+/// * `dir`:  The direction the frame is travelling in.
+/// * `mbuf`: The MBuf holding the frame. Only it's first segment is captured.
+#[inline]
+pub fn capture(dir: Direction, mbuf: *mut MBuf) {
+    if !enabled() {
+        return;
+    }
+
+    // Sample one in every RATE frames.
+    let rate = RATE.load(Ordering::Relaxed);
+    let sample = SEEN.with(|seen| {
+        let n = seen.get() + 1;
+        if n >= rate {
+            seen.set(0);
+            true
+        } else {
+            seen.set(n);
+            false
+        }
+    });
+    if !sample {
+        return;
+    }
+
+    let micros = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() * 1000000 + d.subsec_nanos() as u64 / 1000)
+        .unwrap_or(0);
+
+    let record = unsafe {
+        let len = ::std::cmp::min((*mbuf).data_len(), SNAP_LEN);
+        Record {
+            dir: dir,
+            micros: micros,
+            orig_len: (*mbuf).pkt_len(),
+            frame: slice::from_raw_parts((*mbuf).data_address(0), len).to_vec(),
+        }
+    };
+
+    let mut sink = sink().lock();
+    if sink.records.len() >= sink.depth {
+        sink.records.pop_front();
+    }
+    sink.records.push_back(record);
+}
+
+/// Services toggle requests, and writes out captured frames. Should be called periodically on
+/// a thread that is not processing requests, because it performs file I/O.
+pub fn poll() {
+    if TOGGLE.swap(false, Ordering::Relaxed) {
+        let on = !enabled();
+        ENABLED.store(on, Ordering::Relaxed);
+        info!("Packet capture turned {}.", if on { "on" } else { "off" });
+
+        // Write out whatever is left over, including frames held in memory, and close the file.
+        // The next capture starts a new file.
+        if !on {
+            drain();
+            sink().lock().file = None;
+        }
+
+        return;
+    }
+
+    if enabled() && !sink().lock().ring {
+        drain();
+    }
+}
+
+// Writes all queued frames to the capture file, opening it if required.
+fn drain() {
+    // Swap out the queue so that dispatchers are not held up by the writes below.
+    let (records, path, open) = {
+        let mut sink = sink().lock();
+        let records = ::std::mem::replace(&mut sink.records, VecDeque::new());
+        (records, sink.path.clone(), sink.file.is_some())
+    };
+
+    if records.is_empty() {
+        return;
+    }
+
+    let mut file = if open {
+        sink().lock().file.take().unwrap()
+    } else {
+        match File::create(&path).and_then(|f| {
+            let mut f = BufWriter::new(f);
+            write_header(&mut f).map(|_| f)
+        }) {
+            Ok(f) => f,
+            Err(err) => {
+                warn!("Failed to open capture file {}: {}", path, err);
+                return;
+            }
+        }
+    };
+
+    for record in records.iter() {
+        if let Err(err) = write_record(&mut file, record).and_then(|_| file.flush()) {
+            warn!("Failed to write to capture file {}: {}", path, err);
+            return;
+        }
+    }
+
+    sink().lock().file = Some(file);
+}
+
+// Appends a little-endian u16 to a buffer.
+fn put_u16(buf: &mut Vec<u8>, v: u16) {
+    buf.push(v as u8);
+    buf.push((v >> 8) as u8);
+}
+
+// Appends a little-endian u32 to a buffer.
+fn put_u32(buf: &mut Vec<u8>, v: u32) {
+    put_u16(buf, v as u16);
+    put_u16(buf, (v >> 16) as u16);
+}
+
+// Pads a buffer with zeros to a multiple of four bytes, as pcapng requires.
+fn pad(buf: &mut Vec<u8>) {
+    while buf.len() % 4 != 0 {
+        buf.push(0);
+    }
+}
+
+// Writes a pcapng section header block followed by an ethernet interface description block.
+fn write_header<W: Write>(w: &mut W) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(48);
+
+    put_u32(&mut buf, 0x0A0D0D0A);
+    put_u32(&mut buf, 28);
+    put_u32(&mut buf, 0x1A2B3C4D);
+    put_u16(&mut buf, 1);
+    put_u16(&mut buf, 0);
+    put_u32(&mut buf, 0xffffffff);
+    put_u32(&mut buf, 0xffffffff);
+    put_u32(&mut buf, 28);
+
+    // Timestamps default to microseconds.
+    put_u32(&mut buf, 1);
+    put_u32(&mut buf, 20);
+    put_u16(&mut buf, 1);
+    put_u16(&mut buf, 0);
+    put_u32(&mut buf, SNAP_LEN as u32);
+    put_u32(&mut buf, 20);
+
+    w.write_all(&buf)
+}
+
+/// Extracts the tenant and request identifier from a frame carrying an RPC request or response.
+/// Both headers place the tenant and stamp at the same offsets after two single byte fields.
+///
+/// # Arguments
+///
+/// * `frame`: The frame, starting at it's ethernet header.
+///
+/// # Return
+///
+/// The tenant and request identifier, or None if the frame was too short.
+fn annotate(frame: &[u8]) -> Option<(u32, u64)> {
+    // Ethernet header, an IPv4 header of variable length, and then the UDP header.
+    let ihl = (*frame.get(14)? & 0x0f) as usize * 4;
+    let rpc = 14 + ihl + 8;
+    if frame.len() < rpc + 14 {
+        return None;
+    }
+
+    unsafe {
+        let tenant = ptr::read_unaligned(frame[rpc + 2..].as_ptr() as *const u32);
+        let stamp = ptr::read_unaligned(frame[rpc + 6..].as_ptr() as *const u64);
+        Some((tenant, stamp))
+    }
+}
+
+// Writes a pcapng enhanced packet block for a frame, with it's direction set in the flags and
+// it's annotations in a comment.
+fn write_record<W: Write>(w: &mut W, record: &Record) -> io::Result<()> {
+    let comment = match annotate(&record.frame) {
+        Some((tenant, stamp)) => format!("tenant={} stamp={}", tenant, stamp),
+        None => String::from("malformed"),
+    };
+
+    let mut buf = Vec::with_capacity(64 + record.frame.len() + comment.len());
+    put_u32(&mut buf, 6);
+    put_u32(&mut buf, 0); // Block length, filled in below.
+    put_u32(&mut buf, 0);
+    put_u32(&mut buf, (record.micros >> 32) as u32);
+    put_u32(&mut buf, record.micros as u32);
+    put_u32(&mut buf, record.frame.len() as u32);
+    put_u32(&mut buf, record.orig_len as u32);
+    buf.extend_from_slice(&record.frame);
+    pad(&mut buf);
+
+    // opt_comment.
+    put_u16(&mut buf, 1);
+    put_u16(&mut buf, comment.len() as u16);
+    buf.extend_from_slice(comment.as_bytes());
+    pad(&mut buf);
+
+    // epb_flags, with the inbound/outbound bits set.
+    put_u16(&mut buf, 2);
+    put_u16(&mut buf, 4);
+    put_u32(&mut buf, if record.dir == Direction::Rx { 1 } else { 2 });
+
+    // opt_endofopt.
+    put_u32(&mut buf, 0);
+
+    let len = buf.len() as u32 + 4;
+    put_u32(&mut buf, len);
+    buf[4..8].copy_from_slice(&[len as u8, (len >> 8) as u8, (len >> 16) as u8, (len >> 24) as u8]);
+
+    w.write_all(&buf)
+}
+
+// This module contains unit tests for the pcapng encoder.
+#[cfg(test)]
+mod tests {
+    use super::{annotate, write_record, Direction, Record};
+
+    // Returns a frame with a 20 byte IPv4 header carrying an RPC header for tenant 7, stamp 9.
+    fn frame() -> Vec<u8> {
+        let mut frame = vec![0u8; 14 + 20 + 8 + 14];
+        frame[14] = 0x45;
+        frame[44] = 7;
+        frame[48] = 9;
+        frame
+    }
+
+    // This unit test verifies that the tenant and stamp are extracted from an RPC header.
+    #[test]
+    fn test_annotate() {
+        assert_eq!(Some((7, 9)), annotate(&frame()));
+        assert_eq!(None, annotate(&frame()[..40]));
+    }
+
+    // This unit test verifies that records are padded and carry their length at both ends.
+    #[test]
+    fn test_write_record() {
+        let record = Record {
+            dir: Direction::Tx,
+            micros: 0,
+            orig_len: 57,
+            frame: frame(),
+        };
+
+        let mut buf = Vec::new();
+        write_record(&mut buf, &record).unwrap();
+
+        let len = buf.len();
+        assert_eq!(0, len % 4);
+        assert_eq!(&buf[4..8], &buf[len - 4..]);
+        assert_eq!(len as u8, buf[4]);
+    }
+}
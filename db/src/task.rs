@@ -13,13 +13,40 @@
  * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
  */
 
+use std::cell::Cell;
+
+use super::common::TenantId;
+
 use e2d2::interface::Packet;
 use e2d2::headers::UdpHeader;
 use e2d2::common::EmptyMetadata;
 
+thread_local! {
+    // The priority the request whose task is being created on this thread asked for within it's
+    // tenant's share of the server.
+    static LEVEL: Cell<u8> = Cell::new(0);
+}
+
+/// Sets the priority the request whose task is about to be created on this thread asked for.
+/// Tasks pick this up when created, much like a span or deadline.
+///
+/// # Arguments
+///
+/// * `level`: The priority off the request header, zero if it carried none.
+#[inline]
+pub fn enter_level(level: u8) {
+    LEVEL.with(|current| current.set(level));
+}
+
+/// Returns the priority the request whose task is being created on this thread asked for.
+#[inline]
+pub fn current_level() -> u8 {
+    LEVEL.with(|current| current.get())
+}
+
 /// This enum represents the different states a task can be in.
 #[repr(u8)]
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum TaskState {
     /// A task is in this state when it has just been created, but has not
     /// had a chance to execute on the CPU yet.
@@ -38,6 +65,19 @@ pub enum TaskState {
     COMPLETED = 0x04,
 }
 
+// Implementation of methods on TaskState.
+impl TaskState {
+    /// Returns the name of the state, in lower case.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            TaskState::INITIALIZED => "initialized",
+            TaskState::RUNNING => "running",
+            TaskState::YIELDED => "yielded",
+            TaskState::COMPLETED => "completed",
+        }
+    }
+}
+
 /// This enum represents the priority of a task in the system. A smaller value
 /// indicates a task with a higher priority.
 #[repr(u8)]
@@ -85,6 +125,77 @@ pub trait Task {
     /// The priority of the task.
     fn priority(&self) -> TaskPriority;
 
+    /// When called, this method should return the tenant the task is running on behalf of.
+    ///
+    /// # Return
+    ///
+    /// The identifier of the tenant, or None if the task does not belong to any tenant (ex: the
+    /// Dispatch task).
+    fn tenant(&self) -> Option<TenantId>;
+
+    /// When called, this method should return the name of what the task runs.
+    ///
+    /// # Return
+    ///
+    /// The name of the extension the task is running, or a short description of the work for
+    /// other tasks (ex: "native", "dispatch").
+    fn name(&self) -> &str;
+
+    /// When called, this method should return when the task was created.
+    ///
+    /// # Return
+    ///
+    /// The time-stamp in cycles at which the task was created, or zero if it is not known.
+    fn created(&self) -> u64;
+
+    /// When called, this method should return when the task first got to run.
+    ///
+    /// # Return
+    ///
+    /// The time-stamp in cycles at which the task first ran, or zero if it has not run yet or
+    /// it is not known.
+    fn scheduled(&self) -> u64;
+
+    /// When called, this method should return the part of the time the task ran for that was
+    /// spent in calls into the database.
+    ///
+    /// # Return
+    ///
+    /// The time in cycles spent in calls into the database.
+    fn db_time(&self) -> u64;
+
+    /// When called, this method should return the span of the request the task is servicing.
+    ///
+    /// # Return
+    ///
+    /// The identifier of the span, or zero if the request is not being traced.
+    fn span(&self) -> u64;
+
+    /// When called, this method should return the deadline of the request the task is servicing.
+    ///
+    /// # Return
+    ///
+    /// The time-stamp in cycles by which the request must be answered, or zero if it carried no
+    /// deadline.
+    fn deadline(&self) -> u64;
+
+    /// When called, this method should return the priority the request the task is servicing
+    /// asked for within it's tenant's share of the server.
+    ///
+    /// # Return
+    ///
+    /// The priority, from zero upto MAX_REQUEST_PRIORITY. Of the tasks a tenant has waiting to
+    /// run, those at a higher priority are run first.
+    fn level(&self) -> u8;
+
+    /// When called, this method should complete a task that has not run yet without running it,
+    /// because it's deadline passed. The response, if any, must say so.
+    ///
+    /// # Return
+    ///
+    /// True if the task was completed. False if it cannot be, and has to be run.
+    fn expire(&mut self) -> bool;
+
     /// When called, this method should return any packets or buffers that were passed in during
     /// creation. This method shoulf be called when a task has completed or aborted.
     ///
@@ -14,10 +14,12 @@
  */
 
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::collections::HashMap;
 
-use super::table::Table;
+use super::table::{Quota, Table};
 use super::common::{TableId, TenantId};
+use super::crypt::Key;
 
 use spin::RwLock;
 
@@ -31,6 +33,32 @@ pub struct Tenant {
     /// A map of all the data tables belonging to a tenant. Each data table
     /// has a unique identifier.
     tables: RwLock<HashMap<TableId, Arc<Table>>>,
+
+    /// The credential administrative RPCs on the tenant must carry. Zero if the tenant was
+    /// created at startup, in which case no credential is accepted, and only the operator's can
+    /// act on the tenant.
+    credential: u64,
+
+    /// The number of bytes an invocation of an extension by this tenant can allocate on the
+    /// table heap. Zero if the server's default applies.
+    alloc_quota: usize,
+
+    /// The memory quota shared by every table owned by this tenant.
+    quota: Arc<Quota>,
+
+    /// Set while the tenant is suspended. Requests from a suspended tenant are not executed.
+    suspended: AtomicBool,
+
+    /// Set while the tenant is suspended by the operator, which only the operator can undo.
+    held: AtomicBool,
+
+    /// Tables owned by other tenants that were shared with this one, keyed by the identifier
+    /// they are looked up under. Access to them is governed by the owner's grants.
+    mounts: RwLock<HashMap<TableId, Arc<Table>>>,
+
+    /// The key the tenant's values are encrypted with when written out of memory, if the
+    /// tenant was handed one.
+    key: RwLock<Option<Arc<Key>>>,
 }
 
 // Implementation of methods on tenant.
@@ -50,9 +78,43 @@ impl Tenant {
         Tenant {
             id: id,
             tables: RwLock::new(HashMap::new()),
+            credential: 0,
+            alloc_quota: 0,
+            quota: Arc::new(Quota::new(0)),
+            suspended: AtomicBool::new(false),
+            held: AtomicBool::new(false),
+            mounts: RwLock::new(HashMap::new()),
+            key: RwLock::new(None),
         }
     }
 
+    /// This method returns a new tenant created at runtime, that administrative RPCs must
+    /// authenticate to with a credential. The caller must ensure that the passed in identifier
+    /// is unique.
+    ///
+    /// # Arguments
+    ///
+    /// * `id`:          A unique identifier for the new tenant.
+    /// * `credential`:  The credential administrative RPCs on the tenant must carry.
+    /// * `alloc_quota`: The number of bytes an invocation can allocate, zero for the default.
+    /// * `mem_limit`:   The number of bytes the tenant's objects can take up, zero for no limit.
+    ///
+    /// # Return
+    ///
+    /// A `Tenant` representing a tenant in the system.
+    pub fn with_quota(
+        id: TenantId,
+        credential: u64,
+        alloc_quota: usize,
+        mem_limit: usize,
+    ) -> Tenant {
+        let mut tenant = Tenant::new(id);
+        tenant.credential = credential;
+        tenant.alloc_quota = alloc_quota;
+        tenant.quota = Arc::new(Quota::new(mem_limit));
+        return tenant;
+    }
+
     /// This method returns the identifier for the tenant.
     ///
     /// # Return
@@ -63,6 +125,81 @@ impl Tenant {
         self.id.clone()
     }
 
+    /// This method checks a credential presented by an administrative RPC on the tenant.
+    ///
+    /// # Arguments
+    ///
+    /// * `credential`: The credential on the RPC.
+    ///
+    /// # Return
+    ///
+    /// True if the RPC may operate on the tenant.
+    pub fn authenticate(&self, credential: u64) -> bool {
+        self.credential != 0 && self.credential == credential
+    }
+
+    /// This method returns the memory quota shared by every table owned by the tenant.
+    #[inline]
+    pub fn quota(&self) -> Arc<Quota> {
+        Arc::clone(&self.quota)
+    }
+
+    /// This method returns the number of bytes an invocation by the tenant can allocate on the
+    /// table heap, or zero if the server's default applies.
+    #[inline]
+    pub fn alloc_quota(&self) -> usize {
+        self.alloc_quota
+    }
+
+    /// This method returns true if the tenant is suspended, by itself or by the operator.
+    #[inline]
+    pub fn suspended(&self) -> bool {
+        self.suspended.load(Ordering::Relaxed) || self.held.load(Ordering::Relaxed)
+    }
+
+    /// This method suspends or resumes the tenant. Requests already being executed run to
+    /// completion.
+    ///
+    /// # Arguments
+    ///
+    /// * `suspended`: True if the tenant should be suspended, false if it should be resumed.
+    pub fn set_suspended(&self, suspended: bool) {
+        self.suspended.store(suspended, Ordering::Relaxed);
+    }
+
+    /// This method returns true if the tenant was suspended by the operator.
+    #[inline]
+    pub fn held(&self) -> bool {
+        self.held.load(Ordering::Relaxed)
+    }
+
+    /// This method suspends or resumes the tenant on behalf of the operator. A tenant the
+    /// operator suspended stays suspended until the operator resumes it, even if it resumes
+    /// itself.
+    ///
+    /// # Arguments
+    ///
+    /// * `held`: True if the tenant should be suspended, false if it should be resumed.
+    pub fn set_held(&self, held: bool) {
+        self.held.store(held, Ordering::Relaxed);
+    }
+
+    /// This method returns the key the tenant's values are encrypted with when written out of
+    /// memory, or None if the tenant was not handed one.
+    pub fn key(&self) -> Option<Arc<Key>> {
+        self.key.read().clone()
+    }
+
+    /// This method replaces the key the tenant's values are encrypted with when written out of
+    /// memory. Values already written out stay encrypted under the old key.
+    ///
+    /// # Arguments
+    ///
+    /// * `key`: The new key, or None if values should be written out in the clear.
+    pub fn set_key(&self, key: Option<Key>) {
+        *self.key.write() = key.map(Arc::new);
+    }
+
     /// This method creates a new table for the tenant. If a table with the
     /// passed in identifier already exists, then this method does nothing.
     ///
@@ -74,10 +211,52 @@ impl Tenant {
         let mut map = self.tables.write();
 
         // Insert a new table and return.
-        map.insert(table_id, Arc::new(Table::default()));
+        map.insert(table_id, Arc::new(Table::owned_by(self.id, Arc::clone(&self.quota))));
+    }
+
+    /// This method mounts a table owned by another tenant into this tenant's namespace, under
+    /// the table's identifier at it's owner.
+    ///
+    /// # Arguments
+    ///
+    /// * `table_id`: The identifier of the table at it's owner.
+    /// * `table`:    The table.
+    ///
+    /// # Return
+    ///
+    /// False if the identifier is already taken by a table of this tenant, or by a table of an
+    /// owner other than `table`'s.
+    pub fn mount(&self, table_id: TableId, table: Arc<Table>) -> bool {
+        if self.tables.read().contains_key(&table_id) {
+            return false;
+        }
+
+        let mut mounts = self.mounts.write();
+        if let Some(mounted) = mounts.get(&table_id) {
+            if mounted.owner() != table.owner() {
+                return false;
+            }
+        }
+
+        mounts.insert(table_id, table);
+        return true;
+    }
+
+    /// This method unmounts a table shared with this tenant by another.
+    ///
+    /// # Arguments
+    ///
+    /// * `table_id`: The identifier the table is mounted under.
+    /// * `owner`:    The owner of the table. Tables of any other owner are left mounted.
+    pub fn unmount(&self, table_id: TableId, owner: TenantId) {
+        let mut mounts = self.mounts.write();
+        if mounts.get(&table_id).map_or(false, |table| table.owner() == owner) {
+            mounts.remove(&table_id);
+        }
     }
 
-    /// This method returns a table belonging to the tenant if it exists.
+    /// This method returns a table belonging to the tenant, or shared with it, if it exists.
+    /// Callers must check that the tenant is permitted to access a shared table.
     ///
     /// # Arguments
     ///
@@ -90,7 +269,47 @@ impl Tenant {
         // Acquire a read lock.
         let map = self.tables.read();
 
-        // Lookup on table_id and return.
-        map.get(&table_id).and_then(| table | { Some(Arc::clone(&table)) })
+        // Lookup on table_id and return. Fall back to the tables shared with the tenant.
+        map.get(&table_id)
+            .map(|table| Arc::clone(table))
+            .or_else(|| self.mounts.read().get(&table_id).map(|table| Arc::clone(table)))
+    }
+
+    /// This method returns a table belonging to the tenant, or shared with it, if it exists and
+    /// the tenant is permitted to access it.
+    ///
+    /// # Arguments
+    ///
+    /// * `table_id`: The identifier for the table to be returned.
+    /// * `access`:   The ACCESS_* bits the access needs.
+    ///
+    /// # Return
+    ///
+    /// An atomic reference counted handle to the table if it exists and may be accessed.
+    pub fn table(&self, table_id: TableId, access: u8) -> Option<Arc<Table>> {
+        self.get_table(table_id)
+            .and_then(|table| match table.permits(self.id, access) {
+                true => Some(table),
+                false => None,
+            })
+    }
+
+    /// This method returns all the tables belonging to the tenant, excluding those shared with
+    /// it.
+    ///
+    /// # Return
+    ///
+    /// The identifier of each table along with a handle to it, ordered by identifier.
+    pub fn tables(&self) -> Vec<(TableId, Arc<Table>)> {
+        // Acquire a read lock.
+        let map = self.tables.read();
+
+        let mut tables: Vec<(TableId, Arc<Table>)> = map
+            .iter()
+            .map(|(id, table)| (*id, Arc::clone(table)))
+            .collect();
+        tables.sort_by_key(|&(id, _)| id);
+
+        return tables;
     }
 }
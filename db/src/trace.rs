@@ -0,0 +1,262 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+
+use std::fs::File;
+use std::io::{self, Read};
+
+/// The kind of request on a trace.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TraceOp {
+    /// A lookup of a key.
+    Get,
+
+    /// A write of a key, covering every memcached command that stores a value.
+    Put,
+
+    /// A removal of a key.
+    Delete,
+}
+
+/// A request recorded on a trace.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    /// The time the request was issued at in nanoseconds, relative to the first request.
+    pub time: u64,
+
+    /// The kind of request.
+    pub op: TraceOp,
+
+    /// The tenant that issued the request, between 1 and the number of tenants.
+    pub tenant: u32,
+
+    /// The key the request is on, between 1 and the number of keys.
+    pub key: u32,
+
+    /// The size of the value written by a put in bytes.
+    pub size: u32,
+}
+
+/// A recorded request trace, for replaying realistic workloads instead of synthetic ones.
+///
+/// Traces are text, one request per line. A line is either `<time> <op> <key> <size> [tenant]`,
+/// separated by whitespace with the time in microseconds, or in the comma separated format
+/// of the Twitter cache traces, `<time>,<key>,<key size>,<value size>,<client>,<op>,<ttl>`
+/// with the time in seconds. Empty lines and lines starting with '#' are skipped.
+///
+/// Keys on a trace are anonymized strings that the server knows nothing about, so they are
+/// mapped onto the keys the server was populated with. A numeric key in range is used as is,
+/// anything else is hashed. Tenants and clients are mapped onto the configured tenants the
+/// same way.
+pub struct Trace {
+    // The requests on the trace, ordered by time.
+    records: Vec<Record>,
+}
+
+// Maps a key or client on a trace onto one of `n` identifiers starting at 1.
+fn map_id(token: &str, n: u32) -> u32 {
+    let n = n.max(1);
+    if let Ok(id) = token.parse::<u64>() {
+        if id >= 1 && id <= n as u64 {
+            return id as u32;
+        }
+
+        return (id % n as u64) as u32 + 1;
+    }
+
+    // FNV-1a.
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in token.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    return (hash % n as u64) as u32 + 1;
+}
+
+// Maps the name of an operation on a trace onto the kind of request it is.
+fn parse_op(op: &str) -> Option<TraceOp> {
+    match op.to_lowercase().as_str() {
+        "get" | "gets" => Some(TraceOp::Get),
+
+        "put" | "set" | "add" | "replace" | "cas" | "append" | "prepend" | "incr" | "decr" => {
+            Some(TraceOp::Put)
+        }
+
+        "delete" | "del" => Some(TraceOp::Delete),
+
+        _ => None,
+    }
+}
+
+// Parses a time on a trace into nanoseconds, given the number of nanoseconds in it's unit.
+fn parse_time(time: &str, unit: f64) -> Option<u64> {
+    match time.parse::<f64>() {
+        Ok(time) if time >= 0.0 => Some((time * unit) as u64),
+        _ => None,
+    }
+}
+
+// Implementation of methods on Trace.
+impl Trace {
+    /// Reads a trace from a file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path`:    The file the trace is in.
+    /// * `keys`:    The number of keys the server was populated with.
+    /// * `tenants`: The number of tenants the server was populated with.
+    ///
+    /// # Return
+    ///
+    /// The trace, or an error if the file could not be read or a line could not be parsed.
+    pub fn load(path: &str, keys: u32, tenants: u32) -> io::Result<Trace> {
+        let mut text = String::new();
+        File::open(path)?.read_to_string(&mut text)?;
+
+        Trace::parse(&text, keys, tenants)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    /// Parses a trace.
+    ///
+    /// # Arguments
+    ///
+    /// * `text`:    The contents of the trace.
+    /// * `keys`:    The number of keys the server was populated with.
+    /// * `tenants`: The number of tenants the server was populated with.
+    ///
+    /// # Return
+    ///
+    /// The trace, or a description of the first line that could not be parsed.
+    pub fn parse(text: &str, keys: u32, tenants: u32) -> Result<Trace, String> {
+        let mut records = Vec::new();
+
+        for (num, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.len() == 0 || line.starts_with('#') {
+                continue;
+            }
+
+            let bad =
+                |what: &str| format!("Line {} of the trace has {}: {:?}", num + 1, what, line);
+
+            // Both formats list the same things, in a different order and unit of time.
+            let (time, op, key, size, tenant) = if line.contains(',') {
+                let fields: Vec<&str> = line.split(',').map(|f| f.trim()).collect();
+                if fields.len() < 6 {
+                    return Err(bad("too few fields"));
+                }
+
+                (parse_time(fields[0], 1e9), fields[5], fields[1], fields[3], Some(fields[4]))
+            } else {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                if fields.len() < 4 {
+                    return Err(bad("too few fields"));
+                }
+
+                let tenant = fields.get(4).cloned();
+                (parse_time(fields[0], 1e3), fields[1], fields[2], fields[3], tenant)
+            };
+
+            let time = time.ok_or_else(|| bad("an invalid time"))?;
+            let op = parse_op(op).ok_or_else(|| bad("an unknown operation"))?;
+            let size = size.parse::<u32>().map_err(|_| bad("an invalid size"))?;
+
+            records.push(Record {
+                time: time,
+                op: op,
+                tenant: tenant.map_or(1, |tenant| map_id(tenant, tenants)),
+                key: map_id(key, keys),
+                size: size,
+            });
+        }
+
+        // Make times relative to the first request. Traces are normally already in order, but
+        // merged ones might not be.
+        records.sort_by_key(|record| record.time);
+        let first = records.first().map_or(0, |record| record.time);
+        for record in records.iter_mut() {
+            record.time -= first;
+        }
+
+        Ok(Trace { records: records })
+    }
+
+    /// Returns the requests on the trace, ordered by time.
+    pub fn records(&self) -> &[Record] {
+        &self.records
+    }
+
+    /// Returns the time between the first and last request on the trace in nanoseconds.
+    pub fn duration(&self) -> u64 {
+        self.records.last().map_or(0, |record| record.time)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Tests that both formats are parsed, and that times are made relative to the first request.
+    #[test]
+    fn test_parse() {
+        let text = "# time op key size tenant\n\
+                    1000.5 get 7 0\n\
+                    \n\
+                    1002 SET 8 100 2\n\
+                    20,abc,3,50,4,delete,0\n";
+        let trace = Trace::parse(text, 1000, 4).unwrap();
+        let records = trace.records();
+
+        assert_eq!(3, records.len());
+        assert_eq!(Record { time: 0, op: TraceOp::Get, tenant: 1, key: 7, size: 0 }, records[0]);
+        assert_eq!(1500, records[1].time);
+        assert_eq!(TraceOp::Put, records[1].op);
+        assert_eq!(2, records[1].tenant);
+        assert_eq!(100, records[1].size);
+
+        // The Twitter record is in seconds, and it's key is hashed into range.
+        assert_eq!(20 * 1000 * 1000 * 1000 - 1000500, records[2].time);
+        assert_eq!(TraceOp::Delete, records[2].op);
+        assert_eq!(4, records[2].tenant);
+        assert!(records[2].key >= 1 && records[2].key <= 1000);
+        assert_eq!(records[2].time, trace.duration());
+    }
+
+    // Tests that keys and tenants are mapped into range.
+    #[test]
+    fn test_map_id() {
+        assert_eq!(5, map_id("5", 10));
+        assert_eq!(2, map_id("10", 9));
+        assert_eq!(1, map_id("0", 10));
+        assert_eq!(map_id("user:1234", 10), map_id("user:1234", 10));
+        for key in ["a", "b", "c", "user:1234"].iter() {
+            let id = map_id(key, 10);
+            assert!(id >= 1 && id <= 10);
+        }
+    }
+
+    // Tests that malformed lines are reported with their line number.
+    #[test]
+    fn test_parse_errors() {
+        let err = Trace::parse("1 get 1 0\n2 scan 1 0\n", 10, 1).err().unwrap();
+        assert!(err.starts_with("Line 2 "));
+
+        assert!(Trace::parse("1 get 1\n", 10, 1).is_err());
+        assert!(Trace::parse("x get 1 0\n", 10, 1).is_err());
+        assert!(Trace::parse("1,k,1,x,1,get,0\n", 10, 1).is_err());
+    }
+}
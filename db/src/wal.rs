@@ -0,0 +1,891 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+
+// Durability across crashes: a write-ahead log of every write, and checkpoints of every table.
+// Writes are numbered with a log sequence number (LSN) in the order they are applied in, and
+// appended to an in-memory buffer that a thread of it's own writes to the current log segment
+// and syncs every `wal_sync_ms`. Writes acknowledged since the last sync are lost on a crash.
+// Every `checkpoint_interval_s`, the thread rolls over to a new segment, writes a snapshot of
+// every table (see `snapshot`), and then deletes the segments and checkpoints the new one
+// replaces. The log directory holds:
+//
+//   wal-<first LSN>.log       a segment: entries of an LSN (u64), flags (u8), the length of the
+//                             record (u32), the FNV-1a hash (u64) of all of these and of the
+//                             record, and the record as encoded by `replica::encode()`
+//   checkpoint-<number>/      a snapshot per table, "<tenant>-<table>.snap", and a manifest with
+//                             the LSN the checkpoint covers, written last
+//
+// At startup, `recover()` loads the last complete checkpoint and replays the entries after the
+// LSN it covers, in order. Writes are puts and deletes of whole objects, so replaying an entry
+// the checkpoint already reflects changes nothing, and recovering twice ends in the same state.
+// A bad entry at the end of the last segment is a write torn by the crash, and the segment is
+// cut there. A bad entry anywhere else stops recovery, unless `wal_skip_corrupt` is set, in
+// which case everything from it onwards is set aside.
+//
+// Values of tenants that have a key are sealed with it (see `crypt`). Keys are only ever held in
+// memory, so the sealed objects of a tenant are held back at recovery, and the tenant suspended,
+// until it is handed it's key again through the tenant() RPC. Tenants themselves are not logged:
+// only the tables of tenants that exist when recovery runs are recovered.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use std::str::{from_utf8, FromStr};
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use std::sync::{Arc, Once, ONCE_INIT};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use super::common::{TableId, TenantId};
+use super::crypt::Key;
+use super::master::Master;
+use super::replica::{self, Record};
+use super::snapshot::{self, Fnv};
+use super::toml;
+
+use spin::{Mutex, RwLock};
+
+/// The number of milliseconds between syncs of the log when `wal_sync_ms` is zero.
+pub const DEFAULT_SYNC_MS: u64 = 10;
+
+/// The number of seconds between checkpoints when `checkpoint_interval_s` is zero.
+pub const DEFAULT_CHECKPOINT_S: u64 = 300;
+
+/// The number of bytes in the header of an entry: the LSN (u64), flags (u8), the length of the
+/// record (u32) and the hash (u64), little endian.
+pub const ENTRY_HDR_LEN: usize = 21;
+
+/// Set on an entry whose value is sealed with the tenant's key.
+const FLAG_SEALED: u8 = 0x01;
+
+/// The number of bytes a segment grows to before the log rolls over to a new one.
+const SEGMENT_BYTES: u64 = 64 << 20;
+
+/// The number of entries between progress reports while replaying the log.
+const REPORT_ENTRIES: u64 = 1 << 20;
+
+/// Where the log is kept, and how often it is synced and checkpointed.
+pub struct Settings {
+    /// The directory holding segments and checkpoints.
+    pub dir: String,
+
+    /// The number of milliseconds between syncs of the log, zero for the default.
+    pub sync_ms: u64,
+
+    /// The number of seconds between checkpoints, zero for the default.
+    pub checkpoint_s: u64,
+
+    /// True if recovery should set aside a corrupt part of the log rather than stop.
+    pub skip_corrupt: bool,
+}
+
+// What a checkpoint holds, written to it's directory as TOML once every table is.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Manifest {
+    // The checkpoint's number, and the LSN of the last write it reflects.
+    number: u64,
+    lsn: u64,
+
+    tables: Vec<Entry>,
+}
+
+// A table in a checkpoint.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Entry {
+    tenant: u32,
+    table: u64,
+    objects: u64,
+
+    // Whether values are sealed with the tenant's key.
+    sealed: bool,
+}
+
+// The entries appended since the log was last written out.
+struct Log {
+    // The LSN of the next write, and of the first entry in `buf`.
+    next: u64,
+    first: u64,
+
+    buf: Vec<u8>,
+}
+
+// A write of a tenant whose key is not known yet, held back by recovery.
+struct Held {
+    op: u8,
+    table: TableId,
+    key: Vec<u8>,
+    // The value, sealed with the tenant's key.
+    val: Vec<u8>,
+}
+
+// The state shared by all threads.
+struct Shared {
+    settings: RwLock<Option<Settings>>,
+    log: Mutex<Log>,
+
+    // The keys of tenants that have one, which logged values are sealed with.
+    keys: RwLock<HashMap<TenantId, Arc<Key>>>,
+
+    // Writes held back by recovery until their tenant's key is known, and the number of the
+    // last checkpoint taken.
+    held: Mutex<HashMap<TenantId, Vec<Held>>>,
+    checkpoint: Mutex<u64>,
+}
+
+/// Set once writes are logged.
+static ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Set when a checkpoint was asked for ahead of the interval.
+static REQUESTED: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Initializes SHARED exactly once.
+static INIT: Once = ONCE_INIT;
+
+/// The state shared by all threads. Use `shared()` to access it.
+static mut SHARED: *const Shared = 0 as *const Shared;
+
+// Returns the state shared by all threads, allocating it on first use.
+fn shared() -> &'static Shared {
+    unsafe {
+        INIT.call_once(|| {
+            SHARED = Box::into_raw(Box::new(Shared {
+                settings: RwLock::new(None),
+                log: Mutex::new(Log {
+                    next: 1,
+                    first: 1,
+                    buf: Vec::new(),
+                }),
+                keys: RwLock::new(HashMap::new()),
+                held: Mutex::new(HashMap::new()),
+                checkpoint: Mutex::new(0),
+            }));
+        });
+        &*SHARED
+    }
+}
+
+/// Sets where the log is kept. Must be called before `recover()`.
+///
+/// # Arguments
+///
+/// * `settings`: Where the log is kept, and how often it is synced and checkpointed.
+pub fn configure(settings: Settings) {
+    *shared().settings.write() = Some(settings);
+}
+
+/// Returns true if writes are logged.
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Applies a write, and appends it to the log if writes are logged.
+///
+/// # Arguments
+///
+/// * `rec`:   The record of the write.
+/// * `apply`: Applies the write to the local table.
+pub fn write<F: FnOnce()>(rec: &Record, apply: F) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        apply();
+        return;
+    }
+
+    // Entries are appended in the order writes are applied in, so that replay ends up with the
+    // last value of every key.
+    let key = shared().keys.read().get(&rec.tenant).cloned();
+    let mut log = shared().log.lock();
+    apply();
+
+    let lsn = log.next;
+    log.next += 1;
+    match key {
+        Some(key) => {
+            let val = key.seal(rec.table, rec.key, rec.val);
+            let sealed = Record {
+                op: rec.op,
+                tenant: rec.tenant,
+                table: rec.table,
+                key: rec.key,
+                val: &val,
+            };
+            encode(&mut log.buf, lsn, FLAG_SEALED, &sealed);
+        }
+        None => encode(&mut log.buf, lsn, 0, rec),
+    }
+}
+
+// Appends an entry to a buffer.
+fn encode(buf: &mut Vec<u8>, lsn: u64, flags: u8, rec: &Record) {
+    let mut record = Vec::with_capacity(replica::RECORD_HDR_LEN + rec.key.len() + rec.val.len());
+    replica::encode(&mut record, rec);
+
+    let start = buf.len();
+    push_le(buf, lsn, 8);
+    buf.push(flags);
+    push_le(buf, record.len() as u64, 4);
+
+    let mut hash = Fnv::new();
+    hash.update(&buf[start..]);
+    hash.update(&record);
+    push_le(buf, hash.0, 8);
+    buf.extend_from_slice(&record);
+}
+
+/// Notes a tenant's key, so that it's values are sealed with it when logged or checkpointed.
+/// Writes of the tenant held back by recovery are applied once it's key is known, and the tenant
+/// resumed. A checkpoint is asked for, so that values logged under an earlier key are not needed
+/// to recover.
+///
+/// # Arguments
+///
+/// * `master`: Master, which held back writes are applied to.
+/// * `tenant`: The tenant.
+/// * `key`:    The tenant's key, or None if it no longer has one.
+pub fn set_key(master: &Master, tenant: TenantId, key: Option<Arc<Key>>) {
+    match key {
+        Some(ref key) => shared().keys.write().insert(tenant, Arc::clone(key)),
+        None => shared().keys.write().remove(&tenant),
+    };
+    REQUESTED.store(true, Ordering::Release);
+
+    let key = match key {
+        Some(key) => key,
+        None => return,
+    };
+    let held = match shared().held.lock().remove(&tenant) {
+        Some(held) => held,
+        None => return,
+    };
+
+    // A write that does not open was not sealed with this key, or was changed on disk, and is
+    // dropped rather than applied.
+    let mut dropped = 0;
+    for write in held.into_iter() {
+        let val = match key.open(write.table, &write.key, &write.val) {
+            Some(val) => val,
+            None => {
+                dropped += 1;
+                continue;
+            }
+        };
+        let rec = Record {
+            op: write.op,
+            tenant: tenant,
+            table: write.table,
+            key: &write.key,
+            val: &val,
+        };
+        master.replay_record(&rec);
+    }
+    master.suspend_tenant(tenant, false);
+    info!("Applied the writes of tenant {} held back by recovery", tenant);
+    if dropped > 0 {
+        warn!("Dropped {} writes of tenant {} that did not open with it's key", dropped, tenant);
+    }
+}
+
+/// Writes out and syncs every entry appended since `serve()` last flushed the log, to a segment
+/// of it's own, without waiting for the next sync. Required to shut down without losing writes
+/// that were acknowledged, and so must only be called once no more writes are being logged:
+/// entries `serve()` flushed after this would precede those in the segment on recovery.
+///
+/// # Return
+///
+/// The LSN of the last entry in the log, zero if writes are not logged. An error if the segment
+/// could not be written.
+pub fn sync() -> io::Result<u64> {
+    let dir = match *shared().settings.read() {
+        Some(ref s) => s.dir.clone(),
+        None => return Ok(0),
+    };
+
+    flush(&dir, &mut None, true)
+}
+
+/// Returns true if tables that reflect every logged write up to an LSN, such as those preserved
+/// across a restart in shared memory (see `shm`), can stand in for the last checkpoint. This is
+/// the case unless a later checkpoint covers writes past the LSN, as the log between the two is
+/// gone.
+///
+/// # Arguments
+///
+/// * `lsn`: The LSN of the last logged write the tables reflect.
+pub fn resumes(lsn: u64) -> bool {
+    let dir = match *shared().settings.read() {
+        Some(ref s) => s.dir.clone(),
+        None => return true,
+    };
+
+    let checkpoints = listed(Path::new(&dir), "checkpoint-", "").unwrap_or(Vec::new());
+    checkpoints.iter().all(|&(number, ref path)| match load(path) {
+        Ok(ref manifest) if manifest.number == number => manifest.lsn <= lsn,
+        _ => true,
+    })
+}
+
+/// Asks for a checkpoint to be taken ahead of the interval.
+pub fn request_checkpoint() {
+    REQUESTED.store(true, Ordering::Release);
+}
+
+// What recovery found, reported as it goes.
+#[derive(Default)]
+struct Progress {
+    // Objects loaded off the checkpoint, and entries replayed or skipped as the checkpoint
+    // already reflects them.
+    loaded: u64,
+    replayed: u64,
+    skipped: u64,
+
+    // Writes held back until their tenant's key is known, and writes of tenants that no longer
+    // exist.
+    held: u64,
+    dropped: u64,
+}
+
+/// Recovers every table from the last checkpoint and the log, and starts logging writes. Must
+/// be called once tenants are created and before the server accepts requests, with `serve()`
+/// called on a thread of it's own afterwards.
+///
+/// # Arguments
+///
+/// * `master`:    Master, which tables are recovered into.
+/// * `preserved`: The LSN of the last logged write reflected by tables already loaded, for
+///                which `resumes()` holds. Recovery then picks up from these tables instead of
+///                the last checkpoint.
+///
+/// # Return
+///
+/// An error if the log directory cannot be read, or if the log is corrupt and corruption is not
+/// skipped. Nothing is logged in that case.
+pub fn recover(master: &Master, preserved: Option<u64>) -> io::Result<()> {
+    let settings = shared().settings.read();
+    let settings = match *settings {
+        Some(ref settings) => settings,
+        None => return Ok(()),
+    };
+
+    let start = Instant::now();
+    let dir = Path::new(&settings.dir);
+    fs::create_dir_all(dir)?;
+    let tenants: HashSet<TenantId> = master.tenant_ids().into_iter().collect();
+    let mut progress = Progress::default();
+
+    // The last complete checkpoint. Checkpoints that were being written when the server went
+    // down are removed.
+    let mut last: Option<Manifest> = None;
+    for (number, path) in listed(dir, "checkpoint-", "")? {
+        match load(&path) {
+            Ok(manifest) if manifest.number == number => last = Some(manifest),
+            _ => {
+                warn!("Removing incomplete checkpoint {}", path);
+                fs::remove_dir_all(&path)?;
+            }
+        }
+    }
+
+    let covered = match (preserved, last) {
+        (Some(lsn), last) => {
+            info!("Picking up from tables preserved up to LSN {}", lsn);
+            *shared().checkpoint.lock() = last.map_or(0, |manifest| manifest.number);
+            lsn
+        }
+        (None, Some(ref manifest)) => {
+            info!("Loading checkpoint {}, up to LSN {}", manifest.number, manifest.lsn);
+            load_checkpoint(master, dir, manifest, &tenants, &mut progress)?;
+            *shared().checkpoint.lock() = manifest.number;
+            manifest.lsn
+        }
+        (None, None) => 0,
+    };
+
+    // Replay the segments in order.
+    let segments = listed(dir, "wal-", ".log")?;
+    let total: u64 = segments
+        .iter()
+        .map(|&(_, ref path)| fs::metadata(path).map(|m| m.len()).unwrap_or(0))
+        .sum();
+    let (mut done, mut lsn) = (0, 0);
+    for (idx, &(_, ref path)) in segments.iter().enumerate() {
+        let mut buf = Vec::new();
+        File::open(path)?.read_to_end(&mut buf)?;
+        info!(
+            "Replaying {} ({} of {}, {} of {} bytes of log)",
+            path,
+            idx + 1,
+            segments.len(),
+            done,
+            total
+        );
+
+        let (good, last) = replay(master, &buf, lsn, covered, &tenants, &mut progress);
+        lsn = last;
+        done += buf.len() as u64;
+        if good == buf.len() {
+            continue;
+        }
+
+        // A bad entry at the end of the log is a write torn by the crash.
+        if idx + 1 == segments.len() {
+            warn!(
+                "Cut {} at byte {}, dropping {} bytes of a torn write",
+                path,
+                good,
+                buf.len() - good
+            );
+            OpenOptions::new().write(true).open(path)?.set_len(good as u64)?;
+            break;
+        }
+
+        let msg = format!("{} is corrupt at byte {}", path, good);
+        if !settings.skip_corrupt {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+
+        let later = segments.len() - idx - 1;
+        error!("{}, setting aside it's remainder and {} later segments", msg, later);
+        OpenOptions::new().write(true).open(path)?.set_len(good as u64)?;
+        for &(_, ref later) in segments[idx + 1..].iter() {
+            fs::rename(later, format!("{}.corrupt", later))?;
+        }
+        break;
+    }
+
+    // Logging picks up after the last entry recovered, in a segment of it's own.
+    let lsn = ::std::cmp::max(lsn, covered);
+    {
+        let mut log = shared().log.lock();
+        log.next = lsn + 1;
+        log.first = lsn + 1;
+    }
+    ENABLED.store(true, Ordering::Release);
+
+    // Tenants with writes held back are suspended until their key is known.
+    for (tenant, _) in shared().held.lock().iter() {
+        warn!("Suspending tenant {} until it is handed it's key", tenant);
+        master.suspend_tenant(*tenant, true);
+    }
+
+    info!(
+        "Recovered up to LSN {} in {:?}: {} objects loaded, {} writes replayed, {} skipped, \
+         {} held back for keys, {} of missing tenants dropped",
+        lsn,
+        start.elapsed(),
+        progress.loaded,
+        progress.replayed,
+        progress.skipped,
+        progress.held,
+        progress.dropped
+    );
+    Ok(())
+}
+
+// Loads the tables in a checkpoint.
+fn load_checkpoint(
+    master: &Master,
+    dir: &Path,
+    manifest: &Manifest,
+    tenants: &HashSet<TenantId>,
+    progress: &mut Progress,
+) -> io::Result<()> {
+    for entry in manifest.tables.iter() {
+        let (tenant, table) = (entry.tenant as TenantId, entry.table as TableId);
+        if !tenants.contains(&tenant) {
+            progress.dropped += entry.objects;
+            continue;
+        }
+
+        let path = dir.join(format!("checkpoint-{}", manifest.number));
+        let file = io::BufReader::new(File::open(path.join(table_file(tenant, table)))?);
+        let (mut reader, _) = snapshot::Reader::new(file)?;
+        let mut idx = 0;
+        while let Some((key, val)) = reader.next()? {
+            let rec = Record {
+                op: replica::OP_PUT,
+                tenant: tenant,
+                table: table,
+                key: &key,
+                val: &val,
+            };
+            match entry.sealed {
+                true => hold(&rec, progress),
+                false => master.replay_record(&rec),
+            }
+            idx += 1;
+        }
+        progress.loaded += idx;
+    }
+
+    Ok(())
+}
+
+// Replays the entries in a segment that come after `covered`, and returns the number of bytes
+// of good entries at the start of the segment along with the LSN of the last one. `lsn` is the
+// LSN of the last entry in the segments before, zero if there is none.
+fn replay(
+    master: &Master,
+    buf: &[u8],
+    lsn: u64,
+    covered: u64,
+    tenants: &HashSet<TenantId>,
+    progress: &mut Progress,
+) -> (usize, u64) {
+    let mut off = 0;
+    let mut lsn = lsn;
+    while let Some((entry_lsn, flags, rec, len)) = decode(&buf[off..]) {
+        // LSNs only ever grow, so anything else is corruption.
+        if entry_lsn <= lsn {
+            break;
+        }
+        off += len;
+        lsn = entry_lsn;
+
+        if entry_lsn <= covered {
+            progress.skipped += 1;
+        } else if !tenants.contains(&rec.tenant) {
+            progress.dropped += 1;
+        } else if flags & FLAG_SEALED != 0 {
+            hold(&rec, progress);
+        } else {
+            master.replay_record(&rec);
+            progress.replayed += 1;
+        }
+
+        let seen = progress.replayed + progress.skipped + progress.held + progress.dropped;
+        if seen % REPORT_ENTRIES == 0 {
+            info!("Replayed {} writes, up to LSN {}", progress.replayed, lsn);
+        }
+    }
+
+    (off, lsn)
+}
+
+// Decodes the entry at the start of a buffer, returning it's LSN, flags, record and length, or
+// None if the buffer does not start with a whole entry that matches it's hash.
+fn decode(buf: &[u8]) -> Option<(u64, u8, Record, usize)> {
+    if buf.len() < ENTRY_HDR_LEN {
+        return None;
+    }
+
+    let len = read_le(&buf[9..], 4) as usize;
+    if buf.len() < ENTRY_HDR_LEN + len {
+        return None;
+    }
+
+    let record = &buf[ENTRY_HDR_LEN..ENTRY_HDR_LEN + len];
+    let mut hash = Fnv::new();
+    hash.update(&buf[..13]);
+    hash.update(record);
+    if hash.0 != read_le(&buf[13..], 8) {
+        return None;
+    }
+
+    match replica::decode(record) {
+        Some((rec, rec_len)) if rec_len == len => {
+            Some((read_le(buf, 8), buf[8], rec, ENTRY_HDR_LEN + len))
+        }
+        _ => None,
+    }
+}
+
+// Holds back a sealed write until it's tenant's key is known.
+fn hold(rec: &Record, progress: &mut Progress) {
+    shared()
+        .held
+        .lock()
+        .entry(rec.tenant)
+        .or_insert(Vec::new())
+        .push(Held {
+            op: rec.op,
+            table: rec.table,
+            key: rec.key.to_vec(),
+            val: rec.val.to_vec(),
+        });
+    progress.held += 1;
+}
+
+/// Writes the log out every `wal_sync_ms`, and takes a checkpoint every `checkpoint_interval_s`
+/// or when one is asked for. Blocks the calling thread.
+///
+/// # Arguments
+///
+/// * `master`: Master, which tables are checkpointed from.
+pub fn serve(master: Arc<Master>) {
+    let (dir, sync_ms, checkpoint_s) = match *shared().settings.read() {
+        Some(ref s) => (s.dir.clone(), s.sync_ms, s.checkpoint_s),
+        None => return,
+    };
+    let sync = Duration::from_millis(if sync_ms == 0 { DEFAULT_SYNC_MS } else { sync_ms });
+    let every = match checkpoint_s {
+        0 => Duration::from_secs(DEFAULT_CHECKPOINT_S),
+        _ => Duration::from_secs(checkpoint_s),
+    };
+
+    let mut segment: Option<(File, u64)> = None;
+    let mut last = Instant::now();
+    loop {
+        sleep(sync);
+        if let Err(e) = flush(&dir, &mut segment, false) {
+            error!("Failed to write the log: {}", e);
+            continue;
+        }
+
+        if last.elapsed() >= every || REQUESTED.swap(false, Ordering::AcqRel) {
+            last = Instant::now();
+            if let Err(e) = checkpoint(&master, &dir, &mut segment) {
+                error!("Failed to take a checkpoint: {}", e);
+            }
+        }
+    }
+}
+
+// Writes the entries appended since the last flush to the current segment, and syncs it. The
+// segment is closed afterwards if it grew too large, or if `roll` is set, so that the next flush
+// starts a new one. Returns the LSN of the last entry written.
+fn flush(dir: &str, segment: &mut Option<(File, u64)>, roll: bool) -> io::Result<u64> {
+    let (buf, first, last) = {
+        let mut log = shared().log.lock();
+        let first = log.first;
+        log.first = log.next;
+        (::std::mem::replace(&mut log.buf, Vec::new()), first, log.next - 1)
+    };
+
+    if buf.len() > 0 {
+        if segment.is_none() {
+            let path = Path::new(dir).join(format!("wal-{}.log", first));
+            *segment = Some((File::create(path)?, 0));
+        }
+
+        // The entries are put back if they cannot be written, so that the next flush tries
+        // again.
+        let result = match *segment {
+            Some((ref mut file, ref mut len)) => file
+                .write_all(&buf)
+                .and_then(|_| file.sync_data())
+                .map(|_| *len += buf.len() as u64),
+            None => Ok(()),
+        };
+        // A segment cut short by a failed write would look corrupt to recovery, so it is cut
+        // back to the last whole flush.
+        if let Err(e) = result {
+            if let Some((ref file, len)) = *segment {
+                let _ = file.set_len(len);
+            }
+            let mut log = shared().log.lock();
+            let mut rest = ::std::mem::replace(&mut log.buf, buf);
+            log.buf.append(&mut rest);
+            log.first = first;
+            *segment = None;
+            return Err(e);
+        }
+    }
+
+    if roll || segment.as_ref().map_or(false, |&(_, len)| len >= SEGMENT_BYTES) {
+        *segment = None;
+    }
+    Ok(last)
+}
+
+// Takes a checkpoint of every table, and then removes the segments and checkpoints it replaces.
+fn checkpoint(master: &Master, dir: &str, segment: &mut Option<(File, u64)>) -> io::Result<()> {
+    let start = Instant::now();
+
+    // Every write up to `lsn` was applied before the log was flushed, so the checkpoint
+    // reflects all of them. Later writes may or may not make it in, and are replayed anyway.
+    let lsn = flush(dir, segment, true)?;
+    let number = *shared().checkpoint.lock() + 1;
+
+    let partial = Path::new(dir).join(format!("checkpoint-{}.partial", number));
+    fs::create_dir_all(&partial)?;
+    let mut manifest = Manifest {
+        number: number,
+        lsn: lsn,
+        tables: Vec::new(),
+    };
+
+    let mut objects = 0;
+    for tenant in master.tenant_ids() {
+        let key = shared().keys.read().get(&tenant).cloned();
+        for table in master.owned_tables(tenant).unwrap_or(Vec::new()) {
+            let file = File::create(partial.join(table_file(tenant, table)))?;
+            let flags = key.as_ref().map_or(0, |_| snapshot::FLAG_SEALED);
+            let mut writer = snapshot::Writer::new(BufWriter::new(file), tenant, table, flags)?;
+
+            let mut failed = None;
+            master.visit_table(tenant, table, &mut |rec| {
+                if failed.is_some() {
+                    return;
+                }
+                failed = match key {
+                    Some(ref key) => {
+                        let val = key.seal(table, rec.key, rec.val);
+                        writer.push(rec.key, &val).err()
+                    }
+                    None => writer.push(rec.key, rec.val).err(),
+                };
+                objects += 1;
+            });
+            if let Some(e) = failed {
+                return Err(e);
+            }
+
+            manifest.tables.push(Entry {
+                tenant: tenant,
+                table: table,
+                objects: writer.finish()?,
+                sealed: key.is_some(),
+            });
+        }
+    }
+
+    let written = toml::Value::try_from(&manifest)
+        .and_then(|value| toml::to_string(&value))
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    let mut file = File::create(partial.join("manifest.toml"))?;
+    file.write_all(written.as_bytes())?;
+    file.sync_all()?;
+
+    let path = Path::new(dir).join(format!("checkpoint-{}", number));
+    fs::rename(&partial, &path)?;
+    *shared().checkpoint.lock() = number;
+
+    // Everything in the earlier segments and checkpoints is reflected by this one.
+    let dir = Path::new(dir);
+    for (first, path) in listed(dir, "wal-", ".log")? {
+        if first <= lsn {
+            fs::remove_file(path)?;
+        }
+    }
+    for (older, path) in listed(dir, "checkpoint-", "")? {
+        if older < number {
+            fs::remove_dir_all(path)?;
+        }
+    }
+
+    info!(
+        "Took checkpoint {} up to LSN {}, {} objects in {:?}",
+        number,
+        lsn,
+        objects,
+        start.elapsed()
+    );
+    Ok(())
+}
+
+// Returns the number in the name of every file in a directory named with a prefix, a number
+// and a suffix, along with it's path, ordered by number.
+fn listed(dir: &Path, prefix: &str, suffix: &str) -> io::Result<Vec<(u64, String)>> {
+    let mut listed = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if name.starts_with(prefix) && name.ends_with(suffix) && name.len() > prefix.len() {
+            let number = &name[prefix.len()..name.len() - suffix.len()];
+            if let Ok(number) = u64::from_str(number) {
+                listed.push((number, dir.join(&name).to_string_lossy().into_owned()));
+            }
+        }
+    }
+
+    listed.sort();
+    Ok(listed)
+}
+
+// Reads a checkpoint's manifest.
+fn load(path: &str) -> io::Result<Manifest> {
+    let mut body = Vec::new();
+    File::open(Path::new(path).join("manifest.toml"))?.read_to_end(&mut body)?;
+    from_utf8(&body)
+        .ok()
+        .and_then(|body| toml::from_str(body).ok())
+        .ok_or(io::Error::new(io::ErrorKind::InvalidData, "malformed manifest"))
+}
+
+// Returns the name of the file a table is written to in a checkpoint.
+fn table_file(tenant: TenantId, table: TableId) -> String {
+    format!("{}-{}.snap", tenant, table)
+}
+
+// Writes the lower `n` bytes of a value into a buffer, least significant byte first.
+fn push_le(buf: &mut Vec<u8>, val: u64, n: usize) {
+    for i in 0..n {
+        buf.push((val >> (8 * i)) as u8);
+    }
+}
+
+// Reads `n` bytes off a buffer into a value, least significant byte first.
+fn read_le(buf: &[u8], n: usize) -> u64 {
+    let mut val = 0;
+    for i in 0..n {
+        val |= (buf[i] as u64) << (8 * i);
+    }
+
+    return val;
+}
+
+// This module contains unit tests for the write-ahead log.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Returns a record of a put.
+    fn put<'a>(tenant: TenantId, key: &'a [u8], val: &'a [u8]) -> Record<'a> {
+        Record {
+            op: replica::OP_PUT,
+            tenant: tenant,
+            table: 1,
+            key: key,
+            val: val,
+        }
+    }
+
+    // This unit test verifies that entries decode as encoded, and that torn or corrupt entries
+    // are rejected.
+    #[test]
+    fn test_entries() {
+        let mut buf = Vec::new();
+        encode(&mut buf, 7, 0, &put(1, b"apple", b"red"));
+        encode(&mut buf, 8, FLAG_SEALED, &put(2, b"kiwi", b""));
+
+        let (lsn, flags, rec, len) = decode(&buf).unwrap();
+        assert_eq!((7, 0, 1), (lsn, flags, rec.tenant));
+        assert_eq!((&b"apple"[..], &b"red"[..]), (rec.key, rec.val));
+
+        let (lsn, flags, rec, second) = decode(&buf[len..]).unwrap();
+        assert_eq!((8, FLAG_SEALED, 2), (lsn, flags, rec.tenant));
+        assert_eq!(buf.len(), len + second);
+
+        assert!(decode(&buf[..len - 1]).is_none());
+        let mut corrupt = buf.clone();
+        corrupt[ENTRY_HDR_LEN + 2] ^= 1;
+        assert!(decode(&corrupt).is_none());
+        assert!(decode(&corrupt[len..]).is_some());
+    }
+
+    // This unit test verifies that files are listed by the number in their name.
+    #[test]
+    fn test_listed() {
+        let dir = ::std::env::temp_dir().join(format!("splinter-wal-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for name in ["wal-10.log", "wal-9.log", "wal-x.log", "wal-3.log.corrupt", "x"].iter() {
+            File::create(dir.join(name)).unwrap();
+        }
+
+        let found = listed(&dir, "wal-", ".log").unwrap();
+        assert_eq!(vec![9, 10], found.iter().map(|&(n, _)| n).collect::<Vec<u64>>());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -65,15 +65,283 @@ pub enum OpCode {
     /// This operation fetches multiple records in a single round trip.
     SandstormMultiGetRpc = 0x05,
 
+    /// This operation carries a sequence of gets and puts executed in a single round trip.
+    SandstormMultiOpRpc = 0x06,
+
+    /// This operation removes a key-value pair from the database. It is only carried as an
+    /// operation on a multiop() request.
+    SandstormDeleteRpc = 0x07,
+
+    /// This operation lists a tenant's tables. Received on the install() TCP endpoint.
+    SandstormTablesRpc = 0x08,
+
+    /// This operation fetches counts of the tenants, tables and objects at the server. Received
+    /// on the install() TCP endpoint.
+    SandstormStatsRpc = 0x09,
+
+    /// This operation creates, suspends, resumes or deletes a tenant at runtime. Received on the
+    /// install() TCP endpoint.
+    SandstormTenantRpc = 0x0a,
+
+    /// This operation fetches a tenant's records from the audit log. Received on the install()
+    /// TCP endpoint.
+    SandstormAuditRpc = 0x0b,
+
+    /// This operation fetches a snapshot of the tasks on every scheduler. Received on the
+    /// install() TCP endpoint.
+    SandstormQueuesRpc = 0x0c,
+
+    /// This operation starts or stops the server's sampling profiler. Received on the install()
+    /// TCP endpoint.
+    SandstormProfileRpc = 0x0d,
+
+    /// This operation fetches the most recent requests that took longer than the slow request
+    /// threshold. Received on the install() TCP endpoint.
+    SandstormSlowRpc = 0x0e,
+
+    /// This operation reports where the server's memory went: the heap, packet pools, tenants
+    /// and tables. Received on the install() TCP endpoint.
+    SandstormMemoryRpc = 0x0f,
+
+    /// This operation reads the config the server runs with, or changes fields of it that can
+    /// change at runtime. Received on the install() TCP endpoint.
+    SandstormConfigRpc = 0x10,
+
+    /// This operation checks that every core is making progress, and that the NICs and memory
+    /// are fit to serve requests. Received on the install() TCP endpoint.
+    SandstormHealthRpc = 0x11,
+
+    /// This operation ships writes from a primary to it's backup, reports on replication, or
+    /// promotes a standby to a primary. Received on the install() TCP endpoint.
+    SandstormReplicaRpc = 0x12,
+
+    /// This operation migrates a tenant to another server, reports on a migration, or carries a
+    /// migrating tenant's data to the server it moves to. Received on the install() TCP
+    /// endpoint.
+    SandstormMigrateRpc = 0x13,
+
+    /// This operation reads a partitioned tenant's routing table, or splits, merges or
+    /// reassigns it's ranges. Received on the install() TCP endpoint.
+    SandstormPartitionRpc = 0x14,
+
+    /// This operation exports a table to a snapshot file, or imports one into a table. Received
+    /// on the install() TCP endpoint.
+    SandstormSnapshotRpc = 0x15,
+
+    /// This operation takes a backup of every table to object storage, reports on backups, or
+    /// restores one. Received on the install() TCP endpoint.
+    SandstormBackupRpc = 0x16,
+
+    /// This operation reports what the server knows about every other member of the cluster.
+    /// Received on the install() TCP endpoint.
+    SandstormMembersRpc = 0x17,
+
     /// Any value beyond this represents an invalid rpc.
-    InvalidOperation = 0x06,
+    InvalidOperation = 0x18,
+}
+
+/// The action carried by a config() RPC, in the byte right after it's RpcRequestHeader.
+#[repr(u8)]
+#[derive(PartialEq, Clone, Copy)]
+pub enum ConfigAction {
+    /// Read the config. The response carries it as TOML.
+    Get = 0x01,
+
+    /// Change fields of the config. The action is followed by the operator's credential (u64,
+    /// little endian) and TOML assigning the new values. The response carries a line per field
+    /// that changed, or every problem with the changes.
+    Set = 0x02,
+}
+
+/// The action carried by a replica() RPC, in the byte right after it's RpcRequestHeader. Every
+/// action but Status is followed by a credential (u64, little endian), the `peer_credential`
+/// servers share or the operator's, ahead of it's arguments.
+#[repr(u8)]
+#[derive(PartialEq, Clone, Copy)]
+pub enum ReplicaAction {
+    /// Report on replication at the server. The response carries a line of JSON.
+    Status = 0x01,
+
+    /// Promote a standby to a primary, so that it accepts writes and stops accepting records.
+    Promote = 0x02,
+
+    /// Apply records shipped by a primary. The credential is followed by the epoch of the
+    /// primary's log (u64), the sequence number of the first record (u64) and the number of
+    /// records (u32), little endian, and then by the records as encoded by `replica::encode()`.
+    /// Only accepted by a standby.
+    Apply = 0x03,
+
+    /// Apply records fed by a primary to a read replica. The credential is followed by a byte
+    /// that is 1 if the batch left the primary with nothing more to feed the replica and 0
+    /// otherwise, and then by the same arguments and records as an Apply. Only accepted by a
+    /// read replica.
+    Feed = 0x04,
+
+    /// Apply writes shipped from another site to a geo-replicated tenant. The credential is
+    /// followed by the same arguments as an Apply, and then by entries as encoded by
+    /// `geo::encode()`. Only accepted by a primary, from a server in it's `geo_peers`.
+    Geo = 0x05,
+}
+
+/// The action carried by a migrate() RPC, in the byte right after it's RpcRequestHeader. The
+/// header's tenant is the tenant migrated. Every action is followed by a credential, as a little
+/// endian u64, and then by it's arguments.
+#[repr(u8)]
+#[derive(PartialEq, Clone, Copy)]
+pub enum MigrateAction {
+    /// Start migrating the tenant to another server. The credential is the tenant's or the
+    /// operator's, and is followed by the address of that server's install() TCP endpoint, as
+    /// UTF-8.
+    Start = 0x01,
+
+    /// Report on the last migration started at the server. The credential is the tenant's or the
+    /// operator's. The response carries a line of JSON.
+    Status = 0x02,
+
+    /// Sent to the server a tenant moves to before it's data is, to check that it has the
+    /// tenant. The credential is the `peer_credential` servers share or the operator's. The
+    /// response carries the IP address the server takes requests on, as UTF-8.
+    Prepare = 0x03,
+
+    /// Apply records of the tenant's data, sent to the server it moves to. The credential is the
+    /// `peer_credential` servers share or the operator's, and is followed by the records as
+    /// encoded by `replica::encode()`.
+    Ingest = 0x04,
+}
+
+/// The action carried by a partition() RPC, in the byte right after it's RpcRequestHeader. The
+/// header's tenant is the tenant whose routing table is read or changed. Every action but Show
+/// is followed by the tenant's credential or the operator's (u64, little endian), ahead of it's
+/// arguments. Changes only apply to the server's own copy of the table, and so should be made at
+/// every server holding a range.
+#[repr(u8)]
+#[derive(PartialEq, Clone, Copy)]
+pub enum PartitionAction {
+    /// Read the tenant's routing table. The response carries a line of JSON.
+    Show = 0x01,
+
+    /// Split the range a key falls in at the key. The credential is followed by the IPv4 address
+    /// of the server holding the new range (4 bytes, network order) and the key.
+    Split = 0x02,
+
+    /// Merge the range starting at a key into the one before it. The credential is followed by
+    /// the key.
+    Merge = 0x03,
+
+    /// Hand the range starting at a key to another server. The credential is followed by the
+    /// IPv4 address of the server (4 bytes, network order) and the key.
+    Assign = 0x04,
+}
+
+/// The action carried by a snapshot() RPC, in the byte right after it's RpcRequestHeader. The
+/// action is followed by the credential of the header's tenant or of the operator (u64, little
+/// endian), the identifier of a table of the tenant (u64, little endian) and the path of a
+/// snapshot file relative to the server's `snapshot_dir`, as UTF-8. Snapshots are in the format
+/// described in `snapshot`. The response carries a line of JSON, or on failure a description
+/// of what went wrong.
+#[repr(u8)]
+#[derive(PartialEq, Clone, Copy)]
+pub enum SnapshotAction {
+    /// Write every object of the table to the file, replacing it.
+    Export = 0x01,
+
+    /// Check the file, and then write every object in it to the table, creating the table if
+    /// it does not exist.
+    Import = 0x02,
+}
+
+/// The action carried by a backup() RPC, in the byte right after it's RpcRequestHeader. Backups
+/// are of every tenant, so the header's tenant is only used for auditing. The action is followed
+/// by a credential (u64, little endian), which must be the operator's to start a backup or
+/// restore, and may also be the header tenant's for a report, and then by the action's
+/// arguments. The response carries a line of JSON, or on failure a description of what went
+/// wrong.
+#[repr(u8)]
+#[derive(PartialEq, Clone, Copy)]
+pub enum BackupAction {
+    /// Take a backup. The action is followed by a byte, non-zero if the backup must be a full
+    /// one rather than hold only what changed.
+    Start = 0x01,
+
+    /// Report on the last backup or restore.
+    Status = 0x02,
+
+    /// Restore a backup. The action is followed by the number of the backup (u64, little
+    /// endian), zero for the last one.
+    Restore = 0x03,
+}
+
+/// The action carried by a profile() RPC, in the byte right after it's RpcRequestHeader.
+#[repr(u8)]
+#[derive(PartialEq, Clone, Copy)]
+pub enum ProfileAction {
+    /// Start sampling the stacks of the server's threads. The action is followed by the number
+    /// of samples to take per second of CPU time (u32, zero for the server's default), little
+    /// endian.
+    Start = 0x01,
+
+    /// Stop sampling. The response carries the stacks sampled since the profiler was started,
+    /// folded into one line per distinct stack as flamegraph.pl and inferno expect.
+    Stop = 0x02,
+}
+
+/// The action carried by a tenant() RPC, in the byte right after it's RpcRequestHeader.
+#[repr(u8)]
+#[derive(PartialEq, Clone, Copy)]
+pub enum TenantAction {
+    /// Create a tenant along with it's default tables. The action is followed by the tenant's
+    /// credential (u64, not zero), the number of bytes an invocation may allocate (u64, zero for
+    /// the server's default), the number of bytes the tenant's objects may take up (u64, zero for
+    /// no limit), the number of tables (u32), and the identifier of each table (u64), all little
+    /// endian. The two quotas can only tighten those of the tenant's service tier, or the
+    /// server's defaults if it is in none.
+    Create = 0x01,
+
+    /// Stop executing requests on behalf of the tenant. The action is followed by the tenant's
+    /// credential (u64), or the operator's.
+    Suspend = 0x02,
+
+    /// Resume executing requests on behalf of a suspended tenant. The action is followed by the
+    /// tenant's credential (u64), or the operator's, which a tenant the operator suspended can
+    /// only be resumed with.
+    Resume = 0x03,
+
+    /// Remove the tenant and all of it's tables. The action is followed by the tenant's
+    /// credential (u64).
+    Delete = 0x04,
+
+    /// Replace the access another tenant is granted on one of the tenant's tables, mounting the
+    /// table into the other tenant's namespace under the same identifier. The action is followed
+    /// by the tenant's credential (u64), the table (u64), the other tenant (u32), and the access
+    /// granted (u8, a combination of the `table::ACCESS_*` bits, zero to revoke access).
+    Grant = 0x05,
+
+    /// Replace the key the tenant's values are encrypted with when written out of memory. The
+    /// action is followed by the tenant's credential (u64) and the key (32 bytes), or by nothing
+    /// else if values should no longer be encrypted. The key travels in the clear, so install()
+    /// TCP endpoints should only be reachable from a trusted network.
+    Key = 0x06,
+
+    /// Drain the tenant: stop executing requests on it's behalf, wait for those already being
+    /// executed to complete, then unload it's extensions and remove it along with all of it's
+    /// tables. The action is followed by the tenant's credential (u64). The response carries the
+    /// number of extensions unloaded, tables freed and objects freed as little endian u64s once
+    /// the drain completes.
+    Drain = 0x07,
+
+    /// Publish one of the tenant's tables, mounting it into the namespace of every other tenant,
+    /// present and future, under the same identifier, with read access only. The action is
+    /// followed by the tenant's credential (u64), the table (u64), and whether the table is
+    /// published (u8, one) or withdrawn (u8, zero). A tenant with a table of it's own under the
+    /// same identifier keeps seeing it's own table.
+    Publish = 0x08,
 }
 
 /// This enum represents the status of a completed RPC. A status of 'StatusOk'
 /// means that the RPC completed successfully, and that the payload on the
 /// response can be safely read and interpreted.
 #[repr(u8)]
-#[derive(PartialEq, Clone)]
+#[derive(PartialEq, Clone, Copy, Debug)]
 pub enum RpcStatus {
     /// The RPC completed successfully. The response can be safely unpacked
     /// at the client.
@@ -106,6 +374,83 @@ pub enum RpcStatus {
     /// The RPC failed at the server because it requested for an
     /// invalid/unsupported operation.
     StatusInvalidOperation = 0x08,
+
+    /// The RPC was not executed at the server because it's source exceeded
+    /// it's rate limit. The response consists of only an RpcResponseHeader.
+    StatusRateLimited = 0x09,
+
+    /// The RPC was not executed at the server because the tenant that sent it
+    /// is suspended. The response consists of only an RpcResponseHeader.
+    StatusTenantSuspended = 0x0a,
+
+    /// The RPC failed at the server because it did not carry the credential
+    /// of the tenant it operates on.
+    StatusPermissionDenied = 0x0b,
+
+    /// The RPC failed at the server because writing the object would take
+    /// the owner of the table over it's memory quota.
+    StatusMemoryExhausted = 0x0c,
+
+    /// The RPC invoked an extension that tried to allocate more bytes on the
+    /// table heap than a single invocation can.
+    StatusAllocExhausted = 0x0d,
+
+    /// The RPC did not complete because tasks were still being executed on
+    /// behalf of the tenant it operates on. It can be retried.
+    StatusTenantBusy = 0x0e,
+
+    /// The RPC failed at the server because installing the extension would
+    /// take the tenant over the number of extensions it can install.
+    StatusTooManyExtensions = 0x0f,
+
+    /// The RPC failed at the server because installing the extension would
+    /// take the tenant over the total size of the extensions it can install.
+    StatusExtensionsTooLarge = 0x10,
+
+    /// The RPC was not executed because the server is a standby, which refuses writes until it
+    /// is promoted, or a read replica, which only answers reads that tolerate how far behind the
+    /// primary it is. A standby's response consists of only an RpcResponseHeader; a read
+    /// replica's carries the number of milliseconds it is behind by (u32, little endian) after
+    /// the header, u32::MAX if it has never caught up.
+    StatusNotPrimary = 0x11,
+
+    /// The RPC was not executed because it reads or writes a Raft-replicated table, and the
+    /// server is not the leader of the group replicating it, or stopped being the leader before
+    /// the write was committed. The response consists of only an RpcResponseHeader.
+    StatusNotLeader = 0x12,
+
+    /// The RPC was not executed because the tenant was migrated to another server. The
+    /// RpcResponseHeader is followed by the IPv4 address of that server (4 bytes, network order).
+    StatusTenantMoved = 0x13,
+
+    /// The RPC was not executed because it reads or writes a key of a partitioned tenant that
+    /// another server holds. The RpcResponseHeader is followed by the tenant's routing table, as
+    /// encoded by `partition::Map::encode()`.
+    StatusWrongPartition = 0x14,
+
+    /// The RPC was not executed because the server is draining before it shuts down. The
+    /// response consists of only an RpcResponseHeader. The RPC can be retried once the server
+    /// is back, or against another server.
+    StatusDraining = 0x15,
+
+    /// The RPC was not executed because it's response would not fit in a single UDP datagram,
+    /// the value it reads being too large. The response consists of only the response header
+    /// for the RPC.
+    StatusValueTooLarge = 0x16,
+
+    /// The RPC was not executed because it's deadline passed before the server got to it. The
+    /// response consists of only an RpcResponseHeader. The client has given up on the RPC by
+    /// then, so it should not be retried.
+    StatusDeadlineExceeded = 0x17,
+
+    /// The RPC was not executed because it carried a key longer than the server's
+    /// `max_key_len`. The response consists of only an RpcResponseHeader.
+    StatusKeyTooLong = 0x18,
+
+    /// The RPC invoked an extension that pushed the invocation back to the tenant, to be run at
+    /// the client. The InvokeResponse is followed by the state the extension captured, which the
+    /// client's `Executor` resumes the extension with.
+    StatusPushback = 0x19,
 }
 
 /// This type represents the request header on a typical remote procedure call
@@ -188,8 +533,62 @@ pub struct RpcResponseHeader {
 
     /// Identifier of the RPC request this response is being generated for.
     pub stamp: u64,
+
+    /// Flags set by the server on the way out (ex: RESPONSE_FLAG_CONGESTED).
+    pub flags: u8,
 }
 
+/// Set on the `flags` of an RpcResponseHeader when the server's queues were building up at the
+/// time the response was sent. Much like an ECN congestion experienced mark, this tells clients
+/// to back off before the server has to start dropping requests.
+pub const RESPONSE_FLAG_CONGESTED: u8 = 0x01;
+
+/// The longest key a request can carry, and an object can be stored under. Both describe the
+/// length of their key in 16 bits.
+pub const MAX_KEY_LEN: usize = 0xffff;
+
+/// Set on the `service` byte of an RpcRequestHeader when the request carries a trace context,
+/// in the last TRACE_CONTEXT_LEN bytes of it's payload. The server strips both before
+/// dispatching the request, and records a span for it (see `span`).
+pub const REQUEST_FLAG_TRACED: u8 = 0x80;
+
+/// Set on the `service` byte of an RpcRequestHeader when a get, multiget or invoke can be
+/// answered by a read replica, out of data that is behind the primary's. The request carries
+/// the number of milliseconds behind it is willing to read at (u32, little endian) in the last
+/// STALENESS_BOUND_LEN bytes of it's payload, ahead of any deadline and trace context. The
+/// server strips all of them before executing the request (see `readrep`).
+pub const REQUEST_FLAG_STALE_OK: u8 = 0x40;
+
+/// The number of bytes of staleness bound at the end of a request flagged REQUEST_FLAG_STALE_OK.
+pub const STALENESS_BOUND_LEN: usize = 4;
+
+/// Set on the `service` byte of an RpcRequestHeader when the request carries a deadline: the
+/// number of microseconds the client will wait for a response to it (u32, little endian), in the
+/// DEADLINE_BUDGET_LEN bytes of it's payload right after any staleness bound, and ahead of any
+/// trace context. The server strips both before dispatching the request, and does not start
+/// work on it once the budget has run out (see `deadline`).
+pub const REQUEST_FLAG_DEADLINE: u8 = 0x20;
+
+/// The number of bytes of budget at the end of a request flagged REQUEST_FLAG_DEADLINE.
+pub const DEADLINE_BUDGET_LEN: usize = 4;
+
+/// The bits of the `service` byte of an RpcRequestHeader carrying the priority of the request
+/// within it's tenant's share of the server, from zero, the default, upto MAX_REQUEST_PRIORITY.
+/// Of the tasks a tenant has waiting to run, those at a higher priority run first. The server
+/// clears the bits before dispatching the request.
+pub const REQUEST_PRIORITY_MASK: u8 = 0x18;
+
+/// The number of bits the priority of a request is shifted up by within REQUEST_PRIORITY_MASK.
+pub const REQUEST_PRIORITY_SHIFT: u8 = 3;
+
+/// The highest priority a request can carry.
+pub const MAX_REQUEST_PRIORITY: u8 = 3;
+
+/// The number of bytes of trace context at the end of a traced request: the identifier of the
+/// trace (16 bytes), followed by that of the span the request was issued under at the client
+/// (8 bytes), both in the byte order they are written in on a W3C `traceparent` header.
+pub const TRACE_CONTEXT_LEN: usize = 24;
+
 impl RpcResponseHeader {
     /// This method returns a header of type RpcResponseHeader that can be
     /// added to an RPC response. The status on the header is set to StatusOk.
@@ -199,13 +598,14 @@ impl RpcResponseHeader {
     /// - `tenant`:     The tenant this response should be sent to.
     ///
     /// - `return`: A header of type RpcResponseHeader with the status field
-    ///             set to RpcStatus::StatusOk.
+    ///             set to RpcStatus::StatusOk, and no flags set.
     pub fn new(req_stamp: u64, opcode: OpCode, tenant: u32) -> RpcResponseHeader {
         RpcResponseHeader {
             status: RpcStatus::StatusOk,
             opcode: opcode,
             tenant: tenant,
             stamp: req_stamp,
+            flags: 0,
         }
     }
 }
@@ -831,3 +1231,404 @@ impl EndOffset for MultiGetResponse {
         true
     }
 }
+
+/// This type represents the RPC header on a multiop() request. The payload consists of
+/// `num_ops` operations, each encoded by `multiop::push_entry()`. Operations are executed in
+/// order, and can be on different tables.
+#[repr(C, packed)]
+pub struct MultiOpRequest {
+    /// Generic RPC header consisting of service, opcode, and tenant id.
+    pub common_header: RpcRequestHeader,
+
+    /// The number of operations in the request's payload.
+    pub num_ops: u32,
+}
+
+// Implementation of methods on MultiOpRequest.
+impl MultiOpRequest {
+    /// Constructs an RPC header that can be added to the multiop() request. The header is of type
+    /// `MultiOpRequest`.
+    ///
+    /// # Arguments
+    ///
+    /// * `tenant`: Identifier of the tenant sending the request.
+    /// * `n_ops`:  The number of operations in the request.
+    /// * `stamp`:  Identifier of the RPC. Can be used as a timestamp.
+    pub fn new(tenant: u32, n_ops: u32, stamp: u64) -> MultiOpRequest {
+        MultiOpRequest {
+            common_header: RpcRequestHeader::new(
+                Service::MasterService,
+                OpCode::SandstormMultiOpRpc,
+                tenant,
+                stamp,
+            ),
+            num_ops: n_ops,
+        }
+    }
+}
+
+// Implementation of the EndOffset trait for MultiOpRequest. Refer to
+// GetRequest's implementation of this trait to understand what the methods
+// and types mean.
+impl EndOffset for MultiOpRequest {
+    type PreviousHeader = UdpHeader;
+
+    fn offset(&self) -> usize {
+        size_of::<MultiOpRequest>()
+    }
+
+    fn size() -> usize {
+        size_of::<MultiOpRequest>()
+    }
+
+    fn payload_size(&self, hint: usize) -> usize {
+        hint - self.offset()
+    }
+
+    fn check_correct(&self, _prev: &Self::PreviousHeader) -> bool {
+        true
+    }
+}
+
+/// This type represents the response header for a multiop() RPC request. The payload consists
+/// of `num_results` results, each encoded by `multiop::push_result()`, for the first
+/// `num_results` operations on the request. Operations without a result were not executed,
+/// because their result would not have fit into the response.
+#[repr(C, packed)]
+pub struct MultiOpResponse {
+    /// Generic response header consisting of RPC status and identifier.
+    pub common_header: RpcResponseHeader,
+
+    /// Number of results in the response.
+    pub num_results: u32,
+}
+
+// Implementation of methods on MultiOpResponse.
+impl MultiOpResponse {
+    /// Constructs a response header for the multiop() RPC. The header is of type
+    /// `MultiOpResponse`.
+    ///
+    /// # Arguments
+    ///
+    /// * `stamp`:     RPC identifier. Can be used to timestamp the RPC.
+    /// * `tenant`:    The tenant this response should be sent to.
+    /// * `n_results`: Number of results being returned in the response.
+    pub fn new(stamp: u64, tenant: u32, n_results: u32) -> MultiOpResponse {
+        MultiOpResponse {
+            common_header: RpcResponseHeader::new(stamp, OpCode::SandstormMultiOpRpc, tenant),
+            num_results: n_results,
+        }
+    }
+}
+
+// Implementation of the EndOffset trait for MultiOpResponse. Refer to
+// GetRequest's implementation of this trait to understand what the methods
+// and types mean.
+impl EndOffset for MultiOpResponse {
+    type PreviousHeader = UdpHeader;
+
+    fn offset(&self) -> usize {
+        size_of::<MultiOpResponse>()
+    }
+
+    fn size() -> usize {
+        size_of::<MultiOpResponse>()
+    }
+
+    fn payload_size(&self, hint: usize) -> usize {
+        hint - self.offset()
+    }
+
+    fn check_correct(&self, _prev: &Self::PreviousHeader) -> bool {
+        true
+    }
+}
+
+/// Reads fields off the bytes of a frame or request in place, rather than casting them to one
+/// of the header structs above. Every read is bounds checked, and multi-byte fields are read in
+/// the byte order they are sent in: little-endian for RPC headers, and network order for the
+/// Ethernet, IP and UDP headers in front of them. Parsing this way does not depend on the
+/// alignment of the buffer, the byte order of the host, or the layout of any struct, and never
+/// allocates.
+pub struct Reader<'a> {
+    // The bytes being read.
+    buf: &'a [u8],
+
+    // The offset into `buf` of the next byte to be read.
+    off: usize,
+}
+
+// Implementation of methods on Reader.
+impl<'a> Reader<'a> {
+    /// Returns a reader over a buffer, positioned at it's first byte.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf`: The bytes to read fields off.
+    pub fn new(buf: &'a [u8]) -> Reader<'a> {
+        Reader::at(buf, 0)
+    }
+
+    /// Returns a reader over a buffer, positioned at an offset into it.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf`: The bytes to read fields off.
+    /// * `off`: The offset of the first field to be read. Can be past the end of the buffer, in
+    ///          which case every read fails.
+    pub fn at(buf: &'a [u8], off: usize) -> Reader<'a> {
+        Reader { buf: buf, off: off }
+    }
+
+    /// Returns the offset into the buffer of the next byte to be read.
+    pub fn offset(&self) -> usize {
+        self.off
+    }
+
+    /// Reads a number of bytes as they are.
+    ///
+    /// # Arguments
+    ///
+    /// * `len`: The number of bytes to read.
+    ///
+    /// # Return
+    ///
+    /// The bytes, or None if the buffer ends before them. The reader does not move if so.
+    pub fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let end = self.off.checked_add(len)?;
+        let bytes = self.buf.get(self.off..end)?;
+        self.off = end;
+        Some(bytes)
+    }
+
+    /// Skips over a number of bytes, failing like `bytes()` if the buffer ends before them.
+    pub fn skip(&mut self, len: usize) -> Option<()> {
+        self.bytes(len).map(|_| ())
+    }
+
+    /// Reads a byte.
+    pub fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    /// Reads a little-endian u16.
+    pub fn u16_le(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| b.iter().rev().fold(0, |acc, &b| (acc << 8) | b as u16))
+    }
+
+    /// Reads a little-endian u32.
+    pub fn u32_le(&mut self) -> Option<u32> {
+        self.bytes(4).map(|b| b.iter().rev().fold(0, |acc, &b| (acc << 8) | b as u32))
+    }
+
+    /// Reads a little-endian u64.
+    pub fn u64_le(&mut self) -> Option<u64> {
+        self.bytes(8).map(|b| b.iter().rev().fold(0, |acc, &b| (acc << 8) | b as u64))
+    }
+
+    /// Reads a u16 in network byte order.
+    pub fn u16_be(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| b.iter().fold(0, |acc, &b| (acc << 8) | b as u16))
+    }
+
+    /// Reads a u32 in network byte order.
+    pub fn u32_be(&mut self) -> Option<u32> {
+        self.bytes(4).map(|b| b.iter().fold(0, |acc, &b| (acc << 8) | b as u32))
+    }
+}
+
+/// Reads the tenant and identifier off the RpcRequestHeader at the start of a request, leaving
+/// the reader right after the header.
+///
+/// # Arguments
+///
+/// * `reader`: A reader positioned at the start of the request.
+///
+/// # Return
+///
+/// The tenant and identifier on the header, or None if the request is too short to carry one.
+pub fn read_request_header(reader: &mut Reader) -> Option<(u32, u64)> {
+    reader.skip(2)?;
+    let tenant = reader.u32_le()?;
+    let stamp = reader.u64_le()?;
+    Some((tenant, stamp))
+}
+
+/// The fields on the header of a get(), put() or multiget() request, as read off the bytes of
+/// the request by `KeyedRequest::read()`. The request's keys follow the header.
+pub struct KeyedRequest {
+    /// The tenant that sent the request.
+    pub tenant: u32,
+
+    /// The identifier of the request.
+    pub stamp: u64,
+
+    /// The table the request is on.
+    pub table_id: u64,
+
+    /// The length of the request's key, or of every one of a multiget()'s keys.
+    pub key_length: usize,
+
+    /// The number of keys the request carries, one for a get() or put().
+    pub num_keys: u32,
+
+    /// The number of bytes of header in front of the keys.
+    pub header_length: usize,
+}
+
+// Implementation of methods on KeyedRequest.
+impl KeyedRequest {
+    /// Reads the header of a get(), put() or multiget() request.
+    ///
+    /// # Arguments
+    ///
+    /// * `payload`: The request, from it's RpcRequestHeader on.
+    ///
+    /// # Return
+    ///
+    /// The fields on the header, or None if the request is not one of the three, or is too
+    /// short to carry it's header.
+    pub fn read(payload: &[u8]) -> Option<KeyedRequest> {
+        let opcode = *payload.get(1)?;
+        let mut reader = Reader::new(payload);
+        let (tenant, stamp) = read_request_header(&mut reader)?;
+        let table_id = reader.u64_le()?;
+        let key_length = reader.u16_le()? as usize;
+        let num_keys = match opcode {
+            op if op == OpCode::SandstormGetRpc as u8 => 1,
+            op if op == OpCode::SandstormPutRpc as u8 => 1,
+            op if op == OpCode::SandstormMultiGetRpc as u8 => reader.u32_le()?,
+            _ => return None,
+        };
+
+        Some(KeyedRequest {
+            tenant: tenant,
+            stamp: stamp,
+            table_id: table_id,
+            key_length: key_length,
+            num_keys: num_keys,
+            header_length: reader.offset(),
+        })
+    }
+}
+
+/// The fields on the header of an invoke() or install() request, as read off the bytes of the
+/// request by `NamedRequest::read()`. The name of the extension follows the header, and then
+/// it's arguments or the extension itself.
+pub struct NamedRequest {
+    /// The tenant that sent the request.
+    pub tenant: u32,
+
+    /// The identifier of the request.
+    pub stamp: u64,
+
+    /// The length of the extension's name.
+    pub name_length: usize,
+
+    /// The length of the arguments to the extension, or of the extension being installed.
+    pub body_length: usize,
+
+    /// The number of bytes of header in front of the name.
+    pub header_length: usize,
+}
+
+// Implementation of methods on NamedRequest.
+impl NamedRequest {
+    /// Reads the header of an invoke() or install() request. Both headers have the same layout.
+    ///
+    /// # Arguments
+    ///
+    /// * `payload`: The request, from it's RpcRequestHeader on.
+    ///
+    /// # Return
+    ///
+    /// The fields on the header, or None if the request is too short to carry one.
+    pub fn read(payload: &[u8]) -> Option<NamedRequest> {
+        let mut reader = Reader::new(payload);
+        let (tenant, stamp) = read_request_header(&mut reader)?;
+        let name_length = reader.u32_le()? as usize;
+        let body_length = reader.u32_le()? as usize;
+
+        Some(NamedRequest {
+            tenant: tenant,
+            stamp: stamp,
+            name_length: name_length,
+            body_length: body_length,
+            header_length: reader.offset(),
+        })
+    }
+}
+
+// This module contains unit tests for reading request headers off their bytes.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::slice;
+
+    // Returns the bytes of a header, the way they are laid out on a request.
+    fn bytes<T>(hdr: &T) -> Vec<u8> {
+        unsafe { slice::from_raw_parts(hdr as *const T as *const u8, size_of::<T>()).to_vec() }
+    }
+
+    // This unit test verifies that the fields read off the bytes of get(), put(), multiget()
+    // and invoke() requests match those of the structs they were built from, and that a request
+    // too short to carry it's header is not read.
+    #[test]
+    fn test_read() {
+        let get = bytes(&GetRequest::new(7, 9, 30, 11));
+        let hdr = KeyedRequest::read(&get).unwrap();
+        assert_eq!((7, 11, 9), (hdr.tenant, hdr.stamp, hdr.table_id));
+        assert_eq!((30, 1, get.len()), (hdr.key_length, hdr.num_keys, hdr.header_length));
+
+        let put = bytes(&PutRequest::new(7, 9, 30, 11));
+        let hdr = KeyedRequest::read(&put).unwrap();
+        assert_eq!((30, 1, put.len()), (hdr.key_length, hdr.num_keys, hdr.header_length));
+
+        let multi = bytes(&MultiGetRequest::new(7, 9, 30, 5, 11));
+        let hdr = KeyedRequest::read(&multi).unwrap();
+        assert_eq!((30, 5, multi.len()), (hdr.key_length, hdr.num_keys, hdr.header_length));
+
+        let invoke = bytes(&InvokeRequest::new(7, 3, 40, 11));
+        let hdr = NamedRequest::read(&invoke).unwrap();
+        assert_eq!((7, 11, 3, 40), (hdr.tenant, hdr.stamp, hdr.name_length, hdr.body_length));
+        assert_eq!(invoke.len(), hdr.header_length);
+
+        for len in 0..multi.len() {
+            assert!(KeyedRequest::read(&multi[..len]).is_none());
+        }
+        for len in 0..invoke.len() {
+            assert!(NamedRequest::read(&invoke[..len]).is_none());
+        }
+
+        // Only get(), put() and multiget() requests carry keys.
+        assert!(KeyedRequest::read(&invoke).is_none());
+    }
+
+    // This unit test fuzzes the readers with random bytes, checking that they never panic, and
+    // that a header read never claims to be longer than the bytes it was read off.
+    #[test]
+    fn test_fuzz() {
+        let mut state = 0x9e3779b97f4a7c15u64;
+        let mut rand = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        for _ in 0..100000 {
+            let len = rand() as usize % 48;
+            let mut buf: Vec<u8> = (0..len).map(|_| rand() as u8).collect();
+            if len > 1 && rand() % 2 == 0 {
+                buf[1] = OpCode::SandstormGetRpc as u8 + (rand() % 6) as u8;
+            }
+
+            if let Some(hdr) = KeyedRequest::read(&buf) {
+                assert!(hdr.header_length <= buf.len());
+            }
+            if let Some(hdr) = NamedRequest::read(&buf) {
+                assert!(hdr.header_length <= buf.len());
+            }
+        }
+    }
+}
@@ -0,0 +1,94 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+
+use bytes::Bytes;
+
+use e2d2::common::EmptyMetadata;
+use e2d2::headers::EndOffset;
+use e2d2::interface::Packet;
+use e2d2::native::zcsi::{mbuf_ext_done, mbuf_free, MBuf};
+
+/// Values smaller than this many bytes are always copied into the response packet. Setting up
+/// and reclaiming an external segment costs more than copying a small value.
+pub const MIN_VALUE_LEN: usize = 512;
+
+/// Set if responses are allowed to reference values in table memory instead of copying them.
+static ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+
+thread_local! {
+    // Values referenced by response packets on this thread that may not have been transmitted
+    // yet, along with the segment referencing each of them. Each value is held here until the NIC
+    // releases it's segment, so that the value cannot be freed while it is being DMA'ed out.
+    static PENDING: RefCell<VecDeque<(*mut MBuf, Bytes)>> = RefCell::new(VecDeque::new());
+}
+
+/// Allows responses to reference values in table memory from here on.
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Returns true if zero-copy responses were enabled.
+#[inline]
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Tries to append a value to a response packet without copying it.
+///
+/// # Arguments
+///
+/// * `res`:   The response packet the value should be appended to. Nothing should be added to
+///            it's payload after this call.
+/// * `value`: A handle to the value in table memory.
+///
+/// # Return
+///
+/// True if the value was appended. If false, the caller should copy the value into the packet.
+pub fn append<T: EndOffset>(res: &mut Packet<T, EmptyMetadata>, value: &Bytes) -> bool {
+    if !enabled() || value.len() < MIN_VALUE_LEN {
+        return false;
+    }
+
+    match unsafe { res.chain_external(&value[..]) } {
+        Some(seg) => {
+            PENDING.with(|pending| pending.borrow_mut().push_back((seg, value.clone())));
+            true
+        }
+
+        None => false,
+    }
+}
+
+/// Releases values referenced by response packets that have since been transmitted. Should be
+/// called periodically on every thread that generates responses.
+pub fn reclaim() {
+    PENDING.with(|pending| {
+        let mut pending = pending.borrow_mut();
+
+        // Responses are transmitted in order, so stop at the first one that is still in flight.
+        while let Some(&(seg, _)) = pending.front() {
+            if unsafe { mbuf_ext_done(seg) } == 0 {
+                break;
+            }
+
+            unsafe { mbuf_free(seg) };
+            pending.pop_front();
+        }
+    });
+}
@@ -0,0 +1,394 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+// The agg() extension computes SUM, AVG, MIN, MAX or COUNT over an unsigned integer field of the
+// objects stored under a range or a list of keys of a table. Unlike the aggregate() benchmark
+// extension, it takes it's arguments typed and checked, and is meant both for use and as a
+// reference for writing extensions that read many objects. The arguments are, little endian:
+//
+//   table (u64) | function (u8) | field offset (u16) | field width (u8) | key length (u16) |
+//   mode (u8) | keys
+//
+// The function is one of `Func`, and the field is `width` bytes (1, 2, 4 or 8) at `offset` into
+// every value, read little endian. In `MODE_RANGE`, the keys are a first key (u64) and a number
+// of keys (u32), and every key is it's number written little endian into it's first eight bytes,
+// with any bytes after those zero, the way the workloads lay keys out. In `MODE_LIST`, the keys
+// are laid out back to back.
+//
+// Keys without an object, and objects too short to hold the field, are skipped. The response is
+// a status (u8), followed on success by the number of objects aggregated (u64) and the result
+// (u64). AVG is rounded down. MIN, MAX and AVG over no objects fail with `EMPTY`, and a SUM that
+// does not fit in a u64 fails with `OVERFLOW`. The extension yields every `YIELD_EVERY` keys, so
+// that long ranges do not hold up other requests.
+
+#![feature(generators)]
+#![feature(generator_trait)]
+#![no_std]
+
+extern crate sandstorm;
+
+#[cfg(test)]
+#[macro_use]
+extern crate std;
+
+use sandstorm::boxed::Box;
+use sandstorm::db::DB;
+use sandstorm::pack::{le, pack};
+use sandstorm::rc::Rc;
+use sandstorm::vec::*;
+use sandstorm::Generator;
+
+/// Status codes for the response to the tenant.
+const SUCCESSFUL: u8 = 0x01;
+const INVALIDARG: u8 = 0x02;
+const EMPTY: u8 = 0x03;
+const OVERFLOW: u8 = 0x04;
+
+/// The keys are a first key and a number of keys.
+const MODE_RANGE: u8 = 0x00;
+
+/// The keys are laid out back to back.
+const MODE_LIST: u8 = 0x01;
+
+/// The number of bytes of arguments ahead of the keys.
+const ARGS_HDR_LEN: usize = 15;
+
+/// The number of keys looked up between yields.
+const YIELD_EVERY: u64 = 64;
+
+/// The aggregate functions.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Func {
+    Sum = 1,
+    Avg = 2,
+    Min = 3,
+    Max = 4,
+    Count = 5,
+}
+
+/// The keys aggregated over.
+#[derive(Debug, PartialEq)]
+enum Keys {
+    /// A first key, and a number of keys.
+    Range(u64, u32),
+
+    /// Keys laid out back to back.
+    List(Vec<u8>),
+}
+
+/// The parsed arguments to the extension.
+#[derive(Debug, PartialEq)]
+struct Request {
+    table: u64,
+    func: Func,
+    offset: usize,
+    width: usize,
+    key_len: usize,
+    keys: Keys,
+}
+
+// Implementation of methods on Request.
+impl Request {
+    /// Parses the arguments to the extension.
+    ///
+    /// # Arguments
+    ///
+    /// * `args`: The arguments, laid out as described at the top of this file.
+    ///
+    /// # Return
+    ///
+    /// The request, or None if the arguments are malformed.
+    fn parse(args: &[u8]) -> Option<Request> {
+        if args.len() < ARGS_HDR_LEN {
+            return None;
+        }
+
+        let func = match args[8] {
+            1 => Func::Sum,
+            2 => Func::Avg,
+            3 => Func::Min,
+            4 => Func::Max,
+            5 => Func::Count,
+            _ => return None,
+        };
+
+        let width = args[11] as usize;
+        let key_len = le(&args[12..14]) as usize;
+        if !(width == 1 || width == 2 || width == 4 || width == 8) || key_len == 0 {
+            return None;
+        }
+
+        let rest = &args[ARGS_HDR_LEN..];
+        let keys = match args[14] {
+            MODE_RANGE if rest.len() == 12 => Keys::Range(le(&rest[..8]), le(&rest[8..]) as u32),
+            MODE_LIST if rest.len() % key_len == 0 => {
+                let mut keys = Vec::with_capacity(rest.len());
+                keys.extend_from_slice(rest);
+                Keys::List(keys)
+            }
+            _ => return None,
+        };
+
+        Some(Request {
+            table: le(&args[..8]),
+            func: func,
+            offset: le(&args[9..11]) as usize,
+            width: width,
+            key_len: key_len,
+            keys: keys,
+        })
+    }
+
+    /// Returns the number of keys aggregated over.
+    fn count(&self) -> u64 {
+        match self.keys {
+            Keys::Range(_, count) => count as u64,
+            Keys::List(ref keys) => (keys.len() / self.key_len) as u64,
+        }
+    }
+
+    /// Writes a key into a buffer `key_len` bytes long.
+    ///
+    /// # Arguments
+    ///
+    /// * `idx`: The index of the key, below `count()`.
+    /// * `key`: The buffer.
+    fn key(&self, idx: u64, key: &mut [u8]) {
+        match self.keys {
+            Keys::Range(first, _) => {
+                let id = first.wrapping_add(idx);
+                for (byte, k) in key.iter_mut().enumerate() {
+                    *k = if byte < 8 { (id >> (8 * byte)) as u8 } else { 0 };
+                }
+            }
+
+            Keys::List(ref keys) => {
+                let start = idx as usize * self.key_len;
+                key.copy_from_slice(&keys[start..start + self.key_len]);
+            }
+        }
+    }
+
+    /// Reads the field out of a value.
+    ///
+    /// # Arguments
+    ///
+    /// * `val`: The value.
+    ///
+    /// # Return
+    ///
+    /// The field, or None if the value is too short to hold it.
+    fn field(&self, val: &[u8]) -> Option<u64> {
+        match val.len() >= self.offset + self.width {
+            true => Some(le(&val[self.offset..self.offset + self.width])),
+            false => None,
+        }
+    }
+}
+
+/// The running state of an aggregate.
+struct Accumulator {
+    func: Func,
+    count: u64,
+    sum: u128,
+    min: u64,
+    max: u64,
+}
+
+// Implementation of methods on Accumulator.
+impl Accumulator {
+    /// Returns an aggregate over no objects yet.
+    fn new(func: Func) -> Accumulator {
+        Accumulator {
+            func: func,
+            count: 0,
+            sum: 0,
+            min: u64::max_value(),
+            max: 0,
+        }
+    }
+
+    /// Adds the field of an object to the aggregate.
+    fn add(&mut self, val: u64) {
+        self.count += 1;
+        self.sum += val as u128;
+        self.min = self.min.min(val);
+        self.max = self.max.max(val);
+    }
+
+    /// Returns the number of objects aggregated and the result, or the status to fail with.
+    fn result(&self) -> Result<(u64, u64), u8> {
+        let empty = self.count == 0;
+        match self.func {
+            Func::Count => Ok((self.count, self.count)),
+            Func::Sum if self.sum > u64::max_value() as u128 => Err(OVERFLOW),
+            Func::Sum => Ok((self.count, self.sum as u64)),
+            _ if empty => Err(EMPTY),
+            Func::Avg => Ok((self.count, (self.sum / self.count as u128) as u64)),
+            Func::Min => Ok((self.count, self.min)),
+            Func::Max => Ok((self.count, self.max)),
+        }
+    }
+}
+
+/// This function serves as the entry to the agg extension.
+///
+/// # Arguments
+///
+/// * `db`: An argument whose type implements the `DB` trait which can be used
+///         to interact with the database.
+///
+/// # Return
+///
+/// A coroutine that can be run inside the database.
+#[no_mangle]
+#[allow(unreachable_code)]
+#[allow(unused_assignments)]
+pub fn init(db: Rc<DB>) -> Box<Generator<Yield = u64, Return = u64>> {
+    Box::new(move || {
+        // The arguments are copied out, so that nothing borrowed from them is held across a
+        // yield.
+        let req = match Request::parse(db.args()) {
+            Some(req) => req,
+            None => {
+                db.resp(pack(&INVALIDARG));
+                return 1;
+            }
+        };
+
+        let mut acc = Accumulator::new(req.func);
+        let mut key = Vec::with_capacity(req.key_len);
+        key.resize(req.key_len, 0);
+        for idx in 0..req.count() {
+            req.key(idx, &mut key);
+            let field = db.get(req.table, &key).and_then(|obj| req.field(obj.read()));
+            if let Some(val) = field {
+                acc.add(val);
+            }
+
+            // Yield down to the database every few keys.
+            if (idx + 1) % YIELD_EVERY == 0 {
+                yield 0;
+            }
+        }
+
+        // The status goes first, followed by the count and result on success.
+        match acc.result() {
+            Ok((count, res)) => {
+                db.resp(pack(&SUCCESSFUL));
+                db.resp(pack(&count));
+                db.resp(pack(&res));
+                return 0;
+            }
+
+            Err(status) => {
+                db.resp(pack(&status));
+                return 1;
+            }
+        }
+
+        // XXX: This yield is required to get the compiler to compile this closure into a
+        // generator. It is unreachable and benign.
+        yield 0;
+    })
+}
+
+// This module contains unit tests for the agg() extension.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Returns the arguments aggregating a field over keys in a mode.
+    fn args(func: u8, offset: u16, width: u8, key_len: u16, mode: u8, keys: &[u8]) -> Vec<u8> {
+        let mut args = Vec::new();
+        args.extend_from_slice(&[7, 0, 0, 0, 0, 0, 0, 0, func]);
+        args.extend_from_slice(&[offset as u8, (offset >> 8) as u8, width]);
+        args.extend_from_slice(&[key_len as u8, (key_len >> 8) as u8, mode]);
+        args.extend_from_slice(keys);
+        args
+    }
+
+    // This unit test verifies that well formed arguments are parsed, and malformed ones are not.
+    #[test]
+    fn test_parse() {
+        let range = [1, 1, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0];
+        let req = Request::parse(&args(2, 4, 8, 30, MODE_RANGE, &range)).unwrap();
+        assert_eq!(7, req.table);
+        assert_eq!(Func::Avg, req.func);
+        assert_eq!((4, 8, 30), (req.offset, req.width, req.key_len));
+        assert_eq!(Keys::Range(257, 3), req.keys);
+        assert_eq!(3, req.count());
+
+        let req = Request::parse(&args(5, 0, 1, 2, MODE_LIST, &[1, 2, 3, 4])).unwrap();
+        assert_eq!(Keys::List(vec![1, 2, 3, 4]), req.keys);
+        assert_eq!(2, req.count());
+
+        assert!(Request::parse(&args(6, 0, 1, 2, MODE_LIST, &[1, 2])).is_none());
+        assert!(Request::parse(&args(1, 0, 3, 2, MODE_LIST, &[1, 2])).is_none());
+        assert!(Request::parse(&args(1, 0, 1, 0, MODE_LIST, &[])).is_none());
+        assert!(Request::parse(&args(1, 0, 1, 2, MODE_LIST, &[1, 2, 3])).is_none());
+        assert!(Request::parse(&args(1, 0, 1, 2, MODE_RANGE, &range[..8])).is_none());
+        assert!(Request::parse(&args(1, 0, 1, 2, 2, &[1, 2])).is_none());
+        assert!(Request::parse(&[7; ARGS_HDR_LEN - 1]).is_none());
+    }
+
+    // This unit test verifies that keys in a range are laid out the way the workloads lay them
+    // out, that keys in a list are handed out in order, and that fields are read little endian.
+    #[test]
+    fn test_keys() {
+        let range = [0xff, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0];
+        let req = Request::parse(&args(1, 1, 2, 10, MODE_RANGE, &range)).unwrap();
+        let mut key = vec![0xaa; 10];
+        req.key(1, &mut key);
+        assert_eq!(vec![0, 1, 0, 0, 0, 0, 0, 0, 0, 0], key);
+
+        let req = Request::parse(&args(1, 1, 2, 2, MODE_LIST, &[1, 2, 3, 4])).unwrap();
+        let mut key = vec![0; 2];
+        req.key(1, &mut key);
+        assert_eq!(vec![3, 4], key);
+
+        assert_eq!(Some(0x0302), req.field(&[1, 2, 3]));
+        assert_eq!(None, req.field(&[1, 2]));
+    }
+
+    // This unit test verifies the result of every function, over objects and over none.
+    #[test]
+    fn test_result() {
+        let fold = |func: Func, vals: &[u64]| {
+            let mut acc = Accumulator::new(func);
+            for &val in vals.iter() {
+                acc.add(val);
+            }
+            acc.result()
+        };
+
+        let vals = [4, 9, 2];
+        assert_eq!(Ok((3, 15)), fold(Func::Sum, &vals));
+        assert_eq!(Ok((3, 5)), fold(Func::Avg, &vals));
+        assert_eq!(Ok((3, 2)), fold(Func::Min, &vals));
+        assert_eq!(Ok((3, 9)), fold(Func::Max, &vals));
+        assert_eq!(Ok((3, 3)), fold(Func::Count, &vals));
+
+        assert_eq!(Ok((0, 0)), fold(Func::Sum, &[]));
+        assert_eq!(Ok((0, 0)), fold(Func::Count, &[]));
+        assert_eq!(Err(EMPTY), fold(Func::Avg, &[]));
+        assert_eq!(Err(EMPTY), fold(Func::Min, &[]));
+        assert_eq!(Err(EMPTY), fold(Func::Max, &[]));
+
+        let big = [u64::max_value(), u64::max_value()];
+        assert_eq!(Err(OVERFLOW), fold(Func::Sum, &big));
+        assert_eq!(Ok((2, u64::max_value())), fold(Func::Avg, &big));
+    }
+}
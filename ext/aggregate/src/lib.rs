@@ -22,6 +22,7 @@ use sandstorm::boxed::Box;
 use sandstorm::db::DB;
 use sandstorm::pack::pack;
 use sandstorm::rc::Rc;
+use sandstorm::simd::checksum;
 use sandstorm::size_of;
 use sandstorm::vec::*;
 use sandstorm::Generator;
@@ -68,7 +69,14 @@ fn dispatch(db: Rc<DB>) {
     let arg: &[u8] = db.args();
     let (t, val) = arg.split_at(size_of::<u64>());
     let (n, val) = val.split_at(size_of::<u32>());
-    let (o, key) = val.split_at(size_of::<u32>());
+    let (o, val) = val.split_at(size_of::<u32>());
+
+    // The key is optionally followed by the number of rounds to checksum every record with.
+    if val.len() < KEYLENGTH as usize {
+        db.resp(pack(&INVALIDARG));
+        return;
+    }
+    let (key, r) = val.split_at(KEYLENGTH as usize);
 
     // Get the table id from the unwrapped arguments.
     let mut table: u64 = 0;
@@ -88,6 +96,12 @@ fn dispatch(db: Rc<DB>) {
         order |= (*e as u32) << (idx << 3);
     }
 
+    // Get the number of rounds.
+    let mut rounds: u32 = 0;
+    for (idx, e) in r.iter().take(size_of::<u32>()).enumerate() {
+        rounds |= (*e as u32) << (idx << 3);
+    }
+
     // Retrieve the list of keys to aggregate across.
     let obj = db.get(table, key);
 
@@ -98,7 +112,7 @@ fn dispatch(db: Rc<DB>) {
 
     // Try performing the aggregate if the key list was successfully retrieved.
     if let Some(val) = obj {
-        let r = aggregate(Rc::clone(&db), table, val.read(), num_k, order);
+        let r = aggregate(Rc::clone(&db), table, val.read(), num_k, order, rounds);
         err = r.0;
         res = r.1;
     }
@@ -122,12 +136,14 @@ fn dispatch(db: Rc<DB>) {
 /// * `key`:   List of keys to lookup and aggregate across.
 /// * `num`:   Number of objects to aggregate across.
 /// * `order`: The order of the final polynomial.
+/// * `rounds`: The number of rounds to checksum every record with, the column being the
+///             checksum. If zero, the column is the first byte of every record instead.
 ///
 /// # Return
 /// A tupule consisting of an error code and the result of the aggregation. This
 /// result is valid only if the error code is `SUCCESSFUL`.
 #[inline(always)]
-fn aggregate(db: Rc<DB>, table: u64, keys: &[u8], num: u32, order: u32) -> (u8, u64) {
+fn aggregate(db: Rc<DB>, table: u64, keys: &[u8], num: u32, order: u32, rounds: u32) -> (u8, u64) {
     let mut col = Vec::new();
 
     let buf = db.multiget(
@@ -139,11 +155,11 @@ fn aggregate(db: Rc<DB>, table: u64, keys: &[u8], num: u32, order: u32) -> (u8,
     match buf {
         Some(vals) => {
             if vals.num() > 0 {
-                col.push(vals.read()[0]);
+                col.push(column(vals.read(), rounds));
             }
 
             while vals.next() {
-                col.push(vals.read()[0]);
+                col.push(column(vals.read(), rounds));
             }
         }
 
@@ -151,12 +167,31 @@ fn aggregate(db: Rc<DB>, table: u64, keys: &[u8], num: u32, order: u32) -> (u8,
     }
 
     // Aggregate the saved column.
-    let mut aggr = col.iter().fold(0, |a, e| a + (*e as u64));
+    let mut aggr = col.iter().fold(0u64, |a, e| a.wrapping_add(*e));
 
     // Compute pow(aggr, order).
     for _mul in 1..order {
-        aggr *= aggr;
+        aggr = aggr.wrapping_mul(aggr);
     }
 
     (SUCCESSFUL, aggr)
 }
+
+/// Computes the column aggregated for a record.
+///
+/// # Arguments
+///
+/// * `val`:    The value of the record.
+/// * `rounds`: The number of rounds to checksum the value with. If zero, the column is the first
+///             byte of the value, which keeps the aggregate bound by data access rather than
+///             compute.
+///
+/// # Return
+/// The column of the record.
+#[inline(always)]
+fn column(val: &[u8], rounds: u32) -> u64 {
+    match rounds {
+        0 => val.get(0).map_or(0, |e| *e as u64),
+        _ => checksum(val, rounds),
+    }
+}
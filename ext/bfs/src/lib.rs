@@ -0,0 +1,422 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+// The bfs() extension is an example of dependent lookups: a breadth first search upto a number
+// of hops out of a set of vertices, over a table holding every vertex's adjacency list. A vertex
+// is stored under it's identifier written little endian into the first eight bytes of the key,
+// with any bytes after those zero, and it's value is the identifiers of it's neighbours (u64),
+// little endian and back to back. The arguments are, little endian:
+//
+//   table (u64) | key length (u16) | depth (u8) | fanout (u16) | frontier cap (u32) |
+//   start vertices (u64 each)
+//
+// Every level of the search is looked up `BATCH` vertices at a time with one multiget, so that
+// the lookups of a level overlap, rather than every lookup waiting on the one before it; a batch
+// holding a vertex without an adjacency list falls back to looking up it's vertices one by one.
+// The extension yields after every batch. At most `fanout` neighbours of every vertex are
+// followed, all of them if zero.
+//
+// Once the frontier of the next level grows beyond the cap (`DEFAULT_CAP` if zero), the search
+// is pushed back to the client (`DB::pushback`), and the client's executor resumes it from where
+// it stopped, without a cap. The state it is resumed with is the number of hops left (u8),
+// followed by the vertices visited so far and the vertices on the frontier, each as a count (u32)
+// followed by the identifiers (u64). The response is a status (u8), the number of vertices
+// visited (u32) and their identifiers (u64) in the order they were visited, the start vertices
+// first.
+
+#![crate_type = "dylib"]
+#![feature(generators, generator_trait)]
+
+extern crate sandstorm;
+
+use std::collections::HashSet;
+use std::ops::Generator;
+use std::rc::Rc;
+
+use sandstorm::db::DB;
+use sandstorm::pack::le;
+
+/// Status codes for the response to the tenant.
+const SUCCESSFUL: u8 = 0x01;
+const INVALIDARG: u8 = 0x02;
+
+/// The number of bytes of arguments ahead of the start vertices.
+const ARGS_HDR_LEN: usize = 17;
+
+/// The number of vertices looked up with one multiget.
+const BATCH: usize = 32;
+
+/// The number of vertices the frontier can grow to when the frontier cap is zero.
+const DEFAULT_CAP: usize = 1024;
+
+/// The parsed arguments to the extension.
+#[derive(Debug, PartialEq)]
+struct Request {
+    table: u64,
+    key_len: usize,
+    depth: u8,
+    fanout: usize,
+    cap: usize,
+    start: Vec<u64>,
+}
+
+// Implementation of methods on Request.
+impl Request {
+    /// Parses the arguments to the extension.
+    ///
+    /// # Arguments
+    ///
+    /// * `args`: The arguments, laid out as described at the top of this file.
+    ///
+    /// # Return
+    ///
+    /// The request, or None if the arguments are malformed.
+    fn parse(args: &[u8]) -> Option<Request> {
+        if args.len() < ARGS_HDR_LEN || (args.len() - ARGS_HDR_LEN) % 8 != 0 {
+            return None;
+        }
+
+        let key_len = le(&args[8..10]) as usize;
+        let start: Vec<u64> = args[ARGS_HDR_LEN..].chunks(8).map(le).collect();
+        if key_len < 8 || start.len() == 0 {
+            return None;
+        }
+
+        Some(Request {
+            table: le(&args[..8]),
+            key_len: key_len,
+            depth: args[10],
+            fanout: le(&args[11..13]) as usize,
+            cap: match le(&args[13..17]) as usize {
+                0 => DEFAULT_CAP,
+                cap => cap,
+            },
+            start: start,
+        })
+    }
+
+    /// Appends the key a vertex is stored under to a buffer.
+    fn key(&self, vertex: u64, buf: &mut Vec<u8>) {
+        for byte in 0..self.key_len {
+            buf.push(if byte < 8 { (vertex >> (8 * byte)) as u8 } else { 0 });
+        }
+    }
+}
+
+/// The state of a search.
+struct Search {
+    /// The number of neighbours of every vertex followed, zero for all of them.
+    fanout: usize,
+
+    /// The vertices visited, in the order they were visited, and as a set.
+    order: Vec<u64>,
+    visited: HashSet<u64>,
+
+    /// The vertices whose neighbours are looked up next, and those found so far that make up
+    /// the level after.
+    frontier: Vec<u64>,
+    next: Vec<u64>,
+}
+
+// Implementation of methods on Search.
+impl Search {
+    /// Returns a search starting out of a set of vertices, which are visited right away.
+    fn new(start: &[u64], fanout: usize) -> Search {
+        let mut search = Search {
+            fanout: fanout,
+            order: Vec::new(),
+            visited: HashSet::new(),
+            frontier: Vec::new(),
+            next: Vec::new(),
+        };
+
+        search.follow(start.iter().cloned());
+        search.advance();
+        search
+    }
+
+    /// Visits the neighbours of a vertex on the frontier, given it's adjacency list. Neighbours
+    /// that were visited before are skipped.
+    fn expand(&mut self, list: &[u8]) {
+        let fanout = match self.fanout {
+            0 => list.len() / 8,
+            fanout => fanout,
+        };
+        let neighbours: Vec<u64> = list.chunks(8).filter(|id| id.len() == 8).map(le).collect();
+        self.follow(neighbours.into_iter().take(fanout));
+    }
+
+    /// Visits vertices, adding the ones not visited before to the next level.
+    fn follow<I: Iterator<Item = u64>>(&mut self, vertices: I) {
+        for vertex in vertices {
+            if self.visited.insert(vertex) {
+                self.order.push(vertex);
+                self.next.push(vertex);
+            }
+        }
+    }
+
+    /// Moves on to the next level, once every vertex on the frontier was expanded.
+    fn advance(&mut self) {
+        self.frontier = std::mem::replace(&mut self.next, Vec::new());
+    }
+
+    /// Returns the state a search is pushed back with, at the start of a level.
+    fn state(&self, hops: u8) -> Vec<u8> {
+        let mut state = vec![hops];
+        write_ids(&self.order, &mut state);
+        write_ids(&self.frontier, &mut state);
+        state
+    }
+
+    /// Returns the search a pushed back search is resumed as, and the number of hops it has
+    /// left, or None if the state is malformed.
+    fn resume(state: &[u8], fanout: usize) -> Option<(Search, u8)> {
+        let (order, rest) = parse_ids(state.get(1..)?)?;
+        let (frontier, rest) = parse_ids(rest)?;
+        if rest.len() != 0 {
+            return None;
+        }
+
+        let search = Search {
+            fanout: fanout,
+            visited: order.iter().cloned().collect(),
+            order: order,
+            frontier: frontier,
+            next: Vec::new(),
+        };
+        Some((search, state[0]))
+    }
+}
+
+/// Appends the number of vertices (u32) and their identifiers (u64) to a buffer.
+fn write_ids(ids: &[u64], buf: &mut Vec<u8>) {
+    for byte in 0..4 {
+        buf.push((ids.len() >> (8 * byte)) as u8);
+    }
+    for id in ids.iter() {
+        for byte in 0..8 {
+            buf.push((id >> (8 * byte)) as u8);
+        }
+    }
+}
+
+/// Parses vertices written out by `write_ids()`, returning them along with the bytes after them,
+/// or None if the buffer is too short.
+fn parse_ids(buf: &[u8]) -> Option<(Vec<u64>, &[u8])> {
+    let len = le(buf.get(..4)?) as usize;
+    let end = len.checked_mul(8)?.checked_add(4)?;
+    let ids = buf.get(4..end)?.chunks(8).map(le).collect();
+    Some((ids, &buf[end..]))
+}
+
+/// Writes a response, given the status of the search.
+///
+/// # Arguments
+///
+/// * `db`:     The database to write the response to.
+/// * `status`: `SUCCESSFUL`.
+/// * `search`: The search.
+fn respond(db: &Rc<DB>, status: u8, search: &Search) {
+    let mut buf = vec![status];
+    write_ids(&search.order, &mut buf);
+    db.resp(&buf);
+}
+
+/// This function implements the bfs() extension using the sandstorm interface.
+///
+/// # Arguments
+///
+/// * `db`: An argument whose type implements the `DB` trait which can be used
+///         to interact with the database.
+///
+/// # Return
+///
+/// A coroutine that can be run inside the database.
+#[no_mangle]
+#[allow(unreachable_code)]
+#[allow(unused_assignments)]
+pub fn init(db: Rc<DB>) -> Box<Generator<Yield = u64, Return = u64>> {
+    Box::new(move || {
+        // The arguments are copied out, so that nothing borrowed from them is held across a
+        // yield.
+        let req = match Request::parse(db.args()) {
+            Some(req) => req,
+            None => {
+                db.resp(&[INVALIDARG]);
+                return 1;
+            }
+        };
+
+        // A search pushed back to the client picks up from where it stopped.
+        let resumed = match db.resumed() {
+            Some(state) => Search::resume(state, req.fanout),
+            None => Some((Search::new(&req.start, req.fanout), req.depth)),
+        };
+        let (mut search, mut hops) = match resumed {
+            Some(resumed) => resumed,
+            None => {
+                db.resp(&[INVALIDARG]);
+                return 1;
+            }
+        };
+
+        while hops > 0 && search.frontier.len() > 0 {
+            // Push back a frontier too large to expand in one go. At the client, carry on.
+            if search.frontier.len() > req.cap && db.pushback(&search.state(hops)) {
+                return 0;
+            }
+
+            let mut done = 0;
+            while done < search.frontier.len() {
+                let batch = search.frontier[done..].iter().take(BATCH).cloned().collect::<Vec<_>>();
+                done += batch.len();
+
+                let mut keys = Vec::with_capacity(batch.len() * req.key_len);
+                for vertex in batch.iter() {
+                    req.key(*vertex, &mut keys);
+                }
+
+                // The adjacency lists of the whole batch are looked up at once. Vertices without
+                // one have no neighbours, but fail the multiget, so look them up one by one then.
+                let mut lists = Vec::with_capacity(batch.len());
+                match db.multiget(req.table, req.key_len as u16, &keys) {
+                    Some(ref objs) if objs.num() == batch.len() => {
+                        lists.push(objs.read().to_vec());
+                        while objs.next() {
+                            lists.push(objs.read().to_vec());
+                        }
+                    }
+
+                    _ => {
+                        for key in keys.chunks(req.key_len) {
+                            if let Some(obj) = db.get(req.table, key) {
+                                lists.push(obj.read().to_vec());
+                            }
+                        }
+                    }
+                }
+
+                for list in lists.iter() {
+                    search.expand(list);
+                }
+
+                // Yield down to the database between batches.
+                yield 0;
+            }
+
+            search.advance();
+            hops -= 1;
+        }
+
+        respond(&db, SUCCESSFUL, &search);
+        return 0;
+
+        // XXX: This yield is required to get the compiler to compile this closure into a
+        // generator. It is unreachable and benign.
+        yield 0;
+    })
+}
+
+// This module contains unit tests for the bfs() extension.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Returns the arguments of a search out of vertices.
+    fn args(key_len: u16, depth: u8, fanout: u16, cap: u32, start: &[u64]) -> Vec<u8> {
+        let mut args = vec![9, 0, 0, 0, 0, 0, 0, 0, key_len as u8, (key_len >> 8) as u8, depth];
+        args.extend_from_slice(&[fanout as u8, (fanout >> 8) as u8]);
+        args.extend_from_slice(&[cap as u8, (cap >> 8) as u8, (cap >> 16) as u8, 0]);
+        for id in start.iter() {
+            args.extend((0..8).map(|byte| (id >> (8 * byte)) as u8));
+        }
+        args
+    }
+
+    // Returns an adjacency list.
+    fn list(ids: &[u64]) -> Vec<u8> {
+        ids.iter()
+            .flat_map(|id| (0..8).map(move |byte| (id >> (8 * byte)) as u8))
+            .collect()
+    }
+
+    // This unit test verifies that well formed arguments are parsed, and malformed ones are not.
+    #[test]
+    fn test_parse() {
+        let req = Request::parse(&args(30, 2, 5, 0, &[1, 258])).unwrap();
+        assert_eq!(9, req.table);
+        assert_eq!((30, 2, 5, DEFAULT_CAP), (req.key_len, req.depth, req.fanout, req.cap));
+        assert_eq!(vec![1, 258], req.start);
+        assert_eq!(7, Request::parse(&args(30, 2, 5, 7, &[1])).unwrap().cap);
+
+        assert!(Request::parse(&args(30, 2, 5, 0, &[])).is_none());
+        assert!(Request::parse(&args(7, 2, 5, 0, &[1])).is_none());
+        let mut args = args(30, 2, 5, 0, &[1]);
+        args.pop();
+        assert!(Request::parse(&args).is_none());
+    }
+
+    // This unit test verifies that vertices are stored under their identifier, padded with zeros.
+    #[test]
+    fn test_key() {
+        let req = Request::parse(&args(10, 1, 0, 0, &[1])).unwrap();
+        let mut buf = Vec::new();
+        req.key(0x0201, &mut buf);
+        assert_eq!(vec![1, 2, 0, 0, 0, 0, 0, 0, 0, 0], buf);
+    }
+
+    // This unit test verifies that a search visits every vertex once, level by level, follows
+    // at most `fanout` neighbours, and skips vertices it visited before.
+    #[test]
+    fn test_search() {
+        let mut search = Search::new(&[1, 2, 1], 2);
+        assert_eq!(vec![1, 2], search.order);
+        assert_eq!(vec![1, 2], search.frontier);
+
+        search.expand(&list(&[3, 4, 5]));
+        search.expand(&list(&[1, 3, 6]));
+        search.advance();
+        assert_eq!(vec![1, 2, 3, 4], search.order);
+        assert_eq!(vec![3, 4], search.frontier);
+
+        let mut search = Search::new(&[1], 0);
+        search.expand(&list(&[3, 4, 5]));
+        search.advance();
+        assert_eq!(vec![3, 4, 5], search.frontier);
+    }
+
+    // This unit test verifies that a pushed back search is resumed with the vertices it visited,
+    // it's frontier and the hops it had left, and that malformed state is rejected.
+    #[test]
+    fn test_resume() {
+        let mut search = Search::new(&[1], 0);
+        search.expand(&list(&[3, 4]));
+        search.advance();
+
+        let state = search.state(2);
+        let (mut resumed, hops) = Search::resume(&state, 0).unwrap();
+        assert_eq!(2, hops);
+        assert_eq!(vec![1, 3, 4], resumed.order);
+        assert_eq!(vec![3, 4], resumed.frontier);
+
+        resumed.expand(&list(&[1, 4, 5]));
+        assert_eq!(vec![1, 3, 4, 5], resumed.order);
+
+        assert!(Search::resume(&state[..state.len() - 1], 0).is_none());
+        assert!(Search::resume(&[state.clone(), vec![0]].concat(), 0).is_none());
+        assert!(Search::resume(&[], 0).is_none());
+    }
+}
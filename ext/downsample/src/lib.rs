@@ -0,0 +1,440 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+// The downsample() extension stores time series, and summarizes them at a coarser resolution,
+// the way a monitoring dashboard plots a metric: every series is a single object whose value is
+// a list of points, each a timestamp (u64) followed by a measurement (f64), in the order they
+// were appended. The arguments are, little endian:
+//
+//   opcode (u8) | table (u64) | key length (u16) | key | body
+//
+// where the body depends on the opcode:
+//
+//   APPEND (1): points, 16 bytes each, at least one of them. They are appended to the series
+//               with `DB::append()`, creating it if it does not exist.
+//   QUERY  (2): start (u64) | end (u64) | resolution (u64). The window from `start` upto
+//               `end` is split into buckets `resolution` wide, upto `MAX_BUCKETS` of them, the
+//               last one cut short at `end`.
+//
+// The response to an append is just a status (u8). The response to a query is a status, the
+// number of buckets (u32), and then for every bucket, in order of time, the number of points
+// that fell into it (u32) and their minimum, maximum and average measurement (f64 each, all zero
+// if no point fell into it).
+//
+// Series can grow far larger than a single read should hold up the core for, so a query reads
+// it's series `RANGE_LEN` bytes at a time with `DB::get_range()`, and yields in between. Appends
+// never change bytes already in a series, so the ranges read before an append line up with the
+// ones read after it.
+
+#![crate_type = "dylib"]
+#![feature(generators, generator_trait)]
+
+extern crate sandstorm;
+
+use std::ops::Generator;
+use std::rc::Rc;
+
+use sandstorm::db::DB;
+use sandstorm::pack::le;
+
+/// Status codes for the response to the tenant.
+const SUCCESSFUL: u8 = 0x01;
+const INVALIDARG: u8 = 0x02;
+const NOTFOUND: u8 = 0x03;
+const FAILED: u8 = 0x04;
+
+/// Opcodes of the operations on a series.
+const APPEND: u8 = 0x01;
+const QUERY: u8 = 0x02;
+
+/// The number of bytes of arguments ahead of the key.
+const ARGS_HDR_LEN: usize = 11;
+
+/// The number of bytes in a point.
+const POINT_LEN: usize = 16;
+
+/// The largest number of buckets a query can summarize a series into.
+const MAX_BUCKETS: u64 = 64;
+
+/// The number of bytes of a series read between yields, a whole number of points.
+const RANGE_LEN: usize = 256 * POINT_LEN;
+
+/// An operation on a series.
+#[derive(Debug, PartialEq)]
+enum Op {
+    /// Append points to the series.
+    Append(Vec<u8>),
+
+    /// Summarize the series over a window.
+    Query(Window),
+}
+
+/// The parsed arguments to the extension.
+#[derive(Debug, PartialEq)]
+struct Request {
+    table: u64,
+    key: Vec<u8>,
+    op: Op,
+}
+
+// Implementation of methods on Request.
+impl Request {
+    /// Parses the arguments to the extension.
+    ///
+    /// # Arguments
+    ///
+    /// * `args`: The arguments, laid out as described at the top of this file.
+    ///
+    /// # Return
+    ///
+    /// The request, or None if the arguments are malformed.
+    fn parse(args: &[u8]) -> Option<Request> {
+        if args.len() < ARGS_HDR_LEN {
+            return None;
+        }
+
+        let key_len = le(&args[9..11]) as usize;
+        if key_len == 0 || args.len() < ARGS_HDR_LEN + key_len {
+            return None;
+        }
+
+        let (key, body) = args[ARGS_HDR_LEN..].split_at(key_len);
+        let op = match args[0] {
+            APPEND if body.len() > 0 && body.len() % POINT_LEN == 0 => Op::Append(body.to_vec()),
+
+            QUERY if body.len() == 24 => {
+                Op::Query(Window::new(le(&body[..8]), le(&body[8..16]), le(&body[16..]))?)
+            }
+
+            _ => return None,
+        };
+
+        Some(Request {
+            table: le(&args[1..9]),
+            key: key.to_vec(),
+            op: op,
+        })
+    }
+}
+
+/// A window of time split into buckets.
+#[derive(Debug, PartialEq)]
+struct Window {
+    start: u64,
+    end: u64,
+    resolution: u64,
+}
+
+// Implementation of methods on Window.
+impl Window {
+    /// Returns a window, or None if it is empty or would have more than `MAX_BUCKETS` buckets.
+    fn new(start: u64, end: u64, resolution: u64) -> Option<Window> {
+        if end <= start || resolution == 0 {
+            return None;
+        }
+
+        let window = Window {
+            start: start,
+            end: end,
+            resolution: resolution,
+        };
+
+        match window.buckets() <= MAX_BUCKETS {
+            true => Some(window),
+            false => None,
+        }
+    }
+
+    /// Returns the number of buckets in the window.
+    fn buckets(&self) -> u64 {
+        let span = self.end - self.start;
+        span / self.resolution + if span % self.resolution > 0 { 1 } else { 0 }
+    }
+
+    /// Returns the bucket a timestamp falls into, or None if it is outside the window.
+    fn bucket(&self, timestamp: u64) -> Option<usize> {
+        match timestamp >= self.start && timestamp < self.end {
+            true => Some(((timestamp - self.start) / self.resolution) as usize),
+            false => None,
+        }
+    }
+}
+
+/// The summary of the points that fell into a bucket.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Bucket {
+    count: u32,
+    min: f64,
+    max: f64,
+    sum: f64,
+}
+
+// Implementation of methods on Bucket.
+impl Bucket {
+    /// Returns a bucket no point fell into.
+    fn new() -> Bucket {
+        Bucket {
+            count: 0,
+            min: 0.0,
+            max: 0.0,
+            sum: 0.0,
+        }
+    }
+
+    /// Adds a measurement to the bucket.
+    fn add(&mut self, val: f64) {
+        if self.count == 0 {
+            self.min = val;
+            self.max = val;
+        } else {
+            self.min = self.min.min(val);
+            self.max = self.max.max(val);
+        }
+
+        self.count += 1;
+        self.sum += val;
+    }
+
+    /// Appends the bucket to a response, laid out as described at the top of this file.
+    fn encode(&self, resp: &mut Vec<u8>) {
+        let avg = match self.count {
+            0 => 0.0,
+            count => self.sum / count as f64,
+        };
+
+        resp.extend((0..4).map(|byte| (self.count >> (8 * byte)) as u8));
+        for val in [self.min, self.max, avg].iter() {
+            resp.extend((0..8).map(|byte| (val.to_bits() >> (8 * byte)) as u8));
+        }
+    }
+}
+
+/// Adds the points in a range of a series to the buckets of a window they fall into, ignoring
+/// a partial point at the end.
+///
+/// # Arguments
+///
+/// * `window`:  The window.
+/// * `buckets`: The buckets of the window.
+/// * `points`:  The range of the series, starting at a point.
+fn fold(window: &Window, buckets: &mut [Bucket], points: &[u8]) {
+    for point in points.chunks(POINT_LEN) {
+        if point.len() < POINT_LEN {
+            break;
+        }
+
+        if let Some(idx) = window.bucket(le(&point[..8])) {
+            buckets[idx].add(f64::from_bits(le(&point[8..])));
+        }
+    }
+}
+
+/// This function implements the downsample() extension using the sandstorm interface.
+///
+/// # Arguments
+///
+/// * `db`: An argument whose type implements the `DB` trait which can be used
+///         to interact with the database.
+///
+/// # Return
+///
+/// A coroutine that can be run inside the database.
+#[no_mangle]
+#[allow(unreachable_code)]
+#[allow(unused_assignments)]
+pub fn init(db: Rc<DB>) -> Box<Generator<Yield = u64, Return = u64>> {
+    Box::new(move || {
+        // The arguments are copied out, so that nothing borrowed from them is held across a
+        // yield.
+        let req = match Request::parse(db.args()) {
+            Some(req) => req,
+            None => {
+                db.resp(&[INVALIDARG]);
+                return 1;
+            }
+        };
+
+        let window = match req.op {
+            Op::Append(ref points) => {
+                match db.append(req.table, &req.key, points) {
+                    true => db.resp(&[SUCCESSFUL]),
+                    false => db.resp(&[FAILED]),
+                }
+                return 0;
+            }
+
+            Op::Query(window) => window,
+        };
+
+        // Read the series a range at a time, until a range comes back short of a full one.
+        let mut buckets = vec![Bucket::new(); window.buckets() as usize];
+        let mut offset = 0;
+        loop {
+            let len = match db.get_range(req.table, &req.key, offset, RANGE_LEN) {
+                Some(range) => {
+                    fold(&window, &mut buckets, range.read());
+                    range.len()
+                }
+
+                None if offset == 0 => {
+                    db.resp(&[NOTFOUND]);
+                    return 0;
+                }
+
+                None => 0,
+            };
+
+            if len < RANGE_LEN {
+                break;
+            }
+
+            offset += len;
+            yield 0;
+        }
+
+        let mut resp = Vec::with_capacity(5 + 28 * buckets.len());
+        resp.push(SUCCESSFUL);
+        resp.extend((0..4).map(|byte| (buckets.len() >> (8 * byte)) as u8));
+        for bucket in buckets.iter() {
+            bucket.encode(&mut resp);
+        }
+
+        db.resp(&resp);
+        return 0;
+
+        // XXX: This yield is required to get the compiler to compile this closure into a
+        // generator. It is unreachable and benign.
+        yield 0;
+    })
+}
+
+// This module contains unit tests for the downsample() extension.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Returns the little endian bytes of a list of points.
+    fn points(points: &[(u64, f64)]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for &(timestamp, val) in points.iter() {
+            buf.extend((0..8).map(|byte| (timestamp >> (8 * byte)) as u8));
+            buf.extend((0..8).map(|byte| (val.to_bits() >> (8 * byte)) as u8));
+        }
+        buf
+    }
+
+    // Returns the arguments to the extension.
+    fn args(op: u8, table: u64, key: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut args = vec![op];
+        args.extend((0..8).map(|byte| (table >> (8 * byte)) as u8));
+        args.extend((0..2).map(|byte| (key.len() >> (8 * byte)) as u8));
+        args.extend_from_slice(key);
+        args.extend_from_slice(body);
+        args
+    }
+
+    // This unit test verifies that appends and queries are parsed, and that malformed arguments
+    // are not.
+    #[test]
+    fn test_parse() {
+        let body = points(&[(1, 1.0), (2, 2.0)]);
+        let req = Request::parse(&args(APPEND, 7, &[1, 2, 3], &body)).unwrap();
+        assert_eq!((7, vec![1, 2, 3]), (req.table, req.key));
+        assert_eq!(Op::Append(body.clone()), req.op);
+
+        assert!(Request::parse(&args(APPEND, 7, &[1, 2, 3], &body[..31])).is_none());
+        assert!(Request::parse(&args(APPEND, 7, &[1, 2, 3], &[])).is_none());
+        assert!(Request::parse(&args(APPEND, 7, &[], &body)).is_none());
+        assert!(Request::parse(&args(3, 7, &[1, 2, 3], &body)).is_none());
+
+        let mut query = Vec::new();
+        for val in [100u64, 125, 10].iter() {
+            query.extend((0..8).map(|byte| (val >> (8 * byte)) as u8));
+        }
+        let req = Request::parse(&args(QUERY, 7, &[1], &query)).unwrap();
+        assert_eq!(Op::Query(Window::new(100, 125, 10).unwrap()), req.op);
+
+        assert!(Request::parse(&args(QUERY, 7, &[1], &query[..23])).is_none());
+        assert!(Request::parse(&args(QUERY, 7, &[1], &[0; 24])).is_none());
+        assert!(Request::parse(&args(QUERY, 7, &[1], &[])[..11]).is_none());
+    }
+
+    // This unit test verifies that windows are split into buckets, the last one cut short, and
+    // that windows with no or too many buckets are refused.
+    #[test]
+    fn test_window() {
+        let window = Window::new(100, 125, 10).unwrap();
+        assert_eq!(3, window.buckets());
+        assert_eq!(None, window.bucket(99));
+        assert_eq!(Some(0), window.bucket(100));
+        assert_eq!(Some(1), window.bucket(119));
+        assert_eq!(Some(2), window.bucket(124));
+        assert_eq!(None, window.bucket(125));
+
+        assert_eq!(1, Window::new(0, 1, u64::max_value()).unwrap().buckets());
+        assert_eq!(MAX_BUCKETS, Window::new(0, MAX_BUCKETS, 1).unwrap().buckets());
+        assert!(Window::new(0, MAX_BUCKETS + 1, 1).is_none());
+        assert!(Window::new(5, 5, 1).is_none());
+        assert!(Window::new(0, 5, 0).is_none());
+    }
+
+    // This unit test verifies that points are summarized into the buckets they fall into,
+    // whatever the order they were appended in, and that points outside the window are not.
+    #[test]
+    fn test_fold() {
+        let window = Window::new(0, 30, 10).unwrap();
+        let mut buckets = vec![Bucket::new(); 3];
+        let series = points(&[(5, 2.0), (1, -1.0), (25, 4.0), (30, 9.0), (9, 5.0), (22, 6.0)]);
+
+        // Fold the series in two ranges, the second one with a partial point at the end.
+        fold(&window, &mut buckets, &series[..32]);
+        fold(&window, &mut buckets, &series[32..series.len() - 3]);
+
+        assert_eq!(
+            Bucket {
+                count: 3,
+                min: -1.0,
+                max: 5.0,
+                sum: 6.0,
+            },
+            buckets[0]
+        );
+        assert_eq!(Bucket::new(), buckets[1]);
+        assert_eq!((1, 4.0, 4.0), (buckets[2].count, buckets[2].min, buckets[2].max));
+
+        fold(&window, &mut buckets, &series[series.len() - 16..]);
+        let last = buckets[2];
+        assert_eq!((2, 4.0, 6.0, 10.0), (last.count, last.min, last.max, last.sum));
+    }
+
+    // This unit test verifies that buckets are encoded with their average, and that empty
+    // buckets are all zero.
+    #[test]
+    fn test_encode() {
+        let mut bucket = Bucket::new();
+        let mut resp = Vec::new();
+        bucket.encode(&mut resp);
+        assert_eq!(vec![0; 28], resp);
+
+        bucket.add(1.0);
+        bucket.add(4.0);
+        let mut resp = Vec::new();
+        bucket.encode(&mut resp);
+        assert_eq!(&[2, 0, 0, 0], &resp[..4]);
+        assert_eq!(1.0, f64::from_bits(le(&resp[4..12])));
+        assert_eq!(4.0, f64::from_bits(le(&resp[12..20])));
+        assert_eq!(2.5, f64::from_bits(le(&resp[20..28])));
+    }
+}
@@ -0,0 +1,374 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+// The filter() extension scans a range of keys of a table and returns only the objects matching
+// a predicate, so that clients do not fetch every object to filter them themselves. Every key is
+// it's number written little endian into it's first eight bytes, with any bytes after those
+// zero, the way the workloads lay keys out. The arguments are, little endian:
+//
+//   table (u64) | key length (u16) | first key (u64) | number of keys (u32) | limit (u32) |
+//   predicate
+//
+// The predicate is a program in postfix order, built by `splinter_client::filter::Predicate`:
+//
+//   CMP (0x01) | op (u8) | field offset (u16) | field width (u8) | constant (u64)
+//       pushes whether the field, an unsigned integer `width` (1, 2, 4 or 8) bytes long at
+//       `offset` into the value read little endian, compares to the constant by `op` (one of
+//       `EQ` to `GE`). It is false if the value is too short to hold the field.
+//   AND (0x02), OR (0x03)
+//       pop two results, and push both of them, or either of them, being true.
+//
+// and must leave exactly one result. The response is a status (u8), the number of the first key
+// not scanned (u64), and then every matching object as it's key, the length of it's value (u32)
+// and it's value. Once `limit` objects matched (if it is not zero), the scan stops with a `MORE`
+// status, and can be carried on from the key it returned. The extension yields every
+// `YIELD_EVERY` keys, so that long ranges do not hold up other requests.
+
+#![crate_type = "dylib"]
+#![feature(generators, generator_trait)]
+
+extern crate sandstorm;
+
+use std::ops::Generator;
+use std::rc::Rc;
+
+use sandstorm::db::DB;
+use sandstorm::pack::le;
+
+/// Status codes for the response to the tenant.
+const SUCCESSFUL: u8 = 0x01;
+const INVALIDARG: u8 = 0x02;
+const MORE: u8 = 0x03;
+
+/// The instructions of a predicate.
+const CMP: u8 = 0x01;
+const AND: u8 = 0x02;
+const OR: u8 = 0x03;
+
+/// The comparisons a CMP instruction makes.
+const EQ: u8 = 0x01;
+const NE: u8 = 0x02;
+const LT: u8 = 0x03;
+const LE: u8 = 0x04;
+const GT: u8 = 0x05;
+const GE: u8 = 0x06;
+
+/// The number of bytes in a CMP instruction.
+const CMP_LEN: usize = 13;
+
+/// The number of bytes of arguments ahead of the predicate.
+const ARGS_HDR_LEN: usize = 26;
+
+/// The number of keys looked up between yields.
+const YIELD_EVERY: u64 = 64;
+
+/// An instruction of a predicate.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Insn {
+    /// Compares a field of the value to a constant: the comparison, the field's offset and
+    /// width, and the constant.
+    Cmp(u8, usize, usize, u64),
+    And,
+    Or,
+}
+
+/// The parsed arguments to the extension.
+#[derive(Debug, PartialEq)]
+struct Request {
+    table: u64,
+    key_len: usize,
+    first: u64,
+    count: u32,
+    limit: usize,
+    predicate: Vec<Insn>,
+}
+
+// Implementation of methods on Request.
+impl Request {
+    /// Parses the arguments to the extension.
+    ///
+    /// # Arguments
+    ///
+    /// * `args`: The arguments, laid out as described at the top of this file.
+    ///
+    /// # Return
+    ///
+    /// The request, or None if the arguments or the predicate are malformed.
+    fn parse(args: &[u8]) -> Option<Request> {
+        if args.len() < ARGS_HDR_LEN {
+            return None;
+        }
+
+        let key_len = le(&args[8..10]) as usize;
+        if key_len < 8 {
+            return None;
+        }
+
+        let predicate = match compile(&args[ARGS_HDR_LEN..]) {
+            Some(predicate) => predicate,
+            None => return None,
+        };
+
+        Some(Request {
+            table: le(&args[..8]),
+            key_len: key_len,
+            first: le(&args[10..18]),
+            count: le(&args[18..22]) as u32,
+            limit: le(&args[22..26]) as usize,
+            predicate: predicate,
+        })
+    }
+
+    /// Writes the key with a number into a buffer `key_len` bytes long.
+    fn key(&self, id: u64, key: &mut [u8]) {
+        for (byte, k) in key.iter_mut().enumerate() {
+            *k = if byte < 8 { (id >> (8 * byte)) as u8 } else { 0 };
+        }
+    }
+}
+
+/// Parses a predicate, and checks that it leaves exactly one result without running out of
+/// results to combine along the way.
+///
+/// # Arguments
+///
+/// * `buf`: The predicate.
+///
+/// # Return
+///
+/// The instructions of the predicate, or None if it is malformed.
+fn compile(buf: &[u8]) -> Option<Vec<Insn>> {
+    let mut insns = Vec::new();
+    let mut depth = 0;
+    let mut off = 0;
+    while off < buf.len() {
+        match buf[off] {
+            CMP if buf.len() - off >= CMP_LEN => {
+                let (op, width) = (buf[off + 1], buf[off + 4] as usize);
+                if op < EQ || op > GE || !(width == 1 || width == 2 || width == 4 || width == 8) {
+                    return None;
+                }
+
+                let offset = le(&buf[off + 2..off + 4]) as usize;
+                insns.push(Insn::Cmp(op, offset, width, le(&buf[off + 5..off + CMP_LEN])));
+                depth += 1;
+                off += CMP_LEN;
+            }
+
+            AND | OR if depth >= 2 => {
+                insns.push(if buf[off] == AND { Insn::And } else { Insn::Or });
+                depth -= 1;
+                off += 1;
+            }
+
+            _ => return None,
+        }
+    }
+
+    match depth {
+        1 => Some(insns),
+        _ => None,
+    }
+}
+
+/// Evaluates a predicate over a value.
+///
+/// # Arguments
+///
+/// * `predicate`: The instructions of the predicate, as checked by `compile()`.
+/// * `val`:       The value.
+/// * `stack`:     A stack to evaluate the predicate on, reused across values.
+///
+/// # Return
+///
+/// True if the value matches the predicate.
+fn matches(predicate: &[Insn], val: &[u8], stack: &mut Vec<bool>) -> bool {
+    stack.clear();
+    for insn in predicate.iter() {
+        let result = match *insn {
+            Insn::Cmp(op, offset, width, constant) if val.len() >= offset + width => {
+                let field = le(&val[offset..offset + width]);
+                match op {
+                    EQ => field == constant,
+                    NE => field != constant,
+                    LT => field < constant,
+                    LE => field <= constant,
+                    GT => field > constant,
+                    _ => field >= constant,
+                }
+            }
+            Insn::Cmp(..) => false,
+            Insn::And => stack.pop().unwrap() & stack.pop().unwrap(),
+            Insn::Or => stack.pop().unwrap() | stack.pop().unwrap(),
+        };
+        stack.push(result);
+    }
+
+    stack.pop().unwrap_or(false)
+}
+
+/// This function implements the filter() extension using the sandstorm interface.
+///
+/// # Arguments
+///
+/// * `db`: An argument whose type implements the `DB` trait which can be used
+///         to interact with the database.
+///
+/// # Return
+///
+/// A coroutine that can be run inside the database.
+#[no_mangle]
+#[allow(unreachable_code)]
+#[allow(unused_assignments)]
+pub fn init(db: Rc<DB>) -> Box<Generator<Yield = u64, Return = u64>> {
+    Box::new(move || {
+        // The arguments are copied out, so that nothing borrowed from them is held across a
+        // yield.
+        let req = match Request::parse(db.args()) {
+            Some(req) => req,
+            None => {
+                db.resp(&[INVALIDARG]);
+                return 1;
+            }
+        };
+
+        // Matching objects are collected before the status and the key to carry on from, which
+        // go first, are known.
+        let mut stack = Vec::new();
+        let mut key = vec![0; req.key_len];
+        let mut matched = Vec::new();
+        let mut found = 0;
+        let mut idx = 0;
+        while idx < req.count as u64 {
+            if req.limit > 0 && found == req.limit {
+                break;
+            }
+
+            let id = req.first.wrapping_add(idx);
+            req.key(id, &mut key);
+            if let Some(obj) = db.get(req.table, &key) {
+                let val = obj.read();
+                if matches(&req.predicate, val, &mut stack) {
+                    matched.extend_from_slice(&key);
+                    matched.extend((0..4).map(|byte| (val.len() >> (8 * byte)) as u8));
+                    matched.extend_from_slice(val);
+                    found += 1;
+                }
+            }
+            idx += 1;
+
+            // Yield down to the database every few keys.
+            if idx % YIELD_EVERY == 0 {
+                yield 0;
+            }
+        }
+
+        let status = if idx < req.count as u64 { MORE } else { SUCCESSFUL };
+        let next = req.first.wrapping_add(idx);
+        let next: Vec<u8> = (0..8).map(|byte| (next >> (8 * byte)) as u8).collect();
+        db.resp(&[status]);
+        db.resp(&next);
+        db.resp(&matched);
+        return 0;
+
+        // XXX: This yield is required to get the compiler to compile this closure into a
+        // generator. It is unreachable and benign.
+        yield 0;
+    })
+}
+
+// This module contains unit tests for the filter() extension.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Returns a CMP instruction.
+    fn cmp(op: u8, offset: u16, width: u8, constant: u64) -> Vec<u8> {
+        let mut insn = vec![CMP, op, offset as u8, (offset >> 8) as u8, width];
+        insn.extend((0..8).map(|byte| (constant >> (8 * byte)) as u8));
+        insn
+    }
+
+    // This unit test verifies that well formed predicates are parsed, and that predicates that
+    // leave anything but one result, or are truncated, are not.
+    #[test]
+    fn test_compile() {
+        let mut buf = cmp(GT, 4, 2, 7);
+        assert_eq!(Some(vec![Insn::Cmp(GT, 4, 2, 7)]), compile(&buf));
+
+        buf.extend(cmp(EQ, 0, 1, 1));
+        assert!(compile(&buf).is_none());
+        buf.push(OR);
+        assert_eq!(3, compile(&buf).unwrap().len());
+        buf.push(AND);
+        assert!(compile(&buf).is_none());
+
+        assert!(compile(&[]).is_none());
+        assert!(compile(&cmp(GT, 4, 3, 7)).is_none());
+        assert!(compile(&cmp(0, 4, 2, 7)).is_none());
+        assert!(compile(&cmp(GE + 1, 4, 2, 7)).is_none());
+        assert!(compile(&cmp(GT, 4, 2, 7)[..CMP_LEN - 1]).is_none());
+        assert!(compile(&[0x04]).is_none());
+    }
+
+    // This unit test verifies that every comparison, AND and OR evaluate as they should, and
+    // that a field past the end of the value does not match.
+    #[test]
+    fn test_matches() {
+        let mut stack = Vec::new();
+        let val = [5, 0x34, 0x12];
+        let eval = |buf: &[u8], stack: &mut Vec<bool>| matches(&compile(buf).unwrap(), &val, stack);
+
+        assert!(eval(&cmp(EQ, 1, 2, 0x1234), &mut stack));
+        assert!(eval(&cmp(NE, 0, 1, 4), &mut stack));
+        assert!(eval(&cmp(LT, 0, 1, 6), &mut stack));
+        assert!(eval(&cmp(LE, 0, 1, 5), &mut stack));
+        assert!(!eval(&cmp(GT, 0, 1, 5), &mut stack));
+        assert!(eval(&cmp(GE, 0, 1, 5), &mut stack));
+        assert!(!eval(&cmp(EQ, 2, 2, 0x12), &mut stack));
+        assert!(!eval(&cmp(NE, 2, 2, 0x12), &mut stack));
+
+        // (field0 == 5 AND field1 < 0x1000) OR field0 > 4
+        let mut buf = cmp(EQ, 0, 1, 5);
+        buf.extend(cmp(LT, 1, 2, 0x1000));
+        buf.push(AND);
+        assert!(!eval(&buf, &mut stack));
+        buf.extend(cmp(GT, 0, 1, 4));
+        buf.push(OR);
+        assert!(eval(&buf, &mut stack));
+    }
+
+    // This unit test verifies that the arguments are parsed, and that keys are laid out the way
+    // the workloads lay them out.
+    #[test]
+    fn test_parse() {
+        let mut args = vec![3, 0, 0, 0, 0, 0, 0, 0, 10, 0];
+        args.extend_from_slice(&[0xff, 0, 0, 0, 0, 0, 0, 0, 100, 0, 0, 0, 5, 0, 0, 0]);
+        args.extend(cmp(EQ, 0, 1, 1));
+
+        let req = Request::parse(&args).unwrap();
+        assert_eq!((3, 10, 0xff), (req.table, req.key_len, req.first));
+        assert_eq!((100, 5), (req.count, req.limit));
+        assert_eq!(vec![Insn::Cmp(EQ, 0, 1, 1)], req.predicate);
+
+        let mut key = vec![0xaa; 10];
+        req.key(0x100, &mut key);
+        assert_eq!(vec![0, 1, 0, 0, 0, 0, 0, 0, 0, 0], key);
+
+        assert!(Request::parse(&args[..ARGS_HDR_LEN]).is_none());
+        args[8] = 7;
+        assert!(Request::parse(&args).is_none());
+    }
+}
@@ -0,0 +1,219 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+// The getall() extension looks up a list of keys and returns their values. It is built on stable
+// Rust, against the continuation interface in `sandstorm::continuation` rather than generators,
+// and so keeps the state that survives it's yields in a state machine. The arguments are, little
+// endian:
+//
+//   table (u64) | key length (u16) | keys
+//
+// with the keys following each other, `key length` bytes each. The response is a status (u8),
+// and then for every key, in order, the length of it's value (u32, `u32::MAX` if it does not
+// exist) followed by the value. The extension yields every `YIELD_EVERY` keys.
+
+#![crate_type = "dylib"]
+#![deny(unsafe_code)]
+
+extern crate sandstorm;
+
+use std::mem;
+use std::rc::Rc;
+
+use sandstorm::continuation::{Continuation, Step};
+use sandstorm::db::DB;
+use sandstorm::pack::le;
+
+/// Status codes for the response to the tenant.
+const SUCCESSFUL: u8 = 0x01;
+const INVALIDARG: u8 = 0x02;
+
+/// The number of bytes of arguments ahead of the keys.
+const ARGS_HDR_LEN: usize = 10;
+
+/// The number of keys looked up between yields.
+const YIELD_EVERY: usize = 16;
+
+/// The length written in place of the length of a value that does not exist.
+const MISSING: u32 = u32::max_value();
+
+/// The parsed arguments to the extension.
+#[derive(Debug, PartialEq)]
+struct Request {
+    table: u64,
+    key_len: usize,
+    keys: Vec<u8>,
+}
+
+// Implementation of methods on Request.
+impl Request {
+    /// Parses the arguments to the extension.
+    ///
+    /// # Arguments
+    ///
+    /// * `args`: The arguments, laid out as described at the top of this file.
+    ///
+    /// # Return
+    ///
+    /// The request, or None if the arguments are malformed.
+    fn parse(args: &[u8]) -> Option<Request> {
+        if args.len() < ARGS_HDR_LEN {
+            return None;
+        }
+
+        let key_len = le(&args[8..10]) as usize;
+        if key_len == 0 || (args.len() - ARGS_HDR_LEN) % key_len != 0 {
+            return None;
+        }
+
+        Some(Request {
+            table: le(&args[..8]),
+            key_len: key_len,
+            keys: args[ARGS_HDR_LEN..].to_vec(),
+        })
+    }
+}
+
+/// The states an invocation moves through.
+#[derive(Debug, PartialEq)]
+enum State {
+    /// The arguments have not been parsed yet.
+    Parse,
+
+    /// The keys from the one at the index on have not been looked up yet.
+    Lookup(Request, usize),
+
+    /// The response has been written.
+    Done,
+}
+
+/// An invocation of the extension.
+struct GetAll {
+    // The database the invocation runs against.
+    db: Rc<DB>,
+
+    // The state of the invocation.
+    state: State,
+
+    // The response written so far.
+    resp: Vec<u8>,
+}
+
+impl Continuation for GetAll {
+    fn resume(&mut self) -> Step {
+        loop {
+            match mem::replace(&mut self.state, State::Done) {
+                State::Parse => match Request::parse(self.db.args()) {
+                    Some(req) => {
+                        self.resp.push(SUCCESSFUL);
+                        self.state = State::Lookup(req, 0);
+                    }
+
+                    None => {
+                        self.db.resp(&[INVALIDARG]);
+                        return Step::Done(1);
+                    }
+                },
+
+                State::Lookup(req, next) => {
+                    let count = req.keys.len() / req.key_len;
+                    let last = count.min(next + YIELD_EVERY);
+                    for idx in next..last {
+                        let key = &req.keys[idx * req.key_len..(idx + 1) * req.key_len];
+                        let val = self.db.get(req.table, key);
+                        respond(&mut self.resp, val.as_ref().map(|val| val.read()));
+                    }
+
+                    if last < count {
+                        self.state = State::Lookup(req, last);
+                        return Step::Yield(0);
+                    }
+
+                    self.db.resp(&self.resp);
+                    return Step::Done(0);
+                }
+
+                // Resuming a completed invocation is a bug in the database; do nothing.
+                State::Done => return Step::Done(0),
+            }
+        }
+    }
+}
+
+/// Appends the value of a key to the response, laid out as described at the top of this file.
+///
+/// # Arguments
+///
+/// * `resp`: The response.
+/// * `val`:  The value, or None if the key does not exist.
+fn respond(resp: &mut Vec<u8>, val: Option<&[u8]>) {
+    let len = val.map_or(MISSING, |val| val.len() as u32);
+    resp.extend((0..4).map(|byte| (len >> (8 * byte)) as u8));
+    resp.extend_from_slice(val.unwrap_or(&[]));
+}
+
+/// This function implements the getall() extension using the stable sandstorm interface.
+///
+/// # Arguments
+///
+/// * `db`: An argument whose type implements the `DB` trait which can be used
+///         to interact with the database.
+///
+/// # Return
+///
+/// A continuation that can be run inside the database.
+#[no_mangle]
+#[allow(unsafe_code)]
+pub fn init_stable(db: Rc<DB>) -> Box<Continuation> {
+    Box::new(GetAll {
+        db: db,
+        state: State::Parse,
+        resp: Vec::new(),
+    })
+}
+
+// This module contains unit tests for the getall() extension.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // This unit test verifies that the arguments are parsed, and that malformed ones are not.
+    #[test]
+    fn test_parse() {
+        let mut args = vec![7, 0, 0, 0, 0, 0, 0, 0, 2, 0];
+        args.extend_from_slice(&[1, 0, 2, 0]);
+
+        let req = Request::parse(&args).unwrap();
+        assert_eq!((7, 2, vec![1, 0, 2, 0]), (req.table, req.key_len, req.keys));
+        assert_eq!(0, Request::parse(&args[..10]).unwrap().keys.len());
+
+        assert!(Request::parse(&args[..13]).is_none());
+        assert!(Request::parse(&args[..9]).is_none());
+        args[8] = 0;
+        assert!(Request::parse(&args).is_none());
+    }
+
+    // This unit test verifies that values are appended with their lengths, and that missing
+    // ones are marked as such.
+    #[test]
+    fn test_respond() {
+        let mut resp = Vec::new();
+        respond(&mut resp, Some(&[9, 8]));
+        respond(&mut resp, None);
+        respond(&mut resp, Some(&[]));
+
+        assert_eq!(vec![2, 0, 0, 0, 9, 8, 255, 255, 255, 255, 0, 0, 0, 0], resp);
+    }
+}
@@ -0,0 +1,237 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+// The index_lookup() extension serves lookups on the inverted index the index_put() extension
+// maintains: it returns every object of a data table whose field holds a term. The arguments
+// are, little endian:
+//
+//   data table (u64) | index table (u64) | key length (u16) | offset (u16) | term length (u16) |
+//   term
+//
+// laid out as they were for index_put(). The keys on the term's posting list are looked up
+// `BATCH` at a time with one multiget, falling back to looking them up one by one if one of them
+// is missing, and the extension yields after every batch. Since index_put() cannot update the
+// index and the data table atomically, a posting list can hold keys whose objects were deleted or
+// no longer hold the term; every object is checked against the term before it is returned.
+//
+// The response is a status (u8), the number of objects (u32), and then every object, in the order
+// of the posting list, as it's key, the length of it's value (u32) and it's value.
+
+#![crate_type = "dylib"]
+#![feature(generators, generator_trait)]
+
+extern crate sandstorm;
+
+use std::ops::Generator;
+use std::rc::Rc;
+
+use sandstorm::db::DB;
+use sandstorm::pack::le;
+
+/// Status codes for the response to the tenant.
+const SUCCESSFUL: u8 = 0x01;
+const INVALIDARG: u8 = 0x02;
+
+/// The number of bytes of arguments ahead of the term.
+const ARGS_HDR_LEN: usize = 22;
+
+/// The number of objects looked up with one multiget.
+const BATCH: usize = 32;
+
+/// The parsed arguments to the extension.
+#[derive(Debug, PartialEq)]
+struct Request {
+    data: u64,
+    index: u64,
+    key_len: usize,
+    offset: usize,
+    term: Vec<u8>,
+}
+
+// Implementation of methods on Request.
+impl Request {
+    /// Parses the arguments to the extension.
+    ///
+    /// # Arguments
+    ///
+    /// * `args`: The arguments, laid out as described at the top of this file.
+    ///
+    /// # Return
+    ///
+    /// The request, or None if the arguments are malformed.
+    fn parse(args: &[u8]) -> Option<Request> {
+        if args.len() < ARGS_HDR_LEN {
+            return None;
+        }
+
+        let key_len = le(&args[16..18]) as usize;
+        let term_len = le(&args[20..22]) as usize;
+        if key_len == 0 || term_len == 0 || args.len() != ARGS_HDR_LEN + term_len {
+            return None;
+        }
+
+        Some(Request {
+            data: le(&args[..8]),
+            index: le(&args[8..16]),
+            key_len: key_len,
+            offset: le(&args[18..20]) as usize,
+            term: args[ARGS_HDR_LEN..].to_vec(),
+        })
+    }
+
+    /// Returns true if a value holds the term.
+    fn holds(&self, val: &[u8]) -> bool {
+        val.len() >= self.offset + self.term.len()
+            && &val[self.offset..self.offset + self.term.len()] == &self.term[..]
+    }
+}
+
+/// Appends an object to a response, if it's value holds the term.
+///
+/// # Arguments
+///
+/// * `req`:  The request.
+/// * `key`:  The object's key.
+/// * `val`:  The object's value.
+/// * `resp`: The response the object is appended to.
+///
+/// # Return
+///
+/// True if the object was appended.
+fn found(req: &Request, key: &[u8], val: &[u8], resp: &mut Vec<u8>) -> bool {
+    if !req.holds(val) {
+        return false;
+    }
+
+    resp.extend_from_slice(key);
+    resp.extend((0..4).map(|byte| (val.len() >> (8 * byte)) as u8));
+    resp.extend_from_slice(val);
+    true
+}
+
+/// This function implements the index_lookup() extension using the sandstorm interface.
+///
+/// # Arguments
+///
+/// * `db`: An argument whose type implements the `DB` trait which can be used
+///         to interact with the database.
+///
+/// # Return
+///
+/// A coroutine that can be run inside the database.
+#[no_mangle]
+#[allow(unreachable_code)]
+#[allow(unused_assignments)]
+pub fn init(db: Rc<DB>) -> Box<Generator<Yield = u64, Return = u64>> {
+    Box::new(move || {
+        // The arguments and the posting list are copied out, so that nothing borrowed from them
+        // is held across a yield.
+        let req = match Request::parse(db.args()) {
+            Some(req) => req,
+            None => {
+                db.resp(&[INVALIDARG]);
+                return 1;
+            }
+        };
+
+        let list = db.get(req.index, &req.term).map_or(Vec::new(), |obj| obj.read().to_vec());
+        let keys = list.len() / req.key_len;
+
+        let mut objects = Vec::new();
+        let mut count: u32 = 0;
+        let mut done = 0;
+        while done < keys {
+            let n = std::cmp::min(BATCH, keys - done);
+            let batch = list[done * req.key_len..(done + n) * req.key_len].to_vec();
+            done += n;
+
+            match db.multiget(req.data, req.key_len as u16, &batch) {
+                Some(ref objs) if objs.num() == n => {
+                    let mut idx = 0;
+                    loop {
+                        let key = &batch[idx * req.key_len..(idx + 1) * req.key_len];
+                        if found(&req, key, objs.read(), &mut objects) {
+                            count += 1;
+                        }
+                        idx += 1;
+                        if !objs.next() {
+                            break;
+                        }
+                    }
+                }
+
+                _ => {
+                    for key in batch.chunks(req.key_len) {
+                        if let Some(obj) = db.get(req.data, key) {
+                            if found(&req, key, obj.read(), &mut objects) {
+                                count += 1;
+                            }
+                        }
+                    }
+                }
+            }
+
+            // Yield down to the database between batches.
+            yield 0;
+        }
+
+        db.resp(&[SUCCESSFUL]);
+        db.resp(&(0..4).map(|byte| (count >> (8 * byte)) as u8).collect::<Vec<_>>());
+        db.resp(&objects);
+        return 0;
+
+        // XXX: This yield is required to get the compiler to compile this closure into a
+        // generator. It is unreachable and benign.
+        yield 0;
+    })
+}
+
+// This module contains unit tests for the index_lookup() extension.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Returns the arguments to look up a term at offset 1, with 2 byte keys.
+    fn args(term: &[u8]) -> Vec<u8> {
+        let mut args = vec![1, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 2, 0, 1, 0];
+        args.extend_from_slice(&[term.len() as u8, 0]);
+        args.extend_from_slice(term);
+        args
+    }
+
+    // This unit test verifies that well formed arguments are parsed, and malformed ones are not.
+    #[test]
+    fn test_parse() {
+        let req = Request::parse(&args(&[5, 6])).unwrap();
+        assert_eq!((1, 2, 2, 1), (req.data, req.index, req.key_len, req.offset));
+        assert_eq!(vec![5, 6], req.term);
+
+        assert!(Request::parse(&args(&[])).is_none());
+        let mut args = args(&[5, 6]);
+        args.pop();
+        assert!(Request::parse(&args).is_none());
+    }
+
+    // This unit test verifies that only objects whose value still holds the term are returned.
+    #[test]
+    fn test_found() {
+        let req = Request::parse(&args(&[5, 6])).unwrap();
+        let mut resp = Vec::new();
+        assert!(found(&req, &[1, 2], &[0, 5, 6, 7], &mut resp));
+        assert!(!found(&req, &[3, 4], &[0, 5, 7, 7], &mut resp));
+        assert!(!found(&req, &[3, 4], &[0, 5], &mut resp));
+        assert_eq!(vec![1, 2, 4, 0, 0, 0, 0, 5, 6, 7], resp);
+    }
+}
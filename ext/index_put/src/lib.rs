@@ -0,0 +1,301 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+// The index_put() extension writes an object to a data table, and maintains an inverted index
+// over one field of it's value in a companion index table, so that the index_lookup() extension
+// can find every object whose field holds a term without scanning the data table. The field is
+// the `term length` bytes at `offset` into the value. The index table holds, under every term, a
+// posting list: the keys of the objects holding the term, back to back. The arguments are,
+// little endian:
+//
+//   data table (u64) | index table (u64) | key length (u16) | offset (u16) | term length (u16) |
+//   key | value
+//
+// and the value must be long enough to hold the field. Every object indexed into an index table
+// must use the same key length, offset and term length.
+//
+// Extensions have no way to make several writes atomically, so the writes are ordered such that
+// the index never misses an object: the object's key is first added to the posting list of it's
+// new term, then the object is written, and only then is it's key removed from the posting list
+// of the term it held before, if that changed. A failure or a racing write can leave a key on a
+// posting list whose object no longer holds the term, which index_lookup() checks for, and a
+// later write to the object cleans up. Posting lists are rewritten whole on every change, so
+// their length is bounded by the number of bytes an invocation can allocate.
+//
+// The response is a status (u8): `SUCCESSFUL`, `INVALIDARG`, or `FAILED` if the database refused
+// one of the writes, usually for a quota.
+
+#![crate_type = "dylib"]
+#![feature(generators, generator_trait)]
+
+extern crate sandstorm;
+
+use std::ops::Generator;
+use std::rc::Rc;
+
+use sandstorm::db::DB;
+use sandstorm::pack::le;
+
+/// Status codes for the response to the tenant.
+const SUCCESSFUL: u8 = 0x01;
+const INVALIDARG: u8 = 0x02;
+const FAILED: u8 = 0x03;
+
+/// The number of bytes of arguments ahead of the key.
+const ARGS_HDR_LEN: usize = 22;
+
+/// The parsed arguments to the extension.
+#[derive(Debug, PartialEq)]
+struct Request {
+    data: u64,
+    index: u64,
+    offset: usize,
+    term_len: usize,
+    key: Vec<u8>,
+    val: Vec<u8>,
+}
+
+// Implementation of methods on Request.
+impl Request {
+    /// Parses the arguments to the extension.
+    ///
+    /// # Arguments
+    ///
+    /// * `args`: The arguments, laid out as described at the top of this file.
+    ///
+    /// # Return
+    ///
+    /// The request, or None if the arguments are malformed.
+    fn parse(args: &[u8]) -> Option<Request> {
+        if args.len() < ARGS_HDR_LEN {
+            return None;
+        }
+
+        let key_len = le(&args[16..18]) as usize;
+        let req = Request {
+            data: le(&args[..8]),
+            index: le(&args[8..16]),
+            offset: le(&args[18..20]) as usize,
+            term_len: le(&args[20..22]) as usize,
+            key: args[ARGS_HDR_LEN..].iter().take(key_len).cloned().collect(),
+            val: args[ARGS_HDR_LEN..].iter().skip(key_len).cloned().collect(),
+        };
+
+        if key_len == 0
+            || req.key.len() < key_len
+            || req.term_len == 0
+            || req.data == req.index
+            || term(&req.val, req.offset, req.term_len).is_none()
+        {
+            return None;
+        }
+
+        Some(req)
+    }
+}
+
+/// Returns the term a value holds, or None if it is too short to hold the field.
+fn term(val: &[u8], offset: usize, term_len: usize) -> Option<&[u8]> {
+    if val.len() < offset + term_len {
+        return None;
+    }
+
+    Some(&val[offset..offset + term_len])
+}
+
+/// Returns a posting list with a key added to it, or None if the key is already on it.
+///
+/// # Arguments
+///
+/// * `list`: The posting list.
+/// * `key`:  The key. Every key on the list must be as long as it.
+fn add(list: &[u8], key: &[u8]) -> Option<Vec<u8>> {
+    if list.chunks(key.len()).any(|k| k == key) {
+        return None;
+    }
+
+    let mut out = Vec::with_capacity(list.len() + key.len());
+    out.extend_from_slice(list);
+    out.extend_from_slice(key);
+    Some(out)
+}
+
+/// Returns a posting list with a key removed from it, or None if the key is not on it.
+///
+/// # Arguments
+///
+/// * `list`: The posting list.
+/// * `key`:  The key. Every key on the list must be as long as it.
+fn remove(list: &[u8], key: &[u8]) -> Option<Vec<u8>> {
+    if !list.chunks(key.len()).any(|k| k == key) {
+        return None;
+    }
+
+    let mut out = Vec::with_capacity(list.len());
+    for k in list.chunks(key.len()).filter(|k| *k != key) {
+        out.extend_from_slice(k);
+    }
+    Some(out)
+}
+
+/// Writes an object, replacing any object with it's key.
+///
+/// # Arguments
+///
+/// * `db`:    The database.
+/// * `table`: The table the object is written to.
+/// * `key`:   The object's key.
+/// * `val`:   The object's value.
+///
+/// # Return
+///
+/// True if the object was written.
+fn write(db: &Rc<DB>, table: u64, key: &[u8], val: &[u8]) -> bool {
+    match db.alloc(table, key, val.len() as u64) {
+        Some(mut buf) => {
+            buf.write_slice(val);
+            db.put(buf)
+        }
+        None => false,
+    }
+}
+
+/// Indexes and writes the object in a request, as described at the top of this file.
+///
+/// # Return
+///
+/// True if every write was made.
+fn index_put(db: &Rc<DB>, req: &Request) -> bool {
+    let new = term(&req.val, req.offset, req.term_len).unwrap_or(&[]);
+    let old = db.get(req.data, &req.key).and_then(|obj| {
+        term(obj.read(), req.offset, req.term_len).map(|term| term.to_vec())
+    });
+
+    // Nothing changes on the index if the object keeps it's term.
+    let moved = old.as_ref().map_or(true, |old| &old[..] != new);
+    if moved {
+        let list = db.get(req.index, new).map_or(Vec::new(), |obj| obj.read().to_vec());
+        if let Some(list) = add(&list, &req.key) {
+            if !write(db, req.index, new, &list) {
+                return false;
+            }
+        }
+    }
+
+    if !write(db, req.data, &req.key, &req.val) {
+        return false;
+    }
+
+    if let (true, Some(old)) = (moved, old) {
+        let list = db.get(req.index, &old).map_or(Vec::new(), |obj| obj.read().to_vec());
+        match remove(&list, &req.key) {
+            Some(ref list) if list.len() == 0 => db.del(req.index, &old),
+            Some(list) => return write(db, req.index, &old, &list),
+            None => {}
+        }
+    }
+
+    true
+}
+
+/// This function implements the index_put() extension using the sandstorm interface.
+///
+/// # Arguments
+///
+/// * `db`: An argument whose type implements the `DB` trait which can be used
+///         to interact with the database.
+///
+/// # Return
+///
+/// A coroutine that can be run inside the database.
+#[no_mangle]
+#[allow(unreachable_code)]
+#[allow(unused_assignments)]
+pub fn init(db: Rc<DB>) -> Box<Generator<Yield = u64, Return = u64>> {
+    Box::new(move || {
+        let req = match Request::parse(db.args()) {
+            Some(req) => req,
+            None => {
+                db.resp(&[INVALIDARG]);
+                return 1;
+            }
+        };
+
+        match index_put(&db, &req) {
+            true => {
+                db.resp(&[SUCCESSFUL]);
+                return 0;
+            }
+
+            false => {
+                db.resp(&[FAILED]);
+                return 1;
+            }
+        }
+
+        // XXX: This yield is required to get the compiler to compile this closure into a
+        // generator. It is unreachable and benign.
+        yield 0;
+    })
+}
+
+// This module contains unit tests for the index_put() extension.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Returns the arguments to index an object with a 2 byte key, whose term is 2 bytes long at
+    // offset 1.
+    fn args(key: &[u8], val: &[u8]) -> Vec<u8> {
+        let mut args = vec![1, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0];
+        args.extend_from_slice(&[key.len() as u8, 0, 1, 0, 2, 0]);
+        args.extend_from_slice(key);
+        args.extend_from_slice(val);
+        args
+    }
+
+    // This unit test verifies that well formed arguments are parsed, and that arguments whose
+    // value cannot hold the term or whose key is truncated are not.
+    #[test]
+    fn test_parse() {
+        let req = Request::parse(&args(&[7, 8], &[1, 2, 3, 4])).unwrap();
+        assert_eq!((1, 2, 1, 2), (req.data, req.index, req.offset, req.term_len));
+        assert_eq!((vec![7, 8], vec![1, 2, 3, 4]), (req.key, req.val));
+        assert_eq!(Some(&[2, 3][..]), term(&[1, 2, 3, 4], 1, 2));
+
+        assert!(Request::parse(&args(&[7, 8], &[1, 2])).is_none());
+        assert!(Request::parse(&args(&[7, 8], &[])).is_none());
+        let mut args = args(&[7, 8], &[1, 2, 3]);
+        args[16] = 4;
+        assert!(Request::parse(&args).is_none());
+        args[16] = 2;
+        args[8] = 1;
+        assert!(Request::parse(&args).is_none());
+    }
+
+    // This unit test verifies that keys are added to posting lists once, and removed from them
+    // only if they are on them.
+    #[test]
+    fn test_postings() {
+        let list = add(&[], &[1, 2]).unwrap();
+        let list = add(&list, &[3, 4]).unwrap();
+        assert_eq!(vec![1, 2, 3, 4], list);
+        assert!(add(&list, &[3, 4]).is_none());
+
+        assert_eq!(Some(vec![3, 4]), remove(&list, &[1, 2]));
+        assert_eq!(Some(vec![]), remove(&[3, 4], &[3, 4]));
+        assert!(remove(&list, &[2, 3]).is_none());
+    }
+}
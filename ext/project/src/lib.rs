@@ -0,0 +1,460 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+// The project() extension looks up a list of keys and returns a row of computed columns for
+// every object, like the SELECT list of a SQL query, so that a query layer on the client can
+// compile projections and arithmetic over fields of a value down to a single invoke instead of
+// fetching whole objects. The arguments are, little endian:
+//
+//   table (u64) | key length (u16) | number of keys (u32) | number of columns (u8) |
+//   columns | keys
+//
+// where every column is the length of it's expression (u16) followed by the expression, and the
+// keys follow each other, `key length` bytes each. An expression is a program in postfix order,
+// built by `splinter_client::project::Expr`:
+//
+//   FIELD (0x01) | field offset (u16) | field width (u8)
+//       pushes the field, an unsigned integer `width` (1, 2, 4 or 8) bytes long at `offset`
+//       into the value read little endian. It is null if the value is too short to hold it.
+//   CONST (0x02) | constant (u64)
+//       pushes the constant.
+//   ADD (0x03), SUB (0x04), MUL (0x05), DIV (0x06), MOD (0x07)
+//       pop the right operand and then the left one, and push the result. Addition,
+//       subtraction and multiplication wrap around; division and remainder by zero are null,
+//       and so is the result of any operation on a null.
+//
+// and must leave exactly one result. There can be upto `MAX_COLUMNS` columns. The response is a
+// status (u8), the number of rows (u32), and then a row for every key that exists, in the order
+// of the keys, as the key, a null mask (u8, with bit `i` set if column `i` is null), and the
+// value of every column (u64, zero if null). Keys that do not exist have no row. The extension
+// yields every `YIELD_EVERY` keys, so that long lists do not hold up other requests.
+
+#![crate_type = "dylib"]
+#![feature(generators, generator_trait)]
+
+extern crate sandstorm;
+
+use std::ops::Generator;
+use std::rc::Rc;
+
+use sandstorm::db::DB;
+use sandstorm::pack::le;
+
+/// Status codes for the response to the tenant.
+const SUCCESSFUL: u8 = 0x01;
+const INVALIDARG: u8 = 0x02;
+
+/// The instructions of an expression.
+const FIELD: u8 = 0x01;
+const CONST: u8 = 0x02;
+const ADD: u8 = 0x03;
+const SUB: u8 = 0x04;
+const MUL: u8 = 0x05;
+const DIV: u8 = 0x06;
+const MOD: u8 = 0x07;
+
+/// The number of bytes in a FIELD and a CONST instruction.
+const FIELD_LEN: usize = 4;
+const CONST_LEN: usize = 9;
+
+/// The number of bytes of arguments ahead of the columns.
+const ARGS_HDR_LEN: usize = 15;
+
+/// The largest number of columns, so that a row's null mask fits in a byte.
+const MAX_COLUMNS: usize = 8;
+
+/// The number of keys looked up between yields.
+const YIELD_EVERY: usize = 64;
+
+/// An instruction of an expression.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Insn {
+    /// Pushes a field of the value: it's offset and width.
+    Field(usize, usize),
+
+    /// Pushes a constant.
+    Const(u64),
+
+    /// Pops two operands and pushes the result of an arithmetic operation on them: one of `ADD`
+    /// to `MOD`.
+    Arith(u8),
+}
+
+/// The parsed arguments to the extension.
+#[derive(Debug, PartialEq)]
+struct Request {
+    table: u64,
+    key_len: usize,
+    columns: Vec<Vec<Insn>>,
+    keys: Vec<u8>,
+}
+
+// Implementation of methods on Request.
+impl Request {
+    /// Parses the arguments to the extension.
+    ///
+    /// # Arguments
+    ///
+    /// * `args`: The arguments, laid out as described at the top of this file.
+    ///
+    /// # Return
+    ///
+    /// The request, or None if the arguments or any of the expressions are malformed.
+    fn parse(args: &[u8]) -> Option<Request> {
+        if args.len() < ARGS_HDR_LEN {
+            return None;
+        }
+
+        let key_len = le(&args[8..10]) as usize;
+        let count = le(&args[10..14]) as usize;
+        let ncols = args[14] as usize;
+        if key_len == 0 || ncols == 0 || ncols > MAX_COLUMNS {
+            return None;
+        }
+
+        let mut columns = Vec::with_capacity(ncols);
+        let mut off = ARGS_HDR_LEN;
+        for _ in 0..ncols {
+            if args.len() - off < 2 {
+                return None;
+            }
+            let len = le(&args[off..off + 2]) as usize;
+            off += 2;
+            if args.len() - off < len {
+                return None;
+            }
+
+            match compile(&args[off..off + len]) {
+                Some(column) => columns.push(column),
+                None => return None,
+            }
+            off += len;
+        }
+
+        if (args.len() - off) as u64 != count as u64 * key_len as u64 {
+            return None;
+        }
+
+        Some(Request {
+            table: le(&args[..8]),
+            key_len: key_len,
+            columns: columns,
+            keys: args[off..].to_vec(),
+        })
+    }
+}
+
+/// Parses an expression, and checks that it leaves exactly one result without running out of
+/// operands along the way.
+///
+/// # Arguments
+///
+/// * `buf`: The expression.
+///
+/// # Return
+///
+/// The instructions of the expression, or None if it is malformed.
+fn compile(buf: &[u8]) -> Option<Vec<Insn>> {
+    let mut insns = Vec::new();
+    let mut depth = 0;
+    let mut off = 0;
+    while off < buf.len() {
+        match buf[off] {
+            FIELD if buf.len() - off >= FIELD_LEN => {
+                let width = buf[off + 3] as usize;
+                if !(width == 1 || width == 2 || width == 4 || width == 8) {
+                    return None;
+                }
+
+                insns.push(Insn::Field(le(&buf[off + 1..off + 3]) as usize, width));
+                depth += 1;
+                off += FIELD_LEN;
+            }
+
+            CONST if buf.len() - off >= CONST_LEN => {
+                insns.push(Insn::Const(le(&buf[off + 1..off + CONST_LEN])));
+                depth += 1;
+                off += CONST_LEN;
+            }
+
+            ADD | SUB | MUL | DIV | MOD if depth >= 2 => {
+                insns.push(Insn::Arith(buf[off]));
+                depth -= 1;
+                off += 1;
+            }
+
+            _ => return None,
+        }
+    }
+
+    match depth {
+        1 => Some(insns),
+        _ => None,
+    }
+}
+
+/// Evaluates an expression over a value.
+///
+/// # Arguments
+///
+/// * `expr`:  The instructions of the expression, as checked by `compile()`.
+/// * `val`:   The value.
+/// * `stack`: A stack to evaluate the expression on, reused across values.
+///
+/// # Return
+///
+/// The result of the expression, or None if it is null.
+fn eval(expr: &[Insn], val: &[u8], stack: &mut Vec<Option<u64>>) -> Option<u64> {
+    stack.clear();
+    for insn in expr.iter() {
+        let result = match *insn {
+            Insn::Field(offset, width) if val.len() >= offset + width => {
+                Some(le(&val[offset..offset + width]))
+            }
+            Insn::Field(..) => None,
+            Insn::Const(constant) => Some(constant),
+            Insn::Arith(op) => {
+                let right = stack.pop().unwrap();
+                let left = stack.pop().unwrap();
+                match (left, right) {
+                    (Some(l), Some(r)) => match op {
+                        ADD => Some(l.wrapping_add(r)),
+                        SUB => Some(l.wrapping_sub(r)),
+                        MUL => Some(l.wrapping_mul(r)),
+                        DIV => l.checked_div(r),
+                        _ => l.checked_rem(r),
+                    },
+                    _ => None,
+                }
+            }
+        };
+        stack.push(result);
+    }
+
+    stack.pop().unwrap_or(None)
+}
+
+/// Appends the row of an object to the response, laid out as described at the top of this file.
+///
+/// # Arguments
+///
+/// * `columns`: The expressions of the columns.
+/// * `key`:     The object's key.
+/// * `val`:     The object's value.
+/// * `stack`:   A stack to evaluate the expressions on.
+/// * `rows`:    The rows to append the row to.
+fn project(
+    columns: &[Vec<Insn>],
+    key: &[u8],
+    val: &[u8],
+    stack: &mut Vec<Option<u64>>,
+    rows: &mut Vec<u8>,
+) {
+    rows.extend_from_slice(key);
+
+    let mask = rows.len();
+    rows.push(0);
+    for (idx, column) in columns.iter().enumerate() {
+        let result = match eval(column, val, stack) {
+            Some(result) => result,
+            None => {
+                rows[mask] |= 1 << idx;
+                0
+            }
+        };
+        rows.extend((0..8).map(|byte| (result >> (8 * byte)) as u8));
+    }
+}
+
+/// This function implements the project() extension using the sandstorm interface.
+///
+/// # Arguments
+///
+/// * `db`: An argument whose type implements the `DB` trait which can be used
+///         to interact with the database.
+///
+/// # Return
+///
+/// A coroutine that can be run inside the database.
+#[no_mangle]
+#[allow(unreachable_code)]
+#[allow(unused_assignments)]
+pub fn init(db: Rc<DB>) -> Box<Generator<Yield = u64, Return = u64>> {
+    Box::new(move || {
+        // The arguments are copied out, so that nothing borrowed from them is held across a
+        // yield.
+        let req = match Request::parse(db.args()) {
+            Some(req) => req,
+            None => {
+                db.resp(&[INVALIDARG]);
+                return 1;
+            }
+        };
+
+        // Rows are collected before their number, which goes first, is known.
+        let mut stack = Vec::new();
+        let mut key = vec![0; req.key_len];
+        let mut rows = Vec::new();
+        let mut count: u32 = 0;
+        let mut idx = 0;
+        while idx < req.keys.len() / req.key_len {
+            key.copy_from_slice(&req.keys[idx * req.key_len..(idx + 1) * req.key_len]);
+            if let Some(obj) = db.get(req.table, &key) {
+                project(&req.columns, &key, obj.read(), &mut stack, &mut rows);
+                count += 1;
+            }
+            idx += 1;
+
+            // Yield down to the database every few keys.
+            if idx % YIELD_EVERY == 0 {
+                yield 0;
+            }
+        }
+
+        let count: Vec<u8> = (0..4).map(|byte| (count >> (8 * byte)) as u8).collect();
+        db.resp(&[SUCCESSFUL]);
+        db.resp(&count);
+        db.resp(&rows);
+        return 0;
+
+        // XXX: This yield is required to get the compiler to compile this closure into a
+        // generator. It is unreachable and benign.
+        yield 0;
+    })
+}
+
+// This module contains unit tests for the project() extension.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Returns a FIELD instruction.
+    fn field(offset: u16, width: u8) -> Vec<u8> {
+        vec![FIELD, offset as u8, (offset >> 8) as u8, width]
+    }
+
+    // Returns a CONST instruction.
+    fn constant(val: u64) -> Vec<u8> {
+        let mut insn = vec![CONST];
+        insn.extend((0..8).map(|byte| (val >> (8 * byte)) as u8));
+        insn
+    }
+
+    // This unit test verifies that well formed expressions are parsed, and that expressions that
+    // leave anything but one result, or are truncated, are not.
+    #[test]
+    fn test_compile() {
+        let mut buf = field(4, 2);
+        assert_eq!(Some(vec![Insn::Field(4, 2)]), compile(&buf));
+
+        buf.extend(constant(3));
+        assert!(compile(&buf).is_none());
+        buf.push(MUL);
+        assert_eq!(
+            Some(vec![Insn::Field(4, 2), Insn::Const(3), Insn::Arith(MUL)]),
+            compile(&buf)
+        );
+        buf.push(ADD);
+        assert!(compile(&buf).is_none());
+
+        assert!(compile(&[]).is_none());
+        assert!(compile(&field(0, 3)).is_none());
+        assert!(compile(&field(0, 1)[..FIELD_LEN - 1]).is_none());
+        assert!(compile(&constant(1)[..CONST_LEN - 1]).is_none());
+        assert!(compile(&[0x08]).is_none());
+    }
+
+    // This unit test verifies that every operation evaluates as it should, and that fields past
+    // the end of the value and division by zero are null, as is anything computed from them.
+    #[test]
+    fn test_eval() {
+        let mut stack = Vec::new();
+        let val = [5, 0x34, 0x12];
+        let run = |ops: &[&Vec<u8>], stack: &mut Vec<Option<u64>>| {
+            let buf: Vec<u8> = ops.iter().flat_map(|op| op.iter().cloned()).collect();
+            eval(&compile(&buf).unwrap(), &val, stack)
+        };
+        let (f0, f1, f2) = (field(0, 1), field(1, 2), field(2, 2));
+        let (c0, c7) = (constant(0), constant(7));
+
+        assert_eq!(Some(0x1234), run(&[&f1], &mut stack));
+        assert_eq!(None, run(&[&f2], &mut stack));
+        assert_eq!(Some(12), run(&[&f0, &c7, &vec![ADD]], &mut stack));
+        assert_eq!(Some(2), run(&[&c7, &f0, &vec![SUB]], &mut stack));
+        assert_eq!(Some(u64::max_value() - 1), run(&[&f0, &c7, &vec![SUB]], &mut stack));
+        assert_eq!(Some(35), run(&[&f0, &c7, &vec![MUL]], &mut stack));
+        assert_eq!(Some(1), run(&[&c7, &f0, &vec![DIV]], &mut stack));
+        assert_eq!(Some(2), run(&[&c7, &f0, &vec![MOD]], &mut stack));
+        assert_eq!(None, run(&[&c7, &c0, &vec![DIV]], &mut stack));
+        assert_eq!(None, run(&[&c7, &c0, &vec![MOD]], &mut stack));
+        assert_eq!(None, run(&[&f2, &c7, &vec![ADD]], &mut stack));
+
+        // (field1 - field0) / 7
+        assert_eq!(
+            Some((0x1234 - 5) / 7),
+            run(&[&f1, &f0, &vec![SUB], &c7, &vec![DIV]], &mut stack)
+        );
+    }
+
+    // This unit test verifies that rows hold the key, the null mask and every column.
+    #[test]
+    fn test_project() {
+        let columns = vec![
+            compile(&field(0, 1)).unwrap(),
+            compile(&field(8, 1)).unwrap(),
+            compile(&constant(0x102)).unwrap(),
+        ];
+        let mut stack = Vec::new();
+        let mut rows = Vec::new();
+        project(&columns, &[9, 9], &[4, 5], &mut stack, &mut rows);
+
+        let mut expected = vec![9, 9, 0b010];
+        expected.extend_from_slice(&[4, 0, 0, 0, 0, 0, 0, 0]);
+        expected.extend_from_slice(&[0; 8]);
+        expected.extend_from_slice(&[2, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(expected, rows);
+    }
+
+    // This unit test verifies that the arguments are parsed, and that malformed ones are not.
+    #[test]
+    fn test_parse() {
+        let mut args = vec![3, 0, 0, 0, 0, 0, 0, 0, 2, 0, 2, 0, 0, 0, 2];
+        for expr in [field(0, 4), constant(1)].iter() {
+            args.extend_from_slice(&[expr.len() as u8, 0]);
+            args.extend_from_slice(expr);
+        }
+        args.extend_from_slice(&[1, 0, 2, 0]);
+
+        let req = Request::parse(&args).unwrap();
+        assert_eq!((3, 2), (req.table, req.key_len));
+        assert_eq!(
+            vec![vec![Insn::Field(0, 4)], vec![Insn::Const(1)]],
+            req.columns
+        );
+        assert_eq!(vec![1, 0, 2, 0], req.keys);
+
+        assert!(Request::parse(&args[..args.len() - 1]).is_none());
+        assert!(Request::parse(&args[..ARGS_HDR_LEN]).is_none());
+
+        let mut bad = args.clone();
+        bad[14] = 0;
+        assert!(Request::parse(&bad).is_none());
+        bad[14] = MAX_COLUMNS as u8 + 1;
+        assert!(Request::parse(&bad).is_none());
+        bad[14] = 2;
+        bad[15] = 3;
+        assert!(Request::parse(&bad).is_none());
+    }
+}
@@ -0,0 +1,355 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+// The score() extension scores a list of items against a linear model, the way a
+// recommendation service ranks candidates: it looks up every item's sparse feature vector, and
+// returns it's dot product with the model's weights, so that neither the features nor the model
+// leave the server. The arguments are, little endian:
+//
+//   data table (u64) | model table (u64) | key length (u16) | model key length (u16) |
+//   number of keys (u32) | keys
+//
+// with the keys following each other, `key length` bytes each. An item's value is it's sparse
+// feature vector, a list of feature index (u32) and value (f32) pairs; a partial pair at the end
+// is ignored. The model is a dense vector of f32 weights split into chunks of `CHUNK` weights,
+// with chunk `c` stored under the key holding `c` little endian in it's first eight bytes, any
+// bytes after those zero. Weights missing from the model, because their chunk does not exist or
+// is too short, are zero.
+//
+// The model table is typically owned by another tenant and shared with the ones scoring items
+// against it for reading only, so that one copy of the model serves every tenant. Chunks are
+// cached for the length of a request, upto `MAX_CHUNKS` of them, since items tend to share
+// features.
+//
+// The response is a status (u8), the number of keys (u32), and then for every key, in order,
+// whether it exists (u8, 1 if it does) and it's score (f32, zero if it does not). The extension
+// yields every `YIELD_EVERY` keys, so that long lists do not hold up other requests.
+
+#![crate_type = "dylib"]
+#![feature(generators, generator_trait)]
+
+extern crate sandstorm;
+
+use std::collections::HashMap;
+use std::ops::Generator;
+use std::rc::Rc;
+
+use sandstorm::db::DB;
+use sandstorm::pack::le;
+
+/// Status codes for the response to the tenant.
+const SUCCESSFUL: u8 = 0x01;
+const INVALIDARG: u8 = 0x02;
+
+/// The number of bytes of arguments ahead of the keys.
+const ARGS_HDR_LEN: usize = 24;
+
+/// The number of weights in a chunk of the model.
+const CHUNK: u64 = 64;
+
+/// The largest number of chunks of the model cached by a request.
+const MAX_CHUNKS: usize = 256;
+
+/// The number of keys scored between yields.
+const YIELD_EVERY: usize = 16;
+
+/// The parsed arguments to the extension.
+#[derive(Debug, PartialEq)]
+struct Request {
+    data: u64,
+    model: u64,
+    key_len: usize,
+    model_key_len: usize,
+    keys: Vec<u8>,
+}
+
+// Implementation of methods on Request.
+impl Request {
+    /// Parses the arguments to the extension.
+    ///
+    /// # Arguments
+    ///
+    /// * `args`: The arguments, laid out as described at the top of this file.
+    ///
+    /// # Return
+    ///
+    /// The request, or None if the arguments are malformed.
+    fn parse(args: &[u8]) -> Option<Request> {
+        if args.len() < ARGS_HDR_LEN {
+            return None;
+        }
+
+        let key_len = le(&args[16..18]) as usize;
+        let model_key_len = le(&args[18..20]) as usize;
+        let count = le(&args[20..24]);
+        if key_len == 0 || model_key_len < 8 {
+            return None;
+        }
+
+        if (args.len() - ARGS_HDR_LEN) as u64 != count * key_len as u64 {
+            return None;
+        }
+
+        Some(Request {
+            data: le(&args[..8]),
+            model: le(&args[8..16]),
+            key_len: key_len,
+            model_key_len: model_key_len,
+            keys: args[ARGS_HDR_LEN..].to_vec(),
+        })
+    }
+
+    /// Returns the key the chunk of the model with a number is stored under.
+    fn chunk_key(&self, chunk: u64) -> Vec<u8> {
+        (0..self.model_key_len)
+            .map(|byte| if byte < 8 { (chunk >> (8 * byte)) as u8 } else { 0 })
+            .collect()
+    }
+}
+
+/// The chunks of the model fetched so far by a request.
+struct Model {
+    chunks: HashMap<u64, Vec<f32>>,
+}
+
+// Implementation of methods on Model.
+impl Model {
+    /// Returns an empty cache of chunks.
+    fn new() -> Model {
+        Model {
+            chunks: HashMap::new(),
+        }
+    }
+
+    /// Returns a weight of the model, fetching it's chunk if it is not cached. The cache is
+    /// emptied once it holds `MAX_CHUNKS` chunks.
+    ///
+    /// # Arguments
+    ///
+    /// * `index`: The index of the weight.
+    /// * `fetch`: Returns the weights of a chunk, or None if it does not exist.
+    fn weight<F: FnMut(u64) -> Option<Vec<f32>>>(&mut self, index: u32, fetch: &mut F) -> f32 {
+        let chunk = index as u64 / CHUNK;
+        if !self.chunks.contains_key(&chunk) {
+            if self.chunks.len() >= MAX_CHUNKS {
+                self.chunks.clear();
+            }
+            self.chunks.insert(chunk, fetch(chunk).unwrap_or(Vec::new()));
+        }
+
+        let weights = &self.chunks[&chunk];
+        let idx = (index as u64 % CHUNK) as usize;
+        if idx < weights.len() {
+            weights[idx]
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Returns the dot product of a sparse feature vector with the model.
+///
+/// # Arguments
+///
+/// * `features`: The feature vector, laid out as described at the top of this file.
+/// * `model`:    The chunks of the model fetched so far.
+/// * `fetch`:    Returns the weights of a chunk of the model, or None if it does not exist.
+fn score<F>(features: &[u8], model: &mut Model, fetch: &mut F) -> f32
+where
+    F: FnMut(u64) -> Option<Vec<f32>>,
+{
+    let mut sum = 0.0;
+    for pair in features.chunks(8) {
+        if pair.len() < 8 {
+            break;
+        }
+
+        let index = le(&pair[..4]) as u32;
+        let value = f32::from_bits(le(&pair[4..]) as u32);
+        sum += value * model.weight(index, fetch);
+    }
+    sum
+}
+
+/// Decodes the weights of a chunk of the model, ignoring a partial weight at the end.
+fn weights(buf: &[u8]) -> Vec<f32> {
+    buf.chunks(4)
+        .filter(|c| c.len() == 4)
+        .map(|c| f32::from_bits(le(c) as u32))
+        .collect()
+}
+
+/// This function implements the score() extension using the sandstorm interface.
+///
+/// # Arguments
+///
+/// * `db`: An argument whose type implements the `DB` trait which can be used
+///         to interact with the database.
+///
+/// # Return
+///
+/// A coroutine that can be run inside the database.
+#[no_mangle]
+#[allow(unreachable_code)]
+#[allow(unused_assignments)]
+pub fn init(db: Rc<DB>) -> Box<Generator<Yield = u64, Return = u64>> {
+    Box::new(move || {
+        // The arguments are copied out, so that nothing borrowed from them is held across a
+        // yield.
+        let req = match Request::parse(db.args()) {
+            Some(req) => req,
+            None => {
+                db.resp(&[INVALIDARG]);
+                return 1;
+            }
+        };
+
+        let count = req.keys.len() / req.key_len;
+        let mut resp = Vec::with_capacity(5 + 5 * count);
+        resp.push(SUCCESSFUL);
+        resp.extend((0..4).map(|byte| (count >> (8 * byte)) as u8));
+
+        let mut model = Model::new();
+        let mut idx = 0;
+        while idx < count {
+            {
+                let key = &req.keys[idx * req.key_len..(idx + 1) * req.key_len];
+                let mut fetch = |chunk: u64| {
+                    db.get(req.model, &req.chunk_key(chunk))
+                        .map(|obj| weights(obj.read()))
+                };
+
+                match db.get(req.data, key) {
+                    Some(obj) => {
+                        let s = score(obj.read(), &mut model, &mut fetch);
+                        resp.push(1);
+                        resp.extend((0..4).map(|byte| (s.to_bits() >> (8 * byte)) as u8));
+                    }
+
+                    None => resp.extend_from_slice(&[0, 0, 0, 0, 0]),
+                }
+            }
+            idx += 1;
+
+            // Yield down to the database every few keys.
+            if idx % YIELD_EVERY == 0 {
+                yield 0;
+            }
+        }
+
+        db.resp(&resp);
+        return 0;
+
+        // XXX: This yield is required to get the compiler to compile this closure into a
+        // generator. It is unreachable and benign.
+        yield 0;
+    })
+}
+
+// This module contains unit tests for the score() extension.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Returns the little endian bytes of a list of feature index and value pairs.
+    fn features(pairs: &[(u32, f32)]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for &(index, value) in pairs.iter() {
+            buf.extend((0..4).map(|byte| (index >> (8 * byte)) as u8));
+            buf.extend((0..4).map(|byte| (value.to_bits() >> (8 * byte)) as u8));
+        }
+        buf
+    }
+
+    // This unit test verifies that the arguments are parsed, that chunk keys are laid out the
+    // way the workloads lay keys out, and that malformed arguments are not parsed.
+    #[test]
+    fn test_parse() {
+        let mut args = vec![1, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0];
+        args.extend_from_slice(&[2, 0, 10, 0, 3, 0, 0, 0]);
+        args.extend_from_slice(&[1, 0, 2, 0, 3, 0]);
+
+        let req = Request::parse(&args).unwrap();
+        assert_eq!((1, 2, 2, 10), (req.data, req.model, req.key_len, req.model_key_len));
+        assert_eq!(vec![1, 0, 2, 0, 3, 0], req.keys);
+        assert_eq!(vec![4, 1, 0, 0, 0, 0, 0, 0, 0, 0], req.chunk_key(0x104));
+
+        assert!(Request::parse(&args[..args.len() - 1]).is_none());
+        args[18] = 7;
+        assert!(Request::parse(&args).is_none());
+        args[18] = 10;
+        args[16] = 0;
+        assert!(Request::parse(&args).is_none());
+    }
+
+    // This unit test verifies that scores are the dot product of the features with the model,
+    // that weights missing from the model are zero, and that chunks are fetched once.
+    #[test]
+    fn test_score() {
+        let mut fetched = Vec::new();
+        {
+            let mut fetch = |chunk: u64| {
+                fetched.push(chunk);
+                match chunk {
+                    0 => Some((0..CHUNK).map(|w| w as f32).collect()),
+                    1 => Some(vec![0.5; 2]),
+                    _ => None,
+                }
+            };
+
+            let mut model = Model::new();
+            let item = features(&[(3, 2.0), (CHUNK as u32, 4.0), (CHUNK as u32 + 5, 1.0)]);
+            assert_eq!(8.0, score(&item, &mut model, &mut fetch));
+
+            let item = features(&[(10, 0.5), (3 * CHUNK as u32, 9.0)]);
+            assert_eq!(5.0, score(&item, &mut model, &mut fetch));
+
+            let mut partial = features(&[(1, 1.0)]);
+            partial.extend_from_slice(&[2, 0, 0]);
+            assert_eq!(1.0, score(&partial, &mut model, &mut fetch));
+            assert_eq!(0.0, score(&[], &mut model, &mut fetch));
+        }
+
+        assert_eq!(vec![0, 1, 3], fetched);
+    }
+
+    // This unit test verifies that the cache of chunks is emptied once it is full.
+    #[test]
+    fn test_evict() {
+        let mut fetched = 0;
+        {
+            let mut fetch = |_chunk: u64| {
+                fetched += 1;
+                Some(vec![1.0; CHUNK as usize])
+            };
+
+            let mut model = Model::new();
+            for chunk in 0..MAX_CHUNKS as u32 + 1 {
+                assert_eq!(1.0, model.weight(chunk * CHUNK as u32, &mut fetch));
+            }
+            assert_eq!(1, model.chunks.len());
+            assert_eq!(1.0, model.weight(0, &mut fetch));
+        }
+        assert_eq!(MAX_CHUNKS + 2, fetched);
+    }
+
+    // This unit test verifies that chunks are decoded into their weights.
+    #[test]
+    fn test_weights() {
+        let buf = features(&[(0, 1.5)]);
+        assert_eq!(vec![0.0, 1.5], weights(&buf));
+        assert_eq!(vec![0.0], weights(&buf[..7]));
+    }
+}
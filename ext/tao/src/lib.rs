@@ -10,7 +10,7 @@ use sandstorm::db::DB;
 use sandstorm::{LittleEndian, ReadBytesExt, WriteBytesExt};
 
 use sandstorm::boxed::Box;
-use sandstorm::convert::From;
+use sandstorm::convert::TryFrom;
 use sandstorm::rc::Rc;
 use sandstorm::result::Result;
 use sandstorm::size_of;
@@ -22,6 +22,16 @@ type Id = u64;
 type ObjectType = u16;
 type Time = u64;
 
+/// The number of times an association list is read and written back with a compare and swap
+/// before an update to it gives up.
+const MAX_RETRIES: usize = 16;
+
+/// The largest number of bytes of associations an assoc_get or assoc_range responds with.
+/// Keeps the response within a single frame; a range cut short can be continued from the
+/// position after the last association returned.
+const MAX_RESP: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 enum TaoOp {
     ObjGet = 0,
     ObjAdd = 1,
@@ -30,26 +40,31 @@ enum TaoOp {
     AssocGet = 4,
     AssocAdd = 5,
     AssocDelete = 6,
+    AssocRange = 7,
+    AssocCount = 8,
 }
 
-/// Converts a u8 into a TaoOp.
-impl From<u8> for TaoOp {
-    fn from(original: u8) -> Self {
+/// Converts a u8 into a TaoOp, failing with the u8 if it is not a valid opcode.
+impl TryFrom<u8> for TaoOp {
+    type Error = u8;
+
+    fn try_from(original: u8) -> Result<Self, u8> {
         match original {
-            0 => TaoOp::ObjGet,
-            1 => TaoOp::ObjAdd,
-            2 => TaoOp::ObjUpdate,
-            3 => TaoOp::ObjDelete,
-            4 => TaoOp::AssocGet,
-            5 => TaoOp::AssocAdd,
-            6 => TaoOp::AssocDelete,
-            _ => panic!("Invalid Tao opcode."),
+            0 => Ok(TaoOp::ObjGet),
+            1 => Ok(TaoOp::ObjAdd),
+            2 => Ok(TaoOp::ObjUpdate),
+            3 => Ok(TaoOp::ObjDelete),
+            4 => Ok(TaoOp::AssocGet),
+            5 => Ok(TaoOp::AssocAdd),
+            6 => Ok(TaoOp::AssocDelete),
+            7 => Ok(TaoOp::AssocRange),
+            8 => Ok(TaoOp::AssocCount),
+            _ => Err(original),
         }
     }
 }
 
 type ResponseHandler = fn(db: Rc<DB>, otype: &[u8], object: &[u8]);
-type AssocResponseHandler = fn(db: Rc<DB>, assoc: Association);
 
 #[no_mangle]
 #[allow(unreachable_code)]
@@ -76,14 +91,20 @@ fn dispatch(db: Rc<DB>) -> u64 {
     }
 
     let (opcode, ops) = db.args().split_at(1);
-    let opcode: u8 = 0 | opcode[0] as u8;
+    let op = match TaoOp::try_from(opcode[0]) {
+        Ok(op) => op,
+        Err(_) => {
+            db.resp("Invalid opcode.".as_bytes());
+            return 1;
+        }
+    };
 
-    match TaoOp::from(opcode) {
+    match op {
         TaoOp::ObjGet => obj_get_dispatch(Rc::clone(&db), ops),
         TaoOp::ObjAdd => obj_add_dispatch(Rc::clone(&db), ops),
         TaoOp::ObjUpdate => obj_update_dispatch(Rc::clone(&db), ops),
         TaoOp::ObjDelete => obj_delete_dispatch(Rc::clone(&db), ops),
-        _ => assoc_dispatch(opcode, Rc::clone(&db), ops),
+        _ => assoc_dispatch(op, Rc::clone(&db), ops),
     };
 
     return 0;
@@ -95,30 +116,42 @@ fn dispatch(db: Rc<DB>) -> u64 {
 /// * `db` - a connection to the database.
 /// * `otype` - the type of the object.
 /// * `object` - the bytes representing the objects value.
-fn object_response_handler(db: Rc<DB>, _otype: &[u8], object: &[u8]) {
-    // db.resp(otype);
+fn object_response_handler(db: Rc<DB>, otype: &[u8], object: &[u8]) {
+    db.resp(otype);
     db.resp(object);
 }
 
-/// Handles the response to a client for an association.
+/// Appends an association to the response to a client, as it's id2 (8 bytes), time (8 bytes),
+/// the length of it's data (2 bytes) and it's data, all little endian. Leaves the response as is
+/// if the association would take it past `MAX_RESP` bytes.
 ///
 /// # Arguments
-/// * `db` - a connection to the database.
+/// * `resp` - the response being built.
 /// * `assoc` - the association which needs to be written into the response to the client.
-fn assoc_response_handler(db: Rc<DB>, assoc: Association) {
-    let mut assoc_serialized: Vec<u8> = Vec::with_capacity(Association::size());
-    assoc_serialized
-        .write_u64::<LittleEndian>(assoc.id)
-        .unwrap();
-    assoc_serialized
-        .write_u64::<LittleEndian>(assoc.time)
-        .unwrap();
-
-    db.resp(assoc_serialized.as_slice());
+/// * `val` - the value stored under the association's key, it's time followed by it's data.
+///
+/// # Return
+/// True if the association was appended, false otherwise.
+fn assoc_response_handler(resp: &mut Vec<u8>, assoc: &Association, val: &[u8]) -> bool {
+    let data = if val.len() < size_of::<Time>() {
+        &val[0..0]
+    } else {
+        &val[size_of::<Time>()..]
+    };
+
+    if resp.len() + Association::size() + size_of::<u16>() + data.len() > MAX_RESP {
+        return false;
+    }
+
+    resp.write_u64::<LittleEndian>(assoc.id).unwrap();
+    resp.write_u64::<LittleEndian>(assoc.time).unwrap();
+    resp.write_u16::<LittleEndian>(data.len() as u16).unwrap();
+    resp.extend_from_slice(data);
+    return true;
 }
 
-/// Manages the resquest to perform an object_get. The response is the object retrieved from the
-/// db or an error.
+/// Manages the resquest to perform an object_get. The response is the type of the object
+/// retrieved from the db (2 bytes) followed by it's data, or an error.
 ///
 /// # Packet structure
 /// |table_id = 8|obj_id = 8|
@@ -170,7 +203,7 @@ fn obj_add_dispatch(db: Rc<DB>, ops: &[u8]) {
 }
 
 /// Manages the resquest to perform an object_update. The response is empty if the call was
-/// successful, or an error message otherwise.
+/// successful, or an error message otherwise, including when the object does not exist.
 ///
 /// # Packet structure
 /// |table_id = 8|obj_id = 8|obj_type = 2|value = n > 0|
@@ -230,48 +263,82 @@ fn obj_delete_dispatch(db: Rc<DB>, ops: &[u8]) {
 /// The response for
 ///     add: empty if successful, error message otherwise.
 ///     delete: empty if successful, error message otherwise.
-///     get: bytes representing the association if sucessful, error message otherwise.
+///     get: the number of associations found (4 bytes) followed by each of them, in the order of
+///          the association list (newest first), if successful, error message otherwise.
+///     range: same as get, for the associations at positions pos to pos + limit of the list.
+///     count: the number of associations on the list (8 bytes) if successful, error message
+///            otherwise.
+/// Associations are laid out as described on `assoc_response_handler()`.
 ///
 /// # Packet structure
-/// |table_id = 8|id1 = 8|assoc_type = 2|id2 = 8|
+/// add: |table_id = 8|id1 = 8|assoc_type = 2|id2 = 8|data = n >= 0|
+/// delete: |table_id = 8|id1 = 8|assoc_type = 2|id2 = 8|
+/// get: |table_id = 8|id1 = 8|assoc_type = 2|id2 = 8 * n, n > 0|
+/// range: |table_id = 8|id1 = 8|assoc_type = 2|pos = 4|limit = 4|
+/// count: |table_id = 8|id1 = 8|assoc_type = 2|
 ///
 /// # Arguments
-/// * `opcode` - identifier for which association operation should be called.
+/// * `op` - identifier for which association operation should be called.
 /// * `db` - a connection to the database.
 /// * `ops` - packet information.
-fn assoc_dispatch(opcode: u8, db: Rc<DB>, ops: &[u8]) {
-    // |table_id = 8|id1 = 8|assoc_type = 2|id2 = 8|
-    if ops.len() != 26 {
+fn assoc_dispatch(op: TaoOp, db: Rc<DB>, ops: &[u8]) {
+    // |table_id = 8|id1 = 8|assoc_type = 2|...
+    if ops.len() < 18 {
         db.resp("Invalid packet length.".as_bytes());
         return;
     }
 
     let (table, rest) = ops.split_at(8);
-    let table: u64 = 0 | table[0] as u64 | (table[1] as u64) << 8 | (table[2] as u64) << 16
-        | (table[3] as u64) << 24 | (table[4] as u64) << 32
-        | (table[5] as u64) << 40 | (table[6] as u64) << 48
-        | (table[7] as u64) << 56;
+    let table: u64 = convert_from_slice(table);
 
     let (id1, rest2) = rest.split_at(8);
-    let (assoc_type, id2) = rest2.split_at(2);
+    let (assoc_type, rest3) = rest2.split_at(2);
     let tao = TAO::new(Rc::clone(&db), 0, table);
 
-    match TaoOp::from(opcode) {
+    let valid = match op {
+        TaoOp::AssocAdd => rest3.len() >= 8 && rest3.len() - 8 <= u16::max_value() as usize,
+        TaoOp::AssocDelete | TaoOp::AssocRange => rest3.len() == 8,
+        TaoOp::AssocGet => rest3.len() > 0 && rest3.len() % 8 == 0,
+        _ => rest3.len() == 0,
+    };
+    if !valid {
+        db.resp("Invalid packet length.".as_bytes());
+        return;
+    }
+
+    match op {
         TaoOp::AssocGet => {
-            if tao.association_get(id1, assoc_type, id2, assoc_response_handler) == false {
+            if tao.association_get(id1, assoc_type, rest3) == false {
                 db.resp("ERROR: could not get association.".as_bytes());
             }
         }
         TaoOp::AssocAdd => {
-            if tao.association_add(id1, assoc_type, id2) == false {
+            let (id2, data) = rest3.split_at(8);
+            if tao.association_add(id1, assoc_type, id2, data) == false {
                 db.resp("ERROR: unsuccessful update".as_bytes());
             }
         }
         TaoOp::AssocDelete => {
-            if tao.association_delete(id1, assoc_type, id2) == false {
+            if tao.association_delete(id1, assoc_type, rest3) == false {
                 db.resp("ERROR: unable to delete the association".as_bytes());
             }
         }
+        TaoOp::AssocRange => {
+            let mut window = rest3;
+            let pos = window.read_u32::<LittleEndian>().unwrap() as usize;
+            let limit = window.read_u32::<LittleEndian>().unwrap() as usize;
+            if tao.association_range(id1, assoc_type, pos, limit) == false {
+                db.resp("ERROR: could not get the association range.".as_bytes());
+            }
+        }
+        TaoOp::AssocCount => match tao.association_count(id1, assoc_type) {
+            Some(count) => {
+                let mut resp: Vec<u8> = Vec::with_capacity(size_of::<u64>());
+                resp.write_u64::<LittleEndian>(count).unwrap();
+                db.resp(resp.as_slice());
+            }
+            None => db.resp("ERROR: could not count associations.".as_bytes()),
+        },
         _ => {} // ERROR invalid opcode.
     };
 }
@@ -311,17 +378,32 @@ impl TAO {
     pub fn object_add(&mut self, otype: ObjectType, data: &[u8]) -> Vec<u8> {
         let object_id = self.allocate_unique_id();
 
-        self.object_update(object_id.as_slice(), otype, data);
+        self.object_write(object_id.as_slice(), otype, data);
         return object_id;
     }
 
-    /// Updates the object with the given id and type to contain the data provided.
+    /// Updates the object with the given id and type to contain the data provided. Fails if
+    /// there is no object with the given id.
     ///
     /// # Arguments
     /// * `id` - id of the object to be updated.
     /// * `otype` - type of the object to be updated.
     /// * `data` - updated data to replace current data with.
     pub fn object_update(&self, id: &[u8], otype: ObjectType, data: &[u8]) -> bool {
+        if self.client.get(self.object_table_id, id).is_none() {
+            return false;
+        }
+
+        self.object_write(id, otype, data)
+    }
+
+    /// Writes the object with the given id, type and data, whether it exists or not.
+    ///
+    /// # Arguments
+    /// * `id` - id of the object to be written.
+    /// * `otype` - type of the object to be written.
+    /// * `data` - data of the object.
+    fn object_write(&self, id: &[u8], otype: ObjectType, data: &[u8]) -> bool {
         let space_needed = ObjectHeader::size() + data.len();
 
         let mut container = match self.client
@@ -366,10 +448,14 @@ impl TAO {
                 //  [..header..|.........object data.........]
                 let size_of_header = ObjectHeader::size();
                 let data_slice: &[u8] = data.read();
+                if data_slice.len() < size_of_header {
+                    return false;
+                }
+
                 callback(
                     Rc::clone(&self.client),
                     &data_slice[0..size_of_header],
-                    &data_slice[size_of_header..data_slice.len() - 1],
+                    &data_slice[size_of_header..],
                 );
                 return true;
             }
@@ -380,26 +466,34 @@ impl TAO {
     }
 
     /// Adds the given Association (id1, type, id2) to the AssociationList (id1, type) if one exists.
-    /// Otherwise, creates a new AssociationList and populates it with the given Association.
+    /// Otherwise, creates a new AssociationList and populates it with the given Association. An
+    /// Association already on the list is moved to the front, with the current time and new data.
+    ///
+    /// The Association is written before the list, so that every Association on a list can be
+    /// looked up. The list is updated with compare and swaps, so that Associations added or
+    /// deleted concurrently, and with them the count of the list, are never lost.
     ///
     /// # Arguments
     /// * `id1` - the id of the first object in this Association.
     /// * `association_type` - the type of this association.
     /// * `id2` - the id of the second object in this Association.
-    pub fn association_add(&self, id1: &[u8], association_type: &[u8], id2: &[u8]) -> bool {
-        //Add the association to the table. (id1, atype, id2)<key> -> data<value>.
+    /// * `data` - the data to store with this Association.
+    pub fn association_add(
+        &self,
+        id1: &[u8],
+        association_type: &[u8],
+        id2: &[u8],
+        data: &[u8],
+    ) -> bool {
+        //Add the association to the table. (id1, atype, id2)<key> -> (time, data)<value>.
         let new_assoc = Association {
             id: convert_from_slice(id2),
             time: self.current_time(),
         };
 
-        let mut assoc_key: Vec<u8> =
-            Vec::with_capacity(id1.len() + association_type.len() + id2.len());
-        assoc_key.extend_from_slice(id1);
-        assoc_key.extend_from_slice(association_type);
-        assoc_key.extend_from_slice(id2);
+        let assoc_key = association_key(id1, association_type, id2);
 
-        let space_needed = Association::size();
+        let space_needed = size_of::<Time>() + data.len();
         let mut assoc_container = match self.client.alloc(
             self.association_table_id,
             assoc_key.as_slice(),
@@ -409,182 +503,244 @@ impl TAO {
             Some(o) => o,
         };
 
-        new_assoc.serialize(&mut assoc_container);
-
-        if self.client.put(assoc_container) {
-            // Add the association to the list. (id1, atype) -> (id2)
-            // To do this, assume the list exists. if it doesn't exist, add our entry and add the list
-            // to the db.
-            let mut list_key: Vec<u8> =
-                Vec::with_capacity(id1.len() + association_type.len() + id2.len());
-            list_key.extend_from_slice(id1);
-            list_key.extend_from_slice(association_type);
-
-            let mut list = match self.client
-                .get(self.association_table_id, list_key.as_slice())
-            {
-                Some(list_serialized) => match AssociationList::deserialize(list_serialized.read())
-                {
-                    Ok(ls) => ls,
-                    Err(_) => return false,
-                },
-                None => {
-                    // Create a new AssociationList.
-                    AssociationList::new()
-                }
-            };
-
-            list.add(new_assoc);
+        assoc_container.write_u64(new_assoc.time, true);
+        assoc_container.write_slice(data);
 
-            let mut list_container = match self.client.alloc(
-                self.association_table_id,
-                list_key.as_slice(),
-                list.size() as u64,
-            ) {
-                None => return false,
-                Some(o) => o,
-            };
-
-            list.serialize(&mut list_container);
-
-            return self.client.put(list_container);
-        } else {
+        if self.client.put(assoc_container) == false {
             return false;
         }
+
+        // Add the association to the list. (id1, atype) -> (id2)
+        let len = id1.len() + association_type.len();
+        self.association_list_update(&assoc_key[0..len], |list| {
+            list.add(new_assoc);
+            true
+        })
     }
 
-    /// Deletes the Association (id1, type, id2) and removes it from the List.
+    /// Deletes the Association (id1, type, id2) and removes it from the List. The Association is
+    /// removed from the List first, so that every Association on a list can be looked up.
     ///
     /// # Arguments
     /// * `id1` - the id of the first object in this Association.
     /// * `association_type` - the type of this association.
     /// * `id2` - the id of the second object in this Association.
     pub fn association_delete(&self, id1: &[u8], association_type: &[u8], id2: &[u8]) -> bool {
-        let assoc = Association {
-            id: convert_from_slice(id2),
-            time: 0, //This doesn't matter because we will find the assoc via the id.
-        };
-        let mut list_key: Vec<u8> =
-            Vec::with_capacity(id1.len() + association_type.len() + id2.len());
-        list_key.extend_from_slice(id1);
-        list_key.extend_from_slice(association_type);
+        let id = convert_from_slice(id2);
+        let assoc_key = association_key(id1, association_type, id2);
 
-        let mut list = match self.client
-            .get(self.association_table_id, list_key.as_slice())
-        {
-            Some(list_serialized) => match AssociationList::deserialize(list_serialized.read()) {
-                Ok(ls) => ls,
-                Err(_) => return false,
-            },
-            None => return false,
-        };
+        let len = id1.len() + association_type.len();
+        if self.association_list_update(&assoc_key[0..len], |list| list.remove(id)) == false {
+            return false;
+        }
 
-        list.remove(assoc.id);
+        // Delete the association
+        self.client
+            .del(self.association_table_id, assoc_key.as_slice());
+        return true;
+    }
 
-        // recommit the list
-        let mut list_container = match self.client.alloc(
-            self.association_table_id,
-            list_key.as_slice(),
-            list.size() as u64,
-        ) {
+    /// Responds with the Associations (id1, type, id2) for every id2 in a set that is on the
+    /// AssociationList (id1, type), in the order of the list. Returns true if the operation was
+    /// successful, false otherwise.
+    ///
+    /// # Arguments
+    /// * `id1` - the id of the first object in this Association.
+    /// * `association_type` - the type of this association.
+    /// * `id2s` - the ids of the second objects, 8 bytes each.
+    pub fn association_get(&self, id1: &[u8], association_type: &[u8], id2s: &[u8]) -> bool {
+        let list = match self.association_list(id1, association_type) {
+            Some(list) => list,
             None => return false,
-            Some(o) => o,
         };
 
-        list.serialize(&mut list_container);
-
-        if self.client.put(list_container) {
-            // Delete the association
-            let mut assoc_key: Vec<u8> =
-                Vec::with_capacity(id1.len() + association_type.len() + id2.len());
-            assoc_key.extend_from_slice(id1);
-            assoc_key.extend_from_slice(association_type);
-            assoc_key.extend_from_slice(id2);
-            self.client
-                .del(self.association_table_id, assoc_key.as_slice());
-            return true;
-        } else {
-            return false;
+        let mut found: Vec<Association> = Vec::with_capacity(id2s.len() / size_of::<Id>());
+        for assoc in list.list.iter() {
+            if id2s
+                .chunks(size_of::<Id>())
+                .any(|id2| convert_from_slice(id2) == assoc.id)
+            {
+                found.push(*assoc);
+            }
         }
+
+        self.association_respond(id1, association_type, found.as_slice())
     }
 
-    /// Returns true if the operation was successful, false otherwise;
+    /// Responds with the Associations at positions `pos` to `pos + limit` of the AssociationList
+    /// (id1, type), newest first. Returns true if the operation was successful, false otherwise.
     ///
     /// # Arguments
     /// * `id1` - the id of the first object in this Association.
     /// * `association_type` - the type of this association.
-    /// * `id2` - the id of the second object in this Association.
-    pub fn association_get(
+    /// * `pos` - the position on the list of the first Association to respond with.
+    /// * `limit` - the largest number of Associations to respond with.
+    pub fn association_range(
         &self,
         id1: &[u8],
         association_type: &[u8],
-        id2: &[u8],
-        _assoc_response_handler: AssocResponseHandler,
+        pos: usize,
+        limit: usize,
     ) -> bool {
-        let key_len = id1.len() + association_type.len() + id2.len();
-        let mut assoc_key: Vec<u8> = Vec::with_capacity(key_len * 4);
-        assoc_key.extend_from_slice(id1);
-        assoc_key.extend_from_slice(association_type);
-        assoc_key.extend_from_slice(id2);
+        let list = match self.association_list(id1, association_type) {
+            Some(list) => list,
+            None => return false,
+        };
 
-        // Get the length of the id and atype combined.
-        let len = id1.len() + association_type.len();
+        let (l, r) = list.window(pos, limit);
+        self.association_respond(id1, association_type, &list.list[l..r])
+    }
+
+    /// Returns the number of Associations on the AssociationList (id1, type), or None if the
+    /// list could not be read.
+    ///
+    /// # Arguments
+    /// * `id1` - the id of the first object in this Association.
+    /// * `association_type` - the type of this association.
+    pub fn association_count(&self, id1: &[u8], association_type: &[u8]) -> Option<u64> {
+        match self.association_list(id1, association_type) {
+            Some(list) => Some(list.len() as u64),
+            None => None,
+        }
+    }
+
+    /// Returns the AssociationList (id1, type), which is empty if it does not exist, or None if
+    /// it could not be deserialized.
+    ///
+    /// # Arguments
+    /// * `id1` - the id of the first object in this Association.
+    /// * `association_type` - the type of this association.
+    fn association_list(&self, id1: &[u8], association_type: &[u8]) -> Option<AssociationList> {
+        let mut list_key: Vec<u8> = Vec::with_capacity(id1.len() + association_type.len());
+        list_key.extend_from_slice(id1);
+        list_key.extend_from_slice(association_type);
 
-        // Lookup the association list.
         match self.client
-            .get(self.association_table_id, &assoc_key[0..len])
+            .get(self.association_table_id, list_key.as_slice())
         {
-            Some(a_list) => {
-                let list = a_list.read();
+            Some(list_serialized) => match AssociationList::deserialize(list_serialized.read()) {
+                Ok(ls) => Some(ls),
+                Err(_) => None,
+            },
+            None => Some(AssociationList::new()),
+        }
+    }
 
-                // Get the number of assocs in the list.
-                let s = size_of::<Association>();
-                let n = list.len() / s;
+    /// Applies a change to an AssociationList with compare and swaps, reading the list again
+    /// whenever it changed before it could be written back. Returns true if the change was
+    /// written, or did not need to be, and false if the list could not be read or written, or
+    /// kept changing for `MAX_RETRIES` attempts.
+    ///
+    /// # Arguments
+    /// * `list_key` - the key of the list, (id1, type).
+    /// * `change` - applies the change to the list, returning false if it left the list as is.
+    fn association_list_update<F: Fn(&mut AssociationList) -> bool>(
+        &self,
+        list_key: &[u8],
+        change: F,
+    ) -> bool {
+        for _ in 0..MAX_RETRIES {
+            let old: Option<Vec<u8>> = match self.client.get(self.association_table_id, list_key) {
+                Some(list_serialized) => {
+                    let mut bytes = Vec::with_capacity(list_serialized.len());
+                    bytes.extend_from_slice(list_serialized.read());
+                    Some(bytes)
+                }
+                None => None,
+            };
 
-                // Construct a key for the database lookup.
-                for i in 0..n {
-                    let l = i * s;
-                    let r = l + size_of::<Id>();
-                    let id = &list[l..r];
+            let mut list = match old {
+                Some(ref bytes) => match AssociationList::deserialize(bytes.as_slice()) {
+                    Ok(ls) => ls,
+                    Err(_) => return false,
+                },
+                None => AssociationList::new(),
+            };
 
-                    let l = (key_len * i) + len;
-                    let r = l + id2.len();
+            if change(&mut list) == false {
+                return true;
+            }
 
-                    match i {
-                        0 => assoc_key[l..r].copy_from_slice(id),
+            let mut list_container = match self.client.alloc(
+                self.association_table_id,
+                list_key,
+                list.size() as u64,
+            ) {
+                None => return false,
+                Some(o) => o,
+            };
 
-                        _ => {
-                            assoc_key.extend_from_slice(id1);
-                            assoc_key.extend_from_slice(association_type);
-                            assoc_key.extend_from_slice(id);
-                        }
-                    }
-                }
+            list.serialize(&mut list_container);
 
-                // Lookup the assocs, add them to the response buffer.
-                let buf =
-                    self.client
-                        .multiget(self.association_table_id, key_len as u16, &assoc_key);
+            let expected = match old {
+                Some(ref bytes) => Some(bytes.as_slice()),
+                None => None,
+            };
+            if self.client.cas(list_container, expected) {
+                return true;
+            }
+        }
 
-                match buf {
-                    Some(vals) => {
-                        if vals.num() > 0 {
-                            self.client.resp(vals.read());
-                        }
+        return false;
+    }
 
-                        while vals.next() {
-                            self.client.resp(vals.read());
-                        }
+    /// Responds with the number of Associations (4 bytes) followed by each of them, laid out as
+    /// described on `assoc_response_handler()`. The data of the Associations is looked up with
+    /// a single multiget, falling back to looking them up one by one if one of them is missing,
+    /// in which case it was deleted after the list was read and is left out of the response.
+    /// The response is cut short once it would exceed `MAX_RESP` bytes.
+    ///
+    /// # Arguments
+    /// * `id1` - the id of the first object in these Associations.
+    /// * `association_type` - the type of these associations.
+    /// * `assocs` - the Associations to respond with.
+    fn association_respond(
+        &self,
+        id1: &[u8],
+        association_type: &[u8],
+        assocs: &[Association],
+    ) -> bool {
+        let key_len = id1.len() + association_type.len() + size_of::<Id>();
+        let mut keys: Vec<u8> = Vec::with_capacity(key_len * assocs.len());
+        for assoc in assocs.iter() {
+            keys.extend_from_slice(id1);
+            keys.extend_from_slice(association_type);
+            keys.write_u64::<LittleEndian>(assoc.id).unwrap();
+        }
 
-                        return true;
+        let mut count: u32 = 0;
+        let mut resp: Vec<u8> = Vec::with_capacity(MAX_RESP);
+        match self.client
+            .multiget(self.association_table_id, key_len as u16, keys.as_slice())
+        {
+            Some(ref vals) if vals.num() == assocs.len() && assocs.len() > 0 => {
+                let mut idx = 0;
+                while assoc_response_handler(&mut resp, &assocs[idx], vals.read()) {
+                    count += 1;
+                    idx += 1;
+                    if !vals.next() {
+                        break;
                     }
-
-                    None => return false,
                 }
             }
 
-            None => return false, //Error assoc does not exist.
+            _ => {
+                for (assoc, key) in assocs.iter().zip(keys.chunks(key_len)) {
+                    if let Some(val) = self.client.get(self.association_table_id, key) {
+                        if assoc_response_handler(&mut resp, assoc, val.read()) == false {
+                            break;
+                        }
+                        count += 1;
+                    }
+                }
+            }
         }
+
+        let mut header: Vec<u8> = Vec::with_capacity(size_of::<u32>());
+        header.write_u32::<LittleEndian>(count).unwrap();
+        self.client.resp(header.as_slice());
+        self.client.resp(resp.as_slice());
+        return true;
     }
 
     /// Returns seconds since unix epoch.
@@ -604,6 +760,22 @@ impl TAO {
     }
 }
 
+/// Returns the key of the Association (id1, type, id2). The first `id1.len() + type.len()`
+/// bytes of it are the key of the AssociationList (id1, type).
+///
+/// # Arguments
+/// * `id1` - the id of the first object in this Association.
+/// * `association_type` - the type of this association.
+/// * `id2` - the id of the second object in this Association.
+fn association_key(id1: &[u8], association_type: &[u8], id2: &[u8]) -> Vec<u8> {
+    let mut assoc_key: Vec<u8> =
+        Vec::with_capacity(id1.len() + association_type.len() + id2.len());
+    assoc_key.extend_from_slice(id1);
+    assoc_key.extend_from_slice(association_type);
+    assoc_key.extend_from_slice(id2);
+    assoc_key
+}
+
 /// converts a slice into an u64
 ///
 /// # Arguments
@@ -638,7 +810,7 @@ impl ObjectHeader {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub struct Association {
     id: Id,
     time: Time,
@@ -747,6 +919,13 @@ impl AssociationList {
     /// Memory: O(n) -> Allocates structure to return.
     /// Time: O(n) where n is the length of the list.
     fn deserialize(bytes: &[u8]) -> Result<AssociationList, sandstorm::io::Error> {
+        if bytes.len() % Association::size() != 0 {
+            return Err(sandstorm::io::Error::new(
+                sandstorm::io::ErrorKind::InvalidData,
+                "Association list has a partial association.",
+            ));
+        }
+
         let capacity = bytes.len() / Association::size();
 
         let mut list: Vec<Association> = Vec::with_capacity(capacity);
@@ -765,32 +944,21 @@ impl AssociationList {
     /// (id_1, type, id_2)
     ///
     /// # Costs
-    /// Memory: O(n) -> Allocates structure to return.
+    /// Memory: O(1)
     /// Time: O(n), where n = size of assoc_list
     ///
     /// # Arguments
     /// * `id_2` - the id of the association to be removed.
-    fn remove(&mut self, id_2: Id) {
-        for pos in 0..self.len() {
-            // Find the association.
-            if self.association_at(pos).id == id_2 {
-                // Shift everything down by one.
-                for idx in pos..self.len() - 1 {
-                    //re-arrange references.
-                    self.list[idx] = Association {
-                        id: self.list[idx - 1].id,
-                        time: self.list[idx - 1].time,
-                    };
-                }
-                // Remove extra space at end? This looks to copy data but might save space?
-                self.list.shrink_to_fit();
-                break;
-            }
-        }
+    ///
+    /// # Return
+    /// True if the association was on the list, false otherwise.
+    fn remove(&mut self, id_2: Id) -> bool {
+        let len = self.len();
+        self.list.retain(|assoc| assoc.id != id_2);
+        self.len() != len
     }
 
-    /// Adds the association to this list.
-    /// Farily innefficient to use.
+    /// Adds the association to this list, replacing any association with the same id.
     /// (id_1, type, id_2)
     ///
     /// # Costs
@@ -800,26 +968,31 @@ impl AssociationList {
     /// # Arguments
     /// * `association` - the association to be added.
     fn add(&mut self, association: Association) {
-        // when associations get added, they get added in order of time. newest -> oldest.
-        // Could do a binary search to find insertion point but.. that's probably silly.
-        self.list.push(Association {
-            id: association.id,
-            time: association.time,
-        });
-
-        for pos in 0..self.len() {
-            if self.association_at(pos).time < association.time {
-                // Make room for insertion
-                for idx in self.len() - 2..pos + 1 {
-                    self.list[idx] = Association {
-                        id: self.list[idx + 1].id,
-                        time: self.list[idx + 1].time,
-                    };
-                }
-                self.list[pos] = association;
-                return;
-            }
-        }
+        // when associations get added, they get added in order of time. newest -> oldest. An
+        // association added within the same second as others is the newest of them.
+        self.remove(association.id);
+
+        let pos = self.list
+            .iter()
+            .position(|assoc| assoc.time <= association.time)
+            .unwrap_or(self.len());
+        self.list.insert(pos, association);
+    }
+
+    /// Returns the bounds of the associations at positions `pos` to `pos + limit` of this list,
+    /// clipped to it's length.
+    ///
+    /// # Arguments
+    /// * `pos` - the position of the first association.
+    /// * `limit` - the largest number of associations.
+    fn window(&self, pos: usize, limit: usize) -> (usize, usize) {
+        let l = if pos < self.len() { pos } else { self.len() };
+        let r = if limit < self.len() - l {
+            l + limit
+        } else {
+            self.len()
+        };
+        (l, r)
     }
 }
 
@@ -861,4 +1034,104 @@ mod tests {
 
         assert_eq!(alist, assoc_des);
     }
+
+    // Returns a list holding associations with the given ids and times.
+    fn list(assocs: &[(Id, Time)]) -> AssociationList {
+        let mut list = AssociationList::new();
+        for &(id, time) in assocs.iter() {
+            list.list.push(Association { id: id, time: time });
+        }
+        list
+    }
+
+    // Returns the ids on a list, in order.
+    fn ids(list: &AssociationList) -> Vec<Id> {
+        list.list.iter().map(|assoc| assoc.id).collect()
+    }
+
+    // This unit test verifies that associations are added newest first, and that adding an
+    // association already on the list moves it instead of adding it twice.
+    #[test]
+    fn test_list_add() {
+        let mut alist = list(&[(1, 30), (2, 20), (3, 10)]);
+
+        alist.add(Association { id: 4, time: 25 });
+        assert_eq!(&[1, 4, 2, 3], ids(&alist).as_slice());
+
+        alist.add(Association { id: 3, time: 40 });
+        assert_eq!(&[3, 1, 4, 2], ids(&alist).as_slice());
+
+        alist.add(Association { id: 5, time: 40 });
+        assert_eq!(&[5, 3, 1, 4, 2], ids(&alist).as_slice());
+
+        let mut empty = AssociationList::new();
+        empty.add(Association { id: 9, time: 0 });
+        assert_eq!(&[9], ids(&empty).as_slice());
+    }
+
+    // This unit test verifies that removing an association drops just that association, and
+    // reports whether it was on the list.
+    #[test]
+    fn test_list_remove() {
+        let mut alist = list(&[(1, 30), (2, 20), (3, 10)]);
+
+        assert!(alist.remove(2));
+        assert_eq!(&[1, 3], ids(&alist).as_slice());
+
+        assert!(!alist.remove(2));
+        assert!(alist.remove(1));
+        assert!(alist.remove(3));
+        assert_eq!(0, alist.len());
+    }
+
+    // This unit test verifies that the window of an assoc_range is clipped to the list.
+    #[test]
+    fn test_list_window() {
+        let alist = list(&[(1, 30), (2, 20), (3, 10)]);
+
+        assert_eq!((0, 3), alist.window(0, 64));
+        assert_eq!((1, 2), alist.window(1, 1));
+        assert_eq!((2, 3), alist.window(2, 2));
+        assert_eq!((3, 3), alist.window(7, 2));
+        assert_eq!((0, 0), AssociationList::new().window(0, 4));
+    }
+
+    // This unit test verifies that lists are deserialized from 16 byte associations, and that
+    // a list with a partial association is rejected.
+    #[test]
+    fn test_list_deserialize() {
+        let mut bytes = Vec::new();
+        for &(id, time) in [(7u64, 2u64), (5, 1)].iter() {
+            bytes.write_u64::<LittleEndian>(id).unwrap();
+            bytes.write_u64::<LittleEndian>(time).unwrap();
+        }
+
+        let alist = AssociationList::deserialize(bytes.as_slice()).unwrap();
+        assert_eq!(list(&[(7, 2), (5, 1)]), alist);
+
+        assert!(AssociationList::deserialize(&bytes[0..20]).is_err());
+        assert_eq!(0, AssociationList::deserialize(&[]).unwrap().len());
+    }
+
+    // This unit test verifies that associations are appended to a response with their data,
+    // and that the response is not taken past it's limit.
+    #[test]
+    fn test_assoc_response() {
+        let assoc = Association { id: 0x102, time: 3 };
+        let mut resp = Vec::new();
+
+        assert!(assoc_response_handler(&mut resp, &assoc, &[3, 0, 0, 0, 0, 0, 0, 0, 9, 8]));
+        assert_eq!(
+            &[2, 1, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 2, 0, 9, 8],
+            resp.as_slice()
+        );
+
+        assert!(assoc_response_handler(&mut resp, &assoc, &[]));
+        assert_eq!(38, resp.len());
+
+        resp.resize(MAX_RESP - 18, 0);
+        assert!(!assoc_response_handler(&mut resp, &assoc, &[0; 9]));
+        assert!(assoc_response_handler(&mut resp, &assoc, &[0; 8]));
+        assert_eq!(MAX_RESP, resp.len());
+    }
 }
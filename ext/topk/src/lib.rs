@@ -0,0 +1,375 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+// The topk() extension scans a range of keys of a table and returns the K objects with the
+// largest (or smallest) value of a numeric field, so that clients do not fetch every object to
+// rank them themselves. Every key is it's number written little endian into it's first eight
+// bytes, with any bytes after those zero, the way the workloads lay keys out. The arguments are,
+// little endian:
+//
+//   table (u64) | key length (u16) | first key (u64) | number of keys (u32) | K (u16) |
+//   field offset (u16) | field width (u8) | order (u8)
+//
+// The field is an unsigned integer `width` (1, 2, 4 or 8) bytes long at `offset` into the value,
+// read little endian; objects whose value is too short to hold it are skipped. The order is
+// `LARGEST` or `SMALLEST`, and ties go to the object with the smaller key. K can be at most
+// `MAX_K`.
+//
+// The objects ranked so far are kept on a heap bounded at K entries, whose worst entry is
+// evicted whenever a better object turns up. All of it's memory is allocated once, as the heap
+// fills up: an evicted entry's buffers are reused as scratch space for the object replacing it,
+// so that scanning a long range does not allocate once per object, and the extension's footprint
+// depends on K and the size of the values, not the length of the range.
+//
+// The response is a status (u8), the number of objects (u32), and then every object, best first,
+// as it's key, the length of it's value (u32) and it's value. The extension yields every
+// `YIELD_EVERY` keys, so that long ranges do not hold up other requests.
+
+#![crate_type = "dylib"]
+#![feature(generators, generator_trait)]
+
+extern crate sandstorm;
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::ops::Generator;
+use std::rc::Rc;
+
+use sandstorm::db::DB;
+use sandstorm::pack::le;
+
+/// Status codes for the response to the tenant.
+const SUCCESSFUL: u8 = 0x01;
+const INVALIDARG: u8 = 0x02;
+
+/// The orders objects can be ranked in.
+const LARGEST: u8 = 0x00;
+const SMALLEST: u8 = 0x01;
+
+/// The number of bytes of arguments.
+const ARGS_LEN: usize = 28;
+
+/// The largest number of objects a single invocation can return.
+const MAX_K: usize = 256;
+
+/// The number of keys looked up between yields.
+const YIELD_EVERY: u64 = 64;
+
+/// The parsed arguments to the extension.
+#[derive(Debug, PartialEq)]
+struct Request {
+    table: u64,
+    key_len: usize,
+    first: u64,
+    count: u32,
+    k: usize,
+    offset: usize,
+    width: usize,
+    order: u8,
+}
+
+// Implementation of methods on Request.
+impl Request {
+    /// Parses the arguments to the extension.
+    ///
+    /// # Arguments
+    ///
+    /// * `args`: The arguments, laid out as described at the top of this file.
+    ///
+    /// # Return
+    ///
+    /// The request, or None if the arguments are malformed.
+    fn parse(args: &[u8]) -> Option<Request> {
+        if args.len() != ARGS_LEN {
+            return None;
+        }
+
+        let req = Request {
+            table: le(&args[..8]),
+            key_len: le(&args[8..10]) as usize,
+            first: le(&args[10..18]),
+            count: le(&args[18..22]) as u32,
+            k: le(&args[22..24]) as usize,
+            offset: le(&args[24..26]) as usize,
+            width: args[26] as usize,
+            order: args[27],
+        };
+
+        let width = req.width;
+        if req.key_len < 8
+            || req.k == 0
+            || req.k > MAX_K
+            || !(width == 1 || width == 2 || width == 4 || width == 8)
+            || (req.order != LARGEST && req.order != SMALLEST)
+        {
+            return None;
+        }
+
+        Some(req)
+    }
+
+    /// Writes the key with a number into a buffer `key_len` bytes long.
+    fn key(&self, id: u64, key: &mut [u8]) {
+        for (byte, k) in key.iter_mut().enumerate() {
+            *k = if byte < 8 { (id >> (8 * byte)) as u8 } else { 0 };
+        }
+    }
+
+    /// Returns the rank of a value, higher being better, or None if the value is too short to
+    /// hold the field.
+    fn rank(&self, val: &[u8]) -> Option<u64> {
+        if val.len() < self.offset + self.width {
+            return None;
+        }
+
+        let field = le(&val[self.offset..self.offset + self.width]);
+        match self.order {
+            LARGEST => Some(field),
+            _ => Some(!field),
+        }
+    }
+}
+
+/// An object ranked by the extension.
+#[derive(Debug)]
+struct Entry {
+    /// The rank of the object, higher being better.
+    rank: u64,
+
+    /// The number of the object's key, used to break ties.
+    id: u64,
+
+    /// The object's key and value.
+    key: Vec<u8>,
+    val: Vec<u8>,
+}
+
+// Entries are ordered by how good they are: by rank, and then the smaller key first.
+impl Ord for Entry {
+    fn cmp(&self, other: &Entry) -> Ordering {
+        self.rank.cmp(&other.rank).then(other.id.cmp(&self.id))
+    }
+}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Entry) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Entry) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Entry {}
+
+/// The best K objects seen so far.
+struct Top {
+    /// The number of objects kept.
+    k: usize,
+
+    /// The objects kept, the worst one on top.
+    heap: BinaryHeap<Reverse<Entry>>,
+}
+
+// Implementation of methods on Top.
+impl Top {
+    /// Returns an empty set of objects, with room for K of them allocated up front.
+    fn new(k: usize) -> Top {
+        Top {
+            k: k,
+            heap: BinaryHeap::with_capacity(k),
+        }
+    }
+
+    /// Offers up an object. The object is kept if fewer than K objects were kept so far, or if
+    /// it is better than the worst of them, which is evicted. The object is only copied if it
+    /// is kept, into the evicted entry's buffers once the heap is full.
+    ///
+    /// # Arguments
+    ///
+    /// * `rank`: The rank of the object.
+    /// * `id`:   The number of the object's key.
+    /// * `key`:  The object's key.
+    /// * `val`:  The object's value.
+    fn offer(&mut self, rank: u64, id: u64, key: &[u8], val: &[u8]) {
+        if self.heap.len() < self.k {
+            self.heap.push(Reverse(Entry {
+                rank: rank,
+                id: id,
+                key: key.to_vec(),
+                val: val.to_vec(),
+            }));
+            return;
+        }
+
+        let better = match self.heap.peek() {
+            Some(&Reverse(ref worst)) => (rank, Reverse(id)) > (worst.rank, Reverse(worst.id)),
+            None => false,
+        };
+        if !better {
+            return;
+        }
+
+        if let Some(Reverse(mut entry)) = self.heap.pop() {
+            entry.rank = rank;
+            entry.id = id;
+            entry.key.clear();
+            entry.key.extend_from_slice(key);
+            entry.val.clear();
+            entry.val.extend_from_slice(val);
+            self.heap.push(Reverse(entry));
+        }
+    }
+
+    /// Consumes the set, returning the objects kept, best first.
+    fn into_sorted(self) -> Vec<Entry> {
+        self.heap.into_sorted_vec().into_iter().map(|Reverse(entry)| entry).collect()
+    }
+}
+
+/// This function implements the topk() extension using the sandstorm interface.
+///
+/// # Arguments
+///
+/// * `db`: An argument whose type implements the `DB` trait which can be used
+///         to interact with the database.
+///
+/// # Return
+///
+/// A coroutine that can be run inside the database.
+#[no_mangle]
+#[allow(unreachable_code)]
+#[allow(unused_assignments)]
+pub fn init(db: Rc<DB>) -> Box<Generator<Yield = u64, Return = u64>> {
+    Box::new(move || {
+        // The arguments are copied out, so that nothing borrowed from them is held across a
+        // yield.
+        let req = match Request::parse(db.args()) {
+            Some(req) => req,
+            None => {
+                db.resp(&[INVALIDARG]);
+                return 1;
+            }
+        };
+
+        let mut top = Top::new(req.k);
+        let mut key = vec![0; req.key_len];
+        let mut idx = 0;
+        while idx < req.count as u64 {
+            let id = req.first.wrapping_add(idx);
+            req.key(id, &mut key);
+            if let Some(obj) = db.get(req.table, &key) {
+                let val = obj.read();
+                if let Some(rank) = req.rank(val) {
+                    top.offer(rank, id, &key, val);
+                }
+            }
+            idx += 1;
+
+            // Yield down to the database every few keys.
+            if idx % YIELD_EVERY == 0 {
+                yield 0;
+            }
+        }
+
+        let objects = top.into_sorted();
+        let mut resp = Vec::new();
+        resp.push(SUCCESSFUL);
+        resp.extend((0..4).map(|byte| (objects.len() >> (8 * byte)) as u8));
+        for entry in objects.iter() {
+            resp.extend_from_slice(&entry.key);
+            resp.extend((0..4).map(|byte| (entry.val.len() >> (8 * byte)) as u8));
+            resp.extend_from_slice(&entry.val);
+        }
+        db.resp(&resp);
+        return 0;
+
+        // XXX: This yield is required to get the compiler to compile this closure into a
+        // generator. It is unreachable and benign.
+        yield 0;
+    })
+}
+
+// This module contains unit tests for the topk() extension.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Returns the arguments of a request for the top K objects.
+    fn args(first: u64, count: u32, k: u16, offset: u16, width: u8, order: u8) -> Vec<u8> {
+        let mut args = vec![9, 0, 0, 0, 0, 0, 0, 0, 30, 0];
+        args.extend((0..8).map(|byte| (first >> (8 * byte)) as u8));
+        args.extend((0..4).map(|byte| (count >> (8 * byte)) as u8));
+        args.extend_from_slice(&[k as u8, (k >> 8) as u8, offset as u8, (offset >> 8) as u8]);
+        args.extend_from_slice(&[width, order]);
+        args
+    }
+
+    // Returns the ids of a set of objects, best first.
+    fn ids(top: Top) -> Vec<u64> {
+        top.into_sorted().iter().map(|entry| entry.id).collect()
+    }
+
+    // This unit test verifies that well formed arguments are parsed, and malformed ones are not.
+    #[test]
+    fn test_parse() {
+        let req = Request::parse(&args(0x100, 1000, 10, 4, 2, SMALLEST)).unwrap();
+        assert_eq!((9, 30, 0x100, 1000), (req.table, req.key_len, req.first, req.count));
+        assert_eq!((10, 4, 2, SMALLEST), (req.k, req.offset, req.width, req.order));
+
+        assert!(Request::parse(&args(0, 1, 0, 0, 8, LARGEST)).is_none());
+        assert!(Request::parse(&args(0, 1, MAX_K as u16 + 1, 0, 8, LARGEST)).is_none());
+        assert!(Request::parse(&args(0, 1, 1, 0, 3, LARGEST)).is_none());
+        assert!(Request::parse(&args(0, 1, 1, 0, 8, 2)).is_none());
+        let mut args = args(0, 1, 1, 0, 8, LARGEST);
+        args.pop();
+        assert!(Request::parse(&args).is_none());
+    }
+
+    // This unit test verifies that values are ranked by their field in the requested order, and
+    // that values too short to hold the field are not ranked.
+    #[test]
+    fn test_rank() {
+        let largest = Request::parse(&args(0, 1, 1, 1, 2, LARGEST)).unwrap();
+        let smallest = Request::parse(&args(0, 1, 1, 1, 2, SMALLEST)).unwrap();
+        assert_eq!(Some(0x0302), largest.rank(&[1, 2, 3]));
+        assert!(smallest.rank(&[1, 2, 3]) > smallest.rank(&[1, 2, 4]));
+        assert_eq!(None, largest.rank(&[1, 2]));
+    }
+
+    // This unit test verifies that only the best K objects are kept, best first, with ties going
+    // to the smaller key, and that evicted entries are overwritten with the objects replacing
+    // them.
+    #[test]
+    fn test_top() {
+        let mut top = Top::new(3);
+        for (id, rank) in [5, 1, 9, 3, 9, 7].iter().enumerate() {
+            top.offer(*rank, id as u64, &[id as u8], &[*rank as u8; 2]);
+        }
+        assert_eq!(3, top.heap.len());
+
+        let objects = top.into_sorted();
+        assert_eq!(vec![2, 4, 5], objects.iter().map(|entry| entry.id).collect::<Vec<_>>());
+        assert_eq!(vec![5], objects[2].key);
+        assert_eq!(vec![7, 7], objects[2].val);
+
+        let mut top = Top::new(2);
+        top.offer(1, 1, &[1], &[]);
+        assert_eq!(vec![1], ids(top));
+    }
+}
@@ -0,0 +1,294 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+// The transfer() extension moves an amount from one account to another, as the classic example
+// of a read-validate-write across two keys. An account is stored under it's number written
+// little endian into the first eight bytes of the key, with any bytes after those zero, and it's
+// balance is the first eight bytes of it's value (u64, little endian); the rest of the value is
+// left as is. The arguments are, little endian:
+//
+//   table (u64) | key length (u16) | from account (u64) | to account (u64) | amount (u64)
+//
+// Both balances are updated with compare and swaps: the balance is read, checked, and written
+// back only if the value was not changed in between, and read again otherwise. The source is
+// debited first, and only if it holds the amount; the destination is credited after. Should the
+// credit fail, the debit is undone, so that no amount is lost or made up unless the database
+// refuses the undo too, which the bank workload (`splinter-bank`) checks by summing up every
+// balance before and after a run. Other transfers can see the amount in neither account while
+// one is under way.
+//
+// Every write to an account must go through transfer(), since compare and swaps are only atomic
+// with respect to each other. The response is a status (u8): `SUCCESSFUL`, `INVALIDARG`,
+// `NOACCOUNT` if an account does not exist or it's value is too short to hold a balance,
+// `INSUFFICIENT` if the source does not hold the amount, `CONTENDED` if an account kept changing
+// under the extension for `MAX_RETRIES` attempts, or `FAILED` if the database refused a write,
+// usually for a quota.
+
+#![crate_type = "dylib"]
+#![feature(generators, generator_trait)]
+
+extern crate sandstorm;
+
+use std::ops::Generator;
+use std::rc::Rc;
+
+use sandstorm::db::DB;
+use sandstorm::pack::le;
+
+/// Status codes for the response to the tenant.
+const SUCCESSFUL: u8 = 0x01;
+const INVALIDARG: u8 = 0x02;
+const NOACCOUNT: u8 = 0x03;
+const INSUFFICIENT: u8 = 0x04;
+const CONTENDED: u8 = 0x05;
+const FAILED: u8 = 0x06;
+
+/// The number of bytes of arguments.
+const ARGS_LEN: usize = 34;
+
+/// The number of times a compare and swap on an account is attempted before giving up.
+const MAX_RETRIES: usize = 16;
+
+/// The parsed arguments to the extension.
+#[derive(Debug, PartialEq)]
+struct Request {
+    table: u64,
+    key_len: usize,
+    from: u64,
+    to: u64,
+    amount: u64,
+}
+
+// Implementation of methods on Request.
+impl Request {
+    /// Parses the arguments to the extension.
+    ///
+    /// # Arguments
+    ///
+    /// * `args`: The arguments, laid out as described at the top of this file.
+    ///
+    /// # Return
+    ///
+    /// The request, or None if the arguments are malformed or the accounts are the same.
+    fn parse(args: &[u8]) -> Option<Request> {
+        if args.len() != ARGS_LEN {
+            return None;
+        }
+
+        let req = Request {
+            table: le(&args[..8]),
+            key_len: le(&args[8..10]) as usize,
+            from: le(&args[10..18]),
+            to: le(&args[18..26]),
+            amount: le(&args[26..34]),
+        };
+
+        if req.key_len < 8 || req.from == req.to {
+            return None;
+        }
+
+        Some(req)
+    }
+
+    /// Returns the key an account is stored under.
+    fn key(&self, account: u64) -> Vec<u8> {
+        (0..self.key_len)
+            .map(|byte| if byte < 8 { (account >> (8 * byte)) as u8 } else { 0 })
+            .collect()
+    }
+}
+
+/// Returns the balance held by a value, or None if it is too short to hold one.
+fn balance(val: &[u8]) -> Option<u64> {
+    if val.len() < 8 {
+        return None;
+    }
+
+    Some(le(&val[..8]))
+}
+
+/// Returns a copy of a value with it's balance replaced.
+fn with_balance(val: &[u8], balance: u64) -> Vec<u8> {
+    let mut out = val.to_vec();
+    for byte in 0..8 {
+        out[byte] = (balance >> (8 * byte)) as u8;
+    }
+    out
+}
+
+/// Updates the balance of an account with compare and swaps, reading it again whenever it
+/// changed before it could be written back.
+///
+/// # Arguments
+///
+/// * `db`:     The database.
+/// * `table`:  The table holding the account.
+/// * `key`:    The key of the account.
+/// * `apply`:  Returns the new balance given the current one, or the status to fail with.
+///
+/// # Return
+///
+/// Ok once the new balance was written, or the status the update failed with.
+fn update<F: Fn(u64) -> Result<u64, u8>>(
+    db: &Rc<DB>,
+    table: u64,
+    key: &[u8],
+    apply: F,
+) -> Result<(), u8> {
+    for _ in 0..MAX_RETRIES {
+        let old = match db.get(table, key) {
+            Some(obj) => obj.read().to_vec(),
+            None => return Err(NOACCOUNT),
+        };
+
+        let new = match balance(&old) {
+            Some(balance) => with_balance(&old, apply(balance)?),
+            None => return Err(NOACCOUNT),
+        };
+
+        let mut buf = match db.alloc(table, key, new.len() as u64) {
+            Some(buf) => buf,
+            None => return Err(FAILED),
+        };
+        buf.write_slice(&new);
+
+        if db.cas(buf, Some(&old)) {
+            return Ok(());
+        }
+    }
+
+    Err(CONTENDED)
+}
+
+/// Moves the amount in a request from one account to the other, as described at the top of
+/// this file.
+///
+/// # Return
+///
+/// The status to respond with.
+fn transfer(db: &Rc<DB>, req: &Request) -> u8 {
+    let (from, to, amount) = (req.key(req.from), req.key(req.to), req.amount);
+
+    // Check that the destination exists before debiting the source, so that the common case of
+    // a bad account does not need to undo anything.
+    if db.get(req.table, &to).is_none() {
+        return NOACCOUNT;
+    }
+
+    let debit = update(db, req.table, &from, |balance| match balance >= amount {
+        true => Ok(balance - amount),
+        false => Err(INSUFFICIENT),
+    });
+    if let Err(status) = debit {
+        return status;
+    }
+
+    let credit = update(db, req.table, &to, |balance| {
+        balance.checked_add(amount).ok_or(INVALIDARG)
+    });
+    match credit {
+        Ok(()) => SUCCESSFUL,
+
+        // Undo the debit. It can only fail if the database refuses the write, or the source
+        // keeps changing, in which case the amount is lost.
+        Err(status) => {
+            let refund = update(db, req.table, &from, |balance| {
+                balance.checked_add(amount).ok_or(INVALIDARG)
+            });
+            match refund {
+                Ok(()) => status,
+                Err(_) => FAILED,
+            }
+        }
+    }
+}
+
+/// This function implements the transfer() extension using the sandstorm interface.
+///
+/// # Arguments
+///
+/// * `db`: An argument whose type implements the `DB` trait which can be used
+///         to interact with the database.
+///
+/// # Return
+///
+/// A coroutine that can be run inside the database.
+#[no_mangle]
+#[allow(unreachable_code)]
+#[allow(unused_assignments)]
+pub fn init(db: Rc<DB>) -> Box<Generator<Yield = u64, Return = u64>> {
+    Box::new(move || {
+        let req = match Request::parse(db.args()) {
+            Some(req) => req,
+            None => {
+                db.resp(&[INVALIDARG]);
+                return 1;
+            }
+        };
+
+        let status = transfer(&db, &req);
+        db.resp(&[status]);
+        match status {
+            SUCCESSFUL => return 0,
+            _ => return 1,
+        }
+
+        // XXX: This yield is required to get the compiler to compile this closure into a
+        // generator. It is unreachable and benign.
+        yield 0;
+    })
+}
+
+// This module contains unit tests for the transfer() extension.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Returns the arguments of a transfer between two accounts.
+    fn args(from: u64, to: u64, amount: u64) -> Vec<u8> {
+        let mut args = vec![1, 0, 0, 0, 0, 0, 0, 0, 30, 0];
+        for val in [from, to, amount].iter() {
+            args.extend((0..8).map(|byte| (val >> (8 * byte)) as u8));
+        }
+        args
+    }
+
+    // This unit test verifies that well formed arguments are parsed, and that malformed ones or
+    // transfers from an account to itself are not.
+    #[test]
+    fn test_parse() {
+        let req = Request::parse(&args(3, 0x104, 50)).unwrap();
+        assert_eq!((1, 30, 3, 0x104, 50), (req.table, req.key_len, req.from, req.to, req.amount));
+
+        let key = req.key(0x104);
+        assert_eq!(30, key.len());
+        assert_eq!(&[4, 1, 0, 0, 0, 0, 0, 0, 0, 0], &key[..10]);
+
+        assert!(Request::parse(&args(3, 3, 50)).is_none());
+        let mut args = args(3, 4, 50);
+        args.pop();
+        assert!(Request::parse(&args).is_none());
+    }
+
+    // This unit test verifies that balances are read off and written into the first eight bytes
+    // of a value, leaving the rest of it as is.
+    #[test]
+    fn test_balance() {
+        let val = vec![7, 1, 0, 0, 0, 0, 0, 0, 9, 9];
+        assert_eq!(Some(0x107), balance(&val));
+        assert_eq!(None, balance(&val[..7]));
+        assert_eq!(vec![5, 0, 0, 0, 0, 0, 0, 0, 9, 9], with_balance(&val, 5));
+    }
+}
@@ -0,0 +1,479 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+// The vector() extension stores fixed dimension float vectors, such as embeddings, and answers
+// nearest neighbour queries over them by brute force: the query is scored against every
+// candidate by dot product, and the K highest scoring candidates are returned. A vector is
+// stored under it's identifier written little endian into the first eight bytes of the key, with
+// any bytes after those zero, and it's value is it's `dim` components (f32), little endian and
+// back to back. The arguments start with, little endian:
+//
+//   op (u8) | table (u64) | key length (u16) | dimension (u16)
+//
+// followed, for `OP_PUT`, by any number of vectors to store, each as it's identifier (u64) and
+// it's components (f32 each). The response is a status (u8) and the number of vectors stored
+// (u32); a `FAILED` status means the database refused to store the next vector, usually for a
+// quota, and that the vectors after it were not stored either.
+//
+// For `OP_QUERY`, the header is followed by:
+//
+//   K (u16) | mode (u8) | query (f32 each) | candidates
+//
+// where the candidates are, for `MODE_RANGE`, the first identifier (u64) and the number of
+// identifiers (u32) of a range, and for `MODE_LIST`, any number of identifiers (u64 each), such
+// as those precomputed by a coarser index. Candidates that are missing, or whose value is not
+// exactly `dim` components long, are skipped. The response is a status (u8), the number of
+// neighbours (u32), and then every neighbour, highest score first, as it's identifier (u64) and
+// it's score (f32). K can be at most `MAX_K`.
+//
+// Extensions are compiled without unsafe code, which rules out SIMD intrinsics. Instead, the dot
+// product is written as `LANES` independent partial sums over consecutive components, which
+// the compiler vectorizes to whatever SIMD the target has (SSE or AVX on x86, NEON on ARM), and
+// runs as plain scalar code elsewhere. Candidates are decoded into one scratch buffer that is
+// reused across the whole query. The extension yields every `YIELD_EVERY` vectors, so that
+// large stores and scans do not hold up other requests.
+
+#![crate_type = "dylib"]
+#![feature(generators, generator_trait)]
+
+extern crate sandstorm;
+
+use std::ops::Generator;
+use std::rc::Rc;
+
+use sandstorm::db::DB;
+use sandstorm::pack::le;
+
+/// Status codes for the response to the tenant.
+const SUCCESSFUL: u8 = 0x01;
+const INVALIDARG: u8 = 0x02;
+const FAILED: u8 = 0x03;
+
+/// The operations the extension performs.
+const OP_PUT: u8 = 0x01;
+const OP_QUERY: u8 = 0x02;
+
+/// The ways the candidates of a query are given.
+const MODE_RANGE: u8 = 0x00;
+const MODE_LIST: u8 = 0x01;
+
+/// The number of bytes of arguments common to every operation.
+const ARGS_HDR_LEN: usize = 13;
+
+/// The number of bytes of arguments to a query ahead of the query vector.
+const QUERY_HDR_LEN: usize = ARGS_HDR_LEN + 3;
+
+/// The largest number of neighbours a single query can return.
+const MAX_K: usize = 256;
+
+/// The number of partial sums the dot product is split over.
+const LANES: usize = 8;
+
+/// The number of vectors stored or scored between yields.
+const YIELD_EVERY: usize = 64;
+
+/// The candidates of a query.
+#[derive(Debug, PartialEq)]
+enum Candidates {
+    /// The first identifier and number of identifiers of a range.
+    Range(u64, u32),
+
+    /// A list of identifiers.
+    List(Vec<u64>),
+}
+
+// Implementation of methods on Candidates.
+impl Candidates {
+    /// Returns the number of candidates.
+    fn len(&self) -> usize {
+        match *self {
+            Candidates::Range(_, count) => count as usize,
+            Candidates::List(ref ids) => ids.len(),
+        }
+    }
+
+    /// Returns the identifier of the `idx`th candidate.
+    fn get(&self, idx: usize) -> u64 {
+        match *self {
+            Candidates::Range(first, _) => first.wrapping_add(idx as u64),
+            Candidates::List(ref ids) => ids[idx],
+        }
+    }
+}
+
+/// The operation requested of the extension.
+#[derive(Debug, PartialEq)]
+enum Op {
+    /// Store vectors, given with their identifiers.
+    Put(Vec<(u64, Vec<u8>)>),
+
+    /// Find the `k` nearest neighbours of a query vector among candidates.
+    Query(usize, Vec<f32>, Candidates),
+}
+
+/// The parsed arguments to the extension.
+#[derive(Debug, PartialEq)]
+struct Request {
+    table: u64,
+    key_len: usize,
+    dim: usize,
+    op: Op,
+}
+
+// Implementation of methods on Request.
+impl Request {
+    /// Parses the arguments to the extension.
+    ///
+    /// # Arguments
+    ///
+    /// * `args`: The arguments, laid out as described at the top of this file.
+    ///
+    /// # Return
+    ///
+    /// The request, or None if the arguments are malformed.
+    fn parse(args: &[u8]) -> Option<Request> {
+        if args.len() < ARGS_HDR_LEN {
+            return None;
+        }
+
+        let key_len = le(&args[9..11]) as usize;
+        let dim = le(&args[11..13]) as usize;
+        if key_len < 8 || dim == 0 {
+            return None;
+        }
+
+        let op = match args[0] {
+            OP_PUT => {
+                let entry = 8 + 4 * dim;
+                let rem = &args[ARGS_HDR_LEN..];
+                if rem.len() == 0 || rem.len() % entry != 0 {
+                    return None;
+                }
+
+                let vectors = rem.chunks(entry).map(|e| (le(&e[..8]), e[8..].to_vec())).collect();
+                Op::Put(vectors)
+            }
+
+            OP_QUERY => {
+                if args.len() < QUERY_HDR_LEN + 4 * dim {
+                    return None;
+                }
+
+                let k = le(&args[ARGS_HDR_LEN..ARGS_HDR_LEN + 2]) as usize;
+                if k == 0 || k > MAX_K {
+                    return None;
+                }
+
+                let query = components(&args[QUERY_HDR_LEN..QUERY_HDR_LEN + 4 * dim]);
+                let rem = &args[QUERY_HDR_LEN + 4 * dim..];
+                let candidates = match args[ARGS_HDR_LEN + 2] {
+                    MODE_RANGE if rem.len() == 12 => {
+                        Candidates::Range(le(&rem[..8]), le(&rem[8..12]) as u32)
+                    }
+                    MODE_LIST if rem.len() > 0 && rem.len() % 8 == 0 => {
+                        Candidates::List(rem.chunks(8).map(le).collect())
+                    }
+                    _ => return None,
+                };
+
+                Op::Query(k, query, candidates)
+            }
+
+            _ => return None,
+        };
+
+        Some(Request {
+            table: le(&args[1..9]),
+            key_len: key_len,
+            dim: dim,
+            op: op,
+        })
+    }
+}
+
+/// Writes the key with an identifier into a buffer `key_len` bytes long.
+fn key(id: u64, key: &mut [u8]) {
+    for (byte, k) in key.iter_mut().enumerate() {
+        *k = if byte < 8 { (id >> (8 * byte)) as u8 } else { 0 };
+    }
+}
+
+/// Decodes a vector's components.
+fn components(buf: &[u8]) -> Vec<f32> {
+    let mut out = Vec::with_capacity(buf.len() / 4);
+    decode(buf, &mut out);
+    out
+}
+
+/// Decodes a vector's components into a buffer, replacing what it held.
+fn decode(buf: &[u8], out: &mut Vec<f32>) {
+    out.clear();
+    out.extend(buf.chunks(4).map(|c| f32::from_bits(le(c) as u32)));
+}
+
+/// Returns the dot product of two vectors of the same dimension.
+///
+/// The components are summed over `LANES` independent partial sums, which do not depend on each
+/// other, so that the compiler can keep them in one SIMD register and vectorize the loop. The
+/// components past the last multiple of `LANES` are summed on their own.
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    let n = a.len().min(b.len());
+    let whole = n - n % LANES;
+
+    let mut sums = [0f32; LANES];
+    let mut idx = 0;
+    while idx < whole {
+        let (x, y) = (&a[idx..idx + LANES], &b[idx..idx + LANES]);
+        for lane in 0..LANES {
+            sums[lane] += x[lane] * y[lane];
+        }
+        idx += LANES;
+    }
+
+    let mut sum = sums.iter().fold(0f32, |acc, s| acc + s);
+    for idx in whole..n {
+        sum += a[idx] * b[idx];
+    }
+    sum
+}
+
+/// The highest scoring candidates seen so far, highest first.
+struct Nearest {
+    /// The number of neighbours kept.
+    k: usize,
+
+    /// The identifiers and scores of the neighbours kept.
+    kept: Vec<(u64, f32)>,
+}
+
+// Implementation of methods on Nearest.
+impl Nearest {
+    /// Returns an empty set of neighbours, with room for K of them allocated up front.
+    fn new(k: usize) -> Nearest {
+        Nearest {
+            k: k,
+            kept: Vec::with_capacity(k + 1),
+        }
+    }
+
+    /// Offers up a candidate, which is kept if it scores higher than the lowest scoring of the K
+    /// neighbours kept so far. Ties go to the candidate offered first, and candidates scoring
+    /// NaN are never kept.
+    fn offer(&mut self, id: u64, score: f32) {
+        if score.is_nan() {
+            return;
+        }
+        if self.kept.len() == self.k && self.kept[self.k - 1].1 >= score {
+            return;
+        }
+
+        let pos = self.kept.iter().position(|&(_, s)| s < score).unwrap_or(self.kept.len());
+        self.kept.insert(pos, (id, score));
+        self.kept.truncate(self.k);
+    }
+}
+
+/// Returns an unsigned integer written little endian into it's `n` low bytes.
+fn to_le(val: u64, n: usize) -> Vec<u8> {
+    (0..n).map(|byte| (val >> (8 * byte)) as u8).collect()
+}
+
+/// This function implements the vector() extension using the sandstorm interface.
+///
+/// # Arguments
+///
+/// * `db`: An argument whose type implements the `DB` trait which can be used
+///         to interact with the database.
+///
+/// # Return
+///
+/// A coroutine that can be run inside the database.
+#[no_mangle]
+#[allow(unreachable_code)]
+#[allow(unused_assignments)]
+pub fn init(db: Rc<DB>) -> Box<Generator<Yield = u64, Return = u64>> {
+    Box::new(move || {
+        // The arguments are copied out, so that nothing borrowed from them is held across a
+        // yield.
+        let req = match Request::parse(db.args()) {
+            Some(req) => req,
+            None => {
+                db.resp(&[INVALIDARG]);
+                return 1;
+            }
+        };
+
+        // The operation is moved out of the request, so that iterating over it does not borrow
+        // the request across a yield.
+        let mut buf = vec![0; req.key_len];
+        match req.op {
+            Op::Put(vectors) => {
+                let mut stored = 0;
+                for (id, val) in vectors.into_iter() {
+                    key(id, &mut buf);
+                    let put = match db.alloc(req.table, &buf, val.len() as u64) {
+                        Some(mut obj) => {
+                            obj.write_slice(&val);
+                            db.put(obj)
+                        }
+                        None => false,
+                    };
+                    if !put {
+                        db.resp(&[FAILED]);
+                        db.resp(&to_le(stored as u64, 4));
+                        return 1;
+                    }
+                    stored += 1;
+
+                    // Yield down to the database every few vectors.
+                    if stored % YIELD_EVERY == 0 {
+                        yield 0;
+                    }
+                }
+
+                db.resp(&[SUCCESSFUL]);
+                db.resp(&to_le(stored as u64, 4));
+            }
+
+            Op::Query(k, query, candidates) => {
+                let mut nearest = Nearest::new(k);
+                let mut scratch = Vec::with_capacity(req.dim);
+                for idx in 0..candidates.len() {
+                    let id = candidates.get(idx);
+                    key(id, &mut buf);
+                    if let Some(obj) = db.get(req.table, &buf) {
+                        let val = obj.read();
+                        if val.len() == 4 * req.dim {
+                            decode(val, &mut scratch);
+                            nearest.offer(id, dot(&query, &scratch));
+                        }
+                    }
+
+                    // Yield down to the database every few vectors.
+                    if (idx + 1) % YIELD_EVERY == 0 {
+                        yield 0;
+                    }
+                }
+
+                let mut resp = Vec::with_capacity(5 + 12 * nearest.kept.len());
+                resp.push(SUCCESSFUL);
+                resp.extend(to_le(nearest.kept.len() as u64, 4));
+                for &(id, score) in nearest.kept.iter() {
+                    resp.extend(to_le(id, 8));
+                    resp.extend(to_le(score.to_bits() as u64, 4));
+                }
+                db.resp(&resp);
+            }
+        }
+
+        return 0;
+
+        // XXX: This yield is required to get the compiler to compile this closure into a
+        // generator. It is unreachable and benign.
+        yield 0;
+    })
+}
+
+// This module contains unit tests for the vector() extension.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Returns the header common to the arguments of every operation.
+    fn header(op: u8, dim: u16) -> Vec<u8> {
+        let mut args = vec![op, 9, 0, 0, 0, 0, 0, 0, 0, 30, 0];
+        args.extend_from_slice(&[dim as u8, (dim >> 8) as u8]);
+        args
+    }
+
+    // Returns a vector's components, little endian and back to back.
+    fn floats(vals: &[f32]) -> Vec<u8> {
+        vals.iter().flat_map(|v| to_le(v.to_bits() as u64, 4)).collect()
+    }
+
+    // This unit test verifies that well formed puts are parsed into the vectors they store, and
+    // malformed ones are not.
+    #[test]
+    fn test_parse_put() {
+        let mut args = header(OP_PUT, 2);
+        args.extend(to_le(7, 8));
+        args.extend(floats(&[1.0, 2.0]));
+        args.extend(to_le(8, 8));
+        args.extend(floats(&[3.0, 4.0]));
+
+        let req = Request::parse(&args).unwrap();
+        assert_eq!((9, 30, 2), (req.table, req.key_len, req.dim));
+        assert_eq!(
+            Op::Put(vec![(7, floats(&[1.0, 2.0])), (8, floats(&[3.0, 4.0]))]),
+            req.op
+        );
+
+        args.pop();
+        assert!(Request::parse(&args).is_none());
+        assert!(Request::parse(&header(OP_PUT, 2)).is_none());
+        assert!(Request::parse(&header(OP_PUT, 0)).is_none());
+    }
+
+    // This unit test verifies that well formed queries are parsed into their query vector and
+    // candidates, and malformed ones are not.
+    #[test]
+    fn test_parse_query() {
+        let mut args = header(OP_QUERY, 2);
+        args.extend_from_slice(&[3, 0, MODE_RANGE]);
+        args.extend(floats(&[0.5, -1.0]));
+        args.extend(to_le(100, 8));
+        args.extend(to_le(50, 4));
+        let req = Request::parse(&args).unwrap();
+        assert_eq!(Op::Query(3, vec![0.5, -1.0], Candidates::Range(100, 50)), req.op);
+
+        let mut list = header(OP_QUERY, 2);
+        list.extend_from_slice(&[3, 0, MODE_LIST]);
+        list.extend(floats(&[0.5, -1.0]));
+        list.extend(to_le(4, 8));
+        list.extend(to_le(2, 8));
+        let req = Request::parse(&list).unwrap();
+        assert_eq!(Op::Query(3, vec![0.5, -1.0], Candidates::List(vec![4, 2])), req.op);
+
+        args.pop();
+        assert!(Request::parse(&args).is_none());
+        list[13] = 0;
+        assert!(Request::parse(&list).is_none());
+        list[13] = 3;
+        list[15] = 2;
+        assert!(Request::parse(&list).is_none());
+    }
+
+    // This unit test verifies that the dot product sums over every component, including those
+    // past the last multiple of the number of lanes.
+    #[test]
+    fn test_dot() {
+        let a: Vec<f32> = (0..19).map(|v| v as f32).collect();
+        let b: Vec<f32> = (0..19).map(|v| (v % 3) as f32).collect();
+        let expected = a.iter().zip(b.iter()).fold(0f32, |acc, (x, y)| acc + x * y);
+        assert_eq!(expected, dot(&a, &b));
+        assert_eq!(0f32, dot(&[], &[]));
+    }
+
+    // This unit test verifies that only the K highest scoring candidates are kept, highest
+    // first, with ties going to the candidate offered first, and that NaN scores are skipped.
+    #[test]
+    fn test_nearest() {
+        let mut nearest = Nearest::new(3);
+        for (id, score) in [0.5, 2.0, -1.0, 2.0, 3.0, std::f32::NAN, 1.0].iter().enumerate() {
+            nearest.offer(id as u64, *score);
+        }
+        assert_eq!(vec![(4, 3.0), (1, 2.0), (3, 2.0)], nearest.kept);
+    }
+}
@@ -75,6 +75,16 @@ fn read_port(value: &Value) -> Result<PortConfiguration> {
             }
         };
 
+        let rss = match port_def.get("rss") {
+            Some(&Value::Boolean(l)) => l,
+            None => false,
+            v => {
+                return Err(
+                    ErrorKind::ConfigurationError(format!("Could not parse rss spec {:?}", v)).into(),
+                )
+            }
+        };
+
         let symmetric_queue = port_def.contains_key("cores");
         if symmetric_queue && (port_def.contains_key("rx_cores") || port_def.contains_key("tx_cores")) {
             println!(
@@ -137,6 +147,7 @@ fn read_port(value: &Value) -> Result<PortConfiguration> {
             loopback: loopback,
             csum: csum,
             tso: tso,
+            rss: rss,
         })
     } else {
         Err(
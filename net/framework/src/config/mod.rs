@@ -100,6 +100,9 @@ pub struct PortConfiguration {
     pub loopback: bool,
     pub tso: bool,
     pub csum: bool,
+    /// Spread received packets across RX queues using RSS on the IP and UDP headers. If false,
+    /// flow director rules steer packets with UDP destination port `i` to RX queue `i`.
+    pub rss: bool,
 }
 
 impl Default for PortConfiguration {
@@ -113,6 +116,7 @@ impl Default for PortConfiguration {
             loopback: false,
             tso: false,
             csum: false,
+            rss: false,
         }
     }
 }
@@ -142,7 +146,7 @@ impl fmt::Display for PortConfiguration {
         let tx_queue_str = tx_queues_str_vec.join(" ");
         write!(
             f,
-            "Port {} RXQ_Count: {} RX_Queues: [ {} ] TXQ_Count: {} TX_Queues: {} RXD: {} TXD: {} Loopback {}",
+            "Port {} RXQ_Count: {} RX_Queues: [ {} ] TXQ_Count: {} TX_Queues: {} RXD: {} TXD: {} Loopback {} RSS {}",
             self.name,
             self.rx_queues.len(),
             rx_queue_str,
@@ -150,7 +154,8 @@ impl fmt::Display for PortConfiguration {
             tx_queue_str,
             self.rxd,
             self.txd,
-            self.loopback
+            self.loopback,
+            self.rss
         )
     }
 }
@@ -104,3 +104,24 @@ pub fn init_thread(tid: i32, core: i32) {
 pub fn get_domain() -> i32 {
     NUMA_DOMAIN.with(|f| f.get())
 }
+
+/// The number of mbufs in one of DPDK's packet pools, and the bytes each takes up, as reported by
+/// DPDK. Mbufs in per-core caches count as in use.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MempoolStats {
+    pub size: u64,
+    pub available: u64,
+    pub in_use: u64,
+    pub mbuf_bytes: u64,
+}
+
+/// Read the counts of the packet pool of a NUMA socket. None if the socket has no pool, which is
+/// also the case before the system is initialized.
+pub fn mempool_stats(socket: i32) -> Option<MempoolStats> {
+    let mut stats = MempoolStats::default();
+    match unsafe { zcsi::get_mempool_stats(socket, &mut stats) } {
+        0 => Some(stats),
+        _ => None,
+    }
+}
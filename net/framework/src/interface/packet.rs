@@ -517,6 +517,37 @@ impl<T: EndOffset, M: Sized + Send> Packet<T, M> {
         unsafe { (*self.mbuf).refcnt() }
     }
 
+    /// Chain a segment pointing directly at `data` behind this packet, so that `data` is
+    /// transmitted after the payload without being copied into the packet.
+    ///
+    /// # Safety
+    /// `data` must not be modified or freed until `mbuf_ext_done()` returns true on the returned
+    /// segment; the NIC might still be reading it. After this, the callee is responsible for
+    /// releasing `data` and freeing the segment with `mbuf_free()`.
+    ///
+    /// Returns None if `data` cannot be transmitted in place (ex: it is not physically
+    /// contiguous), in which case the packet is left unmodified.
+    #[inline]
+    pub unsafe fn chain_external(&mut self, data: &[u8]) -> Option<*mut MBuf> {
+        if data.len() > u16::max_value() as usize {
+            return None;
+        }
+
+        let seg = mbuf_alloc_ext(data.as_ptr(), data.len() as u16);
+        if seg.is_null() {
+            return None;
+        }
+
+        (*self.mbuf).chain(seg);
+        Some(seg)
+    }
+
+    /// Returns the number of bytes in this packet that lie beyond the first segment.
+    #[inline]
+    pub fn chained_len(&self) -> usize {
+        unsafe { (*self.mbuf).pkt_len() - (*self.mbuf).data_len() }
+    }
+
     /// Get the mbuf reference by this packet.
     ///
     /// # Safety
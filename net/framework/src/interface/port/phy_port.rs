@@ -37,6 +37,30 @@ pub struct PortQueue {
     rxq: i32,
 }
 
+/// Counters maintained by the NIC for a port, as reported by DPDK. These cover every queue on the
+/// port, including packets dropped by the NIC before they were received by any queue.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NicStats {
+    pub ipackets: u64,
+    pub opackets: u64,
+    pub ibytes: u64,
+    pub obytes: u64,
+    pub imissed: u64,
+    pub ierrors: u64,
+    pub oerrors: u64,
+    pub rx_nombuf: u64,
+}
+
+/// The state of a port's link, as reported by DPDK.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LinkStatus {
+    pub speed_mbps: u32,
+    pub up: u8,
+    pub full_duplex: u8,
+}
+
 impl Drop for PmdPort {
     fn drop(&mut self) {
         if self.connected && self.should_close {
@@ -163,6 +187,22 @@ impl PmdPort {
         }
     }
 
+    /// Steer UDP packets with destination port `dst_port` to receive queue `rxq` using a flow
+    /// director rule. Only valid on ports that were not configured to use RSS.
+    pub fn add_udp_flow_rule(&self, dst_port: u16, rxq: i32) -> Result<()> {
+        if rxq >= self.rxqs || rxq < 0 {
+            return Err(ErrorKind::BadRxQueue(self.port, rxq).into());
+        }
+
+        match unsafe { add_udp_flow_rule(self.port, dst_port, rxq) } {
+            0 => Ok(()),
+            _ => Err(ErrorKind::ConfigurationError(format!(
+                "Could not steer UDP port {} to queue {} on port {}",
+                dst_port, rxq, self.port
+            )).into()),
+        }
+    }
+
     /// Current port ID.
     #[inline]
     pub fn name(&self) -> i32 {
@@ -178,6 +218,32 @@ impl PmdPort {
         )
     }
 
+    /// Set the largest IP packet this port sends and receives.
+    pub fn set_mtu(&self, mtu: u16) -> Result<()> {
+        match unsafe { set_pmd_port_mtu(self.port, mtu as i32) } {
+            0 => Ok(()),
+            _ => Err(ErrorKind::ConfigurationError(format!("Could not set MTU {} on port {}", mtu, self.port)).into()),
+        }
+    }
+
+    /// Read the NIC's counters for this port.
+    pub fn nic_stats(&self) -> Result<NicStats> {
+        let mut stats = NicStats::default();
+        match unsafe { get_port_stats(self.port, &mut stats) } {
+            0 => Ok(stats),
+            _ => Err(ErrorKind::ConfigurationError(format!("Could not read stats for port {}", self.port)).into()),
+        }
+    }
+
+    /// Read the state of this port's link, without waiting for it to come up.
+    pub fn link(&self) -> Result<LinkStatus> {
+        let mut link = LinkStatus::default();
+        match unsafe { get_port_link(self.port, &mut link) } {
+            0 => Ok(link),
+            _ => Err(ErrorKind::ConfigurationError(format!("Could not read link of port {}", self.port)).into()),
+        }
+    }
+
     /// Create a PMD port with a given number of RX and TXQs.
     fn init_dpdk_port(
         port: i32,
@@ -190,11 +256,13 @@ impl PmdPort {
         loopback: bool,
         tso: bool,
         csumoffload: bool,
+        rss: bool,
     ) -> Result<Arc<PmdPort>> {
 
         let loopbackv = i32_from_bool(loopback);
         let tsov = i32_from_bool(tso);
         let csumoffloadv = i32_from_bool(csumoffload);
+        let rssv = i32_from_bool(rss);
         let max_txqs = unsafe { max_txqs(port) };
         let max_rxqs = unsafe { max_rxqs(port) };
         let actual_rxqs = min(max_rxqs, rxqs);
@@ -213,6 +281,7 @@ impl PmdPort {
                     loopbackv,
                     tsov,
                     csumoffloadv,
+                    rssv,
                 )
             };
             if ret == 0 {
@@ -291,6 +360,7 @@ impl PmdPort {
         loopback: bool,
         tso: bool,
         csumoffload: bool,
+        rss: bool,
     ) -> Result<Arc<PmdPort>> {
         let cannonical_spec = PmdPort::cannonicalize_pci(spec);
         let port = unsafe { attach_pmd_device((cannonical_spec[..]).as_ptr()) };
@@ -307,6 +377,7 @@ impl PmdPort {
                 loopback,
                 tso,
                 csumoffload,
+                rss,
             ).chain_err(|| ErrorKind::BadDev(String::from(spec)))
         } else {
             Err(ErrorKind::BadDev(String::from(spec)).into())
@@ -338,6 +409,7 @@ impl PmdPort {
             port_config.loopback,
             port_config.tso,
             port_config.csum,
+            port_config.rss,
         )
     }
 
@@ -349,6 +421,7 @@ impl PmdPort {
     /// -   `rxqs`, `txqs`: Number of RX and TX queues.
     /// -   `tx_cores`, `rx_cores`: Core affinity of where the queues will be used.
     /// -   `nrxd`, `ntxd`: RX and TX descriptors.
+    /// -   `rss`: Steer packets to RX queues using RSS instead of flow director rules.
     pub fn new_port_with_queues_descriptors_offloads(
         name: &str,
         rxqs: i32,
@@ -360,6 +433,7 @@ impl PmdPort {
         loopback: bool,
         tso: bool,
         csumoffload: bool,
+        rss: bool,
     ) -> Result<Arc<PmdPort>> {
         let parts: Vec<_> = name.splitn(2, ':').collect();
         match parts[0] {
@@ -377,6 +451,7 @@ impl PmdPort {
                     loopback,
                     tso,
                     csumoffload,
+                    rss,
                 )
             }
             "null" => PmdPort::null_port(),
@@ -392,6 +467,7 @@ impl PmdPort {
                     loopback,
                     tso,
                     csumoffload,
+                    rss,
                 )
             }
         }
@@ -415,6 +491,7 @@ impl PmdPort {
             false,
             false,
             false,
+            false,
         )
     }
 
@@ -141,6 +141,20 @@ impl MBuf {
         }
     }
 
+    /// Appends a segment to the end of this mbuf's chain of segments, updating the number of
+    /// segments and the total packet length on this (the first) segment.
+    #[inline]
+    pub unsafe fn chain(&mut self, seg: *mut MBuf) {
+        let mut last: *mut MBuf = self;
+        while !(*last).next.is_null() {
+            last = (*last).next;
+        }
+
+        (*last).next = seg;
+        self.nb_segs += 1;
+        self.pkt_len += (*seg).data_len as u32;
+    }
+
     #[inline]
     pub fn refcnt(&self) -> u16 {
         self.refcnt
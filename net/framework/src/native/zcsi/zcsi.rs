@@ -1,5 +1,7 @@
 use super::MBuf;
 use headers::MacAddress;
+use interface::dpdk::MempoolStats;
+use interface::{LinkStatus, NicStats};
 use std::os::raw::c_char;
 #[link(name = "zcsi")]
 extern "C" {
@@ -27,7 +29,12 @@ extern "C" {
         loopback: i32,
         tso: i32,
         csumoffload: i32,
+        rss: i32,
     ) -> i32;
+    pub fn add_udp_flow_rule(port: i32, dst_port: u16, rxq: i32) -> i32;
+    pub fn set_pmd_port_mtu(port: i32, mtu: i32) -> i32;
+    pub fn get_port_stats(port: i32, stats: *mut NicStats) -> i32;
+    pub fn get_port_link(port: i32, link: *mut LinkStatus) -> i32;
     pub fn free_pmd_port(port: i32) -> i32;
     pub fn recv_pkts(port: i32, qid: i32, pkts: *mut *mut MBuf, len: i32) -> i32;
     pub fn send_pkts(port: i32, qid: i32, pkts: *mut *mut MBuf, len: i32) -> i32;
@@ -41,9 +48,12 @@ extern "C" {
     pub fn max_rxqs(port: i32) -> i32;
     pub fn max_txqs(port: i32) -> i32;
     pub fn mbuf_alloc() -> *mut MBuf;
+    pub fn get_mempool_stats(sid: i32, stats: *mut MempoolStats) -> i32;
     pub fn mbuf_free(buf: *mut MBuf);
     pub fn mbuf_alloc_bulk(array: *mut *mut MBuf, len: u16, cnt: i32) -> i32;
     pub fn mbuf_free_bulk(array: *mut *mut MBuf, cnt: i32) -> i32;
+    pub fn mbuf_alloc_ext(addr: *const u8, len: u16) -> *mut MBuf;
+    pub fn mbuf_ext_done(buf: *mut MBuf) -> i32;
     pub fn crc_hash_native(to_hash: *const u8, size: u32, iv: u32) -> u32;
     pub fn ipv4_cksum(payload: *const u8) -> u16;
     pub fn get_thread_id() -> u64;
@@ -274,18 +274,21 @@ pub fn initialize_system(configuration: &NetbricksConfiguration) -> Result<NetBr
     ctx.active_cores = cores.into_iter().collect();
     ctx.active_cores.sort();
 
-    // Populate every core's sibling receive queue.
-    for idx in 0..(ctx.active_cores.len() - 1) {
-        // A core's sibling is it's neighbour.
-        let sibling = ctx.rx_queues.get(&ctx.active_cores[idx + 1]).unwrap();
-        ctx.siblings.insert(ctx.active_cores[idx], sibling[0].clone());
-    }
-
-    // The last core's sibling is the first core's receive queue.
-    {
-        let last = ctx.active_cores.len() - 1;
-        let sibling = ctx.rx_queues.get(&ctx.active_cores[0]).unwrap();
-        ctx.siblings.insert(ctx.active_cores[last], sibling[0].clone());
+    // Populate every core's sibling receive queue. A core's sibling is it's nearest neighbour
+    // receiving on the same port, wrapping around past the last core. Stolen requests are then
+    // always answered on the port they arrived on. A core alone on it's port is it's own sibling.
+    let num_cores = ctx.active_cores.len();
+    for idx in 0..num_cores {
+        let own = ctx.rx_queues.get(&ctx.active_cores[idx]).unwrap()[0].clone();
+        let mut sibling = own.clone();
+        for step in 1..num_cores {
+            let next = ctx.rx_queues.get(&ctx.active_cores[(idx + step) % num_cores]).unwrap();
+            if Arc::ptr_eq(&next[0].port, &own.port) {
+                sibling = next[0].clone();
+                break;
+            }
+        }
+        ctx.siblings.insert(ctx.active_cores[idx], sibling);
     }
 
     Ok(ctx)
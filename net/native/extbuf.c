@@ -0,0 +1,107 @@
+#include <rte_config.h>
+#include <rte_eal.h>
+#include <rte_lcore.h>
+#include <rte_mbuf.h>
+#include <rte_memory.h>
+#include <rte_spinlock.h>
+
+#include <unistd.h>
+
+#include "mempool.h"
+
+/*
+ * Mbufs that don't own a data buffer, and instead point at memory outside of
+ * DPDK (ex: a value stored in a table). These are chained behind a regular
+ * header mbuf so that the value can be transmitted without being copied.
+ *
+ * DPDK 17.08 has no support for external buffers, so these mbufs come from
+ * a separate pool with an empty data room, and are never attached/detached.
+ * Every such mbuf is handed out with a reference count of two. The NIC drops
+ * one reference when it is done transmitting the packet, after which the
+ * caller can check (using mbuf_ext_done()) that the memory is no longer being
+ * DMA'ed out, release the memory, and then free the mbuf.
+ */
+#define EXT_POOL_SIZE (8192 - 1)
+#define EXT_POOL_CACHE 128
+
+static struct rte_mempool *ext_pool[RTE_MAX_NUMA_NODES];
+static rte_spinlock_t ext_pool_lock = RTE_SPINLOCK_INITIALIZER;
+
+static struct rte_mempool *get_ext_pool(int sid) {
+    if (likely(ext_pool[sid] != NULL)) {
+        return ext_pool[sid];
+    }
+
+    rte_spinlock_lock(&ext_pool_lock);
+    if (ext_pool[sid] == NULL) {
+        char name[256];
+        sprintf(name, "ext%d", sid);
+        ext_pool[sid] = rte_pktmbuf_pool_create(name, EXT_POOL_SIZE, EXT_POOL_CACHE, 0, 0, sid);
+    }
+    rte_spinlock_unlock(&ext_pool_lock);
+
+    return ext_pool[sid];
+}
+
+/*
+ * Returns the physical address of a buffer, or RTE_BAD_PHYS_ADDR if the
+ * buffer is not physically contiguous and hence cannot be DMA'ed in one go.
+ */
+static phys_addr_t contiguous_phys_addr(void *addr, uint16_t len) {
+    uintptr_t page  = (uintptr_t)sysconf(_SC_PAGESIZE);
+    uintptr_t start = (uintptr_t)addr;
+    uintptr_t end   = start + len - 1;
+
+    phys_addr_t phys = rte_mem_virt2phy(addr);
+    if (phys == RTE_BAD_PHYS_ADDR) {
+        return RTE_BAD_PHYS_ADDR;
+    }
+
+    /* Check that every page boundary inside the buffer is contiguous. */
+    for (uintptr_t p = (start & ~(page - 1)) + page; p <= end; p += page) {
+        if (rte_mem_virt2phy((void *)p) != phys + (p - start)) {
+            return RTE_BAD_PHYS_ADDR;
+        }
+    }
+
+    return phys;
+}
+
+struct rte_mbuf *mbuf_alloc_ext(void *addr, uint16_t len) {
+    struct rte_mempool *pool;
+    struct rte_mbuf *mbuf;
+    phys_addr_t phys;
+
+    if (len == 0) {
+        return NULL;
+    }
+
+    phys = contiguous_phys_addr(addr, len);
+    if (phys == RTE_BAD_PHYS_ADDR) {
+        return NULL;
+    }
+
+    pool = get_ext_pool(rte_socket_id());
+    if (pool == NULL) {
+        return NULL;
+    }
+
+    mbuf = rte_pktmbuf_alloc(pool);
+    if (mbuf == NULL) {
+        return NULL;
+    }
+
+    mbuf->buf_addr     = addr;
+    mbuf->buf_physaddr = phys;
+    mbuf->buf_len      = len;
+    mbuf->data_off     = 0;
+    mbuf->data_len     = len;
+    mbuf->pkt_len      = len;
+    rte_mbuf_refcnt_set(mbuf, 2);
+
+    return mbuf;
+}
+
+int mbuf_ext_done(struct rte_mbuf *mbuf) {
+    return rte_mbuf_refcnt_read(mbuf) == 1;
+}
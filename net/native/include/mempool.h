@@ -19,6 +19,10 @@ struct rte_mbuf* mbuf_alloc();
 void mbuf_free(struct rte_mbuf* buf);
 int mbuf_alloc_bulk(mbuf_array_t array, uint16_t len, int cnt);
 int mbuf_free_bulk(mbuf_array_t array, int cnt);
+struct rte_mbuf* mbuf_alloc_ext(void* addr, uint16_t len);
+int mbuf_ext_done(struct rte_mbuf* mbuf);
 struct rte_mempool* get_pframe_pool(int coreid, int sid);
 struct rte_mempool* get_mempool_for_core(int coreid);
+struct mempool_stats;
+int get_mempool_stats(int sid, struct mempool_stats* stats);
 #endif
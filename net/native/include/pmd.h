@@ -4,7 +4,13 @@ int num_pmd_ports();
 int get_pmd_ports(struct rte_eth_dev_info* info, int len);
 void enumerate_pmd_ports();
 int init_pmd_port(int port, int rxqs, int txqs, int rxq_core[], int txq_core[], int nrxd, int ntxd,
-                  int loopback, int tso, int csumoffload);
+                  int loopback, int tso, int csumoffload, int rss);
+int add_udp_flow_rule(int port, uint16_t dst_port, int rxq);
+int set_pmd_port_mtu(int port, int mtu);
+struct port_stats;
+int get_port_stats(int port, struct port_stats* stats);
+struct port_link;
+int get_port_link(int port, struct port_link* link);
 int free_pmd_port(int port);
 int recv_pkts(int port, int qid, mbuf_array_t pkts, int len);
 int send_pkts(int port, int qid, mbuf_array_t pkts, int len);
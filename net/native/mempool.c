@@ -214,6 +214,41 @@ int init_secondary_mempool(const char *mempool_name) {
     return 0;
 }
 
+/*
+ * The number of mbufs in a packet pool, and the bytes each takes up. Mirrors
+ * MempoolStats on the Rust side, so the layout of the two must be kept in
+ * sync.
+ */
+struct mempool_stats {
+    uint64_t size;
+    uint64_t available;
+    uint64_t in_use;
+    uint64_t mbuf_bytes;
+};
+
+/*
+ * Read the counts of the packet pool of a NUMA socket (of a core, if pools
+ * are created per core) into 'stats'. Mbufs sitting in per-core caches count
+ * as in use. Returns zero on success, and -EINVAL if there is no such pool.
+ */
+int get_mempool_stats(int sid, struct mempool_stats *stats) {
+#if PER_CORE
+    int max = RTE_MAX_LCORE;
+#else
+    int max = RTE_MAX_NUMA_NODES;
+#endif
+    if (sid < 0 || sid >= max || pframe_pool[sid] == NULL) {
+        return -EINVAL;
+    }
+
+    struct rte_mempool *pool = pframe_pool[sid];
+    stats->size = pool->size;
+    stats->available = rte_mempool_avail_count(pool);
+    stats->in_use = rte_mempool_in_use_count(pool);
+    stats->mbuf_bytes = pool->header_size + pool->elt_size + pool->trailer_size;
+    return 0;
+}
+
 struct rte_mbuf *mbuf_alloc() {
     return rte_pktmbuf_alloc(current_pframe_pool());
 }
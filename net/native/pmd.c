@@ -5,11 +5,13 @@
 #include <rte_ip.h>
 #include "mempool.h"
 
+#include <errno.h>
 #include <pthread.h>
+#include <string.h>
 
-// Set of receive spinlocks. Required to allow any core to receive packets from
-// any rx queue on the port.
-pthread_spinlock_t rx_locks[16];
+// Set of receive spinlocks for every port. Required to allow any core to
+// receive packets from any rx queue on the port.
+pthread_spinlock_t rx_locks[RTE_MAX_ETHPORTS][16];
 
 #define HW_RXCSUM 0
 #define HW_TXCSUM 0
@@ -116,7 +118,7 @@ void enumerate_pmd_ports() {
 }
 
 int init_pmd_port(int port, int rxqs, int txqs, int rxq_core[], int txq_core[], int nrxd, int ntxd,
-                  int loopback, int tso, int csumoffload) {
+                  int loopback, int tso, int csumoffload, int rss) {
     struct rte_eth_dev_info dev_info = {};
     struct rte_eth_conf eth_conf;
     struct rte_eth_rxconf eth_rxconf;
@@ -129,11 +131,6 @@ int init_pmd_port(int port, int rxqs, int txqs, int rxq_core[], int txq_core[],
         return -EINVAL;
     }
 
-    // Initialize all receive locks.
-    for (q = 0; q < 16; q++) {
-        pthread_spin_init(&(rx_locks[q]), PTHREAD_PROCESS_PRIVATE);
-    }
-
     /* Need to accesss rte_eth_devices manually since DPDK currently
      * provides no other mechanism for checking whether something is
      * attached */
@@ -142,9 +139,25 @@ int init_pmd_port(int port, int rxqs, int txqs, int rxq_core[], int txq_core[],
         return -ENODEV;
     }
 
+    // Initialize all of this port's receive locks.
+    for (q = 0; q < 16; q++) {
+        pthread_spin_init(&(rx_locks[port][q]), PTHREAD_PROCESS_PRIVATE);
+    }
+
     eth_conf           = default_eth_conf;
     eth_conf.lpbk_mode = !(!loopback);
 
+    /* If requested, hash the IP addresses and UDP ports of received packets
+     * to pick an rx queue instead of using flow director. Every core then
+     * owns an rx queue without clients having to pick a destination port. */
+    rss = !(!rss);
+    if (rss) {
+        eth_conf.rxmode.mq_mode               = ETH_MQ_RX_RSS;
+        eth_conf.rx_adv_conf.rss_conf.rss_key = NULL;
+        eth_conf.rx_adv_conf.rss_conf.rss_hf  = ETH_RSS_IP | ETH_RSS_UDP;
+        eth_conf.fdir_conf.mode               = RTE_FDIR_MODE_NONE;
+    }
+
     /* Use defaut rx/tx configuration as provided by PMD drivers,
      * with minor tweaks */
     rte_eth_dev_info_get(port, &dev_info);
@@ -167,8 +180,18 @@ int init_pmd_port(int port, int rxqs, int txqs, int rxq_core[], int txq_core[],
     /* Disable promiscuous mode */
     rte_eth_promiscuous_disable(port);
 
+    /* Allocate receive rings and packet buffers on the NIC's NUMA node so
+     * that the NIC never DMAs across sockets. If the node is unknown, fall
+     * back to the node of the core polling the queue. */
+    int nic_sid = rte_eth_dev_socket_id(port);
+
     for (i = 0; i < rxqs; i++) {
         int sid = rte_lcore_to_socket_id(rxq_core[i]);
+        if (nic_sid >= 0 && nic_sid != sid) {
+            printf("Warning: rxq %d on core %d (socket %d) is not on the NIC's socket %d\n", i,
+                   rxq_core[i], sid, nic_sid);
+            sid = nic_sid;
+        }
         ret = rte_eth_rx_queue_setup(port, i, nrxd, sid, &eth_rxconf, get_pframe_pool(rxq_core[i], sid));
         if (ret != 0) {
             printf("Failed to initialize rxq\n");
@@ -192,7 +215,11 @@ int init_pmd_port(int port, int rxqs, int txqs, int rxq_core[], int txq_core[],
         return ret; /* Clean up things */
     }
 
-    /* Flow director setup */
+    /* Flow director setup. Not required if rx queues are picked by RSS. */
+    if (rss) {
+        return 0;
+    }
+
     int retval = 0;
 
     /*
@@ -240,6 +267,98 @@ int init_pmd_port(int port, int rxqs, int txqs, int rxq_core[], int txq_core[],
     return 0;
 }
 
+/*
+ * Add a flow director rule to the port that redirects packets with UDP
+ * destination port 'dst_port' to receive queue 'rxq'. Can be called any time
+ * after the port has been initialized with flow director enabled.
+ */
+int add_udp_flow_rule(int port, uint16_t dst_port, int rxq) {
+    struct rte_eth_fdir_filter fdirf;
+    memset(&fdirf, 0, sizeof(fdirf));
+    fdirf.soft_id = dst_port;
+    fdirf.input.flow_type = RTE_ETH_FLOW_NONFRAG_IPV4_UDP;
+    fdirf.input.flow.udp4_flow.dst_port = rte_cpu_to_be_16(dst_port);
+    fdirf.action.rx_queue = rxq;
+    fdirf.action.behavior = RTE_ETH_FDIR_ACCEPT;
+    fdirf.action.report_status = RTE_ETH_FDIR_NO_REPORT_STATUS;
+    return rte_eth_dev_filter_ctrl(port, RTE_ETH_FILTER_FDIR,
+                    RTE_ETH_FILTER_ADD, &fdirf);
+}
+
+/*
+ * Set the largest IP packet the port sends and receives. Returns zero on
+ * success and a negative value on failure.
+ */
+int set_pmd_port_mtu(int port, int mtu) {
+    return rte_eth_dev_set_mtu(port, mtu);
+}
+
+/*
+ * Counters maintained by the NIC for a port. Mirrors NicStats on the Rust
+ * side, so the layout of the two must be kept in sync.
+ */
+struct port_stats {
+    uint64_t ipackets;
+    uint64_t opackets;
+    uint64_t ibytes;
+    uint64_t obytes;
+    uint64_t imissed;
+    uint64_t ierrors;
+    uint64_t oerrors;
+    uint64_t rx_nombuf;
+};
+
+/*
+ * Read the NIC's counters for a port into 'stats'. Returns zero on success
+ * and a negative value on failure.
+ */
+int get_port_stats(int port, struct port_stats* stats) {
+    struct rte_eth_stats eth_stats;
+    int ret = rte_eth_stats_get(port, &eth_stats);
+    if (ret != 0) {
+        return ret;
+    }
+
+    stats->ipackets = eth_stats.ipackets;
+    stats->opackets = eth_stats.opackets;
+    stats->ibytes = eth_stats.ibytes;
+    stats->obytes = eth_stats.obytes;
+    stats->imissed = eth_stats.imissed;
+    stats->ierrors = eth_stats.ierrors;
+    stats->oerrors = eth_stats.oerrors;
+    stats->rx_nombuf = eth_stats.rx_nombuf;
+    return 0;
+}
+
+/*
+ * The state of a port's link. Mirrors LinkStatus on the Rust side, so the
+ * layout of the two must be kept in sync.
+ */
+struct port_link {
+    uint32_t speed_mbps;
+    uint8_t up;
+    uint8_t full_duplex;
+};
+
+/*
+ * Read the state of a port's link into 'link', without waiting for the link
+ * to come up. Returns zero on success and a negative value on failure.
+ */
+int get_port_link(int port, struct port_link* link) {
+    struct rte_eth_link eth_link;
+    if (!rte_eth_dev_is_valid_port(port)) {
+        return -ENODEV;
+    }
+
+    memset(&eth_link, 0, sizeof(eth_link));
+    rte_eth_link_get_nowait(port, &eth_link);
+
+    link->speed_mbps = eth_link.link_speed;
+    link->up = eth_link.link_status == ETH_LINK_UP;
+    link->full_duplex = eth_link.link_duplex == ETH_LINK_FULL_DUPLEX;
+    return 0;
+}
+
 void free_pmd_port(int port) {
     rte_eth_dev_stop(port);
     rte_eth_dev_close(port);
@@ -247,20 +366,20 @@ void free_pmd_port(int port) {
     int q = 0;
     // Destroy all receive locks.
     for (q = 0; q < 16; q++) {
-        pthread_spin_destroy(&(rx_locks[q]));
+        pthread_spin_destroy(&(rx_locks[port][q]));
     }
 }
 
 int recv_pkts(int port, int qid, mbuf_array_t pkts, int len) {
     // Try to acquire a receive lock. If the lock is busy, then return.
-    if (pthread_spin_trylock(&(rx_locks[qid])) != 0) {
+    if (pthread_spin_trylock(&(rx_locks[port][qid])) != 0) {
         return 0;
     }
 
     int ret = rte_eth_rx_burst(port, qid, (struct rte_mbuf**)pkts, len);
 
     // Release the spinlock.
-    pthread_spin_unlock(&(rx_locks[qid]));
+    pthread_spin_unlock(&(rx_locks[port][qid]));
 
 /* Removed prefetching since the benefit in performance for single core was
  * outweighed by the loss in performance with several cores. */
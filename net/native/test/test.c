@@ -110,10 +110,10 @@ int main (int argc, char* argv[]) {
 	enumerate_pmd_ports();
 	ret = init_pmd_port(PORT_OUT, THREADS, THREADS, 
 			rxq_cores, txq_cores, 256, 256, 
-			PORT_OUT == PORT_IN, 0, 0);
+			PORT_OUT == PORT_IN, 0, 0, 0);
 	assert(ret == 0);
 	if (PORT_IN != PORT_OUT) {
-		ret = init_pmd_port(PORT_IN, THREADS, THREADS, rxq_cores, txq_cores, 128, 512, 0, 0, 0);
+		ret = init_pmd_port(PORT_IN, THREADS, THREADS, rxq_cores, txq_cores, 128, 512, 0, 0, 0, 0);
 		assert(ret == 0);
 	}
 	n[0].tid = 10;
@@ -0,0 +1,268 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+// The `Pack` and `Unpack` derives for `sandstorm::pack`. They are used through sandstorm's
+// `derive` feature, which re-exports them from the pack module next to the traits they
+// implement.
+//
+// A struct deriving either must be `#[repr(C)]`, must not be generic, and must have only `Safe`
+// fields and no padding between or after them. All of this is checked when the struct is
+// compiled, so a client packing invoke arguments and the extension unpacking them from the same
+// struct definition are guaranteed to agree on it's layout. The struct is parsed straight off the
+// token stream so that the crate has no dependencies, and the code is generated as text.
+
+extern crate proc_macro;
+
+use proc_macro::{Delimiter, Spacing, TokenStream, TokenTree};
+
+/// Derives `sandstorm::pack::Pack`, letting a struct be written out as the bytes it occupies.
+#[proc_macro_derive(Pack)]
+pub fn derive_pack(input: TokenStream) -> TokenStream {
+    derive(input, "Pack", false)
+}
+
+/// Derives `sandstorm::pack::Unpack` (and `Safe`), letting a struct be read in place from bytes.
+#[proc_macro_derive(Unpack)]
+pub fn derive_unpack(input: TokenStream) -> TokenStream {
+    derive(input, "Unpack", true)
+}
+
+/// The parts of a struct the derives need: it's name and the types of it's fields.
+struct Struct {
+    name: String,
+    fields: Vec<String>,
+}
+
+/// Generates the implementation of a derive, or a `compile_error!` if the struct can't derive it.
+///
+/// # Arguments
+///
+/// * `input`: The struct the derive was placed on.
+/// * `trait_`: The name of the trait in `sandstorm::pack` to implement.
+/// * `safe`:   True if `Safe` must be implemented along with the trait.
+///
+/// # Return
+///
+/// The generated code.
+fn derive(input: TokenStream, trait_: &str, safe: bool) -> TokenStream {
+    let code = match parse(input) {
+        Ok(s) => generate(&s, trait_, safe),
+        Err(e) => format!("compile_error!({:?});", format!("#[derive({})]: {}", trait_, e)),
+    };
+
+    code.parse().expect("Failed to parse generated code.")
+}
+
+/// Generates the implementation of a derive for a struct.
+///
+/// The layout checks go into a const of their own, so that the helpers they define can't collide
+/// with anything in the module of the struct. Every field type is passed to a function bounded
+/// on `Safe`, and the bytes of padding are computed as the length of an array that must be empty.
+///
+/// # Arguments
+///
+/// * `s`:      The struct to implement the trait for.
+/// * `trait_`: The name of the trait in `sandstorm::pack` to implement.
+/// * `safe`:   True if `Safe` must be implemented along with the trait.
+///
+/// # Return
+///
+/// The generated code.
+fn generate(s: &Struct, trait_: &str, safe: bool) -> String {
+    let mut fields = String::new();
+    let mut sizes = String::from("0");
+    for ty in s.fields.iter() {
+        fields.push_str(&format!("safe::<{}>();", ty));
+        sizes.push_str(&format!(" + ::sandstorm::size_of::<{}>()", ty));
+    }
+
+    let safe = match safe {
+        true => format!("unsafe impl ::sandstorm::pack::Safe for {} {{}}", s.name),
+        false => String::new(),
+    };
+
+    format!(
+        "#[allow(non_upper_case_globals)]
+        const _LAYOUT_{trait_}_{name}: () = {{
+            #[allow(dead_code)]
+            fn safe<T: ::sandstorm::pack::Safe>() {{}}
+
+            #[allow(dead_code)]
+            fn fields() {{ {fields} }}
+
+            #[allow(dead_code)]
+            const PADDING: [(); 0] = [(); ::sandstorm::size_of::<{name}>() - ({sizes})];
+        }};
+
+        {safe}
+        unsafe impl ::sandstorm::pack::{trait_} for {name} {{}}",
+        trait_ = trait_,
+        name = s.name,
+        fields = fields,
+        sizes = sizes,
+        safe = safe
+    )
+}
+
+/// Parses the struct a derive was placed on.
+///
+/// # Arguments
+///
+/// * `input`: The tokens of the struct, including it's attributes.
+///
+/// # Return
+///
+/// The name and field types of the struct, or a description of why it can't be derived for.
+fn parse(input: TokenStream) -> Result<Struct, String> {
+    let mut tokens = input.into_iter().peekable();
+
+    // Outer attributes, only `repr` matters.
+    let mut repr_c = false;
+    while is_punct(tokens.peek(), '#') {
+        tokens.next();
+        if let Some(TokenTree::Group(attr)) = tokens.next() {
+            let mut attr = attr.stream().into_iter();
+            match (attr.next(), attr.next()) {
+                (Some(TokenTree::Ident(ref i)), Some(TokenTree::Group(ref g)))
+                    if i.to_string() == "repr" =>
+                {
+                    for hint in g.stream() {
+                        match hint.to_string().as_str() {
+                            "C" => repr_c = true,
+                            "packed" => {
+                                return Err("packed structs can't be referenced in place".into())
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    skip_visibility(&mut tokens);
+
+    match tokens.next() {
+        Some(TokenTree::Ident(ref i)) if i.to_string() == "struct" => {}
+        _ => return Err("only structs can be packed".into()),
+    }
+
+    let name = match tokens.next() {
+        Some(TokenTree::Ident(i)) => i.to_string(),
+        _ => return Err("expected the name of the struct".into()),
+    };
+
+    if !repr_c {
+        return Err(format!("{} must be #[repr(C)] for it's layout to be fixed", name));
+    }
+
+    let fields = match tokens.next() {
+        Some(TokenTree::Group(ref g)) if g.delimiter() == Delimiter::Brace => {
+            fields(g.stream(), true)
+        }
+        Some(TokenTree::Group(ref g)) if g.delimiter() == Delimiter::Parenthesis => {
+            fields(g.stream(), false)
+        }
+        Some(TokenTree::Punct(ref p)) if p.as_char() == ';' => Vec::new(),
+        _ => return Err(format!("{} can't be generic", name)),
+    };
+
+    Ok(Struct {
+        name: name,
+        fields: fields,
+    })
+}
+
+/// Splits the body of a struct into the types of it's fields.
+///
+/// # Arguments
+///
+/// * `body`:  The tokens between the braces or parentheses of the struct.
+/// * `named`: True if the fields are named, false if the struct is a tuple struct.
+///
+/// # Return
+///
+/// The type of each field, as source text.
+fn fields(body: TokenStream, named: bool) -> Vec<String> {
+    let mut types = Vec::new();
+    let mut tokens = body.into_iter().peekable();
+
+    while tokens.peek().is_some() {
+        // Field attributes and visibility, then the name and colon of a named field.
+        while is_punct(tokens.peek(), '#') {
+            tokens.next();
+            tokens.next();
+        }
+        skip_visibility(&mut tokens);
+        if named {
+            tokens.next();
+            tokens.next();
+        }
+
+        // The type runs upto the next comma outside of angle brackets. The '>' of an arrow in a
+        // function pointer type does not close one.
+        let mut ty = Vec::new();
+        let mut depth = 0;
+        let mut arrow = false;
+        while let Some(token) = tokens.next() {
+            if let TokenTree::Punct(ref p) = token {
+                match p.as_char() {
+                    ',' if depth == 0 => break,
+                    '<' => depth += 1,
+                    '>' if !arrow => depth -= 1,
+                    _ => {}
+                }
+                arrow = p.as_char() == '-' && p.spacing() == Spacing::Joint;
+            } else {
+                arrow = false;
+            }
+            ty.push(token);
+        }
+
+        if !ty.is_empty() {
+            types.push(ty.into_iter().collect::<TokenStream>().to_string());
+        }
+    }
+
+    types
+}
+
+/// Skips `pub`, along with any restriction on it such as `(crate)`.
+fn skip_visibility<I: Iterator<Item = TokenTree>>(tokens: &mut std::iter::Peekable<I>) {
+    let public = match tokens.peek() {
+        Some(TokenTree::Ident(ref i)) => i.to_string() == "pub",
+        _ => false,
+    };
+
+    if public {
+        tokens.next();
+        let restricted = match tokens.peek() {
+            Some(TokenTree::Group(ref g)) => g.delimiter() == Delimiter::Parenthesis,
+            _ => false,
+        };
+        if restricted {
+            tokens.next();
+        }
+    }
+}
+
+/// Returns true if a token is the punctuation character `c`.
+fn is_punct(token: Option<&TokenTree>, c: char) -> bool {
+    match token {
+        Some(TokenTree::Punct(ref p)) => p.as_char() == c,
+        _ => false,
+    }
+}
@@ -13,12 +13,23 @@
  * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
  */
 
+#[cfg(feature = "std")]
 extern crate bytes;
 
-use std::cell::Cell;
+use core::cell::Cell;
 
+#[cfg(not(feature = "std"))]
+use alloc::vec::{IntoIter, Vec};
+
+#[cfg(feature = "std")]
+use std::vec::IntoIter;
+
+#[cfg(feature = "std")]
 use self::bytes::{BufMut, Bytes, BytesMut};
 
+#[cfg(not(feature = "std"))]
+use bytes_alloc::{BufMut, Bytes, BytesMut};
+
 /// This type represents a read-only buffer of bytes that can be received from
 /// the database. This type is primarily used to read objects from the database.
 pub struct ReadBuf {
@@ -74,6 +85,217 @@ impl ReadBuf {
     pub fn read(&self) -> &[u8] {
         self.inner.as_ref()
     }
+
+    /// This method returns a `ReadBuf` over a range of the bytes inside this
+    /// `ReadBuf`, without copying them. The range is cut short at the end of
+    /// this `ReadBuf`.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset`: The offset the range starts at.
+    /// * `len`:    The length of the range.
+    ///
+    /// # Return
+    ///
+    /// A `ReadBuf` over the range, empty if it starts past the end.
+    pub fn range(&self, offset: usize, len: usize) -> ReadBuf {
+        let begin = offset.min(self.inner.len());
+        let end = begin.saturating_add(len).min(self.inner.len());
+
+        ReadBuf {
+            inner: self.inner.slice(begin, end),
+        }
+    }
+
+    /// This method reads a single byte off the `ReadBuf`.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset`: The offset of the byte into the `ReadBuf`.
+    ///
+    /// # Return
+    ///
+    /// The byte, or None if the offset is past the end of the `ReadBuf`.
+    pub fn read_u8(&self, offset: usize) -> Option<u8> {
+        self.read().get(offset).cloned()
+    }
+
+    /// This method reads a single u16 off the `ReadBuf`.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset`: The offset of the u16's first byte into the `ReadBuf`.
+    /// * `le`:     The ordering to be used while performing the read. If true,
+    ///             little-endian will be used. If false, big-endian will be used.
+    ///
+    /// # Return
+    ///
+    /// The u16, or None if the `ReadBuf` ends before it does.
+    pub fn read_u16(&self, offset: usize, le: bool) -> Option<u16> {
+        self.read_uint(offset, 2, le).map(|data| data as u16)
+    }
+
+    /// This method reads a single u32 off the `ReadBuf`.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset`: The offset of the u32's first byte into the `ReadBuf`.
+    /// * `le`:     The ordering to be used while performing the read. If true,
+    ///             little-endian will be used. If false, big-endian will be used.
+    ///
+    /// # Return
+    ///
+    /// The u32, or None if the `ReadBuf` ends before it does.
+    pub fn read_u32(&self, offset: usize, le: bool) -> Option<u32> {
+        self.read_uint(offset, 4, le).map(|data| data as u32)
+    }
+
+    /// This method reads a single u64 off the `ReadBuf`.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset`: The offset of the u64's first byte into the `ReadBuf`.
+    /// * `le`:     The ordering to be used while performing the read. If true,
+    ///             little-endian will be used. If false, big-endian will be used.
+    ///
+    /// # Return
+    ///
+    /// The u64, or None if the `ReadBuf` ends before it does.
+    pub fn read_u64(&self, offset: usize, le: bool) -> Option<u64> {
+        self.read_uint(offset, 8, le)
+    }
+
+    /// This method returns a cursor that reads the `ReadBuf` sequentially from
+    /// it's first byte, such as to parse a serialized record out of it.
+    ///
+    /// # Return
+    ///
+    /// A `ReadCursor` positioned at the start of the `ReadBuf`.
+    pub fn cursor<'a>(&'a self) -> ReadCursor<'a> {
+        ReadCursor { buf: self, pos: 0 }
+    }
+
+    // Reads an unsigned integer `len` bytes long at an offset, in either byte order. Returns
+    // None if the integer does not lie entirely within the `ReadBuf`.
+    fn read_uint(&self, offset: usize, len: usize, le: bool) -> Option<u64> {
+        let end = match offset.checked_add(len) {
+            Some(end) => end,
+            None => return None,
+        };
+
+        self.read().get(offset..end).map(|bytes| match le {
+            true => bytes.iter().rev().fold(0, |acc, &b| (acc << 8) | b as u64),
+            false => bytes.iter().fold(0, |acc, &b| (acc << 8) | b as u64),
+        })
+    }
+}
+
+/// This type reads a `ReadBuf` sequentially, keeping track of how far into it
+/// it has read. Every read is bounds checked: a read that would run past the
+/// end of the `ReadBuf` returns None, and leaves the cursor where it was.
+pub struct ReadCursor<'a> {
+    // The buffer being read.
+    buf: &'a ReadBuf,
+
+    // The offset into the buffer of the next byte to be read.
+    pos: usize,
+}
+
+// Methods on ReadCursor.
+impl<'a> ReadCursor<'a> {
+    /// This method returns the offset into the `ReadBuf` of the next byte the
+    /// cursor will read.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// This method returns the number of bytes left to be read.
+    pub fn remaining(&self) -> usize {
+        self.buf.len().saturating_sub(self.pos)
+    }
+
+    /// This method reads the next byte off the `ReadBuf`.
+    ///
+    /// # Return
+    ///
+    /// The byte, or None if there are no bytes left.
+    pub fn next_u8(&mut self) -> Option<u8> {
+        let data = self.buf.read_u8(self.pos);
+        self.advance(data, 1)
+    }
+
+    /// This method reads the next u16 off the `ReadBuf`.
+    ///
+    /// # Arguments
+    ///
+    /// * `le`: The ordering to be used while performing the read. If true,
+    ///         little-endian will be used. If false, big-endian will be used.
+    ///
+    /// # Return
+    ///
+    /// The u16, or None if fewer than two bytes are left.
+    pub fn next_u16(&mut self, le: bool) -> Option<u16> {
+        let data = self.buf.read_u16(self.pos, le);
+        self.advance(data, 2)
+    }
+
+    /// This method reads the next u32 off the `ReadBuf`.
+    ///
+    /// # Arguments
+    ///
+    /// * `le`: The ordering to be used while performing the read. If true,
+    ///         little-endian will be used. If false, big-endian will be used.
+    ///
+    /// # Return
+    ///
+    /// The u32, or None if fewer than four bytes are left.
+    pub fn next_u32(&mut self, le: bool) -> Option<u32> {
+        let data = self.buf.read_u32(self.pos, le);
+        self.advance(data, 4)
+    }
+
+    /// This method reads the next u64 off the `ReadBuf`.
+    ///
+    /// # Arguments
+    ///
+    /// * `le`: The ordering to be used while performing the read. If true,
+    ///         little-endian will be used. If false, big-endian will be used.
+    ///
+    /// # Return
+    ///
+    /// The u64, or None if fewer than eight bytes are left.
+    pub fn next_u64(&mut self, le: bool) -> Option<u64> {
+        let data = self.buf.read_u64(self.pos, le);
+        self.advance(data, 8)
+    }
+
+    /// This method reads the next few bytes off the `ReadBuf`, without copying
+    /// them.
+    ///
+    /// # Arguments
+    ///
+    /// * `len`: The number of bytes to read.
+    ///
+    /// # Return
+    ///
+    /// A slice over the bytes, or None if fewer than `len` bytes are left.
+    pub fn next_slice(&mut self, len: usize) -> Option<&'a [u8]> {
+        let buf: &'a ReadBuf = self.buf;
+        let data = match self.pos.checked_add(len) {
+            Some(end) => buf.read().get(self.pos..end),
+            None => None,
+        };
+        self.advance(data, len)
+    }
+
+    // Moves the cursor past a value of `len` bytes if it was read, and passes
+    // the value on.
+    fn advance<T>(&mut self, data: Option<T>, len: usize) -> Option<T> {
+        if data.is_some() {
+            self.pos += len;
+        }
+        data
+    }
 }
 
 /// This type represents a read-write buffer of bytes that can be received from
@@ -270,6 +492,17 @@ impl MultiReadBuf {
         self.inner.len()
     }
 
+    /// This method indicates if the cursor is on an object, in which case
+    /// `len()` and `read()` will not panic. The cursor leaves the objects
+    /// once `next()` or `prev()` fail to move it.
+    ///
+    /// # Return
+    ///
+    /// True if the cursor is on an object. False otherwise.
+    pub fn valid(&self) -> bool {
+        !self.panic.get() && self.index.get() < self.inner.len()
+    }
+
     pub fn len(&self) -> usize {
         if self.panic.get() {
             panic!("Out of bounds on MultiReadBuf.");
@@ -319,10 +552,59 @@ impl MultiReadBuf {
     }
 }
 
+/// The values of a list of keys looked up together, in the order of the keys. Unlike a
+/// `MultiReadBuf`, a key that does not exist does not fail the whole lookup: iterating yields
+/// `Some` with the value of every key that exists, and `None` for every key that does not.
+///
+/// ```ignore
+/// for val in db.multiget_iter(table, 8, &keys)? {
+///     match val {
+///         Some(val) => hits += val.read()[0] as u64,
+///         None => misses += 1,
+///     }
+/// }
+/// ```
+pub struct MultiGetIter {
+    // The values not yet iterated over.
+    inner: IntoIter<Option<ReadBuf>>,
+}
+
+// Methods on MultiGetIter.
+impl MultiGetIter {
+    /// This method returns a MultiGetIter over a list of values. Extensions cannot construct a
+    /// `ReadBuf` on their own, and so cannot pass off values as coming from the database.
+    ///
+    /// # Arguments
+    ///
+    /// * `vals`: The value of every key looked up, in order, None for keys that do not exist.
+    ///
+    /// # Return
+    /// The `MultiGetIter` over the passed in values.
+    pub fn new(vals: Vec<Option<ReadBuf>>) -> MultiGetIter {
+        MultiGetIter {
+            inner: vals.into_iter(),
+        }
+    }
+}
+
+impl Iterator for MultiGetIter {
+    type Item = Option<ReadBuf>;
+
+    fn next(&mut self) -> Option<Option<ReadBuf>> {
+        self.inner.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl ExactSizeIterator for MultiGetIter {}
+
 // This module implements simple unit tests for ReadBuf and WriteBuf.
 #[cfg(test)]
 mod tests {
-    use super::{ReadBuf, WriteBuf};
+    use super::{MultiGetIter, ReadBuf, WriteBuf};
     use bytes::{BufMut, Bytes, BytesMut};
 
     // This method tests the "len()" method on ReadBuf.
@@ -381,6 +663,101 @@ mod tests {
         }
     }
 
+    // This method tests that "range()" on ReadBuf returns the bytes in the
+    // range, cut short at the end of the ReadBuf.
+    #[test]
+    fn test_readbuf_range() {
+        // Write data into a BytesMut.
+        let mut buf = BytesMut::with_capacity(10);
+        buf.put_slice(&[1, 2, 3, 4, 5, 6]);
+
+        // Wrap the BytesMut inside a ReadBuf, and verify the ranges over it.
+        unsafe {
+            let buf = ReadBuf::new(buf.freeze());
+            assert_eq!(&[2, 3, 4], buf.range(1, 3).read());
+            assert_eq!(&[5, 6], buf.range(4, 10).read());
+            assert!(buf.range(6, 2).is_empty());
+            assert!(buf.range(9, usize::max_value()).is_empty());
+        }
+    }
+
+    // This method tests the functionality of the "read_u8()" method on
+    // ReadBuf, including reads past the end of the ReadBuf.
+    #[test]
+    fn test_readbuf_readu8() {
+        unsafe {
+            let buf = ReadBuf::new(Bytes::from(&[1u8, 200][..]));
+            assert_eq!(Some(1), buf.read_u8(0));
+            assert_eq!(Some(200), buf.read_u8(1));
+            assert_eq!(None, buf.read_u8(2));
+        }
+    }
+
+    // This method tests the functionality of the "read_u16()" method on
+    // ReadBuf, in either order and at an offset.
+    #[test]
+    fn test_readbuf_readu16() {
+        unsafe {
+            let buf = ReadBuf::new(Bytes::from(&[9u8, 2, 1][..]));
+            assert_eq!(Some(258), buf.read_u16(1, true));
+            assert_eq!(Some(513), buf.read_u16(1, false));
+            assert_eq!(None, buf.read_u16(2, true));
+        }
+    }
+
+    // This method tests the functionality of the "read_u32()" method on
+    // ReadBuf, in either order and at an offset.
+    #[test]
+    fn test_readbuf_readu32() {
+        unsafe {
+            let buf = ReadBuf::new(Bytes::from(&[9u8, 2, 3, 4, 5][..]));
+            assert_eq!(Some(84148994), buf.read_u32(1, true));
+            assert_eq!(Some(33752069), buf.read_u32(1, false));
+            assert_eq!(None, buf.read_u32(2, true));
+        }
+    }
+
+    // This method tests the functionality of the "read_u64()" method on
+    // ReadBuf, in either order, and that reads past the end return None
+    // rather than aborting the extension.
+    #[test]
+    fn test_readbuf_readu64() {
+        unsafe {
+            let buf = ReadBuf::new(Bytes::from(&[2u8, 3, 4, 5, 2, 0, 0, 0][..]));
+            assert_eq!(Some(8674083586), buf.read_u64(0, true));
+            assert_eq!(Some(0x0203040502000000), buf.read_u64(0, false));
+            assert_eq!(None, buf.read_u64(1, true));
+            assert_eq!(None, buf.read_u64(usize::max_value(), true));
+        }
+    }
+
+    // This method tests that a ReadCursor reads a serialized record field by
+    // field, and that a read past the end fails without moving the cursor.
+    #[test]
+    fn test_readcursor() {
+        unsafe {
+            let buf = ReadBuf::new(Bytes::from(&[7u8, 2, 1, 0, 0, 1, 2, 3, 4, 5][..]));
+            let mut cursor = buf.cursor();
+            assert_eq!(10, cursor.remaining());
+
+            assert_eq!(Some(7), cursor.next_u8());
+            assert_eq!(Some(258), cursor.next_u32(true));
+            assert_eq!(Some(&[1, 2][..]), cursor.next_slice(2));
+            assert_eq!(7, cursor.position());
+            assert_eq!(3, cursor.remaining());
+
+            assert_eq!(None, cursor.next_u32(false));
+            assert_eq!(None, cursor.next_slice(usize::max_value()));
+            assert_eq!(7, cursor.position());
+
+            assert_eq!(Some(0x0304), cursor.next_u16(false));
+            assert_eq!(Some(5), cursor.next_u8());
+            assert_eq!(None, cursor.next_u8());
+            assert_eq!(None, cursor.next_u64(true));
+            assert_eq!(0, cursor.remaining());
+        }
+    }
+
     // This method tests the functionality of the "len()" method on WriteBuf.
     #[test]
     fn test_writebuf_len() {
@@ -607,4 +984,19 @@ mod tests {
             buf.write_u64(8674083586, true);
         }
     }
+
+    // This method tests that a MultiGetIter yields every value in order, with keys that do not
+    // exist yielding None.
+    #[test]
+    fn test_multigetiter() {
+        unsafe {
+            let vals = vec![Some(ReadBuf::new(Bytes::from(&[1, 2][..]))), None];
+            let mut iter = MultiGetIter::new(vals);
+            assert_eq!(2, iter.len());
+
+            assert_eq!(&[1, 2], iter.next().unwrap().unwrap().read());
+            assert!(iter.next().unwrap().is_none());
+            assert!(iter.next().is_none());
+        }
+    }
 }
@@ -0,0 +1,255 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! The part of the `bytes` crate the buffers in this crate are built on, implemented on `alloc`
+//! for builds without std, which the `bytes` crate requires. The types behave the way their
+//! namesakes do, including panicking on writes past the capacity of a `BytesMut`. A database
+//! running extensions built this way hands them buffers wrapping these types.
+
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+
+use core::ops::Deref;
+
+/// A cheaply cloneable, immutable range of bytes. Clones and slices share the bytes.
+#[derive(Clone, Debug)]
+pub struct Bytes {
+    // The bytes shared by every clone and slice.
+    inner: Rc<Vec<u8>>,
+
+    // The range of the shared bytes this one is over.
+    begin: usize,
+    end: usize,
+}
+
+// Implementation of methods on Bytes.
+impl Bytes {
+    /// Returns an empty `Bytes`.
+    pub fn new() -> Bytes {
+        Bytes::from(Vec::new())
+    }
+
+    /// Returns an empty `Bytes`. The capacity is ignored, since a `Bytes` cannot grow.
+    pub fn with_capacity(_capacity: usize) -> Bytes {
+        Bytes::new()
+    }
+
+    /// Returns the number of bytes in the range.
+    pub fn len(&self) -> usize {
+        self.end - self.begin
+    }
+
+    /// Returns true if the range is empty.
+    pub fn is_empty(&self) -> bool {
+        self.begin == self.end
+    }
+
+    /// Returns a `Bytes` over a part of this one, sharing it's bytes.
+    ///
+    /// # Arguments
+    ///
+    /// * `begin`: The offset of the part.
+    /// * `end`:   The offset the part ends before.
+    ///
+    /// # Abort
+    ///
+    /// Panics if the part is not within this `Bytes`.
+    pub fn slice(&self, begin: usize, end: usize) -> Bytes {
+        assert!(begin <= end && end <= self.len());
+
+        Bytes {
+            inner: Rc::clone(&self.inner),
+            begin: self.begin + begin,
+            end: self.begin + end,
+        }
+    }
+}
+
+impl From<Vec<u8>> for Bytes {
+    fn from(vec: Vec<u8>) -> Bytes {
+        let len = vec.len();
+
+        Bytes {
+            inner: Rc::new(vec),
+            begin: 0,
+            end: len,
+        }
+    }
+}
+
+impl AsRef<[u8]> for Bytes {
+    fn as_ref(&self) -> &[u8] {
+        &self.inner[self.begin..self.end]
+    }
+}
+
+impl Deref for Bytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.as_ref()
+    }
+}
+
+/// A unique buffer of bytes of a fixed capacity, written to through `BufMut`.
+#[derive(Debug)]
+pub struct BytesMut {
+    // The bytes written so far.
+    inner: Vec<u8>,
+
+    // The number of bytes the buffer can hold.
+    capacity: usize,
+}
+
+// Implementation of methods on BytesMut.
+impl BytesMut {
+    /// Returns an empty buffer that can hold `capacity` bytes.
+    pub fn with_capacity(capacity: usize) -> BytesMut {
+        BytesMut {
+            inner: Vec::with_capacity(capacity),
+            capacity: capacity,
+        }
+    }
+
+    /// Returns the number of bytes written to the buffer.
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    /// Returns the number of bytes the buffer can hold.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Converts the buffer into an immutable `Bytes` over what was written to it.
+    pub fn freeze(self) -> Bytes {
+        Bytes::from(self.inner)
+    }
+}
+
+impl AsRef<[u8]> for BytesMut {
+    fn as_ref(&self) -> &[u8] {
+        &self.inner
+    }
+}
+
+impl Deref for BytesMut {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.inner
+    }
+}
+
+/// Writes to the end of a buffer, panicking if it does not have the room.
+pub trait BufMut {
+    /// Appends a slice of bytes.
+    fn put_slice(&mut self, src: &[u8]);
+
+    /// Appends a byte.
+    fn put_u8(&mut self, n: u8) {
+        self.put_slice(&[n]);
+    }
+
+    /// Appends a u16, little endian.
+    fn put_u16_le(&mut self, n: u16) {
+        self.put_slice(&le(n as u64, 2)[..2]);
+    }
+
+    /// Appends a u16, big endian.
+    fn put_u16_be(&mut self, n: u16) {
+        self.put_slice(&be(n as u64, 2)[6..]);
+    }
+
+    /// Appends a u32, little endian.
+    fn put_u32_le(&mut self, n: u32) {
+        self.put_slice(&le(n as u64, 4)[..4]);
+    }
+
+    /// Appends a u32, big endian.
+    fn put_u32_be(&mut self, n: u32) {
+        self.put_slice(&be(n as u64, 4)[4..]);
+    }
+
+    /// Appends a u64, little endian.
+    fn put_u64_le(&mut self, n: u64) {
+        self.put_slice(&le(n, 8));
+    }
+
+    /// Appends a u64, big endian.
+    fn put_u64_be(&mut self, n: u64) {
+        self.put_slice(&be(n, 8));
+    }
+}
+
+impl BufMut for BytesMut {
+    fn put_slice(&mut self, src: &[u8]) {
+        assert!(self.capacity - self.inner.len() >= src.len());
+        self.inner.extend_from_slice(src);
+    }
+}
+
+// Returns the `n` low bytes of a value little endian, at the start of an array.
+fn le(val: u64, n: usize) -> [u8; 8] {
+    let mut buf = [0; 8];
+    for byte in 0..n {
+        buf[byte] = (val >> (8 * byte)) as u8;
+    }
+    buf
+}
+
+// Returns the `n` low bytes of a value big endian, at the end of an array.
+fn be(val: u64, n: usize) -> [u8; 8] {
+    let mut buf = [0; 8];
+    for byte in 0..n {
+        buf[7 - byte] = (val >> (8 * byte)) as u8;
+    }
+    buf
+}
+
+// This module contains unit tests for the alloc based Bytes and BytesMut.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // This unit test verifies that integers are written in the requested order, and that slices
+    // of the frozen buffer share it's bytes.
+    #[test]
+    fn test_write() {
+        let mut buf = BytesMut::with_capacity(15);
+        buf.put_u8(1);
+        buf.put_u16_le(0x0302);
+        buf.put_u32_be(0x04050607);
+        buf.put_u64_le(0x0f0e0d0c0b0a0908);
+        assert_eq!(15, buf.len());
+
+        let bytes = buf.freeze();
+        let expected: Vec<u8> = (1..16).collect();
+        assert_eq!(&expected[..], bytes.as_ref());
+        assert_eq!(&[4, 5, 6], &bytes.slice(3, 6)[..]);
+        assert_eq!(&[5], &bytes.slice(3, 6).slice(1, 2)[..]);
+        assert!(bytes.slice(15, 15).is_empty());
+    }
+
+    // This unit test verifies that writes past the capacity panic, even when the vector
+    // underneath has room for them.
+    #[test]
+    #[should_panic]
+    fn test_write_overflow() {
+        let mut buf = BytesMut::with_capacity(3);
+        buf.put_u16_be(1);
+        buf.put_u16_be(2);
+    }
+}
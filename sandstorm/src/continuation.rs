@@ -0,0 +1,115 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! An extension interface that builds on stable Rust. Extensions built on generators need a
+//! nightly compiler; an extension can instead export a symbol called `init_stable`, returning a
+//! `Continuation` that the database resumes until it is done, the way it resumes a generator.
+//! The continuation keeps whatever state has to survive a yield in itself, typically as a
+//! state machine:
+//!
+//! ```ignore
+//! #[no_mangle]
+//! pub fn init_stable(db: Rc<DB>) -> Box<Continuation> {
+//!     let mut looked_up = 0;
+//!     continuation::from_fn(move || {
+//!         if looked_up == 2 {
+//!             return Step::Done(0);
+//!         }
+//!
+//!         db.get(1, &[looked_up]);
+//!         looked_up += 1;
+//!         Step::Yield(0)
+//!     })
+//! }
+//! ```
+//!
+//! The database supports both interfaces, and an extension exporting `init` keeps being called
+//! through it, so extensions can move over one at a time. Extensions built on stable Rust depend
+//! on this crate without it's `nightly` feature.
+
+#[cfg(not(feature = "std"))]
+use alloc::boxed::Box;
+
+/// What an invocation did when it was resumed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Step {
+    /// The invocation yielded to the database, and has to be resumed again to make progress.
+    /// The value means what a generator's yielded value does.
+    Yield(u64),
+
+    /// The invocation is complete, and must not be resumed again. The value means what a
+    /// generator's return value does.
+    Done(u64),
+}
+
+/// An invocation of an extension that is run by resuming it until it returns `Step::Done`.
+pub trait Continuation {
+    /// Runs the invocation until it's next yield, or until it completes.
+    ///
+    /// # Return
+    ///
+    /// `Step::Yield` if the invocation yielded, `Step::Done` if it completed.
+    fn resume(&mut self) -> Step;
+}
+
+/// The continuation returned by `from_fn()`.
+pub struct FnContinuation<F: FnMut() -> Step> {
+    // The closure called on every resume.
+    step: F,
+}
+
+impl<F: FnMut() -> Step> Continuation for FnContinuation<F> {
+    fn resume(&mut self) -> Step {
+        (self.step)()
+    }
+}
+
+/// Returns a continuation that calls a closure every time it is resumed. The closure keeps the
+/// state of the invocation in what it captures.
+///
+/// # Arguments
+///
+/// * `step`: The closure, returning what the invocation did on that resume.
+///
+/// # Return
+///
+/// The continuation, ready to be returned from `init_stable`.
+pub fn from_fn<F: FnMut() -> Step + 'static>(step: F) -> Box<Continuation> {
+    Box::new(FnContinuation { step: step })
+}
+
+// This module contains unit tests for continuations.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // This unit test verifies that a continuation built from a closure keeps it's state across
+    // resumes.
+    #[test]
+    fn test_from_fn() {
+        let mut count = 0;
+        let mut cont = from_fn(move || {
+            count += 1;
+            match count {
+                3 => Step::Done(count),
+                _ => Step::Yield(0),
+            }
+        });
+
+        assert_eq!(Step::Yield(0), cont.resume());
+        assert_eq!(Step::Yield(0), cont.resume());
+        assert_eq!(Step::Done(3), cont.resume());
+    }
+}
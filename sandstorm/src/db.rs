@@ -13,7 +13,28 @@
  * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
  */
 
-use super::buf::{ReadBuf, WriteBuf, MultiReadBuf};
+use super::buf::{ReadBuf, WriteBuf, MultiReadBuf, MultiGetIter};
+
+/// The quotas an operation can be refused for. Tells a tenant whether it needs to shrink it's
+/// data or slow down.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QuotaExceeded {
+    /// The objects stored in the tables of the tenant owning the table would take up more than
+    /// the owner's memory quota. Deleting objects frees up room.
+    Memory,
+
+    /// The invocation has already allocated as many bytes on the table heap as an invocation
+    /// can. Issuing the writes over several invocations gets around it.
+    Allocation,
+
+    /// The tenant is sending requests or bytes faster than it's rate limit allows. Retrying the
+    /// request later gets around it.
+    Rate,
+}
+
+/// The number of times `DB::append` retries an append that lost a race against other writes to
+/// the key before giving up.
+pub const APPEND_RETRIES: usize = 16;
 
 /// Definition of the DB trait that will allow extensions to access
 /// the database.
@@ -36,6 +57,54 @@ pub trait DB {
 
     fn multiget(&self, table: u64, key_len: u16, keys: &[u8]) -> Option<MultiReadBuf>;
 
+    /// This method will perform a lookup on a list of keys inside the
+    /// database, and return an iterator over their values. Unlike `multiget`,
+    /// keys that do not exist are yielded as None rather than failing the
+    /// whole lookup, so that misses can be handled per key.
+    ///
+    /// # Arguments
+    ///
+    /// * `table`:   An identifier of the data table the key-value pairs
+    ///              belong to.
+    /// * `key_len`: The length of every key.
+    /// * `keys`:    A slice of bytes over the keys, one after the other.
+    ///
+    /// # Return
+    ///
+    /// An iterator over the value of every key, in order. None if `keys` is
+    /// not made up of keys `key_len` bytes long, or if the table does not
+    /// exist or cannot be read.
+    fn multiget_iter(&self, table: u64, key_len: u16, keys: &[u8]) -> Option<MultiGetIter> {
+        if key_len == 0 || keys.len() % key_len as usize != 0 {
+            return None;
+        }
+
+        let vals = keys.chunks(key_len as usize).map(|key| self.get(table, key)).collect();
+        Some(MultiGetIter::new(vals))
+    }
+
+    /// This method will perform a lookup on a key-value pair inside the
+    /// database, and return a handle over a range of it's value. Reading a
+    /// large object a range at a time lets an extension yield in between, and
+    /// only touch the parts of the value it needs.
+    ///
+    /// # Arguments
+    ///
+    /// * `table`:  An identifier of the data table the key-value pair
+    ///             belongs to.
+    /// * `key`:    A slice of bytes over the key to be looked up.
+    /// * `offset`: The offset into the value the range starts at.
+    /// * `len`:    The length of the range.
+    ///
+    /// # Return
+    ///
+    /// A handle that can be used to read the range if the key-value pair
+    /// exists inside the database. The range is cut short at the end of the
+    /// value, and is empty if it starts past the end.
+    fn get_range(&self, table: u64, key: &[u8], offset: usize, len: usize) -> Option<ReadBuf> {
+        self.get(table, key).map(|val| val.range(offset, len))
+    }
+
     /// This method will allocate space for a key-value pair inside the
     /// database, and if the allocation was successfull, return a handle that
     /// can be used to write a value into the allocation, and that can be
@@ -59,6 +128,31 @@ pub trait DB {
     /// the DB trait.
     fn alloc(&self, table: u64, key: &[u8], val_len: u64) -> Option<WriteBuf>;
 
+    /// This method will return the quota that caused an earlier call to `alloc` to fail. If an
+    /// invocation is refused for a quota, the response to it carries a status identifying the
+    /// quota, even if the extension went on to complete.
+    ///
+    /// # Return
+    ///
+    /// The quota that refused the most recent allocation refused for a quota, or None if no
+    /// allocation was refused for one.
+    fn quota_exceeded(&self) -> Option<QuotaExceeded> {
+        None
+    }
+
+    /// This method will return how much longer the tenant that invoked the extension will wait
+    /// for it's response. An extension doing a lot of work can check it every so often, and
+    /// respond with what it has so far once it runs low, instead of working on a response the
+    /// tenant will no longer use.
+    ///
+    /// # Return
+    ///
+    /// The number of microseconds left before the invocation's deadline, zero once it has passed,
+    /// or None if the invocation has no deadline.
+    fn budget_us(&self) -> Option<u64> {
+        None
+    }
+
     /// This method will add a previously allocated region of memory to the
     /// database.
     ///
@@ -72,6 +166,69 @@ pub trait DB {
     /// False otherwise.
     fn put(&self, buf: WriteBuf) -> bool;
 
+    /// This method will add a previously allocated region of memory to the
+    /// database, but only if the object currently stored under it's key holds
+    /// an expected value. The comparison and the write are atomic with respect
+    /// to every other write to the key, including plain puts and deletes.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf`:      A previously allocated handle to be added to the database.
+    /// * `expected`: The value the object under the handle's key must hold,
+    ///               or None if there must be no object under it.
+    ///
+    /// # Return
+    ///
+    /// True if the object held the expected value and the handle was added to
+    /// the database. False otherwise, in which case the extension can read the
+    /// object again and retry.
+    fn cas(&self, buf: WriteBuf, expected: Option<&[u8]>) -> bool;
+
+    /// This method will append bytes to the value of a key-value pair inside
+    /// the database, creating the pair if it does not exist. Appends to a key
+    /// never change the bytes already in it's value, so ranges read earlier
+    /// stay valid.
+    ///
+    /// The database overrides this method with one that appends in place,
+    /// atomically with respect to every other write to the key. The default
+    /// below copies the whole value on every append, and is built on `cas`,
+    /// so it is only as atomic as the implementation's `cas`.
+    ///
+    /// # Arguments
+    ///
+    /// * `table`: An identifier of the data table the key-value pair
+    ///            belongs to.
+    /// * `key`:   A slice of bytes over the key to be appended to.
+    /// * `data`:  The bytes to be appended.
+    ///
+    /// # Return
+    ///
+    /// True if the bytes were appended. False if the allocation failed, or if
+    /// the append lost the race against other writes to the key
+    /// `APPEND_RETRIES` times in a row.
+    fn append(&self, table: u64, key: &[u8], data: &[u8]) -> bool {
+        for _retry in 0..APPEND_RETRIES {
+            let current = self.get(table, key);
+            let len = current.as_ref().map_or(0, |val| val.len());
+
+            let mut buf = match self.alloc(table, key, (len + data.len()) as u64) {
+                Some(buf) => buf,
+                None => return false,
+            };
+
+            if let Some(ref val) = current {
+                buf.write_slice(val.read());
+            }
+            buf.write_slice(data);
+
+            if self.cas(buf, current.as_ref().map(|val| val.read())) {
+                return true;
+            }
+        }
+
+        false
+    }
+
     /// This method will delete a key-value pair from the database if it exists.
     ///
     /// # Arguments
@@ -99,7 +256,46 @@ pub trait DB {
     ///               extension should perform said serialization for now.
     fn resp(&self, response: &[u8]);
 
-    /// This method is meant for testing, and will not do anything in the real
-    /// system.
+    /// This method will push the invocation back to the tenant that invoked the
+    /// extension, to be run at the client instead. The extension captures
+    /// whatever it needs to carry on in `state`, and should return right
+    /// after. The client runs the extension again, with `resumed` returning
+    /// the state, and the extension picks up from it rather than from the
+    /// start, so writes made before the pushback are not made again.
+    ///
+    /// Anything the extension wrote into it's response before the pushback is
+    /// dropped, and nothing written after it is kept.
+    ///
+    /// # Arguments
+    ///
+    /// * `state`: A slice over the state the extension is resumed with.
+    ///
+    /// # Return
+    ///
+    /// True if the invocation was pushed back. False if it is already running
+    /// at the client, in which case the extension should carry on itself.
+    fn pushback(&self, _state: &[u8]) -> bool {
+        false
+    }
+
+    /// This method will return the state a pushed back invocation is resumed
+    /// with.
+    ///
+    /// # Return
+    ///
+    /// A slice over the state the extension passed to `pushback`, or None if
+    /// the invocation is not resuming from a pushback.
+    fn resumed(&self) -> Option<&[u8]> {
+        None
+    }
+
+    /// This method logs a message on behalf of the extension. The server logs
+    /// it at the debug level under the "ext" target, along with the tenant
+    /// and the name of the extension, so it only shows up if the server's
+    /// `log_levels` ask for it.
+    ///
+    /// # Arguments
+    ///
+    /// * `msg`: The message to be logged.
     fn debug_log(&self, msg: &str);
 }
@@ -0,0 +1,561 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! A version of the `DB` trait and buffer API where every operation that can fail returns a
+//! `Result` saying why, rather than an `Option` or a `bool`, and where writing past the end of a
+//! `WriteBuf` or reading past the end of a `MultiReadBuf` is an error rather than a panic. The
+//! operations are added to the existing types by the traits in this module, and so extensions
+//! opt in by importing them, without any change to the extensions that do not:
+//!
+//! ```ignore
+//! use sandstorm::fallible::{Error, FallibleDB, FallibleWriteBuf};
+//!
+//! fn copy(db: &DB, table: u64, from: &[u8], to: &[u8]) -> Result<(), Error> {
+//!     let val = db.try_get(table, from)?;
+//!     let mut buf = db.try_alloc(table, to, val.len() as u64)?;
+//!     buf.try_write_slice(val.read())?;
+//!     db.try_put(buf)
+//! }
+//! ```
+//!
+//! This module is only built with the `fallible` feature of this crate.
+
+#[cfg(feature = "std")]
+use std::error;
+
+use core::fmt;
+
+use super::buf::{MultiGetIter, MultiReadBuf, ReadBuf, WriteBuf};
+use super::db::{QuotaExceeded, APPEND_RETRIES, DB};
+
+/// The reasons an operation on the database or a buffer can fail.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Error {
+    /// The object does not exist, or the table it was looked up in does not exist or cannot be
+    /// read by the tenant.
+    NotFound,
+
+    /// An allocation was refused for a quota.
+    Quota(QuotaExceeded),
+
+    /// An allocation was refused because the table does not exist or cannot be written by the
+    /// tenant.
+    AllocFailed,
+
+    /// A write was refused, either because the table cannot be written by the tenant, or
+    /// because the server does not accept writes to it (it is a read replica, for instance).
+    WriteFailed,
+
+    /// A compare and swap did not find the expected value, or was refused like a write.
+    Conflict,
+
+    /// An append lost the race against other writes to the key `APPEND_RETRIES` times in a row.
+    Contended,
+
+    /// A write to a `WriteBuf` needed more bytes than it had left.
+    Overflow { needed: usize, remaining: usize },
+
+    /// A `MultiReadBuf` was read, or it's cursor moved, past it's objects.
+    OutOfBounds,
+
+    /// The arguments of an operation were malformed, such as a list of keys that is not made up
+    /// of keys of the length given.
+    InvalidArgument,
+}
+
+#[cfg(feature = "std")]
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::NotFound => "Object not found",
+            Error::Quota(_) => "Allocation refused for a quota",
+            Error::AllocFailed => "Allocation failed",
+            Error::WriteFailed => "Write failed",
+            Error::Conflict => "Compare and swap failed",
+            Error::Contended => "Append contended",
+            Error::Overflow { .. } => "Write overflowed the buffer",
+            Error::OutOfBounds => "Out of bounds on MultiReadBuf",
+            Error::InvalidArgument => "Invalid argument",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::NotFound => write!(f, "Object or table not found"),
+            Error::Quota(quota) => write!(f, "Allocation refused for the {:?} quota", quota),
+            Error::AllocFailed => write!(f, "Allocation failed, table missing or not writable"),
+            Error::WriteFailed => write!(f, "Write refused by the database"),
+            Error::Conflict => write!(f, "Object did not hold the expected value"),
+            Error::Contended => write!(f, "Append lost {} races in a row", APPEND_RETRIES),
+            Error::Overflow { needed, remaining } => write!(
+                f,
+                "Write of {} bytes overflowed the buffer, {} bytes left",
+                needed, remaining
+            ),
+            Error::OutOfBounds => write!(f, "Out of bounds on MultiReadBuf"),
+            Error::InvalidArgument => write!(f, "Malformed arguments to the operation"),
+        }
+    }
+}
+
+/// The operations of the `DB` trait, returning a `Result` instead of an `Option` or a `bool`.
+/// Lookup the `DB` trait for documentation on what each of them does.
+pub trait FallibleDB {
+    /// `DB::get`, failing with `NotFound` if the object does not exist.
+    fn try_get(&self, table: u64, key: &[u8]) -> Result<ReadBuf, Error>;
+
+    /// `DB::multiget`, failing with `NotFound` if any of the objects does not exist.
+    fn try_multiget(&self, table: u64, key_len: u16, keys: &[u8]) -> Result<MultiReadBuf, Error>;
+
+    /// `DB::multiget_iter`, failing with `InvalidArgument` if `key_len` is zero or `keys` is not
+    /// made up of keys `key_len` bytes long, and `NotFound` if the table does not exist. Objects
+    /// that do not exist are yielded as None.
+    fn try_multiget_iter(
+        &self,
+        table: u64,
+        key_len: u16,
+        keys: &[u8],
+    ) -> Result<MultiGetIter, Error>;
+
+    /// `DB::get_range`, failing with `NotFound` if the object does not exist.
+    fn try_get_range(
+        &self,
+        table: u64,
+        key: &[u8],
+        offset: usize,
+        len: usize,
+    ) -> Result<ReadBuf, Error>;
+
+    /// `DB::alloc`, failing with `Quota` if the allocation was refused for a quota, and
+    /// `AllocFailed` otherwise.
+    fn try_alloc(&self, table: u64, key: &[u8], val_len: u64) -> Result<WriteBuf, Error>;
+
+    /// `DB::put`, failing with `WriteFailed`.
+    fn try_put(&self, buf: WriteBuf) -> Result<(), Error>;
+
+    /// `DB::cas`, failing with `Conflict`.
+    fn try_cas(&self, buf: WriteBuf, expected: Option<&[u8]>) -> Result<(), Error>;
+
+    /// `DB::append`, failing with the error of the allocation or write that failed, or
+    /// `Contended` if it lost too many races.
+    fn try_append(&self, table: u64, key: &[u8], data: &[u8]) -> Result<(), Error>;
+
+    /// `DB::del`, which always succeeds.
+    fn try_del(&self, table: u64, key: &[u8]) -> Result<(), Error>;
+}
+
+impl<T: DB + ?Sized> FallibleDB for T {
+    fn try_get(&self, table: u64, key: &[u8]) -> Result<ReadBuf, Error> {
+        self.get(table, key).ok_or(Error::NotFound)
+    }
+
+    fn try_multiget(&self, table: u64, key_len: u16, keys: &[u8]) -> Result<MultiReadBuf, Error> {
+        self.multiget(table, key_len, keys).ok_or(Error::NotFound)
+    }
+
+    fn try_multiget_iter(
+        &self,
+        table: u64,
+        key_len: u16,
+        keys: &[u8],
+    ) -> Result<MultiGetIter, Error> {
+        if key_len == 0 || keys.len() % key_len as usize != 0 {
+            return Err(Error::InvalidArgument);
+        }
+
+        self.multiget_iter(table, key_len, keys).ok_or(Error::NotFound)
+    }
+
+    fn try_get_range(
+        &self,
+        table: u64,
+        key: &[u8],
+        offset: usize,
+        len: usize,
+    ) -> Result<ReadBuf, Error> {
+        self.get_range(table, key, offset, len).ok_or(Error::NotFound)
+    }
+
+    fn try_alloc(&self, table: u64, key: &[u8], val_len: u64) -> Result<WriteBuf, Error> {
+        // The database only remembers the last quota an allocation was refused for, so an
+        // allocation refused for a missing table after one refused for a quota reports the quota.
+        self.alloc(table, key, val_len)
+            .ok_or_else(|| match self.quota_exceeded() {
+                Some(quota) => Error::Quota(quota),
+                None => Error::AllocFailed,
+            })
+    }
+
+    fn try_put(&self, buf: WriteBuf) -> Result<(), Error> {
+        match self.put(buf) {
+            true => Ok(()),
+            false => Err(Error::WriteFailed),
+        }
+    }
+
+    fn try_cas(&self, buf: WriteBuf, expected: Option<&[u8]>) -> Result<(), Error> {
+        match self.cas(buf, expected) {
+            true => Ok(()),
+            false => Err(Error::Conflict),
+        }
+    }
+
+    fn try_append(&self, table: u64, key: &[u8], data: &[u8]) -> Result<(), Error> {
+        // The same as `DB::append`, except that it tells a failed allocation from a lost race.
+        for _retry in 0..APPEND_RETRIES {
+            let current = self.get(table, key);
+            let len = current.as_ref().map_or(0, |val| val.len());
+
+            let mut buf = self.try_alloc(table, key, (len + data.len()) as u64)?;
+            if let Some(ref val) = current {
+                buf.try_write_slice(val.read())?;
+            }
+            buf.try_write_slice(data)?;
+
+            if self.cas(buf, current.as_ref().map(|val| val.read())) {
+                return Ok(());
+            }
+        }
+
+        Err(Error::Contended)
+    }
+
+    fn try_del(&self, table: u64, key: &[u8]) -> Result<(), Error> {
+        self.del(table, key);
+        Ok(())
+    }
+}
+
+/// The writes to a `WriteBuf`, failing with `Overflow` instead of panicking if the buffer does
+/// not have enough bytes left. A write that fails leaves the buffer as it was.
+pub trait FallibleWriteBuf {
+    /// Returns the number of bytes that can still be written into the buffer.
+    fn remaining(&self) -> usize;
+
+    /// `WriteBuf::write_slice`.
+    fn try_write_slice(&mut self, data: &[u8]) -> Result<(), Error>;
+
+    /// `WriteBuf::write_u8`.
+    fn try_write_u8(&mut self, data: u8) -> Result<(), Error>;
+
+    /// `WriteBuf::write_u16`.
+    fn try_write_u16(&mut self, data: u16, le: bool) -> Result<(), Error>;
+
+    /// `WriteBuf::write_u32`.
+    fn try_write_u32(&mut self, data: u32, le: bool) -> Result<(), Error>;
+
+    /// `WriteBuf::write_u64`.
+    fn try_write_u64(&mut self, data: u64, le: bool) -> Result<(), Error>;
+}
+
+// Returns an error if a buffer does not have a number of bytes left.
+fn reserve(buf: &WriteBuf, needed: usize) -> Result<(), Error> {
+    let remaining = buf.remaining();
+    match needed <= remaining {
+        true => Ok(()),
+        false => Err(Error::Overflow {
+            needed: needed,
+            remaining: remaining,
+        }),
+    }
+}
+
+impl FallibleWriteBuf for WriteBuf {
+    fn remaining(&self) -> usize {
+        self.capacity() - self.len()
+    }
+
+    fn try_write_slice(&mut self, data: &[u8]) -> Result<(), Error> {
+        reserve(self, data.len()).map(|_| self.write_slice(data))
+    }
+
+    fn try_write_u8(&mut self, data: u8) -> Result<(), Error> {
+        reserve(self, 1).map(|_| self.write_u8(data))
+    }
+
+    fn try_write_u16(&mut self, data: u16, le: bool) -> Result<(), Error> {
+        reserve(self, 2).map(|_| self.write_u16(data, le))
+    }
+
+    fn try_write_u32(&mut self, data: u32, le: bool) -> Result<(), Error> {
+        reserve(self, 4).map(|_| self.write_u32(data, le))
+    }
+
+    fn try_write_u64(&mut self, data: u64, le: bool) -> Result<(), Error> {
+        reserve(self, 8).map(|_| self.write_u64(data, le))
+    }
+}
+
+/// The reads of a `MultiReadBuf`, failing with `OutOfBounds` instead of panicking once it's
+/// cursor is past it's objects.
+pub trait FallibleMultiReadBuf {
+    /// `MultiReadBuf::len`.
+    fn try_len(&self) -> Result<usize, Error>;
+
+    /// `MultiReadBuf::read`.
+    fn try_read(&self) -> Result<&[u8], Error>;
+
+    /// `MultiReadBuf::next`, failing if there is no next object.
+    fn try_next(&self) -> Result<(), Error>;
+
+    /// `MultiReadBuf::prev`, failing if there is no previous object.
+    fn try_prev(&self) -> Result<(), Error>;
+}
+
+impl FallibleMultiReadBuf for MultiReadBuf {
+    fn try_len(&self) -> Result<usize, Error> {
+        match self.valid() {
+            true => Ok(self.len()),
+            false => Err(Error::OutOfBounds),
+        }
+    }
+
+    fn try_read(&self) -> Result<&[u8], Error> {
+        match self.valid() {
+            true => Ok(self.read()),
+            false => Err(Error::OutOfBounds),
+        }
+    }
+
+    fn try_next(&self) -> Result<(), Error> {
+        match self.num() > 0 && self.next() {
+            true => Ok(()),
+            false => Err(Error::OutOfBounds),
+        }
+    }
+
+    fn try_prev(&self) -> Result<(), Error> {
+        match self.prev() {
+            true => Ok(()),
+            false => Err(Error::OutOfBounds),
+        }
+    }
+}
+
+// This module contains unit tests for the fallible versions of the DB and buffer API.
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    use std::cell::{Cell, RefCell};
+    use std::collections::HashMap;
+
+    extern crate bytes;
+    use self::bytes::{Bytes, BytesMut};
+
+    // A database holding a single table in a map, refusing allocations once a quota is set.
+    struct MapDB {
+        objects: RefCell<HashMap<Vec<u8>, Vec<u8>>>,
+        quota: Cell<Option<QuotaExceeded>>,
+        races: Cell<usize>,
+    }
+
+    impl MapDB {
+        fn new() -> MapDB {
+            MapDB {
+                objects: RefCell::new(HashMap::new()),
+                quota: Cell::new(None),
+                races: Cell::new(0),
+            }
+        }
+    }
+
+    impl DB for MapDB {
+        fn get(&self, table: u64, key: &[u8]) -> Option<ReadBuf> {
+            match table {
+                1 => self.objects
+                    .borrow()
+                    .get(key)
+                    .map(|val| unsafe { ReadBuf::new(Bytes::from(&val[..])) }),
+                _ => None,
+            }
+        }
+
+        fn multiget(&self, table: u64, key_len: u16, keys: &[u8]) -> Option<MultiReadBuf> {
+            let mut vals = Vec::new();
+            for key in keys.chunks(key_len as usize) {
+                vals.push(Bytes::from(self.get(table, key)?.read()));
+            }
+            unsafe { Some(MultiReadBuf::new(vals)) }
+        }
+
+        fn alloc(&self, table: u64, key: &[u8], val_len: u64) -> Option<WriteBuf> {
+            if table != 1 || self.quota.get().is_some() {
+                return None;
+            }
+
+            let mut buf = BytesMut::with_capacity(1 + key.len() + val_len as usize);
+            buf.extend_from_slice(&[key.len() as u8]);
+            buf.extend_from_slice(key);
+            unsafe { Some(WriteBuf::new(table, buf)) }
+        }
+
+        fn quota_exceeded(&self) -> Option<QuotaExceeded> {
+            self.quota.get()
+        }
+
+        fn put(&self, buf: WriteBuf) -> bool {
+            let (_, buf) = unsafe { buf.freeze() };
+            let key_len = buf[0] as usize;
+            let (key, val) = buf[1..].split_at(key_len);
+            self.objects.borrow_mut().insert(key.to_vec(), val.to_vec());
+            true
+        }
+
+        fn cas(&self, buf: WriteBuf, expected: Option<&[u8]>) -> bool {
+            // Lose as many races as asked to.
+            if self.races.get() > 0 {
+                self.races.set(self.races.get() - 1);
+                return false;
+            }
+
+            let (_, frozen) = unsafe { buf.freeze() };
+            let key = frozen[1..1 + frozen[0] as usize].to_vec();
+            if self.objects.borrow().get(&key).map(|val| &val[..]) != expected {
+                return false;
+            }
+
+            let (_, val) = frozen[1..].split_at(key.len());
+            self.objects.borrow_mut().insert(key, val.to_vec());
+            true
+        }
+
+        fn del(&self, _table: u64, key: &[u8]) {
+            self.objects.borrow_mut().remove(key);
+        }
+
+        fn args(&self) -> &[u8] {
+            &[]
+        }
+
+        fn resp(&self, _response: &[u8]) {}
+
+        fn debug_log(&self, _msg: &str) {}
+    }
+
+    // This unit test verifies that lookups of missing objects and tables fail with NotFound.
+    #[test]
+    fn test_get() {
+        let db = MapDB::new();
+        db.objects.borrow_mut().insert(vec![1], vec![1, 2, 3]);
+
+        assert_eq!(&[1, 2, 3], db.try_get(1, &[1]).unwrap().read());
+        assert_eq!(&[2], db.try_get_range(1, &[1], 1, 1).unwrap().read());
+        assert_eq!(Error::NotFound, db.try_get(1, &[2]).err().unwrap());
+        assert_eq!(Error::NotFound, db.try_get(2, &[1]).err().unwrap());
+        assert_eq!(Error::NotFound, db.try_multiget(1, 1, &[1, 2]).err().unwrap());
+        assert_eq!(Error::NotFound, db.try_get_range(1, &[2], 0, 1).err().unwrap());
+    }
+
+    // This unit test verifies that a lookup of a list of keys yields misses per key, and only
+    // fails if the keys are malformed.
+    #[test]
+    fn test_multiget_iter() {
+        let db = MapDB::new();
+        db.objects.borrow_mut().insert(vec![1], vec![1]);
+        db.objects.borrow_mut().insert(vec![3], vec![3, 3]);
+
+        let mut found = Vec::new();
+        for val in db.try_multiget_iter(1, 1, &[1, 2, 3]).unwrap() {
+            found.push(val.map(|val| val.read().to_vec()));
+        }
+        assert_eq!(vec![Some(vec![1]), None, Some(vec![3, 3])], found);
+
+        assert_eq!(Error::InvalidArgument, db.try_multiget_iter(1, 2, &[1, 2, 3]).err().unwrap());
+        assert_eq!(Error::InvalidArgument, db.try_multiget_iter(1, 0, &[]).err().unwrap());
+    }
+
+    // This unit test verifies that refused allocations say whether a quota refused them.
+    #[test]
+    fn test_alloc() {
+        let db = MapDB::new();
+        assert!(db.try_alloc(1, &[1], 4).is_ok());
+        assert_eq!(Error::AllocFailed, db.try_alloc(2, &[1], 4).err().unwrap());
+
+        db.quota.set(Some(QuotaExceeded::Memory));
+        assert_eq!(
+            Error::Quota(QuotaExceeded::Memory),
+            db.try_alloc(1, &[1], 4).err().unwrap()
+        );
+    }
+
+    // This unit test verifies that writes past the end of a WriteBuf fail without changing it.
+    #[test]
+    fn test_overflow() {
+        let db = MapDB::new();
+        let mut buf = db.try_alloc(1, &[1], 64).unwrap();
+        let remaining = buf.remaining();
+
+        assert!(buf.try_write_u64(7, true).is_ok());
+        assert_eq!(remaining - 8, buf.remaining());
+        assert_eq!(
+            Error::Overflow {
+                needed: remaining,
+                remaining: remaining - 8,
+            },
+            buf.try_write_slice(&vec![0; remaining]).err().unwrap()
+        );
+        assert_eq!(8, buf.len());
+
+        assert!(buf.try_write_slice(&vec![0; remaining - 8]).is_ok());
+        assert!(buf.try_write_u8(1).is_err());
+        assert_eq!(remaining, buf.len());
+    }
+
+    // This unit test verifies that compare and swaps and appends report conflicts and
+    // contention, and that appends create and extend objects.
+    #[test]
+    fn test_write() {
+        let db = MapDB::new();
+        let mut buf = db.try_alloc(1, &[1], 1).unwrap();
+        buf.try_write_u8(9).unwrap();
+        assert_eq!(Error::Conflict, db.try_cas(buf, Some(&[1])).err().unwrap());
+
+        assert!(db.try_append(1, &[1], &[1, 2]).is_ok());
+        db.races.set(APPEND_RETRIES - 1);
+        assert!(db.try_append(1, &[1], &[3]).is_ok());
+        assert_eq!(&[1, 2, 3], db.try_get(1, &[1]).unwrap().read());
+
+        db.races.set(APPEND_RETRIES);
+        assert_eq!(Error::Contended, db.try_append(1, &[1], &[4]).err().unwrap());
+        assert_eq!(Error::AllocFailed, db.try_append(2, &[1], &[4]).err().unwrap());
+
+        assert!(db.try_del(1, &[1]).is_ok());
+        assert_eq!(Error::NotFound, db.try_get(1, &[1]).err().unwrap());
+    }
+
+    // This unit test verifies that reads past the objects of a MultiReadBuf fail instead of
+    // panicking, including on one with no objects.
+    #[test]
+    fn test_multiread() {
+        let db = MapDB::new();
+        db.objects.borrow_mut().insert(vec![1], vec![1]);
+        db.objects.borrow_mut().insert(vec![2], vec![2, 2]);
+
+        let vals = db.try_multiget(1, 1, &[1, 2]).unwrap();
+        assert_eq!(&[1], vals.try_read().unwrap());
+        assert!(vals.try_next().is_ok());
+        assert_eq!(2, vals.try_len().unwrap());
+        assert_eq!(Error::OutOfBounds, vals.try_next().err().unwrap());
+        assert_eq!(Error::OutOfBounds, vals.try_read().err().unwrap());
+
+        let empty = db.try_multiget(1, 1, &[]).unwrap();
+        assert_eq!(Error::OutOfBounds, empty.try_read().err().unwrap());
+        assert_eq!(Error::OutOfBounds, empty.try_next().err().unwrap());
+        assert_eq!(Error::OutOfBounds, empty.try_prev().err().unwrap());
+    }
+}
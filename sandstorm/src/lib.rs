@@ -13,26 +13,88 @@
  * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
  */
 
-#![feature(type_ascription)]
-#![feature(generator_trait)]
-#![feature(rustc_private)]
+// Without the `std` feature, only the DB trait, the buffers and pack are built, on core and
+// alloc, so that extensions can be built for targets without std (WASM, for instance).
+#![cfg_attr(not(feature = "std"), no_std)]
+#![cfg_attr(all(not(feature = "std"), feature = "nightly"), feature(alloc))]
+// Without the `nightly` feature, the crate builds on stable Rust, for extensions built on
+// continuations rather than generators.
+#![cfg_attr(feature = "nightly", feature(type_ascription))]
+#![cfg_attr(feature = "nightly", feature(generator_trait))]
+#![cfg_attr(feature = "nightly", feature(rustc_private))]
+
+#[cfg(not(feature = "std"))]
+#[cfg_attr(test, macro_use)]
+extern crate alloc;
+
+#[cfg(feature = "std")]
+extern crate core;
+
+#[cfg(feature = "derive")]
+extern crate sandstorm_derive;
+
+// The code the derives generate names this crate as `sandstorm`, including in it's own tests.
+#[cfg(all(test, feature = "derive"))]
+extern crate self as sandstorm;
 
 pub mod db;
 pub mod buf;
+pub mod pack;
+pub mod continuation;
+
+#[cfg(feature = "std")]
 pub mod null;
+#[cfg(feature = "std")]
 pub mod mock;
-pub mod pack;
+#[cfg(feature = "std")]
 pub mod allocator;
+#[cfg(feature = "std")]
+pub mod simd;
+
+#[cfg(not(feature = "std"))]
+pub mod bytes_alloc;
+
+#[cfg(feature = "fallible")]
+pub mod fallible;
 
+#[cfg(target_arch = "wasm32")]
+pub mod wasm;
+
+#[cfg(feature = "std")]
 pub use std::vec;
+#[cfg(feature = "std")]
 pub use std::result;
+#[cfg(feature = "std")]
 pub use std::time;
+#[cfg(all(feature = "std", feature = "nightly"))]
 pub use std::ops::Generator;
+#[cfg(feature = "std")]
 pub use std::rc;
+#[cfg(feature = "std")]
 pub use std::convert;
+#[cfg(feature = "std")]
 pub use std::boxed;
+#[cfg(feature = "std")]
 pub use std::mem::size_of;
+#[cfg(feature = "std")]
 pub use std::io;
 
+#[cfg(not(feature = "std"))]
+pub use alloc::vec;
+#[cfg(not(feature = "std"))]
+pub use core::result;
+#[cfg(all(not(feature = "std"), feature = "nightly"))]
+pub use core::ops::Generator;
+#[cfg(not(feature = "std"))]
+pub use alloc::rc;
+#[cfg(not(feature = "std"))]
+pub use core::convert;
+#[cfg(not(feature = "std"))]
+pub use alloc::boxed;
+#[cfg(not(feature = "std"))]
+pub use core::mem::size_of;
+
 extern crate byteorder;
-pub use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+pub use byteorder::LittleEndian;
+#[cfg(feature = "std")]
+pub use byteorder::{ReadBytesExt, WriteBytesExt};
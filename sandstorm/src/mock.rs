@@ -86,6 +86,18 @@ impl DB for MockDB {
         return true;
     }
 
+    fn cas(&self, buf: WriteBuf, expected: Option<&[u8]>) -> bool {
+        unsafe {
+            self.debug_log(&format!(
+                "Invoked cas(), buf {:?}, expected {:?}",
+                &buf.freeze().1[..],
+                expected
+            ));
+        }
+
+        return true;
+    }
+
     fn del(&self, table: u64, key: &[u8]) {
         self.debug_log(&format!(
             "Invoked del() on table {} for key {:?}",
@@ -52,6 +52,10 @@ impl DB for NullDB {
         return false;
     }
 
+    fn cas(&self, _buf: WriteBuf, _expected: Option<&[u8]>) -> bool {
+        return false;
+    }
+
     fn del(&self, _table: u64, _key: &[u8]) {}
 
     fn args(&self) -> &[u8] {
@@ -13,8 +13,12 @@
  * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
  */
 
-use std::mem;
-use std::slice;
+use core::mem;
+use core::ptr;
+use core::slice;
+
+#[cfg(feature = "derive")]
+pub use sandstorm_derive::{Pack, Unpack};
 
 /// Indicates a type is safe for the database to cast between raw bytes and values. Only types
 /// endorsed by this trait will be accepted by the unpack and consume functions in this module.
@@ -37,6 +41,17 @@ unsafe impl Safe for f32 {}
 unsafe impl Safe for bool {}
 unsafe impl Safe for () {}
 
+/// Arrays of safe types are safe too, for keys and other fixed length byte strings in structs
+/// deriving `Pack` and `Unpack`.
+macro_rules! safe_arrays {
+    ($($len:expr)*) => { $(unsafe impl<T: Safe> Safe for [T; $len] {})* };
+}
+
+safe_arrays! {
+    1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31 32
+    64 128 256 512 1024
+}
+
 /// Creates a `&'a A` that treats the bytes in `args` as an `A` without copying them. Also, returns a
 /// slice that has been advanced by the number of bytes that `A` occupies to make parsing
 /// slices with many records easier.
@@ -151,6 +166,68 @@ pub fn pack<'a, A>(arg: &'a A) -> &'a [u8]
     unsafe { slice::from_raw_parts(p, l) }
 }
 
+/// Reads an unsigned little endian integer of upto eight bytes, for the fields of arguments and
+/// records that are not laid out to be unpacked: those at unaligned offsets, or whose width is
+/// only known at runtime.
+///
+/// # Arguments
+///
+/// * `buf`: The bytes of the integer, least significant first.
+///
+/// # Return
+/// The integer. Bytes past the eighth are shifted out.
+pub fn le(buf: &[u8]) -> u64 {
+    buf.iter().rev().fold(0, |acc, &byte| (acc << 8) | byte as u64)
+}
+
+/// A struct with a fixed layout that can be written out as the bytes it occupies, as invoke
+/// arguments or as a stored record. Derive it with `#[derive(Pack)]`, which checks that the struct
+/// is `#[repr(C)]`, has only `Safe` fields, and has no padding whose bytes would be undefined.
+pub unsafe trait Pack: Sized {
+    /// The number of bytes a packed value occupies.
+    const LEN: usize = mem::size_of::<Self>();
+
+    /// Returns the bytes of this value, without copying them.
+    fn pack(&self) -> &[u8] {
+        let p = (self as *const Self) as *const u8;
+        unsafe { slice::from_raw_parts(p, Self::LEN) }
+    }
+}
+
+/// A struct with a fixed layout that can be read back from the bytes a `Pack` wrote out. Derive
+/// it with `#[derive(Unpack)]`, which also implements `Safe` for the struct, under the same checks
+/// as `#[derive(Pack)]`, so that it can be nested in tuples and the other derived structs.
+pub unsafe trait Unpack: Safe + Sized {
+    /// See `unpack`. Treats the bytes in `args` as a value without copying them.
+    fn unpack(args: &[u8]) -> Option<&Self> {
+        cast(args)
+    }
+
+    /// See `consume`. Identical, except it returns None instead of panicking if `args` isn't
+    /// aligned or is too short.
+    fn consume(args: &[u8]) -> Option<(&Self, &[u8])> {
+        cast(args).map(|value| (value, &args[mem::size_of::<Self>()..]))
+    }
+
+    /// Copies a value out of `args`, which need not be aligned. Use this on records read out of
+    /// a table, whose values are not guaranteed to be aligned.
+    ///
+    /// # Arguments
+    /// * `args`: Bytes holding the value at their start.
+    ///
+    /// # Return
+    /// A copy of the value, or None if `args` is shorter than it.
+    fn read(args: &[u8]) -> Option<Self>
+        where Self: Copy,
+    {
+        if mem::size_of::<Self>() <= args.len() {
+            Some(unsafe { ptr::read_unaligned(args.as_ptr() as *const Self) })
+        } else {
+            None
+        }
+    }
+}
+
 /// Creates a `&'a A` that treats the bytes in `args` as an `A` without copying them.
 ///
 /// # Arguments
@@ -180,6 +257,9 @@ fn cast<'a, T>(args: &'a [u8]) -> Option<&'a T> {
 mod test {
     use super::*;
 
+    #[cfg(not(feature = "std"))]
+    use alloc::vec::Vec;
+
     #[test]
     fn test_unpack() {
         let mut args = [0u8; 16];
@@ -207,6 +287,60 @@ mod test {
         assert_eq!(None, value);
     }
 
+    #[cfg(feature = "derive")]
+    #[derive(Pack, Unpack, Clone, Copy, Debug, PartialEq)]
+    #[repr(C)]
+    struct Args {
+        table: u64,
+        key_len: u16,
+        key: [u8; 2],
+        count: u32,
+    }
+
+    #[cfg(feature = "derive")]
+    #[derive(Pack, Unpack, Clone, Copy, Debug, PartialEq)]
+    #[repr(C)]
+    struct Record(Args, pub u64);
+
+    // This unit test verifies that derived structs pack to their bytes in memory, and unpack
+    // back from them, nested or not, aligned or not.
+    #[cfg(feature = "derive")]
+    #[test]
+    fn test_derive() {
+        let args = Args { table: 1, key_len: 2, key: [30, 0], count: 0x0201 };
+        assert_eq!(16, Args::LEN);
+        assert_eq!(&[1, 0, 0, 0, 0, 0, 0, 0, 2, 0, 30, 0, 1, 2, 0, 0], args.pack());
+
+        let record = Record(args, 7);
+        let mut buf = [0u64; 8];
+        let bytes: &mut [u8] =
+            unsafe { slice::from_raw_parts_mut(buf.as_mut_ptr() as *mut u8, 64) };
+        bytes[0..24].copy_from_slice(record.pack());
+        bytes[24..40].copy_from_slice(args.pack());
+        bytes[41..57].copy_from_slice(args.pack());
+
+        let (value, rest) = Record::consume(bytes).unwrap();
+        assert_eq!(record, *value);
+        let (value, _) = Args::consume(rest).unwrap();
+        assert_eq!(args, *value);
+
+        // Misaligned values can only be copied out, and short ones not at all.
+        assert_eq!(None, Args::unpack(&bytes[41..]));
+        assert_eq!(Some(args), Args::read(&bytes[41..]));
+        assert_eq!(None, Args::read(&bytes[41..56]));
+        assert_eq!(None, Args::consume(&bytes[56..]));
+    }
+
+    #[test]
+    fn test_le() {
+        assert_eq!(0, le(&[]));
+        assert_eq!(0x01, le(&[0x01]));
+        assert_eq!(0x0201, le(&[0x01, 0x02]));
+        assert_eq!(0x030201, le(&[0x01, 0x02, 0x03]));
+        assert_eq!(0x0807060504030201, le(&[0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08]));
+        assert_eq!(u64::max_value(), le(&[0xff; 8]));
+    }
+
     type OType = u16;
     type ObjectId = u32;
     type Assoc = (ObjectId, ObjectId, OType);
@@ -0,0 +1,157 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! Compute kernels vectorized with explicit SIMD instructions. Extensions are compiled without
+//! unsafe code, and so cannot call the intrinsics in `std::arch` themselves; this module wraps
+//! them behind safe functions, picking the widest instruction set the CPU supports at runtime
+//! and falling back to plain code on other CPUs. Every implementation returns the same result.
+
+#[cfg(target_arch = "x86")]
+use std::arch::x86::*;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+
+/// Returns a checksum of a buffer computed over a number of rounds, so that the compute spent on
+/// every byte can be dialed up or down. Round `r` sums up every byte XORed with `r` (modulo 256),
+/// and the checksum is the sum of every round, wrapping around. A single round is the sum of the
+/// bytes; zero rounds are zero.
+///
+/// # Arguments
+///
+/// * `buf`:    The buffer.
+/// * `rounds`: The number of rounds.
+///
+/// # Return
+///
+/// The checksum.
+pub fn checksum(buf: &[u8], rounds: u32) -> u64 {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("avx2") {
+            return unsafe { checksum_avx2(buf, rounds) };
+        }
+
+        if is_x86_feature_detected!("sse2") {
+            return unsafe { checksum_sse2(buf, rounds) };
+        }
+    }
+
+    checksum_scalar(buf, rounds)
+}
+
+// Sums up every byte of a buffer XORed with a mask.
+fn masked_sum(buf: &[u8], mask: u8) -> u64 {
+    buf.iter().fold(0, |sum, &byte| sum + (byte ^ mask) as u64)
+}
+
+// Implements `checksum()` without SIMD instructions.
+fn checksum_scalar(buf: &[u8], rounds: u32) -> u64 {
+    (0..rounds).fold(0, |sum: u64, round| sum.wrapping_add(masked_sum(buf, round as u8)))
+}
+
+// Implements `checksum()` 32 bytes at a time with AVX2. Every round XORs the bytes with the mask
+// and sums them up with a SAD (sum of absolute differences) against zero, which adds up every 8
+// bytes into a 64 bit lane.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "avx2")]
+unsafe fn checksum_avx2(buf: &[u8], rounds: u32) -> u64 {
+    let blocks = buf.len() / 32;
+    let zero = _mm256_setzero_si256();
+
+    let mut sum: u64 = 0;
+    for round in 0..rounds {
+        let mask = _mm256_set1_epi8(round as u8 as i8);
+        let mut acc = _mm256_setzero_si256();
+        for block in 0..blocks {
+            let val = _mm256_loadu_si256(buf.as_ptr().add(32 * block) as *const __m256i);
+            acc = _mm256_add_epi64(acc, _mm256_sad_epu8(_mm256_xor_si256(val, mask), zero));
+        }
+
+        let mut lanes = [0u64; 4];
+        _mm256_storeu_si256(lanes.as_mut_ptr() as *mut __m256i, acc);
+        let tail = masked_sum(&buf[32 * blocks..], round as u8);
+        sum = sum.wrapping_add(lanes.iter().fold(tail, |sum, lane| sum + lane));
+    }
+
+    sum
+}
+
+// Implements `checksum()` 16 bytes at a time with SSE2, the same way as `checksum_avx2()`.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "sse2")]
+unsafe fn checksum_sse2(buf: &[u8], rounds: u32) -> u64 {
+    let blocks = buf.len() / 16;
+    let zero = _mm_setzero_si128();
+
+    let mut sum: u64 = 0;
+    for round in 0..rounds {
+        let mask = _mm_set1_epi8(round as u8 as i8);
+        let mut acc = _mm_setzero_si128();
+        for block in 0..blocks {
+            let val = _mm_loadu_si128(buf.as_ptr().add(16 * block) as *const __m128i);
+            acc = _mm_add_epi64(acc, _mm_sad_epu8(_mm_xor_si128(val, mask), zero));
+        }
+
+        let mut lanes = [0u64; 2];
+        _mm_storeu_si128(lanes.as_mut_ptr() as *mut __m128i, acc);
+        let tail = masked_sum(&buf[16 * blocks..], round as u8);
+        sum = sum.wrapping_add(lanes.iter().fold(tail, |sum, lane| sum + lane));
+    }
+
+    sum
+}
+
+// This module contains unit tests for the SIMD compute kernels.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Returns a buffer of bytes that are not all the same.
+    fn buffer(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 37 + 11) as u8).collect()
+    }
+
+    // This unit test verifies that the checksum of a few bytes is their sum over every round.
+    #[test]
+    fn test_checksum_small() {
+        assert_eq!(6, checksum(&[1, 2, 3], 1));
+        assert_eq!(6 + (0 + 3 + 2), checksum(&[1, 2, 3], 2));
+        assert_eq!(0, checksum(&[1, 2, 3], 0));
+        assert_eq!(0, checksum(&[], 4));
+    }
+
+    // This unit test verifies that the SIMD implementations agree with the plain one across
+    // lengths that do and do not divide into whole vectors, and across masks that wrap around.
+    #[test]
+    fn test_checksum_simd() {
+        for len in 0..200 {
+            let buf = buffer(len);
+            for rounds in [1, 2, 7, 300].iter() {
+                let expected = checksum_scalar(&buf, *rounds);
+                assert_eq!(expected, checksum(&buf, *rounds));
+
+                #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+                {
+                    if is_x86_feature_detected!("avx2") {
+                        assert_eq!(expected, unsafe { checksum_avx2(&buf, *rounds) });
+                    }
+                    if is_x86_feature_detected!("sse2") {
+                        assert_eq!(expected, unsafe { checksum_sse2(&buf, *rounds) });
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,223 @@
+/* Copyright (c) 2018 University of Utah
+ *
+ * Permission to use, copy, modify, and distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR(S) DISCLAIM ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL AUTHORS BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! The DB trait for extensions built for WASM. The server only loads native extensions, but the
+//! client's executor can run a WASM build of one, for instance to resume an invocation the
+//! native build pushed back. The same code is built for both; the WASM build exports it's
+//! linear memory, and a `run` function handing the extension's `init_stable` to `run()`:
+//!
+//! ```ignore
+//! #[cfg(target_arch = "wasm32")]
+//! #[no_mangle]
+//! pub extern "C" fn run() -> u64 {
+//!     sandstorm::wasm::run(init_stable)
+//! }
+//! ```
+//!
+//! Every call into the database is an import the executor provides, with keys, values and
+//! responses passed as offsets and lengths into the extension's memory. Compare and swap is not
+//! available at the client, and always fails.
+
+#[cfg(feature = "std")]
+extern crate bytes;
+
+#[cfg(feature = "std")]
+use self::bytes::{BufMut, Bytes, BytesMut};
+
+#[cfg(not(feature = "std"))]
+use bytes_alloc::{BufMut, Bytes, BytesMut};
+
+use boxed::Box;
+use rc::Rc;
+use vec::Vec;
+
+use super::buf::{MultiReadBuf, ReadBuf, WriteBuf};
+use super::continuation::{Continuation, Step};
+use super::db::DB;
+
+// The calls into the database, provided by the executor running the extension.
+extern "C" {
+    // Looks up a key, returning the length of it's value, or -1 if it does not exist. The value
+    // is held until it is copied out with `sandstorm_take`.
+    fn sandstorm_get(table: u64, key: *const u8, key_len: u32) -> i32;
+
+    // Copies the value of the last key looked up into the extension's memory.
+    fn sandstorm_take(buf: *mut u8);
+
+    // Writes a key-value pair, returning 1 if it was written, and 0 otherwise.
+    fn sandstorm_put(table: u64, key: *const u8, key_len: u32, val: *const u8, val_len: u32)
+        -> u32;
+
+    // Deletes a key.
+    fn sandstorm_del(table: u64, key: *const u8, key_len: u32);
+
+    // Return the length of the arguments, and copy them into the extension's memory.
+    fn sandstorm_args_len() -> u32;
+    fn sandstorm_args(buf: *mut u8);
+
+    // Return the length of the state the invocation is resumed with, -1 if it is not resuming
+    // from a pushback, and copy the state into the extension's memory.
+    fn sandstorm_resumed_len() -> i32;
+    fn sandstorm_resumed(buf: *mut u8);
+
+    // Appends to the response.
+    fn sandstorm_resp(buf: *const u8, len: u32);
+
+    // Logs a message on behalf of the extension.
+    fn sandstorm_debug_log(msg: *const u8, len: u32);
+}
+
+/// The database a WASM extension sees.
+pub struct Host {
+    // The arguments to the extension, and the state it is resumed with, copied out of the
+    // executor when the invocation starts.
+    args: Vec<u8>,
+    resumed: Option<Vec<u8>>,
+}
+
+// Implementation of methods on Host.
+impl Host {
+    /// Returns the database for an invocation, holding it's arguments and the state it is
+    /// resumed with.
+    pub fn new() -> Host {
+        unsafe {
+            let mut args = Vec::new();
+            args.resize(sandstorm_args_len() as usize, 0);
+            sandstorm_args(args.as_mut_ptr());
+
+            let resumed = match sandstorm_resumed_len() {
+                len if len < 0 => None,
+                len => {
+                    let mut state = Vec::new();
+                    state.resize(len as usize, 0);
+                    sandstorm_resumed(state.as_mut_ptr());
+                    Some(state)
+                }
+            };
+
+            Host {
+                args: args,
+                resumed: resumed,
+            }
+        }
+    }
+
+    // Looks up the value of a key. None if it does not exist.
+    fn read(&self, table: u64, key: &[u8]) -> Option<Bytes> {
+        unsafe {
+            match sandstorm_get(table, key.as_ptr(), key.len() as u32) {
+                len if len < 0 => None,
+                len => {
+                    let mut val = Vec::new();
+                    val.resize(len as usize, 0);
+                    sandstorm_take(val.as_mut_ptr());
+                    Some(Bytes::from(val))
+                }
+            }
+        }
+    }
+}
+
+// The DB trait for Host. Allocations are laid out as the key's length (two bytes, little
+// endian), followed by the key, followed by whatever the extension writes into them.
+impl DB for Host {
+    fn get(&self, table: u64, key: &[u8]) -> Option<ReadBuf> {
+        self.read(table, key).map(|val| unsafe { ReadBuf::new(val) })
+    }
+
+    fn multiget(&self, table: u64, key_len: u16, keys: &[u8]) -> Option<MultiReadBuf> {
+        let mut vals = Vec::new();
+        for key in keys.chunks(key_len as usize) {
+            if key.len() != key_len as usize {
+                break;
+            }
+
+            match self.read(table, key) {
+                Some(val) => vals.push(val),
+                None => return None,
+            }
+        }
+
+        unsafe { Some(MultiReadBuf::new(vals)) }
+    }
+
+    fn alloc(&self, table: u64, key: &[u8], val_len: u64) -> Option<WriteBuf> {
+        if key.len() > u16::max_value() as usize {
+            return None;
+        }
+
+        let mut buf = BytesMut::with_capacity(2 + key.len() + val_len as usize);
+        buf.put_u16_le(key.len() as u16);
+        buf.put_slice(key);
+
+        unsafe { Some(WriteBuf::new(table, buf)) }
+    }
+
+    fn put(&self, buf: WriteBuf) -> bool {
+        let (table, buf) = unsafe { buf.freeze() };
+        let key_len = (buf[0] as usize) | ((buf[1] as usize) << 8);
+        let (key, val) = buf[2..].split_at(key_len);
+
+        unsafe {
+            let (klen, vlen) = (key.len() as u32, val.len() as u32);
+            sandstorm_put(table, key.as_ptr(), klen, val.as_ptr(), vlen) == 1
+        }
+    }
+
+    fn cas(&self, _buf: WriteBuf, _expected: Option<&[u8]>) -> bool {
+        false
+    }
+
+    fn del(&self, table: u64, key: &[u8]) {
+        unsafe { sandstorm_del(table, key.as_ptr(), key.len() as u32) }
+    }
+
+    fn args(&self) -> &[u8] {
+        &self.args
+    }
+
+    fn resp(&self, response: &[u8]) {
+        unsafe { sandstorm_resp(response.as_ptr(), response.len() as u32) }
+    }
+
+    fn resumed(&self) -> Option<&[u8]> {
+        self.resumed.as_ref().map(|state| &state[..])
+    }
+
+    fn debug_log(&self, msg: &str) {
+        unsafe { sandstorm_debug_log(msg.as_ptr(), msg.len() as u32) }
+    }
+}
+
+/// Runs an invocation of a WASM extension to completion. Called from the `run` function the
+/// extension exports.
+///
+/// # Arguments
+///
+/// * `init`: The extension's `init_stable`, returning the continuation to be run.
+///
+/// # Return
+///
+/// The value the continuation completed with.
+pub fn run(init: fn(Rc<DB>) -> Box<Continuation>) -> u64 {
+    let mut cont = init(Rc::new(Host::new()));
+
+    // Yields only matter to the server's scheduler.
+    loop {
+        if let Step::Done(ret) = cont.resume() {
+            return ret;
+        }
+    }
+}